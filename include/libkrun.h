@@ -64,231 +64,1505 @@ int32_t krun_set_log_level(uint32_t level);
  */
 int32_t krun_init_log(int target_fd, uint32_t level, uint32_t style, uint32_t options);
 
+/**
+ * Returns how many host file descriptors the devices of the process hold, out of the budget they
+ * share. The devices opening files or sockets on behalf of the guest, like virtio-fs and vsock,
+ * fail those requests with EMFILE once their share of the budget is used, rather than starving
+ * the other devices. virtio-fs waits a bit for descriptors to be released before failing.
+ *
+ * Arguments:
+ *  "in_use" - a pointer to store the number of file descriptors held by the devices.
+ *  "limit"  - a pointer to store the number of file descriptors the devices may hold in total.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_get_fd_usage(size_t *in_use, size_t *limit);
+
+/**
+ * Sets how many host file descriptors the devices of the process may hold in total. The default
+ * is the soft RLIMIT_NOFILE of the process when the first device is created, minus a reserve for
+ * the library itself, so it must be set again after raising that limit.
+ *
+ * Arguments:
+ *  "limit" - the number of file descriptors the devices may hold in total.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_fd_budget(size_t limit);
+
+#define KRUN_CAP_VIRTIOFS            (1ULL << 0)
+#define KRUN_CAP_OVERLAYFS           (1ULL << 1)
+#define KRUN_CAP_OVERLAYFS_EPHEMERAL (1ULL << 2)
+#define KRUN_CAP_DAX                 (1ULL << 3)
+#define KRUN_CAP_BLK                 (1ULL << 4)
+#define KRUN_CAP_NET                 (1ULL << 5)
+#define KRUN_CAP_GPU                 (1ULL << 6)
+#define KRUN_CAP_SND                 (1ULL << 7)
+#define KRUN_CAP_MEMORY_HOTPLUG      (1ULL << 8)
+#define KRUN_CAP_SNAPSHOT            (1ULL << 9)
+#define KRUN_CAP_EFI                 (1ULL << 10)
+#define KRUN_CAP_TEE                 (1ULL << 11)
+#define KRUN_CAP_AMD_SEV             (1ULL << 12)
+#define KRUN_CAP_OCI                 (1ULL << 13)
+
+/**
+ * Returns the capabilities of this build of the library, which depend on the features it was
+ * built with and on the host platform, so embedders can adapt instead of finding out from the
+ * calls failing with ENOTSUP or missing from the library:
+ *  KRUN_CAP_VIRTIOFS            - virtio-fs shares, krun_add_virtiofs and related calls.
+ *  KRUN_CAP_OVERLAYFS           - overlay roots, krun_set_overlayfs_root.
+ *  KRUN_CAP_OVERLAYFS_EPHEMERAL - overlay roots with a top layer in memory,
+ *                                 krun_set_overlayfs_ephemeral.
+ *  KRUN_CAP_DAX                 - DAX windows for the virtio-fs shares.
+ *  KRUN_CAP_BLK                 - virtio-blk disks, krun_add_disk and related calls.
+ *  KRUN_CAP_NET                 - virtio-net devices, krun_set_passt_fd and krun_set_gvproxy_path.
+ *  KRUN_CAP_GPU                 - virtio-gpu devices, krun_set_gpu_options.
+ *  KRUN_CAP_SND                 - virtio-snd devices, krun_set_snd_device.
+ *  KRUN_CAP_MEMORY_HOTPLUG      - krun_set_hotplug_memory and growing with krun_resize_vm.
+ *  KRUN_CAP_SNAPSHOT            - krun_snapshot_save and krun_snapshot_restore.
+ *  KRUN_CAP_EFI                 - booting the bundled EFI firmware.
+ *  KRUN_CAP_TEE                 - confidential microVMs, krun_set_tee_config_file.
+ *  KRUN_CAP_AMD_SEV             - confidential microVMs on AMD SEV.
+ *  KRUN_CAP_OCI                 - roots built from OCI images, krun_set_root_oci and
+ *                                 krun_set_root_oci_lazy.
+ * The bits are never reused, and the ones unknown to the caller can be ignored.
+ *
+ * Arguments:
+ *  "caps" - a pointer to store the mask of KRUN_CAP_* capabilities.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_get_capabilities(uint64_t *caps);
+
 /**
  * Creates a configuration context.
  *
  * Returns:
- *  The context ID on success or a negative error number on failure.
+ *  The context ID on success or a negative error number on failure.
+ */
+int32_t krun_create_ctx();
+
+/**
+ * Frees an existing configuration context.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_free_ctx(uint32_t ctx_id);
+
+/**
+ * Sets the basic configuration parameters for the microVM.
+ *
+ * Arguments:
+ *  "ctx_id"    - the configuration context ID.
+ *  "num_vcpus" - the number of vCPUs.
+ *  "ram_mib"   - the amount of RAM in MiB.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_vm_config(uint32_t ctx_id, uint8_t num_vcpus, uint32_t ram_mib);
+
+/**
+ * Reserves room for memory to be hotplugged into the microVM at runtime with krun_resize_vm,
+ * through a virtio-mem device. Not available in libkrun-SEV.
+ *
+ * Only the memory the guest plugs in is backed on the host, and the memory it unplugs is released.
+ * The guest kernel needs virtio-mem support (CONFIG_VIRTIO_MEM) and memory hotplug enabled.
+ *
+ * Arguments:
+ *  "ctx_id"  - the configuration context ID.
+ *  "max_mib" - the most memory that can be hotplugged, in MiB, or zero for none.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_hotplug_memory(uint32_t ctx_id, uint32_t max_mib);
+
+/**
+ * Resizes the memory of a microVM. Before krun_start_enter, this sets the amount of RAM it boots
+ * with, like krun_set_vm_config. Once the microVM runs, it may be called from another thread to
+ * change the memory of the guest within the room reserved with krun_set_hotplug_memory: the guest
+ * is requested to plug or unplug memory until it has "ram_mib" MiB, at its own pace. Unplugging
+ * memory the guest still uses may not fully succeed.
+ *
+ * The number of vCPUs can't be changed, as the microVMs have no ACPI to tell the guest about new
+ * vCPUs.
+ *
+ * Arguments:
+ *  "ctx_id"  - the configuration context ID.
+ *  "ram_mib" - the amount of RAM in MiB, including the memory the microVM booted with.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there is no context with that ID
+ *       -ENOTSUP when no room for hotplugged memory was reserved or the guest has no virtio-mem
+ *                driver
+ *       -EINVAL when "ram_mib" is zero or below the memory the microVM booted with, or beyond the
+ *               room reserved for hotplugged memory, which is plugged in multiples of 2 MiB
+ */
+int32_t krun_resize_vm(uint32_t ctx_id, uint32_t ram_mib);
+
+/**
+ * Saves a snapshot of a running microVM to a file, from another thread than the one running
+ * krun_start_enter. Only available on x86_64 Linux, and not in libkrun-SEV.
+ *
+ * The memory of the guest is copied while it keeps running, then its vCPUs are paused and its
+ * devices quiesced for the pages written in the meantime to be copied again, along with the state
+ * of the vCPUs and of the devices. The microVM keeps running afterwards. The guest must not hold
+ * deleted files of a virtio-fs share, nor have files mapped through DAX, and the memory hotplugged
+ * with krun_resize_vm can't be saved.
+ *
+ * A microVM with disks, a GPU, sound or a virtio-fs share served over 9P can't be snapshotted, nor
+ * one using a split irqchip.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "path"   - a null-terminated string with the path of the snapshot file, which is replaced.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there is no running microVM with that ID
+ *       -ENOTSUP when snapshots aren't supported on this host, or for the devices or the irqchip
+ *                of this microVM
+ *       -EBUSY when the guest holds files of a virtio-fs share that can't be saved
+ */
+int32_t krun_snapshot_save(uint32_t ctx_id, const char *path);
+
+/**
+ * Makes krun_start_enter restore the microVM from a snapshot saved by krun_snapshot_save, in place
+ * of booting it. Only available on x86_64 Linux, and not in libkrun-SEV.
+ *
+ * The context must be configured like the one of the snapshotted microVM: the same number of vCPUs
+ * and amount of RAM, and the same devices added in the same order. The virtio-fs shares must have
+ * the same files at the same paths. The connections of vsock and of the network devices are lost.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "path"   - a null-terminated string with the path of the snapshot file.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there is no context with that ID
+ *       -ENOTSUP when snapshots aren't supported on this host
+ */
+int32_t krun_snapshot_restore(uint32_t ctx_id, const char *path);
+
+/**
+ * Sets the path to be use as root for the microVM. Not available in libkrun-SEV.
+ *
+ * Arguments:
+ *  "ctx_id"    - the configuration context ID.
+ *  "root_path" - a null-terminated string representing the path to be used as root.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EEXIST when a root device is already set
+ *
+ * Notes:
+ *  This function is mutually exclusive with krun_set_overlayfs_root.
+ *  The guest can't modify the init binary or the "/.krun_config.json" it reads, which is
+ *  served as it was when the guest first read it.
+ */
+int32_t krun_set_root(uint32_t ctx_id, const char *root_path);
+
+/**
+ * Sets up an OverlayFS to be used as root for the microVM. Not available in libkrun-SEV.
+ *
+ * Arguments:
+ *  "ctx_id"      - the configuration context ID.
+ *  "root_layers" - an array of string pointers to filesystem paths representing
+ *                  the layers to be used for the OverlayFS. The array must be
+ *                  NULL-terminated and contain at least one layer.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EINVAL when no layers are provided
+ *       -EEXIST when a root device is already set
+ *
+ * Notes:
+ *  This function is mutually exclusive with krun_set_root.
+ *  The guest can't modify the init binary or the "/.krun_config.json" it reads, which is
+ *  served as it was when the guest first read it.
+ */
+int32_t krun_set_overlayfs_root(uint32_t ctx_id, const char *const root_layers[]);
+
+/**
+ * Sets up an OverlayFS root made of the layers of an OCI image. Only available in the builds of
+ * the library with OCI support, see KRUN_CAP_OCI, and not in libkrun-SEV.
+ *
+ * The image is either a local OCI image layout directory, as written by "skopeo copy" to an
+ * "oci:" destination, or an uncompressed docker archive, as written by "docker save". For a
+ * multi-platform image, the manifest for linux and the architecture of the host is used.
+ *
+ * The layers are unpacked into "$XDG_CACHE_HOME/libkrun/oci" (or "~/.cache/libkrun/oci"),
+ * where they are shared by all the images, and checked against the digests of the image, a layer
+ * that doesn't match failing the call. As unpacking may take a while, it is done by this call
+ * rather than when the microVM starts. The writes of the guest go to a new top layer in the
+ * "containers" directory of the cache, which is left there when the microVM shuts down, for the
+ * embedder to keep or remove. The root can be made ephemeral with krun_set_overlayfs_ephemeral.
+ *
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID.
+ *  "image_path" - the path of the OCI image layout directory or of the docker archive.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EEXIST  when a root device is already set
+ *       -EINVAL  when the image is malformed or one of its layers doesn't match its digest
+ *       -ENOTSUP when the image uses digests other than SHA-256 or encrypted layers
+ *
+ * Notes:
+ *  This function is mutually exclusive with krun_set_root and krun_set_overlayfs_root.
+ */
+int32_t krun_set_root_oci(uint32_t ctx_id, const char *image_path);
+
+/**
+ * Sets up an OverlayFS root made of the layers of an OCI image, like krun_set_root_oci, but
+ * without unpacking the data of their files. Only available in the builds of the library with OCI
+ * support, see KRUN_CAP_OCI, and not in libkrun-SEV.
+ *
+ * Each layer is read once by this call, to check it against its digest and to create its
+ * directories, links and empty placeholders of its files in the cache. The data of a file is only
+ * extracted from the layer when the guest first opens it, which decompresses the layer up to the
+ * file, so the first open of a file far into a large layer may take a while. The layers indexed
+ * this way are kept in the cache, along with the files extracted so far, and are reused by the
+ * later calls, but not by krun_set_root_oci, nor the other way around.
+ *
+ * The layers must be left in the image for as long as the microVM runs.
+ *
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID.
+ *  "image_path" - the path of the OCI image layout directory or of the docker archive.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EEXIST  when a root device is already set
+ *       -EINVAL  when the image is malformed or one of its layers doesn't match its digest
+ *       -ENOTSUP when the image uses digests other than SHA-256 or encrypted layers
+ *
+ * Notes:
+ *  This function is mutually exclusive with krun_set_root, krun_set_overlayfs_root and
+ *  krun_set_root_oci.
+ */
+int32_t krun_set_root_oci_lazy(uint32_t ctx_id, const char *image_path);
+
+/**
+ * Makes the OverlayFS root set with krun_set_overlayfs_root ephemeral. Not available in
+ * libkrun-SEV.
+ *
+ * All the layers passed to krun_set_overlayfs_root become read-only, and the writes of the guest
+ * go to a RAM-backed top layer (a private directory in /dev/shm) instead, which is discarded when
+ * the VM shuts down. Nothing is ever written to the host disk. Only supported on Linux: on macOS,
+ * the top layer of an OverlayFS root is always the last layer given to krun_set_overlayfs_root.
+ *
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID.
+ *  "size_limit" - the maximum size, in bytes, of the files written by the guest. Writes going over
+ *                 it fail with ENOSPC in the guest.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EINVAL when "size_limit" is zero
+ *       -ENOENT when no OverlayFS root has been set
+ *       -ENOTSUP on macOS
+ */
+int32_t krun_set_overlayfs_ephemeral(uint32_t ctx_id, uint64_t size_limit);
+
+/**
+ * DEPRECATED. Use krun_add_disk instead.
+ *
+ * Sets the path to the disk image that contains the file-system to be used as root for the microVM.
+ * The only supported image format is "raw".
+ *
+ * Arguments:
+ *  "ctx_id"    - the configuration context ID.
+ *  "disk_path" - a null-terminated string representing the path leading to the disk image that
+ *                contains the root file-system.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_root_disk(uint32_t ctx_id, const char *disk_path);
+
+/**
+ * DEPRECATED. Use krun_add_disk instead.
+ *
+ * Sets the path to the disk image that contains the file-system to be used as
+ * a data partition for the microVM.  The only supported image format is "raw".
+ *
+ * Arguments:
+ *  "ctx_id"    - the configuration context ID.
+ *  "disk_path" - a null-terminated string representing the path leading to the disk image that
+ *                contains the root file-system.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_data_disk(uint32_t ctx_id, const char *disk_path);
+
+/**
+ * Adds a disk image to be used as a general partition for the microVM. The only supported image
+ * format is "raw".
+ *
+ * This API is mutually exclusive with the deprecated krun_set_root_disk and
+ * krun_set_data_disk methods and must not be used together.
+ *
+ * This function deliberately only handles images in the Raw format, because it doesn't allow
+ * specifying an image format, and probing an image's format is dangerous. For more information,
+ * see the security note on `krun_add_disk2`, which allows opening non-Raw images.
+ *
+ * Arguments:
+ *  "ctx_id"    - the configuration context ID.
+ *  "block_id"  - a null-terminated string representing the partition.
+ *  "disk_path" - a null-terminated string representing the path leading to the disk image.
+ *  "read_only" - whether the mount should be read-only. Required if the caller does not have
+ *                write permissions (for disk images in /usr/share).
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_add_disk(uint32_t ctx_id, const char *block_id, const char *disk_path, bool read_only);
+
+/* Supported disk image formats */
+#define KRUN_DISK_FORMAT_RAW 0
+#define KRUN_DISK_FORMAT_QCOW2 1
+/**
+ * Adds a disk image to be used as a general partition for the microVM. The supported
+ * image formats are: "raw" and "qcow2".
+ *
+ * A qcow2 image is opened along with its backing files, which are only ever read, so several
+ * images can use the same base image as copy-on-write overlays. The guest sees the active state
+ * of the image, and krun_set_disk_snapshot can revert it to one of its internal snapshots or take
+ * a new one before the microVM starts.
+ *
+ * This API is mutually exclusive with the deprecated krun_set_root_disk and
+ * krun_set_data_disk methods and must not be used together.
+ *
+ * SECURITY NOTE:
+ * Non-Raw images can reference other files, which libkrun will automatically open, and to which the
+ * guest will have access. Libkrun should therefore never be asked to open an image in a non-Raw
+ * format when it doesn't come from a fully trustworthy source.
+ *
+ * Consequently, probing an image's format is quite dangerous and to be avoided if at all possible,
+ * which is why libkrun provides no facilities for doing so. If it's not clear what format an image
+ * has, it may also not be clear whether it can be trusted to not reference files to which the guest
+ * shouldn't have access.
+ *
+ * If probing absolutely can't be avoided, it must only be done on images that are fully trusted, i.e.
+ * before a potentially untrusted guest had write access to it. Specifically, consider that a guest has
+ * full access to all of a Raw image, and can therefore turn it into a file in an arbitrary format, for
+ * example, into a Qcow2 image, referencing and granting a malicious guest access to arbitrary files.
+ * To hand a Raw image to an untrusted and potentially malicious guest, and then to re-probe it after
+ * the guest was able to write to it (when it can no longer be trusted), would therefore be a severe
+ * security vulnerability.
+ *
+ * Therefore, after having probed a yet fully trusted image once, the result must be remembered so the
+ * image will from then on always be opened in the format that was detected originally. When adhering
+ * to this, a guest can write anything they want to a Raw image, it's always going to be opened as a
+ * Raw image, preventing the security vulnerability outlined above.
+ *
+ * However, if at all possible, the image format should be explicitly selected based on knowledge
+ * obtained separately from the pure image data, for example by the user.
+ *
+ * Arguments:
+ *  "ctx_id"      - the configuration context ID.
+ *  "block_id"    - a null-terminated string representing the partition.
+ *  "disk_path"   - a null-terminated string representing the path leading to the disk image.
+ *  "disk_format" - the disk image format (i.e. KRUN_DISK_FORMAT_{RAW, QCOW2})
+ *  "read_only"   - whether the mount should be read-only. Required if the caller does not have
+ *                  write permissions (for disk images in /usr/share).
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_add_disk2(uint32_t ctx_id,
+                       const char *block_id,
+                       const char *disk_path,
+                       uint32_t disk_format,
+                       bool read_only);
+
+#define KRUN_DISK_SNAPSHOT_APPLY 0
+#define KRUN_DISK_SNAPSHOT_CREATE 1
+/**
+ * Selects an internal snapshot of a qcow2 disk image added with krun_add_disk2, to be applied or
+ * taken when the microVM starts, before the guest can see the disk.
+ *
+ * Applying a snapshot reverts the image to the state it was in when the snapshot was taken,
+ * dropping the changes made since. Taking a snapshot records the state the image is in under the
+ * given name, so that it can be applied when booting later. The snapshots are stored in the image
+ * itself, and share the clusters they have in common with its active state and its other
+ * snapshots.
+ *
+ * Arguments:
+ *  "ctx_id"        - the configuration context ID.
+ *  "block_id"      - the block ID the disk was added with.
+ *  "snapshot_name" - a null-terminated string representing the name of the snapshot.
+ *  "action"        - what to do with the snapshot (i.e. KRUN_DISK_SNAPSHOT_{APPLY, CREATE}).
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure. Fails with -ENOENT if no disk was added
+ *  with that block ID, and with -EINVAL if the disk isn't a writable qcow2 image.
+ */
+int32_t krun_set_disk_snapshot(uint32_t ctx_id,
+                               const char *block_id,
+                               const char *snapshot_name,
+                               uint32_t action);
+
+/**
+ * NO LONGER SUPPORTED. DO NOT USE.
+ *
+ * Configures the mapped volumes for the microVM. Only supported on macOS, on Linux use
+ * user_namespaces and bind-mounts instead. Not available in libkrun-SEV.
+ *
+ * Arguments:
+ *  "ctx_id"         - the configuration context ID.
+ *  "mapped_volumes" - an array of string pointers with format "host_path:guest_path" representing
+ *                     the volumes to be mapped inside the microVM
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_mapped_volumes(uint32_t ctx_id, const char *const mapped_volumes[]);
+
+/**
+ * Adds an independent virtio-fs device pointing to a host's directory with a tag.
+ *
+ * Arguments:
+ *  "ctx_id"         - the configuration context ID.
+ *  "c_tag"          - tag to identify the filesystem in the guest.
+ *  "c_path"         - full path to the directory in the host to be exposed to the guest.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_add_virtiofs(uint32_t ctx_id,
+                          const char *c_tag,
+                          const char *c_path);
+
+/**
+ * Adds an independent virtio-fs device pointing to a host's directory with a tag. This
+ * variant allows specifying the size of the DAX window.
+ *
+ * Arguments:
+ *  "ctx_id"         - the configuration context ID.
+ *  "c_tag"          - tag to identify the filesystem in the guest.
+ *  "c_path"         - full path to the directory in the host to be exposed to the guest.
+ *  "shm_size"       - size of the DAX SHM window in bytes.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_add_virtiofs2(uint32_t ctx_id,
+                           const char *c_tag,
+                           const char *c_path,
+                           uint64_t shm_size);
+
+/**
+ * Restricts the guest users that may use a virtio-fs device. Not available in libkrun-SEV.
+ *
+ * The credentials of every request are checked before it reaches the host filesystem, regardless
+ * of the file modes, and requests from other users fail with EACCES in the guest. Guest root is
+ * always allowed. Only the primary group of the requesting process is checked against "gids".
+ *
+ * Arguments:
+ *  "ctx_id"   - the configuration context ID.
+ *  "c_tag"    - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "uids"     - an array of the guest user IDs allowed to use the device.
+ *  "num_uids" - the number of entries in "uids".
+ *  "gids"     - an array of the guest group IDs allowed to use the device.
+ *  "num_gids" - the number of entries in "gids".
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there is no device with that tag
+ */
+int32_t krun_set_virtiofs_access(uint32_t ctx_id,
+                                 const char *c_tag,
+                                 const uint32_t *uids,
+                                 size_t num_uids,
+                                 const uint32_t *gids,
+                                 size_t num_gids);
+
+/**
+ * Runs the requests of every guest user and group on a virtio-fs device as the same host user
+ * and group, like NFS's all_squash. Not available in libkrun-SEV.
+ *
+ * Files created by the guest are owned by "uid" and "gid" on the host, and a chown in the guest
+ * gives them to "uid" and "gid" whatever the owners it asks for. The owners of the files are
+ * reported to the guest as they are on the host.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "c_tag"  - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "uid"    - the host user ID the requests run as.
+ *  "gid"    - the host group ID the requests run as.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there is no device with that tag
+ */
+int32_t krun_set_virtiofs_squash(uint32_t ctx_id,
+                                 const char *c_tag,
+                                 uint32_t uid,
+                                 uint32_t gid);
+
+/**
+ * Translates the guest user and group IDs of a virtio-fs device to host ones by ranges, like the
+ * ID maps of a user namespace. Not available in libkrun-SEV.
+ *
+ * Each range is three consecutive integers: the first guest ID, the first host ID it maps to, and
+ * the number of IDs in the range. The requests run with the host IDs the guest ones map to, and
+ * the owners of the files are reported to the guest as the guest IDs their host ones map back
+ * to. The IDs outside all the ranges map to the overflow ID 65534, both ways. The access rules
+ * of the device, if any, are checked against the guest IDs.
+ *
+ * Arguments:
+ *  "ctx_id"         - the configuration context ID.
+ *  "c_tag"          - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "uid_map"        - an array of "num_uid_ranges" user ID ranges.
+ *  "num_uid_ranges" - the number of ranges in "uid_map".
+ *  "gid_map"        - an array of "num_gid_ranges" group ID ranges.
+ *  "num_gid_ranges" - the number of ranges in "gid_map".
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there is no device with that tag
+ */
+int32_t krun_set_virtiofs_idmap(uint32_t ctx_id,
+                                const char *c_tag,
+                                const uint32_t *uid_map,
+                                size_t num_uid_ranges,
+                                const uint32_t *gid_map,
+                                size_t num_gid_ranges);
+
+/**
+ * Makes the changes to the directory entries of a virtio-fs device durable before they are
+ * acknowledged to the guest. Not available in libkrun-SEV.
+ *
+ * When enabled, the host directories whose entries are created, linked, removed or renamed are
+ * flushed to the disk before the request completes, so that a rename acknowledged to the guest
+ * survives a host crash. This slows down workloads that create or remove many files.
+ *
+ * Arguments:
+ *  "ctx_id"  - the configuration context ID.
+ *  "c_tag"   - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "durable" - whether changes to directory entries are flushed before they are acknowledged.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there is no device with that tag
+ */
+int32_t krun_set_virtiofs_durable(uint32_t ctx_id, const char *c_tag, bool durable);
+
+/**
+ * Allows the guest to set the immutable and append-only flags of the host files shared by a
+ * virtio-fs device, e.g. with chattr. Not available in libkrun-SEV.
+ *
+ * The flags of the host files are always reported to the guest, but by default they can only be
+ * changed on the host. Setting the immutable flag on a Linux host still requires the
+ * CAP_LINUX_IMMUTABLE capability.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "c_tag"  - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "allow"  - whether the guest may set the flags.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there is no device with that tag
+ */
+int32_t krun_set_virtiofs_file_flags(uint32_t ctx_id, const char *c_tag, bool allow);
+
+/**
+ * Allows the guest to empty a directory tree of an overlay virtio-fs device in a single request,
+ * rather than with one request per entry, by issuing the VIRTIO_IOC_REMOVE_TREE ioctl
+ * (_IOR('v', 3, uint64_t[3])) on the open directory. The argument of the ioctl is the maximum
+ * number of entries to remove, or zero for no limit, and it returns the number of entries removed,
+ * the number of entries that couldn't be removed and whether the directory was fully visited, so
+ * that a huge tree can be emptied in several requests. The directory itself is left in place.
+ * Only requests running as root may use it, and never on the root directory. Only supported on
+ * Linux hosts. Not available in libkrun-SEV.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "c_tag"  - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "allow"  - whether the guest may empty directory trees.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there is no device with that tag
+ */
+int32_t krun_set_virtiofs_remove_tree(uint32_t ctx_id, const char *c_tag, bool allow);
+
+/**
+ * Allows the guest to create many small files of a directory of an overlay virtio-fs device in a
+ * single request, with their content, owner, mode and modification time, rather than with several
+ * requests per file, so that a guest agent extracting an archive doesn't wait for a round trip
+ * per file. The files of a batch are all created or none is, and the request fails if any of them
+ * exists already.
+ *
+ * The guest agent issues the VIRTIO_IOC_BATCH_CREATE ioctl (_IOWR('v', 4, size)) on the open
+ * directory, with a batch of at most 16 KiB in native endianness and without padding: a header of
+ * the version (1) and the number of files as uint32_t, then for each file its mode, uid, gid, name
+ * length, content length and modification time nanoseconds as uint32_t and seconds as int64_t,
+ * followed by its name and content. A uid or gid of UINT32_MAX is left alone, and the files are
+ * regular files. It returns a header of the version of the device and the number of files
+ * created, so a batch of no files tells whether the device supports it, while a device that
+ * doesn't allow it fails the ioctl with EOPNOTSUPP. Only supported on Linux hosts. Not available
+ * in libkrun-SEV.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "c_tag"  - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "allow"  - whether the guest may create files in batches.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there is no device with that tag
+ */
+int32_t krun_set_virtiofs_batch_create(uint32_t ctx_id, const char *c_tag, bool allow);
+
+/**
+ * Has the entries of the directories of an overlay virtio-fs device stat'ed on the host in the
+ * background the first time the guest looks a directory up or opens it, up to a number of entries
+ * per directory, so that the host has them cached when the guest looks them up right after, as
+ * shells and tools listing directories do. Only supported on Linux hosts. Not available in
+ * libkrun-SEV.
+ *
+ * Arguments:
+ *  "ctx_id"      - the configuration context ID.
+ *  "c_tag"       - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "max_entries" - the number of entries stat'ed per directory, or zero to disable it, the default.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there is no device with that tag
+ */
+int32_t krun_set_virtiofs_dentry_warming(uint32_t ctx_id, const char *c_tag, uint32_t max_entries);
+
+/**
+ * Limits the number of files and directories the guest may keep open on a virtio-fs device. Past
+ * the limit, the opens of the guest fail with EMFILE until it closes some, and reaching it is
+ * logged once each time. Not available in libkrun-SEV.
+ *
+ * Arguments:
+ *  "ctx_id"      - the configuration context ID.
+ *  "c_tag"       - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "max_handles" - the number of files and directories the guest may keep open, or zero for no
+ *                  limit, the default.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there is no device with that tag
+ */
+int32_t krun_set_virtiofs_max_handles(uint32_t ctx_id, const char *c_tag, uint32_t max_handles);
+
+/**
+ * Returns the number of files and directories the guest keeps open on a virtio-fs device of a
+ * running microVM, and the number of opens that failed for being past the limit set with
+ * krun_set_virtiofs_max_handles. It may be called from another thread than the one running
+ * krun_start_enter. Not available in libkrun-SEV.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "c_tag"  - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "open"   - a pointer to store the number of files and directories the guest keeps open.
+ *  "denied" - a pointer to store the number of opens that failed for being past the limit.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there is no running microVM with that ID
+ *       -ENODEV when the microVM has no virtio-fs device with that tag
+ */
+int32_t krun_get_virtiofs_handles(uint32_t ctx_id,
+                                  const char *c_tag,
+                                  uint64_t *open,
+                                  uint64_t *denied);
+
+/**
+ * Returns the statistics of the host system calls of the virtio-fs devices that failed with a
+ * transient error and were tried again rather than failing the request of the guest. The calls
+ * interrupted by a signal are tried again right away, and those hitting a shortage of the host
+ * (EAGAIN, ENFILE or ENOMEM) a few times with a growing backoff. The statistics cover all the
+ * devices of all the microVMs of the process. Not available in libkrun-SEV.
+ *
+ * Arguments:
+ *  "interrupted" - a pointer to store the number of retries after an interruption by a signal.
+ *  "retries"     - a pointer to store the number of retries after a shortage of the host.
+ *  "recovered"   - a pointer to store the number of calls that succeeded once tried again.
+ *  "exhausted"   - a pointer to store the number of calls that still failed after all their
+ *                  retries, the error being passed on to the guest.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_get_virtiofs_retries(uint64_t *interrupted,
+                                  uint64_t *retries,
+                                  uint64_t *recovered,
+                                  uint64_t *exhausted);
+
+/**
+ * Waits for the mirror of a virtio-fs device of a running microVM, set with
+ * krun_set_virtiofs_mirror, to catch up with the changes the guest made so far, such as before
+ * shutting the microVM down. It may be called from another thread than the one running
+ * krun_start_enter. Not available in libkrun-SEV.
+ *
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID.
+ *  "c_tag"      - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "timeout_ms" - the longest time to wait, in milliseconds.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there is no running microVM with that ID
+ *       -ENODEV when the microVM has no mirrored virtio-fs device with that tag
+ *       -ETIMEDOUT when the mirror didn't catch up in time
+ */
+int32_t krun_drain_virtiofs_mirror(uint32_t ctx_id, const char *c_tag, uint32_t timeout_ms);
+
+/* Flags of krun_pause_virtiofs */
+#define KRUN_FS_PAUSE_CLOSE_FILES     1
+#define KRUN_FS_PAUSE_FAIL_ON_TIMEOUT 2
+/**
+ * Pauses a virtio-fs device of a running microVM, so that the host can remount or check the
+ * volume backing it. The device finishes the requests it is handling and leaves the next ones
+ * queued, the guest seeing them take longer rather than fail, and the writes it buffered are
+ * written to the host. It may be called from another thread than the one running
+ * krun_start_enter. Not available in libkrun-SEV.
+ *
+ * With KRUN_FS_PAUSE_CLOSE_FILES, which only Linux hosts support, the device also closes all its
+ * host files, to open them again by their paths once resumed. The guest then mustn't hold deleted
+ * files of the share, nor have files of it mapped in the DAX window.
+ *
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID.
+ *  "c_tag"      - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "flags"      - KRUN_FS_PAUSE_* flags.
+ *  "timeout_ms" - the longest time the device stays paused, in milliseconds, or 0 for no limit.
+ *                 Past it, the device resumes on its own or, with KRUN_FS_PAUSE_FAIL_ON_TIMEOUT,
+ *                 fails the requests of the guest with EIO until resumed.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EINVAL when "flags" has unknown flags
+ *       -ENOENT when there is no running microVM with that ID
+ *       -ENODEV when the microVM has no virtio-fs device with that tag
+ *       -EAGAIN when the guest didn't activate the device
+ *       -EALREADY when the device is paused already
+ *       -ETIMEDOUT when the device didn't get to the pause in time
+ *       -EBUSY when the host files can't be closed
+ */
+int32_t krun_pause_virtiofs(uint32_t ctx_id, const char *c_tag, uint32_t flags, uint32_t timeout_ms);
+
+/**
+ * Resumes a virtio-fs device paused with krun_pause_virtiofs, which handles the requests the guest
+ * queued meanwhile. Resuming a device that isn't paused does nothing.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "c_tag"  - the tag of the device, or "/dev/root" for the root filesystem.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure. If the device closed its host files and
+ *  can't open them again, it stays paused and the error is returned.
+ *  Documented errors:
+ *       -ENOENT when there is no running microVM with that ID
+ *       -ENODEV when the microVM has no virtio-fs device with that tag
+ */
+int32_t krun_resume_virtiofs(uint32_t ctx_id, const char *c_tag);
+
+/**
+ * Degrades a virtio-fs share to read-only once its host storage keeps failing: when "max_errors"
+ * of the requests writing to the share failed with EIO or EROFS within "window_ms", the share
+ * fails the requests changing it with EROFS, revokes the leases of the guest, and calls
+ * "callback". It stays read-only until re-enabled with krun_reenable_virtiofs. Only virtio-fs
+ * devices speaking FUSE degrade, not the 9p ones.
+ *
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID.
+ *  "c_tag"      - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "max_errors" - the number of failed writes that degrade the share.
+ *  "window_ms"  - how recent, in milliseconds, the failed writes counted are.
+ *  "callback"   - called from a libkrun thread with "opaque" and the number of failed writes when
+ *                 the share degrades, or NULL.
+ *  "opaque"     - passed back to "callback".
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EINVAL when "max_errors" or "window_ms" is zero
+ *       -ENOENT when there is no context or virtio-fs device with that ID or tag
+ */
+int32_t krun_set_virtiofs_degrade(uint32_t ctx_id, const char *c_tag, uint32_t max_errors,
+                                  uint32_t window_ms,
+                                  void (*callback)(void *opaque, uint32_t errors),
+                                  void *opaque);
+
+/**
+ * Makes a virtio-fs share degraded to read-only writable again, once its host storage is
+ * repaired, forgetting the failed writes it counted.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "c_tag"  - the tag of the device, or "/dev/root" for the root filesystem.
+ *
+ * Returns:
+ *  1 if the share was read-only, 0 if it wasn't, or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there is no running microVM with that ID
+ *       -ENODEV when the microVM has no virtio-fs device with that tag
+ */
+int32_t krun_reenable_virtiofs(uint32_t ctx_id, const char *c_tag);
+
+/**
+ * Changes the entry and attribute timeouts of a virtio-fs share while the microVM is running. The
+ * new timeouts apply to the replies sent from then on. When they may be shorter than the ones
+ * handed out before, the guest is told to drop the attributes it cached of the files it holds if
+ * the share grants leases, see krun_set_virtiofs_leases; otherwise the change takes full effect
+ * once the timeouts handed out before expire.
+ *
+ * Arguments:
+ *  "ctx_id"           - the configuration context ID.
+ *  "c_tag"            - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "entry_timeout_ms" - how long the guest may cache the entries of directories, in milliseconds.
+ *  "attr_timeout_ms"  - how long the guest may cache the attributes of files, in milliseconds.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there is no running microVM with that ID
+ *       -ENODEV when the microVM has no virtio-fs device with that tag
+ */
+int32_t krun_set_virtiofs_timeouts(uint32_t ctx_id, const char *c_tag, uint32_t entry_timeout_ms,
+                                   uint32_t attr_timeout_ms);
+
+/**
+ * Starts or stops tracing the requests a virtio-fs device of a running microVM handles. Each
+ * request traced is timestamped when it is taken from the virtqueue, handed to the file system,
+ * answered by it and completed to the guest. The most recent requests are kept, up to 65536 of
+ * them, and exported with krun_export_virtiofs_trace. Tracing is off when the microVM starts. Not
+ * available in libkrun-SEV.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "c_tag"  - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "enable" - whether the requests handled from now on are traced.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there is no running microVM with that ID
+ *       -ENODEV when the microVM has no virtio-fs device with that tag
+ */
+int32_t krun_set_virtiofs_tracing(uint32_t ctx_id, const char *c_tag, bool enable);
+
+/**
+ * Writes the requests traced on a virtio-fs device of a running microVM, see
+ * krun_set_virtiofs_tracing, to a file in the Chrome trace event format, which chrome://tracing and
+ * Perfetto load. Every request shows up as three events, "vring", "host" and "publish", with its
+ * FUSE unique ID in their arguments to line them up with the fuse_request_send and
+ * fuse_request_end events traced in the guest. Not available in libkrun-SEV.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "c_tag"  - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "c_path" - the path of the file to write the trace to, replaced if it exists.
+ *  "clear"  - whether the requests exported are dropped, so the next export only has new ones.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there is no running microVM with that ID
+ *       -ENODEV when the microVM has no virtio-fs device with that tag
+ */
+int32_t krun_export_virtiofs_trace(uint32_t ctx_id, const char *c_tag, const char *c_path,
+                                   bool clear);
+
+/* How the reads of the guest update the access times of the host files */
+#define KRUN_ATIME_HOST     0
+#define KRUN_ATIME_RELATIME 1
+#define KRUN_ATIME_NOATIME  2
+/**
+ * Sets how the reads of the guest update the access times of the files of a virtio-fs device.
+ *
+ * With KRUN_ATIME_HOST, the default, they are updated as the host file system is mounted to. The
+ * files the guest opens with O_NOATIME are left alone when the host allows it, which needs libkrun
+ * to own them or to have CAP_FOWNER.
+ *
+ * With KRUN_ATIME_NOATIME, the reads of the guest never update them, and with
+ * KRUN_ATIME_RELATIME, they are updated on the first read of an open file only when not later
+ * than its last modification or a day old, as with the relatime mount option, whatever
+ * the host file system is mounted with. Both only apply to the files the host allows to open with
+ * O_NOATIME, and are only supported on Linux hosts.
+ *
+ * Not available in libkrun-SEV.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "c_tag"  - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "atime"  - one of the KRUN_ATIME_* values.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EINVAL when "atime" is not a KRUN_ATIME_* value
+ *       -ENOENT when there is no device with that tag
+ */
+int32_t krun_set_virtiofs_atime(uint32_t ctx_id, const char *c_tag, uint32_t atime);
+
+/**
+ * Matches the names the guest looks up on a passthrough virtio-fs device to the ones on the host
+ * whatever their Unicode normalization forms, e.g. an accented letter precomposed (NFC) or
+ * decomposed (NFD), as the host volumes of macOS store them. A lookup of a name missing on the
+ * host falls back to the entry whose name is the same text, the exact name being preferred when
+ * it exists. On a host volume that changes the names it stores, the entries the guest creates keep
+ * the name of the guest in the "user.vm.guest_name" extended attribute, and are listed under it.
+ * Has no effect on overlay devices. Not available in libkrun-SEV.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "c_tag"  - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "enable" - whether the names are matched whatever their normalization forms.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there is no device with that tag
  */
-int32_t krun_create_ctx();
+int32_t krun_set_virtiofs_normalize_names(uint32_t ctx_id, const char *c_tag, bool enable);
 
+/* How the reads of the guest use the host page cache */
+#define KRUN_PAGE_CACHE_HOST      0
+#define KRUN_PAGE_CACHE_STREAMING 1
+#define KRUN_PAGE_CACHE_NOCACHE   2
 /**
- * Frees an existing configuration context.
+ * Sets how the reads of the guest use the page cache of the host for the files of a virtio-fs
+ * device, which the page cache of the guest already holds the data of.
+ *
+ * With KRUN_PAGE_CACHE_HOST, the default, the host caches the files as it does for its own reads.
+ *
+ * With KRUN_PAGE_CACHE_STREAMING, a file the guest reads one request after the other gets a
+ * larger readahead, and once 64 MiB of it were read that way, the pages behind the reads are
+ * dropped from the host cache, so that streaming large files doesn't evict the rest of it. With
+ * KRUN_PAGE_CACHE_NOCACHE, every page the guest reads is dropped from the host cache. On macOS
+ * hosts, which can't drop pages, the files are read without the cache from then on instead.
+ *
+ * Not available in libkrun-SEV.
  *
  * Arguments:
  *  "ctx_id" - the configuration context ID.
+ *  "c_tag"  - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "policy" - one of the KRUN_PAGE_CACHE_* values.
  *
  * Returns:
  *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EINVAL when "policy" is not a KRUN_PAGE_CACHE_* value
+ *       -ENOENT when there is no device with that tag
  */
-int32_t krun_free_ctx(uint32_t ctx_id);
+int32_t krun_set_virtiofs_page_cache(uint32_t ctx_id, const char *c_tag, uint32_t policy);
 
+/* Where the owners the guest gives the files of a virtio-fs device are kept */
+#define KRUN_OWNERSHIP_AUTO    0
+#define KRUN_OWNERSHIP_HOST    1
+#define KRUN_OWNERSHIP_VIRTUAL 2
 /**
- * Sets the basic configuration parameters for the microVM.
+ * Sets where the owners the guest gives the files of a virtio-fs device, and the device nodes it
+ * creates, are kept.
+ *
+ * With KRUN_OWNERSHIP_HOST, the files are given to their owners on the host and the device nodes
+ * are created there, which needs libkrun to run as root or with CAP_CHOWN and CAP_MKNOD.
+ *
+ * With KRUN_OWNERSHIP_VIRTUAL, neither is ever attempted on the host, so that extracting an image
+ * in the guest works when libkrun runs as an unprivileged user: the files stay owned by that user,
+ * and readable and writable by it, while the owner, mode and device number the guest gave them are
+ * kept in the "user.containers.override_stat" extended attribute and reported to the guest
+ * instead. The device nodes are empty regular files on the host. The attribute is hidden from the
+ * guest, and is the one macOS hosts always keep the ownership in.
+ *
+ * KRUN_OWNERSHIP_AUTO, the default, is KRUN_OWNERSHIP_VIRTUAL when libkrun runs as a user other
+ * than root, and KRUN_OWNERSHIP_HOST otherwise. Only supported on Linux hosts. Not available in
+ * libkrun-SEV.
  *
  * Arguments:
  *  "ctx_id"    - the configuration context ID.
- *  "num_vcpus" - the number of vCPUs.
- *  "ram_mib"   - the amount of RAM in MiB.
+ *  "c_tag"     - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "ownership" - one of the KRUN_OWNERSHIP_* values.
  *
  * Returns:
  *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EINVAL when "ownership" is not a KRUN_OWNERSHIP_* value
+ *       -ENOENT when there is no device with that tag
  */
-int32_t krun_set_vm_config(uint32_t ctx_id, uint8_t num_vcpus, uint32_t ram_mib);
+int32_t krun_set_virtiofs_ownership(uint32_t ctx_id, const char *c_tag, uint32_t ownership);
 
 /**
- * Sets the path to be use as root for the microVM. Not available in libkrun-SEV.
+ * Has the guest mount a virtio-fs device on its own at boot, without an fstab entry. The init
+ * of libkrun creates the guest path if missing and mounts the device there, with the options the
+ * device recommends, such as "dax" when it has a DAX window, followed by the given ones, which
+ * take precedence. The entries of /etc/fstab of the guest are mounted first, and keep their
+ * paths. The device also advertises the mount in its configuration space, after the standard
+ * fields, for the guests that read it.
+ *
+ * Not available in libkrun-SEV.
  *
  * Arguments:
- *  "ctx_id"    - the configuration context ID.
- *  "root_path" - a null-terminated string representing the path to be used as root.
+ *  "ctx_id"       - the configuration context ID.
+ *  "c_tag"        - the tag of the device, which can't be the root filesystem.
+ *  "c_guest_path" - the absolute guest path to mount the device at.
+ *  "c_options"    - the options of the mount, as "mount -o" takes them, or NULL for none.
+ *
+ * The tag, the path and the options can't hold whitespace, ':', ';' or '"', nor be longer than
+ * 255 bytes.
  *
  * Returns:
  *  Zero on success or a negative error number on failure.
  *  Documented errors:
- *       -EEXIST when a root device is already set
- *
- * Notes:
- *  This function is mutually exclusive with krun_set_overlayfs_root.
+ *       -EINVAL when the tag, the path or the options are invalid
+ *       -ENOENT when there is no device with that tag
  */
-int32_t krun_set_root(uint32_t ctx_id, const char *root_path);
+int32_t krun_set_virtiofs_guest_mount(uint32_t ctx_id, const char *c_tag,
+                                      const char *c_guest_path, const char *c_options);
 
+/* The protocol the guest uses a shared directory with */
+#define KRUN_FS_PROTOCOL_VIRTIOFS 0
+#define KRUN_FS_PROTOCOL_9P       1
 /**
- * Sets up an OverlayFS to be used as root for the microVM. Not available in libkrun-SEV.
+ * Sets the protocol the guest uses a shared directory with, for the guests shipping only 9p
+ * drivers. Not available in libkrun-SEV.
+ *
+ * With KRUN_FS_PROTOCOL_VIRTIOFS, the default, the device is a virtio-fs one. With
+ * KRUN_FS_PROTOCOL_9P, it is a virtio-9p one with the same tag, speaking 9P2000.L, which the guest
+ * mounts with "mount -t 9p -o trans=virtio,version=9p2000.L <tag> <dir>". It serves the same
+ * directory or overlay, without a DAX window, leases or ioctls, and without the access rules,
+ * virtual files and write coalescing set on the device, and can't be saved in a snapshot.
  *
  * Arguments:
- *  "ctx_id"      - the configuration context ID.
- *  "root_layers" - an array of string pointers to filesystem paths representing
- *                  the layers to be used for the OverlayFS. The array must be
- *                  NULL-terminated and contain at least one layer.
+ *  "ctx_id"   - the configuration context ID.
+ *  "c_tag"    - the tag of the device.
+ *  "protocol" - one of the KRUN_FS_PROTOCOL_* values.
  *
  * Returns:
  *  Zero on success or a negative error number on failure.
  *  Documented errors:
- *       -EINVAL when no layers are provided
- *       -EEXIST when a root device is already set
+ *       -EINVAL when "protocol" is not a KRUN_FS_PROTOCOL_* value
+ *       -ENOENT when there is no device with that tag
+ */
+int32_t krun_set_virtiofs_protocol(uint32_t ctx_id, const char *c_tag, uint32_t protocol);
+
+/**
+ * Sets the width in bits of the inode numbers the guest sees on a virtio-fs device, for the 32-bit
+ * guest userspaces that fail "stat" and "readdir" with EOVERFLOW on larger numbers. Not available
+ * in libkrun-SEV.
  *
- * Notes:
- *  This function is mutually exclusive with krun_set_root.
+ * With 64, the default, the guest sees the inode numbers of the host files. With 32, they are
+ * folded into 32 bits, the numbers that fit being kept as they are, and a file whose folded number
+ * is taken by another gets a free one instead, so that distinct files never share a number. The
+ * folded numbers may change when the microVM restarts. They are not used over 9p.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "c_tag"  - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "width"  - 32 or 64.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EINVAL when "width" is neither 32 nor 64
+ *       -ENOENT when there is no device with that tag
  */
-int32_t krun_set_overlayfs_root(uint32_t ctx_id, const char *const root_layers[]);
+int32_t krun_set_virtiofs_inode_width(uint32_t ctx_id, const char *c_tag, uint32_t width);
 
 /**
- * DEPRECATED. Use krun_add_disk instead.
+ * Sets the modes the files, directories and special files the guest creates on a virtio-fs device
+ * get on the host, for host security policies the modes the guest asks for would break, such as
+ * world-readable secrets. Not available in libkrun-SEV.
  *
- * Sets the path to the disk image that contains the file-system to be used as root for the microVM.
- * The only supported image format is "raw".
+ * The permission bits the guest asks for are replaced with "file_mode" for the files and special
+ * files, and with "dir_mode" for the directories, unless they are negative, and the bits of
+ * "umask" are then cleared from them. The bits of "umask" are also cleared from the modes the
+ * guest sets with "chmod". The guest still sees the modes it asked for, until the mode is changed
+ * on the host or the microVM restarts. The policy is not applied over 9p.
  *
  * Arguments:
  *  "ctx_id"    - the configuration context ID.
- *  "disk_path" - a null-terminated string representing the path leading to the disk image that
- *                contains the root file-system.
+ *  "c_tag"     - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "umask"     - the permission bits cleared from the host modes.
+ *  "file_mode" - the permission bits of the files the guest creates, or -1 for the ones it asks for.
+ *  "dir_mode"  - the permission bits of the directories the guest creates, or -1 for the ones it
+ *                asks for.
  *
  * Returns:
  *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EINVAL when a mode or "umask" has bits other than the permission ones
+ *       -ENOENT when there is no device with that tag
  */
-int32_t krun_set_root_disk(uint32_t ctx_id, const char *disk_path);
+int32_t krun_set_virtiofs_mode_policy(uint32_t ctx_id,
+                                      const char *c_tag,
+                                      uint32_t umask,
+                                      int32_t file_mode,
+                                      int32_t dir_mode);
 
 /**
- * DEPRECATED. Use krun_add_disk instead.
+ * Sets the limits on the requests the guest sends in the background to a virtio-fs device, such
+ * as the writeback of dirty pages. Not available in libkrun-SEV.
  *
- * Sets the path to the disk image that contains the file-system to be used as
- * a data partition for the microVM.  The only supported image format is "raw".
+ * The guest stops sending background requests once "max_background" of them are in flight, and
+ * past "congestion_threshold" it throttles the processes dirtying pages of the device, which bounds
+ * the writeback a guest can queue at once. The device handles background requests after the ones
+ * processes are waiting on. The defaults are 64 and 48.
  *
  * Arguments:
- *  "ctx_id"    - the configuration context ID.
- *  "disk_path" - a null-terminated string representing the path leading to the disk image that
- *                contains the root file-system.
+ *  "ctx_id"               - the configuration context ID.
+ *  "c_tag"                - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "max_background"       - the maximum number of background requests in flight.
+ *  "congestion_threshold" - the number of background requests in flight past which the guest
+ *                           throttles writeback.
  *
  * Returns:
  *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EINVAL when "max_background" is zero or lower than "congestion_threshold"
+ *       -ENOENT when there is no device with that tag
  */
-int32_t krun_set_data_disk(uint32_t ctx_id, const char *disk_path);
+int32_t krun_set_virtiofs_background(uint32_t ctx_id,
+                                     const char *c_tag,
+                                     uint16_t max_background,
+                                     uint16_t congestion_threshold);
 
 /**
- * Adds a disk image to be used as a general partition for the microVM. The only supported image
- * format is "raw".
+ * Enables the revalidation of the attributes the guest cached for the files of a virtio-fs device
+ * that are changed on the host by other processes. Not available in libkrun-SEV.
  *
- * This API is mutually exclusive with the deprecated krun_set_root_disk and
- * krun_set_data_disk methods and must not be used together.
+ * Every "interval_ms", a background thread re-stats a batch of the files the guest recently
+ * accessed, the ones it has open first. The attributes of a file found changed are no longer
+ * cached by the guest for a while, and the data it cached is dropped the next time it opens the
+ * file. Revalidation is disabled by default, and an interval of zero disables it again.
  *
- * This function deliberately only handles images in the Raw format, because it doesn't allow
- * specifying an image format, and probing an image's format is dangerous. For more information,
- * see the security note on `krun_add_disk2`, which allows opening non-Raw images.
+ * Arguments:
+ *  "ctx_id"      - the configuration context ID.
+ *  "c_tag"       - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "interval_ms" - the interval between two revalidations, in milliseconds.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there is no device with that tag
+ */
+int32_t krun_set_virtiofs_revalidate(uint32_t ctx_id, const char *c_tag, uint32_t interval_ms);
+
+/**
+ * Enables the coalescing of the small sequential writes the guest makes to the files of a
+ * virtio-fs device, such as a log written a line at a time. Not available in libkrun-SEV.
+ *
+ * The writes that continue the previous one on the same file handle are buffered, up to
+ * "buffer_size" bytes, and written to the host at once when the buffer is full, when a write
+ * doesn't continue it, when the guest flushes, syncs or closes the file, or after "delay_ms"
+ * milliseconds at the latest. Any other request of the guest involving the file sees the buffered
+ * writes, but other host processes only see them once they're written. An error writing them is
+ * reported by the next flush, sync or close of the file. Writes to files opened with O_SYNC,
+ * O_DSYNC or O_DIRECT are never buffered.
+ *
+ * Coalescing is disabled by default, and a buffer size of zero disables it again.
  *
  * Arguments:
- *  "ctx_id"    - the configuration context ID.
- *  "block_id"  - a null-terminated string representing the partition.
- *  "disk_path" - a null-terminated string representing the path leading to the disk image.
- *  "read_only" - whether the mount should be read-only. Required if the caller does not have
- *                write permissions (for disk images in /usr/share).
+ *  "ctx_id"      - the configuration context ID.
+ *  "c_tag"       - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "buffer_size" - the size of the buffer of each file handle, at most 1 MiB.
+ *  "delay_ms"    - the longest time a buffered write waits before it's written, in milliseconds.
  *
  * Returns:
  *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EINVAL when the buffer is larger than 1 MiB, or the delay is zero
+ *       -ENOENT when there is no device with that tag
  */
-int32_t krun_add_disk(uint32_t ctx_id, const char *block_id, const char *disk_path, bool read_only);
+int32_t krun_set_virtiofs_write_coalescing(uint32_t ctx_id,
+                                           const char *c_tag,
+                                           uint32_t buffer_size,
+                                           uint32_t delay_ms);
 
-/* Supported disk image formats */
-#define KRUN_DISK_FORMAT_RAW 0
-#define KRUN_DISK_FORMAT_QCOW2 1
 /**
- * Adds a disk image to be used as a general partition for the microVM. The supported
- * image formats are: "raw" and "qcow2".
+ * Enables leases on the attributes the guest caches for the files of a virtio-fs device, letting
+ * it cache them much longer while still seeing the changes made on the host. Not available in
+ * libkrun-SEV.
  *
- * This API is mutually exclusive with the deprecated krun_set_root_disk and
- * krun_set_data_disk methods and must not be used together.
+ * The guest caches the attributes of the files it accesses for "timeout_ms" milliseconds. When
+ * the revalidation finds a file changed on the host, by another process or another VM sharing it,
+ * the lease on the file is broken and the guest is told to drop the attributes and data it cached
+ * of that file only. Leases rely on the revalidation, which is enabled with an interval of one
+ * second if krun_set_virtiofs_revalidate wasn't called, and on the notification queue of
+ * virtio-fs: a guest that doesn't accept it is granted no leases. The entries of directories
+ * keep the timeouts of the device.
  *
- * SECURITY NOTE:
- * Non-Raw images can reference other files, which libkrun will automatically open, and to which the
- * guest will have access. Libkrun should therefore never be asked to open an image in a non-Raw
- * format when it doesn't come from a fully trustworthy source.
+ * Leases are disabled by default, and a timeout of zero disables them again.
  *
- * Consequently, probing an image's format is quite dangerous and to be avoided if at all possible,
- * which is why libkrun provides no facilities for doing so. If it's not clear what format an image
- * has, it may also not be clear whether it can be trusted to not reference files to which the guest
- * shouldn't have access.
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID.
+ *  "c_tag"      - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "timeout_ms" - how long the guest may cache the attributes of a file, in milliseconds.
  *
- * If probing absolutely can't be avoided, it must only be done on images that are fully trusted, i.e.
- * before a potentially untrusted guest had write access to it. Specifically, consider that a guest has
- * full access to all of a Raw image, and can therefore turn it into a file in an arbitrary format, for
- * example, into a Qcow2 image, referencing and granting a malicious guest access to arbitrary files.
- * To hand a Raw image to an untrusted and potentially malicious guest, and then to re-probe it after
- * the guest was able to write to it (when it can no longer be trusted), would therefore be a severe
- * security vulnerability.
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there is no device with that tag
+ */
+int32_t krun_set_virtiofs_leases(uint32_t ctx_id, const char *c_tag, uint32_t timeout_ms);
+
+/**
+ * Serves the view the guest has of a virtio-fs device to host tools, read-only, on a UNIX socket.
+ * The requests go through the same file system instance as the guest ones, so that the tools see
+ * the files exactly as the guest does, e.g. merged from the layers of an overlay, without any
+ * change made by the guest missing. Not available in libkrun-SEV.
+ *
+ * Every message is made of an 8-byte header, holding the message type and the payload length as
+ * little endian 32-bit integers, followed by the payload. Paths are relative to the root of the
+ * share and may not contain "..". The requests are answered with a single message:
+ *  - STAT (1), with the path: answered with ATTR (4), holding the mode, uid, gid and link count
+ *    as 32-bit integers, followed by the 64-bit size and modification time in seconds and the
+ *    32-bit nanoseconds of the modification time.
+ *  - LIST (2), with the path of a directory: answered with ENTRIES (5), holding for each entry its
+ *    32-bit DT_* type followed by its NUL-terminated name.
+ *  - READ (3), with the 64-bit offset, the 32-bit length and the path of a regular file: answered
+ *    with DATA (6), holding at most 1 MiB of content.
+ * Failed requests are answered with ERROR (7), holding the 32-bit errno, using Linux values.
  *
- * Therefore, after having probed a yet fully trusted image once, the result must be remembered so the
- * image will from then on always be opened in the format that was detected originally. When adhering
- * to this, a guest can write anything they want to a Raw image, it's always going to be opened as a
- * Raw image, preventing the security vulnerability outlined above.
+ * Arguments:
+ *  "ctx_id"        - the configuration context ID.
+ *  "c_tag"         - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "c_socket_path" - the path of the UNIX socket, which is replaced if it exists.
  *
- * However, if at all possible, the image format should be explicitly selected based on knowledge
- * obtained separately from the pure image data, for example by the user.
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there is no device with that tag
+ */
+int32_t krun_set_virtiofs_inspect_socket(uint32_t ctx_id,
+                                         const char *c_tag,
+                                         const char *c_socket_path);
+
+/**
+ * Fills a directory of a virtio-fs share from a host directory the first time the guest creates
+ * it. Not available in libkrun-SEV.
+ *
+ * When the guest creates the directory "c_path", or a directory under it, the entries of the
+ * matching directory of "c_template" are copied into it, with their modes and owners. A regular
+ * file the guest creates empty under "c_path" likewise gets the content of the matching file of
+ * "c_template". Each path is only filled the first time it's created, and a failure to copy an
+ * entry is logged without failing the guest request.
  *
  * Arguments:
- *  "ctx_id"      - the configuration context ID.
- *  "block_id"    - a null-terminated string representing the partition.
- *  "disk_path"   - a null-terminated string representing the path leading to the disk image.
- *  "disk_format" - the disk image format (i.e. KRUN_DISK_FORMAT_{RAW, QCOW2})
- *  "read_only"   - whether the mount should be read-only. Required if the caller does not have
- *                  write permissions (for disk images in /usr/share).
+ *  "ctx_id"     - the configuration context ID.
+ *  "c_tag"      - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "c_path"     - the path of the directory, relative to the root of the share.
+ *  "c_template" - the path of the host directory holding the template.
  *
  * Returns:
  *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EINVAL when "c_path" has no file name or "c_template" isn't a directory
+ *       -ENOENT when there is no device with that tag
  */
-int32_t krun_add_disk2(uint32_t ctx_id,
-                       const char *block_id,
-                       const char *disk_path,
-                       uint32_t disk_format,
-                       bool read_only);
+int32_t krun_add_virtiofs_dir_template(uint32_t ctx_id,
+                                       const char *c_tag,
+                                       const char *c_path,
+                                       const char *c_template);
+
+#define KRUN_FS_WATCH_CREATE      1
+#define KRUN_FS_WATCH_WRITE       2
+#define KRUN_FS_WATCH_REMOVE      3
+#define KRUN_FS_WATCH_RENAME_FROM 4
+#define KRUN_FS_WATCH_RENAME_TO   5
+#define KRUN_FS_WATCH_ATTRIB      6
 
 /**
- * NO LONGER SUPPORTED. DO NOT USE.
+ * Subscribes to the changes the guest makes under a path of a virtio-fs share. Not available in
+ * libkrun-SEV.
  *
- * Configures the mapped volumes for the microVM. Only supported on macOS, on Linux use
- * user_namespaces and bind-mounts instead. Not available in libkrun-SEV.
+ * Each entry the guest creates, writes, removes, renames or changes the attributes of under "c_path"
+ * is reported to "callback", with its path relative to the root of the share and one of the
+ * KRUN_FS_WATCH_* operations. A rename is reported as KRUN_FS_WATCH_RENAME_FROM on the old path
+ * followed by KRUN_FS_WATCH_RENAME_TO on the new one. Events are delivered from a libkrun thread
+ * once the share has been quiet for 100ms, and the same change made repeatedly in the meantime,
+ * such as the writes of a file being copied, is reported once. Changes made through a DAX mapping
+ * are not reported.
  *
  * Arguments:
- *  "ctx_id"         - the configuration context ID.
- *  "mapped_volumes" - an array of string pointers with format "host_path:guest_path" representing
- *                     the volumes to be mapped inside the microVM
+ *  "ctx_id"   - the configuration context ID.
+ *  "c_tag"    - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "c_path"   - the watched path, relative to the root of the share. "" or "/" watches the whole
+ *               share.
+ *  "callback" - called with each change. "path" is only valid for the duration of the call.
+ *  "opaque"   - passed as is to the callback.
  *
  * Returns:
  *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EINVAL when "callback" is NULL
+ *       -ENOENT when there is no device with that tag
  */
-int32_t krun_set_mapped_volumes(uint32_t ctx_id, const char *const mapped_volumes[]);
+int32_t krun_watch_guest_path(uint32_t ctx_id,
+                              const char *c_tag,
+                              const char *c_path,
+                              void (*callback)(void *opaque, const char *path, uint32_t op),
+                              void *opaque);
 
 /**
- * Adds an independent virtio-fs device pointing to a host's directory with a tag.
+ * Mirrors the writable layer of a virtio-fs share, the top layer of an overlay or the root
+ * directory of a passthrough share, to another host directory, such as one on network storage.
+ * Not available in libkrun-SEV.
+ *
+ * The changes the guest makes, as reported to krun_watch_guest_path, are replicated from a libkrun
+ * thread one after the other, in the order they were made, by copying the state of each changed
+ * path to the mirror. A change that fails to replicate is retried with a growing backoff before
+ * any later change is. On exit, the microVM waits up to 10 seconds for the mirror to catch up;
+ * call krun_drain_virtiofs_mirror to wait for it before that. Overlays with their top layer in
+ * RAM are not mirrored.
  *
  * Arguments:
- *  "ctx_id"         - the configuration context ID.
- *  "c_tag"          - tag to identify the filesystem in the guest.
- *  "c_path"         - full path to the directory in the host to be exposed to the guest.
+ *  "ctx_id"        - the configuration context ID.
+ *  "c_tag"         - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "c_mirror_path" - the mirror directory, created if needed.
  *
  * Returns:
  *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there is no device with that tag
  */
-int32_t krun_add_virtiofs(uint32_t ctx_id,
-                          const char *c_tag,
-                          const char *c_path);
+int32_t krun_set_virtiofs_mirror(uint32_t ctx_id, const char *c_tag, const char *c_mirror_path);
 
 /**
- * Adds an independent virtio-fs device pointing to a host's directory with a tag. This
- * variant allows specifying the size of the DAX window.
+ * Adds a read-only file to a virtio-fs share, whose attributes and content are provided by the
+ * embedder rather than read from the host, such as instance metadata or a token that rotates. Not
+ * available in libkrun-SEV.
+ *
+ * The file shadows whatever the share holds at "c_path", including the layers of an overlay. The
+ * callbacks are called from a libkrun thread every time the guest stats or reads the file, which
+ * neither caches its attributes nor its content, so they may change at any time. The file isn't
+ * listed in its directory, which must exist in the share.
  *
  * Arguments:
- *  "ctx_id"         - the configuration context ID.
- *  "c_tag"          - tag to identify the filesystem in the guest.
- *  "c_path"         - full path to the directory in the host to be exposed to the guest.
- *  "shm_size"       - size of the DAX SHM window in bytes.
+ *  "ctx_id"  - the configuration context ID.
+ *  "c_tag"   - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "c_path"  - the path of the file, relative to the root of the share.
+ *  "mode"    - the permission bits of the file.
+ *  "getattr" - stores the size of the file in "size" and its modification time, in seconds since
+ *              the epoch, in "mtime". Returns zero or a negative error number, which the guest
+ *              gets.
+ *  "read"    - reads at most "len" bytes of the file at "offset" into "buf". Returns the number of
+ *              bytes read, zero past the end of the file, or a negative error number, which the
+ *              guest gets.
+ *  "opaque"  - passed as is to the callbacks.
  *
  * Returns:
  *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EINVAL when a callback is NULL or "c_path" has no file name
+ *       -ENOENT when there is no device with that tag
  */
-int32_t krun_add_virtiofs2(uint32_t ctx_id,
-                           const char *c_tag,
-                           const char *c_path,
-                           uint64_t shm_size);
+int32_t krun_add_virtiofs_virtual_file(uint32_t ctx_id,
+                                       const char *c_tag,
+                                       const char *c_path,
+                                       uint32_t mode,
+                                       int32_t (*getattr)(void *opaque, uint64_t *size, int64_t *mtime),
+                                       int64_t (*read)(void *opaque, uint64_t offset, void *buf, size_t len),
+                                       void *opaque);
+
+/* The points of the life of a virtio-fs share a hook may be registered for */
+#define KRUN_FS_HOOK_PRE_MOUNT    1
+#define KRUN_FS_HOOK_POST_UNMOUNT 2
+#define KRUN_FS_HOOK_PRE_COPY_UP  3
+#define KRUN_FS_HOOK_PRE_UNLINK   4
+/**
+ * Registers a callback of the embedder, called at a point of the life of a virtio-fs share. Not
+ * available in libkrun-SEV.
+ *
+ * KRUN_FS_HOOK_PRE_MOUNT is called before the share starts serving the guest, when the guest mounts
+ * it or a snapshot of the VM is restored, and KRUN_FS_HOOK_POST_UNMOUNT after a virtio-fs guest
+ * unmounted it, both with an empty path. KRUN_FS_HOOK_PRE_COPY_UP is called before an entry of a
+ * lower layer of an overlay is copied up to its top layer, for the file the guest changes and for
+ * each of its directories not copied up yet. KRUN_FS_HOOK_PRE_UNLINK is called before the guest
+ * unlinks a file or removes a directory, but not when a rename replaces it.
+ *
+ * The hooks of the copy-ups and the unlinks may veto them, by returning a negative error number
+ * the guest request then fails with, and the hooks registered after the one vetoing aren't called.
+ * The errors of the other hooks are only logged.
+ *
+ * The callback is called from the libkrun thread handling the guest request, which waits for it
+ * to return: a slow callback holds up the guest process making the request, and the requests of
+ * the other processes on the same entries. It may be called from several threads at once, and
+ * must not call back into libkrun for the same share.
+ *
+ * Arguments:
+ *  "ctx_id"   - the configuration context ID.
+ *  "c_tag"    - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "point"    - one of the KRUN_FS_HOOK_* points.
+ *  "callback" - called with "opaque", the point and the path of the entry, relative to the root of
+ *               the share, which is only valid during the call. Returns zero, or a negative error
+ *               number.
+ *  "opaque"   - passed as is to the callback.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EINVAL when "callback" is NULL or "point" is unknown
+ *       -ENOENT when there is no device with that tag
+ */
+int32_t krun_add_virtiofs_hook(uint32_t ctx_id,
+                               const char *c_tag,
+                               uint32_t point,
+                               int32_t (*callback)(void *opaque, uint32_t point, const char *path),
+                               void *opaque);
+
+/**
+ * Shares the caches of the read-only lower layers of an overlay virtio-fs device with the other
+ * overlay devices of the process stacked on the same lower layers, such as the ones of the VMs
+ * started from the same image: the path filters lookups use to skip the lower layers, built by
+ * walking each layer, and the attributes of the files of the lower layers. They are then built
+ * once for all the devices, rather than by each of them, and take memory once. The lower layers
+ * must not be changed on the host while any of the devices sharing them runs. Has no effect on
+ * passthrough devices. Not available in libkrun-SEV.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "c_tag"  - the tag of the device, or "/dev/root" for the root filesystem.
+ *  "enable" - whether the caches of the lower layers are shared.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there is no device with that tag
+ */
+int32_t krun_set_virtiofs_shared_lowers(uint32_t ctx_id, const char *c_tag, bool enable);
 
 /**
  * Configures the networking to use passt.
@@ -342,11 +1616,14 @@ int32_t krun_set_net_mac(uint32_t ctx_id, uint8_t *const c_mac);
  *
  * Arguments:
  *  "ctx_id"   - the configuration context ID.
- *  "port_map" - an array of string pointers with format "host_port:guest_port"
+ *  "port_map" - an array of string pointers with format "[host_addr:]host_ports:guest_ports"
  *
  * Returns:
  *  Zero on success or a negative error number on failure.
  *  Documented errors:
+ *       -EINVAL when an entry is malformed, or its port ranges have different lengths
+ *       -EEXIST when an entry maps guest ports, or host ports on the same address, already
+ *               mapped by a previous entry
  *       -ENOTSUP when passt networking is used
  *
  * Notes:
@@ -359,6 +1636,13 @@ int32_t krun_set_net_mac(uint32_t ctx_id, uint8_t *const c_mac);
  *  means that for a map such as "8080:80", applications running inside the guest will also
  *  need to access the service through the "8080" port.
  *
+ *  Ports may be given as inclusive ranges of the same length, where each guest port is mapped
+ *  to the host port at the same offset, e.g. "8000-8100:9000-9100" exposes guest port 9050 on
+ *  host port 8050. The optional "host_addr" is the address the host listeners are bound to,
+ *  either IPv4 or IPv6 in brackets, e.g. "127.0.0.1:8080:80" or "[::1]:8080:80". Mapping to
+ *  "[::]" gives dual-stack listeners accepting both IPv4 and IPv6 connections. Without it, the
+ *  listeners are bound to the address the guest listens on.
+ *
  * If past networking mode is used (krun_set_passt_fd was called), port mapping is not supported
  * as an API of libkrun (but you can still do port mapping using command line arguments of passt)
  */
@@ -445,6 +1729,43 @@ int32_t krun_set_gpu_options2(uint32_t ctx_id,
  */
 int32_t krun_set_snd_device(uint32_t ctx_id, bool enable);
 
+/**
+ * Enables or disables the virtio-rng device, which gives the guest entropy from the CSPRNG of the
+ * host (getrandom on Linux, SecRandomCopyBytes on macOS) so that it doesn't block on /dev/random
+ * at boot. It's enabled by default. Not available in libkrun-SEV.
+ *
+ * Arguments:
+ *  "ctx_id"        - the configuration context ID.
+ *  "enable"        - boolean indicating whether virtio-rng should be enabled or disabled.
+ *  "bytes_per_sec" - the bytes of entropy per second the guest may get, with bursts of up to a
+ *                    second worth of bytes, or zero for no limit.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_rng_device(uint32_t ctx_id, bool enable, uint64_t bytes_per_sec);
+
+/**
+ * Returns the statistics of the virtio-rng device of a running microVM. It may be called from
+ * another thread than the one running krun_start_enter. Not available in libkrun-SEV.
+ *
+ * Arguments:
+ *  "ctx_id"    - the configuration context ID.
+ *  "requests"  - a pointer to store the number of requests of the guest served so far.
+ *  "bytes"     - a pointer to store the number of bytes of entropy given to the guest so far.
+ *  "throttled" - a pointer to store the number of times the guest had to wait for the rate limit.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -ENOENT when there is no running microVM with that ID
+ *       -ENODEV when the microVM has no virtio-rng device
+ */
+int32_t krun_get_rng_stats(uint32_t ctx_id,
+                           uint64_t *requests,
+                           uint64_t *bytes,
+                           uint64_t *throttled);
+
 /**
  * Configures a map of rlimits to be set in the guest before starting the isolated binary.
  *
@@ -581,6 +1902,112 @@ int32_t krun_add_vsock_port2(uint32_t ctx_id,
                              uint32_t port,
                              const char *c_filepath,
                              bool listen);
+
+/**
+ * Forwards the connections made to a socket the caller already bound and listens on, such as one
+ * passed by a service manager with socket activation, to a vsock port in the guest. This is the
+ * same as calling "krun_add_vsock_port2" with "listen" set to true, except that libkrun accepts
+ * from the given socket instead of binding one itself.
+ *
+ * Each socket is accepted from independently. The socket is made non-blocking, so it may still be
+ * shared with other processes accepting from it.
+ *
+ * On success, libkrun takes ownership of the file descriptor and closes it when the VM shuts down.
+ * On failure, the file descriptor is left untouched.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "port"   - the vsock port in the guest the connections are forwarded to.
+ *  "fd"     - a listening stream socket, of any family.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ *  Documented errors:
+ *       -EINVAL when "fd" is not a listening socket
+ *       -EEXIST when "port" is already used by another vsock port mapping
+ */
+int32_t krun_add_vsock_listener_fd(uint32_t ctx_id, uint32_t port, int fd);
+
+/**
+ * Makes a UNIX socket available on both sides of the VM, bridging connections over vsock.
+ *
+ * The direction is given by "listen":
+ *  - If false, the guest gets a socket at "guest_path" and connections made to it inside the
+ *    guest are forwarded to "host_path", which a process in the host listens on.
+ *  - If true, libkrun listens on "host_path" and connections made to it are forwarded to
+ *    "guest_path", which is expected to be created by a process in the guest (for example, a
+ *    Docker daemon). A connection made while the guest socket is not accepting connections yet
+ *    waits for it for as long as the host side keeps the connection open, so the guest service
+ *    may be started or restarted at any time.
+ *
+ * The vsock ports used for the bridge are allocated automatically. Requires the guest to be
+ * booted with the init binary bundled with libkrun.
+ *
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID.
+ *  "host_path"  - a null-terminated string representing the path of the UNIX socket in the host.
+ *  "guest_path" - a null-terminated string representing the absolute path of the UNIX socket in
+ *                 the guest. It can't contain whitespace, commas or double quotes.
+ *  "listen"     - true if connections are initiated from the host side, false if they are
+ *                 initiated from the guest side.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_add_unix_socket_map(uint32_t ctx_id,
+                                 const char *host_path,
+                                 const char *guest_path,
+                                 bool listen);
+
+/* vsock port used by the guest clipboard agent. */
+#define KRUN_CLIPBOARD_VSOCK_PORT 0x4b430000
+/* Largest clipboard size accepted by krun_set_clipboard_callbacks (64 MiB). */
+#define KRUN_CLIPBOARD_MAX_SIZE (64 << 20)
+
+/**
+ * Enables a clipboard channel between the host and a clipboard agent running in the guest.
+ *
+ * The guest agent connects to vsock port KRUN_CLIPBOARD_VSOCK_PORT. Each message is made of an
+ * 8-byte header, holding the message type and the payload length as little endian 32-bit
+ * integers, followed by the payload:
+ *  1 HELLO   - version (1), max_size and the supported mime types as NUL-terminated strings, in
+ *              order of preference. Sent by both sides after connecting, the host first. The
+ *              smaller max_size and the mime types supported by both sides are used from then on.
+ *  2 OFFER   - guest to host, the guest clipboard changed. The payload lists the mime types it is
+ *              available in. The host answers with a REQUEST for its preferred one.
+ *  3 REQUEST - asks for the clipboard contents in the NUL-terminated mime type in the payload.
+ *              Answered with DATA or ERROR.
+ *  4 DATA    - a NUL-terminated mime type followed by the clipboard contents.
+ *  5 ERROR   - a 32-bit Linux errno: E2BIG if the message exceeds max_size, EINVAL if it is
+ *              malformed or the mime type wasn't negotiated, ENOENT if there is nothing to return.
+ *
+ * The callbacks are invoked from a libkrun thread, one at a time.
+ *
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID.
+ *  "mime_types" - an array of string pointers with the mime types supported by the host, in order
+ *                 of preference, terminated by a NULL pointer. Each of them must be shorter than
+ *                 256 bytes.
+ *  "max_size"   - the largest clipboard contents, in bytes, exchanged with the guest. Zero picks
+ *                 the default of 16 MiB. Can't be larger than KRUN_CLIPBOARD_MAX_SIZE.
+ *  "get_cb"     - called when the guest pastes. It must copy the host clipboard contents in
+ *                 "mime_type" to "buf", which is "buf_len" bytes long, and return their length,
+ *                 or a negative errno (-ENOENT if the clipboard is empty). May be NULL.
+ *  "set_cb"     - called when the guest copies, with the new clipboard contents in "mime_type".
+ *                 "data" is only valid for the duration of the call. May be NULL.
+ *  "opaque"     - passed as is to the callbacks.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_clipboard_callbacks(uint32_t ctx_id,
+                                     const char *const mime_types[],
+                                     uint32_t max_size,
+                                     ssize_t (*get_cb)(void *opaque, const char *mime_type,
+                                                       uint8_t *buf, size_t buf_len),
+                                     void (*set_cb)(void *opaque, const char *mime_type,
+                                                    const uint8_t *data, size_t len),
+                                     void *opaque);
 /**
  * Returns the eventfd file descriptor to signal the guest to shut down orderly. This must be
  * called before starting the microVM with "krun_start_event". Only available in libkrun-efi.
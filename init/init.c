@@ -11,6 +11,8 @@
 #include <unistd.h>
 
 #include <net/if.h>
+#include <poll.h>
+#include <signal.h>
 #include <sys/ioctl.h>
 #include <sys/mount.h>
 #include <sys/resource.h>
@@ -18,6 +20,7 @@
 #include <sys/stat.h>
 #include <sys/time.h>
 #include <sys/types.h>
+#include <sys/un.h>
 #include <sys/wait.h>
 
 #include <linux/vm_sockets.h>
@@ -472,6 +475,54 @@ static const char *clean_opts(const char *orig, char *buf, size_t buflen)
     return (dst == buf) ? NULL : buf;  /* all stripped? -> NULL */
 }
 
+/* Mount the share `fsname` of type `type` on `dir` with the options `opts`,
+ * creating `dir` if missing. Returns -1 on the errors worth reporting.    */
+static int mount_share(const char *fsname, const char *dir, const char *type,
+                       char *opts)
+{
+    /* ─────────── 1. ensure mount‑point exists (mkdir -p) ───────── */
+    if (mkdir_p(dir, 0755) < 0) {
+        fprintf(stderr,
+            "virtiofs-init: cannot create %s: %s\n",
+            dir, strerror(errno));
+        return -1;
+    }
+
+    /* ─────────── 2. skip if already mounted / busy ─────────────── */
+    switch (is_mounted(dir, fsname, type)) {
+    case  1:  return 0;             /* identical mount already there */
+    case -1:  fprintf(stderr,
+                 "virtiofs-init: %s busy – skipped\n", dir);
+              return -1;
+    }
+
+    /* ─────────── 3. translate common flags BEFORE they vanish ──── */
+    unsigned long flags = 0;
+    struct mntent fake = { .mnt_opts = opts };
+    if (hasmntopt(&fake, "ro"))      flags |= MS_RDONLY;
+    if (hasmntopt(&fake, "nosuid"))  flags |= MS_NOSUID;
+    if (hasmntopt(&fake, "nodev"))   flags |= MS_NODEV;
+    if (hasmntopt(&fake, "noexec"))  flags |= MS_NOEXEC;
+
+    /* Clean "defaults" out of the option list */
+    char optbuf[256];
+    const char *data = clean_opts(opts, optbuf, sizeof(optbuf));
+
+    /* ─────────── 4. actual mount attempt ───────────────────────── */
+    if (mount(fsname, dir, type, flags, data) < 0) {
+        if (errno == ENODEV || errno == ENOENT) {
+            fprintf(stderr,
+                "virtiofs-init: tag %s absent – skipped\n", fsname);
+        } else if (errno != EBUSY) {
+            fprintf(stderr,
+                "virtiofs-init: mount %s→%s failed: %s\n",
+                fsname, dir, strerror(errno));
+            return -1;
+        }
+    }
+    return 0;
+}
+
 /* Mount every virtiofs entry found in /etc/fstab.
  * Idempotent, silent on success, logs only actionable errors.            */
 static int mount_fstab_virtiofs(void)
@@ -504,51 +555,49 @@ static int mount_fstab_virtiofs(void)
         dir[sizeof(dir) - 1]   =
         opts[sizeof(opts) - 1] = '\0';
 
-        /* ─────────── 3. ensure mount‑point exists (mkdir -p) ───────── */
-        if (mkdir_p(dir, 0755) < 0) {
+        /* ─────────── 3. mount it ──────────────────────────────────── */
+        if (mount_share(fsname, dir, "virtiofs", opts) < 0)
+            rc = -1;
+    }
+
+    endmntent(fp);
+    return rc;
+}
+
+/* Mount the shares the host advertises in KRUN_VIRTIOFS_MOUNTS, a
+ * semicolon-separated list of "<tag>:<type>:<guest path>:<options>" items,
+ * the options being the ones the device recommends followed by the ones of
+ * the embedder. The same hints are in the configuration space of each
+ * device, which Linux doesn't expose to userspace.                        */
+static int mount_krun_shares(const char *mounts)
+{
+    char *list, *item, *saveptr, *type, *dir, *opts;
+    int rc = 0;
+
+    list = strdup(mounts);
+    if (!list)
+        return -1;
+
+    for (item = strtok_r(list, ";", &saveptr); item;
+         item = strtok_r(NULL, ";", &saveptr)) {
+        type = strchr(item, ':');
+        dir = type ? strchr(type + 1, ':') : NULL;
+        opts = dir ? strchr(dir + 1, ':') : NULL;
+        if (!opts || dir[1] != '/') {
             fprintf(stderr,
-                "virtiofs-init: cannot create %s: %s\n",
-                dir, strerror(errno));
+                "virtiofs-init: invalid mount '%s' – skipped\n", item);
             rc = -1;
             continue;
         }
+        *type++ = '\0';
+        *dir++ = '\0';
+        *opts++ = '\0';
 
-        /* ─────────── 4. skip if already mounted / busy ─────────────── */
-        switch (is_mounted(dir, fsname, "virtiofs")) {
-        case  1:  continue;            /* identical mount already there */
-        case -1:  fprintf(stderr,
-                     "virtiofs-init: %s busy – skipped\n", dir);
-                  rc = -1;
-                  continue;
-        }
-
-        /* ─────────── 5. translate common flags BEFORE they vanish ──── */
-        unsigned long flags = 0;
-        struct mntent fake = { .mnt_opts = opts };
-        if (hasmntopt(&fake, "ro"))      flags |= MS_RDONLY;
-        if (hasmntopt(&fake, "nosuid"))  flags |= MS_NOSUID;
-        if (hasmntopt(&fake, "nodev"))   flags |= MS_NODEV;
-        if (hasmntopt(&fake, "noexec"))  flags |= MS_NOEXEC;
-
-        /* Clean "defaults" out of the option list */
-        char optbuf[256];
-        const char *data = clean_opts(opts, optbuf, sizeof(optbuf));
-
-        /* ─────────── 6. actual mount attempt ───────────────────────── */
-        if (mount(fsname, dir, "virtiofs", flags, data) < 0) {
-            if (errno == ENODEV || errno == ENOENT) {
-                fprintf(stderr,
-                    "virtiofs-init: tag %s absent – skipped\n", fsname);
-            } else if (errno != EBUSY) {
-                fprintf(stderr,
-                    "virtiofs-init: mount %s→%s failed: %s\n",
-                    fsname, dir, strerror(errno));
-                rc = -1;
-            }
-        }
+        if (mount_share(item, dir, type, opts) < 0)
+            rc = -1;
     }
 
-    endmntent(fp);
+    free(list);
     return rc;
 }
 
@@ -614,6 +663,11 @@ static int mount_filesystems()
 	/* Mount virtiofs shares from /etc/fstab (if any) */
 	mount_fstab_virtiofs();
 
+    /* Then the ones the host asks to, unless fstab took their paths */
+    char *krun_mounts = getenv("KRUN_VIRTIOFS_MOUNTS");
+    if (krun_mounts)
+        mount_krun_shares(krun_mounts);
+
     return 0;
 }
 
@@ -1052,6 +1106,261 @@ void clock_worker()
 }
 #endif
 
+/* Delay between two attempts to connect to a guest socket, in milliseconds */
+#define SOCKET_MAP_RETRY_DELAY 100
+#define SOCKET_MAP_BUFSIZE 16384
+
+#ifndef POLLRDHUP
+#define POLLRDHUP 0x2000
+#endif
+
+static int write_all(int fd, const char *buf, ssize_t len)
+{
+    ssize_t n;
+
+    while (len > 0) {
+        n = write(fd, buf, len);
+        if (n < 0) {
+            if (errno == EINTR)
+                continue;
+            return -1;
+        }
+        buf += n;
+        len -= n;
+    }
+
+    return 0;
+}
+
+/* Copies data both ways until each side has closed its end of the connection. */
+static void relay_sockets(int a, int b)
+{
+    struct pollfd fds[2];
+    char buf[SOCKET_MAP_BUFSIZE];
+    int open_dirs = 2;
+    int peer;
+    ssize_t n;
+    int i;
+
+    fds[0].fd = a;
+    fds[0].events = POLLIN;
+    fds[1].fd = b;
+    fds[1].events = POLLIN;
+
+    while (open_dirs > 0) {
+        if (poll(fds, 2, -1) < 0) {
+            if (errno == EINTR)
+                continue;
+            return;
+        }
+
+        for (i = 0; i < 2; i++) {
+            if (fds[i].fd < 0 || !fds[i].revents)
+                continue;
+
+            peer = i == 0 ? b : a;
+            n = read(fds[i].fd, buf, sizeof(buf));
+            if (n <= 0) {
+                shutdown(peer, SHUT_WR);
+                fds[i].fd = -1;
+                open_dirs--;
+            } else if (write_all(peer, buf, n) < 0) {
+                return;
+            }
+        }
+    }
+}
+
+static int connect_unix(const char *path)
+{
+    struct sockaddr_un addr;
+    int sockfd, err;
+
+    memset(&addr, 0, sizeof(addr));
+    addr.sun_family = AF_UNIX;
+    strncpy(addr.sun_path, path, sizeof(addr.sun_path) - 1);
+
+    sockfd = socket(AF_UNIX, SOCK_STREAM, 0);
+    if (sockfd < 0)
+        return -1;
+
+    if (connect(sockfd, (struct sockaddr *)&addr, sizeof(addr)) < 0) {
+        err = errno;
+        close(sockfd);
+        errno = err;
+        return -1;
+    }
+
+    return sockfd;
+}
+
+/*
+ * Connects the host connection "hostfd" to the guest socket at "path". The
+ * guest service may not be listening yet, or be restarting, so the relay keeps
+ * trying for as long as the host keeps its end of the connection open, rather
+ * than for a fixed number of attempts. Nothing is read from "hostfd" while
+ * waiting, so no data is lost.
+ */
+static int connect_unix_relay(int hostfd, const char *path)
+{
+    struct pollfd pfd;
+    int sockfd;
+
+    pfd.fd = hostfd;
+    pfd.events = POLLRDHUP;
+
+    while (1) {
+        sockfd = connect_unix(path);
+        if (sockfd >= 0 || (errno != ENOENT && errno != ECONNREFUSED))
+            return sockfd;
+
+        if (poll(&pfd, 1, SOCKET_MAP_RETRY_DELAY) < 0) {
+            if (errno == EINTR)
+                continue;
+            return -1;
+        }
+
+        /* The host gave up on the connection */
+        if (pfd.revents & (POLLRDHUP | POLLHUP | POLLERR))
+            return -1;
+    }
+}
+
+static int connect_vsock(unsigned int port)
+{
+    struct sockaddr_vm addr;
+    int sockfd;
+
+    sockfd = socket(AF_VSOCK, SOCK_STREAM, 0);
+    if (sockfd < 0)
+        return -1;
+
+    memset(&addr, 0, sizeof(addr));
+    addr.svm_family = AF_VSOCK;
+    addr.svm_cid = VMADDR_CID_HOST;
+    addr.svm_port = port;
+
+    if (connect(sockfd, (struct sockaddr *)&addr, sizeof(addr)) < 0) {
+        close(sockfd);
+        return -1;
+    }
+
+    return sockfd;
+}
+
+/*
+ * Bridges a single socket map entry. With 'l', we listen on the guest path and
+ * forward connections to the host through the vsock port. With 'c', the host
+ * forwards connections through the vsock port and we connect them to the guest
+ * path, waiting for the guest service to accept them if needed. Never returns.
+ */
+static void socket_map_worker(char mode, unsigned int port, const char *path)
+{
+    struct sockaddr_un unix_addr;
+    struct sockaddr_vm vsock_addr;
+    char parent[PATH_MAX];
+    char *slash;
+    int listenfd, connfd, peerfd;
+
+    /* Children are never waited for, let the kernel reap them. */
+    signal(SIGCHLD, SIG_IGN);
+
+    if (mode == 'l') {
+        strncpy(parent, path, sizeof(parent) - 1);
+        parent[sizeof(parent) - 1] = '\0';
+        slash = strrchr(parent, '/');
+        if (slash && slash != parent) {
+            *slash = '\0';
+            mkdir_p(parent, 0755);
+        }
+
+        memset(&unix_addr, 0, sizeof(unix_addr));
+        unix_addr.sun_family = AF_UNIX;
+        strncpy(unix_addr.sun_path, path, sizeof(unix_addr.sun_path) - 1);
+
+        unlink(path);
+        listenfd = socket(AF_UNIX, SOCK_STREAM, 0);
+        if (listenfd < 0 ||
+            bind(listenfd, (struct sockaddr *)&unix_addr, sizeof(unix_addr)) < 0) {
+            printf("Couldn't bind socket map to '%s': %s\n", path,
+                   strerror(errno));
+            exit(1);
+        }
+    } else {
+        memset(&vsock_addr, 0, sizeof(vsock_addr));
+        vsock_addr.svm_family = AF_VSOCK;
+        vsock_addr.svm_cid = VMADDR_CID_ANY;
+        vsock_addr.svm_port = port;
+
+        listenfd = socket(AF_VSOCK, SOCK_STREAM, 0);
+        if (listenfd < 0 || bind(listenfd, (struct sockaddr *)&vsock_addr,
+                                 sizeof(vsock_addr)) < 0) {
+            printf("Couldn't bind socket map to vsock port %u: %s\n", port,
+                   strerror(errno));
+            exit(1);
+        }
+    }
+
+    if (listen(listenfd, SOMAXCONN) < 0) {
+        perror("listen");
+        exit(1);
+    }
+
+    while (1) {
+        connfd = accept(listenfd, NULL, NULL);
+        if (connfd < 0)
+            continue;
+
+        if (fork() == 0) {
+            close(listenfd);
+
+            if (mode == 'l') {
+                peerfd = connect_vsock(port);
+            } else {
+                peerfd = connect_unix_relay(connfd, path);
+            }
+
+            if (peerfd >= 0) {
+                relay_sockets(connfd, peerfd);
+            }
+            exit(0);
+        }
+
+        close(connfd);
+    }
+}
+
+/*
+ * Starts a worker for each entry of KRUN_UNIX_SOCKET_MAP, a comma-separated
+ * list of "<mode><vsock port>:<guest path>" items.
+ */
+static void setup_socket_maps(const char *socket_maps)
+{
+    char *maps, *item, *saveptr, *path;
+    unsigned long port;
+    char mode;
+
+    maps = strdup(socket_maps);
+    if (!maps)
+        return;
+
+    for (item = strtok_r(maps, ",", &saveptr); item;
+         item = strtok_r(NULL, ",", &saveptr)) {
+        mode = item[0];
+        port = strtoul(&item[1], &path, 10);
+        if ((mode != 'l' && mode != 'c') || *path != ':' || path[1] != '/') {
+            printf("Ignoring invalid socket map '%s'\n", item);
+            continue;
+        }
+
+        if (fork() == 0) {
+            socket_map_worker(mode, port, &path[1]);
+        }
+    }
+
+    free(maps);
+}
+
 int reopen_fd(int fd, char *path, int flags)
 {
     int newfd = open(path, flags);
@@ -1153,6 +1462,7 @@ int main(int argc, char **argv)
     char *krun_init;
     char *config_workdir, *env_workdir;
     char *rlimits;
+    char *socket_maps;
     char **config_argv, **exec_argv;
 
 #ifdef SEV
@@ -1228,6 +1538,11 @@ int main(int argc, char **argv)
     }
 #endif
 
+    socket_maps = getenv("KRUN_UNIX_SOCKET_MAP");
+    if (socket_maps) {
+        setup_socket_maps(socket_maps);
+    }
+
     // We need to fork ourselves, because pid 1 cannot doesn't receive SIGINT
     // signal
     int child = fork();
@@ -245,4 +245,8 @@ impl VirtioDevice for Balloon {
             DeviceState::Activated(_) => true,
         }
     }
+
+    fn snapshot_supported(&self) -> bool {
+        true
+    }
 }
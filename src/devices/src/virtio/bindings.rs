@@ -29,6 +29,11 @@ pub const LINUX_RENAME_WHITEOUT: libc::c_int = 1 << 2;
 pub const LINUX_XATTR_CREATE: libc::c_int = 1;
 pub const LINUX_XATTR_REPLACE: libc::c_int = 2;
 
+pub const LINUX_FS_IOC_GETFLAGS: u32 = 0x8008_6601;
+pub const LINUX_FS_IOC_SETFLAGS: u32 = 0x4008_6602;
+pub const LINUX_FS_IMMUTABLE_FL: u32 = 0x10;
+pub const LINUX_FS_APPEND_FL: u32 = 0x20;
+
 #[cfg(target_os = "macos")]
 pub type stat64 = libc::stat;
 #[cfg(target_os = "linux")]
@@ -13,7 +13,7 @@ use std::io::{self, Write};
 use std::os::linux::fs::MetadataExt;
 #[cfg(target_os = "macos")]
 use std::os::macos::fs::MetadataExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::result;
 use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
@@ -32,11 +32,11 @@ use vm_memory::{ByteValued, GuestMemoryMmap};
 use super::worker::BlockWorker;
 use super::{
     super::{ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_BLOCK},
-    Error, QUEUE_SIZES, SECTOR_SHIFT, SECTOR_SIZE,
+    snapshot, BlockFormat, DiskSnapshot, Error, QUEUE_SIZES, SECTOR_SHIFT, SECTOR_SIZE,
 };
 
 use crate::legacy::IrqChip;
-use crate::virtio::{block::ImageType, ActivateError};
+use crate::virtio::ActivateError;
 
 /// Configuration options for disk caching.
 #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
@@ -208,15 +208,27 @@ pub struct Block {
 impl Block {
     /// Create a new virtio block device that operates on the given file.
     ///
-    /// The given file must be seekable and sizable.
+    /// The given file must be seekable and sizable. The snapshot `disk_snapshot`, if any, is
+    /// applied or taken before the image is opened, and requires a writable qcow2 image.
     pub fn new(
         id: String,
         partuuid: Option<String>,
         cache_type: CacheType,
         disk_image_path: String,
-        disk_image_format: ImageType,
+        disk_image_format: BlockFormat,
+        disk_snapshot: Option<DiskSnapshot>,
         is_disk_read_only: bool,
     ) -> io::Result<Block> {
+        if let Some(disk_snapshot) = &disk_snapshot {
+            if disk_image_format != BlockFormat::Qcow2 || is_disk_read_only {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "snapshots require a writable qcow2 image",
+                ));
+            }
+            snapshot::handle(Path::new(&disk_image_path), disk_snapshot)?;
+        }
+
         let disk_image = OpenOptions::new()
             .read(true)
             .write(!is_disk_read_only)
@@ -225,13 +237,13 @@ impl Block {
         let disk_image_id = DiskProperties::build_disk_image_id(&disk_image);
 
         let disk_image = match disk_image_format {
-            ImageType::Qcow2 => {
+            BlockFormat::Qcow2 => {
                 let mut qcow_disk_image =
                     Qcow2::<ImagoFile>::open_path_sync(disk_image_path, !is_disk_read_only)?;
                 qcow_disk_image.open_implicit_dependencies_sync()?;
                 SyncFormatAccess::new(qcow_disk_image)?
             }
-            ImageType::Raw => {
+            BlockFormat::Raw => {
                 let raw = imago::raw::Raw::open_path_sync(disk_image_path, !is_disk_read_only)?;
                 SyncFormatAccess::new(raw)?
             }
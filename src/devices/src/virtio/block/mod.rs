@@ -2,6 +2,7 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub mod device;
+mod snapshot;
 mod worker;
 
 pub use self::device::{Block, CacheType};
@@ -35,7 +36,19 @@ pub enum Error {
 
 /// Supported disk image formats
 #[derive(Clone, Debug, PartialEq, Eq)]
-pub enum ImageType {
+pub enum BlockFormat {
     Raw,
+    /// A qcow2 image, opened along with its backing chain. The backing files are only read, so
+    /// that several images can share the same base.
     Qcow2,
 }
+
+/// An internal snapshot of a qcow2 image, handled when the disk is created, before the guest can
+/// see it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DiskSnapshot {
+    /// Reverts the image to the snapshot with this name, dropping the changes made since.
+    Apply(String),
+    /// Takes a snapshot with this name of the image as it is.
+    Create(String),
+}
@@ -0,0 +1,825 @@
+//! Internal snapshots of qcow2 images.
+//!
+//! imago doesn't handle snapshots, so they are applied and taken when a disk is created, before
+//! imago opens its image, by editing the metadata of the image directly. Taking a snapshot hands
+//! the active L1 table over to the snapshot and gives the image a copy of it, and applying one
+//! gives the image a copy of the L1 table of the snapshot. Either way the L2 tables and data
+//! clusters end up referenced by both, so their refcounts are incremented and their COPIED flags
+//! cleared, which makes imago copy them before writing to them.
+//!
+//! The COPIED flags of the shared L2 tables are cleared in place, which is safe at any time: a
+//! cluster that isn't flagged is only copied when it could have been written in place. Everything
+//! else is written to new clusters at the end of the image, including a rebuilt refcount
+//! structure, and the header is then switched to it with a single write once the rest is on disk,
+//! so that an update interrupted before the switch leaves the image as it was, at most with leaked
+//! clusters. The autoclear features, such as the dirty bitmaps, don't follow the changes, so they
+//! are cleared first, as the qcow2 specification requires of the programs that don't know them.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::debug;
+
+use super::DiskSnapshot;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+const MAGIC: u32 = 0x5146_49fb;
+
+/// The incompatible feature bits of the images handled: the compression type doesn't change the
+/// metadata, but a dirty image has refcounts that can't be trusted, a corrupt one must not be
+/// written, one with an external data file can't have snapshots, and extended L2 entries have
+/// another layout.
+const SUPPORTED_INCOMPATIBLE_FEATURES: u64 = 1 << 3;
+
+/// The part of the header written to switch to new metadata, from the virtual size to the offset
+/// of the snapshot table.
+const HEADER_UPDATE_OFFSET: u64 = 24;
+
+/// The offset of the autoclear feature bits in the header of version 3 images.
+const AUTOCLEAR_FEATURES_OFFSET: u64 = 88;
+
+/// The mask of the offset in L1 entries and standard L2 entries.
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+
+/// The mask of the offset in refcount table entries.
+const REFTABLE_OFFSET_MASK: u64 = 0xffff_ffff_ffff_fe00;
+
+const COPIED: u64 = 1 << 63;
+
+const COMPRESSED: u64 = 1 << 62;
+
+/// The size of the fixed part of an entry of the snapshot table.
+const SNAPSHOT_HEADER_SIZE: usize = 40;
+
+/// The largest size of the extra data of a snapshot.
+const MAX_EXTRA_DATA_SIZE: usize = 1024;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+struct Header {
+    version: u32,
+    cluster_bits: u32,
+    size: u64,
+    crypt_method: u32,
+    l1_size: u32,
+    l1_table_offset: u64,
+    refcount_table_offset: u64,
+    refcount_table_clusters: u32,
+    nb_snapshots: u32,
+    snapshots_offset: u64,
+    refcount_order: u32,
+}
+
+/// An entry of the snapshot table. The ID and name are kept as raw bytes and the extra data as it
+/// is, so that rewriting the table preserves the entries it doesn't change.
+struct Snapshot {
+    l1_table_offset: u64,
+    l1_size: u32,
+    id: Vec<u8>,
+    name: Vec<u8>,
+    date_sec: u32,
+    date_nsec: u32,
+    vm_clock_nsec: u64,
+    vm_state_size: u32,
+    extra_data: Vec<u8>,
+}
+
+/// A qcow2 image being updated.
+struct Image {
+    file: File,
+    header: Header,
+    cluster_size: u64,
+
+    /// The refcounts of the clusters, but the ones of the refcount structure, which is rebuilt.
+    refcounts: Vec<u64>,
+
+    /// The first cluster past the end of the image, new clusters being allocated from there.
+    next_cluster: u64,
+
+    snapshots: Vec<Snapshot>,
+
+    /// The size of the snapshot table when the image was opened.
+    snapshots_size: u64,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl Header {
+    fn read(file: &File) -> io::Result<Self> {
+        let mut buf = [0; 104];
+        file.read_exact_at(&mut buf, 0)?;
+        let be32 = |at: usize| u32::from_be_bytes(buf[at..at + 4].try_into().unwrap());
+        let be64 = |at: usize| u64::from_be_bytes(buf[at..at + 8].try_into().unwrap());
+
+        if be32(0) != MAGIC {
+            return Err(invalid("not a qcow2 image"));
+        }
+        let version = be32(4);
+        let (incompatible_features, refcount_order) = match version {
+            2 => (0, 4),
+            3 => (be64(72), be32(96)),
+            version => return Err(unsupported(format!("qcow2 version {version}"))),
+        };
+        if incompatible_features & !SUPPORTED_INCOMPATIBLE_FEATURES != 0 {
+            return Err(unsupported(format!(
+                "qcow2 incompatible features {incompatible_features:#x}"
+            )));
+        }
+
+        let header = Header {
+            version,
+            cluster_bits: be32(20),
+            size: be64(24),
+            crypt_method: be32(32),
+            l1_size: be32(36),
+            l1_table_offset: be64(40),
+            refcount_table_offset: be64(48),
+            refcount_table_clusters: be32(56),
+            nb_snapshots: be32(60),
+            snapshots_offset: be64(64),
+            refcount_order,
+        };
+        if !(9..=21).contains(&header.cluster_bits) || header.refcount_order > 6 {
+            return Err(invalid("invalid qcow2 header"));
+        }
+        Ok(header)
+    }
+
+    /// Writes the fields that can change, which are contiguous, in a single write.
+    fn write(&self, file: &File) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(48);
+        buf.extend_from_slice(&self.size.to_be_bytes());
+        buf.extend_from_slice(&self.crypt_method.to_be_bytes());
+        buf.extend_from_slice(&self.l1_size.to_be_bytes());
+        buf.extend_from_slice(&self.l1_table_offset.to_be_bytes());
+        buf.extend_from_slice(&self.refcount_table_offset.to_be_bytes());
+        buf.extend_from_slice(&self.refcount_table_clusters.to_be_bytes());
+        buf.extend_from_slice(&self.nb_snapshots.to_be_bytes());
+        buf.extend_from_slice(&self.snapshots_offset.to_be_bytes());
+        file.write_all_at(&buf, HEADER_UPDATE_OFFSET)
+    }
+
+    /// Clears the autoclear feature bits of a version 3 image.
+    fn clear_autoclear_features(&self, file: &File) -> io::Result<()> {
+        if self.version < 3 {
+            return Ok(());
+        }
+        file.write_all_at(&0u64.to_be_bytes(), AUTOCLEAR_FEATURES_OFFSET)
+    }
+}
+
+impl Snapshot {
+    /// Returns the virtual size of the image when the snapshot was taken, if recorded.
+    fn disk_size(&self) -> Option<u64> {
+        self.extra_data
+            .get(8..16)
+            .map(|size| u64::from_be_bytes(size.try_into().unwrap()))
+    }
+
+    fn encode(&self, table: &mut Vec<u8>) {
+        table.extend_from_slice(&self.l1_table_offset.to_be_bytes());
+        table.extend_from_slice(&self.l1_size.to_be_bytes());
+        table.extend_from_slice(&(self.id.len() as u16).to_be_bytes());
+        table.extend_from_slice(&(self.name.len() as u16).to_be_bytes());
+        table.extend_from_slice(&self.date_sec.to_be_bytes());
+        table.extend_from_slice(&self.date_nsec.to_be_bytes());
+        table.extend_from_slice(&self.vm_clock_nsec.to_be_bytes());
+        table.extend_from_slice(&self.vm_state_size.to_be_bytes());
+        table.extend_from_slice(&(self.extra_data.len() as u32).to_be_bytes());
+        table.extend_from_slice(&self.extra_data);
+        table.extend_from_slice(&self.id);
+        table.extend_from_slice(&self.name);
+        table.resize(table.len().next_multiple_of(8), 0);
+    }
+}
+
+impl Image {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let header = Header::read(&file)?;
+        let cluster_size = 1 << header.cluster_bits;
+        let mut image = Image {
+            next_cluster: file.metadata()?.len().div_ceil(cluster_size),
+            file,
+            header,
+            cluster_size,
+            refcounts: Vec::new(),
+            snapshots: Vec::new(),
+            snapshots_size: 0,
+        };
+        image.read_refcounts()?;
+        image.read_snapshots()?;
+        Ok(image)
+    }
+
+    /// Takes a snapshot named `name` of the active state of the image.
+    fn create(&mut self, name: &str) -> io::Result<()> {
+        if self.find(name).is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("snapshot {name} already exists"),
+            ));
+        }
+
+        // The snapshot takes the active L1 table over, and the image gets a copy of it
+        let l1 = self.read_table(self.header.l1_table_offset, self.header.l1_size as usize)?;
+        self.add_references(&l1, 1, true)?;
+        let l1_table_offset = self.write_l1_copy(&l1)?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let id = self
+            .snapshots
+            .iter()
+            .filter_map(|s| std::str::from_utf8(&s.id).ok()?.parse::<u64>().ok())
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let mut extra_data = vec![0; 8];
+        extra_data.extend_from_slice(&self.header.size.to_be_bytes());
+        self.snapshots.push(Snapshot {
+            l1_table_offset: self.header.l1_table_offset,
+            l1_size: self.header.l1_size,
+            id: id.to_string().into_bytes(),
+            name: name.as_bytes().to_vec(),
+            date_sec: now.as_secs() as u32,
+            date_nsec: now.subsec_nanos(),
+            vm_clock_nsec: 0,
+            vm_state_size: 0,
+            extra_data,
+        });
+        self.header.l1_table_offset = l1_table_offset;
+        Ok(())
+    }
+
+    /// Reverts the image to its snapshot named `name`.
+    fn apply(&mut self, name: &str) -> io::Result<()> {
+        let snapshot = self.find(name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("snapshot {name} not found"),
+            )
+        })?;
+        let (snapshot_l1_offset, snapshot_l1_size) = (snapshot.l1_table_offset, snapshot.l1_size);
+        let size = snapshot.disk_size().unwrap_or(self.header.size);
+
+        // The references of the snapshot are taken before the ones of the active L1 table are
+        // dropped, so that the refcounts of what they share never reach zero
+        let mut l1 = self.read_table(snapshot_l1_offset, snapshot_l1_size as usize)?;
+        self.add_references(&l1, 1, true)?;
+        let active = self.read_table(self.header.l1_table_offset, self.header.l1_size as usize)?;
+        self.add_references(&active, -1, false)?;
+        self.free(self.header.l1_table_offset, active.len() as u64 * 8)?;
+
+        let l2_coverage = self.cluster_size * (self.cluster_size / 8);
+        l1.resize(l1.len().max(size.div_ceil(l2_coverage) as usize), 0);
+        self.header.l1_table_offset = self.write_l1_copy(&l1)?;
+        self.header.l1_size = l1.len() as u32;
+        self.header.size = size;
+        Ok(())
+    }
+
+    /// Writes the new metadata, then switches the header to it.
+    fn commit(mut self) -> io::Result<()> {
+        self.header.clear_autoclear_features(&self.file)?;
+        self.write_metadata()?;
+
+        // The new metadata must be on disk before the header points to it, and the header must be
+        // before imago opens the image
+        self.file.sync_all()?;
+        self.header.write(&self.file)?;
+        self.file.sync_all()
+    }
+
+    /// Writes the snapshot table and the refcounts to new clusters, which nothing points to until
+    /// the header is switched to them and to the active L1 table.
+    fn write_metadata(&mut self) -> io::Result<()> {
+        if self.header.nb_snapshots > 0 {
+            self.free(self.header.snapshots_offset, self.snapshots_size)?;
+        }
+        let mut table = Vec::new();
+        for snapshot in &self.snapshots {
+            snapshot.encode(&mut table);
+        }
+        self.header.snapshots_offset = if table.is_empty() {
+            0
+        } else {
+            let offset = self.allocate(table.len() as u64)?;
+            self.file.write_all_at(&table, offset)?;
+            offset
+        };
+        self.header.nb_snapshots = self.snapshots.len() as u32;
+
+        self.write_refcounts()
+    }
+
+    fn find(&self, name: &str) -> Option<&Snapshot> {
+        self.snapshots.iter().find(|s| s.name == name.as_bytes())
+    }
+
+    /// Adds `delta` to the refcounts of the L2 tables referenced by the L1 table `l1` and of the
+    /// data clusters they reference. With `clear_copied`, the COPIED flags of the L2 tables are
+    /// also cleared.
+    fn add_references(&mut self, l1: &[u64], delta: i64, clear_copied: bool) -> io::Result<()> {
+        for l1_entry in l1 {
+            let l2_offset = l1_entry & OFFSET_MASK;
+            if l2_offset == 0 {
+                continue;
+            }
+
+            let mut l2 = self.read_table(l2_offset, self.cluster_size as usize / 8)?;
+            let mut modified = false;
+            for l2_entry in &mut l2 {
+                if let Some((first, count)) = self.allocation(*l2_entry)? {
+                    for cluster in first..first + count {
+                        self.add_reference(cluster, delta)?;
+                    }
+                }
+                if clear_copied && *l2_entry & COPIED != 0 {
+                    *l2_entry &= !COPIED;
+                    modified = true;
+                }
+            }
+            if modified {
+                self.write_table(l2_offset, &l2)?;
+            }
+            self.add_reference(l2_offset >> self.header.cluster_bits, delta)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the first cluster and the number of clusters holding the data of an L2 entry, if
+    /// any. The data of a compressed cluster may span two of them.
+    fn allocation(&self, l2_entry: u64) -> io::Result<Option<(u64, u64)>> {
+        let cluster_bits = self.header.cluster_bits;
+        if l2_entry & COMPRESSED != 0 {
+            let descriptor = l2_entry & !(COPIED | COMPRESSED);
+            let offset_bits = 62 - (cluster_bits - 8);
+            let offset = descriptor & ((1 << offset_bits) - 1);
+            let sectors = descriptor >> offset_bits;
+            let end = offset + (sectors + 1) * 512 - (offset & 511);
+            let first = offset >> cluster_bits;
+            return Ok(Some((first, end.div_ceil(self.cluster_size) - first)));
+        }
+
+        let offset = l2_entry & OFFSET_MASK;
+        if !offset.is_multiple_of(self.cluster_size) {
+            return Err(invalid("unaligned data cluster"));
+        }
+        Ok((offset != 0).then_some((offset >> cluster_bits, 1)))
+    }
+
+    /// Writes a copy of the L1 table `l1` for the active state of the image, which shares all of
+    /// its L2 tables with a snapshot, and returns its offset.
+    fn write_l1_copy(&mut self, l1: &[u64]) -> io::Result<u64> {
+        let copy: Vec<u64> = l1.iter().map(|entry| entry & !COPIED).collect();
+        let offset = self.allocate(copy.len() as u64 * 8)?;
+        self.write_table(offset, &copy)?;
+        Ok(offset)
+    }
+
+    /// Allocates enough clusters for `size` bytes, and returns the offset of the first one.
+    fn allocate(&mut self, size: u64) -> io::Result<u64> {
+        let first = self.next_cluster;
+        self.next_cluster += size.div_ceil(self.cluster_size).max(1);
+        for cluster in first..self.next_cluster {
+            self.add_reference(cluster, 1)?;
+        }
+        Ok(first << self.header.cluster_bits)
+    }
+
+    /// Drops the references to the clusters holding the `size` bytes at `offset`.
+    fn free(&mut self, offset: u64, size: u64) -> io::Result<()> {
+        let first = offset >> self.header.cluster_bits;
+        for cluster in first..first + size.div_ceil(self.cluster_size).max(1) {
+            self.add_reference(cluster, -1)?;
+        }
+        Ok(())
+    }
+
+    fn add_reference(&mut self, cluster: u64, delta: i64) -> io::Result<()> {
+        // A corrupt offset mustn't grow the refcounts past the image
+        if cluster >= self.next_cluster {
+            return Err(invalid("reference past the end of the image"));
+        }
+        let cluster = cluster as usize;
+        if cluster >= self.refcounts.len() {
+            self.refcounts.resize(cluster + 1, 0);
+        }
+        let max = u64::MAX >> (64 - (1 << self.header.refcount_order));
+        match self.refcounts[cluster].checked_add_signed(delta) {
+            Some(refcount) if refcount <= max => {
+                self.refcounts[cluster] = refcount;
+                Ok(())
+            }
+            Some(_) => Err(unsupported("too many references to a cluster".to_string())),
+            None => Err(invalid("reference to a free cluster")),
+        }
+    }
+
+    /// Reads the refcounts of all the clusters, dropping the references of the refcount structure
+    /// to its own clusters.
+    fn read_refcounts(&mut self) -> io::Result<()> {
+        let table_len = self.header.refcount_table_clusters as u64 * self.cluster_size / 8;
+        let table = self.read_table(self.header.refcount_table_offset, table_len as usize)?;
+        let per_block = self.refcounts_per_block();
+
+        let mut structure = vec![(self.header.refcount_table_offset, table_len * 8)];
+        let mut block = vec![0; self.cluster_size as usize];
+        for (index, entry) in table.iter().enumerate() {
+            let offset = entry & REFTABLE_OFFSET_MASK;
+            if offset == 0 {
+                continue;
+            }
+            if !offset.is_multiple_of(self.cluster_size) {
+                return Err(invalid("unaligned refcount block"));
+            }
+            self.file.read_exact_at(&mut block, offset)?;
+            let first = index as u64 * per_block;
+            for i in 0..per_block {
+                let refcount = get_refcount(&block, i as usize, self.header.refcount_order);
+                if refcount != 0 {
+                    self.add_reference(first + i, refcount as i64)?;
+                }
+            }
+            structure.push((offset, self.cluster_size));
+        }
+
+        for (offset, size) in structure {
+            self.free(offset, size)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a new refcount structure after the last cluster, covering itself.
+    fn write_refcounts(&mut self) -> io::Result<()> {
+        let per_block = self.refcounts_per_block();
+        let start = self.next_cluster;
+        let (mut blocks, mut table_clusters) = (0, 0);
+        loop {
+            let needed_blocks = (start + table_clusters + blocks).div_ceil(per_block);
+            let needed_table_clusters = (needed_blocks * 8).div_ceil(self.cluster_size);
+            if (needed_blocks, needed_table_clusters) == (blocks, table_clusters) {
+                break;
+            }
+            (blocks, table_clusters) = (needed_blocks, needed_table_clusters);
+        }
+        let table_offset = self.allocate(table_clusters * self.cluster_size)?;
+        let blocks_offset = self.allocate(blocks * self.cluster_size)?;
+
+        let mut table = vec![0; (table_clusters * self.cluster_size / 8) as usize];
+        for (index, entry) in table.iter_mut().take(blocks as usize).enumerate() {
+            let mut block = vec![0; self.cluster_size as usize];
+            let first = index * per_block as usize;
+            for (i, refcount) in self
+                .refcounts
+                .iter()
+                .skip(first)
+                .take(per_block as usize)
+                .enumerate()
+            {
+                set_refcount(&mut block, i, self.header.refcount_order, *refcount);
+            }
+            *entry = blocks_offset + index as u64 * self.cluster_size;
+            self.file.write_all_at(&block, *entry)?;
+        }
+        self.write_table(table_offset, &table)?;
+
+        self.header.refcount_table_offset = table_offset;
+        self.header.refcount_table_clusters = table_clusters as u32;
+        Ok(())
+    }
+
+    fn refcounts_per_block(&self) -> u64 {
+        (self.cluster_size * 8) >> self.header.refcount_order
+    }
+
+    /// Reads the snapshot table.
+    fn read_snapshots(&mut self) -> io::Result<()> {
+        let start = self.header.snapshots_offset;
+        if self.header.nb_snapshots > 0 && !start.is_multiple_of(self.cluster_size) {
+            return Err(invalid("unaligned snapshot table"));
+        }
+
+        let mut offset = start;
+        for _ in 0..self.header.nb_snapshots {
+            let mut fixed = [0; SNAPSHOT_HEADER_SIZE];
+            self.file.read_exact_at(&mut fixed, offset)?;
+            let be16 = |at: usize| u16::from_be_bytes(fixed[at..at + 2].try_into().unwrap());
+            let be32 = |at: usize| u32::from_be_bytes(fixed[at..at + 4].try_into().unwrap());
+            let be64 = |at: usize| u64::from_be_bytes(fixed[at..at + 8].try_into().unwrap());
+
+            let id_size = be16(12) as usize;
+            let name_size = be16(14) as usize;
+            let extra_data_size = be32(36) as usize;
+            if extra_data_size > MAX_EXTRA_DATA_SIZE {
+                return Err(invalid("invalid snapshot table"));
+            }
+            let mut rest = vec![0; extra_data_size + id_size + name_size];
+            self.file
+                .read_exact_at(&mut rest, offset + SNAPSHOT_HEADER_SIZE as u64)?;
+            let name = rest.split_off(extra_data_size + id_size);
+            let id = rest.split_off(extra_data_size);
+            self.snapshots.push(Snapshot {
+                l1_table_offset: be64(0),
+                l1_size: be32(8),
+                id,
+                name,
+                date_sec: be32(16),
+                date_nsec: be32(20),
+                vm_clock_nsec: be64(24),
+                vm_state_size: be32(32),
+                extra_data: rest,
+            });
+
+            let size = SNAPSHOT_HEADER_SIZE + extra_data_size + id_size + name_size;
+            offset += size.next_multiple_of(8) as u64;
+        }
+        self.snapshots_size = offset - start;
+        Ok(())
+    }
+
+    /// Reads a table of `len` big-endian entries, which must start a cluster.
+    fn read_table(&self, offset: u64, len: usize) -> io::Result<Vec<u64>> {
+        if !offset.is_multiple_of(self.cluster_size) {
+            return Err(invalid("unaligned table"));
+        }
+        let mut buf = vec![0; len * 8];
+        self.file.read_exact_at(&mut buf, offset)?;
+        Ok(buf
+            .chunks_exact(8)
+            .map(|entry| u64::from_be_bytes(entry.try_into().unwrap()))
+            .collect())
+    }
+
+    fn write_table(&self, offset: u64, table: &[u64]) -> io::Result<()> {
+        let buf: Vec<u8> = table.iter().flat_map(|entry| entry.to_be_bytes()).collect();
+        self.file.write_all_at(&buf, offset)
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Applies or takes `snapshot` in the qcow2 image at `path`, which mustn't be open.
+pub(crate) fn handle(path: &Path, snapshot: &DiskSnapshot) -> io::Result<()> {
+    let mut image = Image::open(path)?;
+    match snapshot {
+        DiskSnapshot::Apply(name) => image.apply(name)?,
+        DiskSnapshot::Create(name) => image.create(name)?,
+    }
+    image.commit()?;
+    debug!("{snapshot:?} in {}", path.display());
+    Ok(())
+}
+
+/// Returns the refcount at `index` in a refcount block of `1 << order`-bit entries.
+fn get_refcount(block: &[u8], index: usize, order: u32) -> u64 {
+    let bits = 1 << order;
+    if bits >= 8 {
+        let bytes = bits / 8;
+        block[index * bytes..(index + 1) * bytes]
+            .iter()
+            .fold(0, |refcount, byte| refcount << 8 | *byte as u64)
+    } else {
+        let per_byte = 8 / bits;
+        (block[index / per_byte] >> (index % per_byte * bits)) as u64 & ((1 << bits) - 1)
+    }
+}
+
+fn set_refcount(block: &mut [u8], index: usize, order: u32, refcount: u64) {
+    let bits = 1 << order;
+    if bits >= 8 {
+        let bytes = bits / 8;
+        let be = refcount.to_be_bytes();
+        block[index * bytes..(index + 1) * bytes].copy_from_slice(&be[8 - bytes..]);
+    } else {
+        let per_byte = 8 / bits;
+        block[index / per_byte] |= (refcount as u8) << (index % per_byte * bits);
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn unsupported(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use imago::file::File as ImagoFile;
+    use imago::qcow2::Qcow2;
+    use imago::SyncFormatAccess;
+
+    use super::*;
+
+    const CLUSTER_BITS: u32 = 16;
+    const CLUSTER_SIZE: usize = 1 << CLUSTER_BITS;
+
+    /// Writes an empty 16 MiB qcow2 image: its header, refcount table, refcount block and L1
+    /// table in its first four clusters.
+    fn new_image(path: &Path) {
+        let mut image = vec![0; 4 * CLUSTER_SIZE];
+        let mut put = |at: usize, bytes: &[u8]| image[at..at + bytes.len()].copy_from_slice(bytes);
+        put(0, &MAGIC.to_be_bytes());
+        put(4, &3u32.to_be_bytes());
+        put(20, &CLUSTER_BITS.to_be_bytes());
+        put(24, &(16u64 << 20).to_be_bytes());
+        put(36, &1u32.to_be_bytes());
+        put(40, &(3 * CLUSTER_SIZE as u64).to_be_bytes());
+        put(48, &(CLUSTER_SIZE as u64).to_be_bytes());
+        put(56, &1u32.to_be_bytes());
+        put(96, &4u32.to_be_bytes());
+        put(100, &104u32.to_be_bytes());
+        put(CLUSTER_SIZE, &(2 * CLUSTER_SIZE as u64).to_be_bytes());
+        for cluster in 0..4 {
+            put(2 * CLUSTER_SIZE + cluster * 2, &1u16.to_be_bytes());
+        }
+        fs::write(path, image).unwrap();
+    }
+
+    fn open(path: &Path) -> SyncFormatAccess<ImagoFile> {
+        let qcow2 = Qcow2::<ImagoFile>::open_path_sync(path, true).unwrap();
+        SyncFormatAccess::new(qcow2).unwrap()
+    }
+
+    fn write(path: &Path, offset: u64, data: &[u8]) {
+        let disk = open(path);
+        disk.write(&data.to_vec(), offset).unwrap();
+        disk.flush().unwrap();
+    }
+
+    fn read(path: &Path, offset: u64, len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        open(path).read(&mut data, offset).unwrap();
+        data
+    }
+
+    /// Checks that the refcounts of the image match the references of its metadata.
+    fn check_refcounts(path: &Path) {
+        let image = Image::open(path).unwrap();
+        let mut expected = Image {
+            file: image.file.try_clone().unwrap(),
+            header: Header::read(&image.file).unwrap(),
+            cluster_size: image.cluster_size,
+            refcounts: Vec::new(),
+            next_cluster: image.next_cluster,
+            snapshots: Vec::new(),
+            snapshots_size: 0,
+        };
+        expected.add_reference(0, 1).unwrap();
+        let mut l1_tables = vec![(image.header.l1_table_offset, image.header.l1_size)];
+        l1_tables.extend(
+            image
+                .snapshots
+                .iter()
+                .map(|s| (s.l1_table_offset, s.l1_size)),
+        );
+        for (offset, size) in l1_tables {
+            let l1 = image.read_table(offset, size as usize).unwrap();
+            expected.add_references(&l1, 1, false).unwrap();
+            expected.add_reference(offset >> CLUSTER_BITS, 1).unwrap();
+        }
+        if image.header.nb_snapshots > 0 {
+            let first = image.header.snapshots_offset >> CLUSTER_BITS;
+            for cluster in first..first + image.snapshots_size.div_ceil(image.cluster_size) {
+                expected.add_reference(cluster, 1).unwrap();
+            }
+        }
+
+        let trim = |refcounts: &[u64]| {
+            let len = refcounts.iter().rposition(|r| *r != 0).map_or(0, |i| i + 1);
+            refcounts[..len].to_vec()
+        };
+        assert_eq!(trim(&image.refcounts), trim(&expected.refcounts));
+    }
+
+    #[test]
+    fn interrupted_update() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk.qcow2");
+        new_image(&path);
+        write(&path, 0, &[1; 4096]);
+
+        // An update stopped before the header switch leaves the image as it was
+        let mut image = Image::open(&path).unwrap();
+        image.create("lost").unwrap();
+        image.write_metadata().unwrap();
+        drop(image);
+        assert_eq!(read(&path, 0, 4096), vec![1; 4096]);
+        assert!(Image::open(&path).unwrap().snapshots.is_empty());
+        check_refcounts(&path);
+
+        handle(&path, &DiskSnapshot::Create("first".to_string())).unwrap();
+        write(&path, 0, &[2; 4096]);
+        handle(&path, &DiskSnapshot::Apply("first".to_string())).unwrap();
+        assert_eq!(read(&path, 0, 4096), vec![1; 4096]);
+        check_refcounts(&path);
+    }
+
+    #[test]
+    fn create_and_apply() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk.qcow2");
+        new_image(&path);
+
+        write(&path, 0, &[1; 4096]);
+
+        // The dirty bitmaps, if any, no longer match the image
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        file.write_all_at(&1u64.to_be_bytes(), AUTOCLEAR_FEATURES_OFFSET)
+            .unwrap();
+        handle(&path, &DiskSnapshot::Create("first".to_string())).unwrap();
+        let mut autoclear = [0xff; 8];
+        file.read_exact_at(&mut autoclear, AUTOCLEAR_FEATURES_OFFSET)
+            .unwrap();
+        assert_eq!(autoclear, [0; 8]);
+        check_refcounts(&path);
+
+        // Writing after the snapshot copies the clusters it shares
+        write(&path, 0, &[2; 4096]);
+        write(&path, 8 << 20, &[3; 4096]);
+        check_refcounts(&path);
+        handle(&path, &DiskSnapshot::Create("second".to_string())).unwrap();
+        check_refcounts(&path);
+
+        let err = handle(&path, &DiskSnapshot::Create("first".to_string())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        handle(&path, &DiskSnapshot::Apply("first".to_string())).unwrap();
+        check_refcounts(&path);
+        assert_eq!(read(&path, 0, 4096), [1; 4096]);
+        assert_eq!(read(&path, 8 << 20, 4096), [0; 4096]);
+
+        // The snapshot is left as it was by writing to the image it was applied to
+        write(&path, 0, &[4; 4096]);
+        check_refcounts(&path);
+        handle(&path, &DiskSnapshot::Apply("second".to_string())).unwrap();
+        check_refcounts(&path);
+        assert_eq!(read(&path, 0, 4096), [2; 4096]);
+        assert_eq!(read(&path, 8 << 20, 4096), [3; 4096]);
+        handle(&path, &DiskSnapshot::Apply("first".to_string())).unwrap();
+        assert_eq!(read(&path, 0, 4096), [1; 4096]);
+        check_refcounts(&path);
+
+        let image = Image::open(&path).unwrap();
+        let names: Vec<_> = image.snapshots.iter().map(|s| s.name.as_slice()).collect();
+        assert_eq!(names, [b"first".as_slice(), b"second"]);
+        let ids: Vec<_> = image.snapshots.iter().map(|s| s.id.as_slice()).collect();
+        assert_eq!(ids, [b"1".as_slice(), b"2"]);
+
+        let err = handle(&path, &DiskSnapshot::Apply("third".to_string())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn corrupt_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk.qcow2");
+        new_image(&path);
+        write(&path, 0, &[1; 4096]);
+
+        // A data cluster far past the end of the image is refused, rather than counted
+        let image = Image::open(&path).unwrap();
+        let l1 = image.read_table(image.header.l1_table_offset, 1).unwrap();
+        image
+            .write_table(l1[0] & OFFSET_MASK, &[COPIED | 1 << 50])
+            .unwrap();
+        drop(image);
+        let err = handle(&path, &DiskSnapshot::Create("first".to_string())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn refcount_widths() {
+        let mut block = [0; 24];
+        for order in 0..=6 {
+            block.fill(0);
+            let max = u64::MAX >> (64 - (1 << order));
+            set_refcount(&mut block, 1, order, max);
+            assert_eq!(get_refcount(&block, 0, order), 0);
+            assert_eq!(get_refcount(&block, 1, order), max);
+            assert_eq!(get_refcount(&block, 2, order), 0);
+        }
+    }
+}
@@ -134,6 +134,19 @@ impl ConsoleControl {
         &self.queue_evt
     }
 
+    /// Returns the messages not sent to the guest yet, for a snapshot.
+    pub fn pending(&self) -> Vec<Vec<u8>> {
+        let queue = self.queue.lock().expect("Poisoned lock");
+        queue.iter().map(|payload| payload.to_vec()).collect()
+    }
+
+    /// Queues the messages returned by `pending` again, for a restored snapshot.
+    pub fn restore(&self, payloads: Vec<Vec<u8>>) {
+        for payload in payloads {
+            self.push_vec(payload);
+        }
+    }
+
     fn push_msg(&self, msg: VirtioConsoleControl) {
         let mut queue = self.queue.lock().expect("Poisoned lock");
         queue.push_back(Payload::ConsoleControl(msg));
@@ -1,5 +1,5 @@
 use std::cmp;
-use std::io::Write;
+use std::io::{self, Write};
 use std::iter::zip;
 use std::mem::{size_of, size_of_val};
 use std::os::unix::io::{AsRawFd, RawFd};
@@ -96,6 +96,8 @@ pub struct Console {
     pub(crate) sigwinch_evt: EventFd,
 
     config: VirtioConsoleConfig,
+    // The ports the guest had opened in a restored snapshot, to start once activated.
+    restored_ports: Vec<u32>,
 }
 
 impl Console {
@@ -135,6 +137,7 @@ impl Console {
                 .map_err(ConsoleError::EventFd)?,
             device_state: DeviceState::Inactive,
             config,
+            restored_ports: Vec::new(),
         })
     }
 
@@ -364,6 +367,20 @@ impl VirtioDevice for Console {
             return Err(ActivateError::BadActivate);
         }
 
+        for port_id in std::mem::take(&mut self.restored_ports) {
+            let Some(port) = self.ports.get_mut(port_id as usize) else {
+                error!("console: the snapshot has an unknown port {port_id}");
+                return Err(ActivateError::BadActivate);
+            };
+            port.start(
+                mem.clone(),
+                self.queues[port_id_to_queue_idx(QueueDirection::Rx, port_id as usize)].clone(),
+                self.queues[port_id_to_queue_idx(QueueDirection::Tx, port_id as usize)].clone(),
+                self.irq.clone(),
+                self.control.clone(),
+            );
+        }
+
         self.device_state = DeviceState::Activated(mem);
 
         Ok(())
@@ -376,6 +393,61 @@ impl VirtioDevice for Console {
         }
     }
 
+    fn snapshot_supported(&self) -> bool {
+        true
+    }
+
+    /// The state is the ports the guest opened, followed by the control messages it wasn't sent
+    /// yet.
+    fn save_state(&self) -> io::Result<Vec<u8>> {
+        let started: Vec<u32> = zip(0u32.., &self.ports)
+            .filter(|(_, port)| port.is_started())
+            .map(|(port_id, _)| port_id)
+            .collect();
+        let pending = self.control.pending();
+
+        let mut state = Vec::new();
+        state.extend((started.len() as u32).to_le_bytes());
+        for port_id in started {
+            state.extend(port_id.to_le_bytes());
+        }
+        state.extend((pending.len() as u32).to_le_bytes());
+        for payload in pending {
+            state.extend((payload.len() as u32).to_le_bytes());
+            state.extend(payload);
+        }
+        Ok(state)
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> io::Result<()> {
+        fn take<'a>(state: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+            if state.len() < len {
+                return Err(io::Error::from(io::ErrorKind::InvalidData));
+            }
+            let (bytes, rest) = state.split_at(len);
+            *state = rest;
+            Ok(bytes)
+        }
+        fn take_u32(state: &mut &[u8]) -> io::Result<u32> {
+            Ok(u32::from_le_bytes(take(state, 4)?.try_into().unwrap()))
+        }
+
+        let mut state = state;
+        let mut started = Vec::new();
+        for _ in 0..take_u32(&mut state)? {
+            started.push(take_u32(&mut state)?);
+        }
+        let mut pending = Vec::new();
+        for _ in 0..take_u32(&mut state)? {
+            let len = take_u32(&mut state)? as usize;
+            pending.push(take(&mut state, len)?.to_vec());
+        }
+
+        self.restored_ports = started;
+        self.control.restore(pending);
+        Ok(())
+    }
+
     fn reset(&mut self) -> bool {
         // Strictly speaking, we should also unsubscribe the queue
         // events, resubscribe the activate eventfd and deactivate
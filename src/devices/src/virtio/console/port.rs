@@ -86,6 +86,11 @@ impl Port {
         self.represents_console
     }
 
+    /// Returns whether the guest opened the port, which started its I/O threads.
+    pub fn is_started(&self) -> bool {
+        matches!(self.state, PortState::Active { .. })
+    }
+
     pub fn notify_rx(&self) {
         if let PortState::Active {
             rx_thread: Some(handle),
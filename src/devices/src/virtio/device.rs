@@ -5,6 +5,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
+use std::io;
 use std::sync::{atomic::AtomicUsize, Arc};
 
 use super::{ActivateResult, Queue};
@@ -123,6 +124,28 @@ pub trait VirtioDevice: AsAny + Send {
     fn shm_region(&self) -> Option<&VirtioShmRegion> {
         None
     }
+
+    /// Returns whether the device can be snapshotted. Devices keeping state on the host that
+    /// `save_state` doesn't return, like connections or requests in flight, can't be.
+    fn snapshot_supported(&self) -> bool {
+        false
+    }
+
+    /// Returns the state the device keeps on the host, to snapshot it once its queues are
+    /// quiesced. Devices that keep nothing the guest relies on across requests have no state.
+    fn save_state(&self) -> io::Result<Vec<u8>> {
+        Ok(Vec::new())
+    }
+
+    /// Sets the state returned by `save_state` for a device restored from a snapshot, before it's
+    /// activated.
+    fn restore_state(&mut self, state: &[u8]) -> io::Result<()> {
+        if state.is_empty() {
+            Ok(())
+        } else {
+            Err(io::Error::from_raw_os_error(libc::ENOTSUP))
+        }
+    }
 }
 
 pub trait VmmExitObserver: Send {
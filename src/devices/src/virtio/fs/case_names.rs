@@ -0,0 +1,101 @@
+//! The case-insensitive lookups of the names of the entries of an overlay.
+//!
+//! The layers of an image extracted on a case-insensitive volume, such as the default APFS ones of
+//! macOS, may hold their entries under another case than the one the files of the image refer to
+//! them with: the volume keeps the case of the first entry extracted under a name, and the later
+//! entries of the same name in another case land in it. Linux guests look the names up byte for
+//! byte, and miss these entries.
+//!
+//! With `case_insensitive` set in the config of an overlay, a lookup of a name missing from a
+//! directory falls back to the entry of the directory whose name is the same once its case is
+//! folded, and the entry is then known by its name in the layers. The names the guest sees are
+//! still case-sensitive: an entry is only ever listed under its name in the layers, and a name
+//! whose case folds to the one of two entries of the directory, as the entries of a layer may
+//! collide with the ones of the layers below it, is a conflict that fails the lookup rather than
+//! picking either entry.
+
+use std::ffi::CString;
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Returns `name` with its case folded, by lowercasing its characters if it is UTF-8 and its ASCII
+/// letters otherwise.
+pub(crate) fn fold(name: &[u8]) -> Vec<u8> {
+    match std::str::from_utf8(name) {
+        Ok(name) => name.to_lowercase().into_bytes(),
+        Err(_) => name.to_ascii_lowercase(),
+    }
+}
+
+/// Returns the name among `names` to look `name` up as: `name` itself if it is listed, or else the
+/// only one whose case folds to the same name, or `None` if there is none. The first two names
+/// that fold to the same name are returned as the error if there are more.
+pub(crate) fn find_folded<I>(names: I, name: &[u8]) -> Result<Option<CString>, (CString, CString)>
+where
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+{
+    let folded = fold(name);
+    let mut found: Option<CString> = None;
+    let mut conflict = None;
+    for other in names {
+        let other = other.as_ref();
+        if other == name {
+            return Ok(CString::new(other).ok());
+        }
+        if fold(other) != folded {
+            continue;
+        }
+        let Ok(other) = CString::new(other) else {
+            continue;
+        };
+        match &found {
+            None => found = Some(other),
+            Some(first) if conflict.is_none() => conflict = Some((first.clone(), other)),
+            Some(_) => (),
+        }
+    }
+
+    match conflict {
+        Some(conflict) => Err(conflict),
+        None => Ok(found),
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn find() {
+        let found = |names: &[&str], name: &str| {
+            find_folded(names, name.as_bytes()).map(|found| found.map(CString::into_bytes))
+        };
+
+        // The exact name is preferred over the ones differing by case, even colliding ones
+        assert_eq!(
+            found(&["Makefile", "makefile"], "makefile"),
+            Ok(Some(b"makefile".to_vec()))
+        );
+        assert_eq!(
+            found(&["a", "Makefile"], "MAKEFILE"),
+            Ok(Some(b"Makefile".to_vec()))
+        );
+        assert_eq!(
+            found(&["\u{c9}t\u{c9}"], "\u{e9}t\u{e9}"),
+            Ok(Some("\u{c9}t\u{c9}".into()))
+        );
+        assert_eq!(found(&["makefile.in"], "Makefile"), Ok(None));
+
+        // Two names differing by case collide for any other case
+        let conflict = found(&["Makefile", "MAKEFILE", "makefile"], "makeFile").unwrap_err();
+        assert_eq!(conflict.0.as_bytes(), b"Makefile");
+        assert_eq!(conflict.1.as_bytes(), b"MAKEFILE");
+    }
+}
@@ -0,0 +1,297 @@
+//! Coalescing of the small sequential writes of the guest into larger host writes.
+//!
+//! A guest writing a file in small pieces, like a log written a line or a byte at a time, costs a
+//! host write per FUSE request. The coalescer buffers the small writes that continue the previous
+//! one on the same handle instead, and writes the buffer to the host at once: when it is full, when
+//! a write doesn't continue it, when the guest flushes, syncs or closes the handle, or once it has
+//! waited for a while. The server flushes the buffers of a file before any other request that may
+//! observe it, so the guest never sees the file without its writes.
+//!
+//! The guest already considers the buffered writes complete, so an error writing them to the host
+//! is reported by the next flush, sync or release of the handle, like the errors of writeback.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::fs::FileExt;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::bindings;
+use super::filesystem::{Context, FileSystem, ZeroCopyReader};
+use super::fuse::{WRITE_CACHE, WRITE_KILL_PRIV};
+use super::{FsImpl, FsWriteCoalescing};
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The open flags of the files whose writes must reach the host before they're acknowledged.
+const UNBUFFERED_FLAGS: u32 =
+    (bindings::LINUX_O_SYNC | bindings::LINUX_O_DSYNC | bindings::LINUX_O_DIRECT) as u32;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// Buffers the small sequential writes of the guest on each handle. A disabled coalescer buffers
+/// nothing.
+#[derive(Default)]
+pub(crate) struct WriteCoalescer(Option<Arc<CoalescerState>>);
+
+struct CoalescerState {
+    fs: Arc<FsImpl>,
+    config: FsWriteCoalescing,
+    /// The buffers of the handles, keyed by inode and handle
+    buffers: Mutex<HashMap<(u64, u64), WriteBuffer>>,
+}
+
+struct WriteBuffer {
+    ctx: Context,
+    flags: u32,
+    offset: u64,
+    data: Vec<u8>,
+    /// When the first write in the buffer was acknowledged
+    since: Instant,
+    /// The first error hit writing the buffer to the host, not yet reported
+    error: Option<io::Error>,
+}
+
+/// Hands the data of a buffer to the file system.
+struct BufferReader<'a>(&'a [u8]);
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl WriteCoalescer {
+    /// Creates a coalescer writing the buffered writes to `fs`, or a disabled one if `config` is
+    /// `None`. The buffers are written to the host when the coalescer is dropped.
+    pub(crate) fn new(fs: &Arc<FsImpl>, config: Option<FsWriteCoalescing>) -> Self {
+        let Some(config) = config else {
+            return Self::default();
+        };
+
+        let state = Arc::new(CoalescerState {
+            fs: fs.clone(),
+            config,
+            buffers: Mutex::new(HashMap::new()),
+        });
+        let weak_state = Arc::downgrade(&state);
+        thread::Builder::new()
+            .name("fs coalesce".into())
+            .spawn(move || flush_periodically(weak_state, config.delay))
+            .unwrap();
+
+        WriteCoalescer(Some(state))
+    }
+
+    /// Whether a write of `size` bytes, with the `write_flags` of the request and the open `flags`
+    /// of the file, may be buffered. Writeback is made of whole pages already, and the writes
+    /// dropping privileges need the credentials of the process that made them.
+    pub(crate) fn accepts(&self, size: u32, write_flags: u32, flags: u32) -> bool {
+        let Some(state) = &self.0 else {
+            return false;
+        };
+
+        size <= state.config.buffer_size
+            && write_flags & (WRITE_CACHE | WRITE_KILL_PRIV) == 0
+            && flags & UNBUFFERED_FLAGS == 0
+    }
+
+    /// Buffers the write of `data` at `offset` through `handle` of `inode`, after writing the
+    /// buffers of `inode` it doesn't continue to the host.
+    pub(crate) fn write(
+        &self,
+        ctx: Context,
+        inode: u64,
+        handle: u64,
+        offset: u64,
+        data: &[u8],
+        flags: u32,
+    ) {
+        let Some(state) = &self.0 else {
+            return;
+        };
+
+        let buffer_size = state.config.buffer_size as usize;
+        let mut buffers = state.buffers.lock().unwrap();
+        for (&(other_inode, other_handle), buffer) in buffers.iter_mut() {
+            if other_inode == inode
+                && (other_handle != handle || !buffer.continued_by(offset, data.len(), buffer_size))
+            {
+                state.flush(other_inode, other_handle, buffer);
+            }
+        }
+
+        let buffer = buffers
+            .entry((inode, handle))
+            .or_insert_with(|| WriteBuffer::new(ctx, offset, flags));
+        buffer.push(ctx, offset, data, flags);
+        if buffer.data.len() >= buffer_size {
+            state.flush(inode, handle, buffer);
+        }
+        buffers.retain(|_, buffer| !buffer.is_idle());
+    }
+
+    /// Writes the buffers of `inode` to the host.
+    pub(crate) fn flush_inode(&self, inode: u64) {
+        if let Some(state) = &self.0 {
+            state.flush_matching(|buffer_inode, _| buffer_inode == inode);
+        }
+    }
+
+    /// Writes all the buffers to the host.
+    pub(crate) fn flush_all(&self) {
+        if let Some(state) = &self.0 {
+            state.flush_matching(|_, _| true);
+        }
+    }
+
+    /// Returns the error hit writing the buffered writes of `handle` of `inode` to the host, if
+    /// any, which is then considered reported.
+    pub(crate) fn take_error(&self, inode: u64, handle: u64) -> Option<io::Error> {
+        let state = self.0.as_ref()?;
+        let mut buffers = state.buffers.lock().unwrap();
+        let buffer = buffers.get_mut(&(inode, handle))?;
+        let error = buffer.error.take();
+        if buffer.is_idle() {
+            buffers.remove(&(inode, handle));
+        }
+        error
+    }
+}
+
+impl Drop for WriteCoalescer {
+    fn drop(&mut self) {
+        let Some(state) = &self.0 else {
+            return;
+        };
+
+        state.flush_matching(|_, _| true);
+        for ((inode, _), buffer) in state.buffers.lock().unwrap().drain() {
+            if let Some(err) = buffer.error {
+                warn!("lost the buffered writes of inode {inode}: {err}");
+            }
+        }
+    }
+}
+
+impl CoalescerState {
+    /// Writes the buffers for which `pred` returns true to the host.
+    fn flush_matching(&self, pred: impl Fn(u64, &WriteBuffer) -> bool) {
+        let mut buffers = self.buffers.lock().unwrap();
+        for (&(inode, handle), buffer) in buffers.iter_mut() {
+            if pred(inode, buffer) {
+                self.flush(inode, handle, buffer);
+            }
+        }
+        buffers.retain(|_, buffer| !buffer.is_idle());
+    }
+
+    /// Writes `buffer` to the host, keeping the error hit, if any, to report it later.
+    fn flush(&self, inode: u64, handle: u64, buffer: &mut WriteBuffer) {
+        let mut written = 0;
+        while written < buffer.data.len() {
+            let res = self.fs.write(
+                buffer.ctx,
+                inode,
+                handle,
+                BufferReader(&buffer.data[written..]),
+                (buffer.data.len() - written) as u32,
+                buffer.offset + written as u64,
+                None,
+                false,
+                false,
+                buffer.flags,
+            );
+            let err = match res {
+                Ok(0) => io::Error::from(io::ErrorKind::WriteZero),
+                Ok(count) => {
+                    written += count;
+                    continue;
+                }
+                Err(err) => err,
+            };
+
+            debug!("buffered writes of inode {inode} failed: {err}");
+            if buffer.error.is_none() {
+                buffer.error = Some(err);
+            }
+            break;
+        }
+        buffer.data.clear();
+    }
+}
+
+impl WriteBuffer {
+    fn new(ctx: Context, offset: u64, flags: u32) -> Self {
+        WriteBuffer {
+            ctx,
+            flags,
+            offset,
+            data: Vec::new(),
+            since: Instant::now(),
+            error: None,
+        }
+    }
+
+    /// Whether a write of `len` bytes at `offset` continues the buffered ones and fits in the
+    /// buffer.
+    fn continued_by(&self, offset: u64, len: usize, buffer_size: usize) -> bool {
+        self.data.is_empty()
+            || (offset == self.offset + self.data.len() as u64
+                && self.data.len() + len <= buffer_size)
+    }
+
+    fn push(&mut self, ctx: Context, offset: u64, data: &[u8], flags: u32) {
+        if self.data.is_empty() {
+            self.ctx = ctx;
+            self.offset = offset;
+            self.flags = flags;
+            self.since = Instant::now();
+        }
+        self.data.extend_from_slice(data);
+    }
+
+    /// Whether the buffer holds neither writes nor an error to report.
+    fn is_idle(&self) -> bool {
+        self.data.is_empty() && self.error.is_none()
+    }
+}
+
+impl Read for BufferReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl ZeroCopyReader for BufferReader<'_> {
+    fn read_to(&mut self, f: &File, count: usize, off: u64) -> io::Result<usize> {
+        let len = count.min(self.0.len());
+        let written = f.write_at(&self.0[..len], off)?;
+        if written == 0 && len > 0 {
+            return Err(io::Error::from(io::ErrorKind::WriteZero));
+        }
+        self.0 = &self.0[written..];
+        Ok(written)
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// The body of the thread writing the buffers that waited for long enough to the host.
+fn flush_periodically(state: Weak<CoalescerState>, delay: Duration) {
+    loop {
+        thread::sleep(delay / 2);
+
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        let now = Instant::now();
+        state.flush_matching(|_, buffer| now.duration_since(buffer.since) >= delay);
+    }
+}
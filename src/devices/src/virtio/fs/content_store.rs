@@ -0,0 +1,183 @@
+//! Content-addressed store of the data of copied up files.
+//!
+//! The store is a host directory shared by the top layers of many overlays, holding one read-only
+//! object per distinct file content, named after the SHA-256 digest of the content. A copy-up whose
+//! source content is already in the store clones the object into the top layer instead of copying
+//! the source, so that the overlays editing the same files share their data blocks. Objects are
+//! only ever cloned (reflinked), never hard linked, so that each copy keeps its own owner, mode and
+//! extended attributes, and the file system breaks the sharing when a copy is modified.
+
+use std::{
+    ffi::CString,
+    fmt::Write,
+    fs::{self, File},
+    io,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileExt, PermissionsExt},
+    },
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use sha2::{Digest, Sha256};
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// Files smaller than this are not worth storing, as they don't fill a single data block.
+pub(crate) const MIN_SIZE: i64 = 4096;
+
+/// The prefix of the temporary files objects are written to before being renamed into place.
+const TMP_PREFIX: &str = "tmp.";
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// A content-addressed store rooted at a host directory.
+pub(crate) struct ContentStore {
+    dir: PathBuf,
+    next_tmp: AtomicU64,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl ContentStore {
+    /// Opens the store rooted at `dir`, creating the directory if needed.
+    pub(crate) fn new(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(ContentStore {
+            dir,
+            next_tmp: AtomicU64::new(0),
+        })
+    }
+
+    /// Returns the hex encoded SHA-256 digest of the contents of `file`.
+    pub(crate) fn digest(file: &File) -> io::Result<String> {
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; 1 << 20];
+        let mut offset = 0;
+        loop {
+            let len = file.read_at(&mut buf, offset)?;
+            if len == 0 {
+                break;
+            }
+            hasher.update(&buf[..len]);
+            offset += len as u64;
+        }
+
+        Ok(hasher.finalize().iter().fold(String::new(), |mut hex, b| {
+            let _ = write!(hex, "{b:02x}");
+            hex
+        }))
+    }
+
+    /// Returns the path of the object with the given digest if it is in the store.
+    pub(crate) fn get(&self, digest: &str) -> Option<CString> {
+        let path = self.object_path(digest);
+        path.is_file()
+            .then(|| CString::new(path.as_os_str().as_bytes()).ok())
+            .flatten()
+    }
+
+    /// Adds an object with the given digest to the store. `clone` must create the file at the
+    /// given path with the content, which is then made read-only and renamed into place, so that
+    /// concurrent insertions of the same content are harmless.
+    pub(crate) fn insert(
+        &self,
+        digest: &str,
+        clone: impl FnOnce(&CString) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let object = self.object_path(digest);
+        if let Some(parent) = object.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let tmp = self.dir.join(format!(
+            "{TMP_PREFIX}{}.{}",
+            std::process::id(),
+            self.next_tmp.fetch_add(1, Ordering::Relaxed)
+        ));
+        let tmp_cstr = CString::new(tmp.as_os_str().as_bytes())
+            .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+
+        let res = clone(&tmp_cstr).and_then(|_| {
+            fs::set_permissions(&tmp, fs::Permissions::from_mode(0o444))?;
+            fs::rename(&tmp, &object)
+        });
+        if res.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+
+        res
+    }
+
+    fn object_path(&self, digest: &str) -> PathBuf {
+        self.dir.join(&digest[..2]).join(digest)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{ffi::OsStr, io::Write as _, path::Path};
+
+    use super::*;
+
+    fn digest_of(data: &[u8]) -> String {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(data).unwrap();
+        ContentStore::digest(&file).unwrap()
+    }
+
+    #[test]
+    fn digest() {
+        assert_eq!(
+            digest_of(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            digest_of(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            digest_of(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(
+            digest_of(&[b'a'; 1_000_000]),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+
+    #[test]
+    fn insert() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ContentStore::new(dir.path().join("store")).unwrap();
+        let digest = digest_of(b"abc");
+        assert!(store.get(&digest).is_none());
+
+        store
+            .insert(&digest, |path| {
+                fs::write(OsStr::from_bytes(path.as_bytes()), b"abc")
+            })
+            .unwrap();
+        let object = store.get(&digest).unwrap();
+        let path = Path::new(OsStr::from_bytes(object.as_bytes()));
+        assert_eq!(fs::read(path).unwrap(), b"abc");
+        assert!(fs::metadata(path).unwrap().permissions().readonly());
+
+        // A failed insertion leaves nothing behind
+        store
+            .insert(&digest_of(b"abcd"), |_| {
+                Err(io::Error::from_raw_os_error(libc::EIO))
+            })
+            .unwrap_err();
+        assert!(fs::read_dir(dir.path().join("store"))
+            .unwrap()
+            .all(|entry| !entry.unwrap().file_name().as_bytes().starts_with(b"tmp.")));
+    }
+}
@@ -0,0 +1,308 @@
+//! Helpers shared by the overlay copy-up implementations.
+//!
+//! `PathLocks` serializes the copy-up of a given path, so that two requests racing to materialize
+//! the same entry in the top layer don't both copy it. It also orders the requests changing the
+//! same directory entry, such as two unlinks racing to create the same whiteout. The keys are
+//! spread over stripes by their hash, each with its own lock and its own waiters, so that the
+//! requests on unrelated paths neither contend nor wake each other up. `run_task_queue` processes
+//! a tree of tasks, such as the directories of a subtree being copied up, with a bounded number of
+//! threads.
+
+use std::{
+    collections::{hash_map::RandomState, HashSet, VecDeque},
+    hash::{BuildHasher, Hash},
+    io,
+    sync::{Condvar, Mutex},
+    thread,
+};
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The number of stripes of a `PathLocks`
+const STRIPES: usize = 64;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// A set of locks keyed by path, taken one at a time by the copy-up of each path segment, or
+/// keyed by directory entry.
+pub(crate) struct PathLocks<K> {
+    stripes: Box<[Stripe<K>]>,
+    hasher: RandomState,
+}
+
+/// The keys held among those hashing to a stripe, and the requests waiting for them.
+struct Stripe<K> {
+    held: Mutex<HashSet<K>>,
+    released: Condvar,
+}
+
+/// Holds the locks of one or more paths until dropped.
+pub(crate) struct PathLockGuard<'a, K: Hash + Eq> {
+    locks: &'a PathLocks<K>,
+    /// The keys held, with their stripe, by stripe
+    keys: Vec<(usize, K)>,
+}
+
+/// The state shared by the threads of `run_task_queue`.
+struct TaskQueue<T> {
+    tasks: VecDeque<T>,
+    /// Number of tasks either queued or being processed
+    pending: usize,
+    error: Option<io::Error>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl<K: Hash + Eq + Clone> PathLocks<K> {
+    pub(crate) fn new() -> Self {
+        let stripes = (0..STRIPES)
+            .map(|_| Stripe {
+                held: Mutex::new(HashSet::new()),
+                released: Condvar::new(),
+            })
+            .collect();
+
+        PathLocks {
+            stripes,
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Takes the lock of `key`, waiting for any other holder to drop it first.
+    pub(crate) fn lock(&self, key: K) -> PathLockGuard<'_, K> {
+        self.lock_all(vec![key])
+    }
+
+    /// Takes the locks of all of `keys` at once, waiting until none of them is held. The keys of a
+    /// stripe are taken together, and the stripes one after the other in their order. Finding a
+    /// key held, the keys of the previous stripes are released until it is free, so that they
+    /// don't hold up other requests in the meantime, and this can't deadlock with another holder
+    /// of the same keys, whatever their order.
+    pub(crate) fn lock_all(&self, keys: Vec<K>) -> PathLockGuard<'_, K> {
+        let mut keys: Vec<(usize, K)> = keys
+            .into_iter()
+            .map(|key| (self.hasher.hash_one(&key) as usize % STRIPES, key))
+            .collect();
+        keys.sort_by_key(|(stripe, _)| *stripe);
+
+        let mut taken = 0;
+        while taken < keys.len() {
+            let stripe_idx = keys[taken].0;
+            let end = taken + keys[taken..].partition_point(|(stripe, _)| *stripe == stripe_idx);
+            let group = &keys[taken..end];
+
+            let stripe = &self.stripes[stripe_idx];
+            let mut held = stripe.held.lock().unwrap();
+            let is_held = |held: &HashSet<K>| group.iter().any(|(_, key)| held.contains(key));
+            if is_held(&held) && taken > 0 {
+                drop(held);
+                self.release(&keys[..taken]);
+                taken = 0;
+                held = stripe.held.lock().unwrap();
+                while is_held(&held) {
+                    held = stripe.released.wait(held).unwrap();
+                }
+                continue;
+            }
+            while is_held(&held) {
+                held = stripe.released.wait(held).unwrap();
+            }
+            held.extend(group.iter().map(|(_, key)| key.clone()));
+            taken = end;
+        }
+
+        PathLockGuard { locks: self, keys }
+    }
+}
+
+impl<K: Hash + Eq> PathLocks<K> {
+    /// Releases `keys`, sorted by stripe, waking up the requests waiting for their stripes.
+    fn release(&self, keys: &[(usize, K)]) {
+        for group in keys.chunk_by(|a, b| a.0 == b.0) {
+            let stripe = &self.stripes[group[0].0];
+            let mut held = stripe.held.lock().unwrap();
+            for (_, key) in group {
+                held.remove(key);
+            }
+            drop(held);
+            stripe.released.notify_all();
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> Default for PathLocks<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Trait Implementations
+//--------------------------------------------------------------------------------------------------
+
+impl<K: Hash + Eq> Drop for PathLockGuard<'_, K> {
+    fn drop(&mut self) {
+        self.locks.release(&self.keys);
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Runs `task` on `initial` and on every task it returns, using up to `workers` threads. Tasks
+/// returned by a task are independent from each other and may run in any order. Processing stops
+/// at the first error, which is returned once the tasks already running have completed.
+pub(crate) fn run_task_queue<T, F>(workers: usize, initial: Vec<T>, task: F) -> io::Result<()>
+where
+    T: Send,
+    F: Fn(T) -> io::Result<Vec<T>> + Sync,
+{
+    if workers <= 1 {
+        let mut tasks = initial;
+        while let Some(next) = tasks.pop() {
+            tasks.extend(task(next)?);
+        }
+        return Ok(());
+    }
+
+    let queue = Mutex::new(TaskQueue {
+        pending: initial.len(),
+        tasks: initial.into(),
+        error: None,
+    });
+    let changed = Condvar::new();
+
+    let worker = || loop {
+        let next = {
+            let mut queue = queue.lock().unwrap();
+            loop {
+                if queue.error.is_some() || queue.pending == 0 {
+                    return;
+                }
+                if let Some(next) = queue.tasks.pop_front() {
+                    break next;
+                }
+                queue = changed.wait(queue).unwrap();
+            }
+        };
+
+        let result = task(next);
+
+        let mut queue = queue.lock().unwrap();
+        match result {
+            Ok(tasks) => {
+                queue.pending += tasks.len();
+                queue.tasks.extend(tasks);
+            }
+            Err(e) => {
+                queue.error.get_or_insert(e);
+            }
+        }
+        queue.pending -= 1;
+        changed.notify_all();
+    };
+
+    thread::scope(|s| {
+        for _ in 1..workers {
+            s.spawn(worker);
+        }
+        worker();
+    });
+
+    match queue.into_inner().unwrap().error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn task_queue() {
+        // Every task below depth 3 spawns two children, for 15 tasks in total
+        for workers in [1, 4] {
+            let count = AtomicUsize::new(0);
+            run_task_queue(workers, vec![0u32], |depth| {
+                count.fetch_add(1, Ordering::Relaxed);
+                Ok(if depth < 3 {
+                    vec![depth + 1, depth + 1]
+                } else {
+                    vec![]
+                })
+            })
+            .unwrap();
+            assert_eq!(count.load(Ordering::Relaxed), 15);
+        }
+
+        let err = run_task_queue(4, vec![0u32], |depth| {
+            if depth == 2 {
+                return Err(io::Error::from_raw_os_error(libc::EIO));
+            }
+            Ok(vec![depth + 1, depth + 1])
+        })
+        .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+    }
+
+    #[test]
+    fn path_locks() {
+        let locks = Arc::new(PathLocks::new());
+        let guard = locks.lock(vec![1, 2]);
+
+        // Other paths are not held up
+        drop(locks.lock(vec![1]));
+
+        let waiter = {
+            let locks = locks.clone();
+            thread::spawn(move || drop(locks.lock(vec![1, 2])))
+        };
+        thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+
+        drop(guard);
+        waiter.join().unwrap();
+
+        // Several keys are taken together, in any order, and the same key may be repeated
+        let guard = locks.lock_all(vec![vec![3], vec![4], vec![3]]);
+        let waiter = {
+            let locks = locks.clone();
+            thread::spawn(move || drop(locks.lock_all(vec![vec![4], vec![5]])))
+        };
+        thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+        drop(locks.lock(vec![5]));
+
+        drop(guard);
+        waiter.join().unwrap();
+    }
+
+    #[test]
+    fn path_locks_order() {
+        // Requests taking the same keys in different orders don't deadlock
+        let locks = PathLocks::new();
+        let keys: Vec<u32> = (0..16).collect();
+        thread::scope(|s| {
+            for i in 0..4 {
+                let (locks, mut keys) = (&locks, keys.clone());
+                s.spawn(move || {
+                    for j in 0..200 {
+                        keys.rotate_left(i + j % 3);
+                        drop(locks.lock_all(keys.clone()));
+                    }
+                });
+            }
+        });
+    }
+}
@@ -0,0 +1,201 @@
+//! The translation of the credentials of the guest to the host ones a share runs its requests with.
+//!
+//! The server translates the user and group of every request before it reaches the file system,
+//! and the owners given by a chown, so the file systems only ever see host credentials, which they
+//! create files with and check permissions against. The owners of the files are translated back
+//! before their attributes are handed to the guest.
+//!
+//! The access rules of a share are checked against the guest credentials, before the translation.
+
+use std::fmt;
+use std::sync::Arc;
+
+use super::bindings;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The ID the users and groups without a mapping translate to, as the kernel's `overflowuid`.
+pub const FS_OVERFLOW_ID: u32 = 65534;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// Translates between the credentials of the guest and those of the host for a share.
+///
+/// The translations are called from the worker thread of the share for every request, so they
+/// should be cheap.
+pub trait CredentialMapper: fmt::Debug + Send + Sync {
+    /// Returns the host user the requests of the guest user `uid` run as.
+    fn host_uid(&self, uid: u32) -> u32;
+
+    /// Returns the host group the requests of the guest group `gid` run as.
+    fn host_gid(&self, gid: u32) -> u32;
+
+    /// Returns the guest user the files of the host user `uid` are owned by.
+    fn guest_uid(&self, uid: u32) -> u32;
+
+    /// Returns the guest group the files of the host group `gid` are owned by.
+    fn guest_gid(&self, gid: u32) -> u32;
+}
+
+/// The credential translation of a share.
+pub type FsCredentials = Arc<dyn CredentialMapper>;
+
+/// Runs the requests with the credentials of the guest, unchanged. This is the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FsIdentity;
+
+/// Runs the requests of every guest user and group as the same host user and group, as NFS's
+/// `all_squash`. The owners of the files are reported to the guest as they are on the host, so
+/// `uid` and `gid` are usually those of the guest user the share is meant for.
+#[derive(Clone, Copy, Debug)]
+pub struct FsSquashAll {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Translates the IDs of the guest by ranges, as the ID maps of a user namespace. The guest IDs
+/// outside the ranges run as `FS_OVERFLOW_ID`, and the host IDs outside them are reported to the
+/// guest as `FS_OVERFLOW_ID`.
+#[derive(Clone, Debug, Default)]
+pub struct FsIdMap {
+    pub uids: Vec<FsIdRange>,
+    pub gids: Vec<FsIdRange>,
+}
+
+/// `count` consecutive guest IDs starting at `guest_start`, mapped to as many host IDs starting at
+/// `host_start`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FsIdRange {
+    pub guest_start: u32,
+    pub host_start: u32,
+    pub count: u32,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl CredentialMapper for FsIdentity {
+    fn host_uid(&self, uid: u32) -> u32 {
+        uid
+    }
+
+    fn host_gid(&self, gid: u32) -> u32 {
+        gid
+    }
+
+    fn guest_uid(&self, uid: u32) -> u32 {
+        uid
+    }
+
+    fn guest_gid(&self, gid: u32) -> u32 {
+        gid
+    }
+}
+
+impl CredentialMapper for FsSquashAll {
+    fn host_uid(&self, _uid: u32) -> u32 {
+        self.uid
+    }
+
+    fn host_gid(&self, _gid: u32) -> u32 {
+        self.gid
+    }
+
+    fn guest_uid(&self, uid: u32) -> u32 {
+        uid
+    }
+
+    fn guest_gid(&self, gid: u32) -> u32 {
+        gid
+    }
+}
+
+impl CredentialMapper for FsIdMap {
+    fn host_uid(&self, uid: u32) -> u32 {
+        map_id(&self.uids, uid, |r| (r.guest_start, r.host_start))
+    }
+
+    fn host_gid(&self, gid: u32) -> u32 {
+        map_id(&self.gids, gid, |r| (r.guest_start, r.host_start))
+    }
+
+    fn guest_uid(&self, uid: u32) -> u32 {
+        map_id(&self.uids, uid, |r| (r.host_start, r.guest_start))
+    }
+
+    fn guest_gid(&self, gid: u32) -> u32 {
+        map_id(&self.gids, gid, |r| (r.host_start, r.guest_start))
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Translates the owners in the attributes `st` of a host file to guest ones.
+pub(crate) fn guest_attr(credentials: &dyn CredentialMapper, st: &mut bindings::stat64) {
+    st.st_uid = credentials.guest_uid(st.st_uid);
+    st.st_gid = credentials.guest_gid(st.st_gid);
+}
+
+/// Translates `id` by the first range of `ranges` holding it, read from the side given by
+/// `bounds` as `(from, to)`.
+fn map_id(ranges: &[FsIdRange], id: u32, bounds: impl Fn(&FsIdRange) -> (u32, u32)) -> u32 {
+    ranges
+        .iter()
+        .find_map(|range| {
+            let (from, to) = bounds(range);
+            let offset = id
+                .checked_sub(from)
+                .filter(|offset| *offset < range.count)?;
+            to.checked_add(offset)
+        })
+        .unwrap_or(FS_OVERFLOW_ID)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn id_map() {
+        let map = FsIdMap {
+            uids: vec![
+                FsIdRange {
+                    guest_start: 0,
+                    host_start: 100000,
+                    count: 1000,
+                },
+                FsIdRange {
+                    guest_start: 1000,
+                    host_start: 501,
+                    count: 1,
+                },
+            ],
+            gids: vec![FsIdRange {
+                guest_start: 0,
+                host_start: 200000,
+                count: 65536,
+            }],
+        };
+
+        assert_eq!(map.host_uid(0), 100000);
+        assert_eq!(map.host_uid(999), 100999);
+        assert_eq!(map.host_uid(1000), 501);
+        assert_eq!(map.guest_uid(501), 1000);
+        assert_eq!(map.guest_uid(100010), 10);
+        assert_eq!(map.host_gid(20), 200020);
+        assert_eq!(map.guest_gid(200020), 20);
+
+        // The IDs outside the ranges overflow, both ways
+        assert_eq!(map.host_uid(1001), FS_OVERFLOW_ID);
+        assert_eq!(map.guest_uid(0), FS_OVERFLOW_ID);
+        assert_eq!(map.guest_gid(265536), FS_OVERFLOW_ID);
+        assert_eq!(FsIdMap::default().host_uid(0), FS_OVERFLOW_ID);
+    }
+}
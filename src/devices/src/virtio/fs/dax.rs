@@ -0,0 +1,373 @@
+//! Mappings of host files into the DAX window, guarded against the truncation of the files.
+//!
+//! Accessing a page of a shared file mapping past the end of the file raises `SIGBUS`, which
+//! kills the VMM. A host process may truncate a file after the guest mapped it, so the pages of
+//! the mapped files that are no longer backed are replaced with zeroed anonymous pages when they
+//! fault instead, whether the VMM faults on them, through a `SIGBUS` handler, or the guest does,
+//! which makes `KVM_RUN` fail with `EFAULT`. The guest then reads zeroes past the end of the file,
+//! and its writes there are dropped, until it sets the mapping up again.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::ptr::{self, null_mut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, Once, OnceLock, TryLockError};
+
+use libc::{c_int, c_void};
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The mapped files, keyed by their address.
+static MAPPINGS: Mutex<BTreeMap<usize, Mapping>> = Mutex::new(BTreeMap::new());
+
+/// The size of the pages, read once the `SIGBUS` handler is installed.
+static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// The `SIGBUS` action installed before ours, to which the faults outside of the mappings go.
+static PREVIOUS_ACTION: OnceLock<libc::sigaction> = OnceLock::new();
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// A file mapped into the DAX window.
+struct Mapping {
+    len: usize,
+    prot: c_int,
+    file: File,
+    offset: u64,
+    /// The length of the start of the mapping still backed by the file
+    backed: usize,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Maps `len` bytes of `file` from `offset` with `prot` at `addr`, or wherever the kernel places
+/// it if `addr` is null, and returns its address. The pages past the end of the file read as
+/// zeroes rather than raising `SIGBUS` if the file is truncated.
+pub(crate) fn map_file(
+    addr: *mut c_void,
+    len: usize,
+    prot: c_int,
+    file: &File,
+    offset: u64,
+) -> io::Result<*mut c_void> {
+    install_sigbus_handler();
+    let file = file.try_clone()?;
+
+    let flags = if addr.is_null() {
+        libc::MAP_SHARED
+    } else {
+        libc::MAP_SHARED | libc::MAP_FIXED
+    };
+    // Safe because the mapping is either placed by the kernel or replaces part of the DAX window,
+    // which nothing but the guest accesses.
+    let addr = unsafe {
+        libc::mmap(
+            addr,
+            len,
+            prot,
+            flags,
+            file.as_raw_fd(),
+            offset as libc::off_t,
+        )
+    };
+    if addr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut mappings = lock_mappings();
+    forget_locked(&mut mappings, addr as usize, len);
+    mappings.insert(
+        addr as usize,
+        Mapping {
+            len,
+            prot,
+            file,
+            offset,
+            backed: len,
+        },
+    );
+    Ok(addr)
+}
+
+/// Stops guarding the files mapped in the `len` bytes at `addr`, which are being unmapped or
+/// mapped to something else.
+pub(crate) fn forget_mappings(addr: *mut c_void, len: usize) {
+    forget_locked(&mut lock_mappings(), addr as usize, len);
+}
+
+/// Returns whether any file is mapped into the DAX window.
+pub(crate) fn has_mappings() -> bool {
+    !lock_mappings().is_empty()
+}
+
+/// Replaces the pages of the mapped files past their end with zeroed pages, after the guest
+/// faulted on one of them. Returns whether any page was replaced, or the fault came from
+/// somewhere else.
+pub fn zero_fill_truncated_mappings() -> bool {
+    let page_size = page_size();
+    let mut replaced = false;
+    for (&addr, mapping) in lock_mappings().iter_mut() {
+        let Ok(metadata) = mapping.file.metadata() else {
+            continue;
+        };
+        let backed = usize::try_from(metadata.len().saturating_sub(mapping.offset))
+            .unwrap_or(usize::MAX)
+            .checked_next_multiple_of(page_size)
+            .unwrap_or(usize::MAX);
+        if backed >= mapping.backed {
+            continue;
+        }
+
+        if let Err(err) = zero_fill(addr + backed, mapping.backed - backed, mapping.prot) {
+            error!("failed to replace the truncated pages of a DAX mapping: {err}");
+            continue;
+        }
+        debug!(
+            "replaced {} truncated bytes of the DAX mapping at {addr:x}",
+            mapping.backed - backed
+        );
+        mapping.backed = backed;
+        replaced = true;
+    }
+    replaced
+}
+
+/// Removes the parts of the mappings overlapping the `len` bytes at `addr`.
+fn forget_locked(mappings: &mut BTreeMap<usize, Mapping>, addr: usize, len: usize) {
+    let end = addr.saturating_add(len);
+    let overlapping: Vec<usize> = mappings
+        .range(..end)
+        .filter(|(&base, mapping)| base + mapping.len > addr)
+        .map(|(&base, _)| base)
+        .collect();
+
+    for base in overlapping {
+        let mapping = mappings.remove(&base).unwrap();
+        if base < addr {
+            if let Some(head) = mapping.slice(0, addr - base) {
+                mappings.insert(base, head);
+            }
+        }
+        if base + mapping.len > end {
+            if let Some(tail) = mapping.slice(end - base, base + mapping.len - end) {
+                mappings.insert(end, tail);
+            }
+        }
+    }
+}
+
+/// Installs the `SIGBUS` handler, once.
+fn install_sigbus_handler() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        PAGE_SIZE.store(page_size(), Ordering::Relaxed);
+
+        // Safe because the structures are plain data, and the handler only does async-signal-safe
+        // operations, as long as no thread faults while holding the lock of the mappings.
+        unsafe {
+            let mut previous: libc::sigaction = mem::zeroed();
+            if libc::sigaction(libc::SIGBUS, ptr::null(), &mut previous) < 0 {
+                warn!(
+                    "failed to get the SIGBUS action: {}",
+                    io::Error::last_os_error()
+                );
+                return;
+            }
+            let _ = PREVIOUS_ACTION.set(previous);
+
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = handle_sigbus as *const () as usize;
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+            if libc::sigaction(libc::SIGBUS, &action, null_mut()) < 0 {
+                warn!(
+                    "failed to install the SIGBUS handler: {}",
+                    io::Error::last_os_error()
+                );
+            }
+        }
+    });
+}
+
+/// Replaces the faulting page with a zeroed one if it belongs to a mapped file, and hands the
+/// signal to the previous action otherwise.
+extern "C" fn handle_sigbus(num: c_int, info: *mut libc::siginfo_t, context: *mut c_void) {
+    // Safe because the kernel hands a valid `siginfo_t` to the handlers with `SA_SIGINFO`.
+    let fault_addr = unsafe { fault_addr(info) };
+    if zero_fill_faulting_page(fault_addr) {
+        return;
+    }
+
+    let previous = PREVIOUS_ACTION.get();
+    match previous {
+        Some(action)
+            if action.sa_sigaction != libc::SIG_DFL && action.sa_sigaction != libc::SIG_IGN =>
+        {
+            // Safe because the previous action was installed as a handler of this kind.
+            unsafe {
+                if action.sa_flags & libc::SA_SIGINFO != 0 {
+                    let handler: extern "C" fn(c_int, *mut libc::siginfo_t, *mut c_void) =
+                        mem::transmute(action.sa_sigaction);
+                    handler(num, info, context);
+                } else {
+                    let handler: extern "C" fn(c_int) = mem::transmute(action.sa_sigaction);
+                    handler(num);
+                }
+            }
+        }
+        // Returning with the default action restored faults again, which then kills the process.
+        _ => unsafe {
+            libc::signal(libc::SIGBUS, libc::SIG_DFL);
+        },
+    }
+}
+
+/// Replaces the page at `fault_addr` with a zeroed one if it belongs to a mapped file.
+fn zero_fill_faulting_page(fault_addr: usize) -> bool {
+    // The lock is taken by spinning, as blocking isn't async-signal-safe. The thread holding it
+    // never faults on the mappings, so it releases it.
+    let mappings = loop {
+        match MAPPINGS.try_lock() {
+            Ok(mappings) => break mappings,
+            Err(TryLockError::Poisoned(err)) => break err.into_inner(),
+            Err(TryLockError::WouldBlock) => std::hint::spin_loop(),
+        }
+    };
+
+    let Some((&addr, mapping)) = mappings.range(..=fault_addr).next_back() else {
+        return false;
+    };
+    if fault_addr >= addr + mapping.len {
+        return false;
+    }
+
+    let page_size = PAGE_SIZE.load(Ordering::Relaxed);
+    zero_fill(fault_addr & !(page_size - 1), page_size, mapping.prot).is_ok()
+}
+
+/// Replaces the `len` bytes at `addr` with zeroed anonymous pages.
+fn zero_fill(addr: usize, len: usize, prot: c_int) -> io::Result<()> {
+    // Safe because the pages replaced are part of a mapped file, which the mapping owns.
+    let ret = unsafe {
+        libc::mmap(
+            addr as *mut c_void,
+            len,
+            prot,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED,
+            -1,
+            0,
+        )
+    };
+    if ret == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn lock_mappings() -> MutexGuard<'static, BTreeMap<usize, Mapping>> {
+    MAPPINGS.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+fn page_size() -> usize {
+    // Safe because it only reads a configuration value.
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn fault_addr(info: *mut libc::siginfo_t) -> usize {
+    (*info).si_addr() as usize
+}
+
+#[cfg(target_os = "macos")]
+unsafe fn fault_addr(info: *mut libc::siginfo_t) -> usize {
+    (*info).si_addr as usize
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl Mapping {
+    /// Returns the mapping of the `len` bytes at `start` into this one.
+    fn slice(&self, start: usize, len: usize) -> Option<Mapping> {
+        Some(Mapping {
+            len,
+            prot: self.prot,
+            file: self.file.try_clone().ok()?,
+            offset: self.offset + start as u64,
+            backed: self.backed.saturating_sub(start).min(len),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Write;
+
+    #[test]
+    fn truncated_file() {
+        let page_size = page_size();
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&vec![1; 4 * page_size]).unwrap();
+
+        let len = 4 * page_size;
+        let addr = map_file(null_mut(), len, libc::PROT_READ, &file, 0).unwrap() as *const u8;
+        assert!(!zero_fill_truncated_mappings());
+
+        // A page the VMM faults on reads as zeroes
+        file.set_len(page_size as u64 + 1).unwrap();
+        // Safe because the pages are mapped.
+        let read = |index: usize| unsafe { ptr::read_volatile(addr.add(index)) };
+        assert_eq!(read(page_size), 1);
+        assert_eq!(read(3 * page_size), 0);
+
+        // And so does every page past the end of the file, once the guest faulted
+        assert!(zero_fill_truncated_mappings());
+        assert!(!zero_fill_truncated_mappings());
+        assert_eq!((read(0), read(page_size), read(2 * page_size)), (1, 1, 0));
+
+        forget_mappings(addr as *mut c_void, len);
+        assert!(!lock_mappings().contains_key(&(addr as usize)));
+        // Safe because the pages are mapped, and no longer used.
+        unsafe { libc::munmap(addr as *mut c_void, len) };
+    }
+
+    #[test]
+    fn forget_part() {
+        let file = tempfile::tempfile().unwrap();
+        let mut mappings = BTreeMap::new();
+        mappings.insert(
+            0x1000,
+            Mapping {
+                len: 0x4000,
+                prot: libc::PROT_READ,
+                file,
+                offset: 0,
+                backed: 0x3000,
+            },
+        );
+
+        forget_locked(&mut mappings, 0x2000, 0x1000);
+        let parts: Vec<_> = mappings
+            .iter()
+            .map(|(&addr, m)| (addr, m.len, m.offset, m.backed))
+            .collect();
+        assert_eq!(
+            parts,
+            [
+                (0x1000, 0x1000, 0, 0x1000),
+                (0x3000, 0x2000, 0x2000, 0x1000)
+            ]
+        );
+    }
+}
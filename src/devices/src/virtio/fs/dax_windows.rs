@@ -0,0 +1,257 @@
+//! The windows of the DAX range mapped to host files, on the hosts where the device maps them.
+//!
+//! On macOS, every range of a file the guest sets up in the DAX range is a host mapping that the
+//! hypervisor adds to the guest memory, and it is tracked until the guest removes it. The guest may
+//! set a range up again without removing it first, e.g. to make a read-only range writable, so the
+//! windows it replaces are handed back to be unmapped rather than leaked. Windows adjacent both in
+//! the guest and on the host, over consecutive ranges of the same file, are coalesced into one,
+//! which a later removal may split again.
+//!
+//! The bytes mapped may be bounded. FUSE has no way to take a range back from the guest, which
+//! keeps accessing the windows it set up until it removes them, so none is ever evicted: setting
+//! up a window that doesn't fit in the bound fails, and the guest may remove some of its windows
+//! before trying again.
+
+use std::collections::BTreeMap;
+#[cfg(target_os = "macos")]
+use std::io;
+
+#[cfg(target_os = "macos")]
+use super::dax;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// The windows of a share, by guest address.
+#[derive(Debug, Default)]
+pub(crate) struct DaxWindows {
+    windows: BTreeMap<u64, Window>,
+    mapped: u64,
+    max_mapped: Option<u64>,
+}
+
+/// A range of a file mapped into the DAX range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Window {
+    pub(crate) host_addr: u64,
+    pub(crate) len: u64,
+    pub(crate) inode: u64,
+    pub(crate) foffset: u64,
+    pub(crate) writable: bool,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl DaxWindows {
+    /// Creates the windows of a share mapping at most `max_mapped` bytes, if set.
+    pub(crate) fn new(max_mapped: Option<u64>) -> Self {
+        DaxWindows {
+            max_mapped,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the number of bytes mapped.
+    pub(crate) fn mapped(&self) -> u64 {
+        self.mapped
+    }
+
+    /// Whether a window of `len` bytes at `guest_addr` fits in the bound, once the windows it
+    /// replaces are removed.
+    pub(crate) fn fits(&self, guest_addr: u64, len: u64) -> bool {
+        let Some(max_mapped) = self.max_mapped else {
+            return true;
+        };
+        let end = guest_addr + len;
+        let replaced: u64 = self
+            .windows
+            .range(..end)
+            .rev()
+            .take_while(|(&addr, w)| guest_addr < addr + w.len)
+            .map(|(&addr, w)| (addr + w.len).min(end) - addr.max(guest_addr))
+            .sum();
+        self.mapped - replaced + len <= max_mapped
+    }
+
+    /// Records the window at `guest_addr`, returning the parts of the windows it replaces, which
+    /// are no longer recorded.
+    pub(crate) fn insert(&mut self, guest_addr: u64, mut window: Window) -> Vec<(u64, Window)> {
+        let replaced = self.carve(guest_addr, window.len);
+        self.mapped += window.len;
+
+        let mut guest_addr = guest_addr;
+        if let Some((&prev_addr, prev)) = self.windows.range(..guest_addr).next_back() {
+            if prev_addr + prev.len == guest_addr && prev.continued_by(&window) {
+                window = Window {
+                    host_addr: prev.host_addr,
+                    len: prev.len + window.len,
+                    foffset: prev.foffset,
+                    ..window
+                };
+                self.windows.remove(&prev_addr);
+                guest_addr = prev_addr;
+            }
+        }
+        let next_addr = guest_addr + window.len;
+        if let Some(next) = self.windows.get(&next_addr) {
+            if window.continued_by(next) {
+                window.len += next.len;
+                self.windows.remove(&next_addr);
+            }
+        }
+        self.windows.insert(guest_addr, window);
+        replaced
+    }
+
+    /// Forgets the `len` bytes at `guest_addr`, returning the parts of the windows removed, or
+    /// `None` if no window covers `guest_addr`.
+    pub(crate) fn remove(&mut self, guest_addr: u64, len: u64) -> Option<Vec<(u64, Window)>> {
+        let starts_window = self
+            .windows
+            .range(..=guest_addr)
+            .next_back()
+            .is_some_and(|(&addr, w)| guest_addr < addr + w.len);
+        starts_window.then(|| self.carve(guest_addr, len))
+    }
+
+    /// Forgets all the windows, returning them.
+    pub(crate) fn clear(&mut self) -> Vec<(u64, Window)> {
+        self.mapped = 0;
+        std::mem::take(&mut self.windows).into_iter().collect()
+    }
+
+    /// Forgets the `len` bytes at `guest_addr`, splitting the windows partly inside, and returns
+    /// the parts removed.
+    fn carve(&mut self, guest_addr: u64, len: u64) -> Vec<(u64, Window)> {
+        let end = guest_addr + len;
+        let first = self
+            .windows
+            .range(..=guest_addr)
+            .next_back()
+            .filter(|(&addr, w)| guest_addr < addr + w.len)
+            .map_or(guest_addr, |(&addr, _)| addr);
+        let overlapping: Vec<u64> = self.windows.range(first..end).map(|(&a, _)| a).collect();
+
+        let mut removed = Vec::new();
+        for addr in overlapping {
+            let window = self.windows.remove(&addr).unwrap();
+            let start = addr.max(guest_addr);
+            let stop = (addr + window.len).min(end);
+            if addr < start {
+                self.windows.insert(addr, window.part(0, start - addr));
+            }
+            if stop < addr + window.len {
+                self.windows
+                    .insert(stop, window.part(stop - addr, addr + window.len - stop));
+            }
+            self.mapped -= stop - start;
+            removed.push((start, window.part(start - addr, stop - start)));
+        }
+        removed
+    }
+}
+
+impl Window {
+    /// Creates a window of `len` bytes at `host_addr`, mapping `inode` from `foffset`.
+    pub(crate) fn new(host_addr: u64, len: u64, inode: u64, foffset: u64, writable: bool) -> Self {
+        Window {
+            host_addr,
+            len,
+            inode,
+            foffset,
+            writable,
+        }
+    }
+
+    /// Whether `next` continues the window in the file and on the host.
+    fn continued_by(&self, next: &Window) -> bool {
+        self.inode == next.inode
+            && self.writable == next.writable
+            && self.host_addr + self.len == next.host_addr
+            && self.foffset + self.len == next.foffset
+    }
+
+    /// Returns the `len` bytes of the window from `offset`.
+    fn part(&self, offset: u64, len: u64) -> Window {
+        Window {
+            host_addr: self.host_addr + offset,
+            len,
+            foffset: self.foffset + offset,
+            ..*self
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Unmaps `window` from the host, once removed from the guest memory.
+#[cfg(target_os = "macos")]
+pub(crate) fn unmap_host(window: &Window) -> io::Result<()> {
+    let host_addr = window.host_addr as *mut libc::c_void;
+    dax::forget_mappings(host_addr, window.len as usize);
+    // Safe because the guest no longer accesses the window, which isn't recorded anymore.
+    if unsafe { libc::munmap(host_addr, window.len as usize) } == -1 {
+        error!("Error unmapping DAX window");
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn window(host_addr: u64, len: u64, foffset: u64) -> Window {
+        Window::new(host_addr, len, 1, foffset, false)
+    }
+
+    fn addrs(windows: &[(u64, Window)]) -> Vec<(u64, u64)> {
+        windows.iter().map(|(addr, w)| (*addr, w.len)).collect()
+    }
+
+    #[test]
+    fn windows() {
+        let mut windows = DaxWindows::new(Some(0x4000));
+
+        // Adjacent in the guest, on the host and in the file: coalesced
+        assert!(windows.insert(0x0, window(0x10000, 0x1000, 0)).is_empty());
+        assert!(windows
+            .insert(0x1000, window(0x11000, 0x1000, 0x1000))
+            .is_empty());
+        assert_eq!(windows.windows.len(), 1);
+        // Elsewhere on the host: not
+        assert!(windows
+            .insert(0x2000, window(0x30000, 0x1000, 0x2000))
+            .is_empty());
+        assert_eq!((windows.windows.len(), windows.mapped()), (2, 0x3000));
+
+        // Setting a range up again replaces the windows there, splitting the coalesced one
+        let replaced = windows.insert(0x1000, window(0x50000, 0x2000, 0x1000));
+        assert_eq!(addrs(&replaced), [(0x1000, 0x1000), (0x2000, 0x1000)]);
+        assert_eq!(replaced[0].1.host_addr, 0x11000);
+        assert_eq!(replaced[0].1.foffset, 0x1000);
+        assert_eq!(windows.mapped(), 0x3000);
+
+        // A removal may cover part of a window only
+        assert!(windows.remove(0x8000, 0x1000).is_none());
+        let removed = windows.remove(0x2000, 0x1000).unwrap();
+        assert_eq!(addrs(&removed), [(0x2000, 0x1000)]);
+        assert_eq!(removed[0].1.host_addr, 0x51000);
+        assert_eq!(windows.mapped(), 0x2000);
+
+        // The windows held count against the bound, but not those a new one replaces
+        assert!(windows.fits(0x8000, 0x2000));
+        assert!(!windows.fits(0x8000, 0x3000));
+        assert!(windows.fits(0x0, 0x3000));
+        assert!(windows.fits(0x1800, 0x2800));
+        assert!(!windows.fits(0x1800, 0x2801));
+        assert_eq!(windows.windows.len(), 2);
+        assert_eq!(addrs(&windows.clear()), [(0x0, 0x1000), (0x1000, 0x1000)]);
+        assert_eq!(windows.mapped(), 0);
+    }
+}
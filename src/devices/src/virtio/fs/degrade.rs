@@ -0,0 +1,206 @@
+//! The emergency read-only mode of a share whose writable storage keeps failing.
+//!
+//! When the host disk backing the writable layer of a share starts failing, every write the guest
+//! keeps making may lose more data, and leaves the share further from what the guest believes it
+//! holds. Once the requests writing to the share failed with `EIO` or `EROFS` the number of times
+//! set in [`FsDegradeOptions`] within its window, the share degrades to read-only, as Linux remounts
+//! a file system read-only after errors: the server fails the requests changing the share with
+//! `EROFS`, revokes the leases the guest holds so that it drops the attributes it cached, and calls
+//! the callback of the embedder. The share stays read-only until the embedder re-enables it, once
+//! the disk is repaired.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// Called with the number of errors that degraded the share, from the worker of the share.
+pub type FsDegradeCallback = Arc<dyn Fn(u32) + Send + Sync>;
+
+/// When a share degrades to read-only.
+#[derive(Clone)]
+pub struct FsDegradeOptions {
+    /// The number of failed writes that degrade the share.
+    pub max_errors: u32,
+    /// How recent the failed writes counted towards `max_errors` are.
+    pub window: Duration,
+    pub callback: Option<FsDegradeCallback>,
+}
+
+/// Whether a share degraded to read-only, kept across the servers of the share a pause may drop.
+/// The share never degrades on its own until given [`FsDegradeOptions`].
+///
+/// Cloning it gives another handle to the same share, so a handle obtained from the device before
+/// it is activated can be used to re-enable the share while the guest is running.
+#[derive(Clone, Default)]
+pub struct FsDegradation(Arc<DegradeState>);
+
+#[derive(Default)]
+struct DegradeState {
+    options: Mutex<Option<FsDegradeOptions>>,
+    /// When the recent writes failed, the oldest first.
+    errors: Mutex<VecDeque<Instant>>,
+    read_only: AtomicBool,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl FsDegradation {
+    /// Has the share degrade to read-only as set in `options`.
+    pub(crate) fn set_options(&self, options: FsDegradeOptions) {
+        *self.0.options.lock().unwrap() = Some(options);
+    }
+
+    /// Whether the share is read-only.
+    pub fn is_read_only(&self) -> bool {
+        self.0.read_only.load(Ordering::Acquire)
+    }
+
+    /// Degrades the share to read-only right away, without calling the callback, e.g. when the
+    /// host finds the disk failing on its own.
+    pub fn degrade(&self) {
+        if !self.0.read_only.swap(true, Ordering::AcqRel) {
+            warn!("virtio-fs: the share was made read-only");
+        }
+    }
+
+    /// Makes the share writable again, forgetting the errors it had, returning whether it was
+    /// read-only.
+    pub fn reenable(&self) -> bool {
+        self.0.errors.lock().unwrap().clear();
+        let was_read_only = self.0.read_only.swap(false, Ordering::AcqRel);
+        if was_read_only {
+            info!("virtio-fs: the share is writable again");
+        }
+        was_read_only
+    }
+
+    /// Counts the failure of a request writing to the share with `e`, returning whether it
+    /// degraded the share to read-only. Only the errors of a failing disk count.
+    pub(crate) fn record(&self, e: &io::Error) -> bool {
+        if !matches!(e.raw_os_error(), Some(libc::EIO | libc::EROFS)) {
+            return false;
+        }
+        let Some(options) = self.0.options.lock().unwrap().clone() else {
+            return false;
+        };
+        if self.is_read_only() {
+            return false;
+        }
+
+        let count = {
+            let now = Instant::now();
+            let mut errors = self.0.errors.lock().unwrap();
+            errors.push_back(now);
+            while errors
+                .front()
+                .is_some_and(|failed| now.duration_since(*failed) > options.window)
+            {
+                errors.pop_front();
+            }
+            errors.len() as u32
+        };
+        if count < options.max_errors || self.0.read_only.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+
+        error!("virtio-fs: {count} writes failed, the share is now read-only");
+        if let Some(callback) = &options.callback {
+            callback(count);
+        }
+        true
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Trait Implementations
+//--------------------------------------------------------------------------------------------------
+
+impl fmt::Debug for FsDegradeOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FsDegradeOptions")
+            .field("max_errors", &self.max_errors)
+            .field("window", &self.window)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Debug for FsDegradation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FsDegradation")
+            .field("read_only", &self.is_read_only())
+            .finish_non_exhaustive()
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::AtomicU32;
+
+    use super::*;
+
+    #[test]
+    fn degrade_after_errors() {
+        let degradation = FsDegradation::default();
+        let eio = io::Error::from_raw_os_error(libc::EIO);
+
+        // Without options, the share never degrades
+        for _ in 0..10 {
+            assert!(!degradation.record(&eio));
+        }
+        assert!(!degradation.is_read_only());
+
+        let called = Arc::new(AtomicU32::new(0));
+        let called_clone = called.clone();
+        degradation.set_options(FsDegradeOptions {
+            max_errors: 3,
+            window: Duration::from_secs(60),
+            callback: Some(Arc::new(move |count| {
+                called_clone.store(count, Ordering::Relaxed)
+            })),
+        });
+
+        // Only the errors of a failing disk count
+        assert!(!degradation.record(&io::Error::from_raw_os_error(libc::ENOSPC)));
+        assert!(!degradation.record(&eio));
+        assert!(!degradation.record(&io::Error::from_raw_os_error(libc::EROFS)));
+        assert!(degradation.record(&eio));
+        assert!(degradation.is_read_only());
+        assert_eq!(called.load(Ordering::Relaxed), 3);
+        assert!(!degradation.record(&eio));
+
+        // Re-enabling forgets the errors
+        assert!(degradation.reenable());
+        assert!(!degradation.is_read_only());
+        assert!(!degradation.record(&eio));
+        assert!(!degradation.reenable());
+    }
+
+    #[test]
+    fn errors_out_of_window() {
+        let degradation = FsDegradation::default();
+        let eio = io::Error::from_raw_os_error(libc::EIO);
+        degradation.set_options(FsDegradeOptions {
+            max_errors: 2,
+            window: Duration::ZERO,
+            callback: None,
+        });
+
+        assert!(!degradation.record(&eio));
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(!degradation.record(&eio));
+        assert!(!degradation.is_read_only());
+    }
+}
@@ -1,24 +1,47 @@
 #[cfg(target_os = "macos")]
 use crossbeam_channel::Sender;
 use std::cmp;
-use std::io::Write;
+use std::io::{self, Write};
+use std::mem::size_of;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicI32, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread::JoinHandle;
+use std::time::Duration;
 
 use utils::eventfd::{EventFd, EFD_NONBLOCK};
+use utils::fd_budget::{FdBudget, FdPriority};
 #[cfg(target_os = "macos")]
 use utils::worker_message::WorkerMessage;
 use virtio_bindings::{virtio_config::VIRTIO_F_VERSION_1, virtio_ring::VIRTIO_RING_F_EVENT_IDX};
 use vm_memory::{ByteValued, GuestMemoryMmap};
 
 use super::super::{
-    ActivateResult, DeviceState, FsError, Queue as VirtQueue, VirtioDevice, VirtioShmRegion,
+    ActivateError, ActivateResult, DeviceState, FsError, Queue as VirtQueue, VirtioDevice,
+    VirtioShmRegion,
 };
-use super::kinds::{FsImplConfig, FsImplShare};
+use super::credentials::FsCredentials;
+use super::degrade::{FsDegradation, FsDegradeOptions};
+use super::dir_template::FsDirTemplate;
+use super::fuse::{NotifyInvalInodeOut, OutHeader};
+use super::handle_quota::FsHandleQuota;
+use super::hooks::{FsHook, FsHooks};
+use super::kinds::{
+    FsAccessRules, FsAtime, FsBackgroundLimits, FsCacheTimeouts, FsGuestMount, FsImplConfig,
+    FsImplShare, FsInodeNumbers, FsLeases, FsModePolicy, FsOwnership, FsPageCache, FsProtocol,
+    FsWriteCoalescing,
+};
+use super::lower_layers::LowerLayerSet;
+use super::mirror::FsMirror;
 use super::overlayfs;
+use super::p9;
 use super::passthrough;
-use super::worker::FsWorker;
+use super::pause::FsPause;
+use super::trace::FsTracer;
+use super::unsupported::FsUnsupportedStats;
+use super::virtual_file::FsVirtualFile;
+use super::watch::FsWatcher;
+use super::worker::{FsWorker, SharedServer};
 use super::ExportTable;
 use super::{defs, defs::uapi};
 use crate::legacy::IrqChip;
@@ -28,6 +51,11 @@ use crate::legacy::IrqChip;
 struct VirtioFsConfig {
     tag: [u8; 36],
     num_request_queues: u32,
+    notify_buf_size: u32,
+    // The mount hints, offered with VIRTIO_FS_F_MOUNT_HINTS.
+    mount_flags: u32,
+    mount_path: [u8; defs::MOUNT_HINT_LEN],
+    mount_options: [u8; defs::MOUNT_HINT_LEN],
 }
 
 impl Default for VirtioFsConfig {
@@ -35,6 +63,10 @@ impl Default for VirtioFsConfig {
         VirtioFsConfig {
             tag: [0; 36],
             num_request_queues: 0,
+            notify_buf_size: 0,
+            mount_flags: 0,
+            mount_path: [0; defs::MOUNT_HINT_LEN],
+            mount_options: [0; defs::MOUNT_HINT_LEN],
         }
     }
 }
@@ -52,10 +84,36 @@ pub struct Fs {
     irq_line: Option<u32>,
     device_state: DeviceState,
     config: VirtioFsConfig,
+    protocol: FsProtocol,
     shm_region: Option<VirtioShmRegion>,
     fs_config: FsImplConfig,
+    access_rules: Option<FsAccessRules>,
+    credentials: Option<FsCredentials>,
+    cache_timeouts: FsCacheTimeouts,
+    background_limits: FsBackgroundLimits,
+    tracer: FsTracer,
+    watcher: FsWatcher,
+    mirror: Option<FsMirror>,
+    revalidate_interval: Option<Duration>,
+    write_coalescing: Option<FsWriteCoalescing>,
+    virtual_files: Vec<FsVirtualFile>,
+    protect_init_config: bool,
+    leases: Option<FsLeases>,
+    inspect_socket: Option<PathBuf>,
+    dir_templates: Vec<FsDirTemplate>,
+    inode_numbers: FsInodeNumbers,
+    mode_policy: Option<FsModePolicy>,
+    guest_mount: Option<FsGuestMount>,
+    degradation: FsDegradation,
+    unsupported: FsUnsupportedStats,
+    handle_quota: FsHandleQuota,
+    hooks: FsHooks,
+    pause: FsPause,
     worker_thread: Option<JoinHandle<()>>,
     worker_stopfd: EventFd,
+    // The server of the running worker, and the state to restore in the next one.
+    server: SharedServer,
+    restored_state: Option<Vec<u8>>,
     exit_code: Arc<AtomicI32>,
     #[cfg(target_os = "macos")]
     map_sender: Option<Sender<WorkerMessage>>,
@@ -74,7 +132,14 @@ impl Fs {
                 .push(EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(FsError::EventFd)?);
         }
 
-        let avail_features = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_RING_F_EVENT_IDX);
+        let avail_features = (1u64 << VIRTIO_F_VERSION_1)
+            | (1u64 << VIRTIO_RING_F_EVENT_IDX)
+            | (1u64 << defs::VIRTIO_FS_F_MOUNT_HINTS);
+
+        // The guest opens as many files as it likes, which mustn't starve the other devices
+        let fd_client = FdBudget::global().register(format!("virtio-fs {fs_id}"), FdPriority::Low);
+        let handle_quota = FsHandleQuota::default();
+        let hooks = FsHooks::default();
 
         let tag = fs_id.into_bytes();
         let mut config = VirtioFsConfig::default();
@@ -83,12 +148,33 @@ impl Fs {
         let fs_config = match fs_share {
             FsImplShare::Passthrough(root_dir) => FsImplConfig::Passthrough(passthrough::Config {
                 root_dir,
+                fd_client: Some(fd_client),
+                handle_quota: Some(handle_quota.clone()),
+                hooks: Some(hooks.clone()),
                 ..Default::default()
             }),
-            FsImplShare::Overlayfs(layers) => FsImplConfig::Overlayfs(overlayfs::Config {
-                layers,
-                ..Default::default()
-            }),
+            FsImplShare::Overlayfs(layers, upper_layer) => {
+                FsImplConfig::Overlayfs(overlayfs::Config {
+                    layers,
+                    upper_layer,
+                    fd_client: Some(fd_client),
+                    handle_quota: Some(handle_quota.clone()),
+                    hooks: Some(hooks.clone()),
+                    ..Default::default()
+                })
+            }
+            #[cfg(feature = "oci")]
+            FsImplShare::LazyOverlayfs(layers, lazy_layers, upper_layer) => {
+                FsImplConfig::Overlayfs(overlayfs::Config {
+                    layers,
+                    lazy_layers,
+                    upper_layer,
+                    fd_client: Some(fd_client),
+                    handle_quota: Some(handle_quota.clone()),
+                    hooks: Some(hooks.clone()),
+                    ..Default::default()
+                })
+            }
         };
 
         Ok(Fs {
@@ -102,10 +188,35 @@ impl Fs {
             irq_line: None,
             device_state: DeviceState::Inactive,
             config,
+            protocol: FsProtocol::Fuse,
             shm_region: None,
             fs_config,
+            access_rules: None,
+            credentials: None,
+            cache_timeouts: Default::default(),
+            background_limits: Default::default(),
+            tracer: Default::default(),
+            watcher: Default::default(),
+            mirror: None,
+            revalidate_interval: None,
+            write_coalescing: None,
+            virtual_files: Vec::new(),
+            protect_init_config: false,
+            leases: None,
+            inspect_socket: None,
+            dir_templates: Vec::new(),
+            inode_numbers: FsInodeNumbers::Native,
+            mode_policy: None,
+            guest_mount: None,
+            degradation: Default::default(),
+            unsupported: Default::default(),
+            handle_quota,
+            hooks,
+            pause: FsPause::new().map_err(FsError::EventFd)?,
             worker_thread: None,
             worker_stopfd: EventFd::new(EFD_NONBLOCK).map_err(FsError::EventFd)?,
+            server: Default::default(),
+            restored_state: None,
             exit_code,
             #[cfg(target_os = "macos")]
             map_sender: None,
@@ -150,6 +261,338 @@ impl Fs {
         fsid
     }
 
+    pub fn set_durable(&mut self, durable: bool) {
+        match &mut self.fs_config {
+            FsImplConfig::Passthrough(cfg) => cfg.durable = durable,
+            FsImplConfig::Overlayfs(cfg) => cfg.durable = durable,
+        }
+    }
+
+    pub fn set_allow_file_flags(&mut self, allow_file_flags: bool) {
+        match &mut self.fs_config {
+            FsImplConfig::Passthrough(cfg) => cfg.allow_file_flags = allow_file_flags,
+            FsImplConfig::Overlayfs(cfg) => cfg.allow_file_flags = allow_file_flags,
+        }
+    }
+
+    /// Lets the guest empty the directory trees of an overlay share in a single request, see
+    /// `overlayfs::Config::remove_tree`. Only Linux hosts support it.
+    pub fn set_remove_tree(&mut self, remove_tree: bool) {
+        #[cfg(target_os = "linux")]
+        if let FsImplConfig::Overlayfs(cfg) = &mut self.fs_config {
+            cfg.remove_tree = remove_tree;
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = remove_tree;
+    }
+
+    /// Lets the guest create many small files of a directory of an overlay share in a single
+    /// request, see `overlayfs::Config::batch_create`. Only Linux hosts support it.
+    pub fn set_batch_create(&mut self, batch_create: bool) {
+        #[cfg(target_os = "linux")]
+        if let FsImplConfig::Overlayfs(cfg) = &mut self.fs_config {
+            cfg.batch_create = batch_create;
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = batch_create;
+    }
+
+    /// Has the entries of the directories of an overlay share stat'ed in the background the first
+    /// time the guest visits them, up to `max_entries` per directory, see
+    /// `overlayfs::Config::dentry_warming`. Only Linux hosts support it.
+    pub fn set_dentry_warming(&mut self, max_entries: usize) {
+        #[cfg(target_os = "linux")]
+        if let FsImplConfig::Overlayfs(cfg) = &mut self.fs_config {
+            cfg.dentry_warming = Some(max_entries);
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = max_entries;
+    }
+
+    pub fn set_access_rules(&mut self, access_rules: FsAccessRules) {
+        self.access_rules = Some(access_rules);
+    }
+
+    /// Sets the translation of the credentials of the guest requests to the host ones they run
+    /// with, see [`CredentialMapper`](super::CredentialMapper).
+    pub fn set_credentials(&mut self, credentials: FsCredentials) {
+        self.credentials = Some(credentials);
+    }
+
+    /// Sets the limits on the background requests advertised to the guest, see
+    /// [`FsBackgroundLimits`].
+    pub fn set_background_limits(&mut self, background_limits: FsBackgroundLimits) {
+        self.background_limits = background_limits.normalized();
+    }
+
+    /// Enables the revalidation of the attributes of the files the guest recently accessed, which
+    /// are re-stat'ed every `interval` to detect the changes made to them on the host.
+    pub fn set_revalidate_interval(&mut self, interval: Duration) {
+        self.revalidate_interval = Some(interval);
+    }
+
+    /// Enables the coalescing of the small sequential writes of the guest, see
+    /// [`FsWriteCoalescing`].
+    pub fn set_write_coalescing(&mut self, write_coalescing: FsWriteCoalescing) {
+        self.write_coalescing = Some(write_coalescing.normalized());
+    }
+
+    /// Adds a file whose attributes and content are provided by the embedder, shadowing whatever
+    /// the share holds at its path, see [`FsVirtualFile`].
+    pub fn add_virtual_file(&mut self, file: FsVirtualFile) {
+        self.virtual_files.push(file);
+    }
+
+    /// Serves the configuration the init binary reads at the root of the share read-only, as it
+    /// was when the guest first reached it, so that the guest can't change what the init binary
+    /// runs. The init binary itself is always read-only.
+    pub fn set_protect_init_config(&mut self, protect_init_config: bool) {
+        self.protect_init_config = protect_init_config;
+    }
+
+    /// Sets the protocol the guest uses the share with, see [`FsProtocol`]. A 9p device has a
+    /// single request queue, and no DAX window.
+    pub fn set_protocol(&mut self, protocol: FsProtocol) {
+        if protocol == FsProtocol::P9 && self.protocol != protocol {
+            self.queues.truncate(1);
+            self.queue_events.truncate(1);
+            self.avail_features = (1u64 << VIRTIO_F_VERSION_1)
+                | (1u64 << VIRTIO_RING_F_EVENT_IDX)
+                | (1u64 << defs::VIRTIO_9P_MOUNT_TAG);
+            if self.leases.take().is_some() {
+                warn!("virtio-fs: leases aren't supported over 9p");
+            }
+        }
+        self.protocol = protocol;
+    }
+
+    /// Enables the leases on the attributes the guest caches, see [`FsLeases`]. The device then
+    /// offers the notification queue they are revoked through. A 9p device has none, and grants
+    /// no leases.
+    pub fn set_leases(&mut self, leases: FsLeases) {
+        if self.protocol == FsProtocol::P9 {
+            warn!("virtio-fs: leases aren't supported over 9p");
+            return;
+        }
+        if self.leases.is_none() {
+            self.queues
+                .push(VirtQueue::new(defs::QUEUE_SIZES[defs::REQ_INDEX]));
+            self.queue_events.push(EventFd::new(EFD_NONBLOCK).unwrap());
+            self.avail_features |= 1 << defs::VIRTIO_FS_F_NOTIFICATION;
+            self.config.notify_buf_size =
+                (size_of::<OutHeader>() + size_of::<NotifyInvalInodeOut>()) as u32;
+        }
+        self.leases = Some(leases);
+    }
+
+    /// Serves the view the guest has of the share to host tools, on the UNIX socket at
+    /// `socket_path`, once the device is activated.
+    pub fn set_inspect_socket(&mut self, socket_path: PathBuf) {
+        self.inspect_socket = Some(socket_path);
+    }
+
+    /// Adds a host directory copied into a directory of the share the first time the guest
+    /// creates it, see [`FsDirTemplate`].
+    pub fn add_dir_template(&mut self, template: FsDirTemplate) {
+        self.dir_templates.push(template);
+    }
+
+    /// Sets the width of the inode numbers the guest sees, see [`FsInodeNumbers`]. Only the FUSE
+    /// server folds them, not the 9p one.
+    pub fn set_inode_numbers(&mut self, inode_numbers: FsInodeNumbers) {
+        self.inode_numbers = inode_numbers;
+    }
+
+    /// Sets the modes the entries the guest creates get on the host, see [`FsModePolicy`]. The
+    /// guest still sees the modes it asked for, as long as the device runs. Only the FUSE server
+    /// applies the policy, not the 9p one.
+    pub fn set_mode_policy(&mut self, policy: Option<FsModePolicy>) {
+        self.mode_policy = policy;
+    }
+
+    /// Degrades the share to read-only once the requests writing to it failed as set in `options`,
+    /// see [`FsDegradation`]. Only the FUSE server counts the failures, not the 9p one.
+    pub fn set_degrade_options(&mut self, options: FsDegradeOptions) {
+        self.degradation.set_options(options);
+    }
+
+    /// Has the guest mount the share at boot, see [`FsGuestMount`]. The path and options are
+    /// advertised in the mount hints of the configuration space, truncated to
+    /// [`FsGuestMount::MAX_LEN`] bytes.
+    pub fn set_guest_mount(&mut self, mount: FsGuestMount) {
+        self.guest_mount = Some(mount);
+    }
+
+    /// Returns the configuration space of a FUSE device, with the mount hints: whether the share
+    /// has a DAX window, how the device caches it, and where and with which options the guest
+    /// mounts it at boot, if it does.
+    fn fuse_config(&self) -> VirtioFsConfig {
+        let mut config = self.config;
+        let dax = self.shm_region.is_some();
+        let mut flags = if dax { defs::MOUNT_HINT_DAX } else { 0 };
+        flags |= match &self.fs_config {
+            #[cfg(target_os = "linux")]
+            FsImplConfig::Passthrough(cfg) => match cfg.cache_policy {
+                passthrough::CachePolicy::Never => defs::MOUNT_HINT_CACHE_NEVER,
+                passthrough::CachePolicy::Auto => 0,
+                passthrough::CachePolicy::Always => defs::MOUNT_HINT_CACHE_ALWAYS,
+            },
+            #[cfg(not(target_os = "linux"))]
+            FsImplConfig::Passthrough(_) => 0,
+            FsImplConfig::Overlayfs(cfg) => match cfg.cache_policy {
+                overlayfs::CachePolicy::Never => defs::MOUNT_HINT_CACHE_NEVER,
+                overlayfs::CachePolicy::Auto => 0,
+                overlayfs::CachePolicy::Always => defs::MOUNT_HINT_CACHE_ALWAYS,
+            },
+        };
+        if let Some(mount) = &self.guest_mount {
+            flags |= defs::MOUNT_HINT_AUTO_MOUNT;
+            let options = mount.mount_options(FsProtocol::Fuse, dax);
+            copy_hint(&mut config.mount_path, mount.path.as_bytes());
+            copy_hint(&mut config.mount_options, options.as_bytes());
+        }
+        config.mount_flags = flags;
+        config
+    }
+
+    /// Sets how the reads of the guest update the access times of the host files, see [`FsAtime`].
+    /// Only Linux hosts support anything but [`FsAtime::Host`].
+    pub fn set_atime(&mut self, atime: FsAtime) {
+        #[cfg(target_os = "linux")]
+        match &mut self.fs_config {
+            FsImplConfig::Passthrough(cfg) => cfg.atime = atime,
+            FsImplConfig::Overlayfs(cfg) => cfg.atime = atime,
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = atime;
+    }
+
+    /// Sets where the owners the guest gives the entries, and the device nodes it creates, are
+    /// kept, see [`FsOwnership`]. Only Linux hosts support anything but [`FsOwnership::Auto`].
+    pub fn set_ownership(&mut self, ownership: FsOwnership) {
+        #[cfg(target_os = "linux")]
+        match &mut self.fs_config {
+            FsImplConfig::Passthrough(cfg) => cfg.ownership = ownership,
+            FsImplConfig::Overlayfs(cfg) => cfg.ownership = ownership,
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = ownership;
+    }
+
+    /// Sets how the reads of the guest use the host page cache, see [`FsPageCache`].
+    pub fn set_page_cache(&mut self, page_cache: FsPageCache) {
+        match &mut self.fs_config {
+            FsImplConfig::Passthrough(cfg) => cfg.page_cache = page_cache,
+            FsImplConfig::Overlayfs(cfg) => cfg.page_cache = page_cache,
+        }
+    }
+
+    /// Has a passthrough share match the names the guest looks up to the ones on the host whatever
+    /// their Unicode normalization forms, and list the names the host volume changes as the guest
+    /// gave them, see `passthrough::Config::normalize_names`. Overlay shares are left alone.
+    pub fn set_normalize_names(&mut self, normalize_names: bool) {
+        if let FsImplConfig::Passthrough(cfg) = &mut self.fs_config {
+            cfg.normalize_names = normalize_names;
+        }
+    }
+
+    /// Mirrors the writable layer of the share to the host directory `target`, see [`FsMirror`].
+    /// The overlays with their top layer in RAM have nothing on the host to mirror, and are left
+    /// alone.
+    pub fn set_mirror(&mut self, target: PathBuf) {
+        let source = match &self.fs_config {
+            FsImplConfig::Passthrough(cfg) => PathBuf::from(&cfg.root_dir),
+            FsImplConfig::Overlayfs(cfg) => match (&cfg.upper_layer, cfg.layers.last()) {
+                (overlayfs::UpperLayer::Disk, Some(top_layer)) => top_layer.clone(),
+                (overlayfs::UpperLayer::Disk, None) => return,
+                (overlayfs::UpperLayer::Ram { .. }, _) => {
+                    warn!("virtio-fs: an overlay with its top layer in RAM can't be mirrored");
+                    return;
+                }
+            },
+        };
+        self.mirror = Some(FsMirror::new(source, target, self.watcher.clone()));
+    }
+
+    /// Returns a handle to the mirror of the share, if it has one.
+    pub fn mirror(&self) -> Option<FsMirror> {
+        self.mirror.clone()
+    }
+
+    /// Limits the handles the guest may keep open on the share to `max_handles`, the opens past
+    /// it failing with `EMFILE`.
+    pub fn set_max_handles(&mut self, max_handles: usize) {
+        self.handle_quota.set_limit(Some(max_handles));
+    }
+
+    /// Returns a handle to change the entry and attribute timeouts of the share while the guest is
+    /// running.
+    pub fn cache_timeouts(&self) -> FsCacheTimeouts {
+        self.cache_timeouts.clone()
+    }
+
+    /// Returns a handle to trace the requests handled by the device, which is disabled until
+    /// enabled through it.
+    pub fn tracer(&self) -> FsTracer {
+        self.tracer.clone()
+    }
+
+    /// Returns a handle to subscribe to the changes the guest makes to the share.
+    pub fn watcher(&self) -> FsWatcher {
+        self.watcher.clone()
+    }
+
+    /// Returns a handle to tell whether the share degraded to read-only, and to re-enable it.
+    pub fn degradation(&self) -> FsDegradation {
+        self.degradation.clone()
+    }
+
+    /// Returns a handle to the count of the requests of the guest the share doesn't support.
+    pub fn unsupported_stats(&self) -> FsUnsupportedStats {
+        self.unsupported.clone()
+    }
+
+    /// Returns a handle to the count of the handles the guest keeps open on the share.
+    pub fn handle_quota(&self) -> FsHandleQuota {
+        self.handle_quota.clone()
+    }
+
+    /// Registers a hook of the embedder, called at its point from the next time the share reaches
+    /// it, see [`FsHooks`].
+    pub fn add_hook(&mut self, hook: FsHook) {
+        self.hooks.add(hook);
+    }
+
+    /// Shares the caches of the lower layers of an overlay share with the other overlays of the
+    /// process stacked on the same ones, see [`LowerLayerSet`], the lookups skipping the lower
+    /// layers with the shared path filters. The lower layers must then not be changed on the host.
+    /// Passthrough shares have no lower layers, and are left alone.
+    pub fn set_shared_lower_layers(&mut self) {
+        let FsImplConfig::Overlayfs(cfg) = &mut self.fs_config else {
+            return;
+        };
+        let lower_count = match cfg.upper_layer {
+            overlayfs::UpperLayer::Disk => cfg.layers.len().saturating_sub(1),
+            overlayfs::UpperLayer::Ram { .. } => cfg.layers.len(),
+        };
+        if lower_count == 0 {
+            return;
+        }
+
+        match LowerLayerSet::shared(&cfg.layers[..lower_count]) {
+            Ok(set) => {
+                cfg.lower_layers = Some(set);
+                cfg.lookup_filters = true;
+            }
+            Err(e) => warn!("virtio-fs: failed to share the lower layers of the overlay: {e}"),
+        }
+    }
+
+    /// Returns a handle to pause the device while the host maintains the volume of the share.
+    pub fn pause(&self) -> FsPause {
+        self.pause.clone()
+    }
+
     #[cfg(target_os = "macos")]
     pub fn set_map_sender(&mut self, map_sender: Sender<WorkerMessage>) {
         self.map_sender = Some(map_sender);
@@ -170,7 +613,10 @@ impl VirtioDevice for Fs {
     }
 
     fn device_type(&self) -> u32 {
-        uapi::VIRTIO_ID_FS
+        match self.protocol {
+            FsProtocol::Fuse => uapi::VIRTIO_ID_FS,
+            FsProtocol::P9 => uapi::VIRTIO_ID_9P,
+        }
     }
 
     fn queues(&self) -> &[VirtQueue] {
@@ -199,7 +645,20 @@ impl VirtioDevice for Fs {
     }
 
     fn read_config(&self, offset: u64, mut data: &mut [u8]) {
-        let config_slice = self.config.as_slice();
+        let fuse_config;
+        let p9_config;
+        let config_slice = match self.protocol {
+            FsProtocol::Fuse => {
+                fuse_config = self.fuse_config();
+                fuse_config.as_slice()
+            }
+            FsProtocol::P9 => {
+                let tag = &self.config.tag;
+                let tag_len = tag.iter().position(|&b| b == 0).unwrap_or(tag.len());
+                p9_config = p9::config_space(&tag[..tag_len]);
+                &p9_config
+            }
+        };
         let config_len = config_slice.len() as u64;
         if offset >= config_len {
             error!("Failed to read config space");
@@ -226,8 +685,14 @@ impl VirtioDevice for Fs {
         }
 
         let event_idx: bool = (self.acked_features & (1 << VIRTIO_RING_F_EVENT_IDX)) != 0;
-        self.queues[defs::HPQ_INDEX].set_event_idx(event_idx);
-        self.queues[defs::REQ_INDEX].set_event_idx(event_idx);
+        for queue in self.queues.iter_mut() {
+            queue.set_event_idx(event_idx);
+        }
+
+        // Without the notification queue, the leases couldn't be revoked
+        let leases = self
+            .leases
+            .filter(|_| self.acked_features & (1 << defs::VIRTIO_FS_F_NOTIFICATION) != 0);
 
         let queue_evts = self
             .queue_events
@@ -245,12 +710,39 @@ impl VirtioDevice for Fs {
             mem.clone(),
             self.shm_region.clone(),
             self.fs_config.clone(),
+            self.access_rules.clone(),
+            self.credentials.clone(),
+            self.cache_timeouts.clone(),
+            self.background_limits,
             self.worker_stopfd.try_clone().unwrap(),
             self.exit_code.clone(),
+            self.tracer.clone(),
+            self.watcher.clone(),
+            self.revalidate_interval,
+            self.write_coalescing,
+            self.virtual_files.clone(),
+            self.protect_init_config,
+            leases,
+            self.inspect_socket.clone(),
+            self.dir_templates.clone(),
+            self.pause.clone(),
+            self.protocol,
+            self.inode_numbers,
+            self.mode_policy,
+            self.degradation.clone(),
+            self.unsupported.clone(),
             #[cfg(target_os = "macos")]
             self.map_sender.clone(),
         );
 
+        if let Some(state) = self.restored_state.take() {
+            if let Err(e) = worker.server().restore_state(&state) {
+                error!("virtio-fs: failed to restore the state of the share: {e}");
+                return Err(ActivateError::BadActivate);
+            }
+        }
+
+        self.server = worker.shared_server();
         self.worker_thread = Some(worker.run());
         self.device_state = DeviceState::Activated(mem);
         Ok(())
@@ -264,7 +756,34 @@ impl VirtioDevice for Fs {
     }
 
     fn shm_region(&self) -> Option<&VirtioShmRegion> {
-        self.shm_region.as_ref()
+        self.shm_region
+            .as_ref()
+            .filter(|_| self.protocol == FsProtocol::Fuse)
+    }
+
+    fn snapshot_supported(&self) -> bool {
+        // The fids of the guest would be lost
+        self.protocol == FsProtocol::Fuse
+    }
+
+    fn save_state(&self) -> io::Result<Vec<u8>> {
+        if self.pause.is_paused() {
+            return Err(io::Error::from_raw_os_error(libc::EBUSY));
+        }
+        // The fids of the guest would be lost
+        if self.protocol == FsProtocol::P9 {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
+        let server = self.server.lock().unwrap().clone();
+        match server {
+            Some(server) => server.save_state(),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> io::Result<()> {
+        self.restored_state = (!state.is_empty()).then(|| state.to_vec());
+        Ok(())
     }
 
     fn reset(&mut self) -> bool {
@@ -274,7 +793,14 @@ impl VirtioDevice for Fs {
                 error!("error waiting for worker thread: {:?}", e);
             }
         }
+        self.server = Default::default();
         self.device_state = DeviceState::Inactive;
         true
     }
 }
+
+/// Copies `value` into the NUL-terminated `field` of the mount hints, truncated to fit.
+fn copy_hint(field: &mut [u8], value: &[u8]) {
+    let len = value.len().min(field.len() - 1);
+    field[..len].copy_from_slice(&value[..len]);
+}
@@ -0,0 +1,310 @@
+//! Directories of a share materialized from host templates.
+//!
+//! The embedder registers a host directory as the template of a path of the share. The first time
+//! the guest creates a directory at that path, or under it, the directory is filled with a copy of
+//! the matching directory of the template, keeping the modes and owners of its entries. A regular
+//! file created under the path for the first time likewise gets the content of the matching file
+//! of the template, if it's created empty. Nothing is copied once the path was created, so that
+//! removing and recreating a directory gives an empty one, as on any other file system.
+//!
+//! The copy goes through the file system serving the guest, so that it lands in the top layer of
+//! an overlay like any change of the guest. It runs on behalf of the device, as root, and a failure
+//! to copy an entry is logged without failing the request that created the directory.
+
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::bindings;
+use super::filesystem::{Context, Extensions, FileSystem, SetattrValid, ZeroCopyReader};
+use super::FsImpl;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The size of the writes copying the content of a template file.
+const COPY_SIZE: usize = 1 << 20;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// A host directory copied into a directory of a share the first time the guest creates it.
+#[derive(Clone, Debug)]
+pub struct FsDirTemplate {
+    /// The path of the directory, relative to the root of the share. The directories and files
+    /// created under it are materialized from the matching entries of the template.
+    pub path: PathBuf,
+    /// The host directory holding the template.
+    pub template: PathBuf,
+}
+
+/// The directory templates of a share, and the paths already materialized from them.
+#[derive(Default)]
+pub(crate) struct DirTemplates {
+    templates: Vec<FsDirTemplate>,
+    materialized: Mutex<HashSet<PathBuf>>,
+}
+
+/// Reads the content of a template file for the writes of the file system.
+struct TemplateReader<'a> {
+    file: &'a File,
+    offset: u64,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl DirTemplates {
+    pub(crate) fn new(templates: Vec<FsDirTemplate>) -> Self {
+        let templates = templates
+            .into_iter()
+            .map(|mut template| {
+                template.path = relative_path(&template.path);
+                template
+            })
+            .collect();
+        DirTemplates {
+            templates,
+            materialized: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Returns the host template of the entry named `name` just created in the directory
+    /// `parent_path` returns the path of, if it has one and wasn't created before. `parent_path`
+    /// is only called if there are templates.
+    pub(crate) fn claim(
+        &self,
+        parent_path: impl FnOnce() -> Option<PathBuf>,
+        name: &[u8],
+    ) -> Option<PathBuf> {
+        if self.templates.is_empty() {
+            return None;
+        }
+
+        let name = name.strip_suffix(b"\0").unwrap_or(name);
+        let path = parent_path()?.join(std::ffi::OsStr::from_bytes(name));
+        let template = self.templates.iter().find_map(|template| {
+            let relative = path.strip_prefix(&template.path).ok()?;
+            Some(template.template.join(relative))
+        })?;
+
+        if !self.materialized.lock().unwrap().insert(path) {
+            return None;
+        }
+        Some(template)
+    }
+}
+
+impl io::Read for TemplateReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.file.read_at(buf, self.offset)?;
+        self.offset += count as u64;
+        Ok(count)
+    }
+}
+
+impl ZeroCopyReader for TemplateReader<'_> {
+    fn read_to(&mut self, f: &File, count: usize, off: u64) -> io::Result<usize> {
+        let mut buf = vec![0u8; count.min(COPY_SIZE)];
+        let count = self.file.read_at(&mut buf, self.offset)?;
+        f.write_all_at(&buf[..count], off)?;
+        self.offset += count as u64;
+        Ok(count)
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// The context the entries of the templates are copied in.
+pub(crate) fn device_context() -> Context {
+    Context {
+        uid: 0,
+        gid: 0,
+        pid: 0,
+    }
+}
+
+/// Fills the directory `inode`, just created by the guest, with a copy of the host directory
+/// `template`, whose mode and owners it takes. Does nothing if `template` isn't a directory.
+pub(crate) fn materialize_dir(fs: &FsImpl, inode: u64, template: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(template) {
+        Ok(metadata) if metadata.is_dir() => metadata,
+        Ok(_) => return Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    copy_dir(fs, inode, template)?;
+    set_owner_and_mode(fs, inode, &metadata)
+}
+
+/// Writes the content of the host file `template` into the regular file `inode`, just created
+/// empty by the guest, which takes its mode and owners. Does nothing if `template` isn't a regular
+/// file.
+pub(crate) fn materialize_file(fs: &FsImpl, inode: u64, template: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(template) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => return Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    let ctx = device_context();
+    let handle = fs
+        .open(ctx, inode, libc::O_WRONLY as u32)?
+        .0
+        .unwrap_or_default();
+    let res = copy_content(fs, inode, handle, template, metadata.len());
+    fs.release(ctx, inode, 0, handle, false, false, None)?;
+    res?;
+    set_owner_and_mode(fs, inode, &metadata)
+}
+
+/// Copies the entries of the host directory `template` into the directory `inode`.
+fn copy_dir(fs: &FsImpl, inode: u64, template: &Path) -> io::Result<()> {
+    let ctx = device_context();
+    let mut entries = fs::read_dir(template)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let metadata = fs::symlink_metadata(&path)?;
+        let name = CString::new(entry.file_name().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mode = metadata.mode() & 0o7777;
+
+        let child = if metadata.is_dir() {
+            let child = fs.mkdir(ctx, inode, &name, mode, 0, Extensions::default())?;
+            copy_dir(fs, child.inode, &path)?;
+            child
+        } else if metadata.is_file() {
+            let flags = (libc::O_WRONLY | libc::O_EXCL) as u32;
+            let (child, handle, _) =
+                fs.create(ctx, inode, &name, mode, flags, 0, Extensions::default())?;
+            let handle = handle.unwrap_or_default();
+            let res = copy_content(fs, child.inode, handle, &path, metadata.len());
+            fs.release(ctx, child.inode, 0, handle, false, false, None)?;
+            res?;
+            child
+        } else if metadata.is_symlink() {
+            let target = CString::new(fs::read_link(&path)?.as_os_str().as_bytes())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let child = fs.symlink(ctx, &target, inode, &name, Extensions::default())?;
+            fs.forget(ctx, child.inode, 1);
+            continue;
+        } else {
+            warn!(
+                "virtio-fs: not copying the special file {} of a directory template",
+                path.display()
+            );
+            continue;
+        };
+
+        let res = set_owner_and_mode(fs, child.inode, &metadata);
+        fs.forget(ctx, child.inode, 1);
+        res?;
+    }
+
+    Ok(())
+}
+
+/// Copies the `len` bytes of the host file `template` into the file `inode` open as `handle`.
+fn copy_content(fs: &FsImpl, inode: u64, handle: u64, template: &Path, len: u64) -> io::Result<()> {
+    let file = File::open(template)?;
+    let mut offset = 0;
+    while offset < len {
+        let size = (len - offset).min(COPY_SIZE as u64) as u32;
+        let reader = TemplateReader {
+            file: &file,
+            offset,
+        };
+        let written = fs.write(
+            device_context(),
+            inode,
+            handle,
+            reader,
+            size,
+            offset,
+            None,
+            false,
+            false,
+            0,
+        )?;
+        if written == 0 {
+            break;
+        }
+        offset += written as u64;
+    }
+    Ok(())
+}
+
+/// Gives the entry `inode` the owners and then the mode of `metadata`, as changing the owners
+/// clears the set-user-ID and set-group-ID bits.
+fn set_owner_and_mode(fs: &FsImpl, inode: u64, metadata: &fs::Metadata) -> io::Result<()> {
+    let ctx = device_context();
+    // Safe because the structure is plain data, for which all zeroes is a valid value.
+    let mut attr: bindings::stat64 = unsafe { std::mem::zeroed() };
+    attr.st_uid = metadata.uid();
+    attr.st_gid = metadata.gid();
+    attr.st_mode = (metadata.mode() & 0o7777) as libc::mode_t;
+    fs.setattr(
+        ctx,
+        inode,
+        attr,
+        None,
+        SetattrValid::UID | SetattrValid::GID,
+    )?;
+    fs.setattr(ctx, inode, attr, None, SetattrValid::MODE)?;
+    Ok(())
+}
+
+/// Returns `path` relative to the root of the share, without its `.` components.
+fn relative_path(path: &Path) -> PathBuf {
+    let path = path.components().collect::<PathBuf>();
+    match path.strip_prefix("/") {
+        Ok(relative) => relative.to_path_buf(),
+        Err(_) => path,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn claim() {
+        let templates = DirTemplates::new(vec![FsDirTemplate {
+            path: PathBuf::from("/workspace/./cache"),
+            template: PathBuf::from("/templates/cache"),
+        }]);
+
+        let claim =
+            |parent: &str, name: &[u8]| templates.claim(|| Some(PathBuf::from(parent)), name);
+        assert_eq!(
+            claim("workspace", b"cache\0"),
+            Some(PathBuf::from("/templates/cache/"))
+        );
+        assert_eq!(
+            claim("workspace/cache", b"sub"),
+            Some(PathBuf::from("/templates/cache/sub"))
+        );
+
+        // Only the first creation of a path is materialized
+        assert_eq!(claim("workspace", b"cache"), None);
+        assert_eq!(claim("workspace", b"other"), None);
+        assert_eq!(claim("", b"workspace"), None);
+
+        let empty = DirTemplates::default();
+        assert_eq!(empty.claim(|| unreachable!(), b"cache"), None);
+    }
+}
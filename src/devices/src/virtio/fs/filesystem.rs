@@ -21,10 +21,31 @@ use super::bindings;
 use super::fuse;
 
 pub use super::fuse::FsOptions;
+pub use fuse::FileLock;
 pub use fuse::OpenOptions;
 pub use fuse::RemovemappingOne;
 pub use fuse::SetattrValid;
 
+/// The creation time of an inode, as returned by `FileSystem::statx`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BirthTime {
+    pub sec: i64,
+    pub nsec: u32,
+}
+
+impl BirthTime {
+    /// Returns a generation telling the file born at this time apart from the files that had its
+    /// inode number before it, or `None` for the zero time of the file systems that don't record
+    /// birth times.
+    pub fn generation(&self) -> Option<u64> {
+        (*self != BirthTime::default()).then(|| {
+            (self.sec as u64)
+                .wrapping_mul(1_000_000_000)
+                .wrapping_add(u64::from(self.nsec))
+        })
+    }
+}
+
 /// Information about a path in the filesystem.
 #[derive(Debug)]
 pub struct Entry {
@@ -443,6 +464,23 @@ pub trait FileSystem {
         Err(io::Error::from_raw_os_error(bindings::LINUX_ENOSYS))
     }
 
+    /// Get the extended attributes for a file / directory, as returned by `statx`.
+    ///
+    /// This returns the same attributes as `getattr`, along with the creation time of the inode if
+    /// the host records it. The mount ID is filled in by the guest
+    /// kernel, which gives the same one to all the inodes of a share.
+    ///
+    /// The default implementation returns the attributes from `getattr` without a creation time.
+    fn statx(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Option<Self::Handle>,
+    ) -> io::Result<(bindings::stat64, Option<BirthTime>, Duration)> {
+        let (st, timeout) = self.getattr(ctx, inode, handle)?;
+        Ok((st, None, timeout))
+    }
+
     /// Set attributes for a file / directory.
     ///
     /// If `handle` is not `None`, then it contains the handle previously returned by the
@@ -676,6 +714,28 @@ pub trait FileSystem {
         Err(io::Error::from_raw_os_error(bindings::LINUX_ENOSYS))
     }
 
+    /// Create and open an anonymous regular file in the directory `parent`, for `O_TMPFILE`.
+    ///
+    /// The file has no name until the guest gives it one with `link`, and is deleted when it is
+    /// released without one. Like `create`, this returns the `Entry` of the file, which increases
+    /// the lookup count for its `Inode` by 1, along with the optional `Handle` and the
+    /// `OpenOptions` of the open file.
+    ///
+    /// If the file system returns an `ENOSYS` error, then the kernel will fail all future
+    /// `O_TMPFILE` opens with `EOPNOTSUPP` without calling this method again.
+    #[allow(clippy::too_many_arguments)]
+    fn tmpfile(
+        &self,
+        ctx: Context,
+        parent: Self::Inode,
+        mode: u32,
+        flags: u32,
+        umask: u32,
+        extensions: Extensions,
+    ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions)> {
+        Err(io::Error::from_raw_os_error(bindings::LINUX_ENOSYS))
+    }
+
     /// Read data from a file.
     ///
     /// Returns `size` bytes of data starting from offset `off` from the file associated with
@@ -1160,25 +1220,61 @@ pub trait FileSystem {
         flags: u32,
         cmd: u32,
         arg: u64,
-        in_size: u32,
+        data: &[u8],
         out_size: u32,
         exit_code: &Arc<AtomicI32>,
     ) -> io::Result<Vec<u8>> {
         Err(io::Error::from_raw_os_error(bindings::LINUX_ENOSYS))
     }
 
-    /// TODO: support this
-    fn getlk(&self) -> io::Result<()> {
+    /// Test for a POSIX lock on a file.
+    ///
+    /// Returns the first lock held by another owner than `owner` that conflicts with `lock`, or
+    /// `lock` with its type set to `F_UNLCK` if there is none. `flags` are the `fuse::LK_*` flags
+    /// of the request.
+    ///
+    /// The kernel only sends this request if the file system enables `FsOptions::POSIX_LOCKS` in
+    /// `init`, and handles the locks itself otherwise.
+    fn getlk(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<FileLock> {
         Err(io::Error::from_raw_os_error(bindings::LINUX_ENOSYS))
     }
 
-    /// TODO: support this
-    fn setlk(&self) -> io::Result<()> {
+    /// Acquire, modify or release a POSIX lock on a file, for `owner`.
+    ///
+    /// If the lock conflicts with one held by another owner, this fails with `EAGAIN`. The locks
+    /// of `owner` on the file must all be released when it is flushed or released with `owner`.
+    fn setlk(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
         Err(io::Error::from_raw_os_error(bindings::LINUX_ENOSYS))
     }
 
-    /// TODO: support this
-    fn setlkw(&self) -> io::Result<()> {
+    /// Like `setlk`, for a process that waits for the conflicting locks to be released. This
+    /// mustn't wait itself but fail with `EAGAIN` like `setlk`: the server then handles the request
+    /// again later, until the lock is taken, without holding up the other requests meanwhile.
+    fn setlkw(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
         Err(io::Error::from_raw_os_error(bindings::LINUX_ENOSYS))
     }
 
@@ -439,6 +439,10 @@ pub const RELEASE_FLOCK_UNLOCK: u32 = 2;
 // Getattr flags.
 pub const GETATTR_FH: u32 = 1;
 
+// Bitmasks for `fuse_statx.mask`, the same as those of the Linux `statx`.
+pub const STATX_BASIC_STATS: u32 = 0x7ff;
+pub const STATX_BTIME: u32 = 0x800;
+
 // Lock flags.
 pub const LK_FLOCK: u32 = 1;
 
@@ -585,6 +589,76 @@ impl Attr {
     }
 }
 
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SxTime {
+    pub tv_sec: i64,
+    pub tv_nsec: u32,
+    pub reserved: i32,
+}
+unsafe impl ByteValued for SxTime {}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Statx {
+    pub mask: u32,
+    pub blksize: u32,
+    pub attributes: u64,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u16,
+    pub spare0: [u16; 1],
+    pub ino: u64,
+    pub size: u64,
+    pub blocks: u64,
+    pub attributes_mask: u64,
+    pub atime: SxTime,
+    pub btime: SxTime,
+    pub ctime: SxTime,
+    pub mtime: SxTime,
+    pub rdev_major: u32,
+    pub rdev_minor: u32,
+    pub dev_major: u32,
+    pub dev_minor: u32,
+    pub spare2: [u64; 14],
+}
+unsafe impl ByteValued for Statx {}
+
+impl Statx {
+    /// Builds the statx attributes from those returned by `getattr` and the creation time, as
+    /// seconds and nanoseconds, when the host records it.
+    pub fn with_btime(st: bindings::stat64, btime: Option<(i64, u32)>) -> Statx {
+        let attr = Attr::from(st);
+        let sx_time = |tv_sec, tv_nsec| SxTime {
+            tv_sec,
+            tv_nsec,
+            reserved: 0,
+        };
+
+        Statx {
+            mask: STATX_BASIC_STATS | btime.map_or(0, |_| STATX_BTIME),
+            blksize: attr.blksize,
+            nlink: attr.nlink,
+            uid: attr.uid,
+            gid: attr.gid,
+            mode: attr.mode as u16,
+            ino: attr.ino,
+            size: attr.size,
+            blocks: attr.blocks,
+            atime: sx_time(st.st_atime, attr.atimensec),
+            btime: btime.map_or_else(Default::default, |(sec, nsec)| sx_time(sec, nsec)),
+            ctime: sx_time(st.st_ctime, attr.ctimensec),
+            mtime: sx_time(st.st_mtime, attr.mtimensec),
+            rdev_major: libc::major(st.st_rdev) as u32,
+            rdev_minor: libc::minor(st.st_rdev) as u32,
+            dev_major: libc::major(st.st_dev) as u32,
+            dev_minor: libc::minor(st.st_dev) as u32,
+            ..Default::default()
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone)]
 pub struct Kstatfs {
@@ -694,6 +768,67 @@ pub enum Opcode {
     CopyFileRange = 47,
     SetupMapping = 48,
     RemoveMapping = 49,
+    Tmpfile = 51,
+    Statx = 52,
+}
+
+impl TryFrom<u32> for Opcode {
+    type Error = ();
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            x if x == Opcode::Lookup as u32 => Ok(Opcode::Lookup),
+            x if x == Opcode::Forget as u32 => Ok(Opcode::Forget),
+            x if x == Opcode::Getattr as u32 => Ok(Opcode::Getattr),
+            x if x == Opcode::Setattr as u32 => Ok(Opcode::Setattr),
+            x if x == Opcode::Readlink as u32 => Ok(Opcode::Readlink),
+            x if x == Opcode::Symlink as u32 => Ok(Opcode::Symlink),
+            x if x == Opcode::Mknod as u32 => Ok(Opcode::Mknod),
+            x if x == Opcode::Mkdir as u32 => Ok(Opcode::Mkdir),
+            x if x == Opcode::Unlink as u32 => Ok(Opcode::Unlink),
+            x if x == Opcode::Rmdir as u32 => Ok(Opcode::Rmdir),
+            x if x == Opcode::Rename as u32 => Ok(Opcode::Rename),
+            x if x == Opcode::Link as u32 => Ok(Opcode::Link),
+            x if x == Opcode::Open as u32 => Ok(Opcode::Open),
+            x if x == Opcode::Read as u32 => Ok(Opcode::Read),
+            x if x == Opcode::Write as u32 => Ok(Opcode::Write),
+            x if x == Opcode::Statfs as u32 => Ok(Opcode::Statfs),
+            x if x == Opcode::Release as u32 => Ok(Opcode::Release),
+            x if x == Opcode::Fsync as u32 => Ok(Opcode::Fsync),
+            x if x == Opcode::Setxattr as u32 => Ok(Opcode::Setxattr),
+            x if x == Opcode::Getxattr as u32 => Ok(Opcode::Getxattr),
+            x if x == Opcode::Listxattr as u32 => Ok(Opcode::Listxattr),
+            x if x == Opcode::Removexattr as u32 => Ok(Opcode::Removexattr),
+            x if x == Opcode::Flush as u32 => Ok(Opcode::Flush),
+            x if x == Opcode::Init as u32 => Ok(Opcode::Init),
+            x if x == Opcode::Opendir as u32 => Ok(Opcode::Opendir),
+            x if x == Opcode::Readdir as u32 => Ok(Opcode::Readdir),
+            x if x == Opcode::Releasedir as u32 => Ok(Opcode::Releasedir),
+            x if x == Opcode::Fsyncdir as u32 => Ok(Opcode::Fsyncdir),
+            x if x == Opcode::Getlk as u32 => Ok(Opcode::Getlk),
+            x if x == Opcode::Setlk as u32 => Ok(Opcode::Setlk),
+            x if x == Opcode::Setlkw as u32 => Ok(Opcode::Setlkw),
+            x if x == Opcode::Access as u32 => Ok(Opcode::Access),
+            x if x == Opcode::Create as u32 => Ok(Opcode::Create),
+            x if x == Opcode::Interrupt as u32 => Ok(Opcode::Interrupt),
+            x if x == Opcode::Bmap as u32 => Ok(Opcode::Bmap),
+            x if x == Opcode::Destroy as u32 => Ok(Opcode::Destroy),
+            x if x == Opcode::Ioctl as u32 => Ok(Opcode::Ioctl),
+            x if x == Opcode::Poll as u32 => Ok(Opcode::Poll),
+            x if x == Opcode::NotifyReply as u32 => Ok(Opcode::NotifyReply),
+            x if x == Opcode::BatchForget as u32 => Ok(Opcode::BatchForget),
+            x if x == Opcode::Fallocate as u32 => Ok(Opcode::Fallocate),
+            x if x == Opcode::Readdirplus as u32 => Ok(Opcode::Readdirplus),
+            x if x == Opcode::Rename2 as u32 => Ok(Opcode::Rename2),
+            x if x == Opcode::Lseek as u32 => Ok(Opcode::Lseek),
+            x if x == Opcode::CopyFileRange as u32 => Ok(Opcode::CopyFileRange),
+            x if x == Opcode::SetupMapping as u32 => Ok(Opcode::SetupMapping),
+            x if x == Opcode::RemoveMapping as u32 => Ok(Opcode::RemoveMapping),
+            x if x == Opcode::Tmpfile as u32 => Ok(Opcode::Tmpfile),
+            x if x == Opcode::Statx as u32 => Ok(Opcode::Statx),
+            _ => Err(()),
+        }
+    }
 }
 
 #[repr(u32)]
@@ -763,6 +898,28 @@ pub struct AttrOut {
 }
 unsafe impl ByteValued for AttrOut {}
 
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct StatxIn {
+    pub getattr_flags: u32,
+    pub reserved: u32,
+    pub fh: u64,
+    pub sx_flags: u32,
+    pub sx_mask: u32,
+}
+unsafe impl ByteValued for StatxIn {}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct StatxOut {
+    pub attr_valid: u64, /* Cache timeout for the attributes */
+    pub attr_valid_nsec: u32,
+    pub flags: u32,
+    pub spare: [u64; 2],
+    pub stat: Statx,
+}
+unsafe impl ByteValued for StatxOut {}
+
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone)]
 pub struct MknodIn {
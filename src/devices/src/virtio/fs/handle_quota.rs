@@ -0,0 +1,130 @@
+//! The quota on the handles the guest keeps open on a share.
+//!
+//! Every file or directory the guest opens holds a handle in the device, and with it a host file
+//! descriptor and some memory, until the guest releases it. A guest process opening files without
+//! ever closing them would otherwise make the device hold millions of handles. With a quota, the
+//! opens past it fail with `EMFILE`, which the guest reports to the process as it would its own
+//! limit, and the other processes of the guest can keep opening files once some are released.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// The count of the handles open on a share, with an optional limit on it.
+///
+/// Cloning it gives another handle to the same count.
+#[derive(Clone, Debug, Default)]
+pub struct FsHandleQuota(Arc<QuotaState>);
+
+#[derive(Debug, Default)]
+struct QuotaState {
+    /// The largest number of open handles, 0 for no limit.
+    limit: AtomicUsize,
+    open: AtomicUsize,
+    /// The number of opens that failed for being past the limit.
+    denied: AtomicU64,
+    /// Whether the limit was reached since it was last reported.
+    reported: AtomicBool,
+}
+
+/// A handle counted by a [`FsHandleQuota`], until dropped.
+#[derive(Debug)]
+pub(crate) struct HandleGrant(Arc<QuotaState>);
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl FsHandleQuota {
+    /// Sets the largest number of handles the guest may keep open, or removes the limit. Lowering
+    /// it below the handles already open doesn't close any, but fails the opens until enough are
+    /// released.
+    pub fn set_limit(&self, limit: Option<usize>) {
+        self.0.limit.store(limit.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        let limit = self.0.limit.load(Ordering::Relaxed);
+        (limit != 0).then_some(limit)
+    }
+
+    /// Returns the number of handles the guest keeps open.
+    pub fn open(&self) -> usize {
+        self.0.open.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of opens that failed for being past the limit.
+    pub fn denied(&self) -> u64 {
+        self.0.denied.load(Ordering::Relaxed)
+    }
+
+    /// Counts a new handle, failing with `EMFILE` if the guest already keeps as many open as the
+    /// limit allows. Reaching the limit is reported once, until the handles go below it again.
+    pub(crate) fn acquire(&self) -> io::Result<HandleGrant> {
+        let limit = self.0.limit.load(Ordering::Relaxed);
+        let acquired = self
+            .0
+            .open
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |open| {
+                (limit == 0 || open < limit).then_some(open + 1)
+            });
+
+        match acquired {
+            Ok(_) => Ok(HandleGrant(self.0.clone())),
+            Err(open) => {
+                self.0.denied.fetch_add(1, Ordering::Relaxed);
+                if !self.0.reported.swap(true, Ordering::Relaxed) {
+                    warn!(
+                        "virtio-fs: the guest keeps {open} handles open, the limit of the share, \
+                         failing the next opens with EMFILE"
+                    );
+                }
+                Err(io::Error::from_raw_os_error(libc::EMFILE))
+            }
+        }
+    }
+}
+
+impl Drop for HandleGrant {
+    fn drop(&mut self) {
+        let open = self.0.open.fetch_sub(1, Ordering::Relaxed) - 1;
+        if open < self.0.limit.load(Ordering::Relaxed) {
+            self.0.reported.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn handle_quota() {
+        let quota = FsHandleQuota::default();
+        assert_eq!(quota.limit(), None);
+
+        // Without a limit, the handles are only counted
+        let grants: Vec<HandleGrant> = (0..3).map(|_| quota.acquire().unwrap()).collect();
+        assert_eq!(quota.open(), 3);
+
+        // Lowering the limit fails the opens until enough handles are released
+        quota.set_limit(Some(2));
+        let err = quota.acquire().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EMFILE));
+        assert_eq!(quota.denied(), 1);
+        drop(grants);
+        assert_eq!(quota.open(), 0);
+
+        let first = quota.acquire().unwrap();
+        let _second = quota.acquire().unwrap();
+        assert!(quota.acquire().is_err());
+        assert_eq!(quota.denied(), 2);
+        drop(first);
+        let _third = quota.acquire().unwrap();
+        assert_eq!(quota.open(), 2);
+    }
+}
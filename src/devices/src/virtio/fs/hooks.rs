@@ -0,0 +1,185 @@
+//! Hooks the embedder runs at points of the life of a share.
+//!
+//! A hook is a callback registered for a [`FsHookPoint`], called with the path of the entry the
+//! share is about to change, relative to its root. The hooks before a copy-up and before an unlink
+//! may veto it by failing, the request of the guest then failing with their error: an embedder
+//! scanning the files before they reach the writable layer fails the copy-up of the ones it
+//! rejects. The hooks of the other points are only told, their errors being logged.
+//!
+//! The hooks are called from the thread handling the request of the guest, which waits for them,
+//! and the hooks of a share may be called from several threads at once. A slow hook holds up the
+//! guest process waiting for the request, and the other requests waiting for the same entries.
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// The points of the life of a share the embedder may hook.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsHookPoint {
+    /// Before the share starts serving the guest, when the guest mounts it or a snapshot of the
+    /// device is restored. The path is empty.
+    PreMount,
+    /// After the guest unmounted the share, which only FUSE guests report. The path is empty.
+    PostUnmount,
+    /// Before an entry of a lower layer of an overlay is copied up to the top layer, for the file
+    /// the guest changes and for each of its directories not in the top layer yet. May veto.
+    PreCopyUp,
+    /// Before the guest unlinks a file or removes a directory. May veto.
+    PreUnlink,
+}
+
+/// What a hook is called for.
+#[derive(Clone, Copy, Debug)]
+pub struct FsHookEvent<'a> {
+    pub point: FsHookPoint,
+    /// The path of the entry, relative to the root of the share.
+    pub path: &'a Path,
+}
+
+/// Called at the point a hook is registered for, on the thread handling the request. An error
+/// vetoes the change if the point allows it.
+pub type FsHookFn = Arc<dyn Fn(&FsHookEvent) -> io::Result<()> + Send + Sync>;
+
+/// A callback of the embedder, called at `point`.
+#[derive(Clone)]
+pub struct FsHook {
+    pub point: FsHookPoint,
+    pub callback: FsHookFn,
+}
+
+/// The hooks registered on a share, called in the order they were registered.
+///
+/// Cloning it gives another handle to the same hooks.
+#[derive(Clone, Default)]
+pub struct FsHooks(Arc<RwLock<Vec<FsHook>>>);
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl FsHookPoint {
+    /// Whether the hooks at this point may veto the change they are called for.
+    pub fn can_veto(self) -> bool {
+        matches!(self, FsHookPoint::PreCopyUp | FsHookPoint::PreUnlink)
+    }
+}
+
+impl FsHooks {
+    /// Registers `hook`, called from the next time the share reaches its point.
+    pub fn add(&self, hook: FsHook) {
+        self.0.write().unwrap().push(hook);
+    }
+
+    /// Whether any hook is registered at `point`, for the callers to skip finding the path.
+    pub(crate) fn has(&self, point: FsHookPoint) -> bool {
+        self.0
+            .read()
+            .unwrap()
+            .iter()
+            .any(|hook| hook.point == point)
+    }
+
+    /// Calls the hooks registered at `point` on `path`. The first one failing at a point that may
+    /// veto stops the others, its error being returned.
+    pub(crate) fn run(&self, point: FsHookPoint, path: &Path) -> io::Result<()> {
+        // Called without the lock, so that the hooks may register others
+        let callbacks: Vec<FsHookFn> = self
+            .0
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|hook| hook.point == point)
+            .map(|hook| hook.callback.clone())
+            .collect();
+
+        let event = FsHookEvent { point, path };
+        for callback in callbacks {
+            if let Err(e) = callback(&event) {
+                if point.can_veto() {
+                    debug!("virtio-fs: a {point:?} hook vetoed {}: {e}", path.display());
+                    return Err(e);
+                }
+                warn!("virtio-fs: a {point:?} hook failed: {e}");
+            }
+        }
+        Ok(())
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Trait Implementations
+//--------------------------------------------------------------------------------------------------
+
+impl fmt::Debug for FsHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FsHook")
+            .field("point", &self.point)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Debug for FsHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.read().unwrap().iter())
+            .finish()
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn veto() {
+        let hooks = FsHooks::default();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        for (point, fail) in [
+            (FsHookPoint::PreUnlink, true),
+            (FsHookPoint::PreUnlink, false),
+            (FsHookPoint::PreMount, true),
+            (FsHookPoint::PreMount, false),
+        ] {
+            let calls = calls.clone();
+            hooks.add(FsHook {
+                point,
+                callback: Arc::new(move |event| {
+                    calls.lock().unwrap().push(event.point);
+                    match fail {
+                        true => Err(io::Error::from_raw_os_error(libc::EACCES)),
+                        false => Ok(()),
+                    }
+                }),
+            });
+        }
+        assert!(!hooks.has(FsHookPoint::PreCopyUp));
+
+        // A veto stops the hooks after it
+        let err = hooks
+            .run(FsHookPoint::PreUnlink, Path::new("a/b"))
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+        assert_eq!(*calls.lock().unwrap(), [FsHookPoint::PreUnlink]);
+
+        // The points that can't be vetoed call every hook
+        calls.lock().unwrap().clear();
+        hooks.run(FsHookPoint::PreMount, Path::new("")).unwrap();
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [FsHookPoint::PreMount, FsHookPoint::PreMount]
+        );
+        hooks.run(FsHookPoint::PreCopyUp, Path::new("a")).unwrap();
+    }
+}
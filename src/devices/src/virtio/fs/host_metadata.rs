@@ -0,0 +1,492 @@
+//! Sanitization of the metadata macOS attaches to files, for the layers leaving the overlay to be
+//! used on Linux.
+//!
+//! macOS records extended attributes of its own on the files it touches, such as
+//! `com.apple.quarantine` on downloads or `com.apple.ResourceFork`, and on file systems without
+//! extended attributes it stores them along with the Finder information in an AppleDouble file
+//! `._<name>` next to the file. Linux consumers know neither: the attributes are noise in an
+//! image, and the AppleDouble files show up as files of their own. A sanitization keeps the
+//! extended attributes whose names match a retention list and drops the others, and turns each
+//! AppleDouble file back into the extended attributes of the file it describes, going through the
+//! same list.
+
+use std::{
+    ffi::{CStr, CString},
+    io,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    ptr::null_mut,
+};
+
+use super::fs_utils::einval;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The prefix of the names of the AppleDouble files
+const APPLE_DOUBLE_PREFIX: &[u8] = b"._";
+
+/// The magic number starting an AppleDouble file
+const APPLE_DOUBLE_MAGIC: u32 = 0x0005_1607;
+
+/// The magic number starting the extended attributes macOS stores after the Finder information
+const ATTR_MAGIC: u32 = 0x4154_5452;
+
+/// The id of the entry holding the resource fork
+const RESOURCE_FORK_ID: u32 = 2;
+
+/// The id of the entry holding the Finder information, followed by the extended attributes
+const FINDER_INFO_ID: u32 = 9;
+
+/// The size of the Finder information
+const FINDER_INFO_SIZE: usize = 32;
+
+/// The extended attribute holding the resource fork on macOS
+const RESOURCE_FORK_XATTR: &[u8] = b"com.apple.ResourceFork";
+
+/// The extended attribute holding the Finder information on macOS
+const FINDER_INFO_XATTR: &[u8] = b"com.apple.FinderInfo";
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// An extended attribute, as its name and value.
+pub(crate) type Xattr = (Vec<u8>, Vec<u8>);
+
+/// The extended attributes a sanitization keeps.
+pub(crate) struct XattrRetention<'a> {
+    /// Prefixes of the names kept
+    pub(crate) keep: &'a [String],
+
+    /// Whether a name is one of the overlay's own, which never leave it
+    pub(crate) is_internal: &'a dyn Fn(&[u8]) -> bool,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl XattrRetention<'_> {
+    /// Whether the extended attribute `name` is kept.
+    pub(crate) fn keeps(&self, name: &[u8]) -> bool {
+        !(self.is_internal)(name)
+            && self
+                .keep
+                .iter()
+                .any(|prefix| name.starts_with(prefix.as_bytes()))
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Returns the name of the file described by the AppleDouble file `name`, if it is named like
+/// one.
+pub(crate) fn apple_double_target(name: &[u8]) -> Option<&[u8]> {
+    name.strip_prefix(APPLE_DOUBLE_PREFIX)
+        .filter(|target| !target.is_empty() && *target != b"." && *target != b"..")
+}
+
+/// Decodes the extended attributes stored in the AppleDouble file `data`: the resource fork, the
+/// Finder information unless it is blank, and the attributes stored after it. Returns `None` if
+/// `data` isn't an AppleDouble file.
+pub(crate) fn decode_apple_double(data: &[u8]) -> Option<Vec<Xattr>> {
+    if be_u32(data, 0)? != APPLE_DOUBLE_MAGIC {
+        return None;
+    }
+
+    let mut xattrs = Vec::new();
+    let num_entries = be_u16(data, 24)? as usize;
+    for i in 0..num_entries {
+        let entry = 26 + i * 12;
+        let id = be_u32(data, entry)?;
+        let offset = be_u32(data, entry + 4)? as usize;
+        let len = be_u32(data, entry + 8)? as usize;
+        let value = data.get(offset..offset.checked_add(len)?)?;
+
+        match id {
+            RESOURCE_FORK_ID if !value.is_empty() => {
+                xattrs.push((RESOURCE_FORK_XATTR.to_vec(), value.to_vec()));
+            }
+            FINDER_INFO_ID => {
+                let finder_info = value.get(..FINDER_INFO_SIZE)?;
+                if finder_info.iter().any(|b| *b != 0) {
+                    xattrs.push((FINDER_INFO_XATTR.to_vec(), finder_info.to_vec()));
+                }
+                // The attributes follow, aligned on 4 bytes
+                decode_attrs(data, offset + FINDER_INFO_SIZE + 2, &mut xattrs)?;
+            }
+            _ => (),
+        }
+    }
+
+    Some(xattrs)
+}
+
+/// Decodes the extended attributes of an AppleDouble file whose header starts at `start`, if
+/// there is one. The offsets of their values are from the start of the file.
+fn decode_attrs(data: &[u8], start: usize, xattrs: &mut Vec<Xattr>) -> Option<()> {
+    if be_u32(data, start) != Some(ATTR_MAGIC) {
+        return Some(());
+    }
+
+    let num_attrs = be_u16(data, start + 34)?;
+    let mut entry = start + 36;
+    for _ in 0..num_attrs {
+        let offset = be_u32(data, entry)? as usize;
+        let len = be_u32(data, entry + 4)? as usize;
+        let name_len = *data.get(entry + 10)? as usize;
+        let name = data.get(entry + 11..entry + 11 + name_len)?;
+        let name = name.split(|b| *b == 0).next().unwrap_or_default();
+        let value = data.get(offset..offset.checked_add(len)?)?;
+        xattrs.push((name.to_vec(), value.to_vec()));
+
+        entry = (entry + 11 + name_len + 3) & !3;
+    }
+
+    Some(())
+}
+
+fn be_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn be_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Returns the extended attributes of the entry at `path`, without following symlinks. Host file
+/// systems without extended attributes give none.
+pub(crate) fn read_xattrs(path: &Path) -> io::Result<Vec<Xattr>> {
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(|_| einval())?;
+    let names = match list(&path) {
+        Ok(names) => names,
+        Err(e) if is_unsupported(&e) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut xattrs = Vec::new();
+    for name in names.split(|b| *b == 0).filter(|name| !name.is_empty()) {
+        let name = CString::new(name).map_err(|_| einval())?;
+        match get(&path, &name) {
+            Ok(value) => xattrs.push((name.into_bytes(), value)),
+            // Removed since it was listed
+            Err(e) if is_missing(&e) => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(xattrs)
+}
+
+/// Removes the extended attributes of `file` that `retention` doesn't keep, leaving the internal
+/// ones alone. A symlink must be opened itself for its own attributes to be stripped.
+#[cfg(target_os = "macos")]
+pub(crate) fn strip_xattrs(file: &std::fs::File, retention: &XattrRetention) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let names = match list_fd(file.as_raw_fd()) {
+        Ok(names) => names,
+        Err(e) if is_unsupported(&e) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for name in names.split(|b| *b == 0).filter(|name| !name.is_empty()) {
+        if retention.keeps(name) || (retention.is_internal)(name) {
+            continue;
+        }
+
+        let name = CString::new(name).map_err(|_| einval())?;
+        match remove_fd(file.as_raw_fd(), &name) {
+            Err(e) if !is_missing(&e) => return Err(e),
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn list(path: &CStr) -> io::Result<Vec<u8>> {
+    loop {
+        let size = unsafe { libc::llistxattr(path.as_ptr(), null_mut(), 0) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0u8; size as usize];
+        let size = unsafe {
+            libc::llistxattr(
+                path.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.len(),
+            )
+        };
+        if size >= 0 {
+            buf.truncate(size as usize);
+            return Ok(buf);
+        }
+
+        // Attributes were added since the size was read
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn list(path: &CStr) -> io::Result<Vec<u8>> {
+    loop {
+        let size = unsafe { libc::listxattr(path.as_ptr(), null_mut(), 0, libc::XATTR_NOFOLLOW) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0u8; size as usize];
+        let size = unsafe {
+            libc::listxattr(
+                path.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.len(),
+                libc::XATTR_NOFOLLOW,
+            )
+        };
+        if size >= 0 {
+            buf.truncate(size as usize);
+            return Ok(buf);
+        }
+
+        // Attributes were added since the size was read
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn get(path: &CStr, name: &CStr) -> io::Result<Vec<u8>> {
+    loop {
+        let size = unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), null_mut(), 0) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0u8; size as usize];
+        let size = unsafe {
+            libc::lgetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        };
+        if size >= 0 {
+            buf.truncate(size as usize);
+            return Ok(buf);
+        }
+
+        // The value grew since its size was read
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn get(path: &CStr, name: &CStr) -> io::Result<Vec<u8>> {
+    loop {
+        let size = unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                null_mut(),
+                0,
+                0,
+                libc::XATTR_NOFOLLOW,
+            )
+        };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0u8; size as usize];
+        let size = unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                0,
+                libc::XATTR_NOFOLLOW,
+            )
+        };
+        if size >= 0 {
+            buf.truncate(size as usize);
+            return Ok(buf);
+        }
+
+        // The value grew since its size was read
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn list_fd(fd: libc::c_int) -> io::Result<Vec<u8>> {
+    loop {
+        let size = unsafe { libc::flistxattr(fd, null_mut(), 0, 0) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0u8; size as usize];
+        let size =
+            unsafe { libc::flistxattr(fd, buf.as_mut_ptr() as *mut libc::c_char, buf.len(), 0) };
+        if size >= 0 {
+            buf.truncate(size as usize);
+            return Ok(buf);
+        }
+
+        // Attributes were added since the size was read
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn remove_fd(fd: libc::c_int, name: &CStr) -> io::Result<()> {
+    if unsafe { libc::fremovexattr(fd, name.as_ptr(), 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn is_missing(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ENODATA)
+}
+
+#[cfg(target_os = "macos")]
+fn is_missing(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ENOATTR)
+}
+
+fn is_unsupported(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ENOTSUP) || e.raw_os_error() == Some(libc::EOPNOTSUPP)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Encodes an AppleDouble file holding `finder_info` and the attributes `attrs`, laid out
+    /// like macOS does.
+    fn apple_double(finder_info: [u8; 32], attrs: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut entries = Vec::new();
+        let mut data = Vec::new();
+        for (name, value) in attrs {
+            let name_len = name.len() + 1;
+            let entry_len = (11 + name_len + 3) & !3;
+            entries.push((name, value, entry_len));
+        }
+        let entries_len: usize = entries.iter().map(|(_, _, len)| len).sum();
+        let mut value_offset = 84 + 36 + entries_len;
+
+        let mut attr_entries = Vec::new();
+        for (name, value, entry_len) in entries {
+            let mut entry = Vec::new();
+            entry.extend_from_slice(&(value_offset as u32).to_be_bytes());
+            entry.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            entry.extend_from_slice(&0u16.to_be_bytes());
+            entry.push(name.len() as u8 + 1);
+            entry.extend_from_slice(name.as_bytes());
+            entry.resize(entry_len, 0);
+            attr_entries.extend_from_slice(&entry);
+            data.extend_from_slice(value);
+            value_offset += value.len();
+        }
+
+        let mut file = Vec::new();
+        file.extend_from_slice(&APPLE_DOUBLE_MAGIC.to_be_bytes());
+        file.extend_from_slice(&0x0002_0000u32.to_be_bytes());
+        file.extend_from_slice(&[0; 16]);
+        file.extend_from_slice(&2u16.to_be_bytes());
+        let total = 84 + 36 + attr_entries.len() + data.len();
+        for (id, offset, len) in [
+            (FINDER_INFO_ID, 50, total - 50),
+            (RESOURCE_FORK_ID, total, 0),
+        ] {
+            file.extend_from_slice(&id.to_be_bytes());
+            file.extend_from_slice(&(offset as u32).to_be_bytes());
+            file.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+        file.extend_from_slice(&finder_info);
+        file.extend_from_slice(&[0; 2]);
+        file.extend_from_slice(&ATTR_MAGIC.to_be_bytes());
+        file.extend_from_slice(&[0; 30]);
+        file.extend_from_slice(&(attrs.len() as u16).to_be_bytes());
+        file.extend_from_slice(&attr_entries);
+        file.extend_from_slice(&data);
+        file
+    }
+
+    #[test]
+    fn decode() {
+        let data = apple_double(
+            [0; 32],
+            &[("com.apple.quarantine", b"0081;"), ("user.tag", b"blue")],
+        );
+        assert_eq!(
+            decode_apple_double(&data).unwrap(),
+            [
+                (b"com.apple.quarantine".to_vec(), b"0081;".to_vec()),
+                (b"user.tag".to_vec(), b"blue".to_vec()),
+            ]
+        );
+
+        // The Finder information is only kept when set
+        let mut finder_info = [0; 32];
+        finder_info[..4].copy_from_slice(b"TEXT");
+        let data = apple_double(finder_info, &[]);
+        assert_eq!(
+            decode_apple_double(&data).unwrap(),
+            [(FINDER_INFO_XATTR.to_vec(), finder_info.to_vec())]
+        );
+
+        // Neither other files nor truncated ones are taken for AppleDouble files
+        assert!(decode_apple_double(b"not an AppleDouble file").is_none());
+        assert!(decode_apple_double(&data[..60]).is_none());
+
+        assert_eq!(apple_double_target(b"._file"), Some(&b"file"[..]));
+        assert_eq!(apple_double_target(b"._"), None);
+        assert_eq!(apple_double_target(b"file"), None);
+    }
+
+    #[test]
+    fn retention() {
+        let keep = ["user.".to_string(), "security.capability".to_string()];
+        let retention = XattrRetention {
+            keep: &keep,
+            is_internal: &|name| name.starts_with(b"user.overlay."),
+        };
+
+        assert!(retention.keeps(b"user.tag"));
+        assert!(retention.keeps(b"security.capability"));
+        assert!(!retention.keeps(b"com.apple.quarantine"));
+        assert!(!retention.keeps(b"security.selinux"));
+        assert!(!retention.keeps(b"user.overlay.origin"));
+    }
+}
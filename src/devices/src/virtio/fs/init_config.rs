@@ -0,0 +1,162 @@
+//! The configuration of the init binary, served read-only to the guest.
+//!
+//! The init binary reads its configuration from the root of the root file system before running
+//! the workload, so a guest able to change it decides what the next boot runs. A share protecting
+//! it serves it as a virtual file, whose content is what the file system holds there the first time
+//! the guest reaches it. Like any virtual file, the guest can neither write it nor remove, rename or
+//! replace its entry.
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime};
+
+use super::filesystem::{Context, Entry, FileSystem, ZeroCopyWriter};
+use super::fuse::ROOT_ID;
+use super::virtual_file::{FsVirtualAttr, FsVirtualFile};
+use super::FsImpl;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The name of the configuration at the root of the share.
+const INIT_CONFIG_NAME: &str = ".krun_config.json";
+
+/// The size of the reads of the configuration from the file system.
+const READ_SIZE: u32 = 1 << 16;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// The configuration as the file system held it when the guest first reached it, or the errno
+/// reading it failed with.
+struct InitConfig {
+    fs: Arc<FsImpl>,
+    snapshot: OnceLock<Result<Snapshot, i32>>,
+}
+
+struct Snapshot {
+    content: Vec<u8>,
+    mtime: SystemTime,
+}
+
+/// Collects the content read from the file system.
+struct SnapshotWriter(Vec<u8>);
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl InitConfig {
+    fn snapshot(&self) -> io::Result<&Snapshot> {
+        self.snapshot
+            .get_or_init(|| {
+                read_snapshot(&self.fs).map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))
+            })
+            .as_ref()
+            .map_err(|errno| io::Error::from_raw_os_error(*errno))
+    }
+}
+
+impl io::Write for SnapshotWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ZeroCopyWriter for SnapshotWriter {
+    fn write_from(&mut self, f: &File, count: usize, off: u64) -> io::Result<usize> {
+        let len = self.0.len();
+        self.0.resize(len + count, 0);
+        let res = f.read_at(&mut self.0[len..], off);
+        self.0.truncate(len + *res.as_ref().unwrap_or(&0));
+        res
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Returns the virtual file serving the configuration of the init binary in the share of `fs`.
+pub(crate) fn init_config_file(fs: &Arc<FsImpl>) -> FsVirtualFile {
+    let config = Arc::new(InitConfig {
+        fs: fs.clone(),
+        snapshot: OnceLock::new(),
+    });
+    let read_config = config.clone();
+
+    FsVirtualFile {
+        path: PathBuf::from(INIT_CONFIG_NAME),
+        mode: 0o444,
+        getattr: Arc::new(move || {
+            let snapshot = config.snapshot()?;
+            Ok(FsVirtualAttr {
+                size: snapshot.content.len() as u64,
+                mtime: snapshot.mtime,
+            })
+        }),
+        read: Arc::new(move |offset, buf| {
+            let content = &read_config.snapshot()?.content;
+            let content = content.get(offset as usize..).unwrap_or_default();
+            let len = content.len().min(buf.len());
+            buf[..len].copy_from_slice(&content[..len]);
+            Ok(len)
+        }),
+    }
+}
+
+/// Reads the configuration from the file system, on behalf of the device rather than of a guest
+/// process.
+fn read_snapshot(fs: &FsImpl) -> io::Result<Snapshot> {
+    let ctx = Context {
+        uid: 0,
+        gid: 0,
+        pid: 0,
+    };
+    let name = CString::new(INIT_CONFIG_NAME).unwrap();
+    let entry = fs.lookup(ctx, ROOT_ID, &name)?;
+    let res = read_entry(fs, ctx, &entry);
+    fs.forget(ctx, entry.inode, 1);
+    res
+}
+
+fn read_entry(fs: &FsImpl, ctx: Context, entry: &Entry) -> io::Result<Snapshot> {
+    if entry.attr.st_mode & libc::S_IFMT != libc::S_IFREG {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    let (handle, _) = fs.open(ctx, entry.inode, libc::O_RDONLY as u32)?;
+    let handle = handle.unwrap_or_default();
+    let mut w = SnapshotWriter(Vec::new());
+    let res = loop {
+        let offset = w.0.len() as u64;
+        match fs.read(ctx, entry.inode, handle, &mut w, READ_SIZE, offset, None, 0) {
+            Ok(0) => break Ok(()),
+            Ok(_) => (),
+            Err(e) => break Err(e),
+        }
+    };
+    let _ = fs.release(ctx, entry.inode, 0, handle, false, false, None);
+    res?;
+
+    let mtime = SystemTime::UNIX_EPOCH
+        + Duration::new(
+            entry.attr.st_mtime.max(0) as u64,
+            entry.attr.st_mtime_nsec as u32,
+        );
+    Ok(Snapshot {
+        content: w.0,
+        mtime,
+    })
+}
@@ -0,0 +1,295 @@
+//! The inode map of the top layer of an overlay, keeping the inode numbers of its files across
+//! restarts.
+//!
+//! The overlay numbers the inodes of the protocol as it first finds their files, so the same file
+//! gets another number each time the overlay is created, and the file handles the guest exports,
+//! which hold the numbers, no longer find it after the VM restarts. Tools that keep the handles or
+//! the numbers across runs, such as the incremental build tools or an NFS server in the guest,
+//! then take every file for a new one.
+//!
+//! With the map, each number given to a file is appended to a file of the top layer along with
+//! the device ID, inode number and birth time of the host file, and the overlay created again
+//! gives the same number to the host file it finds. A file born at another time has reused the
+//! inode number of a deleted one, and gets a number of its own. A file copied up is recorded again
+//! under its copy, so that it keeps its number. The records of a host file given another number
+//! since are dropped when the map is opened, and a record cut short by a crash is ignored. The map
+//! is then rewritten without the host files no longer found in the layers, through a new map
+//! renamed into place, which a crash may leave behind and the next open removes.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::{MetadataExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The name of the map in the top layer root. It starts as the whiteouts do, so that the backends
+/// hide it from the guest.
+pub(crate) const INODE_MAP_FILE: &str = ".wh..wh..inodes";
+
+/// The suffix of the new map written when the map is rewritten, next to it.
+const TMP_SUFFIX: &str = ".tmp";
+
+/// The size of a record: the device ID, inode number and birth time generation of the host file,
+/// the latter 0 if its file system doesn't record birth times, and its number.
+const RECORD_SIZE: usize = 4 * 8;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// The numbers given to the host files of an overlay, by device ID and inode number.
+pub(crate) struct InodeMap {
+    state: Mutex<MapState>,
+}
+
+struct MapState {
+    file: File,
+    /// The birth time generation and number of the host files.
+    inodes: HashMap<(u64, u64), (u64, u64)>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl InodeMap {
+    /// Opens the map of the overlay of `layers`, from the bottom one to the top one, in the top
+    /// layer, creating it if needed. The numbers below `first`, which the overlay gives its own
+    /// inodes, are left out.
+    pub(crate) fn open(layers: &[PathBuf], first: u64) -> io::Result<Self> {
+        let top = layers
+            .last()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let path = top.join(INODE_MAP_FILE);
+        match fs::remove_file(tmp_path(top)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        let mut inodes = HashMap::new();
+        for record in data.chunks_exact(RECORD_SIZE) {
+            let field =
+                |idx: usize| u64::from_le_bytes(record[idx * 8..(idx + 1) * 8].try_into().unwrap());
+            if field(3) >= first {
+                inodes.insert((field(0), field(1)), (field(2), field(3)));
+            }
+        }
+
+        // Only the last record of each host file still found is kept, and none cut short
+        if inodes.len() * RECORD_SIZE != data.len() {
+            match host_files(layers) {
+                Ok(found) => inodes.retain(|key, _| found.contains(key)),
+                Err(e) => warn!("virtio-fs: failed to list the files of the inode map: {e}"),
+            }
+            compact(top, &inodes)?;
+        }
+
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(&path)?;
+
+        Ok(InodeMap {
+            state: Mutex::new(MapState { file, inodes }),
+        })
+    }
+
+    /// Returns the number given to the host file `ino` of the device `dev` born at the time of
+    /// `generation`, if any. A file whose birth time isn't known on either side is taken for the
+    /// one recorded.
+    pub(crate) fn get(&self, dev: u64, ino: u64, generation: Option<u64>) -> Option<u64> {
+        let state = self.state.lock().unwrap();
+        let &(recorded, inode) = state.inodes.get(&(dev, ino))?;
+        match generation {
+            Some(generation) if recorded != 0 && recorded != generation => None,
+            _ => Some(inode),
+        }
+    }
+
+    /// Returns the number following the largest one of the map, or 0 if it is empty.
+    pub(crate) fn next_inode(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        state
+            .inodes
+            .values()
+            .map(|(_, inode)| *inode)
+            .max()
+            .map_or(0, |inode| inode + 1)
+    }
+
+    /// Records that the host file `ino` of the device `dev`, born at the time of `generation`, is
+    /// given the number `inode`.
+    pub(crate) fn record(
+        &self,
+        dev: u64,
+        ino: u64,
+        generation: Option<u64>,
+        inode: u64,
+    ) -> io::Result<()> {
+        let generation = generation.unwrap_or(0);
+        let mut state = self.state.lock().unwrap();
+        if state.inodes.insert((dev, ino), (generation, inode)) == Some((generation, inode)) {
+            return Ok(());
+        }
+        state.file.write_all(&encode(dev, ino, generation, inode))
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Whether `name` is one the map takes in the top layer root, the map's or that of the new map
+/// written when it is rewritten.
+pub(crate) fn is_map_name(name: &[u8]) -> bool {
+    name.strip_prefix(INODE_MAP_FILE.as_bytes())
+        .is_some_and(|rest| rest.is_empty() || rest == TMP_SUFFIX.as_bytes())
+}
+
+/// Returns the path of the new map written in the top layer `top` when the map is rewritten.
+fn tmp_path(top: &Path) -> PathBuf {
+    top.join(format!("{INODE_MAP_FILE}{TMP_SUFFIX}"))
+}
+
+/// Replaces the map in the top layer `top` with the records of `inodes` alone.
+fn compact(top: &Path, inodes: &HashMap<(u64, u64), (u64, u64)>) -> io::Result<()> {
+    let records: Vec<u8> = inodes
+        .iter()
+        .flat_map(|(&(dev, ino), &(generation, inode))| encode(dev, ino, generation, inode))
+        .collect();
+
+    // Write a new map and rename it into place, so that a crash leaves either map whole
+    let tmp_path = tmp_path(top);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)?;
+    file.write_all(&records)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, top.join(INODE_MAP_FILE))?;
+    File::open(top)?.sync_all()
+}
+
+/// Returns the device ID and inode number of every file of `layers`.
+fn host_files(layers: &[PathBuf]) -> io::Result<HashSet<(u64, u64)>> {
+    let mut found = HashSet::new();
+    let mut dirs = layers.to_vec();
+    while let Some(dir) = dirs.pop() {
+        let metadata = fs::symlink_metadata(&dir)?;
+        found.insert((metadata.dev(), metadata.ino()));
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                found.insert((metadata.dev(), metadata.ino()));
+            }
+        }
+    }
+    Ok(found)
+}
+
+fn encode(dev: u64, ino: u64, generation: u64, inode: u64) -> [u8; RECORD_SIZE] {
+    let mut record = [0; RECORD_SIZE];
+    for (idx, field) in [dev, ino, generation, inode].into_iter().enumerate() {
+        record[idx * 8..(idx + 1) * 8].copy_from_slice(&field.to_le_bytes());
+    }
+    record
+}
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn create(dir: &Path, name: &str) -> (u64, u64) {
+        let metadata = File::create(dir.join(name)).unwrap().metadata().unwrap();
+        (metadata.dev(), metadata.ino())
+    }
+
+    #[test]
+    fn reopen() {
+        let lower = tempfile::tempdir().unwrap();
+        let top = tempfile::tempdir().unwrap();
+        let layers = [lower.path().to_path_buf(), top.path().to_path_buf()];
+        fs::create_dir(lower.path().join("dir")).unwrap();
+        let a = create(&lower.path().join("dir"), "a");
+        let b = create(top.path(), "b");
+        let c = create(top.path(), "c");
+
+        let map = InodeMap::open(&layers, 3).unwrap();
+        assert_eq!(map.next_inode(), 0);
+        map.record(a.0, a.1, None, 3).unwrap();
+        map.record(b.0, b.1, None, 4).unwrap();
+        map.record(a.0, a.1, None, 5).unwrap();
+        map.record(c.0, c.1, None, 6).unwrap();
+        drop(map);
+
+        // A record cut short is ignored, and the map is compacted without the files removed
+        let path = top.path().join(INODE_MAP_FILE);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[1, 2, 3]).unwrap();
+        drop(file);
+        fs::remove_file(top.path().join("c")).unwrap();
+
+        let map = InodeMap::open(&layers, 3).unwrap();
+        assert_eq!(map.get(a.0, a.1, None), Some(5));
+        assert_eq!(map.get(b.0, b.1, None), Some(4));
+        assert_eq!(map.get(c.0, c.1, None), None);
+        assert_eq!(map.next_inode(), 6);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 2 * RECORD_SIZE as u64);
+        assert!(!tmp_path(top.path()).exists());
+
+        // The numbers the overlay now gives its own inodes are left out, and a new map left
+        // behind by a crash is removed
+        drop(map);
+        fs::write(tmp_path(top.path()), [1, 2, 3]).unwrap();
+        let map = InodeMap::open(&layers, 5).unwrap();
+        assert!(!tmp_path(top.path()).exists());
+        assert_eq!(map.get(b.0, b.1, None), None);
+        assert_eq!(map.get(a.0, a.1, None), Some(5));
+    }
+
+    #[test]
+    fn reused_inode() {
+        let top = tempfile::tempdir().unwrap();
+        let layers = [top.path().to_path_buf()];
+        let a = create(top.path(), "a");
+
+        let map = InodeMap::open(&layers, 3).unwrap();
+        map.record(a.0, a.1, Some(10), 3).unwrap();
+        drop(map);
+
+        // A file born at another time reused the inode number of a deleted one
+        let map = InodeMap::open(&layers, 3).unwrap();
+        assert_eq!(map.get(a.0, a.1, Some(10)), Some(3));
+        assert_eq!(map.get(a.0, a.1, Some(11)), None);
+        assert_eq!(map.get(a.0, a.1, None), Some(3));
+
+        // The new file is recorded in its place
+        map.record(a.0, a.1, Some(11), 4).unwrap();
+        drop(map);
+        let map = InodeMap::open(&layers, 3).unwrap();
+        assert_eq!(map.get(a.0, a.1, Some(10)), None);
+        assert_eq!(map.get(a.0, a.1, Some(11)), Some(4));
+    }
+}
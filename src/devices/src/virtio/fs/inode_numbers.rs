@@ -0,0 +1,120 @@
+//! The inode numbers the guest sees, folded into 32 bits for the guests that can't take larger
+//! ones.
+//!
+//! The server reports the inode numbers of the host files, which take all 64 bits on some host
+//! file systems, and the virtual files are numbered from 2^63. A 32-bit guest userspace built
+//! without large file support fails `stat` and `readdir` with `EOVERFLOW` on such numbers. With
+//! [`FsInodeNumbers::Folded32`], every number is folded into 32 bits before it is reported, the
+//! same way in the attributes and in the directory entries, so that the guest sees a consistent
+//! number for each file. The numbers that already fit are kept as they are.
+//!
+//! Folding maps distinct numbers to the same one at times, which would make tools such as `find`,
+//! `du` or `tar` take distinct files for hard links of a single one. The server remembers the
+//! number it gave each file, and gives the next free one to a file whose number is taken. The
+//! numbers then depend on the order the guest first reaches the files in, and may change when
+//! the device restarts.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use super::kinds::FsInodeNumbers;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The step between the numbers tried for a file whose folded number is taken, odd so that every
+/// number is tried in turn.
+const PROBE_STEP: u32 = 0x9e37_79b9;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// The inode numbers reported to the guest of a share, kept across the servers of the share a
+/// pause may drop. Cloning it gives another handle to the same numbers.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct InodeNumbers(Option<Arc<Mutex<FoldTable>>>);
+
+#[derive(Debug, Default)]
+struct FoldTable {
+    /// The number reported for each host number.
+    folded: HashMap<u64, u32>,
+    /// The host number each reported number was given to.
+    owners: HashMap<u32, u64>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl InodeNumbers {
+    pub(crate) fn new(numbers: FsInodeNumbers) -> Self {
+        match numbers {
+            FsInodeNumbers::Native => InodeNumbers(None),
+            FsInodeNumbers::Folded32 => InodeNumbers(Some(Default::default())),
+        }
+    }
+
+    /// Returns the number reported to the guest for the host number `ino`.
+    pub(crate) fn guest_ino(&self, ino: u64) -> u64 {
+        let Some(table) = &self.0 else {
+            return ino;
+        };
+        table.lock().unwrap().fold(ino) as u64
+    }
+}
+
+impl FoldTable {
+    fn fold(&mut self, ino: u64) -> u32 {
+        if let Some(folded) = self.folded.get(&ino) {
+            return *folded;
+        }
+
+        let mut folded = if ino <= u32::MAX as u64 {
+            ino as u32
+        } else {
+            (ino ^ (ino >> 32)) as u32
+        };
+        if folded == 0 || self.owners.contains_key(&folded) {
+            debug!("virtio-fs: the folded inode number {folded} of {ino} is taken");
+            while folded == 0 || self.owners.contains_key(&folded) {
+                folded = folded.wrapping_add(PROBE_STEP);
+            }
+        }
+
+        self.folded.insert(ino, folded);
+        self.owners.insert(folded, ino);
+        folded
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fold() {
+        let native = InodeNumbers::new(FsInodeNumbers::Native);
+        assert_eq!(native.guest_ino(1 << 40), 1 << 40);
+
+        let numbers = InodeNumbers::new(FsInodeNumbers::Folded32);
+        assert_eq!(numbers.guest_ino(7), 7);
+        let large = (5 << 32) | 9;
+        assert_eq!(numbers.guest_ino(large), 12);
+        assert_eq!(numbers.guest_ino(large), 12);
+
+        // The number a file would fold to is taken, by a native one or a folded one
+        let taken = numbers.guest_ino((3 << 32) | 4);
+        assert_ne!(taken, 7);
+        assert_ne!(numbers.guest_ino(12), 12);
+        assert_eq!(numbers.clone().guest_ino((3 << 32) | 4), taken);
+
+        // Nothing is reported as 0
+        assert_ne!(numbers.guest_ino(1 << 32 | 1), 0);
+    }
+}
@@ -0,0 +1,186 @@
+//! Paths of the inodes of an overlay, relative to the layer roots.
+//!
+//! Each inode only records its parent and its name in it, so the paths of the inodes of a deep
+//! tree share their prefixes, and renaming a directory moves everything below it at once. The
+//! names are interned in a `NameTable`, shared by all the entries of the same name.
+
+use std::{
+    collections::HashSet,
+    ffi::CStr,
+    sync::{Arc, RwLock},
+};
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The number of names under which a `NameTable` is never compacted.
+const MIN_COMPACT_LEN: usize = 1024;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// An interned file name.
+pub(crate) type Name = Arc<CStr>;
+
+/// The interned names of the entries of an overlay.
+///
+/// The names nobody refers to anymore are dropped lazily, once the table has doubled in size since
+/// it was last compacted, which keeps interning amortized constant time.
+#[derive(Debug)]
+pub(crate) struct NameTable {
+    names: HashSet<Name>,
+    compact_at: usize,
+}
+
+/// The path of an inode, as its name in the directory of its parent.
+///
+/// The root of a layer has no parent. The path is rebuilt on demand by walking up the parents.
+#[derive(Debug)]
+pub(crate) struct InodePath {
+    link: RwLock<Option<(Arc<InodePath>, Name)>>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl NameTable {
+    /// Returns the interned `name`, interning it if needed.
+    pub(crate) fn intern(&mut self, name: &CStr) -> Name {
+        if let Some(name) = self.names.get(name) {
+            return name.clone();
+        }
+
+        if self.names.len() >= self.compact_at {
+            self.compact();
+        }
+
+        let name = Name::from(name);
+        self.names.insert(name.clone());
+        name
+    }
+
+    /// Drops the names only the table refers to.
+    fn compact(&mut self) {
+        self.names.retain(|name| Arc::strong_count(name) > 1);
+        self.compact_at = (self.names.len() * 2).max(MIN_COMPACT_LEN);
+    }
+}
+
+impl InodePath {
+    /// Returns the path of a layer root.
+    pub(crate) fn root() -> Arc<Self> {
+        Arc::new(InodePath {
+            link: RwLock::new(None),
+        })
+    }
+
+    /// Returns the path of the entry `name` of the directory at this path.
+    pub(crate) fn child(self: &Arc<Self>, name: Name) -> Arc<Self> {
+        Arc::new(InodePath {
+            link: RwLock::new(Some((self.clone(), name))),
+        })
+    }
+
+    /// Returns the path of the parent of the inode and its name in it, or `None` for a layer root.
+    pub(crate) fn link(&self) -> Option<(Arc<InodePath>, Name)> {
+        self.link.read().unwrap().clone()
+    }
+
+    /// Returns the name of the inode in its parent, or `None` for a layer root.
+    pub(crate) fn name(&self) -> Option<Name> {
+        self.link().map(|(_, name)| name)
+    }
+
+    /// Returns the names making up the path, from the layer root down.
+    pub(crate) fn names(&self) -> Vec<Name> {
+        let mut names = Vec::new();
+        let mut link = self.link.read().unwrap().clone();
+        while let Some((parent, name)) = link {
+            names.push(name);
+            link = parent.link.read().unwrap().clone();
+        }
+        names.reverse();
+        names
+    }
+
+    /// Returns the number of names making up the path.
+    pub(crate) fn depth(&self) -> usize {
+        let mut depth = 0;
+        let mut parent = self.link.read().unwrap().as_ref().map(|(p, _)| p.clone());
+        while let Some(path) = parent {
+            depth += 1;
+            parent = path.link.read().unwrap().as_ref().map(|(p, _)| p.clone());
+        }
+        depth
+    }
+
+    /// Moves the inode to the entry `name` of the directory at `parent`, along with everything
+    /// below it.
+    pub(crate) fn rename(&self, parent: &Arc<InodePath>, name: Name) {
+        *self.link.write().unwrap() = Some((parent.clone(), name));
+    }
+}
+
+impl Default for NameTable {
+    fn default() -> Self {
+        NameTable {
+            names: HashSet::new(),
+            compact_at: MIN_COMPACT_LEN,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::CString;
+
+    use super::*;
+
+    fn intern(names: &mut NameTable, name: &str) -> Name {
+        names.intern(&CString::new(name).unwrap())
+    }
+
+    fn path_of(path: &InodePath) -> String {
+        let names: Vec<_> = path
+            .names()
+            .iter()
+            .map(|n| n.to_str().unwrap().to_owned())
+            .collect();
+        names.join("/")
+    }
+
+    #[test]
+    fn rename() {
+        let mut names = NameTable::default();
+        let root = InodePath::root();
+        let a = root.child(intern(&mut names, "a"));
+        let b = a.child(intern(&mut names, "b"));
+        let c = b.child(intern(&mut names, "c"));
+        assert_eq!(path_of(&c), "a/b/c");
+        assert_eq!((root.depth(), c.depth()), (0, 3));
+        assert_eq!(root.name(), None);
+
+        // Everything below a renamed directory moves along with it
+        let d = root.child(intern(&mut names, "d"));
+        b.rename(&d, intern(&mut names, "e"));
+        assert_eq!(path_of(&c), "d/e/c");
+        assert_eq!(path_of(&b), "d/e");
+        assert_eq!(c.name().unwrap().to_bytes(), b"c");
+    }
+
+    #[test]
+    fn compact() {
+        let mut names = NameTable::default();
+        let kept = intern(&mut names, "kept");
+        for i in 0..MIN_COMPACT_LEN * 3 {
+            intern(&mut names, &i.to_string());
+        }
+
+        // The names nobody uses are dropped, the others are still shared
+        assert!(names.names.len() <= MIN_COMPACT_LEN);
+        assert!(Arc::ptr_eq(&kept, &intern(&mut names, "kept")));
+    }
+}
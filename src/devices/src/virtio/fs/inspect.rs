@@ -0,0 +1,348 @@
+//! Read-only access to the view the guest has of a share, for host tools.
+//!
+//! The files the guest sees may be nowhere on the host as such, e.g. when they are merged from the
+//! layers of an overlay. Host tools connect to a UNIX socket served by a thread of the device and
+//! read the share through the file system instance serving the guest, so they see exactly what the
+//! guest does. The requests run as root, on behalf of the device rather than of a guest process,
+//! and only ever look up, list and read entries.
+//!
+//! Every message on the connection is made of an 8-byte header, holding the message type and the
+//! payload length as little endian `u32`s, followed by the payload. The paths are relative to the
+//! root of the share, with `/` separated components, and may not go up with `..`. Each request is
+//! answered with a single message:
+//!
+//! - `STAT` (1): the path. Answered with `ATTR`.
+//! - `LIST` (2): the path of a directory. Answered with `ENTRIES`.
+//! - `READ` (3): `offset: u64`, `length: u32` and the path of a regular file. Answered with
+//!   `DATA`, holding at most `MAX_READ_SIZE` bytes, and less at the end of the file.
+//! - `ATTR` (4): `mode: u32`, `uid: u32`, `gid: u32`, `nlink: u32`, `size: u64`, `mtime: i64` and
+//!   `mtime_nsec: u32`, as stored on the host.
+//! - `ENTRIES` (5): for each entry of the directory, `type: u32`, as the `DT_*` constants, and the
+//!   NUL-terminated name.
+//! - `DATA` (6): the content read.
+//! - `ERROR` (7): `errno: u32`, using Linux values. `EINVAL` is returned for malformed requests
+//!   and `E2BIG` for requests or directory listings over `MAX_PAYLOAD_SIZE`.
+
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Weak};
+use std::thread;
+
+use super::super::linux_errno::linux_error;
+use super::bindings;
+use super::filesystem::{Context, FileSystem, ZeroCopyWriter};
+use super::fuse::ROOT_ID;
+use super::FsImpl;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+const MSG_STAT: u32 = 1;
+const MSG_LIST: u32 = 2;
+const MSG_READ: u32 = 3;
+const MSG_ATTR: u32 = 4;
+const MSG_ENTRIES: u32 = 5;
+const MSG_DATA: u32 = 6;
+const MSG_ERROR: u32 = 7;
+
+/// Largest payload of a request or of a directory listing.
+const MAX_PAYLOAD_SIZE: u32 = 16 << 20;
+
+/// Largest content returned by a single `READ`.
+const MAX_READ_SIZE: u32 = 1 << 20;
+
+/// The size of the directory reads from the file system.
+const READDIR_SIZE: u32 = 1 << 16;
+
+/// The credentials the requests run with.
+const CTX: Context = Context {
+    uid: 0,
+    gid: 0,
+    pid: 0,
+};
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// The entries looked up to reach a path, forgotten when dropped.
+struct Lookups<'a> {
+    fs: &'a FsImpl,
+    inodes: Vec<u64>,
+}
+
+/// Collects the content read from the file system.
+struct DataWriter(Vec<u8>);
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl Lookups<'_> {
+    /// Returns the inode reached, the root of the share if nothing was looked up.
+    fn inode(&self) -> u64 {
+        self.inodes.last().copied().unwrap_or(ROOT_ID)
+    }
+}
+
+impl Drop for Lookups<'_> {
+    fn drop(&mut self) {
+        for inode in self.inodes.drain(..) {
+            self.fs.forget(CTX, inode, 1);
+        }
+    }
+}
+
+impl io::Write for DataWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ZeroCopyWriter for DataWriter {
+    fn write_from(&mut self, f: &File, count: usize, off: u64) -> io::Result<usize> {
+        let len = self.0.len();
+        self.0.resize(len + count, 0);
+        let res = f.read_at(&mut self.0[len..], off);
+        self.0.truncate(len + *res.as_ref().unwrap_or(&0));
+        res
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Listens on `socket_path` and serves the host tools reading the share of `fs` from a dedicated
+/// thread, one connection at a time. The thread stops serving once the file system is dropped.
+pub(crate) fn start(fs: &Arc<FsImpl>, socket_path: &Path) -> io::Result<()> {
+    let _ = fs::remove_file(socket_path);
+    let listener = UnixListener::bind(socket_path)?;
+    let fs = Arc::downgrade(fs);
+
+    thread::Builder::new()
+        .name("fs inspect".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = serve(&fs, stream) {
+                            debug!("fs inspection connection closed: {e}");
+                        }
+                    }
+                    Err(e) => error!("failed to accept fs inspection connection: {e}"),
+                }
+                if fs.strong_count() == 0 {
+                    break;
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+fn serve(fs: &Weak<FsImpl>, mut stream: UnixStream) -> io::Result<()> {
+    loop {
+        let mut header = [0u8; 8];
+        match stream.read_exact(&mut header) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        let kind = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let len = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        if len > MAX_PAYLOAD_SIZE {
+            write_message(&mut stream, MSG_ERROR, &(libc::E2BIG as u32).to_le_bytes())?;
+            return Ok(());
+        }
+        let mut payload = vec![0u8; len as usize];
+        stream.read_exact(&mut payload)?;
+
+        let Some(fs) = fs.upgrade() else {
+            return Ok(());
+        };
+        let (kind, reply) = match handle(&fs, kind, &payload) {
+            Ok(reply) => reply,
+            Err(e) => {
+                let errno = linux_error(e).raw_os_error().unwrap_or(libc::EIO);
+                (MSG_ERROR, (errno as u32).to_le_bytes().to_vec())
+            }
+        };
+        write_message(&mut stream, kind, &reply)?;
+    }
+}
+
+/// Runs the request `kind` with `payload`, returning the type and the payload of its answer.
+fn handle(fs: &FsImpl, kind: u32, payload: &[u8]) -> io::Result<(u32, Vec<u8>)> {
+    match kind {
+        MSG_STAT => {
+            let lookups = lookup_path(fs, payload)?;
+            let (st, _) = fs.getattr(CTX, lookups.inode(), None)?;
+            Ok((MSG_ATTR, encode_attr(&st)))
+        }
+        MSG_LIST => {
+            let lookups = lookup_path(fs, payload)?;
+            Ok((MSG_ENTRIES, list_dir(fs, lookups.inode())?))
+        }
+        MSG_READ => {
+            if payload.len() < 12 {
+                return Err(einval());
+            }
+            let offset = u64::from_le_bytes(payload[0..8].try_into().unwrap());
+            let length = u32::from_le_bytes(payload[8..12].try_into().unwrap());
+            let lookups = lookup_path(fs, &payload[12..])?;
+            let data = read_file(fs, lookups.inode(), offset, length.min(MAX_READ_SIZE))?;
+            Ok((MSG_DATA, data))
+        }
+        _ => Err(einval()),
+    }
+}
+
+/// Looks up the entry at `path`, component by component from the root of the share.
+fn lookup_path<'a>(fs: &'a FsImpl, path: &[u8]) -> io::Result<Lookups<'a>> {
+    let mut lookups = Lookups {
+        fs,
+        inodes: Vec::new(),
+    };
+    for name in path_components(path)? {
+        let entry = fs.lookup(CTX, lookups.inode(), &name)?;
+        lookups.inodes.push(entry.inode);
+    }
+    Ok(lookups)
+}
+
+/// Splits `path` into the names to look up, skipping the empty and `.` components.
+fn path_components(path: &[u8]) -> io::Result<Vec<CString>> {
+    path.split(|b| *b == b'/')
+        .filter(|name| !name.is_empty() && *name != b".")
+        .map(|name| {
+            if name == b".." {
+                return Err(einval());
+            }
+            CString::new(name).map_err(|_| einval())
+        })
+        .collect()
+}
+
+#[allow(clippy::useless_conversion)]
+fn encode_attr(st: &bindings::stat64) -> Vec<u8> {
+    let mut attr = Vec::with_capacity(36);
+    // We need this conversion on macOS.
+    attr.extend_from_slice(&u32::from(st.st_mode).to_le_bytes());
+    attr.extend_from_slice(&st.st_uid.to_le_bytes());
+    attr.extend_from_slice(&st.st_gid.to_le_bytes());
+    attr.extend_from_slice(&(st.st_nlink as u32).to_le_bytes());
+    attr.extend_from_slice(&(st.st_size as u64).to_le_bytes());
+    attr.extend_from_slice(&st.st_mtime.to_le_bytes());
+    attr.extend_from_slice(&(st.st_mtime_nsec as u32).to_le_bytes());
+    attr
+}
+
+/// Returns the `ENTRIES` payload listing the directory `inode`.
+fn list_dir(fs: &FsImpl, inode: u64) -> io::Result<Vec<u8>> {
+    let (handle, _) = fs.opendir(CTX, inode, libc::O_RDONLY as u32)?;
+    let handle = handle.unwrap_or_default();
+
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    let res = loop {
+        let mut read = 0;
+        let res = fs.readdir(CTX, inode, handle, READDIR_SIZE, offset, |entry| {
+            offset = entry.offset;
+            read += 1;
+            if entry.name != b"." && entry.name != b".." {
+                entries.extend_from_slice(&entry.type_.to_le_bytes());
+                entries.extend_from_slice(entry.name);
+                entries.push(0);
+            }
+            Ok(1)
+        });
+        match res {
+            Ok(()) if read == 0 => break Ok(()),
+            Ok(()) if entries.len() > MAX_PAYLOAD_SIZE as usize => {
+                break Err(io::Error::from_raw_os_error(libc::E2BIG))
+            }
+            Ok(()) => (),
+            Err(e) => break Err(e),
+        }
+    };
+    let _ = fs.releasedir(CTX, inode, 0, handle);
+    res.map(|_| entries)
+}
+
+/// Reads at most `length` bytes of the regular file `inode` from `offset`.
+fn read_file(fs: &FsImpl, inode: u64, offset: u64, length: u32) -> io::Result<Vec<u8>> {
+    let (st, _) = fs.getattr(CTX, inode, None)?;
+    if st.st_mode & libc::S_IFMT != libc::S_IFREG {
+        return Err(einval());
+    }
+
+    let (handle, _) = fs.open(CTX, inode, libc::O_RDONLY as u32)?;
+    let handle = handle.unwrap_or_default();
+    let mut w = DataWriter(Vec::new());
+    let res = loop {
+        let done = w.0.len() as u32;
+        if done == length {
+            break Ok(());
+        }
+        let res = fs.read(
+            CTX,
+            inode,
+            handle,
+            &mut w,
+            length - done,
+            offset + done as u64,
+            None,
+            0,
+        );
+        match res {
+            Ok(0) => break Ok(()),
+            Ok(_) => (),
+            Err(e) => break Err(e),
+        }
+    };
+    let _ = fs.release(CTX, inode, 0, handle, false, false, None);
+    res.map(|_| w.0)
+}
+
+fn write_message(stream: &mut UnixStream, kind: u32, payload: &[u8]) -> io::Result<()> {
+    let mut message = Vec::with_capacity(8 + payload.len());
+    message.extend_from_slice(&kind.to_le_bytes());
+    message.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    message.extend_from_slice(payload);
+    stream.write_all(&message)
+}
+
+fn einval() -> io::Error {
+    io::Error::from_raw_os_error(libc::EINVAL)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn components() {
+        let names = path_components(b"/a//./b/").unwrap();
+        assert_eq!(
+            names,
+            vec![CString::new("a").unwrap(), CString::new("b").unwrap()]
+        );
+        assert!(path_components(b"").unwrap().is_empty());
+
+        // The paths can't go up or hold NUL bytes
+        let res = path_components(b"a/../../etc");
+        assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::EINVAL));
+        assert!(path_components(b"a\0b").is_err());
+    }
+}
@@ -0,0 +1,400 @@
+//! The intent log of the top layer of an overlay, completing the operations a crash interrupted
+//! halfway.
+//!
+//! Some operations change the top layer in two steps. Removing or renaming an entry the lower
+//! layers also have takes the entry out of the top layer, then whites out the name so that the
+//! lower entry stays hidden. Truncating a file of a lower layer copies it up, then truncates the
+//! copy. A crash between the two steps brings a removed file back, shows a renamed one at both
+//! names, or leaves the whole content in a file the guest truncated.
+//!
+//! With the log, each such operation first appends its intent to a file of the top layer, flushed
+//! to the disk before the first step, and appends its end once done. When the overlay is created
+//! again, the intents that never ended are replayed against the layers: the operations whose
+//! first step happened are rolled forward, and the others are dropped, as if they never started.
+//! Replaying an operation that did complete finds nothing left to do, so the ends are not flushed.
+
+use std::{
+    ffi::OsStr,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{MetadataExt, OpenOptionsExt},
+    },
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The name of the log in the top layer root. It starts as the whiteouts do, so that the backends
+/// hide it from the guest.
+pub(crate) const INTENT_LOG_FILE: &str = ".wh..wh..intents";
+
+/// The size past which the log is emptied once no operation is in progress.
+const COMPACT_SIZE: u64 = 1 << 20;
+
+/// The kinds of records.
+const KIND_END: u8 = 0;
+const KIND_WHITEOUT: u8 = 1;
+const KIND_TRUNCATE: u8 = 2;
+
+/// The size of the header of a record: its length, kind and ID.
+const HEADER_SIZE: usize = 4 + 1 + 8;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// An operation that changes the top layer in several steps. The paths are relative to the layer
+/// roots.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Intent {
+    /// The entry at the path leaves the top layer, then is whited out if a lower layer has it.
+    Whiteout(Vec<u8>),
+
+    /// The file at the path is copied up, then its copy is truncated to the size.
+    Truncate(Vec<u8>, u64),
+}
+
+/// The log of the intents of the operations in progress on the top layer.
+pub(crate) struct IntentLog {
+    state: Mutex<LogState>,
+}
+
+struct LogState {
+    file: File,
+    /// The size of the log.
+    len: u64,
+    next_id: u64,
+    /// The number of operations begun and not yet ended.
+    pending: usize,
+}
+
+/// An operation in progress, whose end is recorded when dropped.
+pub(crate) struct IntentGuard<'a> {
+    log: &'a IntentLog,
+    id: u64,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl IntentLog {
+    /// Opens the log of the top layer `top`, creating it if needed, after replaying the intents
+    /// that never ended against the layers. `lowers` are the lower layers, from bottom to top,
+    /// and `whiteout_path` returns the path of the whiteout hiding a path.
+    pub(crate) fn open(
+        top: &Path,
+        lowers: &[PathBuf],
+        whiteout_path: fn(&[u8]) -> Vec<u8>,
+    ) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(top.join(INTENT_LOG_FILE))?;
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        let intents = pending(&data);
+        for intent in &intents {
+            if let Err(e) = replay(intent, top, lowers, whiteout_path) {
+                warn!("failed to replay the intent {intent:?}: {e}");
+            }
+        }
+
+        // The replayed operations must not be lost along with their intents
+        if !intents.is_empty() {
+            sync_all_dirs(top, &intents)?;
+        }
+        if !data.is_empty() {
+            file.set_len(0)?;
+            file.sync_all()?;
+        }
+
+        Ok(IntentLog {
+            state: Mutex::new(LogState {
+                file,
+                len: 0,
+                next_id: 1,
+                pending: 0,
+            }),
+        })
+    }
+
+    /// Records the intent of an operation before its first step, returning a guard recording its
+    /// end when dropped.
+    pub(crate) fn begin(&self, intent: &Intent) -> io::Result<IntentGuard<'_>> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        let record = encode(id, Some(intent));
+        state.file.write_all(&record)?;
+        state.file.sync_data()?;
+
+        state.next_id += 1;
+        state.len += record.len() as u64;
+        state.pending += 1;
+        Ok(IntentGuard { log: self, id })
+    }
+}
+
+impl Drop for IntentGuard<'_> {
+    fn drop(&mut self) {
+        let mut state = self.log.state.lock().unwrap();
+        state.pending -= 1;
+
+        // With no operation in progress, a large log is started over rather than ended
+        let res = if state.pending == 0 && state.len >= COMPACT_SIZE {
+            state.len = 0;
+            state.file.set_len(0)
+        } else {
+            let record = encode(self.id, None);
+            state.len += record.len() as u64;
+            state.file.write_all(&record)
+        };
+
+        if let Err(e) = res {
+            warn!("failed to record the end of an intent: {e}");
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Encodes the record of the intent `intent` of the operation `id`, or of its end if `None`.
+fn encode(id: u64, intent: Option<&Intent>) -> Vec<u8> {
+    let (kind, size, path) = match intent {
+        None => (KIND_END, None, &[][..]),
+        Some(Intent::Whiteout(path)) => (KIND_WHITEOUT, None, &path[..]),
+        Some(Intent::Truncate(path, size)) => (KIND_TRUNCATE, Some(*size), &path[..]),
+    };
+
+    let len = HEADER_SIZE + size.map_or(0, |_| 8) + path.len();
+    let mut record = Vec::with_capacity(len);
+    record.extend_from_slice(&(len as u32).to_le_bytes());
+    record.push(kind);
+    record.extend_from_slice(&id.to_le_bytes());
+    if let Some(size) = size {
+        record.extend_from_slice(&size.to_le_bytes());
+    }
+    record.extend_from_slice(path);
+    record
+}
+
+/// Returns the intents of the log `data` that never ended, in the order they began. A record cut
+/// short by a crash ends the log.
+fn pending(data: &[u8]) -> Vec<Intent> {
+    let mut intents = Vec::new();
+    let mut rest = data;
+    while rest.len() >= HEADER_SIZE {
+        let len = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+        if len < HEADER_SIZE || len > rest.len() {
+            break;
+        }
+
+        let (record, next) = rest.split_at(len);
+        rest = next;
+        let id = u64::from_le_bytes(record[5..HEADER_SIZE].try_into().unwrap());
+        let payload = &record[HEADER_SIZE..];
+        let intent = match record[4] {
+            KIND_END => {
+                intents.retain(|(begun, _)| *begun != id);
+                continue;
+            }
+            KIND_WHITEOUT => Intent::Whiteout(payload.to_vec()),
+            KIND_TRUNCATE if payload.len() >= 8 => {
+                let size = u64::from_le_bytes(payload[..8].try_into().unwrap());
+                Intent::Truncate(payload[8..].to_vec(), size)
+            }
+            _ => break,
+        };
+        intents.push((id, intent));
+    }
+
+    intents.into_iter().map(|(_, intent)| intent).collect()
+}
+
+/// Completes the operation of `intent` if its first step happened in the top layer `top`.
+fn replay(
+    intent: &Intent,
+    top: &Path,
+    lowers: &[PathBuf],
+    whiteout_path: fn(&[u8]) -> Vec<u8>,
+) -> io::Result<()> {
+    match intent {
+        Intent::Whiteout(path) => {
+            // The entry is still in the top layer, or no lower layer has anything to hide
+            let relative = Path::new(OsStr::from_bytes(path));
+            if exists(&top.join(relative))?
+                || !lowers
+                    .iter()
+                    .any(|layer| exists(&layer.join(relative)).unwrap_or(true))
+            {
+                return Ok(());
+            }
+
+            let whiteout = top.join(OsStr::from_bytes(&whiteout_path(path)));
+            if !whiteout.parent().is_some_and(Path::is_dir) {
+                return Ok(());
+            }
+            match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o000)
+                .open(&whiteout)
+            {
+                Err(e) if e.kind() != io::ErrorKind::AlreadyExists => Err(e),
+                _ => Ok(()),
+            }
+        }
+        Intent::Truncate(path, size) => {
+            // The copy was truncated, or even changed since, unless it still matches its source
+            let relative = Path::new(OsStr::from_bytes(path));
+            let copy = top.join(relative);
+            let Ok(copy_st) = fs::symlink_metadata(&copy) else {
+                return Ok(());
+            };
+            let source = lowers
+                .iter()
+                .rev()
+                .find_map(|layer| fs::symlink_metadata(layer.join(relative)).ok());
+            let Some(source_st) = source else {
+                return Ok(());
+            };
+            if !copy_st.is_file()
+                || copy_st.len() == *size
+                || copy_st.len() != source_st.len()
+                || copy_st.mtime() != source_st.mtime()
+                || copy_st.mtime_nsec() != source_st.mtime_nsec()
+            {
+                return Ok(());
+            }
+
+            let file = OpenOptions::new().write(true).open(&copy)?;
+            file.set_len(*size)?;
+            file.sync_all()
+        }
+    }
+}
+
+/// Whether there is an entry at `path`, without following a symbolic link.
+fn exists(path: &Path) -> io::Result<bool> {
+    match fs::symlink_metadata(path) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Flushes the top layer directories holding the whiteouts created for `intents` to the disk.
+fn sync_all_dirs(top: &Path, intents: &[Intent]) -> io::Result<()> {
+    for intent in intents {
+        let Intent::Whiteout(path) = intent else {
+            continue;
+        };
+        let dir = top.join(OsStr::from_bytes(path));
+        match dir.parent().map(File::open) {
+            Some(Ok(dir)) => dir.sync_all()?,
+            Some(Err(e)) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    fn whiteout_path(path: &[u8]) -> Vec<u8> {
+        let name_start = path
+            .iter()
+            .rposition(|&b| b == b'/')
+            .map_or(0, |pos| pos + 1);
+        let mut whiteout = path[..name_start].to_vec();
+        whiteout.extend_from_slice(b".wh.");
+        whiteout.extend_from_slice(&path[name_start..]);
+        whiteout
+    }
+
+    #[test]
+    fn records() {
+        let whiteout = Intent::Whiteout(b"a/b".to_vec());
+        let truncate = Intent::Truncate(b"c".to_vec(), 7);
+        let mut data = encode(1, Some(&whiteout));
+        data.extend(encode(2, Some(&truncate)));
+        assert_eq!(pending(&data), [whiteout.clone(), truncate.clone()]);
+
+        data.extend(encode(1, None));
+        assert_eq!(pending(&data), std::slice::from_ref(&truncate));
+
+        // A record cut short is ignored
+        let record = encode(3, Some(&whiteout));
+        data.extend_from_slice(&record[..record.len() - 1]);
+        assert_eq!(pending(&data), [truncate]);
+    }
+
+    #[test]
+    fn replay_on_open() {
+        let lower = tempfile::tempdir().unwrap();
+        let top = tempfile::tempdir().unwrap();
+        let lowers = [lower.path().to_path_buf()];
+        for dir in [lower.path(), top.path()] {
+            fs::create_dir(dir.join("d")).unwrap();
+        }
+        fs::write(lower.path().join("d/removed"), b"lower").unwrap();
+        fs::write(lower.path().join("d/kept"), b"lower").unwrap();
+        fs::write(top.path().join("d/kept"), b"top").unwrap();
+        fs::write(lower.path().join("copied"), b"content").unwrap();
+        fs::copy(lower.path().join("copied"), top.path().join("copied")).unwrap();
+        let mtime = fs::metadata(lower.path().join("copied"))
+            .unwrap()
+            .modified();
+        File::options()
+            .write(true)
+            .open(top.path().join("copied"))
+            .unwrap()
+            .set_modified(mtime.unwrap())
+            .unwrap();
+
+        let log = IntentLog::open(top.path(), &lowers, whiteout_path).unwrap();
+        let ended = log.begin(&Intent::Whiteout(b"d/ended".to_vec())).unwrap();
+        drop(ended);
+        for intent in [
+            Intent::Whiteout(b"d/removed".to_vec()),
+            Intent::Whiteout(b"d/kept".to_vec()),
+            Intent::Truncate(b"copied".to_vec(), 2),
+        ] {
+            std::mem::forget(log.begin(&intent).unwrap());
+        }
+        drop(log);
+
+        // The removal is rolled forward, the one that never started is dropped
+        IntentLog::open(top.path(), &lowers, whiteout_path).unwrap();
+        let whiteout = fs::metadata(top.path().join("d/.wh.removed")).unwrap();
+        assert_eq!(whiteout.permissions().mode() & 0o777, 0);
+        assert!(!top.path().join("d/.wh.kept").exists());
+        assert!(!top.path().join("d/.wh.ended").exists());
+        assert_eq!(fs::read(top.path().join("copied")).unwrap(), b"co");
+        assert_eq!(
+            fs::metadata(top.path().join(INTENT_LOG_FILE))
+                .unwrap()
+                .len(),
+            0
+        );
+    }
+}
@@ -1,28 +1,35 @@
 
 
-use std::{ffi::CStr, io, path::PathBuf, sync::{atomic::AtomicI32, Arc}, time::Duration};
+use std::{ffi::{CStr, OsStr}, io, os::unix::ffi::OsStrExt, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, AtomicI32, Ordering}, Arc, RwLock}, time::Duration};
 
 #[cfg(target_os = "macos")]
 use crossbeam_channel::Sender;
 #[cfg(target_os = "macos")]
 use hvf::MemoryMapping;
+use utils::eventfd::{EventFd, EFD_NONBLOCK};
 
 use crate::virtio::bindings;
 
+#[cfg(target_os = "linux")]
+use super::snapshot::{HandleState, InodeState};
 use super::{
     filesystem::{
-        Context, DirEntry, Entry, Extensions, FileSystem, GetxattrReply, ListxattrReply,
-        ZeroCopyReader, ZeroCopyWriter,
+        BirthTime, Context, DirEntry, Entry, Extensions, FileLock, FileSystem, GetxattrReply,
+        ListxattrReply, ZeroCopyReader, ZeroCopyWriter,
     },
     fuse::{FsOptions, OpenOptions, RemovemappingOne, SetattrValid},
+    hooks::{FsHookPoint, FsHooks},
     overlayfs::{self, OverlayFs},
     passthrough::{self, PassthroughFs},
+    server::MAX_BUFFER_SIZE,
 };
 
 //--------------------------------------------------------------------------------------------------
 // Types
 //--------------------------------------------------------------------------------------------------
 
+// Built once per device, so the size of the overlay config doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone, Debug)]
 pub enum FsImplConfig {
     Passthrough(passthrough::Config),
@@ -30,14 +37,441 @@ pub enum FsImplConfig {
 }
 
 pub enum FsImpl {
-    Passthrough(PassthroughFs),
-    Overlayfs(OverlayFs),
+    Passthrough(Box<PassthroughFs>),
+    Overlayfs(Box<OverlayFs>),
 }
 
 #[derive(Clone, Debug)]
 pub enum FsImplShare {
     Passthrough(String),
-    Overlayfs(Vec<PathBuf>),
+    Overlayfs(Vec<PathBuf>, overlayfs::UpperLayer),
+    /// An overlay some of whose lower layers, by index, are read lazily from the blobs of an OCI
+    /// image, see `OciImage::lazy_share`.
+    #[cfg(feature = "oci")]
+    LazyOverlayfs(
+        Vec<PathBuf>,
+        Vec<(usize, Arc<super::oci::LazyLayer>)>,
+        overlayfs::UpperLayer,
+    ),
+}
+
+/// Restricts the guest users that may use a share. The rules are checked against the credentials
+/// of every request before it reaches the file system, regardless of the file modes, and requests
+/// from anyone else fail with `EACCES`.
+///
+/// Guest root is always allowed: it can take any other identity in the guest anyway, and the
+/// kernel uses it for the requests it makes on its own behalf, such as writeback.
+#[derive(Clone, Debug, Default)]
+pub struct FsAccessRules {
+    /// Users allowed to use the share.
+    pub uids: Vec<u32>,
+
+    /// Groups allowed to use the share. Only the primary group of the requesting process is known
+    /// to the device, so supplementary groups are not taken into account.
+    pub gids: Vec<u32>,
+}
+
+impl FsAccessRules {
+    pub fn allows(&self, uid: u32, gid: u32) -> bool {
+        uid == 0 || self.uids.contains(&uid) || self.gids.contains(&gid)
+    }
+}
+
+/// Entry and attribute timeouts of a share that can be changed while the guest is running. Clones
+/// share the same timeouts, so a handle obtained from the device before it is activated keeps
+/// controlling the running file system.
+///
+/// The new timeouts apply to the replies sent after the change. When they may be shorter than the
+/// ones handed out before, the worker tells the guest to drop the attributes it cached of the
+/// inodes it holds through the notification queue. Without the queue, which the guest is only
+/// offered along with leases, lowering the timeouts takes full effect once the timeouts handed out
+/// before the change expire.
+#[derive(Clone, Debug, Default)]
+pub struct FsCacheTimeouts(Arc<CacheTimeouts>);
+
+#[derive(Debug)]
+struct CacheTimeouts {
+    timeouts: RwLock<Option<(Duration, Duration)>>,
+    /// Whether the timeouts may have been shortened since the worker last invalidated the inodes
+    shortened: AtomicBool,
+    /// Signaled when the timeouts may have been shortened
+    event: EventFd,
+}
+
+impl FsCacheTimeouts {
+    /// Replaces the timeouts the file system was configured with.
+    pub fn set(&self, entry_timeout: Duration, attr_timeout: Duration) {
+        let old = self
+            .0
+            .timeouts
+            .write()
+            .unwrap()
+            .replace((entry_timeout, attr_timeout));
+        // The timeouts the file system was configured with aren't known here
+        if old.is_none_or(|(old_entry, old_attr)| {
+            entry_timeout < old_entry || attr_timeout < old_attr
+        }) {
+            self.shortened();
+        }
+    }
+
+    /// Goes back to the timeouts the file system was configured with.
+    pub fn reset(&self) {
+        if self.0.timeouts.write().unwrap().take().is_some() {
+            self.shortened();
+        }
+    }
+
+    /// Returns the entry and attribute timeouts, if they were changed.
+    pub fn get(&self) -> Option<(Duration, Duration)> {
+        *self.0.timeouts.read().unwrap()
+    }
+
+    /// Returns whether the timeouts may have been shortened since the last call.
+    pub(crate) fn take_shortened(&self) -> bool {
+        // The event is only a wake-up, so it doesn't matter whether it was signaled
+        let _ = self.0.event.read();
+        self.0.shortened.swap(false, Ordering::AcqRel)
+    }
+
+    /// Returns the event signaled when the timeouts may have been shortened.
+    pub(crate) fn event(&self) -> &EventFd {
+        &self.0.event
+    }
+
+    fn shortened(&self) {
+        self.0.shortened.store(true, Ordering::Release);
+        if let Err(e) = self.0.event.write(1) {
+            error!("failed to signal shortened cache timeouts: {e:?}");
+        }
+    }
+}
+
+impl Default for CacheTimeouts {
+    fn default() -> Self {
+        CacheTimeouts {
+            timeouts: RwLock::new(None),
+            shortened: AtomicBool::new(false),
+            event: EventFd::new(EFD_NONBLOCK).unwrap(),
+        }
+    }
+}
+
+/// Limits on the requests the guest kernel sends in the background, such as the writeback of dirty
+/// pages, advertised to the guest when it mounts the share. The guest stops sending background
+/// requests once `max_background` of them are in flight, and past `congestion_threshold` it
+/// considers the share congested and throttles the processes dirtying its pages.
+///
+/// The device handles the background requests after the requests processes are waiting on, so a
+/// burst of writeback doesn't hold up e.g. lookups. They are only deferred until
+/// `congestion_threshold` of them are pending, though, so that writeback keeps making progress.
+#[derive(Clone, Copy, Debug)]
+pub struct FsBackgroundLimits {
+    pub max_background: u16,
+    pub congestion_threshold: u16,
+}
+
+impl FsBackgroundLimits {
+    /// Returns the limits with at least one background request allowed and a congestion threshold
+    /// no higher than the maximum.
+    pub fn normalized(self) -> Self {
+        let max_background = self.max_background.max(1);
+        FsBackgroundLimits {
+            max_background,
+            congestion_threshold: self.congestion_threshold.min(max_background),
+        }
+    }
+}
+
+impl Default for FsBackgroundLimits {
+    fn default() -> Self {
+        FsBackgroundLimits {
+            max_background: 64,
+            congestion_threshold: 48,
+        }
+    }
+}
+
+/// The coalescing of the small sequential writes of the guest into larger host writes. Writes
+/// that continue the previous one on the same handle are buffered, up to `buffer_size` bytes, and
+/// written to the host at once, at the latest after about `delay`.
+///
+/// The writes of files the guest opened with `O_SYNC`, `O_DSYNC` or `O_DIRECT` are never buffered.
+#[derive(Clone, Copy, Debug)]
+pub struct FsWriteCoalescing {
+    pub buffer_size: u32,
+    pub delay: Duration,
+}
+
+/// How the reads of the guest update the access times of the host files. Only Linux hosts support
+/// anything but [`FsAtime::Host`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FsAtime {
+    /// The host file system updates them as it is mounted to, even for the files the guest opens
+    /// with `O_NOATIME` if the device may not open them so.
+    #[default]
+    Host,
+    /// Updated on the first read of an open file if not later than the last modification of the
+    /// file or a day old, as Linux does with `relatime`, whatever the host mount.
+    Relatime,
+    /// Never updated by the reads of the guest.
+    Noatime,
+}
+
+/// How the reads of the guest use the page cache of the host, which the page cache of the guest
+/// already holds the data of.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FsPageCache {
+    /// The host caches the files and reads ahead of them as it does for its own reads.
+    #[default]
+    Host,
+    /// The handles reading a file one request after the other get a larger readahead, and once
+    /// they have read enough of it, the pages they read are dropped from the host cache behind
+    /// them, so that streaming large files doesn't evict the rest of the host cache.
+    Streaming,
+    /// The pages the guest reads are dropped from the host cache as soon as they are read, or not
+    /// cached at all on macOS hosts.
+    NoCache,
+}
+
+/// Where the owners the guest gives the entries of a share, and the device nodes it creates in it,
+/// are kept. macOS hosts always keep them in extended attributes, and only Linux hosts support
+/// anything but [`FsOwnership::Auto`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FsOwnership {
+    /// [`FsOwnership::Virtual`] if the device runs as a user other than root, since it can't
+    /// change owners or create device nodes, or [`FsOwnership::Host`] otherwise.
+    #[default]
+    Auto,
+    /// The host files are given to the owners the guest asks for, and the device nodes are
+    /// created on the host, which the device needs the privileges for.
+    Host,
+    /// The owners, the modes and the device numbers the guest asks for are kept in an extended
+    /// attribute of the host files, which stay owned by the user the device runs as, and are
+    /// reported to the guest instead of the host ones. The device nodes are regular files on the
+    /// host.
+    Virtual,
+}
+
+/// The width of the inode numbers the guest sees in the attributes and the directory entries of
+/// the files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FsInodeNumbers {
+    /// The numbers of the host files, which may take all 64 bits.
+    #[default]
+    Native,
+    /// The numbers folded into 32 bits, for the guest userspaces that fail on larger ones. A file
+    /// whose folded number is taken by another gets a free one instead.
+    Folded32,
+}
+
+/// The modes the entries the guest creates get on the host, stricter than the ones the guest asks
+/// for. The guest keeps seeing the modes it asked for in the attributes of these entries.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FsModePolicy {
+    /// The permission bits cleared from the host mode of the entries the guest creates, and of
+    /// the ones it changes the mode of.
+    pub umask: u32,
+    /// The permission bits the files, and the other entries but directories, the guest creates
+    /// get on the host before `umask`, in place of the ones the guest asks for.
+    pub file_mode: Option<u32>,
+    /// The permission bits the directories the guest creates get on the host before `umask`, in
+    /// place of the ones the guest asks for.
+    pub dir_mode: Option<u32>,
+}
+
+/// Leases letting the guest cache the attributes of the files it accesses for `timeout`, much
+/// longer than the timeouts of the share. The lease on a file is broken when the revalidation finds
+/// it changed on the host, and the guest is told to drop what it cached of the file through the
+/// notification queue. Leases are only granted to a guest that accepted the notification queue.
+#[derive(Clone, Copy, Debug)]
+pub struct FsLeases {
+    pub timeout: Duration,
+}
+
+/// The protocol the guest uses a share with, chosen per device.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FsProtocol {
+    /// virtio-fs, FUSE over virtio.
+    #[default]
+    Fuse,
+    /// virtio-9p with the 9P2000.L dialect, for the guests shipping only 9p drivers. The share is
+    /// served by the same file system, without DAX, leases and ioctls, and without the options of
+    /// the FUSE server such as the access rules, the virtual files or the coalescing of writes.
+    /// The fids of the guest aren't part of the state saved in a snapshot.
+    P9,
+}
+
+/// Where the guest mounts a share on its own at boot, advertised to it along with the options the
+/// device recommends for the share.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FsGuestMount {
+    /// The absolute guest path the share is mounted at, created if missing.
+    pub path: String,
+    /// The options of the mount on top of the recommended ones, as `mount -o` takes them.
+    pub options: String,
+}
+
+impl FsWriteCoalescing {
+    /// Returns the coalescing with a buffer no larger than the largest write of the guest, and a
+    /// delay of at least a millisecond.
+    pub fn normalized(self) -> Self {
+        FsWriteCoalescing {
+            buffer_size: self.buffer_size.clamp(1, MAX_BUFFER_SIZE),
+            delay: self.delay.max(Duration::from_millis(1)),
+        }
+    }
+}
+
+impl FsOwnership {
+    /// Returns whether the ownership is virtual for a device running as the current user.
+    pub fn is_virtual(self) -> bool {
+        match self {
+            // Safe because this syscall always succeeds.
+            FsOwnership::Auto => (unsafe { libc::geteuid() }) != 0,
+            FsOwnership::Host => false,
+            FsOwnership::Virtual => true,
+        }
+    }
+}
+
+impl FsProtocol {
+    /// Returns the type of the file system the guest mounts a share served over this protocol as.
+    pub fn guest_fs_type(self) -> &'static str {
+        match self {
+            FsProtocol::Fuse => "virtiofs",
+            FsProtocol::P9 => "9p",
+        }
+    }
+}
+
+impl FsGuestMount {
+    /// The longest path and options the configuration space of the device holds.
+    pub const MAX_LEN: usize = 255;
+
+    /// Returns the options the guest mounts the share with over `protocol`: the ones recommended
+    /// for the share, with a DAX window if `dax`, followed by the ones of the embedder, which take
+    /// precedence.
+    pub fn mount_options(&self, protocol: FsProtocol, dax: bool) -> String {
+        let recommended = match protocol {
+            FsProtocol::Fuse if dax => "dax",
+            FsProtocol::Fuse => "",
+            FsProtocol::P9 => "trans=virtio,version=9p2000.L",
+        };
+        [recommended, self.options.as_str()]
+            .into_iter()
+            .filter(|options| !options.is_empty())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+impl FsImpl {
+    /// Returns the inode of the init binary served in every directory of the share.
+    pub(crate) fn init_inode(&self) -> u64 {
+        match self {
+            FsImpl::Passthrough(fs) => fs.init_inode(),
+            FsImpl::Overlayfs(fs) => fs.init_inode(),
+        }
+    }
+
+    /// Returns the inodes the guest may hold, and cache the attributes of.
+    pub(crate) fn held_inodes(&self) -> Vec<u64> {
+        match self {
+            FsImpl::Passthrough(fs) => fs.held_inodes(),
+            FsImpl::Overlayfs(fs) => fs.held_inodes(),
+        }
+    }
+
+    /// Returns the path of `inode` relative to the root of the share, if it is still reachable.
+    pub(crate) fn inode_path(&self, inode: u64) -> Option<PathBuf> {
+        match self {
+            FsImpl::Passthrough(fs) => fs.inode_path(inode),
+            FsImpl::Overlayfs(fs) => fs.inode_path(inode),
+        }
+    }
+
+    /// Returns the hooks of the embedder on the share, if it has any.
+    pub(crate) fn hooks(&self) -> Option<&FsHooks> {
+        match self {
+            FsImpl::Passthrough(fs) => fs.hooks(),
+            FsImpl::Overlayfs(fs) => fs.hooks(),
+        }
+    }
+
+    /// Runs the hooks of the embedder at `point` on the entry `name` of `parent`, whatever the
+    /// protocol the guest reaches the share with.
+    fn run_entry_hooks(&self, point: FsHookPoint, parent: u64, name: &CStr) -> io::Result<()> {
+        let Some(hooks) = self.hooks().filter(|hooks| hooks.has(point)) else {
+            return Ok(());
+        };
+        // A hook that may veto mustn't be skipped for an entry it can't be told the path of
+        let parent_path = self
+            .inode_path(parent)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        hooks.run(point, &parent_path.join(OsStr::from_bytes(name.to_bytes())))
+    }
+
+    /// Drops what the file system caches of the host files.
+    pub(crate) fn drop_caches(&self) {
+        match self {
+            // Looks everything up on the host every time
+            FsImpl::Passthrough(_) => (),
+            FsImpl::Overlayfs(fs) => fs.drop_caches(),
+        }
+    }
+
+    /// Returns the inodes the guest holds, for a snapshot of the share.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn snapshot_inodes(&self) -> io::Result<Vec<InodeState>> {
+        match self {
+            FsImpl::Passthrough(fs) => fs.snapshot_inodes(),
+            FsImpl::Overlayfs(fs) => fs.snapshot_inodes(),
+        }
+    }
+
+    /// Returns the handles the guest holds, for a snapshot of the share.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn snapshot_handles(&self) -> io::Result<Vec<HandleState>> {
+        match self {
+            FsImpl::Passthrough(fs) => fs.snapshot_handles(),
+            FsImpl::Overlayfs(fs) => fs.snapshot_handles(),
+        }
+    }
+
+    /// Makes the next inodes and handles get numbers from `inode` and `handle` on, at least.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn reserve_numbers(&self, inode: u64, handle: u64) {
+        match self {
+            FsImpl::Passthrough(fs) => fs.reserve_numbers(inode, handle),
+            FsImpl::Overlayfs(fs) => fs.reserve_numbers(inode, handle),
+        }
+    }
+
+    /// Gives the inode `from` the number `to`, `generation` and `refcount` lookups, to restore an
+    /// inode of a snapshot.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn renumber_inode(
+        &self,
+        from: u64,
+        to: u64,
+        generation: u64,
+        refcount: u64,
+    ) -> io::Result<()> {
+        match self {
+            FsImpl::Passthrough(fs) => fs.renumber_inode(from, to, generation, refcount),
+            FsImpl::Overlayfs(fs) => fs.renumber_inode(from, to, generation, refcount),
+        }
+    }
+
+    /// Gives the handle `from` the number `to`, to restore a handle of a snapshot.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn renumber_handle(&self, from: u64, to: u64) -> io::Result<()> {
+        match self {
+            FsImpl::Passthrough(fs) => fs.renumber_handle(from, to),
+            FsImpl::Overlayfs(fs) => fs.renumber_handle(from, to),
+        }
+    }
 }
 
 //--------------------------------------------------------------------------------------------------
@@ -49,6 +483,9 @@ impl FileSystem for FsImpl {
     type Handle = u64;
 
     fn init(&self, capable: FsOptions) -> io::Result<FsOptions> {
+        if let Some(hooks) = self.hooks() {
+            let _ = hooks.run(FsHookPoint::PreMount, Path::new(""));
+        }
         match self {
             FsImpl::Passthrough(fs) => fs.init(capable),
             FsImpl::Overlayfs(fs) => fs.init(capable),
@@ -60,6 +497,9 @@ impl FileSystem for FsImpl {
             FsImpl::Passthrough(fs) => fs.destroy(),
             FsImpl::Overlayfs(fs) => fs.destroy(),
         }
+        if let Some(hooks) = self.hooks() {
+            let _ = hooks.run(FsHookPoint::PostUnmount, Path::new(""));
+        }
     }
 
     fn lookup(&self, ctx: Context, parent: Self::Inode, name: &CStr) -> io::Result<Entry> {
@@ -95,6 +535,18 @@ impl FileSystem for FsImpl {
         }
     }
 
+    fn statx(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Option<Self::Handle>,
+    ) -> io::Result<(bindings::stat64, Option<BirthTime>, Duration)> {
+        match self {
+            FsImpl::Passthrough(fs) => fs.statx(ctx, inode, handle),
+            FsImpl::Overlayfs(fs) => fs.statx(ctx, inode, handle),
+        }
+    }
+
     fn setattr(
         &self,
         ctx: Context,
@@ -163,6 +615,7 @@ impl FileSystem for FsImpl {
     }
 
     fn unlink(&self, ctx: Context, parent: Self::Inode, name: &CStr) -> io::Result<()> {
+        self.run_entry_hooks(FsHookPoint::PreUnlink, parent, name)?;
         match self {
             FsImpl::Passthrough(fs) => fs.unlink(ctx, parent, name),
             FsImpl::Overlayfs(fs) => fs.unlink(ctx, parent, name),
@@ -170,6 +623,7 @@ impl FileSystem for FsImpl {
     }
 
     fn rmdir(&self, ctx: Context, parent: Self::Inode, name: &CStr) -> io::Result<()> {
+        self.run_entry_hooks(FsHookPoint::PreUnlink, parent, name)?;
         match self {
             FsImpl::Passthrough(fs) => fs.rmdir(ctx, parent, name),
             FsImpl::Overlayfs(fs) => fs.rmdir(ctx, parent, name),
@@ -233,6 +687,21 @@ impl FileSystem for FsImpl {
         }
     }
 
+    fn tmpfile(
+        &self,
+        ctx: Context,
+        parent: Self::Inode,
+        mode: u32,
+        flags: u32,
+        umask: u32,
+        extensions: Extensions,
+    ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions)> {
+        match self {
+            FsImpl::Passthrough(fs) => fs.tmpfile(ctx, parent, mode, flags, umask, extensions),
+            FsImpl::Overlayfs(fs) => fs.tmpfile(ctx, parent, mode, flags, umask, extensions),
+        }
+    }
+
     #[allow(clippy::too_many_arguments)]
     fn read<W: io::Write + ZeroCopyWriter>(
         &self,
@@ -594,38 +1063,62 @@ impl FileSystem for FsImpl {
         flags: u32,
         cmd: u32,
         arg: u64,
-        in_size: u32,
+        data: &[u8],
         out_size: u32,
         exit_code: &Arc<AtomicI32>,
     ) -> io::Result<Vec<u8>> {
         match self {
             FsImpl::Passthrough(fs) => {
-                fs.ioctl(ctx, inode, handle, flags, cmd, arg, in_size, out_size, exit_code)
+                fs.ioctl(ctx, inode, handle, flags, cmd, arg, data, out_size, exit_code)
             }
             FsImpl::Overlayfs(fs) => {
-                fs.ioctl(ctx, inode, handle, flags, cmd, arg, in_size, out_size, exit_code)
+                fs.ioctl(ctx, inode, handle, flags, cmd, arg, data, out_size, exit_code)
             }
         }
     }
 
-    fn getlk(&self) -> io::Result<()> {
+    fn getlk(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<FileLock> {
         match self {
-            FsImpl::Passthrough(fs) => fs.getlk(),
-            FsImpl::Overlayfs(fs) => fs.getlk(),
+            FsImpl::Passthrough(fs) => fs.getlk(ctx, inode, handle, owner, lock, flags),
+            FsImpl::Overlayfs(fs) => fs.getlk(ctx, inode, handle, owner, lock, flags),
         }
     }
 
-    fn setlk(&self) -> io::Result<()> {
+    fn setlk(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
         match self {
-            FsImpl::Passthrough(fs) => fs.setlk(),
-            FsImpl::Overlayfs(fs) => fs.setlk(),
+            FsImpl::Passthrough(fs) => fs.setlk(ctx, inode, handle, owner, lock, flags),
+            FsImpl::Overlayfs(fs) => fs.setlk(ctx, inode, handle, owner, lock, flags),
         }
     }
 
-    fn setlkw(&self) -> io::Result<()> {
+    fn setlkw(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
         match self {
-            FsImpl::Passthrough(fs) => fs.setlkw(),
-            FsImpl::Overlayfs(fs) => fs.setlkw(),
+            FsImpl::Passthrough(fs) => fs.setlkw(ctx, inode, handle, owner, lock, flags),
+            FsImpl::Overlayfs(fs) => fs.setlkw(ctx, inode, handle, owner, lock, flags),
         }
     }
 
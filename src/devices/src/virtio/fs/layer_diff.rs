@@ -0,0 +1,516 @@
+//! Differential export of the writable top layer of an overlay.
+//!
+//! A `LayerSnapshot` records the metadata of every entry of a layer directory. Comparing it
+//! against the current contents of the same directory gives the entries that were added or
+//! modified since, which are written out as an OCI layer tar along with whiteouts for the entries
+//! that were removed.
+//!
+//! The export may sanitize the metadata of the entries, see `host_metadata`: their extended
+//! attributes are then written too, those kept by the retention list only, and the AppleDouble
+//! files are written as the extended attributes of the files they describe rather than as files.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ffi::OsStr,
+    fs::{self, File, Metadata},
+    io::{self, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileTypeExt, MetadataExt},
+    },
+    path::{Path, PathBuf},
+};
+
+use super::host_metadata::{self, Xattr, XattrRetention};
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The prefix for whiteout files
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+
+/// The size of a tar block
+const BLOCK_SIZE: usize = 512;
+
+/// The largest size that fits in the 11 octal digits of a ustar header
+const USTAR_MAX_SIZE: u64 = 0o77777777777;
+
+/// The largest uid or gid that fits in the 7 octal digits of a ustar header
+const USTAR_MAX_ID: u32 = 0o7777777;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// The state of the entries of a layer directory at a point in time, keyed by their path
+/// relative to the root of the layer.
+pub(crate) struct LayerSnapshot {
+    entries: BTreeMap<PathBuf, EntryState>,
+}
+
+/// The metadata of an entry that tells whether it changed between two walks. The change time is
+/// updated by any write or metadata change, and a copy-up or a replacement gives a new inode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct EntryState {
+    ino: u64,
+    mode: u32,
+    ctime: i64,
+    ctime_nsec: i64,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl LayerSnapshot {
+    /// Records the state of every entry under `root`, skipping the names for which `is_internal`
+    /// returns true.
+    pub(crate) fn capture(root: &Path, is_internal: impl Fn(&[u8]) -> bool) -> io::Result<Self> {
+        let mut entries = BTreeMap::new();
+        walk(root, Path::new(""), &is_internal, &mut |path, md| {
+            entries.insert(path.to_path_buf(), EntryState::from(md));
+            Ok(())
+        })?;
+
+        Ok(Self { entries })
+    }
+}
+
+impl From<&Metadata> for EntryState {
+    fn from(md: &Metadata) -> Self {
+        Self {
+            ino: md.ino(),
+            mode: md.mode(),
+            ctime: md.ctime(),
+            ctime_nsec: md.ctime_nsec(),
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Writes to `out` an OCI layer tar holding the changes made under `root` since `since` was
+/// captured.
+///
+/// Added and modified entries are written along with their parent directories. Removed entries
+/// are written as whiteouts, unless their parent directory was removed too. Whiteouts and opaque
+/// markers created by the overlay since are regular entries of the layer and are written as is.
+/// With `retention`, the metadata of the entries is sanitized.
+pub(crate) fn write_diff(
+    root: &Path,
+    since: &LayerSnapshot,
+    is_internal: impl Fn(&[u8]) -> bool,
+    retention: Option<&XattrRetention>,
+    out: impl Write,
+) -> io::Result<()> {
+    let mut current = BTreeMap::new();
+    walk(root, Path::new(""), &is_internal, &mut |path, md| {
+        current.insert(path.to_path_buf(), md.clone());
+        Ok(())
+    })?;
+
+    let mut changed: BTreeSet<PathBuf> = current
+        .iter()
+        .filter(|(path, md)| since.entries.get(*path) != Some(&EntryState::from(*md)))
+        .map(|(path, _)| path.clone())
+        .collect();
+
+    // The AppleDouble files are folded into the files they describe, which are written again
+    // when they change
+    let mut folded: HashMap<PathBuf, Vec<Xattr>> = HashMap::new();
+    if let Some(retention) = retention {
+        for (path, md) in &current {
+            let Some(target) = path
+                .file_name()
+                .and_then(|name| host_metadata::apple_double_target(name.as_bytes()))
+            else {
+                continue;
+            };
+            if !md.is_file() {
+                continue;
+            }
+            let Some(xattrs) = host_metadata::decode_apple_double(&fs::read(root.join(path))?)
+            else {
+                continue;
+            };
+
+            let target = path.with_file_name(OsStr::from_bytes(target));
+            if changed.remove(path) && current.contains_key(&target) {
+                changed.insert(target.clone());
+            }
+            let xattrs = xattrs
+                .into_iter()
+                .filter(|(name, _)| retention.keeps(name))
+                .collect();
+            folded.insert(target, xattrs);
+        }
+    }
+
+    let mut whiteouts = BTreeSet::new();
+    for path in since.entries.keys() {
+        if current.contains_key(path) || is_whiteout(path) {
+            continue;
+        }
+
+        // A whiteout for the parent directory already covers its contents
+        let parent = path.parent().unwrap_or(Path::new(""));
+        if !parent.as_os_str().is_empty() && !current.get(parent).is_some_and(Metadata::is_dir) {
+            continue;
+        }
+
+        let mut name = WHITEOUT_PREFIX.to_vec();
+        name.extend_from_slice(path.file_name().unwrap().as_bytes());
+        let whiteout = parent.join(OsStr::from_bytes(&name));
+
+        // The overlay may have created the whiteout itself
+        if !current.contains_key(&whiteout) {
+            whiteouts.insert(whiteout);
+        }
+    }
+
+    // Write the parent directories of every entry, as some tools don't create missing ones
+    for path in changed
+        .iter()
+        .chain(whiteouts.iter())
+        .cloned()
+        .collect::<Vec<_>>()
+    {
+        for ancestor in path.ancestors().skip(1) {
+            if ancestor.as_os_str().is_empty() || !changed.insert(ancestor.to_path_buf()) {
+                break;
+            }
+        }
+    }
+
+    let mut writer = TarWriter { out };
+    let mut links: HashMap<(u64, u64), PathBuf> = HashMap::new();
+    let mut whiteouts = whiteouts.into_iter().peekable();
+    for path in changed {
+        // Keep whiteouts next to the other entries of their directory
+        while let Some(whiteout) = whiteouts.next_if(|whiteout| *whiteout < path) {
+            writer.append_whiteout(&whiteout)?;
+        }
+
+        let md = &current[&path];
+        if md.is_file() && md.nlink() > 1 {
+            if let Some(target) = links.get(&(md.dev(), md.ino())) {
+                writer.append(&path, md, EntryKind::Link(target), &[])?;
+                continue;
+            }
+            links.insert((md.dev(), md.ino()), path.clone());
+        }
+
+        let full_path = root.join(&path);
+        let file_type = md.file_type();
+        let kind = if file_type.is_dir() {
+            EntryKind::Dir
+        } else if file_type.is_file() {
+            EntryKind::File(File::open(&full_path)?)
+        } else if file_type.is_symlink() {
+            EntryKind::Symlink(fs::read_link(&full_path)?)
+        } else if file_type.is_char_device() {
+            EntryKind::Device(b'3')
+        } else if file_type.is_block_device() {
+            EntryKind::Device(b'4')
+        } else if file_type.is_fifo() {
+            EntryKind::Fifo
+        } else {
+            // Sockets can't be stored in a tar
+            continue;
+        };
+
+        let mut xattrs = Vec::new();
+        if let Some(retention) = retention {
+            xattrs = host_metadata::read_xattrs(&full_path)?;
+            xattrs.retain(|(name, _)| retention.keeps(name));
+            // The attributes of the file itself win over those of its AppleDouble file
+            for xattr in folded.remove(&path).unwrap_or_default() {
+                if !xattrs.iter().any(|(name, _)| *name == xattr.0) {
+                    xattrs.push(xattr);
+                }
+            }
+        }
+
+        writer.append(&path, md, kind, &xattrs)?;
+    }
+
+    for whiteout in whiteouts {
+        writer.append_whiteout(&whiteout)?;
+    }
+
+    writer.finish()
+}
+
+/// Calls `visit` for every entry under `dir` in a pre-order walk, with the path relative to the
+/// layer root.
+pub(crate) fn walk(
+    dir: &Path,
+    relative: &Path,
+    is_internal: &impl Fn(&[u8]) -> bool,
+    visit: &mut impl FnMut(&Path, &Metadata) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = entry.file_name();
+        if is_internal(name.as_bytes()) {
+            continue;
+        }
+
+        let md = entry.metadata()?;
+        let path = relative.join(&name);
+        visit(&path, &md)?;
+
+        if md.is_dir() {
+            walk(&entry.path(), &path, is_internal, visit)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn is_whiteout(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.as_bytes().starts_with(WHITEOUT_PREFIX))
+}
+
+//--------------------------------------------------------------------------------------------------
+// Types: Tar
+//--------------------------------------------------------------------------------------------------
+
+enum EntryKind<'a> {
+    File(File),
+    Link(&'a Path),
+    Symlink(PathBuf),
+    Dir,
+    Device(u8),
+    Fifo,
+}
+
+/// A minimal writer of POSIX (pax) tar archives.
+struct TarWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> TarWriter<W> {
+    fn append(
+        &mut self,
+        path: &Path,
+        md: &Metadata,
+        kind: EntryKind,
+        xattrs: &[Xattr],
+    ) -> io::Result<()> {
+        let mut name = path.as_os_str().as_bytes().to_vec();
+        let (type_flag, size, link_name) = match &kind {
+            EntryKind::File(_) => (b'0', md.len(), None),
+            EntryKind::Link(target) => (b'1', 0, Some(target.as_os_str().as_bytes())),
+            EntryKind::Symlink(target) => (b'2', 0, Some(target.as_os_str().as_bytes())),
+            EntryKind::Dir => {
+                name.push(b'/');
+                (b'5', 0, None)
+            }
+            EntryKind::Device(type_flag) => (*type_flag, 0, None),
+            EntryKind::Fifo => (b'6', 0, None),
+        };
+
+        let mut header = Header {
+            name: &name,
+            mode: md.mode() & 0o7777,
+            uid: md.uid(),
+            gid: md.gid(),
+            size,
+            mtime: md.mtime().max(0) as u64,
+            type_flag,
+            link_name: link_name.unwrap_or_default(),
+            rdev: None,
+            xattrs,
+        };
+        if let EntryKind::Device(_) = kind {
+            let rdev = md.rdev();
+            header.rdev = Some((libc::major(rdev as _) as u32, libc::minor(rdev as _) as u32));
+        }
+        self.write_header(&header)?;
+
+        if let EntryKind::File(file) = kind {
+            // The file may change while it is copied, so stick to the size in the header
+            let copied = io::copy(&mut io::Read::take(file, size), &mut self.out)?;
+            if copied < size {
+                io::copy(
+                    &mut io::Read::take(io::repeat(0), size - copied),
+                    &mut self.out,
+                )?;
+            }
+            self.pad(size)?;
+        }
+
+        Ok(())
+    }
+
+    fn append_whiteout(&mut self, path: &Path) -> io::Result<()> {
+        self.write_header(&Header {
+            name: path.as_os_str().as_bytes(),
+            mode: 0o644,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            mtime: 0,
+            type_flag: b'0',
+            link_name: &[],
+            rdev: None,
+            xattrs: &[],
+        })
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.out.write_all(&[0; BLOCK_SIZE * 2])?;
+        self.out.flush()
+    }
+
+    /// Writes a ustar header, preceded by a pax extended header for the fields that don't fit.
+    fn write_header(&mut self, header: &Header) -> io::Result<()> {
+        let mut records = Vec::new();
+        let split = split_name(header.name);
+        if split.is_none() {
+            push_pax_record(&mut records, b"path", header.name);
+        }
+        if header.link_name.len() > 100 {
+            push_pax_record(&mut records, b"linkpath", header.link_name);
+        }
+        if header.size > USTAR_MAX_SIZE {
+            push_pax_record(&mut records, b"size", header.size.to_string().as_bytes());
+        }
+        if header.uid > USTAR_MAX_ID {
+            push_pax_record(&mut records, b"uid", header.uid.to_string().as_bytes());
+        }
+        if header.gid > USTAR_MAX_ID {
+            push_pax_record(&mut records, b"gid", header.gid.to_string().as_bytes());
+        }
+        for (name, value) in header.xattrs {
+            let mut key = b"SCHILY.xattr.".to_vec();
+            key.extend_from_slice(name);
+            push_pax_record(&mut records, &key, value);
+        }
+
+        if !records.is_empty() {
+            let pax = Header {
+                name: b"PaxHeader",
+                mode: 0o644,
+                uid: 0,
+                gid: 0,
+                size: records.len() as u64,
+                mtime: 0,
+                type_flag: b'x',
+                link_name: &[],
+                rdev: None,
+                xattrs: &[],
+            };
+            self.out.write_all(&pax.encode(None))?;
+            self.out.write_all(&records)?;
+            self.pad(records.len() as u64)?;
+        }
+
+        // The name was stored in the pax header, keep a truncated copy for old readers
+        let split = split.unwrap_or((&[], &header.name[..header.name.len().min(100)]));
+        self.out.write_all(&header.encode(Some(split)))
+    }
+
+    fn pad(&mut self, size: u64) -> io::Result<()> {
+        let rem = (size % BLOCK_SIZE as u64) as usize;
+        if rem != 0 {
+            self.out.write_all(&[0; BLOCK_SIZE][rem..])?;
+        }
+        Ok(())
+    }
+}
+
+struct Header<'a> {
+    name: &'a [u8],
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    mtime: u64,
+    type_flag: u8,
+    link_name: &'a [u8],
+    rdev: Option<(u32, u32)>,
+    xattrs: &'a [Xattr],
+}
+
+impl Header<'_> {
+    /// Encodes the header as a ustar block, with the name split into its prefix and name fields.
+    fn encode(&self, split: Option<(&[u8], &[u8])>) -> [u8; BLOCK_SIZE] {
+        let mut block = [0u8; BLOCK_SIZE];
+        let (prefix, name) = split.unwrap_or((&[], self.name));
+
+        put_bytes(&mut block[0..100], name);
+        put_octal(&mut block[100..108], self.mode as u64);
+        put_octal(&mut block[108..116], self.uid.min(USTAR_MAX_ID) as u64);
+        put_octal(&mut block[116..124], self.gid.min(USTAR_MAX_ID) as u64);
+        put_octal(&mut block[124..136], self.size.min(USTAR_MAX_SIZE));
+        put_octal(&mut block[136..148], self.mtime.min(USTAR_MAX_SIZE));
+        block[156] = self.type_flag;
+        put_bytes(&mut block[157..257], self.link_name);
+        block[257..263].copy_from_slice(b"ustar\0");
+        block[263..265].copy_from_slice(b"00");
+        if let Some((major, minor)) = self.rdev {
+            put_octal(&mut block[329..337], major as u64);
+            put_octal(&mut block[337..345], minor as u64);
+        }
+        put_bytes(&mut block[345..500], prefix);
+
+        // The checksum is computed with its own field filled with spaces
+        block[148..156].fill(b' ');
+        let checksum: u64 = block.iter().map(|b| *b as u64).sum();
+        put_octal(&mut block[148..155], checksum);
+
+        block
+    }
+}
+
+/// Splits a name into the prefix and name fields of a ustar header, if it fits.
+fn split_name(name: &[u8]) -> Option<(&[u8], &[u8])> {
+    if name.len() <= 100 {
+        return Some((&[], name));
+    }
+
+    // The prefix must end at a slash that isn't part of the name field
+    let start = name.len().saturating_sub(101);
+    if start > 155 {
+        return None;
+    }
+    let pos = name[start..name.len().min(156)]
+        .iter()
+        .position(|b| *b == b'/')?;
+    let pos = start + pos;
+    Some((&name[..pos], &name[pos + 1..])).filter(|(_, name)| !name.is_empty())
+}
+
+/// Appends a `<len> <key>=<value>\n` record, where `len` counts the whole record.
+fn push_pax_record(records: &mut Vec<u8>, key: &[u8], value: &[u8]) {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + rest.to_string().len();
+    if len.to_string().len() + rest != len {
+        len += 1;
+    }
+
+    records.extend_from_slice(format!("{len} ").as_bytes());
+    records.extend_from_slice(key);
+    records.push(b'=');
+    records.extend_from_slice(value);
+    records.push(b'\n');
+}
+
+fn put_bytes(field: &mut [u8], value: &[u8]) {
+    let len = value.len().min(field.len());
+    field[..len].copy_from_slice(&value[..len]);
+}
+
+/// Writes `value` as zero-padded octal digits followed by a NUL.
+fn put_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{value:0width$o}", width = field.len() - 1);
+    put_bytes(field, digits.as_bytes());
+}
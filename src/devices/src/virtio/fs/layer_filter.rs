@@ -0,0 +1,192 @@
+//! Bloom filters of the paths of the lower layers of an overlay.
+//!
+//! A lookup probes the layers from the top down until one of them has the entry, a whiteout
+//! hiding it or an opaque directory masking it, which costs a few system calls per layer for the
+//! many paths that only exist in one or two of them. A `LayerFilter` records the paths of the
+//! entries, whiteouts and opaque directories of a lower layer, so that a lookup can skip the layers
+//! that certainly have nothing to say about a path. Lower layers are read-only, so a filter stays
+//! valid until they are changed on the host.
+
+use std::{
+    collections::hash_map::DefaultHasher, ffi::OsStr, fs, hash::Hasher, io,
+    os::unix::ffi::OsStrExt, path::Path,
+};
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The prefix for whiteout files
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+
+/// The marker of opaque directories
+const OPAQUE_MARKER: &[u8] = b".wh..wh..opq";
+
+/// Bits per recorded path, which with `HASHES` gives about 1% of false positives.
+const BITS_PER_PATH: usize = 10;
+
+/// Number of bits set per recorded path.
+const HASHES: u64 = 7;
+
+/// The smallest filter, in 64-bit words, so that the filters of tiny layers don't fill up.
+const MIN_WORDS: usize = 16;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// The paths found in a layer. The default filter is empty and may contain any path, which is
+/// what lookups fall back to when a layer can't be walked.
+#[derive(Default)]
+pub(crate) struct LayerFilter {
+    bits: Vec<u64>,
+}
+
+/// What a path recorded in a filter is, so that lookups can tell a directory the path goes
+/// through apart from one masking the lower layers.
+#[derive(Clone, Copy)]
+enum PathKind {
+    Entry = 0,
+    Whiteout = 1,
+    Opaque = 2,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl LayerFilter {
+    /// Walks the layer rooted at `root` and records the paths of its entries.
+    pub(crate) fn build(root: &Path) -> io::Result<Self> {
+        let mut hashes = Vec::new();
+        let mut dirs = vec![Vec::new()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(root.join(OsStr::from_bytes(&dir)))? {
+                let entry = entry?;
+                let name = entry.file_name();
+                let name = name.as_bytes();
+                if name == OPAQUE_MARKER {
+                    hashes.push(hash(PathKind::Opaque, &dir));
+                    continue;
+                }
+
+                let mut path = dir.clone();
+                if !path.is_empty() {
+                    path.push(b'/');
+                }
+
+                if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+                    // Skip the files of the overlay itself
+                    if !hidden.starts_with(WHITEOUT_PREFIX) {
+                        path.extend_from_slice(hidden);
+                        hashes.push(hash(PathKind::Whiteout, &path));
+                    }
+                    continue;
+                }
+
+                path.extend_from_slice(name);
+                hashes.push(hash(PathKind::Entry, &path));
+                if entry.file_type()?.is_dir() {
+                    dirs.push(path);
+                }
+            }
+        }
+
+        let mut filter = LayerFilter {
+            bits: vec![0; (hashes.len() * BITS_PER_PATH).div_ceil(64).max(MIN_WORDS)],
+        };
+        for hash in hashes {
+            for bit in filter.bit_indices(hash) {
+                filter.bits[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Whether a lookup of `path`, relative to the root of the layer, may find anything in the
+    /// layer: the entry itself, a whiteout of it or of one of its ancestors, or an opaque directory
+    /// on the way. The first `known` path segments were already found in the layers above, so an
+    /// ancestor among them only matters if it's whited out here.
+    pub(crate) fn may_affect(&self, path: &[u8], known: usize) -> bool {
+        if self.bits.is_empty() || self.may_contain(PathKind::Opaque, b"") {
+            return true;
+        }
+
+        let ends = path
+            .iter()
+            .enumerate()
+            .filter_map(|(i, b)| (*b == b'/').then_some(i))
+            .chain([path.len()]);
+        for (depth, end) in ends.enumerate() {
+            let prefix = &path[..end];
+            if self.may_contain(PathKind::Whiteout, prefix)
+                || (depth >= known && self.may_contain(PathKind::Entry, prefix))
+                || (end < path.len() && self.may_contain(PathKind::Opaque, prefix))
+            {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    fn may_contain(&self, kind: PathKind, path: &[u8]) -> bool {
+        self.bit_indices(hash(kind, path))
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Derives the bits of a path from its hash by double hashing.
+    fn bit_indices(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 64;
+        let (h1, h2) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        (0..HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+fn hash(kind: PathKind, path: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write_u8(kind as u8);
+    hasher.write(path);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn layer_filter() {
+        let layer = tempfile::tempdir().unwrap();
+        let root = layer.path();
+        fs::create_dir_all(root.join("usr/bin")).unwrap();
+        fs::write(root.join("usr/bin/ls"), b"").unwrap();
+        fs::create_dir(root.join("opt")).unwrap();
+        fs::write(root.join("opt/.wh..wh..opq"), b"").unwrap();
+        fs::write(root.join(".wh.etc"), b"").unwrap();
+
+        let filter = LayerFilter::build(root).unwrap();
+
+        // Entries, including the ones only found on the way to the looked up path
+        assert!(filter.may_affect(b"usr/bin/ls", 0));
+        assert!(filter.may_affect(b"usr/bin/cat", 0));
+        assert!(!filter.may_affect(b"usr/bin/cat", 2));
+        assert!(!filter.may_affect(b"var/log", 0));
+
+        // Whiteouts hide their descendants, and opaque directories all the lower entries below
+        assert!(filter.may_affect(b"etc", 0));
+        assert!(filter.may_affect(b"etc/passwd", 1));
+        assert!(filter.may_affect(b"opt/app", 1));
+        assert!(!filter.may_affect(b".wh.etc", 0));
+
+        // An opaque root masks everything
+        fs::write(root.join(".wh..wh..opq"), b"").unwrap();
+        let filter = LayerFilter::build(root).unwrap();
+        assert!(filter.may_affect(b"var/log", 1));
+
+        assert!(LayerFilter::default().may_affect(b"var/log", 1));
+    }
+}
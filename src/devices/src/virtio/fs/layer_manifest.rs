@@ -0,0 +1,396 @@
+//! Manifests of the lower layers of an overlay, to detect the layers edited on the host.
+//!
+//! The lower layers are meant to be immutable, yet nothing stops a host process from editing their
+//! directories. On the first mount of a layer, a `LayerManifest` records the metadata of each of
+//! its entries, with a digest of a sample of the data of the regular files: their first and last
+//! blocks. The later mounts compare the layer against the manifest, which catches the entries
+//! added, removed or modified since, short of a rewrite of the middle of a file that preserves its
+//! size and modification time.
+//!
+//! The manifests are kept in a host directory, each in a file named after the digest of the
+//! canonical path of its layer.
+
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write as _},
+    fs::{self, File, Metadata},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::{FileExt, MetadataExt},
+    },
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+
+use super::layer_diff;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The first line of a manifest, with the version of its format.
+const HEADER: &str = "krun-layer-manifest 1";
+
+/// The size of the samples of data at the start and the end of regular files.
+const SAMPLE_SIZE: u64 = 4096;
+
+/// The number of drifted entries named when reporting the drift of a layer.
+const MAX_REPORTED: usize = 8;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// The state of the entries of a layer, keyed by their path relative to the root of the layer.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct LayerManifest {
+    entries: BTreeMap<PathBuf, ManifestEntry>,
+}
+
+/// The recorded state of an entry. Directories only record their mode, as their size and
+/// modification time change with their entries, which are recorded on their own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ManifestEntry {
+    mode: u32,
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    /// The digest of the sampled data of a regular file, or of the target of a symlink
+    sample: Option<[u8; 32]>,
+}
+
+/// How an entry of a layer differs from the manifest of the layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Drift {
+    Added,
+    Removed,
+    Modified,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl LayerManifest {
+    /// Records the state of every entry under `root`.
+    pub(crate) fn capture(root: &Path) -> io::Result<Self> {
+        let mut entries = BTreeMap::new();
+        layer_diff::walk(root, Path::new(""), &|_| false, &mut |path, md| {
+            let entry = ManifestEntry::capture(&root.join(path), md)?;
+            entries.insert(path.to_path_buf(), entry);
+            Ok(())
+        })?;
+
+        Ok(Self { entries })
+    }
+
+    /// Reads the manifest stored at `path`, if there is one.
+    pub(crate) fn load(path: &Path) -> io::Result<Option<Self>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let mut lines = BufReader::new(file).lines();
+        if lines.next().transpose()?.as_deref() != Some(HEADER) {
+            return Err(invalid_manifest(path));
+        }
+
+        let mut entries = BTreeMap::new();
+        for line in lines {
+            let line = line?;
+            let (path_field, entry) =
+                ManifestEntry::parse(&line).ok_or_else(|| invalid_manifest(path))?;
+            let entry_path = unescape(path_field).ok_or_else(|| invalid_manifest(path))?;
+            entries.insert(entry_path, entry);
+        }
+
+        Ok(Some(Self { entries }))
+    }
+
+    /// Stores the manifest at `path`, replacing the previous one atomically.
+    pub(crate) fn store(&self, path: &Path) -> io::Result<()> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(format!(".tmp.{}", std::process::id()));
+
+        let mut out = BufWriter::new(File::create(&tmp_path)?);
+        writeln!(out, "{HEADER}")?;
+        for (path, entry) in &self.entries {
+            writeln!(out, "{} {}", entry.format(), escape(path))?;
+        }
+        out.into_inner()
+            .map_err(io::IntoInnerError::into_error)?
+            .sync_all()?;
+
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Returns the entries of `current` that differ from the manifest, sorted by path.
+    pub(crate) fn drift(&self, current: &LayerManifest) -> Vec<(PathBuf, Drift)> {
+        let mut drift: Vec<_> = current
+            .entries
+            .iter()
+            .filter_map(|(path, entry)| match self.entries.get(path) {
+                None => Some((path.clone(), Drift::Added)),
+                Some(recorded) if recorded != entry => Some((path.clone(), Drift::Modified)),
+                Some(_) => None,
+            })
+            .collect();
+        drift.extend(
+            self.entries
+                .keys()
+                .filter(|path| !current.entries.contains_key(*path))
+                .map(|path| (path.clone(), Drift::Removed)),
+        );
+        drift.sort();
+        drift
+    }
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Drift::Added => "added",
+            Drift::Removed => "removed",
+            Drift::Modified => "modified",
+        })
+    }
+}
+
+impl ManifestEntry {
+    fn capture(path: &Path, md: &Metadata) -> io::Result<Self> {
+        let file_type = md.file_type();
+        let sample = if file_type.is_file() {
+            Some(sample_digest(&File::open(path)?, md.size())?)
+        } else if file_type.is_symlink() {
+            let mut hasher = Sha256::new();
+            hasher.update(fs::read_link(path)?.as_os_str().as_bytes());
+            Some(hasher.finalize().into())
+        } else {
+            None
+        };
+
+        let (size, mtime, mtime_nsec) = if file_type.is_dir() {
+            (0, 0, 0)
+        } else {
+            (md.size(), md.mtime(), md.mtime_nsec())
+        };
+        Ok(Self {
+            mode: md.mode(),
+            size,
+            mtime,
+            mtime_nsec,
+            sample,
+        })
+    }
+
+    /// Formats the entry as the fields of a line of a manifest, before the path.
+    fn format(&self) -> String {
+        let sample = match &self.sample {
+            Some(digest) => hex(digest),
+            None => "-".to_string(),
+        };
+        format!(
+            "{:o} {} {}.{:09} {}",
+            self.mode, self.size, self.mtime, self.mtime_nsec, sample
+        )
+    }
+
+    /// Parses a line of a manifest into its path field and its entry.
+    fn parse(line: &str) -> Option<(&str, Self)> {
+        let mut fields = line.split(' ');
+        let mode = u32::from_str_radix(fields.next()?, 8).ok()?;
+        let size = fields.next()?.parse().ok()?;
+        let (mtime, mtime_nsec) = fields.next()?.split_once('.')?;
+        let sample = match fields.next()? {
+            "-" => None,
+            digest => Some(parse_hex(digest)?),
+        };
+        let path = fields.next()?;
+        if fields.next().is_some() {
+            return None;
+        }
+
+        let entry = Self {
+            mode,
+            size,
+            mtime: mtime.parse().ok()?,
+            mtime_nsec: mtime_nsec.parse().ok()?,
+            sample,
+        };
+        Some((path, entry))
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Checks `layer` against its manifest in `manifest_dir`, and returns the entries that drifted
+/// from it, sorted by path. The manifest is recorded instead if there is none yet.
+pub(crate) fn verify(layer: &Path, manifest_dir: &Path) -> io::Result<Vec<(PathBuf, Drift)>> {
+    let mut hasher = Sha256::new();
+    hasher.update(fs::canonicalize(layer)?.as_os_str().as_bytes());
+    let manifest_path = manifest_dir.join(hex(&hasher.finalize()));
+
+    let current = LayerManifest::capture(layer)?;
+    match LayerManifest::load(&manifest_path)? {
+        Some(recorded) => Ok(recorded.drift(&current)),
+        None => {
+            fs::create_dir_all(manifest_dir)?;
+            current.store(&manifest_path)?;
+            Ok(Vec::new())
+        }
+    }
+}
+
+/// Describes the `drift` of `layer` from its manifest, naming the first few entries.
+pub(crate) fn describe(layer: &Path, drift: &[(PathBuf, Drift)]) -> String {
+    let mut description = format!(
+        "layer {} drifted from its manifest: {} entries changed",
+        layer.display(),
+        drift.len()
+    );
+    for (path, drift) in drift.iter().take(MAX_REPORTED) {
+        let _ = write!(description, ", {} ({drift})", path.display());
+    }
+    if drift.len() > MAX_REPORTED {
+        description.push_str(", ...");
+    }
+    description
+}
+
+/// Returns the digest of the size and the first and last `SAMPLE_SIZE` bytes of `file`.
+fn sample_digest(file: &File, size: u64) -> io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    hasher.update(size.to_le_bytes());
+
+    let mut buf = vec![0u8; SAMPLE_SIZE as usize];
+    let tail_start = size.saturating_sub(SAMPLE_SIZE).max(SAMPLE_SIZE);
+    for (start, end) in [(0, size.min(SAMPLE_SIZE)), (tail_start, size)] {
+        if start >= end {
+            continue;
+        }
+        let len = (end - start) as usize;
+        file.read_exact_at(&mut buf[..len], start)?;
+        hasher.update(&buf[..len]);
+    }
+
+    Ok(hasher.finalize().into())
+}
+
+/// Escapes the bytes of `path` that would break the fields of a manifest line.
+fn escape(path: &Path) -> String {
+    path.as_os_str()
+        .as_bytes()
+        .iter()
+        .fold(String::new(), |mut escaped, &b| {
+            if b <= b' ' || b == b'%' || b >= 0x7f {
+                let _ = write!(escaped, "%{b:02x}");
+            } else {
+                escaped.push(b as char);
+            }
+            escaped
+        })
+}
+
+fn unescape(field: &str) -> Option<PathBuf> {
+    let mut bytes = Vec::with_capacity(field.len());
+    let mut rest = field.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    Some(PathBuf::from(std::ffi::OsString::from_vec(bytes)))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{b:02x}");
+        hex
+    })
+}
+
+fn parse_hex(hex: &str) -> Option<[u8; 32]> {
+    let mut digest = [0u8; 32];
+    if hex.len() != 2 * digest.len() {
+        return None;
+    }
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+fn invalid_manifest(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid layer manifest {}", path.display()),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn drift() {
+        let layer = tempfile::tempdir().unwrap();
+        let manifest_dir = tempfile::tempdir().unwrap();
+        let manifests = manifest_dir.path().join("manifests");
+        fs::create_dir(layer.path().join("etc")).unwrap();
+        fs::write(layer.path().join("etc/hosts"), vec![b'a'; 3 * 4096]).unwrap();
+        fs::write(layer.path().join("etc/odd name\n%"), b"").unwrap();
+        std::os::unix::fs::symlink("hosts", layer.path().join("etc/link")).unwrap();
+
+        // The first check records the manifest, which round-trips
+        assert_eq!(verify(layer.path(), &manifests).unwrap(), []);
+        let stored = fs::read_dir(&manifests).unwrap().next().unwrap().unwrap();
+        let loaded = LayerManifest::load(&stored.path()).unwrap().unwrap();
+        assert_eq!(loaded, LayerManifest::capture(layer.path()).unwrap());
+        assert_eq!(verify(layer.path(), &manifests).unwrap(), []);
+
+        // A rewrite of the end of a file keeping its size and modification time is caught
+        let hosts = File::options()
+            .write(true)
+            .open(layer.path().join("etc/hosts"))
+            .unwrap();
+        let mtime = hosts.metadata().unwrap().modified().unwrap();
+        hosts.write_all_at(b"b", 3 * 4096 - 1).unwrap();
+        hosts.set_modified(mtime).unwrap();
+        fs::remove_file(layer.path().join("etc/link")).unwrap();
+        fs::write(layer.path().join("new"), b"").unwrap();
+        assert_eq!(
+            verify(layer.path(), &manifests).unwrap(),
+            [
+                (PathBuf::from("etc/hosts"), Drift::Modified),
+                (PathBuf::from("etc/link"), Drift::Removed),
+                (PathBuf::from("new"), Drift::Added),
+            ]
+        );
+        assert_eq!(
+            describe(Path::new("/layer"), &[(PathBuf::from("new"), Drift::Added)]),
+            "layer /layer drifted from its manifest: 1 entries changed, new (added)"
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("manifest");
+        assert_eq!(LayerManifest::load(&path).unwrap(), None);
+
+        fs::write(&path, format!("{HEADER}\n644 0 0.0 - a b\n")).unwrap();
+        let err = LayerManifest::load(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,110 @@
+//! Validation of the layer roots of an overlay.
+//!
+//! The layers are given as host paths, which may be relative, end with a slash or go through
+//! symlinks, and nothing stops a caller from giving the same directory twice, or one inside
+//! another. The overlay would then only misbehave much later, e.g. by telling the entries apart by
+//! the device and inode numbers of a symlink rather than of its target. The layer roots are
+//! resolved and checked once, before the overlay is created, and the errors name the offending
+//! layer by its index, from the bottom one.
+
+use std::{
+    fs, io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Returns the canonical paths of the roots of `layers`, given from the bottom one to the top
+/// one, after checking that they are directories, none of which is another one or lies inside
+/// another one.
+pub(crate) fn canonicalize(layers: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+    let mut roots: Vec<(PathBuf, (u64, u64))> = Vec::with_capacity(layers.len());
+
+    for (idx, layer) in layers.iter().enumerate() {
+        let path = fs::canonicalize(layer).map_err(|e| layer_error(idx, layer, e.kind(), &e))?;
+        let metadata = fs::metadata(&path).map_err(|e| layer_error(idx, layer, e.kind(), &e))?;
+        if !metadata.is_dir() {
+            return Err(layer_error(
+                idx,
+                layer,
+                io::ErrorKind::InvalidInput,
+                "not a directory",
+            ));
+        }
+
+        let id = (metadata.dev(), metadata.ino());
+        for (other_idx, (other, other_id)) in roots.iter().enumerate() {
+            let problem = if *other_id == id {
+                format!("same directory as layer {other_idx}")
+            } else if path.starts_with(other) {
+                format!("inside layer {other_idx} ({})", other.display())
+            } else if other.starts_with(&path) {
+                format!("contains layer {other_idx} ({})", other.display())
+            } else {
+                continue;
+            };
+            return Err(layer_error(
+                idx,
+                layer,
+                io::ErrorKind::InvalidInput,
+                problem,
+            ));
+        }
+
+        roots.push((path, id));
+    }
+
+    Ok(roots.into_iter().map(|(path, _)| path).collect())
+}
+
+fn layer_error(
+    idx: usize,
+    layer: &Path,
+    kind: io::ErrorKind,
+    problem: impl std::fmt::Display,
+) -> io::Error {
+    io::Error::new(
+        kind,
+        format!("layer {idx} ({}): {problem}", layer.display()),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn layer_roots() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(dir.path()).unwrap();
+        fs::create_dir_all(root.join("lower/sub")).unwrap();
+        fs::create_dir(root.join("upper")).unwrap();
+        fs::write(root.join("file"), b"").unwrap();
+        std::os::unix::fs::symlink("lower", root.join("link")).unwrap();
+
+        // Symlinks, trailing slashes and dot components are resolved
+        let layers = canonicalize(&[root.join("link/"), root.join("./upper")]).unwrap();
+        assert_eq!(layers, vec![root.join("lower"), root.join("upper")]);
+
+        let err = |layers: &[PathBuf]| canonicalize(layers).unwrap_err();
+        let e = err(&[root.join("lower"), root.join("missing")]);
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert!(e.to_string().starts_with("layer 1 ("));
+
+        let e = err(&[root.join("file")]);
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(e.to_string().ends_with("not a directory"));
+
+        let e = err(&[root.join("lower"), root.join("upper"), root.join("link")]);
+        assert!(e.to_string().ends_with("same directory as layer 0"));
+
+        let e = err(&[root.join("lower"), root.join("lower/sub")]);
+        assert!(e.to_string().contains("inside layer 0"));
+
+        let e = err(&[root.join("lower/sub"), root.join("lower")]);
+        assert!(e.to_string().contains("contains layer 0"));
+    }
+}
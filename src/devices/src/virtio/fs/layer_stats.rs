@@ -0,0 +1,137 @@
+//! The statistics of the layers of an overlay.
+//!
+//! How the layers of an overlay are used tells where the time of its requests goes: a layer most
+//! lookups reach through many layers above it, or files copied up again and again from the same
+//! layer. Each layer counts the lookups it serves and its entries copied up or whited out, as the
+//! overlay handles them, while the inodes and the handles of a layer are counted from the tables
+//! of the overlay when its statistics are read, see `OverlayFs::stats`.
+//!
+//! On Linux, the guest reads them through the export table, like the files it exports to the GPU
+//! cross-domain context: the `VIRTIO_IOC_LAYER_STATS` ioctl, `_IOR('v', 6, uint64_t[2])` on any
+//! open file of the overlay, snapshots the statistics into an anonymous file, exports it under the
+//! id of the share and the handle, as `VIRTIO_IOC_EXPORT_FD` does, and returns both. The file
+//! holds, for each layer from the bottom one up, the `lookups`, `copy_ups`, `whiteouts`,
+//! `bytes_copied_up`, `inodes` and `open_handles` of its `FsLayerStats`, as little-endian `u64`s.
+//! Issuing the ioctl again replaces the snapshot, so the statistics are streamed by polling it.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(target_os = "linux")]
+use std::{fs::File, io, io::Write, os::fd::FromRawFd};
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// The statistics of a layer of an overlay, since the overlay was created.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FsLayerStats {
+    /// The lookups that found their entry in the layer.
+    pub lookups: u64,
+    /// The entries of the layer copied up to the top layer.
+    pub copy_ups: u64,
+    /// The whiteouts created to hide entries of the layer.
+    pub whiteouts: u64,
+    /// The bytes of the regular files of the layer copied up to the top layer.
+    pub bytes_copied_up: u64,
+    /// The inodes of the layer the overlay keeps, the root of the layer included.
+    pub inodes: u64,
+    /// The handles the guest keeps open on the inodes of the layer.
+    pub open_handles: u64,
+}
+
+/// The running counts of the layers of an overlay, by layer index.
+#[derive(Debug)]
+pub(crate) struct LayerCounters(Vec<Counters>);
+
+#[derive(Debug, Default)]
+struct Counters {
+    lookups: AtomicU64,
+    copy_ups: AtomicU64,
+    whiteouts: AtomicU64,
+    bytes_copied_up: AtomicU64,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl LayerCounters {
+    pub(crate) fn new(layer_count: usize) -> Self {
+        LayerCounters((0..layer_count).map(|_| Counters::default()).collect())
+    }
+
+    /// Counts a lookup that found its entry in the layer `layer_idx`.
+    pub(crate) fn lookup(&self, layer_idx: usize) {
+        if let Some(counters) = self.0.get(layer_idx) {
+            counters.lookups.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts an entry of the layer `layer_idx` copied up, with `bytes` of data.
+    pub(crate) fn copy_up(&self, layer_idx: usize, bytes: u64) {
+        if let Some(counters) = self.0.get(layer_idx) {
+            counters.copy_ups.fetch_add(1, Ordering::Relaxed);
+            counters.bytes_copied_up.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a whiteout created to hide an entry of the layer `layer_idx`.
+    pub(crate) fn whiteout(&self, layer_idx: usize) {
+        if let Some(counters) = self.0.get(layer_idx) {
+            counters.whiteouts.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the statistics of the layers, with no inodes nor handles counted yet.
+    pub(crate) fn read(&self) -> Vec<FsLayerStats> {
+        self.0
+            .iter()
+            .map(|counters| FsLayerStats {
+                lookups: counters.lookups.load(Ordering::Relaxed),
+                copy_ups: counters.copy_ups.load(Ordering::Relaxed),
+                whiteouts: counters.whiteouts.load(Ordering::Relaxed),
+                bytes_copied_up: counters.bytes_copied_up.load(Ordering::Relaxed),
+                inodes: 0,
+                open_handles: 0,
+            })
+            .collect()
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Returns the statistics of the layers as exported by `VIRTIO_IOC_LAYER_STATS`.
+#[cfg(target_os = "linux")]
+pub(crate) fn encode(stats: &[FsLayerStats]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(stats.len() * 48);
+    for layer in stats {
+        for count in [
+            layer.lookups,
+            layer.copy_ups,
+            layer.whiteouts,
+            layer.bytes_copied_up,
+            layer.inodes,
+            layer.open_handles,
+        ] {
+            encoded.extend_from_slice(&count.to_le_bytes());
+        }
+    }
+    encoded
+}
+
+/// Returns an anonymous file holding the statistics of the layers, to be exported.
+#[cfg(target_os = "linux")]
+pub(crate) fn snapshot(stats: &[FsLayerStats]) -> io::Result<File> {
+    const NAME: &[u8] = b"krun-layer-stats\0";
+    // Safe because the name is NUL-terminated and the result is checked.
+    let fd = unsafe { libc::memfd_create(NAME.as_ptr().cast(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because the fd was just created and nothing else owns it.
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(&encode(stats))?;
+    Ok(file)
+}
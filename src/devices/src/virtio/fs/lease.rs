@@ -0,0 +1,186 @@
+//! Leases on the attributes and data the guest caches.
+//!
+//! The guest caches the attributes of a file for the attribute timeout of the share, which has to
+//! stay short for the changes made on the host to show up in time. With leases, the guest is handed
+//! a much longer timeout for the files it accesses, and the lease on a file is broken as soon as the
+//! revalidation finds it changed on the host, by another process or by another VM sharing the same
+//! files. A broken lease is revoked through the notification queue: the worker pushes an
+//! invalidation of the inode to the guest, which then drops the attributes and the data it cached
+//! of that file only.
+//!
+//! The entries of directories aren't leased: a change of a directory on the host doesn't tell which
+//! of its entries changed, so they keep the timeouts of the share.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use utils::eventfd::{EventFd, EFD_NONBLOCK};
+
+use super::FsLeases;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The revalidation interval of the shares granting leases without setting one.
+pub(crate) const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// The leases the guest holds on the inodes of a share. Disabled leases are never granted.
+#[derive(Clone, Default)]
+pub(crate) struct Leases(Option<Arc<LeaseState>>);
+
+struct LeaseState {
+    timeout: Duration,
+    /// When the leases held by the guest expire, by inode
+    expiries: Mutex<HashMap<u64, Instant>>,
+    /// The inodes whose leases were broken, which the guest is yet to be told about
+    broken: Mutex<Vec<u64>>,
+    /// Signaled when a lease is broken
+    event: EventFd,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl Leases {
+    /// Creates the leases of a share, or disabled ones if `leases` is `None`.
+    pub(crate) fn new(leases: Option<FsLeases>) -> Self {
+        let Some(leases) = leases else {
+            return Self::default();
+        };
+
+        Leases(Some(Arc::new(LeaseState {
+            timeout: leases.timeout,
+            expiries: Mutex::new(HashMap::new()),
+            broken: Mutex::new(Vec::new()),
+            event: EventFd::new(EFD_NONBLOCK).unwrap(),
+        })))
+    }
+
+    /// Grants the guest a lease on `inode`, or extends the one it holds, returning how long the
+    /// guest may cache its attributes.
+    pub(crate) fn grant(&self, inode: u64) -> Option<Duration> {
+        let state = self.0.as_ref()?;
+        state
+            .expiries
+            .lock()
+            .unwrap()
+            .insert(inode, Instant::now() + state.timeout);
+        Some(state.timeout)
+    }
+
+    /// Whether the guest holds a lease on `inode`.
+    pub(crate) fn is_held(&self, inode: u64) -> bool {
+        let Some(state) = &self.0 else {
+            return false;
+        };
+        state
+            .expiries
+            .lock()
+            .unwrap()
+            .get(&inode)
+            .is_some_and(|expiry| *expiry > Instant::now())
+    }
+
+    /// Breaks the lease the guest holds on `inode`, if any, to be revoked by the worker.
+    pub(crate) fn break_lease(&self, inode: u64) {
+        let Some(state) = &self.0 else {
+            return;
+        };
+        let Some(expiry) = state.expiries.lock().unwrap().remove(&inode) else {
+            return;
+        };
+        if expiry <= Instant::now() {
+            // The guest no longer caches anything on the strength of the lease
+            return;
+        }
+
+        debug!("breaking the lease on inode {inode}");
+        state.broken.lock().unwrap().push(inode);
+        if let Err(e) = state.event.write(1) {
+            error!("failed to signal a broken lease: {e:?}");
+        }
+    }
+
+    /// Breaks all the leases the guest holds, to be revoked by the worker.
+    pub(crate) fn break_all(&self) {
+        let Some(state) = &self.0 else {
+            return;
+        };
+        let inodes: Vec<u64> = state.expiries.lock().unwrap().keys().copied().collect();
+        for inode in inodes {
+            self.break_lease(inode);
+        }
+    }
+
+    /// Returns the inodes whose leases were broken since the last call, in the order they were.
+    pub(crate) fn take_broken(&self) -> Vec<u64> {
+        let Some(state) = &self.0 else {
+            return Vec::new();
+        };
+        // The event is only a wake-up, so it doesn't matter whether it was signaled
+        let _ = state.event.read();
+        std::mem::take(&mut *state.broken.lock().unwrap())
+    }
+
+    /// Returns the event signaled when a lease is broken, unless the leases are disabled.
+    pub(crate) fn event(&self) -> Option<&EventFd> {
+        self.0.as_ref().map(|state| &state.event)
+    }
+
+    /// Forgets the leases that expired.
+    pub(crate) fn expire(&self) {
+        if let Some(state) = &self.0 {
+            let now = Instant::now();
+            state
+                .expiries
+                .lock()
+                .unwrap()
+                .retain(|_, expiry| *expiry > now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn leases() {
+        let leases = Leases::new(Some(FsLeases {
+            timeout: Duration::from_secs(3600),
+        }));
+
+        assert_eq!(leases.grant(2), Some(Duration::from_secs(3600)));
+        assert!(leases.grant(3).is_some());
+        assert!(leases.is_held(2));
+
+        // Only the leases that are held are revoked, once
+        leases.break_lease(3);
+        leases.break_lease(3);
+        leases.break_lease(4);
+        assert!(!leases.is_held(3));
+        assert_eq!(leases.event().unwrap().read().unwrap(), 1);
+        assert_eq!(leases.take_broken(), vec![3]);
+        assert!(leases.take_broken().is_empty());
+
+        // Nor are the expired ones
+        let leases = Leases::new(Some(FsLeases {
+            timeout: Duration::ZERO,
+        }));
+        leases.grant(2);
+        leases.break_lease(2);
+        assert!(leases.take_broken().is_empty());
+
+        // Disabled leases are never granted
+        let leases = Leases::new(None);
+        assert_eq!(leases.grant(2), None);
+        assert!(leases.event().is_none());
+    }
+}
@@ -0,0 +1,129 @@
+//! The access times of the host files the guest reads.
+//!
+//! Every read of the guest is a read of a host file, which updates its access time as the host
+//! file system is mounted to, and on a host mounted with `strictatime` that is a metadata write
+//! per read. The guest can't tell the host to leave the files it opens with `O_NOATIME` alone
+//! either, unless the device runs with `CAP_FOWNER` or owns the files. With [`FsAtime::Noatime`]
+//! or [`FsAtime::Relatime`], the files are opened with `O_NOATIME` whenever the host allows it,
+//! and with the latter the device updates the access time itself the way `relatime` would, once
+//! per open file.
+
+use std::io;
+use std::os::fd::RawFd;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::super::FsAtime;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The age past which `relatime` updates an access time even if the file didn't change since.
+const RELATIME_MAX_AGE: i64 = 24 * 60 * 60;
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Returns the flags to open a file the guest opens with `flags`, and whether its access time is
+/// to be updated by the device on the first read.
+pub(crate) fn open_flags(atime: FsAtime, flags: u32) -> (u32, bool) {
+    let noatime = flags & libc::O_NOATIME as u32 != 0;
+    match atime {
+        FsAtime::Host => (flags, false),
+        FsAtime::Relatime => (flags | libc::O_NOATIME as u32, !noatime),
+        FsAtime::Noatime => (flags | libc::O_NOATIME as u32, false),
+    }
+}
+
+/// Returns whether opening a file with `flags` failed only for `O_NOATIME`, which needs the device
+/// to own the file or to have `CAP_FOWNER`, clearing it from `flags` to try again without it.
+pub(crate) fn retry_without_noatime(flags: &mut i32) -> bool {
+    if *flags & libc::O_NOATIME == 0
+        || io::Error::last_os_error().raw_os_error() != Some(libc::EPERM)
+    {
+        return false;
+    }
+
+    *flags &= !libc::O_NOATIME;
+    true
+}
+
+/// Updates the access time of `fd` to now if `relatime` would, leaving the other times alone.
+pub(crate) fn relatime_update(fd: RawFd) -> io::Result<()> {
+    let mut st = std::mem::MaybeUninit::<libc::stat64>::uninit();
+    // Safe because the kernel only writes to `st` and we check the return value.
+    if unsafe { libc::fstat64(fd, st.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because `fstat64` succeeded.
+    let st = unsafe { st.assume_init() };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs() as i64);
+    if !relatime_due(&st, now) {
+        return Ok(());
+    }
+
+    let times = [
+        libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_NOW,
+        },
+        libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+    ];
+    // Safe because this doesn't modify any memory and we check the return value.
+    if unsafe { libc::futimens(fd, times.as_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Returns whether `relatime` updates the access time of a file with the times of `st` at `now`:
+/// when it isn't later than the last modification of the file, or is a day old.
+///
+/// Unlike `relatime`, the last change of the file isn't taken into account, as updating the access
+/// time from the device changes it too.
+fn relatime_due(st: &libc::stat64, now: i64) -> bool {
+    (st.st_atime, st.st_atime_nsec) <= (st.st_mtime, st.st_mtime_nsec)
+        || now - st.st_atime >= RELATIME_MAX_AGE
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn atime_open_flags() {
+        let flags = libc::O_RDONLY as u32;
+        let noatime = flags | libc::O_NOATIME as u32;
+        assert_eq!(open_flags(FsAtime::Host, flags), (flags, false));
+        assert_eq!(open_flags(FsAtime::Host, noatime), (noatime, false));
+        assert_eq!(open_flags(FsAtime::Relatime, flags), (noatime, true));
+        assert_eq!(open_flags(FsAtime::Relatime, noatime), (noatime, false));
+        assert_eq!(open_flags(FsAtime::Noatime, flags), (noatime, false));
+    }
+
+    #[test]
+    fn relatime() {
+        // Safe because `stat64` is plain data.
+        let mut st: libc::stat64 = unsafe { std::mem::zeroed() };
+        st.st_mtime = 100;
+        st.st_ctime = 200;
+
+        // Not updated since the last modification
+        st.st_atime = 100;
+        assert!(relatime_due(&st, 200));
+
+        // Updated since, and not a day ago, whatever the last change
+        st.st_atime = 150;
+        assert!(!relatime_due(&st, 200));
+        assert!(relatime_due(&st, 150 + RELATIME_MAX_AGE));
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        relatime_update(std::os::fd::AsRawFd::as_raw_fd(file.as_file())).unwrap();
+    }
+}
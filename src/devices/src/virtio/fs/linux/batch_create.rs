@@ -0,0 +1,316 @@
+//! The creation of many small files of a directory in a single request.
+//!
+//! Extracting an archive in the guest takes a create, a write, a chown, a chmod and a utimes
+//! request per file, each a round trip to the device. With the `VIRTIO_IOC_BATCH_CREATE` ioctl,
+//! `_IOWR('v', 4, size)` on an open directory, the guest agent instead sends a batch of files with
+//! their attributes and content, which are all created or none is.
+//!
+//! The batch is a header followed by its files, each an entry followed by its name and content, in
+//! native endianness and without padding:
+//!
+//! - header: `u32` version, `u32` number of files
+//! - entry: `u32` mode, `u32` uid, `u32` gid, `u32` name length, `u32` content length, `u32`
+//!   nanoseconds and `i64` seconds of the modification time
+//!
+//! The uid and gid are left alone when `u32::MAX`, as with `chown`. The reply is a header with the
+//! version of the device and the number of files created, so a batch of no files tells the guest
+//! agent whether the device supports the version it speaks. The size of an ioctl is at most 16 KiB,
+//! so the files of a batch are small ones.
+
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::{FromRawFd, RawFd};
+
+use nix::request_code_readwrite;
+
+use super::stat_override;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The version of the batches the device understands.
+pub(crate) const BATCH_CREATE_VERSION: u32 = 1;
+
+const VIRTIO_IOC_MAGIC: u8 = b'v';
+const VIRTIO_IOC_TYPE_BATCH_CREATE: u8 = 4;
+
+/// The size bits of an ioctl request code.
+const IOC_SIZE_MASK: u32 =
+    (request_code_readwrite!(0, 0, (1 << 14) - 1) ^ request_code_readwrite!(0, 0, 0)) as u32;
+
+const HEADER_SIZE: usize = 2 * 4;
+const ENTRY_SIZE: usize = 6 * 4 + 8;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// A file of a batch.
+#[derive(Debug)]
+pub(crate) struct BatchFile<'a> {
+    pub(crate) name: CString,
+    pub(crate) mode: u32,
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    pub(crate) mtime: libc::timespec,
+    pub(crate) data: &'a [u8],
+}
+
+/// Reads the fields of a batch one after the other.
+struct Cursor<'a>(&'a [u8]);
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl<'a> Cursor<'a> {
+    fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_ne_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn i64(&mut self) -> io::Result<i64> {
+        Ok(i64::from_ne_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Returns whether `cmd` is a `VIRTIO_IOC_BATCH_CREATE` ioctl, whatever its size.
+pub(crate) fn is_batch_create(cmd: u32) -> bool {
+    cmd & !IOC_SIZE_MASK
+        == request_code_readwrite!(VIRTIO_IOC_MAGIC, VIRTIO_IOC_TYPE_BATCH_CREATE, 0) as u32
+}
+
+/// Parses a batch. Fails with `EPROTONOSUPPORT` if it isn't of the version the device understands,
+/// and with `EINVAL` if it is malformed, if a file isn't a regular file or if a name isn't a single
+/// component or is there twice.
+pub(crate) fn parse(data: &[u8]) -> io::Result<Vec<BatchFile<'_>>> {
+    let mut cursor = Cursor(data);
+    if data.len() < HEADER_SIZE {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+    if cursor.u32()? != BATCH_CREATE_VERSION {
+        return Err(io::Error::from_raw_os_error(libc::EPROTONOSUPPORT));
+    }
+    let count = cursor.u32()? as usize;
+    if count > data.len() / ENTRY_SIZE {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    let mut names = HashSet::new();
+    let mut files = Vec::with_capacity(count);
+    for _ in 0..count {
+        let mode = cursor.u32()?;
+        let uid = cursor.u32()?;
+        let gid = cursor.u32()?;
+        let name_len = cursor.u32()? as usize;
+        let size = cursor.u32()? as usize;
+        let mtime_nsec = cursor.u32()?;
+        let mtime_sec = cursor.i64()?;
+        let name = cursor.bytes(name_len)?;
+        let data = cursor.bytes(size)?;
+
+        let file_type = mode & libc::S_IFMT;
+        if (file_type != 0 && file_type != libc::S_IFREG)
+            || name.is_empty()
+            || name == b"."
+            || name == b".."
+            || name.contains(&b'/')
+            || mtime_nsec >= 1_000_000_000
+            || !names.insert(name)
+        {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        files.push(BatchFile {
+            name: CString::new(name).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?,
+            mode: mode & 0o7777,
+            uid,
+            gid,
+            mtime: libc::timespec {
+                tv_sec: mtime_sec,
+                tv_nsec: mtime_nsec as i64,
+            },
+            data,
+        });
+    }
+
+    Ok(files)
+}
+
+/// Returns the reply to a batch of which `count` files were created.
+pub(crate) fn reply(count: usize) -> Vec<u8> {
+    let mut reply: Vec<_> = BATCH_CREATE_VERSION.to_ne_bytes().into();
+    reply.extend_from_slice(&(count as u32).to_ne_bytes());
+    reply
+}
+
+/// Creates the `files` in the directory `dir_fd`, none of which may exist, with their content and
+/// attributes. If one of them can't be, the ones already created are removed. With
+/// `virtual_ownership`, their owners and modes are kept in their overrides, see `stat_override`.
+pub(crate) fn create_all(
+    dir_fd: RawFd,
+    files: &[BatchFile],
+    virtual_ownership: bool,
+) -> io::Result<()> {
+    for (i, file) in files.iter().enumerate() {
+        if let Err(e) = create(dir_fd, file, virtual_ownership) {
+            for file in &files[..i] {
+                // Safe because this doesn't modify any memory.
+                unsafe { libc::unlinkat(dir_fd, file.name.as_ptr(), 0) };
+            }
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Creates `file` in the directory `dir_fd`, removing it again if it can't be given its content
+/// or attributes. The ownership is changed before the mode, which it would otherwise clear the
+/// set-user-ID and set-group-ID bits of.
+fn create(dir_fd: RawFd, file: &BatchFile, virtual_ownership: bool) -> io::Result<()> {
+    // Safe because this doesn't modify any memory and we check the return value.
+    let fd = unsafe {
+        libc::openat(
+            dir_fd,
+            file.name.as_ptr(),
+            libc::O_CREAT | libc::O_EXCL | libc::O_WRONLY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            0o600,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because we just opened this fd.
+    let mut f = unsafe { File::from_raw_fd(fd) };
+
+    let times = [file.mtime, file.mtime];
+    let mode = if virtual_ownership {
+        stat_override::host_mode(file.mode)
+    } else {
+        file.mode
+    };
+    let res = f.write_all(file.data).and_then(|_| {
+        if virtual_ownership {
+            own(fd, file)?;
+        }
+        // Safe because these don't modify any memory and we check the return values.
+        if (!virtual_ownership && unsafe { libc::fchown(fd, file.uid, file.gid) } < 0)
+            || unsafe { libc::fchmod(fd, mode) } < 0
+            || unsafe { libc::futimens(fd, times.as_ptr()) } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    });
+    if res.is_err() {
+        // Safe because this doesn't modify any memory.
+        unsafe { libc::unlinkat(dir_fd, file.name.as_ptr(), 0) };
+    }
+    res
+}
+
+/// Keeps the owner and mode of `file`, open as `fd`, in its override.
+fn own(fd: RawFd, file: &BatchFile) -> io::Result<()> {
+    // Safe because we check the return value before reading `st`.
+    let mut st: libc::stat64 = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat64(fd, &mut st) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    stat_override::update(fd, &st, |value| {
+        if file.uid != u32::MAX {
+            value.uid = file.uid;
+        }
+        if file.gid != u32::MAX {
+            value.gid = file.gid;
+        }
+        value.mode = libc::S_IFREG | file.mode;
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::fd::AsRawFd;
+
+    fn batch(files: &[(&str, u32, &[u8])]) -> Vec<u8> {
+        let mut batch: Vec<_> = BATCH_CREATE_VERSION.to_ne_bytes().into();
+        batch.extend_from_slice(&(files.len() as u32).to_ne_bytes());
+        for (name, mode, data) in files {
+            for field in [
+                *mode,
+                u32::MAX,
+                u32::MAX,
+                name.len() as u32,
+                data.len() as u32,
+                5,
+            ] {
+                batch.extend_from_slice(&field.to_ne_bytes());
+            }
+            batch.extend_from_slice(&1_000_000i64.to_ne_bytes());
+            batch.extend_from_slice(name.as_bytes());
+            batch.extend_from_slice(data);
+        }
+        batch
+    }
+
+    #[test]
+    fn batch_create() {
+        let cmd = request_code_readwrite!(VIRTIO_IOC_MAGIC, VIRTIO_IOC_TYPE_BATCH_CREATE, 4096);
+        assert!(is_batch_create(cmd as u32));
+        assert!(!is_batch_create(
+            request_code_readwrite!(VIRTIO_IOC_MAGIC, 3, 4096) as u32
+        ));
+
+        // Malformed batches
+        assert!(parse(&[0; 4]).is_err());
+        let mut data = batch(&[("a", 0o644, b"x")]);
+        data[0] = 9;
+        let e = parse(&data).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EPROTONOSUPPORT));
+        let data = batch(&[("a", 0o644, b"x")]);
+        assert!(parse(&data[..data.len() - 1]).is_err());
+        for files in [
+            &[("a/b", 0o644, &b""[..])][..],
+            &[("..", 0o644, b"")],
+            &[("a", libc::S_IFDIR | 0o755, b"")],
+            &[("a", 0o644, b""), ("a", 0o644, b"")],
+        ] {
+            assert!(parse(&batch(files)).is_err(), "{files:?}");
+        }
+        assert!(parse(&batch(&[])).unwrap().is_empty());
+
+        // All the files are created, or none is
+        let dir = tempfile::tempdir().unwrap();
+        let dir_file = File::open(dir.path()).unwrap();
+        let data = batch(&[("a", 0o640, b"hello"), ("b", 0o600, b"")]);
+        create_all(dir_file.as_raw_fd(), &parse(&data).unwrap(), false).unwrap();
+        assert_eq!(std::fs::read(dir.path().join("a")).unwrap(), b"hello");
+        let metadata = std::fs::metadata(dir.path().join("a")).unwrap();
+        assert_eq!(
+            std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o7777,
+            0o640
+        );
+        assert_eq!(std::os::unix::fs::MetadataExt::mtime(&metadata), 1_000_000);
+
+        let data = batch(&[("c", 0o644, b""), ("a", 0o644, b"")]);
+        let e = create_all(dir_file.as_raw_fd(), &parse(&data).unwrap(), false).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EEXIST));
+        assert!(!dir.path().join("c").exists());
+        assert_eq!(std::fs::read(dir.path().join("a")).unwrap(), b"hello");
+    }
+}
@@ -0,0 +1,179 @@
+//! Warming of the host dentries of the directories the guest visits.
+//!
+//! Shells and tools like `ls -l` usually look up most entries of a directory right after looking
+//! up or reading the directory itself. Each of these lookups probes the layers of the overlay for
+//! the entry and its whiteout, which reads the host directories and inodes from the disk when the
+//! host doesn't have them cached. The first time the guest visits a directory, a background thread
+//! stats its entries in each layer the way the lookups will, up to a budget of entries, so that
+//! the host has them cached by the time the guest asks.
+
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::thread;
+
+use super::overlayfs::{OPAQUE_MARKER, WHITEOUT_PREFIX};
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The number of directories waiting to be warmed above which new ones are skipped.
+const MAX_QUEUED: usize = 64;
+
+/// The number of directories remembered as visited, above which they are all forgotten.
+const MAX_VISITED: usize = 1 << 16;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// The background thread warming the directories, and the directories already visited.
+pub(crate) struct DentryWarmer {
+    visited: Mutex<HashSet<u64>>,
+    queue: SyncSender<Vec<File>>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl DentryWarmer {
+    /// Starts the thread warming at most `budget` entries per directory, which stops once the
+    /// warmer is dropped.
+    pub(crate) fn new(budget: usize) -> io::Result<Self> {
+        let (queue, jobs) = mpsc::sync_channel(MAX_QUEUED);
+        thread::Builder::new()
+            .name("fs dentry warm".into())
+            .spawn(move || run(jobs, budget))?;
+
+        Ok(DentryWarmer {
+            visited: Mutex::new(HashSet::new()),
+            queue,
+        })
+    }
+
+    /// Records a visit of the overlay directory `dir`, returning whether it is the first one.
+    pub(crate) fn first_visit(&self, dir: u64) -> bool {
+        let mut visited = self.visited.lock().unwrap();
+        if visited.len() >= MAX_VISITED {
+            visited.clear();
+        }
+        visited.insert(dir)
+    }
+
+    /// Forgets the directories visited, so that the next visits warm them again.
+    pub(crate) fn forget_visits(&self) {
+        self.visited.lock().unwrap().clear();
+    }
+
+    /// Warms the entries of a directory, given by its host directories in the layers it merges,
+    /// from the top one. Skipped if too many directories are waiting already.
+    pub(crate) fn warm(&self, layers: Vec<File>) {
+        if !layers.is_empty() {
+            let _ = self.queue.try_send(layers);
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+fn run(jobs: Receiver<Vec<File>>, budget: usize) {
+    for layers in jobs {
+        if let Err(e) = warm_dir(&layers, budget) {
+            debug!("virtio-fs: failed to warm the entries of a directory: {e}");
+        }
+    }
+}
+
+/// Stats up to `budget` entries of the directory merging `layers`, from the top one, in each layer
+/// until the one holding the entry, as the lookups do. Returns the number of entries warmed.
+fn warm_dir(layers: &[File], budget: usize) -> io::Result<usize> {
+    let mut seen = HashSet::new();
+    let mut names = Vec::new();
+    let mut merged = 0;
+    'layers: for layer in layers {
+        merged += 1;
+
+        let mut opaque = false;
+        let path = format!("/proc/self/fd/{}", layer.as_raw_fd());
+        for entry in std::fs::read_dir(path)? {
+            let name = entry?.file_name();
+            let name = name.as_bytes();
+            if name == OPAQUE_MARKER.as_bytes() {
+                opaque = true;
+            } else if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX.as_bytes()) {
+                seen.insert(hidden.to_vec());
+            } else if seen.insert(name.to_vec()) {
+                names.push(CString::new(name)?);
+                if names.len() >= budget {
+                    break 'layers;
+                }
+            }
+        }
+
+        // The entries of the layers below an opaque directory are hidden
+        if opaque {
+            break;
+        }
+    }
+
+    for name in &names {
+        for layer in &layers[..merged] {
+            let mut st = std::mem::MaybeUninit::<libc::stat64>::uninit();
+            // Safe because the kernel only writes to `st` and we check the return value.
+            let res = unsafe {
+                libc::fstatat64(
+                    layer.as_raw_fd(),
+                    name.as_ptr(),
+                    st.as_mut_ptr(),
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            };
+            if res == 0 {
+                break;
+            }
+        }
+    }
+
+    Ok(names.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn warm_dir_entries() {
+        let upper = tempfile::tempdir().unwrap();
+        let middle = tempfile::tempdir().unwrap();
+        let lower = tempfile::tempdir().unwrap();
+        for name in ["a", ".wh.b"] {
+            std::fs::write(upper.path().join(name), b"").unwrap();
+        }
+        for name in ["a", "b", "c", ".wh..wh..opq"] {
+            std::fs::write(middle.path().join(name), b"").unwrap();
+        }
+        std::fs::write(lower.path().join("d"), b"").unwrap();
+        let layers: Vec<File> = [&upper, &middle, &lower]
+            .iter()
+            .map(|dir| File::open(dir.path()).unwrap())
+            .collect();
+
+        // Only "a" and "c" are warmed, "b" is whited out and "d" is hidden by the opaque directory
+        assert_eq!(warm_dir(&layers, 100).unwrap(), 2);
+        assert_eq!(warm_dir(&layers, 1).unwrap(), 1);
+
+        let warmer = DentryWarmer::new(100).unwrap();
+        assert!(warmer.first_visit(2));
+        assert!(!warmer.first_visit(2));
+        warmer.warm(layers);
+    }
+}
@@ -1,4 +1,21 @@
+use std::ffi::CStr;
+use std::fs::File;
 use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+use super::super::bindings::{LINUX_FS_APPEND_FL, LINUX_FS_IMMUTABLE_FL};
+use super::super::filesystem::{BirthTime, ZeroCopyWriter};
+use super::super::retry::retry_syscall;
+
+/// The host file flags passed through to the guest.
+const PASSTHROUGH_FILE_FLAGS: u32 = LINUX_FS_IMMUTABLE_FL | LINUX_FS_APPEND_FL;
+
+/// Reads smaller than this are copied as is, as skipping their holes doesn't make up for the cost
+/// of looking for them.
+const SPARSE_READ_MIN_SIZE: usize = 128 << 10;
+
+/// A buffer of zeros standing for the holes of sparse files.
+static ZEROES: [u8; 64 << 10] = [0; 64 << 10];
 
 pub fn ebadf() -> io::Error {
     io::Error::from_raw_os_error(libc::EBADF)
@@ -7,3 +24,222 @@ pub fn ebadf() -> io::Error {
 pub fn einval() -> io::Error {
     io::Error::from_raw_os_error(libc::EINVAL)
 }
+
+/// Copies up to `size` bytes of `f` from `offset` to `w`, like `ZeroCopyWriter::write_from`, but
+/// fills the holes of sparse files with zeros instead of reading them from the disk. Returns the
+/// number of bytes copied, which is only short at the end of the file.
+pub fn write_from_sparse<W: io::Write + ZeroCopyWriter>(
+    w: &mut W,
+    f: &File,
+    size: usize,
+    offset: u64,
+) -> io::Result<usize> {
+    if size < SPARSE_READ_MIN_SIZE {
+        return w.write_from(f, size, offset);
+    }
+
+    let end = offset.saturating_add(size as u64);
+    let mut pos = offset;
+    while pos < end {
+        let (data, eof) = match seek(f, pos, libc::SEEK_DATA) {
+            Ok(data) => (data, false),
+            // The rest of the file is a hole
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => (f.metadata()?.len(), true),
+            // The file system can't tell where the holes are
+            Err(_) if pos == offset => return w.write_from(f, size, offset),
+            Err(e) => return Err(e),
+        };
+
+        let hole_end = data.min(end);
+        while pos < hole_end {
+            let len = (hole_end - pos).min(ZEROES.len() as u64) as usize;
+            w.write_all(&ZEROES[..len])?;
+            pos += len as u64;
+        }
+        if eof || data >= end {
+            break;
+        }
+
+        // A hole follows any data, at worst at the end of the file
+        let data_end = seek(f, pos, libc::SEEK_HOLE)?.min(end);
+        while pos < data_end {
+            let n = w.write_from(f, (data_end - pos) as usize, pos)?;
+            if n == 0 {
+                // The file was truncated
+                return Ok((pos - offset) as usize);
+            }
+            pos += n as u64;
+        }
+    }
+
+    Ok((pos - offset) as usize)
+}
+
+/// Returns the extents of `f` holding data between `offset` and `end`, as their offsets and
+/// lengths, leaving its holes out. The whole range is returned if the file system can't tell where
+/// the holes are.
+pub fn data_extents(f: &File, offset: u64, end: u64) -> io::Result<Vec<(u64, u64)>> {
+    let mut extents = Vec::new();
+    let mut pos = offset;
+    while pos < end {
+        let data = match seek(f, pos, libc::SEEK_DATA) {
+            Ok(data) => data,
+            // The rest of the file is a hole
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => break,
+            // The file system can't tell where the holes are
+            Err(_) if pos == offset => return Ok(vec![(offset, end - offset)]),
+            Err(e) => return Err(e),
+        };
+        if data >= end {
+            break;
+        }
+
+        // A hole follows any data, at worst at the end of the file
+        let data_end = seek(f, data, libc::SEEK_HOLE)?.min(end);
+        if data_end <= data {
+            // The file was truncated
+            break;
+        }
+        extents.push((data, data_end - data));
+        pos = data_end;
+    }
+
+    Ok(extents)
+}
+
+/// Moves the offset of `f` like `lseek(2)` does for the guest. `SEEK_DATA` and `SEEK_HOLE` find the
+/// data and holes of the host file, unless its file system can't tell where they are. The file is
+/// then taken to have no holes but the one at its end, as `lseek(2)` does for such file systems.
+pub fn guest_seek(f: &File, offset: u64, whence: u32) -> io::Result<u64> {
+    let whence = whence as libc::c_int;
+    match seek(f, offset, whence) {
+        Err(e)
+            if (whence == libc::SEEK_DATA || whence == libc::SEEK_HOLE)
+                && offset <= i64::MAX as u64
+                && matches!(e.raw_os_error(), Some(libc::EINVAL | libc::EOPNOTSUPP)) =>
+        {
+            let size = f.metadata()?.len();
+            if offset >= size {
+                return Err(io::Error::from_raw_os_error(libc::ENXIO));
+            }
+            let pos = if whence == libc::SEEK_DATA {
+                offset
+            } else {
+                size
+            };
+            seek(f, pos, libc::SEEK_SET)
+        }
+        res => res,
+    }
+}
+
+fn seek(f: &File, offset: u64, whence: libc::c_int) -> io::Result<u64> {
+    // SAFETY: this doesn't modify any memory and we check the return value.
+    let res = unsafe { libc::lseek64(f.as_raw_fd(), offset as _, whence) };
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res as u64)
+    }
+}
+
+/// Flushes the entries of the directory `path`, relative to `dirfd`, to the disk, so that the files
+/// created, removed or renamed in it survive a crash.
+pub fn sync_dir_at(dirfd: RawFd, path: &CStr) -> io::Result<()> {
+    // Safe because this doesn't modify any memory and we check the return value.
+    let fd = retry_syscall(|| unsafe {
+        libc::openat(
+            dirfd,
+            path.as_ptr(),
+            libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        )
+    });
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safe because we just opened this fd.
+    unsafe { File::from_raw_fd(fd) }.sync_all()
+}
+
+/// Opens an anonymous regular file in the directory `dirfd`, with the open `flags` of the guest and
+/// `mode`, like `O_TMPFILE`. The file stays linkable, since the guest gives it its name with `link`.
+pub fn open_tmpfile(dirfd: RawFd, flags: u32, mode: u32) -> io::Result<File> {
+    let flags = (flags as i32 & !(libc::O_CREAT | libc::O_EXCL | libc::O_TMPFILE))
+        | libc::O_TMPFILE
+        | libc::O_CLOEXEC;
+    // Safe because this doesn't modify any memory and we check the return value.
+    let fd = retry_syscall(|| unsafe { libc::openat(dirfd, c".".as_ptr(), flags, mode) });
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safe because we just opened this fd.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Returns the creation time of `file`, if its file system records it.
+pub fn get_birth_time(file: &File) -> io::Result<Option<BirthTime>> {
+    let mut stx = std::mem::MaybeUninit::<libc::statx>::zeroed();
+    // Safe because this only writes to `stx` and we check the return value.
+    let res = retry_syscall(|| unsafe {
+        libc::statx(
+            file.as_raw_fd(),
+            c"".as_ptr(),
+            libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW,
+            libc::STATX_BTIME,
+            stx.as_mut_ptr(),
+        )
+    });
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safe because statx succeeded.
+    let stx = unsafe { stx.assume_init() };
+    if stx.stx_mask & libc::STATX_BTIME == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(BirthTime {
+        sec: stx.stx_btime.tv_sec,
+        nsec: stx.stx_btime.tv_nsec,
+    }))
+}
+
+/// Returns the immutable and append-only flags of `file`, in the format of `FS_IOC_GETFLAGS`.
+pub fn get_file_flags(file: &File) -> io::Result<u32> {
+    let mut flags: libc::c_int = 0;
+    // Safe because this only writes to `flags` and we check the return value.
+    let res = unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(flags as u32 & PASSTHROUGH_FILE_FLAGS)
+}
+
+/// Sets the immutable and append-only flags of `file` to those in `flags`, in the format of
+/// `FS_IOC_SETFLAGS`. The other flags of the file are kept as they are on the host.
+pub fn set_file_flags(file: &File, flags: u32) -> io::Result<()> {
+    let mut current: libc::c_int = 0;
+    // Safe because this only writes to `current` and we check the return value.
+    let res = unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut current) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let new = (current as u32 & !PASSTHROUGH_FILE_FLAGS) | (flags & PASSTHROUGH_FILE_FLAGS);
+    if new == current as u32 {
+        return Ok(());
+    }
+
+    let new = new as libc::c_int;
+    // Safe because this doesn't modify any memory and we check the return value.
+    let res = unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &new) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
@@ -1,3 +1,9 @@
+mod atime;
+mod batch_create;
+mod dentry_warming;
 pub mod fs_utils;
+mod overlay_xattrs;
+mod posix_locks;
 pub mod passthrough;
+mod stat_override;
 pub mod overlayfs;
@@ -0,0 +1,234 @@
+//! The extended attributes Linux's OverlayFS keeps in its upper directory.
+//!
+//! Tools inspecting the top layer as an OverlayFS upper directory tell copied up entries apart from
+//! the ones created in it by their `overlay.origin`, the file handle of the lower entry they were
+//! copied from, and find the directories holding such entries by their `overlay.impure` marker.
+//! The handle is encoded as OverlayFS stores it, with the UUID of the lower file system, or a null
+//! UUID when the host kernel can't tell it.
+
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The attribute recording the lower entry of a copy.
+pub(crate) const ORIGIN: &str = "origin";
+
+/// The attribute marking the directories holding copies.
+pub(crate) const IMPURE: &str = "impure";
+
+/// The attribute set and removed to check that the namespace can be written.
+const PROBE: &str = "krun.probe";
+
+/// The largest file handle, as `MAX_HANDLE_SZ` in the kernel.
+const MAX_HANDLE_SIZE: usize = 128;
+
+/// The header of an encoded handle: version, magic, length, flags, type and UUID.
+const HANDLE_HEADER_SIZE: usize = 21;
+
+const OVL_FH_VERSION: u8 = 0;
+const OVL_FH_MAGIC: u8 = 0xfb;
+const OVL_FH_FLAG_BIG_ENDIAN: u8 = 1 << 0;
+
+/// `_IOR(0x15, 0, struct fsuuid2)`
+const FS_IOC_GETFSUUID: libc::c_ulong = 0x8011_1500;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+#[repr(C)]
+struct FileHandle {
+    handle_bytes: u32,
+    handle_type: libc::c_int,
+    f_handle: [u8; MAX_HANDLE_SIZE],
+}
+
+#[repr(C)]
+struct FsUuid {
+    len: u8,
+    uuid: [u8; 16],
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Returns the `origin` of a copy of `file`, an `O_PATH` file in the layer whose root directory
+/// is `layer_root`.
+pub(crate) fn encode_origin(file: &File, layer_root: &File) -> io::Result<Vec<u8>> {
+    let mut handle = FileHandle {
+        handle_bytes: MAX_HANDLE_SIZE as u32,
+        handle_type: 0,
+        f_handle: [0; MAX_HANDLE_SIZE],
+    };
+    let mut mount_id: libc::c_int = 0;
+
+    // Safe because the kernel writes at most `handle_bytes` bytes of handle
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_name_to_handle_at,
+            file.as_raw_fd(),
+            c"".as_ptr(),
+            &mut handle as *mut FileHandle,
+            &mut mount_id as *mut libc::c_int,
+            libc::AT_EMPTY_PATH,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let fid = &handle.f_handle[..handle.handle_bytes as usize];
+    let flags = if cfg!(target_endian = "big") {
+        OVL_FH_FLAG_BIG_ENDIAN
+    } else {
+        0
+    };
+    let mut origin = vec![
+        OVL_FH_VERSION,
+        OVL_FH_MAGIC,
+        (HANDLE_HEADER_SIZE + fid.len()) as u8,
+        flags,
+        handle.handle_type as u8,
+    ];
+    origin.extend_from_slice(&fs_uuid(layer_root));
+    origin.extend_from_slice(fid);
+    Ok(origin)
+}
+
+/// Returns the UUID of the file system of `layer_root`, or a null one if it can't be told.
+fn fs_uuid(layer_root: &File) -> [u8; 16] {
+    let path = format!("/proc/self/fd/{}", layer_root.as_raw_fd());
+    let Ok(dir) = File::open(path) else {
+        return [0; 16];
+    };
+
+    let mut uuid = FsUuid {
+        len: 16,
+        uuid: [0; 16],
+    };
+    // Safe because the kernel writes at most `len` bytes of UUID
+    let res = unsafe { libc::ioctl(dir.as_raw_fd(), FS_IOC_GETFSUUID as _, &mut uuid) };
+    if res < 0 || uuid.len != 16 {
+        return [0; 16];
+    }
+    uuid.uuid
+}
+
+/// Sets the attribute `name` of the entry at `path`, not following it if it is a symlink.
+pub(crate) fn set(path: &CStr, name: &CStr, value: &[u8], follow: bool) -> io::Result<()> {
+    // Safe because this doesn't modify any memory and we check the return value.
+    let res = unsafe {
+        let set = if follow {
+            libc::setxattr
+        } else {
+            libc::lsetxattr
+        };
+        set(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Checks that the attributes starting with `prefix` can be set on the directory at `dir`.
+pub(crate) fn check_settable(dir: &Path, prefix: &str) -> io::Result<()> {
+    let path = CString::new(dir.as_os_str().as_bytes())?;
+    let name = CString::new(format!("{prefix}{PROBE}")).unwrap();
+    set(&path, &name, b"", true)?;
+
+    // Safe because this doesn't modify any memory and we check the return value.
+    if unsafe { libc::removexattr(path.as_ptr(), name.as_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Removes the attribute names starting with `prefix` from a `listxattr` reply, in place.
+pub(crate) fn filter_names(names: &mut Vec<u8>, prefix: &[u8]) {
+    let mut kept = 0;
+    let mut start = 0;
+    while start < names.len() {
+        let end = names[start..]
+            .iter()
+            .position(|b| *b == 0)
+            .map_or(names.len(), |pos| start + pos + 1);
+        if !names[start..end].starts_with(prefix) {
+            names.copy_within(start..end, kept);
+            kept += end - start;
+        }
+        start = end;
+    }
+    names.truncate(kept);
+}
+
+/// Returns the path of the entry `name` of the directory `dir`, through `/proc/self/fd`.
+pub(crate) fn entry_path(dir: &File, name: Option<&CStr>) -> CString {
+    let mut path = format!("/proc/self/fd/{}", dir.as_raw_fd()).into_bytes();
+    if let Some(name) = name {
+        path.push(b'/');
+        path.extend_from_slice(name.to_bytes());
+    }
+    // The names of the entries have no nul bytes
+    CString::new(path).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn filter() {
+        let mut names =
+            b"user.a\0trusted.overlay.origin\0user.b\0trusted.overlay.impure\0".to_vec();
+        filter_names(&mut names, b"trusted.overlay.");
+        assert_eq!(names, b"user.a\0user.b\0");
+        let mut names = Vec::new();
+        filter_names(&mut names, b"trusted.overlay.");
+        assert!(names.is_empty());
+    }
+
+    #[test]
+    fn settable() {
+        let dir = tempfile::tempdir().unwrap();
+        check_settable(dir.path(), "user.overlay.").unwrap();
+
+        // The probe is removed
+        let path = CString::new(dir.path().as_os_str().as_bytes()).unwrap();
+        let len = unsafe { libc::listxattr(path.as_ptr(), std::ptr::null_mut(), 0) };
+        assert_eq!(len, 0);
+        assert!(check_settable(&dir.path().join("missing"), "user.overlay.").is_err());
+    }
+
+    #[test]
+    fn origin() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = File::open(dir.path()).unwrap();
+        std::fs::write(dir.path().join("file"), b"data").unwrap();
+        let file = File::open(dir.path().join("file")).unwrap();
+
+        let origin = encode_origin(&file, &root).unwrap();
+        assert_eq!(&origin[..2], &[OVL_FH_VERSION, OVL_FH_MAGIC]);
+        assert_eq!(origin[2] as usize, origin.len());
+        assert!(origin.len() > HANDLE_HEADER_SIZE);
+
+        // Each file has its own handle
+        std::fs::write(dir.path().join("other"), b"data").unwrap();
+        let other = File::open(dir.path().join("other")).unwrap();
+        assert_ne!(encode_origin(&other, &root).unwrap(), origin);
+    }
+}
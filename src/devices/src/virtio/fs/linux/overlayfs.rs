@@ -1,38 +1,76 @@
 use std::{
-    collections::{btree_map, BTreeMap, HashSet},
-    ffi::{CStr, CString},
+    borrow::Cow,
+    collections::{btree_map, BTreeMap, HashSet, VecDeque},
+    ffi::{CStr, CString, OsStr},
     fs::File,
     io,
     mem::{self, MaybeUninit},
     os::{
         fd::{AsRawFd, FromRawFd, RawFd},
-        unix::{ffi::OsStrExt, fs::MetadataExt},
+        unix::{
+            ffi::{OsStrExt, OsStringExt},
+            fs::{DirEntryExt, FileExt, FileTypeExt, PermissionsExt},
+        },
     },
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering},
-        Arc, LazyLock, RwLock,
+        atomic::{AtomicBool, AtomicI32, AtomicU64, AtomicU8, Ordering},
+        Arc, LazyLock, Mutex, RwLock,
     },
     time::Duration,
 };
 
 use caps::{has_cap, CapSet, Capability};
-use intaglio::{cstr::SymbolTable, Symbol};
 use nix::{request_code_none, request_code_read};
+use utils::fd_budget::{FdClient, FdGrant};
 
 use crate::virtio::{
     bindings,
     fs::{
+        case_names,
+        content_store::{self, ContentStore},
+        copy_up::{self, PathLockGuard, PathLocks},
+        dax,
         filesystem::{
-            self, Context, DirEntry, Entry, ExportTable, Extensions, FileSystem, FsOptions,
-            GetxattrReply, ListxattrReply, OpenOptions, SetattrValid, ZeroCopyReader,
-            ZeroCopyWriter,
+            self, BirthTime, Context, DirEntry, Entry, ExportTable, Extensions, FileLock,
+            FileSystem, FsOptions, GetxattrReply, ListxattrReply, OpenOptions, SetattrValid,
+            ZeroCopyReader, ZeroCopyWriter,
+        },
+        fs_utils::{
+            data_extents, get_birth_time, get_file_flags, guest_seek, open_tmpfile, set_file_flags,
+            sync_dir_at, write_from_sparse,
         },
         fuse,
+        handle_quota::{FsHandleQuota, HandleGrant},
+        hooks::{FsHookPoint, FsHooks},
+        host_metadata::XattrRetention,
+        inode_map::{self, InodeMap},
+        inode_path::{InodePath, Name, NameTable},
+        intent_log::{Intent, IntentGuard, IntentLog, INTENT_LOG_FILE},
+        layer_diff::{self, LayerSnapshot},
+        layer_filter::LayerFilter,
+        layer_manifest, layer_paths,
+        layer_stats::{self, FsLayerStats, LayerCounters},
+        lower_layers::LowerLayerSet,
         multikey::MultikeyBTreeMap,
+        page_cache::{self, SequentialReads},
+        prealloc::{self, SequentialWrites},
+        retry::retry_syscall,
+        snapshot::{self, HandleState, InodeState},
+        whiteout_probe::{WhiteoutCache, WhiteoutProbes},
+        FsAtime, FsOwnership, FsPageCache,
     },
 };
 
+use super::atime;
+use super::batch_create;
+use super::dentry_warming::DentryWarmer;
+use super::overlay_xattrs;
+use super::posix_locks::PosixLocks;
+use super::stat_override::{self, StatOverride, OVERRIDE_XATTR};
+#[cfg(feature = "oci")]
+use crate::virtio::fs::oci::LazyLayer;
+
 //--------------------------------------------------------------------------------------------------
 // Modules
 //--------------------------------------------------------------------------------------------------
@@ -46,10 +84,31 @@ mod tests;
 //--------------------------------------------------------------------------------------------------
 
 /// The prefix for whiteout files
-const WHITEOUT_PREFIX: &str = ".wh.";
+pub(super) const WHITEOUT_PREFIX: &str = ".wh.";
 
 /// The marker for opaque directories
-const OPAQUE_MARKER: &str = ".wh..wh..opq";
+pub(super) const OPAQUE_MARKER: &str = ".wh..wh..opq";
+
+/// The prefix of the staging file a regular file is copied to before being renamed into place.
+///
+/// It starts with the whiteout prefix so that the guest can neither see nor create it.
+const COPY_UP_STAGING_PREFIX: &str = ".wh..wh..copyup.";
+
+/// The name in the paths of the anonymous files of `O_TMPFILE`, until the guest links them into a
+/// directory. No entry of the top layer has it, as it starts with the whiteout prefix.
+const TMPFILE_NAME: &CStr = c".wh..wh..tmpfile";
+
+/// The extended attribute recording which source a copy-up staging file is a copy of
+const COPY_UP_XATTR_KEY: &[u8] = b"user.overlayfs.copyup\0";
+
+/// The directory has not been scanned for whiteouts yet
+const WHITEOUTS_UNKNOWN: u8 = 0;
+
+/// The directory contains neither whiteouts nor an opaque marker
+const WHITEOUTS_ABSENT: u8 = 1;
+
+/// The directory may contain whiteouts or an opaque marker
+const WHITEOUTS_PRESENT: u8 = 2;
 
 /// Maximum allowed number of layers for the overlay filesystem.
 const MAX_LAYERS: usize = 128;
@@ -68,10 +127,20 @@ const EMPTY_CSTR: LazyLock<&CStr> =
 const PROC_SELF_FD_CSTR: LazyLock<&CStr> =
     LazyLock::new(|| unsafe { CStr::from_bytes_with_nul_unchecked(b"/proc/self/fd\0") });
 
+/// The RAM-backed directory ephemeral top layers are created in
+const DEV_SHM_CSTR: &CStr = c"/dev/shm";
+
+/// The `mkdtemp` template of the directory holding an ephemeral top layer
+const EPHEMERAL_DIR_TEMPLATE: &[u8] = b"/dev/shm/krun-overlay-XXXXXX\0";
+
 /// FICLONE ioctl for copy-on-write file cloning
 /// Defined in Linux's fs.h as _IOW(0x94, 9, int)
 const FICLONE: u64 = (0x94 << 8) | 9 | (std::mem::size_of::<i32>() as u64) << 16 | 1 << 30;
 
+/// The size of the reads and writes copying up a file that can't be cloned, large enough for a
+/// layer of several gigabytes not to take millions of them
+const COPY_CHUNK_SIZE: usize = 1 << 20;
+
 //--------------------------------------------------------------------------------------------------
 // Types
 //--------------------------------------------------------------------------------------------------
@@ -104,6 +173,9 @@ pub(crate) struct InodeData {
     /// The file handle for the inode
     pub(crate) file: File,
 
+    /// The inode number from the host filesystem
+    pub(crate) ino: libc::ino64_t,
+
     /// The device ID from the host filesystem
     pub(crate) dev: libc::dev_t,
 
@@ -113,11 +185,24 @@ pub(crate) struct InodeData {
     /// Reference count for this inode from the perspective of [`FileSystem::lookup`]
     pub(crate) refcount: AtomicU64,
 
-    /// Path to inode
-    pub(crate) path: Vec<Symbol>,
+    /// Path to inode, shared with the inodes below it
+    pub(crate) path: Arc<InodePath>,
 
     /// The layer index this inode belongs to
     pub(crate) layer_idx: usize,
+
+    /// Whether this directory contains whiteouts, one of the `WHITEOUTS_*` constants.
+    ///
+    /// Computed lazily by [`OverlayFs::may_have_whiteouts`] and only meaningful for directories.
+    pub(crate) whiteouts: AtomicU8,
+
+    /// The generation of the inode, which tells it apart from the files that had the same host
+    /// inode number before it: the birth time of the file if the host records it, or else see
+    /// [`OverlayFs::retire_generation`].
+    pub(crate) generation: u64,
+
+    /// The creation time of the host file, if the file system records it
+    pub(crate) btime: Option<BirthTime>,
 }
 
 /// Data associated with an open file handle
@@ -131,6 +216,81 @@ pub(crate) struct HandleData {
 
     /// Whether the file handle is exported
     exported: AtomicBool,
+
+    /// Whether data has been written through this handle since it was last synced
+    dirty: AtomicBool,
+
+    /// The first error hit by a delayed (writeback) write on this handle, not yet reported
+    write_error: Mutex<Option<io::Error>>,
+
+    /// The writes through this handle, to preallocate ahead of the sequential ones
+    sequential: Mutex<SequentialWrites>,
+
+    /// The reads through this handle, to give the host page cache hints about
+    reads: Mutex<SequentialReads>,
+
+    /// Whether the access time of the file is still to be updated by the device, on the first read
+    relatime: AtomicBool,
+
+    /// The descriptor of `file` in the budget of the process
+    _fd_grant: Option<FdGrant>,
+
+    /// The count of this handle in the quota of the share
+    _handle_grant: Option<HandleGrant>,
+
+    /// The listing of the directory in progress, if this handle is a directory's
+    dir_stream: Mutex<Option<DirStream>>,
+}
+
+/// A merged listing of a directory across the layers, read as the guest lists the directory.
+#[derive(Debug)]
+pub(crate) struct DirStream {
+    /// The path of the directory
+    path: Vec<Name>,
+
+    /// The offset of the next entry, the number of entries listed so far
+    offset: u64,
+
+    /// The directory in the layers left to read, the lowest layer first
+    layers: Vec<(usize, Arc<InodeData>)>,
+
+    /// The directory in the layer being read, and the entries left in it
+    current: Option<(usize, Arc<InodeData>, std::fs::ReadDir)>,
+
+    /// The names of the entries of the layers read and of their whiteouts, which hide the entries
+    /// of the layers below. The last layer adds none.
+    seen: HashSet<Vec<u8>>,
+
+    /// The names whited out by the layer being read, added to `seen` once it is read, as they only
+    /// hide the entries of the layers below and not an entry of the same name in their own layer
+    whiteouts: HashSet<Vec<u8>>,
+
+    /// The entry read but not listed by the last call, the buffer of the guest being full
+    pending: Option<StreamEntry>,
+}
+
+/// An entry of a [`DirStream`].
+#[derive(Debug)]
+struct StreamEntry {
+    ino: u64,
+    type_: u32,
+    name: Vec<u8>,
+}
+
+/// The outcome of a [`OverlayFs::remove_tree`] request
+#[derive(Debug)]
+struct RemoveTreeProgress {
+    /// The number of top layer entries removed
+    removed: u64,
+
+    /// The number of top layer entries that couldn't be removed, and were left behind
+    errors: u64,
+
+    /// Whether all the entries were visited, rather than stopping at the limit of the request
+    done: bool,
+
+    /// The size of the file data freed in the top layer
+    freed: u64,
 }
 
 pub(crate) struct ScopedGid;
@@ -159,6 +319,104 @@ pub enum CachePolicy {
     Always,
 }
 
+/// How the file system treats symlinks whose target is absolute or climbs above the root of the
+/// overlay. The host never follows symlinks found in the layers; this policy only controls what the
+/// guest gets to see.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Link targets are returned to the guest untouched, so they are resolved by the guest kernel
+    /// relative to its own root. This is the default policy.
+    #[default]
+    GuestRelative,
+
+    /// Links with an absolute or escaping target are refused: reading them and creating them both
+    /// fail with `EPERM`.
+    Deny,
+
+    /// Absolute and escaping targets are rewritten into relative targets that resolve within the
+    /// overlay, as if the root of the overlay were `/`.
+    FollowWithinLayer,
+}
+
+/// How the link count of directories is reported to the guest. Tools like `find` assume that a
+/// directory has two links more than it has subdirectories, which is not true of the directory of
+/// any single layer once layers are merged.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirNlinkPolicy {
+    /// Report a link count of 1, which tells those tools that the count is meaningless (as Linux's
+    /// OverlayFS does for merged directories). This is the default.
+    #[default]
+    One,
+
+    /// Report two links plus the number of subdirectories merged across all layers. This requires
+    /// reading the directory in every layer each time its attributes are returned.
+    Merged,
+
+    /// Report the link count of the directory in the layer it is found in.
+    Layer,
+}
+
+/// Where the writable top layer of the overlay lives.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub enum UpperLayer {
+    /// The last entry of `Config::layers` is the writable top layer. This is the default.
+    #[default]
+    Disk,
+
+    /// All the entries of `Config::layers` are read-only lower layers, and the writable top layer
+    /// is a private directory on the RAM-backed `/dev/shm`, removed when the file system is
+    /// dropped. Nothing written by the guest ever reaches the host disk. Writes that would make the
+    /// regular files of the top layer exceed `size_limit` bytes in total fail with `ENOSPC`.
+    Ram { size_limit: u64 },
+}
+
+/// Checks of the lower layers against manifests of their contents, to catch the layers edited on
+/// the host. The manifest of a layer is recorded when the file system is first created with it,
+/// and the later ones compare the layer against it, which walks the whole layer and reads the
+/// first and last blocks of each regular file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayerIntegrity {
+    /// The host directory holding the manifests, created if needed.
+    pub manifest_dir: PathBuf,
+
+    /// What happens when a layer drifted from its manifest.
+    pub on_drift: DriftPolicy,
+}
+
+/// What happens when a lower layer drifted from its manifest.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriftPolicy {
+    /// Log the drifted entries and use the layer anyway. This is the default.
+    #[default]
+    Warn,
+
+    /// Fail to create the file system with `InvalidData`, as when the layers can't be checked.
+    Refuse,
+}
+
+/// How the metadata of the top layer is sanitized for Linux consumers when it leaves the overlay,
+/// see `host_metadata`: the extended attributes not kept are dropped, and the AppleDouble files
+/// are turned back into the extended attributes of the files they describe.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetadataSanitizer {
+    /// Prefixes of the names of the extended attributes kept. The attributes the overlay keeps
+    /// for itself are never kept.
+    ///
+    /// The default keeps `user.*`, `trusted.*` and `security.capability`.
+    pub keep_xattrs: Vec<String>,
+}
+
+/// The namespace of the extended attributes Linux's OverlayFS keeps in the top layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverlayXattrs {
+    /// `trusted.overlay.*`, as mounted by root. Setting them requires `CAP_SYS_ADMIN`.
+    Trusted,
+
+    /// `user.overlay.*`, as mounted with the `userxattr` option. These can't be set on symlinks,
+    /// whose copies then have no origin.
+    User,
+}
+
 /// Configuration options that control the behavior of the file system.
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -222,6 +480,259 @@ pub struct Config {
 
     /// Layers to be used for the overlay filesystem
     pub layers: Vec<PathBuf>,
+
+    /// How symlinks with absolute or escaping targets are handled. See the documentation of
+    /// `SymlinkPolicy` for more details.
+    ///
+    /// The default value for this option is `SymlinkPolicy::GuestRelative`.
+    pub symlink_policy: SymlinkPolicy,
+
+    /// Prefixes of absolute symlink targets to rewrite, as `(from, to)` pairs, for links pointing
+    /// into paths that the guest mounts somewhere else. The targets of the links in the lower
+    /// layers are rewritten when they are read and when the links are copied up to the top layer,
+    /// while the links created by the guest are left alone. Prefixes only match whole path
+    /// components and the longest matching prefix wins.
+    ///
+    /// The default value for this option is empty.
+    pub symlink_target_map: Vec<(PathBuf, PathBuf)>,
+
+    /// The most symlinks followed through the components of a symlink target when checking it
+    /// against the symlink policy. Targets needing more fail with `ELOOP`, as loops do. Unused
+    /// with `SymlinkPolicy::GuestRelative`.
+    ///
+    /// The default value for this option is 40, the limit of Linux.
+    pub max_symlink_depth: usize,
+
+    /// Where the writable top layer lives. See the documentation of `UpperLayer` for more details.
+    ///
+    /// The default value for this option is `UpperLayer::Disk`.
+    pub upper_layer: UpperLayer,
+
+    /// Whether the overlay rejects all the requests changing it with `EROFS`, rather than copying
+    /// entries up and changing the top layer. Nothing is written to the layers either when the
+    /// overlay is created: the intent log isn't replayed, and the access times aren't updated.
+    ///
+    /// The default value for this option is `false`.
+    pub read_only: bool,
+
+    /// How the link count of directories is reported. See the documentation of `DirNlinkPolicy`
+    /// for more details.
+    ///
+    /// The default value for this option is `DirNlinkPolicy::One`.
+    pub dir_nlink: DirNlinkPolicy,
+
+    /// Whether whiteouts are cross-checked against the entries they hide. When enabled, a whiteout
+    /// of the top layer is ignored if the entry it hides was recreated in a lower layer after the
+    /// whiteout was created, e.g. because the lower layers were updated on the host by re-pulling
+    /// an image. The whiteouts of the lower layers are always honored.
+    ///
+    /// The default value for this option is `false`.
+    pub verify_whiteouts: bool,
+
+    /// Whether a lookup of a name missing from a directory finds the entry whose name only differs
+    /// by case, for layers extracted on case-insensitive volumes. The entry is then known by its
+    /// name in the layers, and a name matching several entries of the directory fails with `EIO`.
+    /// See the documentation of the `case_names` module for more details.
+    ///
+    /// The default value for this option is `false`.
+    pub case_insensitive: bool,
+
+    /// Whether changes to directory entries are made durable before they are acknowledged. When
+    /// enabled, the directories whose entries are created, linked, removed or renamed are flushed
+    /// to the disk before the request completes, so that the changes survive a host crash. This
+    /// has a significant cost for workloads that create or remove many files.
+    ///
+    /// The default value for this option is `false`.
+    pub durable: bool,
+
+    /// Whether the operations changing the top layer in two steps record their intent in a log
+    /// of the top layer first, so that the ones a host crash interrupted are completed when the
+    /// overlay is created again: removing or renaming an entry a lower layer has, and truncating a
+    /// file being copied up. Recording an intent flushes the log to the disk. The log is not kept
+    /// for a RAM-backed top layer, which doesn't survive a crash anyway.
+    ///
+    /// The default value for this option is `false`.
+    pub intent_log: bool,
+
+    /// Whether the inode numbers given to the host files are kept in a map of the top layer, so
+    /// that each file gets the same number when the overlay is created again, e.g. after the VM
+    /// restarts, and the file handles the guest exported still find it. See the documentation of
+    /// the `inode_map` module for more details. The map is not kept for a RAM-backed top layer,
+    /// nor for a read-only overlay.
+    ///
+    /// The default value for this option is `false`.
+    pub inode_map: bool,
+
+    /// Whether the guest may set the immutable and append-only flags of the host files, e.g. with
+    /// `chattr`. The flags are always reported to the guest, so that it can tell why writing to such
+    /// files fails, but changing them is left to the host unless this is set.
+    ///
+    /// The default value for this option is `false`.
+    pub allow_file_flags: bool,
+
+    /// The maximum number of threads copying up a directory tree, e.g. when a directory coming
+    /// from a lower layer is renamed. Independent subdirectories are copied in parallel, while the
+    /// copy-up of each entry is serialized with any other request copying up the same path.
+    ///
+    /// The default value for this option is 4.
+    pub copy_up_threads: usize,
+
+    /// Whether all the entries are reported with the same device ID, the one of the top layer,
+    /// rather than the one of the host file system they come from. Tools like `mv` fall back to
+    /// copying and deleting when the device IDs of the source and the destination differ, which
+    /// they do for entries from layers on different host file systems. The inode numbers are still
+    /// the host ones, so entries from different layers may then share an inode number.
+    ///
+    /// The default value for this option is `false`.
+    pub single_dev: bool,
+
+    /// Whether the regular files of the top layer still holding the data of the lower layer file
+    /// they shadow report no blocks, like a file copied up for a `chmod` the guest hasn't written
+    /// to since. Its data is then only counted once, in the lower layer, by the tools adding up the
+    /// space of the layers, and `du` run in the guest reports the space the guest added to the
+    /// lower layers rather than the space of the files it sees. A file is taken to hold the data of
+    /// the lower file at the same path if they have the same size and modification time, which a
+    /// copy-up keeps, at the cost of looking the path up in the lower layers to report the
+    /// attributes of each top layer file.
+    ///
+    /// The default value for this option is `false`, which reports the blocks of the file serving
+    /// the entry.
+    pub differential_blocks: bool,
+
+    /// A host directory holding a content-addressed store of the data of copied up files, shared
+    /// by the overlays whose top layers are on the same host file system. A copy-up of a file whose
+    /// content is already in the store clones it from there, and the content of the other copied
+    /// up files is added to it. Cloning needs a file system with reflinks, e.g. Btrfs or XFS on
+    /// Linux and APFS on macOS, and the store is left alone otherwise. Each copy-up then reads the
+    /// source file one more time to hash it.
+    ///
+    /// The default value for this option is `None`.
+    pub content_store: Option<PathBuf>,
+
+    /// Whether lookups consult a Bloom filter of the paths of each lower layer before probing it,
+    /// skipping the layers that certainly don't have the path, a whiteout of it or an opaque
+    /// directory on the way. This saves a few system calls per layer for most lookups on deep
+    /// stacks of layers. The filter of a layer is built by walking it on the first lookup reaching
+    /// it, and dropped by `refresh_layers`, which must then be called whenever the lower layers
+    /// are changed on the host. The top layer is always probed.
+    ///
+    /// The default value for this option is `false`.
+    pub lookup_filters: bool,
+
+    /// The caches of the lower layers to share with the other overlays stacked on them, see
+    /// `LowerLayerSet`. The path filters of the lower layers are taken from the set rather than
+    /// built by each overlay, and the attributes of the files of the lower layers are cached in
+    /// it, the lower layers being read-only. Creating the file system fails if the lower layers
+    /// aren't the ones of the set.
+    ///
+    /// The default value for this option is `None`, which caches the path filters in the overlay
+    /// and no attributes.
+    pub lower_layers: Option<LowerLayerSet>,
+
+    /// How long the whiteouts and opaque markers found by the lookups are remembered. The probes
+    /// of a request are always memoized, so that it probes each directory once, and with this set
+    /// the results are also kept across requests, so that the lookups of entries of the same
+    /// directory don't probe its ancestors again at every layer. The whiteouts and opaque markers
+    /// added or removed on the host are only seen once the results expire, or after
+    /// `refresh_layers`.
+    ///
+    /// The default value for this option is `None`, which only memoizes the probes of a request.
+    pub whiteout_cache_ttl: Option<Duration>,
+
+    /// Checks of the lower layers against manifests of their contents. See the documentation of
+    /// `LayerIntegrity` for more details.
+    ///
+    /// The default value for this option is `None`.
+    pub layer_integrity: Option<LayerIntegrity>,
+
+    /// Whether copy-ups also record the extended attributes Linux's OverlayFS keeps in its upper
+    /// directory, so that tools inspecting the top layer find it as the kernel would have left it:
+    /// each copy gets an `overlay.origin` holding the file handle of its lower source, and the
+    /// directories holding copies an `overlay.impure` marker. No `overlay.metacopy` is ever
+    /// recorded, since copy-ups always copy the data along with the metadata. The attributes of
+    /// the namespace are hidden from the guest, which can neither read nor change them, and
+    /// creating the file system fails if they can't be set in the top layer.
+    ///
+    /// The default value for this option is `None`.
+    pub overlay_xattrs: Option<OverlayXattrs>,
+
+    /// Whether the guest may empty a directory tree in a single request, with the
+    /// `VIRTIO_IOC_REMOVE_TREE` ioctl on an open directory, rather than with one request per entry.
+    /// The entries of the lower layers are hidden at once by an opaque marker and those of the top
+    /// layer are removed on the host, without following symlinks or crossing into other host file
+    /// systems. Only requests running as root may use it, and never on the root directory.
+    ///
+    /// The default value for this option is `false`.
+    pub remove_tree: bool,
+
+    /// Whether the guest may create many small files of a directory in a single request, with
+    /// their content and attributes, with the `VIRTIO_IOC_BATCH_CREATE` ioctl on the open
+    /// directory, so that extracting an archive doesn't take several requests per file. The files
+    /// of a batch are all created or none is, and the ioctl fails if any of them exists already.
+    ///
+    /// The default value for this option is `false`.
+    pub batch_create: bool,
+
+    /// The number of entries of a directory stat'ed in the background, in each layer until the one
+    /// holding them, the first time the guest looks the directory up or opens it, so that the host
+    /// caches them before the guest looks them up. Finding the directory in each layer is done on
+    /// the request itself.
+    ///
+    /// The default value for this option is `None`, which doesn't warm the directories.
+    pub dentry_warming: Option<usize>,
+
+    /// The registration with the file descriptor budget of the process the descriptors of the
+    /// open handles are accounted to. Opening a file fails with `EMFILE` when the budget is
+    /// exhausted.
+    ///
+    /// The default value for this option is `None`, which doesn't account them.
+    pub fd_client: Option<FdClient>,
+
+    /// The count of the handles open on the share, opening a file failing with `EMFILE` when it
+    /// reaches its limit.
+    ///
+    /// The default value for this option is `None`, which doesn't limit them.
+    pub handle_quota: Option<FsHandleQuota>,
+
+    /// The hooks of the embedder, see `FsHooks`.
+    ///
+    /// The default value for this option is `None`, which calls none.
+    pub hooks: Option<FsHooks>,
+
+    /// How the reads of the guest update the access times of the host files.
+    ///
+    /// The default value for this option is `FsAtime::Host`, which leaves it to the host file
+    /// system.
+    pub atime: FsAtime,
+
+    /// How the metadata of the top layer is sanitized by `export_diff`. See the documentation of
+    /// `MetadataSanitizer` for more details. Copy-ups don't carry the extended attributes of their
+    /// source on Linux, so there is nothing to sanitize in their copies.
+    ///
+    /// The default value for this option is `None`, which exports the entries without their
+    /// extended attributes, and the AppleDouble files as regular files.
+    pub metadata_sanitizer: Option<MetadataSanitizer>,
+
+    /// How the reads of the guest use the host page cache, see `page_cache`.
+    ///
+    /// The default value for this option is `FsPageCache::Host`, which leaves it to the host.
+    pub page_cache: FsPageCache,
+
+    /// Where the owners the guest gives the entries and the device nodes it creates are kept, see
+    /// `stat_override`. A copy-up keeps the owner of its source in the copy when they are virtual.
+    ///
+    /// The default value for this option is `FsOwnership::Auto`, which keeps them on the host if
+    /// the device runs as root.
+    pub ownership: FsOwnership,
+
+    /// The lower layers read lazily from the blobs of an OCI image, by index in `layers`, whose
+    /// paths are the directories of the layers, see `LazyLayer`. Each is indexed when the overlay
+    /// is created, which builds its directory unless it is in the cache already, and the data of
+    /// its files is extracted when they are first copied up.
+    ///
+    /// The default value for this option is empty.
+    #[cfg(feature = "oci")]
+    pub lazy_layers: Vec<(usize, Arc<LazyLayer>)>,
 }
 
 /// An overlay filesystem implementation that combines multiple layers into a single logical filesystem.
@@ -300,20 +811,82 @@ pub struct OverlayFs {
     /// Used to restrict GID changes to privileged processes.
     my_gid: Option<libc::gid_t>,
 
-    /// Whether the process has CAP_FOWNER capability.
-    cap_fowner: bool,
-
     /// Configuration options for the filesystem
     config: Config,
 
-    /// Symbol table for interned filenames to efficiently store and compare path components
-    filenames: Arc<RwLock<SymbolTable>>,
+    /// Interned filenames, shared by the paths of the inodes
+    filenames: Mutex<NameTable>,
 
     /// Root inodes for each layer, ordered from bottom to top. The last element is the upperdir
     /// (writable layer) while all others are read-only lower layers.
     layer_roots: Arc<RwLock<Vec<Inode>>>,
+
+    /// The private directory holding a RAM-backed top layer, removed when the file system is
+    /// dropped. Only set for `UpperLayer::Ram`.
+    _ephemeral_dir: Option<EphemeralDir>,
+
+    /// Total size of the regular files in a RAM-backed top layer, checked against its size limit.
+    upper_usage: Mutex<u64>,
+
+    /// The files of a RAM-backed top layer being resized, by device and inode number, so that
+    /// concurrent resizes of the same file account for its growth once.
+    resize_locks: PathLocks<(u64, u64)>,
+
+    /// Snapshots of the top layer taken with `snapshot`, by id.
+    snapshots: Mutex<BTreeMap<u64, LayerSnapshot>>,
+
+    /// Counter for generating the next snapshot ID
+    next_snapshot: AtomicU64,
+
+    /// The generation to give to the next inode created for a host inode number whose file was
+    /// deleted through the overlay, on the host file systems that don't record birth times, which
+    /// otherwise tell the files apart. Only kept along with `inode_map`, without which every inode
+    /// created gets a new number, so it never outgrows the host inode numbers the map records.
+    generations: Mutex<BTreeMap<InodeAltKey, u64>>,
+
+    /// The paths being copied up to the top layer, so that concurrent requests copy each path once.
+    copy_up_locks: PathLocks<Vec<Name>>,
+
+    /// The directory entries being changed, so that concurrent requests changing the same entry,
+    /// e.g. two unlinks of the same lower file, run one after the other.
+    entry_locks: PathLocks<(Inode, Vec<u8>)>,
+
+    /// The device ID reported for all the entries, if `Config::single_dev` is set.
+    share_dev: Option<libc::dev_t>,
+
+    /// Whether the owners of the entries are kept in extended attributes, see `Config::ownership`.
+    virtual_ownership: bool,
+
+    /// The store that copied up file data is cloned from, if `Config::content_store` is set.
+    content_store: Option<ContentStore>,
+
+    /// The path filter of each layer, built on first use if `Config::lookup_filters` is set.
+    layer_filters: Vec<Mutex<Option<Arc<LayerFilter>>>>,
+
+    /// The counts of the lookups, copy-ups and whiteouts of each layer, see `stats`.
+    layer_stats: LayerCounters,
+
+    /// The whiteout and opaque marker probes kept across requests, see
+    /// `Config::whiteout_cache_ttl`.
+    whiteout_cache: WhiteoutCache,
+
+    /// The thread warming the directories the guest visits, if `Config::dentry_warming` is set.
+    dentry_warmer: Option<DentryWarmer>,
+
+    /// The log of the operations in progress on the top layer, if `Config::intent_log` is set.
+    intent_log: Option<IntentLog>,
+
+    /// The numbers given to the host files across restarts, if `Config::inode_map` is set.
+    inode_map: Option<InodeMap>,
+
+    /// The POSIX locks the guest holds on the files, by lock owner.
+    posix_locks: PosixLocks,
 }
 
+/// The private directory of a RAM-backed top layer, removed along with its contents when dropped,
+/// including when the file system fails to be created.
+struct EphemeralDir(PathBuf);
+
 /// Represents either a file or a path
 enum FileOrPath {
     /// A file
@@ -364,9 +937,78 @@ impl InodeAltKey {
     }
 }
 
+impl InodeData {
+    /// Whether the host file `file` isn't the file of this inode but a new one that got its inode
+    /// number after it was deleted on the host. Host file systems recycle inode numbers, so a hit
+    /// on the alternate key alone doesn't tell.
+    fn is_recycled(&self, file: &File) -> bool {
+        let btime = get_birth_time(file).ok().flatten();
+        matches!((self.btime, btime), (Some(old), Some(new)) if old != new)
+    }
+}
+
+impl HandleData {
+    /// Records the error of a delayed write.
+    ///
+    /// The guest has already considered such writes complete, so the error is kept around until it
+    /// can be reported by [`FileSystem::flush`] or [`FileSystem::release`]. Only the first error is
+    /// kept, later ones are most likely a consequence of it.
+    fn set_write_error(&self, err: &io::Error) {
+        let mut write_error = self.write_error.lock().unwrap();
+        if write_error.is_none() {
+            *write_error = Some(match err.raw_os_error() {
+                Some(errno) => io::Error::from_raw_os_error(errno),
+                None => io::Error::new(err.kind(), err.to_string()),
+            });
+        }
+    }
+
+    /// Takes the pending delayed write error, if any, so that it is only reported once.
+    fn take_write_error(&self) -> Option<io::Error> {
+        self.write_error.lock().unwrap().take()
+    }
+
+    /// Records a write of `size` bytes at `offset` of `fd`, the file of this handle, preallocating
+    /// space ahead of it if the writes through this handle look sequential. Preallocation is only an
+    /// optimization, so failing to preallocate just stops it for this handle.
+    fn preallocate_ahead(&self, fd: RawFd, offset: u64, size: u64) {
+        let mut sequential = self.sequential.lock().unwrap();
+        if let Some((offset, length)) = sequential.record(offset, size) {
+            if let Err(e) = prealloc::preallocate(fd, offset, length) {
+                debug!("virtio-fs: failed to preallocate inode {}: {e}", self.inode);
+                sequential.stop();
+            }
+        }
+    }
+
+    /// Gives back the space preallocated through this handle beyond the end of the file, which no
+    /// other handle may be writing to.
+    fn trim_preallocation(&self) {
+        if let Some(end) = self.sequential.lock().unwrap().preallocated_end() {
+            let fd = self.file.read().unwrap().as_raw_fd();
+            if let Err(e) = prealloc::trim(fd, end) {
+                debug!(
+                    "virtio-fs: failed to trim the preallocation of inode {}: {e}",
+                    self.inode
+                );
+            }
+        }
+    }
+}
+
+impl OverlayXattrs {
+    /// Returns the prefix of the names of the attributes.
+    fn prefix(&self) -> &'static str {
+        match self {
+            OverlayXattrs::Trusted => "trusted.overlay.",
+            OverlayXattrs::User => "user.overlay.",
+        }
+    }
+}
+
 impl OverlayFs {
     /// Creates a new OverlayFs with the given layers
-    pub fn new(config: Config) -> io::Result<Self> {
+    pub fn new(mut config: Config) -> io::Result<Self> {
         if config.layers.is_empty() {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
@@ -374,35 +1016,102 @@ impl OverlayFs {
             ));
         }
 
-        if config.layers.len() > MAX_LAYERS {
+        let ram_upper = matches!(config.upper_layer, UpperLayer::Ram { .. });
+        if config.layers.len() + ram_upper as usize > MAX_LAYERS {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 "maximum overlayfs layer count exceeded",
             ));
         }
 
+        // The lazy layers are indexed on first use, which builds their directories
+        #[cfg(feature = "oci")]
+        for (_, layer) in &config.lazy_layers {
+            layer.index()?;
+        }
+
+        config.layers = layer_paths::canonicalize(&config.layers)?;
+
+        if let Some(integrity) = &config.layer_integrity {
+            let lower_count = config.layers.len() - !ram_upper as usize;
+            Self::check_layer_integrity(&config.layers[..lower_count], integrity)?;
+        }
+
+        if let Some(set) = &config.lower_layers {
+            set.check(&config.layers[..config.layers.len() - !ram_upper as usize])?;
+        }
+
+        // A RAM-backed top layer goes on top of all the given layers
+        let ephemeral_dir = if ram_upper {
+            let dir = Self::create_ephemeral_dir()?;
+            config.layers.push(dir.0.clone());
+            Some(dir)
+        } else {
+            None
+        };
+
+        if let Some(xattrs) = config.overlay_xattrs.filter(|_| !config.read_only) {
+            let top_layer = config.layers.last().unwrap();
+            overlay_xattrs::check_settable(top_layer, xattrs.prefix())?;
+        }
+
+        // Complete the operations interrupted by a crash before anything looks at the layers
+        let intent_log = if config.intent_log && !ram_upper && !config.read_only {
+            let (top_layer, lower_layers) = config.layers.split_last().unwrap();
+            Some(IntentLog::open(top_layer, lower_layers, whiteout_path)?)
+        } else {
+            None
+        };
+
         let mut next_inode = 1;
         let mut inodes = MultikeyBTreeMap::new();
 
         // Initialize the root inodes for all layers
         let layer_roots = Self::init_root_inodes(&config.layers, &mut inodes, &mut next_inode)?;
 
+        // The top layer gives the device ID of the share when a single one is reported
+        let virtual_ownership = config.ownership.is_virtual();
+        let share_dev = config
+            .single_dev
+            .then(|| inodes.get(layer_roots.last().unwrap()).map(|root| root.dev))
+            .flatten();
+
+        let content_store = config
+            .content_store
+            .clone()
+            .map(ContentStore::new)
+            .transpose()?;
+
+        let layer_filters = config.layers.iter().map(|_| Mutex::new(None)).collect();
+        let layer_stats = LayerCounters::new(config.layers.len());
+        let whiteout_cache = WhiteoutCache::new(config.whiteout_cache_ttl);
+        let dentry_warmer = config.dentry_warming.map(DentryWarmer::new).transpose()?;
+
         // Set the `init.krun` inode
         let init_inode = next_inode;
         next_inode += 1;
 
+        // The host files keep the numbers they were given before, which the new ones follow
+        let inode_map = if config.inode_map && !ram_upper && !config.read_only {
+            let map = InodeMap::open(&config.layers, next_inode)?;
+            next_inode = next_inode.max(map.next_inode());
+            Some(map)
+        } else {
+            None
+        };
+
         // Get the file descriptor for /proc/self/fd
         let proc_self_fd = if let Some(fd) = config.proc_sfd_rawfd {
             fd
         } else {
             // Safe because this doesn't modify any memory and we check the return value.
-            let fd = unsafe {
+            let fd = retry_syscall(|| unsafe {
                 libc::openat(
                     libc::AT_FDCWD,
                     PROC_SELF_FD_CSTR.as_ptr(),
                     libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
                 )
-            };
+            });
 
             if fd < 0 {
                 return Err(io::Error::last_os_error());
@@ -429,9 +1138,6 @@ impl OverlayFs {
             Some(unsafe { libc::getgid() })
         };
 
-        let cap_fowner =
-            has_cap(None, CapSet::Effective, Capability::CAP_FOWNER).unwrap_or_default();
-
         // SAFETY: We just opened this fd or it was provided by our caller.
         let proc_self_fd = unsafe { File::from_raw_fd(proc_self_fd) };
 
@@ -447,60 +1153,207 @@ impl OverlayFs {
             announce_submounts: AtomicBool::new(false),
             my_uid,
             my_gid,
-            cap_fowner,
             config,
-            filenames: Arc::new(RwLock::new(SymbolTable::new())),
+            filenames: Mutex::new(NameTable::default()),
             layer_roots: Arc::new(RwLock::new(layer_roots)),
+            _ephemeral_dir: ephemeral_dir,
+            upper_usage: Mutex::new(0),
+            resize_locks: PathLocks::new(),
+            snapshots: Mutex::new(BTreeMap::new()),
+            next_snapshot: AtomicU64::new(1),
+            generations: Mutex::new(BTreeMap::new()),
+            copy_up_locks: PathLocks::new(),
+            entry_locks: PathLocks::new(),
+            share_dev,
+            virtual_ownership,
+            content_store,
+            layer_filters,
+            layer_stats,
+            whiteout_cache,
+            dentry_warmer,
+            intent_log,
+            inode_map,
+            posix_locks: PosixLocks::default(),
         })
     }
 
-    /// Initialize root inodes for all layers
-    ///
-    /// This function processes layers from top to bottom, creating root inodes for each layer.
-    ///
-    /// Parameters:
-    /// - layers: Slice of paths to the layer roots, ordered from bottom to top
-    /// - inodes: Mutable reference to the inodes map to populate
-    /// - next_inode: Mutable reference to the next inode counter
-    ///
-    /// Returns:
-    /// - io::Result<Vec<Inode>> containing the root inodes for each layer
-    fn init_root_inodes(
-        layers: &[PathBuf],
-        inodes: &mut MultikeyBTreeMap<Inode, InodeAltKey, Arc<InodeData>>,
-        next_inode: &mut u64,
-    ) -> io::Result<Vec<Inode>> {
-        // Pre-allocate layer_roots with the right size
-        let mut layer_roots = vec![0; layers.len()];
+    /// Checks the lower `layers` against their manifests, recording the missing ones.
+    fn check_layer_integrity(layers: &[PathBuf], integrity: &LayerIntegrity) -> io::Result<()> {
+        let refuse = integrity.on_drift == DriftPolicy::Refuse;
+        for layer in layers {
+            let drift = match layer_manifest::verify(layer, &integrity.manifest_dir) {
+                Ok(drift) => drift,
+                Err(err) if refuse => return Err(err),
+                Err(err) => {
+                    warn!("failed to check layer {}: {err}", layer.display());
+                    continue;
+                }
+            };
+            if drift.is_empty() {
+                continue;
+            }
 
-        // Process layers from top to bottom
-        for (i, layer_path) in layers.iter().enumerate().rev() {
-            let layer_idx = i; // Layer index from bottom to top
+            let description = layer_manifest::describe(layer, &drift);
+            if refuse {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, description));
+            }
+            warn!("{description}");
+        }
 
-            // Get the stat information for this layer's root
-            let c_path = CString::new(layer_path.to_string_lossy().as_bytes())?;
+        Ok(())
+    }
 
-            // Open the directory
-            let file = Self::open_path_file(&c_path)?;
+    /// Creates a private directory on the RAM-backed `/dev/shm` to hold an ephemeral top layer.
+    fn create_ephemeral_dir() -> io::Result<EphemeralDir> {
+        let mut stfs = MaybeUninit::<libc::statfs64>::zeroed();
+        if unsafe { libc::statfs64(DEV_SHM_CSTR.as_ptr(), stfs.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
 
-            // Get statx information
-            let (st, mnt_id) = Self::statx(file.as_raw_fd(), None)?;
+        // Safe because the kernel guarantees that the struct is now fully initialized.
+        let stfs = unsafe { stfs.assume_init() };
+        if stfs.f_type != libc::TMPFS_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "/dev/shm is not RAM-backed",
+            ));
+        }
 
-            // Create the alt key for this inode
-            let alt_key = InodeAltKey::new(st.st_ino, st.st_dev, mnt_id);
+        let mut template = EPHEMERAL_DIR_TEMPLATE.to_vec();
+        if unsafe { libc::mkdtemp(template.as_mut_ptr() as *mut libc::c_char) }.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        template.pop();
+        let dir = EphemeralDir(PathBuf::from(std::ffi::OsStr::from_bytes(&template)));
 
-            // Create the inode data
-            let inode_id = *next_inode;
-            *next_inode += 1;
+        // mkdtemp creates the directory as 0700, but it becomes the root of the guest
+        std::fs::set_permissions(&dir.0, std::fs::Permissions::from_mode(0o755))?;
 
-            let inode_data = Arc::new(InodeData {
+        Ok(dir)
+    }
+
+    /// Accounts for a regular file of a RAM-backed top layer changing size from `old_size` to
+    /// `new_size`. Growing fails with `ENOSPC` if the layer would exceed its size limit.
+    fn charge_upper_space(&self, old_size: u64, new_size: u64) -> io::Result<()> {
+        let UpperLayer::Ram { size_limit } = self.config.upper_layer else {
+            return Ok(());
+        };
+
+        let mut usage = self.upper_usage.lock().unwrap();
+        let new_usage = (*usage + new_size).saturating_sub(old_size);
+        if new_size > old_size && new_usage > size_limit {
+            return Err(io::Error::from_raw_os_error(libc::ENOSPC));
+        }
+
+        *usage = new_usage;
+        Ok(())
+    }
+
+    /// Runs `op`, which may resize the top layer file `fd` to at most `new_end` bytes, keeping the
+    /// usage of a RAM-backed top layer up to date. Fails with `ENOSPC` without running `op` if the
+    /// layer can't grow that much.
+    ///
+    /// The growth is reserved before `op` runs and reconciled with the size of the file after it,
+    /// so that the usage isn't locked during the I/O of `op`.
+    fn with_upper_space<T>(
+        &self,
+        fd: RawFd,
+        new_end: u64,
+        op: impl FnOnce() -> io::Result<T>,
+    ) -> io::Result<T> {
+        let UpperLayer::Ram { size_limit } = self.config.upper_layer else {
+            return op();
+        };
+
+        // The lock of the file is held across `op`, so that concurrent resizes of the same file
+        // are only accounted once
+        let (st, _) = Self::statx(fd, None)?;
+        let _guard = self.resize_locks.lock((st.st_dev, st.st_ino));
+        let (st, _) = Self::statx(fd, None)?;
+        let old_size = st.st_size as u64;
+        let reserved = new_end.saturating_sub(old_size);
+        {
+            let mut usage = self.upper_usage.lock().unwrap();
+            if *usage + reserved > size_limit {
+                return Err(io::Error::from_raw_os_error(libc::ENOSPC));
+            }
+            *usage += reserved;
+        }
+
+        let res = op();
+        let new_size = Self::statx(fd, None).map_or(old_size, |(st, _)| st.st_size as u64);
+        let mut usage = self.upper_usage.lock().unwrap();
+        *usage = (*usage + new_size).saturating_sub(old_size + reserved);
+
+        res
+    }
+
+    /// Returns the space of a RAM-backed top layer that removing the entry `name` of the directory
+    /// `dir_fd` (or `dir_fd` itself if `name` is `None`) gives back.
+    fn upper_space_freed_by_unlink(&self, dir_fd: RawFd, name: Option<&CStr>) -> u64 {
+        if !matches!(self.config.upper_layer, UpperLayer::Ram { .. }) {
+            return 0;
+        }
+
+        match Self::statx(dir_fd, name) {
+            Ok((st, _)) if st.st_mode & libc::S_IFMT == libc::S_IFREG && st.st_nlink == 1 => {
+                st.st_size as u64
+            }
+            _ => 0,
+        }
+    }
+
+    /// Initialize root inodes for all layers
+    ///
+    /// This function processes layers from top to bottom, creating root inodes for each layer.
+    ///
+    /// Parameters:
+    /// - layers: Slice of paths to the layer roots, ordered from bottom to top
+    /// - inodes: Mutable reference to the inodes map to populate
+    /// - next_inode: Mutable reference to the next inode counter
+    ///
+    /// Returns:
+    /// - io::Result<Vec<Inode>> containing the root inodes for each layer
+    fn init_root_inodes(
+        layers: &[PathBuf],
+        inodes: &mut MultikeyBTreeMap<Inode, InodeAltKey, Arc<InodeData>>,
+        next_inode: &mut u64,
+    ) -> io::Result<Vec<Inode>> {
+        // Pre-allocate layer_roots with the right size
+        let mut layer_roots = vec![0; layers.len()];
+
+        // Process layers from top to bottom
+        for (i, layer_path) in layers.iter().enumerate().rev() {
+            let layer_idx = i; // Layer index from bottom to top
+
+            // Get the stat information for this layer's root
+            let c_path = CString::new(layer_path.as_os_str().as_bytes())?;
+
+            // Open the directory
+            let file = Self::open_path_file(&c_path)?;
+
+            // Get statx information
+            let (st, mnt_id) = Self::statx(file.as_raw_fd(), None)?;
+
+            // Create the alt key for this inode
+            let alt_key = InodeAltKey::new(st.st_ino, st.st_dev, mnt_id);
+
+            // Create the inode data
+            let inode_id = *next_inode;
+            *next_inode += 1;
+
+            let inode_data = Arc::new(InodeData {
                 inode: inode_id,
                 file,
+                ino: st.st_ino,
                 dev: st.st_dev,
                 mnt_id,
                 refcount: AtomicU64::new(1),
-                path: vec![],
+                path: InodePath::root(),
                 layer_idx,
+                whiteouts: AtomicU8::new(WHITEOUTS_UNKNOWN),
+                generation: 0,
+                btime: None,
             });
 
             // Insert the inode into the map
@@ -515,7 +1368,8 @@ impl OverlayFs {
 
     /// Opens a file without following symlinks.
     fn open_file(path: &CStr, flags: i32) -> io::Result<File> {
-        let fd = unsafe { libc::open(path.as_ptr(), flags | libc::O_NOFOLLOW, 0) };
+        let fd =
+            retry_syscall(|| unsafe { libc::open(path.as_ptr(), flags | libc::O_NOFOLLOW, 0) });
         if fd < 0 {
             return Err(io::Error::last_os_error());
         }
@@ -526,7 +1380,9 @@ impl OverlayFs {
 
     /// Opens a file relative to a parent without following symlinks.
     fn open_file_at(parent: RawFd, name: &CStr, flags: i32) -> io::Result<File> {
-        let fd = unsafe { libc::openat(parent, name.as_ptr(), flags | libc::O_NOFOLLOW, 0) };
+        let fd = retry_syscall(|| unsafe {
+            libc::openat(parent, name.as_ptr(), flags | libc::O_NOFOLLOW, 0)
+        });
         if fd < 0 {
             return Err(io::Error::last_os_error());
         }
@@ -552,7 +1408,7 @@ impl OverlayFs {
     /// Performs a statx syscall without any modifications to the returned stat structure.
     fn statx(fd: RawFd, name: Option<&CStr>) -> io::Result<(libc::stat64, u64)> {
         let mut stx = MaybeUninit::<libc::statx>::zeroed();
-        let res = unsafe {
+        let res = retry_syscall(|| unsafe {
             libc::statx(
                 fd,
                 name.unwrap_or(&*EMPTY_CSTR).as_ptr(),
@@ -560,7 +1416,7 @@ impl OverlayFs {
                 libc::STATX_BASIC_STATS | libc::STATX_MNT_ID,
                 stx.as_mut_ptr(),
             )
-        };
+        });
         if res < 0 {
             return Err(io::Error::last_os_error());
         }
@@ -641,13 +1497,19 @@ impl OverlayFs {
         // have much bigger problems.
         //
         // It is safe to follow here since symlinks are returned early as O_PATH files.
-        let fd = unsafe {
-            libc::openat(
-                self.proc_self_fd.as_raw_fd(),
-                fd_str.as_ptr(),
-                flags | libc::O_CLOEXEC & (!libc::O_NOFOLLOW),
-            )
+        let open = |flags| {
+            retry_syscall(|| unsafe {
+                libc::openat(
+                    self.proc_self_fd.as_raw_fd(),
+                    fd_str.as_ptr(),
+                    flags | libc::O_CLOEXEC & (!libc::O_NOFOLLOW),
+                )
+            })
         };
+        let mut fd = open(flags);
+        if fd < 0 && atime::retry_without_noatime(&mut flags) {
+            fd = open(flags);
+        }
 
         if fd < 0 {
             return Err(io::Error::last_os_error());
@@ -657,6 +1519,17 @@ impl OverlayFs {
         Ok(unsafe { File::from_raw_fd(fd) })
     }
 
+    /// Opens `inode` to get or set its flags. Only regular files and directories have their flags
+    /// passed through, as opening other kinds of files may have side effects.
+    fn open_inode_for_flags(&self, inode: Inode) -> io::Result<File> {
+        let file_type = self.get_inode_data(inode)?.file.metadata()?.file_type();
+        if !file_type.is_file() && !file_type.is_dir() {
+            return Err(io::Error::from_raw_os_error(libc::ENOTTY));
+        }
+
+        self.open_inode(inode, libc::O_RDONLY | libc::O_NONBLOCK)
+    }
+
     /// Turns an inode into an opened file or a path.
     fn open_inode_or_path(&self, inode: Inode, flags: i32) -> io::Result<FileOrPath> {
         match self.open_inode(inode, flags) {
@@ -674,8 +1547,324 @@ impl OverlayFs {
         &self.config
     }
 
-    pub fn get_filenames(&self) -> &Arc<RwLock<SymbolTable>> {
-        &self.filenames
+    /// Returns the inode of the init binary served in every directory.
+    pub(crate) fn init_inode(&self) -> Inode {
+        self.init_inode
+    }
+
+    /// Returns the inodes the guest may hold, and cache the attributes of.
+    pub(crate) fn held_inodes(&self) -> Vec<Inode> {
+        self.inodes.read().unwrap().main.keys().copied().collect()
+    }
+
+    /// Returns the hooks of the embedder on the share, if it has any.
+    pub(crate) fn hooks(&self) -> Option<&FsHooks> {
+        self.config.hooks.as_ref()
+    }
+
+    /// Returns the path of `inode` relative to the root of the overlay, if it is known.
+    pub(crate) fn inode_path(&self, inode: Inode) -> Option<PathBuf> {
+        let data = self.get_inode_data(inode).ok()?;
+        Some(PathBuf::from(OsStr::from_bytes(
+            &self.relative_path(&data.path.names()),
+        )))
+    }
+
+    /// Returns the inodes the guest holds, for a snapshot of the share. The roots of the lower
+    /// layers are left out, as the overlay creates them along with the root.
+    pub(crate) fn snapshot_inodes(&self) -> io::Result<Vec<InodeState>> {
+        let layer_roots = self.layer_roots.read().unwrap().clone();
+        let held: Vec<(Inode, u64, u64)> = self
+            .inodes
+            .read()
+            .unwrap()
+            .main
+            .iter()
+            .filter(|(inode, _)| **inode == fuse::ROOT_ID || !layer_roots.contains(inode))
+            .map(|(&inode, (_, data))| {
+                (
+                    inode,
+                    data.generation,
+                    data.refcount.load(Ordering::Acquire),
+                )
+            })
+            .collect();
+
+        held.into_iter()
+            .map(|(inode, generation, refcount)| {
+                Ok(InodeState {
+                    inode,
+                    generation,
+                    refcount,
+                    path: self
+                        .inode_path(inode)
+                        .ok_or_else(|| snapshot::unreachable_inode(inode))?,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the handles the guest holds, for a snapshot of the share.
+    pub(crate) fn snapshot_handles(&self) -> io::Result<Vec<HandleState>> {
+        self.handles
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(&handle, data)| {
+                Ok(HandleState {
+                    handle,
+                    inode: data.inode,
+                    flags: snapshot::open_flags(&data.file.read().unwrap())?,
+                })
+            })
+            .collect()
+    }
+
+    /// Makes the next inodes and handles get numbers from `inode` and `handle` on, at least.
+    pub(crate) fn reserve_numbers(&self, inode: Inode, handle: Handle) {
+        self.next_inode.fetch_max(inode, Ordering::Relaxed);
+        self.next_handle.fetch_max(handle, Ordering::Relaxed);
+    }
+
+    /// Gives the inode `from` the number `to`, which must be free, `generation` and `refcount`
+    /// lookups, to restore an inode of a snapshot.
+    pub(crate) fn renumber_inode(
+        &self,
+        from: Inode,
+        to: Inode,
+        generation: u64,
+        refcount: u64,
+    ) -> io::Result<()> {
+        let mut inodes = self.inodes.write().unwrap();
+        if from == to {
+            let data = inodes.get(&from).ok_or_else(ebadf)?;
+            data.refcount.store(refcount, Ordering::Release);
+            return Ok(());
+        }
+        if inodes.get(&to).is_some() {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST));
+        }
+
+        let altkey = inodes.main.get(&from).ok_or_else(ebadf)?.0;
+        let data = inodes.remove(&from).unwrap();
+        let data = Arc::try_unwrap(data).map_err(|_| io::Error::from_raw_os_error(libc::EBUSY))?;
+        let (dev, ino, btime) = (data.dev, data.ino, data.btime);
+        inodes.insert(
+            to,
+            altkey,
+            Arc::new(InodeData {
+                inode: to,
+                refcount: AtomicU64::new(refcount),
+                generation,
+                ..data
+            }),
+        );
+        drop(inodes);
+
+        self.record_inode(dev, ino, btime, to);
+        Ok(())
+    }
+
+    /// Gives the handle `from` the number `to`, which must be free, to restore a handle of a
+    /// snapshot.
+    pub(crate) fn renumber_handle(&self, from: Handle, to: Handle) -> io::Result<()> {
+        let mut handles = self.handles.write().unwrap();
+        if from == to {
+            return Ok(());
+        }
+        if handles.contains_key(&to) {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST));
+        }
+        let data = handles.remove(&from).ok_or_else(ebadf)?;
+        handles.insert(to, data);
+        Ok(())
+    }
+
+    /// Records the current state of the top layer and returns an id to pass to `export_diff`.
+    pub fn snapshot(&self) -> io::Result<u64> {
+        let snapshot = LayerSnapshot::capture(self.upper_layer_path(), is_internal_name)?;
+        let id = self.next_snapshot.fetch_add(1, Ordering::SeqCst);
+        self.snapshots.lock().unwrap().insert(id, snapshot);
+        Ok(id)
+    }
+
+    /// Writes to `out` an OCI layer tar with the changes made to the top layer since the snapshot
+    /// `since`, including whiteouts for the entries removed in the meantime.
+    ///
+    /// The top layer is read while the guest may be writing to it, so changes made during the
+    /// export may or may not be included.
+    pub fn export_diff(&self, since: u64, out: impl io::Write) -> io::Result<()> {
+        let snapshots = self.snapshots.lock().unwrap();
+        let snapshot = snapshots.get(&since).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("unknown snapshot {since}"))
+        })?;
+
+        let is_internal_xattr = |name: &[u8]| self.is_internal_xattr(name);
+        let retention = self
+            .config
+            .metadata_sanitizer
+            .as_ref()
+            .map(|sanitizer| XattrRetention {
+                keep: &sanitizer.keep_xattrs,
+                is_internal: &is_internal_xattr,
+            });
+        layer_diff::write_diff(
+            self.upper_layer_path(),
+            snapshot,
+            is_internal_name,
+            retention.as_ref(),
+            out,
+        )
+    }
+
+    /// Forgets the snapshot `id`.
+    pub fn release_snapshot(&self, id: u64) {
+        self.snapshots.lock().unwrap().remove(&id);
+    }
+
+    /// Returns the statistics of the layers, from the bottom one up to the top one. The guest reads
+    /// them with the `VIRTIO_IOC_LAYER_STATS` ioctl, see `layer_stats`.
+    pub fn stats(&self) -> Vec<FsLayerStats> {
+        let mut stats = self.layer_stats.read();
+        let handle_inodes: Vec<Inode> = self
+            .handles
+            .read()
+            .unwrap()
+            .values()
+            .map(|handle| handle.inode)
+            .collect();
+        let inodes = self.inodes.read().unwrap();
+        for (_, data) in inodes.main.values() {
+            if let Some(layer) = stats.get_mut(data.layer_idx) {
+                layer.inodes += 1;
+            }
+        }
+        for inode in handle_inodes {
+            if let Some(layer) = inodes
+                .get(&inode)
+                .and_then(|data| stats.get_mut(data.layer_idx))
+            {
+                layer.open_handles += 1;
+            }
+        }
+        stats
+    }
+
+    /// Drops what the overlay caches of the layers: the whiteouts and opaque markers found in each
+    /// directory, the path filters of the lower layers and the directories already warmed.
+    pub(crate) fn drop_caches(&self) {
+        for (_, data) in self.inodes.read().unwrap().main.values() {
+            data.whiteouts.store(WHITEOUTS_UNKNOWN, Ordering::Release);
+        }
+        for filter in &self.layer_filters {
+            *filter.lock().unwrap() = None;
+        }
+        if let Some(set) = &self.config.lower_layers {
+            set.clear();
+        }
+        self.whiteout_cache.clear();
+        if let Some(warmer) = &self.dentry_warmer {
+            warmer.forget_visits();
+        }
+    }
+
+    /// Re-scans the layers after they were modified on the host.
+    ///
+    /// The whiteouts and opaque markers found in each directory are cached, as well as the path
+    /// filters of the lower layers, so the cached state is dropped to pick up the entries added or
+    /// removed out-of-band. When `verify_whiteouts` is enabled, the stale whiteouts of the top
+    /// layer are removed as well.
+    ///
+    /// Returns the number of stale whiteouts removed.
+    pub fn refresh_layers(&self) -> io::Result<usize> {
+        self.drop_caches();
+
+        if !self.config.verify_whiteouts {
+            return Ok(0);
+        }
+
+        let top_layer_idx = self.get_top_layer_idx();
+        let mut removed = 0;
+        let mut dirs = vec![Vec::new()];
+        while let Some(dir) = dirs.pop() {
+            let dir_path = self.upper_layer_path().join(OsStr::from_bytes(&dir));
+            for entry in std::fs::read_dir(&dir_path)? {
+                let entry = entry?;
+                let name = entry.file_name();
+                let mut path = dir.clone();
+                if !path.is_empty() {
+                    path.push(b'/');
+                }
+
+                if let Some(hidden) = name.as_bytes().strip_prefix(WHITEOUT_PREFIX.as_bytes()) {
+                    // Skip the opaque markers and the files of the overlay itself
+                    if hidden.starts_with(WHITEOUT_PREFIX.as_bytes()) {
+                        continue;
+                    }
+
+                    path.extend_from_slice(hidden);
+                    if self.is_stale_whiteout(top_layer_idx, &path) {
+                        std::fs::remove_file(entry.path())?;
+                        removed += 1;
+                    }
+                } else if entry.file_type()?.is_dir() {
+                    path.extend_from_slice(name.as_bytes());
+                    dirs.push(path);
+                }
+            }
+        }
+
+        Ok(removed)
+    }
+
+    fn upper_layer_path(&self) -> &Path {
+        self.config.layers.last().unwrap()
+    }
+
+    /// Records the intent of an operation on the entry at `path` in the log, if
+    /// `Config::intent_log` is set, returning a guard recording its end when dropped. Nothing is
+    /// recorded without lower layers, where every operation takes a single step.
+    fn begin_intent(
+        &self,
+        path: &[Name],
+        intent: impl FnOnce(Vec<u8>) -> Intent,
+    ) -> io::Result<Option<IntentGuard<'_>>> {
+        match &self.intent_log {
+            Some(log) if self.get_top_layer_idx() > 0 => {
+                log.begin(&intent(self.relative_path(path))).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Whether a lookup of `path`, relative to the layer roots, may find anything in the lower layer
+    /// `layer_idx` according to its path filter, the first `known` segments of the path having
+    /// been found in the layers above. The filter is built on the first call for each layer, or
+    /// taken from `Config::lower_layers`.
+    fn lower_layer_may_affect(&self, layer_idx: usize, path: &[u8], known: usize) -> bool {
+        // A layer that can't be walked is always probed
+        let build = || LayerFilter::build(&self.config.layers[layer_idx]).unwrap_or_default();
+        let filter = match &self.config.lower_layers {
+            Some(set) => set.filter(layer_idx, build),
+            None => self.layer_filters[layer_idx]
+                .lock()
+                .unwrap()
+                .get_or_insert_with(|| Arc::new(build()))
+                .clone(),
+        };
+        filter.may_affect(path, known)
+    }
+
+    /// Returns the path made of the `path` names, relative to the layer roots.
+    fn relative_path(&self, path: &[Name]) -> Vec<u8> {
+        let mut relative = Vec::new();
+        for name in path {
+            if !relative.is_empty() {
+                relative.push(b'/');
+            }
+            relative.extend_from_slice(name.to_bytes());
+        }
+        relative
     }
 
     fn get_layer_root(&self, layer_idx: usize) -> io::Result<Arc<InodeData>> {
@@ -699,42 +1888,211 @@ impl OverlayFs {
         self.get_inode_data(inode)
     }
 
-    /// Creates a new inode and adds it to the inode map
+    /// Records the number `inode` given to the host file `ino` of the device `dev`, born at
+    /// `btime`, in the map of `Config::inode_map`, if set. Failing to record it only loses the
+    /// number on a restart.
+    fn record_inode(&self, dev: u64, ino: u64, btime: Option<BirthTime>, inode: Inode) {
+        if let Some(map) = &self.inode_map {
+            let generation = btime.and_then(|btime| btime.generation());
+            if let Err(e) = map.record(dev, ino, generation, inode) {
+                warn!("virtio-fs: failed to record the number of inode {inode}: {e}");
+            }
+        }
+    }
+
+    /// Creates a new inode and adds it to the inode map, unless another thread added one for the
+    /// same host file since the caller looked for it, in which case that one is returned
     fn create_inode(
         &self,
         file: File,
         ino: libc::ino64_t,
         dev: libc::dev_t,
         mnt_id: u64,
-        path: Vec<Symbol>,
+        path: Arc<InodePath>,
         layer_idx: usize,
     ) -> (Inode, Arc<InodeData>) {
-        let inode = self.next_inode.fetch_add(1, Ordering::SeqCst);
+        let alt_key = InodeAltKey::new(ino, dev, mnt_id);
+        let btime = get_birth_time(&file).ok().flatten();
+        let generation = btime
+            .and_then(|btime| btime.generation())
+            .unwrap_or_else(|| {
+                self.generations
+                    .lock()
+                    .unwrap()
+                    .get(&alt_key)
+                    .copied()
+                    .unwrap_or(0)
+            });
+
+        // Replacing the inode would drop the references the guest holds on it
+        let mut inodes = self.inodes.write().unwrap();
+        if let Some(data) = inodes
+            .get_alt(&alt_key)
+            .filter(|data| !data.is_recycled(&file))
+        {
+            return (data.inode, data.clone());
+        }
+
+        // A host file keeps the number it was given, unless its inode is still known by it
+        let recorded = self
+            .inode_map
+            .as_ref()
+            .and_then(|map| map.get(dev, ino, btime.and_then(|btime| btime.generation())));
+        let (inode, numbered) = match recorded {
+            Some(inode) if inodes.get(&inode).is_none() => (inode, false),
+            _ => (self.next_inode.fetch_add(1, Ordering::SeqCst), true),
+        };
 
         let data = Arc::new(InodeData {
             inode,
             file,
+            ino,
             dev,
             mnt_id,
             refcount: AtomicU64::new(1),
             path,
             layer_idx,
+            whiteouts: AtomicU8::new(WHITEOUTS_UNKNOWN),
+            generation,
+            btime,
         });
 
-        let alt_key = InodeAltKey::new(ino, dev, mnt_id);
-        self.inodes
-            .write()
-            .unwrap()
-            .insert(inode, alt_key, data.clone());
+        inodes.insert(inode, alt_key, data.clone());
+        drop(inodes);
+
+        // The map is written to once every request can take the lock of the inodes again
+        if numbered {
+            self.record_inode(dev, ino, btime, inode);
+        }
 
         (inode, data)
     }
 
+    /// Records that the file of the host inode `key` was deleted, so that a file reusing its inode
+    /// number later gets a new generation. The guest needs the inode and generation pairs to stay
+    /// unique to build the file handles of NFS exports. Files deleted on the host directly are not
+    /// noticed.
+    ///
+    /// Nothing is recorded when the new file gets another inode anyway, without `inode_map`, or
+    /// when the host file system records birth times, which tell the files apart.
+    fn retire_generation(&self, key: InodeAltKey) {
+        if self.inode_map.is_none() {
+            return;
+        }
+        let current = self
+            .inodes
+            .read()
+            .unwrap()
+            .get_alt(&key)
+            .map(|data| (data.generation, data.btime));
+        if current.is_some_and(|(_, btime)| btime.and_then(|btime| btime.generation()).is_some()) {
+            return;
+        }
+        let mut generations = self.generations.lock().unwrap();
+        let generation = current
+            .map(|(generation, _)| generation)
+            .or_else(|| generations.get(&key).copied())
+            .unwrap_or(0);
+        generations.insert(key, generation + 1);
+    }
+
+    /// Accounts for the descriptor of a new handle in the budget of `Config::fd_client`, waiting
+    /// for it if the budget is short.
+    fn acquire_fd(&self) -> io::Result<Option<FdGrant>> {
+        self.config
+            .fd_client
+            .as_ref()
+            .map(|client| client.acquire(1))
+            .transpose()
+    }
+
+    /// Counts a new handle in `Config::handle_quota`, failing with `EMFILE` past its limit.
+    fn acquire_handle(&self) -> io::Result<Option<HandleGrant>> {
+        self.config
+            .handle_quota
+            .as_ref()
+            .map(|quota| quota.acquire())
+            .transpose()
+    }
+
+    /// Flushes the top layer directory `dir_fd` to the disk if `Config::durable` is set.
+    fn sync_dir(&self, dir_fd: RawFd) -> io::Result<()> {
+        if !self.config.durable {
+            return Ok(());
+        }
+
+        let fd_str = CString::new(dir_fd.to_string()).map_err(|_| einval())?;
+        sync_dir_at(self.proc_self_fd.as_raw_fd(), &fd_str)
+    }
+
+    /// Sets the access and modification times of `name` in the directory `dir_fd`, or of the
+    /// directory itself if `name` is `None`, to the ones in `st`. Symbolic links are not followed.
+    fn set_times(&self, dir_fd: RawFd, name: Option<&CStr>, st: &libc::stat64) -> io::Result<()> {
+        let tvs = [
+            libc::timespec {
+                tv_sec: st.st_atime,
+                tv_nsec: st.st_atime_nsec,
+            },
+            libc::timespec {
+                tv_sec: st.st_mtime,
+                tv_nsec: st.st_mtime_nsec,
+            },
+        ];
+
+        // Safe because this doesn't modify any memory and we check the return value
+        let res = match name {
+            Some(name) => unsafe {
+                libc::utimensat(
+                    dir_fd,
+                    name.as_ptr(),
+                    tvs.as_ptr(),
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            },
+            None => {
+                let fd_str = CString::new(dir_fd.to_string()).map_err(|_| einval())?;
+                unsafe {
+                    libc::utimensat(
+                        self.proc_self_fd.as_raw_fd(),
+                        fd_str.as_ptr(),
+                        tvs.as_ptr(),
+                        0,
+                    )
+                }
+            }
+        };
+
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Flushes the directory `dir` to the disk if `Config::durable` is set and the directory is in
+    /// the top layer, where its entries may have changed.
+    fn sync_inode_dir(&self, dir: Inode) -> io::Result<()> {
+        let dir_data = self.get_inode_data(dir)?;
+        if dir_data.layer_idx != self.get_top_layer_idx() {
+            return Ok(());
+        }
+
+        self.sync_dir(dir_data.file.as_raw_fd())
+    }
+
     /// Creates an Entry from stat information and inode data
-    fn create_entry(&self, inode: Inode, st: bindings::stat64) -> Entry {
+    fn create_entry(&self, inode: Inode, mut st: bindings::stat64) -> Entry {
+        self.patch_dev(&mut st);
+        self.patch_dir_nlink(inode, &mut st);
+        self.patch_blocks(inode, &mut st);
+        let data = self.inodes.read().unwrap().get(&inode).cloned();
+        if let Some(data) = &data {
+            self.patch_owner(data.file.as_raw_fd(), &mut st);
+        }
+        let generation = data.map_or(0, |data| data.generation);
         Entry {
             inode,
-            generation: 0,
+            generation,
             attr: st,
             attr_flags: 0,
             attr_timeout: self.config.attr_timeout,
@@ -742,10 +2100,126 @@ impl OverlayFs {
         }
     }
 
+    /// Replaces the device ID of an entry with the one of the share if `Config::single_dev` is set.
+    fn patch_dev(&self, st: &mut bindings::stat64) {
+        if let Some(dev) = self.share_dev {
+            st.st_dev = dev;
+        }
+    }
+
+    /// Replaces the owner and mode of the entry open as `fd` with the ones the guest gave it, if
+    /// `Config::ownership` is virtual.
+    fn patch_owner(&self, fd: RawFd, st: &mut bindings::stat64) {
+        if !self.virtual_ownership {
+            return;
+        }
+        if let Some(value) = stat_override::read(fd, None) {
+            value.apply(st);
+        }
+    }
+
+    /// Gives the entry `name` the guest just created in the directory `parent_fd` the owner of
+    /// `ctx`, and `mode` and `rdev`, if the ownership is virtual.
+    fn own_new_entry(
+        &self,
+        parent_fd: RawFd,
+        name: &CStr,
+        ctx: Context,
+        mode: u32,
+        rdev: u64,
+    ) -> io::Result<()> {
+        if !self.virtual_ownership {
+            return Ok(());
+        }
+        let value = StatOverride {
+            uid: ctx.uid,
+            gid: ctx.gid,
+            mode,
+            rdev,
+        };
+        stat_override::write(parent_fd, Some(name), &value)
+    }
+
+    /// Returns the mode of the host file of an entry the guest gives `mode`.
+    fn host_mode(&self, mode: u32) -> u32 {
+        if self.virtual_ownership {
+            stat_override::host_mode(mode)
+        } else {
+            mode
+        }
+    }
+
+    /// Whether `name` is the attribute keeping the owners of the entries, hidden from the guest
+    /// when the ownership is virtual.
+    fn is_override_xattr(&self, name: &CStr) -> bool {
+        self.virtual_ownership && name == OVERRIDE_XATTR
+    }
+
+    /// Reports no blocks for a top layer file still holding the data of the lower layer file it
+    /// shadows, if `Config::differential_blocks` is set.
+    fn patch_blocks(&self, inode: Inode, st: &mut bindings::stat64) {
+        if !self.config.differential_blocks || st.st_mode & libc::S_IFMT != libc::S_IFREG {
+            return;
+        }
+        let top_layer_idx = self.get_top_layer_idx();
+        let Ok(inode_data) = self.get_inode_data(inode) else {
+            return;
+        };
+        if inode_data.layer_idx != top_layer_idx {
+            return;
+        }
+        let Ok(cpath) = CString::new(self.relative_path(&inode_data.path.names())) else {
+            return;
+        };
+
+        for lower_idx in (0..top_layer_idx).rev() {
+            let Ok(layer_root) = self.get_layer_root(lower_idx) else {
+                return;
+            };
+            match Self::statx(layer_root.file.as_raw_fd(), Some(&cpath)) {
+                Ok((lower, _)) => {
+                    if lower.st_mode & libc::S_IFMT == libc::S_IFREG
+                        && lower.st_size == st.st_size
+                        && (lower.st_mtime, lower.st_mtime_nsec) == (st.st_mtime, st.st_mtime_nsec)
+                    {
+                        st.st_blocks = 0;
+                    }
+                    return;
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(_) => return,
+            }
+        }
+    }
+
+    /// Replaces the link count of a directory according to `Config::dir_nlink`.
+    fn patch_dir_nlink(&self, inode: Inode, st: &mut bindings::stat64) {
+        if st.st_mode & libc::S_IFMT != libc::S_IFDIR {
+            return;
+        }
+
+        match self.config.dir_nlink {
+            DirNlinkPolicy::One => st.st_nlink = 1,
+            DirNlinkPolicy::Merged => {
+                let mut subdirs = 0;
+                let res = self.process_dir_entries(inode, |entry| {
+                    if entry.type_ == libc::DT_DIR as u32 {
+                        subdirs += 1;
+                    }
+                    Ok(1)
+                });
+
+                // Keep the count of the layer if the directory can't be read
+                if res.is_ok() {
+                    st.st_nlink = (2 + subdirs) as _;
+                }
+            }
+            DirNlinkPolicy::Layer => {}
+        }
+    }
+
     fn create_whiteout_path(&self, name: &CStr) -> io::Result<CString> {
-        let name_str = name.to_str().map_err(|_| einval())?;
-        let whiteout_path = format!("{WHITEOUT_PREFIX}{name_str}");
-        CString::new(whiteout_path).map_err(|_| einval())
+        CString::new(whiteout_path(name.to_bytes())).map_err(|_| einval())
     }
 
     /// Checks for whiteout file in top layer
@@ -765,6 +2239,52 @@ impl OverlayFs {
         }
     }
 
+    /// Returns whether the whiteout of `path` in the layer `layer_idx` is stale, that is, whether
+    /// the entry it hides was recreated in a lower layer after the whiteout was created.
+    ///
+    /// `path` is the path of the hidden entry relative to the layer roots. The change times are
+    /// compared rather than the modification times, which unpacking an image preserves. Only the
+    /// whiteouts of the top layer can be stale: those of the lower layers come from an image, whose
+    /// layers are unpacked in no particular order, so their change times say nothing of the
+    /// entries they hide.
+    fn is_stale_whiteout(&self, layer_idx: usize, path: &[u8]) -> bool {
+        if layer_idx != self.get_top_layer_idx() {
+            return false;
+        }
+
+        let (Ok(whiteout_cpath), Ok(cpath)) =
+            (CString::new(whiteout_path(path)), CString::new(path))
+        else {
+            return false;
+        };
+
+        let whiteout = match self
+            .get_layer_root(layer_idx)
+            .and_then(|layer_root| Self::statx(layer_root.file.as_raw_fd(), Some(&whiteout_cpath)))
+        {
+            Ok((st, _)) => st,
+            Err(_) => return false,
+        };
+
+        for lower_idx in (0..layer_idx).rev() {
+            let layer_root = match self.get_layer_root(lower_idx) {
+                Ok(layer_root) => layer_root,
+                Err(_) => return false,
+            };
+
+            match Self::statx(layer_root.file.as_raw_fd(), Some(&cpath)) {
+                Ok((st, _)) => {
+                    return (st.st_ctime, st.st_ctime_nsec)
+                        > (whiteout.st_ctime, whiteout.st_ctime_nsec);
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(_) => return false,
+            }
+        }
+
+        false
+    }
+
     /// Checks for an opaque directory marker in the given parent directory path.
     fn check_opaque_marker(&self, parent: RawFd) -> io::Result<bool> {
         let opaque_cpath = CString::new(OPAQUE_MARKER).map_err(|_| einval())?;
@@ -782,19 +2302,50 @@ impl OverlayFs {
         }
     }
 
-    /// Interns a name and returns the corresponding Symbol
-    fn intern_name(&self, name: &CStr) -> io::Result<Symbol> {
-        // Clone the name to avoid lifetime issues
-        let name_to_intern = CString::new(name.to_bytes()).map_err(|_| einval())?;
+    /// Returns whether the directory `dir` may contain whiteouts or an opaque marker.
+    ///
+    /// The directory is scanned the first time this is called and the result is cached in its
+    /// inode, so that lookups through directories without any whiteouts skip the whiteout and
+    /// opaque marker probes entirely. Creating a whiteout marks the directory as
+    /// [`WHITEOUTS_PRESENT`], which is always a safe answer.
+    fn may_have_whiteouts(dir: &InodeData, dir_fd: RawFd) -> io::Result<bool> {
+        match dir.whiteouts.load(Ordering::Acquire) {
+            WHITEOUTS_ABSENT => return Ok(false),
+            WHITEOUTS_PRESENT => return Ok(true),
+            _ => (),
+        }
+
+        let mut present = false;
+        for entry in std::fs::read_dir(format!("/proc/self/fd/{dir_fd}"))? {
+            if entry?
+                .file_name()
+                .as_bytes()
+                .starts_with(WHITEOUT_PREFIX.as_bytes())
+            {
+                present = true;
+                break;
+            }
+        }
 
-        // Get a write lock to intern it
-        let mut filenames = self.filenames.write().unwrap();
-        filenames.intern(name_to_intern).map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!("Failed to intern filename: {}", e),
-            )
-        })
+        // Don't overwrite a whiteout that was created while we were scanning.
+        let state = if present {
+            WHITEOUTS_PRESENT
+        } else {
+            WHITEOUTS_ABSENT
+        };
+        let _ = dir.whiteouts.compare_exchange(
+            WHITEOUTS_UNKNOWN,
+            state,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
+
+        Ok(present)
+    }
+
+    /// Interns a name and returns the corresponding Name
+    fn intern_name(&self, name: &CStr) -> Name {
+        self.filenames.lock().unwrap().intern(name)
     }
 
     /// Gets the InodeData for an inode
@@ -826,6 +2377,14 @@ impl OverlayFs {
         self.layer_roots.read().unwrap().len() - 1
     }
 
+    /// Fails with `EROFS` if the overlay is read-only, see `Config::read_only`.
+    fn check_writable(&self) -> io::Result<()> {
+        if self.config.read_only {
+            return Err(io::Error::from_raw_os_error(libc::EROFS));
+        }
+        Ok(())
+    }
+
     fn bump_refcount(&self, inode: Inode) {
         let inodes = self.inodes.write().unwrap();
         let inode_data = inodes.get(&inode).unwrap();
@@ -870,19 +2429,8 @@ impl OverlayFs {
             ));
         }
 
-        // Convert to str for string pattern matching
-        let name_str = match std::str::from_utf8(name_bytes) {
-            Ok(s) => s,
-            Err(_) => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "name contains invalid UTF-8",
-                ))
-            }
-        };
-
         // Check for whiteout prefix
-        if name_str.starts_with(".wh.") {
+        if name_bytes.starts_with(WHITEOUT_PREFIX.as_bytes()) {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 "name cannot start with whiteout prefix",
@@ -890,7 +2438,7 @@ impl OverlayFs {
         }
 
         // Check for opaque marker
-        if name_str == ".wh..wh..opq" {
+        if name_bytes == OPAQUE_MARKER.as_bytes() {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 "name cannot be an opaque directory marker",
@@ -909,6 +2457,7 @@ impl OverlayFs {
     /// * `layer_root` - Root inode data for the layer being searched
     /// * `path_segments` - Path components to traverse, as interned symbols
     /// * `path_inodes` - Vector to store inode data for each path segment traversed
+    /// * `probes` - The whiteout and opaque marker probes already made by the request
     ///
     /// # Return Value
     /// Returns `Option<io::Result<bindings::stat64>>` where:
@@ -942,10 +2491,12 @@ impl OverlayFs {
     fn lookup_segment_by_segment(
         &self,
         layer_root: &Arc<InodeData>,
-        path_segments: &[Symbol],
+        path_segments: &[Name],
         path_inodes: &mut Vec<Arc<InodeData>>,
+        probes: &mut WhiteoutProbes,
     ) -> Option<io::Result<(File, libc::stat64, u64)>> {
         let mut opaque_marker_found = false;
+        let mut current_data = layer_root.clone();
 
         // Start from layer root
         let root_file = match layer_root.file.try_clone() {
@@ -964,40 +2515,70 @@ impl OverlayFs {
         // Traverse each path segment
         for (depth, segment) in path_segments.iter().enumerate() {
             // Get the current segment name and parent vol path
-            let filenames = self.filenames.read().unwrap();
-            let segment_name = filenames.get(*segment).unwrap();
+            let segment_name: &CStr = segment;
 
-            // Check for whiteout at current level
-            match self.check_whiteout(current.0.as_raw_fd(), segment_name) {
-                Ok(true) => {
-                    return None; // Found whiteout, stop searching
-                }
-                Ok(false) => (), // No whiteout, continue
+            // Only probe for whiteouts and opaque markers if the directory may contain any
+            let whiteouts = match Self::may_have_whiteouts(&current_data, current.0.as_raw_fd()) {
+                Ok(whiteouts) => whiteouts,
                 Err(e) => {
                     return Some(Err(e));
                 }
-            }
+            };
 
-            // Check for opaque marker at current level
-            match self.check_opaque_marker(current.0.as_raw_fd()) {
-                Ok(true) => {
-                    opaque_marker_found = true;
+            if whiteouts {
+                // Check for whiteout at current level
+                let dir_fd = current.0.as_raw_fd();
+                match probes.probe(
+                    &self.whiteout_cache,
+                    current_data.inode,
+                    Some(segment),
+                    || self.check_whiteout(dir_fd, segment_name),
+                ) {
+                    Ok(true) => {
+                        // Found whiteout, stop searching unless the entry was recreated below it.
+                        // A whiteout only hides the lower layers, so an entry of the same name in
+                        // its own layer, which the container tools export when an entry changes
+                        // type, is still found, and masks the lower layers as an opaque one would.
+                        // The whiteout device a rename with `RENAME_WHITEOUT` leaves is no entry.
+                        if !self.config.verify_whiteouts
+                            || !self.is_stale_whiteout(
+                                layer_root.layer_idx,
+                                &self.relative_path(&path_segments[..=depth]),
+                            )
+                        {
+                            match Self::statx(dir_fd, Some(segment_name)) {
+                                Ok((st, _)) if !is_whiteout_device(&st) => {
+                                    opaque_marker_found = true
+                                }
+                                _ => return None,
+                            }
+                        }
+                    }
+                    Ok(false) => (), // No whiteout, continue
+                    Err(e) => {
+                        return Some(Err(e));
+                    }
                 }
-                Ok(false) => (),
-                Err(e) => {
-                    return Some(Err(e));
+
+                // Check for opaque marker at current level
+                match probes.probe(&self.whiteout_cache, current_data.inode, None, || {
+                    self.check_opaque_marker(dir_fd)
+                }) {
+                    Ok(true) => {
+                        opaque_marker_found = true;
+                    }
+                    Ok(false) => (),
+                    Err(e) => {
+                        return Some(Err(e));
+                    }
                 }
             }
 
-            let segment_name = segment_name.to_owned();
-
-            drop(filenames); // Now safe to drop filenames lock
-
-            match Self::statx(current.0.as_raw_fd(), Some(&segment_name)) {
+            match Self::statx(current.0.as_raw_fd(), Some(segment_name)) {
                 Ok((st, mnt_id)) => {
                     // Open the current segment
                     let new_file =
-                        match Self::open_path_file_at(current.0.as_raw_fd(), &segment_name) {
+                        match Self::open_path_file_at(current.0.as_raw_fd(), segment_name) {
                             Ok(file) => {
                                 file
                             }
@@ -1016,15 +2597,12 @@ impl OverlayFs {
 
                     // Create or get inode for this path segment
                     let alt_key = InodeAltKey::new(st.st_ino, st.st_dev, mnt_id);
-                    let inode_data = {
-                        let inodes = self.inodes.read().unwrap();
-                        if let Some(data) = inodes.get_alt(&alt_key) {
-                            data.clone()
-                        } else {
-                            drop(inodes); // Drop read lock before write lock
-
-                            let mut path = path_inodes[depth].path.clone();
-                            path.push(*segment);
+                    let existing = self.inodes.read().unwrap().get_alt(&alt_key).cloned();
+                    let inode_data = match existing {
+                        Some(data) if !data.is_recycled(&new_file) => data,
+                        _ => {
+                            // A new inode replaces a recycled one, whose file is gone
+                            let path = path_inodes[depth].path.child(segment.clone());
 
                             // Safe because we just opened this fd.
                             let (_, data) = self.create_inode(
@@ -1039,6 +2617,7 @@ impl OverlayFs {
                             data
                         }
                     };
+                    current_data = inode_data.clone();
 
                     // Update path_inodes with the current segment's inode data
                     if (depth + 1) >= path_inodes.len() {
@@ -1073,6 +2652,7 @@ impl OverlayFs {
     ///
     /// * `start_layer_idx` - The index of the starting layer (from the topmost, which may be the writable layer).
     /// * `path_segments` - A slice of interned symbols representing the path components to traverse.
+    /// * `probes` - The whiteout and opaque marker probes already made by the request.
     ///
     /// ## Returns
     ///
@@ -1088,9 +2668,15 @@ impl OverlayFs {
     fn lookup_layer_by_layer<'a>(
         &'a self,
         start_layer_idx: usize,
-        path_segments: &[Symbol],
+        path_segments: &[Name],
+        probes: &mut WhiteoutProbes,
     ) -> io::Result<(Entry, Arc<InodeData>, Vec<Arc<InodeData>>)> {
         let mut path_inodes = vec![];
+        let top_layer_idx = self.get_top_layer_idx();
+        let relative_path = self
+            .config
+            .lookup_filters
+            .then(|| self.relative_path(path_segments));
 
         // Start from the start_layer_idx and try each layer down to layer 0
         for layer_idx in (0..=start_layer_idx).rev() {
@@ -1101,20 +2687,37 @@ impl OverlayFs {
                 path_inodes = vec![layer_root.clone()];
             }
 
-            match self.lookup_segment_by_segment(&layer_root, &path_segments, &mut path_inodes) {
+            // Skip the lower layers that have nothing to say about the path
+            if let Some(relative_path) = &relative_path {
+                if layer_idx != top_layer_idx
+                    && !self.lower_layer_may_affect(layer_idx, relative_path, path_inodes.len() - 1)
+                {
+                    continue;
+                }
+            }
+
+            match self.lookup_segment_by_segment(
+                &layer_root,
+                &path_segments,
+                &mut path_inodes,
+                probes,
+            ) {
                 Some(Ok((file, st, mnt_id))) => {
                     let alt_key = InodeAltKey::new(st.st_ino, st.st_dev, mnt_id);
 
                     // Check if we already have this inode
-                    let inodes = self.inodes.read().unwrap();
-                    if let Some(data) = inodes.get_alt(&alt_key) {
-                        return Ok((self.create_entry(data.inode, st), data.clone(), path_inodes));
+                    let existing = self.inodes.read().unwrap().get_alt(&alt_key).cloned();
+                    if let Some(data) = existing.filter(|data| !data.is_recycled(&file)) {
+                        return Ok((self.create_entry(data.inode, st), data, path_inodes));
                     }
 
-                    drop(inodes);
+                    // A new inode replaces a recycled one, whose file is gone
 
-                    // Open the path
-                    let path = path_segments.to_vec();
+                    // The parent is the last inode of the path
+                    let path = match path_segments.last() {
+                        Some(name) => path_inodes[path_segments.len() - 1].path.child(name.clone()),
+                        None => InodePath::root(),
+                    };
 
                     // Create new inode
                     let (inode, data) =
@@ -1151,12 +2754,22 @@ impl OverlayFs {
         let parent_data = self.get_inode_data(parent)?;
 
         // Create path segments for lookup by appending the new name
-        let mut path_segments = parent_data.path.clone();
-        let symbol = self.intern_name(name)?;
-        path_segments.push(symbol);
+        let mut path_segments = parent_data.path.names();
+        path_segments.push(self.intern_name(name));
 
-        let (mut entry, child_data, path_inodes) =
-            self.lookup_layer_by_layer(parent_data.layer_idx, &path_segments)?;
+        let (mut entry, child_data, path_inodes) = match self.lookup_layer_by_layer(
+            parent_data.layer_idx,
+            &path_segments,
+            &mut WhiteoutProbes::default(),
+        ) {
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {
+                return match self.folded_name(parent, name)? {
+                    Some(folded) if folded.as_c_str() != name => self.do_lookup(parent, &folded),
+                    _ => Err(e),
+                };
+            }
+            res => res?,
+        };
 
         // Set the submount flag if the endirectory is a mount point
         let mut attr_flags = 0;
@@ -1172,6 +2785,78 @@ impl OverlayFs {
         Ok((entry, path_inodes))
     }
 
+    /// Returns the name of the entry of the directory `parent` whose name only differs from `name`
+    /// by case, for a lookup of `name` that found nothing, if the lookups fold the case of the
+    /// names. Fails with `EIO` if several entries do.
+    fn folded_name(&self, parent: Inode, name: &CStr) -> io::Result<Option<CString>> {
+        if !self.config.case_insensitive {
+            return Ok(None);
+        }
+
+        let mut names = Vec::new();
+        let mut stream = self.open_dir_stream(parent)?;
+        self.read_dir_stream(&mut stream, |entry| {
+            names.push(entry.name.to_vec());
+            Ok(1)
+        })?;
+
+        case_names::find_folded(names, name.to_bytes()).map_err(|(first, second)| {
+            warn!("virtio-fs: {name:?} matches both {first:?} and {second:?} of inode {parent}");
+            io::Error::from_raw_os_error(libc::EIO)
+        })
+    }
+
+    /// Returns the name of the entry of the directory `parent` a lookup of `name` finds, which is
+    /// `name` itself unless the lookups fold the case of the names, so that the requests changing
+    /// the entry change the one the guest sees.
+    fn entry_name<'a>(&self, parent: Inode, name: &'a CStr) -> io::Result<Cow<'a, CStr>> {
+        if !self.config.case_insensitive {
+            return Ok(Cow::Borrowed(name));
+        }
+
+        let parent_data = self.get_inode_data(parent)?;
+        let mut path_segments = parent_data.path.names();
+        path_segments.push(self.intern_name(name));
+        match self.lookup_layer_by_layer(
+            parent_data.layer_idx,
+            &path_segments,
+            &mut WhiteoutProbes::default(),
+        ) {
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {
+                Ok(match self.folded_name(parent, name)? {
+                    Some(folded) => Cow::Owned(folded),
+                    None => Cow::Borrowed(name),
+                })
+            }
+            _ => Ok(Cow::Borrowed(name)),
+        }
+    }
+
+    /// Runs the hooks of the embedder before the copy-up of `inode_data`, which may veto it.
+    fn run_copy_up_hooks(&self, inode_data: &InodeData) -> io::Result<()> {
+        match self.hooks() {
+            Some(hooks) if hooks.has(FsHookPoint::PreCopyUp) => {
+                let path = self.relative_path(&inode_data.path.names());
+                hooks.run(FsHookPoint::PreCopyUp, Path::new(OsStr::from_bytes(&path)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Extracts the data of `inode_data` if it is a file of a lazy layer, before it is read.
+    #[cfg(feature = "oci")]
+    fn materialize(&self, inode_data: &InodeData) -> io::Result<()> {
+        match self
+            .config
+            .lazy_layers
+            .iter()
+            .find(|(layer_idx, _)| *layer_idx == inode_data.layer_idx)
+        {
+            Some((_, layer)) => layer.materialize(&self.relative_path(&inode_data.path.names())),
+            None => Ok(()),
+        }
+    }
+
     /// Copies up a file or directory from a lower layer to the top layer
     pub(crate) fn copy_up(&self, path_inodes: &[Arc<InodeData>]) -> io::Result<()> {
         // Get the top layer root
@@ -1189,52 +2874,64 @@ impl OverlayFs {
                 continue;
             }
 
+            // Another request may have copied this segment up while we waited for its lock
+            let _guard = self.copy_up_locks.lock(inode_data.path.names());
+            if let Ok(current) = self.get_inode_data(inode_data.inode) {
+                if current.layer_idx == top_layer_idx {
+                    parent = current.file.try_clone()?;
+                    continue;
+                }
+            }
+
             // Get the current segment name
-            let segment_name = {
-                let name = inode_data.path.last().unwrap();
-                let filenames = self.filenames.read().unwrap();
-                filenames.get(*name).unwrap().to_owned()
-            };
+            let segment_name = CString::from(&*inode_data.path.name().unwrap());
+
+            // The entry may have been removed while we waited for its lock, and copying it up
+            // would bring it back next to its whiteout
+            if self.check_whiteout(parent.as_raw_fd(), &segment_name)?
+                && (!self.config.verify_whiteouts
+                    || !self.is_stale_whiteout(
+                        top_layer_idx,
+                        &self.relative_path(&inode_data.path.names()),
+                    ))
+            {
+                return Err(io::Error::from_raw_os_error(libc::ENOENT));
+            }
 
             let (src_stat, _) = Self::statx(inode_data.file.as_raw_fd(), None)?;
             let file_type = src_stat.st_mode & libc::S_IFMT;
 
+            // A directory looked up again from a lower layer after its copy-up, as an ancestor of
+            // an entry only found there, is a different inode than its copy, which already merges
+            // it
+            if file_type == libc::S_IFDIR {
+                if let Ok((st, _)) = Self::statx(parent.as_raw_fd(), Some(&segment_name)) {
+                    if st.st_mode & libc::S_IFMT == libc::S_IFDIR {
+                        parent = Self::open_path_file_at(parent.as_raw_fd(), &segment_name)?;
+                        continue;
+                    }
+                }
+            }
+
+            self.run_copy_up_hooks(inode_data)?;
+
+            let (parent_stat, _) = Self::statx(parent.as_raw_fd(), None)?;
+
             // Copy up the file
             match file_type {
                 libc::S_IFREG => {
-                    // Open source file with O_RDONLY
-                    let src_file = self.open_inode(inode_data.inode, libc::O_RDONLY)?;
-
-                    // Open destination file with O_WRONLY | O_CREAT
-                    let dst_file = Self::open_file_at(
+                    #[cfg(feature = "oci")]
+                    self.materialize(inode_data)?;
+                    let size = src_stat.st_size as u64;
+                    self.charge_upper_space(0, size)?;
+                    if let Err(e) = self.copy_up_regular_file(
+                        inode_data,
                         parent.as_raw_fd(),
                         &segment_name,
-                        libc::O_WRONLY | libc::O_CREAT,
-                    )?;
-
-                    // Try to use FICLONE ioctl for CoW copying first (works on modern Linux filesystems like Btrfs, XFS, etc.)
-                    let result = unsafe {
-                        libc::ioctl(dst_file.as_raw_fd(), FICLONE as _, src_file.as_raw_fd())
-                    };
-
-                    if result < 0 {
-                        debug!("FICLONE failed, falling back to regular copy");
-                        let err = io::Error::last_os_error();
-                        // If FICLONE fails (e.g., across filesystems), fall back to regular copy
-                        if err.raw_os_error() == Some(libc::EXDEV)
-                            || err.raw_os_error() == Some(libc::EINVAL)
-                            || err.raw_os_error() == Some(libc::ETXTBSY)
-                            || err.raw_os_error() == Some(libc::EOPNOTSUPP)
-                        {
-                            // Fall back to regular copy
-                            self.copy_file_contents(
-                                src_file.as_raw_fd(),
-                                dst_file.as_raw_fd(),
-                                (src_stat.st_mode & 0o777) as u32,
-                            )?;
-                        } else {
-                            return Err(err);
-                        }
+                        &src_stat,
+                    ) {
+                        self.charge_upper_space(size, 0)?;
+                        return Err(e);
                     }
                 }
                 libc::S_IFDIR => {
@@ -1243,7 +2940,7 @@ impl OverlayFs {
                         if libc::mkdirat(
                             parent.as_raw_fd(),
                             segment_name.as_ptr(),
-                            src_stat.st_mode & 0o777,
+                            self.host_mode(src_stat.st_mode) & 0o777,
                         ) < 0
                         {
                             return Err(io::Error::last_os_error());
@@ -1267,22 +2964,21 @@ impl OverlayFs {
                     }
 
                     buf.truncate(len as usize);
+                    if let Some(remapped) =
+                        remap_symlink_target(&self.config.symlink_target_map, &buf)
+                    {
+                        buf = remapped;
+                    }
+                    let target = CString::new(buf).map_err(|_| einval())?;
 
+                    // The link is recreated with the same target, remapped if needed. Its
+                    // permissions are not copied because fchmodat would follow the new link and
+                    // change its target on the host.
                     unsafe {
                         if libc::symlinkat(
-                            buf.as_ptr() as *const _,
-                            parent.as_raw_fd(),
-                            segment_name.as_ptr(),
-                        ) < 0
-                        {
-                            return Err(io::Error::last_os_error());
-                        }
-
-                        if libc::fchmodat(
+                            target.as_ptr(),
                             parent.as_raw_fd(),
                             segment_name.as_ptr(),
-                            src_stat.st_mode & 0o777,
-                            0,
                         ) < 0
                         {
                             return Err(io::Error::last_os_error());
@@ -1298,65 +2994,428 @@ impl OverlayFs {
                 }
             }
 
+            // The copy is owned by the device, so it keeps the owner of its source in its override
+            if self.virtual_ownership && file_type != libc::S_IFLNK {
+                let value = stat_override::read(inode_data.file.as_raw_fd(), None)
+                    .unwrap_or_else(|| StatOverride::from_stat(&src_stat));
+                stat_override::write(parent.as_raw_fd(), Some(&segment_name), &value)?;
+            }
+
+            if let Some(xattrs) = self.config.overlay_xattrs {
+                if let Err(e) =
+                    self.set_overlay_xattrs(xattrs, inode_data, &parent, &segment_name, file_type)
+                {
+                    warn!("failed to record the origin of a copy-up: {e}");
+                }
+            }
+
+            // The copy-up itself doesn't change anything the guest can see: the copy keeps the
+            // times of its source, and the parent the ones it had before the copy was added to it.
+            // The request that needed the copy-up then updates the parent times if it changes its
+            // entries.
+            self.set_times(parent.as_raw_fd(), Some(&segment_name), &src_stat)?;
+            self.set_times(parent.as_raw_fd(), None, &parent_stat)?;
+
+            self.sync_dir(parent.as_raw_fd())?;
+
+            let bytes = match file_type {
+                libc::S_IFREG => src_stat.st_size as u64,
+                _ => 0,
+            };
+            self.layer_stats.copy_up(inode_data.layer_idx, bytes);
+
             // Update parent for next iteration
             let child = Self::open_path_file_at(parent.as_raw_fd(), &segment_name)?;
             let (new_stat, new_mnt_id) = Self::statx(child.as_raw_fd(), None)?;
             parent = child.try_clone()?;
 
-            // Update the inode entry to point to the new copy in the top layer
-            let alt_key = InodeAltKey::new(new_stat.st_ino, new_stat.st_dev, new_mnt_id);
-            let mut inodes = self.inodes.write().unwrap();
+            // Update the inode entry to point to the new copy in the top layer
+            let alt_key = InodeAltKey::new(new_stat.st_ino, new_stat.st_dev, new_mnt_id);
+            let mut inodes = self.inodes.write().unwrap();
+
+            // Create new inode data with updated dev/ino/layer_idx but same refcount
+            let btime = get_birth_time(&child).ok().flatten();
+            let new_data = Arc::new(InodeData {
+                inode: inode_data.inode,
+                file: child,
+                ino: new_stat.st_ino,
+                dev: new_stat.st_dev,
+                mnt_id: new_mnt_id,
+                refcount: AtomicU64::new(inode_data.refcount.load(Ordering::SeqCst)),
+                path: inode_data.path.clone(),
+                layer_idx: top_layer_idx,
+                whiteouts: AtomicU8::new(WHITEOUTS_UNKNOWN),
+                generation: inode_data.generation,
+                btime,
+            });
+
+            // Replace the old entry with the new one, which keeps its number across restarts
+            inodes.insert(inode_data.inode, alt_key, new_data);
+            drop(inodes);
+            self.record_inode(new_stat.st_dev, new_stat.st_ino, btime, inode_data.inode);
+        }
+
+        Ok(())
+    }
+
+    /// Copies up a file or directory like `copy_up`, along with everything below it if it is a
+    /// directory. The subdirectories are copied up in parallel, with up to
+    /// `Config::copy_up_threads` threads.
+    fn copy_up_tree(&self, path_inodes: &[Arc<InodeData>]) -> io::Result<()> {
+        self.copy_up(path_inodes)?;
+
+        let Some(last) = path_inodes.last() else {
+            return Ok(());
+        };
+        let (st, _) = Self::statx(last.file.as_raw_fd(), None)?;
+        if st.st_mode & libc::S_IFMT != libc::S_IFDIR {
+            return Ok(());
+        }
+
+        copy_up::run_task_queue(
+            self.config.copy_up_threads,
+            vec![last.path.names()],
+            |path| self.copy_up_dir_entries(&path),
+        )
+    }
+
+    /// Copies up the entries of the directory at `path`, which is already in the top layer, that
+    /// only exist in lower layers. Returns the paths of its subdirectories, whose own entries are
+    /// left to the caller.
+    fn copy_up_dir_entries(&self, path: &[Name]) -> io::Result<Vec<Vec<Name>>> {
+        let top_layer_idx = self.get_top_layer_idx();
+        let mut probes = WhiteoutProbes::default();
+        let (_, dir_data, dir_inodes) =
+            self.lookup_layer_by_layer(top_layer_idx, path, &mut probes)?;
+
+        let mut entries = Vec::new();
+        self.process_dir_entries(dir_data.inode, |entry| {
+            entries.push((entry.name.to_vec(), entry.type_));
+            Ok(1)
+        })?;
+
+        let mut subdirs = Vec::new();
+        for (name, type_) in entries {
+            let name = CString::new(name).map_err(|_| einval())?;
+            let mut child_path = path.to_vec();
+            child_path.push(self.intern_name(&name));
+
+            let (_, _, child_inodes) =
+                self.lookup_layer_by_layer(top_layer_idx, &child_path, &mut probes)?;
+            self.copy_up(&child_inodes)?;
+            self.drop_unreferenced(&child_inodes);
+
+            if type_ == libc::DT_DIR as u32 {
+                subdirs.push(child_path);
+            }
+        }
+
+        self.drop_unreferenced(&dir_inodes);
+        Ok(subdirs)
+    }
+
+    /// Records the lower source of `inode_data` on its copy `name` in the top layer directory
+    /// `parent`, and marks `parent` as holding copies, as Linux's OverlayFS does.
+    fn set_overlay_xattrs(
+        &self,
+        xattrs: OverlayXattrs,
+        inode_data: &InodeData,
+        parent: &File,
+        name: &CStr,
+        file_type: u32,
+    ) -> io::Result<()> {
+        let prefix = xattrs.prefix();
+
+        // User attributes can't be set on symlinks
+        if file_type != libc::S_IFLNK || xattrs == OverlayXattrs::Trusted {
+            let layer_root = self.get_layer_root(inode_data.layer_idx)?;
+            let origin = overlay_xattrs::encode_origin(&inode_data.file, &layer_root.file)?;
+            let attr = CString::new(format!("{prefix}{}", overlay_xattrs::ORIGIN)).unwrap();
+            let path = overlay_xattrs::entry_path(parent, Some(name));
+            overlay_xattrs::set(&path, &attr, &origin, false)?;
+        }
+
+        let attr = CString::new(format!("{prefix}{}", overlay_xattrs::IMPURE)).unwrap();
+        overlay_xattrs::set(&overlay_xattrs::entry_path(parent, None), &attr, b"y", true)
+    }
+
+    /// Copies up a regular file to `name` in the top layer directory `parent`.
+    ///
+    /// The data is first copied to a staging file hidden from the guest, which is only renamed into
+    /// place once its size has been verified against the source. The staging file records the size,
+    /// modification time and identity of its source in an extended attribute, so that a copy-up
+    /// interrupted by e.g. the VM shutting down is resumed on the next attempt, rather than leaving
+    /// a truncated file in the top layer that shadows the intact lower copy.
+    fn copy_up_regular_file(
+        &self,
+        inode_data: &InodeData,
+        parent: RawFd,
+        name: &CStr,
+        src_stat: &libc::stat64,
+    ) -> io::Result<()> {
+        // The source layers are read-only, so the layer index and inode number of the source
+        // identify the staging file across restarts without running into the name length limit.
+        let staging_name = CString::new(format!(
+            "{COPY_UP_STAGING_PREFIX}{}.{}",
+            inode_data.layer_idx, src_stat.st_ino
+        ))
+        .map_err(|_| einval())?;
+
+        let src_file = self.open_inode(inode_data.inode, libc::O_RDONLY)?;
+        let marker = Self::copy_up_marker(&Self::statx(src_file.as_raw_fd(), None)?.0);
+        let dst_file = unsafe {
+            let fd = retry_syscall(|| {
+                libc::openat(
+                    parent,
+                    staging_name.as_ptr(),
+                    libc::O_RDWR | libc::O_CREAT | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+                    0o600,
+                )
+            });
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            // Safe because we just opened this fd.
+            File::from_raw_fd(fd)
+        };
+
+        // A copy that ran out of space is not resumed, but removed to give the space back to the
+        // guest, which can then make room for it.
+        let no_space = |e: io::Error| {
+            if matches!(e.raw_os_error(), Some(libc::ENOSPC | libc::EDQUOT)) {
+                unsafe { libc::unlinkat(parent, staging_name.as_ptr(), 0) };
+            }
+            e
+        };
+
+        // Resume from a previous attempt if it was copying this same source
+        let mut offset = 0;
+        let mut digest = None;
+        let mut stored = false;
+        if Self::get_copy_up_marker(dst_file.as_raw_fd())?.as_deref() == Some(marker.as_bytes()) {
+            let (st, _) = Self::statx(dst_file.as_raw_fd(), None)?;
+            if st.st_size <= src_stat.st_size {
+                offset = st.st_size as u64;
+            }
+        }
+
+        if offset == 0 {
+            if unsafe { libc::ftruncate(dst_file.as_raw_fd(), 0) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            // Recording the source is best effort, without it an interrupted copy-up is restarted
+            // from scratch rather than resumed.
+            unsafe {
+                libc::fsetxattr(
+                    dst_file.as_raw_fd(),
+                    COPY_UP_XATTR_KEY.as_ptr() as *const libc::c_char,
+                    marker.as_ptr() as *const libc::c_void,
+                    marker.len(),
+                    0,
+                )
+            };
+
+            // Data already in the content store is cloned from there
+            if self.content_store.is_some() && src_stat.st_size >= content_store::MIN_SIZE {
+                digest = Some(ContentStore::digest(&src_file)?);
+            }
+            stored = digest
+                .as_deref()
+                .is_some_and(|digest| self.clone_from_content_store(digest, &dst_file, src_stat));
+
+            // Try to use FICLONE ioctl for CoW copying first (works on modern Linux filesystems like Btrfs, XFS, etc.)
+            let result = if stored {
+                0
+            } else {
+                unsafe { libc::ioctl(dst_file.as_raw_fd(), FICLONE as _, src_file.as_raw_fd()) }
+            };
+
+            if result < 0 {
+                debug!("FICLONE failed, falling back to regular copy");
+                let err = io::Error::last_os_error();
+                // If FICLONE fails (e.g., across filesystems), fall back to regular copy
+                if err.raw_os_error() == Some(libc::EXDEV)
+                    || err.raw_os_error() == Some(libc::EINVAL)
+                    || err.raw_os_error() == Some(libc::ETXTBSY)
+                    || err.raw_os_error() == Some(libc::EOPNOTSUPP)
+                {
+                    self.copy_file_contents(&src_file, &dst_file, 0, src_stat.st_size as u64)
+                        .map_err(no_space)?;
+                } else {
+                    return Err(no_space(err));
+                }
+            }
+        } else {
+            debug!("resuming copy-up of {name:?} at offset {offset}");
+            self.copy_file_contents(&src_file, &dst_file, offset, src_stat.st_size as u64)
+                .map_err(no_space)?;
+        }
+
+        // Make sure the copy is complete and durable before it replaces the lower copy
+        if unsafe { libc::fsync(dst_file.as_raw_fd()) } < 0 {
+            return Err(no_space(io::Error::last_os_error()));
+        }
+
+        let (dst_stat, _) = Self::statx(dst_file.as_raw_fd(), None)?;
+        if dst_stat.st_size != src_stat.st_size {
+            error!(
+                "copy-up of {name:?} is incomplete: expected {} bytes, got {}",
+                src_stat.st_size, dst_stat.st_size
+            );
+            return Err(io::Error::from_raw_os_error(libc::EIO));
+        }
+
+        // The copy, resumed or not, must be of the source as it is now
+        if Self::copy_up_marker(&Self::statx(src_file.as_raw_fd(), None)?.0) != marker {
+            error!("source of the copy-up of {name:?} changed during the copy");
+            unsafe { libc::unlinkat(parent, staging_name.as_ptr(), 0) };
+            return Err(io::Error::from_raw_os_error(libc::EIO));
+        }
+
+        // Share the data with the next copy-ups of the same content. This is best effort, as the
+        // store may well be on a file system without reflinks.
+        if let (Some(store), Some(digest), false) = (&self.content_store, &digest, stored) {
+            let res = store.insert(digest, |path| {
+                let object = Self::open_file(
+                    path,
+                    libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_CLOEXEC,
+                )?;
+                if unsafe { libc::ioctl(object.as_raw_fd(), FICLONE as _, dst_file.as_raw_fd()) }
+                    < 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+            if let Err(e) = res {
+                debug!("failed to add the copy-up of {name:?} to the content store: {e}");
+            }
+        }
+
+        unsafe {
+            let res = libc::fremovexattr(
+                dst_file.as_raw_fd(),
+                COPY_UP_XATTR_KEY.as_ptr() as *const libc::c_char,
+            );
+            if res < 0 {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() != Some(libc::ENODATA)
+                    && err.raw_os_error() != Some(libc::EOPNOTSUPP)
+                {
+                    return Err(err);
+                }
+            }
+
+            // Explicitly set permissions to match source file
+            // This will override any effects from the umask
+            if libc::fchmod(
+                dst_file.as_raw_fd(),
+                self.host_mode(src_stat.st_mode) & 0o777,
+            ) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+
+            if libc::renameat(parent, staging_name.as_ptr(), parent, name.as_ptr()) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Clones the content store object with the given digest into `dst_file`. Returns whether the
+    /// object was found and cloned, the caller copying the data itself otherwise.
+    fn clone_from_content_store(
+        &self,
+        digest: &str,
+        dst_file: &File,
+        src_stat: &libc::stat64,
+    ) -> bool {
+        let Some(path) = self.content_store.as_ref().and_then(|s| s.get(digest)) else {
+            return false;
+        };
+
+        let Ok(object) = Self::open_file(&path, libc::O_RDONLY | libc::O_CLOEXEC) else {
+            return false;
+        };
+
+        // Don't trust an object that was truncated behind our back
+        match Self::statx(object.as_raw_fd(), None) {
+            Ok((st, _)) if st.st_size == src_stat.st_size => (),
+            _ => return false,
+        }
+
+        unsafe { libc::ioctl(dst_file.as_raw_fd(), FICLONE as _, object.as_raw_fd()) == 0 }
+    }
 
-            // Create new inode data with updated dev/ino/layer_idx but same refcount
-            let new_data = Arc::new(InodeData {
-                inode: inode_data.inode,
-                file: child,
-                dev: new_stat.st_dev,
-                mnt_id: new_mnt_id,
-                refcount: AtomicU64::new(inode_data.refcount.load(Ordering::SeqCst)),
-                path: inode_data.path.clone(),
-                layer_idx: top_layer_idx,
-            });
+    /// Returns the copy-up marker of the source with the given stat: its size, modification time,
+    /// device and inode number. A staging file is only resumed if its marker is still the one of
+    /// its source.
+    fn copy_up_marker(st: &libc::stat64) -> String {
+        format!(
+            "{}:{}:{}:{}:{}",
+            st.st_size, st.st_mtime, st.st_mtime_nsec, st.st_dev, st.st_ino
+        )
+    }
 
-            // Replace the old entry with the new one
-            inodes.insert(inode_data.inode, alt_key, new_data);
+    /// Reads the copy-up marker of a staging file, if it has one
+    fn get_copy_up_marker(fd: RawFd) -> io::Result<Option<Vec<u8>>> {
+        let mut buf = vec![0u8; 128];
+        let res = unsafe {
+            libc::fgetxattr(
+                fd,
+                COPY_UP_XATTR_KEY.as_ptr() as *const libc::c_char,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        };
+
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::ENODATA) | Some(libc::EOPNOTSUPP) | Some(libc::ERANGE) => Ok(None),
+                _ => Err(err),
+            };
         }
 
-        Ok(())
+        buf.truncate(res as usize);
+        Ok(Some(buf))
     }
 
-    /// Helper method to copy file contents when clonefile is not available or fails
-    fn copy_file_contents(&self, src_fd: RawFd, dst_fd: RawFd, mode: u32) -> io::Result<()> {
-        unsafe {
-            // Copy file contents
-            let mut buf = [0u8; 8192];
-            loop {
-                let n_read = libc::read(src_fd, buf.as_mut_ptr() as *mut _, buf.len());
-                if n_read <= 0 {
+    /// Helper method to copy file contents, starting at `offset`, when FICLONE is not available
+    /// or fails. Only the extents of the source holding data are copied, so that the holes of a
+    /// sparse file stay holes in the top layer. They are reserved first, so that the copy fails with
+    /// `ENOSPC` before writing anything if the top layer doesn't have room for them.
+    fn copy_file_contents(
+        &self,
+        src_file: &File,
+        dst_file: &File,
+        offset: u64,
+        size: u64,
+    ) -> io::Result<()> {
+        let extents = data_extents(src_file, offset, size)?;
+        prealloc::reserve(dst_file.as_raw_fd(), &extents)?;
+
+        let mut buf = vec![0u8; COPY_CHUNK_SIZE];
+        for (start, len) in extents {
+            let end = start + len;
+            let mut pos = start;
+            while pos < end {
+                let want = buf.len().min((end - pos) as usize);
+                let n_read = src_file.read_at(&mut buf[..want], pos)?;
+                if n_read == 0 {
                     break;
                 }
-                let mut pos = 0;
-                while pos < n_read {
-                    let n_written = libc::write(
-                        dst_fd,
-                        buf.as_ptr().add(pos as usize) as *const _,
-                        (n_read - pos) as usize,
-                    );
-                    if n_written <= 0 {
-                        return Err(io::Error::last_os_error());
-                    }
-                    pos += n_written;
-                }
-            }
-
-            // Explicitly set permissions to match source file
-            // This will override any effects from the umask
-            if libc::fchmod(dst_fd, mode as libc::mode_t) < 0 {
-                return Err(io::Error::last_os_error());
+                dst_file.write_all_at(&buf[..n_read], pos)?;
+                pos += n_read as u64;
             }
         }
 
-        Ok(())
+        // The hole the source may end with
+        dst_file.set_len(size)
     }
 
     /// Ensures the file is in the top layer by copying it up if necessary.
@@ -1382,10 +3441,14 @@ impl OverlayFs {
         }
 
         // Build the path segments
-        let path_segments = inode_data.path.clone();
+        let path_segments = inode_data.path.names();
 
         // Lookup the file to get all path inodes
-        let (_, _, path_inodes) = self.lookup_layer_by_layer(top_layer_idx, &path_segments)?;
+        let (_, _, path_inodes) = self.lookup_layer_by_layer(
+            top_layer_idx,
+            &path_segments,
+            &mut WhiteoutProbes::default(),
+        )?;
 
         // Copy up the file
         self.copy_up(&path_inodes)?;
@@ -1407,30 +3470,100 @@ impl OverlayFs {
     fn create_whiteout_for_lower(&self, parent: Inode, name: &CStr) -> io::Result<()> {
         if let Ok((_, mut path_inodes)) = self.do_lookup(parent, name) {
             // Copy up the parent directory if needed
-            path_inodes.pop();
+            let hidden = path_inodes.pop();
             self.copy_up(&path_inodes)?;
-            let parent_fd = self.get_inode_data(parent)?.file.as_raw_fd();
+            let parent_data = self.get_inode_data(parent)?;
+            let parent_fd = parent_data.file.as_raw_fd();
+            parent_data
+                .whiteouts
+                .store(WHITEOUTS_PRESENT, Ordering::Release);
+            self.whiteout_cache.clear();
 
             let whiteout_cpath = self.create_whiteout_path(name)?;
-            let fd = unsafe {
+            let fd = retry_syscall(|| unsafe {
                 libc::openat(
                     parent_fd,
                     whiteout_cpath.as_ptr(),
                     libc::O_CREAT | libc::O_WRONLY | libc::O_EXCL | libc::O_NOFOLLOW,
                     0o000, // Whiteout files have no permissions
                 )
-            };
+            });
 
             if fd < 0 {
-                return Err(io::Error::last_os_error());
+                // The name is already whited out, which is all we were asked for
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() != Some(libc::EEXIST) {
+                    return Err(err);
+                }
+                let (st, _) = Self::statx(parent_fd, Some(&whiteout_cpath))?;
+                if st.st_mode & libc::S_IFMT != libc::S_IFREG {
+                    return Err(err);
+                }
+            } else {
+                unsafe { libc::close(fd) };
+                if let Some(hidden) = hidden {
+                    self.layer_stats.whiteout(hidden.layer_idx);
+                }
             }
+        }
+
+        Ok(())
+    }
+
+    /// Removes the whiteout of `name` from the top layer directory `parent_fd`, once an entry was
+    /// created or moved there under that name. A whiteout hides the entry next to it, not only the
+    /// ones of the lower layers. A directory replacing a whited out entry is made opaque first, so
+    /// that the entries of a lower directory of the same name stay hidden.
+    fn remove_whiteout(&self, parent_fd: RawFd, name: &CStr) -> io::Result<()> {
+        let whiteout_cpath = self.create_whiteout_path(name)?;
+        match Self::statx(parent_fd, Some(&whiteout_cpath)) {
+            Ok(_) => (),
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => return Ok(()),
+            Err(e) => return Err(e),
+        }
 
+        let (st, mnt_id) = Self::statx(parent_fd, Some(name))?;
+        if st.st_mode & libc::S_IFMT == libc::S_IFDIR {
+            let key = InodeAltKey::new(st.st_ino, st.st_dev, mnt_id);
+            if let Some(data) = self.inodes.read().unwrap().get_alt(&key) {
+                data.whiteouts.store(WHITEOUTS_PRESENT, Ordering::Release);
+            }
+
+            let dir = Self::open_dir_at(parent_fd, name)?;
+            let opaque_cpath = CString::new(OPAQUE_MARKER).map_err(|_| einval())?;
+            let fd = retry_syscall(|| unsafe {
+                libc::openat(
+                    dir.as_raw_fd(),
+                    opaque_cpath.as_ptr(),
+                    libc::O_CREAT | libc::O_WRONLY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+                    0o000,
+                )
+            });
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
             unsafe { libc::close(fd) };
+            self.whiteout_cache.clear();
+        }
+
+        let res = unsafe { libc::unlinkat(parent_fd, whiteout_cpath.as_ptr(), 0) };
+        self.whiteout_cache.clear();
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::ENOENT) {
+                return Err(err);
+            }
         }
 
         Ok(())
     }
 
+    /// Takes the lock of the entry `name` of `parent`, which every request creating, removing or
+    /// renaming the entry holds while it changes the layers.
+    fn lock_entry(&self, parent: Inode, name: &CStr) -> PathLockGuard<'_, (Inode, Vec<u8>)> {
+        self.entry_locks.lock((parent, name.to_bytes().to_vec()))
+    }
+
     /// Temporarily changes the effective UID and GID of the current thread to the requested values using RAII guards.
     ///
     /// If the requested UID or GID is 0 (root) or already matches the current effective UID/GID (as stored in my_uid and my_gid),
@@ -1456,6 +3589,11 @@ impl OverlayFs {
         uid: libc::uid_t,
         gid: libc::gid_t,
     ) -> io::Result<(Option<ScopedUid>, Option<ScopedGid>)> {
+        // The entries get their owners in extended attributes instead
+        if self.virtual_ownership {
+            return Ok((None, None));
+        }
+
         // Handle GID changes first since changing UID to non-root may prevent GID changes
         let scoped_gid = if gid == 0 || self.my_gid == Some(gid) {
             // If the requested GID is 0 (root) or matches our current GID,
@@ -1522,24 +3660,187 @@ impl OverlayFs {
         }
     }
 
-    /// Performs an open operation
-    fn do_open(&self, inode: Inode, mut flags: u32) -> io::Result<(Option<Handle>, OpenOptions)> {
-        if !self.cap_fowner {
-            // O_NOATIME can only be used with CAP_FOWNER or if we are the file
-            // owner. Not worth checking the latter, just drop it if we don't
-            // have the cap. This makes overlayfs mounts with virtiofs lower dirs
-            // work.
-            flags &= !(libc::O_NOATIME as u32);
+    /// Reads the target of the symlink `inode_data`, with the prefixes of `symlink_target_map`
+    /// rewritten for the links of the lower layers.
+    fn read_symlink(&self, inode_data: &InodeData) -> io::Result<Vec<u8>> {
+        // Allocate a buffer for the link target
+        let mut buf = vec![0; libc::PATH_MAX as usize];
+
+        // Safe because this will only modify the contents of `buf` and we check the return value.
+        let res = unsafe {
+            libc::readlinkat(
+                inode_data.file.as_raw_fd(),
+                EMPTY_CSTR.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.len(),
+            )
+        };
+
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Resize the buffer to the actual length of the link target
+        buf.resize(res as usize, 0);
+
+        // Links copied up to the top layer were remapped already, and the guest created the others
+        if inode_data.layer_idx < self.get_top_layer_idx() {
+            if let Some(remapped) = remap_symlink_target(&self.config.symlink_target_map, &buf) {
+                buf = remapped;
+            }
+        }
+
+        Ok(buf)
+    }
+
+    /// Resolves the symlink `target` of a link in the directory `dir` of the overlay, following
+    /// the links it goes through but not its last component, as the guest would. Returns `None` if
+    /// the target is relative and stays within the overlay, otherwise returns an equivalent
+    /// relative target clamped at the root of the overlay. Fails with `ELOOP` if it takes more than
+    /// `max_symlink_depth` links.
+    ///
+    /// The links followed are confined on their own when the guest reads them, so only the
+    /// components of `target` itself can lead outside of the overlay.
+    fn confine_symlink_target(
+        &self,
+        mut dir: Vec<Name>,
+        target: &[u8],
+    ) -> io::Result<Option<Vec<u8>>> {
+        let depth = dir.len();
+        let mut escaped = target.starts_with(b"/");
+        if escaped {
+            dir.clear();
+        }
+
+        // Components left to resolve, along with whether they come from `target`
+        let mut pending: VecDeque<(Vec<u8>, bool)> = target
+            .split(|b| *b == b'/')
+            .filter(|c| !c.is_empty() && *c != b".")
+            .map(|c| (c.to_vec(), true))
+            .collect();
+        let mut followed = 0;
+
+        while let Some((component, own)) = pending.pop_front() {
+            if component == b".." {
+                if dir.pop().is_none() && own {
+                    escaped = true;
+                }
+                continue;
+            }
+
+            dir.push(self.intern_name(&CString::new(component).map_err(|_| einval())?));
+            if pending.is_empty() {
+                break;
+            }
+
+            // Components that can't be looked up are left for the guest to fail on
+            let Ok((entry, data, path_inodes)) = self.lookup_layer_by_layer(
+                self.get_top_layer_idx(),
+                &dir,
+                &mut WhiteoutProbes::default(),
+            ) else {
+                continue;
+            };
+            let link_target = (entry.attr.st_mode & libc::S_IFMT == libc::S_IFLNK)
+                .then(|| self.read_symlink(&data));
+            self.drop_unreferenced(&path_inodes);
+            let Some(link_target) = link_target.transpose()? else {
+                continue;
+            };
+
+            followed += 1;
+            if followed > self.config.max_symlink_depth {
+                return Err(io::Error::from_raw_os_error(libc::ELOOP));
+            }
+
+            dir.pop();
+            if link_target.starts_with(b"/") {
+                dir.clear();
+            }
+            for c in link_target.split(|b| *b == b'/').rev() {
+                if !c.is_empty() && c != b"." {
+                    pending.push_front((c.to_vec(), false));
+                }
+            }
+        }
+
+        if !escaped {
+            return Ok(None);
+        }
+
+        let mut confined = b"../".repeat(depth);
+        let names: Vec<&[u8]> = dir.iter().map(|name| name.to_bytes()).collect();
+        confined.extend_from_slice(&names.join(&b'/'));
+        if confined.is_empty() {
+            confined.push(b'.');
+        } else if confined.ends_with(b"/") {
+            confined.pop();
+        }
+
+        Ok(Some(confined))
+    }
+
+    /// Drops the inodes of `path_inodes` the guest holds no reference to from the inode map, for
+    /// the paths an operation looks up on its own, whose inodes the guest never forgets. The first
+    /// one, a layer root, is kept.
+    fn drop_unreferenced(&self, path_inodes: &[Arc<InodeData>]) {
+        let mut inodes = self.inodes.write().unwrap();
+        for data in path_inodes.iter().skip(1) {
+            if data.refcount.load(Ordering::Acquire) == 1 {
+                inodes.remove(&data.inode);
+            }
         }
+    }
+
+    /// Performs an open operation
+    fn do_open(&self, inode: Inode, flags: u32) -> io::Result<(Option<Handle>, OpenOptions)> {
+        // O_NOATIME can only be used with CAP_FOWNER or if we are the file owner, so `open_inode`
+        // drops it when it isn't allowed. This makes overlayfs mounts with virtiofs lower dirs
+        // work.
+        let (flags, relatime) = atime::open_flags(self.config.atime, flags);
 
         // Get the inode data
         let inode_data = self.get_inode_data(inode)?;
 
-        // Ensure the file is in the top layer
-        let inode_data = self.ensure_top_layer(inode_data)?;
+        // A file truncated as it is copied up must not keep its content on a crash
+        let _intent_guard = if flags & (libc::O_TRUNC as u32) != 0
+            && inode_data.layer_idx != self.get_top_layer_idx()
+        {
+            self.begin_intent(&inode_data.path.names(), |path| Intent::Truncate(path, 0))?
+        } else {
+            None
+        };
+
+        // Only the files opened for writing are copied up to the top layer. The others are read
+        // from the layer they are in, which is left alone: their access times aren't updated.
+        let read_only = flags & libc::O_ACCMODE as u32 == libc::O_RDONLY as u32
+            && flags & (libc::O_TRUNC as u32) == 0;
+        if !read_only {
+            self.check_writable()?;
+        }
+        let inode_data = if read_only {
+            #[cfg(feature = "oci")]
+            if inode_data.layer_idx != self.get_top_layer_idx() {
+                self.materialize(&inode_data)?;
+            }
+            inode_data
+        } else {
+            self.ensure_top_layer(inode_data)?
+        };
+        let relatime =
+            relatime && !self.config.read_only && inode_data.layer_idx == self.get_top_layer_idx();
 
         // Open the file with the appropriate flags and generate a new unique handle ID
-        let file = RwLock::new(self.open_inode(inode_data.inode, flags as i32)?);
+        let handle_grant = self.acquire_handle()?;
+        let fd_grant = self.acquire_fd()?;
+        let file = if flags & (libc::O_TRUNC as u32) != 0 {
+            self.with_upper_space(inode_data.file.as_raw_fd(), 0, || {
+                self.open_inode(inode_data.inode, flags as i32)
+            })?
+        } else {
+            self.open_inode(inode_data.inode, flags as i32)?
+        };
+        let file = RwLock::new(file);
         let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
 
         // Create handle data structure with file and empty dirstream
@@ -1547,6 +3848,14 @@ impl OverlayFs {
             inode,
             file,
             exported: Default::default(),
+            dirty: Default::default(),
+            write_error: Default::default(),
+            sequential: Default::default(),
+            reads: Default::default(),
+            relatime: AtomicBool::new(relatime),
+            _fd_grant: fd_grant,
+            _handle_grant: handle_grant,
+            dir_stream: Default::default(),
         };
 
         // Store the handle data in the handles map
@@ -1584,6 +3893,10 @@ impl OverlayFs {
     fn do_release(&self, inode: Inode, handle: Handle) -> io::Result<()> {
         let mut handles = self.handles.write().unwrap();
 
+        // The space preallocated beyond the end of the file is only trimmed once no other handle
+        // may be writing there
+        let shared = handles.iter().any(|(h, data)| *h != handle && data.inode == inode);
+
         if let btree_map::Entry::Occupied(e) = handles.entry(handle) {
             if e.get().inode == inode {
                 if e.get().exported.load(Ordering::Relaxed) {
@@ -1596,10 +3909,19 @@ impl OverlayFs {
                         .remove(&(self.config.export_fsid, handle));
                 }
 
+                // Report any delayed write error that was not picked up by a flush.
+                let write_error = e.get().take_write_error();
+                if !shared {
+                    e.get().trim_preallocation();
+                }
+
                 // We don't need to close the file here because that will happen automatically when
                 // the last `Arc` is dropped.
                 e.remove();
-                return Ok(());
+                return match write_error {
+                    Some(err) => Err(err),
+                    None => Ok(()),
+                };
             }
         }
 
@@ -1617,9 +3939,12 @@ impl OverlayFs {
         extensions: Extensions,
     ) -> io::Result<Entry> {
         if extensions.secctx.is_some() {
-            unimplemented!("SECURITY_CTX is not supported and should not be used by the guest");
+            // SECURITY_CTX is never negotiated on Linux hosts
+            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
 
+        let _guard = self.lock_entry(parent, name);
+
         // Set the credentials for the operation
         let (_uid, _gid) = self.set_scoped_credentials(ctx.uid, ctx.gid)?;
 
@@ -1645,13 +3970,16 @@ impl OverlayFs {
         let parent_fd = parent_data.file.as_raw_fd();
 
         // Create the directory
-        let res = unsafe { libc::mkdirat(parent_fd, name.as_ptr(), mode & !umask) };
+        let mode = libc::S_IFDIR | (mode & !umask & 0o7777);
+        let res = unsafe { libc::mkdirat(parent_fd, name.as_ptr(), self.host_mode(mode)) };
         if res == 0 {
+            self.own_new_entry(parent_fd, name, ctx, mode, 0)?;
+            self.remove_whiteout(parent_fd, name)?;
+            self.sync_dir(parent_fd)?;
             let file = Self::open_path_file_at(parent_fd, name)?;
             let (stat, mnt_id) = Self::statx(file.as_raw_fd(), None)?;
 
-            let mut path = parent_data.path.clone();
-            path.push(self.intern_name(name)?);
+            let path = parent_data.path.child(self.intern_name(name));
 
             // Create the inode for the newly created directory
             let (inode, _) = self.create_inode(
@@ -1673,192 +4001,531 @@ impl OverlayFs {
         Err(io::Error::last_os_error())
     }
 
-    /// Performs an unlink operation
-    fn do_unlink(&self, parent: Inode, name: &CStr, flags: libc::c_int) -> io::Result<()> {
-        let top_layer_idx = self.get_top_layer_idx();
-        let (entry, _) = self.do_lookup(parent, name)?;
+    /// Performs an unlink operation
+    fn do_unlink(&self, parent: Inode, name: &CStr, flags: libc::c_int) -> io::Result<()> {
+        let _guard = self.lock_entry(parent, name);
+
+        // Keep the entry from being copied up while it is removed, which would bring it back
+        let path = self
+            .get_inode_data(parent)?
+            .path
+            .child(self.intern_name(name));
+        let _copy_up_guard = self.copy_up_locks.lock(path.names());
+        let top_layer_idx = self.get_top_layer_idx();
+        let (entry, _) = self.do_lookup(parent, name)?;
+
+        // If the inode is in the top layer. the parent will also be in the top layer, we need to unlink it.
+        let entry_data = self.get_inode_data(entry.inode)?;
+        let mut _intent_guard = None;
+        if entry_data.layer_idx == top_layer_idx {
+            _intent_guard = self.begin_intent(&path.names(), Intent::Whiteout)?;
+            let parent_fd = self.get_inode_data(parent)?.file.as_raw_fd();
+            let freed = self.upper_space_freed_by_unlink(entry_data.file.as_raw_fd(), None);
+            if flags & libc::AT_REMOVEDIR != 0 {
+                self.clear_whiteouts_for_rmdir(entry.inode, &entry_data)?;
+            }
+
+            // Remove the inode from the overlayfs
+            let res = unsafe { libc::unlinkat(parent_fd, name.as_ptr(), flags) };
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            self.charge_upper_space(freed, 0)?;
+
+            if flags & libc::AT_REMOVEDIR != 0 || entry.attr.st_nlink <= 1 {
+                let key = InodeAltKey::new(entry.attr.st_ino, entry_data.dev, entry_data.mnt_id);
+                self.retire_generation(key);
+            }
+        }
+
+        // If after an unlink, the entry still exists in a lower layer, we need to add a whiteout
+        self.create_whiteout_for_lower(parent, name)?;
+
+        self.sync_inode_dir(parent)
+    }
+
+    /// Removes the whiteouts and the opaque marker of the top layer directory `dir`, whose data is
+    /// `dir_data`, before it is removed, since the host refuses to remove a directory holding them.
+    /// Fails with `ENOTEMPTY` if the directory still has entries in the overlay, including the
+    /// ones only a lower layer has.
+    fn clear_whiteouts_for_rmdir(&self, dir: Inode, dir_data: &InodeData) -> io::Result<()> {
+        let mut empty = true;
+        self.process_dir_entries(dir, |_| {
+            empty = false;
+            Ok(0)
+        })?;
+        if !empty {
+            return Err(io::Error::from_raw_os_error(libc::ENOTEMPTY));
+        }
+
+        let dir_fd = dir_data.file.as_raw_fd();
+        if !Self::may_have_whiteouts(dir_data, dir_fd)? {
+            return Ok(());
+        }
+
+        for entry in std::fs::read_dir(format!("/proc/self/fd/{dir_fd}"))? {
+            let name = entry?.file_name();
+            if !name.as_bytes().starts_with(WHITEOUT_PREFIX.as_bytes()) {
+                continue;
+            }
+
+            let name = CString::new(name.as_bytes()).map_err(|_| einval())?;
+            if unsafe { libc::unlinkat(dir_fd, name.as_ptr(), 0) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            self.whiteout_cache.clear();
+        }
+
+        Ok(())
+    }
+
+    /// Empties the directory `dir` for the `VIRTIO_IOC_REMOVE_TREE` ioctl, so that the guest can
+    /// then remove it with a single `rmdir`.
+    ///
+    /// The entries the lower layers have in the directory are hidden first, all at once, by an
+    /// opaque marker in its top layer copy, and are not counted. The entries of the top layer copy
+    /// are then removed depth first, at most `limit` of them unless it is 0, so that the guest can
+    /// empty a huge tree in several requests rather than block in a single one for too long.
+    fn remove_tree(&self, ctx: Context, dir: Inode, limit: u64) -> io::Result<RemoveTreeProgress> {
+        if !self.config.remove_tree || ctx.uid != 0 {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+
+        let data = self.get_inode_data(dir)?;
+        let path = data.path.names();
+        if path.is_empty() {
+            return Err(io::Error::from_raw_os_error(libc::EBUSY));
+        }
+
+        let (st, _) = Self::statx(data.file.as_raw_fd(), None)?;
+        if st.st_mode & libc::S_IFMT != libc::S_IFDIR {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        }
+
+        let data = self.ensure_top_layer(data)?;
+        let dir_fd = data.file.as_raw_fd();
+
+        // Hide the entries of the lower layers before removing the whiteouts of the top layer,
+        // so that none of them shows up in the meantime
+        let top_layer_idx = self.get_top_layer_idx();
+        let mut probes = WhiteoutProbes::default();
+        let in_lower_layer = (0..top_layer_idx).any(|idx| {
+            let Ok(layer_root) = self.get_layer_root(idx) else {
+                return true;
+            };
+            let mut path_inodes = vec![layer_root.clone()];
+            let found =
+                self.lookup_segment_by_segment(&layer_root, &path, &mut path_inodes, &mut probes);
+            match found {
+                Some(Ok(_)) => true,
+                Some(Err(e)) => e.kind() != io::ErrorKind::NotFound,
+                None => false,
+            }
+        });
+        if in_lower_layer {
+            let opaque_cpath = CString::new(OPAQUE_MARKER).map_err(|_| einval())?;
+            data.whiteouts.store(WHITEOUTS_PRESENT, Ordering::Release);
+            let fd = retry_syscall(|| unsafe {
+                libc::openat(
+                    dir_fd,
+                    opaque_cpath.as_ptr(),
+                    libc::O_CREAT | libc::O_WRONLY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+                    0o000,
+                )
+            });
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            unsafe { libc::close(fd) };
+            self.whiteout_cache.clear();
+        }
+
+        let dir_file = Self::open_dir_at(dir_fd, c".")?;
+        let (st, mnt_id) = Self::statx(dir_fd, None)?;
+        let mut progress = RemoveTreeProgress {
+            removed: 0,
+            errors: 0,
+            done: true,
+            freed: 0,
+        };
+        self.remove_top_layer_entries(
+            &dir_file,
+            (st.st_dev, mnt_id),
+            if limit == 0 { u64::MAX } else { limit },
+            true,
+            &mut progress,
+        );
+        self.whiteout_cache.clear();
+
+        self.charge_upper_space(progress.freed, 0)?;
+        self.sync_inode_dir(dir)?;
+        Ok(progress)
+    }
+
+    /// Removes the entries of the top layer directory `dir` for [`Self::remove_tree`], along with
+    /// the entries of its subdirectories, until `limit` entries were removed. Symlinks are never
+    /// followed and the directories of other host file systems, as told by their device and mount
+    /// IDs `dev`, are left alone. The entries that can't be removed are counted and skipped, and
+    /// so is the opaque marker of the `top` directory.
+    fn remove_top_layer_entries(
+        &self,
+        dir: &File,
+        dev: (libc::dev_t, u64),
+        limit: u64,
+        top: bool,
+        progress: &mut RemoveTreeProgress,
+    ) {
+        let dir_fd = dir.as_raw_fd();
+        let names: Vec<CString> = match std::fs::read_dir(format!("/proc/self/fd/{dir_fd}")) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| CString::new(entry.file_name().as_bytes()).ok())
+                .collect(),
+            Err(_) => {
+                progress.errors += 1;
+                return;
+            }
+        };
+
+        for name in names {
+            if top && name.as_bytes() == OPAQUE_MARKER.as_bytes() {
+                continue;
+            }
+
+            let Ok((st, mnt_id)) = Self::statx(dir_fd, Some(&name)) else {
+                progress.errors += 1;
+                continue;
+            };
+
+            let is_dir = st.st_mode & libc::S_IFMT == libc::S_IFDIR;
+            if is_dir {
+                if (st.st_dev, mnt_id) != dev {
+                    progress.errors += 1;
+                    continue;
+                }
+
+                let Ok(subdir) = Self::open_dir_at(dir_fd, &name) else {
+                    progress.errors += 1;
+                    continue;
+                };
+                self.remove_top_layer_entries(&subdir, dev, limit, false, progress);
+                if !progress.done {
+                    return;
+                }
+            }
+
+            if progress.removed >= limit {
+                progress.done = false;
+                return;
+            }
+
+            let flags = if is_dir { libc::AT_REMOVEDIR } else { 0 };
+            if unsafe { libc::unlinkat(dir_fd, name.as_ptr(), flags) } < 0 {
+                progress.errors += 1;
+                continue;
+            }
+
+            progress.removed += 1;
+            if st.st_mode & libc::S_IFMT == libc::S_IFREG && st.st_nlink == 1 {
+                progress.freed += st.st_size as u64;
+            }
+            if is_dir || st.st_nlink <= 1 {
+                self.retire_generation(InodeAltKey::new(st.st_ino, st.st_dev, mnt_id));
+            }
+        }
+    }
+
+    /// Opens the directory `name` of `dir_fd` for reading its entries, without following it if it
+    /// is a symlink.
+    fn open_dir_at(dir_fd: RawFd, name: &CStr) -> io::Result<File> {
+        let fd = retry_syscall(|| unsafe {
+            libc::openat(
+                dir_fd,
+                name.as_ptr(),
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            )
+        });
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    /// Creates the files of `batch` in the directory `dir` for the `VIRTIO_IOC_BATCH_CREATE`
+    /// ioctl, all of them or none, and returns the reply to the guest.
+    ///
+    /// The names are locked and checked to exist in none of the layers before the files are
+    /// created in the top layer copy of the directory, where the whiteouts of their names are
+    /// removed once they all are.
+    fn batch_create(&self, ctx: Context, dir: Inode, batch: &[u8]) -> io::Result<Vec<u8>> {
+        if !self.config.batch_create {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
 
-        // If the inode is in the top layer. the parent will also be in the top layer, we need to unlink it.
-        let entry_data = self.get_inode_data(entry.inode)?;
-        if entry_data.layer_idx == top_layer_idx {
-            let parent_fd = self.get_inode_data(parent)?.file.as_raw_fd();
+        let files = batch_create::parse(batch)?;
+        if files.is_empty() {
+            return Ok(batch_create::reply(0));
+        }
+        for file in &files {
+            Self::validate_name(&file.name)?;
+        }
 
-            // Remove the inode from the overlayfs
-            let res = unsafe { libc::unlinkat(parent_fd, name.as_ptr(), flags) };
-            if res < 0 {
-                return Err(io::Error::last_os_error());
+        let _guard = self.entry_locks.lock_all(
+            files
+                .iter()
+                .map(|file| (dir, file.name.to_bytes().to_vec()))
+                .collect(),
+        );
+        let (_uid, _gid) = self.set_scoped_credentials(ctx.uid, ctx.gid)?;
+
+        for file in &files {
+            match self.do_lookup(dir, &file.name) {
+                Ok((entry, _)) => {
+                    self.do_forget(entry.inode, 1);
+                    return Err(io::Error::from_raw_os_error(libc::EEXIST));
+                }
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => (),
+                Err(e) => return Err(e),
             }
         }
 
-        // If after an unlink, the entry still exists in a lower layer, we need to add a whiteout
-        self.create_whiteout_for_lower(parent, name)?;
+        let data = self.ensure_top_layer(self.get_inode_data(dir)?)?;
+        let dir_fd = data.file.as_raw_fd();
+        let size = files.iter().map(|file| file.data.len() as u64).sum();
+        self.charge_upper_space(0, size)?;
+        if let Err(e) = batch_create::create_all(dir_fd, &files, self.virtual_ownership) {
+            let _ = self.charge_upper_space(size, 0);
+            return Err(e);
+        }
 
-        Ok(())
+        for file in &files {
+            self.remove_whiteout(dir_fd, &file.name)?;
+        }
+        self.sync_dir(dir_fd)?;
+        Ok(batch_create::reply(files.len()))
     }
 
-    /// Returns an iterator over all valid entries in the directory across all layers.
-    ///
-    /// Note: OverlayFs is a high-level, layered filesystem. A simple readdir on a single directory does not produce the complete view.
-    /// This function traverses the directory across multiple layers, merging entries while handling duplicates,
-    /// whiteout files, and opaque markers.
-    ///
-    /// ## Arguments
-    /// * `dir` - The inode of the directory to iterate over.
-    /// * `add_entry` - A callback function that processes each directory entry. If the callback returns 0,
-    ///                it signals that the directory buffer is full and iteration should stop.
-    ///
-    /// ## Returns
-    /// * `Ok(())` if the directory was iterated successfully.
-    /// * `Err(io::Error)` if an error occurred during iteration.
-    pub(super) fn process_dir_entries<F>(&self, dir: Inode, mut add_entry: F) -> io::Result<()>
-    where
-        F: FnMut(DirEntry) -> io::Result<usize>,
-    {
-        // Local state to track iteration over layers
-        struct LazyReaddirState {
-            current_layer: isize, // current layer (top-down)
-            inode_data: Option<Arc<InodeData>>,
-            current_iter: Option<std::fs::ReadDir>,
-            seen: HashSet<Vec<u8>>,
+    /// Has the entries of the directory `dir` warmed in the background if this is the first time
+    /// the guest visits it, see `Config::dentry_warming`.
+    fn warm_dentries(&self, dir: Inode) {
+        let Some(warmer) = &self.dentry_warmer else {
+            return;
+        };
+        if !warmer.first_visit(dir) {
+            return;
         }
-
-        let inode_data = self.get_inode_data(dir)?;
-        let top_layer = self.get_top_layer_idx() as isize;
-        let path = inode_data.path.clone();
-        let mut state = LazyReaddirState {
-            current_layer: top_layer,
-            inode_data: None,
-            current_iter: None,
-            seen: HashSet::new(),
+        let Ok(inode_data) = self.get_inode_data(dir) else {
+            return;
         };
 
-        let mut current_offset = 0u64;
-        let mut opaque_marker_found = false;
-        loop {
-            // If no current iterator, attempt to initialize one for the current layer
-            if state.current_iter.is_none() {
-                if state.current_layer < 0 {
-                    break; // All layers exhausted
-                }
+        // The directory in each layer it merges, from the top one, as the readdir finds them
+        let path = inode_data.path.names();
+        let mut probes = WhiteoutProbes::default();
+        let mut layers = Vec::new();
+        for layer_idx in (0..=self.get_top_layer_idx()).rev() {
+            let Ok(layer_root) = self.get_layer_root(layer_idx) else {
+                return;
+            };
+            let mut path_inodes = vec![layer_root.clone()];
+            match self.lookup_segment_by_segment(&layer_root, &path, &mut path_inodes, &mut probes)
+            {
+                Some(Ok(_)) => match path_inodes.last().unwrap().file.try_clone() {
+                    Ok(file) => layers.push(file),
+                    Err(_) => return,
+                },
+                Some(Err(e)) if e.kind() == io::ErrorKind::NotFound => continue,
+                Some(Err(_)) => return,
+                None => break,
+            }
+        }
 
-                let layer_root = self.get_layer_root(state.current_layer as usize)?;
-                let mut path_inodes = vec![layer_root.clone()];
+        warmer.warm(layers);
+    }
 
-                match self.lookup_segment_by_segment(&layer_root, &path, &mut path_inodes) {
-                    Some(Ok(_)) => {
-                        let last_inode = path_inodes.last().unwrap();
-                        let path = Self::data_to_path(last_inode)?;
-                        let dir_str = path.as_c_str().to_str().map_err(|_| {
-                            io::Error::new(io::ErrorKind::Other, "Invalid path string")
-                        })?;
+    /// Starts a merged listing of the directory `dir`, finding it in each layer that isn't hidden
+    /// from it by a whiteout or an opaque directory above.
+    fn open_dir_stream(&self, dir: Inode) -> io::Result<DirStream> {
+        let inode_data = self.get_inode_data(dir)?;
+        let path = inode_data.path.names();
 
-                        state.inode_data = Some(last_inode.clone());
-                        state.current_iter = Some(std::fs::read_dir(dir_str)?);
-                    }
-                    Some(Err(e)) if e.kind() == io::ErrorKind::NotFound => {
-                        state.current_layer -= 1;
-                        continue;
-                    }
-                    Some(Err(e)) => return Err(e),
-                    None => {
-                        state.current_layer = -1;
-                        continue;
+        let mut layers = Vec::new();
+        let mut probes = WhiteoutProbes::default();
+        for layer_idx in (0..=self.get_top_layer_idx()).rev() {
+            let layer_root = self.get_layer_root(layer_idx)?;
+            let mut path_inodes = vec![layer_root.clone()];
+            match self.lookup_segment_by_segment(&layer_root, &path, &mut path_inodes, &mut probes)
+            {
+                Some(Ok(_)) => {
+                    let dir_data = path_inodes.pop().unwrap();
+                    let opaque = self.check_opaque_marker(dir_data.file.as_raw_fd())?;
+                    layers.push((layer_idx, dir_data));
+                    if opaque {
+                        break;
                     }
                 }
+                Some(Err(e)) if e.kind() == io::ErrorKind::NotFound => continue,
+                Some(Err(e)) => return Err(e),
+                None => break,
             }
+        }
+        layers.reverse();
 
-            if let Some(iter) = state.current_iter.as_mut() {
-                if let Some(entry_result) = iter.next() {
-                    let entry = entry_result?;
-                    let name = entry.file_name();
-                    let name_str = name.to_string_lossy();
+        Ok(DirStream {
+            path,
+            offset: 0,
+            layers,
+            current: None,
+            seen: HashSet::new(),
+            whiteouts: HashSet::new(),
+            pending: None,
+        })
+    }
 
-                    if state.seen.contains(name.as_bytes()) {
-                        continue;
-                    }
+    /// Returns the next entry of the merged listing of `stream`, leaving out the whiteouts and the
+    /// entries hidden by them or by the layers above.
+    fn next_dir_stream_entry(&self, stream: &mut DirStream) -> io::Result<Option<StreamEntry>> {
+        loop {
+            if stream.current.is_none() {
+                let Some((layer_idx, dir_data)) = stream.layers.pop() else {
+                    return Ok(None);
+                };
+                let path = Self::data_to_path(&dir_data)?;
+                let iter = std::fs::read_dir(OsStr::from_bytes(path.as_bytes()))?;
+                stream.current = Some((layer_idx, dir_data, iter));
+            }
 
-                    // Handle opaque marker and whiteout files
-                    if name_str == OPAQUE_MARKER {
-                        // Opaque marker found; mark it and skip this entry
-                        opaque_marker_found = true;
-                        continue;
-                    } else if name_str.starts_with(WHITEOUT_PREFIX) {
-                        // Whiteout file; skip it
-                        let actual = &name_str[WHITEOUT_PREFIX.len()..];
-                        state.seen.insert(actual.as_bytes().to_vec());
-                        continue;
-                    } else {
-                        state.seen.insert(name.as_bytes().to_vec());
-                    }
+            let (layer_idx, _, iter) = stream.current.as_mut().unwrap();
+            let layer_idx = *layer_idx;
+            let Some(entry) = iter.next() else {
+                stream.current = None;
+                let whiteouts = std::mem::take(&mut stream.whiteouts);
+                stream.seen.extend(whiteouts);
+                continue;
+            };
+            let entry = entry?;
+            let name = entry.file_name().into_vec();
+            // Nothing is below the last layer for its entries to hide
+            let last = stream.layers.is_empty();
 
-                    let metadata = entry.metadata()?;
-                    let mode = metadata.mode() as u32;
-                    let s_ifmt = libc::S_IFMT as u32;
-                    let type_ = if mode & s_ifmt == (libc::S_IFDIR as u32) {
-                        libc::DT_DIR
-                    } else if mode & s_ifmt == (libc::S_IFREG as u32) {
-                        libc::DT_REG
-                    } else if mode & s_ifmt == (libc::S_IFLNK as u32) {
-                        libc::DT_LNK
-                    } else if mode & s_ifmt == (libc::S_IFIFO as u32) {
-                        libc::DT_FIFO
-                    } else if mode & s_ifmt == (libc::S_IFCHR as u32) {
-                        libc::DT_CHR
-                    } else if mode & s_ifmt == (libc::S_IFBLK as u32) {
-                        libc::DT_BLK
-                    } else if mode & s_ifmt == (libc::S_IFSOCK as u32) {
-                        libc::DT_SOCK
-                    } else {
-                        libc::DT_UNKNOWN
+            if name == OPAQUE_MARKER.as_bytes() || stream.seen.contains(&name) {
+                continue;
+            }
+            if let Some(actual) = name.strip_prefix(WHITEOUT_PREFIX.as_bytes()) {
+                // Hides the entry of the layers below unless it is stale
+                if !last {
+                    let stale = self.config.verify_whiteouts && {
+                        let mut actual_path = self.relative_path(&stream.path);
+                        if !actual_path.is_empty() {
+                            actual_path.push(b'/');
+                        }
+                        actual_path.extend_from_slice(actual);
+                        self.is_stale_whiteout(layer_idx, &actual_path)
                     };
+                    if !stale {
+                        stream.whiteouts.insert(actual.to_vec());
+                    }
+                }
+                continue;
+            }
+            if !last {
+                stream.seen.insert(name.clone());
+            }
+
+            // The type comes from the directory entry, without a stat of the file, unless the
+            // host file system doesn't report it
+            let file_type = entry.file_type()?;
+            let type_ = if file_type.is_dir() {
+                libc::DT_DIR
+            } else if file_type.is_file() {
+                libc::DT_REG
+            } else if file_type.is_symlink() {
+                libc::DT_LNK
+            } else if file_type.is_fifo() {
+                libc::DT_FIFO
+            } else if file_type.is_char_device() {
+                libc::DT_CHR
+            } else if file_type.is_block_device() {
+                libc::DT_BLK
+            } else if file_type.is_socket() {
+                libc::DT_SOCK
+            } else {
+                libc::DT_UNKNOWN
+            };
 
-                    current_offset += 1;
+            return Ok(Some(StreamEntry {
+                ino: entry.ino(),
+                type_: type_ as u32,
+                name,
+            }));
+        }
+    }
 
-                    let dir_entry = DirEntry {
-                        ino: metadata.ino(),
-                        offset: current_offset,
-                        type_: type_ as u32,
-                        name: name.as_bytes(),
-                    };
+    /// Lists the entries of `stream` from its offset, calling `add_entry` for each until it returns
+    /// 0, in which case the entry it was given is kept for the next call.
+    fn read_dir_stream<F>(&self, stream: &mut DirStream, mut add_entry: F) -> io::Result<()>
+    where
+        F: FnMut(DirEntry) -> io::Result<usize>,
+    {
+        loop {
+            let entry = match stream.pending.take() {
+                Some(entry) => entry,
+                None => match self.next_dir_stream_entry(stream)? {
+                    Some(entry) => entry,
+                    None => return Ok(()),
+                },
+            };
 
-                    if add_entry(dir_entry)? == 0 {
-                        return Ok(());
-                    }
-                } else {
-                    state.current_iter = None;
-                    if opaque_marker_found {
-                        break;
-                    }
-                    state.current_layer -= 1;
-                    continue;
-                }
+            let dir_entry = DirEntry {
+                ino: entry.ino,
+                offset: stream.offset + 1,
+                type_: entry.type_,
+                name: &entry.name,
+            };
+            if add_entry(dir_entry)? == 0 {
+                stream.pending = Some(entry);
+                return Ok(());
             }
+            stream.offset += 1;
         }
+    }
 
-        Ok(())
+    /// Calls `add_entry` for each entry of the directory `dir`, merged across all layers, until it
+    /// returns 0.
+    ///
+    /// Note: OverlayFs is a high-level, layered filesystem. A simple readdir on a single directory does not produce the complete view.
+    /// This function traverses the directory across multiple layers, merging entries while handling duplicates,
+    /// whiteout files, and opaque markers.
+    pub(super) fn process_dir_entries<F>(&self, dir: Inode, add_entry: F) -> io::Result<()>
+    where
+        F: FnMut(DirEntry) -> io::Result<usize>,
+    {
+        let mut stream = self.open_dir_stream(dir)?;
+        self.read_dir_stream(&mut stream, add_entry)
     }
 
     /// Reads directory entries for the given inode by merging entries from all underlying layers.
     ///
     /// Unlike conventional filesystems that simply call readdir on a directory file descriptor,
-    /// OverlayFs must aggregate entries from multiple layers. The `offset` parameter specifies the starting
-    /// index in the merged list of directory entries. The provided `add_entry` callback is invoked for each
-    /// entry; a return value of 0 indicates that the directory buffer is full and reading should cease.
-    ///
-    /// NOTE: The current implementation of offset does not entirely follow FUSE expected behaviors.
-    /// Changes to entries in the write layer can affect the offset, potentially causing inconsistencies
-    /// in directory listing between calls.
-    ///
-    /// TODO: Implement a more robust offset handling mechanism that maintains consistency even when
-    /// the underlying directory structure changes. One way is making offset a composite value of
-    /// layer (1 MSB) + offset (7 LSB). This will also require having multiple open dirs from lower layers
-    /// in [HandleData].
+    /// OverlayFs must aggregate entries from multiple layers. The `offset` parameter is the index
+    /// of the first entry to list in the merged listing. The provided `add_entry` callback is
+    /// invoked for each entry; a return value of 0 indicates that the directory buffer is full and
+    /// reading should cease.
+    ///
+    /// The listing is kept by the handle, so that a readdir continuing at the offset where the
+    /// previous one stopped resumes it, with the layers it was reading still open, rather than
+    /// reading the directory again up to the offset. The entries are listed as they are read from
+    /// the layers, the names of a directory found in a single layer not being kept at all, and
+    /// only the ones of the upper layers otherwise, to leave out the entries of the lower layers
+    /// they hide. A readdir at any other offset, such as after a `rewinddir`, starts the listing
+    /// again.
     pub(super) fn do_readdir<F>(
         &self,
         inode: Inode,
+        handle: Handle,
         size: u32,
         offset: u64,
-        mut add_entry: F,
+        add_entry: F,
     ) -> io::Result<()>
     where
         F: FnMut(DirEntry) -> io::Result<usize>,
@@ -1867,15 +4534,25 @@ impl OverlayFs {
             return Ok(());
         }
 
-        let mut current_offset = 0u64;
-        self.process_dir_entries(inode, |entry| {
-            if current_offset < offset {
-                current_offset += 1;
-                return Ok(1);
+        let data = self.get_inode_handle_data(inode, handle)?;
+        let mut cached = data.dir_stream.lock().unwrap();
+        let mut stream = match cached.take() {
+            Some(stream) if stream.offset == offset => stream,
+            _ => {
+                let mut stream = self.open_dir_stream(inode)?;
+                let mut skipped = 0;
+                self.read_dir_stream(&mut stream, |_| {
+                    skipped += 1;
+                    Ok(if skipped <= offset { 1 } else { 0 })
+                })?;
+                stream
             }
+        };
 
-            add_entry(entry)
-        })
+        // A listing that failed midway is started again by the next readdir
+        self.read_dir_stream(&mut stream, add_entry)?;
+        *cached = Some(stream);
+        Ok(())
     }
 
     fn do_create(
@@ -1889,9 +4566,12 @@ impl OverlayFs {
         extensions: Extensions,
     ) -> io::Result<(Entry, Option<Handle>, OpenOptions)> {
         if extensions.secctx.is_some() {
-            unimplemented!("SECURITY_CTX is not supported and should not be used by the guest");
+            // SECURITY_CTX is never negotiated on Linux hosts
+            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
 
+        let _guard = self.lock_entry(parent, name);
+
         // Set the credentials for the operation
         let (_uid, _gid) = self.set_scoped_credentials(ctx.uid, ctx.gid)?;
 
@@ -1915,30 +4595,37 @@ impl OverlayFs {
 
         // Get the parent file descriptor
         let parent_fd = parent_data.file.as_raw_fd();
+        let handle_grant = self.acquire_handle()?;
+        let fd_grant = self.acquire_fd()?;
 
         // Safe because this doesn't modify any memory and we check the return value. We don't
         // really check `flags` because if the kernel can't handle poorly specified flags then we
         // have much bigger problems.
-        let fd = unsafe {
+        let fd = retry_syscall(|| unsafe {
             libc::openat(
                 parent_fd,
                 name.as_ptr(),
                 flags as i32 | libc::O_CREAT | libc::O_CLOEXEC | libc::O_NOFOLLOW,
-                mode & !(umask & 0o777),
+                self.host_mode(mode & !(umask & 0o777)),
             )
-        };
+        });
 
         if fd < 0 {
             return Err(io::Error::last_os_error());
         }
 
+        // Safe because we just opened this fd.
+        let file = unsafe { File::from_raw_fd(fd) };
+        let mode = libc::S_IFREG | (mode & !umask & 0o7777);
+        self.own_new_entry(parent_fd, name, ctx, mode, 0)?;
+        self.remove_whiteout(parent_fd, name)?;
+        self.sync_dir(parent_fd)?;
+
         let (stat, mnt_id) = Self::statx(fd, None)?;
 
-        let mut path = parent_data.path.clone();
-        path.push(self.intern_name(name)?);
+        let path = parent_data.path.child(self.intern_name(name));
 
         // Create the inode for the newly created file
-        let file = unsafe { File::from_raw_fd(fd) };
         let (inode, _) = self.create_inode(
             file.try_clone()?,
             stat.st_ino,
@@ -1957,6 +4644,97 @@ impl OverlayFs {
             inode: entry.inode,
             file: RwLock::new(file),
             exported: Default::default(),
+            dirty: Default::default(),
+            write_error: Default::default(),
+            sequential: Default::default(),
+            reads: Default::default(),
+            relatime: Default::default(),
+            _fd_grant: fd_grant,
+            _handle_grant: handle_grant,
+            dir_stream: Default::default(),
+        };
+
+        self.handles.write().unwrap().insert(handle, Arc::new(data));
+
+        let mut opts = OpenOptions::empty();
+        match self.config.cache_policy {
+            CachePolicy::Never => opts |= OpenOptions::DIRECT_IO,
+            CachePolicy::Always => opts |= OpenOptions::KEEP_CACHE,
+            _ => {}
+        };
+
+        Ok((entry, Some(handle), opts))
+    }
+
+    fn do_tmpfile(
+        &self,
+        ctx: Context,
+        parent: Inode,
+        mode: u32,
+        flags: u32,
+        umask: u32,
+        extensions: Extensions,
+    ) -> io::Result<(Entry, Option<Handle>, OpenOptions)> {
+        if extensions.secctx.is_some() {
+            // SECURITY_CTX is never negotiated on Linux hosts
+            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+        }
+
+        // Set the credentials for the operation
+        let (_uid, _gid) = self.set_scoped_credentials(ctx.uid, ctx.gid)?;
+
+        // Ensure parent directory is in the top layer
+        let parent_data = self.get_inode_data(parent)?;
+        let parent_data = self.ensure_top_layer(parent_data)?;
+
+        let handle_grant = self.acquire_handle()?;
+        let fd_grant = self.acquire_fd()?;
+
+        let file = open_tmpfile(
+            parent_data.file.as_raw_fd(),
+            flags,
+            self.host_mode(mode & !(umask & 0o777)),
+        )?;
+        let mode = libc::S_IFREG | (mode & !umask & 0o7777);
+        if self.virtual_ownership {
+            let value = StatOverride {
+                uid: ctx.uid,
+                gid: ctx.gid,
+                mode,
+                rdev: 0,
+            };
+            stat_override::write(file.as_raw_fd(), None, &value)?;
+        }
+
+        let (stat, mnt_id) = Self::statx(file.as_raw_fd(), None)?;
+
+        // The file gets its real path once it is linked, see `do_link`
+        let path = parent_data.path.child(self.intern_name(TMPFILE_NAME));
+
+        let (inode, _) = self.create_inode(
+            file.try_clone()?,
+            stat.st_ino,
+            stat.st_dev,
+            mnt_id,
+            path,
+            parent_data.layer_idx,
+        );
+
+        let entry = self.create_entry(inode, stat);
+
+        let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        let data = HandleData {
+            inode: entry.inode,
+            file: RwLock::new(file),
+            exported: Default::default(),
+            dirty: Default::default(),
+            write_error: Default::default(),
+            sequential: Default::default(),
+            reads: Default::default(),
+            relatime: Default::default(),
+            _fd_grant: fd_grant,
+            _handle_grant: handle_grant,
+            dir_stream: Default::default(),
         };
 
         self.handles.write().unwrap().insert(handle, Arc::new(data));
@@ -1972,8 +4750,19 @@ impl OverlayFs {
     }
 
     fn do_getattr(&self, inode: Inode) -> io::Result<(libc::stat64, Duration)> {
-        let fd = self.get_inode_data(inode)?.file.as_raw_fd();
-        let (st, _) = Self::statx(fd, None)?;
+        let inode_data = self.get_inode_data(inode)?;
+        let fd = inode_data.file.as_raw_fd();
+        let stat = || Self::statx(fd, None).map(|(st, _)| st);
+        let mut st = match &self.config.lower_layers {
+            Some(set) if inode_data.layer_idx < self.get_top_layer_idx() => {
+                set.attr(inode_data.dev, inode_data.ino, stat)?
+            }
+            _ => stat()?,
+        };
+        self.patch_dir_nlink(inode, &mut st);
+        self.patch_dev(&mut st);
+        self.patch_blocks(inode, &mut st);
+        self.patch_owner(fd, &mut st);
 
         Ok((st, self.config.attr_timeout))
     }
@@ -1986,14 +4775,42 @@ impl OverlayFs {
         new_name: &CStr,
         flags: u32,
     ) -> io::Result<()> {
-        // Copy up the old path to the top layer if not already in the top layer
+        let _guard = self.entry_locks.lock_all(vec![
+            (old_parent, old_name.to_bytes().to_vec()),
+            (new_parent, new_name.to_bytes().to_vec()),
+        ]);
+
+        // Copy up the old path to the top layer if not already in the top layer. A directory takes
+        // its entries from the lower layers along with it.
         let (_, old_path_inodes) = self.do_lookup(old_parent, old_name)?;
-        self.copy_up(&old_path_inodes)?;
+        self.copy_up_tree(&old_path_inodes)?;
         let old_parent_data = self.get_inode_data(old_parent)?;
 
+        // Keep the old entry from being copied up again until it is whited out
+        let old_path = old_parent_data.path.child(self.intern_name(old_name));
+        let _copy_up_guard = self.copy_up_locks.lock(old_path.names());
+
         // Copy up the new parent to the top layer if not already in the top layer
         let new_parent_data = self.ensure_top_layer(self.get_inode_data(new_parent)?)?;
 
+        // A file replaced by the rename gives its space back, and its inode number may be reused
+        let (freed, replaced) = if flags & libc::RENAME_EXCHANGE == 0 {
+            let new_parent_fd = new_parent_data.file.as_raw_fd();
+            (
+                self.upper_space_freed_by_unlink(new_parent_fd, Some(new_name)),
+                Self::statx(new_parent_fd, Some(new_name)).ok(),
+            )
+        } else {
+            (0, None)
+        };
+
+        // The old entry leaves the top layer before it is whited out
+        let _intent_guard = if flags & libc::RENAME_EXCHANGE == 0 {
+            self.begin_intent(&old_path.names(), Intent::Whiteout)?
+        } else {
+            None
+        };
+
         // Perform the rename
         let res = unsafe {
             libc::renameat2(
@@ -2009,12 +4826,84 @@ impl OverlayFs {
             return Err(io::Error::last_os_error());
         }
 
-        // After successful rename, check if we need to add a whiteout for the old path
-        self.create_whiteout_for_lower(old_parent, old_name)?;
+        self.rename_inode_paths(
+            (&old_parent_data.path, self.intern_name(old_name)),
+            (&new_parent_data.path, self.intern_name(new_name)),
+            flags & libc::RENAME_EXCHANGE != 0,
+        );
+
+        self.charge_upper_space(freed, 0)?;
+        self.remove_whiteout(new_parent_data.file.as_raw_fd(), new_name)?;
+
+        if let Some((st, mnt_id)) = replaced {
+            let key = InodeAltKey::new(st.st_ino, st.st_dev, mnt_id);
+            let moved = Self::statx(new_parent_data.file.as_raw_fd(), Some(new_name))?;
+            let is_dir = st.st_mode & libc::S_IFMT == libc::S_IFDIR;
+            if key != InodeAltKey::new(moved.0.st_ino, moved.0.st_dev, moved.1)
+                && (is_dir || st.st_nlink <= 1)
+            {
+                self.retire_generation(key);
+            }
+        }
+
+        // After successful rename, check if we need to add a whiteout for the old path, unless
+        // the entries were exchanged or the entry was renamed to itself
+        if flags & libc::RENAME_WHITEOUT != 0
+            || Self::statx(old_parent_data.file.as_raw_fd(), Some(old_name)).is_err()
+        {
+            self.create_whiteout_for_lower(old_parent, old_name)?;
+        }
+
+        self.sync_inode_dir(old_parent)?;
+        if new_parent != old_parent {
+            self.sync_inode_dir(new_parent)?;
+        }
 
         Ok(())
     }
 
+    /// Moves the top layer inodes at the `old` entry, given as its parent path and name, to the
+    /// `new` one after a rename, so that their entries are looked up at their new location. The
+    /// inodes below them follow along. When `exchange` is set, the inodes at the `new` entry move
+    /// to the `old` one as well.
+    fn rename_inode_paths(
+        &self,
+        old: (&Arc<InodePath>, Name),
+        new: (&Arc<InodePath>, Name),
+        exchange: bool,
+    ) {
+        let entry_path = |(parent, name): &(&Arc<InodePath>, Name)| {
+            let mut path = parent.names();
+            path.push(name.clone());
+            path
+        };
+        let old_path = entry_path(&old);
+        let new_path = entry_path(&new);
+
+        // Find all the inodes to move before moving any, as moving one may change the path of
+        // another when exchanging
+        let top_layer_idx = self.get_top_layer_idx();
+        let mut moves = Vec::new();
+        for (_, data) in self.inodes.read().unwrap().main.values() {
+            if data.layer_idx != top_layer_idx {
+                continue;
+            }
+
+            let Some(name) = data.path.name() else {
+                continue;
+            };
+            if name == old.1 && data.path.names() == old_path {
+                moves.push((data.path.clone(), &new));
+            } else if exchange && name == new.1 && data.path.names() == new_path {
+                moves.push((data.path.clone(), &old));
+            }
+        }
+
+        for (path, (parent, name)) in moves {
+            path.rename(parent, name.clone());
+        }
+    }
+
     fn do_mknod(
         &self,
         ctx: Context,
@@ -2026,9 +4915,12 @@ impl OverlayFs {
         extensions: Extensions,
     ) -> io::Result<Entry> {
         if extensions.secctx.is_some() {
-            unimplemented!("SECURITY_CTX is not supported and should not be used by the guest");
+            // SECURITY_CTX is never negotiated on Linux hosts
+            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
 
+        let _guard = self.lock_entry(parent, name);
+
         // Set the credentials for the operation
         let (_uid, _gid) = self.set_scoped_credentials(ctx.uid, ctx.gid)?;
 
@@ -2053,22 +4945,46 @@ impl OverlayFs {
         // Get the parent file descriptor
         let parent_fd = parent_data.file.as_raw_fd();
 
-        // Create the node device
-        let res = unsafe {
-            libc::mknodat(
-                parent_fd,
-                name.as_ptr(),
-                (mode & !umask) as libc::mode_t,
-                u64::from(rdev),
-            )
+        // Create the node device, or an empty file standing for it if the ownership is virtual
+        let mode = mode & !umask;
+        let file_type = mode & libc::S_IFMT;
+        let res = if self.virtual_ownership && stat_override::is_device(file_type) {
+            let fd = retry_syscall(|| unsafe {
+                libc::openat(
+                    parent_fd,
+                    name.as_ptr(),
+                    libc::O_WRONLY
+                        | libc::O_CREAT
+                        | libc::O_EXCL
+                        | libc::O_CLOEXEC
+                        | libc::O_NOFOLLOW,
+                    self.host_mode(mode),
+                )
+            });
+            if fd >= 0 {
+                // Safe because we just opened this fd.
+                unsafe { libc::close(fd) };
+            }
+            fd.min(0)
+        } else {
+            unsafe {
+                libc::mknodat(
+                    parent_fd,
+                    name.as_ptr(),
+                    file_type | self.host_mode(mode),
+                    u64::from(rdev),
+                )
+            }
         };
 
         if res == 0 {
+            self.own_new_entry(parent_fd, name, ctx, mode, u64::from(rdev))?;
+            self.remove_whiteout(parent_fd, name)?;
+            self.sync_dir(parent_fd)?;
             let file = Self::open_path_file_at(parent_fd, name)?;
             let (stat, mnt_id) = Self::statx(file.as_raw_fd(), None)?;
 
-            let mut path = parent_data.path.clone();
-            path.push(self.intern_name(name)?);
+            let path = parent_data.path.child(self.intern_name(name));
 
             // Create the inode for the newly created directory
             let (inode, _) = self.create_inode(
@@ -2091,6 +5007,8 @@ impl OverlayFs {
     }
 
     fn do_link(&self, inode: Inode, newparent: Inode, newname: &CStr) -> io::Result<Entry> {
+        let _guard = self.lock_entry(newparent, newname);
+
         // Get the fd for the source file.
         let inode_data = self.get_inode_data(inode)?;
 
@@ -2123,11 +5041,20 @@ impl OverlayFs {
         };
 
         if res == 0 {
+            self.remove_whiteout(new_parent_fd, newname)?;
+            self.sync_dir(new_parent_fd)?;
+
+            // An anonymous file of `O_TMPFILE` takes the path of its first link
+            if stat.st_nlink == 0 {
+                inode_data
+                    .path
+                    .rename(&new_parent_data.path, self.intern_name(newname));
+            }
+
             let file = Self::open_path_file_at(new_parent_fd, newname)?;
             let (stat, mnt_id) = Self::statx(file.as_raw_fd(), None)?;
 
-            let mut path = new_parent_data.path.clone();
-            path.push(self.intern_name(newname)?);
+            let path = new_parent_data.path.child(self.intern_name(newname));
 
             // Create the inode for the newly created directory
             let (inode, _) = self.create_inode(
@@ -2158,9 +5085,12 @@ impl OverlayFs {
         extensions: Extensions,
     ) -> io::Result<Entry> {
         if extensions.secctx.is_some() {
-            unimplemented!("SECURITY_CTX is not supported and should not be used by the guest");
+            // SECURITY_CTX is never negotiated on Linux hosts
+            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
 
+        let _guard = self.lock_entry(parent, name);
+
         // Set the credentials for the operation
         let (_uid, _gid) = self.set_scoped_credentials(ctx.uid, ctx.gid)?;
 
@@ -2180,6 +5110,16 @@ impl OverlayFs {
 
         // Ensure parent directory is in the top layer
         let parent_data = self.get_inode_data(parent)?;
+
+        // Refuse links pointing outside of the overlay if the policy says so
+        if self.config.symlink_policy == SymlinkPolicy::Deny
+            && self
+                .confine_symlink_target(parent_data.path.names(), linkname.to_bytes())?
+                .is_some()
+        {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+
         let parent_data = self.ensure_top_layer(parent_data)?;
 
         // Get the parent file descriptor
@@ -2189,11 +5129,12 @@ impl OverlayFs {
         let res = unsafe { libc::symlinkat(linkname.as_ptr(), parent_fd, name.as_ptr()) };
 
         if res == 0 {
+            self.remove_whiteout(parent_fd, name)?;
+            self.sync_dir(parent_fd)?;
             let file = Self::open_path_file_at(parent_fd, name)?;
             let (stat, mnt_id) = Self::statx(file.as_raw_fd(), None)?;
 
-            let mut path = parent_data.path.clone();
-            path.push(self.intern_name(name)?);
+            let path = parent_data.path.child(self.intern_name(name));
 
             // Create the inode for the newly created directory
             let (inode, _) = self.create_inode(
@@ -2218,26 +5159,21 @@ impl OverlayFs {
     fn do_readlink(&self, inode: Inode) -> io::Result<Vec<u8>> {
         // Get the path for this inode
         let inode_data = self.get_inode_data(inode)?;
+        let mut buf = self.read_symlink(&inode_data)?;
+
+        // Apply the symlink policy to targets that point outside of the overlay
+        if self.config.symlink_policy != SymlinkPolicy::GuestRelative {
+            let mut dir = inode_data.path.names();
+            dir.pop();
+            if let Some(confined) = self.confine_symlink_target(dir, &buf)? {
+                if self.config.symlink_policy == SymlinkPolicy::Deny {
+                    return Err(io::Error::from_raw_os_error(libc::EPERM));
+                }
 
-        // Allocate a buffer for the link target
-        let mut buf = vec![0; libc::PATH_MAX as usize];
-
-        // Safe because this will only modify the contents of `buf` and we check the return value.
-        let res = unsafe {
-            libc::readlinkat(
-                inode_data.file.as_raw_fd(),
-                EMPTY_CSTR.as_ptr(),
-                buf.as_mut_ptr() as *mut libc::c_char,
-                buf.len(),
-            )
-        };
-
-        if res < 0 {
-            return Err(io::Error::last_os_error());
+                buf = confined;
+            }
         }
 
-        // Resize the buffer to the actual length of the link target
-        buf.resize(res as usize, 0);
         Ok(buf)
     }
 
@@ -2247,6 +5183,14 @@ impl OverlayFs {
             return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
 
+        // The attributes of Linux's OverlayFS are private to the overlay
+        if self.is_overlay_xattr(name) {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
+        if self.is_override_xattr(name) {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+
         // Get the inode data
         let inode_data = self.get_inode_data(inode)?;
 
@@ -2302,6 +5246,14 @@ impl OverlayFs {
             return Err(io::Error::from_raw_os_error(libc::ENODATA));
         }
 
+        // The attributes of Linux's OverlayFS are private to the overlay
+        if self.is_overlay_xattr(name) {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
+        if self.is_override_xattr(name) {
+            return Err(io::Error::from_raw_os_error(libc::ENODATA));
+        }
+
         // Safe because this will only modify the contents of `buf`
         let mut buf = vec![0; size as usize];
 
@@ -2357,9 +5309,43 @@ impl OverlayFs {
             return Err(io::Error::from_raw_os_error(libc::ENODATA));
         }
 
+        // The names of the attributes of Linux's OverlayFS and of the override are hidden, so
+        // they're all listed to be filtered out
+        if self.config.overlay_xattrs.is_some() || self.virtual_ownership {
+            let mut names = vec![0; self.list_xattrs(inode, &mut [])?];
+            let len = self.list_xattrs(inode, &mut names)?;
+            names.truncate(len);
+            if let Some(xattrs) = self.config.overlay_xattrs {
+                overlay_xattrs::filter_names(&mut names, xattrs.prefix().as_bytes());
+            }
+            if self.virtual_ownership {
+                overlay_xattrs::filter_names(&mut names, OVERRIDE_XATTR.to_bytes_with_nul());
+            }
+            return if size == 0 {
+                Ok(ListxattrReply::Count(names.len() as u32))
+            } else if names.len() > size as usize {
+                Err(io::Error::from_raw_os_error(libc::ERANGE))
+            } else {
+                Ok(ListxattrReply::Names(names))
+            };
+        }
+
         // Safe because this will only modify the contents of `buf`
         let mut buf = vec![0; size as usize];
+        let len = self.list_xattrs(inode, &mut buf)?;
+
+        if size == 0 {
+            Ok(ListxattrReply::Count(len as u32))
+        } else {
+            // Truncate the buffer to the actual length of the value
+            buf.resize(len, 0);
+            Ok(ListxattrReply::Names(buf))
+        }
+    }
 
+    /// Lists the names of the attributes of `inode` in `buf`, returning their length. An empty
+    /// `buf` only queries the length.
+    fn list_xattrs(&self, inode: Inode, buf: &mut [u8]) -> io::Result<usize> {
         // The f{set,get,remove,list}xattr functions don't work on an fd opened with `O_PATH` so we
         // need to get a new fd. This doesn't work for symlinks, so we use the l* family of
         // functions in that case.
@@ -2370,7 +5356,7 @@ impl OverlayFs {
                     libc::flistxattr(
                         file.as_raw_fd(),
                         buf.as_mut_ptr() as *mut libc::c_char,
-                        size as libc::size_t,
+                        buf.len(),
                     )
                 }
             }
@@ -2380,7 +5366,7 @@ impl OverlayFs {
                     libc::llistxattr(
                         path.as_ptr(),
                         buf.as_mut_ptr() as *mut libc::c_char,
-                        size as libc::size_t,
+                        buf.len(),
                     )
                 }
             }
@@ -2390,13 +5376,24 @@ impl OverlayFs {
             return Err(io::Error::last_os_error());
         }
 
-        if size == 0 {
-            Ok(ListxattrReply::Count(res as u32))
-        } else {
-            // Truncate the buffer to the actual length of the value
-            buf.resize(res as usize, 0);
-            Ok(ListxattrReply::Names(buf))
-        }
+        Ok(res as usize)
+    }
+
+    /// Whether `name` is in the namespace of the attributes of Linux's OverlayFS, when the overlay
+    /// records them.
+    fn is_overlay_xattr(&self, name: &CStr) -> bool {
+        self.config
+            .overlay_xattrs
+            .is_some_and(|xattrs| name.to_bytes().starts_with(xattrs.prefix().as_bytes()))
+    }
+
+    /// Whether `name` is one of the attributes the overlay keeps for itself in the top layer.
+    fn is_internal_xattr(&self, name: &[u8]) -> bool {
+        name == &COPY_UP_XATTR_KEY[..COPY_UP_XATTR_KEY.len() - 1]
+            || self
+                .config
+                .overlay_xattrs
+                .is_some_and(|xattrs| name.starts_with(xattrs.prefix().as_bytes()))
     }
 
     fn do_removexattr(&self, inode: Inode, name: &CStr) -> io::Result<()> {
@@ -2405,6 +5402,14 @@ impl OverlayFs {
             return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
 
+        // The attributes of Linux's OverlayFS are private to the overlay
+        if self.is_overlay_xattr(name) {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
+        if self.is_override_xattr(name) {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+
         // Get the inode data
         let inode_data = self.get_inode_data(inode)?;
 
@@ -2444,37 +5449,35 @@ impl OverlayFs {
         let data = self.get_inode_handle_data(inode, handle)?;
         let fd = data.file.write().unwrap().as_raw_fd();
 
-        // Safe because this doesn't modify any memory and we check the return value.
-        let res = unsafe {
-            libc::fallocate64(
-                fd,
-                mode as libc::c_int,
-                offset as libc::off64_t,
-                length as libc::off64_t,
-            )
-        };
+        self.with_upper_space(fd, offset.saturating_add(length), || {
+            // Safe because this doesn't modify any memory and we check the return value.
+            let res = unsafe {
+                libc::fallocate64(
+                    fd,
+                    mode as libc::c_int,
+                    offset as libc::off64_t,
+                    length as libc::off64_t,
+                )
+            };
 
-        if res < 0 {
-            return Err(io::Error::last_os_error());
-        }
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
 
-        Ok(())
+            Ok(())
+        })
     }
 
     fn do_lseek(&self, inode: Inode, handle: Handle, offset: u64, whence: u32) -> io::Result<u64> {
         let data = self.get_inode_handle_data(inode, handle)?;
-        let fd = data.file.write().unwrap().as_raw_fd();
-
-        // Safe because this doesn't modify any memory and we check the return value.
-        let res = unsafe { libc::lseek64(fd, offset as libc::off64_t, whence as libc::c_int) };
-
-        if res < 0 {
-            return Err(io::Error::last_os_error());
-        }
-
-        Ok(res as u64)
+        let f = data.file.read().unwrap();
+        guest_seek(&f, offset, whence)
     }
 
+    /// Copies a range of a file to another. The destination was copied up to the top layer when it
+    /// was opened for writing, while the source is read from the layer it was opened in, without
+    /// being copied up.
+    #[allow(clippy::too_many_arguments)]
     fn do_copyfilerange(
         &self,
         inode_in: Inode,
@@ -2488,26 +5491,32 @@ impl OverlayFs {
     ) -> io::Result<usize> {
         let data_in = self.get_inode_handle_data(inode_in, handle_in)?;
         let data_out = self.get_inode_handle_data(inode_out, handle_out)?;
-        let fd_in = data_in.file.write().unwrap().as_raw_fd();
-        let fd_out = data_out.file.write().unwrap().as_raw_fd();
+        let file_in = data_in.file.read().unwrap();
+        let file_out = data_out.file.read().unwrap();
+        let (fd_in, fd_out) = (file_in.as_raw_fd(), file_out.as_raw_fd());
 
-        // Safe because this doesn't modify any memory and we check the return value.
-        let res = unsafe {
-            libc::copy_file_range(
-                fd_in,
-                &mut (offset_in as i64) as &mut _ as *mut _,
-                fd_out,
-                &mut (offset_out as i64) as &mut _ as *mut _,
-                len.try_into().unwrap(),
-                flags.try_into().unwrap(),
-            )
-        };
+        let copied = self.with_upper_space(fd_out, offset_out.saturating_add(len), || {
+            // Safe because this doesn't modify any memory and we check the return value.
+            let res = unsafe {
+                libc::copy_file_range(
+                    fd_in,
+                    &mut (offset_in as i64) as &mut _ as *mut _,
+                    fd_out,
+                    &mut (offset_out as i64) as &mut _ as *mut _,
+                    len.try_into().unwrap(),
+                    flags.try_into().unwrap(),
+                )
+            };
 
-        if res < 0 {
-            return Err(io::Error::last_os_error());
-        }
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
 
-        Ok(res as usize)
+            Ok(res as usize)
+        })?;
+        data_out.dirty.store(true, Ordering::Release);
+
+        Ok(copied)
     }
 
     fn do_setupmapping(
@@ -2553,6 +5562,7 @@ impl OverlayFs {
             if ret == libc::MAP_FAILED {
                 return Err(io::Error::last_os_error());
             }
+            dax::forget_mappings(addr as *mut libc::c_void, len as usize);
 
             let to_copy = if len as usize > INIT_BINARY.len() {
                 INIT_BINARY.len()
@@ -2576,22 +5586,13 @@ impl OverlayFs {
         let inode_data = self.ensure_top_layer(inode_data)?;
 
         let file = self.open_inode(inode_data.inode, open_flags)?;
-        let fd = file.as_raw_fd();
-
-        let ret = unsafe {
-            libc::mmap(
-                addr as *mut libc::c_void,
-                len as usize,
-                prot_flags,
-                libc::MAP_SHARED | libc::MAP_FIXED,
-                fd,
-                foffset as libc::off_t,
-            )
-        };
-
-        if ret == libc::MAP_FAILED {
-            return Err(io::Error::last_os_error());
-        }
+        dax::map_file(
+            addr as *mut libc::c_void,
+            len as usize,
+            prot_flags,
+            &file,
+            foffset,
+        )?;
 
         Ok(())
     }
@@ -2621,17 +5622,21 @@ impl OverlayFs {
             if ret == libc::MAP_FAILED {
                 return Err(io::Error::last_os_error());
             }
+            dax::forget_mappings(addr as *mut libc::c_void, req.len as usize);
         }
 
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn do_ioctl(
         &self,
+        ctx: Context,
         inode: Inode,
         handle: Handle,
         cmd: u32,
         arg: u64,
+        data: &[u8],
         out_size: u32,
         exit_code: &Arc<AtomicI32>,
     ) -> io::Result<Vec<u8>> {
@@ -2649,6 +5654,22 @@ impl OverlayFs {
         const VIRTIO_IOC_EXIT_CODE_REQ: u32 =
             request_code_none!(VIRTIO_IOC_MAGIC, VIRTIO_IOC_TYPE_EXIT_CODE) as u32;
 
+        const VIRTIO_IOC_TYPE_REMOVE_TREE: u8 = 3;
+        const VIRTIO_IOC_REMOVE_TREE_SIZE: usize = 3 * mem::size_of::<u64>();
+        const VIRTIO_IOC_REMOVE_TREE_REQ: u32 = request_code_read!(
+            VIRTIO_IOC_MAGIC,
+            VIRTIO_IOC_TYPE_REMOVE_TREE,
+            VIRTIO_IOC_REMOVE_TREE_SIZE
+        ) as u32;
+
+        const VIRTIO_IOC_TYPE_LAYER_STATS: u8 = 6;
+        const VIRTIO_IOC_LAYER_STATS_SIZE: usize = 2 * mem::size_of::<u64>();
+        const VIRTIO_IOC_LAYER_STATS_REQ: u32 = request_code_read!(
+            VIRTIO_IOC_MAGIC,
+            VIRTIO_IOC_TYPE_LAYER_STATS,
+            VIRTIO_IOC_LAYER_STATS_SIZE
+        ) as u32;
+
         match cmd {
             VIRTIO_IOC_EXPORT_FD_REQ => {
                 if out_size as usize != VIRTIO_IOC_EXPORT_FD_SIZE {
@@ -2683,6 +5704,78 @@ impl OverlayFs {
                 exit_code.store(arg as i32, Ordering::SeqCst);
                 Ok(Vec::new())
             }
+            VIRTIO_IOC_REMOVE_TREE_REQ => {
+                if out_size as usize != VIRTIO_IOC_REMOVE_TREE_SIZE {
+                    return Err(io::Error::from_raw_os_error(libc::EINVAL));
+                }
+                self.check_writable()?;
+
+                let progress = self.remove_tree(ctx, inode, arg)?;
+                let mut ret: Vec<_> = progress.removed.to_ne_bytes().into();
+                ret.extend_from_slice(&progress.errors.to_ne_bytes());
+                ret.extend_from_slice(&(progress.done as u64).to_ne_bytes());
+                Ok(ret)
+            }
+            VIRTIO_IOC_LAYER_STATS_REQ => {
+                if out_size as usize != VIRTIO_IOC_LAYER_STATS_SIZE {
+                    return Err(io::Error::from_raw_os_error(libc::EINVAL));
+                }
+
+                // Read before taking the locks, which `stats` takes too
+                let snapshot = layer_stats::snapshot(&self.stats())?;
+
+                let mut exports = self
+                    .config
+                    .export_table
+                    .as_ref()
+                    .ok_or(io::Error::from_raw_os_error(libc::EOPNOTSUPP))?
+                    .lock()
+                    .unwrap();
+
+                let handles = self.handles.read().unwrap();
+                let data = handles
+                    .get(&handle)
+                    .filter(|hd| hd.inode == inode)
+                    .ok_or_else(ebadf)?;
+
+                data.exported.store(true, Ordering::Relaxed);
+
+                exports.insert((self.config.export_fsid, handle), snapshot);
+
+                let mut ret: Vec<_> = self.config.export_fsid.to_ne_bytes().into();
+                ret.extend_from_slice(&handle.to_ne_bytes());
+                Ok(ret)
+            }
+            cmd if batch_create::is_batch_create(cmd) => {
+                self.check_writable()?;
+                let reply = self.batch_create(ctx, inode, data)?;
+                if (out_size as usize) < reply.len() {
+                    return Err(io::Error::from_raw_os_error(libc::EINVAL));
+                }
+                Ok(reply)
+            }
+            bindings::LINUX_FS_IOC_GETFLAGS => {
+                let file = self.open_inode_for_flags(inode)?;
+                Ok(get_file_flags(&file)?.to_ne_bytes().to_vec())
+            }
+            bindings::LINUX_FS_IOC_SETFLAGS => {
+                if !self.config.allow_file_flags {
+                    return Err(io::Error::from_raw_os_error(libc::EPERM));
+                }
+                self.check_writable()?;
+
+                let flags = data
+                    .get(..mem::size_of::<u32>())
+                    .and_then(|b| b.try_into().ok())
+                    .map(u32::from_ne_bytes)
+                    .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+
+                // The flags are only set on the top layer copy of the file
+                self.ensure_top_layer(self.get_inode_data(inode)?)?;
+                let file = self.open_inode_for_flags(inode)?;
+                set_file_flags(&file, flags)?;
+                Ok(Vec::new())
+            }
             _ => Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP)),
         }
     }
@@ -2702,6 +5795,65 @@ fn einval() -> io::Error {
     io::Error::from_raw_os_error(libc::EINVAL)
 }
 
+/// Rewrites the prefix of the absolute symlink `target` according to `map`, a list of `(from, to)`
+/// prefixes. Returns `None` if no prefix matches.
+fn remap_symlink_target(map: &[(PathBuf, PathBuf)], target: &[u8]) -> Option<Vec<u8>> {
+    fn trim(prefix: &Path) -> &[u8] {
+        let prefix = prefix.as_os_str().as_bytes();
+        let len = prefix
+            .iter()
+            .rposition(|&b| b != b'/')
+            .map_or(0, |pos| pos + 1);
+        &prefix[..len]
+    }
+
+    if !target.starts_with(b"/") {
+        return None;
+    }
+
+    let (from, to) = map
+        .iter()
+        .map(|(from, to)| (trim(from), trim(to)))
+        .filter(|(from, _)| {
+            target.starts_with(from) && (target.len() == from.len() || target[from.len()] == b'/')
+        })
+        .max_by_key(|(from, _)| from.len())?;
+
+    let mut remapped = to.to_vec();
+    remapped.extend_from_slice(&target[from.len()..]);
+    if remapped.is_empty() {
+        remapped.push(b'/');
+    }
+
+    Some(remapped)
+}
+
+/// Returns the path of the whiteout hiding `path`.
+fn whiteout_path(path: &[u8]) -> Vec<u8> {
+    let name_start = path
+        .iter()
+        .rposition(|&b| b == b'/')
+        .map_or(0, |pos| pos + 1);
+    let mut whiteout = path[..name_start].to_vec();
+    whiteout.extend_from_slice(WHITEOUT_PREFIX.as_bytes());
+    whiteout.extend_from_slice(&path[name_start..]);
+    whiteout
+}
+
+/// Whether `st` is the one of a whiteout as the kernel overlay writes them, a character device
+/// numbered 0.
+fn is_whiteout_device(st: &libc::stat64) -> bool {
+    st.st_mode & libc::S_IFMT == libc::S_IFCHR && st.st_rdev == 0
+}
+
+/// Whether `name` is a file the overlay keeps for its own purposes in the top layer.
+fn is_internal_name(name: &[u8]) -> bool {
+    name.starts_with(COPY_UP_STAGING_PREFIX.as_bytes())
+        || name == INTENT_LOG_FILE.as_bytes()
+        || name == TMPFILE_NAME.to_bytes()
+        || inode_map::is_map_name(name)
+}
+
 //--------------------------------------------------------------------------------------------------
 // Trait Implementations
 //--------------------------------------------------------------------------------------------------
@@ -2715,7 +5867,8 @@ impl FileSystem for OverlayFs {
         unsafe { libc::umask(0o000) };
 
         // Enable readdirplus if supported
-        let mut opts = FsOptions::DO_READDIRPLUS | FsOptions::READDIRPLUS_AUTO;
+        let mut opts =
+            FsOptions::DO_READDIRPLUS | FsOptions::READDIRPLUS_AUTO | FsOptions::POSIX_LOCKS;
 
         // Enable writeback caching if requested and supported
         if self.config.writeback && capable.contains(FsOptions::WRITEBACK_CACHE) {
@@ -2768,6 +5921,7 @@ impl FileSystem for OverlayFs {
             st.st_size = INIT_BINARY.len() as i64;
             st.st_ino = self.init_inode;
             st.st_mode = 0o100_755;
+            self.patch_dev(&mut st);
 
             return Ok(Entry {
                 inode: self.init_inode,
@@ -2779,8 +5933,14 @@ impl FileSystem for OverlayFs {
             });
         }
 
-        let (entry, _) = self.do_lookup(parent, name)?;
+        let (entry, path_inodes) = self.do_lookup(parent, name)?;
+        if let Some(data) = path_inodes.last() {
+            self.layer_stats.lookup(data.layer_idx);
+        }
         self.bump_refcount(entry.inode);
+        if entry.attr.st_mode & libc::S_IFMT == libc::S_IFDIR {
+            self.warm_dentries(entry.inode);
+        }
         Ok(entry)
     }
 
@@ -2794,7 +5954,9 @@ impl FileSystem for OverlayFs {
         inode: Inode,
         flags: u32,
     ) -> io::Result<(Option<Handle>, OpenOptions)> {
-        self.do_open(inode, flags | (libc::O_DIRECTORY as u32))
+        let res = self.do_open(inode, flags | (libc::O_DIRECTORY as u32))?;
+        self.warm_dentries(inode);
+        Ok(res)
     }
 
     fn releasedir(
@@ -2816,6 +5978,7 @@ impl FileSystem for OverlayFs {
         umask: u32,
         extensions: Extensions,
     ) -> io::Result<Entry> {
+        self.check_writable()?;
         Self::validate_name(name)?;
         let entry = self.do_mkdir(ctx, parent, name, mode, umask, extensions)?;
         self.bump_refcount(entry.inode);
@@ -2823,14 +5986,15 @@ impl FileSystem for OverlayFs {
     }
 
     fn rmdir(&self, _ctx: Context, parent: Inode, name: &CStr) -> io::Result<()> {
-        self.do_unlink(parent, name, libc::AT_REMOVEDIR)
+        self.check_writable()?;
+        self.do_unlink(parent, &self.entry_name(parent, name)?, libc::AT_REMOVEDIR)
     }
 
     fn readdir<F>(
         &self,
         _ctx: Context,
         inode: Inode,
-        _handle: Handle,
+        handle: Handle,
         size: u32,
         offset: u64,
         add_entry: F,
@@ -2838,7 +6002,7 @@ impl FileSystem for OverlayFs {
     where
         F: FnMut(filesystem::DirEntry<'_>) -> io::Result<usize>,
     {
-        self.do_readdir(inode, size, offset, add_entry)
+        self.do_readdir(inode, handle, size, offset, add_entry)
     }
 
     fn readdirplus<F>(
@@ -2853,8 +6017,7 @@ impl FileSystem for OverlayFs {
     where
         F: FnMut(filesystem::DirEntry<'_>, Entry) -> io::Result<usize>,
     {
-        let _ = self.get_inode_handle_data(inode, handle)?;
-        self.do_readdir(inode, size, offset, |dir_entry| {
+        self.do_readdir(inode, handle, size, offset, |dir_entry| {
             let (entry, _) = self.do_lookup(inode, &CString::new(dir_entry.name).unwrap())?;
             add_entry(dir_entry, entry)
         })
@@ -2879,10 +6042,13 @@ impl FileSystem for OverlayFs {
         inode: Inode,
         _flags: u32,
         handle: Handle,
-        _flush: bool,
+        flush: bool,
         _flock_release: bool,
-        _lock_owner: Option<u64>,
+        lock_owner: Option<u64>,
     ) -> io::Result<()> {
+        if let Some(owner) = lock_owner.filter(|_| flush) {
+            self.posix_locks.release(inode, owner);
+        }
         self.do_release(inode, handle)
     }
 
@@ -2896,6 +6062,7 @@ impl FileSystem for OverlayFs {
         umask: u32,
         extensions: Extensions,
     ) -> io::Result<(Entry, Option<Handle>, OpenOptions)> {
+        self.check_writable()?;
         Self::validate_name(name)?;
         let (entry, handle, opts) =
             self.do_create(ctx, parent, name, mode, flags, umask, extensions)?;
@@ -2903,8 +6070,24 @@ impl FileSystem for OverlayFs {
         Ok((entry, handle, opts))
     }
 
+    fn tmpfile(
+        &self,
+        ctx: Context,
+        parent: Inode,
+        mode: u32,
+        flags: u32,
+        umask: u32,
+        extensions: Extensions,
+    ) -> io::Result<(Entry, Option<Handle>, OpenOptions)> {
+        self.check_writable()?;
+        let (entry, handle, opts) = self.do_tmpfile(ctx, parent, mode, flags, umask, extensions)?;
+        self.bump_refcount(entry.inode);
+        Ok((entry, handle, opts))
+    }
+
     fn unlink(&self, _ctx: Context, parent: Inode, name: &CStr) -> io::Result<()> {
-        self.do_unlink(parent, name, 0)
+        self.check_writable()?;
+        self.do_unlink(parent, &self.entry_name(parent, name)?, 0)
     }
 
     fn read<W: io::Write + ZeroCopyWriter>(
@@ -2926,7 +6109,22 @@ impl FileSystem for OverlayFs {
         let data = self.get_inode_handle_data(inode, handle)?;
 
         let f = data.file.read().unwrap();
-        w.write_from(&f, size as usize, offset)
+        let res = write_from_sparse(&mut w, &f, size as usize, offset);
+        if res.is_ok() && data.relatime.swap(false, Ordering::Relaxed) {
+            if let Err(e) = atime::relatime_update(f.as_raw_fd()) {
+                debug!("read: failed to update the access time of {inode}: {e}");
+            }
+        }
+        if let Ok(read) = res {
+            page_cache::advise_read(
+                &data.reads,
+                self.config.page_cache,
+                f.as_raw_fd(),
+                offset,
+                read as u64,
+            );
+        }
+        res
     }
 
     fn write<R: io::Read + ZeroCopyReader>(
@@ -2938,10 +6136,11 @@ impl FileSystem for OverlayFs {
         size: u32,
         offset: u64,
         _lock_owner: Option<u64>,
-        _delayed_write: bool,
+        delayed_write: bool,
         kill_priv: bool,
         _flags: u32,
     ) -> io::Result<usize> {
+        self.check_writable()?;
         if kill_priv {
             // We need to change credentials during a write so that the kernel will remove setuid
             // or setgid bits from the file if it was written to by someone other than the owner.
@@ -2950,7 +6149,24 @@ impl FileSystem for OverlayFs {
 
         let data = self.get_inode_handle_data(inode, handle)?;
         let f = data.file.read().unwrap();
-        r.read_to(&f, size as usize, offset)
+        let res = self.with_upper_space(f.as_raw_fd(), offset.saturating_add(size as u64), || {
+            r.read_to(&f, size as usize, offset)
+        });
+
+        match &res {
+            Ok(written) => {
+                data.dirty.store(true, Ordering::Release);
+
+                // The space of a RAM-backed top layer isn't fragmented, only used up
+                if self.config.upper_layer == UpperLayer::Disk {
+                    data.preallocate_ahead(f.as_raw_fd(), offset, *written as u64);
+                }
+            }
+            Err(e) if delayed_write => data.set_write_error(e),
+            Err(_) => (),
+        }
+
+        res
     }
 
     fn getattr(
@@ -2962,6 +6178,18 @@ impl FileSystem for OverlayFs {
         self.do_getattr(inode)
     }
 
+    fn statx(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        _handle: Option<Handle>,
+    ) -> io::Result<(libc::stat64, Option<BirthTime>, Duration)> {
+        let (st, timeout) = self.do_getattr(inode)?;
+        let btime = get_birth_time(&self.get_inode_data(inode)?.file)?;
+
+        Ok((st, btime, timeout))
+    }
+
     fn setattr(
         &self,
         _ctx: Context,
@@ -2970,9 +6198,22 @@ impl FileSystem for OverlayFs {
         handle: Option<Handle>,
         valid: SetattrValid,
     ) -> io::Result<(libc::stat64, Duration)> {
+        self.check_writable()?;
         // Get the inode data
         let inode_data = self.get_inode_data(inode)?;
 
+        // A file truncated as it is copied up must not keep its content on a crash
+        let _intent_guard = if valid.contains(SetattrValid::SIZE)
+            && inode_data.layer_idx != self.get_top_layer_idx()
+        {
+            let size = attr.st_size as u64;
+            self.begin_intent(&inode_data.path.names(), |path| {
+                Intent::Truncate(path, size)
+            })?
+        } else {
+            None
+        };
+
         // Ensure the file is in the top layer before modifying attributes
         let inode_data = self.ensure_top_layer(inode_data)?;
 
@@ -2990,12 +6231,13 @@ impl FileSystem for OverlayFs {
 
         // Handle mode changes
         if valid.contains(SetattrValid::MODE) {
+            let mode = self.host_mode(attr.st_mode);
             // Safe because this doesn't modify any memory and we check the return value.
             let res = unsafe {
                 match file_id {
-                    FileId::Fd(fd) => libc::fchmod(fd, attr.st_mode),
+                    FileId::Fd(fd) => libc::fchmod(fd, mode),
                     FileId::Path(ref p) => {
-                        libc::fchmodat(self.proc_self_fd.as_raw_fd(), p.as_ptr(), attr.st_mode, 0)
+                        libc::fchmodat(self.proc_self_fd.as_raw_fd(), p.as_ptr(), mode, 0)
                     }
                 }
             };
@@ -3003,10 +6245,29 @@ impl FileSystem for OverlayFs {
             if res < 0 {
                 return Err(io::Error::last_os_error());
             }
+
+            if self.virtual_ownership {
+                let fd = inode_data.file.as_raw_fd();
+                let (st, _) = Self::statx(fd, None)?;
+                stat_override::update(fd, &st, |value| {
+                    value.mode = (value.mode & libc::S_IFMT) | (attr.st_mode & 0o7777)
+                })?;
+            }
         }
 
-        // Handle ownership changes
-        if valid.intersects(SetattrValid::UID | SetattrValid::GID) {
+        // Handle ownership changes, which only change the override if the ownership is virtual
+        if valid.intersects(SetattrValid::UID | SetattrValid::GID) && self.virtual_ownership {
+            let fd = inode_data.file.as_raw_fd();
+            let (st, _) = Self::statx(fd, None)?;
+            stat_override::update(fd, &st, |value| {
+                if valid.contains(SetattrValid::UID) {
+                    value.uid = attr.st_uid;
+                }
+                if valid.contains(SetattrValid::GID) {
+                    value.gid = attr.st_gid;
+                }
+            })?;
+        } else if valid.intersects(SetattrValid::UID | SetattrValid::GID) {
             let uid = if valid.contains(SetattrValid::UID) {
                 attr.st_uid
             } else {
@@ -3039,19 +6300,24 @@ impl FileSystem for OverlayFs {
 
         // Handle size changes
         if valid.contains(SetattrValid::SIZE) {
-            // Safe because this doesn't modify any memory and we check the return value.
-            let res = match file_id {
-                FileId::Fd(fd) => unsafe { libc::ftruncate(fd, attr.st_size) },
-                _ => {
-                    // There is no `ftruncateat` so we need to get a new fd and truncate it.
-                    let f = self.open_inode(inode, libc::O_NONBLOCK | libc::O_RDWR)?;
-                    unsafe { libc::ftruncate(f.as_raw_fd(), attr.st_size) }
+            let fd = inode_data.file.as_raw_fd();
+            self.with_upper_space(fd, attr.st_size as u64, || {
+                // Safe because this doesn't modify any memory and we check the return value.
+                let res = match file_id {
+                    FileId::Fd(fd) => unsafe { libc::ftruncate(fd, attr.st_size) },
+                    _ => {
+                        // There is no `ftruncateat` so we need to get a new fd and truncate it.
+                        let f = self.open_inode(inode, libc::O_NONBLOCK | libc::O_RDWR)?;
+                        unsafe { libc::ftruncate(f.as_raw_fd(), attr.st_size) }
+                    }
+                };
+
+                if res < 0 {
+                    return Err(io::Error::last_os_error());
                 }
-            };
 
-            if res < 0 {
-                return Err(io::Error::last_os_error());
-            }
+                Ok(())
+            })?;
         }
 
         // Handle timestamp changes
@@ -3107,9 +6373,12 @@ impl FileSystem for OverlayFs {
         newname: &CStr,
         flags: u32,
     ) -> io::Result<()> {
+        self.check_writable()?;
         Self::validate_name(oldname)?;
         Self::validate_name(newname)?;
-        self.do_rename(olddir, oldname, newdir, newname, flags)
+        let oldname = self.entry_name(olddir, oldname)?;
+        let newname = self.entry_name(newdir, newname)?;
+        self.do_rename(olddir, &oldname, newdir, &newname, flags)
     }
 
     fn mknod(
@@ -3122,6 +6391,7 @@ impl FileSystem for OverlayFs {
         umask: u32,
         extensions: Extensions,
     ) -> io::Result<Entry> {
+        self.check_writable()?;
         Self::validate_name(name)?;
         let entry = self.do_mknod(ctx, parent, name, mode, rdev, umask, extensions)?;
         self.bump_refcount(entry.inode);
@@ -3135,6 +6405,7 @@ impl FileSystem for OverlayFs {
         newparent: Inode,
         newname: &CStr,
     ) -> io::Result<Entry> {
+        self.check_writable()?;
         Self::validate_name(newname)?;
         let entry = self.do_link(inode, newparent, newname)?;
         self.bump_refcount(entry.inode);
@@ -3149,6 +6420,7 @@ impl FileSystem for OverlayFs {
         name: &CStr,
         extensions: Extensions,
     ) -> io::Result<Entry> {
+        self.check_writable()?;
         Self::validate_name(name)?;
         let entry = self.do_symlink(ctx, linkname, parent, name, extensions)?;
         self.bump_refcount(entry.inode);
@@ -3164,10 +6436,23 @@ impl FileSystem for OverlayFs {
         _ctx: Context,
         inode: Inode,
         handle: Handle,
-        _lock_owner: u64,
+        lock_owner: u64,
     ) -> io::Result<()> {
         let data = self.get_inode_handle_data(inode, handle)?;
 
+        // Closing a file releases the POSIX locks of its owner
+        self.posix_locks.release(inode, lock_owner);
+
+        // With writeback caching the guest may have considered writes complete that were never
+        // synced to the host. Sync them now so that errors surface when the guest closes the file.
+        if self.writeback.load(Ordering::Relaxed) && data.dirty.swap(false, Ordering::AcqRel) {
+            // Safe because this doesn't modify any memory and we check the return value.
+            let res = unsafe { libc::fsync(data.file.read().unwrap().as_raw_fd()) };
+            if res < 0 {
+                data.set_write_error(&io::Error::last_os_error());
+            }
+        }
+
         // Since this method is called whenever an fd is closed in the client, we can emulate that
         // behavior by doing the same thing (dup-ing the fd and then immediately closing it). Safe
         // because this doesn't modify any memory and we check the return values.
@@ -3180,8 +6465,12 @@ impl FileSystem for OverlayFs {
             if libc::close(newfd) < 0 {
                 return Err(io::Error::last_os_error());
             }
+        }
 
-            Ok(())
+        // Report the first delayed write error on this handle, if any, exactly once.
+        match data.take_write_error() {
+            Some(err) => Err(err),
+            None => Ok(()),
         }
     }
 
@@ -3227,6 +6516,10 @@ impl FileSystem for OverlayFs {
             return Ok(());
         }
 
+        if mode & libc::W_OK != 0 {
+            self.check_writable()?;
+        }
+
         if (mode & libc::R_OK) != 0
             && ctx.uid != 0
             && (st.st_uid != ctx.uid || st.st_mode & 0o400 == 0)
@@ -3267,6 +6560,7 @@ impl FileSystem for OverlayFs {
         value: &[u8],
         flags: u32,
     ) -> io::Result<()> {
+        self.check_writable()?;
         self.do_setxattr(inode, name, value, flags)
     }
 
@@ -3285,6 +6579,7 @@ impl FileSystem for OverlayFs {
     }
 
     fn removexattr(&self, _ctx: Context, inode: Inode, name: &CStr) -> io::Result<()> {
+        self.check_writable()?;
         self.do_removexattr(inode, name)
     }
 
@@ -3297,6 +6592,7 @@ impl FileSystem for OverlayFs {
         offset: u64,
         length: u64,
     ) -> io::Result<()> {
+        self.check_writable()?;
         self.do_fallocate(inode, handle, mode, offset, length)
     }
 
@@ -3323,6 +6619,7 @@ impl FileSystem for OverlayFs {
         len: u64,
         flags: u64,
     ) -> io::Result<usize> {
+        self.check_writable()?;
         self.do_copyfilerange(
             inode_in, handle_in, offset_in, inode_out, handle_out, offset_out, len, flags,
         )
@@ -3340,6 +6637,9 @@ impl FileSystem for OverlayFs {
         host_shm_base: u64,
         shm_size: u64,
     ) -> io::Result<()> {
+        if flags & fuse::SetupmappingFlags::WRITE.bits() != 0 {
+            self.check_writable()?;
+        }
         self.do_setupmapping(inode, foffset, len, flags, moffset, host_shm_base, shm_size)
     }
 
@@ -3353,19 +6653,61 @@ impl FileSystem for OverlayFs {
         self.do_removemapping(requests, host_shm_base, shm_size)
     }
 
-    fn ioctl(
+    fn getlk(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: FileLock,
+        _flags: u32,
+    ) -> io::Result<FileLock> {
+        let data = self.get_inode_handle_data(inode, handle)?;
+        let file = data.file.read().unwrap();
+        self.posix_locks.get(inode, &file, owner, &lock)
+    }
+
+    fn setlk(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: FileLock,
+        _flags: u32,
+    ) -> io::Result<()> {
+        let data = self.get_inode_handle_data(inode, handle)?;
+        let file = data.file.read().unwrap();
+        self.posix_locks.set(inode, &file, owner, &lock)
+    }
+
+    fn setlkw(
         &self,
         _ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: FileLock,
+        _flags: u32,
+    ) -> io::Result<()> {
+        let data = self.get_inode_handle_data(inode, handle)?;
+        let file = data.file.read().unwrap();
+        self.posix_locks.set(inode, &file, owner, &lock)
+    }
+
+    fn ioctl(
+        &self,
+        ctx: Context,
         inode: Self::Inode,
         handle: Self::Handle,
         _flags: u32,
         cmd: u32,
         arg: u64,
-        _in_size: u32,
+        data: &[u8],
         out_size: u32,
         exit_code: &Arc<AtomicI32>,
     ) -> io::Result<Vec<u8>> {
-        self.do_ioctl(inode, handle, cmd, arg, out_size, exit_code)
+        self.do_ioctl(ctx, inode, handle, cmd, arg, data, out_size, exit_code)
     }
 }
 
@@ -3393,6 +6735,18 @@ impl Drop for ScopedUid {
     }
 }
 
+impl Drop for EphemeralDir {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.0) {
+            log::error!(
+                "failed to remove ephemeral top layer {}: {}",
+                self.0.display(),
+                e
+            );
+        }
+    }
+}
+
 impl Default for Config {
     fn default() -> Self {
         Config {
@@ -3406,6 +6760,49 @@ impl Default for Config {
             export_fsid: 0,
             export_table: None,
             layers: vec![],
+            symlink_policy: Default::default(),
+            symlink_target_map: Vec::new(),
+            max_symlink_depth: 40,
+            upper_layer: Default::default(),
+            read_only: false,
+            dir_nlink: Default::default(),
+            verify_whiteouts: false,
+            case_insensitive: false,
+            durable: false,
+            intent_log: false,
+            inode_map: false,
+            allow_file_flags: false,
+            copy_up_threads: 4,
+            single_dev: false,
+            differential_blocks: false,
+            content_store: None,
+            lookup_filters: false,
+            lower_layers: None,
+            whiteout_cache_ttl: None,
+            layer_integrity: None,
+            overlay_xattrs: None,
+            remove_tree: false,
+            batch_create: false,
+            dentry_warming: None,
+            fd_client: None,
+            handle_quota: None,
+            hooks: None,
+            atime: FsAtime::Host,
+            metadata_sanitizer: None,
+            page_cache: FsPageCache::Host,
+            ownership: FsOwnership::Auto,
+            #[cfg(feature = "oci")]
+            lazy_layers: Vec::new(),
+        }
+    }
+}
+
+impl Default for MetadataSanitizer {
+    fn default() -> Self {
+        Self {
+            keep_xattrs: ["user.", "trusted.", "security.capability"]
+                .map(String::from)
+                .to_vec(),
         }
     }
 }
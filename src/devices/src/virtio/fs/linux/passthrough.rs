@@ -9,23 +9,43 @@ use std::ffi::{CStr, CString};
 use std::fs::File;
 use std::io;
 use std::mem::{self, size_of, MaybeUninit};
+use std::os::unix::ffi::OsStringExt;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 
 use caps::{has_cap, CapSet, Capability};
 use nix::{request_code_none, request_code_read};
 
+use utils::fd_budget::{FdClient, FdGrant};
 use vm_memory::ByteValued;
 
+use super::super::dax;
 use super::super::filesystem::{
-    Context, DirEntry, Entry, ExportTable, Extensions, FileSystem, FsOptions, GetxattrReply,
-    ListxattrReply, OpenOptions, SetattrValid, ZeroCopyReader, ZeroCopyWriter,
+    BirthTime, Context, DirEntry, Entry, ExportTable, Extensions, FileLock, FileSystem, FsOptions,
+    GetxattrReply, ListxattrReply, OpenOptions, SetattrValid, ZeroCopyReader, ZeroCopyWriter,
 };
 use super::super::fuse;
+use super::super::handle_quota::{FsHandleQuota, HandleGrant};
+use super::super::hooks::FsHooks;
+use super::super::page_cache::{self, SequentialReads};
+use super::super::{FsAtime, FsOwnership, FsPageCache};
+use super::super::bindings::{LINUX_FS_IOC_GETFLAGS, LINUX_FS_IOC_SETFLAGS};
+use super::atime;
+use super::overlay_xattrs;
+use super::fs_utils::{
+    get_birth_time, get_file_flags, guest_seek, open_tmpfile, set_file_flags, sync_dir_at,
+    write_from_sparse,
+};
 use super::super::multikey::MultikeyBTreeMap;
+use super::super::retry::retry_syscall;
+use super::super::snapshot::{self, HandleState, InodeState};
+use super::super::unicode_names;
+use super::posix_locks::PosixLocks;
+use super::stat_override::{self, StatOverride, OVERRIDE_XATTR};
 
 const CURRENT_DIR_CSTR: &[u8] = b".\0";
 const PARENT_DIR_CSTR: &[u8] = b"..\0";
@@ -52,12 +72,53 @@ struct InodeData {
     dev: u64,
     mnt_id: u64,
     refcount: AtomicU64,
+    // The creation time of the host file, if the file system records it.
+    btime: Option<BirthTime>,
+}
+
+impl InodeData {
+    /// Whether the host file created at `btime` isn't the file of this inode but a new one that got
+    /// its inode number after it was deleted. Host file systems recycle inode numbers, so a hit on
+    /// the alternate key alone doesn't tell.
+    fn is_recycled(&self, btime: Option<BirthTime>) -> bool {
+        matches!((self.btime, btime), (Some(old), Some(new)) if old != new)
+    }
 }
 
 struct HandleData {
     inode: Inode,
     file: RwLock<File>,
     exported: AtomicBool,
+    // Whether the access time of the file is still to be updated by the device, on the first read.
+    relatime: AtomicBool,
+    // The reads through this handle, to give the host page cache hints about.
+    reads: Mutex<SequentialReads>,
+    // The first error hit by a delayed (writeback) write on this handle, not yet reported.
+    write_error: Mutex<Option<io::Error>>,
+    _fd_grant: Option<FdGrant>,
+    _handle_grant: Option<HandleGrant>,
+}
+
+impl HandleData {
+    /// Records the error of a delayed write.
+    ///
+    /// The guest has already considered such writes complete, so the error is kept around until it
+    /// can be reported by [`FileSystem::flush`] or [`FileSystem::release`]. Only the first error is
+    /// kept, later ones are most likely a consequence of it.
+    fn set_write_error(&self, err: &io::Error) {
+        let mut write_error = self.write_error.lock().unwrap();
+        if write_error.is_none() {
+            *write_error = Some(match err.raw_os_error() {
+                Some(errno) => io::Error::from_raw_os_error(errno),
+                None => io::Error::new(err.kind(), err.to_string()),
+            });
+        }
+    }
+
+    /// Takes the pending delayed write error, if any, so that it is only reported once.
+    fn take_write_error(&self) -> Option<io::Error> {
+        self.write_error.lock().unwrap().take()
+    }
 }
 
 #[repr(C, packed)]
@@ -118,6 +179,13 @@ macro_rules! scoped_cred {
 scoped_cred!(ScopedUid, libc::uid_t, libc::SYS_setresuid);
 scoped_cred!(ScopedGid, libc::gid_t, libc::SYS_setresgid);
 
+/// Returns the path of the entry `name` of the directory open as `dir_fd` through `/proc/self/fd`.
+fn proc_entry_path(dir_fd: RawFd, name: &[u8]) -> Option<CString> {
+    let mut path = format!("/proc/self/fd/{dir_fd}/").into_bytes();
+    path.extend_from_slice(name);
+    CString::new(path).ok()
+}
+
 fn ebadf() -> io::Error {
     io::Error::from_raw_os_error(libc::EBADF)
 }
@@ -154,7 +222,7 @@ fn statx(f: &File) -> io::Result<(libc::stat64, u64)> {
 
     // Safe because the kernel will only write data in `st` and we check the return
     // value.
-    let res = unsafe {
+    let res = retry_syscall(|| unsafe {
         libc::statx(
             f.as_raw_fd(),
             pathname.as_ptr(),
@@ -162,7 +230,7 @@ fn statx(f: &File) -> io::Result<(libc::stat64, u64)> {
             libc::STATX_BASIC_STATS | libc::STATX_MNT_ID,
             stx.as_mut_ptr(),
         )
-    };
+    });
     if res >= 0 {
         // Safe because the kernel guarantees that the struct is now fully initialized.
         let stx = unsafe { stx.assume_init() };
@@ -290,6 +358,62 @@ pub struct Config {
     pub export_fsid: u64,
     /// Table of exported FDs to share with other subsystems.
     pub export_table: Option<ExportTable>,
+
+    /// Whether changes to directory entries are made durable before they are acknowledged. When
+    /// enabled, the directories whose entries are created, linked, removed or renamed are flushed
+    /// to the disk before the request completes, so that the changes survive a host crash. This
+    /// has a significant cost for workloads that create or remove many files.
+    ///
+    /// The default value for this option is `false`.
+    pub durable: bool,
+
+    /// Whether the guest may set the immutable and append-only flags of the host files, e.g. with
+    /// `chattr`. The flags are always reported to the guest, so that it can tell why writing to such
+    /// files fails, but changing them is left to the host unless this is set.
+    ///
+    /// The default value for this option is `false`.
+    pub allow_file_flags: bool,
+
+    /// The registration with the file descriptor budget of the process the descriptors of the
+    /// open handles are accounted to. Opening a file fails with `EMFILE` when the budget is
+    /// exhausted.
+    ///
+    /// The default is `None`, which doesn't account them.
+    pub fd_client: Option<FdClient>,
+
+    /// The count of the handles open on the share, opening a file failing with `EMFILE` when it
+    /// reaches its limit.
+    ///
+    /// The default is `None`, which doesn't limit them.
+    pub handle_quota: Option<FsHandleQuota>,
+
+    /// The hooks of the embedder, see `FsHooks`.
+    ///
+    /// The default is `None`, which calls none.
+    pub hooks: Option<FsHooks>,
+
+    /// How the reads of the guest update the access times of the host files.
+    ///
+    /// The default is `FsAtime::Host`, which leaves it to the host file system.
+    pub atime: FsAtime,
+
+    /// Whether the lookups of names missing on the host fall back to the entries whose names are
+    /// canonically equivalent, and the names the host volume changes are listed as the guest gave
+    /// them, see `unicode_names`.
+    ///
+    /// The default is `false`, which matches and lists the names byte for byte.
+    pub normalize_names: bool,
+
+    /// How the reads of the guest use the host page cache, see `page_cache`.
+    ///
+    /// The default is `FsPageCache::Host`, which leaves it to the host.
+    pub page_cache: FsPageCache,
+
+    /// Where the owners the guest gives the entries and the device nodes it creates are kept, see
+    /// `stat_override`.
+    ///
+    /// The default is `FsOwnership::Auto`, which keeps them on the host if the device runs as root.
+    pub ownership: FsOwnership,
 }
 
 impl Default for Config {
@@ -304,6 +428,15 @@ impl Default for Config {
             proc_sfd_rawfd: None,
             export_fsid: 0,
             export_table: None,
+            durable: false,
+            allow_file_flags: false,
+            fd_client: None,
+            handle_quota: None,
+            hooks: None,
+            atime: FsAtime::Host,
+            normalize_names: false,
+            page_cache: FsPageCache::Host,
+            ownership: FsOwnership::Auto,
         }
     }
 }
@@ -340,7 +473,16 @@ pub struct PassthroughFs {
     announce_submounts: AtomicBool,
     my_uid: Option<libc::uid_t>,
     my_gid: Option<libc::gid_t>,
-    cap_fowner: bool,
+
+    // Whether the host volume stores names in another form than the one they are given in, so
+    // that the guest names are kept in extended attributes.
+    changes_names: bool,
+
+    // Whether the owners of the entries are kept in extended attributes rather than on the host.
+    virtual_ownership: bool,
+
+    // The POSIX locks the guest holds on the files, by lock owner.
+    posix_locks: PosixLocks,
 
     cfg: Config,
 }
@@ -361,13 +503,13 @@ impl PassthroughFs {
             let proc_cstr = unsafe { CStr::from_bytes_with_nul_unchecked(PROC_CSTR) };
 
             // Safe because this doesn't modify any memory and we check the return value.
-            let fd = unsafe {
+            let fd = retry_syscall(|| unsafe {
                 libc::openat(
                     libc::AT_FDCWD,
                     proc_cstr.as_ptr(),
                     libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
                 )
-            };
+            });
             if fd < 0 {
                 return Err(io::Error::last_os_error());
             }
@@ -391,12 +533,13 @@ impl PassthroughFs {
             Some(unsafe { libc::getgid() })
         };
 
-        let cap_fowner =
-            has_cap(None, CapSet::Effective, Capability::CAP_FOWNER).unwrap_or_default();
-
         // Safe because we just opened this fd or it was provided by our caller.
         let proc_self_fd = unsafe { File::from_raw_fd(fd) };
 
+        let changes_names =
+            cfg.normalize_names && unicode_names::host_changes_names(Path::new(&cfg.root_dir));
+        let virtual_ownership = cfg.ownership.is_virtual();
+
         Ok(PassthroughFs {
             inodes: RwLock::new(MultikeyBTreeMap::new()),
             next_inode: AtomicU64::new(fuse::ROOT_ID + 2),
@@ -412,11 +555,132 @@ impl PassthroughFs {
             announce_submounts: AtomicBool::new(false),
             my_uid,
             my_gid,
-            cap_fowner,
+            changes_names,
+            virtual_ownership,
+            posix_locks: PosixLocks::default(),
             cfg,
         })
     }
 
+    /// Returns the inode of the init binary served in every directory.
+    pub(crate) fn init_inode(&self) -> Inode {
+        self.init_inode
+    }
+
+    /// Returns the inodes the guest may hold, and cache the attributes of.
+    pub(crate) fn held_inodes(&self) -> Vec<Inode> {
+        self.inodes.read().unwrap().main.keys().copied().collect()
+    }
+
+    /// Returns the hooks of the embedder on the share, if it has any.
+    pub(crate) fn hooks(&self) -> Option<&FsHooks> {
+        self.cfg.hooks.as_ref()
+    }
+
+    /// Returns the path of `inode` relative to the shared directory, if it can be found.
+    pub(crate) fn inode_path(&self, inode: Inode) -> Option<PathBuf> {
+        let data = self.inodes.read().unwrap().get(&inode).cloned()?;
+        let path = std::fs::read_link(format!("/proc/self/fd/{}", data.file.as_raw_fd())).ok()?;
+        let root = std::fs::canonicalize(&self.cfg.root_dir).ok()?;
+        path.strip_prefix(root).ok().map(Path::to_path_buf)
+    }
+
+    /// Returns the inodes the guest holds, for a snapshot of the share.
+    pub(crate) fn snapshot_inodes(&self) -> io::Result<Vec<InodeState>> {
+        let held: Vec<(Inode, u64)> = self
+            .inodes
+            .read()
+            .unwrap()
+            .main
+            .iter()
+            .map(|(&inode, (_, data))| (inode, data.refcount.load(Ordering::Acquire)))
+            .collect();
+
+        held.into_iter()
+            .map(|(inode, refcount)| {
+                Ok(InodeState {
+                    inode,
+                    generation: 0,
+                    refcount,
+                    path: self
+                        .inode_path(inode)
+                        .ok_or_else(|| snapshot::unreachable_inode(inode))?,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the handles the guest holds, for a snapshot of the share.
+    pub(crate) fn snapshot_handles(&self) -> io::Result<Vec<HandleState>> {
+        self.handles
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(&handle, data)| {
+                Ok(HandleState {
+                    handle,
+                    inode: data.inode,
+                    flags: snapshot::open_flags(&data.file.read().unwrap())?,
+                })
+            })
+            .collect()
+    }
+
+    /// Makes the next inodes and handles get numbers from `inode` and `handle` on, at least.
+    pub(crate) fn reserve_numbers(&self, inode: Inode, handle: Handle) {
+        self.next_inode.fetch_max(inode, Ordering::Relaxed);
+        self.next_handle.fetch_max(handle, Ordering::Relaxed);
+    }
+
+    /// Gives the inode `from` the number `to`, which must be free, and `refcount` lookups, to
+    /// restore an inode of a snapshot. The generations of the inodes are always 0.
+    pub(crate) fn renumber_inode(
+        &self,
+        from: Inode,
+        to: Inode,
+        _generation: u64,
+        refcount: u64,
+    ) -> io::Result<()> {
+        let mut inodes = self.inodes.write().unwrap();
+        if from == to {
+            let data = inodes.get(&from).ok_or_else(ebadf)?;
+            data.refcount.store(refcount, Ordering::Release);
+            return Ok(());
+        }
+        if inodes.get(&to).is_some() {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST));
+        }
+
+        let altkey = inodes.main.get(&from).ok_or_else(ebadf)?.0;
+        let data = inodes.remove(&from).unwrap();
+        let data = Arc::try_unwrap(data).map_err(|_| io::Error::from_raw_os_error(libc::EBUSY))?;
+        inodes.insert(
+            to,
+            altkey,
+            Arc::new(InodeData {
+                inode: to,
+                refcount: AtomicU64::new(refcount),
+                ..data
+            }),
+        );
+        Ok(())
+    }
+
+    /// Gives the handle `from` the number `to`, which must be free, to restore a handle of a
+    /// snapshot.
+    pub(crate) fn renumber_handle(&self, from: Handle, to: Handle) -> io::Result<()> {
+        let mut handles = self.handles.write().unwrap();
+        if from == to {
+            return Ok(());
+        }
+        if handles.contains_key(&to) {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST));
+        }
+        let data = handles.remove(&from).ok_or_else(ebadf)?;
+        handles.insert(to, data);
+        Ok(())
+    }
+
     fn open_inode(&self, inode: Inode, mut flags: i32) -> io::Result<File> {
         let data = self
             .inodes
@@ -452,13 +716,19 @@ impl PassthroughFs {
         // really check `flags` because if the kernel can't handle poorly specified flags then we
         // have much bigger problems. Also, clear the `O_NOFOLLOW` flag if it is set since we need
         // to follow the `/proc/self/fd` symlink to get the file.
-        let fd = unsafe {
-            libc::openat(
-                self.proc_self_fd.as_raw_fd(),
-                pathname.as_ptr(),
-                (flags | libc::O_CLOEXEC) & (!libc::O_NOFOLLOW),
-            )
+        let open = |flags| {
+            retry_syscall(|| unsafe {
+                libc::openat(
+                    self.proc_self_fd.as_raw_fd(),
+                    pathname.as_ptr(),
+                    (flags | libc::O_CLOEXEC) & (!libc::O_NOFOLLOW),
+                )
+            })
         };
+        let mut fd = open(flags);
+        if fd < 0 && atime::retry_without_noatime(&mut flags) {
+            fd = open(flags);
+        }
         if fd < 0 {
             return Err(io::Error::last_os_error());
         }
@@ -467,6 +737,25 @@ impl PassthroughFs {
         Ok(unsafe { File::from_raw_fd(fd) })
     }
 
+    /// Opens `inode` to get or set its flags. Only regular files and directories have their flags
+    /// passed through, as opening other kinds of files may have side effects.
+    fn open_inode_for_flags(&self, inode: Inode) -> io::Result<File> {
+        let data = self
+            .inodes
+            .read()
+            .unwrap()
+            .get(&inode)
+            .cloned()
+            .ok_or_else(ebadf)?;
+
+        let file_type = data.file.metadata()?.file_type();
+        if !file_type.is_file() && !file_type.is_dir() {
+            return Err(io::Error::from_raw_os_error(libc::ENOTTY));
+        }
+
+        self.open_inode(inode, libc::O_RDONLY | libc::O_NONBLOCK)
+    }
+
     fn open_inode_or_path(&self, inode: Inode, flags: i32) -> io::Result<FileOrLink> {
         match self.open_inode(inode, flags) {
             Ok(a) => Ok(FileOrLink::File(a)),
@@ -490,6 +779,36 @@ impl PassthroughFs {
         }
     }
 
+    /// Returns the name of the entry of the directory `dir` canonically equivalent to `name`, for
+    /// a lookup of `name` that found nothing, if the names are normalized.
+    fn equivalent_name(&self, dir: &InodeData, name: &CStr) -> Option<CString> {
+        if !self.cfg.normalize_names || name.to_bytes().is_ascii() {
+            return None;
+        }
+        let entries = std::fs::read_dir(format!("/proc/self/fd/{}", dir.file.as_raw_fd())).ok()?;
+        let names = entries.filter_map(|entry| Some(entry.ok()?.file_name().into_vec()));
+        unicode_names::find_equivalent(names, name.to_bytes())
+    }
+
+    /// Keeps the name `name` the guest created an entry of the directory `dir` as, if the host
+    /// volume changes it.
+    fn keep_guest_name(&self, dir: &InodeData, name: &CStr) {
+        if self.changes_names && unicode_names::needs_sidecar(name.to_bytes()) {
+            if let Some(path) = proc_entry_path(dir.file.as_raw_fd(), name.to_bytes()) {
+                unicode_names::write_sidecar(&path, name.to_bytes());
+            }
+        }
+    }
+
+    /// Returns the name the guest created the entry `name` of the directory open as `dir_fd` as,
+    /// if the host volume changed it.
+    fn guest_name(&self, dir_fd: RawFd, name: &[u8]) -> Option<CString> {
+        if !self.changes_names || name.is_ascii() {
+            return None;
+        }
+        unicode_names::read_sidecar(&proc_entry_path(dir_fd, name)?)
+    }
+
     fn do_lookup(&self, parent: Inode, name: &CStr) -> io::Result<Entry> {
         let p = self
             .inodes
@@ -499,22 +818,48 @@ impl PassthroughFs {
             .cloned()
             .ok_or_else(ebadf)?;
 
-        // Safe because this doesn't modify any memory and we check the return value.
-        let fd = unsafe {
-            libc::openat(
-                p.file.as_raw_fd(),
-                name.as_ptr(),
-                libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
-            )
+        let open = |name: &CStr| {
+            // Safe because this doesn't modify any memory and we check the return value.
+            let fd = retry_syscall(|| unsafe {
+                libc::openat(
+                    p.file.as_raw_fd(),
+                    name.as_ptr(),
+                    libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+                )
+            });
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Safe because we just opened this fd.
+            Ok(unsafe { File::from_raw_fd(fd) })
+        };
+        let f = match open(name) {
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {
+                match self.equivalent_name(&p, name) {
+                    Some(host_name) => open(&host_name)?,
+                    None => return Err(e),
+                }
+            }
+            res => res?,
         };
-        if fd < 0 {
-            return Err(io::Error::last_os_error());
-        }
 
-        // Safe because we just opened this fd.
-        let f = unsafe { File::from_raw_fd(fd) };
+        let entry = self.add_inode(&p, f)?;
 
+        debug!(
+            "do_lookup: {}, inode: {:?}",
+            name.to_str().unwrap(),
+            entry.inode
+        );
+
+        Ok(entry)
+    }
+
+    /// Returns the entry of the file `f` in the directory `p`, adding its inode if it isn't known
+    /// yet, or taking another reference on it otherwise.
+    fn add_inode(&self, p: &InodeData, f: File) -> io::Result<Entry> {
         let (st, mnt_id) = statx(&f)?;
+        let mut attr = st;
+        self.patch_owner(f.as_raw_fd(), &mut attr);
 
         let mut attr_flags: u32 = 0;
 
@@ -530,42 +875,50 @@ impl PassthroughFs {
             dev: st.st_dev,
             mnt_id,
         };
+        let btime = get_birth_time(&f).ok().flatten();
         let data = self.inodes.read().unwrap().get_alt(&altkey).cloned();
 
-        let inode = if let Some(data) = data {
-            // Matches with the release store in `forget`.
-            data.refcount.fetch_add(1, Ordering::Acquire);
-            data.inode
-        } else {
-            // There is a possible race here where 2 threads end up adding the same file
-            // into the inode list.  However, since each of those will get a unique Inode
-            // value and unique file descriptors this shouldn't be that much of a problem.
-            let inode = self.next_inode.fetch_add(1, Ordering::Relaxed);
-            self.inodes.write().unwrap().insert(
-                inode,
-                InodeAltKey {
-                    ino: st.st_ino,
-                    dev: st.st_dev,
-                    mnt_id,
-                },
-                Arc::new(InodeData {
-                    inode,
-                    file: f,
-                    dev: st.st_dev,
-                    mnt_id,
-                    refcount: AtomicU64::new(1),
-                }),
-            );
+        let inode = match data {
+            Some(data) if !data.is_recycled(btime) => {
+                // Matches with the release store in `forget`.
+                data.refcount.fetch_add(1, Ordering::Acquire);
+                data.inode
+            }
+            stale => {
+                if let Some(stale) = stale {
+                    debug!("do_lookup: inode {} was recycled by the host", stale.inode);
+                }
 
-            inode
+                // There is a possible race here where 2 threads end up adding the same file
+                // into the inode list.  However, since each of those will get a unique Inode
+                // value and unique file descriptors this shouldn't be that much of a problem.
+                // Inserting the new inode drops the stale one, whose file is gone anyway.
+                let inode = self.next_inode.fetch_add(1, Ordering::Relaxed);
+                self.inodes.write().unwrap().insert(
+                    inode,
+                    InodeAltKey {
+                        ino: st.st_ino,
+                        dev: st.st_dev,
+                        mnt_id,
+                    },
+                    Arc::new(InodeData {
+                        inode,
+                        file: f,
+                        dev: st.st_dev,
+                        mnt_id,
+                        refcount: AtomicU64::new(1),
+                        btime,
+                    }),
+                );
+
+                inode
+            }
         };
 
-        debug!("do_lookup: {}, inode: {:?}", name.to_str().unwrap(), inode);
-
         Ok(Entry {
             inode,
             generation: 0,
-            attr: st,
+            attr,
             attr_flags,
             attr_timeout: self.cfg.attr_timeout,
             entry_timeout: self.cfg.entry_timeout,
@@ -597,12 +950,14 @@ impl PassthroughFs {
             .ok_or_else(ebadf)?;
 
         let mut buf = vec![0; size as usize];
+        let dir_fd;
 
         {
             // Since we are going to work with the kernel offset, we have to acquire the file lock
             // for both the `lseek64` and `getdents64` syscalls to ensure that no other thread
             // changes the kernel offset while we are using it.
             let dir = data.file.write().unwrap();
+            dir_fd = dir.as_raw_fd();
 
             // Safe because this doesn't modify any memory and we check the return value.
             let res =
@@ -658,6 +1013,10 @@ impl PassthroughFs {
                 // break the loop so return `Ok` with a non-zero value instead.
                 Ok(1)
             } else {
+                // The guest name is followed by a nul byte too, as `readdirplus` expects
+                let guest_name = self.guest_name(dir_fd, name);
+                let name =
+                    unicode_names::guest_name(name, guest_name.as_deref().map(CStr::to_bytes));
                 add_entry(DirEntry {
                     ino: dirent64.d_ino,
                     offset: dirent64.d_off as u64,
@@ -681,15 +1040,14 @@ impl PassthroughFs {
         Ok(())
     }
 
-    fn do_open(&self, inode: Inode, mut flags: u32) -> io::Result<(Option<Handle>, OpenOptions)> {
+    fn do_open(&self, inode: Inode, flags: u32) -> io::Result<(Option<Handle>, OpenOptions)> {
         debug!("do_open: {:?}", inode);
-        if !self.cap_fowner {
-            // O_NOATIME can only be used with CAP_FOWNER or if we are the file
-            // owner. Not worth checking the latter, just drop it if we don't
-            // have the cap. This makes overlayfs mounts with virtiofs lower dirs
-            // work.
-            flags &= !(libc::O_NOATIME as u32);
-        }
+        // O_NOATIME can only be used with CAP_FOWNER or if we are the file owner, so `open_inode`
+        // drops it when it isn't allowed. This makes overlayfs mounts with virtiofs lower dirs
+        // work.
+        let (flags, relatime) = atime::open_flags(self.cfg.atime, flags);
+        let handle_grant = self.acquire_handle()?;
+        let fd_grant = self.acquire_fd()?;
         let file = RwLock::new(self.open_inode(inode, flags as i32)?);
 
         let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
@@ -697,6 +1055,11 @@ impl PassthroughFs {
             inode,
             file,
             exported: Default::default(),
+            relatime: AtomicBool::new(relatime),
+            reads: Default::default(),
+            write_error: Default::default(),
+            _fd_grant: fd_grant,
+            _handle_grant: handle_grant,
         };
 
         self.handles.write().unwrap().insert(handle, Arc::new(data));
@@ -736,10 +1099,16 @@ impl PassthroughFs {
                         .remove(&(self.cfg.export_fsid, handle));
                 }
 
+                // Report any delayed write error that was not picked up by a flush.
+                let write_error = e.get().take_write_error();
+
                 // We don't need to close the file here because that will happen automatically when
                 // the last `Arc` is dropped.
                 e.remove();
-                return Ok(());
+                return match write_error {
+                    Some(err) => Err(err),
+                    None => Ok(()),
+                };
             }
         }
 
@@ -755,7 +1124,8 @@ impl PassthroughFs {
             .cloned()
             .ok_or_else(ebadf)?;
 
-        let st = stat(&data.file)?;
+        let mut st = stat(&data.file)?;
+        self.patch_owner(data.file.as_raw_fd(), &mut st);
 
         Ok((st, self.cfg.attr_timeout))
     }
@@ -772,17 +1142,100 @@ impl PassthroughFs {
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe { libc::unlinkat(data.file.as_raw_fd(), name.as_ptr(), flags) };
         if res == 0 {
-            Ok(())
+            self.sync_dir(&data)
         } else {
             Err(io::Error::last_os_error())
         }
     }
 
+    /// Accounts for the descriptor of a new handle in the budget of `Config::fd_client`, waiting
+    /// for it if the budget is short.
+    fn acquire_fd(&self) -> io::Result<Option<FdGrant>> {
+        self.cfg
+            .fd_client
+            .as_ref()
+            .map(|client| client.acquire(1))
+            .transpose()
+    }
+
+    /// Counts a new handle in `Config::handle_quota`, failing with `EMFILE` past its limit.
+    fn acquire_handle(&self) -> io::Result<Option<HandleGrant>> {
+        self.cfg
+            .handle_quota
+            .as_ref()
+            .map(|quota| quota.acquire())
+            .transpose()
+    }
+
+    /// Flushes the entries of the directory `data` to the disk if `Config::durable` is set.
+    fn sync_dir(&self, data: &InodeData) -> io::Result<()> {
+        if !self.cfg.durable {
+            return Ok(());
+        }
+
+        let procname = CString::new(format!("{}", data.file.as_raw_fd()))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        sync_dir_at(self.proc_self_fd.as_raw_fd(), &procname)
+    }
+
+    /// Replaces the host attributes `st` of the entry open as `fd` with the ones the guest gave it,
+    /// if the ownership is virtual.
+    fn patch_owner(&self, fd: RawFd, st: &mut libc::stat64) {
+        if !self.virtual_ownership {
+            return;
+        }
+        if let Some(value) = stat_override::read(fd, None) {
+            value.apply(st);
+        }
+    }
+
+    /// Gives the entry `name` the guest just created in the directory `dir` the owner of `ctx`, and
+    /// `mode` and `rdev`, if the ownership is virtual.
+    fn own_new_entry(
+        &self,
+        dir: &InodeData,
+        name: &CStr,
+        ctx: Context,
+        mode: u32,
+        rdev: u64,
+    ) -> io::Result<()> {
+        if !self.virtual_ownership {
+            return Ok(());
+        }
+        let value = StatOverride {
+            uid: ctx.uid,
+            gid: ctx.gid,
+            mode,
+            rdev,
+        };
+        stat_override::write(dir.file.as_raw_fd(), Some(name), &value)
+    }
+
+    /// Returns whether `name` is the extended attribute keeping the owners of the entries, hidden
+    /// from the guest when the ownership is virtual.
+    fn is_override_xattr(&self, name: &CStr) -> bool {
+        self.virtual_ownership && name == OVERRIDE_XATTR
+    }
+
+    /// Returns the mode of the host file of an entry the guest gives `mode`.
+    fn host_mode(&self, mode: u32) -> u32 {
+        if self.virtual_ownership {
+            stat_override::host_mode(mode)
+        } else {
+            mode
+        }
+    }
+
     fn set_creds(
         &self,
         uid: libc::uid_t,
         gid: libc::gid_t,
     ) -> io::Result<(Option<ScopedUid>, Option<ScopedGid>)> {
+        // The entries get their owners in extended attributes instead
+        if self.virtual_ownership {
+            return Ok((None, None));
+        }
+
         // Change the gid first, since once we change the uid we lose the capability to change the gid.
         let scoped_gid = if gid == 0 || self.my_gid == Some(gid) {
             // Always allow "root" accesses even if we don't have root powers.
@@ -858,13 +1311,13 @@ impl FileSystem for PassthroughFs {
         // Safe because this doesn't modify any memory and we check the return value.
         // We use `O_PATH` because we just want this for traversing the directory tree
         // and not for actually reading the contents.
-        let fd = unsafe {
+        let fd = retry_syscall(|| unsafe {
             libc::openat(
                 libc::AT_FDCWD,
                 root.as_ptr(),
                 libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
             )
-        };
+        });
         if fd < 0 {
             return Err(io::Error::last_os_error());
         }
@@ -873,6 +1326,7 @@ impl FileSystem for PassthroughFs {
         let f = unsafe { File::from_raw_fd(fd) };
 
         let (st, mnt_id) = statx(&f)?;
+        let btime = get_birth_time(&f).ok().flatten();
 
         // Safe because this doesn't modify any memory and there is no need to check the return
         // value because this system call always succeeds. We need to clear the umask here because
@@ -895,10 +1349,12 @@ impl FileSystem for PassthroughFs {
                 dev: st.st_dev,
                 mnt_id,
                 refcount: AtomicU64::new(2),
+                btime,
             }),
         );
 
-        let mut opts = FsOptions::DO_READDIRPLUS | FsOptions::READDIRPLUS_AUTO;
+        let mut opts =
+            FsOptions::DO_READDIRPLUS | FsOptions::READDIRPLUS_AUTO | FsOptions::POSIX_LOCKS;
         if self.cfg.writeback && capable.contains(FsOptions::WRITEBACK_CACHE) {
             opts |= FsOptions::WRITEBACK_CACHE;
             self.writeback.store(true, Ordering::Relaxed);
@@ -1004,7 +1460,8 @@ impl FileSystem for PassthroughFs {
         extensions: Extensions,
     ) -> io::Result<Entry> {
         if extensions.secctx.is_some() {
-            unimplemented!("SECURITY_CTX is not supported and should not be used by the guest");
+            // SECURITY_CTX is never negotiated on Linux hosts
+            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
 
         let (_uid, _gid) = self.set_creds(ctx.uid, ctx.gid)?;
@@ -1016,9 +1473,14 @@ impl FileSystem for PassthroughFs {
             .cloned()
             .ok_or_else(ebadf)?;
 
+        let mode = libc::S_IFDIR | (mode & !umask & 0o7777);
         // Safe because this doesn't modify any memory and we check the return value.
-        let res = unsafe { libc::mkdirat(data.file.as_raw_fd(), name.as_ptr(), mode & !umask) };
+        let res =
+            unsafe { libc::mkdirat(data.file.as_raw_fd(), name.as_ptr(), self.host_mode(mode)) };
         if res == 0 {
+            self.own_new_entry(&data, name, ctx, mode, 0)?;
+            self.keep_guest_name(&data, name);
+            self.sync_dir(&data)?;
             self.do_lookup(parent, name)
         } else {
             Err(io::Error::last_os_error())
@@ -1089,10 +1551,13 @@ impl FileSystem for PassthroughFs {
         inode: Inode,
         _flags: u32,
         handle: Handle,
-        _flush: bool,
+        flush: bool,
         _flock_release: bool,
-        _lock_owner: Option<u64>,
+        lock_owner: Option<u64>,
     ) -> io::Result<()> {
+        if let Some(owner) = lock_owner.filter(|_| flush) {
+            self.posix_locks.release(inode, owner);
+        }
         self.do_release(inode, handle)
     }
 
@@ -1107,7 +1572,8 @@ impl FileSystem for PassthroughFs {
         extensions: Extensions,
     ) -> io::Result<(Entry, Option<Handle>, OpenOptions)> {
         if extensions.secctx.is_some() {
-            unimplemented!("SECURITY_CTX is not supported and should not be used by the guest");
+            // SECURITY_CTX is never negotiated on Linux hosts
+            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
 
         let (_uid, _gid) = self.set_creds(ctx.uid, ctx.gid)?;
@@ -1119,17 +1585,38 @@ impl FileSystem for PassthroughFs {
             .cloned()
             .ok_or_else(ebadf)?;
 
+        let handle_grant = self.acquire_handle()?;
+        let fd_grant = self.acquire_fd()?;
+
+        let mode = libc::S_IFREG | (mode & !(umask & 0o777) & 0o7777);
         // Safe because this doesn't modify any memory and we check the return value. We don't
         // really check `flags` because if the kernel can't handle poorly specified flags then we
         // have much bigger problems.
-        let fd = unsafe {
-            libc::openat(
-                data.file.as_raw_fd(),
-                name.as_ptr(),
-                flags as i32 | libc::O_CREAT | libc::O_CLOEXEC | libc::O_NOFOLLOW,
-                mode & !(umask & 0o777),
-            )
+        let open = |flags: i32| {
+            retry_syscall(|| unsafe {
+                libc::openat(
+                    data.file.as_raw_fd(),
+                    name.as_ptr(),
+                    flags | libc::O_CLOEXEC | libc::O_NOFOLLOW,
+                    self.host_mode(mode),
+                )
+            })
+        };
+        // An entry that already exists keeps its owner, so whether the file is new must be known
+        let flags = flags as i32 | libc::O_CREAT;
+        let mut created = true;
+        let mut fd = if self.virtual_ownership {
+            open(flags | libc::O_EXCL)
+        } else {
+            open(flags)
         };
+        if fd < 0
+            && flags & libc::O_EXCL == 0
+            && io::Error::last_os_error().raw_os_error() == Some(libc::EEXIST)
+        {
+            created = false;
+            fd = open(flags & !libc::O_CREAT);
+        }
         if fd < 0 {
             return Err(io::Error::last_os_error());
         }
@@ -1137,6 +1624,11 @@ impl FileSystem for PassthroughFs {
         // Safe because we just opened this fd.
         let file = RwLock::new(unsafe { File::from_raw_fd(fd) });
 
+        if created {
+            self.own_new_entry(&data, name, ctx, mode, 0)?;
+        }
+        self.keep_guest_name(&data, name);
+        self.sync_dir(&data)?;
         let entry = self.do_lookup(parent, name)?;
 
         let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
@@ -1144,6 +1636,90 @@ impl FileSystem for PassthroughFs {
             inode: entry.inode,
             file,
             exported: Default::default(),
+            relatime: Default::default(),
+            reads: Default::default(),
+            write_error: Default::default(),
+            _fd_grant: fd_grant,
+            _handle_grant: handle_grant,
+        };
+
+        self.handles.write().unwrap().insert(handle, Arc::new(data));
+
+        let mut opts = OpenOptions::empty();
+        match self.cfg.cache_policy {
+            CachePolicy::Never => opts |= OpenOptions::DIRECT_IO,
+            CachePolicy::Always => opts |= OpenOptions::KEEP_CACHE,
+            _ => {}
+        };
+
+        Ok((entry, Some(handle), opts))
+    }
+
+    fn tmpfile(
+        &self,
+        ctx: Context,
+        parent: Inode,
+        mode: u32,
+        flags: u32,
+        umask: u32,
+        extensions: Extensions,
+    ) -> io::Result<(Entry, Option<Handle>, OpenOptions)> {
+        if extensions.secctx.is_some() {
+            // SECURITY_CTX is never negotiated on Linux hosts
+            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+        }
+
+        let (_uid, _gid) = self.set_creds(ctx.uid, ctx.gid)?;
+        let data = self
+            .inodes
+            .read()
+            .unwrap()
+            .get(&parent)
+            .cloned()
+            .ok_or_else(ebadf)?;
+
+        let handle_grant = self.acquire_handle()?;
+        let fd_grant = self.acquire_fd()?;
+
+        let mode = libc::S_IFREG | (mode & !(umask & 0o777) & 0o7777);
+        let file = open_tmpfile(data.file.as_raw_fd(), flags, self.host_mode(mode))?;
+        if self.virtual_ownership {
+            let value = StatOverride {
+                uid: ctx.uid,
+                gid: ctx.gid,
+                mode,
+                rdev: 0,
+            };
+            stat_override::write(file.as_raw_fd(), None, &value)?;
+        }
+
+        // The file has no name, so its inode is opened through the open file
+        let pathname = CString::new(format!("{}", file.as_raw_fd()))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        // Safe because this doesn't modify any memory and we check the return value.
+        let fd = retry_syscall(|| unsafe {
+            libc::openat(
+                self.proc_self_fd.as_raw_fd(),
+                pathname.as_ptr(),
+                libc::O_PATH | libc::O_CLOEXEC,
+            )
+        });
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because we just opened this fd.
+        let entry = self.add_inode(&data, unsafe { File::from_raw_fd(fd) })?;
+
+        let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        let data = HandleData {
+            inode: entry.inode,
+            file: RwLock::new(file),
+            exported: Default::default(),
+            relatime: Default::default(),
+            reads: Default::default(),
+            write_error: Default::default(),
+            _fd_grant: fd_grant,
+            _handle_grant: handle_grant,
         };
 
         self.handles.write().unwrap().insert(handle, Arc::new(data));
@@ -1198,7 +1774,22 @@ impl FileSystem for PassthroughFs {
         // This is safe because write_from uses preadv64, so the underlying file descriptor
         // offset is not affected by this operation.
         let f = data.file.read().unwrap();
-        w.write_from(&f, size as usize, offset)
+        let res = write_from_sparse(&mut w, &f, size as usize, offset);
+        if res.is_ok() && data.relatime.swap(false, Ordering::Relaxed) {
+            if let Err(e) = atime::relatime_update(f.as_raw_fd()) {
+                debug!("read: failed to update the access time of {inode}: {e}");
+            }
+        }
+        if let Ok(read) = res {
+            page_cache::advise_read(
+                &data.reads,
+                self.cfg.page_cache,
+                f.as_raw_fd(),
+                offset,
+                read as u64,
+            );
+        }
+        res
     }
 
     fn write<R: io::Read + ZeroCopyReader>(
@@ -1210,7 +1801,7 @@ impl FileSystem for PassthroughFs {
         size: u32,
         offset: u64,
         _lock_owner: Option<u64>,
-        _delayed_write: bool,
+        delayed_write: bool,
         kill_priv: bool,
         _flags: u32,
     ) -> io::Result<usize> {
@@ -1232,7 +1823,13 @@ impl FileSystem for PassthroughFs {
         // This is safe because read_to uses pwritev64, so the underlying file descriptor
         // offset is not affected by this operation.
         let f = data.file.read().unwrap();
-        r.read_to(&f, size as usize, offset)
+        let res = r.read_to(&f, size as usize, offset);
+        if let Err(e) = &res {
+            if delayed_write {
+                data.set_write_error(e);
+            }
+        }
+        res
     }
 
     fn getattr(
@@ -1244,6 +1841,24 @@ impl FileSystem for PassthroughFs {
         self.do_getattr(inode)
     }
 
+    fn statx(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        _handle: Option<Handle>,
+    ) -> io::Result<(libc::stat64, Option<BirthTime>, Duration)> {
+        let (st, timeout) = self.do_getattr(inode)?;
+        let data = self
+            .inodes
+            .read()
+            .unwrap()
+            .get(&inode)
+            .cloned()
+            .ok_or_else(ebadf)?;
+
+        Ok((st, get_birth_time(&data.file)?, timeout))
+    }
+
     fn setattr(
         &self,
         _ctx: Context,
@@ -1285,21 +1900,43 @@ impl FileSystem for PassthroughFs {
         };
 
         if valid.contains(SetattrValid::MODE) {
+            let mode = self.host_mode(attr.st_mode);
             // Safe because this doesn't modify any memory and we check the return value.
             let res = unsafe {
                 match data {
-                    Data::Handle(fd) => libc::fchmod(fd, attr.st_mode),
+                    Data::Handle(fd) => libc::fchmod(fd, mode),
                     Data::ProcPath(ref p) => {
-                        libc::fchmodat(self.proc_self_fd.as_raw_fd(), p.as_ptr(), attr.st_mode, 0)
+                        libc::fchmodat(self.proc_self_fd.as_raw_fd(), p.as_ptr(), mode, 0)
                     }
                 }
             };
-            if res < 0 {
+            // The host files the device doesn't own keep their host modes
+            if res < 0
+                && !(self.virtual_ownership
+                    && io::Error::last_os_error().raw_os_error() == Some(libc::EPERM))
+            {
                 return Err(io::Error::last_os_error());
             }
+
+            if self.virtual_ownership {
+                let st = stat(&inode_data.file)?;
+                stat_override::update(inode_data.file.as_raw_fd(), &st, |value| {
+                    value.mode = (value.mode & libc::S_IFMT) | (attr.st_mode & 0o7777)
+                })?;
+            }
         }
 
-        if valid.intersects(SetattrValid::UID | SetattrValid::GID) {
+        if valid.intersects(SetattrValid::UID | SetattrValid::GID) && self.virtual_ownership {
+            let st = stat(&inode_data.file)?;
+            stat_override::update(inode_data.file.as_raw_fd(), &st, |value| {
+                if valid.contains(SetattrValid::UID) {
+                    value.uid = attr.st_uid;
+                }
+                if valid.contains(SetattrValid::GID) {
+                    value.gid = attr.st_gid;
+                }
+            })?;
+        } else if valid.intersects(SetattrValid::UID | SetattrValid::GID) {
             let uid = if valid.contains(SetattrValid::UID) {
                 attr.st_uid
             } else {
@@ -1425,6 +2062,11 @@ impl FileSystem for PassthroughFs {
             )
         };
         if res == 0 {
+            self.keep_guest_name(&new_inode, newname);
+            self.sync_dir(&old_inode)?;
+            if olddir != newdir {
+                self.sync_dir(&new_inode)?;
+            }
             Ok(())
         } else {
             Err(io::Error::last_os_error())
@@ -1442,7 +2084,8 @@ impl FileSystem for PassthroughFs {
         extensions: Extensions,
     ) -> io::Result<Entry> {
         if extensions.secctx.is_some() {
-            unimplemented!("SECURITY_CTX is not supported and should not be used by the guest");
+            // SECURITY_CTX is never negotiated on Linux hosts
+            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
 
         let (_uid, _gid) = self.set_creds(ctx.uid, ctx.gid)?;
@@ -1454,19 +2097,45 @@ impl FileSystem for PassthroughFs {
             .cloned()
             .ok_or_else(ebadf)?;
 
+        let mode = mode & !umask;
+        let file_type = mode & libc::S_IFMT;
         // Safe because this doesn't modify any memory and we check the return value.
-        let res = unsafe {
-            libc::mknodat(
-                data.file.as_raw_fd(),
-                name.as_ptr(),
-                (mode & !umask) as libc::mode_t,
-                u64::from(rdev),
-            )
+        let res = if self.virtual_ownership && stat_override::is_device(file_type) {
+            // The device nodes are empty files on the host
+            let fd = retry_syscall(|| unsafe {
+                libc::openat(
+                    data.file.as_raw_fd(),
+                    name.as_ptr(),
+                    libc::O_WRONLY
+                        | libc::O_CREAT
+                        | libc::O_EXCL
+                        | libc::O_CLOEXEC
+                        | libc::O_NOFOLLOW,
+                    self.host_mode(mode),
+                )
+            });
+            if fd >= 0 {
+                // Safe because we just opened this fd.
+                unsafe { libc::close(fd) };
+            }
+            fd.min(0)
+        } else {
+            unsafe {
+                libc::mknodat(
+                    data.file.as_raw_fd(),
+                    name.as_ptr(),
+                    file_type | self.host_mode(mode),
+                    u64::from(rdev),
+                )
+            }
         };
 
         if res < 0 {
             Err(io::Error::last_os_error())
         } else {
+            self.own_new_entry(&data, name, ctx, mode, u64::from(rdev))?;
+            self.keep_guest_name(&data, name);
+            self.sync_dir(&data)?;
             self.do_lookup(parent, name)
         }
     }
@@ -1507,6 +2176,8 @@ impl FileSystem for PassthroughFs {
             )
         };
         if res == 0 {
+            self.keep_guest_name(&new_inode, newname);
+            self.sync_dir(&new_inode)?;
             self.do_lookup(newparent, newname)
         } else {
             Err(io::Error::last_os_error())
@@ -1523,7 +2194,8 @@ impl FileSystem for PassthroughFs {
     ) -> io::Result<Entry> {
         // Set security context on symlink.
         if extensions.secctx.is_some() {
-            unimplemented!("SECURITY_CTX is not supported and should not be used by the guest");
+            // SECURITY_CTX is never negotiated on Linux hosts
+            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
 
         let (_uid, _gid) = self.set_creds(ctx.uid, ctx.gid)?;
@@ -1539,6 +2211,8 @@ impl FileSystem for PassthroughFs {
         let res =
             unsafe { libc::symlinkat(linkname.as_ptr(), data.file.as_raw_fd(), name.as_ptr()) };
         if res == 0 {
+            self.keep_guest_name(&data, name);
+            self.sync_dir(&data)?;
             self.do_lookup(parent, name)
         } else {
             Err(io::Error::last_os_error())
@@ -1581,7 +2255,7 @@ impl FileSystem for PassthroughFs {
         _ctx: Context,
         inode: Inode,
         handle: Handle,
-        _lock_owner: u64,
+        lock_owner: u64,
     ) -> io::Result<()> {
         let data = self
             .handles
@@ -1592,6 +2266,9 @@ impl FileSystem for PassthroughFs {
             .cloned()
             .ok_or_else(ebadf)?;
 
+        // Closing a file releases the POSIX locks of its owner
+        self.posix_locks.release(inode, lock_owner);
+
         // Since this method is called whenever an fd is closed in the client, we can emulate that
         // behavior by doing the same thing (dup-ing the fd and then immediately closing it). Safe
         // because this doesn't modify any memory and we check the return values.
@@ -1602,11 +2279,15 @@ impl FileSystem for PassthroughFs {
             }
 
             if libc::close(newfd) < 0 {
-                Err(io::Error::last_os_error())
-            } else {
-                Ok(())
+                return Err(io::Error::last_os_error());
             }
         }
+
+        // Report the first delayed write error on this handle, if any, exactly once.
+        match data.take_write_error() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
     }
 
     fn fsync(&self, _ctx: Context, inode: Inode, datasync: bool, handle: Handle) -> io::Result<()> {
@@ -1656,7 +2337,8 @@ impl FileSystem for PassthroughFs {
             .cloned()
             .ok_or_else(ebadf)?;
 
-        let st = stat(&data.file)?;
+        let mut st = stat(&data.file)?;
+        self.patch_owner(data.file.as_raw_fd(), &mut st);
         let mode = mask as i32 & (libc::R_OK | libc::W_OK | libc::X_OK);
 
         if mode == libc::F_OK {
@@ -1707,6 +2389,9 @@ impl FileSystem for PassthroughFs {
         if !self.cfg.xattr {
             return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
+        if self.is_override_xattr(name) {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
 
         // The f{set,get,remove,list}xattr functions don't work on an fd opened with `O_PATH` so we
         // need to get a new fd. This doesn't work for symlinks, so we use the l* family of
@@ -1756,7 +2441,7 @@ impl FileSystem for PassthroughFs {
             return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
 
-        if inode == self.init_inode {
+        if inode == self.init_inode || self.is_override_xattr(name) {
             return Err(io::Error::from_raw_os_error(libc::ENODATA));
         }
 
@@ -1839,6 +2524,9 @@ impl FileSystem for PassthroughFs {
             Ok(ListxattrReply::Count(res as u32))
         } else {
             buf.resize(res as usize, 0);
+            if self.virtual_ownership {
+                overlay_xattrs::filter_names(&mut buf, OVERRIDE_XATTR.to_bytes_with_nul());
+            }
             Ok(ListxattrReply::Names(buf))
         }
     }
@@ -1847,6 +2535,9 @@ impl FileSystem for PassthroughFs {
         if !self.cfg.xattr {
             return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
+        if self.is_override_xattr(name) {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
 
         // The f{set,get,remove,list}xattr functions don't work on an fd opened with `O_PATH` so we
         // need to get a new fd. This doesn't work for symlinks, so we use the l* family of
@@ -1921,15 +2612,8 @@ impl FileSystem for PassthroughFs {
             .cloned()
             .ok_or_else(ebadf)?;
 
-        let fd = data.file.write().unwrap().as_raw_fd();
-
-        // Safe because this doesn't modify any memory and we check the return value.
-        let res = unsafe { libc::lseek(fd, offset as libc::off64_t, whence as libc::c_int) };
-        if res < 0 {
-            Err(io::Error::last_os_error())
-        } else {
-            Ok(res as u64)
-        }
+        let f = data.file.read().unwrap();
+        guest_seek(&f, offset, whence)
     }
 
     fn copyfilerange(
@@ -2033,6 +2717,7 @@ impl FileSystem for PassthroughFs {
             if std::ptr::eq(ret, libc::MAP_FAILED) {
                 return Err(io::Error::last_os_error());
             }
+            dax::forget_mappings(addr as *mut libc::c_void, len as usize);
 
             let to_copy = if len as usize > INIT_BINARY.len() {
                 INIT_BINARY.len()
@@ -2050,21 +2735,13 @@ impl FileSystem for PassthroughFs {
         }
 
         let file = self.open_inode(inode, open_flags)?;
-        let fd = file.as_raw_fd();
-
-        let ret = unsafe {
-            libc::mmap(
-                addr as *mut libc::c_void,
-                len as usize,
-                prot_flags,
-                libc::MAP_SHARED | libc::MAP_FIXED,
-                fd,
-                foffset as libc::off_t,
-            )
-        };
-        if std::ptr::eq(ret, libc::MAP_FAILED) {
-            return Err(io::Error::last_os_error());
-        }
+        dax::map_file(
+            addr as *mut libc::c_void,
+            len as usize,
+            prot_flags,
+            &file,
+            foffset,
+        )?;
 
         Ok(())
     }
@@ -2095,11 +2772,75 @@ impl FileSystem for PassthroughFs {
             if std::ptr::eq(ret, libc::MAP_FAILED) {
                 return Err(io::Error::last_os_error());
             }
+            dax::forget_mappings(addr as *mut libc::c_void, req.len as usize);
         }
 
         Ok(())
     }
 
+    fn getlk(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: FileLock,
+        _flags: u32,
+    ) -> io::Result<FileLock> {
+        let data = self
+            .handles
+            .read()
+            .unwrap()
+            .get(&handle)
+            .filter(|hd| hd.inode == inode)
+            .cloned()
+            .ok_or_else(ebadf)?;
+        let file = data.file.read().unwrap();
+        self.posix_locks.get(inode, &file, owner, &lock)
+    }
+
+    fn setlk(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: FileLock,
+        _flags: u32,
+    ) -> io::Result<()> {
+        let data = self
+            .handles
+            .read()
+            .unwrap()
+            .get(&handle)
+            .filter(|hd| hd.inode == inode)
+            .cloned()
+            .ok_or_else(ebadf)?;
+        let file = data.file.read().unwrap();
+        self.posix_locks.set(inode, &file, owner, &lock)
+    }
+
+    fn setlkw(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: FileLock,
+        _flags: u32,
+    ) -> io::Result<()> {
+        let data = self
+            .handles
+            .read()
+            .unwrap()
+            .get(&handle)
+            .filter(|hd| hd.inode == inode)
+            .cloned()
+            .ok_or_else(ebadf)?;
+        let file = data.file.read().unwrap();
+        self.posix_locks.set(inode, &file, owner, &lock)
+    }
+
     fn ioctl(
         &self,
         _ctx: Context,
@@ -2108,7 +2849,7 @@ impl FileSystem for PassthroughFs {
         _flags: u32,
         cmd: u32,
         arg: u64,
-        _in_size: u32,
+        data: &[u8],
         out_size: u32,
         exit_code: &Arc<AtomicI32>,
     ) -> io::Result<Vec<u8>> {
@@ -2160,6 +2901,25 @@ impl FileSystem for PassthroughFs {
                 exit_code.store(arg as i32, Ordering::SeqCst);
                 Ok(Vec::new())
             }
+            LINUX_FS_IOC_GETFLAGS => {
+                let file = self.open_inode_for_flags(inode)?;
+                Ok(get_file_flags(&file)?.to_ne_bytes().to_vec())
+            }
+            LINUX_FS_IOC_SETFLAGS => {
+                if !self.cfg.allow_file_flags {
+                    return Err(io::Error::from_raw_os_error(libc::EPERM));
+                }
+
+                let flags = data
+                    .get(..mem::size_of::<u32>())
+                    .and_then(|b| b.try_into().ok())
+                    .map(u32::from_ne_bytes)
+                    .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+
+                let file = self.open_inode_for_flags(inode)?;
+                set_file_flags(&file, flags)?;
+                Ok(Vec::new())
+            }
             _ => Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP)),
         }
     }
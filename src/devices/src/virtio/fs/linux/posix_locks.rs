@@ -0,0 +1,190 @@
+//! The POSIX locks the guest takes on the files of the share.
+//!
+//! The guest takes its locks for the processes that own them, but the device is a single host
+//! process, whose own POSIX locks would never conflict with each other. The locks of each owner are
+//! taken as open file description locks instead, on a file of the inode the owner opens when it
+//! first locks it, so that owners conflict with each other and with the host processes locking the
+//! same files. The file is closed when the owner closes the inode, which releases its locks, as
+//! closing any descriptor of a file releases the POSIX locks of a process on it.
+
+use std::collections::{hash_map, HashMap};
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::sync::Mutex;
+
+use super::super::fuse::FileLock;
+use super::super::retry::retry_syscall;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The end of the locks that reach the end of the file, whatever its size.
+const OFFSET_MAX: u64 = i64::MAX as u64;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// The files the lock owners of the guest hold their locks on, by inode and owner.
+#[derive(Default)]
+pub(crate) struct PosixLocks {
+    owners: Mutex<HashMap<(u64, u64), File>>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl PosixLocks {
+    /// Returns the first lock that conflicts with `lock` of `owner` on `inode`, open as `file`,
+    /// or `lock` unlocked if none does.
+    pub(crate) fn get(
+        &self,
+        inode: u64,
+        file: &File,
+        owner: u64,
+        lock: &FileLock,
+    ) -> io::Result<FileLock> {
+        let mut flock = to_flock(lock)?;
+        self.with_owner_file(inode, file, owner, |owner_file| {
+            fcntl_lock(owner_file, libc::F_OFD_GETLK, &mut flock)
+        })?;
+
+        Ok(from_flock(&flock))
+    }
+
+    /// Takes or releases `lock` for `owner` on `inode`, open as `file`. A lock that conflicts with
+    /// another fails with `EAGAIN`, also for the requests that wait for it: the device can't wait
+    /// without stalling the other requests, among which the one releasing the other lock may be,
+    /// so the server handles those again until they get it.
+    pub(crate) fn set(
+        &self,
+        inode: u64,
+        file: &File,
+        owner: u64,
+        lock: &FileLock,
+    ) -> io::Result<()> {
+        let mut flock = to_flock(lock)?;
+
+        // Releasing a lock of an owner that has none does nothing
+        if lock.type_ == libc::F_UNLCK as u32
+            && !self.owners.lock().unwrap().contains_key(&(inode, owner))
+        {
+            return Ok(());
+        }
+
+        let res = self.with_owner_file(inode, file, owner, |owner_file| {
+            fcntl_lock(owner_file, libc::F_OFD_SETLK, &mut flock)
+        });
+        match res {
+            Err(e) if e.raw_os_error() == Some(libc::EACCES) => {
+                Err(io::Error::from_raw_os_error(libc::EAGAIN))
+            }
+            res => res,
+        }
+    }
+
+    /// Releases all the locks of `owner` on `inode`.
+    pub(crate) fn release(&self, inode: u64, owner: u64) {
+        self.owners.lock().unwrap().remove(&(inode, owner));
+    }
+
+    /// Calls `f` with the file of `owner` on `inode`, opening it from `file` if the owner has none
+    /// yet.
+    fn with_owner_file<F>(&self, inode: u64, file: &File, owner: u64, f: F) -> io::Result<()>
+    where
+        F: FnOnce(&File) -> io::Result<()>,
+    {
+        let mut owners = self.owners.lock().unwrap();
+        let owner_file = match owners.entry((inode, owner)) {
+            hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            hash_map::Entry::Vacant(entry) => entry.insert(reopen(file)?),
+        };
+        f(owner_file)
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Opens a new file description of `file`, for reading and writing if the host allows it, so that
+/// the owner can take both kinds of locks through it, or with the access mode of `file` otherwise.
+fn reopen(file: &File) -> io::Result<File> {
+    let path = CString::new(format!("/proc/self/fd/{}", file.as_raw_fd())).unwrap();
+    let open = |flags: i32| {
+        // Safe because this doesn't modify any memory and we check the return value.
+        retry_syscall(|| unsafe { libc::open(path.as_ptr(), flags | libc::O_CLOEXEC) })
+    };
+
+    let mut fd = open(libc::O_RDWR);
+    if fd < 0 {
+        // Safe because this doesn't modify any memory and we check the return value.
+        let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        fd = open(flags & libc::O_ACCMODE);
+    }
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safe because we just opened this fd.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Runs the lock command `cmd` on `file`, again only while it is interrupted: a lock that conflicts
+/// fails with `EAGAIN`, which isn't a shortage of the host to wait out.
+fn fcntl_lock(file: &File, cmd: libc::c_int, flock: &mut libc::flock) -> io::Result<()> {
+    loop {
+        // Safe because the kernel only writes to `flock` and we check the return value.
+        let res = unsafe { libc::fcntl(file.as_raw_fd(), cmd, flock as *mut _) };
+        if res >= 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINTR) {
+            return Err(err);
+        }
+    }
+}
+
+/// Returns the `flock` of `lock`, whose range ends at `OFFSET_MAX` if it reaches the end of the
+/// file.
+fn to_flock(lock: &FileLock) -> io::Result<libc::flock> {
+    if lock.start > OFFSET_MAX || lock.end > OFFSET_MAX || lock.end < lock.start {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    // Safe because `flock` is a plain C struct.
+    let mut flock: libc::flock = unsafe { std::mem::zeroed() };
+    flock.l_type = lock.type_ as libc::c_short;
+    flock.l_whence = libc::SEEK_SET as libc::c_short;
+    flock.l_start = lock.start as libc::off_t;
+    flock.l_len = if lock.end == OFFSET_MAX {
+        0
+    } else {
+        (lock.end - lock.start + 1) as libc::off_t
+    };
+    Ok(flock)
+}
+
+/// Returns the lock of `flock`. Its process is left out, as the open file description locks have
+/// none and the ones of the host processes mean nothing to the guest.
+fn from_flock(flock: &libc::flock) -> FileLock {
+    let start = flock.l_start as u64;
+    FileLock {
+        start,
+        end: if flock.l_len == 0 {
+            OFFSET_MAX
+        } else {
+            start + flock.l_len as u64 - 1
+        },
+        type_: flock.l_type as u32,
+        pid: 0,
+    }
+}
@@ -0,0 +1,274 @@
+//! The owners, modes and device numbers the guest gives the entries of a share whose ownership is
+//! virtual, see `FsOwnership::Virtual`.
+//!
+//! A device running as a user other than root can neither give the host files to other users nor
+//! create device nodes, so extracting an image in the guest fails on its first `chown` or `mknod`.
+//! With a virtual ownership, neither is ever attempted on the host: the entries the guest creates
+//! stay owned by the user the device runs as, and readable and writable by it, while the owner,
+//! the mode and the device number the guest gave them are kept in the [`OVERRIDE_XATTR`] extended
+//! attribute and reported instead of the host ones. The device nodes are empty regular files on
+//! the host.
+//!
+//! The attribute is the one macOS hosts keep the ownership in, as `uid:gid:0mode`, with the device
+//! number appended as `:rdev` for the device nodes, so that a share written on one host reads the
+//! same on the other. Linux allows no user extended attributes on symlinks, which keep the owners
+//! they have on the host.
+
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::fd::RawFd;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The extended attribute keeping the ownership the guest sees.
+pub(crate) const OVERRIDE_XATTR: &CStr = c"user.containers.override_stat";
+
+/// The longest value of [`OVERRIDE_XATTR`]: two ids, a mode and a device number.
+const MAX_VALUE_LEN: usize = 64;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// What [`OVERRIDE_XATTR`] reports of an entry in place of its host attributes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct StatOverride {
+    pub uid: u32,
+    pub gid: u32,
+    /// The mode, with the type of the entry, which differs from the host one for device nodes.
+    pub mode: u32,
+    /// The device number of a device node.
+    pub rdev: u64,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl StatOverride {
+    /// Returns the override reporting the attributes `st` of an entry as they are.
+    pub(crate) fn from_stat(st: &libc::stat64) -> Self {
+        StatOverride {
+            uid: st.st_uid,
+            gid: st.st_gid,
+            mode: st.st_mode,
+            rdev: st.st_rdev,
+        }
+    }
+
+    /// Replaces the attributes `st` of an entry on the host with the ones the guest gave it.
+    pub(crate) fn apply(&self, st: &mut libc::stat64) {
+        st.st_uid = self.uid;
+        st.st_gid = self.gid;
+        let file_type = match self.mode & libc::S_IFMT {
+            0 => st.st_mode & libc::S_IFMT,
+            file_type => file_type,
+        };
+        st.st_mode = file_type | (self.mode & !libc::S_IFMT);
+        if is_device(file_type) {
+            st.st_rdev = self.rdev;
+        }
+    }
+
+    fn parse(value: &[u8]) -> Option<Self> {
+        let value = std::str::from_utf8(value).ok()?;
+        let mut items = value.split(':');
+        let uid = items.next()?.parse().ok()?;
+        let gid = items.next()?.parse().ok()?;
+        let mode = u32::from_str_radix(items.next()?, 8).ok()?;
+        let rdev = match items.next() {
+            Some(rdev) => rdev.parse().ok()?,
+            None => 0,
+        };
+        Some(StatOverride {
+            uid,
+            gid,
+            mode,
+            rdev,
+        })
+    }
+
+    fn encode(&self) -> String {
+        let mut value = format!("{}:{}:0{:o}", self.uid, self.gid, self.mode);
+        if is_device(self.mode & libc::S_IFMT) {
+            value.push_str(&format!(":{}", self.rdev));
+        }
+        value
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Returns the override of the entry `name` of the directory `dir`, or of `dir` itself if there is
+/// no name, if it has one. Both may be `O_PATH` descriptors.
+pub(crate) fn read(dir: RawFd, name: Option<&CStr>) -> Option<StatOverride> {
+    let path = entry_path(dir, name);
+    let mut buf = [0u8; MAX_VALUE_LEN];
+    // Safe because this only writes to `buf`, within its length, and we check the return value.
+    // The path of `dir` itself is a link to follow, the path of an entry isn't.
+    let res = unsafe {
+        let get = if name.is_none() {
+            libc::getxattr
+        } else {
+            libc::lgetxattr
+        };
+        get(
+            path.as_ptr(),
+            OVERRIDE_XATTR.as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+        )
+    };
+    if res < 0 {
+        return None;
+    }
+    StatOverride::parse(&buf[..res as usize])
+}
+
+/// Keeps `value` as the override of the entry `name` of the directory `dir`, or of `dir` itself if
+/// there is no name.
+pub(crate) fn write(dir: RawFd, name: Option<&CStr>, value: &StatOverride) -> io::Result<()> {
+    let path = entry_path(dir, name);
+    let value = value.encode();
+    // Safe because this doesn't modify any memory and we check the return value.
+    let res = unsafe {
+        let set = if name.is_none() {
+            libc::setxattr
+        } else {
+            libc::lsetxattr
+        };
+        set(
+            path.as_ptr(),
+            OVERRIDE_XATTR.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Changes the override of the entry open as `fd` with `change`, starting from its host
+/// attributes `st` if it has none yet. Changing a symlink does nothing, as it can't have one.
+pub(crate) fn update(
+    fd: RawFd,
+    st: &libc::stat64,
+    change: impl FnOnce(&mut StatOverride),
+) -> io::Result<()> {
+    if st.st_mode & libc::S_IFMT == libc::S_IFLNK {
+        return Ok(());
+    }
+    let mut value = read(fd, None).unwrap_or_else(|| StatOverride::from_stat(st));
+    change(&mut value);
+    write(fd, None, &value)
+}
+
+/// Returns the mode of the host file of an entry the guest gives `mode`: its permissions, with the
+/// ones the device needs to keep using it as the owner.
+pub(crate) fn host_mode(mode: u32) -> u32 {
+    let owner = if mode & libc::S_IFMT == libc::S_IFDIR {
+        libc::S_IRWXU
+    } else {
+        libc::S_IRUSR | libc::S_IWUSR
+    };
+    (mode & 0o777) | owner
+}
+
+/// Returns whether the file type `file_type` is one of a device node, which the host gets a
+/// regular file for.
+pub(crate) fn is_device(file_type: u32) -> bool {
+    file_type == libc::S_IFCHR || file_type == libc::S_IFBLK
+}
+
+fn entry_path(dir: RawFd, name: Option<&CStr>) -> CString {
+    let mut path = format!("/proc/self/fd/{dir}").into_bytes();
+    if let Some(name) = name {
+        path.push(b'/');
+        path.extend_from_slice(name.to_bytes());
+    }
+    // The names of the entries have no nul bytes
+    CString::new(path).unwrap()
+}
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::os::fd::AsRawFd;
+
+    use super::*;
+
+    #[test]
+    fn encoding() {
+        // The values macOS hosts write read the same
+        let value = StatOverride::parse(b"1000:100:0100644").unwrap();
+        assert_eq!(
+            value,
+            StatOverride {
+                uid: 1000,
+                gid: 100,
+                mode: libc::S_IFREG | 0o644,
+                rdev: 0,
+            }
+        );
+        assert_eq!(value.encode(), "1000:100:0100644");
+
+        let device = StatOverride {
+            uid: 0,
+            gid: 0,
+            mode: libc::S_IFCHR | 0o666,
+            rdev: 0x103,
+        };
+        assert_eq!(device.encode(), "0:0:020666:259");
+        assert_eq!(
+            StatOverride::parse(device.encode().as_bytes()),
+            Some(device)
+        );
+        assert!(StatOverride::parse(b"0:0").is_none());
+        assert!(StatOverride::parse(b"a:0:0644").is_none());
+
+        // A device node is reported as one, whatever the host file is
+        let mut st: libc::stat64 = unsafe { std::mem::zeroed() };
+        st.st_mode = libc::S_IFREG | 0o600;
+        device.apply(&mut st);
+        assert_eq!(st.st_mode, libc::S_IFCHR | 0o666);
+        assert_eq!(st.st_rdev, 0x103);
+        assert_eq!(host_mode(libc::S_IFDIR | 0o500), 0o700);
+        assert_eq!(host_mode(libc::S_IFREG | 0o4444), 0o644);
+    }
+
+    #[test]
+    fn read_write() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("file"), b"").unwrap();
+        let root = File::open(dir.path()).unwrap();
+        let name = c"file";
+        assert!(read(root.as_raw_fd(), Some(name)).is_none());
+
+        let value = StatOverride {
+            uid: 1234,
+            gid: 5678,
+            mode: libc::S_IFREG | 0o640,
+            rdev: 0,
+        };
+        write(root.as_raw_fd(), Some(name), &value).unwrap();
+        assert_eq!(read(root.as_raw_fd(), Some(name)), Some(value));
+
+        // The directory itself is reached through its descriptor
+        let mut st: libc::stat64 = unsafe { std::mem::zeroed() };
+        st.st_mode = libc::S_IFDIR | 0o755;
+        update(root.as_raw_fd(), &st, |value| value.uid = 42).unwrap();
+        let value = read(root.as_raw_fd(), None).unwrap();
+        assert_eq!((value.uid, value.mode), (42, libc::S_IFDIR | 0o755));
+    }
+}
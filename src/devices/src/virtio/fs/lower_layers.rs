@@ -0,0 +1,215 @@
+//! Caches of the lower layers of overlays, shared by the overlays stacked on the same ones.
+//!
+//! Sandboxes started from the same image stack their overlays on the same read-only lower layers,
+//! and each overlay would otherwise learn the same things about them on its own: the path filter
+//! of each layer, built by walking all of it, and the attributes of the files the guests look at.
+//! A [`LowerLayerSet`] holds them once for all the overlays attached to it, so that the overlays
+//! started after the first one find them already built, and the memory they take doesn't grow
+//! with the number of overlays.
+//!
+//! What the caches hold is only valid as long as the lower layers aren't changed on the host.
+//! Refreshing the layers of any overlay attached to a set drops the caches of the set, for all
+//! of its overlays.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+
+use super::layer_filter::LayerFilter;
+use super::layer_paths;
+use crate::virtio::bindings;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The number of attributes a set caches, above which it forgets them all.
+const MAX_ATTRS: usize = 1 << 16;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// The caches of a stack of lower layers, which overlays with these lower layers attach to with
+/// `overlayfs::Config::lower_layers`.
+///
+/// Cloning it gives another handle to the same caches, which are freed with the last one.
+#[derive(Clone)]
+pub struct LowerLayerSet(Arc<LowerLayers>);
+
+struct LowerLayers {
+    /// The canonical paths of the layers, from the bottom one.
+    layers: Vec<PathBuf>,
+    /// The path filter of each layer, built by the first overlay needing it.
+    filters: Vec<Mutex<Option<Arc<LayerFilter>>>>,
+    /// The attributes of the files of the layers, by their device and inode numbers.
+    attrs: RwLock<HashMap<(u64, u64), bindings::stat64>>,
+}
+
+/// The sets handed out by [`LowerLayerSet::shared`], kept until their last handle is dropped.
+static SHARED: Mutex<Vec<Weak<LowerLayers>>> = Mutex::new(Vec::new());
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl LowerLayerSet {
+    /// Creates the caches of the lower layers `layers`, given from the bottom one as in
+    /// `overlayfs::Config::layers`, without the top layer.
+    pub fn new(layers: &[PathBuf]) -> io::Result<Self> {
+        let layers = layer_paths::canonicalize(layers)?;
+        let filters = layers.iter().map(|_| Mutex::new(None)).collect();
+        Ok(LowerLayerSet(Arc::new(LowerLayers {
+            layers,
+            filters,
+            attrs: Default::default(),
+        })))
+    }
+
+    /// Returns the set of the lower layers `layers` another overlay of the process is attached
+    /// to, or a new one if there is none.
+    pub fn shared(layers: &[PathBuf]) -> io::Result<Self> {
+        let canonical = layer_paths::canonicalize(layers)?;
+
+        let mut shared = SHARED.lock().unwrap();
+        shared.retain(|set| set.strong_count() > 0);
+        if let Some(set) = shared
+            .iter()
+            .filter_map(Weak::upgrade)
+            .find(|set| set.layers == canonical)
+        {
+            return Ok(LowerLayerSet(set));
+        }
+
+        let set = Self::new(&canonical)?;
+        shared.push(Arc::downgrade(&set.0));
+        Ok(set)
+    }
+
+    /// Returns the canonical paths of the layers, from the bottom one.
+    pub fn layers(&self) -> &[PathBuf] {
+        &self.0.layers
+    }
+
+    /// Returns the number of handles to the set, one for each overlay attached to it and for each
+    /// clone the embedder keeps.
+    pub fn handles(&self) -> usize {
+        Arc::strong_count(&self.0)
+    }
+
+    /// Checks that `layers`, the canonical lower layers of an overlay, are the layers of the set.
+    pub(crate) fn check(&self, layers: &[PathBuf]) -> io::Result<()> {
+        if layers != self.layers() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the lower layers of the overlay aren't the ones of its lower layer set",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the path filter of the layer `layer_idx`, built with `build` if no overlay did yet.
+    pub(crate) fn filter(
+        &self,
+        layer_idx: usize,
+        build: impl FnOnce() -> LayerFilter,
+    ) -> Arc<LayerFilter> {
+        self.0.filters[layer_idx]
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| Arc::new(build()))
+            .clone()
+    }
+
+    /// Returns the attributes of the file `ino` of the device `dev` of a lower layer, from `stat`
+    /// if no overlay looked at them yet.
+    pub(crate) fn attr(
+        &self,
+        dev: u64,
+        ino: u64,
+        stat: impl FnOnce() -> io::Result<bindings::stat64>,
+    ) -> io::Result<bindings::stat64> {
+        if let Some(st) = self.0.attrs.read().unwrap().get(&(dev, ino)) {
+            return Ok(*st);
+        }
+
+        let st = stat()?;
+        let mut attrs = self.0.attrs.write().unwrap();
+        if attrs.len() >= MAX_ATTRS {
+            attrs.clear();
+        }
+        attrs.insert((dev, ino), st);
+        Ok(st)
+    }
+
+    /// Forgets what the set caches, after the layers were changed on the host.
+    pub(crate) fn clear(&self) {
+        for filter in &self.0.filters {
+            *filter.lock().unwrap() = None;
+        }
+        self.0.attrs.write().unwrap().clear();
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Trait Implementations
+//--------------------------------------------------------------------------------------------------
+
+impl fmt::Debug for LowerLayerSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LowerLayerSet")
+            .field("layers", &self.0.layers)
+            .finish_non_exhaustive()
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+
+    use super::*;
+
+    #[test]
+    fn shared() {
+        let dir = tempfile::tempdir().unwrap();
+        let layers = vec![dir.path().join("a"), dir.path().join("b")];
+        for layer in &layers {
+            std::fs::create_dir(layer).unwrap();
+        }
+
+        // The overlays on the same layers, however they are named, share their caches
+        let set = LowerLayerSet::shared(&layers).unwrap();
+        let other =
+            LowerLayerSet::shared(&[dir.path().join("a/../a"), dir.path().join("b/")]).unwrap();
+        assert!(Arc::ptr_eq(&set.0, &other.0));
+        assert_eq!(set.handles(), 2);
+        assert!(!Arc::ptr_eq(
+            &set.0,
+            &LowerLayerSet::shared(&layers[..1]).unwrap().0
+        ));
+
+        // Attributes are read once, until the set is cleared
+        let stats = Cell::new(0);
+        let stat = || {
+            stats.set(stats.get() + 1);
+            Ok(unsafe { std::mem::zeroed() })
+        };
+        set.attr(1, 2, stat).unwrap();
+        other.attr(1, 2, stat).unwrap();
+        assert_eq!(stats.get(), 1);
+        other.clear();
+        set.attr(1, 2, stat).unwrap();
+        assert_eq!(stats.get(), 2);
+
+        // A set is dropped with its last handle
+        let weak = Arc::downgrade(&set.0);
+        drop((set, other));
+        assert!(weak.upgrade().is_none());
+    }
+}
@@ -1,7 +1,35 @@
+use std::ffi::CStr;
+use std::fs::File;
 use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 
 use super::super::super::linux_errno::linux_error;
 
+use super::super::bindings::{LINUX_FS_APPEND_FL, LINUX_FS_IMMUTABLE_FL};
+use super::super::filesystem::{BirthTime, ZeroCopyWriter};
+use super::super::retry::retry_syscall;
+
+/// The `SEEK_DATA` of the guest, which is `SEEK_HOLE` on macOS.
+const LINUX_SEEK_DATA: u32 = 3;
+
+/// The `SEEK_HOLE` of the guest, which is `SEEK_DATA` on macOS.
+const LINUX_SEEK_HOLE: u32 = 4;
+
+/// The most a copy of a range of a file copies at once, so that the other requests of the guest
+/// don't wait for it too long.
+const COPY_RANGE_MAX_SIZE: u64 = 16 << 20;
+
+/// The size of the buffer a range of a file is copied through.
+const COPY_RANGE_BUFFER_SIZE: u64 = 1 << 20;
+
+/// Reads smaller than this are copied as is, as skipping their holes doesn't make up for the cost
+/// of looking for them.
+const SPARSE_READ_MIN_SIZE: usize = 128 << 10;
+
+/// A buffer of zeros standing for the holes of sparse files.
+static ZEROES: [u8; 64 << 10] = [0; 64 << 10];
+
 pub fn ebadf() -> io::Error {
     linux_error(io::Error::from_raw_os_error(libc::EBADF))
 }
@@ -9,3 +37,279 @@ pub fn ebadf() -> io::Error {
 pub fn einval() -> io::Error {
     linux_error(io::Error::from_raw_os_error(libc::EINVAL))
 }
+
+/// Copies up to `size` bytes of `f` from `offset` to `w`, like `ZeroCopyWriter::write_from`, but
+/// fills the holes of sparse files with zeros instead of reading them from the disk. Returns the
+/// number of bytes copied, which is only short at the end of the file.
+pub fn write_from_sparse<W: io::Write + ZeroCopyWriter>(
+    w: &mut W,
+    f: &File,
+    size: usize,
+    offset: u64,
+) -> io::Result<usize> {
+    if size < SPARSE_READ_MIN_SIZE {
+        return w.write_from(f, size, offset);
+    }
+
+    let end = offset.saturating_add(size as u64);
+    let mut pos = offset;
+    while pos < end {
+        let (data, eof) = match seek(f, pos, libc::SEEK_DATA) {
+            Ok(data) => (data, false),
+            // The rest of the file is a hole
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => (f.metadata()?.len(), true),
+            // The file system can't tell where the holes are
+            Err(_) if pos == offset => return w.write_from(f, size, offset),
+            Err(e) => return Err(e),
+        };
+
+        let hole_end = data.min(end);
+        while pos < hole_end {
+            let len = (hole_end - pos).min(ZEROES.len() as u64) as usize;
+            w.write_all(&ZEROES[..len])?;
+            pos += len as u64;
+        }
+        if eof || data >= end {
+            break;
+        }
+
+        // A hole follows any data, at worst at the end of the file
+        let data_end = seek(f, pos, libc::SEEK_HOLE)?.min(end);
+        while pos < data_end {
+            let n = w.write_from(f, (data_end - pos) as usize, pos)?;
+            if n == 0 {
+                // The file was truncated
+                return Ok((pos - offset) as usize);
+            }
+            pos += n as u64;
+        }
+    }
+
+    Ok((pos - offset) as usize)
+}
+
+/// Returns the extents of `f` holding data between `offset` and `end`, as their offsets and
+/// lengths, leaving its holes out. The whole range is returned if the file system can't tell where
+/// the holes are.
+pub fn data_extents(f: &File, offset: u64, end: u64) -> io::Result<Vec<(u64, u64)>> {
+    let mut extents = Vec::new();
+    let mut pos = offset;
+    while pos < end {
+        let data = match seek(f, pos, libc::SEEK_DATA) {
+            Ok(data) => data,
+            // The rest of the file is a hole
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => break,
+            // The file system can't tell where the holes are
+            Err(_) if pos == offset => return Ok(vec![(offset, end - offset)]),
+            Err(e) => return Err(e),
+        };
+        if data >= end {
+            break;
+        }
+
+        // A hole follows any data, at worst at the end of the file
+        let data_end = seek(f, data, libc::SEEK_HOLE)?.min(end);
+        if data_end <= data {
+            // The file was truncated
+            break;
+        }
+        extents.push((data, data_end - data));
+        pos = data_end;
+    }
+
+    Ok(extents)
+}
+
+/// Moves the offset of `f` like `lseek(2)` does for the guest, whose `SEEK_DATA` and `SEEK_HOLE`
+/// have the numbers of each other on macOS. They find the data and holes of the host file, unless
+/// its file system can't tell where they are. The file is then taken to have no holes but the one
+/// at its end, as Linux does for such file systems.
+pub fn guest_seek(f: &File, offset: u64, whence: u32) -> io::Result<u64> {
+    let whence = match whence {
+        LINUX_SEEK_DATA => libc::SEEK_DATA,
+        LINUX_SEEK_HOLE => libc::SEEK_HOLE,
+        whence => whence as libc::c_int,
+    };
+    match seek(f, offset, whence) {
+        Err(e)
+            if (whence == libc::SEEK_DATA || whence == libc::SEEK_HOLE)
+                && offset <= i64::MAX as u64
+                && matches!(e.raw_os_error(), Some(libc::EINVAL | libc::ENOTSUP)) =>
+        {
+            let size = f.metadata()?.len();
+            if offset >= size {
+                return Err(linux_error(io::Error::from_raw_os_error(libc::ENXIO)));
+            }
+            let pos = if whence == libc::SEEK_DATA {
+                offset
+            } else {
+                size
+            };
+            seek(f, pos, libc::SEEK_SET).map_err(linux_error)
+        }
+        res => res.map_err(linux_error),
+    }
+}
+
+/// Copies up to `len` bytes of `from` at `offset_in` to `to` at `offset_out` for the guest, like
+/// `copy_file_range(2)`, and returns how many were copied. A copy of the whole of `from` to an empty
+/// `to` is left to `fcopyfile(3)`, which can clone the data or skip the holes of the file. Other
+/// ranges are copied through a buffer, at most `COPY_RANGE_MAX_SIZE` bytes at a time, as the guest
+/// asks for the rest of a short copy.
+pub fn copy_file_range(
+    from: &File,
+    offset_in: u64,
+    to: &File,
+    offset_out: u64,
+    len: u64,
+) -> io::Result<usize> {
+    let size = from.metadata().map_err(linux_error)?.len();
+    if offset_in == 0
+        && offset_out == 0
+        && len >= size
+        && to.metadata().map_err(linux_error)?.len() == 0
+    {
+        // `fcopyfile` copies from the offsets of the files
+        seek(from, 0, libc::SEEK_SET).map_err(linux_error)?;
+        seek(to, 0, libc::SEEK_SET).map_err(linux_error)?;
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe {
+            libc::fcopyfile(
+                from.as_raw_fd(),
+                to.as_raw_fd(),
+                std::ptr::null_mut(),
+                libc::COPYFILE_DATA,
+            )
+        };
+        if res < 0 {
+            return Err(linux_error(io::Error::last_os_error()));
+        }
+        return Ok(size as usize);
+    }
+
+    let len = len
+        .min(COPY_RANGE_MAX_SIZE)
+        .min(size.saturating_sub(offset_in));
+    let mut buf = vec![0; len.min(COPY_RANGE_BUFFER_SIZE) as usize];
+    let mut copied = 0;
+    while copied < len {
+        let chunk = (len - copied).min(buf.len() as u64) as usize;
+        let read = from
+            .read_at(&mut buf[..chunk], offset_in + copied)
+            .map_err(linux_error)?;
+        if read == 0 {
+            break;
+        }
+        to.write_all_at(&buf[..read], offset_out + copied)
+            .map_err(linux_error)?;
+        copied += read as u64;
+    }
+
+    Ok(copied as usize)
+}
+
+fn seek(f: &File, offset: u64, whence: libc::c_int) -> io::Result<u64> {
+    // SAFETY: this doesn't modify any memory and we check the return value.
+    let res = unsafe { libc::lseek(f.as_raw_fd(), offset as _, whence) };
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res as u64)
+    }
+}
+
+/// Flushes the entries of the directory `path`, relative to `dirfd`, to the disk, so that the files
+/// created, removed or renamed in it survive a crash.
+pub fn sync_dir_at(dirfd: RawFd, path: &CStr) -> io::Result<()> {
+    // Safe because this doesn't modify any memory and we check the return value.
+    let fd = retry_syscall(|| unsafe {
+        libc::openat(
+            dirfd,
+            path.as_ptr(),
+            libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        )
+    });
+    if fd < 0 {
+        return Err(linux_error(io::Error::last_os_error()));
+    }
+
+    // Safe because we just opened this fd. `sync_all` issues F_FULLFSYNC, which also flushes the
+    // drive's write cache.
+    unsafe { File::from_raw_fd(fd) }
+        .sync_all()
+        .map_err(linux_error)
+}
+
+/// Returns the creation time of the file at `path`.
+pub fn get_birth_time(path: &CStr) -> io::Result<Option<BirthTime>> {
+    let mut st = std::mem::MaybeUninit::<libc::stat>::zeroed();
+    // Safe because this only writes to `st` and we check the return value.
+    let res = retry_syscall(|| unsafe { libc::lstat(path.as_ptr(), st.as_mut_ptr()) });
+    if res < 0 {
+        return Err(linux_error(io::Error::last_os_error()));
+    }
+
+    // Safe because lstat succeeded.
+    let st = unsafe { st.assume_init() };
+    Ok(Some(BirthTime {
+        sec: st.st_birthtime,
+        nsec: st.st_birthtime_nsec as u32,
+    }))
+}
+
+/// Returns the immutable and append-only flags of `file`, in the format of the Linux
+/// `FS_IOC_GETFLAGS`. Both the user and the system variants of the flags are reported.
+pub fn get_file_flags(file: &File) -> io::Result<u32> {
+    let st_flags = stat_flags(file)?;
+
+    let mut flags = 0;
+    if st_flags & (libc::UF_IMMUTABLE | libc::SF_IMMUTABLE) != 0 {
+        flags |= LINUX_FS_IMMUTABLE_FL;
+    }
+    if st_flags & (libc::UF_APPEND | libc::SF_APPEND) != 0 {
+        flags |= LINUX_FS_APPEND_FL;
+    }
+    Ok(flags)
+}
+
+/// Sets the immutable and append-only flags of `file` to those in `flags`, in the format of the
+/// Linux `FS_IOC_SETFLAGS`. Flags are set with their user variant, which doesn't require
+/// privileges, and the other flags of the file are kept as they are on the host.
+pub fn set_file_flags(file: &File, flags: u32) -> io::Result<()> {
+    let current = stat_flags(file)?;
+
+    let mut new = current;
+    for (linux_flag, user_flag, system_flag) in [
+        (LINUX_FS_IMMUTABLE_FL, libc::UF_IMMUTABLE, libc::SF_IMMUTABLE),
+        (LINUX_FS_APPEND_FL, libc::UF_APPEND, libc::SF_APPEND),
+    ] {
+        if flags & linux_flag == 0 {
+            new &= !(user_flag | system_flag);
+        } else if current & system_flag == 0 {
+            new |= user_flag;
+        }
+    }
+    if new == current {
+        return Ok(());
+    }
+
+    // Safe because this doesn't modify any memory and we check the return value.
+    let res = unsafe { libc::fchflags(file.as_raw_fd(), new) };
+    if res < 0 {
+        return Err(linux_error(io::Error::last_os_error()));
+    }
+
+    Ok(())
+}
+
+fn stat_flags(file: &File) -> io::Result<u32> {
+    let mut st = std::mem::MaybeUninit::<libc::stat>::zeroed();
+    // Safe because this only writes to `st` and we check the return value.
+    let res = retry_syscall(|| unsafe { libc::fstat(file.as_raw_fd(), st.as_mut_ptr()) });
+    if res < 0 {
+        return Err(linux_error(io::Error::last_os_error()));
+    }
+
+    // Safe because fstat succeeded.
+    Ok(unsafe { st.assume_init() }.st_flags)
+}
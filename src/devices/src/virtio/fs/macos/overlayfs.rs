@@ -1,29 +1,58 @@
-use std::collections::{btree_map, BTreeMap, HashMap, HashSet};
-use std::ffi::{CStr, CString};
-use std::fs::File;
-use std::io;
+use std::borrow::Cow;
+use std::collections::{btree_map, BTreeMap, HashMap, HashSet, VecDeque};
+use std::ffi::{CStr, CString, OsStr};
+use std::fs::{self, File};
+use std::io::{self, Write};
 use std::mem::MaybeUninit;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::FileExt;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
-use std::path::PathBuf;
-use std::ptr::null_mut;
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::path::{Path, PathBuf};
+use std::ptr::{null_mut, NonNull};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, AtomicU8, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 
 use crossbeam_channel::{unbounded, Sender};
 use hvf::MemoryMapping;
-use intaglio::cstr::SymbolTable;
-use intaglio::Symbol;
+use utils::fd_budget::{FdClient, FdGrant};
 
 use crate::virtio::bindings;
+use crate::virtio::fs::case_names;
+use crate::virtio::fs::content_store::{self, ContentStore};
+use crate::virtio::fs::copy_up::{self, PathLockGuard, PathLocks};
+use crate::virtio::fs::dax;
+use crate::virtio::fs::dax_windows::{self, DaxWindows, Window};
 use crate::virtio::fs::filesystem::{
-    Context, DirEntry, Entry, ExportTable, Extensions, FileSystem, FsOptions, GetxattrReply,
-    ListxattrReply, OpenOptions, SecContext, SetattrValid, ZeroCopyReader, ZeroCopyWriter,
+    BirthTime, Context, DirEntry, Entry, ExportTable, Extensions, FileSystem, FsOptions,
+    GetxattrReply, ListxattrReply, OpenOptions, SecContext, SetattrValid, ZeroCopyReader,
+    ZeroCopyWriter,
+};
+use crate::virtio::fs::fs_utils::{
+    copy_file_range, data_extents, get_file_flags, guest_seek, set_file_flags, sync_dir_at,
+    write_from_sparse,
 };
 use crate::virtio::fs::fuse;
+use crate::virtio::fs::handle_quota::{FsHandleQuota, HandleGrant};
+use crate::virtio::fs::hooks::{FsHookPoint, FsHooks};
+use crate::virtio::fs::host_metadata::{self, XattrRetention};
+use crate::virtio::fs::inode_map::{self, InodeMap};
+use crate::virtio::fs::inode_path::{InodePath, Name, NameTable};
+use crate::virtio::fs::intent_log::{Intent, IntentGuard, IntentLog, INTENT_LOG_FILE};
+use crate::virtio::fs::layer_diff::{self, LayerSnapshot};
+use crate::virtio::fs::layer_filter::LayerFilter;
+use crate::virtio::fs::layer_manifest;
+use crate::virtio::fs::layer_paths;
+use crate::virtio::fs::layer_stats::{FsLayerStats, LayerCounters};
+use crate::virtio::fs::lower_layers::LowerLayerSet;
 use crate::virtio::fs::multikey::MultikeyBTreeMap;
+#[cfg(feature = "oci")]
+use crate::virtio::fs::oci::LazyLayer;
+use crate::virtio::fs::page_cache::{self, SequentialReads};
+use crate::virtio::fs::prealloc::{self, SequentialWrites};
+use crate::virtio::fs::retry::retry_syscall;
+use crate::virtio::fs::whiteout_probe::{WhiteoutCache, WhiteoutProbes};
+use crate::virtio::fs::FsPageCache;
 use crate::virtio::linux_errno::{linux_error, LINUX_ERANGE};
 
 
@@ -44,15 +73,49 @@ const WHITEOUT_PREFIX: &str = ".wh.";
 /// The marker for opaque directories
 const OPAQUE_MARKER: &str = ".wh..wh..opq";
 
+/// The marker for opaque directories, as a C string for the `*at()` syscalls
+const OPAQUE_MARKER_CSTR: &[u8] = b".wh..wh..opq\0";
+
+/// The prefix of the staging file a regular file is copied to before being renamed into place.
+///
+/// It starts with the whiteout prefix so that the guest can neither see nor create it.
+const COPY_UP_STAGING_PREFIX: &str = ".wh..wh..copyup.";
+
+/// The file of the top layer recording the owner and permissions set on lower layer directories,
+/// which are changed without copying the directories up. Each change is appended to it, and the
+/// records overridden since are dropped when the file system is created.
+///
+/// It starts with the whiteout prefix so that the guest can neither see nor create it.
+const DIR_OVERRIDES_FILE: &str = ".wh..wh..diroverrides";
+
+/// The extended attribute recording which source a copy-up staging file is a copy of
+const COPY_UP_XATTR_KEY: &[u8] = b"user.overlayfs.copyup\0";
+
+/// The directory has not been scanned for whiteouts yet
+const WHITEOUTS_UNKNOWN: u8 = 0;
+
+/// The directory contains neither whiteouts nor an opaque marker
+const WHITEOUTS_ABSENT: u8 = 1;
+
+/// The directory may contain whiteouts or an opaque marker
+const WHITEOUTS_PRESENT: u8 = 2;
+
 /// The volume directory
 const VOL_DIR: &str = ".vol";
 
+/// The number of directory handles kept open besides the layer roots, see [`OverlayFs::dir_fd`]
+const MAX_DIR_FDS: usize = 256;
+
 /// The owner and permissions attribute
 const OWNER_PERMS_XATTR_KEY: &[u8] = b"user.vm.owner_perms\0";
 
 /// Maximum allowed number of layers for the overlay filesystem.
 const MAX_LAYERS: usize = 128;
 
+/// The size of the reads and writes copying up a file that can't be cloned, large enough for a
+/// layer of several gigabytes not to take millions of them
+const COPY_CHUNK_SIZE: usize = 1 << 20;
+
 #[cfg(not(feature = "efi"))]
 static INIT_BINARY: &[u8] = include_bytes!("../../../../../../init/init");
 
@@ -93,11 +156,31 @@ pub(crate) struct InodeData {
     /// Reference count for this inode from the perspective of [`FileSystem::lookup`]
     pub(crate) refcount: AtomicU64,
 
-    /// Path to inode
-    pub(crate) path: Vec<Symbol>,
+    /// Path to inode, shared with the inodes below it
+    pub(crate) path: Arc<InodePath>,
 
     /// The layer index this inode belongs to
     pub(crate) layer_idx: usize,
+
+    /// Handle of a layer root, which the entries of the layer are resolved from with the `*at()`
+    /// syscall family.
+    ///
+    /// Only populated for layer roots. The handles of the other directories are opened on demand,
+    /// see [`OverlayFs::dir_fd`].
+    pub(crate) dirfd: Option<Arc<File>>,
+
+    /// Whether this directory contains whiteouts, one of the `WHITEOUTS_*` constants.
+    ///
+    /// Computed lazily by [`OverlayFs::may_have_whiteouts`] and only meaningful for directories.
+    pub(crate) whiteouts: AtomicU8,
+
+    /// The generation of the inode, which tells it apart from the files that had the same host
+    /// inode number before it: the birth time of the file if the host records it, or else see
+    /// [`OverlayFs::retire_generation`].
+    pub(crate) generation: u64,
+
+    /// The creation time of the host file
+    pub(crate) btime: BirthTime,
 }
 
 /// The caching policy that the file system should report to the FUSE client. By default the FUSE
@@ -130,6 +213,62 @@ pub(crate) struct HandleData {
 
     /// The underlying file object
     pub(crate) file: RwLock<std::fs::File>,
+
+    /// Whether data has been written through this handle since it was last synced
+    pub(crate) dirty: AtomicBool,
+
+    /// The first error hit by a delayed (writeback) write on this handle, not yet reported
+    pub(crate) write_error: Mutex<Option<io::Error>>,
+
+    /// The writes through this handle, to preallocate ahead of the sequential ones
+    pub(crate) sequential: Mutex<SequentialWrites>,
+
+    /// The reads through this handle, to give the host page cache hints about
+    pub(crate) reads: Mutex<SequentialReads>,
+
+    /// The descriptor of `file` in the budget of the process
+    _fd_grant: Option<FdGrant>,
+
+    /// The count of this handle in the quota of the share
+    _handle_grant: Option<HandleGrant>,
+
+    /// The listing of the directory in progress, if this handle is a directory's
+    dir_stream: Mutex<Option<DirStream>>,
+}
+
+/// A merged listing of a directory across the layers, read as the guest lists the directory.
+#[derive(Debug)]
+pub(crate) struct DirStream {
+    /// The path of the directory
+    path: Vec<Name>,
+
+    /// The offset of the next entry, the number of entries listed so far
+    offset: u64,
+
+    /// The directory in the layers left to read, the lowest layer first
+    layers: Vec<(usize, Arc<InodeData>)>,
+
+    /// The directory in the layer being read, and the entries left in it
+    current: Option<(usize, Arc<InodeData>, HostDir)>,
+
+    /// The names of the entries of the layers read and of their whiteouts, which hide the entries
+    /// of the layers below. The last layer adds none.
+    seen: HashSet<Vec<u8>>,
+
+    /// The names whited out by the layer being read, added to `seen` once it is read, as they only
+    /// hide the entries of the layers below and not an entry of the same name in their own layer
+    whiteouts: HashSet<Vec<u8>>,
+
+    /// The entry read but not listed by the last call, the buffer of the guest being full
+    pending: Option<StreamEntry>,
+}
+
+/// An entry of a [`DirStream`].
+#[derive(Debug)]
+struct StreamEntry {
+    ino: u64,
+    type_: u32,
+    name: Vec<u8>,
 }
 
 /// Represents either a file descriptor or a path
@@ -142,6 +281,136 @@ enum FileId {
     Path(CString),
 }
 
+/// The entry `name` of a host directory, for the `*at()` syscall family.
+#[derive(Clone)]
+struct EntryAt {
+    /// The directory, or `None` if `name` is an absolute path
+    dir: Option<Arc<File>>,
+
+    /// The name of the entry in `dir`
+    name: CString,
+}
+
+/// The handles of the directories below the layer roots that were resolved through last, keyed
+/// by their layer and the address of their path, which they keep alive.
+#[derive(Default)]
+struct DirFds {
+    entries: HashMap<(usize, usize), DirFd>,
+    clock: u64,
+}
+
+/// A handle of [`DirFds`].
+struct DirFd {
+    _path: Arc<InodePath>,
+    file: Arc<File>,
+    used: u64,
+}
+
+/// The entries of a host directory, read through a handle of their own.
+#[derive(Debug)]
+struct HostDir {
+    dir: NonNull<libc::DIR>,
+}
+
+/// An entry of a [`HostDir`].
+struct HostDirEntry {
+    ino: u64,
+    type_: u8,
+    name: Vec<u8>,
+}
+
+/// How the file system treats symlinks whose target is absolute or climbs above the root of the
+/// overlay. The host never follows symlinks found in the layers; this policy only controls what the
+/// guest gets to see.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Link targets are returned to the guest untouched, so they are resolved by the guest kernel
+    /// relative to its own root. This is the default policy.
+    #[default]
+    GuestRelative,
+
+    /// Links with an absolute or escaping target are refused: reading them and creating them both
+    /// fail with `EPERM`.
+    Deny,
+
+    /// Absolute and escaping targets are rewritten into relative targets that resolve within the
+    /// overlay, as if the root of the overlay were `/`.
+    FollowWithinLayer,
+}
+
+/// How the link count of directories is reported to the guest. Tools like `find` assume that a
+/// directory has two links more than it has subdirectories, which is not true of the directory of
+/// any single layer once layers are merged.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DirNlinkPolicy {
+    /// Report a link count of 1, which tells those tools that the count is meaningless (as Linux's
+    /// OverlayFS does for merged directories). This is the default.
+    #[default]
+    One,
+
+    /// Report two links plus the number of subdirectories merged across all layers. This requires
+    /// reading the directory in every layer each time its attributes are returned.
+    Merged,
+
+    /// Report the link count of the directory in the layer it is found in.
+    Layer,
+}
+
+/// Where the writable top layer of the overlay lives.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum UpperLayer {
+    /// The last entry of `Config::layers` is the writable top layer. This is the default.
+    #[default]
+    Disk,
+
+    /// A RAM-backed top layer limited to `size_limit` bytes. macOS has no RAM-backed file system
+    /// to hold it, so it is not supported there.
+    Ram { size_limit: u64 },
+}
+
+/// Checks of the lower layers against manifests of their contents, to catch the layers edited on
+/// the host. The manifest of a layer is recorded when the file system is first created with it,
+/// and the later ones compare the layer against it, which walks the whole layer and reads the
+/// first and last blocks of each regular file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayerIntegrity {
+    /// The host directory holding the manifests, created if needed.
+    pub manifest_dir: PathBuf,
+
+    /// What happens when a layer drifted from its manifest.
+    pub on_drift: DriftPolicy,
+}
+
+/// What happens when a lower layer drifted from its manifest.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriftPolicy {
+    /// Log the drifted entries and use the layer anyway. This is the default.
+    #[default]
+    Warn,
+
+    /// Fail to create the file system with `InvalidData`, as when the layers can't be checked.
+    Refuse,
+}
+
+/// How the metadata of the top layer is sanitized for Linux consumers when it leaves the overlay,
+/// see `host_metadata`: the extended attributes not kept are dropped, and the AppleDouble files
+/// are turned back into the extended attributes of the files they describe.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetadataSanitizer {
+    /// Prefixes of the names of the extended attributes kept. The attributes the overlay keeps
+    /// for itself are never kept.
+    ///
+    /// The default keeps `user.*`, `trusted.*` and `security.capability`.
+    pub keep_xattrs: Vec<String>,
+
+    /// Whether the copies made by copy-ups are sanitized too, dropping the extended attributes
+    /// not kept, such as `com.apple.quarantine`, as soon as they reach the top layer. The clones
+    /// keep those of their source otherwise.
+    ///
+    /// The default is `false`.
+    pub copy_up: bool,
+}
+
 /// Configuration for the overlay filesystem
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -184,6 +453,214 @@ pub struct Config {
 
     /// Layers to be used for the overlay filesystem
     pub layers: Vec<PathBuf>,
+
+    /// How symlinks with absolute or escaping targets are handled. See the documentation of
+    /// `SymlinkPolicy` for more details.
+    ///
+    /// The default value for this option is `SymlinkPolicy::GuestRelative`.
+    pub symlink_policy: SymlinkPolicy,
+
+    /// Prefixes of absolute symlink targets to rewrite, as `(from, to)` pairs, for links pointing
+    /// into paths that the guest mounts somewhere else. The targets of the links in the lower
+    /// layers are rewritten when they are read and when the links are copied up to the top layer,
+    /// while the links created by the guest are left alone. Prefixes only match whole path
+    /// components and the longest matching prefix wins.
+    ///
+    /// The default value for this option is empty.
+    pub symlink_target_map: Vec<(PathBuf, PathBuf)>,
+
+    /// The most symlinks followed through the components of a symlink target when checking it
+    /// against the symlink policy. Targets needing more fail with `ELOOP`, as loops do. Unused
+    /// with `SymlinkPolicy::GuestRelative`.
+    ///
+    /// The default value for this option is 40, the limit of Linux.
+    pub max_symlink_depth: usize,
+
+    /// Where the writable top layer lives. See the documentation of `UpperLayer` for more details.
+    /// Only `UpperLayer::Disk` is supported on macOS, creating the overlay fails with the others.
+    ///
+    /// The default value for this option is `UpperLayer::Disk`.
+    pub upper_layer: UpperLayer,
+
+    /// Whether the overlay rejects all the requests changing it with `EROFS`, rather than copying
+    /// entries up and changing the top layer. Nothing is written to the layers either when the
+    /// overlay is created: the intent log isn't replayed.
+    ///
+    /// The default value for this option is `false`.
+    pub read_only: bool,
+
+    /// How the link count of directories is reported. See the documentation of `DirNlinkPolicy`
+    /// for more details.
+    ///
+    /// The default value for this option is `DirNlinkPolicy::One`.
+    pub dir_nlink: DirNlinkPolicy,
+
+    /// Whether whiteouts are cross-checked against the entries they hide. When enabled, a whiteout
+    /// of the top layer is ignored if the entry it hides was recreated in a lower layer after the
+    /// whiteout was created, e.g. because the lower layers were updated on the host by re-pulling
+    /// an image. The whiteouts of the lower layers are always honored.
+    ///
+    /// The default value for this option is `false`.
+    pub verify_whiteouts: bool,
+
+    /// Whether a lookup of a name missing from a directory finds the entry whose name only differs
+    /// by case, for layers extracted on case-insensitive volumes. The entry is then known by its
+    /// name in the layers, and a name matching several entries of the directory fails with `EIO`.
+    /// See the documentation of the `case_names` module for more details.
+    ///
+    /// The default value for this option is `false`.
+    pub case_insensitive: bool,
+
+    /// Whether changes to directory entries are made durable before they are acknowledged. When
+    /// enabled, the directories whose entries are created, linked, removed or renamed are flushed
+    /// to the disk before the request completes, so that the changes survive a host crash. This
+    /// has a significant cost for workloads that create or remove many files.
+    ///
+    /// The default value for this option is `false`.
+    pub durable: bool,
+
+    /// Whether the operations changing the top layer in two steps record their intent in a log
+    /// of the top layer first, so that the ones a host crash interrupted are completed when the
+    /// overlay is created again: removing or renaming an entry a lower layer has, and truncating a
+    /// file being copied up. Recording an intent flushes the log to the disk.
+    ///
+    /// The default value for this option is `false`.
+    pub intent_log: bool,
+
+    /// Whether the inode numbers given to the host files are kept in a map of the top layer, so
+    /// that each file gets the same number when the overlay is created again, e.g. after the VM
+    /// restarts, and the file handles the guest exported still find it. See the documentation of
+    /// the `inode_map` module for more details. The map is not kept for a read-only overlay.
+    ///
+    /// The default value for this option is `false`.
+    pub inode_map: bool,
+
+    /// Whether the guest may set the immutable and append-only flags of the host files, e.g. with
+    /// `chattr`. The flags are always reported to the guest, so that it can tell why writing to such
+    /// files fails, but changing them is left to the host unless this is set.
+    ///
+    /// The default value for this option is `false`.
+    pub allow_file_flags: bool,
+
+    /// The maximum number of threads copying up a directory tree, e.g. when a directory coming
+    /// from a lower layer is renamed. Independent subdirectories are copied in parallel, while the
+    /// copy-up of each entry is serialized with any other request copying up the same path.
+    ///
+    /// The default value for this option is 4.
+    pub copy_up_threads: usize,
+
+    /// Whether all the entries are reported with the same device ID, the one of the top layer,
+    /// rather than the one of the host file system they come from. Tools like `mv` fall back to
+    /// copying and deleting when the device IDs of the source and the destination differ, which
+    /// they do for entries from layers on different host file systems. The inode numbers are still
+    /// the host ones, so entries from different layers may then share an inode number.
+    ///
+    /// The default value for this option is `false`.
+    pub single_dev: bool,
+
+    /// Whether the regular files of the top layer still holding the data of the lower layer file
+    /// they shadow report no blocks, like a file copied up for a `chmod` the guest hasn't written
+    /// to since. Its data is then only counted once, in the lower layer, by the tools adding up the
+    /// space of the layers, and `du` run in the guest reports the space the guest added to the
+    /// lower layers rather than the space of the files it sees. A file is taken to hold the data of
+    /// the lower file at the same path if they have the same size and modification time, which a
+    /// copy-up keeps, at the cost of looking the path up in the lower layers to report the
+    /// attributes of each top layer file.
+    ///
+    /// The default value for this option is `false`, which reports the blocks of the file serving
+    /// the entry.
+    pub differential_blocks: bool,
+
+    /// A host directory holding a content-addressed store of the data of copied up files, shared
+    /// by the overlays whose top layers are on the same host file system. A copy-up of a file whose
+    /// content is already in the store clones it from there, and the content of the other copied
+    /// up files is added to it. Cloning needs a file system with reflinks, e.g. Btrfs or XFS on
+    /// Linux and APFS on macOS, and the store is left alone otherwise. Each copy-up then reads the
+    /// source file one more time to hash it.
+    ///
+    /// The default value for this option is `None`.
+    pub content_store: Option<PathBuf>,
+
+    /// Whether lookups consult a Bloom filter of the paths of each lower layer before probing it,
+    /// skipping the layers that certainly don't have the path, a whiteout of it or an opaque
+    /// directory on the way. This saves a few system calls per layer for most lookups on deep
+    /// stacks of layers. The filter of a layer is built by walking it on the first lookup reaching
+    /// it, and dropped by `refresh_layers`, which must then be called whenever the lower layers
+    /// are changed on the host. The top layer is always probed.
+    ///
+    /// The default value for this option is `false`.
+    pub lookup_filters: bool,
+
+    /// The caches of the lower layers to share with the other overlays stacked on them, see
+    /// `LowerLayerSet`. The path filters of the lower layers are taken from the set rather than
+    /// built by each overlay, and the attributes of the files of the lower layers are cached in
+    /// it, the lower layers being read-only. Creating the file system fails if the lower layers
+    /// aren't the ones of the set.
+    ///
+    /// The default value for this option is `None`, which caches the path filters in the overlay
+    /// and no attributes.
+    pub lower_layers: Option<LowerLayerSet>,
+
+    /// How long the whiteouts and opaque markers found by the lookups are remembered. The probes
+    /// of a request are always memoized, so that it probes each directory once, and with this set
+    /// the results are also kept across requests, so that the lookups of entries of the same
+    /// directory don't probe its ancestors again at every layer. The whiteouts and opaque markers
+    /// added or removed on the host are only seen once the results expire, or after
+    /// `refresh_layers`.
+    ///
+    /// The default value for this option is `None`, which only memoizes the probes of a request.
+    pub whiteout_cache_ttl: Option<Duration>,
+
+    /// Checks of the lower layers against manifests of their contents. See the documentation of
+    /// `LayerIntegrity` for more details.
+    ///
+    /// The default value for this option is `None`.
+    pub layer_integrity: Option<LayerIntegrity>,
+
+    /// The registration with the file descriptor budget of the process the descriptors of the
+    /// open handles are accounted to. Opening a file fails with `EMFILE` when the budget is
+    /// exhausted.
+    ///
+    /// The default value for this option is `None`, which doesn't account them.
+    pub fd_client: Option<FdClient>,
+
+    /// The count of the handles open on the share, opening a file failing with `EMFILE` when it
+    /// reaches its limit.
+    ///
+    /// The default value for this option is `None`, which doesn't limit them.
+    pub handle_quota: Option<FsHandleQuota>,
+
+    /// The hooks of the embedder, see `FsHooks`.
+    ///
+    /// The default value for this option is `None`, which calls none.
+    pub hooks: Option<FsHooks>,
+
+    /// The most bytes of the files the guest may have mapped into the DAX range at once. Setting up
+    /// a mapping past it fails with `ENOMEM`, see `dax_windows`.
+    ///
+    /// The default value for this option is `None`, which doesn't limit them.
+    pub dax_max_mapped: Option<u64>,
+
+    /// How the metadata of the top layer is sanitized by `export_diff`, and optionally by the
+    /// copy-ups. See the documentation of `MetadataSanitizer` for more details.
+    ///
+    /// The default value for this option is `None`, which exports the entries without their
+    /// extended attributes, and the AppleDouble files as regular files.
+    pub metadata_sanitizer: Option<MetadataSanitizer>,
+
+    /// How the reads of the guest use the host page cache, see `page_cache`.
+    ///
+    /// The default value for this option is `FsPageCache::Host`, which leaves it to the host.
+    pub page_cache: FsPageCache,
+
+    /// The lower layers read lazily from the blobs of an OCI image, by index in `layers`, whose
+    /// paths are the directories of the layers, see `LazyLayer`. Each is indexed when the overlay
+    /// is created, which builds its directory unless it is in the cache already, and the data of
+    /// its files is extracted when they are first copied up.
+    ///
+    /// The default value for this option is empty.
+    #[cfg(feature = "oci")]
+    pub lazy_layers: Vec<(usize, Arc<LazyLayer>)>,
 }
 
 /// An overlay filesystem implementation that combines multiple layers into a single logical filesystem.
@@ -239,8 +716,8 @@ pub struct OverlayFs {
     /// The `init.krun` handle ID
     init_handle: u64,
 
-    /// Map of memory-mapped windows
-    map_windows: Mutex<HashMap<u64, u64>>,
+    /// The files mapped into the DAX range, by guest address
+    map_windows: Mutex<DaxWindows>,
 
     /// Whether writeback caching is enabled
     writeback: AtomicBool,
@@ -251,11 +728,64 @@ pub struct OverlayFs {
     /// Configuration options
     config: Config,
 
-    /// Symbol table for interned filenames
-    filenames: Arc<RwLock<SymbolTable>>,
+    /// Interned filenames, shared by the paths of the inodes
+    filenames: Mutex<NameTable>,
 
     /// Root inodes for each layer, ordered from bottom to top
     layer_roots: Arc<RwLock<Vec<Inode>>>,
+
+    /// Snapshots of the top layer taken with `snapshot`, by id
+    snapshots: Mutex<BTreeMap<u64, LayerSnapshot>>,
+
+    /// Owner and permissions set on lower layer directories, by path relative to the layer roots.
+    /// Persisted in `DIR_OVERRIDES_FILE`.
+    dir_overrides: Mutex<HashMap<Vec<u8>, (u32, u32, u16)>>,
+
+    /// Counter for generating the next snapshot ID
+    next_snapshot: AtomicU64,
+
+    /// The generation to give to the next inode created for a host inode number whose file was
+    /// deleted through the overlay, on the host file systems that don't record birth times, which
+    /// otherwise tell the files apart. Only kept along with `inode_map`, without which every inode
+    /// created gets a new number, so it never outgrows the host inode numbers the map records.
+    generations: Mutex<BTreeMap<InodeAltKey, u64>>,
+
+    /// The paths being copied up to the top layer, so that concurrent requests copy each path once.
+    copy_up_locks: PathLocks<Vec<Name>>,
+
+    /// The directory entries being changed, so that concurrent requests changing the same entry,
+    /// e.g. two unlinks of the same lower file, run one after the other.
+    entry_locks: PathLocks<(Inode, Vec<u8>)>,
+
+    /// The device ID reported for all the entries, if `Config::single_dev` is set.
+    share_dev: Option<libc::dev_t>,
+
+    /// The store that copied up file data is cloned from, if `Config::content_store` is set.
+    content_store: Option<ContentStore>,
+
+    /// The devices of the lower layers whose files can't be cloned to the top layer, e.g. as they
+    /// are on another volume, or on one without clones such as exFAT or a network share. Their
+    /// files are copied right away, rather than after a failed clone each.
+    clone_unsupported: Mutex<HashSet<libc::dev_t>>,
+
+    /// The log of the operations in progress on the top layer, if `Config::intent_log` is set.
+    intent_log: Option<IntentLog>,
+
+    /// The numbers given to the host files across restarts, if `Config::inode_map` is set.
+    inode_map: Option<InodeMap>,
+
+    /// The path filter of each layer, built on first use if `Config::lookup_filters` is set.
+    layer_filters: Vec<Mutex<Option<Arc<LayerFilter>>>>,
+
+    /// The counts of the lookups, copy-ups and whiteouts of each layer, see `stats`.
+    layer_stats: LayerCounters,
+
+    /// The whiteout and opaque marker probes kept across requests, see
+    /// `Config::whiteout_cache_ttl`.
+    whiteout_cache: WhiteoutCache,
+
+    /// The handles of the directories below the layer roots, see `dir_fd`.
+    dir_fds: Mutex<DirFds>,
 }
 
 //--------------------------------------------------------------------------------------------------
@@ -268,9 +798,236 @@ impl InodeAltKey {
     }
 }
 
+impl InodeData {
+    /// Whether the host file `st` isn't the file of this inode but a new one that got its inode
+    /// number after it was deleted on the host. Host file systems recycle inode numbers, so a hit
+    /// on the alternate key alone doesn't tell.
+    fn is_recycled(&self, st: &bindings::stat64) -> bool {
+        self.btime != birth_time(st)
+    }
+}
+
+impl HandleData {
+    /// Records the error of a delayed write.
+    ///
+    /// The guest has already considered such writes complete, so the error is kept around until it
+    /// can be reported by [`FileSystem::flush`] or [`FileSystem::release`]. Only the first error is
+    /// kept, later ones are most likely a consequence of it.
+    fn set_write_error(&self, err: &io::Error) {
+        let mut write_error = self.write_error.lock().unwrap();
+        if write_error.is_none() {
+            *write_error = Some(match err.raw_os_error() {
+                Some(errno) => io::Error::from_raw_os_error(errno),
+                None => io::Error::new(err.kind(), err.to_string()),
+            });
+        }
+    }
+
+    /// Takes the pending delayed write error, if any, so that it is only reported once.
+    fn take_write_error(&self) -> Option<io::Error> {
+        self.write_error.lock().unwrap().take()
+    }
+
+    /// Records a write of `size` bytes at `offset` of `fd`, the file of this handle, preallocating
+    /// space ahead of it if the writes through this handle look sequential. Preallocation is only an
+    /// optimization, so failing to preallocate just stops it for this handle.
+    fn preallocate_ahead(&self, fd: RawFd, offset: u64, size: u64) {
+        let mut sequential = self.sequential.lock().unwrap();
+        if let Some((offset, length)) = sequential.record(offset, size) {
+            if let Err(e) = prealloc::preallocate(fd, offset, length) {
+                debug!("virtio-fs: failed to preallocate inode {}: {e}", self.inode);
+                sequential.stop();
+            }
+        }
+    }
+
+    /// Gives back the space preallocated through this handle beyond the end of the file, which no
+    /// other handle may be writing to.
+    fn trim_preallocation(&self) {
+        if let Some(end) = self.sequential.lock().unwrap().preallocated_end() {
+            let fd = self.file.read().unwrap().as_raw_fd();
+            if let Err(e) = prealloc::trim(fd, end) {
+                debug!(
+                    "virtio-fs: failed to trim the preallocation of inode {}: {e}",
+                    self.inode
+                );
+            }
+        }
+    }
+}
+
+impl EntryAt {
+    /// The directory to pass to the `*at()` syscall family.
+    fn dirfd(&self) -> RawFd {
+        self.dir
+            .as_ref()
+            .map_or(libc::AT_FDCWD, |dir| dir.as_raw_fd())
+    }
+
+    /// Returns the entry `name` of the same directory.
+    fn sibling(&self, name: CString) -> EntryAt {
+        EntryAt {
+            dir: self.dir.clone(),
+            name,
+        }
+    }
+
+    /// Returns the entry of the whiteout of this one.
+    fn whiteout(&self) -> io::Result<EntryAt> {
+        let name = CString::new(whiteout_path(self.name.to_bytes())).map_err(|_| einval())?;
+        Ok(self.sibling(name))
+    }
+
+    fn stat(&self) -> io::Result<bindings::stat64> {
+        OverlayFs::unpatched_stat_at(self.dirfd(), &self.name)
+    }
+
+    fn patched_stat(&self) -> io::Result<bindings::stat64> {
+        OverlayFs::patched_stat_at(self.dirfd(), &self.name)
+    }
+
+    /// Opens the entry with `flags`, creating it with `mode` if `flags` ask for it.
+    fn open(&self, flags: i32, mode: libc::c_uint) -> io::Result<File> {
+        let fd = retry_syscall(|| unsafe {
+            libc::openat(
+                self.dirfd(),
+                self.name.as_ptr(),
+                flags | libc::O_CLOEXEC,
+                mode,
+            )
+        });
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Safe because we just opened this fd.
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    /// Opens the entry to read and change its metadata.
+    fn open_meta(&self) -> io::Result<File> {
+        OverlayFs::open_meta_at(self.dirfd(), &self.name)
+    }
+
+    fn unlink(&self, flags: i32) -> io::Result<()> {
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe { libc::unlinkat(self.dirfd(), self.name.as_ptr(), flags) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Renames the entry to `to`, with the `RENAME_*` flags of `renameatx_np`.
+    fn rename_to(&self, to: &EntryAt, flags: libc::c_uint) -> io::Result<()> {
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe {
+            libc::renameatx_np(
+                self.dirfd(),
+                self.name.as_ptr(),
+                to.dirfd(),
+                to.name.as_ptr(),
+                flags,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Returns the target of the entry, which must be a symlink.
+    fn read_link(&self) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; libc::PATH_MAX as usize];
+        // Safe because this will only modify the contents of `buf` and we check the return value.
+        let len = unsafe {
+            libc::readlinkat(
+                self.dirfd(),
+                self.name.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.len(),
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        buf.truncate(len as usize);
+        Ok(buf)
+    }
+}
+
+impl DirFds {
+    fn get(&mut self, layer_idx: usize, path: &Arc<InodePath>) -> Option<Arc<File>> {
+        self.clock += 1;
+        let entry = self
+            .entries
+            .get_mut(&(layer_idx, Arc::as_ptr(path) as usize))?;
+        entry.used = self.clock;
+        Some(entry.file.clone())
+    }
+
+    /// Keeps the handle `file` of the directory at `path`, closing the one used least recently if
+    /// [`MAX_DIR_FDS`] are already kept.
+    fn insert(&mut self, layer_idx: usize, path: &Arc<InodePath>, file: Arc<File>) {
+        if self.entries.len() >= MAX_DIR_FDS {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, dir)| dir.used)
+                .map(|(key, _)| *key);
+            if let Some(key) = oldest {
+                self.entries.remove(&key);
+            }
+        }
+
+        self.clock += 1;
+        self.entries.insert(
+            (layer_idx, Arc::as_ptr(path) as usize),
+            DirFd {
+                _path: path.clone(),
+                file,
+                used: self.clock,
+            },
+        );
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+impl HostDir {
+    /// Opens the entries of the directory `dirfd`, through a handle of their own so that the
+    /// position of `dirfd` is left alone.
+    fn open(dirfd: RawFd) -> io::Result<Self> {
+        let fd = retry_syscall(|| unsafe {
+            libc::openat(
+                dirfd,
+                c".".as_ptr(),
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+            )
+        });
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Safe because we just opened this fd, which the stream owns on success.
+        let dir = unsafe { libc::fdopendir(fd) };
+        match NonNull::new(dir) {
+            Some(dir) => Ok(HostDir { dir }),
+            None => {
+                let err = io::Error::last_os_error();
+                unsafe { libc::close(fd) };
+                Err(err)
+            }
+        }
+    }
+}
+
 impl OverlayFs {
     /// Creates a new OverlayFs with the given layers
-    pub fn new(config: Config) -> io::Result<Self> {
+    pub fn new(mut config: Config) -> io::Result<Self> {
         if config.layers.is_empty() {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
@@ -285,16 +1042,75 @@ impl OverlayFs {
             ));
         }
 
+        // The lazy layers are indexed on first use, which builds their directories
+        #[cfg(feature = "oci")]
+        for (_, layer) in &config.lazy_layers {
+            layer.index()?;
+        }
+
+        config.layers = layer_paths::canonicalize(&config.layers)?;
+
+        if config.upper_layer != UpperLayer::Disk {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "RAM-backed top layers are not supported on macOS",
+            ));
+        }
+
+        if let Some(integrity) = &config.layer_integrity {
+            let lower_count = config.layers.len() - 1;
+            Self::check_layer_integrity(&config.layers[..lower_count], integrity)?;
+        }
+
+        if let Some(set) = &config.lower_layers {
+            set.check(&config.layers[..config.layers.len() - 1])?;
+        }
+
+        // Complete the operations interrupted by a crash before anything looks at the layers
+        let intent_log = if config.intent_log && !config.read_only {
+            let (top_layer, lower_layers) = config.layers.split_last().unwrap();
+            Some(IntentLog::open(top_layer, lower_layers, whiteout_path)?)
+        } else {
+            None
+        };
+
         let mut next_inode = 1;
         let mut inodes = MultikeyBTreeMap::new();
 
         // Initialize the root inodes for all layers
         let layer_roots = Self::init_root_inodes(&config.layers, &mut inodes, &mut next_inode)?;
 
+        // The top layer gives the device ID of the share when a single one is reported
+        let share_dev = config
+            .single_dev
+            .then(|| inodes.get(layer_roots.last().unwrap()).map(|root| root.dev))
+            .flatten();
+
+        let content_store = config
+            .content_store
+            .clone()
+            .map(ContentStore::new)
+            .transpose()?;
+
+        let layer_filters = config.layers.iter().map(|_| Mutex::new(None)).collect();
+        let layer_stats = LayerCounters::new(config.layers.len());
+        let whiteout_cache = WhiteoutCache::new(config.whiteout_cache_ttl);
+
         // Set the `init.krun` inode
         let init_inode = next_inode;
         next_inode += 1;
 
+        // The host files keep the numbers they were given before, which the new ones follow
+        let inode_map = if config.inode_map && !config.read_only {
+            let map = InodeMap::open(&config.layers, next_inode)?;
+            next_inode = next_inode.max(map.next_inode());
+            Some(map)
+        } else {
+            None
+        };
+
+        let dir_overrides = Self::load_dir_overrides(config.layers.last().unwrap())?;
+
         Ok(OverlayFs {
             inodes: RwLock::new(inodes),
             next_inode: AtomicU64::new(next_inode),
@@ -302,15 +1118,56 @@ impl OverlayFs {
             handles: RwLock::new(BTreeMap::new()),
             next_handle: AtomicU64::new(1),
             init_handle: 0,
-            map_windows: Mutex::new(HashMap::new()),
+            map_windows: Mutex::new(DaxWindows::new(config.dax_max_mapped)),
             writeback: AtomicBool::new(false),
             announce_submounts: AtomicBool::new(false),
             config,
-            filenames: Arc::new(RwLock::new(SymbolTable::new())),
+            filenames: Mutex::new(NameTable::default()),
             layer_roots: Arc::new(RwLock::new(layer_roots)),
+            snapshots: Mutex::new(BTreeMap::new()),
+            next_snapshot: AtomicU64::new(1),
+            generations: Mutex::new(BTreeMap::new()),
+            dir_overrides: Mutex::new(dir_overrides),
+            copy_up_locks: PathLocks::new(),
+            entry_locks: PathLocks::new(),
+            share_dev,
+            content_store,
+            layer_filters,
+            layer_stats,
+            whiteout_cache,
+            dir_fds: Mutex::new(DirFds::default()),
+            clone_unsupported: Mutex::new(HashSet::new()),
+            intent_log,
+            inode_map,
         })
     }
 
+    /// Checks the lower `layers` against their manifests, recording the missing ones.
+    fn check_layer_integrity(layers: &[PathBuf], integrity: &LayerIntegrity) -> io::Result<()> {
+        let refuse = integrity.on_drift == DriftPolicy::Refuse;
+        for layer in layers {
+            let drift = match layer_manifest::verify(layer, &integrity.manifest_dir) {
+                Ok(drift) => drift,
+                Err(err) if refuse => return Err(err),
+                Err(err) => {
+                    warn!("failed to check layer {}: {err}", layer.display());
+                    continue;
+                }
+            };
+            if drift.is_empty() {
+                continue;
+            }
+
+            let description = layer_manifest::describe(layer, &drift);
+            if refuse {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, description));
+            }
+            warn!("{description}");
+        }
+
+        Ok(())
+    }
+
     /// Initialize root inodes for all layers
     ///
     /// This function processes layers from top to bottom, creating root inodes for each layer.
@@ -334,42 +1191,269 @@ impl OverlayFs {
         for (i, layer_path) in layers.iter().enumerate().rev() {
             let layer_idx = i; // Layer index from bottom to top
 
-            // Get the stat information for this layer's root
-            let c_path = CString::new(layer_path.to_string_lossy().as_bytes())?;
-            let st = Self::unpatched_stat(&FileId::Path(c_path))?;
+            // Pre-open the layer root so that lookups can be resolved relative to it
+            let c_path = CString::new(layer_path.as_os_str().as_bytes())?;
+            let dirfd = Self::open_layer_root(&c_path)?;
+            let st = Self::unpatched_stat(&FileId::Fd(dirfd.as_raw_fd()))?;
+
+            // Create the alt key for this inode
+            let alt_key = InodeAltKey::new(st.st_ino, st.st_dev as i32);
+
+            // Create the inode data
+            let inode_id = *next_inode;
+            *next_inode += 1;
+
+            let inode_data = Arc::new(InodeData {
+                inode: inode_id,
+                ino: st.st_ino,
+                dev: st.st_dev as i32,
+                refcount: AtomicU64::new(1),
+                path: InodePath::root(),
+                layer_idx,
+                dirfd: Some(Arc::new(dirfd)),
+                whiteouts: AtomicU8::new(WHITEOUTS_UNKNOWN),
+                generation: 0,
+                btime: birth_time(&st),
+            });
+
+            // Insert the inode into the map
+            inodes.insert(inode_id, alt_key, inode_data);
+
+            // Store the root inode for this layer
+            layer_roots[layer_idx] = inode_id;
+        }
+
+        Ok(layer_roots)
+    }
+
+    /// Opens and validates the root directory of a layer.
+    ///
+    /// The returned handle is only used as an anchor for the `*at()` syscalls, so it is opened with
+    /// `O_EVTONLY` and does not require read permission on the directory.
+    ///
+    /// ## Arguments
+    /// * `path` - Path to the layer root on the host
+    ///
+    /// ## Returns
+    /// * `io::Result<File>` - The opened directory handle, or `ENOTDIR` if the path is not a directory
+    fn open_layer_root(path: &CStr) -> io::Result<File> {
+        let fd = retry_syscall(|| unsafe {
+            libc::open(
+                path.as_ptr(),
+                libc::O_EVTONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+            )
+        });
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Safe because we just opened this fd.
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        // `O_DIRECTORY` already rejects most non-directories, but be explicit about it since
+        // everything below the root is resolved relative to this handle.
+        let st = Self::unpatched_stat(&FileId::Fd(file.as_raw_fd()))?;
+        if (st.st_mode & libc::S_IFMT) != libc::S_IFDIR {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        }
+
+        Ok(file)
+    }
+
+    /// Opens a directory relative to `dirfd` without following a trailing symlink.
+    fn open_dir_at(dirfd: RawFd, name: &CStr) -> io::Result<File> {
+        let fd = retry_syscall(|| unsafe {
+            libc::openat(
+                dirfd,
+                name.as_ptr(),
+                libc::O_EVTONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            )
+        });
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Safe because we just opened this fd.
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    pub fn get_config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Returns the inode of the init binary served in every directory.
+    pub(crate) fn init_inode(&self) -> Inode {
+        self.init_inode
+    }
+
+    /// Returns the inodes the guest may hold, and cache the attributes of.
+    pub(crate) fn held_inodes(&self) -> Vec<Inode> {
+        self.inodes.read().unwrap().main.keys().copied().collect()
+    }
+
+    /// Returns the hooks of the embedder on the share, if it has any.
+    pub(crate) fn hooks(&self) -> Option<&FsHooks> {
+        self.config.hooks.as_ref()
+    }
+
+    /// Returns the path of `inode` relative to the root of the overlay, if it is known.
+    pub(crate) fn inode_path(&self, inode: Inode) -> Option<PathBuf> {
+        let data = self.get_inode_data(inode).ok()?;
+        Some(PathBuf::from(OsStr::from_bytes(
+            &self.relative_path(&data.path.names()),
+        )))
+    }
+
+    /// Records the current state of the top layer and returns an id to pass to `export_diff`.
+    pub fn snapshot(&self) -> io::Result<u64> {
+        let snapshot = LayerSnapshot::capture(self.upper_layer_path(), is_internal_name)?;
+        let id = self.next_snapshot.fetch_add(1, Ordering::SeqCst);
+        self.snapshots.lock().unwrap().insert(id, snapshot);
+        Ok(id)
+    }
+
+    /// Writes to `out` an OCI layer tar with the changes made to the top layer since the snapshot
+    /// `since`, including whiteouts for the entries removed in the meantime.
+    ///
+    /// The top layer is read while the guest may be writing to it, so changes made during the
+    /// export may or may not be included.
+    pub fn export_diff(&self, since: u64, out: impl io::Write) -> io::Result<()> {
+        let snapshots = self.snapshots.lock().unwrap();
+        let snapshot = snapshots.get(&since).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("unknown snapshot {since}"))
+        })?;
+
+        let retention = self
+            .config
+            .metadata_sanitizer
+            .as_ref()
+            .map(|sanitizer| XattrRetention {
+                keep: &sanitizer.keep_xattrs,
+                is_internal: &is_internal_xattr,
+            });
+        layer_diff::write_diff(
+            self.upper_layer_path(),
+            snapshot,
+            is_internal_name,
+            retention.as_ref(),
+            out,
+        )
+    }
+
+    /// Forgets the snapshot `id`.
+    pub fn release_snapshot(&self, id: u64) {
+        self.snapshots.lock().unwrap().remove(&id);
+    }
+
+    /// Returns the statistics of the layers, from the bottom one up to the top one.
+    pub fn stats(&self) -> Vec<FsLayerStats> {
+        let mut stats = self.layer_stats.read();
+        let handle_inodes: Vec<Inode> = self
+            .handles
+            .read()
+            .unwrap()
+            .values()
+            .map(|handle| handle.inode)
+            .collect();
+        let inodes = self.inodes.read().unwrap();
+        for (_, data) in inodes.main.values() {
+            if let Some(layer) = stats.get_mut(data.layer_idx) {
+                layer.inodes += 1;
+            }
+        }
+        for inode in handle_inodes {
+            if let Some(layer) = inodes
+                .get(&inode)
+                .and_then(|data| stats.get_mut(data.layer_idx))
+            {
+                layer.open_handles += 1;
+            }
+        }
+        stats
+    }
+
+    /// Drops what the overlay caches of the layers: the whiteouts and opaque markers found in each
+    /// directory and the path filters of the lower layers.
+    pub(crate) fn drop_caches(&self) {
+        for (_, data) in self.inodes.read().unwrap().main.values() {
+            data.whiteouts.store(WHITEOUTS_UNKNOWN, Ordering::Release);
+        }
+        for filter in &self.layer_filters {
+            *filter.lock().unwrap() = None;
+        }
+        if let Some(set) = &self.config.lower_layers {
+            set.clear();
+        }
+        self.whiteout_cache.clear();
+    }
 
-            // Create the alt key for this inode
-            let alt_key = InodeAltKey::new(st.st_ino, st.st_dev as i32);
+    /// Re-scans the layers after they were modified on the host.
+    ///
+    /// The whiteouts and opaque markers found in each directory are cached, as well as the path
+    /// filters of the lower layers, so the cached state is dropped to pick up the entries added or
+    /// removed out-of-band. When `verify_whiteouts` is enabled, the stale whiteouts of the top
+    /// layer are removed as well.
+    ///
+    /// Returns the number of stale whiteouts removed.
+    pub fn refresh_layers(&self) -> io::Result<usize> {
+        self.drop_caches();
 
-            // Create the inode data
-            let inode_id = *next_inode;
-            *next_inode += 1;
+        if !self.config.verify_whiteouts {
+            return Ok(0);
+        }
 
-            let inode_data = Arc::new(InodeData {
-                inode: inode_id,
-                ino: st.st_ino,
-                dev: st.st_dev as i32,
-                refcount: AtomicU64::new(1),
-                path: vec![],
-                layer_idx,
-            });
+        let top_layer_idx = self.get_top_layer_idx();
+        let mut removed = 0;
+        let mut dirs = vec![Vec::new()];
+        while let Some(dir) = dirs.pop() {
+            let dir_path = self.upper_layer_path().join(OsStr::from_bytes(&dir));
+            for entry in fs::read_dir(&dir_path)? {
+                let entry = entry?;
+                let name = entry.file_name();
+                let mut path = dir.clone();
+                if !path.is_empty() {
+                    path.push(b'/');
+                }
 
-            // Insert the inode into the map
-            inodes.insert(inode_id, alt_key, inode_data);
+                if let Some(hidden) = name.as_bytes().strip_prefix(WHITEOUT_PREFIX.as_bytes()) {
+                    // Skip the opaque markers and the files of the overlay itself
+                    if hidden.starts_with(WHITEOUT_PREFIX.as_bytes()) {
+                        continue;
+                    }
 
-            // Store the root inode for this layer
-            layer_roots[layer_idx] = inode_id;
+                    path.extend_from_slice(hidden);
+                    if self.is_stale_whiteout(top_layer_idx, &path) {
+                        fs::remove_file(entry.path())?;
+                        removed += 1;
+                    }
+                } else if entry.file_type()?.is_dir() {
+                    path.extend_from_slice(name.as_bytes());
+                    dirs.push(path);
+                }
+            }
         }
 
-        Ok(layer_roots)
+        Ok(removed)
     }
 
-    pub fn get_config(&self) -> &Config {
-        &self.config
+    fn upper_layer_path(&self) -> &Path {
+        self.config.layers.last().unwrap()
     }
 
-    pub fn get_filenames(&self) -> &Arc<RwLock<SymbolTable>> {
-        &self.filenames
+    /// Records the intent of an operation on the entry at `path` in the log, if
+    /// `Config::intent_log` is set, returning a guard recording its end when dropped. Nothing is
+    /// recorded without lower layers, where every operation takes a single step.
+    fn begin_intent(
+        &self,
+        path: &[Name],
+        intent: impl FnOnce(Vec<u8>) -> Intent,
+    ) -> io::Result<Option<IntentGuard<'_>>> {
+        match &self.intent_log {
+            Some(log) if self.get_top_layer_idx() > 0 => {
+                log.begin(&intent(self.relative_path(path))).map(Some)
+            }
+            _ => Ok(None),
+        }
     }
 
     fn get_layer_root(&self, layer_idx: usize) -> io::Result<Arc<InodeData>> {
@@ -393,15 +1477,52 @@ impl OverlayFs {
         self.get_inode_data(inode)
     }
 
-    /// Creates a new inode and adds it to the inode map
+    /// Records the number `inode` given to the host file `ino` of the device `dev`, born at
+    /// `btime`, in the map of `Config::inode_map`, if set. Failing to record it only loses the
+    /// number on a restart.
+    fn record_inode(&self, dev: i32, ino: u64, btime: BirthTime, inode: Inode) {
+        if let Some(map) = &self.inode_map {
+            if let Err(e) = map.record(dev as u64, ino, btime.generation(), inode) {
+                warn!("virtio-fs: failed to record the number of inode {inode}: {e}");
+            }
+        }
+    }
+
+    /// Creates a new inode and adds it to the inode map, unless another thread added one for the
+    /// same host file since the caller looked for it, in which case that one is returned
     fn create_inode(
         &self,
         ino: u64,
         dev: i32,
-        path: Vec<Symbol>,
+        btime: BirthTime,
+        path: Arc<InodePath>,
         layer_idx: usize,
     ) -> (Inode, Arc<InodeData>) {
-        let inode = self.next_inode.fetch_add(1, Ordering::SeqCst);
+        let alt_key = InodeAltKey::new(ino, dev);
+        let generation = btime.generation().unwrap_or_else(|| {
+            self.generations
+                .lock()
+                .unwrap()
+                .get(&alt_key)
+                .copied()
+                .unwrap_or(0)
+        });
+
+        // Replacing the inode would drop the references the guest holds on it
+        let mut inodes = self.inodes.write().unwrap();
+        if let Some(data) = inodes.get_alt(&alt_key).filter(|data| data.btime == btime) {
+            return (data.inode, data.clone());
+        }
+
+        // A host file keeps the number it was given, unless its inode is still known by it
+        let (inode, numbered) = match self
+            .inode_map
+            .as_ref()
+            .and_then(|map| map.get(dev as u64, ino, btime.generation()))
+        {
+            Some(inode) if inodes.get(&inode).is_none() => (inode, false),
+            _ => (self.next_inode.fetch_add(1, Ordering::SeqCst), true),
+        };
 
         let data = Arc::new(InodeData {
             inode,
@@ -410,13 +1531,19 @@ impl OverlayFs {
             refcount: AtomicU64::new(1),
             path,
             layer_idx,
+            dirfd: None,
+            whiteouts: AtomicU8::new(WHITEOUTS_UNKNOWN),
+            generation,
+            btime,
         });
 
-        let alt_key = InodeAltKey::new(ino, dev);
-        self.inodes
-            .write()
-            .unwrap()
-            .insert(inode, alt_key, data.clone());
+        inodes.insert(inode, alt_key, data.clone());
+        drop(inodes);
+
+        // The map is written to once every request can take the lock of the inodes again
+        if numbered {
+            self.record_inode(dev, ino, btime, inode);
+        }
 
         (inode, data)
     }
@@ -450,6 +1577,14 @@ impl OverlayFs {
         self.layer_roots.read().unwrap().len() - 1
     }
 
+    /// Fails with `EROFS` if the overlay is read-only, see `Config::read_only`.
+    fn check_writable(&self) -> io::Result<()> {
+        if self.config.read_only {
+            return Err(linux_error(io::Error::from_raw_os_error(libc::EROFS)));
+        }
+        Ok(())
+    }
+
     fn bump_refcount(&self, inode: Inode) {
         let inodes = self.inodes.write().unwrap();
         let inode_data = inodes.get(&inode).unwrap();
@@ -488,41 +1623,84 @@ impl OverlayFs {
         }
     }
 
-    /// Converts a dev/ino pair to a volume path
-    fn dev_ino_to_vol_path(&self, dev: i32, ino: u64) -> io::Result<CString> {
-        let path = format!("/{}/{}/{}", VOL_DIR, dev, ino);
-        CString::new(path).map_err(|_| einval())
-    }
-
-    /// Converts a dev/ino pair and name to a volume path
-    fn dev_ino_and_name_to_vol_path(&self, dev: i32, ino: u64, name: &CStr) -> io::Result<CString> {
-        let path = format!("/{}/{}/{}/{}", VOL_DIR, dev, ino, name.to_string_lossy());
-        CString::new(path).map_err(|_| einval())
+    /// Returns the handle of the directory at `path` in the layer `layer_idx`, to resolve its
+    /// entries with the `*at()` syscall family.
+    ///
+    /// Layer roots keep their handle. The handles of the other directories are opened from their
+    /// parent when needed, and only the [`MAX_DIR_FDS`] used last are kept open.
+    fn dir_fd(&self, layer_idx: usize, path: &Arc<InodePath>) -> io::Result<Arc<File>> {
+        match path.link() {
+            Some((parent, name)) => {
+                if let Some(dir) = self.dir_fds.lock().unwrap().get(layer_idx, path) {
+                    return Ok(dir);
+                }
+                let parent_fd = self.dir_fd(layer_idx, &parent)?;
+                self.child_dir_fd(layer_idx, path, parent_fd.as_raw_fd(), &name)
+            }
+            None => self
+                .get_layer_root(layer_idx)?
+                .dirfd
+                .clone()
+                .ok_or_else(ebadf),
+        }
     }
 
-    fn dev_ino_and_name_to_vol_whiteout_path(
+    /// Like [`Self::dir_fd`] for the directory `name` of `parent_fd`, which is opened from there if
+    /// its handle isn't kept.
+    fn child_dir_fd(
         &self,
-        dev: i32,
-        ino: u64,
+        layer_idx: usize,
+        path: &Arc<InodePath>,
+        parent_fd: RawFd,
         name: &CStr,
-    ) -> io::Result<CString> {
-        // Create whiteout file (.wh.<name>) in parent directory
-        let whiteout_name = format!(
-            "{}{}",
-            WHITEOUT_PREFIX,
-            name.to_str().map_err(|_| einval())?
-        );
+    ) -> io::Result<Arc<File>> {
+        if let Some(dir) = self.dir_fds.lock().unwrap().get(layer_idx, path) {
+            return Ok(dir);
+        }
+        let dir = Arc::new(Self::open_dir_at(parent_fd, name)?);
+        self.dir_fds
+            .lock()
+            .unwrap()
+            .insert(layer_idx, path, dir.clone());
+        Ok(dir)
+    }
 
-        let whiteout_cstr = CString::new(whiteout_name).map_err(|_| einval())?;
+    /// Returns the host file of `data` as an entry of its parent directory, or as `.` in itself for
+    /// a layer root.
+    ///
+    /// The name is checked to still lead to the file. A file the overlay only knows under another
+    /// name, such as a hard link whose recorded name was removed, is found by its `/.vol` path.
+    fn entry_at(&self, data: &InodeData) -> io::Result<EntryAt> {
+        let Some((parent, name)) = data.path.link() else {
+            return Ok(EntryAt {
+                dir: Some(self.dir_fd(data.layer_idx, &data.path)?),
+                name: c".".into(),
+            });
+        };
 
-        // Get full path for whiteout file
-        self.dev_ino_and_name_to_vol_path(dev, ino, &whiteout_cstr)
+        let entry = EntryAt {
+            dir: Some(self.dir_fd(data.layer_idx, &parent)?),
+            name: CString::from(&*name),
+        };
+        match entry.stat() {
+            Ok(st) if st.st_ino == data.ino && st.st_dev as i32 == data.dev => Ok(entry),
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => {
+                let path = format!("/{}/{}/{}", VOL_DIR, data.dev, data.ino);
+                Ok(EntryAt {
+                    dir: None,
+                    name: CString::new(path).map_err(|_| einval())?,
+                })
+            }
+        }
     }
 
-    /// Converts an inode number to a volume path
-    fn inode_number_to_vol_path(&self, inode: Inode) -> io::Result<CString> {
-        let data = self.get_inode_data(inode)?;
-        self.dev_ino_to_vol_path(data.dev, data.ino)
+    /// Returns the entry `name` of the directory `parent`, which may not exist yet.
+    fn child_at(&self, parent: &InodeData, name: &CStr) -> io::Result<EntryAt> {
+        Ok(EntryAt {
+            dir: Some(self.dir_fd(parent.layer_idx, &parent.path)?),
+            name: name.into(),
+        })
     }
 
     /// Turns an inode into an opened file.
@@ -546,21 +1724,13 @@ impl OverlayFs {
             flags &= !libc::O_APPEND;
         }
 
-        let c_path = self.inode_number_to_vol_path(inode)?;
-
-        let fd = unsafe {
-            libc::open(
-                c_path.as_ptr(),
-                (flags | libc::O_CLOEXEC) & (!libc::O_NOFOLLOW) & (!libc::O_EXLOCK),
-            )
-        };
-
-        if fd < 0 {
-            return Err(linux_error(io::Error::last_os_error()));
-        }
+        let entry = self.entry_at(&self.get_inode_data(inode)?)?;
 
-        // Safe because we just opened this fd.
-        Ok(unsafe { File::from_raw_fd(fd) })
+        // A symlink in a layer is never followed on the host, where a crafted image could point it
+        // at the host files or loop it back onto itself. Opening one fails with `ELOOP`.
+        entry
+            .open((flags | libc::O_NOFOLLOW) & (!libc::O_EXLOCK), 0)
+            .map_err(linux_error)
     }
 
     /// Parses open flags
@@ -592,11 +1762,117 @@ impl OverlayFs {
         mflags
     }
 
+    /// Records that the file of the host inode `key` was deleted, so that a file reusing its inode
+    /// number later gets a new generation. The guest needs the inode and generation pairs to stay
+    /// unique to build the file handles of NFS exports. Files deleted on the host directly are not
+    /// noticed.
+    ///
+    /// Nothing is recorded when the new file gets another inode anyway, without `inode_map`, or
+    /// when the host file system records birth times, which tell the files apart.
+    fn retire_generation(&self, key: InodeAltKey) {
+        if self.inode_map.is_none() {
+            return;
+        }
+        let current = self
+            .inodes
+            .read()
+            .unwrap()
+            .get_alt(&key)
+            .map(|data| (data.generation, data.btime));
+        if current.is_some_and(|(_, btime)| btime.generation().is_some()) {
+            return;
+        }
+        let mut generations = self.generations.lock().unwrap();
+        let generation = current
+            .map(|(generation, _)| generation)
+            .or_else(|| generations.get(&key).copied())
+            .unwrap_or(0);
+        generations.insert(key, generation + 1);
+    }
+
+    /// Accounts for the descriptor of a new handle in the budget of `Config::fd_client`, waiting
+    /// for it if the budget is short.
+    fn acquire_fd(&self) -> io::Result<Option<FdGrant>> {
+        self.config
+            .fd_client
+            .as_ref()
+            .map(|client| client.acquire(1).map_err(linux_error))
+            .transpose()
+    }
+
+    /// Counts a new handle in `Config::handle_quota`, failing with `EMFILE` past its limit.
+    fn acquire_handle(&self) -> io::Result<Option<HandleGrant>> {
+        self.config
+            .handle_quota
+            .as_ref()
+            .map(|quota| quota.acquire().map_err(linux_error))
+            .transpose()
+    }
+
+    /// Flushes the top layer directory `dir` to the disk if `Config::durable` is set.
+    fn sync_dir(&self, dir: &File) -> io::Result<()> {
+        if !self.config.durable {
+            return Ok(());
+        }
+
+        sync_dir_at(dir.as_raw_fd(), c".")
+    }
+
+    /// Sets the access and modification times of `entry` to the ones in `st`. Symbolic links are
+    /// not followed.
+    fn set_times(entry: &EntryAt, st: &bindings::stat64) -> io::Result<()> {
+        let tvs = [
+            libc::timespec {
+                tv_sec: st.st_atime,
+                tv_nsec: st.st_atime_nsec,
+            },
+            libc::timespec {
+                tv_sec: st.st_mtime,
+                tv_nsec: st.st_mtime_nsec,
+            },
+        ];
+
+        let res = unsafe {
+            libc::utimensat(
+                entry.dirfd(),
+                entry.name.as_ptr(),
+                tvs.as_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Flushes the directory `dir` to the disk if `Config::durable` is set and the directory is in
+    /// the top layer, where its entries may have changed.
+    fn sync_inode_dir(&self, dir: Inode) -> io::Result<()> {
+        let dir_data = self.get_inode_data(dir)?;
+        if dir_data.layer_idx != self.get_top_layer_idx() || !self.config.durable {
+            return Ok(());
+        }
+
+        self.sync_dir(&self.dir_fd(dir_data.layer_idx, &dir_data.path)?)
+    }
+
     /// Creates an Entry from stat information and inode data
-    fn create_entry(&self, inode: Inode, st: bindings::stat64) -> Entry {
+    fn create_entry(&self, inode: Inode, mut st: bindings::stat64) -> Entry {
+        self.patch_dev(&mut st);
+        self.patch_dir_overrides(inode, &mut st);
+        self.patch_dir_nlink(inode, &mut st);
+        self.patch_blocks(inode, &mut st);
+        let generation = self
+            .inodes
+            .read()
+            .unwrap()
+            .get(&inode)
+            .map_or(0, |data| data.generation);
         Entry {
             inode,
-            generation: 0,
+            generation,
             attr: st,
             attr_flags: 0,
             attr_timeout: self.config.attr_timeout,
@@ -604,42 +1880,378 @@ impl OverlayFs {
         }
     }
 
-    /// Checks for whiteout file in top layer
-    fn check_whiteout(&self, parent_path: &CStr, name: &CStr) -> io::Result<bool> {
-        let parent_str = parent_path.to_str().map_err(|_| einval())?;
-        let name_str = name.to_str().map_err(|_| einval())?;
+    /// Replaces the device ID of an entry with the one of the share if `Config::single_dev` is set.
+    fn patch_dev(&self, st: &mut bindings::stat64) {
+        if let Some(dev) = self.share_dev {
+            st.st_dev = dev;
+        }
+    }
+
+    /// Reports no blocks for a top layer file still holding the data of the lower layer file it
+    /// shadows, if `Config::differential_blocks` is set.
+    fn patch_blocks(&self, inode: Inode, st: &mut bindings::stat64) {
+        if !self.config.differential_blocks || st.st_mode & libc::S_IFMT != libc::S_IFREG {
+            return;
+        }
+        let top_layer_idx = self.get_top_layer_idx();
+        let Ok(inode_data) = self.get_inode_data(inode) else {
+            return;
+        };
+        if inode_data.layer_idx != top_layer_idx {
+            return;
+        }
+        let Ok(cpath) = CString::new(self.relative_path(&inode_data.path.names())) else {
+            return;
+        };
+
+        for lower_idx in (0..top_layer_idx).rev() {
+            let Some(root_fd) = self
+                .get_layer_root(lower_idx)
+                .ok()
+                .and_then(|layer_root| layer_root.dirfd.as_ref().map(|dir| dir.as_raw_fd()))
+            else {
+                return;
+            };
+            match Self::unpatched_stat_at(root_fd, &cpath) {
+                Ok(lower) => {
+                    if lower.st_mode & libc::S_IFMT == libc::S_IFREG
+                        && lower.st_size == st.st_size
+                        && (lower.st_mtime, lower.st_mtime_nsec) == (st.st_mtime, st.st_mtime_nsec)
+                    {
+                        st.st_blocks = 0;
+                    }
+                    return;
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(_) => return,
+            }
+        }
+    }
+
+    /// Replaces the link count of a directory according to `Config::dir_nlink`.
+    fn patch_dir_nlink(&self, inode: Inode, st: &mut bindings::stat64) {
+        if st.st_mode & libc::S_IFMT != libc::S_IFDIR {
+            return;
+        }
+
+        match self.config.dir_nlink {
+            DirNlinkPolicy::One => st.st_nlink = 1,
+            DirNlinkPolicy::Merged => {
+                let mut subdirs = 0;
+                let res = self.process_dir_entries(inode, |entry| {
+                    if entry.type_ == libc::DT_DIR as u32 {
+                        subdirs += 1;
+                    }
+                    Ok(1)
+                });
+
+                // Keep the count of the layer if the directory can't be read
+                if res.is_ok() {
+                    st.st_nlink = (2 + subdirs) as _;
+                }
+            }
+            DirNlinkPolicy::Layer => {}
+        }
+    }
+
+    /// Applies the owner and permissions set on a directory that is still in a lower layer.
+    fn patch_dir_overrides(&self, inode: Inode, st: &mut bindings::stat64) {
+        if st.st_mode & libc::S_IFMT != libc::S_IFDIR {
+            return;
+        }
+
+        let overrides = self.dir_overrides.lock().unwrap();
+        if overrides.is_empty() {
+            return;
+        }
+
+        let Ok(inode_data) = self.get_inode_data(inode) else {
+            return;
+        };
+        if inode_data.layer_idx == self.get_top_layer_idx() {
+            return;
+        }
+
+        if let Some((uid, gid, mode)) = overrides.get(&self.relative_path(&inode_data.path.names()))
+        {
+            st.st_uid = *uid;
+            st.st_gid = *gid;
+            st.st_mode = (st.st_mode & !0o7777u16) | mode;
+        }
+    }
+
+    /// Reads the directory overrides recorded in the top layer at `upper`, and compacts their
+    /// file if it holds records that were overridden since, or the partial record of a crash.
+    fn load_dir_overrides(upper: &Path) -> io::Result<HashMap<Vec<u8>, (u32, u32, u16)>> {
+        let buf = match fs::read(upper.join(DIR_OVERRIDES_FILE)) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e),
+        };
+
+        // Each record is a path followed by an `uid:gid:mode` value, both NUL-terminated. An empty
+        // value drops the override of the path.
+        let mut overrides = HashMap::new();
+        let mut records = 0;
+        let mut rest = &buf[..];
+        loop {
+            // A record cut short by a crash is left out
+            let Some(path_end) = rest.iter().position(|&b| b == 0) else {
+                break;
+            };
+            let Some(value_len) = rest[path_end + 1..].iter().position(|&b| b == 0) else {
+                break;
+            };
+            let value_end = path_end + 1 + value_len;
+            let (path, value) = (&rest[..path_end], &rest[path_end + 1..value_end]);
+            rest = &rest[value_end + 1..];
+            records += 1;
+            if value.is_empty() {
+                overrides.remove(path);
+                continue;
+            }
+
+            let parsed = std::str::from_utf8(value).ok().and_then(|value| {
+                let mut parts = value.split(':');
+                Some((
+                    parts.next()?.parse().ok()?,
+                    parts.next()?.parse().ok()?,
+                    u16::from_str_radix(parts.next()?, 8).ok()?,
+                ))
+            });
+
+            match parsed {
+                Some(parsed) => {
+                    overrides.insert(path.to_vec(), parsed);
+                }
+                None => warn!(
+                    "ignoring malformed directory override for {:?}",
+                    String::from_utf8_lossy(path)
+                ),
+            }
+        }
+
+        if records != overrides.len() || !rest.is_empty() {
+            Self::compact_dir_overrides(upper, &overrides)?;
+        }
+
+        Ok(overrides)
+    }
+
+    /// Replaces the file of the directory overrides in the top layer at `upper` with the records
+    /// of `overrides` alone.
+    fn compact_dir_overrides(
+        upper: &Path,
+        overrides: &HashMap<Vec<u8>, (u32, u32, u16)>,
+    ) -> io::Result<()> {
+        let mut buf = Vec::new();
+        for (path, value) in overrides {
+            encode_dir_override(&mut buf, path, Some(*value));
+        }
+
+        // Write a new file and rename it into place, so that a crash leaves either file whole
+        let tmp_path = upper.join(format!("{DIR_OVERRIDES_FILE}.tmp"));
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, upper.join(DIR_OVERRIDES_FILE))?;
+        File::open(upper)?.sync_all()
+    }
+
+    /// Appends the changes of the directory overrides in `changes` to their file in the top
+    /// layer, `None` dropping the override of a path. The caller holds the lock of
+    /// `dir_overrides`, so that the records are appended in the order they were made.
+    fn append_dir_overrides(
+        &self,
+        changes: &[(Vec<u8>, Option<(u32, u32, u16)>)],
+    ) -> io::Result<()> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        let mut buf = Vec::new();
+        for (path, value) in changes {
+            encode_dir_override(&mut buf, path, *value);
+        }
+
+        let path = self.upper_layer_path().join(DIR_OVERRIDES_FILE);
+        let created = !path.exists();
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        file.write_all(&buf)?;
+        file.sync_data()?;
+        if created {
+            File::open(self.upper_layer_path())?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Drops the overrides of the directory at `path`, relative to the layer roots, and of the
+    /// directories below it, once it was removed or moved away.
+    fn drop_dir_overrides(&self, path: &[u8]) -> io::Result<()> {
+        let mut overrides = self.dir_overrides.lock().unwrap();
+        let dropped: Vec<_> = overrides
+            .keys()
+            .filter(|key| {
+                key.strip_prefix(path)
+                    .is_some_and(|rest| rest.is_empty() || rest[0] == b'/')
+            })
+            .cloned()
+            .collect();
+        for key in &dropped {
+            overrides.remove(key);
+        }
+
+        let changes: Vec<_> = dropped.into_iter().map(|key| (key, None)).collect();
+        self.append_dir_overrides(&changes)
+    }
+
+    /// Whether a lookup of `path`, relative to the layer roots, may find anything in the lower layer
+    /// `layer_idx` according to its path filter, the first `known` segments of the path having
+    /// been found in the layers above. The filter is built on the first call for each layer, or
+    /// taken from `Config::lower_layers`.
+    fn lower_layer_may_affect(&self, layer_idx: usize, path: &[u8], known: usize) -> bool {
+        // A layer that can't be walked is always probed
+        let build = || LayerFilter::build(&self.config.layers[layer_idx]).unwrap_or_default();
+        let filter = match &self.config.lower_layers {
+            Some(set) => set.filter(layer_idx, build),
+            None => self.layer_filters[layer_idx]
+                .lock()
+                .unwrap()
+                .get_or_insert_with(|| Arc::new(build()))
+                .clone(),
+        };
+        filter.may_affect(path, known)
+    }
+
+    /// Returns the path made of the `path` names, relative to the layer roots.
+    fn relative_path(&self, path: &[Name]) -> Vec<u8> {
+        let mut relative = Vec::new();
+        for name in path {
+            if !relative.is_empty() {
+                relative.push(b'/');
+            }
+            relative.extend_from_slice(name.to_bytes());
+        }
+        relative
+    }
 
-        let whiteout_path = format!("{}/{}{}", parent_str, WHITEOUT_PREFIX, name_str);
-        let whiteout_cpath = CString::new(whiteout_path).map_err(|_| einval())?;
+    /// Checks for a whiteout file for `name` in the directory referred to by `parent_fd`
+    fn check_whiteout(&self, parent_fd: RawFd, name: &CStr) -> io::Result<bool> {
+        let mut whiteout_name = WHITEOUT_PREFIX.as_bytes().to_vec();
+        whiteout_name.extend_from_slice(name.to_bytes());
+        let whiteout_cname = CString::new(whiteout_name).map_err(|_| einval())?;
 
-        match Self::unpatched_stat(&FileId::Path(whiteout_cpath)) {
+        match Self::unpatched_stat_at(parent_fd, &whiteout_cname) {
             Ok(_) => Ok(true),
             Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
             Err(e) => Err(e),
         }
     }
 
-    /// Interns a name and returns the corresponding Symbol
-    fn intern_name(&self, name: &CStr) -> io::Result<Symbol> {
-        // Clone the name to avoid lifetime issues
-        let name_to_intern = CString::new(name.to_bytes()).map_err(|_| einval())?;
+    /// Returns whether the whiteout of `path` in the layer `layer_idx` is stale, that is, whether
+    /// the entry it hides was recreated in a lower layer after the whiteout was created.
+    ///
+    /// `path` is the path of the hidden entry relative to the layer roots. The change times are
+    /// compared rather than the modification times, which unpacking an image preserves. Only the
+    /// whiteouts of the top layer can be stale: those of the lower layers come from an image, whose
+    /// layers are unpacked in no particular order, so their change times say nothing of the
+    /// entries they hide.
+    fn is_stale_whiteout(&self, layer_idx: usize, path: &[u8]) -> bool {
+        if layer_idx != self.get_top_layer_idx() {
+            return false;
+        }
+
+        let (Ok(whiteout_cpath), Ok(cpath)) =
+            (CString::new(whiteout_path(path)), CString::new(path))
+        else {
+            return false;
+        };
 
-        // Get a write lock to intern it
-        let mut filenames = self.filenames.write().unwrap();
-        filenames.intern(name_to_intern).map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!("Failed to intern filename: {}", e),
-            )
-        })
+        let layer_root_fd = |layer_idx| {
+            self.get_layer_root(layer_idx).and_then(|layer_root| {
+                layer_root
+                    .dirfd
+                    .as_ref()
+                    .map(|dir| dir.as_raw_fd())
+                    .ok_or_else(ebadf)
+            })
+        };
+
+        let whiteout = match layer_root_fd(layer_idx)
+            .and_then(|root_fd| Self::unpatched_stat_at(root_fd, &whiteout_cpath))
+        {
+            Ok(st) => st,
+            Err(_) => return false,
+        };
+
+        for lower_idx in (0..layer_idx).rev() {
+            let root_fd = match layer_root_fd(lower_idx) {
+                Ok(root_fd) => root_fd,
+                Err(_) => return false,
+            };
+
+            match Self::unpatched_stat_at(root_fd, &cpath) {
+                Ok(st) => {
+                    return (st.st_ctime, st.st_ctime_nsec)
+                        > (whiteout.st_ctime, whiteout.st_ctime_nsec);
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(_) => return false,
+            }
+        }
+
+        false
+    }
+
+    /// Returns whether the directory `dir` may contain whiteouts or an opaque marker.
+    ///
+    /// The directory is scanned the first time this is called and the result is cached in its
+    /// inode, so that lookups through directories without any whiteouts skip the whiteout and
+    /// opaque marker probes entirely. Creating a whiteout marks the directory as
+    /// [`WHITEOUTS_PRESENT`], which is always a safe answer.
+    fn may_have_whiteouts(dir: &InodeData, dir_fd: RawFd) -> io::Result<bool> {
+        match dir.whiteouts.load(Ordering::Acquire) {
+            WHITEOUTS_ABSENT => return Ok(false),
+            WHITEOUTS_PRESENT => return Ok(true),
+            _ => (),
+        }
+
+        let mut present = false;
+        for entry in HostDir::open(dir_fd)? {
+            if entry?.name.starts_with(WHITEOUT_PREFIX.as_bytes()) {
+                present = true;
+                break;
+            }
+        }
+
+        // Don't overwrite a whiteout that was created while we were scanning.
+        let state = if present {
+            WHITEOUTS_PRESENT
+        } else {
+            WHITEOUTS_ABSENT
+        };
+        let _ = dir.whiteouts.compare_exchange(
+            WHITEOUTS_UNKNOWN,
+            state,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
+
+        Ok(present)
+    }
+
+    /// Interns a name and returns the corresponding Name
+    fn intern_name(&self, name: &CStr) -> Name {
+        self.filenames.lock().unwrap().intern(name)
     }
 
-    /// Checks for an opaque directory marker in the given parent directory path.
-    fn check_opaque_marker(&self, parent_path: &CStr) -> io::Result<bool> {
-        let parent_str = parent_path.to_str().map_err(|_| einval())?;
-        let opaque_path = format!("{}/{}", parent_str, OPAQUE_MARKER);
-        let opaque_cpath = CString::new(opaque_path).map_err(|_| einval())?;
-        match Self::unpatched_stat(&FileId::Path(opaque_cpath)) {
+    /// Checks for an opaque directory marker in the directory referred to by `parent_fd`.
+    fn check_opaque_marker(&self, parent_fd: RawFd) -> io::Result<bool> {
+        let opaque_cname = unsafe { CStr::from_bytes_with_nul_unchecked(OPAQUE_MARKER_CSTR) };
+        match Self::unpatched_stat_at(parent_fd, opaque_cname) {
             Ok(_) => Ok(true),
             Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
             Err(e) => Err(e),
@@ -684,19 +2296,8 @@ impl OverlayFs {
             ));
         }
 
-        // Convert to str for string pattern matching
-        let name_str = match std::str::from_utf8(name_bytes) {
-            Ok(s) => s,
-            Err(_) => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "name contains invalid UTF-8",
-                ))
-            }
-        };
-
         // Check for whiteout prefix
-        if name_str.starts_with(".wh.") {
+        if name_bytes.starts_with(WHITEOUT_PREFIX.as_bytes()) {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 "name cannot start with whiteout prefix",
@@ -704,7 +2305,7 @@ impl OverlayFs {
         }
 
         // Check for opaque marker
-        if name_str == ".wh..wh..opq" {
+        if name_bytes == OPAQUE_MARKER.as_bytes() {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 "name cannot be an opaque directory marker",
@@ -723,6 +2324,7 @@ impl OverlayFs {
     /// * `layer_root` - Root inode data for the layer being searched
     /// * `path_segments` - Path components to traverse, as interned symbols
     /// * `path_inodes` - Vector to store inode data for each path segment traversed
+    /// * `probes` - The whiteout and opaque marker probes already made by the request
     ///
     /// # Return Value
     /// Returns `Option<io::Result<bindings::stat64>>` where:
@@ -756,82 +2358,96 @@ impl OverlayFs {
     fn lookup_segment_by_segment(
         &self,
         layer_root: &Arc<InodeData>,
-        path_segments: &[Symbol],
+        path_segments: &[Name],
         path_inodes: &mut Vec<Arc<InodeData>>,
+        probes: &mut WhiteoutProbes,
     ) -> Option<io::Result<bindings::stat64>> {
         let mut current_stat;
-        let mut parent_dev = layer_root.dev;
-        let mut parent_ino = layer_root.ino;
         let mut opaque_marker_found = false;
 
-        // Start from layer root
-        let root_vol_path = match self.dev_ino_to_vol_path(parent_dev, parent_ino) {
-            Ok(path) => path,
-            Err(e) => return Some(Err(e)),
+        // The directory currently being searched, starting from the layer root
+        let mut parent_data = layer_root.clone();
+        let mut parent_dir = match layer_root.dirfd.clone() {
+            Some(dir) => dir,
+            None => return Some(Err(ebadf())),
         };
 
-        current_stat = match Self::patched_stat(&FileId::Path(root_vol_path)) {
+        current_stat = match Self::patched_stat(&FileId::Fd(parent_dir.as_raw_fd())) {
             Ok(stat) => stat,
             Err(e) => return Some(Err(e)),
         };
 
         // Traverse each path segment
         for (depth, segment) in path_segments.iter().enumerate() {
-            // Get the current segment name and parent vol path
-            let filenames = self.filenames.read().unwrap();
-            let segment_name = filenames.get(*segment).unwrap();
-            let parent_vol_path = match self.dev_ino_to_vol_path(parent_dev, parent_ino) {
-                Ok(path) => path,
-                Err(e) => return Some(Err(e)),
-            };
+            // Get the current segment name and the handle of the directory it lives in
+            let segment_name = CString::from(&**segment);
+            let parent_fd = parent_dir.as_raw_fd();
 
-            // Check for whiteout at current level
-            match self.check_whiteout(&parent_vol_path, segment_name) {
-                Ok(true) => return None, // Found whiteout, stop searching
-                Ok(false) => (),         // No whiteout, continue
+            // Only probe for whiteouts and opaque markers if the directory may contain any
+            let whiteouts = match Self::may_have_whiteouts(&parent_data, parent_fd) {
+                Ok(whiteouts) => whiteouts,
                 Err(e) => return Some(Err(e)),
-            }
+            };
 
-            // Check for opaque marker at current level
-            match self.check_opaque_marker(&parent_vol_path) {
-                Ok(true) => {
-                    opaque_marker_found = true;
+            if whiteouts {
+                // Check for whiteout at current level
+                match probes.probe(
+                    &self.whiteout_cache,
+                    parent_data.inode,
+                    Some(segment),
+                    || self.check_whiteout(parent_fd, &segment_name),
+                ) {
+                    Ok(true) => {
+                        // Found whiteout, stop searching unless the entry was recreated below it.
+                        // A whiteout only hides the lower layers, so an entry of the same name in
+                        // its own layer, which the container tools export when an entry changes
+                        // type, is still found, and masks the lower layers as an opaque one would.
+                        if !self.config.verify_whiteouts
+                            || !self.is_stale_whiteout(
+                                layer_root.layer_idx,
+                                &self.relative_path(&path_segments[..=depth]),
+                            )
+                        {
+                            if Self::patched_stat_at(parent_fd, &segment_name).is_err() {
+                                return None;
+                            }
+                            opaque_marker_found = true;
+                        }
+                    }
+                    Ok(false) => (), // No whiteout, continue
+                    Err(e) => return Some(Err(e)),
                 }
-                Ok(false) => (),
-                Err(e) => return Some(Err(e)),
-            }
 
-            // Try to stat the current segment using parent dev/ino
-            let current_vol_path =
-                match self.dev_ino_and_name_to_vol_path(parent_dev, parent_ino, segment_name) {
-                    Ok(path) => path,
+                // Check for opaque marker at current level
+                match probes.probe(&self.whiteout_cache, parent_data.inode, None, || {
+                    self.check_opaque_marker(parent_fd)
+                }) {
+                    Ok(true) => {
+                        opaque_marker_found = true;
+                    }
+                    Ok(false) => (),
                     Err(e) => return Some(Err(e)),
-                };
-
-            drop(filenames); // Now safe to drop filenames lock
+                }
+            }
 
-            match Self::patched_stat(&FileId::Path(current_vol_path)) {
+            // Try to stat the current segment relative to its parent
+            match Self::patched_stat_at(parent_fd, &segment_name) {
                 Ok(st) => {
-                    // Update parent dev/ino for next iteration
-                    parent_dev = st.st_dev as i32;
-                    parent_ino = st.st_ino;
                     current_stat = st;
 
                     // Create or get inode for this path segment
                     let alt_key = InodeAltKey::new(st.st_ino, st.st_dev as i32);
-                    let inode_data = {
-                        let inodes = self.inodes.read().unwrap();
-                        if let Some(data) = inodes.get_alt(&alt_key) {
-                            data.clone()
-                        } else {
-                            drop(inodes); // Drop read lock before write lock
-
-                            let mut path = path_inodes[depth].path.clone();
-                            path.push(*segment);
+                    let existing = self.inodes.read().unwrap().get_alt(&alt_key).cloned();
+                    let inode_data = match existing {
+                        Some(data) if !data.is_recycled(&st) => data,
+                        _ => {
+                            // A new inode replaces a recycled one, whose file is gone
+                            let path = path_inodes[depth].path.child(segment.clone());
 
                             let (_, data) = self.create_inode(
                                 st.st_ino,
                                 st.st_dev as i32,
+                                birth_time(&st),
                                 path,
                                 layer_root.layer_idx,
                             );
@@ -840,6 +2456,20 @@ impl OverlayFs {
                         }
                     };
 
+                    // Descend into this segment if there is more of the path to walk
+                    if depth + 1 < path_segments.len() {
+                        parent_dir = match self.child_dir_fd(
+                            layer_root.layer_idx,
+                            &inode_data.path,
+                            parent_fd,
+                            &segment_name,
+                        ) {
+                            Ok(dir) => dir,
+                            Err(e) => return Some(Err(e)),
+                        };
+                        parent_data = inode_data.clone();
+                    }
+
                     // Update path_inodes with the current segment's inode data
                     if (depth + 1) >= path_inodes.len() {
                         // Haven't seen this depth before, append
@@ -886,9 +2516,15 @@ impl OverlayFs {
     fn lookup_layer_by_layer<'a>(
         &'a self,
         start_layer_idx: usize,
-        path_segments: &[Symbol],
+        path_segments: &[Name],
+        probes: &mut WhiteoutProbes,
     ) -> io::Result<(Entry, Arc<InodeData>, Vec<Arc<InodeData>>)> {
         let mut path_inodes = vec![];
+        let top_layer_idx = self.get_top_layer_idx();
+        let relative_path = self
+            .config
+            .lookup_filters
+            .then(|| self.relative_path(path_segments));
 
         // Start from the start_layer_idx and try each layer down to layer 0
         for layer_idx in (0..=start_layer_idx).rev() {
@@ -899,23 +2535,43 @@ impl OverlayFs {
                 path_inodes = vec![layer_root.clone()];
             }
 
-            match self.lookup_segment_by_segment(&layer_root, &path_segments, &mut path_inodes) {
+            // Skip the lower layers that have nothing to say about the path
+            if let Some(relative_path) = &relative_path {
+                if layer_idx != top_layer_idx
+                    && !self.lower_layer_may_affect(layer_idx, relative_path, path_inodes.len() - 1)
+                {
+                    continue;
+                }
+            }
+
+            match self.lookup_segment_by_segment(
+                &layer_root,
+                &path_segments,
+                &mut path_inodes,
+                probes,
+            ) {
                 Some(Ok(st)) => {
                     let alt_key = InodeAltKey::new(st.st_ino, st.st_dev as i32);
 
                     // Check if we already have this inode
-                    let inodes = self.inodes.read().unwrap();
-                    if let Some(data) = inodes.get_alt(&alt_key) {
-                        return Ok((self.create_entry(data.inode, st), data.clone(), path_inodes));
+                    let existing = self.inodes.read().unwrap().get_alt(&alt_key).cloned();
+                    if let Some(data) = existing.filter(|data| !data.is_recycled(&st)) {
+                        return Ok((self.create_entry(data.inode, st), data, path_inodes));
                     }
 
-                    drop(inodes);
-
-                    // Create new inode
+                    // Create new inode, which replaces a recycled one, whose file is gone. The
+                    // parent is the last inode of the path.
+                    let path = match path_segments.last() {
+                        Some(name) => path_inodes[path_segments.len() - 1]
+                            .path
+                            .child(name.clone()),
+                        None => InodePath::root(),
+                    };
                     let (inode, data) = self.create_inode(
                         st.st_ino,
                         st.st_dev as i32,
-                        path_segments.to_vec(),
+                        birth_time(&st),
+                        path,
                         layer_idx,
                     );
                     path_inodes.push(data.clone());
@@ -950,11 +2606,22 @@ impl OverlayFs {
         let parent_data = self.get_inode_data(parent)?;
 
         // Create path segments for lookup by appending the new name
-        let mut path_segments = parent_data.path.clone();
-        let symbol = self.intern_name(name)?;
-        path_segments.push(symbol);
+        let mut path_segments = parent_data.path.names();
+        path_segments.push(self.intern_name(name));
 
-        let (mut entry, child_data, path_inodes) = self.lookup_layer_by_layer(parent_data.layer_idx, &path_segments)?;
+        let (mut entry, child_data, path_inodes) = match self.lookup_layer_by_layer(
+            parent_data.layer_idx,
+            &path_segments,
+            &mut WhiteoutProbes::default(),
+        ) {
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {
+                return match self.folded_name(parent, name)? {
+                    Some(folded) if folded.as_c_str() != name => self.do_lookup(parent, &folded),
+                    _ => Err(e),
+                };
+            }
+            res => res?,
+        };
 
         // Set the submount flag if the entry is a directory and the submounts are announced
         let mut attr_flags = 0;
@@ -987,14 +2654,14 @@ impl OverlayFs {
     fn unpatched_stat(file: &FileId) -> io::Result<bindings::stat64> {
         let mut st = MaybeUninit::<bindings::stat64>::zeroed();
 
-        let ret = unsafe {
+        let ret = retry_syscall(|| unsafe {
             match file {
                 FileId::Path(path) => {
                     libc::lstat(path.as_ptr(), st.as_mut_ptr() as *mut libc::stat)
                 }
                 FileId::Fd(fd) => libc::fstat(*fd, st.as_mut_ptr() as *mut libc::stat),
             }
-        };
+        });
         if ret < 0 {
             return Err(io::Error::last_os_error());
         }
@@ -1036,6 +2703,73 @@ impl OverlayFs {
         Ok(stat)
     }
 
+    /// Performs a raw `fstatat` syscall on `name` relative to the directory `dirfd`.
+    ///
+    /// Like [`Self::unpatched_stat`], trailing symlinks are not followed and no overlayfs
+    /// metadata is applied to the result.
+    fn unpatched_stat_at(dirfd: RawFd, name: &CStr) -> io::Result<bindings::stat64> {
+        let mut st = MaybeUninit::<bindings::stat64>::zeroed();
+
+        let ret = retry_syscall(|| unsafe {
+            libc::fstatat(
+                dirfd,
+                name.as_ptr(),
+                st.as_mut_ptr() as *mut libc::stat,
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        });
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(unsafe { st.assume_init() })
+    }
+
+    /// Performs an `fstatat` syscall on `name` relative to `dirfd` and patches the result with
+    /// overlayfs metadata, like [`Self::patched_stat`].
+    ///
+    /// There is no `*at()` variant of `getxattr` on macOS, so the entry is opened to read the
+    /// owner/permission overrides. Device nodes are left as they are, as opening one would open
+    /// the device, and so are the entries that can't be opened, such as sockets.
+    fn patched_stat_at(dirfd: RawFd, name: &CStr) -> io::Result<bindings::stat64> {
+        let mut stat = Self::unpatched_stat_at(dirfd, name)?;
+        if matches!(stat.st_mode & libc::S_IFMT, libc::S_IFCHR | libc::S_IFBLK) {
+            return Ok(stat);
+        }
+
+        // Get owner and permissions from xattr
+        let Ok(file) = Self::open_meta_at(dirfd, name) else {
+            return Ok(stat);
+        };
+        if let Ok(Some((uid, gid, mode))) =
+            Self::get_owner_perms_attr(&FileId::Fd(file.as_raw_fd()), &stat)
+        {
+            stat.st_uid = uid;
+            stat.st_gid = gid;
+            stat.st_mode = (stat.st_mode & !0o7777u16) | mode;
+        }
+
+        Ok(stat)
+    }
+
+    /// Opens the entry `name` of `dirfd` to read and change its metadata, opening a symlink itself
+    /// rather than its target.
+    fn open_meta_at(dirfd: RawFd, name: &CStr) -> io::Result<File> {
+        let fd = retry_syscall(|| unsafe {
+            libc::openat(
+                dirfd,
+                name.as_ptr(),
+                libc::O_EVTONLY | libc::O_SYMLINK | libc::O_NONBLOCK | libc::O_CLOEXEC,
+            )
+        });
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Safe because we just opened this fd.
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
     fn get_owner_perms_attr(
         file: &FileId,
         st: &bindings::stat64,
@@ -1166,91 +2900,206 @@ impl OverlayFs {
         Ok(())
     }
 
+    /// Returns the name of the entry of the directory `parent` whose name only differs from `name`
+    /// by case, for a lookup of `name` that found nothing, if the lookups fold the case of the
+    /// names. Fails with `EIO` if several entries do.
+    fn folded_name(&self, parent: Inode, name: &CStr) -> io::Result<Option<CString>> {
+        if !self.config.case_insensitive {
+            return Ok(None);
+        }
+
+        let mut names = Vec::new();
+        let mut stream = self.open_dir_stream(parent)?;
+        self.read_dir_stream(&mut stream, |entry| {
+            names.push(entry.name.to_vec());
+            Ok(1)
+        })?;
+
+        case_names::find_folded(names, name.to_bytes()).map_err(|(first, second)| {
+            warn!("virtio-fs: {name:?} matches both {first:?} and {second:?} of inode {parent}");
+            io::Error::from_raw_os_error(libc::EIO)
+        })
+    }
+
+    /// Returns the name of the entry of the directory `parent` a lookup of `name` finds, which is
+    /// `name` itself unless the lookups fold the case of the names, so that the requests changing
+    /// the entry change the one the guest sees.
+    fn entry_name<'a>(&self, parent: Inode, name: &'a CStr) -> io::Result<Cow<'a, CStr>> {
+        if !self.config.case_insensitive {
+            return Ok(Cow::Borrowed(name));
+        }
+
+        let parent_data = self.get_inode_data(parent)?;
+        let mut path_segments = parent_data.path.names();
+        path_segments.push(self.intern_name(name));
+        match self.lookup_layer_by_layer(
+            parent_data.layer_idx,
+            &path_segments,
+            &mut WhiteoutProbes::default(),
+        ) {
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {
+                Ok(match self.folded_name(parent, name)? {
+                    Some(folded) => Cow::Owned(folded),
+                    None => Cow::Borrowed(name),
+                })
+            }
+            _ => Ok(Cow::Borrowed(name)),
+        }
+    }
+
+    /// Runs the hooks of the embedder before the copy-up of `inode_data`, which may veto it.
+    fn run_copy_up_hooks(&self, inode_data: &InodeData) -> io::Result<()> {
+        match self.hooks() {
+            Some(hooks) if hooks.has(FsHookPoint::PreCopyUp) => {
+                let path = self.relative_path(&inode_data.path.names());
+                hooks.run(FsHookPoint::PreCopyUp, Path::new(OsStr::from_bytes(&path)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Extracts the data of `inode_data` if it is a file of a lazy layer, before it is read.
+    #[cfg(feature = "oci")]
+    fn materialize(&self, inode_data: &InodeData) -> io::Result<()> {
+        match self
+            .config
+            .lazy_layers
+            .iter()
+            .find(|(layer_idx, _)| *layer_idx == inode_data.layer_idx)
+        {
+            Some((_, layer)) => layer.materialize(&self.relative_path(&inode_data.path.names())),
+            None => Ok(()),
+        }
+    }
+
     /// Copies up a file or directory from a lower layer to the top layer
     pub(crate) fn copy_up(&self, path_inodes: &[Arc<InodeData>]) -> io::Result<()> {
-        // Get the top layer root
         let top_layer_idx = self.get_top_layer_idx();
-        let top_layer_root = self.get_layer_root(top_layer_idx)?;
 
-        // Start from root and copy up each segment that's not in the top layer
-        let mut parent_dev = top_layer_root.dev;
-        let mut parent_ino = top_layer_root.ino;
-
-        // Skip the root inode
+        // Skip the root inode, and copy up each segment that's not in the top layer
         for inode_data in path_inodes.iter().skip(1) {
             // Skip if this segment is already in the top layer
             if inode_data.layer_idx == top_layer_idx {
-                parent_dev = inode_data.dev;
-                parent_ino = inode_data.ino;
                 continue;
             }
 
-            // Get the current segment name
-            let segment_name = {
-                let name = inode_data.path.last().unwrap();
-                let filenames = self.filenames.read().unwrap();
-                filenames.get(*name).unwrap().to_owned()
+            // Another request may have copied this segment up while we waited for its lock
+            let _guard = self.copy_up_locks.lock(inode_data.path.names());
+            if let Ok(current) = self.get_inode_data(inode_data.inode) {
+                if current.layer_idx == top_layer_idx {
+                    continue;
+                }
+            }
+
+            // The parent was copied up by the previous iterations
+            let (parent_path, segment) = inode_data.path.link().ok_or_else(einval)?;
+            let parent_dir = self.dir_fd(top_layer_idx, &parent_path)?;
+            let dst = EntryAt {
+                dir: Some(parent_dir.clone()),
+                name: CString::from(&*segment),
             };
 
-            // Get source and destination paths
-            let src_path = self.dev_ino_to_vol_path(inode_data.dev, inode_data.ino)?;
-            let dst_path =
-                self.dev_ino_and_name_to_vol_path(parent_dev, parent_ino, &segment_name)?;
+            // The entry may have been removed while we waited for its lock, and copying it up
+            // would bring it back next to its whiteout
+            if dst.whiteout()?.stat().is_ok()
+                && (!self.config.verify_whiteouts
+                    || !self.is_stale_whiteout(
+                        top_layer_idx,
+                        &self.relative_path(&inode_data.path.names()),
+                    ))
+            {
+                return Err(io::Error::from_raw_os_error(libc::ENOENT));
+            }
 
             // Get source file/directory stats
-            let src_stat = Self::patched_stat(&FileId::Path(src_path.clone()))?;
+            let src = self.entry_at(inode_data)?;
+            let src_stat = src.patched_stat()?;
             let file_type = src_stat.st_mode & libc::S_IFMT;
 
+            // A directory looked up again from a lower layer after its copy-up, as an ancestor of
+            // an entry only found there, is a different inode than its copy, which already merges
+            // it
+            if file_type == libc::S_IFDIR {
+                if let Ok(st) = dst.stat() {
+                    if st.st_mode & libc::S_IFMT == libc::S_IFDIR {
+                        continue;
+                    }
+                }
+            }
+
+            self.run_copy_up_hooks(inode_data)?;
+
+            let parent = EntryAt {
+                dir: Some(parent_dir.clone()),
+                name: c".".into(),
+            };
+            let parent_stat = parent.stat()?;
+
             // Copy up the file/directory
             match file_type {
                 libc::S_IFREG => {
-                    // Regular file: use clonefile for COW semantics if available
-                    // Use clonefile for COW semantics
-                    let result = unsafe { clonefile(src_path.as_ptr(), dst_path.as_ptr(), 0) };
-
-                    if result < 0 {
-                        let err = io::Error::last_os_error();
-                        // If clonefile fails (e.g., across filesystems), fall back to regular copy
-                        if err.raw_os_error() == Some(libc::EXDEV)
-                            || err.raw_os_error() == Some(libc::ENOTSUP)
-                        {
-                            // Fall back to regular copy
-                            self.copy_file_contents(
-                                &src_path,
-                                &dst_path,
-                                (src_stat.st_mode & 0o777) as u32,
-                            )?;
-                        } else {
-                            return Err(err);
-                        }
-                    }
+                    #[cfg(feature = "oci")]
+                    self.materialize(inode_data)?;
+
+                    // The source layers are read-only, so the layer index and inode number of the
+                    // source identify the staging file across restarts without running into the
+                    // name length limit.
+                    let staging = dst.sibling(
+                        CString::new(format!(
+                            "{COPY_UP_STAGING_PREFIX}{}.{}",
+                            inode_data.layer_idx, src_stat.st_ino
+                        ))
+                        .map_err(|_| einval())?,
+                    );
+
+                    self.copy_up_regular_file(&src, &staging, &dst, &src_stat)?;
                 }
                 libc::S_IFDIR => {
                     // Directory: just create it with the same permissions
                     unsafe {
-                        if libc::mkdir(dst_path.as_ptr(), src_stat.st_mode & 0o777) < 0 {
+                        if libc::mkdirat(dst.dirfd(), dst.name.as_ptr(), src_stat.st_mode & 0o777)
+                            < 0
+                        {
                             return Err(io::Error::last_os_error());
                         }
 
                         // Explicitly set directory permissions to match source
-                        if libc::chmod(dst_path.as_ptr(), src_stat.st_mode & 0o777) < 0 {
+                        if libc::fchmodat(
+                            dst.dirfd(),
+                            dst.name.as_ptr(),
+                            src_stat.st_mode & 0o777,
+                            0,
+                        ) < 0
+                        {
                             return Err(io::Error::last_os_error());
                         }
                     }
+
+                    // Carry over the owner and permissions set while it was in a lower layer
+                    let mut overrides = self.dir_overrides.lock().unwrap();
+                    let path = self.relative_path(&inode_data.path.names());
+                    if let Some((uid, gid, mode)) = overrides.remove(&path) {
+                        Self::set_owner_perms_attr(
+                            &FileId::Fd(dst.open_meta()?.as_raw_fd()),
+                            &src_stat,
+                            Some((uid, gid)),
+                            Some(mode),
+                        )?;
+                        self.append_dir_overrides(&[(path, None)])?;
+                    }
                 }
                 libc::S_IFLNK => {
                     // Symbolic link: read target and recreate link
-                    let mut buf = vec![0u8; libc::PATH_MAX as usize];
-                    let len = unsafe {
-                        libc::readlink(src_path.as_ptr(), buf.as_mut_ptr() as *mut _, buf.len())
-                    };
-                    if len < 0 {
-                        return Err(io::Error::last_os_error());
+                    let mut buf = src.read_link()?;
+                    if let Some(remapped) =
+                        remap_symlink_target(&self.config.symlink_target_map, &buf)
+                    {
+                        buf = remapped;
                     }
-                    buf.truncate(len as usize);
+                    let target = CString::new(buf).map_err(|_| einval())?;
 
                     unsafe {
-                        if libc::symlink(buf.as_ptr() as *const _, dst_path.as_ptr()) < 0 {
+                        if libc::symlinkat(target.as_ptr(), dst.dirfd(), dst.name.as_ptr()) < 0 {
                             return Err(io::Error::last_os_error());
                         }
 
@@ -1267,12 +3116,41 @@ impl OverlayFs {
                 }
             }
 
-            // Update parent dev/ino for next iteration
-            let new_stat = Self::unpatched_stat(&FileId::Path(dst_path))?;
-            parent_dev = new_stat.st_dev as i32;
-            parent_ino = new_stat.st_ino;
+            if let Some(sanitizer) = self
+                .config
+                .metadata_sanitizer
+                .as_ref()
+                .filter(|s| s.copy_up)
+            {
+                let retention = XattrRetention {
+                    keep: &sanitizer.keep_xattrs,
+                    is_internal: &is_internal_xattr,
+                };
+                let res = dst
+                    .open_meta()
+                    .and_then(|file| host_metadata::strip_xattrs(&file, &retention));
+                if let Err(e) = res {
+                    warn!("failed to sanitize the copy-up of {:?}: {e}", dst.name);
+                }
+            }
+
+            // The copy-up itself doesn't change anything the guest can see: the copy keeps the
+            // times of its source, and the parent the ones it had before the copy was added to it.
+            // The request that needed the copy-up then updates the parent times if it changes its
+            // entries.
+            Self::set_times(&dst, &src_stat)?;
+            Self::set_times(&parent, &parent_stat)?;
+
+            self.sync_dir(&parent_dir)?;
+
+            let bytes = match file_type {
+                libc::S_IFREG => src_stat.st_size as u64,
+                _ => 0,
+            };
+            self.layer_stats.copy_up(inode_data.layer_idx, bytes);
 
             // Update the inode entry to point to the new copy in the top layer
+            let new_stat = dst.stat()?;
             let alt_key = InodeAltKey::new(new_stat.st_ino, new_stat.st_dev as i32);
             let mut inodes = self.inodes.write().unwrap();
 
@@ -1284,71 +3162,433 @@ impl OverlayFs {
                 refcount: AtomicU64::new(inode_data.refcount.load(Ordering::SeqCst)),
                 path: inode_data.path.clone(),
                 layer_idx: top_layer_idx,
+                dirfd: None,
+                whiteouts: AtomicU8::new(WHITEOUTS_UNKNOWN),
+                generation: inode_data.generation,
+                btime: birth_time(&new_stat),
             });
 
-            // Replace the old entry with the new one
+            // Replace the old entry with the new one, which keeps its number across restarts
             inodes.insert(inode_data.inode, alt_key, new_data);
+            drop(inodes);
+            self.record_inode(
+                new_stat.st_dev as i32,
+                new_stat.st_ino,
+                birth_time(&new_stat),
+                inode_data.inode,
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Copies up a file or directory like `copy_up`, along with everything below it if it is a
+    /// directory. The subdirectories are copied up in parallel, with up to
+    /// `Config::copy_up_threads` threads.
+    fn copy_up_tree(&self, path_inodes: &[Arc<InodeData>]) -> io::Result<()> {
+        self.copy_up(path_inodes)?;
+
+        let Some(last) = path_inodes.last() else {
+            return Ok(());
+        };
+        let st = self.entry_at(last)?.stat()?;
+        if st.st_mode & libc::S_IFMT != libc::S_IFDIR {
+            return Ok(());
+        }
+
+        copy_up::run_task_queue(
+            self.config.copy_up_threads,
+            vec![last.path.names()],
+            |path| self.copy_up_dir_entries(&path),
+        )
+    }
+
+    /// Copies up the entries of the directory at `path`, which is already in the top layer, that
+    /// only exist in lower layers. Returns the paths of its subdirectories, whose own entries are
+    /// left to the caller.
+    fn copy_up_dir_entries(&self, path: &[Name]) -> io::Result<Vec<Vec<Name>>> {
+        let top_layer_idx = self.get_top_layer_idx();
+        let mut probes = WhiteoutProbes::default();
+        let (_, dir_data, dir_inodes) =
+            self.lookup_layer_by_layer(top_layer_idx, path, &mut probes)?;
+
+        let mut entries = Vec::new();
+        self.process_dir_entries(dir_data.inode, |entry| {
+            entries.push((entry.name.to_vec(), entry.type_));
+            Ok(1)
+        })?;
+
+        let mut subdirs = Vec::new();
+        for (name, type_) in entries {
+            let name = CString::new(name).map_err(|_| einval())?;
+            let mut child_path = path.to_vec();
+            child_path.push(self.intern_name(&name));
+
+            let (_, _, child_inodes) =
+                self.lookup_layer_by_layer(top_layer_idx, &child_path, &mut probes)?;
+            self.copy_up(&child_inodes)?;
+            self.drop_unreferenced(&child_inodes);
+
+            if type_ == libc::DT_DIR as u32 {
+                subdirs.push(child_path);
+            }
+        }
+
+        self.drop_unreferenced(&dir_inodes);
+        Ok(subdirs)
+    }
+
+    /// Copies up the regular file `src` to `dst` in the top layer.
+    ///
+    /// The data is first copied to `staging`, a file hidden from the guest, which is only renamed
+    /// into place once its size has been verified against the source. The staging file records the
+    /// size, modification time and identity of its source in an extended attribute, so that a
+    /// copy-up interrupted by e.g. the VM shutting down is resumed on the next attempt, rather than
+    /// leaving a truncated file in the top layer that shadows the intact lower copy.
+    fn copy_up_regular_file(
+        &self,
+        src: &EntryAt,
+        staging: &EntryAt,
+        dst: &EntryAt,
+        src_stat: &bindings::stat64,
+    ) -> io::Result<()> {
+        let marker = Self::copy_up_marker(&src.stat()?);
+
+        // Resume from a previous attempt if it was copying this same source
+        let mut offset = 0;
+        match staging.stat() {
+            Ok(st) => {
+                if Self::get_copy_up_marker(&staging.open_meta()?)?.as_deref()
+                    == Some(marker.as_bytes())
+                    && st.st_size <= src_stat.st_size
+                {
+                    offset = st.st_size as u64;
+                } else {
+                    staging.unlink(0)?;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+
+        // A copy that ran out of space is not resumed, but removed to give the space back to the
+        // guest, which can then make room for it.
+        let size = src_stat.st_size as u64;
+        let no_space = |e: io::Error| {
+            if matches!(e.raw_os_error(), Some(libc::ENOSPC | libc::EDQUOT)) {
+                let _ = staging.unlink(0);
+            }
+            e
+        };
+
+        let mut digest = None;
+        let mut stored = false;
+        if offset == 0 {
+            // Data already in the content store is cloned from there
+            if self.content_store.is_some() && src_stat.st_size >= content_store::MIN_SIZE {
+                let src_file = src.open(libc::O_RDONLY, 0)?;
+                digest = Some(ContentStore::digest(&src_file)?);
+            }
+            stored = digest.as_deref().is_some_and(|digest| {
+                self.clone_from_content_store(digest, src, staging, src_stat)
+            });
+
+            if !stored && !self.clone_file(src, staging, src_stat.st_dev)? {
+                self.copy_file_contents(src, staging, Some(&marker), 0, size)
+                    .map_err(no_space)?;
+            }
+        } else {
+            debug!("resuming copy-up of {:?} at offset {offset}", dst.name);
+            self.copy_file_contents(src, staging, None, offset, size)
+                .map_err(no_space)?;
+        }
+
+        // Make sure the copy is complete before it replaces the lower copy
+        let dst_stat = staging.stat()?;
+        if dst_stat.st_size != src_stat.st_size {
+            error!(
+                "copy-up of {:?} is incomplete: expected {} bytes, got {}",
+                dst.name, src_stat.st_size, dst_stat.st_size
+            );
+            return Err(io::Error::from_raw_os_error(libc::EIO));
+        }
+
+        // The copy, resumed or not, must be of the source as it is now
+        if Self::copy_up_marker(&src.stat()?) != marker {
+            error!("source of the copy-up of {:?} changed during the copy", dst.name);
+            let _ = staging.unlink(0);
+            return Err(io::Error::from_raw_os_error(libc::EIO));
+        }
+
+        // Share the data with the next copy-ups of the same content. This is best effort, as the
+        // store may well be on a file system without clones. The object must not carry the
+        // extended attributes of this copy, clonefile copying them along with the data.
+        if let (Some(store), Some(digest), false) = (&self.content_store, &digest, stored) {
+            let res = store.insert(digest, |path| {
+                let res = unsafe {
+                    libc::clonefileat(
+                        staging.dirfd(),
+                        staging.name.as_ptr(),
+                        libc::AT_FDCWD,
+                        path.as_ptr(),
+                        0,
+                    )
+                };
+                if res < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Self::remove_all_xattrs(path)
+            });
+            if let Err(e) = res {
+                debug!(
+                    "failed to add the copy-up of {:?} to the content store: {e}",
+                    dst.name
+                );
+            }
+        }
+
+        let staging_file = staging.open_meta()?;
+        unsafe {
+            let res = libc::fremovexattr(
+                staging_file.as_raw_fd(),
+                COPY_UP_XATTR_KEY.as_ptr() as *const libc::c_char,
+                0,
+            );
+            if res < 0 {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() != Some(libc::ENOATTR)
+                    && err.raw_os_error() != Some(libc::ENOTSUP)
+                {
+                    return Err(err);
+                }
+            }
+
+            // Explicitly set permissions to match source file
+            // This will override any effects from the umask
+            if libc::fchmod(staging_file.as_raw_fd(), src_stat.st_mode & 0o777) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        staging.rename_to(dst, 0)
+    }
+
+    /// Clones `src`, a file on the device `dev`, to `dst` for copy-on-write semantics, which
+    /// creates the destination in one go. Returns false if the volumes don't support it, the
+    /// caller copying the data instead, and remembers the device so that the next copy-ups from it
+    /// don't try again.
+    fn clone_file(&self, src: &EntryAt, dst: &EntryAt, dev: libc::dev_t) -> io::Result<bool> {
+        if self.clone_unsupported.lock().unwrap().contains(&dev) {
+            return Ok(false);
+        }
+
+        let res = unsafe {
+            libc::clonefileat(
+                src.dirfd(),
+                src.name.as_ptr(),
+                dst.dirfd(),
+                dst.name.as_ptr(),
+                0,
+            )
+        };
+        if res == 0 {
+            return Ok(true);
+        }
+
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::ENOTSUP) | Some(libc::EOPNOTSUPP) | Some(libc::EXDEV) => {
+                if self.clone_unsupported.lock().unwrap().insert(dev) {
+                    info!("files of device {dev} can't be cloned to the top layer, copying: {err}");
+                }
+                Ok(false)
+            }
+            _ => Err(err),
+        }
+    }
+
+    /// Clones the content store object with the given digest to `staging`, along with the
+    /// extended attributes of `src`. Returns whether the object was found and cloned, the caller
+    /// copying the source itself otherwise.
+    fn clone_from_content_store(
+        &self,
+        digest: &str,
+        src: &EntryAt,
+        staging: &EntryAt,
+        src_stat: &bindings::stat64,
+    ) -> bool {
+        let Some(object) = self.content_store.as_ref().and_then(|s| s.get(digest)) else {
+            return false;
+        };
+
+        // Don't trust an object that was truncated behind our back
+        match Self::unpatched_stat(&FileId::Path(object.clone())) {
+            Ok(st) if st.st_size == src_stat.st_size => (),
+            _ => return false,
+        }
+
+        let res = unsafe {
+            libc::clonefileat(
+                libc::AT_FDCWD,
+                object.as_ptr(),
+                staging.dirfd(),
+                staging.name.as_ptr(),
+                0,
+            )
+        };
+        if res < 0 {
+            return false;
+        }
+
+        // The source may carry the owner and permissions of the file, which must be kept
+        let copied = match (src.open_meta(), staging.open(libc::O_WRONLY, 0)) {
+            (Ok(src_file), Ok(staging_file)) => unsafe {
+                libc::fcopyfile(
+                    src_file.as_raw_fd(),
+                    staging_file.as_raw_fd(),
+                    null_mut(),
+                    libc::COPYFILE_XATTR,
+                ) == 0
+            },
+            _ => false,
+        };
+        if !copied {
+            let _ = staging.unlink(0);
+            return false;
+        }
+
+        true
+    }
+
+    /// Removes every extended attribute of the file at `path`
+    fn remove_all_xattrs(path: &CStr) -> io::Result<()> {
+        let size = unsafe { libc::listxattr(path.as_ptr(), null_mut(), 0, libc::XATTR_NOFOLLOW) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0u8; size as usize];
+        let size = unsafe {
+            libc::listxattr(
+                path.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.len(),
+                libc::XATTR_NOFOLLOW,
+            )
+        };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        buf.truncate(size as usize);
+
+        for name in buf.split(|&b| b == 0).filter(|name| !name.is_empty()) {
+            let name =
+                CString::new(name).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+            if unsafe { libc::removexattr(path.as_ptr(), name.as_ptr(), libc::XATTR_NOFOLLOW) } < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
         }
 
         Ok(())
     }
 
-    /// Helper method to copy file contents when clonefile is not available or fails
+    /// Returns the copy-up marker of the source with the given stat: its size, modification time,
+    /// device and inode number. A staging file is only resumed if its marker is still the one of
+    /// its source.
+    fn copy_up_marker(st: &bindings::stat64) -> String {
+        format!(
+            "{}:{}:{}:{}:{}",
+            st.st_size, st.st_mtime, st.st_mtime_nsec, st.st_dev, st.st_ino
+        )
+    }
+
+    /// Reads the copy-up marker of a staging file, if it has one
+    fn get_copy_up_marker(file: &File) -> io::Result<Option<Vec<u8>>> {
+        let mut buf = vec![0u8; 128];
+        let res = unsafe {
+            libc::fgetxattr(
+                file.as_raw_fd(),
+                COPY_UP_XATTR_KEY.as_ptr() as *const libc::c_char,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                0,
+                0,
+            )
+        };
+
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::ENOATTR) | Some(libc::ENOTSUP) | Some(libc::ERANGE) => Ok(None),
+                _ => Err(err),
+            };
+        }
+
+        buf.truncate(res as usize);
+        Ok(Some(buf))
+    }
+
+    /// Helper method to copy file contents when clonefile is not available or fails.
+    ///
+    /// With `marker`, the destination is created and the marker recorded on it before any data is
+    /// copied. Otherwise the destination must exist, and the copy resumes at `offset`. Only the
+    /// extents of the source holding data are copied, so that the holes of a sparse file stay holes
+    /// in the top layer. They are reserved first, so that the copy fails with `ENOSPC` before
+    /// writing anything if the top layer doesn't have room for them.
     fn copy_file_contents(
         &self,
-        src_path: &CString,
-        dst_path: &CString,
-        mode: u32,
+        src: &EntryAt,
+        dst: &EntryAt,
+        marker: Option<&str>,
+        offset: u64,
+        size: u64,
     ) -> io::Result<()> {
-        unsafe {
-            let src_file = libc::open(src_path.as_ptr(), libc::O_RDONLY);
-            if src_file < 0 {
-                return Err(io::Error::last_os_error());
-            }
+        let src_file = src.open(libc::O_RDONLY, 0)?;
+        let flags = match marker {
+            Some(_) => libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL,
+            None => libc::O_WRONLY,
+        };
+        let dst_file = dst.open(flags, 0o600)?;
 
-            let dst_file = libc::open(
-                dst_path.as_ptr(),
-                libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL,
-                mode,
-            );
-            if dst_file < 0 {
-                libc::close(src_file);
-                return Err(io::Error::last_os_error());
+        unsafe {
+            // Recording the source is best effort, without it an interrupted copy-up is restarted
+            // from scratch rather than resumed.
+            if let Some(marker) = marker {
+                libc::fsetxattr(
+                    dst_file.as_raw_fd(),
+                    COPY_UP_XATTR_KEY.as_ptr() as *const libc::c_char,
+                    marker.as_ptr() as *const libc::c_void,
+                    marker.len(),
+                    0,
+                    0,
+                );
             }
 
-            // Copy file contents
-            let mut buf = [0u8; 8192];
-            loop {
-                let n_read = libc::read(src_file, buf.as_mut_ptr() as *mut _, buf.len());
-                if n_read <= 0 {
-                    break;
-                }
-                let mut pos = 0;
-                while pos < n_read {
-                    let n_written = libc::write(
-                        dst_file,
-                        buf.as_ptr().add(pos as usize) as *const _,
-                        (n_read - pos) as usize,
-                    );
-                    if n_written <= 0 {
-                        libc::close(src_file);
-                        libc::close(dst_file);
-                        return Err(io::Error::last_os_error());
+            let extents = data_extents(&src_file, offset, size)?;
+            prealloc::reserve(dst_file.as_raw_fd(), &extents)?;
+
+            let mut buf = vec![0u8; COPY_CHUNK_SIZE];
+            for (start, len) in extents {
+                let end = start + len;
+                let mut pos = start;
+                while pos < end {
+                    let want = buf.len().min((end - pos) as usize);
+                    let n_read = src_file.read_at(&mut buf[..want], pos)?;
+                    if n_read == 0 {
+                        break;
                     }
-                    pos += n_written;
+                    dst_file.write_all_at(&buf[..n_read], pos)?;
+                    pos += n_read as u64;
                 }
             }
 
-            // Explicitly set permissions to match source file
-            // This will override any effects from the umask
-            if libc::fchmod(dst_file, mode as libc::mode_t) < 0 {
-                libc::close(src_file);
-                libc::close(dst_file);
+            // The hole the source may end with
+            dst_file.set_len(size)?;
+
+            if libc::fsync(dst_file.as_raw_fd()) < 0 {
                 return Err(io::Error::last_os_error());
             }
-
-            libc::close(src_file);
-            libc::close(dst_file);
         }
 
         Ok(())
@@ -1377,10 +3617,14 @@ impl OverlayFs {
         }
 
         // Build the path segments
-        let path_segments = inode_data.path.clone();
+        let path_segments = inode_data.path.names();
 
         // Lookup the file to get all path inodes
-        let (_, _, path_inodes) = self.lookup_layer_by_layer(top_layer_idx, &path_segments)?;
+        let (_, _, path_inodes) = self.lookup_layer_by_layer(
+            top_layer_idx,
+            &path_segments,
+            &mut WhiteoutProbes::default(),
+        )?;
 
         // Copy up the file
         self.copy_up(&path_inodes)?;
@@ -1402,195 +3646,242 @@ impl OverlayFs {
     fn create_whiteout_for_lower(&self, parent: Inode, name: &CStr) -> io::Result<()> {
         if let Ok((_, mut path_inodes)) = self.do_lookup(parent, name) {
             // Copy up the parent directory if needed
-            path_inodes.pop();
+            let hidden = path_inodes.pop();
             self.copy_up(&path_inodes)?;
             let parent_data = self.get_inode_data(parent)?;
-
-            // Create the whiteout file
-            let whiteout_path =
-                self.dev_ino_and_name_to_vol_whiteout_path(parent_data.dev, parent_data.ino, name)?;
-
-            let fd = unsafe {
-                libc::open(
-                    whiteout_path.as_ptr(),
-                    libc::O_CREAT | libc::O_WRONLY | libc::O_EXCL,
-                    0o000, // Whiteout files have no permissions
-                )
-            };
-
-            if fd < 0 {
-                return Err(io::Error::last_os_error());
+            parent_data
+                .whiteouts
+                .store(WHITEOUTS_PRESENT, Ordering::Release);
+            self.whiteout_cache.clear();
+
+            // Create the whiteout file, which has no permissions
+            let whiteout = self.child_at(&parent_data, name)?.whiteout()?;
+            match whiteout.open(libc::O_CREAT | libc::O_WRONLY | libc::O_EXCL, 0o000) {
+                Ok(_) => {
+                    if let Some(hidden) = hidden {
+                        self.layer_stats.whiteout(hidden.layer_idx);
+                    }
+                }
+                Err(err) => {
+                    // The name is already whited out, which is all we were asked for
+                    if err.raw_os_error() != Some(libc::EEXIST) {
+                        return Err(err);
+                    }
+                    let st = whiteout.stat()?;
+                    if st.st_mode & libc::S_IFMT != libc::S_IFREG {
+                        return Err(err);
+                    }
+                }
             }
-
-            unsafe { libc::close(fd) };
         }
 
         Ok(())
     }
 
-    /// Returns an iterator over all valid entries in the directory across all layers.
-    ///
-    /// Note: OverlayFs is a high-level, layered filesystem. A simple readdir on a single directory does not produce the complete view.
-    /// This function traverses the directory across multiple layers, merging entries while handling duplicates,
-    /// whiteout files, and opaque markers.
-    ///
-    /// ## Arguments
-    /// * `dir` - The inode of the directory to iterate over.
-    /// * `add_entry` - A callback function that processes each directory entry. If the callback returns 0,
-    ///                it signals that the directory buffer is full and iteration should stop.
-    ///
-    /// ## Returns
-    /// * `Ok(())` if the directory was iterated successfully.
-    /// * `Err(io::Error)` if an error occurred during iteration.
-    pub(super) fn process_dir_entries<F>(&self, dir: Inode, mut add_entry: F) -> io::Result<()>
-    where
-        F: FnMut(DirEntry) -> io::Result<usize>,
-    {
-        // Local state to track iteration over layers
-        struct LazyReaddirState {
-            current_layer: isize, // current layer (top-down)
-            inode_data: Option<Arc<InodeData>>,
-            current_iter: Option<std::fs::ReadDir>,
-            seen: HashSet<Vec<u8>>,
+    /// Removes the whiteout of `name` from the top layer directory `parent_data`, once an entry
+    /// was created or moved there under that name. A whiteout hides the entry next to it, not only
+    /// the ones of the lower layers. A directory replacing a whited out entry is made opaque first,
+    /// so that the entries of a lower directory of the same name stay hidden.
+    fn remove_whiteout(&self, parent_data: &InodeData, name: &CStr) -> io::Result<()> {
+        let entry = self.child_at(parent_data, name)?;
+        let whiteout = entry.whiteout()?;
+        match whiteout.stat() {
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
         }
 
-        let inode_data = self.get_inode_data(dir)?;
-        let top_layer = self.get_top_layer_idx() as isize;
-        let path = inode_data.path.clone();
-        let mut state = LazyReaddirState {
-            current_layer: top_layer,
-            inode_data: None,
-            current_iter: None,
-            seen: HashSet::new(),
-        };
+        let st = entry.stat()?;
+        if st.st_mode & libc::S_IFMT == libc::S_IFDIR {
+            let key = InodeAltKey::new(st.st_ino, st.st_dev as i32);
+            if let Some(data) = self.inodes.read().unwrap().get_alt(&key) {
+                data.whiteouts.store(WHITEOUTS_PRESENT, Ordering::Release);
+            }
 
-        let mut current_offset = 0u64;
-        let mut opaque_marker_found = false;
-        loop {
-            // If no current iterator, attempt to initialize one for the current layer
-            if state.current_iter.is_none() {
-                if state.current_layer < 0 {
-                    break; // All layers exhausted
-                }
+            let opaque_cname = unsafe { CStr::from_bytes_with_nul_unchecked(OPAQUE_MARKER_CSTR) };
+            let opaque = EntryAt {
+                dir: Some(Arc::new(Self::open_dir_at(entry.dirfd(), &entry.name)?)),
+                name: opaque_cname.into(),
+            };
+            opaque.open(libc::O_CREAT | libc::O_WRONLY | libc::O_NOFOLLOW, 0o000)?;
+            self.whiteout_cache.clear();
+        }
+
+        let res = whiteout.unlink(0);
+        self.whiteout_cache.clear();
+        match res {
+            Err(e) if e.raw_os_error() != Some(libc::ENOENT) => Err(e),
+            _ => Ok(()),
+        }
+    }
 
-                let layer_root = self.get_layer_root(state.current_layer as usize)?;
-                let mut path_inodes = vec![layer_root.clone()];
+    /// Takes the lock of the entry `name` of `parent`, which every request creating, removing or
+    /// renaming the entry holds while it changes the layers.
+    fn lock_entry(&self, parent: Inode, name: &CStr) -> PathLockGuard<'_, (Inode, Vec<u8>)> {
+        self.entry_locks.lock((parent, name.to_bytes().to_vec()))
+    }
 
-                match self.lookup_segment_by_segment(&layer_root, &path, &mut path_inodes) {
-                    Some(Ok(_)) => {
-                        let last_inode = path_inodes.last().unwrap();
-                        let vol_path = self.inode_number_to_vol_path((**last_inode).inode)?;
-                        let dir_str = vol_path.as_c_str().to_str().map_err(|_| {
-                            io::Error::new(io::ErrorKind::Other, "Invalid path string")
-                        })?;
+    /// Starts a merged listing of the directory `dir`, finding it in each layer that isn't hidden
+    /// from it by a whiteout or an opaque directory above.
+    fn open_dir_stream(&self, dir: Inode) -> io::Result<DirStream> {
+        let inode_data = self.get_inode_data(dir)?;
+        let path = inode_data.path.names();
 
-                        state.inode_data = Some(last_inode.clone());
-                        state.current_iter = Some(std::fs::read_dir(dir_str)?);
-                    }
-                    Some(Err(e)) if e.kind() == io::ErrorKind::NotFound => {
-                        state.current_layer -= 1;
-                        continue;
-                    }
-                    Some(Err(e)) => return Err(e),
-                    None => {
-                        state.current_layer = -1;
-                        continue;
+        let mut layers = Vec::new();
+        let mut probes = WhiteoutProbes::default();
+        for layer_idx in (0..=self.get_top_layer_idx()).rev() {
+            let layer_root = self.get_layer_root(layer_idx)?;
+            let mut path_inodes = vec![layer_root.clone()];
+            match self.lookup_segment_by_segment(&layer_root, &path, &mut path_inodes, &mut probes)
+            {
+                Some(Ok(_)) => {
+                    let dir_data = path_inodes.pop().unwrap();
+                    let dir_fd = self.dir_fd(layer_idx, &dir_data.path)?;
+                    let opaque = self.check_opaque_marker(dir_fd.as_raw_fd())?;
+                    layers.push((layer_idx, dir_data));
+                    if opaque {
+                        break;
                     }
                 }
+                Some(Err(e)) if e.kind() == io::ErrorKind::NotFound => continue,
+                Some(Err(e)) => return Err(e),
+                None => break,
             }
+        }
+        layers.reverse();
 
-            if let Some(iter) = state.current_iter.as_mut() {
-                if let Some(entry_result) = iter.next() {
-                    let entry = entry_result?;
-                    let name = entry.file_name();
-                    let name_str = name.to_string_lossy();
+        Ok(DirStream {
+            path,
+            offset: 0,
+            layers,
+            current: None,
+            seen: HashSet::new(),
+            whiteouts: HashSet::new(),
+            pending: None,
+        })
+    }
 
-                    if state.seen.contains(name.as_bytes()) {
-                        continue;
-                    }
+    /// Returns the next entry of the merged listing of `stream`, leaving out the whiteouts and the
+    /// entries hidden by them or by the layers above.
+    fn next_dir_stream_entry(&self, stream: &mut DirStream) -> io::Result<Option<StreamEntry>> {
+        loop {
+            if stream.current.is_none() {
+                let Some((layer_idx, dir_data)) = stream.layers.pop() else {
+                    return Ok(None);
+                };
+                let iter = HostDir::open(self.dir_fd(layer_idx, &dir_data.path)?.as_raw_fd())?;
+                stream.current = Some((layer_idx, dir_data, iter));
+            }
 
-                    // Handle opaque marker and whiteout files
-                    if name_str == OPAQUE_MARKER {
-                        // Opaque marker found; mark it and skip this entry
-                        opaque_marker_found = true;
-                        continue;
-                    } else if name_str.starts_with(WHITEOUT_PREFIX) {
-                        // Whiteout file; skip it
-                        let actual = &name_str[WHITEOUT_PREFIX.len()..];
-                        state.seen.insert(actual.as_bytes().to_vec());
-                        continue;
-                    } else {
-                        state.seen.insert(name.as_bytes().to_vec());
-                    }
+            let (layer_idx, _, iter) = stream.current.as_mut().unwrap();
+            let layer_idx = *layer_idx;
+            let Some(entry) = iter.next() else {
+                stream.current = None;
+                let whiteouts = std::mem::take(&mut stream.whiteouts);
+                stream.seen.extend(whiteouts);
+                continue;
+            };
+            let HostDirEntry { ino, type_, name } = entry?;
+            // Nothing is below the last layer for its entries to hide
+            let last = stream.layers.is_empty();
 
-                    let metadata = entry.metadata()?;
-                    let mode = metadata.mode() as u32;
-                    let s_ifmt = libc::S_IFMT as u32;
-                    let type_ = if mode & s_ifmt == (libc::S_IFDIR as u32) {
-                        libc::DT_DIR
-                    } else if mode & s_ifmt == (libc::S_IFREG as u32) {
-                        libc::DT_REG
-                    } else if mode & s_ifmt == (libc::S_IFLNK as u32) {
-                        libc::DT_LNK
-                    } else if mode & s_ifmt == (libc::S_IFIFO as u32) {
-                        libc::DT_FIFO
-                    } else if mode & s_ifmt == (libc::S_IFCHR as u32) {
-                        libc::DT_CHR
-                    } else if mode & s_ifmt == (libc::S_IFBLK as u32) {
-                        libc::DT_BLK
-                    } else if mode & s_ifmt == (libc::S_IFSOCK as u32) {
-                        libc::DT_SOCK
-                    } else {
-                        libc::DT_UNKNOWN
+            if name == OPAQUE_MARKER.as_bytes() || stream.seen.contains(&name) {
+                continue;
+            }
+            if let Some(actual) = name.strip_prefix(WHITEOUT_PREFIX.as_bytes()) {
+                // Hides the entry of the layers below unless it is stale
+                if !last {
+                    let stale = self.config.verify_whiteouts && {
+                        let mut actual_path = self.relative_path(&stream.path);
+                        if !actual_path.is_empty() {
+                            actual_path.push(b'/');
+                        }
+                        actual_path.extend_from_slice(actual);
+                        self.is_stale_whiteout(layer_idx, &actual_path)
                     };
+                    if !stale {
+                        stream.whiteouts.insert(actual.to_vec());
+                    }
+                }
+                continue;
+            }
+            if !last {
+                stream.seen.insert(name.clone());
+            }
 
-                    current_offset += 1;
+            return Ok(Some(StreamEntry {
+                ino,
+                type_: type_ as u32,
+                name,
+            }));
+        }
+    }
 
-                    let dir_entry = DirEntry {
-                        ino: metadata.ino(),
-                        offset: current_offset,
-                        type_: type_ as u32,
-                        name: name.as_bytes(),
-                    };
+    /// Lists the entries of `stream` from its offset, calling `add_entry` for each until it returns
+    /// 0, in which case the entry it was given is kept for the next call.
+    fn read_dir_stream<F>(&self, stream: &mut DirStream, mut add_entry: F) -> io::Result<()>
+    where
+        F: FnMut(DirEntry) -> io::Result<usize>,
+    {
+        loop {
+            let entry = match stream.pending.take() {
+                Some(entry) => entry,
+                None => match self.next_dir_stream_entry(stream)? {
+                    Some(entry) => entry,
+                    None => return Ok(()),
+                },
+            };
 
-                    if add_entry(dir_entry)? == 0 {
-                        return Ok(());
-                    }
-                } else {
-                    state.current_iter = None;
-                    if opaque_marker_found {
-                        break;
-                    }
-                    state.current_layer -= 1;
-                    continue;
-                }
+            let dir_entry = DirEntry {
+                ino: entry.ino,
+                offset: stream.offset + 1,
+                type_: entry.type_,
+                name: &entry.name,
+            };
+            if add_entry(dir_entry)? == 0 {
+                stream.pending = Some(entry);
+                return Ok(());
             }
+            stream.offset += 1;
         }
+    }
 
-        Ok(())
+    /// Calls `add_entry` for each entry of the directory `dir`, merged across all layers, until it
+    /// returns 0.
+    ///
+    /// Note: OverlayFs is a high-level, layered filesystem. A simple readdir on a single directory does not produce the complete view.
+    /// This function traverses the directory across multiple layers, merging entries while handling duplicates,
+    /// whiteout files, and opaque markers.
+    pub(super) fn process_dir_entries<F>(&self, dir: Inode, add_entry: F) -> io::Result<()>
+    where
+        F: FnMut(DirEntry) -> io::Result<usize>,
+    {
+        let mut stream = self.open_dir_stream(dir)?;
+        self.read_dir_stream(&mut stream, add_entry)
     }
 
     /// Reads directory entries for the given inode by merging entries from all underlying layers.
     ///
     /// Unlike conventional filesystems that simply call readdir on a directory file descriptor,
-    /// OverlayFs must aggregate entries from multiple layers. The `offset` parameter specifies the starting
-    /// index in the merged list of directory entries. The provided `add_entry` callback is invoked for each
-    /// entry; a return value of 0 indicates that the directory buffer is full and reading should cease.
-    ///
-    /// NOTE: The current implementation of offset does not entirely follow FUSE expected behaviors.
-    /// Changes to entries in the write layer can affect the offset, potentially causing inconsistencies
-    /// in directory listing between calls.
-    ///
-    /// TODO: Implement a more robust offset handling mechanism that maintains consistency even when
-    /// the underlying directory structure changes. One way is making offset a composite value of
-    /// layer (1 MSB) + offset (7 LSB). This will also require having multiple open dirs from lower layers
-    /// in [HandleData].
+    /// OverlayFs must aggregate entries from multiple layers. The `offset` parameter is the index
+    /// of the first entry to list in the merged listing. The provided `add_entry` callback is
+    /// invoked for each entry; a return value of 0 indicates that the directory buffer is full and
+    /// reading should cease.
+    ///
+    /// The listing is kept by the handle, so that a readdir continuing at the offset where the
+    /// previous one stopped resumes it, with the layers it was reading still open, rather than
+    /// reading the directory again up to the offset. The entries are listed as they are read from
+    /// the layers, the names of a directory found in a single layer not being kept at all, and
+    /// only the ones of the upper layers otherwise, to leave out the entries of the lower layers
+    /// they hide. A readdir at any other offset, such as after a `rewinddir`, starts the listing
+    /// again.
     pub(super) fn do_readdir<F>(
         &self,
         inode: Inode,
+        handle: Handle,
         size: u32,
         offset: u64,
-        mut add_entry: F,
+        add_entry: F,
     ) -> io::Result<()>
     where
         F: FnMut(DirEntry) -> io::Result<usize>,
@@ -1599,15 +3890,139 @@ impl OverlayFs {
             return Ok(());
         }
 
-        let mut current_offset = 0u64;
-        self.process_dir_entries(inode, |entry| {
-            if current_offset < offset {
-                current_offset += 1;
-                return Ok(1);
+        let data = self.get_inode_handle_data(inode, handle)?;
+        let mut cached = data.dir_stream.lock().unwrap();
+        let mut stream = match cached.take() {
+            Some(stream) if stream.offset == offset => stream,
+            _ => {
+                let mut stream = self.open_dir_stream(inode)?;
+                let mut skipped = 0;
+                self.read_dir_stream(&mut stream, |_| {
+                    skipped += 1;
+                    Ok(if skipped <= offset { 1 } else { 0 })
+                })?;
+                stream
             }
+        };
 
-            add_entry(entry)
-        })
+        // A listing that failed midway is started again by the next readdir
+        self.read_dir_stream(&mut stream, add_entry)?;
+        *cached = Some(stream);
+        Ok(())
+    }
+
+    /// Reads the target of the symlink `inode_data`, with the prefixes of `symlink_target_map`
+    /// rewritten for the links of the lower layers.
+    fn read_symlink(&self, inode_data: &InodeData) -> io::Result<Vec<u8>> {
+        let mut buf = self.entry_at(inode_data)?.read_link()?;
+
+        // Links copied up to the top layer were remapped already, and the guest created the others
+        if inode_data.layer_idx < self.get_top_layer_idx() {
+            if let Some(remapped) = remap_symlink_target(&self.config.symlink_target_map, &buf) {
+                buf = remapped;
+            }
+        }
+
+        Ok(buf)
+    }
+
+    /// Resolves the symlink `target` of a link in the directory `dir` of the overlay, following
+    /// the links it goes through but not its last component, as the guest would. Returns `None` if
+    /// the target is relative and stays within the overlay, otherwise returns an equivalent
+    /// relative target clamped at the root of the overlay. Fails with `ELOOP` if it takes more than
+    /// `max_symlink_depth` links.
+    ///
+    /// The links followed are confined on their own when the guest reads them, so only the
+    /// components of `target` itself can lead outside of the overlay.
+    fn confine_symlink_target(
+        &self,
+        mut dir: Vec<Name>,
+        target: &[u8],
+    ) -> io::Result<Option<Vec<u8>>> {
+        let depth = dir.len();
+        let mut escaped = target.starts_with(b"/");
+        if escaped {
+            dir.clear();
+        }
+
+        // Components left to resolve, along with whether they come from `target`
+        let mut pending: VecDeque<(Vec<u8>, bool)> = target
+            .split(|b| *b == b'/')
+            .filter(|c| !c.is_empty() && *c != b".")
+            .map(|c| (c.to_vec(), true))
+            .collect();
+        let mut followed = 0;
+
+        while let Some((component, own)) = pending.pop_front() {
+            if component == b".." {
+                if dir.pop().is_none() && own {
+                    escaped = true;
+                }
+                continue;
+            }
+
+            dir.push(self.intern_name(&CString::new(component).map_err(|_| einval())?));
+            if pending.is_empty() {
+                break;
+            }
+
+            // Components that can't be looked up are left for the guest to fail on
+            let Ok((entry, data, path_inodes)) = self.lookup_layer_by_layer(
+                self.get_top_layer_idx(),
+                &dir,
+                &mut WhiteoutProbes::default(),
+            ) else {
+                continue;
+            };
+            let link_target = (entry.attr.st_mode & libc::S_IFMT == libc::S_IFLNK)
+                .then(|| self.read_symlink(&data));
+            self.drop_unreferenced(&path_inodes);
+            let Some(link_target) = link_target.transpose()? else {
+                continue;
+            };
+
+            followed += 1;
+            if followed > self.config.max_symlink_depth {
+                return Err(linux_error(io::Error::from_raw_os_error(libc::ELOOP)));
+            }
+
+            dir.pop();
+            if link_target.starts_with(b"/") {
+                dir.clear();
+            }
+            for c in link_target.split(|b| *b == b'/').rev() {
+                if !c.is_empty() && c != b"." {
+                    pending.push_front((c.to_vec(), false));
+                }
+            }
+        }
+
+        if !escaped {
+            return Ok(None);
+        }
+
+        let mut confined = b"../".repeat(depth);
+        let names: Vec<&[u8]> = dir.iter().map(|name| name.to_bytes()).collect();
+        confined.extend_from_slice(&names.join(&b'/'));
+        if confined.is_empty() {
+            confined.push(b'.');
+        } else if confined.ends_with(b"/") {
+            confined.pop();
+        }
+
+        Ok(Some(confined))
+    }
+
+    /// Drops the inodes of `path_inodes` the guest holds no reference to from the inode map, for
+    /// the paths an operation looks up on its own, whose inodes the guest never forgets. The first
+    /// one, a layer root, is kept.
+    fn drop_unreferenced(&self, path_inodes: &[Arc<InodeData>]) {
+        let mut inodes = self.inodes.write().unwrap();
+        for data in path_inodes.iter().skip(1) {
+            if data.refcount.load(Ordering::Acquire) == 1 {
+                inodes.remove(&data.inode);
+            }
+        }
     }
 
     /// Performs an open operation
@@ -1618,15 +4033,48 @@ impl OverlayFs {
         // Get the inode data
         let inode_data = self.get_inode_data(inode)?;
 
-        // Ensure the file is in the top layer
-        let inode_data = self.ensure_top_layer(inode_data)?;
+        // A file truncated as it is copied up must not keep its content on a crash
+        let _intent_guard =
+            if flags & libc::O_TRUNC != 0 && inode_data.layer_idx != self.get_top_layer_idx() {
+                self.begin_intent(&inode_data.path.names(), |path| Intent::Truncate(path, 0))?
+            } else {
+                None
+            };
+
+        // Only the files opened for writing are copied up to the top layer. The others are read
+        // from the layer they are in.
+        let read_only = flags & libc::O_ACCMODE == libc::O_RDONLY && flags & libc::O_TRUNC == 0;
+        if !read_only {
+            self.check_writable()?;
+        }
+        let inode_data = if read_only {
+            #[cfg(feature = "oci")]
+            if inode_data.layer_idx != self.get_top_layer_idx() {
+                self.materialize(&inode_data)?;
+            }
+            inode_data
+        } else {
+            self.ensure_top_layer(inode_data)?
+        };
 
         // Open the file with the appropriate flags and generate a new unique handle ID
+        let handle_grant = self.acquire_handle()?;
+        let fd_grant = self.acquire_fd()?;
         let file = RwLock::new(self.open_inode(inode_data.inode, flags)?);
         let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
 
         // Create handle data structure with file and empty dirstream
-        let data = HandleData { inode, file };
+        let data = HandleData {
+            inode,
+            file,
+            dirty: Default::default(),
+            write_error: Default::default(),
+            sequential: Default::default(),
+            reads: Default::default(),
+            _fd_grant: fd_grant,
+            _handle_grant: handle_grant,
+            dir_stream: Default::default(),
+        };
 
         // Store the handle data in the handles map
         self.handles.write().unwrap().insert(handle, Arc::new(data));
@@ -1660,12 +4108,25 @@ impl OverlayFs {
     fn do_release(&self, inode: Inode, handle: Handle) -> io::Result<()> {
         let mut handles = self.handles.write().unwrap();
 
+        // The space preallocated beyond the end of the file is only trimmed once no other handle
+        // may be writing there
+        let shared = handles.iter().any(|(h, data)| *h != handle && data.inode == inode);
+
         if let btree_map::Entry::Occupied(e) = handles.entry(handle) {
             if e.get().inode == inode {
+                // Report any delayed write error that was not picked up by a flush.
+                let write_error = e.get().take_write_error();
+                if !shared {
+                    e.get().trim_preallocation();
+                }
+
                 // We don't need to close the file here because that will happen automatically when
                 // the last `Arc` is dropped.
                 e.remove();
-                return Ok(());
+                return match write_error {
+                    Some(err) => Err(linux_error(err)),
+                    None => Ok(()),
+                };
             }
         }
 
@@ -1674,8 +4135,18 @@ impl OverlayFs {
 
     /// Performs a getattr operation
     fn do_getattr(&self, inode: Inode) -> io::Result<(bindings::stat64, Duration)> {
-        let c_path = self.inode_number_to_vol_path(inode)?;
-        let st = Self::patched_stat(&FileId::Path(c_path))?;
+        let inode_data = self.get_inode_data(inode)?;
+        let stat = || self.entry_at(&inode_data)?.patched_stat();
+        let mut st = match &self.config.lower_layers {
+            Some(set) if inode_data.layer_idx < self.get_top_layer_idx() => {
+                set.attr(inode_data.dev as u64, inode_data.ino, stat)?
+            }
+            _ => stat()?,
+        };
+        self.patch_dir_overrides(inode, &mut st);
+        self.patch_dir_nlink(inode, &mut st);
+        self.patch_dev(&mut st);
+        self.patch_blocks(inode, &mut st);
 
         Ok((st, self.config.attr_timeout))
     }
@@ -1691,21 +4162,68 @@ impl OverlayFs {
         // Get the inode data
         let inode_data = self.get_inode_data(inode)?;
 
+        // Changing only the owner or permissions of a lower layer directory doesn't need the
+        // directory, and all of its parents, to be copied up: record the change on the side
+        let metadata_only =
+            SetattrValid::UID | SetattrValid::GID | SetattrValid::MODE | SetattrValid::CTIME;
+        if inode_data.layer_idx != self.get_top_layer_idx() && (valid - metadata_only).is_empty() {
+            let (current_stat, _) = self.do_getattr(inode)?;
+            if current_stat.st_mode & libc::S_IFMT == libc::S_IFDIR {
+                let uid = if valid.contains(SetattrValid::UID) {
+                    attr.st_uid
+                } else {
+                    current_stat.st_uid
+                };
+                let gid = if valid.contains(SetattrValid::GID) {
+                    attr.st_gid
+                } else {
+                    current_stat.st_gid
+                };
+                let mode = if valid.contains(SetattrValid::MODE) {
+                    attr.st_mode
+                } else {
+                    current_stat.st_mode
+                };
+
+                let path = self.relative_path(&inode_data.path.names());
+                let value = (uid, gid, mode & 0o7777);
+                let mut overrides = self.dir_overrides.lock().unwrap();
+                overrides.insert(path.clone(), value);
+                self.append_dir_overrides(&[(path, Some(value))])?;
+                drop(overrides);
+
+                return self.do_getattr(inode);
+            }
+        }
+
+        // A file truncated as it is copied up must not keep its content on a crash
+        let _intent_guard = if valid.contains(SetattrValid::SIZE)
+            && inode_data.layer_idx != self.get_top_layer_idx()
+        {
+            let size = attr.st_size as u64;
+            self.begin_intent(&inode_data.path.names(), |path| {
+                Intent::Truncate(path, size)
+            })?
+        } else {
+            None
+        };
+
         // Ensure the file is in the top layer before modifying attributes
         let inode_data = self.ensure_top_layer(inode_data)?;
 
-        // Get the file identifier - either from handle or path
-        let file_id = if let Some(handle) = handle {
+        // Get the file identifier - either from handle or from the file opened for its metadata
+        let meta;
+        let fd = if let Some(handle) = handle {
             // Get the handle data
             let handles = self.handles.read().unwrap();
             let handle_data = handles.get(&handle).ok_or_else(ebadf)?;
             let file = handle_data.file.read().unwrap();
-            FileId::Fd(file.as_raw_fd())
+            file.as_raw_fd()
         } else {
-            // Use path if no handle available
-            let c_path = self.dev_ino_to_vol_path(inode_data.dev, inode_data.ino)?;
-            FileId::Path(c_path)
+            meta = self.entry_at(&inode_data)?.open_meta()?;
+            meta.as_raw_fd()
         };
+        let file_id = FileId::Fd(fd);
 
         // Consolidate attribute changes using a single setattrlist call
         let current_stat = Self::patched_stat(&file_id)?;
@@ -1739,13 +4257,14 @@ impl OverlayFs {
             Self::set_owner_perms_attr(&file_id, &current_stat, None, Some(mode))?;
         }
 
-        // Handle size changes
+        // Handle size changes, through a file opened for writing if there is no handle
         if valid.contains(SetattrValid::SIZE) {
-            let res = match file_id {
-                FileId::Fd(fd) => unsafe { libc::ftruncate(fd, attr.st_size) },
-                FileId::Path(ref c_path) => unsafe {
-                    libc::truncate(c_path.as_ptr(), attr.st_size)
-                },
+            let res = match handle {
+                Some(_) => unsafe { libc::ftruncate(fd, attr.st_size) },
+                None => {
+                    let file = self.entry_at(&inode_data)?.open(libc::O_WRONLY, 0)?;
+                    unsafe { libc::ftruncate(file.as_raw_fd(), attr.st_size) }
+                }
             };
 
             if res < 0 {
@@ -1781,15 +4300,7 @@ impl OverlayFs {
             }
 
             // Safe because this doesn't modify any memory and we check the return value
-            let res = match file_id {
-                FileId::Fd(fd) => unsafe { libc::futimens(fd, tvs.as_ptr()) },
-                FileId::Path(ref c_path) => unsafe {
-                    let fd = libc::open(c_path.as_ptr(), libc::O_SYMLINK | libc::O_CLOEXEC);
-                    let res = libc::futimens(fd, tvs.as_ptr());
-                    libc::close(fd);
-                    res
-                },
-            };
+            let res = unsafe { libc::futimens(fd, tvs.as_ptr()) };
 
             if res < 0 {
                 return Err(io::Error::last_os_error());
@@ -1810,6 +4321,8 @@ impl OverlayFs {
         umask: u32,
         extensions: Extensions,
     ) -> io::Result<Entry> {
+        let _guard = self.lock_entry(parent, name);
+
         // Check if an entry with the same name already exists in the parent directory
         match self.do_lookup(parent, name) {
             Ok(_) => {
@@ -1830,38 +4343,44 @@ impl OverlayFs {
         // Ensure parent directory is in the top layer
         let parent_data = self.ensure_top_layer(parent_data)?;
 
-        // Get the path for the new directory
-        let c_path = self.dev_ino_and_name_to_vol_path(parent_data.dev, parent_data.ino, name)?;
+        // Get the entry for the new directory
+        let entry_at = self.child_at(&parent_data, name)?;
 
         // Create the directory with initial permissions
-        let res = unsafe { libc::mkdir(c_path.as_ptr(), 0o700) };
+        let res = unsafe { libc::mkdirat(entry_at.dirfd(), entry_at.name.as_ptr(), 0o700) };
         if res == 0 {
+            self.remove_whiteout(&parent_data, name)?;
+            self.sync_dir(entry_at.dir.as_ref().unwrap())?;
+
+            let file = entry_at.open_meta()?;
+            let file_id = FileId::Fd(file.as_raw_fd());
+
             // Set security context if provided
             if let Some(secctx) = extensions.secctx {
-                Self::set_secctx(&FileId::Path(c_path.clone()), secctx, false)?;
+                Self::set_secctx(&file_id, secctx, false)?;
             }
 
             // Get the initial stat for the directory
-            let stat = Self::unpatched_stat(&FileId::Path(c_path.clone()))?;
+            let stat = Self::unpatched_stat(&file_id)?;
 
             // Set ownership and permissions
             Self::set_owner_perms_attr(
-                &FileId::Path(c_path.clone()),
+                &file_id,
                 &stat,
                 Some((ctx.uid, ctx.gid)),
                 Some((mode & !umask) as u16),
             )?;
 
             // Get the updated stat for the directory
-            let updated_stat = Self::patched_stat(&FileId::Path(c_path))?;
+            let updated_stat = Self::patched_stat(&file_id)?;
 
-            let mut path = parent_data.path.clone();
-            path.push(self.intern_name(name)?);
+            let path = parent_data.path.child(self.intern_name(name));
 
             // Create the inode for the newly created directory
             let (inode, _) = self.create_inode(
                 updated_stat.st_ino,
                 updated_stat.st_dev,
+                birth_time(&updated_stat),
                 path,
                 parent_data.layer_idx,
             );
@@ -1878,50 +4397,77 @@ impl OverlayFs {
 
     /// Performs an unlink operation
     fn do_unlink(&self, parent: Inode, name: &CStr) -> io::Result<()> {
+        let _guard = self.lock_entry(parent, name);
+
+        // Keep the entry from being copied up while it is removed, which would bring it back
+        let path = self
+            .get_inode_data(parent)?
+            .path
+            .child(self.intern_name(name));
+        let _copy_up_guard = self.copy_up_locks.lock(path.names());
         let top_layer_idx = self.get_top_layer_idx();
         let (entry, _) = self.do_lookup(parent, name)?;
 
         // If the inode is in the top layer, we need to unlink it.
         let entry_data = self.get_inode_data(entry.inode)?;
+        let mut _intent_guard = None;
         if entry_data.layer_idx == top_layer_idx {
-            // Get the path for the inode
-            let c_path = self.inode_number_to_vol_path(entry.inode)?;
+            _intent_guard = self.begin_intent(&path.names(), Intent::Whiteout)?;
 
-            // Remove the inode from the overlayfs
-            let res = unsafe { libc::unlink(c_path.as_ptr()) };
-            if res < 0 {
-                return Err(io::Error::last_os_error());
+            // Remove the entry from the top layer
+            let parent_path = self.get_inode_data(parent)?.path.clone();
+            let entry_at = EntryAt {
+                dir: Some(self.dir_fd(top_layer_idx, &parent_path)?),
+                name: name.into(),
+            };
+            entry_at.unlink(0)?;
+
+            if entry.attr.st_nlink <= 1 {
+                self.retire_generation(InodeAltKey::new(entry_data.ino, entry_data.dev));
             }
         }
 
         // If after an unlink, the entry still exists in a lower layer, we need to add a whiteout
         self.create_whiteout_for_lower(parent, name)?;
 
-        Ok(())
+        self.sync_inode_dir(parent)
     }
 
     /// Performs an rmdir operation
     fn do_rmdir(&self, parent: Inode, name: &CStr) -> io::Result<()> {
+        let _guard = self.lock_entry(parent, name);
+
+        // Keep the entry from being copied up while it is removed, which would bring it back
+        let path = self
+            .get_inode_data(parent)?
+            .path
+            .child(self.intern_name(name));
+        let _copy_up_guard = self.copy_up_locks.lock(path.names());
         let top_layer_idx = self.get_top_layer_idx();
         let (entry, _) = self.do_lookup(parent, name)?;
 
         // If the inode is in the top layer, we need to unlink it.
         let entry_data = self.get_inode_data(entry.inode)?;
+        let mut _intent_guard = None;
         if entry_data.layer_idx == top_layer_idx {
-            // Get the path for the inode
-            let c_path = self.inode_number_to_vol_path(entry.inode)?;
+            _intent_guard = self.begin_intent(&path.names(), Intent::Whiteout)?;
 
-            // Remove the inode from the overlayfs
-            let res = unsafe { libc::rmdir(c_path.as_ptr()) };
-            if res < 0 {
-                return Err(io::Error::last_os_error());
-            }
+            // Remove the entry from the top layer
+            let parent_path = self.get_inode_data(parent)?.path.clone();
+            let entry_at = EntryAt {
+                dir: Some(self.dir_fd(top_layer_idx, &parent_path)?),
+                name: name.into(),
+            };
+            entry_at.unlink(libc::AT_REMOVEDIR)?;
+
+            self.retire_generation(InodeAltKey::new(entry_data.ino, entry_data.dev));
         }
 
         // If after an rmdir, the entry still exists in a lower layer, we need to add a whiteout
         self.create_whiteout_for_lower(parent, name)?;
+        self.drop_dir_overrides(&self.relative_path(&path.names()))?;
 
-        Ok(())
+        self.sync_inode_dir(parent)
     }
 
     /// Performs a symlink operation
@@ -1933,6 +4479,8 @@ impl OverlayFs {
         name: &CStr,
         extensions: Extensions,
     ) -> io::Result<Entry> {
+        let _guard = self.lock_entry(parent, name);
+
         // Check if an entry with the same name already exists in the parent directory
         match self.do_lookup(parent, name) {
             Ok(_) => {
@@ -1950,42 +4498,53 @@ impl OverlayFs {
         // Get the parent inode data
         let parent_data = self.get_inode_data(parent)?;
 
+        // Refuse links pointing outside of the overlay if the policy says so
+        if self.config.symlink_policy == SymlinkPolicy::Deny
+            && self
+                .confine_symlink_target(parent_data.path.names(), linkname.to_bytes())?
+                .is_some()
+        {
+            return Err(linux_error(io::Error::from_raw_os_error(libc::EPERM)));
+        }
+
         // Ensure parent directory is in the top layer
         let parent_data = self.ensure_top_layer(parent_data)?;
 
-        // Get the path for the new directory
-        let c_path = self.dev_ino_and_name_to_vol_path(parent_data.dev, parent_data.ino, name)?;
+        // Get the entry for the new link
+        let entry_at = self.child_at(&parent_data, name)?;
 
-        // Create the directory with initial permissions
-        let res = unsafe { libc::symlink(linkname.as_ptr(), c_path.as_ptr()) };
+        // Create the link
+        let res =
+            unsafe { libc::symlinkat(linkname.as_ptr(), entry_at.dirfd(), entry_at.name.as_ptr()) };
         if res == 0 {
+            self.remove_whiteout(&parent_data, name)?;
+            self.sync_dir(entry_at.dir.as_ref().unwrap())?;
+
+            let file = entry_at.open_meta()?;
+            let file_id = FileId::Fd(file.as_raw_fd());
+
             // Set security context if provided
             if let Some(secctx) = extensions.secctx {
-                Self::set_secctx(&FileId::Path(c_path.clone()), secctx, true)?;
+                Self::set_secctx(&file_id, secctx, true)?;
             }
 
-            // Get the initial stat for the directory
-            let stat = Self::unpatched_stat(&FileId::Path(c_path.clone()))?;
+            // Get the initial stat for the link
+            let stat = Self::unpatched_stat(&file_id)?;
 
             // Set ownership and permissions
             let mode = libc::S_IFLNK | 0o777;
-            Self::set_owner_perms_attr(
-                &FileId::Path(c_path.clone()),
-                &stat,
-                Some((ctx.uid, ctx.gid)),
-                Some(mode),
-            )?;
+            Self::set_owner_perms_attr(&file_id, &stat, Some((ctx.uid, ctx.gid)), Some(mode))?;
 
-            // Get the updated stat for the directory
-            let updated_stat = Self::patched_stat(&FileId::Path(c_path))?;
+            // Get the updated stat for the link
+            let updated_stat = Self::patched_stat(&file_id)?;
 
-            let mut path = parent_data.path.clone();
-            path.push(self.intern_name(name)?);
+            let path = parent_data.path.child(self.intern_name(name));
 
             // Create the inode for the newly created directory
             let (inode, _) = self.create_inode(
                 updated_stat.st_ino,
                 updated_stat.st_dev,
+                birth_time(&updated_stat),
                 path,
                 parent_data.layer_idx,
             );
@@ -2008,19 +4567,27 @@ impl OverlayFs {
         new_name: &CStr,
         flags: u32,
     ) -> io::Result<()> {
-        // Copy up the old path to the top layer if not already in the top layer
+        let _guard = self.entry_locks.lock_all(vec![
+            (old_parent, old_name.to_bytes().to_vec()),
+            (new_parent, new_name.to_bytes().to_vec()),
+        ]);
+
+        // Copy up the old path to the top layer if not already in the top layer. A directory takes
+        // its entries from the lower layers along with it.
         let (_, old_path_inodes) = self.do_lookup(old_parent, old_name)?;
-        self.copy_up(&old_path_inodes)?;
+        self.copy_up_tree(&old_path_inodes)?;
         let old_parent_data = self.get_inode_data(old_parent)?;
 
+        // Keep the old entry from being copied up again until it is whited out
+        let old_entry_path = old_parent_data.path.child(self.intern_name(old_name));
+        let _copy_up_guard = self.copy_up_locks.lock(old_entry_path.names());
+
         // Copy up the new parent to the top layer if not already in the top layer
         let new_parent_data = self.ensure_top_layer(self.get_inode_data(new_parent)?)?;
 
-        // Get the paths for rename operation
-        let old_path =
-            self.dev_ino_and_name_to_vol_path(old_parent_data.dev, old_parent_data.ino, old_name)?;
-        let new_path =
-            self.dev_ino_and_name_to_vol_path(new_parent_data.dev, new_parent_data.ino, new_name)?;
+        // Get the entries for rename operation
+        let old_at = self.child_at(&old_parent_data, old_name)?;
+        let new_at = self.child_at(&new_parent_data, new_name)?;
 
         // Set up rename flags
         let mut mflags: u32 = 0;
@@ -2038,50 +4605,85 @@ impl OverlayFs {
             return Err(linux_error(io::Error::from_raw_os_error(libc::EINVAL)));
         }
 
-        // Perform the rename
-        let res = unsafe { libc::renamex_np(old_path.as_ptr(), new_path.as_ptr(), mflags) };
-        if res < 0 {
-            return Err(io::Error::last_os_error());
-        }
+        // A file replaced by the rename may have its inode number reused
+        let replaced = if mflags & libc::RENAME_SWAP == 0 {
+            new_at.stat().ok()
+        } else {
+            None
+        };
 
-        // After successful rename, check if we need to add a whiteout for the old path
-        self.create_whiteout_for_lower(old_parent, old_name)?;
+        // The old entry leaves the top layer before it is whited out
+        let _intent_guard = if mflags & libc::RENAME_SWAP == 0 {
+            self.begin_intent(&old_entry_path.names(), Intent::Whiteout)?
+        } else {
+            None
+        };
 
-        // If LINUX_RENAME_WHITEOUT is set, create a character device at the old path location
-        if ((flags as i32) & bindings::LINUX_RENAME_WHITEOUT) != 0 {
-            let fd = unsafe {
-                libc::open(
-                    old_path.as_ptr(),
-                    libc::O_CREAT | libc::O_CLOEXEC | libc::O_NOFOLLOW,
-                    0o600,
-                )
-            };
+        // Perform the rename
+        old_at.rename_to(&new_at, mflags)?;
 
-            let stat = Self::unpatched_stat(&FileId::Fd(fd))?;
-            Self::set_owner_perms_attr(&FileId::Fd(fd), &stat, None, Some(libc::S_IFCHR | 0o600))?;
+        self.rename_inode_paths(
+            (&old_parent_data.path, self.intern_name(old_name)),
+            (&new_parent_data.path, self.intern_name(new_name)),
+            mflags & libc::RENAME_SWAP != 0,
+        );
 
-            if fd < 0 {
-                return Err(io::Error::last_os_error());
+        if let Some(st) = replaced {
+            let key = InodeAltKey::new(st.st_ino, st.st_dev as i32);
+            let moved = new_at.stat()?;
+            let is_dir = st.st_mode & libc::S_IFMT == libc::S_IFDIR;
+            if key != InodeAltKey::new(moved.st_ino, moved.st_dev as i32)
+                && (is_dir || st.st_nlink <= 1)
+            {
+                self.retire_generation(key);
             }
+        }
+
+        self.remove_whiteout(&new_parent_data, new_name)?;
+
+        // After successful rename, check if we need to add a whiteout for the old path, unless
+        // the entries were exchanged or the entry was renamed to itself
+        if ((flags as i32) & bindings::LINUX_RENAME_WHITEOUT) != 0 || old_at.stat().is_err() {
+            self.create_whiteout_for_lower(old_parent, old_name)?;
+            self.drop_dir_overrides(&self.relative_path(&old_entry_path.names()))?;
+        }
+
+        // The lower directories a replaced directory merged are hidden for good
+        if replaced.is_some_and(|st| st.st_mode & libc::S_IFMT == libc::S_IFDIR) {
+            let new_entry_path = new_parent_data.path.child(self.intern_name(new_name));
+            self.drop_dir_overrides(&self.relative_path(&new_entry_path.names()))?;
+        }
 
-            unsafe { libc::close(fd) };
+        // If LINUX_RENAME_WHITEOUT is set, create a character device at the old path location
+        if ((flags as i32) & bindings::LINUX_RENAME_WHITEOUT) != 0 {
+            let file = old_at.open(libc::O_CREAT | libc::O_NOFOLLOW, 0o600)?;
+            let file_id = FileId::Fd(file.as_raw_fd());
+            let stat = Self::unpatched_stat(&file_id)?;
+            Self::set_owner_perms_attr(&file_id, &stat, None, Some(libc::S_IFCHR | 0o600))?;
+        }
+
+        self.sync_inode_dir(old_parent)?;
+        if new_parent != old_parent {
+            self.sync_inode_dir(new_parent)?;
         }
 
         Ok(())
     }
 
     fn do_link(&self, inode: Inode, new_parent: Inode, new_name: &CStr) -> io::Result<Entry> {
+        let _guard = self.lock_entry(new_parent, new_name);
+
         // Get the inode data for the source file
         let inode_data = self.get_inode_data(inode)?;
 
         // Copy up the source file to the top layer if needed
         let inode_data = self.ensure_top_layer(inode_data)?;
 
-        // Get source and destination paths
-        let src_path = self.dev_ino_to_vol_path(inode_data.dev, inode_data.ino)?;
+        // Get the source entry
+        let src_at = self.entry_at(&inode_data)?;
 
         // Extraneous check to ensure the source file is not a symlink
-        let stat = Self::unpatched_stat(&FileId::Path(src_path.clone()))?;
+        let stat = src_at.stat()?;
         if stat.st_mode & libc::S_IFMT == libc::S_IFLNK {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
@@ -2091,29 +4693,37 @@ impl OverlayFs {
 
         // Get and ensure new parent is in top layer
         let new_parent_data = self.ensure_top_layer(self.get_inode_data(new_parent)?)?;
-
-
-        let dst_path =
-            self.dev_ino_and_name_to_vol_path(new_parent_data.dev, new_parent_data.ino, new_name)?;
+        let dst_at = self.child_at(&new_parent_data, new_name)?;
 
         // Create the hard link
-        let res = unsafe { libc::link(src_path.as_ptr(), dst_path.as_ptr()) };
+        let res = unsafe {
+            libc::linkat(
+                src_at.dirfd(),
+                src_at.name.as_ptr(),
+                dst_at.dirfd(),
+                dst_at.name.as_ptr(),
+                0,
+            )
+        };
 
         if res < 0 {
             return Err(io::Error::last_os_error());
         }
 
+        self.remove_whiteout(&new_parent_data, new_name)?;
+        self.sync_dir(dst_at.dir.as_ref().unwrap())?;
+
         // Get the entry for the newly created link
-        let mut path = new_parent_data.path.clone();
-        path.push(self.intern_name(new_name)?);
+        let path = new_parent_data.path.child(self.intern_name(new_name));
 
         // Get stats for the new link
-        let stat = Self::patched_stat(&FileId::Path(dst_path))?;
+        let stat = dst_at.patched_stat()?;
 
         // Create new inode for the link pointing to same dev/ino as source
         let (inode, _) = self.create_inode(
             stat.st_ino,
             stat.st_dev as i32,
+            birth_time(&stat),
             path,
             new_parent_data.layer_idx,
         );
@@ -2158,26 +4768,22 @@ impl OverlayFs {
 
     fn do_readlink(&self, inode: Inode) -> io::Result<Vec<u8>> {
         // Get the path for this inode
-        let c_path = self.inode_number_to_vol_path(inode)?;
-
-        // Allocate a buffer for the link target
-        let mut buf = vec![0; libc::PATH_MAX as usize];
-
-        // Call readlink to get the symlink target
-        let res = unsafe {
-            libc::readlink(
-                c_path.as_ptr(),
-                buf.as_mut_ptr() as *mut libc::c_char,
-                buf.len(),
-            )
-        };
+        let inode_data = self.get_inode_data(inode)?;
+        let mut buf = self.read_symlink(&inode_data)?;
+
+        // Apply the symlink policy to targets that point outside of the overlay
+        if self.config.symlink_policy != SymlinkPolicy::GuestRelative {
+            let mut dir = inode_data.path.names();
+            dir.pop();
+            if let Some(confined) = self.confine_symlink_target(dir, &buf)? {
+                if self.config.symlink_policy == SymlinkPolicy::Deny {
+                    return Err(linux_error(io::Error::from_raw_os_error(libc::EPERM)));
+                }
 
-        if res < 0 {
-            return Err(io::Error::last_os_error());
+                buf = confined;
+            }
         }
 
-        // Resize the buffer to the actual length of the link target
-        buf.resize(res as usize, 0);
         Ok(buf)
     }
 
@@ -2187,8 +4793,8 @@ impl OverlayFs {
             return Err(linux_error(io::Error::from_raw_os_error(libc::ENOSYS)));
         }
 
-        // Don't allow setting the owner/permissions attribute
-        if name.to_bytes() == OWNER_PERMS_XATTR_KEY {
+        // Don't allow setting the owner/permissions attribute, or the other ones of the overlay
+        if is_internal_xattr(name.to_bytes()) {
             return Err(linux_error(io::Error::from_raw_os_error(libc::EACCES)));
         }
 
@@ -2208,13 +4814,13 @@ impl OverlayFs {
             mflags |= libc::XATTR_REPLACE;
         }
 
-        // Get the path for this inode
-        let c_path = self.inode_number_to_vol_path(inode_data.inode)?;
+        // Open the file for its metadata
+        let file = self.entry_at(&inode_data)?.open_meta()?;
 
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe {
-            libc::setxattr(
-                c_path.as_ptr(),
+            libc::fsetxattr(
+                file.as_raw_fd(),
                 name.as_ptr(),
                 value.as_ptr() as *const libc::c_void,
                 value.len(),
@@ -2241,20 +4847,20 @@ impl OverlayFs {
             return Err(linux_error(io::Error::from_raw_os_error(libc::ENODATA)));
         }
 
-        // Don't allow getting the owner/permissions attribute
-        if name.to_bytes() == OWNER_PERMS_XATTR_KEY {
+        // Don't allow getting the owner/permissions attribute, or the other ones of the overlay
+        if is_internal_xattr(name.to_bytes()) {
             return Err(linux_error(io::Error::from_raw_os_error(libc::EACCES)));
         }
 
-        // Get the path for this inode
-        let c_path = self.inode_number_to_vol_path(inode)?;
+        // Open the file for its metadata
+        let file = self.entry_at(&self.get_inode_data(inode)?)?.open_meta()?;
 
         // Safe because this will only modify the contents of `buf`
         let mut buf = vec![0; size as usize];
         let res = unsafe {
             if size == 0 {
-                libc::getxattr(
-                    c_path.as_ptr(),
+                libc::fgetxattr(
+                    file.as_raw_fd(),
                     name.as_ptr(),
                     std::ptr::null_mut(),
                     size as libc::size_t,
@@ -2262,8 +4868,8 @@ impl OverlayFs {
                     0,
                 )
             } else {
-                libc::getxattr(
-                    c_path.as_ptr(),
+                libc::fgetxattr(
+                    file.as_raw_fd(),
                     name.as_ptr(),
                     buf.as_mut_ptr() as *mut libc::c_void,
                     size as libc::size_t,
@@ -2297,66 +4903,58 @@ impl OverlayFs {
             return Err(linux_error(io::Error::from_raw_os_error(libc::ENOSYS)));
         }
 
-        // Get the path for this inode
-        let c_path = self.inode_number_to_vol_path(inode)?;
-
-        // Safe because this will only modify the contents of `buf`.
-        let mut buf = vec![0; 512_usize];
-        let res = unsafe {
-            libc::listxattr(
-                c_path.as_ptr(),
-                buf.as_mut_ptr() as *mut libc::c_char,
-                512,
-                0,
-            )
-        };
+        // Open the file for its metadata
+        let file = self.entry_at(&self.get_inode_data(inode)?)?.open_meta()?;
 
-        if res < 0 {
-            let last_error = io::Error::last_os_error();
-            if last_error.raw_os_error() == Some(libc::ERANGE) {
-                return Err(io::Error::from_raw_os_error(LINUX_ERANGE));
+        // The whole list is read whatever the size asked for, as the attributes of the overlay are
+        // left out of the reply
+        let mut buf = Vec::new();
+        loop {
+            // Safe because this doesn't modify any memory and we check the return value.
+            let len = unsafe { libc::flistxattr(file.as_raw_fd(), null_mut(), 0, 0) };
+            if len < 0 {
+                return Err(linux_error(io::Error::last_os_error()));
             }
+            buf.resize(len as usize, 0);
 
-            return Err(linux_error(last_error));
-        }
-
-        // Truncate the buffer to the actual length of the list of attributes
-        buf.truncate(res as usize);
-
-        if size == 0 {
-            let mut clean_size = res as usize;
-
-            // Remove the owner/permissions attribute from the list of attributes
-            for attr in buf.split(|c| *c == 0) {
-                if attr.starts_with(&OWNER_PERMS_XATTR_KEY[..OWNER_PERMS_XATTR_KEY.len() - 1]) {
-                    clean_size -= OWNER_PERMS_XATTR_KEY.len();
-                }
+            // Safe because this will only modify the contents of `buf`.
+            let res = unsafe {
+                libc::flistxattr(
+                    file.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_char,
+                    buf.len(),
+                    0,
+                )
+            };
+            if res >= 0 {
+                buf.truncate(res as usize);
+                break;
             }
 
-            Ok(ListxattrReply::Count(clean_size as u32))
-        } else {
-            let mut clean_buf = Vec::new();
-
-            // Remove the owner/permissions attribute from the list of attributes
-            for attr in buf.split(|c| *c == 0) {
-                if attr.is_empty()
-                    || attr.starts_with(&OWNER_PERMS_XATTR_KEY[..OWNER_PERMS_XATTR_KEY.len() - 1])
-                {
-                    continue;
-                }
+            // An attribute was added in between, list them again
+            let last_error = io::Error::last_os_error();
+            if last_error.raw_os_error() != Some(libc::ERANGE) {
+                return Err(linux_error(last_error));
+            }
+        }
 
-                clean_buf.extend_from_slice(attr);
-                clean_buf.push(0);
+        // Remove the owner/permissions attribute, and the other ones of the overlay, from the list
+        let mut clean_buf = Vec::new();
+        for attr in buf.split(|c| *c == 0) {
+            if attr.is_empty() || is_internal_xattr(attr) {
+                continue;
             }
 
-            // Shrink the buffer to the actual length of the list of attributes
-            clean_buf.shrink_to_fit();
+            clean_buf.extend_from_slice(attr);
+            clean_buf.push(0);
+        }
 
+        if size == 0 {
+            Ok(ListxattrReply::Count(clean_buf.len() as u32))
+        } else if clean_buf.len() > size as usize {
             // Return an error if the buffer exceeds the requested size
-            if clean_buf.len() > size as usize {
-                return Err(io::Error::from_raw_os_error(LINUX_ERANGE));
-            }
-
+            Err(io::Error::from_raw_os_error(LINUX_ERANGE))
+        } else {
             Ok(ListxattrReply::Names(clean_buf))
         }
     }
@@ -2367,8 +4965,8 @@ impl OverlayFs {
             return Err(linux_error(io::Error::from_raw_os_error(libc::ENOSYS)));
         }
 
-        // Don't allow setting the owner/permissions attribute
-        if name.to_bytes() == OWNER_PERMS_XATTR_KEY {
+        // Don't allow removing the owner/permissions attribute, or the other ones of the overlay
+        if is_internal_xattr(name.to_bytes()) {
             return Err(linux_error(io::Error::from_raw_os_error(libc::EACCES)));
         }
 
@@ -2378,11 +4976,11 @@ impl OverlayFs {
         // Ensure the file is in the top layer before modifying attributes
         let inode_data = self.ensure_top_layer(inode_data)?;
 
-        // Get the path for this inode
-        let c_path = self.inode_number_to_vol_path(inode_data.inode)?;
+        // Open the file for its metadata
+        let file = self.entry_at(&inode_data)?.open_meta()?;
 
         // Safe because this doesn't modify any memory and we check the return value.
-        let res = unsafe { libc::removexattr(c_path.as_ptr(), name.as_ptr(), 0) };
+        let res = unsafe { libc::fremovexattr(file.as_raw_fd(), name.as_ptr(), 0) };
         if res < 0 {
             return Err(linux_error(io::Error::last_os_error()));
         }
@@ -2400,6 +4998,8 @@ impl OverlayFs {
         umask: u32,
         extensions: Extensions,
     ) -> io::Result<(Entry, Option<Handle>, OpenOptions)> {
+        let _guard = self.lock_entry(parent, name);
+
         // Check if an entry with the same name already exists in the parent directory
         match self.do_lookup(parent, name) {
             Ok(_) => {
@@ -2420,8 +5020,8 @@ impl OverlayFs {
         // Ensure parent directory is in the top layer
         let parent_data = self.ensure_top_layer(parent_data)?;
 
-        // Get the path for the new directory
-        let c_path = self.dev_ino_and_name_to_vol_path(parent_data.dev, parent_data.ino, name)?;
+        // Get the entry for the new file
+        let entry_at = self.child_at(&parent_data, name)?;
 
         let flags = self.parse_open_flags(flags as i32);
         let hostmode = if (flags & libc::O_DIRECTORY) != 0 {
@@ -2429,51 +5029,42 @@ impl OverlayFs {
         } else {
             0o600
         };
-
-        // Safe because this doesn't modify any memory and we check the return value. We don't
-        // really check `flags` because if the kernel can't handle poorly specified flags then we
-        // have much bigger problems.
-        let fd = unsafe {
-            libc::open(
-                c_path.as_ptr(),
-                flags | libc::O_CREAT | libc::O_CLOEXEC | libc::O_NOFOLLOW,
-                hostmode,
-            )
-        };
-
-        if fd < 0 {
-            return Err(linux_error(io::Error::last_os_error()));
-        }
+        let handle_grant = self.acquire_handle()?;
+        let fd_grant = self.acquire_fd()?;
+
+        // We don't really check `flags` because if the kernel can't handle poorly specified flags
+        // then we have much bigger problems.
+        let file = entry_at
+            .open(flags | libc::O_CREAT | libc::O_NOFOLLOW, hostmode)
+            .map_err(linux_error)?;
+        let fd = file.as_raw_fd();
 
         // Set security context
         if let Some(secctx) = extensions.secctx {
             Self::set_secctx(&FileId::Fd(fd), secctx, false)?
         };
 
-        // Get the initial stat for the directory
-        let stat = Self::unpatched_stat(&FileId::Path(c_path.clone()))?;
+        // Get the initial stat for the file
+        let stat = Self::unpatched_stat(&FileId::Fd(fd))?;
 
         // Set ownership and permissions
-        if let Err(e) = Self::set_owner_perms_attr(
+        Self::set_owner_perms_attr(
             &FileId::Fd(fd),
             &stat,
             Some((ctx.uid, ctx.gid)),
             Some((libc::S_IFREG as u32 | (mode & !(umask & 0o777))) as u16),
-        ) {
-            unsafe { libc::close(fd) };
-            return Err(e);
-        }
+        )?;
 
-        // Get the updated stat for the directory
-        let updated_stat = Self::patched_stat(&FileId::Path(c_path))?;
+        // Get the updated stat for the file
+        let updated_stat = Self::patched_stat(&FileId::Fd(fd))?;
 
-        let mut path = parent_data.path.clone();
-        path.push(self.intern_name(name)?);
+        let path = parent_data.path.child(self.intern_name(name));
 
         // Create the inode for the newly created directory
         let (inode, _) = self.create_inode(
             updated_stat.st_ino,
             updated_stat.st_dev,
+            birth_time(&updated_stat),
             path,
             parent_data.layer_idx,
         );
@@ -2481,13 +5072,22 @@ impl OverlayFs {
         // Create the entry for the newly created directory
         let entry = self.create_entry(inode, updated_stat);
 
-        // Safe because we just opened this fd.
-        let file = RwLock::new(unsafe { File::from_raw_fd(fd) });
+        let file = RwLock::new(file);
+
+        self.remove_whiteout(&parent_data, name)?;
+        self.sync_dir(entry_at.dir.as_ref().unwrap())?;
 
         let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
         let data = HandleData {
             inode: entry.inode,
             file,
+            dirty: Default::default(),
+            write_error: Default::default(),
+            sequential: Default::default(),
+            reads: Default::default(),
+            _fd_grant: fd_grant,
+            _handle_grant: handle_grant,
+            dir_stream: Default::default(),
         };
 
         self.handles.write().unwrap().insert(handle, Arc::new(data));
@@ -2502,6 +5102,48 @@ impl OverlayFs {
         Ok((entry, Some(handle), opts))
     }
 
+    /// Moves the top layer inodes at the `old` entry, given as its parent path and name, to the
+    /// `new` one after a rename, so that their entries are looked up at their new location. The
+    /// inodes below them follow along. When `exchange` is set, the inodes at the `new` entry move
+    /// to the `old` one as well.
+    fn rename_inode_paths(
+        &self,
+        old: (&Arc<InodePath>, Name),
+        new: (&Arc<InodePath>, Name),
+        exchange: bool,
+    ) {
+        let entry_path = |(parent, name): &(&Arc<InodePath>, Name)| {
+            let mut path = parent.names();
+            path.push(name.clone());
+            path
+        };
+        let old_path = entry_path(&old);
+        let new_path = entry_path(&new);
+
+        // Find all the inodes to move before moving any, as moving one may change the path of
+        // another when exchanging
+        let top_layer_idx = self.get_top_layer_idx();
+        let mut moves = Vec::new();
+        for (_, data) in self.inodes.read().unwrap().main.values() {
+            if data.layer_idx != top_layer_idx {
+                continue;
+            }
+
+            let Some(name) = data.path.name() else {
+                continue;
+            };
+            if name == old.1 && data.path.names() == old_path {
+                moves.push((data.path.clone(), &new));
+            } else if exchange && name == new.1 && data.path.names() == new_path {
+                moves.push((data.path.clone(), &old));
+            }
+        }
+
+        for (path, (parent, name)) in moves {
+            path.rename(parent, name.clone());
+        }
+    }
+
     fn do_mknod(
         &self,
         ctx: Context,
@@ -2511,6 +5153,8 @@ impl OverlayFs {
         umask: u32,
         extensions: Extensions,
     ) -> io::Result<Entry> {
+        let _guard = self.lock_entry(parent, name);
+
         // Check if an entry with the same name already exists in the parent directory
         match self.do_lookup(parent, name) {
             Ok(_) => {
@@ -2531,62 +5175,52 @@ impl OverlayFs {
         // Ensure parent directory is in the top layer
         let parent_data = self.ensure_top_layer(parent_data)?;
 
-        // Get the path for the new directory
-        let c_path = self.dev_ino_and_name_to_vol_path(parent_data.dev, parent_data.ino, name)?;
+        // Get the entry for the new node
+        let entry_at = self.child_at(&parent_data, name)?;
 
         // NOTE: file nodes are created as regular file on macos following the passthroughfs
         // behavior.
-        let fd = unsafe {
-            libc::open(
-                c_path.as_ptr(),
-                libc::O_CREAT | libc::O_CLOEXEC | libc::O_NOFOLLOW,
-                0o600,
-            )
-        };
-
-        if fd < 0 {
-            return Err(linux_error(io::Error::last_os_error()));
-        }
+        let file = entry_at
+            .open(libc::O_CREAT | libc::O_NOFOLLOW, 0o600)
+            .map_err(linux_error)?;
+        let fd = file.as_raw_fd();
 
         // Set security context
         if let Some(secctx) = extensions.secctx {
             Self::set_secctx(&FileId::Fd(fd), secctx, false)?
         };
 
-        // Get the initial stat for the directory
-        let stat = Self::unpatched_stat(&FileId::Path(c_path.clone()))?;
+        // Get the initial stat for the node
+        let stat = Self::unpatched_stat(&FileId::Fd(fd))?;
 
         // Set ownership and permissions
-        if let Err(e) = Self::set_owner_perms_attr(
+        Self::set_owner_perms_attr(
             &FileId::Fd(fd),
             &stat,
             Some((ctx.uid, ctx.gid)),
             Some((mode & !umask) as u16),
-        ) {
-            unsafe { libc::close(fd) };
-            return Err(e);
-        }
+        )?;
 
-        // Get the updated stat for the directory
-        let updated_stat = Self::patched_stat(&FileId::Path(c_path))?;
+        // Get the updated stat for the node
+        let updated_stat = Self::patched_stat(&FileId::Fd(fd))?;
 
-        let mut path = parent_data.path.clone();
-        path.push(self.intern_name(name)?);
+        drop(file);
+        self.remove_whiteout(&parent_data, name)?;
+        self.sync_dir(entry_at.dir.as_ref().unwrap())?;
+
+        let path = parent_data.path.child(self.intern_name(name));
 
         // Create the inode for the newly created directory
         let (inode, _) = self.create_inode(
             updated_stat.st_ino,
             updated_stat.st_dev,
+            birth_time(&updated_stat),
             path,
             parent_data.layer_idx,
         );
 
         // Create the entry for the newly created directory
-        let entry = self.create_entry(inode, updated_stat);
-
-        unsafe { libc::close(fd) };
-
-        Ok(entry)
+        Ok(self.create_entry(inode, updated_stat))
     }
 
     fn do_fallocate(
@@ -2633,28 +5267,41 @@ impl OverlayFs {
 
     fn do_lseek(&self, inode: Inode, handle: Handle, offset: u64, whence: u32) -> io::Result<u64> {
         let data = self.get_inode_handle_data(inode, handle)?;
+        let f = data.file.read().unwrap();
+        guest_seek(&f, offset, whence)
+    }
 
-        // SEEK_DATA and SEEK_HOLE have slightly different semantics
-        // in Linux vs. macOS, which means we can't support them.
-        let mwhence = if whence == 3 {
-            // SEEK_DATA
-            return Ok(offset);
-        } else if whence == 4 {
-            // SEEK_HOLE
-            libc::SEEK_END
-        } else {
-            whence as i32
-        };
-
-        let fd = data.file.write().unwrap().as_raw_fd();
-
-        // Safe because this doesn't modify any memory and we check the return value.
-        let res = unsafe { libc::lseek(fd, offset as bindings::off64_t, mwhence as libc::c_int) };
-        if res < 0 {
-            return Err(linux_error(io::Error::last_os_error()));
+    /// Copies a range of a file to another. The destination was copied up to the top layer when it
+    /// was opened for writing, while the source is read from the layer it was opened in, without
+    /// being copied up.
+    #[allow(clippy::too_many_arguments)]
+    fn do_copyfilerange(
+        &self,
+        inode_in: Inode,
+        handle_in: Handle,
+        offset_in: u64,
+        inode_out: Inode,
+        handle_out: Handle,
+        offset_out: u64,
+        len: u64,
+        flags: u64,
+    ) -> io::Result<usize> {
+        if flags != 0 {
+            return Err(einval());
         }
 
-        Ok(res as u64)
+        let data_in = self.get_inode_handle_data(inode_in, handle_in)?;
+        let data_out = self.get_inode_handle_data(inode_out, handle_out)?;
+        let copied = copy_file_range(
+            &data_in.file.read().unwrap(),
+            offset_in,
+            &data_out.file.read().unwrap(),
+            offset_out,
+            len,
+        )?;
+        data_out.dirty.store(true, Ordering::Release);
+
+        Ok(copied)
     }
 
     fn do_setupmapping(
@@ -2688,30 +5335,30 @@ impl OverlayFs {
         let inode_data = self.get_inode_data(inode)?;
         let inode_data = self.ensure_top_layer(inode_data)?;
 
+        // We've checked that map_sender is something above.
+        let sender = map_sender.as_ref().unwrap();
+
+        // The windows the guest holds are never taken back from it, so a mapping that doesn't fit
+        // in the bound once those it replaces are gone fails. The replaced ones are unmapped first.
+        let mut windows = self.map_windows.lock().unwrap();
+        if !windows.fits(guest_addr, len) {
+            return Err(linux_error(io::Error::from_raw_os_error(libc::ENOMEM)));
+        }
+        for (addr, window) in windows.remove(guest_addr, len).unwrap_or_default() {
+            remove_window(sender, addr, &window).map_err(linux_error)?;
+        }
+
         let file = self.open_inode(inode_data.inode, libc::O_RDWR)?;
         let fd = file.as_raw_fd();
 
-        let host_addr = unsafe {
-            libc::mmap(
-                null_mut(),
-                len as usize,
-                prot_flags,
-                libc::MAP_SHARED,
-                fd,
-                foffset as libc::off_t,
-            )
-        };
-        if host_addr == libc::MAP_FAILED {
-            return Err(linux_error(io::Error::last_os_error()));
-        }
+        let host_addr = dax::map_file(null_mut(), len as usize, prot_flags, &file, foffset)
+            .map_err(linux_error)?;
 
         let ret = unsafe { libc::close(fd) };
         if ret == -1 {
             return Err(linux_error(io::Error::last_os_error()));
         }
 
-        // We've checked that map_sender is something above.
-        let sender = map_sender.as_ref().unwrap();
         let (reply_sender, reply_receiver) = unbounded();
         sender
             .send(MemoryMapping::AddMapping(
@@ -2723,14 +5370,14 @@ impl OverlayFs {
             .unwrap();
         if !reply_receiver.recv().unwrap() {
             error!("Error requesting HVF the addition of a DAX window");
+            dax::forget_mappings(host_addr, len as usize);
             unsafe { libc::munmap(host_addr, len as usize) };
             return Err(linux_error(io::Error::from_raw_os_error(libc::EINVAL)));
         }
 
-        self.map_windows
-            .lock()
-            .unwrap()
-            .insert(guest_addr, host_addr as u64);
+        let writable = prot_flags & libc::PROT_WRITE != 0;
+        let window = Window::new(host_addr as u64, len, inode, foffset, writable);
+        windows.insert(guest_addr, window);
 
         Ok(())
     }
@@ -2751,9 +5398,9 @@ impl OverlayFs {
             if (req.moffset + req.len) > shm_size {
                 return Err(linux_error(io::Error::from_raw_os_error(libc::EINVAL)));
             }
-            let host_addr = match self.map_windows.lock().unwrap().remove(&guest_addr) {
-                Some(a) => a,
-                None => return Err(linux_error(io::Error::from_raw_os_error(libc::EINVAL))),
+            // A removal may span several windows, or part of a coalesced one
+            let Some(windows) = self.map_windows.lock().unwrap().remove(guest_addr, req.len) else {
+                return Err(linux_error(io::Error::from_raw_os_error(libc::EINVAL)));
             };
             debug!(
                 "removemapping: guest_addr={:x} len={:?}",
@@ -2761,23 +5408,8 @@ impl OverlayFs {
             );
 
             let sender = map_sender.as_ref().unwrap();
-            let (reply_sender, reply_receiver) = unbounded();
-            sender
-                .send(MemoryMapping::RemoveMapping(
-                    reply_sender,
-                    guest_addr,
-                    req.len,
-                ))
-                .unwrap();
-            if !reply_receiver.recv().unwrap() {
-                error!("Error requesting HVF the removal of a DAX window");
-                return Err(linux_error(io::Error::from_raw_os_error(libc::EINVAL)));
-            }
-
-            let ret = unsafe { libc::munmap(host_addr as *mut libc::c_void, req.len as usize) };
-            if ret == -1 {
-                error!("Error unmapping DAX window");
-                return Err(linux_error(io::Error::last_os_error()));
+            for (addr, window) in windows {
+                remove_window(sender, addr, &window).map_err(linux_error)?;
             }
         }
 
@@ -2799,6 +5431,107 @@ fn einval() -> io::Error {
     io::Error::from_raw_os_error(libc::EINVAL)
 }
 
+/// Removes `window`, at `guest_addr`, from the guest memory and unmaps it from the host.
+fn remove_window(
+    sender: &Sender<MemoryMapping>,
+    guest_addr: u64,
+    window: &Window,
+) -> io::Result<()> {
+    let (reply_sender, reply_receiver) = unbounded();
+    sender
+        .send(MemoryMapping::RemoveMapping(
+            reply_sender,
+            guest_addr,
+            window.len,
+        ))
+        .unwrap();
+    if !reply_receiver.recv().unwrap() {
+        error!("Error requesting HVF the removal of a DAX window");
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    dax_windows::unmap_host(window)
+}
+
+/// Rewrites the prefix of the absolute symlink `target` according to `map`, a list of `(from, to)`
+/// prefixes. Returns `None` if no prefix matches.
+fn remap_symlink_target(map: &[(PathBuf, PathBuf)], target: &[u8]) -> Option<Vec<u8>> {
+    fn trim(prefix: &Path) -> &[u8] {
+        let prefix = prefix.as_os_str().as_bytes();
+        let len = prefix
+            .iter()
+            .rposition(|&b| b != b'/')
+            .map_or(0, |pos| pos + 1);
+        &prefix[..len]
+    }
+
+    if !target.starts_with(b"/") {
+        return None;
+    }
+
+    let (from, to) = map
+        .iter()
+        .map(|(from, to)| (trim(from), trim(to)))
+        .filter(|(from, _)| {
+            target.starts_with(from) && (target.len() == from.len() || target[from.len()] == b'/')
+        })
+        .max_by_key(|(from, _)| from.len())?;
+
+    let mut remapped = to.to_vec();
+    remapped.extend_from_slice(&target[from.len()..]);
+    if remapped.is_empty() {
+        remapped.push(b'/');
+    }
+
+    Some(remapped)
+}
+
+/// Returns the path of the whiteout hiding `path`.
+fn whiteout_path(path: &[u8]) -> Vec<u8> {
+    let name_start = path
+        .iter()
+        .rposition(|&b| b == b'/')
+        .map_or(0, |pos| pos + 1);
+    let mut whiteout = path[..name_start].to_vec();
+    whiteout.extend_from_slice(WHITEOUT_PREFIX.as_bytes());
+    whiteout.extend_from_slice(&path[name_start..]);
+    whiteout
+}
+
+/// Appends the record of the directory override of `path` to `buf`, with an empty value if the
+/// override is dropped.
+fn encode_dir_override(buf: &mut Vec<u8>, path: &[u8], value: Option<(u32, u32, u16)>) {
+    buf.extend_from_slice(path);
+    buf.push(0);
+    if let Some((uid, gid, mode)) = value {
+        buf.extend_from_slice(format!("{uid}:{gid}:{mode:o}").as_bytes());
+    }
+    buf.push(0);
+}
+
+/// Returns the creation time of the host file `st`.
+fn birth_time(st: &bindings::stat64) -> BirthTime {
+    BirthTime {
+        sec: st.st_birthtime,
+        nsec: st.st_birthtime_nsec as u32,
+    }
+}
+
+/// Whether `name` is a file the overlay keeps for its own purposes in the top layer.
+fn is_internal_name(name: &[u8]) -> bool {
+    name.starts_with(COPY_UP_STAGING_PREFIX.as_bytes())
+        || name.starts_with(DIR_OVERRIDES_FILE.as_bytes())
+        || name == INTENT_LOG_FILE.as_bytes()
+        || inode_map::is_map_name(name)
+}
+
+/// Whether the extended attribute `name` is one the overlay keeps for itself in the top layer.
+fn is_internal_xattr(name: &[u8]) -> bool {
+    [COPY_UP_XATTR_KEY, OWNER_PERMS_XATTR_KEY]
+        .iter()
+        .any(|key| name == &key[..key.len() - 1])
+}
+
 //--------------------------------------------------------------------------------------------------
 // Trait Implementations
 //--------------------------------------------------------------------------------------------------
@@ -2833,26 +5566,27 @@ impl FileSystem for OverlayFs {
         // Clear all handles
         self.handles.write().unwrap().clear();
 
-        // Clear all inodes
+        // Clear all inodes, and the directory handles kept for them
         self.inodes.write().unwrap().clear();
+        self.dir_fds.lock().unwrap().clear();
 
         // Clear any memory-mapped windows
         self.map_windows.lock().unwrap().clear();
     }
 
     fn statfs(&self, _ctx: Context, inode: Self::Inode) -> io::Result<bindings::statvfs64> {
-        // Get the path for this inode
-        let c_path = self.inode_number_to_vol_path(inode)?;
+        // Open the file for its metadata
+        let file = self.entry_at(&self.get_inode_data(inode)?)?.open_meta()?;
 
-        // Call statvfs64 to get filesystem statistics
+        // Call fstatvfs64 to get filesystem statistics
         // Safe because this will only modify `out` and we check the return value.
         let mut out = MaybeUninit::<bindings::statvfs64>::zeroed();
-        let res = unsafe { bindings::statvfs64(c_path.as_ptr(), out.as_mut_ptr()) };
+        let res = unsafe { bindings::fstatvfs64(file.as_raw_fd(), out.as_mut_ptr()) };
         if res < 0 {
             return Err(io::Error::last_os_error());
         }
 
-        // Safe because statvfs64 initialized the struct
+        // Safe because fstatvfs64 initialized the struct
         Ok(unsafe { out.assume_init() })
     }
 
@@ -2868,6 +5602,7 @@ impl FileSystem for OverlayFs {
             st.st_size = INIT_BINARY.len() as i64;
             st.st_ino = self.init_inode;
             st.st_mode = 0o100_755;
+            self.patch_dev(&mut st);
 
             return Ok(Entry {
                 inode: self.init_inode,
@@ -2879,7 +5614,10 @@ impl FileSystem for OverlayFs {
             })
         }
 
-        let (entry, _) = self.do_lookup(parent, name)?;
+        let (entry, path_inodes) = self.do_lookup(parent, name)?;
+        if let Some(data) = path_inodes.last() {
+            self.layer_stats.lookup(data.layer_idx);
+        }
         self.bump_refcount(entry.inode);
         Ok(entry)
     }
@@ -2897,6 +5635,16 @@ impl FileSystem for OverlayFs {
         self.do_getattr(inode)
     }
 
+    fn statx(
+        &self,
+        _ctx: Context,
+        inode: Self::Inode,
+        _handle: Option<Self::Handle>,
+    ) -> io::Result<(bindings::stat64, Option<BirthTime>, Duration)> {
+        let (st, timeout) = self.do_getattr(inode)?;
+        Ok((st, Some(birth_time(&st)), timeout))
+    }
+
     fn setattr(
         &self,
         _ctx: Context,
@@ -2905,6 +5653,7 @@ impl FileSystem for OverlayFs {
         handle: Option<Self::Handle>,
         valid: SetattrValid,
     ) -> io::Result<(bindings::stat64, Duration)> {
+        self.check_writable()?;
         self.do_setattr(inode, attr, handle, valid)
     }
 
@@ -2921,6 +5670,7 @@ impl FileSystem for OverlayFs {
         umask: u32,
         extensions: Extensions,
     ) -> io::Result<Entry> {
+        self.check_writable()?;
         Self::validate_name(name)?;
         let entry = self.do_mkdir(ctx, parent, name, mode, umask, extensions)?;
         self.bump_refcount(entry.inode);
@@ -2928,13 +5678,15 @@ impl FileSystem for OverlayFs {
     }
 
     fn unlink(&self, _ctx: Context, parent: Self::Inode, name: &CStr) -> io::Result<()> {
+        self.check_writable()?;
         Self::validate_name(name)?;
-        self.do_unlink(parent, name)
+        self.do_unlink(parent, &self.entry_name(parent, name)?)
     }
 
     fn rmdir(&self, _ctx: Context, parent: Self::Inode, name: &CStr) -> io::Result<()> {
+        self.check_writable()?;
         Self::validate_name(name)?;
-        self.do_rmdir(parent, name)
+        self.do_rmdir(parent, &self.entry_name(parent, name)?)
     }
 
     fn symlink(
@@ -2945,6 +5697,7 @@ impl FileSystem for OverlayFs {
         name: &CStr,
         extensions: Extensions,
     ) -> io::Result<Entry> {
+        self.check_writable()?;
         Self::validate_name(name)?;
         let entry = self.do_symlink(ctx, linkname, parent, name, extensions)?;
         self.bump_refcount(entry.inode);
@@ -2960,9 +5713,12 @@ impl FileSystem for OverlayFs {
         new_name: &CStr,
         flags: u32,
     ) -> io::Result<()> {
+        self.check_writable()?;
         Self::validate_name(old_name)?;
         Self::validate_name(new_name)?;
-        self.do_rename(old_parent, old_name, new_parent, new_name, flags)
+        let old_name = self.entry_name(old_parent, old_name)?;
+        let new_name = self.entry_name(new_parent, new_name)?;
+        self.do_rename(old_parent, &old_name, new_parent, &new_name, flags)
     }
 
     fn link(
@@ -2972,6 +5728,7 @@ impl FileSystem for OverlayFs {
         new_parent: Self::Inode,
         new_name: &CStr,
     ) -> io::Result<Entry> {
+        self.check_writable()?;
         Self::validate_name(new_name)?;
         let entry = self.do_link(inode, new_parent, new_name)?;
         self.bump_refcount(entry.inode);
@@ -3010,7 +5767,17 @@ impl FileSystem for OverlayFs {
         let data = self.get_inode_handle_data(inode, handle)?;
 
         let f = data.file.read().unwrap();
-        w.write_from(&f, size as usize, offset)
+        let res = write_from_sparse(&mut w, &f, size as usize, offset);
+        if let Ok(read) = res {
+            page_cache::advise_read(
+                &data.reads,
+                self.config.page_cache,
+                f.as_raw_fd(),
+                offset,
+                read as u64,
+            );
+        }
+        res
     }
 
     fn write<R: io::Read + ZeroCopyReader>(
@@ -3022,13 +5789,25 @@ impl FileSystem for OverlayFs {
         size: u32,
         offset: u64,
         _lock_owner: Option<u64>,
-        _delayed_write: bool,
+        delayed_write: bool,
         _kill_priv: bool,
         _flags: u32,
     ) -> io::Result<usize> {
+        self.check_writable()?;
         let data = self.get_inode_handle_data(inode, handle)?;
         let f = data.file.read().unwrap();
-        r.read_to(&f, size as usize, offset)
+        let res = r.read_to(&f, size as usize, offset);
+
+        match &res {
+            Ok(written) => {
+                data.dirty.store(true, Ordering::Release);
+                data.preallocate_ahead(f.as_raw_fd(), offset, *written as u64);
+            }
+            Err(e) if delayed_write => data.set_write_error(e),
+            Err(_) => (),
+        }
+
+        res
     }
 
     fn flush(
@@ -3040,6 +5819,16 @@ impl FileSystem for OverlayFs {
     ) -> io::Result<()> {
         let data = self.get_inode_handle_data(inode, handle)?;
 
+        // With writeback caching the guest may have considered writes complete that were never
+        // synced to the host. Sync them now so that errors surface when the guest closes the file.
+        if self.writeback.load(Ordering::Relaxed) && data.dirty.swap(false, Ordering::AcqRel) {
+            // Safe because this doesn't modify any memory and we check the return value.
+            let res = unsafe { libc::fsync(data.file.read().unwrap().as_raw_fd()) };
+            if res < 0 {
+                data.set_write_error(&io::Error::last_os_error());
+            }
+        }
+
         // Since this method is called whenever an fd is closed in the client, we can emulate that
         // behavior by doing the same thing (dup-ing the fd and then immediately closing it). Safe
         // because this doesn't modify any memory and we check the return values.
@@ -3052,8 +5841,12 @@ impl FileSystem for OverlayFs {
             if libc::close(newfd) < 0 {
                 return Err(linux_error(io::Error::last_os_error()));
             }
+        }
 
-            Ok(())
+        // Report the first delayed write error on this handle, if any, exactly once.
+        match data.take_write_error() {
+            Some(err) => Err(linux_error(err)),
+            None => Ok(()),
         }
     }
 
@@ -3109,8 +5902,7 @@ impl FileSystem for OverlayFs {
     where
         F: FnMut(DirEntry) -> io::Result<usize>,
     {
-        let _ = self.get_inode_handle_data(inode, handle)?;
-        self.do_readdir(inode, size, offset, add_entry)
+        self.do_readdir(inode, handle, size, offset, add_entry)
     }
 
     fn readdirplus<F>(
@@ -3125,8 +5917,7 @@ impl FileSystem for OverlayFs {
     where
         F: FnMut(DirEntry, Entry) -> io::Result<usize>,
     {
-        let _ = self.get_inode_handle_data(inode, handle)?;
-        self.do_readdir(inode, size, offset, |dir_entry| {
+        self.do_readdir(inode, handle, size, offset, |dir_entry| {
             let (entry, _) = self.do_lookup(inode, &CString::new(dir_entry.name).unwrap())?;
             add_entry(dir_entry, entry)
         })
@@ -3161,6 +5952,7 @@ impl FileSystem for OverlayFs {
         value: &[u8],
         flags: u32,
     ) -> io::Result<()> {
+        self.check_writable()?;
         self.do_setxattr(inode, name, value, flags)
     }
 
@@ -3184,13 +5976,14 @@ impl FileSystem for OverlayFs {
     }
 
     fn removexattr(&self, _ctx: Context, inode: Self::Inode, name: &CStr) -> io::Result<()> {
+        self.check_writable()?;
         self.do_removexattr(inode, name)
     }
 
     fn access(&self, ctx: Context, inode: Self::Inode, mask: u32) -> io::Result<()> {
-        let c_path = self.inode_number_to_vol_path(inode)?;
-
-        let st = Self::patched_stat(&FileId::Path(c_path))?;
+        let st = self
+            .entry_at(&self.get_inode_data(inode)?)?
+            .patched_stat()?;
 
         let mode = mask as i32 & (libc::R_OK | libc::W_OK | libc::X_OK);
 
@@ -3199,6 +5992,10 @@ impl FileSystem for OverlayFs {
             return Ok(());
         }
 
+        if mode & libc::W_OK != 0 {
+            self.check_writable()?;
+        }
+
         if (mode & libc::R_OK) != 0
             && ctx.uid != 0
             && (st.st_uid != ctx.uid || st.st_mode & 0o400 == 0)
@@ -3241,6 +6038,7 @@ impl FileSystem for OverlayFs {
         umask: u32,
         extensions: Extensions,
     ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions)> {
+        self.check_writable()?;
         Self::validate_name(name)?;
         let (entry, handle, opts) = self.do_create(ctx, parent, name, mode, flags, umask, extensions)?;
         self.bump_refcount(entry.inode);
@@ -3257,6 +6055,7 @@ impl FileSystem for OverlayFs {
         umask: u32,
         extensions: Extensions,
     ) -> io::Result<Entry> {
+        self.check_writable()?;
         Self::validate_name(name)?;
         let entry = self.do_mknod(ctx, parent, name, mode, umask, extensions)?;
         self.bump_refcount(entry.inode);
@@ -3272,6 +6071,7 @@ impl FileSystem for OverlayFs {
         offset: u64,
         length: u64,
     ) -> io::Result<()> {
+        self.check_writable()?;
         self.do_fallocate(inode, handle, offset, length)
     }
 
@@ -3286,6 +6086,24 @@ impl FileSystem for OverlayFs {
         self.do_lseek(inode, handle, offset, whence)
     }
 
+    fn copyfilerange(
+        &self,
+        _ctx: Context,
+        inode_in: Inode,
+        handle_in: Handle,
+        offset_in: u64,
+        inode_out: Inode,
+        handle_out: Handle,
+        offset_out: u64,
+        len: u64,
+        flags: u64,
+    ) -> io::Result<usize> {
+        self.check_writable()?;
+        self.do_copyfilerange(
+            inode_in, handle_in, offset_in, inode_out, handle_out, offset_out, len, flags,
+        )
+    }
+
     fn setupmapping(
         &self,
         _ctx: Context,
@@ -3299,6 +6117,9 @@ impl FileSystem for OverlayFs {
         shm_size: u64,
         map_sender: &Option<Sender<MemoryMapping>>,
     ) -> io::Result<()> {
+        if flags & fuse::SetupmappingFlags::WRITE.bits() != 0 {
+            self.check_writable()?;
+        }
         self.do_setupmapping(
             inode,
             foffset,
@@ -3321,6 +6142,45 @@ impl FileSystem for OverlayFs {
     ) -> io::Result<()> {
         self.do_removemapping(requests, guest_shm_base, shm_size, map_sender)
     }
+
+    fn ioctl(
+        &self,
+        _ctx: Context,
+        inode: Self::Inode,
+        _handle: Self::Handle,
+        _flags: u32,
+        cmd: u32,
+        _arg: u64,
+        data: &[u8],
+        _out_size: u32,
+        _exit_code: &Arc<AtomicI32>,
+    ) -> io::Result<Vec<u8>> {
+        match cmd {
+            bindings::LINUX_FS_IOC_GETFLAGS => {
+                let file = self.entry_at(&self.get_inode_data(inode)?)?.open_meta()?;
+                Ok(get_file_flags(&file)?.to_ne_bytes().to_vec())
+            }
+            bindings::LINUX_FS_IOC_SETFLAGS => {
+                if !self.config.allow_file_flags {
+                    return Err(linux_error(io::Error::from_raw_os_error(libc::EPERM)));
+                }
+                self.check_writable()?;
+
+                let flags = data
+                    .get(..std::mem::size_of::<u32>())
+                    .and_then(|b| b.try_into().ok())
+                    .map(u32::from_ne_bytes)
+                    .ok_or_else(einval)?;
+
+                // The flags are only set on the top layer copy of the file
+                let inode_data = self.ensure_top_layer(self.get_inode_data(inode)?)?;
+                let file = self.entry_at(&inode_data)?.open_meta()?;
+                set_file_flags(&file, flags)?;
+                Ok(Vec::new())
+            }
+            _ => Err(linux_error(io::Error::from_raw_os_error(libc::EOPNOTSUPP))),
+        }
+    }
 }
 
 impl Default for Config {
@@ -3335,32 +6195,98 @@ impl Default for Config {
             export_fsid: 0,
             export_table: None,
             layers: vec![],
+            symlink_policy: SymlinkPolicy::default(),
+            symlink_target_map: Vec::new(),
+            max_symlink_depth: 40,
+            upper_layer: UpperLayer::default(),
+            read_only: false,
+            dir_nlink: DirNlinkPolicy::default(),
+            verify_whiteouts: false,
+            case_insensitive: false,
+            durable: false,
+            intent_log: false,
+            inode_map: false,
+            allow_file_flags: false,
+            copy_up_threads: 4,
+            single_dev: false,
+            differential_blocks: false,
+            content_store: None,
+            lookup_filters: false,
+            lower_layers: None,
+            whiteout_cache_ttl: None,
+            layer_integrity: None,
+            fd_client: None,
+            handle_quota: None,
+            hooks: None,
+            dax_max_mapped: None,
+            metadata_sanitizer: None,
+            page_cache: FsPageCache::Host,
+            #[cfg(feature = "oci")]
+            lazy_layers: Vec::new(),
         }
     }
 }
 
-//--------------------------------------------------------------------------------------------------
-// External Functions
-//--------------------------------------------------------------------------------------------------
+impl Default for MetadataSanitizer {
+    fn default() -> Self {
+        Self {
+            keep_xattrs: ["user.", "trusted.", "security.capability"]
+                .map(String::from)
+                .to_vec(),
+            copy_up: false,
+        }
+    }
+}
 
-extern "C" {
-    /// macOS system call for cloning a file with COW semantics
-    ///
-    /// Creates a copy-on-write clone of a file.
-    ///
-    /// ## Arguments
-    ///
-    /// * `src` - Path to the source file
-    /// * `dst` - Path to the destination file
-    /// * `flags` - Currently unused, must be 0
-    ///
-    /// ## Returns
-    ///
-    /// * `0` on success
-    /// * `-1` on error with errno set
-    fn clonefile(
-        src: *const libc::c_char,
-        dst: *const libc::c_char,
-        flags: libc::c_int,
-    ) -> libc::c_int;
+impl Iterator for HostDir {
+    type Item = io::Result<HostDirEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // Safe because the stream is open, and errno is reset to tell the end of the stream
+            // from an error.
+            let dirent = unsafe {
+                *libc::__error() = 0;
+                libc::readdir(self.dir.as_ptr())
+            };
+            if dirent.is_null() {
+                let err = io::Error::last_os_error();
+                return (err.raw_os_error() != Some(0)).then_some(Err(err));
+            }
+
+            // Safe because readdir returned an entry, which stays valid until the next call.
+            let dirent = unsafe { &*dirent };
+            let name = unsafe { CStr::from_ptr(dirent.d_name.as_ptr()) };
+            if name == c"." || name == c".." {
+                continue;
+            }
+
+            let mut type_ = dirent.d_type;
+            if type_ == libc::DT_UNKNOWN {
+                // Safe because the stream is open.
+                let dirfd = unsafe { libc::dirfd(self.dir.as_ptr()) };
+                type_ = match OverlayFs::unpatched_stat_at(dirfd, name) {
+                    Ok(st) => ((st.st_mode & libc::S_IFMT) >> 12) as u8,
+                    Err(e) => return Some(Err(e)),
+                };
+            }
+
+            return Some(Ok(HostDirEntry {
+                ino: dirent.d_ino,
+                type_,
+                name: name.to_bytes().to_vec(),
+            }));
+        }
+    }
 }
+
+impl Drop for HostDir {
+    fn drop(&mut self) {
+        // Safe because the stream is open, and closed only here.
+        unsafe { libc::closedir(self.dir.as_ptr()) };
+    }
+}
+
+// Safe because the stream is only used through `&mut self`, and the libc stream functions don't
+// depend on the thread they are called from.
+unsafe impl Send for HostDir {}
@@ -4,14 +4,15 @@
 
 use std::collections::btree_map;
 use std::collections::BTreeMap;
-use std::collections::HashMap;
-use std::ffi::{CStr, CString};
+use std::ffi::{CStr, CString, OsStr};
 use std::fs::File;
 use std::io;
 #[cfg(not(feature = "efi"))]
 use std::mem;
 use std::mem::MaybeUninit;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
 use std::ptr::null_mut;
 use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
@@ -19,18 +20,31 @@ use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 
 use crossbeam_channel::{unbounded, Sender};
+use utils::fd_budget::{FdClient, FdGrant};
 use utils::worker_message::WorkerMessage;
 
 use crate::virtio::fs::filesystem::SecContext;
 
 use super::super::super::linux_errno::{linux_error, LINUX_ERANGE};
 use super::super::bindings;
+use super::super::dax;
+use super::super::dax_windows::{self, DaxWindows, Window};
 use super::super::filesystem::{
-    Context, DirEntry, Entry, ExportTable, Extensions, FileSystem, FsOptions, GetxattrReply,
-    ListxattrReply, OpenOptions, SetattrValid, ZeroCopyReader, ZeroCopyWriter,
+    BirthTime, Context, DirEntry, Entry, ExportTable, Extensions, FileSystem, FsOptions,
+    GetxattrReply, ListxattrReply, OpenOptions, SetattrValid, ZeroCopyReader, ZeroCopyWriter,
 };
 use super::super::fuse;
+use super::super::handle_quota::{FsHandleQuota, HandleGrant};
+use super::super::hooks::FsHooks;
+use super::fs_utils::{
+    copy_file_range, get_birth_time, get_file_flags, guest_seek, set_file_flags, sync_dir_at,
+    write_from_sparse,
+};
 use super::super::multikey::MultikeyBTreeMap;
+use super::super::page_cache::{self, SequentialReads};
+use super::super::retry::retry_syscall;
+use super::super::unicode_names;
+use super::super::FsPageCache;
 
 const INIT_CSTR: &[u8] = b"init.krun\0";
 const XATTR_KEY: &[u8] = b"user.containers.override_stat\0";
@@ -54,6 +68,17 @@ struct InodeData {
     ino: u64,
     dev: i32,
     refcount: AtomicU64,
+    /// The creation time of the host file
+    btime: BirthTime,
+}
+
+impl InodeData {
+    /// Whether the host file `st` isn't the file of this inode but a new one that got its inode
+    /// number after it was deleted. Host file systems recycle inode numbers, so a hit on the
+    /// alternate key alone doesn't tell.
+    fn is_recycled(&self, st: &bindings::stat64) -> bool {
+        self.btime != birth_time(st)
+    }
 }
 
 struct DirStream {
@@ -65,6 +90,34 @@ struct HandleData {
     inode: Inode,
     file: RwLock<File>,
     dirstream: Mutex<DirStream>,
+    // The reads through this handle, to give the host page cache hints about.
+    reads: Mutex<SequentialReads>,
+    // The first error hit by a delayed (writeback) write on this handle, not yet reported.
+    write_error: Mutex<Option<io::Error>>,
+    _fd_grant: Option<FdGrant>,
+    _handle_grant: Option<HandleGrant>,
+}
+
+impl HandleData {
+    /// Records the error of a delayed write.
+    ///
+    /// The guest has already considered such writes complete, so the error is kept around until it
+    /// can be reported by [`FileSystem::flush`] or [`FileSystem::release`]. Only the first error is
+    /// kept, later ones are most likely a consequence of it.
+    fn set_write_error(&self, err: &io::Error) {
+        let mut write_error = self.write_error.lock().unwrap();
+        if write_error.is_none() {
+            *write_error = Some(match err.raw_os_error() {
+                Some(errno) => io::Error::from_raw_os_error(errno),
+                None => io::Error::new(err.kind(), err.to_string()),
+            });
+        }
+    }
+
+    /// Takes the pending delayed write error, if any, so that it is only reported once.
+    fn take_write_error(&self) -> Option<io::Error> {
+        self.write_error.lock().unwrap().take()
+    }
 }
 
 fn ebadf() -> io::Error {
@@ -248,7 +301,7 @@ fn fstat(fd: RawFd, host: bool) -> io::Result<bindings::stat64> {
 
     // Safe because the kernel will only write data in `st` and we check the return
     // value.
-    let res = unsafe { libc::fstat(fd, st.as_mut_ptr()) };
+    let res = retry_syscall(|| unsafe { libc::fstat(fd, st.as_mut_ptr()) });
     if res >= 0 {
         // Safe because the kernel guarantees that the struct is now fully initialized.
         let mut st = unsafe { st.assume_init() };
@@ -276,7 +329,7 @@ fn lstat(c_path: &CString, host: bool) -> io::Result<bindings::stat64> {
 
     // Safe because the kernel will only write data in `st` and we check the return
     // value.
-    let res = unsafe { libc::lstat(c_path.as_ptr(), st.as_mut_ptr()) };
+    let res = retry_syscall(|| unsafe { libc::lstat(c_path.as_ptr(), st.as_mut_ptr()) });
     if res >= 0 {
         // Safe because the kernel guarantees that the struct is now fully initialized.
         let mut st = unsafe { st.assume_init() };
@@ -299,6 +352,14 @@ fn lstat(c_path: &CString, host: bool) -> io::Result<bindings::stat64> {
     }
 }
 
+/// Returns the creation time of the host file `st`.
+fn birth_time(st: &bindings::stat64) -> BirthTime {
+    BirthTime {
+        sec: st.st_birthtime,
+        nsec: st.st_birthtime_nsec as u32,
+    }
+}
+
 /// The caching policy that the file system should report to the FUSE client. By default the FUSE
 /// protocol uses close-to-open consistency. This means that any cached contents of the file are
 /// invalidated the next time that file is opened.
@@ -393,6 +454,57 @@ pub struct Config {
     pub export_fsid: u64,
     /// Table of exported FDs to share with other subsystems. Not supported for macos.
     pub export_table: Option<ExportTable>,
+
+    /// Whether changes to directory entries are made durable before they are acknowledged. When
+    /// enabled, the directories whose entries are created, linked, removed or renamed are flushed
+    /// to the disk before the request completes, so that the changes survive a host crash. This
+    /// has a significant cost for workloads that create or remove many files.
+    ///
+    /// The default value for this option is `false`.
+    pub durable: bool,
+
+    /// Whether the guest may set the immutable and append-only flags of the host files, e.g. with
+    /// `chattr`. The flags are always reported to the guest, so that it can tell why writing to such
+    /// files fails, but changing them is left to the host unless this is set.
+    ///
+    /// The default value for this option is `false`.
+    pub allow_file_flags: bool,
+
+    /// The registration with the file descriptor budget of the process the descriptors of the
+    /// open handles are accounted to. Opening a file fails with `EMFILE` when the budget is
+    /// exhausted.
+    ///
+    /// The default is `None`, which doesn't account them.
+    pub fd_client: Option<FdClient>,
+
+    /// The count of the handles open on the share, opening a file failing with `EMFILE` when it
+    /// reaches its limit.
+    ///
+    /// The default is `None`, which doesn't limit them.
+    pub handle_quota: Option<FsHandleQuota>,
+
+    /// The hooks of the embedder, see `FsHooks`.
+    ///
+    /// The default is `None`, which calls none.
+    pub hooks: Option<FsHooks>,
+
+    /// The most bytes of the files the guest may have mapped into the DAX range at once. Setting up
+    /// a mapping past it fails with `ENOMEM`, see `dax_windows`.
+    ///
+    /// The default is `None`, which doesn't limit them.
+    pub dax_max_mapped: Option<u64>,
+
+    /// Whether the lookups of names missing on the host fall back to the entries whose names are
+    /// canonically equivalent, and the names the host volume changes are listed as the guest gave
+    /// them, see `unicode_names`.
+    ///
+    /// The default is `false`, which matches and lists the names as the host volume does.
+    pub normalize_names: bool,
+
+    /// How the reads of the guest use the host page cache, see `page_cache`.
+    ///
+    /// The default is `FsPageCache::Host`, which leaves it to the host.
+    pub page_cache: FsPageCache,
 }
 
 impl Default for Config {
@@ -407,6 +519,14 @@ impl Default for Config {
             proc_sfd_rawfd: None,
             export_fsid: 0,
             export_table: None,
+            durable: false,
+            allow_file_flags: false,
+            fd_client: None,
+            handle_quota: None,
+            hooks: None,
+            dax_max_mapped: None,
+            normalize_names: false,
+            page_cache: FsPageCache::Host,
         }
     }
 }
@@ -425,12 +545,15 @@ pub struct PassthroughFs {
     next_handle: AtomicU64,
     init_handle: u64,
 
-    map_windows: Mutex<HashMap<u64, u64>>,
+    map_windows: Mutex<DaxWindows>,
 
     // Whether writeback caching is enabled for this directory. This will only be true when
     // `cfg.writeback` is true and `init` was called with `FsOptions::WRITEBACK_CACHE`.
     writeback: AtomicBool,
     announce_submounts: AtomicBool,
+    // Whether the host volume stores names in another form than the one they are given in, so
+    // that the guest names are kept in extended attributes.
+    changes_names: bool,
     cfg: Config,
 }
 
@@ -439,19 +562,22 @@ impl PassthroughFs {
         let root = CString::new(cfg.root_dir.as_str()).expect("CString::new failed");
 
         // Safe because this doesn't modify any memory and we check the return value.
-        let fd = unsafe {
+        let fd = retry_syscall(|| unsafe {
             libc::openat(
                 libc::AT_FDCWD,
                 root.as_ptr(),
                 libc::O_NOFOLLOW | libc::O_CLOEXEC,
             )
-        };
+        });
         if fd < 0 {
             return Err(linux_error(io::Error::last_os_error()));
         }
 
         unsafe { libc::close(fd) };
 
+        let changes_names =
+            cfg.normalize_names && unicode_names::host_changes_names(Path::new(&cfg.root_dir));
+
         Ok(PassthroughFs {
             inodes: RwLock::new(MultikeyBTreeMap::new()),
             next_inode: AtomicU64::new(fuse::ROOT_ID + 2),
@@ -461,10 +587,11 @@ impl PassthroughFs {
             next_handle: AtomicU64::new(1),
             init_handle: 0,
 
-            map_windows: Mutex::new(HashMap::new()),
+            map_windows: Mutex::new(DaxWindows::new(cfg.dax_max_mapped)),
 
             writeback: AtomicBool::new(false),
             announce_submounts: AtomicBool::new(false),
+            changes_names,
             cfg,
         })
     }
@@ -485,6 +612,57 @@ impl PassthroughFs {
         Ok(cstr)
     }
 
+    /// Returns the inode of the init binary served in every directory.
+    pub(crate) fn init_inode(&self) -> Inode {
+        self.init_inode
+    }
+
+    /// Returns the inodes the guest may hold, and cache the attributes of.
+    pub(crate) fn held_inodes(&self) -> Vec<Inode> {
+        self.inodes.read().unwrap().main.keys().copied().collect()
+    }
+
+    /// Returns the hooks of the embedder on the share, if it has any.
+    pub(crate) fn hooks(&self) -> Option<&FsHooks> {
+        self.cfg.hooks.as_ref()
+    }
+
+    /// Opens `inode` to read and change its metadata, opening a symlink itself rather than its
+    /// target.
+    fn open_inode_meta(&self, inode: Inode) -> io::Result<File> {
+        let vol_path = self.inode_to_path(inode)?;
+        let fd = retry_syscall(|| unsafe {
+            libc::open(
+                vol_path.as_ptr(),
+                libc::O_EVTONLY | libc::O_SYMLINK | libc::O_CLOEXEC,
+            )
+        });
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Safe because we just opened this fd.
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    /// Returns the path of `inode` relative to the shared directory, if it can be found.
+    pub(crate) fn inode_path(&self, inode: Inode) -> Option<PathBuf> {
+        let file = self.open_inode_meta(inode).ok()?;
+
+        let mut buf = vec![0u8; libc::PATH_MAX as usize];
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETPATH, buf.as_mut_ptr()) } < 0 {
+            return None;
+        }
+        let len = buf.iter().position(|&b| b == 0)?;
+        buf.truncate(len);
+
+        let root = std::fs::canonicalize(&self.cfg.root_dir).ok()?;
+        Path::new(OsStr::from_bytes(&buf))
+            .strip_prefix(root)
+            .ok()
+            .map(Path::to_path_buf)
+    }
+
     fn name_to_path(&self, parent: Inode, name: &CStr) -> io::Result<CString> {
         debug!(
             "name_to_path: parent={} name={}",
@@ -532,12 +710,12 @@ impl PassthroughFs {
 
         let c_path = self.inode_to_path(inode)?;
 
-        let fd = unsafe {
+        let fd = retry_syscall(|| unsafe {
             libc::open(
                 c_path.as_ptr(),
                 (flags | libc::O_CLOEXEC) & (!libc::O_NOFOLLOW) & (!libc::O_EXLOCK),
             )
-        };
+        });
         if fd < 0 {
             return Err(linux_error(io::Error::last_os_error()));
         }
@@ -546,6 +724,38 @@ impl PassthroughFs {
         Ok(unsafe { File::from_raw_fd(fd) })
     }
 
+    /// Returns the name of the entry of the directory `parent` canonically equivalent to `name`,
+    /// for a lookup of `name` that found nothing, if the names are normalized.
+    fn equivalent_name(&self, parent: Inode, name: &CStr) -> Option<CString> {
+        if !self.cfg.normalize_names || name.to_bytes().is_ascii() {
+            return None;
+        }
+        let dir = self.inode_to_path(parent).ok()?;
+        let entries = std::fs::read_dir(OsStr::from_bytes(dir.to_bytes())).ok()?;
+        let names = entries.filter_map(|entry| Some(entry.ok()?.file_name().into_vec()));
+        unicode_names::find_equivalent(names, name.to_bytes())
+    }
+
+    /// Keeps the name `name` the guest created an entry of the directory `parent` as, if the host
+    /// volume changes it.
+    fn keep_guest_name(&self, parent: Inode, name: &CStr) {
+        if self.changes_names && unicode_names::needs_sidecar(name.to_bytes()) {
+            if let Ok(path) = self.name_to_path(parent, name) {
+                unicode_names::write_sidecar(&path, name.to_bytes());
+            }
+        }
+    }
+
+    /// Returns the name the guest created the entry `name` of the directory `parent` as, if the
+    /// host volume changed it.
+    fn guest_name(&self, parent: Inode, name: &[u8]) -> Option<CString> {
+        if !self.changes_names || name.is_ascii() {
+            return None;
+        }
+        let path = self.name_to_path(parent, &CString::new(name).ok()?).ok()?;
+        unicode_names::read_sidecar(&path)
+    }
+
     fn do_lookup(&self, parent: Inode, name: &CStr) -> io::Result<Entry> {
         let parent_data = self
             .inodes
@@ -556,7 +766,19 @@ impl PassthroughFs {
             .ok_or_else(ebadf)?;
 
         let c_path = self.name_to_path(parent, name)?;
-        let st = lstat(&c_path, false)?;
+        let (c_path, st) = match lstat(&c_path, false) {
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {
+                match self.equivalent_name(parent, name) {
+                    Some(host_name) => {
+                        let c_path = self.name_to_path(parent, &host_name)?;
+                        let st = lstat(&c_path, false)?;
+                        (c_path, st)
+                    }
+                    None => return Err(e),
+                }
+            }
+            res => (c_path, res?),
+        };
 
         debug!(
             "do_lookup: inode={} path={}",
@@ -579,30 +801,39 @@ impl PassthroughFs {
         };
         let data = self.inodes.read().unwrap().get_alt(&altkey).cloned();
 
-        let inode = if let Some(data) = data {
-            // Matches with the release store in `forget`.
-            data.refcount.fetch_add(1, Ordering::Acquire);
-            data.inode
-        } else {
-            // There is a possible race here where 2 threads end up adding the same file
-            // into the inode list.  However, since each of those will get a unique Inode
-            // value and unique file descriptors this shouldn't be that much of a problem.
-            let inode = self.next_inode.fetch_add(1, Ordering::Relaxed);
-            self.inodes.write().unwrap().insert(
-                inode,
-                InodeAltKey {
-                    ino: st.st_ino,
-                    dev: st.st_dev,
-                },
-                Arc::new(InodeData {
-                    inode,
-                    ino: st.st_ino,
-                    dev: st.st_dev,
-                    refcount: AtomicU64::new(1),
-                }),
-            );
+        let inode = match data {
+            Some(data) if !data.is_recycled(&st) => {
+                // Matches with the release store in `forget`.
+                data.refcount.fetch_add(1, Ordering::Acquire);
+                data.inode
+            }
+            stale => {
+                if let Some(stale) = stale {
+                    debug!("do_lookup: inode {} was recycled by the host", stale.inode);
+                }
 
-            inode
+                // There is a possible race here where 2 threads end up adding the same file
+                // into the inode list.  However, since each of those will get a unique Inode
+                // value and unique file descriptors this shouldn't be that much of a problem.
+                // Inserting the new inode drops the stale one, whose file is gone anyway.
+                let inode = self.next_inode.fetch_add(1, Ordering::Relaxed);
+                self.inodes.write().unwrap().insert(
+                    inode,
+                    InodeAltKey {
+                        ino: st.st_ino,
+                        dev: st.st_dev,
+                    },
+                    Arc::new(InodeData {
+                        inode,
+                        ino: st.st_ino,
+                        dev: st.st_dev,
+                        refcount: AtomicU64::new(1),
+                        btime: birth_time(&st),
+                    }),
+                );
+
+                inode
+            }
         };
 
         Ok(Entry {
@@ -679,12 +910,16 @@ impl PassthroughFs {
                 continue;
             }
 
+            // The guest name is followed by a nul byte, as `readdirplus` expects
+            let guest_name = self.guest_name(inode, &name);
+            let name = unicode_names::guest_name(&name, guest_name.as_deref().map(CStr::to_bytes));
+
             let res = unsafe {
                 add_entry(DirEntry {
                     ino: (*dentry).d_ino,
                     offset: (ds.offset + 1) as u64,
                     type_: u32::from((*dentry).d_type),
-                    name: &name,
+                    name,
                 })
             };
 
@@ -712,6 +947,8 @@ impl PassthroughFs {
     fn do_open(&self, inode: Inode, flags: u32) -> io::Result<(Option<Handle>, OpenOptions)> {
         let flags = self.parse_open_flags(flags as i32);
 
+        let handle_grant = self.acquire_handle()?;
+        let fd_grant = self.acquire_fd()?;
         let file = RwLock::new(self.open_inode(inode, flags)?);
 
         let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
@@ -722,6 +959,10 @@ impl PassthroughFs {
                 stream: 0,
                 offset: 0,
             }),
+            reads: Default::default(),
+            write_error: Default::default(),
+            _fd_grant: fd_grant,
+            _handle_grant: handle_grant,
         };
 
         self.handles.write().unwrap().insert(handle, Arc::new(data));
@@ -748,10 +989,16 @@ impl PassthroughFs {
 
         if let btree_map::Entry::Occupied(e) = handles.entry(handle) {
             if e.get().inode == inode {
+                // Report any delayed write error that was not picked up by a flush.
+                let write_error = e.get().take_write_error();
+
                 // We don't need to close the file here because that will happen automatically when
                 // the last `Arc` is dropped.
                 e.remove();
-                return Ok(());
+                return match write_error {
+                    Some(err) => Err(linux_error(err)),
+                    None => Ok(()),
+                };
             }
         }
 
@@ -775,7 +1022,9 @@ impl PassthroughFs {
     ) -> io::Result<()> {
         let c_path = self.inode_to_path(parent)?;
 
-        let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_NOFOLLOW | libc::O_CLOEXEC) };
+        let fd = retry_syscall(|| unsafe {
+            libc::open(c_path.as_ptr(), libc::O_NOFOLLOW | libc::O_CLOEXEC)
+        });
         if fd < 0 {
             return Err(io::Error::last_os_error());
         }
@@ -785,12 +1034,41 @@ impl PassthroughFs {
         unsafe { libc::close(fd) };
 
         if res == 0 {
-            Ok(())
+            self.sync_dir(parent)
         } else {
             Err(linux_error(io::Error::last_os_error()))
         }
     }
 
+    /// Accounts for the descriptor of a new handle in the budget of `Config::fd_client`, waiting
+    /// for it if the budget is short.
+    fn acquire_fd(&self) -> io::Result<Option<FdGrant>> {
+        self.cfg
+            .fd_client
+            .as_ref()
+            .map(|client| client.acquire(1).map_err(linux_error))
+            .transpose()
+    }
+
+    /// Counts a new handle in `Config::handle_quota`, failing with `EMFILE` past its limit.
+    fn acquire_handle(&self) -> io::Result<Option<HandleGrant>> {
+        self.cfg
+            .handle_quota
+            .as_ref()
+            .map(|quota| quota.acquire().map_err(linux_error))
+            .transpose()
+    }
+
+    /// Flushes the entries of the directory `dir` to the disk if `Config::durable` is set.
+    fn sync_dir(&self, dir: Inode) -> io::Result<()> {
+        if !self.cfg.durable {
+            return Ok(());
+        }
+
+        let c_path = self.inode_to_path(dir)?;
+        sync_dir_at(libc::AT_FDCWD, &c_path)
+    }
+
     fn parse_open_flags(&self, flags: i32) -> i32 {
         let mut mflags: i32 = flags & 0b11;
 
@@ -852,6 +1130,28 @@ fn set_secctx(file: StatFile, secctx: SecContext, symlink: bool) -> io::Result<(
     }
 }
 
+/// Removes `window`, at `guest_addr`, from the guest memory and unmaps it from the host.
+fn remove_window(
+    sender: &Sender<WorkerMessage>,
+    guest_addr: u64,
+    window: &Window,
+) -> io::Result<()> {
+    let (reply_sender, reply_receiver) = unbounded();
+    sender
+        .send(WorkerMessage::GpuRemoveMapping(
+            reply_sender,
+            guest_addr,
+            window.len,
+        ))
+        .unwrap();
+    if !reply_receiver.recv().unwrap() {
+        error!("Error requesting HVF the removal of a DAX window");
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    dax_windows::unmap_host(window)
+}
+
 fn forget_one(
     inodes: &mut MultikeyBTreeMap<Inode, InodeAltKey, Arc<InodeData>>,
     inode: Inode,
@@ -900,13 +1200,13 @@ impl FileSystem for PassthroughFs {
         // Safe because this doesn't modify any memory and we check the return value.
         // We use `O_PATH` because we just want this for traversing the directory tree
         // and not for actually reading the contents.
-        let fd = unsafe {
+        let fd = retry_syscall(|| unsafe {
             libc::openat(
                 libc::AT_FDCWD,
                 root.as_ptr(),
                 libc::O_NOFOLLOW | libc::O_CLOEXEC,
             )
-        };
+        });
         if fd < 0 {
             return Err(io::Error::last_os_error());
         }
@@ -935,6 +1235,7 @@ impl FileSystem for PassthroughFs {
                 ino: st.st_ino,
                 dev: st.st_dev,
                 refcount: AtomicU64::new(2),
+                btime: birth_time(&st),
             }),
         );
 
@@ -1059,6 +1360,7 @@ impl FileSystem for PassthroughFs {
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe { libc::mkdir(c_path.as_ptr(), 0o700) };
         if res == 0 {
+            self.keep_guest_name(parent, name);
             // Set security context
             if let Some(secctx) = extensions.secctx {
                 set_secctx(StatFile::Path(&c_path), secctx, false)?
@@ -1069,6 +1371,7 @@ impl FileSystem for PassthroughFs {
                 Some((ctx.uid, ctx.gid)),
                 Some(mode & !umask),
             )?;
+            self.sync_dir(parent)?;
             self.do_lookup(parent, name)
         } else {
             Err(linux_error(io::Error::last_os_error()))
@@ -1164,17 +1467,19 @@ impl FileSystem for PassthroughFs {
         } else {
             0o600
         };
+        let handle_grant = self.acquire_handle()?;
+        let fd_grant = self.acquire_fd()?;
 
         // Safe because this doesn't modify any memory and we check the return value. We don't
         // really check `flags` because if the kernel can't handle poorly specified flags then we
         // have much bigger problems.
-        let fd = unsafe {
+        let fd = retry_syscall(|| unsafe {
             libc::open(
                 c_path.as_ptr(),
                 flags | libc::O_CREAT | libc::O_CLOEXEC | libc::O_NOFOLLOW,
                 hostmode,
             )
-        };
+        });
         if fd < 0 {
             return Err(linux_error(io::Error::last_os_error()));
         }
@@ -1196,6 +1501,8 @@ impl FileSystem for PassthroughFs {
         // Safe because we just opened this fd.
         let file = RwLock::new(unsafe { File::from_raw_fd(fd) });
 
+        self.keep_guest_name(parent, name);
+        self.sync_dir(parent)?;
         let entry = self.do_lookup(parent, name)?;
 
         let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
@@ -1206,6 +1513,10 @@ impl FileSystem for PassthroughFs {
                 stream: 0,
                 offset: 0,
             }),
+            reads: Default::default(),
+            write_error: Default::default(),
+            _fd_grant: fd_grant,
+            _handle_grant: handle_grant,
         };
 
         self.handles.write().unwrap().insert(handle, Arc::new(data));
@@ -1261,7 +1572,17 @@ impl FileSystem for PassthroughFs {
         // This is safe because write_from uses preadv64, so the underlying file descriptor
         // offset is not affected by this operation.
         let f = data.file.read().unwrap();
-        w.write_from(&f, size as usize, offset)
+        let res = write_from_sparse(&mut w, &f, size as usize, offset);
+        if let Ok(read) = res {
+            page_cache::advise_read(
+                &data.reads,
+                self.cfg.page_cache,
+                f.as_raw_fd(),
+                offset,
+                read as u64,
+            );
+        }
+        res
     }
 
     fn write<R: io::Read + ZeroCopyReader>(
@@ -1273,7 +1594,7 @@ impl FileSystem for PassthroughFs {
         size: u32,
         offset: u64,
         _lock_owner: Option<u64>,
-        _delayed_write: bool,
+        delayed_write: bool,
         _kill_priv: bool,
         _flags: u32,
     ) -> io::Result<usize> {
@@ -1289,7 +1610,13 @@ impl FileSystem for PassthroughFs {
         // This is safe because read_to uses pwritev64, so the underlying file descriptor
         // offset is not affected by this operation.
         let f = data.file.read().unwrap();
-        r.read_to(&f, size as usize, offset)
+        let res = r.read_to(&f, size as usize, offset);
+        if let Err(e) = &res {
+            if delayed_write {
+                data.set_write_error(e);
+            }
+        }
+        res
     }
 
     fn getattr(
@@ -1301,6 +1628,18 @@ impl FileSystem for PassthroughFs {
         self.do_getattr(inode)
     }
 
+    fn statx(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        _handle: Option<Handle>,
+    ) -> io::Result<(bindings::stat64, Option<BirthTime>, Duration)> {
+        let (st, timeout) = self.do_getattr(inode)?;
+        let btime = get_birth_time(&self.inode_to_path(inode)?)?;
+
+        Ok((st, btime, timeout))
+    }
+
     fn setattr(
         &self,
         _ctx: Context,
@@ -1449,13 +1788,13 @@ impl FileSystem for PassthroughFs {
         let res = unsafe { libc::renamex_np(old_cpath.as_ptr(), new_cpath.as_ptr(), mflags) };
         if res == 0 {
             if ((flags as i32) & bindings::LINUX_RENAME_WHITEOUT) != 0 {
-                let fd = unsafe {
+                let fd = retry_syscall(|| unsafe {
                     libc::open(
                         old_cpath.as_ptr(),
                         libc::O_CREAT | libc::O_CLOEXEC | libc::O_NOFOLLOW,
                         0o600,
                     )
-                };
+                });
                 if fd > 0 {
                     if let Err(e) =
                         set_xattr_stat(StatFile::Fd(fd), None, Some((libc::S_IFCHR | 0o600) as u32))
@@ -1467,6 +1806,12 @@ impl FileSystem for PassthroughFs {
                 }
             }
 
+            self.keep_guest_name(newdir, newname);
+            self.sync_dir(olddir)?;
+            if olddir != newdir {
+                self.sync_dir(newdir)?;
+            }
+
             let entry = self.do_lookup(newdir, newname)?;
             self.forget(ctx, entry.inode, 1);
 
@@ -1488,13 +1833,13 @@ impl FileSystem for PassthroughFs {
     ) -> io::Result<Entry> {
         let c_path = self.name_to_path(parent, name)?;
 
-        let fd = unsafe {
+        let fd = retry_syscall(|| unsafe {
             libc::open(
                 c_path.as_ptr(),
                 libc::O_CREAT | libc::O_CLOEXEC | libc::O_NOFOLLOW,
                 0o600,
             )
-        };
+        });
         if fd < 0 {
             Err(linux_error(io::Error::last_os_error()))
         } else {
@@ -1513,6 +1858,8 @@ impl FileSystem for PassthroughFs {
             }
 
             unsafe { libc::close(fd) };
+            self.keep_guest_name(parent, name);
+            self.sync_dir(parent)?;
             self.do_lookup(parent, name)
         }
     }
@@ -1530,6 +1877,8 @@ impl FileSystem for PassthroughFs {
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe { libc::link(orig_c_path.as_ptr(), link_c_path.as_ptr()) };
         if res == 0 {
+            self.keep_guest_name(newparent, newname);
+            self.sync_dir(newparent)?;
             self.do_lookup(newparent, newname)
         } else {
             Err(linux_error(io::Error::last_os_error()))
@@ -1549,11 +1898,13 @@ impl FileSystem for PassthroughFs {
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe { libc::symlink(linkname.as_ptr(), c_path.as_ptr()) };
         if res == 0 {
+            self.keep_guest_name(parent, name);
             // Set security context
             if let Some(secctx) = extensions.secctx {
                 set_secctx(StatFile::Path(&c_path), secctx, true)?
             };
 
+            self.sync_dir(parent)?;
             let mut entry = self.do_lookup(parent, name)?;
             let mode = libc::S_IFLNK | 0o777;
             set_xattr_stat(
@@ -1615,11 +1966,15 @@ impl FileSystem for PassthroughFs {
             }
 
             if libc::close(newfd) < 0 {
-                Err(linux_error(io::Error::last_os_error()))
-            } else {
-                Ok(())
+                return Err(linux_error(io::Error::last_os_error()));
             }
         }
+
+        // Report the first delayed write error on this handle, if any, exactly once.
+        match data.take_write_error() {
+            Some(err) => Err(linux_error(err)),
+            None => Ok(()),
+        }
     }
 
     fn fsync(
@@ -1960,27 +2315,45 @@ impl FileSystem for PassthroughFs {
             .cloned()
             .ok_or_else(ebadf)?;
 
-        // SEEK_DATA and SEEK_HOLE have slightly different semantics
-        // in Linux vs. macOS, which means we can't support them.
-        let mwhence = if whence == 3 {
-            // SEEK_DATA
-            return Ok(offset);
-        } else if whence == 4 {
-            // SEEK_HOLE
-            libc::SEEK_END
-        } else {
-            whence as i32
-        };
-
-        let fd = data.file.write().unwrap().as_raw_fd();
+        let f = data.file.read().unwrap();
+        guest_seek(&f, offset, whence)
+    }
 
-        // Safe because this doesn't modify any memory and we check the return value.
-        let res = unsafe { libc::lseek(fd, offset as bindings::off64_t, mwhence as libc::c_int) };
-        if res < 0 {
-            Err(linux_error(io::Error::last_os_error()))
-        } else {
-            Ok(res as u64)
+    fn copyfilerange(
+        &self,
+        _ctx: Context,
+        inode_in: Inode,
+        handle_in: Handle,
+        offset_in: u64,
+        inode_out: Inode,
+        handle_out: Handle,
+        offset_out: u64,
+        len: u64,
+        flags: u64,
+    ) -> io::Result<usize> {
+        if flags != 0 {
+            return Err(einval());
         }
+
+        let handle_data = |inode, handle| {
+            self.handles
+                .read()
+                .unwrap()
+                .get(&handle)
+                .filter(|hd| hd.inode == inode)
+                .cloned()
+                .ok_or_else(ebadf)
+        };
+        let data_in = handle_data(inode_in, handle_in)?;
+        let data_out = handle_data(inode_out, handle_out)?;
+
+        copy_file_range(
+            &data_in.file.read().unwrap(),
+            offset_in,
+            &data_out.file.read().unwrap(),
+            offset_out,
+            len,
+        )
     }
 
     fn setupmapping(
@@ -2017,30 +2390,30 @@ impl FileSystem for PassthroughFs {
             inode, guest_addr, len
         );
 
+        // We've checked that map_sender is something above.
+        let sender = map_sender.as_ref().unwrap();
+
+        // The windows the guest holds are never taken back from it, so a mapping that doesn't fit
+        // in the bound once those it replaces are gone fails. The replaced ones are unmapped first.
+        let mut windows = self.map_windows.lock().unwrap();
+        if !windows.fits(guest_addr, len) {
+            return Err(linux_error(io::Error::from_raw_os_error(libc::ENOMEM)));
+        }
+        for (addr, window) in windows.remove(guest_addr, len).unwrap_or_default() {
+            remove_window(sender, addr, &window).map_err(linux_error)?;
+        }
+
         let file = self.open_inode(inode, libc::O_RDWR)?;
         let fd = file.as_raw_fd();
 
-        let host_addr = unsafe {
-            libc::mmap(
-                null_mut(),
-                len as usize,
-                prot_flags,
-                libc::MAP_SHARED,
-                fd,
-                foffset as libc::off_t,
-            )
-        };
-        if host_addr == libc::MAP_FAILED {
-            return Err(linux_error(io::Error::last_os_error()));
-        }
+        let host_addr = dax::map_file(null_mut(), len as usize, prot_flags, &file, foffset)
+            .map_err(linux_error)?;
 
         let ret = unsafe { libc::close(fd) };
         if ret == -1 {
             return Err(linux_error(io::Error::last_os_error()));
         }
 
-        // We've checked that map_sender is something above.
-        let sender = map_sender.as_ref().unwrap();
         let (reply_sender, reply_receiver) = unbounded();
         sender
             .send(WorkerMessage::GpuAddMapping(
@@ -2052,14 +2425,14 @@ impl FileSystem for PassthroughFs {
             .unwrap();
         if !reply_receiver.recv().unwrap() {
             error!("Error requesting HVF the addition of a DAX window");
+            dax::forget_mappings(host_addr, len as usize);
             unsafe { libc::munmap(host_addr, len as usize) };
             return Err(linux_error(io::Error::from_raw_os_error(libc::EINVAL)));
         }
 
-        self.map_windows
-            .lock()
-            .unwrap()
-            .insert(guest_addr, host_addr as u64);
+        let writable = prot_flags & libc::PROT_WRITE != 0;
+        let window = Window::new(host_addr as u64, len, inode, foffset, writable);
+        windows.insert(guest_addr, window);
 
         Ok(())
     }
@@ -2081,9 +2454,9 @@ impl FileSystem for PassthroughFs {
             if (req.moffset + req.len) > shm_size {
                 return Err(linux_error(io::Error::from_raw_os_error(libc::EINVAL)));
             }
-            let host_addr = match self.map_windows.lock().unwrap().remove(&guest_addr) {
-                Some(a) => a,
-                None => return Err(linux_error(io::Error::from_raw_os_error(libc::EINVAL))),
+            // A removal may span several windows, or part of a coalesced one
+            let Some(windows) = self.map_windows.lock().unwrap().remove(guest_addr, req.len) else {
+                return Err(linux_error(io::Error::from_raw_os_error(libc::EINVAL)));
             };
             debug!(
                 "removemapping: guest_addr={:x} len={:?}",
@@ -2091,23 +2464,8 @@ impl FileSystem for PassthroughFs {
             );
 
             let sender = map_sender.as_ref().unwrap();
-            let (reply_sender, reply_receiver) = unbounded();
-            sender
-                .send(WorkerMessage::GpuRemoveMapping(
-                    reply_sender,
-                    guest_addr,
-                    req.len,
-                ))
-                .unwrap();
-            if !reply_receiver.recv().unwrap() {
-                error!("Error requesting HVF the removal of a DAX window");
-                return Err(linux_error(io::Error::from_raw_os_error(libc::EINVAL)));
-            }
-
-            let ret = unsafe { libc::munmap(host_addr as *mut libc::c_void, req.len as usize) };
-            if ret == -1 {
-                error!("Error unmapping DAX window");
-                return Err(linux_error(io::Error::last_os_error()));
+            for (addr, window) in windows {
+                remove_window(sender, addr, &window).map_err(linux_error)?;
             }
         }
 
@@ -2117,12 +2475,12 @@ impl FileSystem for PassthroughFs {
     fn ioctl(
         &self,
         _ctx: Context,
-        _inode: Self::Inode,
+        inode: Self::Inode,
         _handle: Self::Handle,
         _flags: u32,
         cmd: u32,
         arg: u64,
-        _in_size: u32,
+        data: &[u8],
         _out_size: u32,
         exit_code: &Arc<AtomicI32>,
     ) -> io::Result<Vec<u8>> {
@@ -2135,6 +2493,25 @@ impl FileSystem for PassthroughFs {
                 exit_code.store(arg as i32, Ordering::SeqCst);
                 Ok(Vec::new())
             }
+            bindings::LINUX_FS_IOC_GETFLAGS => {
+                let file = self.open_inode_meta(inode)?;
+                Ok(get_file_flags(&file)?.to_ne_bytes().to_vec())
+            }
+            bindings::LINUX_FS_IOC_SETFLAGS => {
+                if !self.cfg.allow_file_flags {
+                    return Err(io::Error::from_raw_os_error(libc::EPERM));
+                }
+
+                let flags = data
+                    .get(..mem::size_of::<u32>())
+                    .and_then(|b| b.try_into().ok())
+                    .map(u32::from_ne_bytes)
+                    .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+
+                let file = self.open_inode_meta(inode)?;
+                set_file_flags(&file, flags)?;
+                Ok(Vec::new())
+            }
             _ => Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP)),
         }
     }
@@ -0,0 +1,424 @@
+//! Mirroring of the writable layer of a share to a second host directory.
+//!
+//! For resilience, an embedder may have the changes the guest makes to the writable layer of a
+//! share, the top layer of an overlay or the root directory of a passthrough share, replicated to
+//! another host directory, such as one on network storage. The mirror subscribes to the changes of
+//! the share like any watcher, and a replication thread brings each changed path of the mirror to
+//! the state it has in the layer, one change after the other in the order they were made.
+//!
+//! Replicating the state of a path rather than the request that changed it makes a change safe to
+//! replicate again, so one that failed, say while the network storage is away, is retried with a
+//! growing backoff before any later change is replicated. Before shutting down, the embedder
+//! drains the mirror to wait for it to catch up with the layer.
+
+use std::collections::{HashSet, VecDeque};
+use std::ffi::{CString, OsString};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::watch::{FsWatch, FsWatchEvent, FsWatchOp, FsWatcher};
+use crate::virtio::VmmExitObserver;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The prefix of the whiteouts of the top layer of an overlay.
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+
+/// The prefix of the files an overlay copies up into, before renaming them into place.
+const COPY_UP_STAGING_PREFIX: &[u8] = b".wh..wh..copyup.";
+
+/// The file of a mirror directory a file is copied into, before renaming it into place.
+const MIRROR_STAGING_NAME: &str = ".wh..wh..mirror";
+
+/// The wait before retrying a change that failed to replicate, doubled at each failure.
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The longest wait before retrying a change that failed to replicate.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How long the replication thread sleeps when there is no change to replicate, before checking
+/// whether the mirror is gone.
+const IDLE_WAIT: Duration = Duration::from_secs(1);
+
+/// How often a drain checks whether the changes not yet handed to the mirror were.
+const DRAIN_POLL: Duration = Duration::from_millis(10);
+
+/// How long the microVM waits for the mirror to catch up when it exits.
+const EXIT_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// Replicates the changes the guest makes to the writable layer of a share to a mirror directory.
+/// Clones share the same replication, so a handle obtained from the device before it is activated
+/// can be used to drain the mirror while the guest is running.
+#[derive(Clone)]
+pub struct FsMirror(Arc<MirrorState>);
+
+struct MirrorState {
+    /// The writable layer of the share.
+    source: PathBuf,
+    target: PathBuf,
+    watcher: FsWatcher,
+    queue: Mutex<MirrorQueue>,
+    changed: Condvar,
+    drained: Condvar,
+}
+
+#[derive(Default)]
+struct MirrorQueue {
+    /// The changes not replicated yet, the first one being replicated or retried.
+    events: VecDeque<FsWatchEvent>,
+    queued: HashSet<FsWatchEvent>,
+    /// Whether the replication thread is replicating the first change.
+    busy: bool,
+    /// The number of times a change failed to replicate.
+    failures: u64,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl FsMirror {
+    /// Mirrors the layer `source` to the host directory `target`, created if needed, starting
+    /// with the changes `watcher` reports from now on.
+    pub(crate) fn new(source: PathBuf, target: PathBuf, watcher: FsWatcher) -> Self {
+        let mirror = FsMirror(Arc::new(MirrorState {
+            source,
+            target,
+            watcher: watcher.clone(),
+            queue: Mutex::new(MirrorQueue::default()),
+            changed: Condvar::new(),
+            drained: Condvar::new(),
+        }));
+
+        let state = Arc::downgrade(&mirror.0);
+        thread::Builder::new()
+            .name("fs mirror".into())
+            .spawn(move || replicate(state))
+            .unwrap();
+
+        // The watcher mustn't keep the mirror alive, which holds the watcher
+        let state = Arc::downgrade(&mirror.0);
+        watcher.watch(FsWatch {
+            path: PathBuf::new(),
+            callback: Arc::new(move |event| {
+                if let Some(state) = state.upgrade() {
+                    state.record(event);
+                }
+            }),
+        });
+
+        mirror
+    }
+
+    /// Returns the mirror directory.
+    pub fn target(&self) -> &Path {
+        &self.0.target
+    }
+
+    /// Returns the number of changes not replicated yet.
+    pub fn pending(&self) -> usize {
+        self.0.queue.lock().unwrap().events.len()
+    }
+
+    /// Returns the number of times a change failed to replicate, each of them being retried.
+    pub fn failures(&self) -> u64 {
+        self.0.queue.lock().unwrap().failures
+    }
+
+    /// Waits for the mirror to catch up with the changes the guest made so far, for at most
+    /// `timeout`. Returns whether it did.
+    pub fn drain(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut queue = self.0.queue.lock().unwrap();
+        loop {
+            if queue.events.is_empty() && !queue.busy && self.0.watcher.is_idle() {
+                return true;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return false;
+            }
+
+            // The changes the watcher holds back don't wake the drain up, so it polls for them
+            queue = self
+                .0
+                .drained
+                .wait_timeout(queue, remaining.min(DRAIN_POLL))
+                .unwrap()
+                .0;
+        }
+    }
+}
+
+impl MirrorState {
+    /// Queues a change for replication, unless the same change is already waiting.
+    fn record(&self, event: &FsWatchEvent) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.queued.insert(event.clone()) {
+            queue.events.push_back(event.clone());
+            self.changed.notify_one();
+        }
+    }
+
+    /// Brings the path of `event` of the mirror to the state it has in the layer, along with the
+    /// whiteout removing or renaming an entry of a lower layer left in its place.
+    fn sync_event(&self, event: &FsWatchEvent) -> io::Result<()> {
+        let recursive = matches!(event.op, FsWatchOp::Create | FsWatchOp::RenameTo);
+        sync_path(&self.source, &self.target, &event.path, recursive)?;
+
+        if let (Some(parent), Some(name)) = (event.path.parent(), event.path.file_name()) {
+            let mut whiteout = OsString::from(std::ffi::OsStr::from_bytes(WHITEOUT_PREFIX));
+            whiteout.push(name);
+            sync_path(&self.source, &self.target, &parent.join(whiteout), false)?;
+        }
+        Ok(())
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Trait Implementations
+//--------------------------------------------------------------------------------------------------
+
+impl fmt::Debug for FsMirror {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FsMirror")
+            .field("source", &self.0.source)
+            .field("target", &self.0.target)
+            .finish_non_exhaustive()
+    }
+}
+
+impl VmmExitObserver for FsMirror {
+    fn on_vmm_exit(&mut self) {
+        if !self.drain(EXIT_DRAIN_TIMEOUT) {
+            warn!(
+                "virtio-fs: {} changes weren't mirrored to {:?} before exiting",
+                self.pending(),
+                self.0.target
+            );
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// The body of the replication thread, which exits once the mirror is dropped.
+fn replicate(state: Weak<MirrorState>) {
+    let mut backoff = MIN_BACKOFF;
+    while let Some(state) = state.upgrade() {
+        let mut queue = state.queue.lock().unwrap();
+        let Some(event) = queue.events.front().cloned() else {
+            let _ = state.changed.wait_timeout(queue, IDLE_WAIT).unwrap();
+            continue;
+        };
+        queue.busy = true;
+        drop(queue);
+
+        let res = state.sync_event(&event);
+
+        let mut queue = state.queue.lock().unwrap();
+        queue.busy = false;
+        match res {
+            Ok(()) => {
+                queue.events.pop_front();
+                queue.queued.remove(&event);
+                backoff = MIN_BACKOFF;
+                state.drained.notify_all();
+            }
+            Err(e) => {
+                queue.failures += 1;
+                drop(queue);
+                if backoff == MIN_BACKOFF {
+                    warn!(
+                        "virtio-fs: failed to mirror {:?} to {:?}, retrying: {e}",
+                        event.path, state.target
+                    );
+                }
+                drop(state);
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Brings `path` of the mirror `target` to the state it has in the layer `source`, with all the
+/// entries below it if `recursive`. The directories leading to it are created first if missing.
+fn sync_path(source: &Path, target: &Path, path: &Path, recursive: bool) -> io::Result<()> {
+    fs::create_dir_all(target)?;
+
+    let mut ancestor = PathBuf::new();
+    for component in path.parent().into_iter().flat_map(Path::components) {
+        ancestor.push(component);
+        let is_dir = fs::symlink_metadata(target.join(&ancestor)).is_ok_and(|m| m.is_dir());
+        if !is_dir {
+            sync_entry(&source.join(&ancestor), &target.join(&ancestor), false)?;
+        }
+    }
+
+    sync_entry(&source.join(path), &target.join(path), recursive)
+}
+
+/// Copies the entry `src` of the layer to `dst`, or removes `dst` if `src` is gone.
+fn sync_entry(src: &Path, dst: &Path, recursive: bool) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(src) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return remove(dst),
+        Err(e) => return Err(e),
+    };
+    let existing = fs::symlink_metadata(dst).ok();
+    let file_type = metadata.file_type();
+
+    if file_type.is_dir() {
+        if !existing.is_some_and(|m| m.is_dir()) {
+            remove(dst)?;
+            fs::create_dir(dst)?;
+        }
+        if recursive {
+            let mut names = HashSet::new();
+            for entry in fs::read_dir(src)? {
+                let name = entry?.file_name();
+                if !name.as_bytes().starts_with(COPY_UP_STAGING_PREFIX) {
+                    sync_entry(&src.join(&name), &dst.join(&name), true)?;
+                    names.insert(name);
+                }
+            }
+            for entry in fs::read_dir(dst)? {
+                let name = entry?.file_name();
+                if !names.contains(&name) {
+                    remove(&dst.join(name))?;
+                }
+            }
+        }
+    } else if file_type.is_file() {
+        if existing.is_some_and(|m| m.is_dir()) {
+            remove(dst)?;
+        }
+        // Renamed into place so that the mirror never holds a partial copy of the file
+        let staging = dst.with_file_name(MIRROR_STAGING_NAME);
+        fs::copy(src, &staging)?;
+        fs::rename(&staging, dst)?;
+    } else if file_type.is_symlink() {
+        let link = fs::read_link(src)?;
+        remove(dst)?;
+        std::os::unix::fs::symlink(link, dst)?;
+    } else {
+        debug!("virtio-fs: not mirroring the special file {src:?}");
+        return remove(dst);
+    }
+
+    set_attributes(dst, &metadata)
+}
+
+/// Gives `dst` the owner, mode and times of `metadata`. The owner is best effort, as the device
+/// may not be allowed to give files away, and is set before the mode, which it would otherwise
+/// clear the set-user-ID and set-group-ID bits of.
+fn set_attributes(dst: &Path, metadata: &fs::Metadata) -> io::Result<()> {
+    let _ = std::os::unix::fs::lchown(dst, Some(metadata.uid()), Some(metadata.gid()));
+    if !metadata.file_type().is_symlink() {
+        fs::set_permissions(dst, fs::Permissions::from_mode(metadata.mode() & 0o7777))?;
+    }
+
+    let path = CString::new(dst.as_os_str().as_bytes())?;
+    let times = [
+        libc::timespec {
+            tv_sec: metadata.atime() as _,
+            tv_nsec: metadata.atime_nsec() as _,
+        },
+        libc::timespec {
+            tv_sec: metadata.mtime() as _,
+            tv_nsec: metadata.mtime_nsec() as _,
+        },
+    ];
+    // Safe because this doesn't modify any memory and we check the return value.
+    let res = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Removes `path` and, if it is a directory, everything below it.
+fn remove(path: &Path) -> io::Result<()> {
+    let res = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) => Err(e),
+    };
+    match res {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mirror() {
+        let source = tempfile::tempdir().unwrap();
+        let target = tempfile::tempdir().unwrap();
+        let watcher = FsWatcher::new(Duration::from_millis(10));
+        let mirror = FsMirror::new(
+            source.path().to_path_buf(),
+            target.path().join("mirror"),
+            watcher.clone(),
+        );
+        let mirrored = |path: &str| target.path().join("mirror").join(path);
+
+        // A directory created with its content, and the directories leading to a written file
+        fs::create_dir_all(source.path().join("a/b")).unwrap();
+        fs::write(source.path().join("a/b/file"), b"hello").unwrap();
+        fs::write(source.path().join("a/b/.wh..wh..copyup.12"), b"").unwrap();
+        fs::create_dir_all(source.path().join("c/d")).unwrap();
+        fs::write(source.path().join("c/d/file"), b"x").unwrap();
+        std::os::unix::fs::symlink("b/file", source.path().join("a/link")).unwrap();
+        watcher.record(PathBuf::from("a"), FsWatchOp::Create);
+        watcher.record(PathBuf::from("c/d/file"), FsWatchOp::Write);
+        assert!(mirror.drain(Duration::from_secs(10)));
+        assert_eq!(fs::read(mirrored("a/b/file")).unwrap(), b"hello");
+        assert_eq!(fs::read(mirrored("c/d/file")).unwrap(), b"x");
+        assert_eq!(
+            fs::read_link(mirrored("a/link")).unwrap(),
+            PathBuf::from("b/file")
+        );
+        assert!(!mirrored("a/b/.wh..wh..copyup.12").exists());
+
+        // A removal, which leaves a whiteout in the top layer of an overlay
+        fs::remove_file(source.path().join("c/d/file")).unwrap();
+        fs::write(source.path().join("c/d/.wh.file"), b"").unwrap();
+        fs::set_permissions(source.path().join("a"), fs::Permissions::from_mode(0o700)).unwrap();
+        watcher.record(PathBuf::from("c/d/file"), FsWatchOp::Remove);
+        watcher.record(PathBuf::from("a"), FsWatchOp::Attrib);
+        assert!(mirror.drain(Duration::from_secs(10)));
+        assert!(!mirrored("c/d/file").exists());
+        assert!(mirrored("c/d/.wh.file").exists());
+        let mode = fs::metadata(mirrored("a")).unwrap().mode();
+        assert_eq!(mode & 0o7777, 0o700);
+        assert_eq!(mirror.pending(), 0);
+        assert_eq!(mirror.failures(), 0);
+    }
+}
@@ -1,11 +1,54 @@
+mod case_names;
+mod coalesce;
+mod content_store;
+mod copy_up;
+mod credentials;
+mod dax;
+#[cfg(any(target_os = "macos", test))]
+mod dax_windows;
+mod degrade;
 mod device;
+mod dir_template;
 #[allow(dead_code)]
 mod filesystem;
+mod handle_quota;
+mod hooks;
+mod host_metadata;
+mod init_config;
+mod inode_map;
+mod inode_numbers;
+mod inspect;
+mod intent_log;
+mod revalidate;
 mod server;
+mod snapshot;
 pub mod fuse;
+mod inode_path;
 mod kinds;
+mod layer_diff;
+mod layer_filter;
+mod layer_manifest;
+mod layer_paths;
+mod layer_stats;
+mod lease;
+mod lower_layers;
+mod mirror;
+mod mode_policy;
 #[allow(dead_code)]
 mod multikey;
+#[cfg(feature = "oci")]
+mod oci;
+mod p9;
+mod page_cache;
+mod pause;
+mod prealloc;
+mod retry;
+mod trace;
+mod unicode_names;
+mod unsupported;
+mod virtual_file;
+mod watch;
+mod whiteout_probe;
 mod worker;
 
 #[cfg(target_os = "linux")]
@@ -29,9 +72,28 @@ pub use macos::passthrough;
 use super::bindings;
 use super::descriptor_utils;
 
+pub use self::credentials::{
+    CredentialMapper, FsCredentials, FsIdMap, FsIdRange, FsIdentity, FsSquashAll, FS_OVERFLOW_ID,
+};
+pub use self::dax::zero_fill_truncated_mappings;
 pub use self::defs::uapi::VIRTIO_ID_FS as TYPE_FS;
+pub use self::degrade::{FsDegradation, FsDegradeCallback, FsDegradeOptions};
 pub use self::device::Fs;
+pub use self::dir_template::FsDirTemplate;
 pub use self::filesystem::ExportTable;
+pub use self::handle_quota::FsHandleQuota;
+pub use self::layer_stats::FsLayerStats;
+pub use self::hooks::{FsHook, FsHookEvent, FsHookFn, FsHookPoint, FsHooks};
+pub use self::lower_layers::LowerLayerSet;
+pub use self::mirror::FsMirror;
+#[cfg(feature = "oci")]
+pub use self::oci::{LazyLayer, OciImage};
+pub use self::pause::{FsPause, FsPauseOptions, FsPauseTimeout};
+pub use self::retry::FsRetryStats;
+pub use self::trace::FsTracer;
+pub use self::unsupported::FsUnsupportedStats;
+pub use self::virtual_file::{FsVirtualAttr, FsVirtualFile, FsVirtualGetattrFn, FsVirtualReadFn};
+pub use self::watch::{FsWatch, FsWatchCallback, FsWatchEvent, FsWatchOp, FsWatcher};
 
 mod defs {
     pub const FS_DEV_ID: &str = "virtio_fs";
@@ -41,9 +103,33 @@ mod defs {
     pub const HPQ_INDEX: usize = 0;
     // Request queue.
     pub const REQ_INDEX: usize = 1;
+    // Notification queue, which comes before the request queue if the guest accepted it.
+    pub const NOTIFY_INDEX: usize = 1;
+    // Feature bit of the notification queue.
+    pub const VIRTIO_FS_F_NOTIFICATION: u64 = 0;
+    // Feature bit of the mount hints following the standard configuration space, a libkrun
+    // extension. It takes the last device-specific bit, away from the ones the specification
+    // assigns next.
+    pub const VIRTIO_FS_F_MOUNT_HINTS: u64 = 23;
+    // Flags of the mount hints: the share has a DAX window, the device caches as the `Never` or
+    // `Always` cache policy (neither is `Auto`), and the guest mounts the share at boot.
+    pub const MOUNT_HINT_DAX: u32 = 1 << 0;
+    pub const MOUNT_HINT_CACHE_NEVER: u32 = 1 << 1;
+    pub const MOUNT_HINT_CACHE_ALWAYS: u32 = 1 << 2;
+    pub const MOUNT_HINT_AUTO_MOUNT: u32 = 1 << 3;
+    // Size of the NUL-terminated path and options of the mount hints.
+    pub const MOUNT_HINT_LEN: usize = 256;
+    // Feature bit of the mount tag in the configuration space of a virtio-9p device.
+    pub const VIRTIO_9P_MOUNT_TAG: u64 = 0;
+    // Maximum time a completed request may wait in the used ring before it's published.
+    pub const MAX_USED_BATCH_LATENCY: std::time::Duration = std::time::Duration::from_micros(500);
+    // Time between the tries of the requests waiting for a lock, which the processes of the host
+    // may release without the device knowing.
+    pub const LOCK_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_millis(10);
 
     pub mod uapi {
         pub const VIRTIO_ID_FS: u32 = 26;
+        pub const VIRTIO_ID_9P: u32 = 9;
     }
 }
 
@@ -74,6 +160,9 @@ pub enum FsError {
     InvalidXattrSize((u32, usize)),
     QueueReader(DescriptorError),
     QueueWriter(DescriptorError),
+    /// The request waits for a lock held by another owner. It wasn't replied to, and must be
+    /// handled again once the lock may have been released.
+    WouldBlock,
 }
 
 type Result<T> = std::result::Result<T, FsError>;
@@ -0,0 +1,158 @@
+//! The host modes of the entries the guest creates, restricted by the mode policy of the share.
+//!
+//! With a [`FsModePolicy`], the server replaces the mode the guest asks for when it creates an
+//! entry with the default mode of the policy, if any, and clears the bits of the umask of the
+//! policy from it, as well as from the modes the guest later sets with `chmod`. The entries then
+//! land on the host with the modes the host security policy allows, whatever the guest does.
+//!
+//! The guest keeps seeing the modes it asked for: the server remembers them, by the host device
+//! and inode numbers of the entries, and reports them in place of the host ones for as long as
+//! the host mode of an entry is the one the server gave it. An entry whose mode was changed on the
+//! host is reported with its host mode again. The modes are only remembered while the device runs,
+//! so an entry created before a restart of the device is reported with its host mode.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use super::kinds::FsModePolicy;
+use crate::virtio::bindings;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The permission bits of a mode, with the set-id and sticky ones.
+const PERM_BITS: u32 = 0o7777;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// The modes the guest of a share asked for, kept across the servers of the share a pause may
+/// drop. Cloning it gives another handle to the same modes.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct GuestModes(Option<Arc<PolicyModes>>);
+
+#[derive(Debug)]
+struct PolicyModes {
+    policy: FsModePolicy,
+    /// The host and the guest permission bits of each entry whose mode the policy changed, by
+    /// its host device and inode numbers.
+    modes: Mutex<HashMap<(u64, u64), (u32, u32)>>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl GuestModes {
+    pub(crate) fn new(policy: Option<FsModePolicy>) -> Self {
+        GuestModes(policy.map(|policy| {
+            Arc::new(PolicyModes {
+                policy,
+                modes: Default::default(),
+            })
+        }))
+    }
+
+    /// Returns the mode to create an entry with on the host, for the guest asking for `mode`, which
+    /// holds the file type of the entry unless it is a directory.
+    pub(crate) fn create_mode(&self, mode: u32, is_dir: bool) -> u32 {
+        let Some(modes) = &self.0 else {
+            return mode;
+        };
+        let policy = &modes.policy;
+        let default = if is_dir {
+            policy.dir_mode
+        } else {
+            policy.file_mode
+        };
+        let perms = default.unwrap_or(mode) & PERM_BITS & !policy.umask;
+        (mode & !PERM_BITS) | perms
+    }
+
+    /// Returns the mode to set on the host, for the guest setting `mode`.
+    pub(crate) fn chmod_mode(&self, mode: u32) -> u32 {
+        match &self.0 {
+            Some(modes) => mode & !(modes.policy.umask & PERM_BITS),
+            None => mode,
+        }
+    }
+
+    /// Remembers that the entry with the host attributes `st` has the permission bits of `mode`
+    /// for the guest.
+    pub(crate) fn set(&self, st: &bindings::stat64, mode: u32) {
+        let Some(modes) = &self.0 else {
+            return;
+        };
+        let key = host_key(st);
+        let host = perms(st);
+        let guest = mode & PERM_BITS;
+        let mut map = modes.modes.lock().unwrap();
+        if host == guest {
+            map.remove(&key);
+        } else {
+            map.insert(key, (host, guest));
+        }
+    }
+
+    /// Replaces the permission bits of the host attributes `st` with the ones the guest asked for,
+    /// unless the mode of the entry was changed on the host since.
+    pub(crate) fn guest_attr(&self, st: &mut bindings::stat64) {
+        let Some(modes) = &self.0 else {
+            return;
+        };
+        let key = host_key(st);
+        let mut map = modes.modes.lock().unwrap();
+        let Some((host, guest)) = map.get(&key).copied() else {
+            return;
+        };
+        if perms(st) != host {
+            map.remove(&key);
+            return;
+        }
+        st.st_mode = (st.st_mode & !(PERM_BITS as libc::mode_t)) | guest as libc::mode_t;
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Returns the host device and inode numbers of the entry with the attributes `st`.
+#[allow(clippy::unnecessary_cast)] // The types of the fields differ between hosts
+fn host_key(st: &bindings::stat64) -> (u64, u64) {
+    (st.st_dev as u64, st.st_ino as u64)
+}
+
+/// Returns the permission bits of the mode of the attributes `st`.
+#[allow(clippy::useless_conversion)] // We need this conversion on macOS
+fn perms(st: &bindings::stat64) -> u32 {
+    u32::from(st.st_mode) & PERM_BITS
+}
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn create_mode() {
+        let modes = GuestModes::new(Some(FsModePolicy {
+            umask: 0o077,
+            file_mode: None,
+            dir_mode: Some(0o750),
+        }));
+        assert_eq!(modes.create_mode(0o644, false), 0o600);
+        assert_eq!(modes.create_mode(0o777, true), 0o700);
+        assert_eq!(modes.chmod_mode(0o4755), 0o4700);
+
+        // Without a policy, the guest modes are kept as they are
+        let modes = GuestModes::default();
+        assert_eq!(modes.create_mode(0o644, false), 0o644);
+        assert_eq!(modes.chmod_mode(0o777), 0o777);
+    }
+}
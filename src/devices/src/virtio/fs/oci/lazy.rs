@@ -0,0 +1,315 @@
+//! Layers read lazily from their blobs.
+//!
+//! Unpacking every layer of an image before booting it takes as long as decompressing and writing
+//! all of its files, most of which the guest never reads. A lazy layer is instead indexed when the
+//! overlay using it is created: its blob is decompressed once, to check its digest and to build
+//! the directory of the layer, with its directories, links, device nodes and whiteouts as an
+//! unpacked layer has them, but with each regular file an empty placeholder of the right size and
+//! attributes. The data of a file is only extracted into its placeholder when the overlay first
+//! copies it up, which it does before reading it.
+//!
+//! A gzip stream can't be read from the middle, so extracting a file decompresses the blob up to
+//! it. The decompressed stream is kept where the last extraction left it, so that extracting files
+//! in the order of the archive decompresses the blob once. The blob is trusted to stay the one
+//! that was indexed, as the cache of unpacked layers trusts its layers.
+//!
+//! The directory of a layer is `<digest>` in the cache, next to the index of its placeholders,
+//! `<digest>.index`, and to the log of the ones extracted so far, `<digest>.filled`, so that the
+//! cache outlives the process like the one of unpacked layers does.
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsStr};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use super::{invalid, remove_tree, set_mtime, Layer, TMP_PREFIX};
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The suffix of the index of the placeholders of a layer.
+const INDEX_SUFFIX: &str = ".index";
+
+/// The suffix of the log of the placeholders extracted.
+const FILLED_SUFFIX: &str = ".filled";
+
+/// The size of the fixed part of a record of the index: the offset, size, mode and modification
+/// time of a placeholder, and the length of its path.
+const RECORD_SIZE: usize = 32;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// The placeholders of a layer, by path in the layer.
+type Placeholders = HashMap<Vec<u8>, Placeholder>;
+
+/// A lower layer of an overlay read lazily from its blob.
+pub struct LazyLayer {
+    layer: Layer,
+
+    /// The directory of the layer.
+    dir: PathBuf,
+
+    /// The placeholders, once the layer is indexed.
+    placeholders: OnceLock<Placeholders>,
+
+    state: Mutex<State>,
+}
+
+/// A regular file of a lazy layer, whose data is extracted when first read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) struct Placeholder {
+    /// The offset of the data in the tar of the layer, which also identifies the file among its
+    /// hard links.
+    pub(super) offset: u64,
+    pub(super) size: u64,
+    pub(super) mode: u32,
+    pub(super) mtime: i64,
+}
+
+#[derive(Default)]
+struct State {
+    /// The offsets of the placeholders extracted.
+    filled: HashSet<u64>,
+
+    /// The tar of the layer, and the offset it was read up to, by the last extraction.
+    cursor: Option<(Box<dyn Read + Send>, u64)>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl LazyLayer {
+    pub(super) fn new(layer: Layer, cache: &Path) -> Self {
+        LazyLayer {
+            dir: cache.join(&layer.digest),
+            layer,
+            placeholders: OnceLock::new(),
+            state: Default::default(),
+        }
+    }
+
+    /// Returns the directory of the layer, which exists once it is indexed.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Indexes the layer unless done already, building its directory if it isn't in the cache.
+    pub fn index(&self) -> io::Result<()> {
+        if self.placeholders.get().is_some() {
+            return Ok(());
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if self.placeholders.get().is_some() {
+            return Ok(());
+        }
+        let (placeholders, filled) = match self.load() {
+            Ok(loaded) => loaded,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (self.build()?, HashSet::new()),
+            Err(e) => {
+                warn!("rebuilding the layer {}: {e}", self.dir.display());
+                (self.build()?, HashSet::new())
+            }
+        };
+        state.filled = filled;
+        let _ = self.placeholders.set(placeholders);
+        Ok(())
+    }
+
+    /// Extracts the data of the regular file at `path` in the layer into its placeholder, unless
+    /// done already. The other entries have no data to extract.
+    pub fn materialize(&self, path: &[u8]) -> io::Result<()> {
+        let Some(placeholder) = self
+            .placeholders
+            .get()
+            .and_then(|placeholders| placeholders.get(path))
+            .copied()
+        else {
+            return Ok(());
+        };
+
+        let mut state = self.state.lock().unwrap();
+        if state.filled.contains(&placeholder.offset) {
+            return Ok(());
+        }
+
+        // The mode of the placeholder may not let it be written, and writing it clears its
+        // set-user-ID and set-group-ID bits, so it is restored along with its time after
+        let file_path = self.dir.join(OsStr::from_bytes(path));
+        fs::set_permissions(&file_path, fs::Permissions::from_mode(0o600))?;
+        let res = Self::fill(&self.layer, &mut state, &file_path, &placeholder);
+        fs::set_permissions(&file_path, fs::Permissions::from_mode(placeholder.mode))?;
+        set_mtime(
+            &CString::new(file_path.as_os_str().as_bytes())?,
+            placeholder.mtime,
+        )?;
+        res?;
+
+        // A crash before this is logged extracts the data again
+        OpenOptions::new()
+            .append(true)
+            .open(self.sibling(FILLED_SUFFIX))?
+            .write_all(&placeholder.offset.to_le_bytes())?;
+        state.filled.insert(placeholder.offset);
+        debug!(
+            "extracted {} of the layer {}",
+            String::from_utf8_lossy(path),
+            self.dir.display()
+        );
+        Ok(())
+    }
+
+    /// Writes the data of `placeholder` into the file at `path`, reading the tar of `layer` on
+    /// from where the last extraction left it if it isn't past the data.
+    fn fill(
+        layer: &Layer,
+        state: &mut State,
+        path: &Path,
+        placeholder: &Placeholder,
+    ) -> io::Result<()> {
+        let (mut tar, pos) = match state.cursor.take() {
+            Some((tar, pos)) if pos <= placeholder.offset => (tar, pos),
+            _ => (layer.tar()?, 0),
+        };
+
+        let skip = placeholder.offset - pos;
+        if io::copy(&mut (&mut tar).take(skip), &mut io::sink())? < skip {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        if io::copy(&mut (&mut tar).take(placeholder.size), &mut file)? < placeholder.size {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        file.sync_data()?;
+
+        state.cursor = Some((tar, placeholder.offset + placeholder.size));
+        Ok(())
+    }
+
+    /// Reads the index of the layer and the log of its placeholders extracted.
+    fn load(&self) -> io::Result<(Placeholders, HashSet<u64>)> {
+        if !fs::symlink_metadata(&self.dir)?.is_dir() {
+            return Err(invalid("not a directory"));
+        }
+
+        let mut index = Vec::new();
+        File::open(self.sibling(INDEX_SUFFIX))?.read_to_end(&mut index)?;
+        let placeholders = decode_index(&index)?;
+
+        let mut log = Vec::new();
+        File::open(self.sibling(FILLED_SUFFIX))?.read_to_end(&mut log)?;
+        // An offset only partly written was not logged
+        let filled = log
+            .chunks_exact(8)
+            .map(|offset| u64::from_le_bytes(offset.try_into().unwrap()))
+            .collect();
+        Ok((placeholders, filled))
+    }
+
+    /// Builds the directory of the layer, failing if the layer doesn't match its digest, and
+    /// returns its placeholders. The directory is built aside and renamed into place once its
+    /// index is written, so that an interrupted build leaves no directory behind.
+    fn build(&self) -> io::Result<Placeholders> {
+        let parent = self.dir.parent().unwrap();
+        let tmp = parent.join(format!(
+            "{TMP_PREFIX}{}-{}",
+            self.layer.digest,
+            std::process::id()
+        ));
+        if tmp.exists() {
+            remove_tree(&tmp)?;
+        }
+        fs::create_dir(&tmp)?;
+
+        let mut placeholders = HashMap::new();
+        let result = self
+            .layer
+            .extract(&tmp, Some(&mut placeholders))
+            .and_then(|_| {
+                let index = self.sibling(INDEX_SUFFIX);
+                let index_tmp = tmp.with_extension("index");
+                fs::write(&index_tmp, encode_index(&placeholders))?;
+                fs::rename(&index_tmp, index)?;
+                File::create(self.sibling(FILLED_SUFFIX))?;
+                if fs::symlink_metadata(&self.dir).is_ok() {
+                    remove_tree(&self.dir)?;
+                }
+                fs::rename(&tmp, &self.dir)
+            });
+        if let Err(e) = result {
+            let _ = remove_tree(&tmp);
+            return Err(e);
+        }
+        Ok(placeholders)
+    }
+
+    /// Returns the path of the file next to the directory of the layer with the suffix `suffix`.
+    fn sibling(&self, suffix: &str) -> PathBuf {
+        let mut path = self.dir.clone().into_os_string();
+        path.push(suffix);
+        PathBuf::from(path)
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Trait Implementations
+//--------------------------------------------------------------------------------------------------
+
+impl fmt::Debug for LazyLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LazyLayer")
+            .field("layer", &self.layer)
+            .field("dir", &self.dir)
+            .finish_non_exhaustive()
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+fn encode_index(placeholders: &Placeholders) -> Vec<u8> {
+    let mut index = Vec::new();
+    for (path, placeholder) in placeholders {
+        index.extend_from_slice(&placeholder.offset.to_le_bytes());
+        index.extend_from_slice(&placeholder.size.to_le_bytes());
+        index.extend_from_slice(&placeholder.mode.to_le_bytes());
+        index.extend_from_slice(&placeholder.mtime.to_le_bytes());
+        index.extend_from_slice(&(path.len() as u32).to_le_bytes());
+        index.extend_from_slice(path);
+    }
+    index
+}
+
+fn decode_index(mut index: &[u8]) -> io::Result<Placeholders> {
+    let mut placeholders = HashMap::new();
+    while !index.is_empty() {
+        if index.len() < RECORD_SIZE {
+            return Err(invalid("truncated index"));
+        }
+        let (record, rest) = index.split_at(RECORD_SIZE);
+        let placeholder = Placeholder {
+            offset: u64::from_le_bytes(record[0..8].try_into().unwrap()),
+            size: u64::from_le_bytes(record[8..16].try_into().unwrap()),
+            mode: u32::from_le_bytes(record[16..20].try_into().unwrap()),
+            mtime: i64::from_le_bytes(record[20..28].try_into().unwrap()),
+        };
+        let len = u32::from_le_bytes(record[28..32].try_into().unwrap()) as usize;
+        if rest.len() < len {
+            return Err(invalid("truncated index"));
+        }
+        let (path, rest) = rest.split_at(len);
+        placeholders.insert(path.to_vec(), placeholder);
+        index = rest;
+    }
+    Ok(placeholders)
+}
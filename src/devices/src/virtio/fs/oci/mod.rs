@@ -0,0 +1,1227 @@
+//! Overlays built from OCI images.
+//!
+//! An image is read from a local OCI image layout, the directory `skopeo copy` or `buildah push`
+//! write with `oci:`, or from an uncompressed docker archive, the file `docker save` writes. The
+//! manifest for the platform of the host is picked from the index of a multi-platform layout.
+//!
+//! Each layer is unpacked once into a cache directory shared by all the images, named after the
+//! SHA-256 digest the image gives for it: the digest of the blob of an OCI layer, and that of the
+//! uncompressed tar of a docker one. The digest is checked while unpacking, and a layer that
+//! doesn't match it is discarded, so the cache only ever holds layers that are what the image says.
+//! A layer is unpacked into a temporary directory renamed into place once complete, so concurrent
+//! unpacks of the same layer are harmless and an interrupted one leaves nothing behind. The
+//! whiteouts of the layers are kept as they are, in the format the overlay understands.
+//!
+//! Unless unpacked as root, the files are owned by the user unpacking them, and device nodes and
+//! the extended attributes outside the `user.` namespace are skipped.
+//!
+//! The layers can also be left packed, and read lazily by the overlay, see `lazy`.
+
+mod lazy;
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{CStr, CString, OsStr};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use flate2::read::MultiGzDecoder;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tar::{Archive, EntryType};
+
+pub use self::lazy::LazyLayer;
+use self::lazy::Placeholder;
+use super::kinds::FsImplShare;
+use super::overlayfs::UpperLayer;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The media types of the indexes pointing to the manifests of the platforms of an image.
+const INDEX_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+];
+
+/// The deepest nesting of indexes followed to a manifest.
+const MAX_INDEX_DEPTH: usize = 4;
+
+/// The largest index, manifest or configuration read, to bound the memory of a malformed image.
+const MAX_DOCUMENT_SIZE: u64 = 4 << 20;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// The prefix of the directories layers are unpacked into before being renamed into place.
+const TMP_PREFIX: &str = ".tmp-";
+
+/// The prefix of the pax records of extended attributes.
+const PAX_XATTR_PREFIX: &[u8] = b"SCHILY.xattr.";
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// An image whose layers make the lower layers of an overlay.
+pub struct OciImage {
+    /// The layers, from the bottom one to the top one.
+    layers: Vec<Layer>,
+}
+
+#[derive(Clone, Debug)]
+struct Layer {
+    source: LayerSource,
+    /// The hexadecimal SHA-256 digest of the layer, of what `digest_of` says.
+    digest: String,
+    digest_of: DigestOf,
+}
+
+#[derive(Clone, Debug)]
+enum LayerSource {
+    Blob(PathBuf),
+    /// A member of a docker archive, `size` bytes at `offset` in the archive.
+    Member {
+        archive: PathBuf,
+        offset: u64,
+        size: u64,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DigestOf {
+    /// The blob as stored, compressed or not.
+    Blob,
+    /// The uncompressed tar.
+    Tar,
+}
+
+/// Hashes the data read through it.
+struct DigestReader<'a, R: Read> {
+    inner: R,
+    hasher: &'a mut Sha256,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EntryKind {
+    File,
+    Link,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+    Dir,
+    Fifo,
+}
+
+/// An entry of the tar of a layer, with the attributes its headers give it.
+#[derive(Debug, Clone)]
+struct Entry {
+    path: Vec<u8>,
+    kind: EntryKind,
+    link_name: Vec<u8>,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: i64,
+    /// The size of the data of a regular file, zero for the other entries.
+    size: u64,
+    rdev: (u32, u32),
+    xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// The `oci-layout` file of an OCI image layout.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ImageLayout {
+    #[allow(dead_code)]
+    image_layout_version: String,
+}
+
+/// An image index or an image manifest, told apart by their members.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Document {
+    media_type: Option<String>,
+    manifests: Option<Vec<Descriptor>>,
+    layers: Option<Vec<Descriptor>>,
+}
+
+/// A descriptor of a blob of an OCI image layout.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    #[serde(default)]
+    media_type: String,
+    #[serde(default)]
+    digest: String,
+    size: Option<u64>,
+    platform: Option<Platform>,
+}
+
+#[derive(Deserialize)]
+struct Platform {
+    #[serde(default)]
+    os: String,
+    #[serde(default)]
+    architecture: String,
+}
+
+/// An image of the `manifest.json` of a docker archive.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ArchiveImage {
+    config: String,
+    layers: Vec<String>,
+}
+
+/// The configuration of an image, of which only the digests of the uncompressed layers are read.
+#[derive(Deserialize)]
+struct ImageConfig {
+    rootfs: RootFs,
+}
+
+#[derive(Deserialize)]
+struct RootFs {
+    diff_ids: Vec<String>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl OciImage {
+    /// Reads the manifest of the image at `path`, an OCI image layout directory or a docker
+    /// archive. The layers are only read when unpacked.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if fs::metadata(path)?.is_dir() {
+            Self::open_layout(path)
+        } else {
+            Self::open_docker_archive(path)
+        }
+    }
+
+    /// Returns the number of layers of the image.
+    pub fn layer_count(&self) -> usize {
+        self.layers.len()
+    }
+
+    /// Unpacks the layers of the image into `cache` that aren't there yet, returning their
+    /// directories from the bottom one to the top one.
+    pub fn unpack(&self, cache: &Path) -> io::Result<Vec<PathBuf>> {
+        let dir = cache.join("sha256");
+        fs::create_dir_all(&dir)?;
+        self.layers.iter().map(|layer| layer.unpack(&dir)).collect()
+    }
+
+    /// Returns an overlay of the layers of the image, unpacked into `cache`, under the writable
+    /// `top_layer`.
+    pub fn share(&self, cache: &Path, top_layer: PathBuf) -> io::Result<FsImplShare> {
+        let mut layers = self.unpack(cache)?;
+        layers.push(top_layer);
+        Ok(FsImplShare::Overlayfs(layers, UpperLayer::Disk))
+    }
+
+    /// Returns an overlay of the layers of the image under the writable `top_layer`, read lazily
+    /// from their blobs into `cache`, see [`LazyLayer`]. Nothing is read until the overlay is
+    /// created.
+    pub fn lazy_share(&self, cache: &Path, top_layer: PathBuf) -> io::Result<FsImplShare> {
+        let dir = cache.join("lazy");
+        fs::create_dir_all(&dir)?;
+        let lazy_layers: Vec<_> = self
+            .layers
+            .iter()
+            .map(|layer| Arc::new(LazyLayer::new(layer.clone(), &dir)))
+            .collect();
+        let mut layers: Vec<_> = lazy_layers
+            .iter()
+            .map(|layer| layer.dir().to_path_buf())
+            .collect();
+        layers.push(top_layer);
+        Ok(FsImplShare::LazyOverlayfs(
+            layers,
+            lazy_layers.into_iter().enumerate().collect(),
+            UpperLayer::Disk,
+        ))
+    }
+
+    fn open_layout(dir: &Path) -> io::Result<Self> {
+        read_document::<ImageLayout>(File::open(dir.join("oci-layout"))?)
+            .map_err(|e| invalid(&format!("not an OCI image layout: {e}")))?;
+
+        let mut document: Document = read_document(File::open(dir.join("index.json"))?)?;
+        let mut depth = 0;
+        let descriptors = loop {
+            if let Some(layers) = document.layers {
+                break layers;
+            }
+            let is_index = document.manifests.is_some()
+                || document
+                    .media_type
+                    .as_deref()
+                    .is_some_and(|media_type| INDEX_MEDIA_TYPES.contains(&media_type));
+            if !is_index {
+                return Err(invalid("the image has no manifest"));
+            }
+            depth += 1;
+            if depth > MAX_INDEX_DEPTH {
+                return Err(invalid("too many nested indexes"));
+            }
+            let descriptor = select_manifest(&document)?;
+            document = serde_json::from_slice(&read_blob(dir, descriptor)?)?;
+        };
+
+        let layers = descriptors
+            .iter()
+            .map(|descriptor| {
+                let media_type = &descriptor.media_type;
+                if media_type.contains("encrypted") {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!("unsupported layer of type {media_type}"),
+                    ));
+                }
+                let digest = parse_digest(&descriptor.digest)?;
+                Ok(Layer {
+                    source: LayerSource::Blob(dir.join("blobs/sha256").join(&digest)),
+                    digest,
+                    digest_of: DigestOf::Blob,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(OciImage { layers })
+    }
+
+    fn open_docker_archive(path: &Path) -> io::Result<Self> {
+        // The members of the archive, read in place when needed
+        let mut members = HashMap::new();
+        let mut archive = Archive::new(File::open(path)?);
+        for member in archive.entries_with_seek()? {
+            let member = member?;
+            if member.header().entry_type().is_file() {
+                let name = normalize(&member.path_bytes())?;
+                members.insert(name, (member.raw_file_position(), member.size()));
+            }
+        }
+        let member = |name: &str| -> io::Result<(u64, u64)> {
+            let name = normalize(name.as_bytes())?;
+            members.get(&name).copied().ok_or_else(|| {
+                invalid(&format!(
+                    "the archive has no {}",
+                    String::from_utf8_lossy(&name)
+                ))
+            })
+        };
+        let read_member = |name: &str| -> io::Result<io::Take<File>> {
+            let (offset, size) = member(name)?;
+            let mut file = File::open(path)?;
+            file.seek(SeekFrom::Start(offset))?;
+            Ok(file.take(size))
+        };
+
+        let images: Vec<ArchiveImage> = read_document(read_member("manifest.json")?)?;
+        let [image] = images.as_slice() else {
+            return Err(invalid("the archive must hold a single image"));
+        };
+        let config: ImageConfig = read_document(read_member(&image.config)?)?;
+        let diff_ids = config.rootfs.diff_ids;
+        if image.layers.len() != diff_ids.len() {
+            return Err(invalid("the layers don't match the diff_ids"));
+        }
+
+        let layers = image
+            .layers
+            .iter()
+            .zip(&diff_ids)
+            .map(|(name, diff_id)| {
+                let (offset, size) = member(name)?;
+                Ok(Layer {
+                    source: LayerSource::Member {
+                        archive: path.to_path_buf(),
+                        offset,
+                        size,
+                    },
+                    digest: parse_digest(diff_id)?,
+                    digest_of: DigestOf::Tar,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(OciImage { layers })
+    }
+}
+
+impl Layer {
+    /// Returns the directory of the layer in the cache `dir`, unpacking it if it isn't there yet.
+    fn unpack(&self, dir: &Path) -> io::Result<PathBuf> {
+        let target = dir.join(&self.digest);
+        if fs::symlink_metadata(&target).is_ok_and(|md| md.is_dir()) {
+            return Ok(target);
+        }
+
+        let tmp = dir.join(format!(
+            "{TMP_PREFIX}{}-{}",
+            self.digest,
+            std::process::id()
+        ));
+        if tmp.exists() {
+            remove_tree(&tmp)?;
+        }
+        fs::create_dir(&tmp)?;
+
+        let result = self
+            .extract(&tmp, None)
+            .and_then(|_| fs::rename(&tmp, &target));
+        match result {
+            Ok(()) => Ok(target),
+            // Unpacked by another process meanwhile
+            Err(_) if fs::symlink_metadata(&target).is_ok_and(|md| md.is_dir()) => {
+                remove_tree(&tmp)?;
+                Ok(target)
+            }
+            Err(e) => {
+                let _ = remove_tree(&tmp);
+                Err(e)
+            }
+        }
+    }
+
+    /// Extracts the layer into `root`, failing if it doesn't match its digest. With
+    /// `placeholders`, the regular files are left empty and added to it instead, see `lazy`.
+    fn extract(
+        &self,
+        root: &Path,
+        placeholders: Option<&mut HashMap<Vec<u8>, Placeholder>>,
+    ) -> io::Result<()> {
+        let mut blob = BufReader::new(self.source.open()?);
+        let magic = magic(&mut blob)?;
+
+        let mut blob_hasher = Sha256::new();
+        let mut tar_hasher = Sha256::new();
+        {
+            let blob = DigestReader {
+                inner: blob,
+                hasher: &mut blob_hasher,
+            };
+            let mut archive = Archive::new(DigestReader {
+                inner: decompress(blob, &magic)?,
+                hasher: &mut tar_hasher,
+            });
+            unpack_entries(&mut archive, root, placeholders)?;
+
+            // The whole of the blob is hashed, past the end of the archive
+            io::copy(&mut archive.into_inner(), &mut io::sink())?;
+        }
+
+        let hasher = match self.digest_of {
+            DigestOf::Blob => blob_hasher,
+            DigestOf::Tar => tar_hasher,
+        };
+        let digest = hex(&hasher.finalize());
+        if digest != self.digest {
+            return Err(invalid(&format!(
+                "the layer sha256:{} has the digest sha256:{digest}",
+                self.digest
+            )));
+        }
+        Ok(())
+    }
+
+    /// Opens the tar of the layer, decompressing its blob.
+    fn tar(&self) -> io::Result<Box<dyn Read + Send>> {
+        let mut blob = BufReader::new(self.source.open()?);
+        let magic = magic(&mut blob)?;
+        decompress(blob, &magic)
+    }
+}
+
+impl LayerSource {
+    fn open(&self) -> io::Result<Box<dyn Read + Send>> {
+        match self {
+            LayerSource::Blob(path) => Ok(Box::new(File::open(path)?)),
+            LayerSource::Member {
+                archive,
+                offset,
+                size,
+            } => {
+                let mut file = File::open(archive)?;
+                file.seek(SeekFrom::Start(*offset))?;
+                Ok(Box::new(file.take(*size)))
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for DigestReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_string())
+}
+
+/// Returns the first bytes of `blob`, telling how it is compressed.
+fn magic(blob: &mut impl BufRead) -> io::Result<Vec<u8>> {
+    Ok(blob
+        .fill_buf()?
+        .iter()
+        .take(ZSTD_MAGIC.len())
+        .copied()
+        .collect())
+}
+
+/// Returns the tar of `blob`, whose first bytes are `magic`.
+fn decompress<'a>(
+    blob: impl Read + Send + 'a,
+    magic: &[u8],
+) -> io::Result<Box<dyn Read + Send + 'a>> {
+    Ok(if magic.starts_with(GZIP_MAGIC) {
+        Box::new(MultiGzDecoder::new(blob))
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        Box::new(zstd::stream::read::Decoder::new(blob)?)
+    } else {
+        Box::new(blob)
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, b| {
+        let _ = std::fmt::Write::write_fmt(&mut hex, format_args!("{b:02x}"));
+        hex
+    })
+}
+
+/// Returns the hexadecimal digest of a `sha256:<hex>` digest.
+fn parse_digest(digest: &str) -> io::Result<String> {
+    let Some((algorithm, hex)) = digest.split_once(':') else {
+        return Err(invalid(&format!("malformed digest {digest:?}")));
+    };
+    if algorithm != "sha256" {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("unsupported digest algorithm {algorithm}"),
+        ));
+    }
+    if hex.len() != 64 || !hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return Err(invalid(&format!("malformed digest {digest:?}")));
+    }
+    Ok(hex.to_string())
+}
+
+fn read_document<T: DeserializeOwned>(input: impl Read) -> io::Result<T> {
+    let mut data = Vec::new();
+    input.take(MAX_DOCUMENT_SIZE + 1).read_to_end(&mut data)?;
+    if data.len() as u64 > MAX_DOCUMENT_SIZE {
+        return Err(invalid("document too large"));
+    }
+    Ok(serde_json::from_slice(&data)?)
+}
+
+/// Reads the blob of `descriptor` in the layout `dir`, checking its size and digest.
+fn read_blob(dir: &Path, descriptor: &Descriptor) -> io::Result<Vec<u8>> {
+    let digest = parse_digest(&descriptor.digest)?;
+    let size = descriptor.size;
+    if size.is_some_and(|size| size > MAX_DOCUMENT_SIZE) {
+        return Err(invalid("document too large"));
+    }
+
+    let mut data = Vec::new();
+    File::open(dir.join("blobs/sha256").join(&digest))?
+        .take(MAX_DOCUMENT_SIZE + 1)
+        .read_to_end(&mut data)?;
+    if size.is_some_and(|size| size != data.len() as u64) || hex(&Sha256::digest(&data)) != digest {
+        return Err(invalid(&format!("the blob sha256:{digest} is corrupted")));
+    }
+    Ok(data)
+}
+
+/// Picks the manifest of an index for the platform of the host, or its only manifest.
+fn select_manifest(index: &Document) -> io::Result<&Descriptor> {
+    let manifests = index.manifests.as_deref().unwrap_or_default();
+    if let [manifest] = manifests {
+        return Ok(manifest);
+    }
+
+    let architecture = match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        arch => arch,
+    };
+    manifests
+        .iter()
+        .find(|manifest| {
+            manifest.platform.as_ref().is_some_and(|platform| {
+                platform.os == "linux" && platform.architecture == architecture
+            })
+        })
+        .ok_or_else(|| {
+            invalid(&format!(
+                "the image has no manifest for linux/{architecture}"
+            ))
+        })
+}
+
+/// Returns the components of the path of an entry, without the leading `/` or `./`, failing if
+/// it has `..` components.
+fn normalize(path: &[u8]) -> io::Result<Vec<u8>> {
+    let mut normalized = Vec::new();
+    for component in Path::new(OsStr::from_bytes(path)).components() {
+        match component {
+            Component::Normal(name) => {
+                if !normalized.is_empty() {
+                    normalized.push(b'/');
+                }
+                normalized.extend_from_slice(name.as_bytes());
+            }
+            Component::RootDir | Component::CurDir => (),
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(invalid(&format!(
+                    "the path {} leaves the layer",
+                    String::from_utf8_lossy(path)
+                )));
+            }
+        }
+    }
+    Ok(normalized)
+}
+
+/// Returns an entry of a tar archive, or `None` for the sparse files, the global headers and the
+/// other rare entry types, which are skipped. The pax extended headers give the entry its
+/// modification time and extended attributes, on top of what the tar crate takes from them.
+fn read_entry<R: Read>(member: &mut tar::Entry<R>) -> io::Result<Option<Entry>> {
+    let header = member.header();
+    let kind = match header.entry_type() {
+        EntryType::Regular | EntryType::Continuous => EntryKind::File,
+        EntryType::Link => EntryKind::Link,
+        EntryType::Symlink => EntryKind::Symlink,
+        EntryType::Char => EntryKind::CharDevice,
+        EntryType::Block => EntryKind::BlockDevice,
+        EntryType::Directory => EntryKind::Dir,
+        EntryType::Fifo => EntryKind::Fifo,
+        entry_type => {
+            debug!("skipping a tar entry of type {entry_type:?}");
+            return Ok(None);
+        }
+    };
+    let id =
+        |id: u64| u32::try_from(id).map_err(|_| invalid("malformed tar archive: id too large"));
+    let mut entry = Entry {
+        path: member.path_bytes().into_owned(),
+        kind,
+        link_name: member
+            .link_name_bytes()
+            .map(Cow::into_owned)
+            .unwrap_or_default(),
+        mode: header.mode()? & 0o7777,
+        uid: id(header.uid()?)?,
+        gid: id(header.gid()?)?,
+        mtime: header.mtime()? as i64,
+        // Only regular files have data, whatever the size of the others says
+        size: if kind == EntryKind::File {
+            member.size()
+        } else {
+            0
+        },
+        rdev: (
+            header.device_major().ok().flatten().unwrap_or(0),
+            header.device_minor().ok().flatten().unwrap_or(0),
+        ),
+        xattrs: Vec::new(),
+    };
+
+    if let Some(extensions) = member.pax_extensions()? {
+        for extension in extensions {
+            let extension = extension?;
+            let key = extension.key_bytes();
+            if key == b"mtime" {
+                entry.mtime = extension
+                    .value()
+                    .ok()
+                    .and_then(|value| value.split('.').next())
+                    .and_then(|value| value.parse().ok())
+                    .ok_or_else(|| invalid("malformed tar archive: invalid pax number"))?;
+            } else if let Some(name) = key.strip_prefix(PAX_XATTR_PREFIX) {
+                entry
+                    .xattrs
+                    .push((name.to_vec(), extension.value_bytes().to_vec()));
+            }
+        }
+    }
+    Ok(Some(entry))
+}
+
+/// Creates the entries of the tar `archive` in the directory `root`. Later entries replace the
+/// earlier ones with the same path. The attributes of the directories are set last, as creating
+/// their entries changes their times. With `placeholders`, the regular files holding data are
+/// created empty, with their size, and added to it by path.
+fn unpack_entries<R: Read>(
+    archive: &mut Archive<R>,
+    root: &Path,
+    mut placeholders: Option<&mut HashMap<Vec<u8>, Placeholder>>,
+) -> io::Result<()> {
+    let mut dirs = BTreeMap::new();
+    for member in archive.entries()? {
+        let mut member = member?;
+        let Some(entry) = read_entry(&mut member)? else {
+            continue;
+        };
+        let name = normalize(&entry.path)?;
+        if name.is_empty() {
+            dirs.insert(root.to_path_buf(), entry);
+            continue;
+        }
+        let path = root.join(OsStr::from_bytes(&name));
+        check_ancestors(root, &name, true)?;
+        if let Some(placeholders) = placeholders.as_deref_mut() {
+            placeholders.remove(&name);
+        }
+
+        match fs::symlink_metadata(&path) {
+            Ok(md) if md.is_dir() && entry.kind == EntryKind::Dir => (),
+            Ok(md) if md.is_dir() => remove_tree(&path)?,
+            Ok(_) => fs::remove_file(&path)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+
+        match entry.kind {
+            EntryKind::Dir => {
+                if !path.is_dir() {
+                    fs::create_dir(&path)?;
+                }
+                dirs.insert(path, entry);
+                continue;
+            }
+            EntryKind::File => {
+                let mut file = OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(&path)?;
+                match placeholders.as_deref_mut() {
+                    Some(placeholders) if entry.size > 0 => {
+                        file.set_len(entry.size)?;
+                        let placeholder = Placeholder {
+                            offset: member.raw_file_position(),
+                            size: entry.size,
+                            mode: entry.mode,
+                            mtime: entry.mtime,
+                        };
+                        placeholders.insert(name, placeholder);
+                    }
+                    _ => {
+                        io::copy(&mut member, &mut file)?;
+                    }
+                }
+            }
+            EntryKind::Link => {
+                let target = normalize(&entry.link_name)?;
+                check_ancestors(root, &target, false)?;
+                fs::hard_link(root.join(OsStr::from_bytes(&target)), &path)?;
+                // The data of the link is the one of its target
+                if let Some(placeholders) = placeholders.as_deref_mut() {
+                    if let Some(placeholder) = placeholders.get(&target).copied() {
+                        placeholders.insert(name, placeholder);
+                    }
+                }
+                continue;
+            }
+            EntryKind::Symlink => {
+                std::os::unix::fs::symlink(OsStr::from_bytes(&entry.link_name), &path)?;
+            }
+            EntryKind::CharDevice | EntryKind::BlockDevice | EntryKind::Fifo => {
+                if !make_node(&path, &entry)? {
+                    continue;
+                }
+            }
+        }
+        set_attributes(&path, &entry)?;
+    }
+
+    // Children before their parents
+    for (path, entry) in dirs.iter().rev() {
+        set_attributes(path, entry)?;
+    }
+    Ok(())
+}
+
+/// Checks that the ancestors of `name` in `root` are directories rather than symlinks that could
+/// lead out of it, creating the missing ones if `create`.
+fn check_ancestors(root: &Path, name: &[u8], create: bool) -> io::Result<()> {
+    let mut path = root.to_path_buf();
+    let mut components = Path::new(OsStr::from_bytes(name)).components().peekable();
+    while let Some(component) = components.next() {
+        if components.peek().is_none() {
+            break;
+        }
+        path.push(component);
+        match fs::symlink_metadata(&path) {
+            Ok(md) if md.is_dir() => (),
+            Ok(_) => {
+                return Err(invalid(&format!(
+                    "the path {} goes through a non-directory",
+                    String::from_utf8_lossy(name)
+                )));
+            }
+            Err(e) if create && e.kind() == io::ErrorKind::NotFound => fs::create_dir(&path)?,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Creates a device node or a FIFO, returning `false` if it isn't allowed to.
+fn make_node(path: &Path, entry: &Entry) -> io::Result<bool> {
+    let file_type = match entry.kind {
+        EntryKind::CharDevice => libc::S_IFCHR,
+        EntryKind::BlockDevice => libc::S_IFBLK,
+        _ => libc::S_IFIFO,
+    };
+    #[cfg(target_os = "linux")]
+    let rdev = libc::makedev(entry.rdev.0, entry.rdev.1);
+    #[cfg(target_os = "macos")]
+    let rdev = libc::makedev(entry.rdev.0 as i32, entry.rdev.1 as i32);
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // Safe because this doesn't modify any memory and we check the return value.
+    if unsafe { libc::mknod(c_path.as_ptr(), file_type | 0o600, rdev) } < 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() == Some(libc::EPERM) {
+            debug!("skipping the device node {}: {e}", path.display());
+            return Ok(false);
+        }
+        return Err(e);
+    }
+    Ok(true)
+}
+
+/// Gives the entry at `path` the owner, mode, extended attributes and modification time of
+/// `entry`. The owner is only changed if allowed to, and is changed before the mode, which it
+/// would otherwise clear the set-user-ID and set-group-ID bits of.
+fn set_attributes(path: &Path, entry: &Entry) -> io::Result<()> {
+    if let Err(e) = std::os::unix::fs::lchown(path, Some(entry.uid), Some(entry.gid)) {
+        if !matches!(e.raw_os_error(), Some(libc::EPERM | libc::EINVAL)) {
+            return Err(e);
+        }
+    }
+    if entry.kind != EntryKind::Symlink {
+        fs::set_permissions(path, fs::Permissions::from_mode(entry.mode))?;
+    }
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    #[cfg(target_os = "linux")]
+    for (name, value) in &entry.xattrs {
+        let Ok(c_name) = CString::new(name.as_slice()) else {
+            continue;
+        };
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe {
+            libc::lsetxattr(
+                c_path.as_ptr(),
+                c_name.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+            )
+        };
+        if res < 0 {
+            debug!(
+                "skipping the extended attribute {} of {}: {}",
+                String::from_utf8_lossy(name),
+                path.display(),
+                io::Error::last_os_error()
+            );
+        }
+    }
+
+    set_mtime(&c_path, entry.mtime)
+}
+
+/// Sets the access and modification times of the entry at `c_path` to `mtime`.
+fn set_mtime(c_path: &CStr, mtime: i64) -> io::Result<()> {
+    let time = libc::timespec {
+        tv_sec: mtime as libc::time_t,
+        tv_nsec: 0,
+    };
+    let times = [time, time];
+    // Safe because this doesn't modify any memory and we check the return value.
+    let res = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Removes a directory tree, whatever the modes of its directories.
+fn remove_tree(path: &Path) -> io::Result<()> {
+    fs::set_permissions(path, fs::Permissions::from_mode(0o700))?;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            remove_tree(&entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    fs::remove_dir(path)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::os::unix::fs::MetadataExt;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use tar::{Builder, Header};
+
+    use super::*;
+    use crate::virtio::fs::filesystem::{Context, FileSystem};
+    use crate::virtio::fs::overlayfs::{self, OverlayFs};
+    use crate::virtio::fuse::FsOptions;
+
+    fn sha256(data: &[u8]) -> String {
+        hex(&Sha256::digest(data))
+    }
+
+    /// Appends an entry to `archive`, preceded by a pax header with `pax` records if any. The path
+    /// and the link name are written as they are, even if they leave the archive.
+    fn append(
+        archive: &mut Builder<Vec<u8>>,
+        path: &str,
+        entry_type: EntryType,
+        link_name: &str,
+        data: &[u8],
+        pax: &[(&str, &[u8])],
+    ) {
+        archive.append_pax_extensions(pax.iter().copied()).unwrap();
+        let mut header = Header::new_ustar();
+        header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+        header.as_old_mut().linkname[..link_name.len()].copy_from_slice(link_name.as_bytes());
+        header.set_entry_type(entry_type);
+        header.set_mode(0o644);
+        header.set_uid(1000);
+        header.set_gid(1000);
+        header.set_mtime(1000);
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+        archive.append(&header, data).unwrap();
+    }
+
+    /// Returns a layer with a file, a symlink, a hard link and a whiteout.
+    fn layer(name: &str, content: &[u8]) -> Vec<u8> {
+        let mut tar = Builder::new(Vec::new());
+        let file = format!("dir/{name}");
+        append(&mut tar, &file, EntryType::Regular, "", content, &[]);
+        append(&mut tar, "dir/link", EntryType::Symlink, name, b"", &[]);
+        append(&mut tar, "hard", EntryType::Link, &file, b"", &[]);
+        append(&mut tar, ".wh.removed", EntryType::Regular, "", b"", &[]);
+        tar.into_inner().unwrap()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn write_blob(dir: &Path, data: &[u8]) -> String {
+        let digest = sha256(data);
+        fs::write(dir.join("blobs/sha256").join(&digest), data).unwrap();
+        digest
+    }
+
+    /// Writes an OCI layout of `layers`, under a multi-platform index.
+    fn write_layout(dir: &Path, layers: &[Vec<u8>]) {
+        fs::create_dir_all(dir.join("blobs/sha256")).unwrap();
+        fs::write(
+            dir.join("oci-layout"),
+            br#"{"imageLayoutVersion": "1.0.0"}"#,
+        )
+        .unwrap();
+        let descriptors: Vec<String> = layers
+            .iter()
+            .map(|layer| {
+                format!(
+                    r#"{{"mediaType": "application/vnd.oci.image.layer.v1.tar+gzip", "digest": "sha256:{}", "size": {}}}"#,
+                    write_blob(dir, layer),
+                    layer.len()
+                )
+            })
+            .collect();
+        let manifest = format!(
+            r#"{{"schemaVersion": 2, "layers": [{}]}}"#,
+            descriptors.join(",")
+        );
+        let manifest_digest = write_blob(dir, manifest.as_bytes());
+        let architecture = match std::env::consts::ARCH {
+            "x86_64" => "amd64",
+            "aarch64" => "arm64",
+            arch => arch,
+        };
+        let index = format!(
+            r#"{{"schemaVersion": 2, "mediaType": "application/vnd.oci.image.index.v1+json",
+                "manifests": [
+                  {{"digest": "sha256:{}", "size": 2, "platform": {{"os": "linux", "architecture": "other"}}}},
+                  {{"digest": "sha256:{manifest_digest}", "size": {}, "platform": {{"os": "linux", "architecture": "{architecture}"}}}}
+                ]}}"#,
+            "0".repeat(64),
+            manifest.len()
+        );
+        fs::write(dir.join("index.json"), index).unwrap();
+    }
+
+    #[test]
+    fn unpack_oci_layout() {
+        let image = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        let layers = [
+            gzip(&layer("a", b"first")),
+            zstd::encode_all(&layer("b", b"second")[..], 0).unwrap(),
+        ];
+        write_layout(image.path(), &layers);
+
+        let oci = OciImage::open(image.path()).unwrap();
+        assert_eq!(oci.layer_count(), 2);
+        let dirs = oci.unpack(cache.path()).unwrap();
+        assert_eq!(
+            dirs[0],
+            cache.path().join("sha256").join(sha256(&layers[0]))
+        );
+        assert_eq!(fs::read(dirs[0].join("dir/a")).unwrap(), b"first");
+        assert_eq!(fs::read(dirs[1].join("hard")).unwrap(), b"second");
+        assert_eq!(
+            fs::read_link(dirs[1].join("dir/link")).unwrap(),
+            Path::new("b")
+        );
+        assert!(dirs[1].join(".wh.removed").is_file());
+        let md = fs::metadata(dirs[0].join("dir/a")).unwrap();
+        assert_eq!((md.mode() & 0o7777, md.mtime()), (0o644, 1000));
+
+        // The cached layers are reused as they are
+        fs::write(dirs[0].join("dir/a"), b"edited").unwrap();
+        let share = oci.share(cache.path(), image.path().join("top")).unwrap();
+        match share {
+            FsImplShare::Overlayfs(layers, UpperLayer::Disk) => {
+                assert_eq!(layers.len(), 3);
+                assert_eq!(fs::read(layers[0].join("dir/a")).unwrap(), b"edited");
+            }
+            _ => panic!("not an overlay"),
+        }
+    }
+
+    #[test]
+    fn unpack_docker_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        let layer = layer("a", b"hello");
+        let config = format!(
+            r#"{{"rootfs": {{"type": "layers", "diff_ids": ["sha256:{}"]}}}}"#,
+            sha256(&layer)
+        );
+        let mut archive = Builder::new(Vec::new());
+        let regular = EntryType::Regular;
+        append(
+            &mut archive,
+            "config.json",
+            regular,
+            "",
+            config.as_bytes(),
+            &[],
+        );
+        append(&mut archive, "abc/layer.tar", regular, "", &layer, &[]);
+        let manifest = br#"[{"Config": "config.json", "Layers": ["abc/layer.tar"]}]"#;
+        append(&mut archive, "manifest.json", regular, "", manifest, &[]);
+        fs::write(dir.path().join("image.tar"), archive.into_inner().unwrap()).unwrap();
+
+        let oci = OciImage::open(&dir.path().join("image.tar")).unwrap();
+        let dirs = oci.unpack(cache.path()).unwrap();
+        assert_eq!(fs::read(dirs[0].join("dir/a")).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn read_entries() {
+        let long_name = format!("dir/{}", "n".repeat(150));
+        let mut archive = Builder::new(Vec::new());
+        append(
+            &mut archive,
+            "dir/file",
+            EntryType::Regular,
+            "",
+            b"hello",
+            &[
+                ("uid", b"100000"),
+                ("mtime", b"1700000000.5"),
+                ("SCHILY.xattr.user.key", b"value"),
+            ],
+        );
+        append(
+            &mut archive,
+            "global",
+            EntryType::XGlobalHeader,
+            "",
+            b"12 key=val\n",
+            &[],
+        );
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Symlink);
+        header.set_mode(0o777);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(1000);
+        header.set_size(0);
+        archive
+            .append_link(&mut header, &long_name, "file")
+            .unwrap();
+        let archive = archive.into_inner().unwrap();
+
+        let mut archive = Archive::new(&archive[..]);
+        let entries: Vec<_> = archive
+            .entries()
+            .unwrap()
+            .map(|member| read_entry(&mut member.unwrap()).unwrap())
+            .collect();
+        let [Some(file), None, Some(symlink)] = entries.as_slice() else {
+            panic!("unexpected entries {entries:?}");
+        };
+        assert_eq!((file.path.as_slice(), file.size), (&b"dir/file"[..], 5));
+        assert_eq!((file.uid, file.gid, file.mtime), (100000, 1000, 1700000000));
+        assert_eq!(file.xattrs, vec![(b"user.key".to_vec(), b"value".to_vec())]);
+        assert_eq!(
+            (
+                symlink.path.as_slice(),
+                symlink.kind,
+                symlink.link_name.as_slice()
+            ),
+            (long_name.as_bytes(), EntryKind::Symlink, &b"file"[..])
+        );
+
+        // A corrupted header
+        let mut corrupted = archive.into_inner().to_vec();
+        corrupted[0] ^= 1;
+        assert!(Archive::new(&corrupted[..])
+            .entries()
+            .unwrap()
+            .next()
+            .unwrap()
+            .is_err());
+    }
+
+    #[test]
+    fn unpack_corrupted_layers() {
+        let image = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        let layers = [gzip(&layer("a", b"first"))];
+        write_layout(image.path(), &layers);
+
+        // A layer that isn't what the manifest says is discarded
+        let blob = image.path().join("blobs/sha256").join(sha256(&layers[0]));
+        fs::write(&blob, gzip(&layer("a", b"other"))).unwrap();
+        let oci = OciImage::open(image.path()).unwrap();
+        let e = oci.unpack(cache.path()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            fs::read_dir(cache.path().join("sha256")).unwrap().count(),
+            0
+        );
+
+        // Entries leaving the layer
+        for escaping in [
+            &[("../escape", EntryType::Regular, "")][..],
+            &[
+                ("link", EntryType::Symlink, "/tmp"),
+                ("link/file", EntryType::Regular, ""),
+            ],
+            &[("hard", EntryType::Link, "../../etc/passwd")],
+        ] {
+            let mut tar = Builder::new(Vec::new());
+            for (path, entry_type, link_name) in escaping {
+                append(&mut tar, path, *entry_type, link_name, b"", &[]);
+            }
+            let tar = tar.into_inner().unwrap();
+            fs::write(&blob, &tar).unwrap();
+            let mut layers = OciImage::open(image.path()).unwrap().layers;
+            layers[0].digest = sha256(&tar);
+            let oci = OciImage { layers };
+            assert!(oci.unpack(cache.path()).is_err());
+        }
+    }
+
+    #[test]
+    fn lazy_layers() {
+        let image = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        let layers = [
+            gzip(&layer("a", b"first")),
+            zstd::encode_all(&layer("b", b"second")[..], 0).unwrap(),
+        ];
+        write_layout(image.path(), &layers);
+        let oci = OciImage::open(image.path()).unwrap();
+        fs::create_dir(image.path().join("top")).unwrap();
+        let share = oci
+            .lazy_share(cache.path(), image.path().join("top"))
+            .unwrap();
+        let FsImplShare::LazyOverlayfs(dirs, lazy_layers, UpperLayer::Disk) = share else {
+            panic!("not a lazy overlay");
+        };
+        assert_eq!(dirs.len(), 3);
+        assert_eq!(lazy_layers.len(), 2);
+
+        // The files are placeholders of the right size and attributes until extracted
+        let layer = &lazy_layers[0].1;
+        layer.index().unwrap();
+        let file = layer.dir().join("dir/a");
+        assert_eq!(fs::read(&file).unwrap(), [0; 5]);
+        assert_eq!(
+            fs::read_link(layer.dir().join("dir/link")).unwrap(),
+            Path::new("a")
+        );
+        assert!(layer.dir().join(".wh.removed").is_file());
+        layer.materialize(b"hard").unwrap();
+        assert_eq!(fs::read(&file).unwrap(), b"first");
+        let md = fs::metadata(&file).unwrap();
+        assert_eq!((md.mode() & 0o7777, md.mtime()), (0o644, 1000));
+
+        // The files extracted are remembered across processes
+        fs::write(&file, b"edited").unwrap();
+        let reloaded = LazyLayer::new(oci.layers[0].clone(), &cache.path().join("lazy"));
+        reloaded.index().unwrap();
+        reloaded.materialize(b"dir/a").unwrap();
+        assert_eq!(fs::read(&file).unwrap(), b"edited");
+
+        // The overlay extracts the files of its lazy layers as it opens them, in place when they
+        // are only read
+        let fs = OverlayFs::new(overlayfs::Config {
+            layers: dirs.clone(),
+            lazy_layers,
+            ..Default::default()
+        })
+        .unwrap();
+        fs.init(FsOptions::empty()).unwrap();
+        let ctx = Context::default();
+        let dir = fs.lookup(ctx, 1, c"dir").unwrap();
+        let entry = fs.lookup(ctx, dir.inode, c"b").unwrap();
+        fs.open(ctx, entry.inode, libc::O_RDONLY as u32).unwrap();
+        assert_eq!(fs::read(dirs[1].join("dir/b")).unwrap(), b"second");
+        assert!(!image.path().join("top/dir/b").exists());
+    }
+}
@@ -0,0 +1,813 @@
+//! A 9P2000.L front-end to the file system of a share, for the guests shipping only 9p drivers.
+//!
+//! A device using it offers itself as a virtio-9p one, with a single request queue, and each
+//! request is served by the calls of the file system the equivalent FUSE request makes. The guest
+//! names files with fids rather than inodes: the fids on an inode share a single lookup of it,
+//! taken by the walk that first reached it and forgotten with the last of them, and a fid opened
+//! holds the handle of its file. 9P requests carry no credentials, so they run as the user the fid
+//! was attached as, with the group the requests creating files give.
+//!
+//! What FUSE has and 9P doesn't is left out: DAX, the leases and the ioctls. So are the options
+//! applied by the FUSE server, such as the access rules, the virtual files or the coalescing of
+//! writes, and the guest keeps its locks to itself.
+
+mod wire;
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::mem;
+
+use super::super::linux_errno::linux_error;
+use super::descriptor_utils::{Reader, Writer};
+use super::filesystem::{Context, Entry, Extensions, FileSystem, SetattrValid};
+use super::fs_utils::{ebadf, einval};
+use super::fuse::{Attr, FsOptions, Kstatfs, ROOT_ID};
+use super::server::{ZCReader, ZCWriter, MAX_BUFFER_SIZE};
+use super::{bindings, FsError as Error, FsImpl, Result};
+use wire::{dirent, Decoder, Encoder, Qid, HEADER_SIZE};
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The only dialect served.
+const VERSION: &[u8] = b"9P2000.L";
+
+/// The room the client leaves for the header of a read or write in `msize`.
+const IOHDRSZ: u32 = 24;
+
+/// The largest messages exchanged, which hold reads and writes as large as FUSE ones.
+const MAX_MSIZE: u32 = MAX_BUFFER_SIZE + IOHDRSZ;
+
+/// The smallest messages the client may ask for.
+const MIN_MSIZE: u32 = 4096;
+
+/// The most names a walk may go through.
+const MAXWELEM: usize = 16;
+
+/// The user of an attach giving its name only.
+const NONUNAME: u32 = u32::MAX;
+
+/// The magic number of the file system reported by `Tstatfs`.
+const V9FS_MAGIC: u32 = 0x0102_1997;
+
+/// The attributes every `Rgetattr` holds.
+const GETATTR_BASIC: u64 = 0x0000_07ff;
+const GETATTR_BTIME: u64 = 0x0000_0800;
+
+// The attributes a `Tsetattr` sets.
+const SETATTR_MODE: u32 = 0x0000_0001;
+const SETATTR_UID: u32 = 0x0000_0002;
+const SETATTR_GID: u32 = 0x0000_0004;
+const SETATTR_SIZE: u32 = 0x0000_0008;
+const SETATTR_ATIME: u32 = 0x0000_0010;
+const SETATTR_MTIME: u32 = 0x0000_0020;
+const SETATTR_CTIME: u32 = 0x0000_0040;
+const SETATTR_ATIME_SET: u32 = 0x0000_0080;
+const SETATTR_MTIME_SET: u32 = 0x0000_0100;
+
+/// The flag of a `Tunlinkat` removing a directory, as on Linux.
+const LINUX_AT_REMOVEDIR: u32 = 0x200;
+
+/// The status of a lock taken, and the type of a lock not held.
+const LOCK_SUCCESS: u8 = 0;
+const LOCK_TYPE_UNLCK: u8 = 2;
+
+// The types of the requests, each reply having the type of its request plus one.
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TSYMLINK: u8 = 16;
+const TMKNOD: u8 = 18;
+const TREADLINK: u8 = 22;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TLOCK: u8 = 52;
+const TGETLOCK: u8 = 54;
+const TLINK: u8 = 70;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// The 9P session of the guest with a share, which outlives the servers of the share a pause may
+/// drop and create anew, the inodes and handles keeping their numbers.
+#[derive(Default)]
+pub(crate) struct P9Server {
+    fids: HashMap<u32, Fid>,
+    /// The number of fids on each inode
+    held: HashMap<u64, u64>,
+    msize: u32,
+    initialized: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Fid {
+    inode: u64,
+    uid: u32,
+    open: Option<Open>,
+}
+
+/// The handle of the file or directory a fid opened.
+#[derive(Clone, Copy)]
+struct Open {
+    handle: u64,
+    flags: u32,
+    dir: bool,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl P9Server {
+    /// Handles the request in `r` with `fs`, writing the reply to `w`, and returns the size of the
+    /// reply.
+    pub(crate) fn handle_message(
+        &mut self,
+        fs: &FsImpl,
+        mut r: Reader,
+        mut w: Writer,
+    ) -> Result<usize> {
+        let (type_, tag) = read_header(&mut r)?;
+        let reply = Encoder::new(type_.wrapping_add(1), tag);
+        let mut dec = Decoder(&mut r);
+        let result = match type_ {
+            TVERSION => self.version(fs, &mut dec, reply),
+            _ if !self.initialized => Err(einval()),
+            TATTACH => self.attach(fs, &mut dec, reply),
+            TFLUSH => dec.u16().map(|_| reply),
+            TWALK => self.walk(fs, &mut dec, reply),
+            TCLUNK => self.clunk(fs, &mut dec, reply),
+            TREMOVE => self.remove(fs, &mut dec),
+            TLOPEN => self.lopen(fs, &mut dec, reply),
+            TLCREATE => self.lcreate(fs, &mut dec, reply),
+            TREAD => return self.read(fs, tag, r, w),
+            TWRITE => self.write(fs, r, reply),
+            TREADDIR => self.readdir(fs, &mut dec, reply),
+            TGETATTR => self.getattr(fs, &mut dec, reply),
+            TSETATTR => self.setattr(fs, &mut dec, reply),
+            TSTATFS => self.statfs(fs, &mut dec, reply),
+            TFSYNC => self.fsync(fs, &mut dec, reply),
+            TMKDIR => self.mkdir(fs, &mut dec, reply),
+            TMKNOD => self.mknod(fs, &mut dec, reply),
+            TSYMLINK => self.symlink(fs, &mut dec, reply),
+            TLINK => self.link(fs, &mut dec, reply),
+            TREADLINK => self.readlink(fs, &mut dec, reply),
+            TRENAMEAT => self.renameat(fs, &mut dec, reply),
+            TUNLINKAT => self.unlinkat(fs, &mut dec, reply),
+            TLOCK => lock(&mut dec, reply),
+            TGETLOCK => getlock(&mut dec, reply),
+            // The extended attributes, the authentication, and the requests naming files by fid
+            // only when the client has the ones naming them by directory and name
+            _ => Err(errno(libc::EOPNOTSUPP)),
+        };
+
+        match result {
+            Ok(reply) => {
+                let reply = reply.finish();
+                w.write_all(&reply).map_err(Error::EncodeMessage)?;
+                Ok(reply.len())
+            }
+            Err(e) => reply_error(e, tag, w),
+        }
+    }
+
+    /// Starts a new session, clunking the fids of the previous one.
+    fn version(&mut self, fs: &FsImpl, dec: &mut Decoder, reply: Encoder) -> io::Result<Encoder> {
+        let msize = dec.u32()?;
+        let version = dec.bytes()?;
+
+        for (_, fid) in mem::take(&mut self.fids) {
+            self.release(fs, fid);
+        }
+        if !version.starts_with(VERSION) {
+            return Ok(reply.u32(msize).bytes(b"unknown"));
+        }
+        if msize < MIN_MSIZE {
+            return Err(einval());
+        }
+        if !self.initialized {
+            fs.init(FsOptions::empty())?;
+            self.initialized = true;
+        }
+        self.msize = msize.min(MAX_MSIZE);
+        Ok(reply.u32(self.msize).bytes(VERSION))
+    }
+
+    fn attach(&mut self, fs: &FsImpl, dec: &mut Decoder, reply: Encoder) -> io::Result<Encoder> {
+        let fid = dec.u32()?;
+        let _afid = dec.u32()?;
+        let _uname = dec.bytes()?;
+        let _aname = dec.bytes()?;
+        let n_uname = dec.u32()?;
+
+        if self.fids.contains_key(&fid) {
+            return Err(ebadf());
+        }
+        let uid = if n_uname == NONUNAME { 0 } else { n_uname };
+        let (st, _) = fs.getattr(ctx(uid, 0), ROOT_ID, None)?;
+        self.hold(fs, ROOT_ID, false);
+        self.fids.insert(
+            fid,
+            Fid {
+                inode: ROOT_ID,
+                uid,
+                open: None,
+            },
+        );
+        Ok(reply.qid(Qid::from_stat(&st)))
+    }
+
+    /// Walks from a fid through names, giving the file reached to a new fid if all of them were
+    /// walked through. The parent of the root is the root.
+    fn walk(&mut self, fs: &FsImpl, dec: &mut Decoder, reply: Encoder) -> io::Result<Encoder> {
+        let fid = dec.u32()?;
+        let newfid = dec.u32()?;
+        let nwname = dec.u16()? as usize;
+        if nwname > MAXWELEM {
+            return Err(einval());
+        }
+        let names = (0..nwname)
+            .map(|_| dec.name())
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let start = self.fid(fid)?;
+        if newfid != fid && self.fids.contains_key(&newfid) {
+            return Err(ebadf());
+        }
+        let ctx = ctx(start.uid, 0);
+
+        let mut inode = start.inode;
+        // The inode the last name was looked up as, whose lookup goes to the new fid
+        let mut looked_up = None;
+        let mut qids = Vec::new();
+        for name in &names {
+            let next = if name.as_bytes() == b".." && inode == ROOT_ID {
+                fs.getattr(ctx, ROOT_ID, None)
+                    .map(|(st, _)| (ROOT_ID, st, false))
+            } else {
+                fs.lookup(ctx, inode, name)
+                    .and_then(|entry| match entry.inode {
+                        0 => Err(errno(libc::ENOENT)),
+                        _ => Ok((entry.inode, entry.attr, true)),
+                    })
+            };
+            match next {
+                Ok((next, st, counted)) => {
+                    if let Some(prev) = looked_up.take() {
+                        fs.forget(ctx, prev, 1);
+                    }
+                    if counted {
+                        looked_up = Some(next);
+                    }
+                    inode = next;
+                    qids.push(Qid::from_stat(&st));
+                }
+                Err(e) if qids.is_empty() => return Err(e),
+                Err(_) => break,
+            }
+        }
+
+        if qids.len() == names.len() {
+            self.hold(fs, inode, looked_up.is_some());
+            let walked = Fid {
+                inode,
+                uid: start.uid,
+                open: None,
+            };
+            if let Some(old) = self.fids.insert(newfid, walked) {
+                self.release(fs, old);
+            }
+        } else if let Some(prev) = looked_up {
+            fs.forget(ctx, prev, 1);
+        }
+
+        let reply = reply.u16(qids.len() as u16);
+        Ok(qids.into_iter().fold(reply, Encoder::qid))
+    }
+
+    fn clunk(&mut self, fs: &FsImpl, dec: &mut Decoder, reply: Encoder) -> io::Result<Encoder> {
+        let fid = dec.u32()?;
+        let fid = self.fids.remove(&fid).ok_or_else(ebadf)?;
+        self.release(fs, fid);
+        Ok(reply)
+    }
+
+    /// Clunks a fid, failing to remove its file: a fid doesn't name the directory the file is in,
+    /// and the client falls back to `Tremove` only when `Tunlinkat` isn't supported.
+    fn remove(&mut self, fs: &FsImpl, dec: &mut Decoder) -> io::Result<Encoder> {
+        let fid = dec.u32()?;
+        let fid = self.fids.remove(&fid).ok_or_else(ebadf)?;
+        self.release(fs, fid);
+        Err(errno(libc::EOPNOTSUPP))
+    }
+
+    fn lopen(&mut self, fs: &FsImpl, dec: &mut Decoder, reply: Encoder) -> io::Result<Encoder> {
+        let fid = dec.u32()?;
+        let flags = dec.u32()?;
+
+        let f = self.fid(fid)?;
+        if f.open.is_some() {
+            return Err(ebadf());
+        }
+        let ctx = ctx(f.uid, 0);
+        let (st, _) = fs.getattr(ctx, f.inode, None)?;
+        let qid = Qid::from_stat(&st);
+        let (handle, _) = if qid.is_dir() {
+            fs.opendir(ctx, f.inode, flags)?
+        } else {
+            fs.open(ctx, f.inode, flags)?
+        };
+
+        self.fids.get_mut(&fid).unwrap().open = Some(Open {
+            handle: handle.unwrap_or(0),
+            flags,
+            dir: qid.is_dir(),
+        });
+        // The client reads and writes as much as the messages hold
+        Ok(reply.qid(qid).u32(0))
+    }
+
+    /// Creates a file in the directory of a fid, which becomes the fid of the file opened.
+    fn lcreate(&mut self, fs: &FsImpl, dec: &mut Decoder, reply: Encoder) -> io::Result<Encoder> {
+        let fid = dec.u32()?;
+        let name = dec.name()?;
+        let flags = dec.u32()?;
+        let mode = dec.u32()?;
+        let gid = dec.u32()?;
+
+        let f = self.fid(fid)?;
+        if f.open.is_some() {
+            return Err(ebadf());
+        }
+        let (entry, handle, _) = fs.create(
+            ctx(f.uid, gid),
+            f.inode,
+            &name,
+            mode,
+            flags,
+            0,
+            Extensions::default(),
+        )?;
+
+        self.hold(fs, entry.inode, true);
+        let created = Fid {
+            inode: entry.inode,
+            uid: f.uid,
+            open: Some(Open {
+                handle: handle.unwrap_or(0),
+                flags,
+                dir: false,
+            }),
+        };
+        if let Some(old) = self.fids.insert(fid, created) {
+            self.release(fs, old);
+        }
+        Ok(reply.qid(Qid::from_stat(&entry.attr)).u32(0))
+    }
+
+    /// Reads from the file of a fid, straight to the reply in the queue.
+    fn read(&self, fs: &FsImpl, tag: u16, mut r: Reader, mut w: Writer) -> Result<usize> {
+        let mut dec = Decoder(&mut r);
+        let result = (|| {
+            let fid = dec.u32()?;
+            let offset = dec.u64()?;
+            let count = dec.u32()?;
+
+            let (f, open) = self.open_fid(fid)?;
+            if open.dir {
+                return Err(errno(libc::EISDIR));
+            }
+            let header_size = HEADER_SIZE + 4;
+            let count = count.min(self.msize - header_size as u32);
+            let data = ZCWriter(w.split_at(header_size).map_err(|_| einval())?);
+            fs.read(
+                ctx(f.uid, 0),
+                f.inode,
+                open.handle,
+                data,
+                count,
+                offset,
+                None,
+                open.flags,
+            )
+        })();
+
+        match result {
+            Ok(count) => {
+                let header = Encoder::new(TREAD + 1, tag)
+                    .u32(count as u32)
+                    .finish_header(count);
+                w.write_all(&header).map_err(Error::EncodeMessage)?;
+                Ok(header.len() + count)
+            }
+            Err(e) => reply_error(e, tag, w),
+        }
+    }
+
+    /// Writes to the file of a fid, straight from the request in the queue.
+    fn write(&self, fs: &FsImpl, mut r: Reader, reply: Encoder) -> io::Result<Encoder> {
+        let mut dec = Decoder(&mut r);
+        let fid = dec.u32()?;
+        let offset = dec.u64()?;
+        let count = dec.u32()?;
+
+        let (f, open) = self.open_fid(fid)?;
+        if count as usize > r.available_bytes() {
+            return Err(einval());
+        }
+        let written = fs.write(
+            ctx(f.uid, 0),
+            f.inode,
+            open.handle,
+            ZCReader(r),
+            count,
+            offset,
+            None,
+            false,
+            false,
+            open.flags,
+        )?;
+        Ok(reply.u32(written as u32))
+    }
+
+    fn readdir(&self, fs: &FsImpl, dec: &mut Decoder, reply: Encoder) -> io::Result<Encoder> {
+        let fid = dec.u32()?;
+        let offset = dec.u64()?;
+        let count = dec.u32()?;
+
+        let (f, open) = self.open_fid(fid)?;
+        if !open.dir {
+            return Err(errno(libc::ENOTDIR));
+        }
+        let count = count.min(self.msize - (HEADER_SIZE + 4) as u32);
+        let mut data = Vec::new();
+        fs.readdir(ctx(f.uid, 0), f.inode, open.handle, count, offset, |d| {
+            let entry = dirent(Qid::from_dirent(d.ino, d.type_), d.offset, d.type_, d.name);
+            if data.len() + entry.len() > count as usize {
+                return Ok(0);
+            }
+            data.extend_from_slice(&entry);
+            Ok(entry.len())
+        })?;
+        Ok(reply.u32(data.len() as u32).raw(&data))
+    }
+
+    fn getattr(&self, fs: &FsImpl, dec: &mut Decoder, reply: Encoder) -> io::Result<Encoder> {
+        let fid = dec.u32()?;
+        let _request_mask = dec.u64()?;
+
+        let f = self.fid(fid)?;
+        let handle = f.open.filter(|open| !open.dir).map(|open| open.handle);
+        let (st, btime, _) = fs.statx(ctx(f.uid, 0), f.inode, handle)?;
+        let (valid, btime_sec, btime_nsec) = match btime {
+            Some(btime) => (GETATTR_BASIC | GETATTR_BTIME, btime.sec as u64, btime.nsec),
+            None => (GETATTR_BASIC, 0, 0),
+        };
+        let attr = Attr::from(st);
+        Ok(reply
+            .u64(valid)
+            .qid(Qid::from_stat(&st))
+            .u32(attr.mode)
+            .u32(attr.uid)
+            .u32(attr.gid)
+            .u64(attr.nlink.into())
+            .u64(attr.rdev.into())
+            .u64(attr.size)
+            .u64(attr.blksize.into())
+            .u64(attr.blocks)
+            .u64(attr.atime)
+            .u64(attr.atimensec.into())
+            .u64(attr.mtime)
+            .u64(attr.mtimensec.into())
+            .u64(attr.ctime)
+            .u64(attr.ctimensec.into())
+            .u64(btime_sec)
+            .u64(btime_nsec as u64)
+            // The generation and the data version
+            .u64(0)
+            .u64(0))
+    }
+
+    fn setattr(&self, fs: &FsImpl, dec: &mut Decoder, reply: Encoder) -> io::Result<Encoder> {
+        let fid = dec.u32()?;
+        let valid = dec.u32()?;
+        let mode = dec.u32()?;
+        let uid = dec.u32()?;
+        let gid = dec.u32()?;
+        let size = dec.u64()?;
+        let atime = (dec.u64()?, dec.u64()?);
+        let mtime = (dec.u64()?, dec.u64()?);
+
+        let f = self.fid(fid)?;
+        // Safe because we are zero-initializing a struct with only POD fields.
+        let mut attr: bindings::stat64 = unsafe { mem::zeroed() };
+        let mut to_set = SetattrValid::empty();
+        if valid & SETATTR_MODE != 0 {
+            attr.st_mode = mode as _;
+            to_set |= SetattrValid::MODE;
+        }
+        if valid & SETATTR_UID != 0 {
+            attr.st_uid = uid;
+            to_set |= SetattrValid::UID;
+        }
+        if valid & SETATTR_GID != 0 {
+            attr.st_gid = gid;
+            to_set |= SetattrValid::GID;
+        }
+        if valid & SETATTR_SIZE != 0 {
+            attr.st_size = size as _;
+            to_set |= SetattrValid::SIZE;
+        }
+        // The times not set to a value are set to the current time
+        if valid & SETATTR_ATIME != 0 {
+            to_set |= SetattrValid::ATIME;
+            if valid & SETATTR_ATIME_SET != 0 {
+                attr.st_atime = atime.0 as _;
+                attr.st_atime_nsec = atime.1 as _;
+            } else {
+                to_set |= SetattrValid::ATIME_NOW;
+            }
+        }
+        if valid & SETATTR_MTIME != 0 {
+            to_set |= SetattrValid::MTIME;
+            if valid & SETATTR_MTIME_SET != 0 {
+                attr.st_mtime = mtime.0 as _;
+                attr.st_mtime_nsec = mtime.1 as _;
+            } else {
+                to_set |= SetattrValid::MTIME_NOW;
+            }
+        }
+        if valid & SETATTR_CTIME != 0 {
+            to_set |= SetattrValid::CTIME;
+        }
+
+        let handle = f.open.filter(|open| !open.dir).map(|open| open.handle);
+        fs.setattr(ctx(f.uid, 0), f.inode, attr, handle, to_set)?;
+        Ok(reply)
+    }
+
+    fn statfs(&self, fs: &FsImpl, dec: &mut Decoder, reply: Encoder) -> io::Result<Encoder> {
+        let fid = dec.u32()?;
+
+        let f = self.fid(fid)?;
+        let st = fs.statfs(ctx(f.uid, 0), f.inode)?;
+        let fsid = st.f_fsid;
+        let st = Kstatfs::from(st);
+        Ok(reply
+            .u32(V9FS_MAGIC)
+            .u32(st.bsize)
+            .u64(st.blocks)
+            .u64(st.bfree)
+            .u64(st.bavail)
+            .u64(st.files)
+            .u64(st.ffree)
+            .u64(fsid)
+            .u32(st.namelen))
+    }
+
+    fn fsync(&self, fs: &FsImpl, dec: &mut Decoder, reply: Encoder) -> io::Result<Encoder> {
+        let fid = dec.u32()?;
+        let datasync = dec.u32()? != 0;
+
+        let (f, open) = self.open_fid(fid)?;
+        let ctx = ctx(f.uid, 0);
+        if open.dir {
+            fs.fsyncdir(ctx, f.inode, datasync, open.handle)?;
+        } else {
+            fs.fsync(ctx, f.inode, datasync, open.handle)?;
+        }
+        Ok(reply)
+    }
+
+    fn mkdir(&self, fs: &FsImpl, dec: &mut Decoder, reply: Encoder) -> io::Result<Encoder> {
+        let dfid = dec.u32()?;
+        let name = dec.name()?;
+        let mode = dec.u32()?;
+        let gid = dec.u32()?;
+
+        let d = self.fid(dfid)?;
+        let ctx = ctx(d.uid, gid);
+        let entry = fs.mkdir(ctx, d.inode, &name, mode, 0, Extensions::default())?;
+        Ok(reply.qid(forget_entry(fs, ctx, entry)))
+    }
+
+    fn mknod(&self, fs: &FsImpl, dec: &mut Decoder, reply: Encoder) -> io::Result<Encoder> {
+        let dfid = dec.u32()?;
+        let name = dec.name()?;
+        let mode = dec.u32()?;
+        let major = dec.u32()?;
+        let minor = dec.u32()?;
+        let gid = dec.u32()?;
+
+        let d = self.fid(dfid)?;
+        let ctx = ctx(d.uid, gid);
+        // The device number as the guest kernel encodes it in FUSE requests
+        let rdev = (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12);
+        let entry = fs.mknod(ctx, d.inode, &name, mode, rdev, 0, Extensions::default())?;
+        Ok(reply.qid(forget_entry(fs, ctx, entry)))
+    }
+
+    fn symlink(&self, fs: &FsImpl, dec: &mut Decoder, reply: Encoder) -> io::Result<Encoder> {
+        let dfid = dec.u32()?;
+        let name = dec.name()?;
+        let target = dec.name()?;
+        let gid = dec.u32()?;
+
+        let d = self.fid(dfid)?;
+        let ctx = ctx(d.uid, gid);
+        let entry = fs.symlink(ctx, &target, d.inode, &name, Extensions::default())?;
+        Ok(reply.qid(forget_entry(fs, ctx, entry)))
+    }
+
+    fn link(&self, fs: &FsImpl, dec: &mut Decoder, reply: Encoder) -> io::Result<Encoder> {
+        let dfid = dec.u32()?;
+        let fid = dec.u32()?;
+        let name = dec.name()?;
+
+        let d = self.fid(dfid)?;
+        let f = self.fid(fid)?;
+        let ctx = ctx(d.uid, 0);
+        let entry = fs.link(ctx, f.inode, d.inode, &name)?;
+        forget_entry(fs, ctx, entry);
+        Ok(reply)
+    }
+
+    fn readlink(&self, fs: &FsImpl, dec: &mut Decoder, reply: Encoder) -> io::Result<Encoder> {
+        let fid = dec.u32()?;
+
+        let f = self.fid(fid)?;
+        let target = fs.readlink(ctx(f.uid, 0), f.inode)?;
+        Ok(reply.bytes(&target))
+    }
+
+    fn renameat(&self, fs: &FsImpl, dec: &mut Decoder, reply: Encoder) -> io::Result<Encoder> {
+        let olddirfid = dec.u32()?;
+        let oldname = dec.name()?;
+        let newdirfid = dec.u32()?;
+        let newname = dec.name()?;
+
+        let olddir = self.fid(olddirfid)?;
+        let newdir = self.fid(newdirfid)?;
+        fs.rename(
+            ctx(olddir.uid, 0),
+            olddir.inode,
+            &oldname,
+            newdir.inode,
+            &newname,
+            0,
+        )?;
+        Ok(reply)
+    }
+
+    fn unlinkat(&self, fs: &FsImpl, dec: &mut Decoder, reply: Encoder) -> io::Result<Encoder> {
+        let dirfid = dec.u32()?;
+        let name = dec.name()?;
+        let flags = dec.u32()?;
+
+        let d = self.fid(dirfid)?;
+        if flags & LINUX_AT_REMOVEDIR != 0 {
+            fs.rmdir(ctx(d.uid, 0), d.inode, &name)?;
+        } else {
+            fs.unlink(ctx(d.uid, 0), d.inode, &name)?;
+        }
+        Ok(reply)
+    }
+
+    fn fid(&self, fid: u32) -> io::Result<Fid> {
+        self.fids.get(&fid).copied().ok_or_else(ebadf)
+    }
+
+    /// Returns a fid that must be open, with its handle.
+    fn open_fid(&self, fid: u32) -> io::Result<(Fid, Open)> {
+        let f = self.fid(fid)?;
+        let open = f.open.ok_or_else(ebadf)?;
+        Ok((f, open))
+    }
+
+    /// Has one more fid on `inode`, which holds a lookup more if `looked_up`. The fids on an inode
+    /// share a single lookup of it.
+    fn hold(&mut self, fs: &FsImpl, inode: u64, looked_up: bool) {
+        let count = self.held.entry(inode).or_default();
+        if looked_up && *count > 0 {
+            fs.forget(ctx(0, 0), inode, 1);
+        }
+        *count += 1;
+    }
+
+    /// Closes the handle of a fid no longer used, and forgets its inode if no other fid is on it.
+    fn release(&mut self, fs: &FsImpl, fid: Fid) {
+        let ctx = ctx(fid.uid, 0);
+        if let Some(open) = fid.open {
+            let result = if open.dir {
+                fs.releasedir(ctx, fid.inode, open.flags, open.handle)
+            } else {
+                fs.release(ctx, fid.inode, open.flags, open.handle, false, false, None)
+            };
+            if let Err(e) = result {
+                debug!("virtio-9p: failed to release handle {}: {e}", open.handle);
+            }
+        }
+
+        if let Some(count) = self.held.get_mut(&fid.inode) {
+            *count -= 1;
+            if *count == 0 {
+                self.held.remove(&fid.inode);
+                // The root is never forgotten
+                if fid.inode != ROOT_ID {
+                    fs.forget(ctx, fid.inode, 1);
+                }
+            }
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Returns the configuration space of a virtio-9p device with the mount tag `tag`.
+pub(crate) fn config_space(tag: &[u8]) -> Vec<u8> {
+    let mut config = (tag.len() as u16).to_le_bytes().to_vec();
+    config.extend_from_slice(tag);
+    config
+}
+
+/// Fails the request in `r` with `EIO` without reaching the file system, for a device whose pause
+/// timed out.
+pub(crate) fn reply_unavailable(mut r: Reader, w: Writer) -> Result<usize> {
+    let (_, tag) = read_header(&mut r)?;
+    reply_error(errno(libc::EIO), tag, w)
+}
+
+/// Reads the type and the tag of a request.
+fn read_header(r: &mut Reader) -> Result<(u8, u16)> {
+    let mut dec = Decoder(r);
+    let header = (|| Ok((dec.u32()?, dec.u8()?, dec.u16()?)))();
+    let (_size, type_, tag) = header.map_err(Error::DecodeMessage)?;
+    Ok((type_, tag))
+}
+
+fn reply_error(e: io::Error, tag: u16, mut w: Writer) -> Result<usize> {
+    let ecode = e.raw_os_error().unwrap_or(libc::EIO);
+    let reply = Encoder::new(RLERROR, tag).u32(ecode as u32).finish();
+    w.write_all(&reply).map_err(Error::EncodeMessage)?;
+    Ok(reply.len())
+}
+
+/// Takes a lock, which the guest keeps to itself.
+fn lock(dec: &mut Decoder, reply: Encoder) -> io::Result<Encoder> {
+    let _fid = dec.u32()?;
+    let _type = dec.u8()?;
+    let _flags = dec.u32()?;
+    let _start = dec.u64()?;
+    let _length = dec.u64()?;
+    let _proc_id = dec.u32()?;
+    let _client_id = dec.bytes()?;
+    Ok(reply.u8(LOCK_SUCCESS))
+}
+
+/// Tests a lock, which no one else holds.
+fn getlock(dec: &mut Decoder, reply: Encoder) -> io::Result<Encoder> {
+    let _fid = dec.u32()?;
+    let _type = dec.u8()?;
+    let start = dec.u64()?;
+    let length = dec.u64()?;
+    let proc_id = dec.u32()?;
+    let client_id = dec.bytes()?;
+    Ok(reply
+        .u8(LOCK_TYPE_UNLCK)
+        .u64(start)
+        .u64(length)
+        .u32(proc_id)
+        .bytes(&client_id))
+}
+
+/// Forgets the lookup of an entry created, which no fid is on, and returns its qid.
+fn forget_entry(fs: &FsImpl, ctx: Context, entry: Entry) -> Qid {
+    fs.forget(ctx, entry.inode, 1);
+    Qid::from_stat(&entry.attr)
+}
+
+fn ctx(uid: u32, gid: u32) -> Context {
+    Context { uid, gid, pid: 0 }
+}
+
+fn errno(errno: i32) -> io::Error {
+    linux_error(io::Error::from_raw_os_error(errno))
+}
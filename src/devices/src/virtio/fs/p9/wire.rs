@@ -0,0 +1,196 @@
+//! The encoding of the 9P2000.L messages: little-endian integers, and strings prefixed with their
+//! length on 16 bits.
+
+use std::ffi::CString;
+use std::io::{self, Read};
+
+use super::super::bindings;
+use super::super::descriptor_utils::Reader;
+use super::super::fs_utils::einval;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The size of the header of every message: its size, type and tag.
+pub(super) const HEADER_SIZE: usize = 7;
+
+/// The type of a qid naming a directory.
+const QTDIR: u8 = 0x80;
+
+/// The type of a qid naming a symbolic link.
+const QTSYMLINK: u8 = 0x02;
+
+/// The type of a qid naming a regular file, or anything else.
+const QTFILE: u8 = 0x00;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// The identity of a file for the client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) struct Qid {
+    pub(super) type_: u8,
+    pub(super) version: u32,
+    pub(super) path: u64,
+}
+
+/// Decodes the fields of a request from the queue.
+pub(super) struct Decoder<'a, 'b>(pub(super) &'b mut Reader<'a>);
+
+/// Encodes a reply, before it is written to the queue.
+pub(super) struct Encoder(Vec<u8>);
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl Qid {
+    /// Returns the qid of the file with the attributes `st`.
+    pub(super) fn from_stat(st: &bindings::stat64) -> Self {
+        let type_ = match st.st_mode & libc::S_IFMT {
+            libc::S_IFDIR => QTDIR,
+            libc::S_IFLNK => QTSYMLINK,
+            _ => QTFILE,
+        };
+        Qid {
+            type_,
+            version: 0,
+            path: st.st_ino,
+        }
+    }
+
+    /// Returns the qid of a directory entry of type `d_type`.
+    pub(super) fn from_dirent(ino: u64, d_type: u32) -> Self {
+        let type_ = match d_type as u8 {
+            libc::DT_DIR => QTDIR,
+            libc::DT_LNK => QTSYMLINK,
+            _ => QTFILE,
+        };
+        Qid {
+            type_,
+            version: 0,
+            path: ino,
+        }
+    }
+
+    pub(super) fn is_dir(&self) -> bool {
+        self.type_ == QTDIR
+    }
+}
+
+impl Decoder<'_, '_> {
+    /// Fills `buf`, failing with `EINVAL` if the request is too short.
+    fn fill(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.0.read_exact(buf).map_err(|_| einval())
+    }
+
+    pub(super) fn u8(&mut self) -> io::Result<u8> {
+        let mut buf = [0; 1];
+        self.fill(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    pub(super) fn u16(&mut self) -> io::Result<u16> {
+        let mut buf = [0; 2];
+        self.fill(&mut buf)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    pub(super) fn u32(&mut self) -> io::Result<u32> {
+        let mut buf = [0; 4];
+        self.fill(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    pub(super) fn u64(&mut self) -> io::Result<u64> {
+        let mut buf = [0; 8];
+        self.fill(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    pub(super) fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.u16()?;
+        let mut buf = vec![0; len as usize];
+        self.fill(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Decodes a name, which can't hold a nul byte.
+    pub(super) fn name(&mut self) -> io::Result<CString> {
+        CString::new(self.bytes()?).map_err(|_| einval())
+    }
+}
+
+impl Encoder {
+    /// Starts the reply of type `type_` to the request `tag`.
+    pub(super) fn new(type_: u8, tag: u16) -> Self {
+        let mut buf = Vec::with_capacity(64);
+        buf.extend_from_slice(&[0; 4]);
+        buf.push(type_);
+        buf.extend_from_slice(&tag.to_le_bytes());
+        Encoder(buf)
+    }
+
+    pub(super) fn u8(mut self, v: u8) -> Self {
+        self.0.push(v);
+        self
+    }
+
+    pub(super) fn u16(mut self, v: u16) -> Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    pub(super) fn u32(mut self, v: u32) -> Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    pub(super) fn u64(mut self, v: u64) -> Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    pub(super) fn bytes(self, v: &[u8]) -> Self {
+        let mut enc = self.u16(v.len() as u16);
+        enc.0.extend_from_slice(v);
+        enc
+    }
+
+    pub(super) fn qid(self, qid: Qid) -> Self {
+        self.u8(qid.type_).u32(qid.version).u64(qid.path)
+    }
+
+    /// Appends raw bytes, already encoded.
+    pub(super) fn raw(mut self, v: &[u8]) -> Self {
+        self.0.extend_from_slice(v);
+        self
+    }
+
+    /// Returns the reply, with its size set.
+    pub(super) fn finish(self) -> Vec<u8> {
+        self.finish_header(0)
+    }
+
+    /// Returns the beginning of a reply followed by `payload` more bytes, with its size set.
+    pub(super) fn finish_header(mut self, payload: usize) -> Vec<u8> {
+        let size = (self.0.len() + payload) as u32;
+        self.0[..4].copy_from_slice(&size.to_le_bytes());
+        self.0
+    }
+}
+
+/// Encodes a directory entry of a `Rreaddir`, returning it.
+pub(super) fn dirent(qid: Qid, offset: u64, d_type: u32, name: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(24 + name.len());
+    buf.push(qid.type_);
+    buf.extend_from_slice(&qid.version.to_le_bytes());
+    buf.extend_from_slice(&qid.path.to_le_bytes());
+    buf.extend_from_slice(&offset.to_le_bytes());
+    buf.push(d_type as u8);
+    buf.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    buf.extend_from_slice(name);
+    buf
+}
@@ -0,0 +1,269 @@
+//! Hints to the host page cache about the reads of the guest.
+//!
+//! The data the guest reads from a share lands in its own page cache, so keeping it in the host
+//! page cache as well mostly doubles the memory it takes, and a guest streaming a large file
+//! through the share evicts everything else the host had cached. Under the [`FsPageCache`] policy
+//! of a share, each handle watches the offsets it reads at: once it has read a few requests one
+//! after the other, the host is told to read ahead of it further, and once the stream has gone on
+//! long enough, the pages behind it are dropped from the host cache in chunks. Without the cache
+//! at all, every page read is dropped right away.
+//!
+//! macOS can't drop a range of pages from its cache, so a handle that would drop some stops
+//! caching the pages it reads from then on instead.
+
+use std::io;
+use std::os::fd::RawFd;
+use std::sync::Mutex;
+
+use super::kinds::FsPageCache;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The number of reads one after the other that make a stream.
+const MIN_STREAK: u32 = 4;
+
+/// How much of a file a stream reads before the pages behind it are dropped, so that the files
+/// read whole but small stay cached.
+const STREAM_THRESHOLD: u64 = 64 << 20;
+
+/// The size of the chunks the pages behind a stream are dropped in.
+const DROP_CHUNK: u64 = 8 << 20;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// The reads of a handle, telling which hints to give the host page cache about them.
+#[derive(Debug, Default)]
+pub(crate) struct SequentialReads {
+    /// The offset the next read of the stream starts at.
+    next_offset: u64,
+
+    /// The number of reads of the stream so far.
+    streak: u32,
+
+    /// The offset the stream started at.
+    start: u64,
+
+    /// The end of the pages of the stream dropped so far.
+    dropped_end: u64,
+
+    /// Whether the host was told the handle reads sequentially.
+    sequential: bool,
+}
+
+/// The readahead the host page cache gives a handle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ReadAhead {
+    /// The default one.
+    Normal,
+    /// A larger one, for a handle reading a file one request after the other.
+    Sequential,
+}
+
+/// The hints to give the host page cache after a read.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct CacheHints {
+    /// The readahead to switch the handle to, if it changed.
+    pub readahead: Option<ReadAhead>,
+
+    /// The offset and length of the pages to drop from the cache, if any.
+    pub drop: Option<(u64, u64)>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl SequentialReads {
+    /// Records a read of `size` bytes at `offset` under `policy`, returning the hints to give the
+    /// host page cache about it.
+    pub(crate) fn record(&mut self, policy: FsPageCache, offset: u64, size: u64) -> CacheHints {
+        let end = offset.saturating_add(size);
+        let mut hints = CacheHints::default();
+        match policy {
+            FsPageCache::Host => (),
+            FsPageCache::NoCache => {
+                if size > 0 {
+                    hints.drop = Some((offset, size));
+                }
+            }
+            FsPageCache::Streaming => {
+                if self.streak > 0 && offset == self.next_offset {
+                    self.streak = self.streak.saturating_add(1);
+                } else {
+                    self.streak = 1;
+                    self.start = offset;
+                    self.dropped_end = offset;
+                    if self.sequential {
+                        self.sequential = false;
+                        hints.readahead = Some(ReadAhead::Normal);
+                    }
+                }
+                self.next_offset = end;
+
+                if self.streak < MIN_STREAK {
+                    return hints;
+                }
+                if !self.sequential {
+                    self.sequential = true;
+                    hints.readahead = Some(ReadAhead::Sequential);
+                }
+                if end - self.start >= STREAM_THRESHOLD && end - self.dropped_end >= DROP_CHUNK {
+                    hints.drop = Some((self.dropped_end, end - self.dropped_end));
+                    self.dropped_end = end;
+                }
+            }
+        }
+        hints
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Records a read of `size` bytes at `offset` of `fd` through a handle with the reads `reads`,
+/// and gives the host page cache the hints it calls for under `policy`. The hints are only advice,
+/// so failing to give them doesn't fail the read.
+pub(crate) fn advise_read(
+    reads: &Mutex<SequentialReads>,
+    policy: FsPageCache,
+    fd: RawFd,
+    offset: u64,
+    size: u64,
+) {
+    if policy == FsPageCache::Host {
+        return;
+    }
+    let hints = reads.lock().unwrap().record(policy, offset, size);
+    if let Err(e) = apply(fd, hints) {
+        debug!("virtio-fs: failed to give the page cache hints {hints:?}: {e}");
+    }
+}
+
+/// Gives the host page cache the `hints` about `fd`.
+#[cfg(target_os = "linux")]
+fn apply(fd: RawFd, hints: CacheHints) -> io::Result<()> {
+    if let Some(readahead) = hints.readahead {
+        let advice = match readahead {
+            ReadAhead::Normal => libc::POSIX_FADV_NORMAL,
+            ReadAhead::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+        };
+        fadvise(fd, 0, 0, advice)?;
+    }
+    if let Some((offset, length)) = hints.drop {
+        fadvise(fd, offset, length, libc::POSIX_FADV_DONTNEED)?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn fadvise(fd: RawFd, offset: u64, length: u64, advice: libc::c_int) -> io::Result<()> {
+    // Safe because this doesn't modify any memory.
+    let res = unsafe {
+        libc::posix_fadvise64(fd, offset as libc::off64_t, length as libc::off64_t, advice)
+    };
+    // Unlike most calls, this returns the error rather than setting errno.
+    if res != 0 {
+        return Err(io::Error::from_raw_os_error(res));
+    }
+    Ok(())
+}
+
+/// Gives the host page cache the `hints` about `fd`. The readahead is on by default, so only the
+/// sequential one is asked for, and the pages to drop stop the caching of `fd` altogether.
+#[cfg(target_os = "macos")]
+fn apply(fd: RawFd, hints: CacheHints) -> io::Result<()> {
+    if hints.readahead == Some(ReadAhead::Sequential) {
+        fcntl_on(fd, libc::F_RDAHEAD)?;
+    }
+    if hints.drop.is_some() {
+        fcntl_on(fd, libc::F_NOCACHE)?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn fcntl_on(fd: RawFd, cmd: libc::c_int) -> io::Result<()> {
+    // Safe because this doesn't modify any memory and we check the return value.
+    let res = unsafe { libc::fcntl(fd, cmd, 1) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const READ: u64 = 128 << 10;
+
+    #[test]
+    fn streaming_reads() {
+        let mut reads = SequentialReads::default();
+        let policy = FsPageCache::Streaming;
+
+        // A stream only starts after a few reads one after the other
+        for i in 0..3 {
+            assert_eq!(reads.record(policy, i * READ, READ), CacheHints::default());
+        }
+        assert_eq!(
+            reads.record(policy, 3 * READ, READ),
+            CacheHints {
+                readahead: Some(ReadAhead::Sequential),
+                drop: None,
+            }
+        );
+
+        // The pages behind it are dropped once it passes the threshold, a chunk at a time
+        let mut offset = 4 * READ;
+        let mut hints = CacheHints::default();
+        while hints.drop.is_none() {
+            hints = reads.record(policy, offset, READ);
+            offset += READ;
+        }
+        assert_eq!(offset, STREAM_THRESHOLD);
+        assert_eq!(hints.drop, Some((0, STREAM_THRESHOLD)));
+        let mut drops = 0;
+        for _ in 0..DROP_CHUNK / READ {
+            drops += usize::from(reads.record(policy, offset, READ).drop.is_some());
+            offset += READ;
+        }
+        assert_eq!(drops, 1);
+
+        // A read elsewhere breaks the stream
+        assert_eq!(
+            reads.record(policy, 0, READ),
+            CacheHints {
+                readahead: Some(ReadAhead::Normal),
+                drop: None,
+            }
+        );
+        assert_eq!(reads.record(policy, READ, READ), CacheHints::default());
+    }
+
+    #[test]
+    fn uncached_reads() {
+        let mut reads = SequentialReads::default();
+        assert_eq!(
+            reads.record(FsPageCache::NoCache, READ, READ).drop,
+            Some((READ, READ))
+        );
+        assert_eq!(
+            reads.record(FsPageCache::NoCache, READ, 0),
+            CacheHints::default()
+        );
+        assert_eq!(
+            reads.record(FsPageCache::Host, 0, READ),
+            CacheHints::default()
+        );
+    }
+}
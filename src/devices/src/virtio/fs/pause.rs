@@ -0,0 +1,282 @@
+//! Pausing of a share for the maintenance of the host volume backing it.
+//!
+//! To remount or check the file system a share is on, the host needs the device to leave it alone
+//! for a while, without the guest seeing errors. Pausing the device has its worker finish the
+//! requests it is handling and leave the next ones in the virtqueues, where the guest waits for
+//! them as it would for a slow disk, the size of the queues bounding how many it queues up. The
+//! buffered writes are written to the host first, and the revalidation of the attributes stops.
+//! With [`FsPauseOptions::close_files`], the device also saves the inodes and handles the guest
+//! holds and closes all its host files, to open them again by their paths once resumed, the way a
+//! snapshot is restored.
+//!
+//! A pause may be given a timeout, in case the embedder never resumes the device, past which the
+//! device either resumes on its own or fails the requests of the guest with `EIO` until resumed.
+
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use utils::eventfd::{EventFd, EFD_NONBLOCK};
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// How long a pause waits for the worker to get to it, while it handles a request.
+const QUIESCE_TIMEOUT: Duration = Duration::from_secs(10);
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// What a paused device does once its pause times out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FsPauseTimeout {
+    /// Resumes, as if the embedder had. This is the default.
+    #[default]
+    Resume,
+    /// Stays paused, failing the requests of the guest with `EIO` until resumed.
+    Fail,
+}
+
+/// How to pause a device.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FsPauseOptions {
+    /// Closes the host files of the share while paused. The pause fails if the guest holds files
+    /// that can't be found again by their paths, such as deleted ones, or has files of the share
+    /// mapped in the DAX window. Only Linux hosts support it.
+    pub close_files: bool,
+    /// The longest time the device stays paused, without limit if `None`.
+    pub timeout: Option<Duration>,
+    pub on_timeout: FsPauseTimeout,
+}
+
+/// Pauses and resumes the worker of a device. Clones share the same worker, so a handle obtained
+/// from the device before it is activated can be used while the guest is running.
+#[derive(Clone)]
+pub struct FsPause(Arc<PauseState>);
+
+struct PauseState {
+    /// Wakes the worker up when a pause or a resume is requested.
+    event: EventFd,
+    handshake: Mutex<Handshake>,
+    changed: Condvar,
+}
+
+struct Handshake {
+    /// Whether a worker is running.
+    attached: bool,
+    stage: Stage,
+    /// The error the worker hit pausing or resuming, for the request that waits for it.
+    error: Option<io::Error>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Stage {
+    Running,
+    /// A pause is requested, and taken up by the worker if `taken`.
+    Pausing {
+        options: FsPauseOptions,
+        taken: bool,
+    },
+    Paused,
+    Resuming,
+}
+
+/// A request for the worker.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PauseRequest {
+    Pause(FsPauseOptions),
+    Resume,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl FsPause {
+    pub fn new() -> io::Result<Self> {
+        Ok(FsPause(Arc::new(PauseState {
+            event: EventFd::new(EFD_NONBLOCK)?,
+            handshake: Mutex::new(Handshake {
+                attached: false,
+                stage: Stage::Running,
+                error: None,
+            }),
+            changed: Condvar::new(),
+        })))
+    }
+
+    /// Pauses the device, returning once it stopped using the share. Fails with `EAGAIN` if the
+    /// device isn't running, with `EALREADY` if it is paused already, and with `ETIMEDOUT` if it
+    /// didn't get to the pause in time, in which case it isn't paused.
+    pub fn pause(&self, options: FsPauseOptions) -> io::Result<()> {
+        let mut handshake = self.0.handshake.lock().unwrap();
+        if !handshake.attached {
+            return Err(io::Error::from_raw_os_error(libc::EAGAIN));
+        }
+        if handshake.stage != Stage::Running {
+            return Err(io::Error::from_raw_os_error(libc::EALREADY));
+        }
+        handshake.stage = Stage::Pausing {
+            options,
+            taken: false,
+        };
+        handshake.error = None;
+        self.0.event.write(1)?;
+
+        // Once the worker took the pause up, it reports back as soon as it has paused
+        let deadline = Instant::now() + QUIESCE_TIMEOUT;
+        loop {
+            match handshake.stage {
+                Stage::Pausing { taken: false, .. } => {
+                    let wait = deadline.saturating_duration_since(Instant::now());
+                    if wait.is_zero() {
+                        handshake.stage = Stage::Running;
+                        return Err(io::Error::from_raw_os_error(libc::ETIMEDOUT));
+                    }
+                    handshake = self.0.changed.wait_timeout(handshake, wait).unwrap().0;
+                }
+                Stage::Pausing { taken: true, .. } => {
+                    handshake = self.0.changed.wait(handshake).unwrap();
+                }
+                Stage::Paused => return Ok(()),
+                Stage::Running | Stage::Resuming => {
+                    return Err(handshake
+                        .error
+                        .take()
+                        .unwrap_or_else(|| io::Error::from_raw_os_error(libc::EAGAIN)));
+                }
+            }
+        }
+    }
+
+    /// Resumes the device, returning once it uses the share again. If the device closed its host
+    /// files and can't open them again, say because the volume isn't back, it stays paused and
+    /// the error is returned.
+    pub fn resume(&self) -> io::Result<()> {
+        let mut handshake = self.0.handshake.lock().unwrap();
+        if handshake.stage != Stage::Paused {
+            return Ok(());
+        }
+        handshake.stage = Stage::Resuming;
+        handshake.error = None;
+        self.0.event.write(1)?;
+
+        while handshake.stage == Stage::Resuming {
+            handshake = self.0.changed.wait(handshake).unwrap();
+        }
+        match handshake.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether the device is paused.
+    pub fn is_paused(&self) -> bool {
+        matches!(
+            self.0.handshake.lock().unwrap().stage,
+            Stage::Paused | Stage::Resuming
+        )
+    }
+
+    /// Records that a worker started or stopped. A stopped worker leaves the device running, and
+    /// fails the request waiting for it.
+    pub(crate) fn attach(&self, attached: bool) {
+        let mut handshake = self.0.handshake.lock().unwrap();
+        handshake.attached = attached;
+        if !attached {
+            if handshake.stage != Stage::Running {
+                handshake.error = Some(io::Error::from_raw_os_error(libc::EAGAIN));
+            }
+            handshake.stage = Stage::Running;
+            self.0.changed.notify_all();
+        }
+    }
+
+    /// Returns the event waking the worker up.
+    pub(crate) fn event(&self) -> &EventFd {
+        &self.0.event
+    }
+
+    /// Returns the request the worker was woken up for, if any.
+    pub(crate) fn take_request(&self) -> Option<PauseRequest> {
+        let _ = self.0.event.read();
+        let mut handshake = self.0.handshake.lock().unwrap();
+        match &mut handshake.stage {
+            Stage::Pausing { options, taken } if !*taken => {
+                *taken = true;
+                Some(PauseRequest::Pause(*options))
+            }
+            Stage::Resuming => Some(PauseRequest::Resume),
+            _ => None,
+        }
+    }
+
+    /// Reports how the worker handled the last request: the device is paused after a successful
+    /// pause or a failed resume, and running otherwise.
+    pub(crate) fn report(&self, result: io::Result<()>) {
+        let mut handshake = self.0.handshake.lock().unwrap();
+        let pausing = matches!(handshake.stage, Stage::Pausing { .. });
+        handshake.stage = match (pausing, result.is_ok()) {
+            (true, true) | (false, false) => Stage::Paused,
+            (true, false) | (false, true) => Stage::Running,
+        };
+        handshake.error = result.err();
+        self.0.changed.notify_all();
+    }
+
+    /// Records that the worker resumed on its own, its pause having timed out.
+    pub(crate) fn timed_out(&self) {
+        let mut handshake = self.0.handshake.lock().unwrap();
+        if handshake.stage == Stage::Paused {
+            handshake.stage = Stage::Running;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn pause_handshake() {
+        let pause = FsPause::new().unwrap();
+        let options = FsPauseOptions::default();
+        let err = pause.pause(options).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EAGAIN));
+
+        // A worker taking the requests up as they come
+        pause.attach(true);
+        let worker = {
+            let pause = pause.clone();
+            thread::spawn(move || {
+                for result in [
+                    Ok(()),
+                    Err(io::Error::from_raw_os_error(libc::ENOENT)),
+                    Ok(()),
+                ] {
+                    while pause.take_request().is_none() {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                    pause.report(result);
+                }
+            })
+        };
+
+        pause.pause(options).unwrap();
+        assert!(pause.is_paused());
+        let err = pause.pause(options).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EALREADY));
+
+        // A failed resume leaves the device paused
+        let err = pause.resume().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+        assert!(pause.is_paused());
+        pause.resume().unwrap();
+        assert!(!pause.is_paused());
+        pause.resume().unwrap();
+        worker.join().unwrap();
+    }
+}
@@ -0,0 +1,306 @@
+//! Preallocation of the top layer files the guest writes sequentially.
+//!
+//! A guest streaming a large file into the share writes it in requests of at most the size of its
+//! FUSE buffers, so the host file grows by many small extents that the host file system may not
+//! keep together. Once a handle has written a few requests one after the other, space is allocated
+//! ahead of the writes without changing the size of the file, in chunks that double as the stream
+//! goes on, the way readahead grows its window. What is left beyond the end of the file when the
+//! handle is released is given back.
+//!
+//! The data of a file copied up to the top layer is reserved the same way before it is copied, so
+//! that a top layer without room for it fails the copy-up with `ENOSPC` up front, rather than
+//! halfway through with a partial copy. Only the extents holding data are reserved, the holes of a
+//! sparse file staying holes in its copy.
+
+use std::io;
+use std::os::fd::RawFd;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The number of writes one after the other that make a stream.
+const MIN_STREAK: u32 = 4;
+
+/// The first chunk preallocated ahead of a stream.
+const MIN_CHUNK: u64 = 1 << 20;
+
+/// The chunk the preallocation of a stream stops growing at.
+const MAX_CHUNK: u64 = 64 << 20;
+
+/// The free space a reservation leaves on the file system, on top of the data, for the metadata of
+/// the file and the directory entries of the copy-up.
+const RESERVE_SLACK: u64 = 1 << 20;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// The writes of a handle, telling when to preallocate ahead of them.
+#[derive(Debug, Default)]
+pub(crate) struct SequentialWrites {
+    /// The offset the next write of the stream starts at.
+    next_offset: u64,
+
+    /// The number of writes of the stream so far.
+    streak: u32,
+
+    /// The last chunk preallocated ahead of the stream, 0 if none yet.
+    chunk: u64,
+
+    /// The end of the space preallocated through the handle.
+    preallocated_end: u64,
+
+    /// Whether the file system of the file can't preallocate.
+    unsupported: bool,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl SequentialWrites {
+    /// Records a write of `size` bytes at `offset`, returning the offset and length of the space to
+    /// preallocate ahead of it, if any.
+    ///
+    /// The next chunk is preallocated once the writes come within half a chunk of the end of the
+    /// space already preallocated, so that the file system never waits for it.
+    pub(crate) fn record(&mut self, offset: u64, size: u64) -> Option<(u64, u64)> {
+        if offset == self.next_offset {
+            self.streak = self.streak.saturating_add(1);
+        } else {
+            self.streak = 1;
+            self.chunk = 0;
+        }
+        let end = offset.saturating_add(size);
+        self.next_offset = end;
+
+        if self.unsupported
+            || self.streak < MIN_STREAK
+            || end.saturating_add(self.chunk / 2) < self.preallocated_end
+        {
+            return None;
+        }
+
+        self.chunk = (self.chunk * 2).clamp(MIN_CHUNK, MAX_CHUNK);
+        let start = end.max(self.preallocated_end);
+        let target = end.saturating_add(self.chunk);
+        self.preallocated_end = target;
+        Some((start, target - start))
+    }
+
+    /// Stops preallocating, after the file system failed to.
+    pub(crate) fn stop(&mut self) {
+        self.unsupported = true;
+    }
+
+    /// Returns the end of the space preallocated through the handle, if any.
+    pub(crate) fn preallocated_end(&self) -> Option<u64> {
+        (self.preallocated_end > 0).then_some(self.preallocated_end)
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Allocates `length` bytes of `fd` from `offset`, without changing its size.
+#[cfg(target_os = "linux")]
+pub(crate) fn preallocate(fd: RawFd, offset: u64, length: u64) -> io::Result<()> {
+    // Safe because this doesn't modify any memory and we check the return value.
+    let res = unsafe {
+        libc::fallocate64(
+            fd,
+            libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off64_t,
+            length as libc::off64_t,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Allocates `length` more bytes of `fd` past the space it already has, without changing its size.
+/// The chunks of a stream follow each other, so this is the space from `offset`.
+#[cfg(target_os = "macos")]
+pub(crate) fn preallocate(fd: RawFd, _offset: u64, length: u64) -> io::Result<()> {
+    let mut store = libc::fstore_t {
+        fst_flags: libc::F_ALLOCATECONTIG,
+        fst_posmode: libc::F_PEOFPOSMODE,
+        fst_offset: 0,
+        fst_length: length as libc::off_t,
+        fst_bytesalloc: 0,
+    };
+
+    // Safe because `store` outlives the call and we check the return value.
+    let res = unsafe { libc::fcntl(fd, libc::F_PREALLOCATE, &mut store as *mut _) };
+    if res < 0 {
+        store.fst_flags = libc::F_ALLOCATEALL;
+        // Safe for the same reason.
+        let res = unsafe { libc::fcntl(fd, libc::F_PREALLOCATE, &mut store as *mut _) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Reserves the `extents` of `fd`, as their offsets and lengths, to be written next, failing with
+/// `ENOSPC` if the file system doesn't have room for all of them and a little more. The free space
+/// is checked first, as not all file systems can preallocate, and those that can't just go without
+/// the reservation.
+#[allow(clippy::useless_conversion)]
+pub(crate) fn reserve(fd: RawFd, extents: &[(u64, u64)]) -> io::Result<()> {
+    let length = extents
+        .iter()
+        .fold(0u64, |total, (_, length)| total.saturating_add(*length));
+    if length == 0 {
+        return Ok(());
+    }
+
+    let mut st = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // Safe because the kernel only writes to `st` and we check the return value.
+    if unsafe { libc::fstatvfs(fd, st.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because `fstatvfs` succeeded.
+    let st = unsafe { st.assume_init() };
+    let available = u64::from(st.f_bavail).saturating_mul(u64::from(st.f_frsize));
+    if !has_room(available, length) {
+        return Err(io::Error::from_raw_os_error(libc::ENOSPC));
+    }
+
+    for &(offset, length) in extents {
+        match preallocate(fd, offset, length) {
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ENOSPC | libc::EDQUOT)) => {
+                return Err(e)
+            }
+            // The file system can't preallocate
+            Err(_) => break,
+            Ok(()) => (),
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether `available` bytes of free space leave room for `length` bytes of data.
+fn has_room(available: u64, length: u64) -> bool {
+    length
+        .checked_add(RESERVE_SLACK)
+        .is_some_and(|needed| needed <= available)
+}
+
+/// Gives back the space preallocated in `fd` up to `end` beyond the end of the file, which
+/// truncating the file to its size does. Nothing else may resize the file meanwhile.
+pub(crate) fn trim(fd: RawFd, end: u64) -> io::Result<()> {
+    let mut st = std::mem::MaybeUninit::<libc::stat>::uninit();
+    // Safe because the kernel only writes to `st` and we check the return value.
+    if unsafe { libc::fstat(fd, st.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because `fstat` succeeded.
+    let size = unsafe { st.assume_init() }.st_size;
+    if end <= size as u64 {
+        return Ok(());
+    }
+
+    // Safe because this doesn't modify any memory and we check the return value.
+    if unsafe { libc::ftruncate(fd, size) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const WRITE: u64 = 128 << 10;
+
+    #[test]
+    fn sequential_writes() {
+        let mut writes = SequentialWrites::default();
+        assert_eq!(writes.preallocated_end(), None);
+
+        // A stream only starts after a few writes one after the other
+        for i in 0..3 {
+            assert_eq!(writes.record(i * WRITE, WRITE), None);
+        }
+        assert_eq!(
+            writes.record(3 * WRITE, WRITE),
+            Some((4 * WRITE, MIN_CHUNK))
+        );
+
+        // The next chunk, twice as large, comes once the writes are within half a chunk of the end
+        let mut offset = 4 * WRITE;
+        let mut next = None;
+        while next.is_none() {
+            offset += WRITE;
+            next = writes.record(offset - WRITE, WRITE);
+        }
+        assert_eq!(offset, 4 * WRITE + MIN_CHUNK / 2);
+        assert_eq!(next, Some((4 * WRITE + MIN_CHUNK, 3 * MIN_CHUNK / 2)));
+        let end = writes.preallocated_end().unwrap();
+        assert_eq!(end, offset + 2 * MIN_CHUNK);
+
+        // Writing elsewhere ends the stream, but the space preallocated stays to be trimmed
+        assert_eq!(writes.record(0, WRITE), None);
+        assert_eq!(writes.preallocated_end(), Some(end));
+
+        // The chunks stop growing at the largest
+        let mut writes = SequentialWrites::default();
+        for i in 0..10_000 {
+            writes.record(i * WRITE, WRITE);
+        }
+        let ahead = writes.preallocated_end().unwrap() - 10_000 * WRITE;
+        assert!(ahead > MAX_CHUNK / 2 && ahead <= MAX_CHUNK);
+
+        writes.stop();
+        assert_eq!(writes.record(10_000 * WRITE, WRITE), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn preallocate_trim() {
+        use std::io::Write;
+        use std::os::fd::AsRawFd;
+        use std::os::unix::fs::MetadataExt;
+
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&[1; 4096]).unwrap();
+        let fd = file.as_raw_fd();
+
+        if preallocate(fd, 4096, MIN_CHUNK).is_err() {
+            // The file system of the temporary directory can't preallocate
+            return;
+        }
+        let metadata = file.metadata().unwrap();
+        assert_eq!(metadata.len(), 4096);
+        assert!(metadata.blocks() * 512 >= 4096 + MIN_CHUNK);
+
+        trim(fd, 4096 + MIN_CHUNK).unwrap();
+        let metadata = file.metadata().unwrap();
+        assert_eq!(metadata.len(), 4096);
+        assert!(metadata.blocks() * 512 < MIN_CHUNK);
+    }
+
+    #[test]
+    fn reserve_space() {
+        use std::os::fd::AsRawFd;
+
+        assert!(has_room(RESERVE_SLACK + 10, 10));
+        assert!(!has_room(RESERVE_SLACK + 10, 11));
+        assert!(!has_room(u64::MAX, u64::MAX));
+
+        let file = tempfile::tempfile().unwrap();
+        let fd = file.as_raw_fd();
+        reserve(fd, &[(0, 4096)]).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 0);
+        let e = reserve(fd, &[(0, u64::MAX / 2)]).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOSPC));
+        let e = reserve(fd, &[(0, u64::MAX / 4), (u64::MAX / 2, u64::MAX / 4)]).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOSPC));
+    }
+}
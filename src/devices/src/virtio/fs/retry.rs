@@ -0,0 +1,297 @@
+//! Retries of the host system calls failing with transient errors.
+//!
+//! A system call interrupted by a signal fails with `EINTR`, and one hitting a passing shortage of
+//! the host, such as the system-wide limit of open files (`ENFILE`) or of kernel memory (`ENOMEM`
+//! and `EAGAIN`), fails although trying again a moment later would succeed. Passing these errors
+//! on to the guest fails its requests for nothing, e.g. a build that opens many files at once. The
+//! system calls opening and stat'ing the host files, and the reads and writes of their data, are
+//! instead tried again: right away and for as long as they are interrupted, and with a backoff
+//! doubling from `INITIAL_BACKOFF` up to `MAX_RETRIES` times for the shortages, so a shortage that
+//! lasts still fails the request after a little while.
+//!
+//! `EAGAIN` is also what a file opened with `O_NONBLOCK` returns when it isn't ready, which the
+//! guest asked for, so the reads and writes of such files aren't retried on it. The retries of all
+//! the shares are counted in [`FsRetryStats`].
+
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The most times a system call is tried again after a shortage.
+const MAX_RETRIES: u32 = 6;
+
+/// The wait before the first retry after a shortage, doubling at each of the next ones.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(1);
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// The statistics of the retries of the host system calls of all the shares of the process.
+#[derive(Debug, Default)]
+pub struct FsRetryStats {
+    interrupted: AtomicU64,
+    retries: AtomicU64,
+    recovered: AtomicU64,
+    exhausted: AtomicU64,
+}
+
+/// The retries of a system call.
+struct Retry {
+    retries: u32,
+    backoff: Duration,
+    /// Whether `EAGAIN` is a shortage rather than a file not ready.
+    again_is_shortage: bool,
+}
+
+/// The results of the system calls that return a negative value on failure, with the error in
+/// `errno`.
+pub(crate) trait SyscallResult: Copy {
+    fn failed(self) -> bool;
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl FsRetryStats {
+    const fn new() -> Self {
+        FsRetryStats {
+            interrupted: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+            recovered: AtomicU64::new(0),
+            exhausted: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the statistics of the process.
+    pub fn global() -> &'static FsRetryStats {
+        static STATS: FsRetryStats = FsRetryStats::new();
+        &STATS
+    }
+
+    /// Returns the number of system calls tried again after being interrupted by a signal.
+    pub fn interrupted(&self) -> u64 {
+        self.interrupted.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of system calls tried again after a shortage of the host.
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of system calls that succeeded after being tried again.
+    pub fn recovered(&self) -> u64 {
+        self.recovered.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of system calls that still hit a shortage after all their retries, their
+    /// error being passed on to the guest.
+    pub fn exhausted(&self) -> u64 {
+        self.exhausted.load(Ordering::Relaxed)
+    }
+}
+
+impl Retry {
+    fn new(again_is_shortage: bool) -> Self {
+        Retry {
+            retries: 0,
+            backoff: INITIAL_BACKOFF,
+            again_is_shortage,
+        }
+    }
+
+    /// Returns whether to try again after the error `errno`, having waited for it if needed.
+    fn again(&mut self, errno: Option<i32>) -> bool {
+        let stats = FsRetryStats::global();
+        match errno {
+            Some(libc::EINTR) => {
+                stats.interrupted.fetch_add(1, Ordering::Relaxed);
+            }
+            Some(libc::ENFILE | libc::ENOMEM) => return self.backoff(),
+            Some(libc::EAGAIN) if self.again_is_shortage => return self.backoff(),
+            _ => return false,
+        }
+        self.retries += 1;
+        true
+    }
+
+    fn backoff(&mut self) -> bool {
+        let stats = FsRetryStats::global();
+        if self.retries >= MAX_RETRIES {
+            stats.exhausted.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        stats.retries.fetch_add(1, Ordering::Relaxed);
+        thread::sleep(self.backoff);
+        self.backoff *= 2;
+        self.retries += 1;
+        true
+    }
+
+    fn succeeded(&self) {
+        if self.retries > 0 {
+            FsRetryStats::global()
+                .recovered
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl SyscallResult for i32 {
+    fn failed(self) -> bool {
+        self < 0
+    }
+}
+
+impl SyscallResult for isize {
+    fn failed(self) -> bool {
+        self < 0
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Runs the system call `op`, again while it fails with a transient error. Returns the result of
+/// the last try, `errno` holding its error if it failed.
+pub(crate) fn retry_syscall<T: SyscallResult>(mut op: impl FnMut() -> T) -> T {
+    let mut retry = Retry::new(true);
+    loop {
+        let res = op();
+        if !res.failed() {
+            retry.succeeded();
+            return res;
+        }
+        // Read before anything else can change it
+        let err = io::Error::last_os_error();
+        if !retry.again(err.raw_os_error()) {
+            // Restores the error of the last try, which the bookkeeping may have changed
+            set_errno(err.raw_os_error().unwrap_or(0));
+            return res;
+        }
+    }
+}
+
+/// Runs `op`, reading or writing the data of `file`, again while it fails with a transient error.
+/// `EAGAIN` is only taken for a shortage if `file` isn't non-blocking.
+pub(crate) fn retry_file_io<T>(
+    file: &File,
+    mut op: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    let mut retry = None;
+    loop {
+        match op() {
+            Ok(value) => {
+                if let Some(retry) = &retry {
+                    Retry::succeeded(retry);
+                }
+                return Ok(value);
+            }
+            Err(e) => {
+                let retry = retry.get_or_insert_with(|| Retry::new(!is_nonblocking(file)));
+                if !retry.again(e.raw_os_error()) {
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+fn is_nonblocking(file: &File) -> bool {
+    // Safe because this doesn't modify any memory.
+    let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+    flags >= 0 && flags & libc::O_NONBLOCK != 0
+}
+
+fn set_errno(errno: i32) {
+    // Safe because the pointer to `errno` is valid for the thread.
+    #[cfg(target_os = "linux")]
+    unsafe {
+        *libc::__errno_location() = errno
+    };
+    // Safe for the same reason.
+    #[cfg(target_os = "macos")]
+    unsafe {
+        *libc::__error() = errno
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn retry_transient_errors() {
+        let fail = |errno| {
+            set_errno(errno);
+            -1
+        };
+
+        // Interrupted calls are tried again for as long as they are
+        let mut tries = 0;
+        let res = retry_syscall(|| {
+            tries += 1;
+            if tries < 20 {
+                fail(libc::EINTR)
+            } else {
+                0
+            }
+        });
+        assert_eq!((res, tries), (0, 20));
+
+        // Shortages a bounded number of times, leaving the error in errno
+        let mut tries = 0;
+        let res = retry_syscall(|| {
+            tries += 1;
+            fail(libc::ENFILE)
+        });
+        assert_eq!((res, tries), (-1, MAX_RETRIES + 1));
+        assert_eq!(
+            io::Error::last_os_error().raw_os_error(),
+            Some(libc::ENFILE)
+        );
+
+        // Other errors never
+        let mut tries = 0;
+        assert_eq!(
+            retry_syscall(|| {
+                tries += 1;
+                fail(libc::ENOENT)
+            }),
+            -1
+        );
+        assert_eq!(tries, 1);
+
+        // A non-blocking file that isn't ready fails right away
+        let (reader, _writer) = std::os::unix::net::UnixStream::pair().unwrap();
+        reader.set_nonblocking(true).unwrap();
+        let file = File::from(std::os::fd::OwnedFd::from(reader));
+        let mut tries = 0;
+        let err = retry_file_io(&file, || {
+            tries += 1;
+            Err::<(), _>(io::Error::from_raw_os_error(libc::EAGAIN))
+        })
+        .unwrap_err();
+        assert_eq!((err.raw_os_error(), tries), (Some(libc::EAGAIN), 1));
+        let mut tries = 0;
+        retry_file_io(&file, || {
+            tries += 1;
+            match tries {
+                1 => Err(io::Error::from_raw_os_error(libc::ENOMEM)),
+                _ => Ok(()),
+            }
+        })
+        .unwrap();
+        assert_eq!(tries, 2);
+        assert!(FsRetryStats::global().recovered() > 0);
+    }
+}
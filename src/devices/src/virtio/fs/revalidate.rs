@@ -0,0 +1,339 @@
+//! Revalidation of the attributes the guest cached for files changed on the host.
+//!
+//! Other host processes may change the files backing a share, while the guest keeps using the
+//! attributes it cached until their timeout expires. The revalidator re-stats the inodes the guest
+//! recently accessed at a low rate, the ones it has open first, and flags those that changed. The
+//! device has no notification queue to push an invalidation to the guest, so a change is pushed
+//! with the next replies about the inode instead: the inode is considered volatile for a while, and
+//! its attributes are handed out without letting the guest cache them, and the next open of the
+//! file drops the data the guest cached. The guest holding a lease on the inode is told right away
+//! instead, through the notification queue.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::bindings;
+use super::filesystem::{Context, FileSystem};
+use super::lease::Leases;
+use super::FsImpl;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The most inodes re-stat'ed per interval, to keep the revalidation cheap on large working sets.
+const BATCH_SIZE: usize = 64;
+
+/// Inodes the guest neither accessed for this many intervals nor has open are no longer tracked.
+const RECENT_INTERVALS: u32 = 60;
+
+/// Inodes stay volatile for this many intervals after the last change detected.
+const VOLATILE_INTERVALS: u32 = 10;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// Tracks the inodes the guest accessed and detects the changes made to them on the host. A
+/// disabled revalidator tracks nothing.
+#[derive(Default)]
+pub(crate) struct Revalidator(Option<Arc<RevalidatorState>>);
+
+struct RevalidatorState {
+    interval: Duration,
+    leases: Leases,
+    inodes: Mutex<HashMap<u64, TrackedInode>>,
+    /// Whether the device is paused, held for the whole of a revalidation so that pausing waits
+    /// for the one under way
+    paused: Mutex<bool>,
+}
+
+struct TrackedInode {
+    /// The attributes last seen, if they are known to be the ones the guest has
+    stamp: Option<Stamp>,
+    /// Bumped by the changes of the guest, so that a revalidation racing with one of them doesn't
+    /// mistake it for a change made on the host
+    guest_changes: u64,
+    opens: u32,
+    last_access: Instant,
+    last_check: Option<Instant>,
+    /// Whether a change was detected since the file was last opened
+    changed: bool,
+    volatile_until: Option<Instant>,
+}
+
+/// The attributes whose change makes what the guest cached stale.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    size: i64,
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl Revalidator {
+    /// Creates a revalidator re-stating the inodes of `fs` every `interval`, or a disabled one if
+    /// `interval` is `None`, breaking the `leases` on those found changed. The revalidation thread
+    /// exits once the revalidator or the file system is dropped.
+    pub(crate) fn new(fs: &Arc<FsImpl>, interval: Option<Duration>, leases: Leases) -> Self {
+        let Some(interval) = interval else {
+            return Self::default();
+        };
+
+        let state = Arc::new(RevalidatorState {
+            interval,
+            leases,
+            inodes: Mutex::new(HashMap::new()),
+            paused: Mutex::new(false),
+        });
+        let weak_state = Arc::downgrade(&state);
+        let weak_fs = Arc::downgrade(fs);
+        thread::Builder::new()
+            .name("fs revalidate".into())
+            .spawn(move || revalidate_periodically(weak_state, weak_fs, interval))
+            .unwrap();
+
+        Revalidator(Some(state))
+    }
+
+    /// Records that the guest was handed the attributes `st` of `inode`, returning whether the
+    /// inode is volatile, in which case the guest shouldn't cache them.
+    pub(crate) fn accessed(&self, inode: u64, st: &bindings::stat64) -> bool {
+        let Some(state) = &self.0 else {
+            return false;
+        };
+
+        let now = Instant::now();
+        let mut inodes = state.inodes.lock().unwrap();
+        let tracked = inodes
+            .entry(inode)
+            .or_insert_with(|| TrackedInode::new(now));
+        tracked.stamp = Some(Stamp::new(st));
+        tracked.last_access = now;
+        tracked.volatile_until.is_some_and(|until| until > now)
+    }
+
+    /// Records that the guest opened `inode`, returning whether it changed on the host since it
+    /// was last opened, in which case the guest should drop the data it cached.
+    pub(crate) fn opened(&self, inode: u64) -> bool {
+        let Some(state) = &self.0 else {
+            return false;
+        };
+
+        let now = Instant::now();
+        let mut inodes = state.inodes.lock().unwrap();
+        let tracked = inodes
+            .entry(inode)
+            .or_insert_with(|| TrackedInode::new(now));
+        tracked.opens += 1;
+        tracked.last_access = now;
+        std::mem::take(&mut tracked.changed)
+    }
+
+    /// Records that the guest closed `inode`.
+    pub(crate) fn released(&self, inode: u64) {
+        if let Some(state) = &self.0 {
+            if let Some(tracked) = state.inodes.lock().unwrap().get_mut(&inode) {
+                tracked.opens = tracked.opens.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Records that the guest changed `inode`, whose new attributes are then taken as they are on
+    /// the next revalidation rather than as a change made on the host.
+    pub(crate) fn changed_by_guest(&self, inode: u64) {
+        if let Some(state) = &self.0 {
+            if let Some(tracked) = state.inodes.lock().unwrap().get_mut(&inode) {
+                tracked.stamp = None;
+                tracked.guest_changes += 1;
+            }
+        }
+    }
+
+    /// Stops or restarts the revalidation while the device is paused, returning once no
+    /// revalidation is under way.
+    pub(crate) fn set_paused(&self, paused: bool) {
+        if let Some(state) = &self.0 {
+            *state.paused.lock().unwrap() = paused;
+        }
+    }
+}
+
+impl RevalidatorState {
+    /// Re-stats the inodes that are due, the ones the guest has open or holds a lease on first,
+    /// and flags those whose attributes changed.
+    fn revalidate(&self, fs: &FsImpl) {
+        let now = Instant::now();
+        self.leases.expire();
+        let due: Vec<(u64, u64)> = {
+            let mut inodes = self.inodes.lock().unwrap();
+            let recent = self.interval * RECENT_INTERVALS;
+            inodes.retain(|inode, tracked| {
+                tracked.opens > 0
+                    || now.duration_since(tracked.last_access) < recent
+                    || self.leases.is_held(*inode)
+            });
+
+            let mut due: Vec<_> = inodes
+                .iter()
+                .map(|(inode, tracked)| {
+                    let in_use = tracked.opens > 0 || self.leases.is_held(*inode);
+                    let key = (!in_use, tracked.last_check);
+                    (key, *inode, tracked.guest_changes)
+                })
+                .collect();
+            due.sort_unstable_by_key(|(key, ..)| *key);
+            due.into_iter()
+                .take(BATCH_SIZE)
+                .map(|(_, inode, guest_changes)| (inode, guest_changes))
+                .collect()
+        };
+
+        // The inodes are re-stat'ed on behalf of the device rather than of a guest process
+        let ctx = Context {
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        };
+        for (inode, guest_changes) in due {
+            let res = fs.getattr(ctx, inode, None);
+
+            let mut inodes = self.inodes.lock().unwrap();
+            let Ok((st, _)) = res else {
+                // The inode is gone
+                inodes.remove(&inode);
+                continue;
+            };
+            let Some(tracked) = inodes.get_mut(&inode) else {
+                continue;
+            };
+
+            tracked.last_check = Some(now);
+            if tracked.guest_changes != guest_changes {
+                continue;
+            }
+
+            let stamp = Stamp::new(&st);
+            if tracked.stamp.is_some_and(|old| old != stamp) {
+                debug!("inode {inode} changed on the host");
+                tracked.changed = true;
+                tracked.volatile_until = Some(now + self.interval * VOLATILE_INTERVALS);
+                self.leases.break_lease(inode);
+            }
+            tracked.stamp = Some(stamp);
+        }
+    }
+}
+
+impl TrackedInode {
+    fn new(now: Instant) -> Self {
+        TrackedInode {
+            stamp: None,
+            guest_changes: 0,
+            opens: 0,
+            last_access: now,
+            last_check: None,
+            changed: false,
+            volatile_until: None,
+        }
+    }
+}
+
+impl Stamp {
+    fn new(st: &bindings::stat64) -> Self {
+        Stamp {
+            size: st.st_size,
+            mtime: (st.st_mtime, st.st_mtime_nsec),
+            ctime: (st.st_ctime, st.st_ctime_nsec),
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// The body of the revalidation thread.
+fn revalidate_periodically(state: Weak<RevalidatorState>, fs: Weak<FsImpl>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let (Some(state), Some(fs)) = (state.upgrade(), fs.upgrade()) else {
+            return;
+        };
+        let paused = state.paused.lock().unwrap();
+        if !*paused {
+            state.revalidate(&fs);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::CString;
+    use std::fs;
+
+    use super::*;
+    use crate::virtio::fs::passthrough::{self, PassthroughFs};
+    use crate::virtio::fs::FsLeases;
+    use crate::virtio::fuse::FsOptions;
+
+    #[test]
+    fn revalidate() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("file"), b"a").unwrap();
+        fs::write(dir.path().join("other"), b"a").unwrap();
+
+        let fs = Arc::new(FsImpl::Passthrough(Box::new(
+            PassthroughFs::new(passthrough::Config {
+                root_dir: dir.path().to_str().unwrap().to_string(),
+                ..Default::default()
+            })
+            .unwrap(),
+        )));
+        fs.init(FsOptions::empty()).unwrap();
+        let lookup = |name: &str| {
+            fs.lookup(Context::default(), 1, &CString::new(name).unwrap())
+                .unwrap()
+        };
+
+        // The thread is only started by `new`, and is kept from running here by the interval
+        let leases = Leases::new(Some(FsLeases {
+            timeout: Duration::from_secs(3600),
+        }));
+        let revalidator = Revalidator::new(&fs, Some(Duration::from_secs(3600)), leases.clone());
+        let state = revalidator.0.as_ref().unwrap();
+
+        let file = lookup("file");
+        let other = lookup("other");
+        assert!(!revalidator.accessed(file.inode, &file.attr));
+        assert!(!revalidator.accessed(other.inode, &other.attr));
+        assert!(!revalidator.opened(file.inode));
+        leases.grant(file.inode);
+        leases.grant(other.inode);
+
+        // A change made by another host process is detected, and breaks the lease on the file
+        fs::write(dir.path().join("file"), b"ab").unwrap();
+        state.revalidate(&fs);
+        assert_eq!(leases.take_broken(), vec![file.inode]);
+        assert!(revalidator.accessed(file.inode, &lookup("file").attr));
+        assert!(revalidator.opened(file.inode));
+        assert!(!revalidator.opened(file.inode));
+
+        // While a change made by the guest isn't
+        fs::write(dir.path().join("other"), b"ab").unwrap();
+        revalidator.changed_by_guest(other.inode);
+        state.revalidate(&fs);
+        assert!(!revalidator.accessed(other.inode, &lookup("other").attr));
+        assert!(!revalidator.opened(other.inode));
+        assert!(leases.take_broken().is_empty());
+
+        // A disabled revalidator tracks nothing
+        let revalidator = Revalidator::new(&fs, None, Leases::default());
+        assert!(!revalidator.accessed(file.inode, &file.attr));
+    }
+}
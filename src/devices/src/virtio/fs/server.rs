@@ -4,21 +4,43 @@ use crossbeam_channel::Sender;
 use utils::worker_message::WorkerMessage;
 
 use std::convert::TryInto;
-use std::ffi::{CStr, CString};
+use std::ffi::{CStr, CString, OsStr};
 use std::fs::File;
 use std::io::{self, Read, Write};
 use std::mem::size_of;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use vm_memory::ByteValued;
 
 use super::super::linux_errno::linux_error;
+use super::coalesce::WriteCoalescer;
+use super::credentials::{self, FsCredentials, FsIdentity};
+use super::degrade::FsDegradation;
 use super::descriptor_utils::{Reader, Writer};
+use super::dir_template::{self, DirTemplates, FsDirTemplate};
 use super::filesystem::{Context, DirEntry, Entry, Extensions, FileSystem, GetxattrReply, ListxattrReply, SecContext, ZeroCopyReader, ZeroCopyWriter};
 use super::fs_utils::einval;
 use super::fuse::*;
-use super::{bindings, FsImpl};
+use super::init_config::init_config_file;
+use super::inode_numbers::InodeNumbers;
+use super::inspect;
+use super::lease::{self, Leases};
+use super::mode_policy::GuestModes;
+use super::retry::retry_file_io;
+use super::revalidate::Revalidator;
+use super::snapshot::{self, FsState};
+use super::trace::RequestTrace;
+use super::unsupported::FsUnsupportedStats;
+use super::virtual_file::{is_virtual_inode, FsVirtualFile, VirtualFiles};
+use super::watch::{FsWatchOp, FsWatcher};
+use super::{
+    bindings, FsAccessRules, FsBackgroundLimits, FsCacheTimeouts, FsImpl, FsLeases,
+    FsWriteCoalescing,
+};
 use super::{FsError as Error, Result};
 use crate::virtio::VirtioShmRegion;
 
@@ -30,6 +52,14 @@ pub(super) const MAX_BUFFER_SIZE: u32 = 1 << 20;
 pub(super) const BUFFER_HEADER_SIZE: u32 = 0x1000;
 pub(super) const DIRENT_PADDING: [u8; 8] = [0; 8];
 
+/// The name the init binary is served under in every directory of the share.
+const INIT_NAME: &[u8] = b"init.krun";
+
+/// The `VIRTIO_IOC_DROP_CACHES` ioctl, `_IO('v', 5)` as encoded by the Linux guest, issued by
+/// guest root on the root of the share to have the device drop its caches, like
+/// `/proc/sys/vm/drop_caches` does for the guest, and write the writes it buffered to the host.
+const VIRTIO_IOC_DROP_CACHES_REQ: u32 = 0x7605;
+
 //--------------------------------------------------------------------------------------------------
 // Types
 //--------------------------------------------------------------------------------------------------
@@ -40,23 +70,388 @@ pub(super) const DIRENT_PADDING: [u8; 8] = [0; 8];
 /// - [`PassthroughFs`]: For direct passthrough access to the host filesystem
 /// - [`OverlayFs`]: For overlayfs functionality to combine multiple filesystem layers
 pub struct FsImplServer {
-    fs: FsImpl,
+    fs: Arc<FsImpl>,
     options: AtomicU64,
+    access_rules: Option<FsAccessRules>,
+    credentials: FsCredentials,
+    cache_timeouts: FsCacheTimeouts,
+    background_limits: FsBackgroundLimits,
+    watcher: FsWatcher,
+    revalidator: Revalidator,
+    leases: Leases,
+    coalescer: WriteCoalescer,
+    virtual_files: VirtualFiles,
+    dir_templates: DirTemplates,
+    inode_numbers: InodeNumbers,
+    guest_modes: GuestModes,
+    degradation: FsDegradation,
+    unsupported: FsUnsupportedStats,
 }
 
-struct ZCReader<'a>(Reader<'a>);
+pub(super) struct ZCReader<'a>(pub(super) Reader<'a>);
 
-struct ZCWriter<'a>(Writer<'a>);
+pub(super) struct ZCWriter<'a>(pub(super) Writer<'a>);
 
 //--------------------------------------------------------------------------------------------------
 // Methods
 //--------------------------------------------------------------------------------------------------
 
 impl FsImplServer {
-    pub fn new(fs: FsImpl) -> FsImplServer {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        fs: FsImpl,
+        access_rules: Option<FsAccessRules>,
+        credentials: Option<FsCredentials>,
+        cache_timeouts: FsCacheTimeouts,
+        background_limits: FsBackgroundLimits,
+        watcher: FsWatcher,
+        revalidate_interval: Option<Duration>,
+        write_coalescing: Option<FsWriteCoalescing>,
+        mut virtual_files: Vec<FsVirtualFile>,
+        protect_init_config: bool,
+        leases: Option<FsLeases>,
+        inspect_socket: Option<PathBuf>,
+        dir_templates: Vec<FsDirTemplate>,
+        inode_numbers: InodeNumbers,
+        guest_modes: GuestModes,
+        degradation: FsDegradation,
+        unsupported: FsUnsupportedStats,
+    ) -> FsImplServer {
+        let fs = Arc::new(fs);
+        if protect_init_config {
+            virtual_files.push(init_config_file(&fs));
+        }
+        if let Some(socket_path) = inspect_socket {
+            if let Err(e) = inspect::start(&fs, &socket_path) {
+                error!(
+                    "failed to serve the inspection socket {}: {e}",
+                    socket_path.display()
+                );
+            }
+        }
+        // Leases are broken by the revalidation
+        let revalidate_interval = revalidate_interval.or(leases.map(|_| lease::CHECK_INTERVAL));
+        let leases = Leases::new(leases);
+        let revalidator = Revalidator::new(&fs, revalidate_interval, leases.clone());
+        let coalescer = WriteCoalescer::new(&fs, write_coalescing);
         FsImplServer {
             fs,
             options: AtomicU64::new(FsOptions::empty().bits()),
+            access_rules,
+            credentials: credentials.unwrap_or_else(|| Arc::new(FsIdentity)),
+            cache_timeouts,
+            background_limits,
+            watcher,
+            revalidator,
+            leases,
+            coalescer,
+            virtual_files: VirtualFiles::new(virtual_files),
+            dir_templates: DirTemplates::new(dir_templates),
+            inode_numbers,
+            guest_modes,
+            degradation,
+            unsupported,
+        }
+    }
+
+    /// Reports a change of the entry `name` of the directory `parent` to the watchers of the share.
+    fn notify_entry(&self, parent: u64, name: &[u8], op: FsWatchOp) {
+        self.revalidator.changed_by_guest(parent);
+        if !self.watcher.is_active() {
+            return;
+        }
+        if let (Some(dir), Ok(name)) = (self.fs.inode_path(parent), bytes_to_cstr(name)) {
+            self.watcher
+                .record(dir.join(OsStr::from_bytes(name.to_bytes())), op);
+        }
+    }
+
+    /// Reports a change of `inode` to the watchers of the share.
+    fn notify_inode(&self, inode: u64, op: FsWatchOp) {
+        self.revalidator.changed_by_guest(inode);
+        if !self.watcher.is_active() {
+            return;
+        }
+        if let Some(path) = self.fs.inode_path(inode) {
+            self.watcher.record(path, op);
+        }
+    }
+
+    /// Returns the error hit writing the buffered writes of `handle` of `inode` to the host, if
+    /// any, as the result of a request reporting it to the guest.
+    fn coalesced_write_result(&self, inode: u64, handle: u64) -> io::Result<()> {
+        match self.coalescer.take_error(inode, handle) {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Counts `e`, the failure of a request writing to the share, towards its degradation to
+    /// read-only. The leases are broken once it degrades, for the guest to drop what it cached of
+    /// the writes that failed.
+    fn count_write_error(&self, e: io::Error) -> io::Error {
+        if self.degradation.record(&e) {
+            self.leases.break_all();
+        }
+        e
+    }
+
+    /// Returns the leases the guest holds on the inodes of the share.
+    pub(crate) fn leases(&self) -> &Leases {
+        &self.leases
+    }
+
+    /// Returns the file system of the share, for the front-ends of other protocols.
+    pub(crate) fn fs(&self) -> &FsImpl {
+        &self.fs
+    }
+
+    /// Returns the state of the share for a snapshot of the guest, which mustn't be sending
+    /// requests. The buffered writes are written to the host first.
+    pub(crate) fn save_state(&self) -> io::Result<Vec<u8>> {
+        self.coalescer.flush_all();
+        let state = snapshot::save(&self.fs, self.options.load(Ordering::Relaxed))?;
+        Ok(state.encode())
+    }
+
+    /// Stops using the host files of the share until `resume` is called, the worker having stopped
+    /// handling requests. The buffered writes are written to the host first.
+    pub(crate) fn pause(&self) {
+        self.coalescer.flush_all();
+        self.revalidator.set_paused(true);
+    }
+
+    pub(crate) fn resume(&self) {
+        self.revalidator.set_paused(false);
+    }
+
+    /// Drops what the device caches of the share for the `VIRTIO_IOC_DROP_CACHES` ioctl, for tests
+    /// and benchmarks to start from cold caches. It is also a barrier: the buffered writes are
+    /// written to the host first, so that host processes see all the writes the guest made before.
+    /// The leases are broken, for the guest to drop the attributes and data it cached on their
+    /// strength.
+    fn drop_caches(&self, ctx: Context, nodeid: u64) -> io::Result<()> {
+        if nodeid != ROOT_ID {
+            return Err(io::Error::from_raw_os_error(libc::ENOTTY));
+        }
+        if ctx.uid != 0 {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+
+        self.coalescer.flush_all();
+        self.fs.drop_caches();
+        self.leases.break_all();
+        Ok(())
+    }
+
+    /// Puts the share in the state returned by `save_state`, as if the guest had mounted it and
+    /// held the same inodes and handles. The leases the guest held are dropped.
+    pub(crate) fn restore_state(&self, state: &[u8]) -> io::Result<()> {
+        let state = FsState::decode(state)?;
+        self.fs.init(FsOptions::from_bits_truncate(state.options))?;
+        self.options.store(state.options, Ordering::Relaxed);
+        snapshot::restore(&self.fs, &state)
+    }
+
+    /// Applies the timeouts set at runtime, if any, to an entry returned by the file system. The
+    /// attributes of an inode changed on the host are not to be cached by the guest for a while,
+    /// and those of the others are cached for as long as the lease on them, if leases are granted.
+    /// The owners and the mode of the entry are translated to the ones the guest sees, and its
+    /// inode number too.
+    fn apply_entry_timeouts(&self, mut entry: Entry) -> Entry {
+        if let Some((entry_timeout, attr_timeout)) = self.cache_timeouts.get() {
+            entry.entry_timeout = entry_timeout;
+            entry.attr_timeout = attr_timeout;
+        }
+        if entry.inode != 0 {
+            if self.revalidator.accessed(entry.inode, &entry.attr) {
+                entry.attr_timeout = Duration::ZERO;
+            } else if let Some(lease_timeout) = self.leases.grant(entry.inode) {
+                entry.attr_timeout = lease_timeout;
+            }
+        }
+        entry.attr = self.guest_attr(entry.attr);
+        entry
+    }
+
+    /// Fills the entry `name` of the directory `parent`, just created by the guest, from its
+    /// directory template if it has one, and returns `entry` with the attributes it then has.
+    fn materialize_template(&self, parent: u64, name: &[u8], mut entry: Entry) -> Entry {
+        let Some(template) = self
+            .dir_templates
+            .claim(|| self.fs.inode_path(parent), name)
+        else {
+            return entry;
+        };
+
+        let res = if entry.attr.st_mode & libc::S_IFMT == libc::S_IFDIR {
+            dir_template::materialize_dir(&self.fs, entry.inode, &template)
+        } else if entry.attr.st_size == 0 {
+            dir_template::materialize_file(&self.fs, entry.inode, &template)
+        } else {
+            Ok(())
+        };
+        if let Err(e) = res {
+            warn!(
+                "virtio-fs: failed to materialize the template {}: {e}",
+                template.display()
+            );
+        }
+
+        let ctx = dir_template::device_context();
+        if let Ok((st, _)) = self.fs.getattr(ctx, entry.inode, None) {
+            entry.attr = st;
+        }
+        entry
+    }
+
+    /// Returns the attributes `st` returned by the file system with their owners translated to
+    /// guest credentials, and the mode the guest asked for.
+    fn guest_attr(&self, mut st: bindings::stat64) -> bindings::stat64 {
+        credentials::guest_attr(&*self.credentials, &mut st);
+        self.guest_modes.guest_attr(&mut st);
+        self.guest_ino(st)
+    }
+
+    /// Returns the attributes `st` with the inode number the guest sees.
+    fn guest_ino(&self, mut st: bindings::stat64) -> bindings::stat64 {
+        st.st_ino = self.inode_numbers.guest_ino(st.st_ino);
+        st
+    }
+
+    /// Applies the attribute timeout set at runtime, if any, or the one of the lease on `inode`, to
+    /// one returned by the file system for the attributes `st` of `inode`.
+    fn apply_attr_timeout(&self, inode: u64, st: &bindings::stat64, timeout: Duration) -> Duration {
+        if self.revalidator.accessed(inode, st) {
+            return Duration::ZERO;
+        }
+        if let Some(lease_timeout) = self.leases.grant(inode) {
+            return lease_timeout;
+        }
+        self.cache_timeouts
+            .get()
+            .map_or(timeout, |(_, attr_timeout)| attr_timeout)
+    }
+
+    /// Writes the buffered writes the request with `in_header` may observe to the host, so that it
+    /// is handled as if they had been written right away. Writes take care of their own.
+    fn flush_coalesced_writes(&self, in_header: &InHeader) {
+        match in_header.opcode {
+            x if x == Opcode::Write as u32 || is_unrelated_to_file_data(x) => (),
+            x if involves_own_node_only(x) => self.coalescer.flush_inode(in_header.nodeid),
+            _ => self.coalescer.flush_all(),
+        }
+    }
+
+    /// Returns the entry of `d` in the directory `parent` to hand to the guest, which has no inode
+    /// if a virtual file shadows it, so that the guest looks the virtual file up rather than
+    /// cache the entry of the file system.
+    fn shadow_virtual_file(&self, ctx: Context, parent: u64, d: &DirEntry, entry: Entry) -> Entry {
+        if entry.inode == 0 {
+            return entry;
+        }
+
+        if self
+            .virtual_files
+            .lookup(|| self.fs.inode_path(parent), d.name)
+            .is_none()
+        {
+            return entry;
+        }
+
+        self.fs.forget(ctx, entry.inode, 1);
+        Entry { inode: 0, ..entry }
+    }
+
+    /// Whether the entry `name` of the directory `parent` is the init binary or a virtual file,
+    /// which the guest can't remove, rename or replace.
+    fn is_protected_entry(&self, parent: u64, name: &[u8]) -> bool {
+        let name = name.strip_suffix(b"\0").unwrap_or(name);
+        (self.fs.init_inode() != 0 && name == INIT_NAME)
+            || self
+                .virtual_files
+                .lookup(|| self.fs.inode_path(parent), name)
+                .is_some()
+    }
+
+    /// Handles a request about the virtual file with the inode of the request. Virtual files are
+    /// read-only regular files, and are opened for direct I/O, so that the guest reads their
+    /// current content every time.
+    fn virtual_file_request(&self, in_header: InHeader, mut r: Reader, w: Writer) -> Result<usize> {
+        let inode = in_header.nodeid;
+        let unique = in_header.unique;
+
+        match in_header.opcode {
+            // There is no reply for forget messages.
+            x if x == Opcode::Forget as u32 => Ok(0),
+            x if x == Opcode::Getattr as u32 => match self.virtual_files.stat(inode) {
+                Ok(st) => {
+                    let out = AttrOut {
+                        attr: self.guest_ino(st).into(),
+                        ..Default::default()
+                    };
+                    reply_ok(Some(out), None, unique, w)
+                }
+                Err(e) => reply_error(linux_error(e), unique, w),
+            },
+            x if x == Opcode::Statx as u32 => match self.virtual_files.stat(inode) {
+                Ok(st) => {
+                    let out = StatxOut {
+                        stat: Statx::with_btime(self.guest_ino(st), None),
+                        ..Default::default()
+                    };
+                    reply_ok(Some(out), None, unique, w)
+                }
+                Err(e) => reply_error(linux_error(e), unique, w),
+            },
+            x if x == Opcode::Open as u32 => {
+                let OpenIn { flags, .. } = r.read_obj().map_err(Error::DecodeMessage)?;
+                let write_flags = (libc::O_ACCMODE | bindings::LINUX_O_TRUNC) as u32;
+                if flags & write_flags != 0 {
+                    return reply_errno(libc::EACCES, unique, w);
+                }
+
+                let out = OpenOut {
+                    open_flags: OpenOptions::DIRECT_IO.bits(),
+                    ..Default::default()
+                };
+                reply_ok(Some(out), None, unique, w)
+            }
+            x if x == Opcode::Read as u32 => {
+                let ReadIn { offset, size, .. } = r.read_obj().map_err(Error::DecodeMessage)?;
+                if size > MAX_BUFFER_SIZE {
+                    return reply_errno(libc::ENOMEM, unique, w);
+                }
+                let Some(file) = self.virtual_files.get(inode) else {
+                    return reply_errno(libc::ENOENT, unique, w);
+                };
+
+                let mut buf = vec![0; size as usize];
+                match (file.read)(offset, &mut buf) {
+                    Ok(count) => {
+                        reply_ok(None::<u8>, Some(&buf[..count.min(buf.len())]), unique, w)
+                    }
+                    Err(e) => reply_error(linux_error(e), unique, w),
+                }
+            }
+            x if x == Opcode::Access as u32 => {
+                let AccessIn { mask, .. } = r.read_obj().map_err(Error::DecodeMessage)?;
+                if mask & libc::W_OK as u32 != 0 {
+                    return reply_errno(libc::EACCES, unique, w);
+                }
+                reply_ok(None::<u8>, None, unique, w)
+            }
+            x if x == Opcode::Flush as u32
+                || x == Opcode::Fsync as u32
+                || x == Opcode::Release as u32 =>
+            {
+                reply_ok(None::<u8>, None, unique, w)
+            }
+            x if x == Opcode::Getxattr as u32 || x == Opcode::Listxattr as u32 => {
+                reply_errno(libc::ENOTSUP, unique, w)
+            }
+            x if is_directory_request(x) => reply_errno(libc::ENOTDIR, unique, w),
+            _ => reply_errno(libc::EPERM, unique, w),
         }
     }
 
@@ -67,6 +462,7 @@ impl FsImplServer {
         w: Writer,
         shm_region: &Option<VirtioShmRegion>,
         exit_code: &Arc<AtomicI32>,
+        mut trace: Option<&mut RequestTrace>,
         #[cfg(target_os = "macos")] map_sender: &Option<Sender<WorkerMessage>>,
     ) -> Result<usize> {
         let in_header: InHeader = r.read_obj().map_err(Error::DecodeMessage)?;
@@ -79,7 +475,42 @@ impl FsImplServer {
             );
         }
 
-        match in_header.opcode {
+        if let Some(rules) = &self.access_rules {
+            if !is_exempt_from_access_rules(in_header.opcode)
+                && !rules.allows(in_header.uid, in_header.gid)
+            {
+                return reply_error(
+                    linux_error(io::Error::from_raw_os_error(libc::EACCES)),
+                    in_header.unique,
+                    w,
+                );
+            }
+        }
+
+        // The file system only sees the host credentials the guest ones translate to
+        let in_header = InHeader {
+            uid: self.credentials.host_uid(in_header.uid),
+            gid: self.credentials.host_gid(in_header.gid),
+            ..in_header
+        };
+
+        if let Some(trace) = trace.as_mut() {
+            trace.unique = in_header.unique;
+            trace.opcode = in_header.opcode;
+            trace.dispatched = Some(Instant::now());
+        }
+
+        self.flush_coalesced_writes(&in_header);
+
+        let res = match in_header.opcode {
+            x if Opcode::try_from(x).is_err() => self.reply_unsupported(in_header, w),
+            _ if is_virtual_inode(in_header.nodeid) => self.virtual_file_request(in_header, r, w),
+            x if in_header.nodeid == self.fs.init_inode() && modifies_own_node(x) => {
+                reply_errno(libc::EPERM, in_header.unique, w)
+            }
+            x if self.degradation.is_read_only() && modifies_share(x) => {
+                reply_errno(libc::EROFS, in_header.unique, w)
+            }
             x if x == Opcode::Lookup as u32 => self.lookup(in_header, r, w),
             x if x == Opcode::Forget as u32 => self.forget(in_header, r), // No reply.
             x if x == Opcode::Getattr as u32 => self.getattr(in_header, r, w),
@@ -113,6 +544,7 @@ impl FsImplServer {
             x if x == Opcode::Setlkw as u32 => self.setlkw(in_header, r, w),
             x if x == Opcode::Access as u32 => self.access(in_header, r, w),
             x if x == Opcode::Create as u32 => self.create(in_header, r, w),
+            x if x == Opcode::Tmpfile as u32 => self.tmpfile(in_header, r, w),
             x if x == Opcode::Interrupt as u32 => self.interrupt(in_header),
             x if x == Opcode::Bmap as u32 => self.bmap(in_header, r, w),
             x if x == Opcode::Destroy as u32 => self.destroy(),
@@ -125,6 +557,7 @@ impl FsImplServer {
             x if x == Opcode::Rename2 as u32 => self.rename2(in_header, r, w),
             x if x == Opcode::Lseek as u32 => self.lseek(in_header, r, w),
             x if x == Opcode::CopyFileRange as u32 => self.copyfilerange(in_header, r, w),
+            x if x == Opcode::Statx as u32 => self.statx(in_header, r, w),
             x if (x == Opcode::SetupMapping as u32) && shm_region.is_some() => {
                 let shm = shm_region.as_ref().unwrap();
                 #[cfg(target_os = "linux")]
@@ -157,12 +590,14 @@ impl FsImplServer {
                     map_sender,
                 )
             }
-            _ => reply_error(
-                linux_error(io::Error::from_raw_os_error(libc::ENOSYS)),
-                in_header.unique,
-                w,
-            ),
+            _ => self.reply_unsupported(in_header, w),
+        };
+
+        if let Some(trace) = trace {
+            trace.returned = Some(Instant::now());
         }
+
+        res
     }
 
     fn lookup(&self, in_header: InHeader, mut r: Reader, w: Writer) -> Result<usize> {
@@ -176,12 +611,26 @@ impl FsImplServer {
 
         let name = bytes_to_cstr(buf.as_ref())?;
 
+        let parent = in_header.nodeid;
+        if let Some(inode) = self
+            .virtual_files
+            .lookup(|| self.fs.inode_path(parent), name.to_bytes())
+        {
+            return match self.virtual_files.entry(inode) {
+                Ok(mut entry) => {
+                    entry.attr = self.guest_ino(entry.attr);
+                    reply_ok(Some(EntryOut::from(entry)), None, in_header.unique, w)
+                }
+                Err(e) => reply_error(linux_error(e), in_header.unique, w),
+            };
+        }
+
         match self
             .fs
             .lookup(Context::from(in_header), in_header.nodeid.into(), name)
         {
             Ok(entry) => {
-                let out = EntryOut::from(entry);
+                let out = EntryOut::from(self.apply_entry_timeouts(entry));
 
                 reply_ok(Some(out), None, in_header.unique, w)
             }
@@ -213,11 +662,41 @@ impl FsImplServer {
             .getattr(Context::from(in_header), in_header.nodeid.into(), handle)
         {
             Ok((st, timeout)) => {
+                let timeout = self.apply_attr_timeout(in_header.nodeid, &st, timeout);
                 let out = AttrOut {
                     attr_valid: timeout.as_secs(),
                     attr_valid_nsec: timeout.subsec_nanos(),
                     dummy: 0,
-                    attr: st.into(),
+                    attr: self.guest_attr(st).into(),
+                };
+                reply_ok(Some(out), None, in_header.unique, w)
+            }
+            Err(e) => reply_error(e, in_header.unique, w),
+        }
+    }
+
+    fn statx(&self, in_header: InHeader, mut r: Reader, w: Writer) -> Result<usize> {
+        let StatxIn {
+            getattr_flags, fh, ..
+        } = r.read_obj().map_err(Error::DecodeMessage)?;
+
+        let handle = if (getattr_flags & GETATTR_FH) != 0 {
+            Some(fh)
+        } else {
+            None
+        };
+
+        match self
+            .fs
+            .statx(Context::from(in_header), in_header.nodeid, handle)
+        {
+            Ok((st, btime, timeout)) => {
+                let timeout = self.apply_attr_timeout(in_header.nodeid, &st, timeout);
+                let out = StatxOut {
+                    attr_valid: timeout.as_secs(),
+                    attr_valid_nsec: timeout.subsec_nanos(),
+                    stat: Statx::with_btime(self.guest_attr(st), btime.map(|t| (t.sec, t.nsec))),
+                    ..Default::default()
                 };
                 reply_ok(Some(out), None, in_header.unique, w)
             }
@@ -236,7 +715,17 @@ impl FsImplServer {
 
         let valid = SetattrValid::from_bits_truncate(setattr_in.valid);
 
-        let st: bindings::stat64 = setattr_in.into();
+        let guest_mode = setattr_in.mode;
+        let mut st: bindings::stat64 = setattr_in.into();
+        if valid.contains(SetattrValid::UID) {
+            st.st_uid = self.credentials.host_uid(st.st_uid);
+        }
+        if valid.contains(SetattrValid::GID) {
+            st.st_gid = self.credentials.host_gid(st.st_gid);
+        }
+        if valid.contains(SetattrValid::MODE) {
+            st.st_mode = self.guest_modes.chmod_mode(guest_mode) as _;
+        }
 
         match self.fs.setattr(
             Context::from(in_header),
@@ -246,15 +735,26 @@ impl FsImplServer {
             valid,
         ) {
             Ok((st, timeout)) => {
+                if valid.contains(SetattrValid::MODE) {
+                    self.guest_modes.set(&st, guest_mode);
+                }
+                let op = if valid.contains(SetattrValid::SIZE) {
+                    FsWatchOp::Write
+                } else {
+                    FsWatchOp::Attrib
+                };
+                self.notify_inode(in_header.nodeid, op);
+
+                let timeout = self.apply_attr_timeout(in_header.nodeid, &st, timeout);
                 let out = AttrOut {
                     attr_valid: timeout.as_secs(),
                     attr_valid_nsec: timeout.subsec_nanos(),
                     dummy: 0,
-                    attr: st.into(),
+                    attr: self.guest_attr(st).into(),
                 };
                 reply_ok(Some(out), None, in_header.unique, w)
             }
-            Err(e) => reply_error(e, in_header.unique, w),
+            Err(e) => reply_error(self.count_write_error(e), in_header.unique, w),
         }
     }
 
@@ -286,6 +786,10 @@ impl FsImplServer {
         let name = components.next().ok_or(Error::MissingParameter)?;
         let linkname = components.next().ok_or(Error::MissingParameter)?;
 
+        if self.is_protected_entry(in_header.nodeid, name) {
+            return reply_errno(libc::EPERM, in_header.unique, w);
+        }
+
         let options = FsOptions::from_bits_truncate(self.options.load(Ordering::Relaxed));
 
         let extensions = get_extensions(options, name.len() + linkname.len(), buf.as_slice())?;
@@ -298,11 +802,12 @@ impl FsImplServer {
             extensions,
         ) {
             Ok(entry) => {
-                let out = EntryOut::from(entry);
+                self.notify_entry(in_header.nodeid, name, FsWatchOp::Create);
+                let out = EntryOut::from(self.apply_entry_timeouts(entry));
 
                 reply_ok(Some(out), None, in_header.unique, w)
             }
-            Err(e) => reply_error(e, in_header.unique, w),
+            Err(e) => reply_error(self.count_write_error(e), in_header.unique, w),
         }
     }
 
@@ -321,6 +826,10 @@ impl FsImplServer {
         let mut components = buf.split_inclusive(|c| *c == b'\0');
         let name = components.next().ok_or(Error::MissingParameter)?;
 
+        if self.is_protected_entry(in_header.nodeid, name) {
+            return reply_errno(libc::EPERM, in_header.unique, w);
+        }
+
         let options = FsOptions::from_bits_truncate(self.options.load(Ordering::Relaxed));
 
         let extensions = get_extensions(options, name.len(), buf.as_slice())?;
@@ -329,17 +838,19 @@ impl FsImplServer {
             Context::from(in_header),
             in_header.nodeid.into(),
             bytes_to_cstr(name)?,
-            mode,
+            self.guest_modes.create_mode(mode, false),
             rdev,
             umask,
             extensions,
         ) {
             Ok(entry) => {
-                let out = EntryOut::from(entry);
+                self.guest_modes.set(&entry.attr, mode & !umask);
+                self.notify_entry(in_header.nodeid, name, FsWatchOp::Create);
+                let out = EntryOut::from(self.apply_entry_timeouts(entry));
 
                 reply_ok(Some(out), None, in_header.unique, w)
             }
-            Err(e) => reply_error(e, in_header.unique, w),
+            Err(e) => reply_error(self.count_write_error(e), in_header.unique, w),
         }
     }
 
@@ -356,6 +867,10 @@ impl FsImplServer {
         let mut components = buf.split_inclusive(|c| *c == b'\0');
         let name = components.next().ok_or(Error::MissingParameter)?;
 
+        if self.is_protected_entry(in_header.nodeid, name) {
+            return reply_errno(libc::EPERM, in_header.unique, w);
+        }
+
         let options = FsOptions::from_bits_truncate(self.options.load(Ordering::Relaxed));
 
         let extensions = get_extensions(options, name.len(), buf.as_slice())?;
@@ -364,16 +879,19 @@ impl FsImplServer {
             Context::from(in_header),
             in_header.nodeid.into(),
             bytes_to_cstr(name)?,
-            mode,
+            self.guest_modes.create_mode(mode, true),
             umask,
             extensions,
         ) {
             Ok(entry) => {
-                let out = EntryOut::from(entry);
+                self.notify_entry(in_header.nodeid, name, FsWatchOp::Create);
+                let entry = self.materialize_template(in_header.nodeid, name, entry);
+                self.guest_modes.set(&entry.attr, mode & !umask);
+                let out = EntryOut::from(self.apply_entry_timeouts(entry));
 
                 reply_ok(Some(out), None, in_header.unique, w)
             }
-            Err(e) => reply_error(e, in_header.unique, w),
+            Err(e) => reply_error(self.count_write_error(e), in_header.unique, w),
         }
     }
 
@@ -385,13 +903,20 @@ impl FsImplServer {
 
         r.read_exact(&mut name).map_err(Error::DecodeMessage)?;
 
+        if self.is_protected_entry(in_header.nodeid, &name) {
+            return reply_errno(libc::EPERM, in_header.unique, w);
+        }
+
         match self.fs.unlink(
             Context::from(in_header),
             in_header.nodeid.into(),
             bytes_to_cstr(&name)?,
         ) {
-            Ok(()) => reply_ok(None::<u8>, None, in_header.unique, w),
-            Err(e) => reply_error(e, in_header.unique, w),
+            Ok(()) => {
+                self.notify_entry(in_header.nodeid, &name, FsWatchOp::Remove);
+                reply_ok(None::<u8>, None, in_header.unique, w)
+            }
+            Err(e) => reply_error(self.count_write_error(e), in_header.unique, w),
         }
     }
 
@@ -403,13 +928,20 @@ impl FsImplServer {
 
         r.read_exact(&mut name).map_err(Error::DecodeMessage)?;
 
+        if self.is_protected_entry(in_header.nodeid, &name) {
+            return reply_errno(libc::EPERM, in_header.unique, w);
+        }
+
         match self.fs.rmdir(
             Context::from(in_header),
             in_header.nodeid.into(),
             bytes_to_cstr(&name)?,
         ) {
-            Ok(()) => reply_ok(None::<u8>, None, in_header.unique, w),
-            Err(e) => reply_error(e, in_header.unique, w),
+            Ok(()) => {
+                self.notify_entry(in_header.nodeid, &name, FsWatchOp::Remove);
+                reply_ok(None::<u8>, None, in_header.unique, w)
+            }
+            Err(e) => reply_error(self.count_write_error(e), in_header.unique, w),
         }
     }
 
@@ -439,6 +971,12 @@ impl FsImplServer {
 
         let (oldname, newname) = buf.split_at(split_pos);
 
+        if self.is_protected_entry(in_header.nodeid, oldname)
+            || self.is_protected_entry(newdir, newname)
+        {
+            return reply_errno(libc::EPERM, in_header.unique, w);
+        }
+
         match self.fs.rename(
             Context::from(in_header),
             in_header.nodeid.into(),
@@ -447,8 +985,12 @@ impl FsImplServer {
             bytes_to_cstr(newname)?,
             flags,
         ) {
-            Ok(()) => reply_ok(None::<u8>, None, in_header.unique, w),
-            Err(e) => reply_error(e, in_header.unique, w),
+            Ok(()) => {
+                self.notify_entry(in_header.nodeid, oldname, FsWatchOp::RenameFrom);
+                self.notify_entry(newdir, newname, FsWatchOp::RenameTo);
+                reply_ok(None::<u8>, None, in_header.unique, w)
+            }
+            Err(e) => reply_error(self.count_write_error(e), in_header.unique, w),
         }
     }
 
@@ -478,6 +1020,10 @@ impl FsImplServer {
 
         r.read_exact(&mut name).map_err(Error::DecodeMessage)?;
 
+        if self.is_protected_entry(in_header.nodeid, &name) {
+            return reply_errno(libc::EPERM, in_header.unique, w);
+        }
+
         match self.fs.link(
             Context::from(in_header),
             oldnodeid.into(),
@@ -485,22 +1031,37 @@ impl FsImplServer {
             bytes_to_cstr(&name)?,
         ) {
             Ok(entry) => {
-                let out = EntryOut::from(entry);
+                self.notify_entry(in_header.nodeid, &name, FsWatchOp::Create);
+                let out = EntryOut::from(self.apply_entry_timeouts(entry));
 
                 reply_ok(Some(out), None, in_header.unique, w)
             }
-            Err(e) => reply_error(e, in_header.unique, w),
+            Err(e) => reply_error(self.count_write_error(e), in_header.unique, w),
         }
     }
 
     fn open(&self, in_header: InHeader, mut r: Reader, w: Writer) -> Result<usize> {
         let OpenIn { flags, .. } = r.read_obj().map_err(Error::DecodeMessage)?;
 
+        // The init binary is only ever read
+        let write_flags = (libc::O_ACCMODE | bindings::LINUX_O_TRUNC) as u32;
+        if in_header.nodeid == self.fs.init_inode() && flags & write_flags != 0 {
+            return reply_errno(libc::EACCES, in_header.unique, w);
+        }
+        if self.degradation.is_read_only() && flags & write_flags != 0 {
+            return reply_errno(libc::EROFS, in_header.unique, w);
+        }
+
         match self
             .fs
             .open(Context::from(in_header), in_header.nodeid.into(), flags)
         {
-            Ok((handle, opts)) => {
+            Ok((handle, mut opts)) => {
+                // The data the guest cached is stale if the file changed on the host
+                if self.revalidator.opened(in_header.nodeid) {
+                    opts.remove(OpenOptions::KEEP_CACHE);
+                }
+
                 let out = OpenOut {
                     fh: handle.map(Into::into).unwrap_or(0),
                     open_flags: opts.bits(),
@@ -592,6 +1153,30 @@ impl FsImplServer {
             None
         };
 
+        if self.coalescer.accepts(size, write_flags, flags) {
+            let mut data = vec![0; size as usize];
+            r.read_exact(&mut data).map_err(Error::DecodeMessage)?;
+            self.coalescer.write(
+                Context::from(in_header),
+                in_header.nodeid,
+                fh,
+                offset,
+                &data,
+                flags,
+            );
+            self.notify_inode(in_header.nodeid, FsWatchOp::Write);
+
+            let out = WriteOut {
+                size,
+                ..Default::default()
+            };
+
+            return reply_ok(Some(out), None, in_header.unique, w);
+        }
+
+        // The write must follow the buffered ones
+        self.coalescer.flush_inode(in_header.nodeid);
+
         let delayed_write = write_flags & WRITE_CACHE != 0;
         let kill_priv = write_flags & WRITE_KILL_PRIV != 0;
 
@@ -610,6 +1195,8 @@ impl FsImplServer {
             flags,
         ) {
             Ok(count) => {
+                self.notify_inode(in_header.nodeid, FsWatchOp::Write);
+
                 let out = WriteOut {
                     size: count as u32,
                     ..Default::default()
@@ -617,7 +1204,7 @@ impl FsImplServer {
 
                 reply_ok(Some(out), None, in_header.unique, w)
             }
-            Err(e) => reply_error(e, in_header.unique, w),
+            Err(e) => reply_error(self.count_write_error(e), in_header.unique, w),
         }
     }
 
@@ -647,6 +1234,7 @@ impl FsImplServer {
             None
         };
 
+        let write_error = self.coalescer.take_error(in_header.nodeid, fh);
         match self.fs.release(
             Context::from(in_header),
             in_header.nodeid.into(),
@@ -656,8 +1244,14 @@ impl FsImplServer {
             flock_release,
             lock_owner,
         ) {
-            Ok(()) => reply_ok(None::<u8>, None, in_header.unique, w),
-            Err(e) => reply_error(e, in_header.unique, w),
+            Ok(()) => {
+                self.revalidator.released(in_header.nodeid);
+                match write_error {
+                    Some(e) => reply_error(self.count_write_error(e), in_header.unique, w),
+                    None => reply_ok(None::<u8>, None, in_header.unique, w),
+                }
+            }
+            Err(e) => reply_error(self.count_write_error(e), in_header.unique, w),
         }
     }
 
@@ -667,14 +1261,18 @@ impl FsImplServer {
         } = r.read_obj().map_err(Error::DecodeMessage)?;
         let datasync = fsync_flags & 0x1 != 0;
 
-        match self.fs.fsync(
-            Context::from(in_header),
-            in_header.nodeid.into(),
-            datasync,
-            fh.into(),
-        ) {
+        match self
+            .fs
+            .fsync(
+                Context::from(in_header),
+                in_header.nodeid.into(),
+                datasync,
+                fh.into(),
+            )
+            .and_then(|()| self.coalesced_write_result(in_header.nodeid, fh))
+        {
             Ok(()) => reply_ok(None::<u8>, None, in_header.unique, w),
-            Err(e) => reply_error(e, in_header.unique, w),
+            Err(e) => reply_error(self.count_write_error(e), in_header.unique, w),
         }
     }
 
@@ -710,8 +1308,11 @@ impl FsImplServer {
             value,
             flags,
         ) {
-            Ok(()) => reply_ok(None::<u8>, None, in_header.unique, w),
-            Err(e) => reply_error(e, in_header.unique, w),
+            Ok(()) => {
+                self.notify_inode(in_header.nodeid, FsWatchOp::Attrib);
+                reply_ok(None::<u8>, None, in_header.unique, w)
+            }
+            Err(e) => reply_error(self.count_write_error(e), in_header.unique, w),
         }
     }
 
@@ -796,22 +1397,29 @@ impl FsImplServer {
             .fs
             .removexattr(Context::from(in_header), in_header.nodeid.into(), name)
         {
-            Ok(()) => reply_ok(None::<u8>, None, in_header.unique, w),
-            Err(e) => reply_error(e, in_header.unique, w),
+            Ok(()) => {
+                self.notify_inode(in_header.nodeid, FsWatchOp::Attrib);
+                reply_ok(None::<u8>, None, in_header.unique, w)
+            }
+            Err(e) => reply_error(self.count_write_error(e), in_header.unique, w),
         }
     }
 
     fn flush(&self, in_header: InHeader, mut r: Reader, w: Writer) -> Result<usize> {
         let FlushIn { fh, lock_owner, .. } = r.read_obj().map_err(Error::DecodeMessage)?;
 
-        match self.fs.flush(
-            Context::from(in_header),
-            in_header.nodeid.into(),
-            fh.into(),
-            lock_owner,
-        ) {
+        match self
+            .fs
+            .flush(
+                Context::from(in_header),
+                in_header.nodeid.into(),
+                fh.into(),
+                lock_owner,
+            )
+            .and_then(|()| self.coalesced_write_result(in_header.nodeid, fh))
+        {
             Ok(()) => reply_ok(None::<u8>, None, in_header.unique, w),
-            Err(e) => reply_error(e, in_header.unique, w),
+            Err(e) => reply_error(self.count_write_error(e), in_header.unique, w),
         }
     }
 
@@ -896,8 +1504,8 @@ impl FsImplServer {
                     minor: KERNEL_MINOR_VERSION,
                     max_readahead,
                     flags: enabled as u32,
-                    max_background: u16::MAX,
-                    congestion_threshold: (u16::MAX / 4) * 3,
+                    max_background: self.background_limits.max_background,
+                    congestion_threshold: self.background_limits.congestion_threshold,
                     max_write: MAX_BUFFER_SIZE,
                     time_gran: 1, // nanoseconds
                     max_pages: max_pages.try_into().unwrap(),
@@ -970,7 +1578,12 @@ impl FsImplServer {
                 fh.into(),
                 size,
                 offset,
-                |d, e| add_dirent(&mut cursor, size, d, Some(e)),
+                |mut d, e| {
+                    let e =
+                        self.shadow_virtual_file(Context::from(in_header), in_header.nodeid, &d, e);
+                    d.ino = self.inode_numbers.guest_ino(d.ino);
+                    add_dirent(&mut cursor, size, d, Some(self.apply_entry_timeouts(e)))
+                },
             )
         } else {
             self.fs.readdir(
@@ -979,7 +1592,10 @@ impl FsImplServer {
                 fh.into(),
                 size,
                 offset,
-                |d| add_dirent(&mut cursor, size, d, None),
+                |mut d| {
+                    d.ino = self.inode_numbers.guest_ino(d.ino);
+                    add_dirent(&mut cursor, size, d, None)
+                },
             )
         };
 
@@ -1034,31 +1650,68 @@ impl FsImplServer {
             fh.into(),
         ) {
             Ok(()) => reply_ok(None::<u8>, None, in_header.unique, w),
-            Err(e) => reply_error(e, in_header.unique, w),
+            Err(e) => reply_error(self.count_write_error(e), in_header.unique, w),
         }
     }
 
-    fn getlk(&self, in_header: InHeader, mut _r: Reader, w: Writer) -> Result<usize> {
-        if let Err(e) = self.fs.getlk() {
-            reply_error(e, in_header.unique, w)
-        } else {
-            Ok(0)
+    fn getlk(&self, in_header: InHeader, mut r: Reader, w: Writer) -> Result<usize> {
+        let LkIn {
+            fh,
+            owner,
+            lk,
+            lk_flags,
+            ..
+        } = r.read_obj().map_err(Error::DecodeMessage)?;
+
+        match self.fs.getlk(
+            Context::from(in_header),
+            in_header.nodeid.into(),
+            fh.into(),
+            owner,
+            lk,
+            lk_flags,
+        ) {
+            Ok(lk) => reply_ok(Some(LkOut { lk }), None, in_header.unique, w),
+            Err(e) if e.raw_os_error() == Some(bindings::LINUX_ENOSYS) => {
+                self.reply_unsupported(in_header, w)
+            }
+            Err(e) => reply_error(e, in_header.unique, w),
         }
     }
 
-    fn setlk(&self, in_header: InHeader, mut _r: Reader, w: Writer) -> Result<usize> {
-        if let Err(e) = self.fs.setlk() {
-            reply_error(e, in_header.unique, w)
-        } else {
-            Ok(0)
-        }
+    fn setlk(&self, in_header: InHeader, r: Reader, w: Writer) -> Result<usize> {
+        self.do_setlk(in_header, r, w, false)
     }
 
-    fn setlkw(&self, in_header: InHeader, mut _r: Reader, w: Writer) -> Result<usize> {
-        if let Err(e) = self.fs.setlkw() {
-            reply_error(e, in_header.unique, w)
+    fn setlkw(&self, in_header: InHeader, r: Reader, w: Writer) -> Result<usize> {
+        self.do_setlk(in_header, r, w, true)
+    }
+
+    fn do_setlk(&self, in_header: InHeader, mut r: Reader, w: Writer, wait: bool) -> Result<usize> {
+        let LkIn {
+            fh,
+            owner,
+            lk,
+            lk_flags,
+            ..
+        } = r.read_obj().map_err(Error::DecodeMessage)?;
+
+        let ctx = Context::from(in_header);
+        let res = if wait {
+            self.fs
+                .setlkw(ctx, in_header.nodeid.into(), fh.into(), owner, lk, lk_flags)
         } else {
-            Ok(0)
+            self.fs
+                .setlk(ctx, in_header.nodeid.into(), fh.into(), owner, lk, lk_flags)
+        };
+        match res {
+            Ok(()) => reply_ok(None::<u8>, None, in_header.unique, w),
+            Err(e) if e.raw_os_error() == Some(bindings::LINUX_ENOSYS) => {
+                self.reply_unsupported(in_header, w)
+            }
+            // The worker handles the request again until the lock is free
+            Err(e) if wait && e.raw_os_error() == Some(libc::EAGAIN) => Err(Error::WouldBlock),
+            Err(e) => reply_error(e, in_header.unique, w),
         }
     }
 
@@ -1090,6 +1743,10 @@ impl FsImplServer {
         let mut components = buf.split_inclusive(|c| *c == b'\0');
         let name = components.next().ok_or(Error::MissingParameter)?;
 
+        if self.is_protected_entry(in_header.nodeid, name) {
+            return reply_errno(libc::EPERM, in_header.unique, w);
+        }
+
         let options = FsOptions::from_bits_truncate(self.options.load(Ordering::Relaxed));
 
         let extensions = get_extensions(options, name.len(), buf.as_slice())?;
@@ -1098,12 +1755,18 @@ impl FsImplServer {
             Context::from(in_header),
             in_header.nodeid.into(),
             bytes_to_cstr(name)?,
-            mode,
+            self.guest_modes.create_mode(mode, false),
             flags,
             umask,
             extensions,
         ) {
             Ok((entry, handle, opts)) => {
+                self.notify_entry(in_header.nodeid, name, FsWatchOp::Create);
+
+                let entry = self.materialize_template(in_header.nodeid, name, entry);
+                self.guest_modes.set(&entry.attr, mode & !umask);
+                let entry = self.apply_entry_timeouts(entry);
+                self.revalidator.opened(entry.inode);
                 let entry_out = EntryOut {
                     nodeid: entry.inode,
                     generation: entry.generation,
@@ -1127,7 +1790,66 @@ impl FsImplServer {
                     w,
                 )
             }
-            Err(e) => reply_error(e, in_header.unique, w),
+            Err(e) => reply_error(self.count_write_error(e), in_header.unique, w),
+        }
+    }
+
+    fn tmpfile(&self, in_header: InHeader, mut r: Reader, w: Writer) -> Result<usize> {
+        let CreateIn {
+            flags, mode, umask, ..
+        } = r.read_obj().map_err(Error::DecodeMessage)?;
+
+        let namelen = (in_header.len as usize)
+            .checked_sub(size_of::<InHeader>())
+            .and_then(|l| l.checked_sub(size_of::<CreateIn>()))
+            .ok_or(Error::InvalidHeaderLength)?;
+
+        let mut buf = vec![0; namelen];
+
+        // The kernel sends the name of the unnamed dentry of the file, "/", which is only read to
+        // find the extensions after it.
+        r.read_exact(&mut buf).map_err(Error::DecodeMessage)?;
+        let name = buf.split_inclusive(|c| *c == b'\0').next().unwrap_or(&[]);
+
+        let options = FsOptions::from_bits_truncate(self.options.load(Ordering::Relaxed));
+
+        let extensions = get_extensions(options, name.len(), buf.as_slice())?;
+
+        match self.fs.tmpfile(
+            Context::from(in_header),
+            in_header.nodeid.into(),
+            self.guest_modes.create_mode(mode, false),
+            flags,
+            umask,
+            extensions,
+        ) {
+            Ok((entry, handle, opts)) => {
+                self.guest_modes.set(&entry.attr, mode & !umask);
+                let entry = self.apply_entry_timeouts(entry);
+                self.revalidator.opened(entry.inode);
+                let entry_out = EntryOut {
+                    nodeid: entry.inode,
+                    generation: entry.generation,
+                    entry_valid: entry.entry_timeout.as_secs(),
+                    attr_valid: entry.attr_timeout.as_secs(),
+                    entry_valid_nsec: entry.entry_timeout.subsec_nanos(),
+                    attr_valid_nsec: entry.attr_timeout.subsec_nanos(),
+                    attr: entry.attr.into(),
+                };
+                let open_out = OpenOut {
+                    fh: handle.map(Into::into).unwrap_or(0),
+                    open_flags: opts.bits(),
+                    ..Default::default()
+                };
+
+                reply_ok(
+                    Some(entry_out),
+                    Some(open_out.as_slice()),
+                    in_header.unique,
+                    w,
+                )
+            }
+            Err(e) => reply_error(self.count_write_error(e), in_header.unique, w),
         }
     }
 
@@ -1136,11 +1858,7 @@ impl FsImplServer {
     }
 
     fn bmap(&self, in_header: InHeader, mut _r: Reader, w: Writer) -> Result<usize> {
-        if let Err(e) = self.fs.bmap() {
-            reply_error(e, in_header.unique, w)
-        } else {
-            Ok(0)
-        }
+        self.reply_stub(self.fs.bmap(), in_header, w)
     }
 
     fn destroy(&self) -> Result<usize> {
@@ -1166,6 +1884,21 @@ impl FsImplServer {
             out_size,
         } = r.read_obj().map_err(Error::DecodeMessage)?;
 
+        // The input size comes from the guest, so it must not be allocated before it is checked
+        if in_size as usize > r.available_bytes() {
+            return reply_errno(libc::EINVAL, in_header.unique, w);
+        }
+
+        let mut data = vec![0u8; in_size as usize];
+        r.read_exact(&mut data).map_err(Error::DecodeMessage)?;
+
+        if cmd == VIRTIO_IOC_DROP_CACHES_REQ {
+            return match self.drop_caches(Context::from(in_header), in_header.nodeid) {
+                Ok(()) => reply_ok(Some(IoctlOut::default()), None, in_header.unique, w),
+                Err(e) => reply_error(linux_error(e), in_header.unique, w),
+            };
+        }
+
         match self.fs.ioctl(
             Context::from(in_header),
             in_header.nodeid.into(),
@@ -1173,7 +1906,7 @@ impl FsImplServer {
             flags,
             cmd,
             arg,
-            in_size,
+            &data,
             out_size,
             exit_code,
         ) {
@@ -1189,18 +1922,31 @@ impl FsImplServer {
     }
 
     fn poll(&self, in_header: InHeader, mut _r: Reader, w: Writer) -> Result<usize> {
-        if let Err(e) = self.fs.poll() {
-            reply_error(e, in_header.unique, w)
-        } else {
-            Ok(0)
-        }
+        self.reply_stub(self.fs.poll(), in_header, w)
     }
 
     fn notify_reply(&self, in_header: InHeader, mut _r: Reader, w: Writer) -> Result<usize> {
-        if let Err(e) = self.fs.notify_reply() {
-            reply_error(e, in_header.unique, w)
-        } else {
-            Ok(0)
+        self.reply_stub(self.fs.notify_reply(), in_header, w)
+    }
+
+    /// Answers a request with an opcode the server doesn't implement with `ENOSYS`, counting it.
+    fn reply_unsupported(&self, in_header: InHeader, w: Writer) -> Result<usize> {
+        self.unsupported.record(in_header.opcode);
+        reply_error(
+            linux_error(io::Error::from_raw_os_error(libc::ENOSYS)),
+            in_header.unique,
+            w,
+        )
+    }
+
+    /// Answers a request the file system only has a stub for, which takes no arguments and
+    /// returns nothing to reply with, so that the guest never waits for a reply that won't come.
+    fn reply_stub(&self, res: io::Result<()>, in_header: InHeader, w: Writer) -> Result<usize> {
+        match res {
+            Err(e) if e.raw_os_error() != Some(bindings::LINUX_ENOSYS) => {
+                reply_error(e, in_header.unique, w)
+            }
+            _ => self.reply_unsupported(in_header, w),
         }
     }
 
@@ -1255,8 +2001,11 @@ impl FsImplServer {
             offset,
             length,
         ) {
-            Ok(()) => reply_ok(None::<u8>, None, in_header.unique, w),
-            Err(e) => reply_error(e, in_header.unique, w),
+            Ok(()) => {
+                self.notify_inode(in_header.nodeid, FsWatchOp::Write);
+                reply_ok(None::<u8>, None, in_header.unique, w)
+            }
+            Err(e) => reply_error(self.count_write_error(e), in_header.unique, w),
         }
     }
 
@@ -1293,6 +2042,10 @@ impl FsImplServer {
             ..
         } = r.read_obj().map_err(Error::DecodeMessage)?;
 
+        if nodeid_out == self.fs.init_inode() {
+            return reply_errno(libc::EPERM, in_header.unique, w);
+        }
+
         match self.fs.copyfilerange(
             Context::from(in_header),
             in_header.nodeid.into(),
@@ -1305,6 +2058,8 @@ impl FsImplServer {
             flags,
         ) {
             Ok(count) => {
+                self.notify_inode(nodeid_out, FsWatchOp::Write);
+
                 let out = WriteOut {
                     size: count as u32,
                     ..Default::default()
@@ -1312,7 +2067,7 @@ impl FsImplServer {
 
                 reply_ok(Some(out), None, in_header.unique, w)
             }
-            Err(e) => reply_error(e, in_header.unique, w),
+            Err(e) => reply_error(self.count_write_error(e), in_header.unique, w),
         }
     }
 
@@ -1406,7 +2161,7 @@ impl FsImplServer {
 
 impl ZeroCopyReader for ZCReader<'_> {
     fn read_to(&mut self, f: &File, count: usize, off: u64) -> io::Result<usize> {
-        self.0.read_to_at(f, count, off)
+        retry_file_io(f, || self.0.read_to_at(f, count, off))
     }
 }
 
@@ -1418,7 +2173,7 @@ impl io::Read for ZCReader<'_> {
 
 impl ZeroCopyWriter for ZCWriter<'_> {
     fn write_from(&mut self, f: &File, count: usize, off: u64) -> io::Result<usize> {
-        self.0.write_from_at(f, count, off)
+        retry_file_io(f, || self.0.write_from_at(f, count, off))
     }
 }
 
@@ -1473,6 +2228,206 @@ fn reply_ok<T: ByteValued>(
     Ok(w.bytes_written())
 }
 
+/// Whether the access rules of a share apply to requests with `opcode`. Requests that don't reach
+/// the file contents or that release resources are always let through: failing them would only
+/// leak inodes and handles.
+fn is_exempt_from_access_rules(opcode: u32) -> bool {
+    [
+        Opcode::Init,
+        Opcode::Destroy,
+        Opcode::Forget,
+        Opcode::BatchForget,
+        Opcode::Interrupt,
+        Opcode::Flush,
+        Opcode::Release,
+        Opcode::Releasedir,
+    ]
+    .into_iter()
+    .any(|exempt| exempt as u32 == opcode)
+}
+
+/// Whether requests with `opcode` are only valid for directories.
+fn is_directory_request(opcode: u32) -> bool {
+    [
+        Opcode::Lookup,
+        Opcode::Mknod,
+        Opcode::Mkdir,
+        Opcode::Symlink,
+        Opcode::Create,
+        Opcode::Tmpfile,
+        Opcode::Unlink,
+        Opcode::Rmdir,
+        Opcode::Rename,
+        Opcode::Rename2,
+        Opcode::Link,
+        Opcode::Opendir,
+        Opcode::Readdir,
+        Opcode::Readdirplus,
+        Opcode::Releasedir,
+        Opcode::Fsyncdir,
+    ]
+    .into_iter()
+    .any(|dir| dir as u32 == opcode)
+}
+
+/// Whether requests with `opcode` change the data or the attributes of their own node.
+fn modifies_own_node(opcode: u32) -> bool {
+    [
+        Opcode::Setattr,
+        Opcode::Write,
+        Opcode::Setxattr,
+        Opcode::Removexattr,
+        Opcode::Fallocate,
+    ]
+    .into_iter()
+    .any(|modifying| modifying as u32 == opcode)
+}
+
+/// Whether requests with `opcode` change the share, and are refused once it is read-only.
+fn modifies_share(opcode: u32) -> bool {
+    [
+        Opcode::Setattr,
+        Opcode::Symlink,
+        Opcode::Mknod,
+        Opcode::Mkdir,
+        Opcode::Unlink,
+        Opcode::Rmdir,
+        Opcode::Rename,
+        Opcode::Rename2,
+        Opcode::Link,
+        Opcode::Write,
+        Opcode::Setxattr,
+        Opcode::Removexattr,
+        Opcode::Create,
+        Opcode::Tmpfile,
+        Opcode::Fallocate,
+        Opcode::CopyFileRange,
+    ]
+    .into_iter()
+    .any(|modifying| modifying as u32 == opcode)
+}
+
+/// Whether requests with `opcode` involve neither the data nor the attributes of any file.
+fn is_unrelated_to_file_data(opcode: u32) -> bool {
+    [
+        Opcode::Init,
+        Opcode::Forget,
+        Opcode::BatchForget,
+        Opcode::Interrupt,
+        Opcode::NotifyReply,
+    ]
+    .into_iter()
+    .any(|unrelated| unrelated as u32 == opcode)
+}
+
+/// Whether requests with `opcode` involve the data or the attributes of their own node only,
+/// rather than those of other nodes as well, like the entries of a directory do.
+fn involves_own_node_only(opcode: u32) -> bool {
+    [
+        Opcode::Getattr,
+        Opcode::Setattr,
+        Opcode::Readlink,
+        Opcode::Open,
+        Opcode::Read,
+        Opcode::Release,
+        Opcode::Fsync,
+        Opcode::Setxattr,
+        Opcode::Getxattr,
+        Opcode::Listxattr,
+        Opcode::Removexattr,
+        Opcode::Flush,
+        Opcode::Getlk,
+        Opcode::Setlk,
+        Opcode::Setlkw,
+        Opcode::Access,
+        Opcode::Bmap,
+        Opcode::Ioctl,
+        Opcode::Poll,
+        Opcode::Fallocate,
+        Opcode::Lseek,
+        Opcode::Statx,
+        Opcode::SetupMapping,
+    ]
+    .into_iter()
+    .any(|own| own as u32 == opcode)
+}
+
+/// How the worker schedules a request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum RequestClass {
+    /// Only looks up metadata the host likely has at hand
+    Normal,
+    /// May keep the worker busy for a while, e.g. reading or writing the data of the host files,
+    /// syncing them or copying them up, or waiting for a lock
+    MayBlock,
+    /// Sent by the guest kernel in the background rather than on behalf of a waiting process, i.e.
+    /// the writeback of dirty pages from its page cache
+    Background,
+}
+
+impl RequestClass {
+    /// Whether a request of this class may keep the worker busy for a while
+    pub(super) fn may_block(self) -> bool {
+        self != RequestClass::Normal
+    }
+}
+
+/// Classifies the request in `r` from its header. A request without a valid header is a normal
+/// one, as it fails right away.
+pub(super) fn classify_request(mut r: Reader) -> RequestClass {
+    let Ok(in_header) = r.read_obj::<InHeader>() else {
+        return RequestClass::Normal;
+    };
+    if in_header.opcode == Opcode::Write as u32
+        && r.read_obj::<WriteIn>()
+            .is_ok_and(|write_in| write_in.write_flags & WRITE_CACHE != 0)
+    {
+        return RequestClass::Background;
+    }
+
+    let quick = [
+        Opcode::Lookup,
+        Opcode::Forget,
+        Opcode::BatchForget,
+        Opcode::Getattr,
+        Opcode::Readlink,
+        Opcode::Statfs,
+        Opcode::Getxattr,
+        Opcode::Listxattr,
+        Opcode::Opendir,
+        Opcode::Readdir,
+        Opcode::Readdirplus,
+        Opcode::Releasedir,
+        Opcode::Getlk,
+        Opcode::Access,
+        Opcode::Interrupt,
+        Opcode::Lseek,
+        Opcode::Statx,
+    ]
+    .into_iter()
+    .any(|quick| quick as u32 == in_header.opcode);
+    if quick {
+        RequestClass::Normal
+    } else {
+        RequestClass::MayBlock
+    }
+}
+
+/// Fails the request in `r` with `EIO` without reaching the file system, for a device whose pause
+/// timed out. The requests without a reply get none, and the inodes they forget stay referenced.
+pub(super) fn reply_unavailable(mut r: Reader, w: Writer) -> Result<usize> {
+    let in_header: InHeader = r.read_obj().map_err(Error::DecodeMessage)?;
+    match in_header.opcode {
+        x if x == Opcode::Forget as u32
+            || x == Opcode::BatchForget as u32
+            || x == Opcode::Interrupt as u32 =>
+        {
+            Ok(0)
+        }
+        _ => reply_errno(libc::EIO, in_header.unique, w),
+    }
+}
+
 fn reply_error(e: io::Error, unique: u64, mut w: Writer) -> Result<usize> {
     let header = OutHeader {
         len: size_of::<OutHeader>() as u32,
@@ -1487,6 +2442,11 @@ fn reply_error(e: io::Error, unique: u64, mut w: Writer) -> Result<usize> {
     Ok(w.bytes_written())
 }
 
+/// Replies with `errno` to a request refused before it reaches the file system.
+fn reply_errno(errno: i32, unique: u64, w: Writer) -> Result<usize> {
+    reply_error(linux_error(io::Error::from_raw_os_error(errno)), unique, w)
+}
+
 fn bytes_to_cstr(buf: &[u8]) -> Result<&CStr> {
     // Convert to a `CStr` first so that we can drop the '\0' byte at the end
     // and make sure there are no interior '\0' bytes.
@@ -1646,7 +2606,7 @@ fn get_extensions(options: FsOptions, skip: usize, request_bytes: &[u8]) -> Resu
             ExtType::SupGroups => {
                 // We're not exposing this feature to the guest, so we shouldn't get
                 // any messages including this extension.
-                unimplemented!("Support for supplemental groups is not implemented");
+                return Err(Error::DecodeMessage(einval()));
             }
         }
 
@@ -1662,3 +2622,53 @@ fn get_extensions(options: FsOptions, skip: usize, request_bytes: &[u8]) -> Resu
 
     Ok(extensions)
 }
+
+#[cfg(test)]
+mod test {
+    use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+    use super::*;
+    use crate::virtio::descriptor_utils::{create_descriptor_chain, DescriptorType};
+
+    fn classify(opcode: Opcode, write_flags: u32) -> RequestClass {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let in_header = InHeader {
+            opcode: opcode as u32,
+            ..Default::default()
+        };
+        let write_in = WriteIn {
+            write_flags,
+            ..Default::default()
+        };
+        mem.write_obj(in_header, GuestAddress(0x1000)).unwrap();
+        mem.write_obj(
+            write_in,
+            GuestAddress(0x1000 + size_of::<InHeader>() as u64),
+        )
+        .unwrap();
+
+        let len = (size_of::<InHeader>() + size_of::<WriteIn>()) as u32;
+        let chain = create_descriptor_chain(
+            &mem,
+            GuestAddress(0),
+            GuestAddress(0x1000),
+            vec![(DescriptorType::Readable, len)],
+            0,
+        )
+        .unwrap();
+        classify_request(Reader::new(&mem, chain).unwrap())
+    }
+
+    #[test]
+    fn request_classes() {
+        assert_eq!(classify(Opcode::Write, WRITE_CACHE), RequestClass::Background);
+        assert_eq!(
+            classify(Opcode::Write, WRITE_CACHE | WRITE_LOCKOWNER),
+            RequestClass::Background
+        );
+        assert_eq!(classify(Opcode::Write, 0), RequestClass::MayBlock);
+        assert_eq!(classify(Opcode::Fsync, 0), RequestClass::MayBlock);
+        assert_eq!(classify(Opcode::Lookup, WRITE_CACHE), RequestClass::Normal);
+        assert_eq!(classify(Opcode::Getattr, 0), RequestClass::Normal);
+    }
+}
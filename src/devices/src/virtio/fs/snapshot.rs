@@ -0,0 +1,324 @@
+//! The state of a share saved in a snapshot of the guest.
+//!
+//! The guest refers to the files of a share by the inode and handle numbers the device gave it,
+//! which only mean something to the process that gave them. A snapshot saves the inodes the guest
+//! holds by their paths, with their lookup counts, and the handles it holds by their inodes and
+//! open flags. Restoring it looks the paths up again, from the shallowest, opens the files again,
+//! and gives them back their numbers.
+//!
+//! The guest must not hold deleted files, as there is no path to find them again, nor mappings
+//! of the DAX window, which aren't part of the guest memory.
+
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+#[cfg(target_os = "linux")]
+use std::{collections::HashSet, ffi::CString, fs::File, os::fd::AsRawFd};
+
+#[cfg(target_os = "linux")]
+use super::dax;
+#[cfg(target_os = "linux")]
+use super::dir_template::device_context;
+#[cfg(target_os = "linux")]
+use super::filesystem::FileSystem;
+#[cfg(target_os = "linux")]
+use super::fuse;
+use super::FsImpl;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// An inode the guest holds.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct InodeState {
+    pub(crate) inode: u64,
+    pub(crate) generation: u64,
+    /// The number of lookups the guest didn't forget yet.
+    pub(crate) refcount: u64,
+    /// The path of the inode, relative to the root of the share.
+    pub(crate) path: PathBuf,
+}
+
+/// A handle the guest holds.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct HandleState {
+    pub(crate) handle: u64,
+    pub(crate) inode: u64,
+    /// The flags of the open file, with `O_DIRECTORY` for the directories.
+    pub(crate) flags: u32,
+}
+
+/// The state of a share.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct FsState {
+    /// The options negotiated with the guest when it mounted the share.
+    pub(crate) options: u64,
+    pub(crate) inodes: Vec<InodeState>,
+    pub(crate) handles: Vec<HandleState>,
+}
+
+/// Reads the fields of an encoded state.
+struct Decoder<'a>(&'a [u8]);
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl FsState {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&self.options.to_le_bytes());
+        out.extend_from_slice(&(self.inodes.len() as u64).to_le_bytes());
+        for inode in &self.inodes {
+            let path = inode.path.as_os_str().as_bytes();
+            out.extend_from_slice(&inode.inode.to_le_bytes());
+            out.extend_from_slice(&inode.generation.to_le_bytes());
+            out.extend_from_slice(&inode.refcount.to_le_bytes());
+            out.extend_from_slice(&(path.len() as u64).to_le_bytes());
+            out.extend_from_slice(path);
+        }
+        out.extend_from_slice(&(self.handles.len() as u64).to_le_bytes());
+        for handle in &self.handles {
+            out.extend_from_slice(&handle.handle.to_le_bytes());
+            out.extend_from_slice(&handle.inode.to_le_bytes());
+            out.extend_from_slice(&u64::from(handle.flags).to_le_bytes());
+        }
+        out
+    }
+
+    pub(crate) fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let mut decoder = Decoder(bytes);
+        let options = decoder.u64()?;
+
+        let count = decoder.u64()?;
+        let mut inodes = Vec::new();
+        for _ in 0..count {
+            let inode = decoder.u64()?;
+            let generation = decoder.u64()?;
+            let refcount = decoder.u64()?;
+            let len = decoder.u64()?;
+            let path = OsStr::from_bytes(decoder.bytes(len)?);
+            inodes.push(InodeState {
+                inode,
+                generation,
+                refcount,
+                path: PathBuf::from(path),
+            });
+        }
+
+        let count = decoder.u64()?;
+        let mut handles = Vec::new();
+        for _ in 0..count {
+            handles.push(HandleState {
+                handle: decoder.u64()?,
+                inode: decoder.u64()?,
+                flags: decoder.u64()? as u32,
+            });
+        }
+
+        if !decoder.0.is_empty() {
+            return Err(invalid_state());
+        }
+        Ok(FsState {
+            options,
+            inodes,
+            handles,
+        })
+    }
+}
+
+impl<'a> Decoder<'a> {
+    fn bytes(&mut self, len: u64) -> io::Result<&'a [u8]> {
+        let len = usize::try_from(len).map_err(|_| invalid_state())?;
+        if self.0.len() < len {
+            return Err(invalid_state());
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Returns the flags to open `file` again with, for the handles of the snapshot.
+#[cfg(target_os = "linux")]
+pub(crate) fn open_flags(file: &File) -> io::Result<u32> {
+    // Safe because this doesn't modify any memory and we check the return value.
+    let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let flags = if file.metadata()?.is_dir() {
+        flags | libc::O_DIRECTORY
+    } else {
+        flags
+    };
+    Ok(flags as u32)
+}
+
+/// Returns the state of `fs`, whose guest negotiated `options` and isn't sending requests.
+#[cfg(target_os = "linux")]
+pub(crate) fn save(fs: &FsImpl, options: u64) -> io::Result<FsState> {
+    if dax::has_mappings() {
+        return Err(io::Error::new(
+            io::ErrorKind::ResourceBusy,
+            "the guest has files of the share mapped in the DAX window",
+        ));
+    }
+
+    let inodes = fs.snapshot_inodes()?;
+    for inode in &inodes {
+        let (st, _) = fs.getattr(device_context(), inode.inode, None)?;
+        if st.st_nlink == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                format!("the guest holds the deleted file {}", inode.path.display()),
+            ));
+        }
+    }
+
+    Ok(FsState {
+        options,
+        inodes,
+        handles: fs.snapshot_handles()?,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn save(_fs: &FsImpl, _options: u64) -> io::Result<FsState> {
+    Err(io::Error::from_raw_os_error(libc::ENOTSUP))
+}
+
+/// Gives the guest back the inodes and handles of `state` in `fs`, which was just initialized.
+#[cfg(target_os = "linux")]
+pub(crate) fn restore(fs: &FsImpl, state: &FsState) -> io::Result<()> {
+    let ctx = device_context();
+
+    // The inodes looked up on the way mustn't take the numbers of the ones not restored yet
+    let next_inode = state.inodes.iter().map(|i| i.inode + 1).max().unwrap_or(0);
+    let next_handle = state
+        .handles
+        .iter()
+        .map(|h| h.handle + 1)
+        .max()
+        .unwrap_or(0);
+    fs.reserve_numbers(next_inode, next_handle);
+
+    let mut inodes: Vec<&InodeState> = state.inodes.iter().collect();
+    inodes.sort_by_key(|inode| inode.path.components().count());
+
+    let mut restored = HashSet::new();
+    for saved in inodes {
+        let mut looked_up = Vec::new();
+        let mut inode = fuse::ROOT_ID;
+        for name in saved.path.iter() {
+            let name = CString::new(name.as_bytes()).map_err(|_| invalid_state())?;
+            inode = fs.lookup(ctx, inode, &name)?.inode;
+            looked_up.push(inode);
+        }
+
+        // Only the lookup of the inode itself is kept
+        let found = looked_up.pop();
+        for inode in looked_up {
+            fs.forget(ctx, inode, 1);
+        }
+        match found {
+            None if saved.inode != fuse::ROOT_ID => return Err(invalid_state()),
+            Some(found) if restored.contains(&found) => {
+                fs.forget(ctx, found, 1);
+                warn!(
+                    "virtio-fs: inode {} was restored as inode {found}, a link to the same file",
+                    saved.inode
+                );
+                continue;
+            }
+            found => fs.renumber_inode(
+                found.unwrap_or(fuse::ROOT_ID),
+                saved.inode,
+                saved.generation,
+                saved.refcount,
+            )?,
+        }
+        restored.insert(saved.inode);
+    }
+
+    for saved in &state.handles {
+        let flags = saved.flags & !((libc::O_CREAT | libc::O_EXCL | libc::O_TRUNC) as u32);
+        let (handle, _) = if flags & libc::O_DIRECTORY as u32 != 0 {
+            fs.opendir(ctx, saved.inode, flags)?
+        } else {
+            fs.open(ctx, saved.inode, flags)?
+        };
+        if let Some(handle) = handle {
+            fs.renumber_handle(handle, saved.handle)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn restore(_fs: &FsImpl, _state: &FsState) -> io::Result<()> {
+    Err(io::Error::from_raw_os_error(libc::ENOTSUP))
+}
+
+/// The error of the inodes that can't be saved, as the guest can't reach them anymore.
+pub(crate) fn unreachable_inode(inode: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ResourceBusy,
+        format!("inode {inode} held by the guest is no longer in the share"),
+    )
+}
+
+fn invalid_state() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid virtio-fs state")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode_decode() {
+        let state = FsState {
+            options: 0x1234_5678_9abc,
+            inodes: vec![
+                InodeState {
+                    inode: 1,
+                    generation: 0,
+                    refcount: 2,
+                    path: PathBuf::new(),
+                },
+                InodeState {
+                    inode: 7,
+                    generation: 3,
+                    refcount: 1,
+                    path: PathBuf::from("dir/file"),
+                },
+            ],
+            handles: vec![HandleState {
+                handle: 4,
+                inode: 7,
+                flags: libc::O_RDWR as u32,
+            }],
+        };
+        let encoded = state.encode();
+        assert_eq!(FsState::decode(&encoded).unwrap(), state);
+
+        assert!(FsState::decode(&encoded[..encoded.len() - 1]).is_err());
+        assert_eq!(
+            FsState::decode(&FsState::default().encode()).unwrap(),
+            FsState::default()
+        );
+    }
+}
@@ -3,7 +3,8 @@ use std::{ffi::CString, fs, io};
 use crate::virtio::{
     bindings,
     fs::filesystem::{Context, Extensions, FileSystem},
-    fuse::FsOptions,
+    fs::overlayfs::{Config, SymlinkPolicy},
+    fuse::{FsOptions, SetattrValid},
 };
 
 use super::helper;
@@ -490,18 +491,12 @@ fn test_mkdir_invalid_name() -> io::Result<()> {
         }
     }
 
-    // Test invalid UTF-8 separately since it can't be represented as a string literal
-    let invalid_utf8 = vec![0x66, 0x6f, 0x6f, 0x80, 0x62, 0x61, 0x72]; // "foo<invalid>bar"
-    let name = CString::new(invalid_utf8).unwrap();
-    match fs.mkdir(ctx, 1, &name, 0o755, 0, Extensions::default()) {
-        Ok(_) => panic!("Expected mkdir with invalid UTF-8 to fail"),
-        Err(e) => assert_eq!(
-            e.kind(),
-            io::ErrorKind::InvalidInput,
-            "Wrong error kind for invalid UTF-8: expected {:?}, got {:?}",
-            io::ErrorKind::InvalidInput,
-            e.kind()
-        ),
+    // Names that aren't valid UTF-8 are passed through as bytes, for the host to accept or not
+    #[cfg(target_os = "linux")]
+    {
+        let invalid_utf8 = vec![0x66, 0x6f, 0x6f, 0x80, 0x62, 0x61, 0x72]; // "foo<invalid>bar"
+        let name = CString::new(invalid_utf8).unwrap();
+        fs.mkdir(ctx, 1, &name, 0o755, 0, Extensions::default())?;
     }
 
     // Test with valid but unusual names
@@ -862,6 +857,58 @@ fn test_symlink_invalid_name() -> io::Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_symlink_deny_escaping_target() -> io::Result<()> {
+    // Create test layers:
+    // Lower layer: dir/
+    let layers = vec![vec![("dir", true, 0o755)]];
+
+    let cfg = Config {
+        symlink_policy: SymlinkPolicy::Deny,
+        ..Default::default()
+    };
+    let (fs, temp_dirs) = helper::create_overlayfs_with_config(layers, cfg)?;
+    helper::debug_print_layers(&temp_dirs, false)?;
+
+    // Initialize filesystem
+    fs.init(FsOptions::empty())?;
+
+    let ctx = Context::default();
+    let dir_entry = fs.lookup(ctx, 1, &CString::new("dir").unwrap())?;
+
+    // Absolute and escaping targets are refused
+    for (parent, target) in [
+        (1, "/etc/passwd"),
+        (1, "../etc"),
+        (dir_entry.inode, "../.."),
+        (dir_entry.inode, "a/../../../b"),
+    ] {
+        let link_name = CString::new("link").unwrap();
+        let target_name = CString::new(target).unwrap();
+        let result = fs.symlink(ctx, &target_name, parent, &link_name, Extensions::default());
+        assert_eq!(
+            result.unwrap_err().raw_os_error(),
+            Some(libc::EPERM),
+            "{target}"
+        );
+    }
+
+    // Targets that stay within the overlay are still allowed
+    let link_name = CString::new("link").unwrap();
+    let target_name = CString::new("../dir").unwrap();
+    fs.symlink(
+        ctx,
+        &target_name,
+        dir_entry.inode,
+        &link_name,
+        Extensions::default(),
+    )?;
+    let lookup_entry = fs.lookup(ctx, dir_entry.inode, &link_name)?;
+    assert_eq!(fs.readlink(ctx, lookup_entry.inode)?, b"../dir");
+
+    Ok(())
+}
+
 #[test]
 fn test_rename_basic() -> io::Result<()> {
     // Create test layers
@@ -1132,6 +1179,153 @@ fn test_rename_complex_layers() -> io::Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_rename_lower_dir_tree() -> io::Result<()> {
+    // Create test layers:
+    // Lower layer: dir1/{file1, gone, sub/file2, sub/deep/file3, sub2/file4}
+    // Upper layer: dir1/{.wh.gone, file5}
+    let layers = vec![
+        vec![
+            ("dir1", true, 0o755),
+            ("dir1/file1", false, 0o644),
+            ("dir1/gone", false, 0o644),
+            ("dir1/sub", true, 0o755),
+            ("dir1/sub/file2", false, 0o644),
+            ("dir1/sub/deep", true, 0o755),
+            ("dir1/sub/deep/file3", false, 0o644),
+            ("dir1/sub2", true, 0o700),
+            ("dir1/sub2/file4", false, 0o600),
+        ],
+        vec![
+            ("dir1", true, 0o755),
+            ("dir1/.wh.gone", false, 0o644),
+            ("dir1/file5", false, 0o644),
+        ],
+    ];
+    let (fs, temp_dirs) = helper::create_overlayfs(layers)?;
+    let ctx = Context::default();
+
+    let dir1_name = CString::new("dir1")?;
+    let moved_name = CString::new("moved")?;
+    let dir1 = fs.lookup(ctx, 1, &dir1_name)?;
+    let old_sub = fs.lookup(ctx, dir1.inode, &CString::new("sub")?)?;
+    fs.rename(ctx, 1, &dir1_name, 1, &moved_name, 0)?;
+    assert!(fs.lookup(ctx, 1, &dir1_name).is_err());
+
+    // Inodes looked up before the rename resolve their entries at the new location
+    fs.lookup(ctx, old_sub.inode, &CString::new("file2")?)?;
+
+    // The whole tree moved along with the directory, except for the whited out entry
+    let moved = fs.lookup(ctx, 1, &moved_name)?;
+    for name in ["file1", "file5", "sub", "sub2"] {
+        fs.lookup(ctx, moved.inode, &CString::new(name)?)?;
+    }
+    assert!(fs.lookup(ctx, moved.inode, &CString::new("gone")?).is_err());
+
+    let sub = fs.lookup(ctx, moved.inode, &CString::new("sub")?)?;
+    fs.lookup(ctx, sub.inode, &CString::new("file2")?)?;
+    let deep = fs.lookup(ctx, sub.inode, &CString::new("deep")?)?;
+    fs.lookup(ctx, deep.inode, &CString::new("file3")?)?;
+
+    let sub2 = fs.lookup(ctx, moved.inode, &CString::new("sub2")?)?;
+    let file4 = fs.lookup(ctx, sub2.inode, &CString::new("file4")?)?;
+    assert_eq!(file4.attr.st_mode & 0o777, 0o600);
+
+    // The entries were materialized in the top layer
+    let top_layer = temp_dirs.last().unwrap().path();
+    assert!(top_layer.join("moved/sub/deep/file3").is_file());
+    assert!(top_layer.join("moved/sub2/file4").is_file());
+    assert!(!top_layer.join("moved/gone").exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_rename_lower_dir_tree_inodes() -> io::Result<()> {
+    // Create test layers:
+    // Lower layer: dir1/{file0..file15, sub/{file0..file15}}
+    // Upper layer: (empty)
+    let names: Vec<String> = (0..16)
+        .flat_map(|i| [format!("dir1/file{i}"), format!("dir1/sub/file{i}")])
+        .collect();
+    let mut lower = vec![("dir1", true, 0o755), ("dir1/sub", true, 0o755)];
+    lower.extend(names.iter().map(|name| (name.as_str(), false, 0o644)));
+    let (fs, _temp_dirs) = helper::create_overlayfs(vec![lower, vec![]])?;
+    let ctx = Context::default();
+
+    let dir1_name = CString::new("dir1")?;
+    let dir1 = fs.lookup(ctx, 1, &dir1_name)?;
+    let inode_count = fs.inodes.read().unwrap().main.len();
+    fs.rename(ctx, 1, &dir1_name, 1, &CString::new("moved")?, 0)?;
+
+    // The entries copied up along with the directory don't stay in the inode map, as the guest
+    // never looked them up and so never forgets them. Only the lower copies of the two
+    // directories do, as for any lookup going through them.
+    assert_eq!(fs.inodes.read().unwrap().main.len(), inode_count + 2);
+    let sub = fs.lookup(ctx, dir1.inode, &CString::new("sub")?)?;
+    fs.lookup(ctx, sub.inode, &CString::new("file15")?)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_durable_entry_changes() -> io::Result<()> {
+    // Create test layers:
+    // Lower layer: dir1/file1
+    // Upper layer: dir2/
+    let layers = vec![
+        vec![("dir1", true, 0o755), ("dir1/file1", false, 0o644)],
+        vec![("dir2", true, 0o755)],
+    ];
+    let cfg = Config {
+        durable: true,
+        ..Default::default()
+    };
+    let (fs, temp_dirs) = helper::create_overlayfs_with_config(layers, cfg)?;
+    fs.init(FsOptions::empty())?;
+
+    let ctx = Context::default();
+    let dir1_entry = fs.lookup(ctx, 1, &CString::new("dir1").unwrap())?;
+    let dir2_entry = fs.lookup(ctx, 1, &CString::new("dir2").unwrap())?;
+
+    // Every kind of entry change goes through the directory flushes
+    let (entry, handle, _) = fs.create(
+        ctx,
+        dir2_entry.inode,
+        &CString::new("new").unwrap(),
+        0o644,
+        libc::O_RDWR as u32,
+        0,
+        Extensions::default(),
+    )?;
+    fs.release(ctx, entry.inode, 0, handle.unwrap(), false, false, None)?;
+    fs.mkdir(
+        ctx,
+        dir2_entry.inode,
+        &CString::new("subdir").unwrap(),
+        0o755,
+        0,
+        Extensions::default(),
+    )?;
+    fs.rename(
+        ctx,
+        dir1_entry.inode,
+        &CString::new("file1").unwrap(),
+        dir2_entry.inode,
+        &CString::new("moved").unwrap(),
+        0,
+    )?;
+    fs.unlink(ctx, dir2_entry.inode, &CString::new("new").unwrap())?;
+
+    let top_layer = temp_dirs.last().unwrap().path();
+    assert!(top_layer.join("dir2/moved").exists());
+    assert!(top_layer.join("dir2/subdir").is_dir());
+    assert!(!top_layer.join("dir2/new").exists());
+    assert!(top_layer.join("dir1/.wh.file1").exists());
+
+    Ok(())
+}
+
 #[test]
 fn test_create_basic() -> io::Result<()> {
     // Create test layers:
@@ -1486,3 +1680,258 @@ fn test_mknod_nested() -> io::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_parent_times_on_child_changes() -> io::Result<()> {
+    // Create test layers:
+    // Lower layer: one directory per kind of change under base, all dated back to 2001
+    // Upper layer: empty
+    let layers = vec![
+        vec![
+            ("base", true, 0o755),
+            ("base/create", true, 0o755),
+            ("base/mkdir", true, 0o755),
+            ("base/unlink", true, 0o755),
+            ("base/unlink/file", false, 0o644),
+            ("base/rmdir", true, 0o755),
+            ("base/rmdir/dir", true, 0o755),
+            ("base/rename", true, 0o755),
+            ("base/rename/file", false, 0o644),
+            ("base/chmod", true, 0o755),
+            ("base/chmod/file", false, 0o644),
+        ],
+        vec![],
+    ];
+
+    let (fs, temp_dirs) = helper::create_overlayfs(layers)?;
+    helper::debug_print_layers(&temp_dirs, false)?;
+
+    let old = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+    let lower = temp_dirs[0].path();
+    for path in [
+        "base/create",
+        "base/mkdir",
+        "base/unlink/file",
+        "base/unlink",
+        "base/rmdir/dir",
+        "base/rmdir",
+        "base/rename/file",
+        "base/rename",
+        "base/chmod/file",
+        "base/chmod",
+        "base",
+    ] {
+        fs::File::open(lower.join(path))?
+            .set_times(fs::FileTimes::new().set_accessed(old).set_modified(old))?;
+    }
+
+    fs.init(FsOptions::empty())?;
+    let ctx = Context::default();
+    let base = fs.lookup(ctx, 1, &CString::new("base").unwrap())?.inode;
+    let dir = |name: &str| fs.lookup(ctx, base, &CString::new(name).unwrap());
+    let name = |name: &str| CString::new(name).unwrap();
+
+    let create = dir("create")?.inode;
+    let (entry, handle, _) = fs.create(
+        ctx,
+        create,
+        &name("file"),
+        0o644,
+        0,
+        0o022,
+        Extensions::default(),
+    )?;
+    fs.release(ctx, entry.inode, 0, handle.unwrap(), false, false, None)?;
+
+    let mkdir = dir("mkdir")?.inode;
+    fs.mkdir(
+        ctx,
+        mkdir,
+        &name("dir"),
+        0o755,
+        0o022,
+        Extensions::default(),
+    )?;
+
+    let unlink = dir("unlink")?.inode;
+    fs.unlink(ctx, unlink, &name("file"))?;
+
+    let rmdir = dir("rmdir")?.inode;
+    fs.rmdir(ctx, rmdir, &name("dir"))?;
+
+    let rename = dir("rename")?.inode;
+    fs.rename(ctx, rename, &name("file"), rename, &name("moved"), 0)?;
+
+    // Changing the attributes of an entry copies up its parent without changing its entries
+    let chmod = dir("chmod")?.inode;
+    let file = fs.lookup(ctx, chmod, &name("file"))?;
+    let mut attr = file.attr;
+    attr.st_mode = (attr.st_mode & !0o777) | 0o600;
+    let (attr, _) = fs.setattr(ctx, file.inode, attr, None, SetattrValid::MODE)?;
+    assert_eq!(attr.st_mtime, 1_000_000_000);
+
+    // The directories whose entries changed are dated from the change, even the ones that were
+    // copied up for it, while the copy-ups left the other ones alone
+    for changed in [create, mkdir, unlink, rmdir, rename] {
+        let (attr, _) = fs.getattr(ctx, changed, None)?;
+        assert!(attr.st_mtime > 1_000_000_000);
+        assert!(attr.st_ctime > 1_000_000_000);
+    }
+    for unchanged in [chmod, base] {
+        let (attr, _) = fs.getattr(ctx, unchanged, None)?;
+        assert_eq!(attr.st_mtime, 1_000_000_000);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_recreate_whited_out() -> io::Result<()> {
+    let layers = vec![
+        vec![
+            ("file", false, 0o644),
+            ("kept", false, 0o644),
+            ("dir", true, 0o755),
+            ("dir/lower", false, 0o644),
+        ],
+        vec![],
+    ];
+    let (fs, temp_dirs) = helper::create_overlayfs(layers)?;
+    fs.init(FsOptions::empty())?;
+    let ctx = Context::default();
+    let name = |name: &str| CString::new(name).unwrap();
+    let top_layer = temp_dirs[1].path();
+
+    // A file created over a whiteout replaces it
+    fs.lookup(ctx, 1, &name("file"))?;
+    fs.unlink(ctx, 1, &name("file"))?;
+    let (entry, handle, _) = fs.create(
+        ctx,
+        1,
+        &name("file"),
+        0o644,
+        0,
+        0o022,
+        Extensions::default(),
+    )?;
+    fs.release(ctx, entry.inode, 0, handle.unwrap(), false, false, None)?;
+    assert!(!top_layer.join(".wh.file").exists());
+    assert_eq!(fs.lookup(ctx, 1, &name("file"))?.inode, entry.inode);
+
+    // A directory created over a whiteout is opaque
+    let dir = fs.lookup(ctx, 1, &name("dir"))?;
+    fs.unlink(ctx, dir.inode, &name("lower"))?;
+    fs.rmdir(ctx, 1, &name("dir"))?;
+    let dir = fs.mkdir(ctx, 1, &name("dir"), 0o755, 0, Extensions::default())?;
+    assert!(!top_layer.join(".wh.dir").exists());
+    assert!(top_layer.join("dir/.wh..wh..opq").exists());
+    assert!(fs.lookup(ctx, dir.inode, &name("lower")).is_err());
+
+    // Renaming an entry to itself leaves it in place
+    fs.rename(ctx, 1, &name("kept"), 1, &name("kept"), 0)?;
+    fs.lookup(ctx, 1, &name("kept"))?;
+    assert!(!top_layer.join(".wh.kept").exists());
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_batch_create() -> io::Result<()> {
+    use std::sync::{atomic::AtomicI32, Arc};
+
+    use nix::request_code_readwrite;
+
+    use crate::virtio::fs::overlayfs::OverlayFs;
+
+    let ctx = Context::default();
+    let exit_code = Arc::new(AtomicI32::new(0));
+    let batch_create = |fs: &OverlayFs, inode, files: &[(&str, &[u8])]| {
+        let mut batch: Vec<u8> = 1u32.to_ne_bytes().into();
+        batch.extend_from_slice(&(files.len() as u32).to_ne_bytes());
+        for (name, data) in files {
+            for field in [
+                0o640,
+                u32::MAX,
+                u32::MAX,
+                name.len() as u32,
+                data.len() as u32,
+                0,
+            ] {
+                batch.extend_from_slice(&field.to_ne_bytes());
+            }
+            batch.extend_from_slice(&1_000_000i64.to_ne_bytes());
+            batch.extend_from_slice(name.as_bytes());
+            batch.extend_from_slice(data);
+        }
+        let cmd = request_code_readwrite!(b'v', 4, batch.len()) as u32;
+        let out = fs.ioctl(
+            ctx,
+            inode,
+            0,
+            0,
+            cmd,
+            0,
+            &batch,
+            batch.len() as u32,
+            &exit_code,
+        )?;
+        Ok::<_, io::Error>(u32::from_ne_bytes(out[4..8].try_into().unwrap()))
+    };
+
+    // Create test layers:
+    // Lower layer: dir1/, dir1/a, dir1/b
+    // Upper layer: dir1/, dir1/.wh.b
+    let layers = vec![
+        vec![
+            ("dir1", true, 0o755),
+            ("dir1/a", false, 0o644),
+            ("dir1/b", false, 0o644),
+        ],
+        vec![("dir1", true, 0o755), ("dir1/.wh.b", false, 0o644)],
+    ];
+    let dir1_name = CString::new("dir1").unwrap();
+
+    // The request is refused unless allowed
+    let (fs, _temp_dirs) = helper::create_overlayfs(layers.clone())?;
+    let dir1 = fs.lookup(ctx, 1, &dir1_name)?;
+    let res = batch_create(&fs, dir1.inode, &[("c", b"")]);
+    assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::EOPNOTSUPP));
+
+    let cfg = Config {
+        batch_create: true,
+        ..Default::default()
+    };
+    let (fs, temp_dirs) = helper::create_overlayfs_with_config(layers, cfg)?;
+    let dir1 = fs.lookup(ctx, 1, &dir1_name)?;
+
+    // An empty batch only tells the version
+    assert_eq!(batch_create(&fs, dir1.inode, &[])?, 0);
+
+    // The files are created in the top layer, replacing the whited out ones
+    assert_eq!(
+        batch_create(&fs, dir1.inode, &[("b", b"new"), ("c", b"hello")])?,
+        2
+    );
+    let upper = temp_dirs[1].path().join("dir1");
+    assert_eq!(fs::read(upper.join("c"))?, b"hello");
+    assert!(!upper.join(".wh.b").exists());
+    let b = fs.lookup(ctx, dir1.inode, &CString::new("b").unwrap())?;
+    assert_eq!(b.attr.st_size, 3);
+    assert_eq!(b.attr.st_mode & 0o7777, 0o640);
+    assert_eq!(b.attr.st_mtime, 1_000_000);
+
+    // None of the files is created if one exists in any layer
+    let res = batch_create(&fs, dir1.inode, &[("d", b""), ("a", b"")]);
+    assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::EEXIST));
+    let res = fs.lookup(ctx, dir1.inode, &CString::new("d").unwrap());
+    assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::ENOENT));
+    assert!(!upper.join("d").exists());
+
+    // Names that aren't single components or that are whiteouts are refused
+    for name in ["e/f", ".wh.e"] {
+        assert!(batch_create(&fs, dir1.inode, &[(name, b"")]).is_err());
+    }
+
+    Ok(())
+}
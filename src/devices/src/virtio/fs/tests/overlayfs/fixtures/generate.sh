@@ -0,0 +1,93 @@
+#!/bin/sh
+
+# Regenerates the layer tars of the fixtures of the overlay tests, see README.md. Run it from this
+# directory with GNU tar. The merged.golden files are written by hand, and only change when the
+# behavior of the overlay is meant to.
+
+set -e
+
+umask 022
+FIXTURES="$(pwd)"
+WORK="$(mktemp -d)"
+trap 'rm -rf "$WORK"' EXIT
+
+# Writes the layer tree $WORK/$1/$2 as $1/$2.tar, the way the container tools export layers: a
+# ustar archive of root-owned entries named without a leading "./".
+pack() {
+	mkdir -p "$FIXTURES/$1"
+	(cd "$WORK/$1/$2" && ls -A | tar --format=ustar --sort=name --numeric-owner \
+		--owner=0 --group=0 --mtime=@1700000000 -b 1 -cf - -T -) > "$FIXTURES/$1/$2.tar"
+}
+
+# file PATH MODE CONTENT
+file() {
+	mkdir -p "$(dirname "$1")"
+	printf '%s\n' "$3" > "$1"
+	chmod "$2" "$1"
+}
+
+# whiteout PATH
+whiteout() {
+	mkdir -p "$(dirname "$1")"
+	: > "$(dirname "$1")/.wh.$(basename "$1")"
+}
+
+# opaque DIR
+opaque() {
+	mkdir -p "$1"
+	: > "$1/.wh..wh..opq"
+}
+
+layer() {
+	mkdir -p "$WORK/$1/$2"
+	cd "$WORK/$1/$2"
+}
+
+# A directory made opaque above a layer whiting out one of its entries
+layer opaque_over_whiteout 0
+file etc/a 644 a
+file etc/b 644 b
+file etc/sub/c 644 c
+layer opaque_over_whiteout 1
+whiteout etc/a
+file etc/c 644 c1
+layer opaque_over_whiteout 2
+opaque etc
+file etc/d 644 d
+
+# A symlink removed by a whiteout, next to another one to the same target
+layer symlink_whiteout 0
+file bin/busybox 755 busybox
+ln -s busybox bin/sh
+ln -s /bin/busybox bin/ls
+layer symlink_whiteout 1
+whiteout bin/sh
+
+# Hard links within a layer, one of which the layer above replaces
+layer hardlinks 0
+file usr/bin/python3 755 "python 3.12"
+ln usr/bin/python3 usr/bin/python3.12
+layer hardlinks 1
+file usr/bin/python3.12 755 "python 3.12.1"
+file usr/bin/pip 755 pip
+ln usr/bin/pip usr/bin/pip3
+
+# An opaque directory holding a file named like one of the directory it hides
+layer opaque_same_name 0
+file var/lib/state 644 lower
+file var/lib/cache/x 644 x
+layer opaque_same_name 1
+opaque var/lib
+file var/lib/state 600 upper
+
+# A directory replaced by a file, with the whiteout podman writes next to it
+layer whiteout_same_name 0
+file opt/tool/bin 755 bin
+layer whiteout_same_name 1
+whiteout opt/tool
+file opt/tool 644 tool
+
+for dir in "$WORK"/*/*; do
+	fixture="$(basename "$(dirname "$dir")")"
+	pack "$fixture" "$(basename "$dir")"
+done
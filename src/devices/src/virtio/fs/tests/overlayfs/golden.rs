@@ -0,0 +1,307 @@
+//! Golden tests of the overlay on the layer fixtures of `fixtures`, miniature layers in the format
+//! the container tools export them in, see `fixtures/README.md`. Each fixture is checked against
+//! the listing of its merged view in its `merged.golden` file, and against the outcome of the
+//! changes its edge case keeps getting wrong.
+
+use std::{
+    ffi::CString,
+    fmt::Write,
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use tempfile::TempDir;
+
+use crate::virtio::{
+    fs::filesystem::{Context, Extensions, FileSystem, SetattrValid},
+    fs::overlayfs::{Config, OverlayFs},
+    fuse::FsOptions,
+};
+
+use super::helper::TestContainer;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+const FIXTURES: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/src/virtio/fs/tests/overlayfs/fixtures"
+);
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+fn name(name: &str) -> CString {
+    CString::new(name).unwrap()
+}
+
+/// Returns the fixtures, in order.
+fn fixtures() -> Vec<PathBuf> {
+    let mut fixtures: Vec<_> = fs::read_dir(FIXTURES)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_dir())
+        .collect();
+    fixtures.sort();
+    fixtures
+}
+
+/// Unpacks the layers of the fixture `fixture`, from `0.tar` up, under an empty top layer, and
+/// returns the overlay of them.
+fn unpack(fixture: &Path) -> io::Result<(OverlayFs, Vec<TempDir>)> {
+    let mut temp_dirs = Vec::new();
+    let mut layer_paths = Vec::new();
+    for idx in 0.. {
+        let tar = fixture.join(format!("{idx}.tar"));
+        if !tar.exists() {
+            break;
+        }
+        let dir = TempDir::new()?;
+        let status = Command::new("tar")
+            .arg("-xpf")
+            .arg(&tar)
+            .arg("--no-same-owner")
+            .arg("-C")
+            .arg(dir.path())
+            .status()?;
+        assert!(status.success(), "failed to unpack {}", tar.display());
+        layer_paths.push(dir.path().to_path_buf());
+        temp_dirs.push(dir);
+    }
+    let top = TempDir::new()?;
+    layer_paths.push(top.path().to_path_buf());
+    temp_dirs.push(top);
+
+    let fs = OverlayFs::new(Config {
+        layers: layer_paths,
+        ..Default::default()
+    })?;
+    fs.init(FsOptions::empty())?;
+    Ok((fs, temp_dirs))
+}
+
+/// Returns the names of the entries of the directory `inode`, sorted.
+fn readdir(fs: &OverlayFs, inode: u64) -> io::Result<Vec<String>> {
+    let ctx = Context::default();
+    let (handle, _) = fs.opendir(ctx, inode, libc::O_RDONLY as u32)?;
+    let handle = handle.unwrap();
+    let mut names = Vec::new();
+    let res = fs.readdir(ctx, inode, handle, 65536, 0, |entry| {
+        names.push(String::from_utf8(entry.name.to_vec()).unwrap());
+        Ok(1)
+    });
+    fs.releasedir(ctx, inode, 0, handle)?;
+    res?;
+    names.sort();
+    Ok(names)
+}
+
+/// Returns the content of the file `inode`.
+fn read(fs: &OverlayFs, inode: u64) -> io::Result<String> {
+    let ctx = Context::default();
+    let size = fs.getattr(ctx, inode, None)?.0.st_size;
+    if size == 0 {
+        return Ok(String::new());
+    }
+    let (handle, _) = fs.open(ctx, inode, libc::O_RDONLY as u32)?;
+    let handle = handle.unwrap();
+    let mut writer = TestContainer(Vec::new());
+    let res = fs.read(ctx, inode, handle, &mut writer, size as u32, 0, None, 0);
+    fs.release(ctx, inode, 0, handle, false, false, None)?;
+    res?;
+    Ok(String::from_utf8(writer.0).unwrap())
+}
+
+/// Lists the merged view below the directory `inode`, one entry per line: the directories with
+/// their modes, the files with their modes and contents, and the symlinks with their targets.
+fn render(fs: &OverlayFs, inode: u64, prefix: &str, out: &mut String) -> io::Result<()> {
+    let ctx = Context::default();
+    for entry_name in readdir(fs, inode)? {
+        let entry = fs.lookup(ctx, inode, &name(&entry_name))?;
+        let path = format!("{prefix}{entry_name}");
+        let mode = entry.attr.st_mode & 0o7777;
+        match entry.attr.st_mode & libc::S_IFMT {
+            libc::S_IFDIR => {
+                writeln!(out, "{path}/ {mode:o}").unwrap();
+                render(fs, entry.inode, &format!("{path}/"), out)?;
+            }
+            libc::S_IFREG => {
+                let content = read(fs, entry.inode)?;
+                writeln!(out, "{path} {mode:o} {content:?}").unwrap();
+            }
+            libc::S_IFLNK => {
+                let target = fs.readlink(ctx, entry.inode)?;
+                writeln!(out, "{path} -> {}", String::from_utf8_lossy(&target)).unwrap();
+            }
+            file_type => writeln!(out, "{path} type {file_type:o}").unwrap(),
+        }
+        fs.forget(ctx, entry.inode, 1);
+    }
+    Ok(())
+}
+
+/// Looks up the entry at `path`, returning its inode.
+fn lookup_path(fs: &OverlayFs, path: &str) -> io::Result<u64> {
+    let mut inode = 1;
+    for component in path.split('/') {
+        inode = fs
+            .lookup(Context::default(), inode, &name(component))?
+            .inode;
+    }
+    Ok(inode)
+}
+
+fn assert_enoent(res: io::Result<u64>) {
+    assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::ENOENT));
+}
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_merged_views() -> io::Result<()> {
+    let fixtures = fixtures();
+    assert!(!fixtures.is_empty());
+    for fixture in fixtures {
+        let (fs, _temp_dirs) = unpack(&fixture)?;
+        let mut merged = String::new();
+        render(&fs, 1, "", &mut merged)?;
+        let golden = fs::read_to_string(fixture.join("merged.golden"))?;
+        assert_eq!(
+            merged,
+            golden,
+            "the merged view of {} changed",
+            fixture.display()
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_opaque_over_whiteout() -> io::Result<()> {
+    let (fs, _temp_dirs) = unpack(&Path::new(FIXTURES).join("opaque_over_whiteout"))?;
+    let ctx = Context::default();
+    let etc = lookup_path(&fs, "etc")?;
+
+    // Neither the entry the opaque directory hides nor the one whited out below it comes back
+    // when the guest creates an entry of the same name
+    for hidden in ["a", "b", "c", "sub"] {
+        assert_enoent(lookup_path(&fs, &format!("etc/{hidden}")));
+    }
+    let (entry, handle, _) = fs.create(
+        ctx,
+        etc,
+        &name("a"),
+        0o644,
+        libc::O_RDWR as u32,
+        0,
+        Extensions::default(),
+    )?;
+    fs.release(ctx, entry.inode, 0, handle.unwrap(), false, false, None)?;
+    assert_eq!(read(&fs, entry.inode)?, "");
+    assert_eq!(readdir(&fs, etc)?, ["a", "d"]);
+
+    fs.unlink(ctx, etc, &name("d"))?;
+    assert_eq!(readdir(&fs, etc)?, ["a"]);
+    fs.unlink(ctx, etc, &name("a"))?;
+    assert!(readdir(&fs, etc)?.is_empty());
+    fs.rmdir(ctx, 1, &name("etc"))?;
+    assert_enoent(lookup_path(&fs, "etc"));
+    Ok(())
+}
+
+#[test]
+fn test_symlink_whiteout() -> io::Result<()> {
+    let (fs, _temp_dirs) = unpack(&Path::new(FIXTURES).join("symlink_whiteout"))?;
+    let ctx = Context::default();
+    let bin = lookup_path(&fs, "bin")?;
+
+    // The whiteout hides the link, not its target
+    assert_enoent(lookup_path(&fs, "bin/sh"));
+    assert_eq!(read(&fs, lookup_path(&fs, "bin/busybox")?)?, "busybox\n");
+
+    // A link recreated in its place points where it is told to
+    let entry = fs.symlink(
+        ctx,
+        &name("/bin/busybox"),
+        bin,
+        &name("sh"),
+        Extensions::default(),
+    )?;
+    assert_eq!(fs.readlink(ctx, entry.inode)?, b"/bin/busybox");
+    assert_eq!(readdir(&fs, bin)?, ["busybox", "ls", "sh"]);
+
+    fs.unlink(ctx, bin, &name("sh"))?;
+    assert_enoent(lookup_path(&fs, "bin/sh"));
+    assert_eq!(readdir(&fs, bin)?, ["busybox", "ls"]);
+    Ok(())
+}
+
+#[test]
+fn test_hardlinks() -> io::Result<()> {
+    let (fs, temp_dirs) = unpack(&Path::new(FIXTURES).join("hardlinks"))?;
+    let ctx = Context::default();
+
+    // The links of a layer are the same inode, unless a layer above replaces one of them
+    assert_eq!(
+        lookup_path(&fs, "usr/bin/pip")?,
+        lookup_path(&fs, "usr/bin/pip3")?
+    );
+    let python3 = lookup_path(&fs, "usr/bin/python3")?;
+    assert_ne!(python3, lookup_path(&fs, "usr/bin/python3.12")?);
+    assert_eq!(read(&fs, python3)?, "python 3.12\n");
+
+    // Truncating a link copies it up alone, leaving the other link of its layer as it was
+    let mut attr = fs.getattr(ctx, python3, None)?.0;
+    attr.st_size = 0;
+    fs.setattr(ctx, python3, attr, None, SetattrValid::SIZE)?;
+    assert_eq!(read(&fs, python3)?, "");
+    assert_eq!(
+        read(&fs, lookup_path(&fs, "usr/bin/python3.12")?)?,
+        "python 3.12.1\n"
+    );
+    let lower = temp_dirs[0].path().join("usr/bin");
+    assert_eq!(fs::read(lower.join("python3"))?, b"python 3.12\n");
+    assert_eq!(fs::read(lower.join("python3.12"))?, b"python 3.12\n");
+    Ok(())
+}
+
+#[test]
+fn test_opaque_same_name() -> io::Result<()> {
+    let (fs, _temp_dirs) = unpack(&Path::new(FIXTURES).join("opaque_same_name"))?;
+    let ctx = Context::default();
+    let lib = lookup_path(&fs, "var/lib")?;
+
+    // The file next to the opaque marker shadows the one it hides, which never shows through
+    let state = lookup_path(&fs, "var/lib/state")?;
+    assert_eq!(read(&fs, state)?, "upper\n");
+    assert_enoent(lookup_path(&fs, "var/lib/cache"));
+
+    fs.unlink(ctx, lib, &name("state"))?;
+    assert_enoent(lookup_path(&fs, "var/lib/state"));
+    assert!(readdir(&fs, lib)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_whiteout_same_name() -> io::Result<()> {
+    let (fs, _temp_dirs) = unpack(&Path::new(FIXTURES).join("whiteout_same_name"))?;
+    let ctx = Context::default();
+    let opt = lookup_path(&fs, "opt")?;
+
+    // The file replacing the directory is found, not the directory nor the whiteout
+    let tool = fs.lookup(ctx, opt, &name("tool"))?;
+    assert_eq!(tool.attr.st_mode & libc::S_IFMT, libc::S_IFREG);
+    assert!(fs.lookup(ctx, tool.inode, &name("bin")).is_err());
+
+    // A directory created in its place is empty
+    fs.unlink(ctx, opt, &name("tool"))?;
+    let dir = fs.mkdir(ctx, opt, &name("tool"), 0o755, 0, Extensions::default())?;
+    assert!(readdir(&fs, dir.inode)?.is_empty());
+    assert_enoent(lookup_path(&fs, "opt/tool/bin"));
+    Ok(())
+}
@@ -1,8 +1,12 @@
-use std::{ffi::CString, io};
+use std::{ffi::CString, fs, io, thread, time::Duration};
 
 use crate::virtio::{
-    fs::filesystem::{Context, FileSystem},
+    fs::{
+        filesystem::{Context, Extensions, FileSystem},
+        LowerLayerSet,
+    },
     fuse::FsOptions,
+    overlayfs::{Config, OverlayFs},
 };
 
 use super::helper;
@@ -456,3 +460,396 @@ fn test_lookup_opaque_with_empty_subdir() -> io::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_lookup_stale_whiteout() -> io::Result<()> {
+    // Create test layers:
+    // Lower layer: dir1/file1, dir1/file2
+    // Upper layer: dir1/.wh.file1
+    let layers = vec![
+        vec![
+            ("dir1", true, 0o755),
+            ("dir1/file1", false, 0o644),
+            ("dir1/file2", false, 0o644),
+        ],
+        vec![("dir1", true, 0o755), ("dir1/.wh.file1", false, 0o644)],
+    ];
+
+    let cfg = Config {
+        verify_whiteouts: true,
+        ..Default::default()
+    };
+    let (fs, temp_dirs) = helper::create_overlayfs_with_config(layers, cfg)?;
+    fs.init(FsOptions::empty())?;
+    let ctx = Context::default();
+
+    let dir1_name = CString::new("dir1").unwrap();
+    let file1_name = CString::new("file1").unwrap();
+    let dir1_entry = fs.lookup(ctx, 1, &dir1_name)?;
+    assert!(fs.lookup(ctx, dir1_entry.inode, &file1_name).is_err());
+
+    // Recreate file1 in the lower layer on the host, after the whiteout
+    thread::sleep(Duration::from_millis(20));
+    let lower_file1 = temp_dirs[0].path().join("dir1/file1");
+    fs::remove_file(&lower_file1)?;
+    fs::write(&lower_file1, b"new content")?;
+
+    // The whiteout is stale, so file1 is visible again in lookups and listings
+    let entry = fs.lookup(ctx, dir1_entry.inode, &file1_name)?;
+    assert_eq!(entry.attr.st_size, 11);
+
+    let (handle, _) = fs.opendir(ctx, dir1_entry.inode, libc::O_RDONLY as u32)?;
+    let handle = handle.unwrap();
+    let mut entries = Vec::new();
+    fs.readdir(ctx, dir1_entry.inode, handle, 4096, 0, |dir_entry| {
+        entries.push(String::from_utf8_lossy(dir_entry.name).to_string());
+        Ok(1)
+    })?;
+    entries.sort();
+    assert_eq!(entries, vec!["file1", "file2"]);
+
+    // Refreshing the layers removes the stale whiteout
+    assert_eq!(fs.refresh_layers()?, 1);
+    assert!(!temp_dirs[1].path().join("dir1/.wh.file1").exists());
+    fs.lookup(ctx, dir1_entry.inode, &file1_name)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_lookup_lower_whiteout_never_stale() -> io::Result<()> {
+    // Create test layers:
+    // Bottom layer: dir1/file1, dir1/file2
+    // Middle layer: dir1/.wh.file1
+    // Upper layer: dir1
+    let layers = vec![
+        vec![
+            ("dir1", true, 0o755),
+            ("dir1/file1", false, 0o644),
+            ("dir1/file2", false, 0o644),
+        ],
+        vec![("dir1", true, 0o755), ("dir1/.wh.file1", false, 0o644)],
+        vec![("dir1", true, 0o755)],
+    ];
+
+    let cfg = Config {
+        verify_whiteouts: true,
+        ..Default::default()
+    };
+    let (fs, temp_dirs) = helper::create_overlayfs_with_config(layers, cfg)?;
+    fs.init(FsOptions::empty())?;
+    let ctx = Context::default();
+
+    // The bottom layer gets a newer file1 than the whiteout of the middle layer, as when the
+    // layers of an image are unpacked out of order
+    thread::sleep(Duration::from_millis(20));
+    let lower_file1 = temp_dirs[0].path().join("dir1/file1");
+    fs::remove_file(&lower_file1)?;
+    fs::write(&lower_file1, b"new content")?;
+
+    // The whiteout still hides file1 in lookups and listings
+    let dir1_name = CString::new("dir1").unwrap();
+    let file1_name = CString::new("file1").unwrap();
+    let dir1_entry = fs.lookup(ctx, 1, &dir1_name)?;
+    assert!(fs.lookup(ctx, dir1_entry.inode, &file1_name).is_err());
+
+    let (handle, _) = fs.opendir(ctx, dir1_entry.inode, libc::O_RDONLY as u32)?;
+    let handle = handle.unwrap();
+    let mut entries = Vec::new();
+    fs.readdir(ctx, dir1_entry.inode, handle, 4096, 0, |dir_entry| {
+        entries.push(String::from_utf8_lossy(dir_entry.name).to_string());
+        Ok(1)
+    })?;
+    entries.sort();
+    assert_eq!(entries, vec!["file2"]);
+
+    // Refreshing the layers leaves it alone
+    assert_eq!(fs.refresh_layers()?, 0);
+    assert!(temp_dirs[1].path().join("dir1/.wh.file1").exists());
+    assert!(fs.lookup(ctx, dir1_entry.inode, &file1_name).is_err());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_lookup_case_insensitive() -> io::Result<()> {
+    // Create test layers:
+    // Lower layer: Makefile, Src/Main.c, README
+    // Upper layer: readme
+    let layers = || {
+        vec![
+            vec![
+                ("Makefile", false, 0o644),
+                ("Src", true, 0o755),
+                ("Src/Main.c", false, 0o644),
+                ("README", false, 0o644),
+            ],
+            vec![("readme", false, 0o644)],
+        ]
+    };
+    let ctx = Context::default();
+    let name = |name: &str| CString::new(name).unwrap();
+    let errno = |res: io::Result<_>| res.map(drop).unwrap_err().raw_os_error();
+
+    // The lookups match the names byte for byte by default
+    let (fs, _temp_dirs) = helper::create_overlayfs(layers())?;
+    assert_eq!(
+        errno(fs.lookup(ctx, 1, &name("makefile"))),
+        Some(libc::ENOENT)
+    );
+
+    let cfg = Config {
+        case_insensitive: true,
+        ..Default::default()
+    };
+    let (fs, temp_dirs) = helper::create_overlayfs_with_config(layers(), cfg)?;
+    fs.init(FsOptions::empty())?;
+
+    // A missing name finds the entry that only differs by case, at any depth
+    let makefile = fs.lookup(ctx, 1, &name("Makefile"))?;
+    assert_eq!(fs.lookup(ctx, 1, &name("makefile"))?.inode, makefile.inode);
+    let src = fs.lookup(ctx, 1, &name("SRC"))?;
+    assert_eq!(src.attr.st_mode & libc::S_IFMT, libc::S_IFDIR);
+    fs.lookup(ctx, src.inode, &name("main.C"))?;
+
+    // The entries of different layers colliding are still found by their own names only
+    let upper_readme = fs.lookup(ctx, 1, &name("readme"))?;
+    let lower_readme = fs.lookup(ctx, 1, &name("README"))?;
+    assert_ne!(upper_readme.inode, lower_readme.inode);
+    assert_eq!(errno(fs.lookup(ctx, 1, &name("Readme"))), Some(libc::EIO));
+
+    // Removing the entry through another case hides the entry of the layers
+    fs.unlink(ctx, 1, &name("MAKEFILE"))?;
+    assert!(temp_dirs[1].path().join(".wh.Makefile").exists());
+    assert_eq!(
+        errno(fs.lookup(ctx, 1, &name("Makefile"))),
+        Some(libc::ENOENT)
+    );
+    assert_eq!(
+        errno(fs.lookup(ctx, 1, &name("makefile"))),
+        Some(libc::ENOENT)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_lookup_with_filters() -> io::Result<()> {
+    // Create test layers:
+    // Layer 0: usr/bin/ls, etc/passwd, opt/app/bin
+    // Layer 1: .wh.etc, opt/.wh..wh..opq, opt/lib
+    // Layer 2: var/log
+    // Layer 3 (top): empty
+    let layers = vec![
+        vec![
+            ("usr", true, 0o755),
+            ("usr/bin", true, 0o755),
+            ("usr/bin/ls", false, 0o755),
+            ("etc", true, 0o755),
+            ("etc/passwd", false, 0o644),
+            ("opt", true, 0o755),
+            ("opt/app", true, 0o755),
+            ("opt/app/bin", false, 0o755),
+        ],
+        vec![
+            (".wh.etc", false, 0o644),
+            ("opt", true, 0o755),
+            ("opt/.wh..wh..opq", false, 0o644),
+            ("opt/lib", false, 0o644),
+        ],
+        vec![("var", true, 0o755), ("var/log", false, 0o644)],
+        vec![],
+    ];
+
+    let cfg = Config {
+        lookup_filters: true,
+        ..Default::default()
+    };
+    let (fs, temp_dirs) = helper::create_overlayfs_with_config(layers, cfg)?;
+    fs.init(FsOptions::empty())?;
+    let ctx = Context::default();
+
+    // Entries are found through the layers that don't have them
+    let usr_entry = fs.lookup(ctx, 1, &CString::new("usr").unwrap())?;
+    let bin_entry = fs.lookup(ctx, usr_entry.inode, &CString::new("bin").unwrap())?;
+    fs.lookup(ctx, bin_entry.inode, &CString::new("ls").unwrap())?;
+    fs.lookup(ctx, 1, &CString::new("var").unwrap())?;
+
+    // Whiteouts and opaque directories still hide the lower entries
+    assert!(fs.lookup(ctx, 1, &CString::new("etc").unwrap()).is_err());
+    let opt_entry = fs.lookup(ctx, 1, &CString::new("opt").unwrap())?;
+    fs.lookup(ctx, opt_entry.inode, &CString::new("lib").unwrap())?;
+    assert!(fs
+        .lookup(ctx, opt_entry.inode, &CString::new("app").unwrap())
+        .is_err());
+
+    // Entries added to a lower layer on the host are only found once the layers are refreshed
+    fs::write(temp_dirs[0].path().join("motd"), b"")?;
+    let motd_name = CString::new("motd").unwrap();
+    assert!(fs.lookup(ctx, 1, &motd_name).is_err());
+    fs.refresh_layers()?;
+    fs.lookup(ctx, 1, &motd_name)?;
+
+    // Entries created by the guest in the top layer are found right away
+    let home_name = CString::new("home").unwrap();
+    fs.mkdir(ctx, 1, &home_name, 0o755, 0, Extensions::default())?;
+    fs.lookup(ctx, 1, &home_name)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_lookup_shared_lower_layers() -> io::Result<()> {
+    let lower = tempfile::tempdir()?;
+    fs::write(lower.path().join("file"), b"abc")?;
+    let set = LowerLayerSet::shared(&[lower.path().to_path_buf()])?;
+
+    let tops = [tempfile::tempdir()?, tempfile::tempdir()?];
+    let overlays = tops
+        .iter()
+        .map(|top| {
+            let fs = OverlayFs::new(Config {
+                layers: vec![lower.path().to_path_buf(), top.path().to_path_buf()],
+                lookup_filters: true,
+                lower_layers: Some(set.clone()),
+                ..Default::default()
+            })?;
+            fs.init(FsOptions::empty())?;
+            Ok(fs)
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    assert_eq!(set.handles(), 3);
+    let ctx = Context::default();
+    let file_name = CString::new("file").unwrap();
+
+    // The filter and the attributes are taken from the host by the first overlay, and found in
+    // the set by the second one
+    let entry = overlays[0].lookup(ctx, 1, &file_name)?;
+    assert_eq!(overlays[0].getattr(ctx, entry.inode, None)?.0.st_size, 3);
+    fs::write(lower.path().join("file"), b"abcdef")?;
+    fs::write(lower.path().join("motd"), b"")?;
+    let entry = overlays[1].lookup(ctx, 1, &file_name)?;
+    assert_eq!(overlays[1].getattr(ctx, entry.inode, None)?.0.st_size, 3);
+    let motd_name = CString::new("motd").unwrap();
+    assert!(overlays[1].lookup(ctx, 1, &motd_name).is_err());
+
+    // Refreshing the layers of either overlay drops the caches of both
+    overlays[0].refresh_layers()?;
+    assert_eq!(overlays[1].getattr(ctx, entry.inode, None)?.0.st_size, 6);
+    overlays[1].lookup(ctx, 1, &motd_name)?;
+
+    // An overlay can't be attached to the set of other lower layers
+    let top = tempfile::tempdir()?;
+    fs::create_dir(top.path().join("other"))?;
+    let err = OverlayFs::new(Config {
+        layers: vec![top.path().join("other"), lower.path().to_path_buf()],
+        lower_layers: Some(set.clone()),
+        ..Default::default()
+    })
+    .err()
+    .unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    drop(overlays);
+    assert_eq!(set.handles(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_lookup_whiteout_cache() -> io::Result<()> {
+    // Layer 0: dir/a, dir/b, dir/c
+    // Layer 1 (top): dir/.wh.b
+    let layers = vec![
+        vec![
+            ("dir", true, 0o755),
+            ("dir/a", false, 0o644),
+            ("dir/b", false, 0o644),
+            ("dir/c", false, 0o644),
+        ],
+        vec![("dir", true, 0o755), ("dir/.wh.b", false, 0o644)],
+    ];
+
+    let cfg = Config {
+        whiteout_cache_ttl: Some(Duration::from_secs(3600)),
+        ..Default::default()
+    };
+    let (fs, temp_dirs) = helper::create_overlayfs_with_config(layers, cfg)?;
+    fs.init(FsOptions::empty())?;
+    let ctx = Context::default();
+
+    let dir_entry = fs.lookup(ctx, 1, &CString::new("dir").unwrap())?;
+    let (a_name, b_name, c_name) = (
+        CString::new("a").unwrap(),
+        CString::new("b").unwrap(),
+        CString::new("c").unwrap(),
+    );
+    fs.lookup(ctx, dir_entry.inode, &a_name)?;
+    assert!(fs.lookup(ctx, dir_entry.inode, &b_name).is_err());
+
+    // The whiteouts created and removed by the overlay are seen right away
+    fs.unlink(ctx, dir_entry.inode, &a_name)?;
+    assert!(fs.lookup(ctx, dir_entry.inode, &a_name).is_err());
+    fs.mknod(
+        ctx,
+        dir_entry.inode,
+        &b_name,
+        libc::S_IFREG | 0o644,
+        0,
+        0,
+        Extensions::default(),
+    )?;
+    fs.lookup(ctx, dir_entry.inode, &b_name)?;
+
+    // The ones created on the host only once the layers are refreshed
+    fs.lookup(ctx, dir_entry.inode, &c_name)?;
+    fs::write(temp_dirs[1].path().join("dir/.wh.c"), b"")?;
+    fs.lookup(ctx, dir_entry.inode, &c_name)?;
+    fs.refresh_layers()?;
+    assert!(fs.lookup(ctx, dir_entry.inode, &c_name).is_err());
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_lookup_dentry_warming() -> io::Result<()> {
+    // Layer 0: dir/a, dir/b, dir/sub/c
+    // Layer 1 (top): dir/.wh.a, dir/d
+    let layers = vec![
+        vec![
+            ("dir", true, 0o755),
+            ("dir/a", false, 0o644),
+            ("dir/b", false, 0o644),
+            ("dir/sub", true, 0o755),
+            ("dir/sub/c", false, 0o644),
+        ],
+        vec![
+            ("dir", true, 0o755),
+            ("dir/.wh.a", false, 0o644),
+            ("dir/d", false, 0o644),
+        ],
+    ];
+
+    let cfg = Config {
+        dentry_warming: Some(2),
+        ..Default::default()
+    };
+    let (fs, _temp_dirs) = helper::create_overlayfs_with_config(layers, cfg)?;
+    fs.init(FsOptions::empty())?;
+    let ctx = Context::default();
+
+    // Warming the directories visited doesn't change what the lookups find
+    let dir_entry = fs.lookup(ctx, 1, &CString::new("dir").unwrap())?;
+    let (handle, _) = fs.opendir(ctx, dir_entry.inode, libc::O_RDONLY as u32)?;
+    assert!(fs
+        .lookup(ctx, dir_entry.inode, &CString::new("a").unwrap())
+        .is_err());
+    fs.lookup(ctx, dir_entry.inode, &CString::new("b").unwrap())?;
+    fs.lookup(ctx, dir_entry.inode, &CString::new("d").unwrap())?;
+    let sub_entry = fs.lookup(ctx, dir_entry.inode, &CString::new("sub").unwrap())?;
+    fs.lookup(ctx, sub_entry.inode, &CString::new("c").unwrap())?;
+    fs.releasedir(ctx, dir_entry.inode, 0, handle.unwrap())?;
+
+    Ok(())
+}
@@ -4,7 +4,8 @@ use crate::virtio::{
     bindings::{self, LINUX_ENODATA, LINUX_ENOSYS},
     fs::filesystem::{Context, FileSystem, GetxattrReply, ListxattrReply},
     fuse::{FsOptions, SetattrValid},
-    linux_errno::LINUX_ERANGE, overlayfs::{Config, OverlayFs},
+    linux_errno::LINUX_ERANGE,
+    overlayfs::{Config, DirNlinkPolicy, OverlayFs},
 };
 
 use super::helper;
@@ -91,6 +92,40 @@ fn test_getattr_invalid_inode() -> io::Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_statx() -> io::Result<()> {
+    // Create test layers:
+    // Lower layer: file1
+    // Upper layer: empty
+    let layers = vec![vec![("file1", false, 0o644)], vec![]];
+
+    let (fs, temp_dirs) = helper::create_overlayfs(layers)?;
+    fs.init(FsOptions::empty())?;
+    let ctx = Context::default();
+
+    let file1_name = CString::new("file1").unwrap();
+    let file1_entry = fs.lookup(ctx, 1, &file1_name)?;
+    let (attr, _) = fs.getattr(ctx, file1_entry.inode, None)?;
+    let (sx_attr, btime, _) = fs.statx(ctx, file1_entry.inode, None)?;
+    assert_eq!(sx_attr.st_ino, attr.st_ino);
+    assert_eq!(sx_attr.st_mode, attr.st_mode);
+    assert_eq!(sx_attr.st_mtime, attr.st_mtime);
+
+    // The creation time is the one of the host file, when its file system records it
+    let host_btime = fs::metadata(temp_dirs[0].path().join("file1"))?
+        .created()
+        .ok()
+        .map(|t| t.duration_since(std::time::UNIX_EPOCH).unwrap());
+    assert_eq!(
+        btime.map(|t| (t.sec as u64, t.nsec)),
+        host_btime.map(|t| (t.as_secs(), t.subsec_nanos()))
+    );
+
+    assert!(fs.statx(ctx, 999999, None).is_err());
+
+    Ok(())
+}
+
 #[test]
 fn test_getattr_whiteout() -> io::Result<()> {
     // Create test layers:
@@ -259,6 +294,184 @@ fn test_getattr_complex() -> io::Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_getattr_dir_nlink() -> io::Result<()> {
+    // Create test layers:
+    // Lower layer: dir/a/, dir/b/, dir/file
+    // Upper layer: dir/c/, dir/.wh.a (whiteout for a)
+    let layers = vec![
+        vec![
+            ("dir", true, 0o755),
+            ("dir/a", true, 0o755),
+            ("dir/b", true, 0o755),
+            ("dir/file", false, 0o644),
+        ],
+        vec![
+            ("dir", true, 0o755),
+            ("dir/c", true, 0o755),
+            ("dir/.wh.a", false, 0o644),
+        ],
+    ];
+
+    // Only b and c are subdirectories of the merged directory
+    for (policy, nlink) in [
+        (DirNlinkPolicy::One, 1),
+        (DirNlinkPolicy::Merged, 4),
+        (DirNlinkPolicy::Layer, 3),
+    ] {
+        let cfg = Config {
+            dir_nlink: policy,
+            ..Default::default()
+        };
+        let (fs, _temp_dirs) = helper::create_overlayfs_with_config(layers.clone(), cfg)?;
+        fs.init(FsOptions::empty())?;
+
+        let ctx = Context::default();
+        let entry = fs.lookup(ctx, 1, &CString::new("dir").unwrap())?;
+        assert_eq!(entry.attr.st_nlink, nlink, "{policy:?}");
+
+        let (attr, _) = fs.getattr(ctx, entry.inode, None)?;
+        assert_eq!(attr.st_nlink, nlink, "{policy:?}");
+
+        // Regular files keep their link count
+        let file_entry = fs.lookup(ctx, entry.inode, &CString::new("file").unwrap())?;
+        assert_eq!(file_entry.attr.st_nlink, 1);
+    }
+
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_single_dev() -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    use crate::virtio::fs::{filesystem::Extensions, overlayfs::UpperLayer};
+
+    // The lower layer lives in a temporary directory and the top layer in /dev/shm
+    let layers = vec![vec![("dir", true, 0o755), ("dir/file", false, 0o644)]];
+    let cfg = Config {
+        upper_layer: UpperLayer::Ram { size_limit: 4096 },
+        single_dev: true,
+        ..Default::default()
+    };
+    let (fs, _temp_dirs) = helper::create_overlayfs_with_config(layers, cfg)?;
+    fs.init(FsOptions::empty())?;
+    let top_dev = fs::metadata(fs.get_config().layers.last().unwrap())?.dev();
+
+    // Entries from both layers report the device of the top layer
+    let ctx = Context::default();
+    let dir = fs.lookup(ctx, 1, &CString::new("dir").unwrap())?;
+    let file = fs.lookup(ctx, dir.inode, &CString::new("file").unwrap())?;
+    let (created, _, _) = fs.create(
+        ctx,
+        dir.inode,
+        &CString::new("created").unwrap(),
+        0o644,
+        libc::O_RDWR as u32,
+        0,
+        Extensions::default(),
+    )?;
+    for entry in [&dir, &file, &created] {
+        assert_eq!(entry.attr.st_dev, top_dev);
+        let (attr, _) = fs.getattr(ctx, entry.inode, None)?;
+        assert_eq!(attr.st_dev, top_dev);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_differential_blocks() -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let layers = vec![vec![("file", false, 0o644)], vec![]];
+    let cfg = Config {
+        differential_blocks: true,
+        ..Default::default()
+    };
+    let (fs, temp_dirs) = helper::create_overlayfs_with_config(layers, cfg)?;
+    fs::write(temp_dirs[0].path().join("file"), vec![1; 64 << 10])?;
+    fs.init(FsOptions::empty())?;
+
+    // A lower layer file reports its blocks
+    let ctx = Context::default();
+    let file = fs.lookup(ctx, 1, &CString::new("file").unwrap())?;
+    assert!(file.attr.st_blocks > 0);
+
+    // A copy-up only adds the blocks the guest writes to it
+    let mut attr = file.attr;
+    attr.st_mode = (attr.st_mode & !0o777) | 0o600;
+    let (attr, _) = fs.setattr(ctx, file.inode, attr, None, SetattrValid::MODE)?;
+    assert_eq!(attr.st_blocks, 0);
+    let entry = fs.lookup(ctx, 1, &CString::new("file").unwrap())?;
+    assert_eq!(entry.attr.st_blocks, 0);
+
+    let upper = temp_dirs[1].path().join("file");
+    fs::write(&upper, vec![2; 64 << 10])?;
+    let (attr, _) = fs.getattr(ctx, file.inode, None)?;
+    assert_eq!(attr.st_blocks as u64, fs::metadata(&upper)?.blocks());
+    assert!(attr.st_blocks > 0);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_copy_up_sparse_file() -> io::Result<()> {
+    use std::os::unix::fs::{FileExt, MetadataExt};
+
+    let layers = vec![vec![("sparse", false, 0o644)], vec![]];
+    let (fs, temp_dirs) = helper::create_overlayfs(layers)?;
+    let lower = fs::OpenOptions::new()
+        .write(true)
+        .open(temp_dirs[0].path().join("sparse"))?;
+    lower.write_all_at(b"head", 0)?;
+    lower.write_all_at(b"middle", 4 << 20)?;
+    lower.set_len(16 << 20)?;
+    fs.init(FsOptions::empty())?;
+
+    // The holes of the lower file stay holes in the copy
+    let ctx = Context::default();
+    let file = fs.lookup(ctx, 1, &CString::new("sparse").unwrap())?;
+    let mut attr = file.attr;
+    attr.st_mode = (attr.st_mode & !0o777) | 0o600;
+    let (attr, _) = fs.setattr(ctx, file.inode, attr, None, SetattrValid::MODE)?;
+    assert_eq!(attr.st_size, 16 << 20);
+    assert!((attr.st_blocks as u64) * 512 < 1 << 20);
+
+    let upper = fs::read(temp_dirs[1].path().join("sparse"))?;
+    assert_eq!(upper.len(), 16 << 20);
+    assert_eq!(&upper[..4], b"head");
+    assert_eq!(&upper[4 << 20..(4 << 20) + 6], b"middle");
+    assert!(upper[(4 << 20) + 6..].iter().all(|b| *b == 0));
+    assert_eq!(
+        fs::metadata(temp_dirs[1].path().join("sparse"))?.blocks(),
+        attr.st_blocks as u64
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_copy_up_large_file() -> io::Result<()> {
+    let layers = vec![vec![("large", false, 0o644)], vec![]];
+    let (fs, temp_dirs) = helper::create_overlayfs(layers)?;
+    let content: Vec<u8> = (0..(3 << 20) + 17).map(|i: u32| (i % 251) as u8).collect();
+    fs::write(temp_dirs[0].path().join("large"), &content)?;
+    fs.init(FsOptions::empty())?;
+
+    // The data is copied in several chunks, the last one short
+    let ctx = Context::default();
+    let file = fs.lookup(ctx, 1, &CString::new("large").unwrap())?;
+    let mut attr = file.attr;
+    attr.st_mode = (attr.st_mode & !0o777) | 0o600;
+    fs.setattr(ctx, file.inode, attr, None, SetattrValid::MODE)?;
+    assert!(fs::read(temp_dirs[1].path().join("large"))? == content);
+
+    Ok(())
+}
+
 #[test]
 fn test_setattr_basic() -> io::Result<()> {
     // Create test layers:
@@ -317,6 +530,135 @@ fn test_setattr_copy_up() -> io::Result<()> {
     Ok(())
 }
 
+#[test]
+#[cfg(target_os = "macos")]
+fn test_setattr_lower_dir_without_copy_up() -> io::Result<()> {
+    // Create test layers:
+    // Lower layer: dir1/sub/
+    // Upper layer: empty
+    let layers = vec![
+        vec![("dir1", true, 0o755), ("dir1/sub", true, 0o755)],
+        vec![],
+    ];
+
+    let (fs, temp_dirs) = helper::create_overlayfs(layers)?;
+    fs.init(FsOptions::empty())?;
+    let ctx = Context::default();
+
+    let dir1_entry = fs.lookup(ctx, 1, &CString::new("dir1").unwrap())?;
+    let sub_name = CString::new("sub").unwrap();
+    let sub_entry = fs.lookup(ctx, dir1_entry.inode, &sub_name)?;
+
+    // Change the owner and permissions of the lower directory
+    let mut attr = sub_entry.attr;
+    attr.st_uid = 1234;
+    attr.st_gid = 5678;
+    attr.st_mode = (attr.st_mode & !0o7777) | 0o700;
+    let valid = SetattrValid::UID | SetattrValid::GID | SetattrValid::MODE;
+    let (new_attr, _) = fs.setattr(ctx, sub_entry.inode, attr, None, valid)?;
+    assert_eq!(new_attr.st_uid, 1234);
+    assert_eq!(new_attr.st_gid, 5678);
+    assert_eq!(new_attr.st_mode & 0o7777, 0o700);
+
+    // Nothing was copied up
+    assert!(!temp_dirs[1].path().join("dir1").exists());
+
+    // The change persists across instances
+    drop(fs);
+    let fs = OverlayFs::new(Config {
+        layers: temp_dirs
+            .iter()
+            .map(|dir| dir.path().to_path_buf())
+            .collect(),
+        ..Default::default()
+    })?;
+    fs.init(FsOptions::empty())?;
+    let dir1_entry = fs.lookup(ctx, 1, &CString::new("dir1").unwrap())?;
+    let sub_entry = fs.lookup(ctx, dir1_entry.inode, &sub_name)?;
+    assert_eq!(sub_entry.attr.st_uid, 1234);
+    assert_eq!(sub_entry.attr.st_gid, 5678);
+    assert_eq!(sub_entry.attr.st_mode & 0o7777, 0o700);
+
+    // Other changes copy the directory up, along with its owner and permissions
+    let (new_attr, _) = fs.setattr(
+        ctx,
+        sub_entry.inode,
+        sub_entry.attr,
+        None,
+        SetattrValid::ATIME | SetattrValid::MTIME,
+    )?;
+    assert!(temp_dirs[1].path().join("dir1/sub").is_dir());
+    assert_eq!(new_attr.st_uid, 1234);
+    assert_eq!(new_attr.st_gid, 5678);
+    assert_eq!(new_attr.st_mode & 0o7777, 0o700);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "macos")]
+fn test_setattr_lower_dir_overrides_log() -> io::Result<()> {
+    // Create test layers:
+    // Lower layer: dir1/sub/, dir1/gone/
+    // Upper layer: empty
+    let layers = vec![
+        vec![
+            ("dir1", true, 0o755),
+            ("dir1/sub", true, 0o755),
+            ("dir1/gone", true, 0o755),
+        ],
+        vec![],
+    ];
+
+    let (fs, temp_dirs) = helper::create_overlayfs(layers)?;
+    fs.init(FsOptions::empty())?;
+    let ctx = Context::default();
+    let log_path = temp_dirs[1].path().join(".wh..wh..diroverrides");
+
+    let dir1_entry = fs.lookup(ctx, 1, &CString::new("dir1").unwrap())?;
+    let sub_name = CString::new("sub").unwrap();
+    let gone_name = CString::new("gone").unwrap();
+    let sub_entry = fs.lookup(ctx, dir1_entry.inode, &sub_name)?;
+    let gone_entry = fs.lookup(ctx, dir1_entry.inode, &gone_name)?;
+
+    // Each change is appended to the log
+    let chmod = |inode, attr: bindings::stat64, mode| {
+        let mut attr = attr;
+        attr.st_mode = (attr.st_mode & !0o7777) | mode;
+        fs.setattr(ctx, inode, attr, None, SetattrValid::MODE)
+    };
+    chmod(sub_entry.inode, sub_entry.attr, 0o700)?;
+    chmod(sub_entry.inode, sub_entry.attr, 0o750)?;
+    chmod(gone_entry.inode, gone_entry.attr, 0o711)?;
+    let len = fs::metadata(&log_path)?.len();
+
+    // Removing a directory drops its override
+    fs.rmdir(ctx, dir1_entry.inode, &gone_name)?;
+    assert!(fs::metadata(&log_path)?.len() > len);
+
+    // The next instance keeps the last override of each directory still there, alone
+    drop(fs);
+    let fs = OverlayFs::new(Config {
+        layers: temp_dirs
+            .iter()
+            .map(|dir| dir.path().to_path_buf())
+            .collect(),
+        ..Default::default()
+    })?;
+    fs.init(FsOptions::empty())?;
+    let uid = sub_entry.attr.st_uid;
+    let gid = sub_entry.attr.st_gid;
+    assert_eq!(
+        fs::read(&log_path)?,
+        format!("dir1/sub\0{uid}:{gid}:750\0").into_bytes()
+    );
+    let dir1_entry = fs.lookup(ctx, 1, &CString::new("dir1").unwrap())?;
+    let sub_entry = fs.lookup(ctx, dir1_entry.inode, &sub_name)?;
+    assert_eq!(sub_entry.attr.st_mode & 0o7777, 0o750);
+
+    Ok(())
+}
+
 #[test]
 fn test_setattr_timestamps() -> io::Result<()> {
     // Create test layers with a single file
@@ -919,3 +1261,234 @@ fn test_xattrs() -> io::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_listxattr_long() -> io::Result<()> {
+    let layers = vec![vec![("file", false, 0o644)], vec![]];
+    let (fs, _temp_dirs) = helper::create_overlayfs_with_config(
+        layers,
+        Config {
+            xattr: true,
+            ..Default::default()
+        },
+    )?;
+    fs.init(FsOptions::empty())?;
+    let ctx = Context::default();
+    let file = fs.lookup(ctx, 1, &CString::new("file").unwrap())?;
+
+    // The names of the attributes are listed in full, however long the list
+    let mut expected: Vec<_> = (0..32)
+        .map(|i| format!("user.test.a_rather_long_attribute_name_{i:02}"))
+        .collect();
+    for name in &expected {
+        fs.setxattr(
+            ctx,
+            file.inode,
+            &CString::new(name.as_str()).unwrap(),
+            b"v",
+            0,
+        )?;
+    }
+    expected.sort();
+
+    let Ok(ListxattrReply::Count(count)) = fs.listxattr(ctx, file.inode, 0) else {
+        panic!("Expected ListxattrReply::Count");
+    };
+    let Ok(ListxattrReply::Names(names)) = fs.listxattr(ctx, file.inode, count) else {
+        panic!("Expected ListxattrReply::Names");
+    };
+    assert_eq!(names.len(), count as usize);
+    assert!(names.len() > 1024);
+    let mut names: Vec<_> = names
+        .split(|b| *b == 0)
+        .filter(|n| !n.is_empty())
+        .map(|n| String::from_utf8(n.to_vec()).unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names, expected);
+    let Err(e) = fs.listxattr(ctx, file.inode, count - 1) else {
+        panic!("Expected ERANGE error");
+    };
+    assert_eq!(e.raw_os_error(), Some(LINUX_ERANGE));
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_overlay_xattrs() -> io::Result<()> {
+    use std::{os::unix::ffi::OsStrExt, path::Path};
+
+    use crate::virtio::fs::overlayfs::OverlayXattrs;
+
+    fn get_xattr(path: &Path, name: &str) -> Option<Vec<u8>> {
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let name = CString::new(name).unwrap();
+        let mut buf = vec![0; 256];
+        let res = unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        };
+        (res >= 0).then(|| buf[..res as usize].to_vec())
+    }
+
+    // Create test layers:
+    // Lower layer: dir1/file1, file2
+    // Upper layer: file3
+    let layers = vec![
+        vec![
+            ("dir1", true, 0o755),
+            ("dir1/file1", false, 0o644),
+            ("file2", false, 0o644),
+        ],
+        vec![("file3", false, 0o644)],
+    ];
+
+    let cfg = Config {
+        overlay_xattrs: Some(OverlayXattrs::User),
+        ..Default::default()
+    };
+    let (fs, temp_dirs) = helper::create_overlayfs_with_config(layers, cfg)?;
+    fs.init(FsOptions::empty())?;
+    let ctx = Context::default();
+    let upper = temp_dirs[1].path();
+
+    // Copy up dir1/file1 by changing its mode
+    let dir1 = fs.lookup(ctx, 1, &CString::new("dir1").unwrap())?;
+    let file1 = fs.lookup(ctx, dir1.inode, &CString::new("file1").unwrap())?;
+    let mut attr = file1.attr;
+    attr.st_mode = libc::S_IFREG | 0o600;
+    fs.setattr(ctx, file1.inode, attr, None, SetattrValid::MODE)?;
+
+    // Both copies record their origin, and their parents that they hold copies
+    for path in ["dir1", "dir1/file1"] {
+        let origin = get_xattr(&upper.join(path), "user.overlay.origin").unwrap();
+        assert_eq!(&origin[..2], &[0, 0xfb]);
+        assert_eq!(origin[2] as usize, origin.len());
+    }
+    let impure = get_xattr(&upper.join("dir1"), "user.overlay.impure");
+    assert_eq!(impure.as_deref(), Some(&b"y"[..]));
+    assert!(get_xattr(upper, "user.overlay.impure").is_some());
+
+    // The files created in the top layer have no origin
+    assert!(get_xattr(&upper.join("file3"), "user.overlay.origin").is_none());
+
+    // The guest can neither see nor change the attributes
+    let origin_name = CString::new("user.overlay.origin").unwrap();
+    for res in [
+        fs.getxattr(ctx, file1.inode, &origin_name, 100).map(|_| ()),
+        fs.setxattr(ctx, file1.inode, &origin_name, b"", 0),
+        fs.removexattr(ctx, file1.inode, &origin_name),
+    ] {
+        assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::EOPNOTSUPP));
+    }
+
+    let test_name = CString::new("user.test").unwrap();
+    fs.setxattr(ctx, file1.inode, &test_name, b"value", 0)?;
+    match fs.listxattr(ctx, file1.inode, 0)? {
+        ListxattrReply::Count(count) => assert_eq!(count, 10),
+        _ => panic!("Expected ListxattrReply::Count"),
+    }
+    match fs.listxattr(ctx, file1.inode, 100)? {
+        ListxattrReply::Names(names) => assert_eq!(names, b"user.test\0"),
+        _ => panic!("Expected ListxattrReply::Names"),
+    }
+    assert_eq!(
+        fs.listxattr(ctx, file1.inode, 5)
+            .err()
+            .unwrap()
+            .raw_os_error(),
+        Some(LINUX_ERANGE)
+    );
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_file_flags() -> io::Result<()> {
+    use std::sync::{atomic::AtomicI32, Arc};
+
+    use crate::virtio::bindings::{
+        LINUX_FS_APPEND_FL, LINUX_FS_IOC_GETFLAGS, LINUX_FS_IOC_SETFLAGS,
+    };
+
+    let ctx = Context::default();
+    let exit_code = Arc::new(AtomicI32::new(0));
+    let getflags = |fs: &OverlayFs, inode| {
+        let out = fs.ioctl(
+            ctx,
+            inode,
+            0,
+            0,
+            LINUX_FS_IOC_GETFLAGS,
+            0,
+            &[],
+            4,
+            &exit_code,
+        )?;
+        Ok::<_, io::Error>(u32::from_ne_bytes(out.try_into().unwrap()))
+    };
+    let setflags = |fs: &OverlayFs, inode, flags: u32| {
+        let data = flags.to_ne_bytes();
+        fs.ioctl(
+            ctx,
+            inode,
+            0,
+            0,
+            LINUX_FS_IOC_SETFLAGS,
+            0,
+            &data,
+            0,
+            &exit_code,
+        )
+    };
+
+    // Create test layers:
+    // Lower layer: file1
+    // Upper layer: empty
+    let layers = vec![vec![("file1", false, 0o644)], vec![]];
+
+    // Setting the flags is refused unless allowed
+    let (fs, _temp_dirs) = helper::create_overlayfs(layers.clone())?;
+    fs.init(FsOptions::empty())?;
+    let entry = fs.lookup(ctx, 1, &CString::new("file1").unwrap())?;
+    let res = setflags(&fs, entry.inode, LINUX_FS_APPEND_FL);
+    assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::EPERM));
+
+    let cfg = Config {
+        allow_file_flags: true,
+        ..Default::default()
+    };
+    let (fs, temp_dirs) = helper::create_overlayfs_with_config(layers, cfg)?;
+    fs.init(FsOptions::empty())?;
+    let entry = fs.lookup(ctx, 1, &CString::new("file1").unwrap())?;
+
+    match getflags(&fs, entry.inode) {
+        Ok(flags) => assert_eq!(flags, 0),
+        // The host file system doesn't support the flags
+        Err(e) if e.raw_os_error() == Some(libc::ENOTTY) => return Ok(()),
+        Err(e) => return Err(e),
+    }
+    match setflags(&fs, entry.inode, LINUX_FS_APPEND_FL) {
+        Ok(_) => {}
+        // Setting the append-only flag requires CAP_LINUX_IMMUTABLE on the host
+        Err(e) if e.raw_os_error() == Some(libc::EPERM) => return Ok(()),
+        Err(e) => return Err(e),
+    }
+
+    // The flag is set on the top layer copy and reported back to the guest
+    assert_eq!(getflags(&fs, entry.inode)?, LINUX_FS_APPEND_FL);
+    let upper_file = temp_dirs[1].path().join("file1");
+    assert!(fs::OpenOptions::new().write(true).open(upper_file).is_err());
+
+    // Clear it so the layers can be removed
+    setflags(&fs, entry.inode, 0)?;
+    assert_eq!(getflags(&fs, entry.inode)?, 0);
+
+    Ok(())
+}
@@ -3,9 +3,13 @@ use std::{ffi::CString, fs, io, os::unix::fs::PermissionsExt, path::PathBuf};
 use tempfile::TempDir;
 
 use crate::virtio::{
-    fs::filesystem::{Context, FileSystem},
+    fs::{
+        filesystem::{Context, Extensions, FileSystem},
+        intent_log::{Intent, IntentLog},
+        FsLayerStats,
+    },
     fuse::FsOptions,
-    overlayfs::{Config, OverlayFs},
+    overlayfs::{Config, DriftPolicy, LayerIntegrity, MetadataSanitizer, OverlayFs},
 };
 
 use super::helper;
@@ -544,3 +548,596 @@ fn test_link_existing_name() -> io::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_export_diff() -> io::Result<()> {
+    // Create test layers:
+    // Lower layer: lower_file, dir/lower_in_dir
+    // Upper layer: upper_file, gone/child, keep/
+    let layers = vec![
+        vec![
+            ("lower_file", false, 0o644),
+            ("dir", true, 0o755),
+            ("dir/lower_in_dir", false, 0o644),
+        ],
+        vec![
+            ("upper_file", false, 0o644),
+            ("gone", true, 0o755),
+            ("gone/child", false, 0o644),
+            ("keep", true, 0o755),
+        ],
+    ];
+
+    let (fs, temp_dirs) = helper::create_overlayfs(layers)?;
+    fs.init(FsOptions::empty())?;
+    let ctx = Context::default();
+
+    // Changes made before the snapshot are not part of the diff
+    let before = CString::new("before").unwrap();
+    fs.create(ctx, 1, &before, 0o644, 0, 0o022, Extensions::default())?;
+
+    let snapshot = fs.snapshot()?;
+
+    let new_file = CString::new("new_file").unwrap();
+    fs.create(ctx, 1, &new_file, 0o644, 0, 0o022, Extensions::default())?;
+    fs.unlink(ctx, 1, &CString::new("upper_file").unwrap())?;
+    fs.unlink(ctx, 1, &CString::new("lower_file").unwrap())?;
+
+    let gone_name = CString::new("gone").unwrap();
+    let gone_entry = fs.lookup(ctx, 1, &gone_name)?;
+    fs.unlink(ctx, gone_entry.inode, &CString::new("child").unwrap())?;
+    fs.rmdir(ctx, 1, &gone_name)?;
+
+    let keep_entry = fs.lookup(ctx, 1, &CString::new("keep").unwrap())?;
+    let sub_name = CString::new("sub").unwrap();
+    fs.mkdir(
+        ctx,
+        keep_entry.inode,
+        &sub_name,
+        0o755,
+        0,
+        Extensions::default(),
+    )?;
+
+    let mut tar = Vec::new();
+    fs.export_diff(snapshot, &mut tar)?;
+
+    // Collect the names of the archive entries
+    let mut names = Vec::new();
+    let mut offset = 0;
+    while tar[offset..offset + 512].iter().any(|b| *b != 0) {
+        let header = &tar[offset..offset + 512];
+        let name = &header[..100];
+        let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(100)];
+        let size = std::str::from_utf8(&header[124..135]).unwrap();
+        let size = u64::from_str_radix(size, 8).unwrap() as usize;
+
+        names.push(String::from_utf8(name.to_vec()).unwrap());
+        offset += 512 + size.div_ceil(512) * 512;
+    }
+
+    // The overlay creates whiteouts for lower layer entries itself, the others are added by the
+    // export
+    names.sort();
+    assert_eq!(
+        names,
+        vec![
+            ".wh.gone",
+            ".wh.lower_file",
+            ".wh.upper_file",
+            "keep/",
+            "keep/sub/",
+            "new_file",
+        ]
+    );
+
+    // Unknown snapshots are rejected
+    assert!(fs.export_diff(snapshot + 1, io::sink()).is_err());
+
+    // The upper layer is left untouched
+    assert!(temp_dirs[1].path().join(".wh.lower_file").exists());
+    assert!(!temp_dirs[1].path().join(".wh.upper_file").exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_export_diff_long_path() -> io::Result<()> {
+    // Lower layer: (empty)
+    // Upper layer: (empty)
+    let (fs, _temp_dirs) = helper::create_overlayfs(vec![vec![], vec![]])?;
+    fs.init(FsOptions::empty())?;
+    let ctx = Context::default();
+    let snapshot = fs.snapshot()?;
+
+    // A path too long for the prefix and name fields of a ustar header
+    let dir_name = CString::new("d".repeat(100)).unwrap();
+    let mut parent = 1;
+    let mut path = String::new();
+    for _ in 0..3 {
+        parent = fs
+            .mkdir(ctx, parent, &dir_name, 0o755, 0, Extensions::default())?
+            .inode;
+        path.push_str(dir_name.to_str().unwrap());
+        path.push('/');
+    }
+    let name = CString::new("f").unwrap();
+    fs.create(ctx, parent, &name, 0o644, 0, 0o022, Extensions::default())?;
+    path.push('f');
+    assert!(path.len() > 256);
+
+    let mut tar = Vec::new();
+    fs.export_diff(snapshot, &mut tar)?;
+
+    // The path is given by a pax record instead
+    let record = format!("path={path}\n");
+    assert!(tar.windows(record.len()).any(|w| w == record.as_bytes()));
+
+    Ok(())
+}
+
+#[test]
+fn test_export_diff_sanitized() -> io::Result<()> {
+    // Lower layer: (empty)
+    // Upper layer: (empty)
+    let cfg = Config {
+        metadata_sanitizer: Some(MetadataSanitizer {
+            keep_xattrs: vec!["user.keep.".into(), "com.apple.ResourceFork".into()],
+        }),
+        ..Default::default()
+    };
+    let (fs, temp_dirs) = helper::create_overlayfs_with_config(vec![vec![], vec![]], cfg)?;
+    fs.init(FsOptions::empty())?;
+    let ctx = Context::default();
+    let snapshot = fs.snapshot()?;
+
+    let name = CString::new("f").unwrap();
+    let (entry, _, _) = fs.create(ctx, 1, &name, 0o644, 0, 0o022, Extensions::default())?;
+    let keep = CString::new("user.keep.a").unwrap();
+    fs.setxattr(ctx, entry.inode, &keep, b"1", 0)?;
+    let drop = CString::new("user.drop").unwrap();
+    fs.setxattr(ctx, entry.inode, &drop, b"2", 0)?;
+
+    // An AppleDouble file holding a resource fork, for `f` and for a file that doesn't exist
+    let mut apple_double = vec![0, 5, 0x16, 7, 0, 2, 0, 0];
+    apple_double.extend_from_slice(&[0; 16]);
+    apple_double.extend_from_slice(&[0, 1, 0, 0, 0, 2, 0, 0, 0, 38, 0, 0, 0, 4]);
+    apple_double.extend_from_slice(b"rsrc");
+    fs::write(temp_dirs[1].path().join("._f"), &apple_double)?;
+    fs::write(temp_dirs[1].path().join("._gone"), &apple_double)?;
+
+    let mut tar = Vec::new();
+    fs.export_diff(snapshot, &mut tar)?;
+
+    // Collect the names of the archive entries and the pax records
+    let mut names = Vec::new();
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while tar[offset..offset + 512].iter().any(|b| *b != 0) {
+        let header = &tar[offset..offset + 512];
+        let name = &header[..100];
+        let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(100)];
+        let size = std::str::from_utf8(&header[124..135]).unwrap();
+        let size = u64::from_str_radix(size, 8).unwrap() as usize;
+
+        if header[156] == b'x' {
+            records.extend_from_slice(&tar[offset + 512..offset + 512 + size]);
+        } else {
+            names.push(String::from_utf8(name.to_vec()).unwrap());
+        }
+        offset += 512 + size.div_ceil(512) * 512;
+    }
+
+    // The AppleDouble files are folded into the attributes of `f`, or dropped
+    assert_eq!(names, vec!["f"]);
+    let has_record = |record: &[u8]| records.windows(record.len()).any(|w| w == record);
+    assert!(has_record(b"SCHILY.xattr.user.keep.a=1\n"));
+    assert!(has_record(b"SCHILY.xattr.com.apple.ResourceFork=rsrc\n"));
+    assert!(!has_record(b"user.drop"));
+
+    Ok(())
+}
+
+#[test]
+fn test_inode_path() -> io::Result<()> {
+    // Layer 0 (bottom):
+    //   - dir1/
+    //   - dir1/file1
+    // Layer 1 (top):
+    //   (empty)
+    let layers = vec![
+        vec![("dir1", true, 0o755), ("dir1/file1", false, 0o644)],
+        vec![],
+    ];
+
+    let (fs, _temp_dirs) = helper::create_overlayfs(layers)?;
+    let ctx = Context::default();
+
+    let dir1_entry = fs.lookup(ctx, 1, &CString::new("dir1").unwrap())?;
+    let file1_entry = fs.lookup(ctx, dir1_entry.inode, &CString::new("file1").unwrap())?;
+
+    // Paths are relative to the root of the share, whichever layer the entry is in
+    assert_eq!(fs.inode_path(1), Some(PathBuf::new()));
+    assert_eq!(fs.inode_path(dir1_entry.inode), Some(PathBuf::from("dir1")));
+    assert_eq!(
+        fs.inode_path(file1_entry.inode),
+        Some(PathBuf::from("dir1/file1"))
+    );
+    assert_eq!(fs.inode_path(u64::MAX), None);
+
+    Ok(())
+}
+
+#[test]
+fn test_layer_stats() -> io::Result<()> {
+    // Layer 0 (bottom):
+    //   - dir1/
+    //   - dir1/file1
+    // Layer 1 (middle):
+    //   - file2
+    // Layer 2 (top):
+    //   (empty)
+    let layers = vec![
+        vec![("dir1", true, 0o755), ("dir1/file1", false, 0o644)],
+        vec![("file2", false, 0o644)],
+        vec![],
+    ];
+
+    let (fs, temp_dirs) = helper::create_overlayfs(layers)?;
+    fs::write(temp_dirs[0].path().join("dir1/file1"), b"hello")?;
+    fs.init(FsOptions::empty())?;
+    let ctx = Context::default();
+
+    // Each layer only holds its root so far
+    let stats = fs.stats();
+    assert_eq!(stats.len(), 3);
+    assert!(stats.iter().all(|layer| *layer
+        == FsLayerStats {
+            inodes: 1,
+            ..Default::default()
+        }));
+
+    let dir1_entry = fs.lookup(ctx, 1, &CString::new("dir1").unwrap())?;
+    let file1_entry = fs.lookup(ctx, dir1_entry.inode, &CString::new("file1").unwrap())?;
+    let file2_entry = fs.lookup(ctx, 1, &CString::new("file2").unwrap())?;
+
+    // Opening a file for writing copies it up along with its parent
+    let (handle, _) = fs.open(ctx, file1_entry.inode, libc::O_RDWR as u32)?;
+    let stats = fs.stats();
+    assert_eq!(
+        (stats[0].lookups, stats[1].lookups, stats[2].lookups),
+        (2, 1, 0)
+    );
+    assert_eq!((stats[0].copy_ups, stats[0].bytes_copied_up), (2, 5));
+    assert_eq!((stats[0].inodes, stats[2].inodes), (1, 3));
+    assert_eq!(stats[2].open_handles, 1);
+
+    // Removing a lower file whites it out
+    fs.unlink(ctx, 1, &CString::new("file2").unwrap())?;
+    assert_eq!(fs.stats()[1].whiteouts, 1);
+    assert_eq!(fs.stats()[1].copy_ups, 0);
+
+    fs.release(
+        ctx,
+        file1_entry.inode,
+        0,
+        handle.unwrap(),
+        false,
+        false,
+        None,
+    )?;
+    assert_eq!(fs.stats()[2].open_handles, 0);
+    fs.forget(ctx, file2_entry.inode, 1);
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_layer_stats_export() -> io::Result<()> {
+    use std::{
+        collections::BTreeMap,
+        io::{Read, Seek},
+        sync::{atomic::AtomicI32, Arc, Mutex},
+    };
+
+    use nix::request_code_read;
+
+    const LAYER_STATS_REQ: u32 = request_code_read!(b'v', 6, 16) as u32;
+
+    let export_table = Arc::new(Mutex::new(BTreeMap::new()));
+    let cfg = Config {
+        export_fsid: 7,
+        export_table: Some(export_table.clone()),
+        ..Default::default()
+    };
+    let layers = vec![vec![("file1", false, 0o644)], vec![]];
+    let (fs, _temp_dirs) = helper::create_overlayfs_with_config(layers, cfg)?;
+    fs.init(FsOptions::empty())?;
+    let ctx = Context::default();
+    let exit_code = Arc::new(AtomicI32::new(0));
+
+    let file1_entry = fs.lookup(ctx, 1, &CString::new("file1").unwrap())?;
+    let (handle, _) = fs.open(ctx, file1_entry.inode, libc::O_RDONLY as u32)?;
+    let handle = handle.unwrap();
+    let layer_stats = || {
+        fs.ioctl(
+            ctx,
+            file1_entry.inode,
+            handle,
+            0,
+            LAYER_STATS_REQ,
+            0,
+            &[],
+            16,
+            &exit_code,
+        )
+    };
+
+    // The snapshot is exported under the id of the share and the handle
+    let out = layer_stats()?;
+    assert_eq!(out[..8], 7u64.to_ne_bytes());
+    assert_eq!(out[8..], handle.to_ne_bytes());
+    let read_counts = || -> io::Result<Vec<u64>> {
+        let mut exports = export_table.lock().unwrap();
+        let file = exports.get_mut(&(7, handle)).unwrap();
+        let mut data = Vec::new();
+        file.rewind()?;
+        file.read_to_end(&mut data)?;
+        Ok(data
+            .chunks(8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+            .collect())
+    };
+    // Six counts per layer, the lookups first and the open handles last
+    let total = |counts: &[u64], idx: usize| counts[idx] + counts[6 + idx];
+    let counts = read_counts()?;
+    assert_eq!(counts.len(), 2 * 6);
+    assert_eq!((counts[0], total(&counts, 5)), (1, 1));
+
+    // Issuing it again replaces the snapshot with the current statistics
+    fs.lookup(ctx, 1, &CString::new("file1").unwrap())?;
+    layer_stats()?;
+    assert_eq!(total(&read_counts()?, 0), 2);
+
+    // Releasing the handle removes the export
+    fs.release(ctx, file1_entry.inode, 0, handle, false, false, None)?;
+    assert!(export_table.lock().unwrap().is_empty());
+
+    // Without an export table, the ioctl isn't supported
+    let (fs, _temp_dirs) = helper::create_overlayfs(vec![vec![]])?;
+    fs.init(FsOptions::empty())?;
+    let (handle, _) = fs.opendir(ctx, 1, libc::O_RDONLY as u32)?;
+    let err = fs
+        .ioctl(
+            ctx,
+            1,
+            handle.unwrap(),
+            0,
+            LAYER_STATS_REQ,
+            0,
+            &[],
+            16,
+            &exit_code,
+        )
+        .unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EOPNOTSUPP));
+
+    Ok(())
+}
+
+#[test]
+fn test_layer_integrity() -> io::Result<()> {
+    // Layer 0 (bottom):
+    //   - etc/
+    //   - etc/hosts
+    // Layer 1 (top):
+    //   (empty)
+    let layers = vec![
+        vec![("etc", true, 0o755), ("etc/hosts", false, 0o644)],
+        vec![],
+    ];
+    let manifest_dir = TempDir::new()?;
+    let integrity = LayerIntegrity {
+        manifest_dir: manifest_dir.path().to_path_buf(),
+        on_drift: DriftPolicy::Refuse,
+    };
+    let cfg = Config {
+        layer_integrity: Some(integrity.clone()),
+        ..Default::default()
+    };
+
+    // The first mount records the manifest of the lower layer
+    let (fs, temp_dirs) = helper::create_overlayfs_with_config(layers, cfg.clone())?;
+    drop(fs);
+    assert_eq!(fs::read_dir(manifest_dir.path())?.count(), 1);
+    let cfg = Config {
+        layers: temp_dirs
+            .iter()
+            .map(|dir| dir.path().to_path_buf())
+            .collect(),
+        ..cfg
+    };
+
+    // The top layer may change
+    fs::write(temp_dirs[1].path().join("new"), b"")?;
+    OverlayFs::new(cfg.clone())?;
+
+    // But not the lower ones
+    fs::write(
+        temp_dirs[0].path().join("etc/hosts"),
+        b"127.0.0.1 localhost\n",
+    )?;
+    let err = OverlayFs::new(cfg.clone()).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("etc/hosts (modified)"));
+
+    // Unless drift is only warned about
+    let cfg = Config {
+        layer_integrity: Some(LayerIntegrity {
+            on_drift: DriftPolicy::Warn,
+            ..integrity
+        }),
+        ..cfg
+    };
+    OverlayFs::new(cfg)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_intent_log() -> io::Result<()> {
+    // Lower layer:
+    //   - truncated
+    //   - removed
+    // Upper layer: (empty)
+    let layers = vec![
+        vec![("truncated", false, 0o644), ("removed", false, 0o644)],
+        vec![],
+    ];
+    let cfg = Config {
+        intent_log: true,
+        ..Default::default()
+    };
+    let (fs, temp_dirs) = helper::create_overlayfs_with_config(layers, cfg.clone())?;
+    fs.init(FsOptions::empty())?;
+    let ctx = Context::default();
+    let lower = temp_dirs[0].path();
+    let upper = temp_dirs[1].path();
+    fs::write(lower.join("truncated"), b"content")?;
+
+    // An operation that completes leaves nothing to replay
+    let name = CString::new("truncated").unwrap();
+    let entry = fs.lookup(ctx, 1, &name)?;
+    fs.open(ctx, entry.inode, (libc::O_RDWR | libc::O_TRUNC) as u32)?;
+    assert_eq!(fs::read(upper.join("truncated"))?, b"");
+    drop(fs);
+
+    // An unlink interrupted after the entry left the top layer is rolled forward
+    let cfg = Config {
+        layers: vec![lower.to_path_buf(), upper.to_path_buf()],
+        ..cfg
+    };
+    let log = IntentLog::open(upper, &cfg.layers[..1], |path| {
+        [b".wh.".as_slice(), path].concat()
+    })?;
+    std::mem::forget(log.begin(&Intent::Whiteout(b"removed".to_vec()))?);
+    drop(log);
+
+    let fs = OverlayFs::new(cfg)?;
+    fs.init(FsOptions::empty())?;
+    let name = CString::new("removed").unwrap();
+    let err = fs.lookup(ctx, 1, &name).err().unwrap();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+    assert!(upper.join(".wh.removed").exists());
+    assert_eq!(fs::read(upper.join("truncated"))?, b"");
+
+    // The log itself is hidden from the guest
+    let name = CString::new(".wh..wh..intents").unwrap();
+    assert!(fs.lookup(ctx, 1, &name).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_inode_map() -> io::Result<()> {
+    // Lower layer:
+    //   - lower_file
+    //   - copied_file
+    // Upper layer:
+    //   - upper_file
+    let layers = vec![
+        vec![("lower_file", false, 0o644), ("copied_file", false, 0o644)],
+        vec![("upper_file", false, 0o644)],
+    ];
+    let cfg = Config {
+        inode_map: true,
+        ..Default::default()
+    };
+    let (fs, temp_dirs) = helper::create_overlayfs_with_config(layers, cfg.clone())?;
+    fs.init(FsOptions::empty())?;
+    let ctx = Context::default();
+    let lower = temp_dirs[0].path();
+    let upper = temp_dirs[1].path();
+
+    let names = ["lower_file", "copied_file", "upper_file"].map(|name| CString::new(name).unwrap());
+    let mut inodes = Vec::new();
+    for name in &names {
+        inodes.push(fs.lookup(ctx, 1, name)?.inode);
+    }
+
+    // A file copied up keeps its number
+    fs.open(ctx, inodes[1], libc::O_RDWR as u32)?;
+    assert!(upper.join("copied_file").exists());
+    drop(fs);
+
+    // The overlay created again gives the files the same numbers, whatever the lookup order
+    let cfg = Config {
+        layers: vec![lower.to_path_buf(), upper.to_path_buf()],
+        ..cfg
+    };
+    let fs = OverlayFs::new(cfg)?;
+    fs.init(FsOptions::empty())?;
+    for (name, inode) in names.iter().zip(&inodes).rev() {
+        assert_eq!(fs.lookup(ctx, 1, name)?.inode, *inode);
+    }
+
+    // A new file is given a number none of the others has
+    let name = CString::new("new_file").unwrap();
+    let (entry, _, _) = fs.create(
+        ctx,
+        1,
+        &name,
+        0o644,
+        libc::O_RDWR as u32,
+        0,
+        Extensions::default(),
+    )?;
+    assert!(!inodes.contains(&entry.inode));
+
+    // The map itself is hidden from the guest
+    let name = CString::new(".wh..wh..inodes").unwrap();
+    assert!(fs.lookup(ctx, 1, &name).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_inode_map_leftover_tmp() -> io::Result<()> {
+    let cfg = Config {
+        inode_map: true,
+        ..Default::default()
+    };
+    let (fs, temp_dirs) = helper::create_overlayfs_with_config(vec![vec![], vec![]], cfg)?;
+    fs.init(FsOptions::empty())?;
+    let ctx = Context::default();
+
+    // The new map of a rewrite cut short, left in the top layer
+    let snapshot = fs.snapshot()?;
+    let tmp_name = ".wh..wh..inodes.tmp";
+    fs::write(temp_dirs[1].path().join(tmp_name), b"stale")?;
+    let new_file = CString::new("new_file").unwrap();
+    fs.create(ctx, 1, &new_file, 0o644, 0, 0o022, Extensions::default())?;
+
+    // It is hidden from the guest
+    assert!(fs.lookup(ctx, 1, &CString::new(tmp_name).unwrap()).is_err());
+    let (handle, _) = fs.opendir(ctx, 1, libc::O_RDONLY as u32)?;
+    let handle = handle.unwrap();
+    let mut names = Vec::new();
+    fs.readdir(ctx, 1, handle, 65536, 0, |entry| {
+        names.push(entry.name.to_vec());
+        Ok(1)
+    })?;
+    fs.releasedir(ctx, 1, 0, handle)?;
+    assert!(names.contains(&b"new_file".to_vec()));
+    assert!(!names.iter().any(|name| name.starts_with(b".wh.")));
+
+    // And left out of the exported changes
+    let mut tar = Vec::new();
+    fs.export_diff(snapshot, &mut tar)?;
+    let contains = |name: &[u8]| tar.windows(name.len()).any(|window| window == name);
+    assert!(contains(b"new_file"));
+    assert!(!contains(tmp_name.as_bytes()));
+
+    Ok(())
+}
@@ -1,6 +1,9 @@
 #[cfg(test)]
 mod create;
 
+#[cfg(test)]
+mod golden;
+
 #[cfg(test)]
 mod lookup;
 
@@ -10,6 +13,10 @@ mod metadata;
 #[cfg(test)]
 mod misc;
 
+// APFS refuses the names that aren't valid UTF-8
+#[cfg(all(test, target_os = "linux"))]
+mod names;
+
 #[cfg(test)]
 mod open;
 
@@ -19,6 +26,9 @@ mod read;
 #[cfg(test)]
 mod remove;
 
+#[cfg(test)]
+mod stress;
+
 #[cfg(test)]
 mod write;
 
@@ -159,6 +169,14 @@ mod helper {
     // Helper function to create an overlayfs with specified layers
     pub(super) fn create_overlayfs(
         layers: Vec<Vec<(&str, bool, u32)>>,
+    ) -> io::Result<(OverlayFs, Vec<TempDir>)> {
+        create_overlayfs_with_config(layers, Config::default())
+    }
+
+    // Helper function to create an overlayfs with specified layers and configuration
+    pub(super) fn create_overlayfs_with_config(
+        layers: Vec<Vec<(&str, bool, u32)>>,
+        cfg: Config,
     ) -> io::Result<(OverlayFs, Vec<TempDir>)> {
         let mut temp_dirs = Vec::new();
         let mut layer_paths = Vec::new();
@@ -171,7 +189,7 @@ mod helper {
 
         let cfg = Config {
             layers: layer_paths,
-            ..Default::default()
+            ..cfg
         };
 
         let overlayfs = OverlayFs::new(cfg)?;
@@ -0,0 +1,157 @@
+//! Names that aren't valid UTF-8, which Linux guests may use for any entry.
+
+use std::{
+    collections::BTreeSet,
+    ffi::{CString, OsStr},
+    fs, io,
+    os::unix::ffi::OsStrExt,
+};
+
+use crate::virtio::{
+    fs::filesystem::{Context, Extensions, FileSystem},
+    fs::overlayfs::OverlayFs,
+    fuse::FsOptions,
+};
+
+use super::helper;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// A Latin-1 encoded "café".
+const LOWER_NAME: &[u8] = b"caf\xe9";
+
+/// A lone continuation byte and an overlong encoding of "/".
+const UPPER_NAME: &[u8] = b"\x80-\xc0\xaf";
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+fn name(name: &[u8]) -> CString {
+    CString::new(name).unwrap()
+}
+
+/// Returns the names of the entries of the directory `inode`.
+fn readdir(fs: &OverlayFs, inode: u64) -> io::Result<BTreeSet<Vec<u8>>> {
+    let ctx = Context::default();
+    let (handle, _) = fs.opendir(ctx, inode, libc::O_RDONLY as u32)?;
+    let handle = handle.unwrap();
+    let mut names = BTreeSet::new();
+    let res = fs.readdir(ctx, inode, handle, 65536, 0, |entry| {
+        names.insert(entry.name.to_vec());
+        Ok(1)
+    });
+    fs.releasedir(ctx, inode, 0, handle)?;
+    res.map(|_| names)
+}
+
+/// Creates an overlay whose lower layer holds `dir/café`, in Latin-1, returning the inode of
+/// `dir`.
+fn create_overlayfs() -> io::Result<(OverlayFs, Vec<tempfile::TempDir>, u64)> {
+    let (fs, temp_dirs) =
+        helper::create_overlayfs(vec![vec![("dir", true, 0o755)], vec![("dir", true, 0o755)]])?;
+    fs::write(
+        temp_dirs[0]
+            .path()
+            .join("dir")
+            .join(OsStr::from_bytes(LOWER_NAME)),
+        b"lower",
+    )?;
+    fs.init(FsOptions::empty())?;
+    let dir = fs.lookup(Context::default(), 1, &name(b"dir"))?.inode;
+    Ok((fs, temp_dirs, dir))
+}
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_non_utf8_lookup_and_readdir() -> io::Result<()> {
+    let (fs, temp_dirs, dir) = create_overlayfs()?;
+    let ctx = Context::default();
+
+    let entry = fs.lookup(ctx, dir, &name(LOWER_NAME))?;
+    assert_eq!(entry.attr.st_size, 5);
+
+    let (created, handle, _) = fs.create(
+        ctx,
+        dir,
+        &name(UPPER_NAME),
+        0o644,
+        libc::O_RDWR as u32,
+        0,
+        Extensions::default(),
+    )?;
+    fs.release(ctx, created.inode, 0, handle.unwrap(), false, false, None)?;
+    assert!(temp_dirs[1]
+        .path()
+        .join("dir")
+        .join(OsStr::from_bytes(UPPER_NAME))
+        .exists());
+
+    // The names are listed byte for byte
+    assert_eq!(
+        readdir(&fs, dir)?,
+        BTreeSet::from([LOWER_NAME.to_vec(), UPPER_NAME.to_vec()])
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_non_utf8_unlink() -> io::Result<()> {
+    let (fs, temp_dirs, dir) = create_overlayfs()?;
+    let ctx = Context::default();
+
+    // The whiteout hiding the lower entry keeps its name
+    fs.unlink(ctx, dir, &name(LOWER_NAME))?;
+    let mut whiteout = b".wh.".to_vec();
+    whiteout.extend_from_slice(LOWER_NAME);
+    assert!(temp_dirs[1]
+        .path()
+        .join("dir")
+        .join(OsStr::from_bytes(&whiteout))
+        .exists());
+
+    assert_eq!(
+        fs.lookup(ctx, dir, &name(LOWER_NAME))
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ENOENT)
+    );
+    assert!(readdir(&fs, dir)?.is_empty());
+
+    // And the entry can be created again over it
+    fs.mkdir(ctx, dir, &name(LOWER_NAME), 0o755, 0, Extensions::default())?;
+    assert_eq!(readdir(&fs, dir)?, BTreeSet::from([LOWER_NAME.to_vec()]));
+
+    Ok(())
+}
+
+#[test]
+fn test_non_utf8_rename() -> io::Result<()> {
+    let (fs, temp_dirs, dir) = create_overlayfs()?;
+    let ctx = Context::default();
+
+    fs.rename(ctx, dir, &name(LOWER_NAME), 1, &name(UPPER_NAME), 0)?;
+
+    assert_eq!(
+        fs::read(temp_dirs[1].path().join(OsStr::from_bytes(UPPER_NAME)))?,
+        b"lower"
+    );
+    assert!(fs.lookup(ctx, dir, &name(LOWER_NAME)).is_err());
+    assert!(readdir(&fs, dir)?.is_empty());
+    assert!(readdir(&fs, 1)?.contains(UPPER_NAME));
+
+    // Names with the whiteout prefix are refused, whatever follows it
+    let mut whiteout = b".wh.".to_vec();
+    whiteout.extend_from_slice(UPPER_NAME);
+    assert!(fs
+        .rename(ctx, 1, &name(UPPER_NAME), 1, &name(&whiteout), 0)
+        .is_err());
+
+    Ok(())
+}
@@ -1,6 +1,9 @@
 use std::{ffi::CString, io};
 
+use utils::fd_budget::{FdBudget, FdPriority};
+
 use crate::virtio::fs::filesystem::{Context, Extensions, FileSystem};
+use crate::virtio::fs::overlayfs::Config;
 
 use super::helper;
 
@@ -376,3 +379,50 @@ fn test_opendir_with_different_flags() -> io::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_open_fd_budget() -> io::Result<()> {
+    let layers = vec![vec![("file1", false, 0o644)]];
+    let budget = FdBudget::new(2);
+    let cfg = Config {
+        fd_client: Some(budget.register("overlay", FdPriority::High)),
+        ..Default::default()
+    };
+
+    let (fs, _temp_dirs) = helper::create_overlayfs_with_config(layers, cfg)?;
+    let ctx = Context::default();
+    let entry = fs.lookup(ctx, 1, &CString::new("file1").unwrap())?;
+
+    // Every open handle holds a descriptor of the budget
+    let (first, _) = fs.open(ctx, entry.inode, libc::O_RDONLY as u32)?;
+    let (second, _) = fs.open(ctx, entry.inode, libc::O_RDONLY as u32)?;
+    assert_eq!(budget.usage().in_use, 2);
+
+    let e = fs
+        .open(ctx, entry.inode, libc::O_RDONLY as u32)
+        .unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::EMFILE));
+    let e = fs
+        .create(
+            ctx,
+            1,
+            &CString::new("file2").unwrap(),
+            0o644,
+            libc::O_RDWR as u32,
+            0o022,
+            Extensions::default(),
+        )
+        .unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::EMFILE));
+
+    // Releasing a handle gives its descriptor back
+    fs.release(ctx, entry.inode, 0, first.unwrap(), false, false, None)?;
+    assert_eq!(budget.usage().in_use, 1);
+    let (third, _) = fs.open(ctx, entry.inode, libc::O_RDONLY as u32)?;
+
+    fs.release(ctx, entry.inode, 0, second.unwrap(), false, false, None)?;
+    fs.release(ctx, entry.inode, 0, third.unwrap(), false, false, None)?;
+    assert_eq!(budget.usage().in_use, 0);
+
+    Ok(())
+}
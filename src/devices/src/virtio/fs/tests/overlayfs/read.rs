@@ -1,7 +1,8 @@
-use std::{ffi::CString, fs, io};
+use std::{ffi::CString, fs, io, os::unix::fs::PermissionsExt};
 
 use crate::virtio::{
-    fs::filesystem::{Context, FileSystem},
+    fs::filesystem::{Context, Extensions, FileSystem},
+    fs::overlayfs::{Config, SymlinkPolicy},
     fuse::FsOptions,
     overlayfs::tests::helper::TestContainer,
 };
@@ -160,6 +161,273 @@ fn test_readlink_nested() -> io::Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_readlink_escaping_targets() -> io::Result<()> {
+    // Create test layers:
+    // Lower layer:
+    //   - dir/abs -> /etc/passwd
+    //   - dir/up -> ../../../../etc/shadow
+    //   - dir/chain -> up
+    //   - dir/detour -> x/../../..
+    //   - dir/sibling -> ../other
+    //   - dir/sub -> .
+    //   - dir/through -> sub/../..
+    let layers = vec![vec![("dir", true, 0o755), ("other", false, 0o644)]];
+
+    for policy in [
+        SymlinkPolicy::GuestRelative,
+        SymlinkPolicy::Deny,
+        SymlinkPolicy::FollowWithinLayer,
+    ] {
+        let cfg = Config {
+            symlink_policy: policy,
+            ..Default::default()
+        };
+        let (fs, temp_dirs) = helper::create_overlayfs_with_config(layers.clone(), cfg)?;
+        let dir = temp_dirs[0].path().join("dir");
+        std::os::unix::fs::symlink("/etc/passwd", dir.join("abs"))?;
+        std::os::unix::fs::symlink("../../../../etc/shadow", dir.join("up"))?;
+        std::os::unix::fs::symlink("up", dir.join("chain"))?;
+        std::os::unix::fs::symlink("x/../../..", dir.join("detour"))?;
+        std::os::unix::fs::symlink("../other", dir.join("sibling"))?;
+        std::os::unix::fs::symlink(".", dir.join("sub"))?;
+        std::os::unix::fs::symlink("sub/../..", dir.join("through"))?;
+
+        // Initialize filesystem
+        fs.init(FsOptions::empty())?;
+
+        let ctx = Context::default();
+        let dir_entry = fs.lookup(ctx, 1, &CString::new("dir").unwrap())?;
+        let readlink = |name: &str| {
+            let entry = fs.lookup(ctx, dir_entry.inode, &CString::new(name).unwrap())?;
+            fs.readlink(ctx, entry.inode)
+        };
+
+        // Links that stay within the overlay are never touched
+        assert_eq!(readlink("chain")?, b"up");
+        assert_eq!(readlink("sibling")?, b"../other");
+
+        match policy {
+            SymlinkPolicy::GuestRelative => {
+                assert_eq!(readlink("abs")?, b"/etc/passwd");
+                assert_eq!(readlink("up")?, b"../../../../etc/shadow");
+                assert_eq!(readlink("detour")?, b"x/../../..");
+                assert_eq!(readlink("through")?, b"sub/../..");
+            }
+            SymlinkPolicy::Deny => {
+                for name in ["abs", "up", "detour", "through"] {
+                    let err = readlink(name).unwrap_err();
+                    assert_eq!(err.raw_os_error(), Some(libc::EPERM), "{name}");
+                }
+            }
+            SymlinkPolicy::FollowWithinLayer => {
+                assert_eq!(readlink("abs")?, b"../etc/passwd");
+                assert_eq!(readlink("up")?, b"../etc/shadow");
+                assert_eq!(readlink("detour")?, b"..");
+                assert_eq!(readlink("through")?, b"..");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_copy_up_symlink_does_not_touch_target() -> io::Result<()> {
+    // Create test layers:
+    // Lower layer: link -> <absolute path of a host file outside of the overlay>
+    // Upper layer: empty
+    let layers = vec![vec![], vec![]];
+
+    let (fs, temp_dirs) = helper::create_overlayfs(layers)?;
+
+    let host_dir = tempfile::tempdir()?;
+    let host_file = host_dir.path().join("secret");
+    fs::write(&host_file, "secret")?;
+    fs::set_permissions(&host_file, fs::Permissions::from_mode(0o600))?;
+    std::os::unix::fs::symlink(&host_file, temp_dirs[0].path().join("link"))?;
+
+    // Initialize filesystem
+    fs.init(FsOptions::empty())?;
+
+    // Renaming the link copies it up to the top layer
+    let ctx = Context::default();
+    let link_name = CString::new("link").unwrap();
+    let new_name = CString::new("moved").unwrap();
+    fs.lookup(ctx, 1, &link_name)?;
+    fs.rename(ctx, 1, &link_name, 1, &new_name, 0)?;
+
+    // The link was recreated as is and its host target was left alone
+    let moved = temp_dirs[1].path().join("moved");
+    assert_eq!(fs::read_link(&moved)?, host_file);
+    let mode = fs::metadata(&host_file)?.permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    Ok(())
+}
+
+#[test]
+fn test_symlink_loops() -> io::Result<()> {
+    // Create test layers:
+    // Lower layer:
+    //   - self -> self
+    //   - a -> b
+    //   - b -> a
+    //   - dir/
+    //     - up -> ../dir/up
+    // Upper layer: empty
+    let layers = vec![vec![("dir", true, 0o755)], vec![]];
+    let cfg = Config {
+        symlink_policy: SymlinkPolicy::FollowWithinLayer,
+        ..Default::default()
+    };
+
+    let (fs, temp_dirs) = helper::create_overlayfs_with_config(layers, cfg)?;
+    let lower = temp_dirs[0].path();
+    std::os::unix::fs::symlink("self", lower.join("self"))?;
+    std::os::unix::fs::symlink("b", lower.join("a"))?;
+    std::os::unix::fs::symlink("a", lower.join("b"))?;
+    std::os::unix::fs::symlink("../dir/up", lower.join("dir/up"))?;
+
+    // Initialize filesystem
+    fs.init(FsOptions::empty())?;
+
+    // The loops are served as links, for the guest to resolve
+    let ctx = Context::default();
+    for (name, target) in [("self", "self"), ("a", "b"), ("b", "a")] {
+        let entry = fs.lookup(ctx, 1, &CString::new(name).unwrap())?;
+        assert_eq!(entry.attr.st_mode & libc::S_IFMT, libc::S_IFLNK);
+        assert_eq!(fs.readlink(ctx, entry.inode)?, target.as_bytes());
+    }
+    let dir = fs.lookup(ctx, 1, &CString::new("dir").unwrap())?;
+    let up_name = CString::new("up").unwrap();
+    let up = fs.lookup(ctx, dir.inode, &up_name)?;
+    assert_eq!(fs.readlink(ctx, up.inode)?, b"../dir/up");
+
+    // Copying them up recreates them as they are
+    let a_name = CString::new("a").unwrap();
+    let moved_name = CString::new("moved").unwrap();
+    fs.rename(ctx, 1, &a_name, 1, &moved_name, 0)?;
+    fs.rename(ctx, dir.inode, &up_name, 1, &up_name, 0)?;
+    let upper = temp_dirs[1].path();
+    assert_eq!(fs::read_link(upper.join("moved"))?.to_str(), Some("b"));
+    assert_eq!(fs::read_link(upper.join("up"))?.to_str(), Some("../dir/up"));
+
+    Ok(())
+}
+
+#[test]
+fn test_readlink_symlink_depth_limit() -> io::Result<()> {
+    // Create test layers:
+    // Lower layer:
+    //   - real/file
+    //   - h1 -> h2
+    //   - h2 -> h3
+    //   - h3 -> real
+    //   - link -> h1/file
+    //   - loop -> loop/x
+    let layers = vec![vec![("real", true, 0o755), ("real/file", false, 0o644)]];
+
+    for policy in [SymlinkPolicy::Deny, SymlinkPolicy::FollowWithinLayer] {
+        for max_symlink_depth in [2, 3] {
+            let cfg = Config {
+                symlink_policy: policy,
+                max_symlink_depth,
+                ..Default::default()
+            };
+            let (fs, temp_dirs) = helper::create_overlayfs_with_config(layers.clone(), cfg)?;
+            let lower = temp_dirs[0].path();
+            std::os::unix::fs::symlink("h2", lower.join("h1"))?;
+            std::os::unix::fs::symlink("h3", lower.join("h2"))?;
+            std::os::unix::fs::symlink("real", lower.join("h3"))?;
+            std::os::unix::fs::symlink("h1/file", lower.join("link"))?;
+            std::os::unix::fs::symlink("loop/x", lower.join("loop"))?;
+
+            // Initialize filesystem
+            fs.init(FsOptions::empty())?;
+
+            let ctx = Context::default();
+            let readlink = |name: &str| {
+                let entry = fs.lookup(ctx, 1, &CString::new(name).unwrap())?;
+                fs.readlink(ctx, entry.inode)
+            };
+
+            // The chain takes 3 links to resolve
+            if max_symlink_depth < 3 {
+                let err = readlink("link").unwrap_err();
+                assert_eq!(err.raw_os_error(), Some(libc::ELOOP));
+            } else {
+                assert_eq!(readlink("link")?, b"h1/file");
+            }
+
+            // The loop never resolves
+            let err = readlink("loop").unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::ELOOP));
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_readlink_remapped_targets() -> io::Result<()> {
+    // Create test layers:
+    // Lower layer:
+    //   - file -> /mnt/share/file
+    //   - deep -> /mnt/share/deep/file
+    //   - exact -> /mnt/share/
+    //   - other -> /mnt/shared/file
+    //   - relative -> mnt/share/file
+    // Upper layer: empty
+    let layers = vec![vec![], vec![]];
+    let cfg = Config {
+        symlink_target_map: vec![
+            ("/mnt/share".into(), "/data".into()),
+            ("/mnt/share/deep/".into(), "/deep".into()),
+        ],
+        ..Default::default()
+    };
+
+    let (fs, temp_dirs) = helper::create_overlayfs_with_config(layers, cfg)?;
+    let lower = temp_dirs[0].path();
+    std::os::unix::fs::symlink("/mnt/share/file", lower.join("file"))?;
+    std::os::unix::fs::symlink("/mnt/share/deep/file", lower.join("deep"))?;
+    std::os::unix::fs::symlink("/mnt/share/", lower.join("exact"))?;
+    std::os::unix::fs::symlink("/mnt/shared/file", lower.join("other"))?;
+    std::os::unix::fs::symlink("mnt/share/file", lower.join("relative"))?;
+
+    // Initialize filesystem
+    fs.init(FsOptions::empty())?;
+
+    let ctx = Context::default();
+    let readlink = |name: &str| {
+        let entry = fs.lookup(ctx, 1, &CString::new(name).unwrap())?;
+        fs.readlink(ctx, entry.inode)
+    };
+
+    assert_eq!(readlink("file")?, b"/data/file");
+    assert_eq!(readlink("deep")?, b"/deep/file");
+    assert_eq!(readlink("exact")?, b"/data/");
+    assert_eq!(readlink("other")?, b"/mnt/shared/file");
+    assert_eq!(readlink("relative")?, b"mnt/share/file");
+
+    // Copying a link up writes the remapped target to the top layer
+    let link_name = CString::new("file").unwrap();
+    let new_name = CString::new("moved").unwrap();
+    fs.rename(ctx, 1, &link_name, 1, &new_name, 0)?;
+    let moved = temp_dirs[1].path().join("moved");
+    assert_eq!(fs::read_link(&moved)?.as_os_str(), "/data/file");
+    assert_eq!(readlink("moved")?, b"/data/file");
+
+    // Links created by the guest are left alone
+    let target = CString::new("/mnt/share/new").unwrap();
+    let new_link = CString::new("new").unwrap();
+    fs.symlink(ctx, &target, 1, &new_link, Extensions::default())?;
+    assert_eq!(readlink("new")?, b"/mnt/share/new");
+
+    Ok(())
+}
+
 #[test]
 fn test_readlink_errors() -> io::Result<()> {
     // Create test layers:
@@ -858,6 +1126,44 @@ fn test_readdir_whiteout() -> io::Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_readdir_whiteout_same_name() -> io::Result<()> {
+    // Layer 0 (bottom): dir1 with the directories tool0 to tool15
+    // Layer 1 (top): dir1 with the files tool0 to tool15, each next to a whiteout of its name
+    let names: Vec<_> = (0..16).map(|i| format!("dir1/tool{i}")).collect();
+    let whiteouts: Vec<_> = (0..16).map(|i| format!("dir1/.wh.tool{i}")).collect();
+    let mut lower = vec![("dir1", true, 0o755)];
+    lower.extend(names.iter().map(|name| (name.as_str(), true, 0o755)));
+    let mut upper = vec![("dir1", true, 0o755)];
+    upper.extend(names.iter().map(|name| (name.as_str(), false, 0o644)));
+    upper.extend(whiteouts.iter().map(|name| (name.as_str(), false, 0o644)));
+    let (fs, _temp_dirs) = helper::create_overlayfs(vec![lower, upper])?;
+    let ctx = Context::default();
+
+    let entry = fs.lookup(ctx, 1, &CString::new("dir1").unwrap())?;
+    let (handle, _opts) = fs.opendir(ctx, entry.inode, libc::O_RDONLY as u32)?;
+    let handle = handle.unwrap();
+    let mut entries = Vec::new();
+    fs.readdir(ctx, entry.inode, handle, 4096, 0, |dir_entry| {
+        entries.push((
+            String::from_utf8_lossy(dir_entry.name).to_string(),
+            dir_entry.type_,
+        ));
+        Ok(1)
+    })?;
+
+    // The whiteouts hide the directories below, not the files of their own layer, whichever of
+    // the two is read first
+    entries.sort();
+    let mut expected: Vec<_> = (0..16)
+        .map(|i| (format!("tool{i}"), libc::DT_REG as u32))
+        .collect();
+    expected.sort();
+    assert_eq!(entries, expected);
+
+    Ok(())
+}
+
 #[test]
 fn test_readdir_multiple_layers() -> io::Result<()> {
     let layers = vec![
@@ -1263,3 +1569,105 @@ fn test_readdir_shadow() -> io::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_read_sparse_file() -> io::Result<()> {
+    // Create test layers:
+    // Lower layer: sparse (4 MiB with data at 1 MiB + 100 and at 3 MiB)
+    let layers = vec![vec![("sparse", false, 0o644)]];
+
+    let (fs, temp_dirs) = helper::create_overlayfs(layers)?;
+
+    let path = temp_dirs[0].path().join("sparse");
+    {
+        use std::os::unix::fs::FileExt;
+
+        let file = fs::OpenOptions::new().write(true).open(&path)?;
+        file.set_len(4 << 20)?;
+        file.write_at(b"first", (1 << 20) + 100)?;
+        file.write_at(&[0xaa; 1000], 3 << 20)?;
+    }
+    let expected = fs::read(&path)?;
+
+    let ctx = Context::default();
+    let file_name = CString::new("sparse").unwrap();
+    let entry = fs.lookup(ctx, 1, &file_name)?;
+    let (handle, _opts) = fs.open(ctx, entry.inode, libc::O_RDONLY as u32)?;
+    let handle = handle.unwrap();
+
+    // Read across holes and data, and past the end of the file
+    for (offset, size, len) in [
+        (0, 2 << 20, 2 << 20),
+        (512 << 10, 3 << 20, 3 << 20),
+        ((1 << 20) + 102, 256 << 10, 256 << 10),
+        (3 << 20, 2 << 20, 1 << 20),
+        (4 << 20, 1 << 20, 0),
+    ] {
+        let mut writer = TestContainer(Vec::new());
+        let bytes_read = fs.read(ctx, entry.inode, handle, &mut writer, size, offset, None, 0)?;
+
+        assert_eq!(bytes_read, len, "read of {size} bytes at {offset}");
+        assert_eq!(writer.0, expected[offset as usize..offset as usize + len]);
+    }
+
+    fs.release(ctx, entry.inode, 0, handle, false, false, None)?;
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_read_atime() -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    use std::time::{Duration, SystemTime};
+
+    use crate::virtio::fs::FsAtime;
+
+    let layers = vec![vec![("file1", false, 0o644)]];
+    let set_times = |path: &std::path::Path, atime: SystemTime, mtime: SystemTime| {
+        let times = fs::FileTimes::new().set_accessed(atime).set_modified(mtime);
+        fs::File::options().write(true).open(path)?.set_times(times)
+    };
+    let read_atime = |fs: &crate::virtio::fs::overlayfs::OverlayFs| -> io::Result<()> {
+        let ctx = Context::default();
+        let entry = fs.lookup(ctx, 1, &CString::new("file1").unwrap())?;
+        let (handle, _opts) = fs.open(ctx, entry.inode, libc::O_RDONLY as u32)?;
+        let handle = handle.unwrap();
+        let mut writer = TestContainer(Vec::new());
+        fs.read(ctx, entry.inode, handle, &mut writer, 100, 0, None, 0)?;
+        fs.release(ctx, entry.inode, 0, handle, false, false, None)
+    };
+    let old = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+    let recent = SystemTime::now() - Duration::from_secs(60);
+
+    // The reads never update the access time
+    let cfg = Config {
+        atime: FsAtime::Noatime,
+        ..Default::default()
+    };
+    let (fs, temp_dirs) = helper::create_overlayfs_with_config(layers.clone(), cfg)?;
+    let path = temp_dirs[0].path().join("file1");
+    fs::write(&path, b"Hello, World!")?;
+    set_times(&path, old, old)?;
+    read_atime(&fs)?;
+    assert_eq!(fs::metadata(&path)?.atime(), 1_000_000);
+
+    // The first read updates it if not later than the last modification
+    let cfg = Config {
+        atime: FsAtime::Relatime,
+        ..Default::default()
+    };
+    let (fs, temp_dirs) = helper::create_overlayfs_with_config(layers, cfg)?;
+    let path = temp_dirs[0].path().join("file1");
+    fs::write(&path, b"Hello, World!")?;
+    set_times(&path, old, old)?;
+    read_atime(&fs)?;
+    let accessed = fs::metadata(&path)?.accessed()?;
+    assert!(accessed > recent);
+
+    // But not once later than the last modification, until it's a day old
+    read_atime(&fs)?;
+    assert_eq!(fs::metadata(&path)?.accessed()?, accessed);
+
+    Ok(())
+}
@@ -66,6 +66,37 @@ fn test_unlink_whiteout() -> io::Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_unlink_concurrent() -> io::Result<()> {
+    // Create an overlayfs with a file in the lower layer and an empty upper layer
+    let (fs, temp_dirs) = helper::create_overlayfs(vec![
+        vec![("file1.txt", false, 0o644)], // lower layer
+        vec![],                            // upper layer
+    ])?;
+    let ctx = Context::default();
+    let file_name = CString::new("file1.txt").unwrap();
+    fs.lookup(ctx, 1, &file_name)?;
+
+    // Racing unlinks of the same name are ordered: the first one creates the whiteout, and the
+    // others find the file gone
+    let results = std::thread::scope(|s| {
+        let handles = (0..8)
+            .map(|_| s.spawn(|| fs.unlink(ctx, 1, &file_name)))
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+    assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+    for err in results.iter().filter_map(|r| r.as_ref().err()) {
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+    }
+    assert!(temp_dirs[1].path().join(".wh.file1.txt").exists());
+
+    Ok(())
+}
+
 #[test]
 fn test_unlink_multiple_layers() -> io::Result<()> {
     // Create an overlayfs with three layers, each containing different files
@@ -314,6 +345,37 @@ fn test_rmdir_whiteout() -> io::Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_rmdir_after_whiteouts() -> io::Result<()> {
+    // Create test layers:
+    // Lower layer: dir1/, dir1/file1
+    // Upper layer: dir1/
+    let (fs, temp_dirs) = helper::create_overlayfs(vec![
+        vec![("dir1", true, 0o755), ("dir1/file1", false, 0o644)],
+        vec![("dir1", true, 0o755)],
+    ])?;
+    let ctx = Context::default();
+
+    // The directory can't be removed while it has entries, even in a lower layer only
+    let dir1_name = CString::new("dir1").unwrap();
+    let file1_name = CString::new("file1").unwrap();
+    let dir1 = fs.lookup(ctx, 1, &dir1_name)?;
+    let res = fs.rmdir(ctx, 1, &dir1_name);
+    assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::ENOTEMPTY));
+
+    // Once they are removed, the whiteouts left in the top layer don't prevent it
+    fs.unlink(ctx, dir1.inode, &file1_name)?;
+    assert!(temp_dirs[1].path().join("dir1/.wh.file1").exists());
+    fs.rmdir(ctx, 1, &dir1_name)?;
+    match fs.lookup(ctx, 1, &dir1_name) {
+        Ok(_) => panic!("Directory still exists after rmdir"),
+        Err(e) => assert_eq!(e.raw_os_error(), Some(libc::ENOENT)),
+    }
+    assert!(temp_dirs[0].path().join("dir1/file1").exists());
+
+    Ok(())
+}
+
 #[test]
 fn test_rmdir_multiple_layers() -> io::Result<()> {
     // Create an overlayfs with three layers, each containing different directories
@@ -506,3 +568,150 @@ fn test_rmdir_complex_layers() -> io::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_unlink_generation() -> io::Result<()> {
+    use std::{fs, thread, time::Duration};
+
+    use crate::virtio::fs::overlayfs::Config;
+
+    // Create test layers:
+    // Upper layer: file1, dir1/
+    let layers = vec![vec![("file1", false, 0o644), ("dir1", true, 0o755)]];
+    let cfg = Config {
+        inode_map: true,
+        ..Default::default()
+    };
+    let (fs, temp_dirs) = helper::create_overlayfs_with_config(layers, cfg)?;
+    let ctx = Context::default();
+
+    // The generation of a file survives forgetting it
+    let file1_name = CString::new("file1").unwrap();
+    let entry = fs.lookup(ctx, 1, &file1_name)?;
+    let generation = entry.generation;
+    fs.forget(ctx, entry.inode, 1);
+    let entry = fs.lookup(ctx, 1, &file1_name)?;
+    assert_eq!(entry.generation, generation);
+
+    // Deleting files and directories keeps nothing when the host records birth times, as the
+    // ones the tests run on do
+    fs.unlink(ctx, 1, &file1_name)?;
+    fs.forget(ctx, entry.inode, 1);
+    let dir1_name = CString::new("dir1").unwrap();
+    let dir1_entry = fs.lookup(ctx, 1, &dir1_name)?;
+    fs.rmdir(ctx, 1, &dir1_name)?;
+    fs.forget(ctx, dir1_entry.inode, 1);
+    assert!(fs.generations.lock().unwrap().is_empty());
+
+    // A file created afterwards gets another generation, even with the same host inode number
+    thread::sleep(Duration::from_millis(10));
+    fs::write(temp_dirs[0].path().join("file1"), b"new")?;
+    let new_entry = fs.lookup(ctx, 1, &file1_name)?;
+    assert_ne!(new_entry.generation, generation);
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_remove_tree() -> io::Result<()> {
+    use std::{
+        fs,
+        sync::{atomic::AtomicI32, Arc},
+    };
+
+    use nix::request_code_read;
+
+    use crate::virtio::fs::overlayfs::{Config, OverlayFs};
+
+    const REMOVE_TREE_REQ: u32 = request_code_read!(b'v', 3, 24) as u32;
+
+    let ctx = Context::default();
+    let exit_code = Arc::new(AtomicI32::new(0));
+    let remove_tree = |fs: &OverlayFs, ctx, inode, limit| {
+        let out = fs.ioctl(
+            ctx,
+            inode,
+            0,
+            0,
+            REMOVE_TREE_REQ,
+            limit,
+            &[],
+            24,
+            &exit_code,
+        )?;
+        let counts: Vec<u64> = out
+            .chunks(8)
+            .map(|b| u64::from_ne_bytes(b.try_into().unwrap()))
+            .collect();
+        Ok::<_, io::Error>((counts[0], counts[1], counts[2] != 0))
+    };
+
+    // Create test layers:
+    // Lower layer: dir1/, dir1/a, dir1/sub/, dir1/sub/b, file1
+    // Upper layer: dir1/, dir1/c, dir1/sub2/, dir1/sub2/d
+    let layers = vec![
+        vec![
+            ("dir1", true, 0o755),
+            ("dir1/a", false, 0o644),
+            ("dir1/sub", true, 0o755),
+            ("dir1/sub/b", false, 0o644),
+            ("file1", false, 0o644),
+        ],
+        vec![
+            ("dir1", true, 0o755),
+            ("dir1/c", false, 0o644),
+            ("dir1/sub2", true, 0o755),
+            ("dir1/sub2/d", false, 0o644),
+        ],
+    ];
+    let dir1_name = CString::new("dir1").unwrap();
+
+    // The request is refused unless allowed
+    let (fs, _temp_dirs) = helper::create_overlayfs(layers.clone())?;
+    let dir1 = fs.lookup(ctx, 1, &dir1_name)?;
+    let res = remove_tree(&fs, ctx, dir1.inode, 0);
+    assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::EPERM));
+
+    let cfg = Config {
+        remove_tree: true,
+        ..Default::default()
+    };
+    let (fs, temp_dirs) = helper::create_overlayfs_with_config(layers, cfg)?;
+    let dir1 = fs.lookup(ctx, 1, &dir1_name)?;
+
+    // Only root may use it, and not on the root directory or on a file
+    let user = Context {
+        uid: 1000,
+        ..Default::default()
+    };
+    let res = remove_tree(&fs, user, dir1.inode, 0);
+    assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::EPERM));
+    let res = remove_tree(&fs, ctx, 1, 0);
+    assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::EBUSY));
+    let file1 = fs.lookup(ctx, 1, &CString::new("file1").unwrap())?;
+    let res = remove_tree(&fs, ctx, file1.inode, 0);
+    assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::ENOTDIR));
+
+    // The entries of the lower layer are hidden at once, those of the top layer up to the limit
+    assert_eq!(remove_tree(&fs, ctx, dir1.inode, 1)?, (1, 0, false));
+    for name in ["a", "sub"] {
+        let res = fs.lookup(ctx, dir1.inode, &CString::new(name).unwrap());
+        assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::ENOENT));
+    }
+    assert_eq!(remove_tree(&fs, ctx, dir1.inode, 0)?, (2, 0, true));
+
+    // Only the opaque marker is left in the top layer, and the lower layer is untouched
+    let upper_entries: Vec<_> = fs::read_dir(temp_dirs[1].path().join("dir1"))?
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(upper_entries, vec![".wh..wh..opq"]);
+    assert!(temp_dirs[0].path().join("dir1/sub/b").exists());
+
+    // The directory can then be removed
+    fs.rmdir(ctx, 1, &dir1_name)?;
+    let res = fs.lookup(ctx, 1, &dir1_name);
+    assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::ENOENT));
+
+    Ok(())
+}
@@ -0,0 +1,299 @@
+//! Randomized concurrent operations against a layered overlay.
+//!
+//! Every phase runs a few threads doing random creates, writes, renames, unlinks, readdirs and
+//! chmods on a small set of names shared with the lower layer, so that they keep racing on the same
+//! copy-ups and whiteouts. Once the threads are done, the merged view is checked against the one
+//! computed from the layers on the host.
+//!
+//! The operations of a thread are drawn from a seed printed by the test, which can be given back
+//! through `OVERLAYFS_STRESS_SEED`, although the interleaving of the threads is up to the scheduler.
+//! `test_overlayfs_soak` runs many more phases, given by `OVERLAYFS_SOAK_PHASES`, and is only run
+//! on demand:
+//!
+//! ```text
+//! cargo test -p devices test_overlayfs_soak -- --ignored --nocapture
+//! ```
+
+use std::{collections::BTreeSet, env, ffi::CString, fs, io, path::Path, thread};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::virtio::{
+    fs::filesystem::{Context, Extensions, FileSystem},
+    fs::overlayfs::OverlayFs,
+    fuse::{FsOptions, SetattrValid},
+};
+
+use super::helper::{self, TestContainer};
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The directories the operations run in, from the root.
+const DIRS: [&str; 3] = ["", "x", "y"];
+
+/// The names the operations pick from. The first half exist in the lower layer.
+const NAMES: [&str; 6] = ["a", "b", "c", "d", "e", "f"];
+
+const THREADS: u64 = 8;
+
+const OPERATIONS: usize = 200;
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+
+const OPAQUE_MARKER: &str = ".wh..wh..opq";
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+fn name(name: &str) -> CString {
+    CString::new(name).unwrap()
+}
+
+/// Runs `phases` phases of concurrent operations, checking the merged view after each of them.
+fn run(phases: u64) -> io::Result<()> {
+    let seed = env::var("OVERLAYFS_STRESS_SEED")
+        .ok()
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or_else(rand::random::<u64>);
+    println!("OVERLAYFS_STRESS_SEED={seed}");
+
+    let files: Vec<String> = ["", "x/", "y/"]
+        .into_iter()
+        .flat_map(|dir| {
+            NAMES[..NAMES.len() / 2]
+                .iter()
+                .map(move |file| format!("{dir}{file}"))
+        })
+        .collect();
+    let mut lower = vec![("x", true, 0o755), ("y", true, 0o755)];
+    lower.extend(files.iter().map(|file| (file.as_str(), false, 0o644)));
+    let (fs, temp_dirs) = helper::create_overlayfs(vec![lower, vec![]])?;
+    for dir in ["", "x/", "y/"] {
+        fs::write(temp_dirs[0].path().join(format!("{dir}a")), b"lower")?;
+    }
+    fs.init(FsOptions::empty())?;
+
+    let ctx = Context::default();
+    let mut dirs = vec![1];
+    for dir in &DIRS[1..] {
+        dirs.push(fs.lookup(ctx, 1, &name(dir))?.inode);
+    }
+
+    for phase in 0..phases {
+        thread::scope(|scope| {
+            for thread in 0..THREADS {
+                let (fs, dirs) = (&fs, &dirs);
+                scope.spawn(move || {
+                    let mut rng = StdRng::seed_from_u64(seed ^ (phase << 32 | thread));
+                    for _ in 0..OPERATIONS {
+                        // Losing a race for a name is expected
+                        if let Err(e) = operate(fs, dirs, &mut rng) {
+                            if !matches!(
+                                e.kind(),
+                                io::ErrorKind::NotFound | io::ErrorKind::AlreadyExists
+                            ) {
+                                panic!("phase {phase}, thread {thread}: {e}");
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        for (dir, inode) in DIRS.iter().zip(&dirs) {
+            check_dir(&fs, *inode, temp_dirs[0].path(), temp_dirs[1].path(), dir)
+                .unwrap_or_else(|e| panic!("phase {phase}, directory /{dir}: {e}"));
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs a random operation on a random name of one of `dirs`.
+fn operate(fs: &OverlayFs, dirs: &[u64], rng: &mut StdRng) -> io::Result<()> {
+    let ctx = Context::default();
+    let dir = dirs[rng.gen_range(0..dirs.len())];
+    let file = name(NAMES[rng.gen_range(0..NAMES.len())]);
+
+    match rng.gen_range(0..6) {
+        0 => {
+            let (entry, handle, _) =
+                fs.create(ctx, dir, &file, 0o644, 0, 0o022, Extensions::default())?;
+            fs.release(ctx, entry.inode, 0, handle.unwrap(), false, false, None)?;
+            fs.forget(ctx, entry.inode, 1);
+        }
+        1 => {
+            let inode = fs.lookup(ctx, dir, &file)?.inode;
+            let res = fs
+                .open(ctx, inode, libc::O_RDWR as u32)
+                .and_then(|(handle, _)| {
+                    let handle = handle.unwrap();
+                    let data = vec![b'w'; rng.gen_range(1..64)];
+                    let offset = rng.gen_range(0..16);
+                    let res = fs.write(
+                        ctx,
+                        inode,
+                        handle,
+                        TestContainer(data.clone()),
+                        data.len() as u32,
+                        offset,
+                        None,
+                        false,
+                        false,
+                        0,
+                    );
+                    fs.release(ctx, inode, 0, handle, false, false, None)?;
+                    res.map(|_| ())
+                });
+            fs.forget(ctx, inode, 1);
+            res?;
+        }
+        2 => {
+            let new_dir = dirs[rng.gen_range(0..dirs.len())];
+            let new_file = name(NAMES[rng.gen_range(0..NAMES.len())]);
+            fs.rename(ctx, dir, &file, new_dir, &new_file, 0)?;
+        }
+        3 => fs.unlink(ctx, dir, &file)?,
+        4 => {
+            readdir(fs, dir)?;
+        }
+        _ => {
+            let entry = fs.lookup(ctx, dir, &file)?;
+            let mut attr = entry.attr;
+            attr.st_mode = (attr.st_mode & !0o777) | [0o600, 0o644][rng.gen_range(0..2)];
+            let res = fs.setattr(ctx, entry.inode, attr, None, SetattrValid::MODE);
+            fs.forget(ctx, entry.inode, 1);
+            res?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the names of the entries of the directory `inode`, in the order they were listed.
+fn readdir(fs: &OverlayFs, inode: u64) -> io::Result<Vec<String>> {
+    let ctx = Context::default();
+    let (handle, _) = fs.opendir(ctx, inode, libc::O_RDONLY as u32)?;
+    let handle = handle.unwrap();
+    let mut names = Vec::new();
+    let res = fs.readdir(ctx, inode, handle, 65536, 0, |entry| {
+        names.push(String::from_utf8_lossy(entry.name).to_string());
+        Ok(1)
+    });
+    fs.releasedir(ctx, inode, 0, handle)?;
+    res.map(|_| names)
+}
+
+/// Checks the merged view of the directory `inode`, at `dir` in the layers, against the one of the
+/// layers `lower` and `upper` on the host.
+fn check_dir(fs: &OverlayFs, inode: u64, lower: &Path, upper: &Path, dir: &str) -> io::Result<()> {
+    let ctx = Context::default();
+    let host_names = |root: &Path| -> io::Result<BTreeSet<String>> {
+        match fs::read_dir(root.join(dir)) {
+            Ok(entries) => entries
+                .map(|entry| Ok(entry?.file_name().into_string().unwrap()))
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+            Err(e) => Err(e),
+        }
+    };
+    let upper_names = host_names(upper)?;
+
+    let lower_names = host_names(lower)?;
+
+    // Every whiteout hides an entry of the lower layer, and never sits next to an entry
+    let whiteouts: BTreeSet<&str> = upper_names
+        .iter()
+        .filter(|n| *n != OPAQUE_MARKER)
+        .filter_map(|n| n.strip_prefix(WHITEOUT_PREFIX))
+        .collect();
+    if let Some(both) = whiteouts.iter().find(|n| upper_names.contains(**n)) {
+        return Err(io::Error::other(format!(
+            "{both} is both whited out and present"
+        )));
+    }
+    if let Some(stale) = whiteouts.iter().find(|n| !lower_names.contains(**n)) {
+        return Err(io::Error::other(format!(
+            "{stale} is whited out but not in the lower layer"
+        )));
+    }
+
+    let mut expected: BTreeSet<String> = upper_names
+        .iter()
+        .filter(|n| !n.starts_with(WHITEOUT_PREFIX))
+        .cloned()
+        .collect();
+    if !upper_names.contains(OPAQUE_MARKER) {
+        expected.extend(
+            lower_names
+                .into_iter()
+                .filter(|n| !whiteouts.contains(n.as_str())),
+        );
+    }
+
+    // The listing holds every visible entry once, and nothing else
+    let listed = readdir(fs, inode)?;
+    let unique: BTreeSet<String> = listed.iter().cloned().collect();
+    if unique.len() != listed.len() {
+        return Err(io::Error::other(format!("duplicate entries in {listed:?}")));
+    }
+    if unique != expected {
+        return Err(io::Error::other(format!(
+            "listed {unique:?}, expected {expected:?}"
+        )));
+    }
+
+    // Every name resolves to the topmost layer holding it, or to nothing
+    for file in NAMES {
+        match fs.lookup(ctx, inode, &name(file)) {
+            Ok(entry) => {
+                fs.forget(ctx, entry.inode, 1);
+                if !expected.contains(file) {
+                    return Err(io::Error::other(format!("{file} is a ghost")));
+                }
+                let root = if upper_names.contains(file) {
+                    upper
+                } else {
+                    lower
+                };
+                let len = fs::metadata(root.join(dir).join(file))?.len();
+                if entry.attr.st_size as u64 != len {
+                    return Err(io::Error::other(format!(
+                        "{file} is {} bytes, {len} on the host",
+                        entry.attr.st_size
+                    )));
+                }
+            }
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {
+                if expected.contains(file) {
+                    return Err(io::Error::other(format!("{file} cannot be looked up")));
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_overlayfs_stress() -> io::Result<()> {
+    run(4)
+}
+
+#[test]
+#[ignore]
+fn test_overlayfs_soak() -> io::Result<()> {
+    let phases = env::var("OVERLAYFS_SOAK_PHASES")
+        .ok()
+        .and_then(|phases| phases.parse().ok())
+        .unwrap_or(200);
+    run(phases)
+}
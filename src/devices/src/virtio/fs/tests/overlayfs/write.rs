@@ -206,6 +206,207 @@ fn test_write_after_copy_up() -> io::Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_write_after_interrupted_copy_up() -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    // Create an overlayfs with two layers, where file1 exists in the lower layer
+    let layers = vec![vec![("file1", false, 0o644)], vec![]];
+    let (fs, temp_dirs) = helper::create_overlayfs(layers)?;
+    std::fs::write(temp_dirs[0].path().join("file1"), b"Hello, World!")?;
+
+    // Leave behind a truncated staging file, as if a previous copy-up had been interrupted
+    let ino = std::fs::metadata(temp_dirs[0].path().join("file1"))?.ino();
+    let staging_path = temp_dirs[1].path().join(format!(".wh..wh..copyup.0.{ino}"));
+    std::fs::write(&staging_path, b"Hel")?;
+
+    let ctx = Context::default();
+
+    // Opening the file for writing copies it up again from scratch
+    let file_name = CString::new("file1").unwrap();
+    let entry = fs.lookup(ctx, 1, &file_name)?;
+    let (handle, _opts) = fs.open(ctx, entry.inode, libc::O_WRONLY as u32)?;
+    fs.release(ctx, entry.inode, 0, handle.unwrap(), false, false, None)?;
+
+    let file_content = std::fs::read(temp_dirs[1].path().join("file1"))?;
+    assert_eq!(file_content, b"Hello, World!");
+    assert!(!staging_path.exists());
+
+    // The staging file is hidden from the guest
+    assert!(fs
+        .lookup(
+            ctx,
+            1,
+            &CString::new(format!(".wh..wh..copyup.0.{ino}")).unwrap()
+        )
+        .is_err());
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_write_after_resumed_copy_up() -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    // Create an overlayfs with two layers, where file1 exists in the lower layer
+    let layers = vec![vec![("file1", false, 0o644)], vec![]];
+    let (fs, temp_dirs) = helper::create_overlayfs(layers)?;
+    std::fs::write(temp_dirs[0].path().join("file1"), b"Hello, World!")?;
+
+    // Leave behind a partial staging file recording the source it is a copy of. Its content
+    // differs from the source so we can tell whether the copy was resumed or restarted.
+    let src = std::fs::metadata(temp_dirs[0].path().join("file1"))?;
+    let staging_path = temp_dirs[1]
+        .path()
+        .join(format!(".wh..wh..copyup.0.{}", src.ino()));
+    std::fs::write(&staging_path, b"HELLO")?;
+
+    let marker = format!(
+        "{}:{}:{}:{}:{}",
+        src.size(),
+        src.mtime(),
+        src.mtime_nsec(),
+        src.dev(),
+        src.ino()
+    );
+    let c_staging_path = CString::new(staging_path.to_str().unwrap()).unwrap();
+    let res = unsafe {
+        libc::setxattr(
+            c_staging_path.as_ptr(),
+            c"user.overlayfs.copyup".as_ptr(),
+            marker.as_ptr() as *const libc::c_void,
+            marker.len(),
+            0,
+        )
+    };
+    assert_eq!(res, 0);
+
+    let ctx = Context::default();
+
+    // Opening the file for writing resumes the copy-up where it stopped
+    let file_name = CString::new("file1").unwrap();
+    let entry = fs.lookup(ctx, 1, &file_name)?;
+    let (handle, _opts) = fs.open(ctx, entry.inode, libc::O_WRONLY as u32)?;
+    fs.release(ctx, entry.inode, 0, handle.unwrap(), false, false, None)?;
+
+    let file_content = std::fs::read(temp_dirs[1].path().join("file1"))?;
+    assert_eq!(file_content, b"HELLO, World!");
+    assert!(!staging_path.exists());
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_write_after_copy_up_of_other_source() -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    // Create an overlayfs with two layers, where file1 exists in the lower layer
+    let layers = vec![vec![("file1", false, 0o644)], vec![]];
+    let (fs, temp_dirs) = helper::create_overlayfs(layers)?;
+    std::fs::write(temp_dirs[0].path().join("file1"), b"Hello, World!")?;
+
+    // Leave behind a partial staging file whose marker matches the size and modification time of
+    // the source, but records another file as its source
+    let src = std::fs::metadata(temp_dirs[0].path().join("file1"))?;
+    let staging_path = temp_dirs[1]
+        .path()
+        .join(format!(".wh..wh..copyup.0.{}", src.ino()));
+    std::fs::write(&staging_path, b"HELLO")?;
+
+    let marker = format!(
+        "{}:{}:{}:{}:{}",
+        src.size(),
+        src.mtime(),
+        src.mtime_nsec(),
+        src.dev(),
+        src.ino() + 1
+    );
+    let c_staging_path = CString::new(staging_path.to_str().unwrap()).unwrap();
+    let res = unsafe {
+        libc::setxattr(
+            c_staging_path.as_ptr(),
+            c"user.overlayfs.copyup".as_ptr(),
+            marker.as_ptr() as *const libc::c_void,
+            marker.len(),
+            0,
+        )
+    };
+    assert_eq!(res, 0);
+
+    let ctx = Context::default();
+
+    // Opening the file for writing copies it up again from scratch
+    let file_name = CString::new("file1").unwrap();
+    let entry = fs.lookup(ctx, 1, &file_name)?;
+    let (handle, _opts) = fs.open(ctx, entry.inode, libc::O_WRONLY as u32)?;
+    fs.release(ctx, entry.inode, 0, handle.unwrap(), false, false, None)?;
+
+    let file_content = std::fs::read(temp_dirs[1].path().join("file1"))?;
+    assert_eq!(file_content, b"Hello, World!");
+    assert!(!staging_path.exists());
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_write_after_copy_up_with_content_store() -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    use crate::virtio::fs::overlayfs::Config;
+
+    // Two overlays share a store and have the same file in their lower layer
+    let store_dir = tempfile::tempdir()?;
+    let content: Vec<u8> = (0..16384u32).map(|i| (i % 251) as u8).collect();
+    let ctx = Context::default();
+    for _ in 0..2 {
+        let layers = vec![vec![("file1", false, 0o644)], vec![]];
+        let cfg = Config {
+            content_store: Some(store_dir.path().join("store")),
+            ..Default::default()
+        };
+        let (fs, temp_dirs) = helper::create_overlayfs_with_config(layers, cfg)?;
+        std::fs::write(temp_dirs[0].path().join("file1"), &content)?;
+
+        // The copy-up is intact whether or not the store file system supports clones
+        let file_name = CString::new("file1").unwrap();
+        let entry = fs.lookup(ctx, 1, &file_name)?;
+        let (handle, _opts) = fs.open(ctx, entry.inode, libc::O_WRONLY as u32)?;
+        let mut reader = TestContainer(b"HELLO".to_vec());
+        fs.write(
+            ctx,
+            entry.inode,
+            handle.unwrap(),
+            &mut reader,
+            5,
+            0,
+            None,
+            false,
+            false,
+            0,
+        )?;
+        fs.release(ctx, entry.inode, 0, handle.unwrap(), false, false, None)?;
+
+        let file_content = std::fs::read(temp_dirs[1].path().join("file1"))?;
+        assert_eq!(&file_content[..5], b"HELLO");
+        assert_eq!(file_content[5..], content[5..]);
+        assert_eq!(std::fs::read(temp_dirs[0].path().join("file1"))?, content);
+    }
+
+    // Objects are never modified through the copies, and no temporary files are left behind
+    for entry in std::fs::read_dir(store_dir.path().join("store"))? {
+        let entry = entry?;
+        assert!(!entry.file_name().as_bytes().starts_with(b"tmp."));
+        for object in std::fs::read_dir(entry.path())? {
+            assert_eq!(std::fs::read(object?.path())?, content);
+        }
+    }
+
+    Ok(())
+}
+
 #[test]
 fn test_write_invalid_handle() -> io::Result<()> {
     // Create a simple overlayfs with a single layer containing a file
@@ -426,3 +627,210 @@ fn test_write_with_whiteouts_and_opaque_dirs() -> io::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_write_delayed_error_reported_once() -> io::Result<()> {
+    let layers = vec![vec![("file1", false, 0o644)]];
+    let (fs, _temp_dirs) = helper::create_overlayfs(layers)?;
+
+    let ctx = Context::default();
+
+    let file_name = CString::new("file1").unwrap();
+    let entry = fs.lookup(ctx, 1, &file_name)?;
+    let (handle, _opts) = fs.open(ctx, entry.inode, libc::O_RDWR as u32)?;
+    let handle = handle.unwrap();
+
+    // A write at an offset the host cannot represent fails. As a regular write the error is
+    // returned right away and must not be reported again.
+    let content = b"Hello, World!";
+    let mut reader = TestContainer(content.to_vec());
+    let result = fs.write(
+        ctx,
+        entry.inode,
+        handle,
+        &mut reader,
+        content.len() as u32,
+        u64::MAX,
+        None,
+        false,
+        false,
+        0,
+    );
+    assert!(result.is_err());
+    fs.flush(ctx, entry.inode, handle, 0)?;
+
+    // As a delayed write the error is kept and reported by the next flush, exactly once
+    let mut reader = TestContainer(content.to_vec());
+    let result = fs.write(
+        ctx,
+        entry.inode,
+        handle,
+        &mut reader,
+        content.len() as u32,
+        u64::MAX,
+        None,
+        true,
+        false,
+        0,
+    );
+    assert!(result.is_err());
+    assert!(fs.flush(ctx, entry.inode, handle, 0).is_err());
+    fs.flush(ctx, entry.inode, handle, 0)?;
+
+    // A delayed write error not picked up by a flush is reported on release instead
+    let mut reader = TestContainer(content.to_vec());
+    let result = fs.write(
+        ctx,
+        entry.inode,
+        handle,
+        &mut reader,
+        content.len() as u32,
+        u64::MAX,
+        None,
+        true,
+        false,
+        0,
+    );
+    assert!(result.is_err());
+    assert!(fs
+        .release(ctx, entry.inode, 0, handle, false, false, None)
+        .is_err());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_write_ephemeral_upper_layer() -> io::Result<()> {
+    use crate::virtio::fs::{
+        filesystem::Extensions,
+        overlayfs::{Config, UpperLayer},
+    };
+
+    // Create an overlayfs with a single read-only layer and a RAM-backed top layer of 64 bytes
+    let layers = vec![vec![("file1", false, 0o644)]];
+    let cfg = Config {
+        upper_layer: UpperLayer::Ram { size_limit: 64 },
+        ..Default::default()
+    };
+    let (fs, temp_dirs) = helper::create_overlayfs_with_config(layers, cfg)?;
+    std::fs::write(temp_dirs[0].path().join("file1"), b"Hello, World!")?;
+    let upper_dir = fs.get_config().layers.last().unwrap().clone();
+    assert!(upper_dir.starts_with("/dev/shm"));
+
+    let ctx = Context::default();
+    let write = |inode, handle, len: usize, offset| {
+        let mut reader = TestContainer(vec![b'x'; len]);
+        fs.write(
+            ctx,
+            inode,
+            handle,
+            &mut reader,
+            len as u32,
+            offset,
+            None,
+            false,
+            false,
+            0,
+        )
+    };
+
+    // Copying file1 up and growing it takes 40 bytes
+    let file1_name = CString::new("file1").unwrap();
+    let file1 = fs.lookup(ctx, 1, &file1_name)?;
+    let (handle1, _opts) = fs.open(ctx, file1.inode, libc::O_WRONLY as u32)?;
+    assert_eq!(write(file1.inode, handle1.unwrap(), 40, 0)?, 40);
+
+    // A second file can only take what is left
+    let file2_name = CString::new("file2").unwrap();
+    let (file2, handle2, _opts) = fs.create(
+        ctx,
+        1,
+        &file2_name,
+        0o644,
+        libc::O_RDWR as u32,
+        0o022,
+        Extensions::default(),
+    )?;
+    let handle2 = handle2.unwrap();
+    let err = write(file2.inode, handle2, 30, 0).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOSPC));
+    assert_eq!(write(file2.inode, handle2, 24, 0)?, 24);
+
+    // Removing file1 gives its space back
+    fs.release(ctx, file1.inode, 0, handle1.unwrap(), false, false, None)?;
+    fs.unlink(ctx, 1, &file1_name)?;
+    assert_eq!(write(file2.inode, handle2, 30, 24)?, 30);
+    fs.release(ctx, file2.inode, 0, handle2, false, false, None)?;
+
+    // The given layer was left alone, and the top layer goes away with the file system
+    assert_eq!(
+        std::fs::read(temp_dirs[0].path().join("file1"))?,
+        b"Hello, World!"
+    );
+    assert_eq!(std::fs::read_dir(temp_dirs[0].path())?.count(), 1);
+    assert!(upper_dir.join("file2").exists());
+    drop(fs);
+    assert!(!upper_dir.exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_write_read_only() -> io::Result<()> {
+    use crate::virtio::fs::{
+        filesystem::{Extensions, SetattrValid},
+        overlayfs::Config,
+    };
+
+    // Create a read-only overlayfs with a file in each layer
+    let layers = vec![vec![("file1", false, 0o644)], vec![("file2", false, 0o644)]];
+    let cfg = Config {
+        read_only: true,
+        ..Default::default()
+    };
+    let (fs, temp_dirs) = helper::create_overlayfs_with_config(layers, cfg)?;
+
+    let ctx = Context::default();
+    let erofs = |res: io::Result<()>| res.unwrap_err().raw_os_error() == Some(libc::EROFS);
+
+    // The files can still be read, from any layer
+    let file1_name = CString::new("file1").unwrap();
+    let file1 = fs.lookup(ctx, 1, &file1_name)?;
+    let (handle, _opts) = fs.open(ctx, file1.inode, libc::O_RDONLY as u32)?;
+    fs.release(ctx, file1.inode, 0, handle.unwrap(), false, false, None)?;
+    fs.access(ctx, file1.inode, libc::R_OK as u32)?;
+
+    // Nothing is copied up nor changed in the top layer
+    let file2 = fs.lookup(ctx, 1, &CString::new("file2").unwrap())?;
+    for inode in [file1.inode, file2.inode] {
+        assert!(erofs(fs.open(ctx, inode, libc::O_RDWR as u32).map(drop)));
+        assert!(erofs(fs.access(ctx, inode, libc::W_OK as u32)));
+    }
+    let mut attr = file1.attr;
+    attr.st_mode = 0o600;
+    assert!(erofs(
+        fs.setattr(ctx, file1.inode, attr, None, SetattrValid::MODE)
+            .map(drop)
+    ));
+    assert!(erofs(fs.unlink(ctx, 1, &file1_name)));
+    assert!(erofs(
+        fs.create(
+            ctx,
+            1,
+            &CString::new("file3").unwrap(),
+            0o644,
+            libc::O_RDWR as u32,
+            0o022,
+            Extensions::default(),
+        )
+        .map(drop)
+    ));
+
+    let top_entries: Vec<_> = std::fs::read_dir(temp_dirs[1].path())?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<io::Result<_>>()?;
+    assert_eq!(top_entries, ["file2"]);
+
+    Ok(())
+}
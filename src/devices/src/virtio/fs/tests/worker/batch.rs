@@ -0,0 +1,85 @@
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use vm_memory::ByteValued;
+
+use crate::virtio::fs::defs::MAX_USED_BATCH_LATENCY;
+use crate::virtio::fs::fuse::{
+    GetattrIn, Opcode, SetattrIn, SetattrValid, KERNEL_MINOR_VERSION, KERNEL_VERSION, ROOT_ID,
+};
+use crate::virtio::fs::{overlayfs, FsHook, FsHookPoint, FsHooks, FsImplConfig};
+
+use super::helper::TestClient;
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_slow_request_publishes_batch() {
+    let lower = tempfile::tempdir().unwrap();
+    let upper = tempfile::tempdir().unwrap();
+    for name in ["a", "slow", "next"] {
+        fs::write(lower.path().join(name), name).unwrap();
+    }
+    let hooks = FsHooks::default();
+    let fs_config = FsImplConfig::Overlayfs(overlayfs::Config {
+        layers: vec![lower.path().to_path_buf(), upper.path().to_path_buf()],
+        hooks: Some(hooks.clone()),
+        ..Default::default()
+    });
+    let mut client = TestClient::new(fs_config);
+    client.init(KERNEL_VERSION, KERNEL_MINOR_VERSION).unwrap();
+    let a = client.lookup(ROOT_ID, "a").unwrap();
+    let slow = client.lookup(ROOT_ID, "slow").unwrap();
+    let next = client.lookup(ROOT_ID, "next").unwrap();
+
+    // The copy-up of "slow" outlasts the latency of a batch, and the copy-ups record what was
+    // published when they start
+    let published = client.published();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let seen_by_hook = seen.clone();
+    hooks.add(FsHook {
+        point: FsHookPoint::PreCopyUp,
+        callback: Arc::new(move |event| {
+            let name = event.path.file_name().unwrap().to_str().unwrap();
+            seen_by_hook
+                .lock()
+                .unwrap()
+                .push((name.to_string(), published()));
+            if name == "slow" {
+                thread::sleep(MAX_USED_BATCH_LATENCY * 4);
+            }
+            Ok(())
+        }),
+    });
+
+    let before = client.published()();
+    let getattr_in = GetattrIn::default();
+    let setattr_in = SetattrIn {
+        valid: SetattrValid::MODE.bits(),
+        mode: libc::S_IFREG | 0o600,
+        ..Default::default()
+    };
+    let replies = client.send_batch(
+        &[
+            (Opcode::Getattr, a.nodeid, &[getattr_in.as_slice()]),
+            (Opcode::Setattr, slow.nodeid, &[setattr_in.as_slice()]),
+            (Opcode::Setattr, next.nodeid, &[setattr_in.as_slice()]),
+        ],
+        256,
+    );
+    assert!(replies.iter().all(|reply| reply.error == 0));
+
+    // The requests completed don't wait for the ones after them that may block, whether the
+    // deadline passed or not
+    assert_eq!(
+        *seen.lock().unwrap(),
+        [
+            ("slow".to_string(), before.wrapping_add(1)),
+            ("next".to_string(), before.wrapping_add(2)),
+        ]
+    );
+    assert_eq!(client.published()(), before.wrapping_add(3));
+}
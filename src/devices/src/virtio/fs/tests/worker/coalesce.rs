@@ -0,0 +1,139 @@
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::virtio::fs::fuse::ROOT_ID;
+use crate::virtio::fs::FsWriteCoalescing;
+
+use super::helper::{DeviceOptions, TestClient};
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_coalesce_sequential_writes() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut client = coalescing_client(dir.path(), Duration::from_secs(3600));
+    let path = dir.path().join("file");
+
+    let (entry, handle) = client.create(ROOT_ID, "file", 0o644, libc::O_RDWR).unwrap();
+    for (offset, byte) in b"hello".iter().enumerate() {
+        assert_eq!(
+            client
+                .write(entry.nodeid, handle.fh, offset as u64, &[*byte])
+                .unwrap(),
+            1
+        );
+    }
+    assert_eq!(fs::read(&path).unwrap(), b"");
+
+    // The guest sees its writes, which are written to the host first
+    assert_eq!(client.getattr(entry.nodeid).unwrap().attr.size, 5);
+    assert_eq!(fs::read(&path).unwrap(), b"hello");
+
+    // A write that doesn't continue the buffered ones has them written first
+    client.write(entry.nodeid, handle.fh, 5, b" wor").unwrap();
+    client.write(entry.nodeid, handle.fh, 0, b"H").unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"hello wor");
+    assert_eq!(
+        client.read(entry.nodeid, handle.fh, 0, 4096).unwrap(),
+        b"Hello wor"
+    );
+
+    // As does filling the buffer
+    client.write(entry.nodeid, handle.fh, 9, b"ld").unwrap();
+    client
+        .write(entry.nodeid, handle.fh, 11, &[b'!'; 14])
+        .unwrap();
+    assert_eq!(fs::read(&path).unwrap().len(), 25);
+
+    // And flushing the handle
+    client.write(entry.nodeid, handle.fh, 25, b"\n").unwrap();
+    client.flush(entry.nodeid, handle.fh).unwrap();
+    assert_eq!(fs::read(&path).unwrap().len(), 26);
+    client.release(entry.nodeid, handle.fh).unwrap();
+}
+
+#[test]
+fn test_coalesce_after_delay() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut client = coalescing_client(dir.path(), Duration::from_millis(10));
+
+    let (entry, handle) = client.create(ROOT_ID, "file", 0o644, libc::O_RDWR).unwrap();
+    client.write(entry.nodeid, handle.fh, 0, b"log").unwrap();
+    for _ in 0..500 {
+        if fs::read(dir.path().join("file")).unwrap() == b"log" {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    panic!("the buffered write wasn't written after its delay");
+}
+
+#[test]
+fn test_coalesce_bypass() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut client = coalescing_client(dir.path(), Duration::from_secs(3600));
+    let path = dir.path().join("file");
+
+    // Synchronous writes are never buffered
+    let (entry, handle) = client
+        .create(ROOT_ID, "file", 0o644, libc::O_RDWR | libc::O_SYNC)
+        .unwrap();
+    client
+        .write_with_flags(entry.nodeid, handle.fh, 0, b"sync", libc::O_SYNC)
+        .unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"sync");
+    client.release(entry.nodeid, handle.fh).unwrap();
+
+    // Nor are writes larger than the buffer, which follow the buffered ones
+    let handle = client.open(entry.nodeid, libc::O_RDWR).unwrap();
+    client.write(entry.nodeid, handle.fh, 0, b"a").unwrap();
+    client
+        .write(entry.nodeid, handle.fh, 0, &[b'b'; 32])
+        .unwrap();
+    assert_eq!(fs::read(&path).unwrap(), [b'b'; 32]);
+    client.release(entry.nodeid, handle.fh).unwrap();
+}
+
+#[test]
+fn test_coalesce_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("file"), b"data").unwrap();
+    let mut client = coalescing_client(dir.path(), Duration::from_secs(3600));
+
+    // The error of a buffered write is reported by the next flush of the handle, once
+    let entry = client.lookup(ROOT_ID, "file").unwrap();
+    let handle = client.open(entry.nodeid, libc::O_RDONLY).unwrap();
+    assert_eq!(client.write(entry.nodeid, handle.fh, 0, b"x").unwrap(), 1);
+    assert_eq!(
+        client.flush(entry.nodeid, handle.fh).unwrap_err(),
+        libc::EBADF
+    );
+    client.flush(entry.nodeid, handle.fh).unwrap();
+
+    // Or by its release
+    client.write(entry.nodeid, handle.fh, 0, b"x").unwrap();
+    assert_eq!(
+        client.release(entry.nodeid, handle.fh).unwrap_err(),
+        libc::EBADF
+    );
+    assert_eq!(fs::read(dir.path().join("file")).unwrap(), b"data");
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Creates a client of a device coalescing the writes of the guest in buffers of 16 bytes.
+fn coalescing_client(root: &Path, delay: Duration) -> TestClient {
+    let options = DeviceOptions {
+        write_coalescing: Some(FsWriteCoalescing {
+            buffer_size: 16,
+            delay,
+        }),
+        ..Default::default()
+    };
+    TestClient::passthrough_with_options(root, options)
+}
@@ -0,0 +1,158 @@
+use std::fs;
+use std::os::unix::fs::{chown, MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::virtio::fs::fuse::{SetattrIn, SetattrValid, ROOT_ID};
+use crate::virtio::fs::{FsCredentials, FsIdMap, FsIdRange, FsSquashAll, FS_OVERFLOW_ID};
+
+use super::helper::{DeviceOptions, TestClient};
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// A credential policy, with what it makes of the user and group 1000 of the guest and host.
+struct Policy {
+    credentials: Option<FsCredentials>,
+    /// The host ID the requests of guest ID 1000 run as
+    host: u32,
+    /// The guest ID the files created by guest ID 1000 are reported as
+    created: u32,
+    /// The guest ID the files of host ID 1000 are reported as
+    host_1000: u32,
+    /// Whether all the guest IDs run as the same host ID
+    squashed: bool,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+fn policies() -> Vec<Policy> {
+    let range = FsIdRange {
+        guest_start: 0,
+        host_start: 100000,
+        count: 65536,
+    };
+    vec![
+        Policy {
+            credentials: None,
+            host: 1000,
+            created: 1000,
+            host_1000: 1000,
+            squashed: false,
+        },
+        Policy {
+            credentials: Some(Arc::new(FsSquashAll {
+                uid: 2000,
+                gid: 2000,
+            })),
+            host: 2000,
+            created: 2000,
+            host_1000: 1000,
+            squashed: true,
+        },
+        Policy {
+            credentials: Some(Arc::new(FsIdMap {
+                uids: vec![range],
+                gids: vec![range],
+            })),
+            host: 101000,
+            created: 1000,
+            host_1000: FS_OVERFLOW_ID,
+            squashed: false,
+        },
+    ]
+}
+
+fn new_client(root: &Path, policy: &Policy) -> TestClient {
+    fs::set_permissions(root, fs::Permissions::from_mode(0o777)).unwrap();
+    TestClient::passthrough_with_options(
+        root,
+        DeviceOptions {
+            credentials: policy.credentials.clone(),
+            ..Default::default()
+        },
+    )
+}
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_credentials_create() {
+    for policy in policies() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = new_client(dir.path(), &policy);
+
+        // The file is created with the host credentials, and reported with the guest ones
+        (client.uid, client.gid) = (1000, 1000);
+        let (entry, _) = client.create(ROOT_ID, "file", 0o644, libc::O_RDWR).unwrap();
+        let metadata = fs::metadata(dir.path().join("file")).unwrap();
+        assert_eq!((metadata.uid(), metadata.gid()), (policy.host, policy.host));
+        assert_eq!(
+            (entry.attr.uid, entry.attr.gid),
+            (policy.created, policy.created)
+        );
+        let attr = client.getattr(entry.nodeid).unwrap().attr;
+        assert_eq!((attr.uid, attr.gid), (policy.created, policy.created));
+    }
+}
+
+#[test]
+fn test_credentials_chown() {
+    for policy in policies() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("file"), b"").unwrap();
+        chown(dir.path().join("file"), Some(1000), Some(1000)).unwrap();
+        let mut client = new_client(dir.path(), &policy);
+
+        let entry = client.lookup(ROOT_ID, "file").unwrap();
+        assert_eq!(
+            (entry.attr.uid, entry.attr.gid),
+            (policy.host_1000, policy.host_1000)
+        );
+
+        // The owners given by the guest are translated to host ones
+        let setattr_in = SetattrIn {
+            valid: (SetattrValid::UID | SetattrValid::GID).bits(),
+            uid: 1000,
+            gid: 1000,
+            ..Default::default()
+        };
+        let attr = client.setattr(entry.nodeid, setattr_in).unwrap().attr;
+        let metadata = fs::metadata(dir.path().join("file")).unwrap();
+        assert_eq!((metadata.uid(), metadata.gid()), (policy.host, policy.host));
+        assert_eq!((attr.uid, attr.gid), (policy.created, policy.created));
+    }
+}
+
+#[test]
+fn test_credentials_access() {
+    for policy in policies() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, b"").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        chown(&path, Some(policy.host), Some(policy.host)).unwrap();
+        let mut client = new_client(dir.path(), &policy);
+        let entry = client.lookup(ROOT_ID, "file").unwrap();
+
+        // The permissions are checked against the host credentials
+        (client.uid, client.gid) = (1000, 1000);
+        client
+            .access(entry.nodeid, libc::R_OK | libc::W_OK)
+            .unwrap();
+
+        (client.uid, client.gid) = (1001, 1001);
+        let res = client.access(entry.nodeid, libc::R_OK);
+        if policy.squashed {
+            // Any guest user runs as the owner
+            res.unwrap();
+        } else {
+            assert_eq!(res.unwrap_err(), libc::EACCES);
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use std::fs;
+
+use crate::virtio::fs::fuse::ROOT_ID;
+use crate::virtio::fs::FsDegradation;
+
+use super::helper::{DeviceOptions, TestClient};
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_degraded_share() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("file"), b"data").unwrap();
+    let degradation = FsDegradation::default();
+    let options = DeviceOptions {
+        degradation: degradation.clone(),
+        ..Default::default()
+    };
+    let mut client = TestClient::passthrough_with_options(dir.path(), options);
+    let file = client.lookup(ROOT_ID, "file").unwrap().nodeid;
+
+    // A read-only share refuses the requests changing it, and still serves the reads
+    degradation.degrade();
+    assert_eq!(
+        client.mkdir(ROOT_ID, "dir", 0o755).unwrap_err(),
+        libc::EROFS
+    );
+    assert_eq!(
+        client
+            .create(ROOT_ID, "new", 0o644, libc::O_RDWR)
+            .unwrap_err(),
+        libc::EROFS
+    );
+    assert_eq!(client.unlink(ROOT_ID, "file").unwrap_err(), libc::EROFS);
+    assert_eq!(
+        client
+            .open(file, libc::O_WRONLY | libc::O_TRUNC)
+            .unwrap_err(),
+        libc::EROFS
+    );
+    let fh = client.open(file, libc::O_RDONLY).unwrap().fh;
+    assert_eq!(client.read(file, fh, 0, 4).unwrap(), b"data");
+    client.release(file, fh).unwrap();
+    assert!(dir.path().join("file").exists());
+
+    // Once re-enabled, it is writable again
+    assert!(degradation.reenable());
+    client.mkdir(ROOT_ID, "dir", 0o755).unwrap();
+    assert!(dir.path().join("dir").is_dir());
+}
@@ -0,0 +1,114 @@
+use std::fs;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use crate::virtio::fs::fuse::ROOT_ID;
+use crate::virtio::fs::FsDirTemplate;
+
+use super::helper::{DeviceOptions, TestClient};
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_dir_template_mkdir() {
+    let share = tempfile::tempdir().unwrap();
+    let template = tempfile::tempdir().unwrap();
+    fs::create_dir(template.path().join("config")).unwrap();
+    fs::write(template.path().join("config/settings"), b"defaults").unwrap();
+    fs::create_dir(template.path().join("logs")).unwrap();
+    set_mode(&template.path().join("logs"), 0o700);
+    symlink("config/settings", template.path().join("current")).unwrap();
+    set_mode(template.path(), 0o750);
+
+    let mut client = dir_template_client(share.path(), "/workspace", template.path());
+
+    // The directory comes back with the attributes of the template
+    let entry = client.mkdir(ROOT_ID, "workspace", 0o755).unwrap();
+    assert_eq!(entry.attr.mode & 0o7777, 0o750);
+    assert_eq!(entry.attr.nlink, 4);
+
+    let workspace = share.path().join("workspace");
+    assert_eq!(
+        fs::read(workspace.join("config/settings")).unwrap(),
+        b"defaults"
+    );
+    assert_eq!(mode(&workspace.join("logs")), 0o700);
+    assert_eq!(
+        fs::read_link(workspace.join("current")).unwrap(),
+        PathBuf::from("config/settings")
+    );
+
+    // The guest sees the copy
+    let config = client.lookup(entry.nodeid, "config").unwrap();
+    let settings = client.lookup(config.nodeid, "settings").unwrap();
+    assert_eq!(settings.attr.size, 8);
+
+    // Directories outside of the template are left alone
+    let other = client.mkdir(ROOT_ID, "other", 0o755).unwrap();
+    assert_eq!(other.attr.mode & 0o7777, 0o755);
+    assert_eq!(fs::read_dir(share.path().join("other")).unwrap().count(), 0);
+}
+
+#[test]
+fn test_dir_template_create() {
+    let share = tempfile::tempdir().unwrap();
+    fs::create_dir(share.path().join("workspace")).unwrap();
+    let template = tempfile::tempdir().unwrap();
+    fs::write(template.path().join("settings"), b"defaults").unwrap();
+    set_mode(&template.path().join("settings"), 0o600);
+
+    let mut client = dir_template_client(share.path(), "workspace", template.path());
+    let workspace = client.lookup(ROOT_ID, "workspace").unwrap();
+
+    // A file created in a directory that already existed gets the content of its template
+    let (entry, handle) = client
+        .create(workspace.nodeid, "settings", 0o644, libc::O_RDWR)
+        .unwrap();
+    assert_eq!(entry.attr.size, 8);
+    assert_eq!(entry.attr.mode & 0o7777, 0o600);
+    assert_eq!(
+        client.read(entry.nodeid, handle.fh, 0, 4096).unwrap(),
+        b"defaults"
+    );
+    client.release(entry.nodeid, handle.fh).unwrap();
+
+    // Only the first time
+    client.unlink(workspace.nodeid, "settings").unwrap();
+    let (entry, handle) = client
+        .create(workspace.nodeid, "settings", 0o644, libc::O_RDWR)
+        .unwrap();
+    assert_eq!(entry.attr.size, 0);
+    client.release(entry.nodeid, handle.fh).unwrap();
+
+    // Files without a template are created empty
+    let (entry, handle) = client
+        .create(workspace.nodeid, "notes", 0o644, libc::O_RDWR)
+        .unwrap();
+    assert_eq!(entry.attr.size, 0);
+    client.release(entry.nodeid, handle.fh).unwrap();
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+fn dir_template_client(root: &Path, path: &str, template: &Path) -> TestClient {
+    let options = DeviceOptions {
+        dir_templates: vec![FsDirTemplate {
+            path: PathBuf::from(path),
+            template: template.to_path_buf(),
+        }],
+        ..Default::default()
+    };
+    TestClient::passthrough_with_options(root, options)
+}
+
+fn set_mode(path: &Path, mode: u32) {
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+}
+
+fn mode(path: &Path) -> u32 {
+    fs::metadata(path).unwrap().permissions().mode() & 0o7777
+}
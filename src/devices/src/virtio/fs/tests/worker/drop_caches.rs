@@ -0,0 +1,69 @@
+use std::fs;
+use std::time::Duration;
+
+use crate::virtio::fs::fuse::{KERNEL_MINOR_VERSION, KERNEL_VERSION, ROOT_ID};
+use crate::virtio::fs::{overlayfs, FsImplConfig, FsWriteCoalescing};
+
+use super::helper::{DeviceOptions, TestClient};
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+const VIRTIO_IOC_DROP_CACHES: u32 = 0x7605;
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_drop_caches() {
+    let lower = tempfile::tempdir().unwrap();
+    let upper = tempfile::tempdir().unwrap();
+    fs::write(lower.path().join("a"), b"a").unwrap();
+    let fs_config = FsImplConfig::Overlayfs(overlayfs::Config {
+        layers: vec![lower.path().to_path_buf(), upper.path().to_path_buf()],
+        lookup_filters: true,
+        ..Default::default()
+    });
+    let options = DeviceOptions {
+        write_coalescing: Some(FsWriteCoalescing {
+            buffer_size: 16,
+            delay: Duration::from_secs(3600),
+        }),
+        ..Default::default()
+    };
+    let mut client = TestClient::with_options(fs_config, options);
+    client.init(KERNEL_VERSION, KERNEL_MINOR_VERSION).unwrap();
+
+    // The path filter of the lower layer, built by the first lookup, misses the files added later
+    let a = client.lookup(ROOT_ID, "a").unwrap();
+    fs::write(lower.path().join("b"), b"b").unwrap();
+    assert_eq!(client.lookup(ROOT_ID, "b").unwrap_err(), libc::ENOENT);
+    let (entry, handle) = client.create(ROOT_ID, "c", 0o644, libc::O_RDWR).unwrap();
+    client.write(entry.nodeid, handle.fh, 0, b"c").unwrap();
+    assert_eq!(fs::read(upper.path().join("c")).unwrap(), b"");
+
+    // Only guest root may drop the caches, on the root of the share
+    assert_eq!(
+        client
+            .ioctl(a.nodeid, 0, VIRTIO_IOC_DROP_CACHES)
+            .unwrap_err(),
+        libc::ENOTTY
+    );
+    client.uid = 1000;
+    assert_eq!(
+        client
+            .ioctl(ROOT_ID, 0, VIRTIO_IOC_DROP_CACHES)
+            .unwrap_err(),
+        libc::EPERM
+    );
+    client.uid = 0;
+
+    assert_eq!(fs::read(upper.path().join("c")).unwrap(), b"");
+
+    // The buffered writes reach the host, and the new lower file shows up
+    client.ioctl(ROOT_ID, 0, VIRTIO_IOC_DROP_CACHES).unwrap();
+    assert_eq!(fs::read(upper.path().join("c")).unwrap(), b"c");
+    client.lookup(ROOT_ID, "b").unwrap();
+}
@@ -0,0 +1,98 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::virtio::fs::fuse::{Opcode, KERNEL_MINOR_VERSION, KERNEL_VERSION, ROOT_ID};
+use crate::virtio::fs::{overlayfs, FsHook, FsHookPoint, FsHooks, FsImplConfig};
+
+use super::helper::TestClient;
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_hooks() {
+    let lower = tempfile::tempdir().unwrap();
+    let upper = tempfile::tempdir().unwrap();
+    fs::create_dir(lower.path().join("dir")).unwrap();
+    fs::write(lower.path().join("dir/secret"), b"secret").unwrap();
+    fs::write(lower.path().join("dir/public"), b"public").unwrap();
+    fs::write(lower.path().join("keep"), b"keep").unwrap();
+
+    // Vetoes the copy-up and the unlink of the entries named after it
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let hooks = FsHooks::default();
+    for point in [
+        FsHookPoint::PreMount,
+        FsHookPoint::PostUnmount,
+        FsHookPoint::PreCopyUp,
+        FsHookPoint::PreUnlink,
+    ] {
+        let calls = calls.clone();
+        hooks.add(FsHook {
+            point,
+            callback: Arc::new(move |event| {
+                calls
+                    .lock()
+                    .unwrap()
+                    .push((event.point, event.path.to_path_buf()));
+                match event.path.file_name() {
+                    Some(name) if name == "secret" || name == "keep" => {
+                        Err(io::Error::from_raw_os_error(libc::EACCES))
+                    }
+                    _ => Ok(()),
+                }
+            }),
+        });
+    }
+    let fs_config = FsImplConfig::Overlayfs(overlayfs::Config {
+        layers: vec![lower.path().to_path_buf(), upper.path().to_path_buf()],
+        hooks: Some(hooks),
+        ..Default::default()
+    });
+    let mut client = TestClient::new(fs_config);
+    client.init(KERNEL_VERSION, KERNEL_MINOR_VERSION).unwrap();
+    let take_calls = || std::mem::take(&mut *calls.lock().unwrap());
+    assert_eq!(take_calls(), [(FsHookPoint::PreMount, PathBuf::new())]);
+
+    // The copy-up of the directory goes through, but not the one of the file in it
+    let dir = client.lookup(ROOT_ID, "dir").unwrap();
+    let secret = client.lookup(dir.nodeid, "secret").unwrap();
+    assert_eq!(
+        client.open(secret.nodeid, libc::O_RDWR).unwrap_err(),
+        libc::EACCES
+    );
+    assert_eq!(
+        take_calls(),
+        [
+            (FsHookPoint::PreCopyUp, PathBuf::from("dir")),
+            (FsHookPoint::PreCopyUp, PathBuf::from("dir/secret")),
+        ]
+    );
+    assert!(upper.path().join("dir").is_dir());
+    assert!(!upper.path().join("dir/secret").exists());
+
+    let public = client.lookup(dir.nodeid, "public").unwrap();
+    client.open(public.nodeid, libc::O_RDWR).unwrap();
+    assert_eq!(
+        take_calls(),
+        [(FsHookPoint::PreCopyUp, PathBuf::from("dir/public"))]
+    );
+
+    // A vetoed unlink leaves the file alone
+    assert_eq!(client.unlink(ROOT_ID, "keep").unwrap_err(), libc::EACCES);
+    client.lookup(ROOT_ID, "keep").unwrap();
+    client.unlink(dir.nodeid, "public").unwrap();
+    assert_eq!(
+        take_calls(),
+        [
+            (FsHookPoint::PreUnlink, PathBuf::from("keep")),
+            (FsHookPoint::PreUnlink, PathBuf::from("dir/public")),
+        ]
+    );
+
+    assert!(client.send(Opcode::Destroy, 0, &[], 0).is_none());
+    assert_eq!(take_calls(), [(FsHookPoint::PostUnmount, PathBuf::new())]);
+}
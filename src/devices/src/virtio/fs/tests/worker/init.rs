@@ -0,0 +1,78 @@
+use crate::virtio::fs::fuse::{Opcode, KERNEL_MINOR_VERSION, KERNEL_VERSION};
+use crate::virtio::fs::server::MAX_BUFFER_SIZE;
+use crate::virtio::fs::FsImplConfig;
+use crate::virtio::passthrough::Config;
+
+use super::helper::TestClient;
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+fn new_client(dir: &tempfile::TempDir) -> TestClient {
+    TestClient::new(FsImplConfig::Passthrough(Config {
+        root_dir: dir.path().to_str().unwrap().to_string(),
+        ..Default::default()
+    }))
+}
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_init() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut client = new_client(&dir);
+
+    let out = client.init(KERNEL_VERSION, KERNEL_MINOR_VERSION).unwrap();
+    assert_eq!(out.major, KERNEL_VERSION);
+    assert_eq!(out.minor, KERNEL_MINOR_VERSION);
+    assert_eq!(out.max_readahead, 0x2_0000);
+    assert_eq!(out.max_write, MAX_BUFFER_SIZE);
+    assert_eq!((out.max_background, out.congestion_threshold), (64, 48));
+
+    // Nothing the client didn't offer is enabled
+    assert_eq!(out.flags, 0);
+    assert_eq!(out.flags2, 0);
+}
+
+#[test]
+fn test_init_version_negotiation() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut client = new_client(&dir);
+
+    // Older protocols are refused
+    assert_eq!(
+        client.init(KERNEL_VERSION - 1, 0).unwrap_err(),
+        libc::EPROTO
+    );
+    assert_eq!(
+        client
+            .init(KERNEL_VERSION, KERNEL_MINOR_VERSION - 1)
+            .unwrap_err(),
+        libc::EPROTO
+    );
+
+    // A newer major version is answered with the supported one, for the client to retry with it
+    let out = client.init(KERNEL_VERSION + 1, 0).unwrap();
+    assert_eq!(
+        (out.major, out.minor),
+        (KERNEL_VERSION, KERNEL_MINOR_VERSION)
+    );
+    assert_eq!(out.max_write, 0);
+
+    client.init(KERNEL_VERSION, KERNEL_MINOR_VERSION).unwrap();
+}
+
+#[test]
+fn test_unknown_opcode() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut client = TestClient::passthrough(dir.path());
+
+    // The mappings are only handled by devices with a shared memory region
+    assert_eq!(
+        client.request(Opcode::SetupMapping, 1, &[&[0; 40]], 0),
+        Err(libc::ENOSYS)
+    );
+}
@@ -0,0 +1,108 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+
+use crate::virtio::fs::fuse::ROOT_ID;
+
+use super::helper::{DeviceOptions, TestClient};
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Sends the request `kind` with `payload` and returns the type and the payload of the answer.
+fn request(stream: &mut UnixStream, kind: u32, payload: &[u8]) -> (u32, Vec<u8>) {
+    let mut message = kind.to_le_bytes().to_vec();
+    message.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    message.extend_from_slice(payload);
+    stream.write_all(&message).unwrap();
+
+    let mut header = [0u8; 8];
+    stream.read_exact(&mut header).unwrap();
+    let mut reply = vec![0u8; u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize];
+    stream.read_exact(&mut reply).unwrap();
+    (u32::from_le_bytes(header[0..4].try_into().unwrap()), reply)
+}
+
+fn read_request(path: &str, offset: u64, length: u32) -> Vec<u8> {
+    let mut payload = offset.to_le_bytes().to_vec();
+    payload.extend_from_slice(&length.to_le_bytes());
+    payload.extend_from_slice(path.as_bytes());
+    payload
+}
+
+/// Returns the names of an `ENTRIES` payload.
+fn entry_names(mut entries: &[u8]) -> Vec<String> {
+    let mut names = Vec::new();
+    while !entries.is_empty() {
+        let end = entries[4..].iter().position(|b| *b == 0).unwrap() + 4;
+        names.push(String::from_utf8(entries[4..end].to_vec()).unwrap());
+        entries = &entries[end + 1..];
+    }
+    names.sort();
+    names
+}
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_inspect_overlay() {
+    let lower = tempfile::tempdir().unwrap();
+    let upper = tempfile::tempdir().unwrap();
+    fs::write(lower.path().join("kept"), b"lower").unwrap();
+    fs::write(lower.path().join("removed"), b"lower").unwrap();
+    let socket_dir = tempfile::tempdir().unwrap();
+    let socket_path = socket_dir.path().join("inspect.sock");
+
+    let mut client = TestClient::overlay_with_options(
+        vec![lower.path().to_path_buf(), upper.path().to_path_buf()],
+        DeviceOptions {
+            inspect_socket: Some(socket_path.clone()),
+            ..Default::default()
+        },
+    );
+
+    // The guest changes the merged view
+    client.lookup(ROOT_ID, "removed").unwrap();
+    client.unlink(ROOT_ID, "removed").unwrap();
+    let (entry, handle) = client.create(ROOT_ID, "new", 0o644, libc::O_RDWR).unwrap();
+    client.write(entry.nodeid, handle.fh, 0, b"guest").unwrap();
+    client.release(entry.nodeid, handle.fh).unwrap();
+
+    // The host sees it as the guest does
+    let mut stream = UnixStream::connect(&socket_path).unwrap();
+    let (kind, entries) = request(&mut stream, 2, b"/");
+    assert_eq!(kind, 5);
+    assert!(entry_names(&entries).contains(&"kept".to_string()));
+    assert!(entry_names(&entries).contains(&"new".to_string()));
+    assert!(!entry_names(&entries).contains(&"removed".to_string()));
+
+    let (kind, attr) = request(&mut stream, 1, b"new");
+    assert_eq!(kind, 4);
+    let mode = u32::from_le_bytes(attr[0..4].try_into().unwrap());
+    let size = u64::from_le_bytes(attr[16..24].try_into().unwrap());
+    assert_eq!((mode & libc::S_IFMT, size), (libc::S_IFREG, 5));
+
+    assert_eq!(
+        request(&mut stream, 3, &read_request("new", 0, 100)),
+        (6, b"guest".to_vec())
+    );
+    assert_eq!(
+        request(&mut stream, 3, &read_request("./kept", 1, 3)),
+        (6, b"owe".to_vec())
+    );
+
+    // Missing entries and paths going up fail
+    let enoent = (7, (libc::ENOENT as u32).to_le_bytes().to_vec());
+    let einval = (7, (libc::EINVAL as u32).to_le_bytes().to_vec());
+    assert_eq!(request(&mut stream, 1, b"removed"), enoent);
+    assert_eq!(request(&mut stream, 1, b"../etc"), einval);
+    assert_eq!(request(&mut stream, 3, &read_request("/", 0, 1)), einval);
+    assert_eq!(request(&mut stream, 9, b""), einval);
+
+    // Nothing was written by the host
+    assert!(!upper.path().join(".wh.kept").exists());
+    assert_eq!(fs::read(lower.path().join("kept")).unwrap(), b"lower");
+}
@@ -0,0 +1,297 @@
+use std::fs;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use vm_memory::ByteValued;
+
+use crate::virtio::fs::fuse::{
+    IoctlIn, IoctlOut, Opcode, OpenOptions, WriteIn, WriteOut, ROOT_ID, WRITE_CACHE,
+};
+
+use super::helper::TestClient;
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_create_write_read() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut client = TestClient::passthrough(dir.path());
+
+    let (entry, handle) = client.create(ROOT_ID, "file", 0o644, libc::O_RDWR).unwrap();
+    assert_eq!(entry.attr.mode & libc::S_IFMT, libc::S_IFREG);
+    assert_eq!(
+        client.write(entry.nodeid, handle.fh, 0, b"hello").unwrap(),
+        5
+    );
+    assert_eq!(
+        client.write(entry.nodeid, handle.fh, 5, b" world").unwrap(),
+        6
+    );
+
+    assert_eq!(
+        client.read(entry.nodeid, handle.fh, 0, 4096).unwrap(),
+        b"hello world"
+    );
+    assert_eq!(client.read(entry.nodeid, handle.fh, 6, 3).unwrap(), b"wor");
+    assert_eq!(client.read(entry.nodeid, handle.fh, 100, 10).unwrap(), b"");
+    client.release(entry.nodeid, handle.fh).unwrap();
+
+    assert_eq!(fs::read(dir.path().join("file")).unwrap(), b"hello world");
+    assert_eq!(client.getattr(entry.nodeid).unwrap().attr.size, 11);
+}
+
+#[test]
+fn test_read_large() {
+    let dir = tempfile::tempdir().unwrap();
+    let data: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+    fs::write(dir.path().join("file"), &data).unwrap();
+    let mut client = TestClient::passthrough(dir.path());
+
+    let entry = client.lookup(ROOT_ID, "file").unwrap();
+    let handle = client.open(entry.nodeid, libc::O_RDONLY).unwrap();
+    assert_eq!(
+        client.read(entry.nodeid, handle.fh, 0, 1 << 20).unwrap(),
+        data
+    );
+
+    // Reads larger than the buffers advertised in INIT are refused
+    assert_eq!(
+        client
+            .read(entry.nodeid, handle.fh, 0, (1 << 20) + 1)
+            .unwrap_err(),
+        libc::ENOMEM
+    );
+    client.release(entry.nodeid, handle.fh).unwrap();
+}
+
+#[test]
+fn test_seek_holes() {
+    const SIZE: u64 = 4 << 20;
+    let write_sparse = |path: &Path| {
+        let file = fs::File::create(path).unwrap();
+        file.write_all_at(&[1; 4096], 0).unwrap();
+        file.write_all_at(&[1; 4096], SIZE / 2).unwrap();
+        file.set_len(SIZE).unwrap();
+    };
+    let dir = tempfile::tempdir().unwrap();
+    write_sparse(&dir.path().join("file"));
+    let lower = tempfile::tempdir().unwrap();
+    let upper = tempfile::tempdir().unwrap();
+    write_sparse(&lower.path().join("file"));
+    let layers = vec![lower.path().to_path_buf(), upper.path().to_path_buf()];
+
+    let clients = [
+        TestClient::passthrough(dir.path()),
+        TestClient::overlay_with_options(layers, Default::default()),
+    ];
+    for mut client in clients {
+        let entry = client.lookup(ROOT_ID, "file").unwrap();
+        // The file of the lower layer, then its copy in the top layer
+        for flags in [libc::O_RDONLY, libc::O_RDWR] {
+            let handle = client.open(entry.nodeid, flags).unwrap();
+            let seek = |client: &mut TestClient, offset, whence| {
+                client.lseek(entry.nodeid, handle.fh, offset, whence)
+            };
+
+            assert_eq!(seek(&mut client, 0, libc::SEEK_DATA), Ok(0));
+            let hole = seek(&mut client, 0, libc::SEEK_HOLE).unwrap();
+            assert!((4096..SIZE / 2).contains(&hole), "hole at {hole}");
+            assert_eq!(seek(&mut client, hole, libc::SEEK_DATA), Ok(SIZE / 2));
+            assert_eq!(
+                seek(&mut client, SIZE / 2 + 4096, libc::SEEK_DATA),
+                Err(libc::ENXIO)
+            );
+            assert_eq!(seek(&mut client, SIZE, libc::SEEK_HOLE), Err(libc::ENXIO));
+            assert_eq!(seek(&mut client, 10, libc::SEEK_SET), Ok(10));
+            assert_eq!(seek(&mut client, 0, libc::SEEK_END), Ok(SIZE));
+            client.release(entry.nodeid, handle.fh).unwrap();
+        }
+    }
+}
+
+#[test]
+fn test_copy_file_range() {
+    let lower = tempfile::tempdir().unwrap();
+    let upper = tempfile::tempdir().unwrap();
+    fs::write(lower.path().join("src"), b"0123456789").unwrap();
+    fs::write(lower.path().join("dst"), b"abcdefghij").unwrap();
+    let layers = vec![lower.path().to_path_buf(), upper.path().to_path_buf()];
+    let mut client = TestClient::overlay_with_options(layers, Default::default());
+
+    let src = client.lookup(ROOT_ID, "src").unwrap();
+    let dst = client.lookup(ROOT_ID, "dst").unwrap();
+    let src_handle = client.open(src.nodeid, libc::O_RDONLY).unwrap();
+    let dst_handle = client.open(dst.nodeid, libc::O_RDWR).unwrap();
+
+    let copied = client
+        .copy_file_range(
+            src.nodeid,
+            src_handle.fh,
+            2,
+            dst.nodeid,
+            dst_handle.fh,
+            4,
+            5,
+        )
+        .unwrap();
+    assert_eq!(copied, 5);
+    assert_eq!(
+        client.read(dst.nodeid, dst_handle.fh, 0, 16).unwrap(),
+        b"abcd23456j"
+    );
+
+    // Only the destination is copied up
+    assert_eq!(fs::read(upper.path().join("dst")).unwrap(), b"abcd23456j");
+    assert!(!upper.path().join("src").exists());
+    assert_eq!(fs::read(lower.path().join("dst")).unwrap(), b"abcdefghij");
+}
+
+#[test]
+fn test_tmpfile() {
+    let lower = tempfile::tempdir().unwrap();
+    let upper = tempfile::tempdir().unwrap();
+    fs::create_dir(lower.path().join("dir")).unwrap();
+    let layers = vec![lower.path().to_path_buf(), upper.path().to_path_buf()];
+
+    let passthrough_dir = tempfile::tempdir().unwrap();
+    fs::create_dir(passthrough_dir.path().join("dir")).unwrap();
+    let clients = [
+        (
+            TestClient::passthrough(passthrough_dir.path()),
+            passthrough_dir.path().to_path_buf(),
+        ),
+        (
+            TestClient::overlay_with_options(layers, Default::default()),
+            upper.path().to_path_buf(),
+        ),
+    ];
+
+    for (mut client, top) in clients {
+        let dir = client.lookup(ROOT_ID, "dir").unwrap();
+        let (entry, handle) = client.tmpfile(dir.nodeid, 0o600, libc::O_RDWR).unwrap();
+        assert_eq!(entry.attr.mode & libc::S_IFMT, libc::S_IFREG);
+        assert_eq!(entry.attr.nlink, 0);
+        client.write(entry.nodeid, handle.fh, 0, b"hello").unwrap();
+
+        // The file has no name until it is linked
+        assert_eq!(fs::read_dir(top.join("dir")).unwrap().count(), 0);
+        let linked = client.link(entry.nodeid, ROOT_ID, "file").unwrap();
+        assert_eq!(linked.nodeid, entry.nodeid);
+        assert_eq!(linked.attr.nlink, 1);
+        client.release(entry.nodeid, handle.fh).unwrap();
+
+        assert_eq!(client.lookup(ROOT_ID, "file").unwrap().nodeid, entry.nodeid);
+        let handle = client.open(entry.nodeid, libc::O_RDONLY).unwrap();
+        assert_eq!(
+            client.read(entry.nodeid, handle.fh, 0, 10).unwrap(),
+            b"hello"
+        );
+        client.release(entry.nodeid, handle.fh).unwrap();
+        assert_eq!(fs::read(top.join("file")).unwrap(), b"hello");
+
+        // An anonymous file released without a name is gone
+        let (entry, handle) = client.tmpfile(dir.nodeid, 0o600, libc::O_RDWR).unwrap();
+        client.release(entry.nodeid, handle.fh).unwrap();
+        assert_eq!(fs::read_dir(top.join("dir")).unwrap().count(), 0);
+    }
+}
+
+#[test]
+fn test_io_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("file"), b"data").unwrap();
+    fs::create_dir(dir.path().join("dir")).unwrap();
+    let mut client = TestClient::passthrough(dir.path());
+
+    let entry = client.lookup(ROOT_ID, "file").unwrap();
+    let handle = client.open(entry.nodeid, libc::O_RDONLY).unwrap();
+    assert!(!OpenOptions::from_bits_truncate(handle.open_flags).contains(OpenOptions::DIRECT_IO));
+
+    // Writing through a read-only handle
+    assert_eq!(
+        client.write(entry.nodeid, handle.fh, 0, b"x").unwrap_err(),
+        libc::EBADF
+    );
+    client.release(entry.nodeid, handle.fh).unwrap();
+
+    // Using a released handle
+    assert_eq!(
+        client.read(entry.nodeid, handle.fh, 0, 10).unwrap_err(),
+        libc::EBADF
+    );
+
+    // Creating an existing file exclusively
+    assert_eq!(
+        client
+            .create(ROOT_ID, "file", 0o644, libc::O_RDWR | libc::O_EXCL)
+            .unwrap_err(),
+        libc::EEXIST
+    );
+
+    // Opening a directory for writing
+    let dir_entry = client.lookup(ROOT_ID, "dir").unwrap();
+    assert_eq!(
+        client.open(dir_entry.nodeid, libc::O_RDWR).unwrap_err(),
+        libc::EISDIR
+    );
+
+    // An ioctl claiming more input than the request holds
+    let ioctl_in = IoctlIn {
+        in_size: u32::MAX,
+        ..Default::default()
+    };
+    assert_eq!(
+        client
+            .request(
+                Opcode::Ioctl,
+                ROOT_ID,
+                &[ioctl_in.as_slice()],
+                size_of::<IoctlOut>() as u32,
+            )
+            .unwrap_err(),
+        libc::EINVAL
+    );
+}
+
+#[test]
+fn test_delayed_write_error() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("file"), b"data").unwrap();
+    let mut client = TestClient::passthrough(dir.path());
+
+    let entry = client.lookup(ROOT_ID, "file").unwrap();
+    let handle = client.open(entry.nodeid, libc::O_RDONLY).unwrap();
+    let delayed_write = |client: &mut TestClient| {
+        let write_in = WriteIn {
+            fh: handle.fh,
+            size: 1,
+            write_flags: WRITE_CACHE,
+            ..Default::default()
+        };
+        client.request_obj::<WriteOut>(Opcode::Write, entry.nodeid, &[write_in.as_slice(), b"x"])
+    };
+
+    // A regular write fails right away, and isn't reported again
+    assert_eq!(
+        client.write(entry.nodeid, handle.fh, 0, b"x").unwrap_err(),
+        libc::EBADF
+    );
+    client.flush(entry.nodeid, handle.fh).unwrap();
+
+    // The error of a delayed write is reported by the next flush, exactly once
+    assert_eq!(delayed_write(&mut client).unwrap_err(), libc::EBADF);
+    assert_eq!(
+        client.flush(entry.nodeid, handle.fh).unwrap_err(),
+        libc::EBADF
+    );
+    client.flush(entry.nodeid, handle.fh).unwrap();
+
+    // Or by the release of the handle if no flush picked it up
+    assert_eq!(delayed_write(&mut client).unwrap_err(), libc::EBADF);
+    assert_eq!(
+        client.release(entry.nodeid, handle.fh).unwrap_err(),
+        libc::EBADF
+    );
+}
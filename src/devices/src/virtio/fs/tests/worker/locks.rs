@@ -0,0 +1,105 @@
+use std::fs;
+
+use crate::virtio::fs::fuse::{FileLock, ROOT_ID};
+
+use super::helper::TestClient;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The end of the locks that reach the end of the file.
+const OFFSET_MAX: u64 = i64::MAX as u64;
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_posix_locks() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("file"), b"data").unwrap();
+    let lower = tempfile::tempdir().unwrap();
+    let upper = tempfile::tempdir().unwrap();
+    fs::write(upper.path().join("file"), b"data").unwrap();
+    let layers = vec![lower.path().to_path_buf(), upper.path().to_path_buf()];
+
+    let clients = [
+        TestClient::passthrough(dir.path()),
+        TestClient::overlay_with_options(layers, Default::default()),
+    ];
+    for mut client in clients {
+        let entry = client.lookup(ROOT_ID, "file").unwrap();
+        let first = client.open(entry.nodeid, libc::O_RDWR).unwrap();
+        let second = client.open(entry.nodeid, libc::O_RDWR).unwrap();
+        let lock = |type_: i32, start: u64, end: u64| FileLock {
+            start,
+            end,
+            type_: type_ as u32,
+            pid: 0,
+        };
+
+        // The lock of the first owner conflicts with the ones of the second one only
+        client
+            .setlk(entry.nodeid, first.fh, 1, lock(libc::F_WRLCK, 0, 9), false)
+            .unwrap();
+        let conflict = client
+            .getlk(
+                entry.nodeid,
+                second.fh,
+                2,
+                lock(libc::F_RDLCK, 5, OFFSET_MAX),
+            )
+            .unwrap();
+        assert_eq!(conflict.type_, libc::F_WRLCK as u32);
+        assert_eq!((conflict.start, conflict.end), (0, 9));
+        let free = client
+            .getlk(
+                entry.nodeid,
+                first.fh,
+                1,
+                lock(libc::F_WRLCK, 0, OFFSET_MAX),
+            )
+            .unwrap();
+        assert_eq!(free.type_, libc::F_UNLCK as u32);
+        assert_eq!(
+            client.setlk(entry.nodeid, second.fh, 2, lock(libc::F_RDLCK, 5, 5), false),
+            Err(libc::EAGAIN)
+        );
+        // A lock waited for is taken once the other one is released, the requests sent
+        // meanwhile being handled
+        client.submit_setlkw(entry.nodeid, second.fh, 2, lock(libc::F_RDLCK, 5, 5));
+        assert!(client.take_waiting_reply().is_none());
+        client
+            .setlk(
+                entry.nodeid,
+                second.fh,
+                2,
+                lock(libc::F_RDLCK, 10, OFFSET_MAX),
+                true,
+            )
+            .unwrap();
+
+        // Releasing part of a lock frees that part only
+        client
+            .setlk(entry.nodeid, first.fh, 1, lock(libc::F_UNLCK, 0, 4), false)
+            .unwrap();
+        client
+            .setlk(entry.nodeid, second.fh, 2, lock(libc::F_RDLCK, 0, 4), false)
+            .unwrap();
+        assert_eq!(
+            client.setlk(entry.nodeid, second.fh, 2, lock(libc::F_RDLCK, 5, 9), false),
+            Err(libc::EAGAIN)
+        );
+
+        // Closing any file of the owner releases all its locks
+        client.flush_owner(entry.nodeid, second.fh, 1).unwrap();
+        assert_eq!(client.take_waiting_reply().unwrap().error, 0);
+        client
+            .setlk(entry.nodeid, second.fh, 2, lock(libc::F_WRLCK, 5, 9), false)
+            .unwrap();
+
+        client.release(entry.nodeid, first.fh).unwrap();
+        client.release(entry.nodeid, second.fh).unwrap();
+    }
+}
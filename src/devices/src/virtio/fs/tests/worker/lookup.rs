@@ -0,0 +1,102 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+
+use crate::virtio::fs::fuse::{KERNEL_MINOR_VERSION, KERNEL_VERSION, ROOT_ID};
+use crate::virtio::fs::{passthrough, FsImplConfig};
+
+use super::helper::TestClient;
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_lookup() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir(dir.path().join("dir")).unwrap();
+    fs::write(dir.path().join("dir/file"), b"hello").unwrap();
+    let mut client = TestClient::passthrough(dir.path());
+
+    let dir_entry = client.lookup(ROOT_ID, "dir").unwrap();
+    assert_eq!(dir_entry.attr.mode & libc::S_IFMT, libc::S_IFDIR);
+
+    let file_entry = client.lookup(dir_entry.nodeid, "file").unwrap();
+    let metadata = fs::metadata(dir.path().join("dir/file")).unwrap();
+    assert_eq!(file_entry.attr.mode & libc::S_IFMT, libc::S_IFREG);
+    assert_eq!(file_entry.attr.size, 5);
+    assert_eq!(file_entry.attr.ino, metadata.ino());
+
+    // Looking the same file up again returns the same inode
+    let again = client.lookup(dir_entry.nodeid, "file").unwrap();
+    assert_eq!(again.nodeid, file_entry.nodeid);
+
+    let attr_out = client.getattr(file_entry.nodeid).unwrap();
+    assert_eq!(attr_out.attr.size, 5);
+    assert_eq!(attr_out.attr.ino, metadata.ino());
+}
+
+#[test]
+fn test_lookup_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("file"), b"").unwrap();
+    let mut client = TestClient::passthrough(dir.path());
+
+    assert_eq!(client.lookup(ROOT_ID, "missing").unwrap_err(), libc::ENOENT);
+
+    let file = client.lookup(ROOT_ID, "file").unwrap();
+    assert_eq!(
+        client.lookup(file.nodeid, "child").unwrap_err(),
+        libc::ENOTDIR
+    );
+
+    // Unknown inodes are rejected
+    assert_eq!(client.getattr(0xdead).unwrap_err(), libc::EBADF);
+}
+
+#[test]
+fn test_lookup_normalized_names() {
+    let nfc = "caf\u{e9}";
+    let nfd = "cafe\u{301}";
+    let dir = tempfile::tempdir().unwrap();
+    let host_ino = |name| fs::metadata(dir.path().join(name)).unwrap().ino();
+    fs::write(dir.path().join(nfd), b"nfd").unwrap();
+
+    // Without normalization, the names are matched byte for byte
+    let mut client = TestClient::passthrough(dir.path());
+    assert_eq!(client.lookup(ROOT_ID, nfc).unwrap_err(), libc::ENOENT);
+
+    // With it, a name falls back to the entry of the same text
+    let mut client = TestClient::new(FsImplConfig::Passthrough(passthrough::Config {
+        root_dir: dir.path().to_str().unwrap().to_string(),
+        normalize_names: true,
+        ..Default::default()
+    }));
+    client.init(KERNEL_VERSION, KERNEL_MINOR_VERSION).unwrap();
+    let entry = client.lookup(ROOT_ID, nfc).unwrap();
+    assert_eq!(entry.attr.ino, host_ino(nfd));
+    assert_eq!(client.lookup(ROOT_ID, "cafe").unwrap_err(), libc::ENOENT);
+
+    // The exact name is preferred once it exists
+    fs::write(dir.path().join(nfc), b"nfc").unwrap();
+    let exact = client.lookup(ROOT_ID, nfc).unwrap();
+    assert_eq!(exact.attr.ino, host_ino(nfc));
+
+    // The probe of the host volume left nothing behind
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+}
+
+#[test]
+fn test_forget() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("file"), b"").unwrap();
+    let mut client = TestClient::passthrough(dir.path());
+
+    let entry = client.lookup(ROOT_ID, "file").unwrap();
+    client.getattr(entry.nodeid).unwrap();
+
+    // Forget has no reply, and the inode is gone once all its lookups are forgotten
+    client.forget(entry.nodeid, 1);
+    assert_eq!(client.getattr(entry.nodeid).unwrap_err(), libc::EBADF);
+
+    client.lookup(ROOT_ID, "file").unwrap();
+}
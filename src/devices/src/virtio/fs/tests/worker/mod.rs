@@ -0,0 +1,1139 @@
+#[cfg(test)]
+mod init;
+
+#[cfg(test)]
+mod batch;
+
+#[cfg(test)]
+mod coalesce;
+
+#[cfg(test)]
+mod credentials;
+
+#[cfg(test)]
+mod degrade;
+
+#[cfg(test)]
+mod dir_template;
+
+#[cfg(test)]
+mod drop_caches;
+
+#[cfg(test)]
+mod hooks;
+
+#[cfg(test)]
+mod inspect;
+
+#[cfg(test)]
+mod io;
+
+#[cfg(test)]
+mod locks;
+
+#[cfg(test)]
+mod lookup;
+
+#[cfg(test)]
+mod mode_policy;
+
+#[cfg(test)]
+mod ownership;
+
+#[cfg(test)]
+mod p9;
+
+#[cfg(test)]
+mod pause;
+
+#[cfg(test)]
+mod readdir;
+
+#[cfg(test)]
+mod snapshot;
+
+#[cfg(test)]
+mod timeouts;
+
+#[cfg(test)]
+mod trace;
+
+#[cfg(test)]
+mod unsupported;
+
+#[cfg(test)]
+mod virtual_file;
+
+//--------------------------------------------------------------------------------------------------
+// Modules: Helper
+//--------------------------------------------------------------------------------------------------
+
+/// An in-process FUSE client, which exchanges requests with the device through an in-memory
+/// request queue in the same way the guest kernel does, without booting a VM. Each request is
+/// placed in the queue and processed synchronously by the worker, so that the tests are
+/// deterministic.
+#[cfg(test)]
+mod helper {
+    use std::ffi::CString;
+    use std::mem::size_of;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicI32, AtomicUsize};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use utils::eventfd::{EventFd, EFD_NONBLOCK};
+    use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryMmap};
+
+    use crate::virtio::fs::defs::NOTIFY_INDEX;
+    use crate::virtio::fs::fuse::*;
+    use crate::virtio::fs::server::{BUFFER_HEADER_SIZE, MAX_BUFFER_SIZE};
+    use crate::virtio::fs::worker::FsWorker;
+    use crate::virtio::fs::{overlayfs, passthrough};
+    use crate::virtio::fs::{
+        FsCacheTimeouts, FsCredentials, FsDegradation, FsDirTemplate, FsImplConfig, FsInodeNumbers,
+        FsLeases, FsModePolicy, FsPause, FsPauseOptions, FsProtocol, FsTracer, FsUnsupportedStats,
+        FsVirtualFile, FsWriteCoalescing,
+    };
+    use crate::virtio::queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+    use crate::virtio::Queue;
+
+    //--------------------------------------------------------------------------------------------------
+    // Constants
+    //--------------------------------------------------------------------------------------------------
+
+    const QUEUE_SIZE: u16 = 16;
+    const MEM_SIZE: usize = 0x40_0000;
+
+    const DESC_TABLE_ADDR: u64 = 0x0;
+    const AVAIL_RING_ADDR: u64 = 0x1000;
+    const USED_RING_ADDR: u64 = 0x2000;
+
+    /// Where the request is placed, in the first descriptor of the chain.
+    const REQUEST_ADDR: u64 = 0x1_0000;
+
+    /// Where the reply is written, in the next descriptors of the chain.
+    const REPLY_ADDR: u64 = 0x20_0000;
+    const MAX_REPLY_SIZE: u32 = MAX_BUFFER_SIZE + BUFFER_HEADER_SIZE;
+
+    /// Where the requests sent together are placed, each followed by its reply.
+    const BATCH_ADDR: u64 = 0x38_0000;
+    const BATCH_SLOT_SIZE: u32 = 0x1000;
+
+    /// Where the request left waiting while others are sent is placed, as the chain starting with
+    /// the descriptor `WAITING_HEAD`, followed by its reply.
+    const WAITING_HEAD: u16 = QUEUE_SIZE - 3;
+    const WAITING_ADDR: u64 = 0x3c_0000;
+
+    /// The rings of the notification queue, and the buffers provided in it.
+    const NOTIFY_DESC_TABLE_ADDR: u64 = 0x3000;
+    const NOTIFY_AVAIL_RING_ADDR: u64 = 0x4000;
+    const NOTIFY_USED_RING_ADDR: u64 = 0x5000;
+    const NOTIFY_BUFFER_ADDR: u64 = 0x30_0000;
+    const NOTIFY_BUFFER_SIZE: u32 = 64;
+
+    //--------------------------------------------------------------------------------------------------
+    // Types
+    //--------------------------------------------------------------------------------------------------
+
+    pub(super) struct TestClient {
+        worker: FsWorker,
+        mem: GuestMemoryMmap,
+        unique: u64,
+        avail_idx: u16,
+        /// The indexes of the notification queue, in its available and used rings
+        notify_avail_idx: u16,
+        notify_used_idx: u16,
+        /// The unique of the request left waiting, if any, and the address of its reply
+        waiting: Option<(u64, u64)>,
+        /// The guest credentials the requests are sent with
+        pub(super) uid: u32,
+        pub(super) gid: u32,
+    }
+
+    /// The options of the device, beyond the file system it shares.
+    #[derive(Default)]
+    pub(super) struct DeviceOptions {
+        pub(super) write_coalescing: Option<FsWriteCoalescing>,
+        pub(super) virtual_files: Vec<FsVirtualFile>,
+        pub(super) protect_init_config: bool,
+        pub(super) credentials: Option<FsCredentials>,
+        pub(super) inspect_socket: Option<PathBuf>,
+        pub(super) dir_templates: Vec<FsDirTemplate>,
+        pub(super) protocol: FsProtocol,
+        pub(super) inode_numbers: FsInodeNumbers,
+        pub(super) mode_policy: Option<FsModePolicy>,
+        pub(super) degradation: FsDegradation,
+        pub(super) unsupported: FsUnsupportedStats,
+        pub(super) cache_timeouts: FsCacheTimeouts,
+        pub(super) tracer: FsTracer,
+        /// Leases, along with the notification queue they are revoked through
+        pub(super) leases: Option<FsLeases>,
+    }
+
+    /// The reply of the device to a request.
+    pub(super) struct Reply {
+        pub(super) error: i32,
+        pub(super) data: Vec<u8>,
+    }
+
+    //--------------------------------------------------------------------------------------------------
+    // Methods
+    //--------------------------------------------------------------------------------------------------
+
+    impl TestClient {
+        /// Creates a client of a device sharing `fs_config`, without initializing the session.
+        pub(super) fn new(fs_config: FsImplConfig) -> Self {
+            Self::with_options(fs_config, DeviceOptions::default())
+        }
+
+        /// Creates a client of a device sharing `fs_config` with `options`, without initializing
+        /// the session.
+        pub(super) fn with_options(fs_config: FsImplConfig, options: DeviceOptions) -> Self {
+            let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+
+            // The notification queue has rings of its own
+            let num_queues = if options.leases.is_some() { 3 } else { 2 };
+            let queues = (0..num_queues)
+                .map(|index| {
+                    let mut queue = Queue::new(QUEUE_SIZE);
+                    queue.size = QUEUE_SIZE;
+                    queue.ready = true;
+                    if options.leases.is_some() && index == NOTIFY_INDEX {
+                        queue.desc_table = GuestAddress(NOTIFY_DESC_TABLE_ADDR);
+                        queue.avail_ring = GuestAddress(NOTIFY_AVAIL_RING_ADDR);
+                        queue.used_ring = GuestAddress(NOTIFY_USED_RING_ADDR);
+                    } else {
+                        queue.desc_table = GuestAddress(DESC_TABLE_ADDR);
+                        queue.avail_ring = GuestAddress(AVAIL_RING_ADDR);
+                        queue.used_ring = GuestAddress(USED_RING_ADDR);
+                    }
+                    queue
+                })
+                .collect();
+            let queue_evts = (0..num_queues)
+                .map(|_| EventFd::new(EFD_NONBLOCK).unwrap())
+                .collect();
+
+            let worker = FsWorker::new(
+                queues,
+                queue_evts,
+                Arc::new(AtomicUsize::new(0)),
+                EventFd::new(EFD_NONBLOCK).unwrap(),
+                None,
+                None,
+                mem.clone(),
+                None,
+                fs_config,
+                None,
+                options.credentials,
+                options.cache_timeouts,
+                Default::default(),
+                EventFd::new(EFD_NONBLOCK).unwrap(),
+                Arc::new(AtomicI32::new(0)),
+                options.tracer,
+                Default::default(),
+                None,
+                options.write_coalescing,
+                options.virtual_files,
+                options.protect_init_config,
+                options.leases,
+                options.inspect_socket,
+                options.dir_templates,
+                FsPause::new().unwrap(),
+                options.protocol,
+                options.inode_numbers,
+                options.mode_policy,
+                options.degradation,
+                options.unsupported,
+                #[cfg(target_os = "macos")]
+                None,
+            );
+            worker.pause.attach(true);
+
+            TestClient {
+                worker,
+                mem,
+                unique: 0,
+                avail_idx: 0,
+                notify_avail_idx: 0,
+                notify_used_idx: 0,
+                waiting: None,
+                uid: 0,
+                gid: 0,
+            }
+        }
+
+        /// Creates a client of a device sharing `root` directly, with an initialized session.
+        pub(super) fn passthrough(root: &Path) -> Self {
+            Self::passthrough_with_options(root, DeviceOptions::default())
+        }
+
+        /// Creates a client of a device sharing `root` directly with `options`, with an
+        /// initialized session.
+        pub(super) fn passthrough_with_options(root: &Path, options: DeviceOptions) -> Self {
+            let fs_config = FsImplConfig::Passthrough(passthrough::Config {
+                root_dir: root.to_str().unwrap().to_string(),
+                ..Default::default()
+            });
+            let mut client = Self::with_options(fs_config, options);
+            let out = client.init(KERNEL_VERSION, KERNEL_MINOR_VERSION).unwrap();
+            assert_eq!(
+                (out.major, out.minor),
+                (KERNEL_VERSION, KERNEL_MINOR_VERSION)
+            );
+            client
+        }
+
+        /// Creates a client of a device sharing an overlay of `layers`, from the bottom one to the
+        /// top one, with `options`, with an initialized session.
+        pub(super) fn overlay_with_options(layers: Vec<PathBuf>, options: DeviceOptions) -> Self {
+            let fs_config = FsImplConfig::Overlayfs(overlayfs::Config {
+                layers,
+                ..Default::default()
+            });
+            let mut client = Self::with_options(fs_config, options);
+            client.init(KERNEL_VERSION, KERNEL_MINOR_VERSION).unwrap();
+            client
+        }
+
+        /// Places a request in the queue, with room for a reply of `reply_size` bytes past its
+        /// header, and has the worker process it, returning the reply written to the queue, if
+        /// any.
+        pub(super) fn send(
+            &mut self,
+            opcode: Opcode,
+            nodeid: u64,
+            args: &[&[u8]],
+            reply_size: u32,
+        ) -> Option<Reply> {
+            self.submit(opcode, nodeid, args, reply_size);
+            self.take_reply()
+        }
+
+        /// Sends a request like `send`, with an opcode the client may not know of.
+        pub(super) fn send_raw(
+            &mut self,
+            opcode: u32,
+            nodeid: u64,
+            args: &[&[u8]],
+            reply_size: u32,
+        ) -> Option<Reply> {
+            self.submit_raw(opcode, nodeid, args, reply_size);
+            self.take_reply()
+        }
+
+        /// Places a request in the queue like `send`, and notifies the worker, which processes it
+        /// unless paused.
+        pub(super) fn submit(
+            &mut self,
+            opcode: Opcode,
+            nodeid: u64,
+            args: &[&[u8]],
+            reply_size: u32,
+        ) {
+            self.submit_raw(opcode as u32, nodeid, args, reply_size)
+        }
+
+        fn submit_raw(&mut self, opcode: u32, nodeid: u64, args: &[&[u8]], reply_size: u32) {
+            assert!(reply_size <= MAX_REPLY_SIZE);
+            self.place_request(
+                0,
+                REQUEST_ADDR,
+                REPLY_ADDR,
+                opcode,
+                nodeid,
+                args,
+                reply_size,
+            );
+            self.make_available(self.worker.req_index, &[0]);
+        }
+
+        /// Places a request in the queue like `submit`, apart from the other requests so that it may
+        /// be left waiting while they are sent, and has the worker process it. Its reply is taken
+        /// with `take_waiting_reply` once the worker completed it.
+        pub(super) fn submit_waiting(
+            &mut self,
+            opcode: Opcode,
+            nodeid: u64,
+            args: &[&[u8]],
+            reply_size: u32,
+        ) {
+            assert!(self.waiting.is_none());
+            assert!(reply_size + size_of::<OutHeader>() as u32 <= BATCH_SLOT_SIZE);
+            let reply_addr = WAITING_ADDR + u64::from(BATCH_SLOT_SIZE);
+            self.place_request(
+                WAITING_HEAD,
+                WAITING_ADDR,
+                reply_addr,
+                opcode as u32,
+                nodeid,
+                args,
+                reply_size,
+            );
+            self.waiting = Some((self.unique, reply_addr));
+            self.make_available(self.worker.req_index, &[WAITING_HEAD]);
+        }
+
+        /// Returns the reply to the request left waiting, if the worker completed it.
+        pub(super) fn take_waiting_reply(&mut self) -> Option<Reply> {
+            let (unique, reply_addr) = self.waiting?;
+            let header: OutHeader = self.mem.read_obj(GuestAddress(reply_addr)).unwrap();
+            if header.len == 0 {
+                return None;
+            }
+            assert_eq!(header.unique, unique);
+            self.waiting = None;
+
+            let mut data = vec![0; header.len as usize - size_of::<OutHeader>()];
+            self.mem
+                .read_slice(
+                    &mut data,
+                    GuestAddress(reply_addr + size_of::<OutHeader>() as u64),
+                )
+                .unwrap();
+            Some(Reply {
+                error: -header.error,
+                data,
+            })
+        }
+
+        /// Places the requests of `batch` in the queue at once, with room for a reply of
+        /// `reply_size` bytes past its header each, and has the worker process them together,
+        /// returning their replies.
+        pub(super) fn send_batch(
+            &mut self,
+            batch: &[(Opcode, u64, &[&[u8]])],
+            reply_size: u32,
+        ) -> Vec<Reply> {
+            assert!(batch.len() * 3 <= QUEUE_SIZE as usize);
+            assert!(reply_size + size_of::<OutHeader>() as u32 <= BATCH_SLOT_SIZE);
+            let slots: Vec<_> = (0..batch.len() as u16)
+                .map(|index| {
+                    let request_addr =
+                        BATCH_ADDR + u64::from(index * 2) * u64::from(BATCH_SLOT_SIZE);
+                    (
+                        index * 3,
+                        request_addr,
+                        request_addr + u64::from(BATCH_SLOT_SIZE),
+                    )
+                })
+                .collect();
+            let mut uniques = Vec::new();
+            for ((opcode, nodeid, args), &(head, request_addr, reply_addr)) in
+                batch.iter().zip(&slots)
+            {
+                self.place_request(
+                    head,
+                    request_addr,
+                    reply_addr,
+                    *opcode as u32,
+                    *nodeid,
+                    args,
+                    reply_size,
+                );
+                uniques.push(self.unique);
+            }
+            let heads: Vec<_> = slots.iter().map(|&(head, _, _)| head).collect();
+            self.make_available(self.worker.req_index, &heads);
+            assert!(self.completed(), "the requests weren't completed");
+
+            slots
+                .iter()
+                .zip(uniques)
+                .map(|(&(_, _, reply_addr), unique)| {
+                    let header: OutHeader = self.mem.read_obj(GuestAddress(reply_addr)).unwrap();
+                    assert_eq!(header.unique, unique);
+                    let mut data = vec![0; header.len as usize - size_of::<OutHeader>()];
+                    self.mem
+                        .read_slice(
+                            &mut data,
+                            GuestAddress(reply_addr + size_of::<OutHeader>() as u64),
+                        )
+                        .unwrap();
+                    Reply {
+                        error: -header.error,
+                        data,
+                    }
+                })
+                .collect()
+        }
+
+        /// Returns a function telling how many completions the worker published in the request
+        /// queue so far, which the callbacks of the file system may call while it handles a
+        /// request.
+        pub(super) fn published(&self) -> impl Fn() -> u16 + Send + Sync + 'static {
+            let mem = self.mem.clone();
+            move || mem.read_obj(GuestAddress(USED_RING_ADDR + 2)).unwrap()
+        }
+
+        /// Places a request at `request_addr` as the chain starting with the descriptor `head`,
+        /// its reply to be written at `reply_addr`, without making it available.
+        #[allow(clippy::too_many_arguments)]
+        fn place_request(
+            &mut self,
+            head: u16,
+            request_addr: u64,
+            reply_addr: u64,
+            opcode: u32,
+            nodeid: u64,
+            args: &[&[u8]],
+            reply_size: u32,
+        ) {
+            self.unique += 1;
+            let len = size_of::<InHeader>() + args.iter().map(|arg| arg.len()).sum::<usize>();
+            let header = InHeader {
+                len: len as u32,
+                opcode,
+                unique: self.unique,
+                nodeid,
+                uid: self.uid,
+                gid: self.gid,
+                ..Default::default()
+            };
+
+            let mut addr = GuestAddress(request_addr);
+            self.mem.write_obj(header, addr).unwrap();
+            addr = GuestAddress(request_addr + size_of::<InHeader>() as u64);
+            for arg in args {
+                self.mem.write_slice(arg, addr).unwrap();
+                addr = GuestAddress(addr.0 + arg.len() as u64);
+            }
+            self.mem.write_obj(0u32, GuestAddress(reply_addr)).unwrap();
+
+            // Like the guest driver, the header of the reply gets a descriptor of its own, and the
+            // payload one sized for the reply expected
+            let header_len = size_of::<OutHeader>() as u32;
+            let index = u64::from(head);
+            self.write_desc(index, request_addr, len as u32, VIRTQ_DESC_F_NEXT, head + 1);
+            if reply_size == 0 {
+                self.write_desc(index + 1, reply_addr, header_len, VIRTQ_DESC_F_WRITE, 0);
+            } else {
+                self.write_desc(
+                    index + 1,
+                    reply_addr,
+                    header_len,
+                    VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT,
+                    head + 2,
+                );
+                self.write_desc(
+                    index + 2,
+                    reply_addr + u64::from(header_len),
+                    reply_size,
+                    VIRTQ_DESC_F_WRITE,
+                    0,
+                );
+            }
+        }
+
+        /// Places the 9P message `request` in the request queue of a 9p device, with room for a
+        /// reply of `reply_size` bytes, and has the worker process it, returning the type and the
+        /// body of the reply.
+        pub(super) fn p9_request(&mut self, request: &[u8], reply_size: u32) -> (u8, Vec<u8>) {
+            self.mem
+                .write_slice(request, GuestAddress(REQUEST_ADDR))
+                .unwrap();
+            self.write_desc(0, REQUEST_ADDR, request.len() as u32, VIRTQ_DESC_F_NEXT, 1);
+            self.write_desc(1, REPLY_ADDR, reply_size, VIRTQ_DESC_F_WRITE, 0);
+            self.make_available(self.worker.req_index, &[0]);
+            assert!(self.completed(), "the request wasn't completed");
+
+            // The size of the reply is both in the used ring and at its start
+            let slot = u64::from(self.avail_idx.wrapping_sub(1) % QUEUE_SIZE);
+            let used_len: u32 = self
+                .mem
+                .read_obj(GuestAddress(USED_RING_ADDR + 4 + 8 * slot + 4))
+                .unwrap();
+            let mut reply = vec![0; used_len as usize];
+            self.mem
+                .read_slice(&mut reply, GuestAddress(REPLY_ADDR))
+                .unwrap();
+            assert_eq!(reply[..4], used_len.to_le_bytes());
+            (reply[4], reply[7..].to_vec())
+        }
+
+        /// Makes the chains starting with the descriptors `heads` available in the queue
+        /// `queue_index`, and has the worker process them.
+        fn make_available(&mut self, queue_index: usize, heads: &[u16]) {
+            for &head in heads {
+                let slot = AVAIL_RING_ADDR + 4 + 2 * u64::from(self.avail_idx % QUEUE_SIZE);
+                self.mem.write_obj(head, GuestAddress(slot)).unwrap();
+                self.avail_idx = self.avail_idx.wrapping_add(1);
+            }
+            self.mem
+                .write_obj(self.avail_idx, GuestAddress(AVAIL_RING_ADDR + 2))
+                .unwrap();
+
+            self.worker.queue_evts[queue_index].write(1).unwrap();
+            self.worker.handle_event(queue_index);
+        }
+
+        /// Provides `count` buffers in the notification queue, and has the worker send the
+        /// invalidations waiting for them.
+        pub(super) fn provide_notify_buffers(&mut self, count: u16) {
+            for _ in 0..count {
+                let index = self.notify_avail_idx % QUEUE_SIZE;
+                let desc = GuestAddress(NOTIFY_DESC_TABLE_ADDR + 16 * u64::from(index));
+                let addr = NOTIFY_BUFFER_ADDR + u64::from(index) * u64::from(NOTIFY_BUFFER_SIZE);
+                self.mem.write_obj(addr, desc).unwrap();
+                self.mem
+                    .write_obj(NOTIFY_BUFFER_SIZE, GuestAddress(desc.0 + 8))
+                    .unwrap();
+                self.mem
+                    .write_obj(VIRTQ_DESC_F_WRITE, GuestAddress(desc.0 + 12))
+                    .unwrap();
+
+                let slot = NOTIFY_AVAIL_RING_ADDR + 4 + 2 * u64::from(index);
+                self.mem.write_obj(index, GuestAddress(slot)).unwrap();
+                self.notify_avail_idx = self.notify_avail_idx.wrapping_add(1);
+            }
+            self.mem
+                .write_obj(
+                    self.notify_avail_idx,
+                    GuestAddress(NOTIFY_AVAIL_RING_ADDR + 2),
+                )
+                .unwrap();
+
+            let _ = self.worker.queue_evts[NOTIFY_INDEX].read();
+            self.worker.send_invalidations();
+        }
+
+        /// Returns the inodes the worker invalidated through the notification queue since the last
+        /// call.
+        pub(super) fn take_invalidations(&mut self) -> Vec<u64> {
+            let used_idx: u16 = self
+                .mem
+                .read_obj(GuestAddress(NOTIFY_USED_RING_ADDR + 2))
+                .unwrap();
+            let mut inodes = Vec::new();
+            while self.notify_used_idx != used_idx {
+                let slot =
+                    NOTIFY_USED_RING_ADDR + 4 + 8 * u64::from(self.notify_used_idx % QUEUE_SIZE);
+                let index: u32 = self.mem.read_obj(GuestAddress(slot)).unwrap();
+                let addr = NOTIFY_BUFFER_ADDR + u64::from(index) * u64::from(NOTIFY_BUFFER_SIZE);
+                let header: OutHeader = self.mem.read_obj(GuestAddress(addr)).unwrap();
+                assert_eq!(header.error, NotifyOpcode::InvalInode as i32);
+                let out: NotifyInvalInodeOut = self
+                    .mem
+                    .read_obj(GuestAddress(addr + size_of::<OutHeader>() as u64))
+                    .unwrap();
+                inodes.push(out.ino);
+                self.notify_used_idx = self.notify_used_idx.wrapping_add(1);
+            }
+            inodes
+        }
+
+        /// Changes the entry and attribute timeouts of the share, having the worker take the
+        /// change up.
+        pub(super) fn set_cache_timeouts(
+            &mut self,
+            entry_timeout: Duration,
+            attr_timeout: Duration,
+        ) {
+            self.worker.cache_timeouts.set(entry_timeout, attr_timeout);
+            self.worker.handle_timeouts_event();
+        }
+
+        /// Whether the worker completed the last request submitted, besides the one left waiting.
+        pub(super) fn completed(&self) -> bool {
+            let used_idx: u16 = self.mem.read_obj(GuestAddress(USED_RING_ADDR + 2)).unwrap();
+            let waiting = self.waiting.is_some_and(|(_, reply_addr)| {
+                self.mem.read_obj::<u32>(GuestAddress(reply_addr)).unwrap() == 0
+            });
+            used_idx == self.avail_idx.wrapping_sub(u16::from(waiting))
+        }
+
+        /// Returns the reply to the last request submitted, which must be completed, if any.
+        pub(super) fn take_reply(&mut self) -> Option<Reply> {
+            assert!(self.completed(), "the request wasn't completed");
+
+            let header: OutHeader = self.mem.read_obj(GuestAddress(REPLY_ADDR)).unwrap();
+            if header.len == 0 {
+                return None;
+            }
+            assert_eq!(header.unique, self.unique);
+
+            let mut data = vec![0; header.len as usize - size_of::<OutHeader>()];
+            self.mem
+                .read_slice(
+                    &mut data,
+                    GuestAddress(REPLY_ADDR + size_of::<OutHeader>() as u64),
+                )
+                .unwrap();
+            Some(Reply {
+                error: -header.error,
+                data,
+            })
+        }
+
+        /// Sends a request expecting a reply, returning its payload or the error number.
+        pub(super) fn request(
+            &mut self,
+            opcode: Opcode,
+            nodeid: u64,
+            args: &[&[u8]],
+            reply_size: u32,
+        ) -> Result<Vec<u8>, i32> {
+            let reply = self
+                .send(opcode, nodeid, args, reply_size)
+                .expect("no reply");
+            match reply.error {
+                0 => Ok(reply.data),
+                error => Err(error),
+            }
+        }
+
+        /// Sends a request expecting a reply of type `T`.
+        pub(super) fn request_obj<T: ByteValued + Default>(
+            &mut self,
+            opcode: Opcode,
+            nodeid: u64,
+            args: &[&[u8]],
+        ) -> Result<T, i32> {
+            self.request(opcode, nodeid, args, size_of::<T>() as u32)
+                .map(|data| read_obj(&data))
+        }
+
+        pub(super) fn init(&mut self, major: u32, minor: u32) -> Result<InitOut, i32> {
+            let init_in = InitInCompat {
+                major,
+                minor,
+                max_readahead: 0x2_0000,
+                flags: 0,
+            };
+            self.request_obj(Opcode::Init, 0, &[init_in.as_slice()])
+        }
+
+        pub(super) fn lookup(&mut self, parent: u64, name: &str) -> Result<EntryOut, i32> {
+            let name = CString::new(name).unwrap();
+            self.request_obj(Opcode::Lookup, parent, &[name.as_bytes_with_nul()])
+        }
+
+        pub(super) fn forget(&mut self, nodeid: u64, nlookup: u64) {
+            let forget_in = ForgetIn { nlookup };
+            assert!(self
+                .send(Opcode::Forget, nodeid, &[forget_in.as_slice()], 0)
+                .is_none());
+        }
+
+        pub(super) fn getattr(&mut self, nodeid: u64) -> Result<AttrOut, i32> {
+            let getattr_in = GetattrIn::default();
+            self.request_obj(Opcode::Getattr, nodeid, &[getattr_in.as_slice()])
+        }
+
+        pub(super) fn mkdir(
+            &mut self,
+            parent: u64,
+            name: &str,
+            mode: u32,
+        ) -> Result<EntryOut, i32> {
+            let name = CString::new(name).unwrap();
+            let mkdir_in = MkdirIn { mode, umask: 0 };
+            self.request_obj(
+                Opcode::Mkdir,
+                parent,
+                &[mkdir_in.as_slice(), name.as_bytes_with_nul()],
+            )
+        }
+
+        pub(super) fn create(
+            &mut self,
+            parent: u64,
+            name: &str,
+            mode: u32,
+            flags: i32,
+        ) -> Result<(EntryOut, OpenOut), i32> {
+            let name = CString::new(name).unwrap();
+            let create_in = CreateIn {
+                flags: flags as u32,
+                mode,
+                ..Default::default()
+            };
+            self.request(
+                Opcode::Create,
+                parent,
+                &[create_in.as_slice(), name.as_bytes_with_nul()],
+                (size_of::<EntryOut>() + size_of::<OpenOut>()) as u32,
+            )
+            .map(|data| (read_obj(&data), read_obj(&data[size_of::<EntryOut>()..])))
+        }
+
+        /// Creates an anonymous file in `parent`, sending the name the kernel sends with it.
+        pub(super) fn tmpfile(
+            &mut self,
+            parent: u64,
+            mode: u32,
+            flags: i32,
+        ) -> Result<(EntryOut, OpenOut), i32> {
+            let create_in = CreateIn {
+                flags: flags as u32,
+                mode,
+                ..Default::default()
+            };
+            self.request(
+                Opcode::Tmpfile,
+                parent,
+                &[create_in.as_slice(), b"/\0"],
+                (size_of::<EntryOut>() + size_of::<OpenOut>()) as u32,
+            )
+            .map(|data| (read_obj(&data), read_obj(&data[size_of::<EntryOut>()..])))
+        }
+
+        pub(super) fn link(
+            &mut self,
+            nodeid: u64,
+            newparent: u64,
+            newname: &str,
+        ) -> Result<EntryOut, i32> {
+            let newname = CString::new(newname).unwrap();
+            let link_in = LinkIn { oldnodeid: nodeid };
+            self.request_obj(
+                Opcode::Link,
+                newparent,
+                &[link_in.as_slice(), newname.as_bytes_with_nul()],
+            )
+        }
+
+        pub(super) fn setattr(
+            &mut self,
+            nodeid: u64,
+            setattr_in: SetattrIn,
+        ) -> Result<AttrOut, i32> {
+            self.request_obj(Opcode::Setattr, nodeid, &[setattr_in.as_slice()])
+        }
+
+        pub(super) fn access(&mut self, nodeid: u64, mask: i32) -> Result<(), i32> {
+            let access_in = AccessIn {
+                mask: mask as u32,
+                ..Default::default()
+            };
+            self.request(Opcode::Access, nodeid, &[access_in.as_slice()], 0)
+                .map(drop)
+        }
+
+        pub(super) fn unlink(&mut self, parent: u64, name: &str) -> Result<(), i32> {
+            let name = CString::new(name).unwrap();
+            self.request(Opcode::Unlink, parent, &[name.as_bytes_with_nul()], 0)
+                .map(drop)
+        }
+
+        pub(super) fn rename(
+            &mut self,
+            parent: u64,
+            name: &str,
+            newdir: u64,
+            newname: &str,
+        ) -> Result<(), i32> {
+            let name = CString::new(name).unwrap();
+            let newname = CString::new(newname).unwrap();
+            let rename_in = RenameIn { newdir };
+            self.request(
+                Opcode::Rename,
+                parent,
+                &[
+                    rename_in.as_slice(),
+                    name.as_bytes_with_nul(),
+                    newname.as_bytes_with_nul(),
+                ],
+                0,
+            )
+            .map(drop)
+        }
+
+        pub(super) fn open(&mut self, nodeid: u64, flags: i32) -> Result<OpenOut, i32> {
+            let open_in = OpenIn {
+                flags: flags as u32,
+                ..Default::default()
+            };
+            self.request_obj(Opcode::Open, nodeid, &[open_in.as_slice()])
+        }
+
+        pub(super) fn read(
+            &mut self,
+            nodeid: u64,
+            fh: u64,
+            offset: u64,
+            size: u32,
+        ) -> Result<Vec<u8>, i32> {
+            let read_in = ReadIn {
+                fh,
+                offset,
+                size,
+                ..Default::default()
+            };
+            self.request(Opcode::Read, nodeid, &[read_in.as_slice()], size)
+        }
+
+        pub(super) fn write(
+            &mut self,
+            nodeid: u64,
+            fh: u64,
+            offset: u64,
+            data: &[u8],
+        ) -> Result<u32, i32> {
+            self.write_with_flags(nodeid, fh, offset, data, 0)
+        }
+
+        /// Writes through a handle whose file was opened with `flags`.
+        pub(super) fn write_with_flags(
+            &mut self,
+            nodeid: u64,
+            fh: u64,
+            offset: u64,
+            data: &[u8],
+            flags: i32,
+        ) -> Result<u32, i32> {
+            let write_in = WriteIn {
+                fh,
+                offset,
+                size: data.len() as u32,
+                flags: flags as u32,
+                ..Default::default()
+            };
+            self.request_obj::<WriteOut>(Opcode::Write, nodeid, &[write_in.as_slice(), data])
+                .map(|out| out.size)
+        }
+
+        pub(super) fn flush(&mut self, nodeid: u64, fh: u64) -> Result<(), i32> {
+            let flush_in = FlushIn {
+                fh,
+                ..Default::default()
+            };
+            self.request(Opcode::Flush, nodeid, &[flush_in.as_slice()], 0)
+                .map(|_| ())
+        }
+
+        /// Flushes a handle closed by `lock_owner`.
+        pub(super) fn flush_owner(
+            &mut self,
+            nodeid: u64,
+            fh: u64,
+            lock_owner: u64,
+        ) -> Result<(), i32> {
+            let flush_in = FlushIn {
+                fh,
+                lock_owner,
+                ..Default::default()
+            };
+            self.request(Opcode::Flush, nodeid, &[flush_in.as_slice()], 0)
+                .map(|_| ())
+        }
+
+        pub(super) fn getlk(
+            &mut self,
+            nodeid: u64,
+            fh: u64,
+            owner: u64,
+            lk: FileLock,
+        ) -> Result<FileLock, i32> {
+            let lk_in = LkIn {
+                fh,
+                owner,
+                lk,
+                ..Default::default()
+            };
+            self.request_obj::<LkOut>(Opcode::Getlk, nodeid, &[lk_in.as_slice()])
+                .map(|out| out.lk)
+        }
+
+        /// Takes or releases a lock, waiting for it with `FUSE_SETLKW` if `wait` is set.
+        pub(super) fn setlk(
+            &mut self,
+            nodeid: u64,
+            fh: u64,
+            owner: u64,
+            lk: FileLock,
+            wait: bool,
+        ) -> Result<(), i32> {
+            let lk_in = LkIn {
+                fh,
+                owner,
+                lk,
+                ..Default::default()
+            };
+            let opcode = if wait { Opcode::Setlkw } else { Opcode::Setlk };
+            self.request(opcode, nodeid, &[lk_in.as_slice()], 0)
+                .map(drop)
+        }
+
+        /// Takes a lock with `FUSE_SETLKW`, leaving the request waiting until the worker gets it.
+        pub(super) fn submit_setlkw(&mut self, nodeid: u64, fh: u64, owner: u64, lk: FileLock) {
+            let lk_in = LkIn {
+                fh,
+                owner,
+                lk,
+                ..Default::default()
+            };
+            self.submit_waiting(Opcode::Setlkw, nodeid, &[lk_in.as_slice()], 0);
+        }
+
+        pub(super) fn lseek(
+            &mut self,
+            nodeid: u64,
+            fh: u64,
+            offset: u64,
+            whence: i32,
+        ) -> Result<u64, i32> {
+            let lseek_in = LseekIn {
+                fh,
+                offset,
+                whence: whence as u32,
+                ..Default::default()
+            };
+            self.request_obj::<LseekOut>(Opcode::Lseek, nodeid, &[lseek_in.as_slice()])
+                .map(|out| out.offset)
+        }
+
+        #[allow(clippy::too_many_arguments)]
+        pub(super) fn copy_file_range(
+            &mut self,
+            nodeid_in: u64,
+            fh_in: u64,
+            off_in: u64,
+            nodeid_out: u64,
+            fh_out: u64,
+            off_out: u64,
+            len: u64,
+        ) -> Result<u32, i32> {
+            let copy_in = CopyfilerangeIn {
+                fh_in,
+                off_in,
+                nodeid_out,
+                fh_out,
+                off_out,
+                len,
+                flags: 0,
+            };
+            self.request_obj::<WriteOut>(Opcode::CopyFileRange, nodeid_in, &[copy_in.as_slice()])
+                .map(|out| out.size)
+        }
+
+        pub(super) fn release(&mut self, nodeid: u64, fh: u64) -> Result<(), i32> {
+            let release_in = ReleaseIn {
+                fh,
+                ..Default::default()
+            };
+            self.request(Opcode::Release, nodeid, &[release_in.as_slice()], 0)
+                .map(|_| ())
+        }
+
+        pub(super) fn opendir(&mut self, nodeid: u64) -> Result<OpenOut, i32> {
+            let open_in = OpenIn::default();
+            self.request_obj(Opcode::Opendir, nodeid, &[open_in.as_slice()])
+        }
+
+        /// Reads the entries of a directory, with their attributes, starting at `offset` and
+        /// fitting in `size` bytes.
+        pub(super) fn readdirplus(
+            &mut self,
+            nodeid: u64,
+            fh: u64,
+            offset: u64,
+            size: u32,
+        ) -> Result<Vec<(String, Direntplus)>, i32> {
+            let read_in = ReadIn {
+                fh,
+                offset,
+                size,
+                ..Default::default()
+            };
+            let data = self.request(Opcode::Readdirplus, nodeid, &[read_in.as_slice()], size)?;
+
+            let mut entries = Vec::new();
+            let mut pos = 0;
+            while pos < data.len() {
+                let entry: Direntplus = read_obj(&data[pos..]);
+                let name_start = pos + size_of::<Direntplus>();
+                let name = &data[name_start..name_start + entry.dirent.namelen as usize];
+                entries.push((String::from_utf8(name.to_vec()).unwrap(), entry));
+                // Entries are padded to 8 bytes
+                pos = (name_start + entry.dirent.namelen as usize + 7) & !7;
+            }
+            Ok(entries)
+        }
+
+        pub(super) fn releasedir(&mut self, nodeid: u64, fh: u64) -> Result<(), i32> {
+            let release_in = ReleaseIn {
+                fh,
+                ..Default::default()
+            };
+            self.request(Opcode::Releasedir, nodeid, &[release_in.as_slice()], 0)
+                .map(|_| ())
+        }
+
+        /// Issues the ioctl `cmd`, taking and returning no data, on `nodeid`.
+        pub(super) fn ioctl(&mut self, nodeid: u64, fh: u64, cmd: u32) -> Result<(), i32> {
+            let ioctl_in = IoctlIn {
+                fh,
+                cmd,
+                ..Default::default()
+            };
+            self.request(
+                Opcode::Ioctl,
+                nodeid,
+                &[ioctl_in.as_slice()],
+                size_of::<IoctlOut>() as u32,
+            )
+            .map(|_| ())
+        }
+
+        /// Returns the state of the share, as saved in a snapshot of the guest.
+        pub(super) fn save_state(&self) -> std::io::Result<Vec<u8>> {
+            self.worker.server().save_state()
+        }
+
+        /// Puts the share, not initialized yet, in the state returned by `save_state`.
+        pub(super) fn restore_state(&mut self, state: &[u8]) -> std::io::Result<()> {
+            self.worker.server().restore_state(state)
+        }
+
+        /// Pauses the device with `options`, having the worker take the pause up.
+        pub(super) fn pause(&mut self, options: FsPauseOptions) -> std::io::Result<()> {
+            let pause = self.worker.pause.clone();
+            self.run_pause_request(move || pause.pause(options))
+        }
+
+        pub(super) fn resume(&mut self) -> std::io::Result<()> {
+            let pause = self.worker.pause.clone();
+            self.run_pause_request(move || pause.resume())
+        }
+
+        /// Applies the timeout policy of the pause, if it timed out.
+        pub(super) fn check_pause_timeout(&mut self) {
+            self.worker.check_pause_timeout();
+        }
+
+        /// Runs `request` of a pause, which waits for the worker, on another thread.
+        fn run_pause_request(
+            &mut self,
+            request: impl FnOnce() -> std::io::Result<()> + Send + 'static,
+        ) -> std::io::Result<()> {
+            let request = thread::spawn(request);
+            while !request.is_finished() {
+                self.worker.handle_pause_event();
+                thread::sleep(Duration::from_millis(1));
+            }
+            request.join().unwrap()
+        }
+
+        fn write_desc(&self, index: u64, addr: u64, len: u32, flags: u16, next: u16) {
+            let desc = GuestAddress(DESC_TABLE_ADDR + 16 * index);
+            self.mem.write_obj(addr, desc).unwrap();
+            self.mem.write_obj(len, GuestAddress(desc.0 + 8)).unwrap();
+            self.mem
+                .write_obj(flags, GuestAddress(desc.0 + 12))
+                .unwrap();
+            self.mem.write_obj(next, GuestAddress(desc.0 + 14)).unwrap();
+        }
+    }
+
+    //--------------------------------------------------------------------------------------------------
+    // Functions
+    //--------------------------------------------------------------------------------------------------
+
+    fn read_obj<T: ByteValued + Default>(data: &[u8]) -> T {
+        let mut obj = T::default();
+        obj.as_mut_slice().copy_from_slice(&data[..size_of::<T>()]);
+        obj
+    }
+}
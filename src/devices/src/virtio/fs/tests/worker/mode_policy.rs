@@ -0,0 +1,64 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use crate::virtio::fs::fuse::{SetattrIn, SetattrValid, ROOT_ID};
+use crate::virtio::fs::FsModePolicy;
+
+use super::helper::{DeviceOptions, TestClient};
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+fn host_mode(path: &Path) -> u32 {
+    fs::metadata(path).unwrap().permissions().mode() & 0o7777
+}
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_mode_policy() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = DeviceOptions {
+        mode_policy: Some(FsModePolicy {
+            umask: 0o077,
+            file_mode: None,
+            dir_mode: Some(0o750),
+        }),
+        ..Default::default()
+    };
+    let mut client = TestClient::passthrough_with_options(dir.path(), options);
+
+    // The entries land on the host with the modes of the policy, and the guest sees the ones it
+    // asked for
+    let (entry, _) = client.create(ROOT_ID, "file", 0o644, libc::O_RDWR).unwrap();
+    assert_eq!(host_mode(&dir.path().join("file")), 0o600);
+    assert_eq!(entry.attr.mode & 0o7777, 0o644);
+    let attr = client.getattr(entry.nodeid).unwrap().attr;
+    assert_eq!(attr.mode & 0o7777, 0o644);
+
+    let subdir = client.mkdir(ROOT_ID, "dir", 0o777).unwrap();
+    assert_eq!(host_mode(&dir.path().join("dir")), 0o700);
+    assert_eq!(subdir.attr.mode & 0o7777, 0o777);
+    assert_eq!(subdir.attr.mode & libc::S_IFMT, libc::S_IFDIR);
+    let lookup = client.lookup(ROOT_ID, "dir").unwrap();
+    assert_eq!(lookup.attr.mode & 0o7777, 0o777);
+
+    // The umask also applies to the modes the guest sets
+    let setattr_in = SetattrIn {
+        valid: SetattrValid::MODE.bits(),
+        mode: libc::S_IFREG | 0o666,
+        ..Default::default()
+    };
+    let attr = client.setattr(entry.nodeid, setattr_in).unwrap().attr;
+    assert_eq!(host_mode(&dir.path().join("file")), 0o600);
+    assert_eq!(attr.mode & 0o7777, 0o666);
+
+    // A mode changed on the host is reported as it is
+    fs::set_permissions(dir.path().join("file"), fs::Permissions::from_mode(0o640)).unwrap();
+    let attr = client.getattr(entry.nodeid).unwrap().attr;
+    assert_eq!(attr.mode & 0o7777, 0o640);
+}
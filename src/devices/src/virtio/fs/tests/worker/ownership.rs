@@ -0,0 +1,115 @@
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+
+use vm_memory::ByteValued;
+
+use crate::virtio::fs::fuse::{
+    EntryOut, GetxattrIn, MknodIn, Opcode, SetattrIn, SetattrValid, KERNEL_MINOR_VERSION,
+    KERNEL_VERSION, ROOT_ID,
+};
+use crate::virtio::fs::{passthrough, FsImplConfig, FsOwnership};
+
+use super::helper::{DeviceOptions, TestClient};
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+fn virtual_client(root: &Path) -> TestClient {
+    let fs_config = FsImplConfig::Passthrough(passthrough::Config {
+        root_dir: root.to_str().unwrap().to_string(),
+        ownership: FsOwnership::Virtual,
+        ..Default::default()
+    });
+    let mut client = TestClient::with_options(fs_config, DeviceOptions::default());
+    client.init(KERNEL_VERSION, KERNEL_MINOR_VERSION).unwrap();
+    client
+}
+
+fn mknod(client: &mut TestClient, name: &str, mode: u32, rdev: u32) -> EntryOut {
+    let name = CString::new(name).unwrap();
+    let mknod_in = MknodIn {
+        mode,
+        rdev,
+        umask: 0,
+        padding: 0,
+    };
+    let data = client
+        .request(
+            Opcode::Mknod,
+            ROOT_ID,
+            &[mknod_in.as_slice(), name.as_bytes_with_nul()],
+            std::mem::size_of::<EntryOut>() as u32,
+        )
+        .unwrap();
+    *EntryOut::from_slice(&data).unwrap()
+}
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_virtual_ownership() {
+    let dir = tempfile::tempdir().unwrap();
+    let host_uid = fs::metadata(dir.path()).unwrap().uid();
+    let mut client = virtual_client(dir.path());
+    client.uid = 1234;
+    client.gid = 5678;
+
+    // The guest sees the owner it created the file as, while the host file keeps the one of the
+    // device
+    let (entry, _) = client.create(ROOT_ID, "file", 0o640, libc::O_RDWR).unwrap();
+    assert_eq!((entry.attr.uid, entry.attr.gid), (1234, 5678));
+    assert_eq!(entry.attr.mode, libc::S_IFREG | 0o640);
+    assert_eq!(
+        fs::metadata(dir.path().join("file")).unwrap().uid(),
+        host_uid
+    );
+    let attr = client.lookup(ROOT_ID, "file").unwrap().attr;
+    assert_eq!((attr.uid, attr.gid), (1234, 5678));
+
+    // A chown only changes what the guest sees
+    let setattr_in = SetattrIn {
+        valid: (SetattrValid::UID | SetattrValid::MODE).bits(),
+        uid: 42,
+        mode: libc::S_IFREG | 0o4755,
+        ..Default::default()
+    };
+    let attr = client.setattr(entry.nodeid, setattr_in).unwrap().attr;
+    assert_eq!((attr.uid, attr.gid), (42, 5678));
+    assert_eq!(attr.mode, libc::S_IFREG | 0o4755);
+    let metadata = fs::metadata(dir.path().join("file")).unwrap();
+    assert_eq!(metadata.uid(), host_uid);
+    assert_eq!(metadata.mode() & 0o7777, 0o755);
+
+    // A device node is an empty file on the host
+    let rdev = libc::makedev(1, 3) as u32;
+    let entry = mknod(&mut client, "null", libc::S_IFCHR | 0o666, rdev);
+    assert_eq!(entry.attr.mode, libc::S_IFCHR | 0o666);
+    assert_eq!(entry.attr.rdev, rdev);
+    let metadata = fs::symlink_metadata(dir.path().join("null")).unwrap();
+    assert!(metadata.file_type().is_file());
+    assert!(!metadata.file_type().is_char_device());
+    let attr = client.getattr(entry.nodeid).unwrap().attr;
+    assert_eq!((attr.mode, attr.rdev), (libc::S_IFCHR | 0o666, rdev));
+
+    // The attribute keeping the ownership is hidden from the guest
+    let getxattr_in = GetxattrIn {
+        size: 1024,
+        padding: 0,
+    };
+    let listed = client
+        .request(
+            Opcode::Listxattr,
+            entry.nodeid,
+            &[getxattr_in.as_slice()],
+            1024,
+        )
+        .unwrap();
+    assert!(!listed
+        .split(|b| *b == 0)
+        .any(|name| name == b"user.containers.override_stat"));
+}
@@ -0,0 +1,201 @@
+use std::fs;
+
+use crate::virtio::fs::{passthrough, FsImplConfig, FsProtocol};
+
+use super::helper::{DeviceOptions, TestClient};
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TGETATTR: u8 = 24;
+const TREADDIR: u8 = 40;
+const TMKDIR: u8 = 72;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+
+const REPLY_SIZE: u32 = 0x1000;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// A 9P client of a device, encoding the requests it sends.
+struct P9Client(TestClient);
+
+/// A request being encoded.
+#[derive(Default)]
+struct Msg(Vec<u8>);
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl P9Client {
+    fn new(root: &std::path::Path) -> Self {
+        let fs_config = FsImplConfig::Passthrough(passthrough::Config {
+            root_dir: root.to_str().unwrap().to_string(),
+            ..Default::default()
+        });
+        let options = DeviceOptions {
+            protocol: FsProtocol::P9,
+            ..Default::default()
+        };
+        P9Client(TestClient::with_options(fs_config, options))
+    }
+
+    /// Sends the request `type_` with `body`, returning the body of the reply, or the error.
+    fn call(&mut self, type_: u8, body: Msg) -> Result<Vec<u8>, u32> {
+        let mut request = (body.0.len() as u32 + 7).to_le_bytes().to_vec();
+        request.push(type_);
+        request.extend_from_slice(&1u16.to_le_bytes());
+        request.extend_from_slice(&body.0);
+        match self.0.p9_request(&request, REPLY_SIZE) {
+            (RLERROR, reply) => Err(u32::from_le_bytes(reply[..4].try_into().unwrap())),
+            (reply_type, reply) => {
+                assert_eq!(reply_type, type_ + 1);
+                Ok(reply)
+            }
+        }
+    }
+
+    fn walk(&mut self, fid: u32, newfid: u32, names: &[&str]) -> Result<Vec<u8>, u32> {
+        let mut msg = Msg::default().u32(fid).u32(newfid).u16(names.len() as u16);
+        for name in names {
+            msg = msg.str(name);
+        }
+        self.call(TWALK, msg)
+    }
+
+    fn lopen(&mut self, fid: u32, flags: i32) -> Result<Vec<u8>, u32> {
+        self.call(TLOPEN, Msg::default().u32(fid).u32(flags as u32))
+    }
+
+    fn read(&mut self, fid: u32, offset: u64, count: u32) -> Result<Vec<u8>, u32> {
+        let reply = self.call(TREAD, Msg::default().u32(fid).u64(offset).u32(count))?;
+        Ok(reply[4..].to_vec())
+    }
+}
+
+impl Msg {
+    fn u16(mut self, v: u16) -> Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn u32(mut self, v: u32) -> Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn u64(mut self, v: u64) -> Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn str(self, v: &str) -> Self {
+        let mut msg = self.u16(v.len() as u16);
+        msg.0.extend_from_slice(v.as_bytes());
+        msg
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Returns the names of the entries of a `Rreaddir`.
+fn dirent_names(reply: &[u8]) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut pos = 4;
+    while pos < reply.len() {
+        // The qid, the offset and the type come first
+        pos += 13 + 8 + 1;
+        let len = u16::from_le_bytes([reply[pos], reply[pos + 1]]) as usize;
+        names.push(String::from_utf8(reply[pos + 2..pos + 2 + len].to_vec()).unwrap());
+        pos += 2 + len;
+    }
+    names.sort();
+    names
+}
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_p9_session() {
+    let root = tempfile::tempdir().unwrap();
+    fs::write(root.path().join("a"), b"hello").unwrap();
+    let mut client = P9Client::new(root.path());
+
+    // Nothing is served before the version is agreed on
+    assert_eq!(client.walk(0, 1, &[]), Err(libc::EINVAL as u32));
+    let version = client
+        .call(TVERSION, Msg::default().u32(8192).str("9P2000.L"))
+        .unwrap();
+    assert_eq!(version[..4], 8192u32.to_le_bytes());
+    assert_eq!(&version[6..], b"9P2000.L");
+
+    let attach = Msg::default().u32(0).u32(u32::MAX).str("").str("").u32(0);
+    let qid = client.call(TATTACH, attach).unwrap();
+    assert_eq!(qid[0], 0x80);
+
+    // Reading and writing a file
+    assert_eq!(client.walk(0, 1, &["missing"]), Err(libc::ENOENT as u32));
+    let qids = client.walk(0, 1, &["a"]).unwrap();
+    assert_eq!(qids[..2], 1u16.to_le_bytes());
+    client.lopen(1, libc::O_RDWR).unwrap();
+    assert_eq!(client.read(1, 0, 100).unwrap(), b"hello");
+    let mut write = Msg::default().u32(1).u64(5).u32(6);
+    write.0.extend_from_slice(b" world");
+    assert_eq!(client.call(TWRITE, write).unwrap(), 6u32.to_le_bytes());
+    assert_eq!(fs::read(root.path().join("a")).unwrap(), b"hello world");
+    let attr = client
+        .call(TGETATTR, Msg::default().u32(1).u64(0x7ff))
+        .unwrap();
+    // The size follows the mask, the qid, the mode, the owners, the links and the device
+    assert_eq!(attr[8 + 13 + 12 + 16..][..8], 11u64.to_le_bytes());
+
+    // Creating and removing files, through a fid walked from the root
+    client.walk(0, 2, &[]).unwrap();
+    let create = Msg::default()
+        .u32(2)
+        .str("b")
+        .u32((libc::O_CREAT | libc::O_RDWR) as u32)
+        .u32(0o644)
+        .u32(0);
+    client.call(TLCREATE, create).unwrap();
+    assert!(root.path().join("b").is_file());
+    let mkdir = Msg::default().u32(0).str("d").u32(0o755).u32(0);
+    client.call(TMKDIR, mkdir).unwrap();
+    assert!(root.path().join("d").is_dir());
+
+    client.walk(0, 3, &[]).unwrap();
+    client.lopen(3, libc::O_RDONLY | libc::O_DIRECTORY).unwrap();
+    let entries = client
+        .call(TREADDIR, Msg::default().u32(3).u64(0).u32(4096))
+        .unwrap();
+    assert_eq!(dirent_names(&entries), [".", "..", "a", "b", "d"]);
+
+    client
+        .call(TUNLINKAT, Msg::default().u32(0).str("b").u32(0))
+        .unwrap();
+    client
+        .call(TUNLINKAT, Msg::default().u32(0).str("d").u32(0x200))
+        .unwrap();
+    assert!(!root.path().join("b").exists());
+    assert!(!root.path().join("d").exists());
+
+    // A fid clunked is no longer known
+    client.call(TCLUNK, Msg::default().u32(1)).unwrap();
+    assert_eq!(client.read(1, 0, 100), Err(libc::EBADF as u32));
+}
@@ -0,0 +1,122 @@
+use std::fs;
+use std::mem::size_of;
+use std::time::Duration;
+
+use vm_memory::ByteValued;
+
+use crate::virtio::fs::fuse::{AttrOut, GetattrIn, Opcode, ROOT_ID};
+use crate::virtio::fs::{FsPauseOptions, FsPauseTimeout};
+
+use super::helper::TestClient;
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_pause_queues_requests() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("file"), b"hello").unwrap();
+    let mut client = TestClient::passthrough(dir.path());
+    let file = client.lookup(ROOT_ID, "file").unwrap();
+
+    client.pause(FsPauseOptions::default()).unwrap();
+    let err = client.pause(FsPauseOptions::default()).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EALREADY));
+
+    // The request waits in the queue until the device resumes
+    submit_getattr(&mut client, file.nodeid);
+    assert!(!client.completed());
+    client.resume().unwrap();
+    let reply = client.take_reply().unwrap();
+    assert_eq!(reply.error, 0);
+    assert_eq!(reply.data.len(), size_of::<AttrOut>());
+    client.getattr(file.nodeid).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_pause_close_files() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir(dir.path().join("dir")).unwrap();
+    fs::write(dir.path().join("dir/file"), b"hello").unwrap();
+    let mut client = TestClient::passthrough(dir.path());
+    let parent = client.lookup(ROOT_ID, "dir").unwrap();
+    let file = client.lookup(parent.nodeid, "file").unwrap();
+    let handle = client.open(file.nodeid, libc::O_RDWR).unwrap().fh;
+
+    // The share can be moved away while paused, the files being opened again by their paths
+    let options = FsPauseOptions {
+        close_files: true,
+        ..Default::default()
+    };
+    client.pause(options).unwrap();
+    let moved = dir.path().with_extension("moved");
+    fs::rename(dir.path(), &moved).unwrap();
+    assert!(client.resume().is_err());
+    fs::rename(&moved, dir.path()).unwrap();
+    client.resume().unwrap();
+
+    assert_eq!(client.read(file.nodeid, handle, 0, 16).unwrap(), b"hello");
+    client.write(file.nodeid, handle, 5, b" world").unwrap();
+    client.release(file.nodeid, handle).unwrap();
+    assert_eq!(
+        fs::read(dir.path().join("dir/file")).unwrap(),
+        b"hello world"
+    );
+
+    // The guest holding a deleted file, it can't be closed
+    client.unlink(parent.nodeid, "file").unwrap();
+    assert!(client.pause(options).is_err());
+    client.getattr(file.nodeid).unwrap();
+}
+
+#[test]
+fn test_pause_timeout() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("file"), b"").unwrap();
+    let mut client = TestClient::passthrough(dir.path());
+    let file = client.lookup(ROOT_ID, "file").unwrap();
+
+    // Resumes on its own
+    client
+        .pause(FsPauseOptions {
+            timeout: Some(Duration::ZERO),
+            ..Default::default()
+        })
+        .unwrap();
+    submit_getattr(&mut client, file.nodeid);
+    assert!(!client.completed());
+    client.check_pause_timeout();
+    assert_eq!(client.take_reply().unwrap().error, 0);
+    client.getattr(file.nodeid).unwrap();
+
+    // Fails the requests until resumed
+    client
+        .pause(FsPauseOptions {
+            timeout: Some(Duration::ZERO),
+            on_timeout: FsPauseTimeout::Fail,
+            ..Default::default()
+        })
+        .unwrap();
+    submit_getattr(&mut client, file.nodeid);
+    client.check_pause_timeout();
+    assert_eq!(client.take_reply().unwrap().error, libc::EIO);
+    assert_eq!(client.getattr(file.nodeid).unwrap_err(), libc::EIO);
+    client.resume().unwrap();
+    client.getattr(file.nodeid).unwrap();
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+fn submit_getattr(client: &mut TestClient, nodeid: u64) {
+    let getattr_in = GetattrIn::default();
+    client.submit(
+        Opcode::Getattr,
+        nodeid,
+        &[getattr_in.as_slice()],
+        size_of::<AttrOut>() as u32,
+    );
+}
@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+use crate::virtio::fs::fuse::ROOT_ID;
+use crate::virtio::fs::{overlayfs, FsImplConfig};
+
+use super::helper::TestClient;
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_readdirplus() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("file"), b"abc").unwrap();
+    fs::create_dir(dir.path().join("subdir")).unwrap();
+    let mut client = TestClient::passthrough(dir.path());
+
+    let handle = client.opendir(ROOT_ID).unwrap();
+    let entries = client.readdirplus(ROOT_ID, handle.fh, 0, 4096).unwrap();
+    let entries: BTreeMap<_, _> = entries.into_iter().collect();
+    assert_eq!(
+        entries.keys().map(String::as_str).collect::<Vec<_>>(),
+        [".", "..", "file", "subdir"]
+    );
+
+    // The entries come with their attributes, and are looked up
+    let file = entries["file"];
+    assert_eq!(file.dirent.type_, libc::DT_REG as u32);
+    assert_eq!(file.entry_out.attr.size, 3);
+    assert_eq!(
+        client.lookup(ROOT_ID, "file").unwrap().nodeid,
+        file.entry_out.nodeid
+    );
+    let subdir = entries["subdir"];
+    assert_eq!(subdir.dirent.type_, libc::DT_DIR as u32);
+    assert_eq!(subdir.entry_out.attr.mode & libc::S_IFMT, libc::S_IFDIR);
+
+    client.releasedir(ROOT_ID, handle.fh).unwrap();
+}
+
+#[test]
+fn test_readdirplus_offset() {
+    let dir = tempfile::tempdir().unwrap();
+    for i in 0..32 {
+        fs::write(dir.path().join(format!("file{i:02}")), b"").unwrap();
+    }
+    let mut client = TestClient::passthrough(dir.path());
+
+    // A buffer too small for all the entries is continued from the offset of the last one
+    let handle = client.opendir(ROOT_ID).unwrap();
+    let mut names = Vec::new();
+    let mut offset = 0;
+    loop {
+        let entries = client.readdirplus(ROOT_ID, handle.fh, offset, 512).unwrap();
+        let Some((_, last)) = entries.last() else {
+            break;
+        };
+        assert!(entries.len() < 34);
+        offset = last.dirent.off;
+        names.extend(entries.into_iter().map(|(name, _)| name));
+    }
+    names.sort();
+
+    let mut expected: Vec<_> = (0..32).map(|i| format!("file{i:02}")).collect();
+    expected.extend([".".to_string(), "..".to_string()]);
+    expected.sort();
+    assert_eq!(names, expected);
+
+    client.releasedir(ROOT_ID, handle.fh).unwrap();
+}
+
+#[test]
+fn test_readdirplus_overlay_resume() {
+    let lower = tempfile::tempdir().unwrap();
+    let upper = tempfile::tempdir().unwrap();
+    for i in 0..48 {
+        fs::write(lower.path().join(format!("file{i:02}")), b"").unwrap();
+    }
+    for i in 40..56 {
+        fs::write(upper.path().join(format!("file{i:02}")), b"").unwrap();
+    }
+    fs::write(upper.path().join(".wh.file07"), b"").unwrap();
+    let fs_config = FsImplConfig::Overlayfs(overlayfs::Config {
+        layers: vec![lower.path().to_path_buf(), upper.path().to_path_buf()],
+        ..Default::default()
+    });
+    let mut client = TestClient::new(fs_config);
+
+    // The merged listing is read in pieces, each entry once
+    let handle = client.opendir(ROOT_ID).unwrap();
+    let mut listed = Vec::new();
+    let mut offset = 0;
+    loop {
+        let entries = client.readdirplus(ROOT_ID, handle.fh, offset, 512).unwrap();
+        let Some((_, last)) = entries.last() else {
+            break;
+        };
+        offset = last.dirent.off;
+        listed.extend(entries.into_iter().map(|(name, e)| (e.dirent.off, name)));
+    }
+    let mut names: Vec<_> = listed.iter().map(|(_, name)| name.clone()).collect();
+    names.sort();
+    let expected: Vec<_> = (0..56)
+        .filter(|i| *i != 7)
+        .map(|i| format!("file{i:02}"))
+        .collect();
+    assert_eq!(names, expected);
+
+    // A readdir at an earlier offset lists the same entries from there
+    let (seek, _) = listed[20];
+    let entries = client.readdirplus(ROOT_ID, handle.fh, seek, 512).unwrap();
+    assert!(!entries.is_empty());
+    for (i, (name, entry)) in entries.into_iter().enumerate() {
+        assert_eq!((entry.dirent.off, name), listed[21 + i]);
+    }
+
+    client.releasedir(ROOT_ID, handle.fh).unwrap();
+}
@@ -0,0 +1,120 @@
+use std::fs;
+use std::path::Path;
+
+use crate::virtio::fs::fuse::ROOT_ID;
+use crate::virtio::fs::{overlayfs, FsImplConfig};
+
+use super::helper::TestClient;
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_snapshot_passthrough() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir(dir.path().join("dir")).unwrap();
+    fs::write(dir.path().join("dir/file"), b"hello").unwrap();
+    fs::write(dir.path().join("other"), b"").unwrap();
+    let mut client = TestClient::passthrough(dir.path());
+    let (file, dir_handle, file_handle) = hold_files(&mut client);
+    let state = client.save_state().unwrap();
+
+    // A new device gives back the same inodes and handles, with the same lookup counts
+    let mut restored = TestClient::new(passthrough_config(dir.path()));
+    restored.restore_state(&state).unwrap();
+    check_files(&mut restored, file, dir_handle, file_handle);
+}
+
+#[test]
+fn test_snapshot_overlay() {
+    let lower = tempfile::tempdir().unwrap();
+    let upper = tempfile::tempdir().unwrap();
+    fs::create_dir(lower.path().join("dir")).unwrap();
+    fs::write(lower.path().join("dir/file"), b"hello").unwrap();
+    fs::write(upper.path().join("other"), b"").unwrap();
+    let layers = vec![lower.path().to_path_buf(), upper.path().to_path_buf()];
+    let mut client = TestClient::overlay_with_options(layers.clone(), Default::default());
+    let (file, dir_handle, file_handle) = hold_files(&mut client);
+    let state = client.save_state().unwrap();
+
+    let mut restored = TestClient::new(FsImplConfig::Overlayfs(overlayfs::Config {
+        layers,
+        ..Default::default()
+    }));
+    restored.restore_state(&state).unwrap();
+    check_files(&mut restored, file, dir_handle, file_handle);
+}
+
+#[test]
+fn test_snapshot_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("file"), b"").unwrap();
+    let mut client = TestClient::passthrough(dir.path());
+
+    // Deleted files the guest holds can't be found again
+    let file = client.lookup(ROOT_ID, "file").unwrap();
+    client.unlink(ROOT_ID, "file").unwrap();
+    assert!(client.save_state().is_err());
+    client.forget(file.nodeid, 1);
+    let state = client.save_state().unwrap();
+
+    let mut restored = TestClient::new(passthrough_config(dir.path()));
+    assert!(restored.restore_state(&state[..state.len() - 1]).is_err());
+
+    // Files deleted since the snapshot are missing
+    fs::write(dir.path().join("file"), b"").unwrap();
+    let mut client = TestClient::passthrough(dir.path());
+    client.lookup(ROOT_ID, "file").unwrap();
+    let state = client.save_state().unwrap();
+    fs::remove_file(dir.path().join("file")).unwrap();
+    let mut restored = TestClient::new(passthrough_config(dir.path()));
+    assert!(restored.restore_state(&state).is_err());
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+fn passthrough_config(root: &Path) -> FsImplConfig {
+    FsImplConfig::Passthrough(crate::virtio::fs::passthrough::Config {
+        root_dir: root.to_str().unwrap().to_string(),
+        ..Default::default()
+    })
+}
+
+/// Has the guest hold `dir/file` twice, open, and `dir` open, after dropping the inode of `other`
+/// so that a new device wouldn't number them the same. Returns the inode of the file and the
+/// handles of the directory and the file.
+fn hold_files(client: &mut TestClient) -> (u64, u64, u64) {
+    let other = client.lookup(ROOT_ID, "other").unwrap();
+    client.forget(other.nodeid, 1);
+
+    let dir = client.lookup(ROOT_ID, "dir").unwrap();
+    let file = client.lookup(dir.nodeid, "file").unwrap();
+    client.lookup(dir.nodeid, "file").unwrap();
+    let dir_handle = client.opendir(dir.nodeid).unwrap().fh;
+    let file_handle = client.open(file.nodeid, libc::O_RDWR).unwrap().fh;
+    (file.nodeid, dir_handle, file_handle)
+}
+
+fn check_files(client: &mut TestClient, file: u64, dir_handle: u64, file_handle: u64) {
+    assert_eq!(client.read(file, file_handle, 0, 16).unwrap(), b"hello");
+    client.write(file, file_handle, 5, b" world").unwrap();
+    assert_eq!(
+        client.read(file, file_handle, 0, 16).unwrap(),
+        b"hello world"
+    );
+
+    let dir = client.lookup(ROOT_ID, "dir").unwrap();
+    let entries = client.readdirplus(dir.nodeid, dir_handle, 0, 4096).unwrap();
+    assert!(entries.iter().any(|(name, _)| name == "file"));
+    client.releasedir(dir.nodeid, dir_handle).unwrap();
+    client.release(file, file_handle).unwrap();
+
+    // The file was looked up twice before the snapshot, and once more by `readdirplus`
+    client.forget(file, 2);
+    assert!(client.getattr(file).is_ok());
+    client.forget(file, 1);
+    assert_eq!(client.getattr(file).unwrap_err(), libc::EBADF);
+}
@@ -0,0 +1,47 @@
+use std::fs;
+use std::time::Duration;
+
+use crate::virtio::fs::fuse::{KERNEL_MINOR_VERSION, KERNEL_VERSION, ROOT_ID};
+use crate::virtio::fs::{passthrough, FsImplConfig, FsLeases};
+
+use super::helper::{DeviceOptions, TestClient};
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_shortened_timeouts_invalidate() {
+    let root = tempfile::tempdir().unwrap();
+    fs::write(root.path().join("a"), b"a").unwrap();
+    let fs_config = FsImplConfig::Passthrough(passthrough::Config {
+        root_dir: root.path().to_str().unwrap().to_string(),
+        ..Default::default()
+    });
+    let options = DeviceOptions {
+        leases: Some(FsLeases {
+            timeout: Duration::from_secs(3600),
+        }),
+        ..Default::default()
+    };
+    let mut client = TestClient::with_options(fs_config, options);
+    client.init(KERNEL_VERSION, KERNEL_MINOR_VERSION).unwrap();
+    let a = client.lookup(ROOT_ID, "a").unwrap();
+    client.provide_notify_buffers(8);
+    assert!(client.take_invalidations().is_empty());
+
+    // The guest is told to drop what it cached of the inodes it holds
+    client.set_cache_timeouts(Duration::from_secs(1), Duration::from_secs(1));
+    let invalidated = client.take_invalidations();
+    assert!(invalidated.contains(&a.nodeid));
+
+    // Longer timeouts don't make the cached attributes wrong
+    client.set_cache_timeouts(Duration::from_secs(10), Duration::from_secs(1));
+    assert!(client.take_invalidations().is_empty());
+
+    // Nor do the same ones, but any shorter timeout does
+    client.set_cache_timeouts(Duration::from_secs(10), Duration::from_secs(1));
+    assert!(client.take_invalidations().is_empty());
+    client.set_cache_timeouts(Duration::from_secs(10), Duration::from_millis(500));
+    assert!(client.take_invalidations().contains(&a.nodeid));
+}
@@ -0,0 +1,44 @@
+use crate::virtio::fs::fuse::ROOT_ID;
+use crate::virtio::fs::FsTracer;
+
+use super::helper::{DeviceOptions, TestClient};
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_trace_requests() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("a"), b"a").unwrap();
+    let tracer = FsTracer::default();
+    let options = DeviceOptions {
+        tracer: tracer.clone(),
+        ..Default::default()
+    };
+    let mut client = TestClient::passthrough_with_options(dir.path(), options);
+
+    // Nothing is recorded until tracing is enabled
+    client.lookup(ROOT_ID, "a").unwrap();
+    assert_eq!(tracer.chrome_trace(), "{\"traceEvents\":[]}");
+
+    tracer.enable();
+    client.lookup(ROOT_ID, "a").unwrap();
+    assert_eq!(client.lookup(ROOT_ID, "b").unwrap_err(), libc::ENOENT);
+    tracer.disable();
+    client.lookup(ROOT_ID, "a").unwrap();
+
+    // Each request traced has its three stages, with the unique ID the guest sent it with
+    let json = tracer.chrome_trace();
+    assert_eq!(
+        json.matches("\"name\":\"FUSE_LOOKUP\"").count(),
+        6,
+        "{json}"
+    );
+    for stage in ["vring", "host", "publish"] {
+        assert_eq!(json.matches(&format!("\"cat\":\"{stage}\"")).count(), 2);
+    }
+    assert!(json.contains("\"unique\":3,\"opcode\":1"), "{json}");
+    assert!(json.contains("\"unique\":4,\"opcode\":1"), "{json}");
+    assert!(json.contains("\"tid\":1,"), "{json}");
+}
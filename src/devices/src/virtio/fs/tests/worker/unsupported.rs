@@ -0,0 +1,51 @@
+use std::collections::BTreeMap;
+
+use crate::virtio::fs::fuse::{Opcode, KERNEL_MINOR_VERSION, KERNEL_VERSION, ROOT_ID};
+use crate::virtio::fs::FsUnsupportedStats;
+
+use super::helper::{DeviceOptions, TestClient};
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_unsupported_opcodes() {
+    let dir = tempfile::tempdir().unwrap();
+    let stats = FsUnsupportedStats::default();
+    let options = DeviceOptions {
+        unsupported: stats.clone(),
+        ..Default::default()
+    };
+    let mut client = TestClient::passthrough_with_options(dir.path(), options);
+
+    // Every opcode is answered, or dropped for its malformed arguments, without bringing the
+    // device down. The ones the server doesn't implement fail with ENOSYS.
+    let args = [0u8; 512];
+    let stubs = [
+        Opcode::Bmap,
+        Opcode::Poll,
+        Opcode::NotifyReply,
+        // There is no shared memory region to map files into
+        Opcode::SetupMapping,
+        Opcode::RemoveMapping,
+    ]
+    .map(|opcode| opcode as u32);
+    let mut expected = BTreeMap::new();
+    let opcodes = (0..=64).chain([4096, u32::MAX]);
+    for opcode in opcodes.filter(|x| *x != Opcode::Init as u32 && *x != Opcode::Destroy as u32) {
+        let reply = client.send_raw(opcode, ROOT_ID, &[&args], 4096);
+        if Opcode::try_from(opcode).is_err() || stubs.contains(&opcode) {
+            assert_eq!(reply.unwrap().error, libc::ENOSYS, "opcode {opcode}");
+            expected.insert(opcode, 1);
+        }
+    }
+    assert_eq!(stats.counts(), expected);
+    assert_eq!(stats.total(), expected.len() as u64);
+    client.getattr(ROOT_ID).unwrap();
+
+    // The session itself is still handled as usual
+    client.send_raw(Opcode::Init as u32, 0, &[&args], 4096);
+    client.init(KERNEL_VERSION, KERNEL_MINOR_VERSION).unwrap();
+    assert!(client.send_raw(Opcode::Destroy as u32, 0, &[], 0).is_none());
+}
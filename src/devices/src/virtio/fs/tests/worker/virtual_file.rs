@@ -0,0 +1,254 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::virtio::fs::fuse::{OpenOptions, ROOT_ID};
+use crate::virtio::fs::{FsInodeNumbers, FsVirtualAttr, FsVirtualFile};
+
+use super::helper::{DeviceOptions, TestClient};
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_virtual_file_read() {
+    let dir = tempfile::tempdir().unwrap();
+    let (file, content) = virtual_file("token");
+    let mut client = virtual_file_client(dir.path(), vec![file]);
+
+    let entry = client.lookup(ROOT_ID, "token").unwrap();
+    assert_eq!(entry.attr.mode & libc::S_IFMT, libc::S_IFREG);
+    assert_eq!(entry.attr.mode & 0o7777, 0o440);
+    assert_eq!((entry.attr.size, entry.attr.mtime), (5, 1000));
+    assert_eq!((entry.entry_valid, entry.attr_valid), (0, 0));
+
+    let handle = client.open(entry.nodeid, libc::O_RDONLY).unwrap();
+    assert!(OpenOptions::from_bits_truncate(handle.open_flags).contains(OpenOptions::DIRECT_IO));
+    assert_eq!(
+        client.read(entry.nodeid, handle.fh, 0, 4096).unwrap(),
+        b"first"
+    );
+
+    // The content is read again every time, as it may change at any time
+    *content.lock().unwrap() = b"second".to_vec();
+    assert_eq!(client.getattr(entry.nodeid).unwrap().attr.size, 6);
+    assert_eq!(client.read(entry.nodeid, handle.fh, 2, 3).unwrap(), b"con");
+    assert_eq!(client.read(entry.nodeid, handle.fh, 10, 3).unwrap(), b"");
+    client.release(entry.nodeid, handle.fh).unwrap();
+
+    // Virtual files are read-only regular files
+    assert_eq!(
+        client.open(entry.nodeid, libc::O_RDWR).unwrap_err(),
+        libc::EACCES
+    );
+    assert_eq!(
+        client.write(entry.nodeid, 0, 0, b"x").unwrap_err(),
+        libc::EPERM
+    );
+    assert_eq!(client.opendir(entry.nodeid).unwrap_err(), libc::ENOTDIR);
+    assert_eq!(client.lookup(entry.nodeid, "a").unwrap_err(), libc::ENOTDIR);
+    client.forget(entry.nodeid, 1);
+}
+
+#[test]
+fn test_virtual_file_shadows_host_file() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir(dir.path().join("etc")).unwrap();
+    fs::write(dir.path().join("etc/metadata.json"), b"host").unwrap();
+    fs::write(dir.path().join("etc/other"), b"host").unwrap();
+    let (file, _) = virtual_file("/etc/metadata.json");
+    let mut client = virtual_file_client(dir.path(), vec![file]);
+
+    let etc = client.lookup(ROOT_ID, "etc").unwrap();
+    let entry = client.lookup(etc.nodeid, "metadata.json").unwrap();
+    let handle = client.open(entry.nodeid, libc::O_RDONLY).unwrap();
+    assert_eq!(
+        client.read(entry.nodeid, handle.fh, 0, 4096).unwrap(),
+        b"first"
+    );
+    client.release(entry.nodeid, handle.fh).unwrap();
+
+    // Only the path of the virtual file is shadowed
+    assert_ne!(client.lookup(etc.nodeid, "other").unwrap().attr.size, 5);
+    assert_eq!(
+        client.lookup(ROOT_ID, "metadata.json").unwrap_err(),
+        libc::ENOENT
+    );
+
+    // The guest doesn't cache the entry of the host file when it lists the directory
+    let handle = client.opendir(etc.nodeid).unwrap();
+    let entries = client.readdirplus(etc.nodeid, handle.fh, 0, 4096).unwrap();
+    let (_, shadowed) = entries
+        .iter()
+        .find(|(name, _)| name == "metadata.json")
+        .unwrap();
+    assert_eq!(shadowed.entry_out.nodeid, 0);
+    let (_, other) = entries.iter().find(|(name, _)| name == "other").unwrap();
+    assert_ne!(other.entry_out.nodeid, 0);
+    client.releasedir(etc.nodeid, handle.fh).unwrap();
+}
+
+#[test]
+fn test_virtual_file_entry_protected() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("token"), b"host").unwrap();
+    fs::write(dir.path().join("other"), b"host").unwrap();
+    let (file, _) = virtual_file("token");
+    let mut client = virtual_file_client(dir.path(), vec![file]);
+
+    // The guest can neither remove nor replace the entry of a virtual file
+    assert_eq!(client.unlink(ROOT_ID, "token").unwrap_err(), libc::EPERM);
+    assert_eq!(
+        client
+            .rename(ROOT_ID, "other", ROOT_ID, "token")
+            .unwrap_err(),
+        libc::EPERM
+    );
+    assert_eq!(
+        client
+            .create(ROOT_ID, "token", 0o644, libc::O_RDWR)
+            .unwrap_err(),
+        libc::EPERM
+    );
+    assert!(dir.path().join("token").exists());
+    client.unlink(ROOT_ID, "other").unwrap();
+}
+
+#[test]
+fn test_virtual_file_folded_inode() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("host"), b"host").unwrap();
+    let (file, _) = virtual_file("token");
+    let options = DeviceOptions {
+        virtual_files: vec![file],
+        inode_numbers: FsInodeNumbers::Folded32,
+        ..Default::default()
+    };
+    let mut client = TestClient::passthrough_with_options(dir.path(), options);
+
+    // The virtual files are numbered past 32 bits
+    let entry = client.lookup(ROOT_ID, "token").unwrap();
+    assert!(entry.attr.ino != 0 && entry.attr.ino <= u32::MAX as u64);
+    assert_eq!(
+        client.getattr(entry.nodeid).unwrap().attr.ino,
+        entry.attr.ino
+    );
+
+    // A file is listed with the number it is looked up with
+    let host = client.lookup(ROOT_ID, "host").unwrap();
+    let handle = client.opendir(ROOT_ID).unwrap();
+    let entries = client.readdirplus(ROOT_ID, handle.fh, 0, 4096).unwrap();
+    let (_, listed) = entries.iter().find(|(name, _)| name == "host").unwrap();
+    assert_eq!(listed.dirent.ino, host.attr.ino);
+    assert_eq!(listed.entry_out.attr.ino, host.attr.ino);
+    client.releasedir(ROOT_ID, handle.fh).unwrap();
+}
+
+#[test]
+fn test_init_config_read_only() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join(".krun_config.json"), b"{}").unwrap();
+    let options = DeviceOptions {
+        protect_init_config: true,
+        ..Default::default()
+    };
+    let mut client = TestClient::passthrough_with_options(dir.path(), options);
+
+    let entry = client.lookup(ROOT_ID, ".krun_config.json").unwrap();
+    assert_eq!(entry.attr.mode & 0o7777, 0o444);
+    let handle = client.open(entry.nodeid, libc::O_RDONLY).unwrap();
+    assert_eq!(
+        client.read(entry.nodeid, handle.fh, 0, 4096).unwrap(),
+        b"{}"
+    );
+    client.release(entry.nodeid, handle.fh).unwrap();
+
+    // The content is the one the guest first saw, whatever happens to the host file
+    fs::write(dir.path().join(".krun_config.json"), b"{\"Cmd\":[]}").unwrap();
+    assert_eq!(client.getattr(entry.nodeid).unwrap().attr.size, 2);
+    assert_eq!(
+        client.open(entry.nodeid, libc::O_WRONLY).unwrap_err(),
+        libc::EACCES
+    );
+    assert_eq!(
+        client.unlink(ROOT_ID, ".krun_config.json").unwrap_err(),
+        libc::EPERM
+    );
+
+    // The init binary is just as read-only
+    let init = client.lookup(ROOT_ID, "init.krun").unwrap();
+    assert_eq!(
+        client.open(init.nodeid, libc::O_RDWR).unwrap_err(),
+        libc::EACCES
+    );
+    assert_eq!(
+        client.write(init.nodeid, 0, 0, b"x").unwrap_err(),
+        libc::EPERM
+    );
+    assert_eq!(
+        client
+            .rename(ROOT_ID, "init.krun", ROOT_ID, "other")
+            .unwrap_err(),
+        libc::EPERM
+    );
+}
+
+#[test]
+fn test_init_config_missing() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = DeviceOptions {
+        protect_init_config: true,
+        ..Default::default()
+    };
+    let mut client = TestClient::passthrough_with_options(dir.path(), options);
+
+    assert_eq!(
+        client.lookup(ROOT_ID, ".krun_config.json").unwrap_err(),
+        libc::ENOENT
+    );
+    assert_eq!(
+        client
+            .create(ROOT_ID, ".krun_config.json", 0o644, libc::O_RDWR)
+            .unwrap_err(),
+        libc::EPERM
+    );
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Returns a virtual file at `path` and its content, initially "first".
+fn virtual_file(path: &str) -> (FsVirtualFile, Arc<Mutex<Vec<u8>>>) {
+    let content = Arc::new(Mutex::new(b"first".to_vec()));
+    let getattr_content = content.clone();
+    let read_content = content.clone();
+    let file = FsVirtualFile {
+        path: PathBuf::from(path),
+        mode: 0o440,
+        getattr: Arc::new(move || {
+            Ok(FsVirtualAttr {
+                size: getattr_content.lock().unwrap().len() as u64,
+                mtime: SystemTime::UNIX_EPOCH + Duration::from_secs(1000),
+            })
+        }),
+        read: Arc::new(move |offset, buf| {
+            let content = read_content.lock().unwrap();
+            let content = content.get(offset as usize..).unwrap_or_default();
+            let len = content.len().min(buf.len());
+            buf[..len].copy_from_slice(&content[..len]);
+            Ok(len)
+        }),
+    };
+    (file, content)
+}
+
+fn virtual_file_client(root: &std::path::Path, virtual_files: Vec<FsVirtualFile>) -> TestClient {
+    let options = DeviceOptions {
+        virtual_files,
+        ..Default::default()
+    };
+    TestClient::passthrough_with_options(root, options)
+}
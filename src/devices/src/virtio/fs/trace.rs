@@ -0,0 +1,281 @@
+//! Per-request tracing of the virtio-fs device.
+//!
+//! Each request is timestamped when its descriptor chain is popped from the virtqueue, when it is
+//! dispatched to the file system after its header was decoded, when the file system returns, and
+//! when its completion is published to the guest. The traces are exported in the Chrome trace event
+//! format, with the FUSE unique ID of every request in its arguments, so that they can be lined up
+//! with the `fuse_request_send`/`fuse_request_end` events recorded in the guest.
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use super::fuse::Opcode;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// Number of requests kept by default, the oldest ones being dropped first.
+const DEFAULT_CAPACITY: usize = 64 << 10;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// Records the timing of the requests handled by a virtio-fs device. Clones share the same
+/// records, so a handle obtained from the device before it is activated can be used to turn
+/// tracing on and to export the traces while the guest is running.
+#[derive(Clone)]
+pub struct FsTracer(Arc<TracerState>);
+
+struct TracerState {
+    enabled: AtomicBool,
+    capacity: usize,
+    epoch: Instant,
+    epoch_us: u64,
+    records: Mutex<VecDeque<RequestTrace>>,
+}
+
+/// The timestamps of a single request.
+#[derive(Clone, Debug)]
+pub struct RequestTrace {
+    pub queue: usize,
+    pub unique: u64,
+    pub opcode: u32,
+    pub popped: Instant,
+    pub dispatched: Option<Instant>,
+    pub returned: Option<Instant>,
+    pub published: Option<Instant>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl FsTracer {
+    /// Creates a disabled tracer keeping up to `capacity` requests.
+    pub fn new(capacity: usize) -> Self {
+        let epoch_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_micros() as u64);
+        FsTracer(Arc::new(TracerState {
+            enabled: AtomicBool::new(false),
+            capacity,
+            epoch: Instant::now(),
+            epoch_us,
+            records: Mutex::new(VecDeque::new()),
+        }))
+    }
+
+    pub fn enable(&self) {
+        self.0.enabled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn disable(&self) {
+        self.0.enabled.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Starts the trace of a request popped from `queue`, if tracing is enabled.
+    pub(crate) fn start(&self, queue: usize) -> Option<RequestTrace> {
+        self.is_enabled().then(|| RequestTrace {
+            queue,
+            unique: 0,
+            opcode: 0,
+            popped: Instant::now(),
+            dispatched: None,
+            returned: None,
+            published: None,
+        })
+    }
+
+    /// Stores the traces of requests whose completions were just published.
+    pub(crate) fn finish(&self, traces: impl IntoIterator<Item = RequestTrace>) {
+        let published = Instant::now();
+        let mut records = self.0.records.lock().unwrap();
+        for mut trace in traces {
+            trace.published = Some(published);
+            if records.len() == self.0.capacity {
+                records.pop_front();
+            }
+            records.push_back(trace);
+        }
+    }
+
+    /// Drops the recorded requests.
+    pub fn clear(&self) {
+        self.0.records.lock().unwrap().clear();
+    }
+
+    /// Returns the recorded requests as a Chrome trace event JSON document. Every request is made
+    /// of three complete events, one per stage: "vring" from the pop to the dispatch, "host" from
+    /// the dispatch to the return of the file system and "publish" from the return to the
+    /// completion being published. Timestamps are in microseconds since the Unix epoch, and each
+    /// virtqueue is shown as a separate thread.
+    pub fn chrome_trace(&self) -> String {
+        let records = self.0.records.lock().unwrap();
+        let mut out = String::from("{\"traceEvents\":[");
+        let mut first = true;
+        for trace in records.iter() {
+            let (Some(dispatched), Some(returned), Some(published)) =
+                (trace.dispatched, trace.returned, trace.published)
+            else {
+                continue;
+            };
+
+            for (stage, start, end) in [
+                ("vring", trace.popped, dispatched),
+                ("host", dispatched, returned),
+                ("publish", returned, published),
+            ] {
+                if !first {
+                    out.push(',');
+                }
+                first = false;
+                let _ = write!(
+                    out,
+                    "{{\"name\":\"{}\",\"cat\":\"{}\",\"ph\":\"X\",\"ts\":{},\"dur\":{},\"pid\":0,\
+                     \"tid\":{},\"args\":{{\"unique\":{},\"opcode\":{}}}}}",
+                    opcode_name(trace.opcode),
+                    stage,
+                    self.timestamp_us(start),
+                    end.saturating_duration_since(start).as_micros(),
+                    trace.queue,
+                    trace.unique,
+                    trace.opcode,
+                );
+            }
+        }
+        out.push_str("]}");
+        out
+    }
+
+    fn timestamp_us(&self, instant: Instant) -> u64 {
+        self.0.epoch_us + instant.saturating_duration_since(self.0.epoch).as_micros() as u64
+    }
+}
+
+impl Default for FsTracer {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Returns the name the guest uses for a FUSE opcode in its traces.
+fn opcode_name(opcode: u32) -> &'static str {
+    const NAMES: &[(Opcode, &str)] = &[
+        (Opcode::Lookup, "FUSE_LOOKUP"),
+        (Opcode::Forget, "FUSE_FORGET"),
+        (Opcode::Getattr, "FUSE_GETATTR"),
+        (Opcode::Setattr, "FUSE_SETATTR"),
+        (Opcode::Readlink, "FUSE_READLINK"),
+        (Opcode::Symlink, "FUSE_SYMLINK"),
+        (Opcode::Mknod, "FUSE_MKNOD"),
+        (Opcode::Mkdir, "FUSE_MKDIR"),
+        (Opcode::Unlink, "FUSE_UNLINK"),
+        (Opcode::Rmdir, "FUSE_RMDIR"),
+        (Opcode::Rename, "FUSE_RENAME"),
+        (Opcode::Link, "FUSE_LINK"),
+        (Opcode::Open, "FUSE_OPEN"),
+        (Opcode::Read, "FUSE_READ"),
+        (Opcode::Write, "FUSE_WRITE"),
+        (Opcode::Statfs, "FUSE_STATFS"),
+        (Opcode::Release, "FUSE_RELEASE"),
+        (Opcode::Fsync, "FUSE_FSYNC"),
+        (Opcode::Setxattr, "FUSE_SETXATTR"),
+        (Opcode::Getxattr, "FUSE_GETXATTR"),
+        (Opcode::Listxattr, "FUSE_LISTXATTR"),
+        (Opcode::Removexattr, "FUSE_REMOVEXATTR"),
+        (Opcode::Flush, "FUSE_FLUSH"),
+        (Opcode::Init, "FUSE_INIT"),
+        (Opcode::Opendir, "FUSE_OPENDIR"),
+        (Opcode::Readdir, "FUSE_READDIR"),
+        (Opcode::Releasedir, "FUSE_RELEASEDIR"),
+        (Opcode::Fsyncdir, "FUSE_FSYNCDIR"),
+        (Opcode::Getlk, "FUSE_GETLK"),
+        (Opcode::Setlk, "FUSE_SETLK"),
+        (Opcode::Setlkw, "FUSE_SETLKW"),
+        (Opcode::Access, "FUSE_ACCESS"),
+        (Opcode::Create, "FUSE_CREATE"),
+        (Opcode::Interrupt, "FUSE_INTERRUPT"),
+        (Opcode::Bmap, "FUSE_BMAP"),
+        (Opcode::Destroy, "FUSE_DESTROY"),
+        (Opcode::Ioctl, "FUSE_IOCTL"),
+        (Opcode::Poll, "FUSE_POLL"),
+        (Opcode::NotifyReply, "FUSE_NOTIFY_REPLY"),
+        (Opcode::BatchForget, "FUSE_BATCH_FORGET"),
+        (Opcode::Fallocate, "FUSE_FALLOCATE"),
+        (Opcode::Readdirplus, "FUSE_READDIRPLUS"),
+        (Opcode::Rename2, "FUSE_RENAME2"),
+        (Opcode::Lseek, "FUSE_LSEEK"),
+        (Opcode::CopyFileRange, "FUSE_COPY_FILE_RANGE"),
+        (Opcode::SetupMapping, "FUSE_SETUPMAPPING"),
+        (Opcode::RemoveMapping, "FUSE_REMOVEMAPPING"),
+        (Opcode::Tmpfile, "FUSE_TMPFILE"),
+        (Opcode::Statx, "FUSE_STATX"),
+    ];
+
+    NAMES
+        .iter()
+        .find(|(op, _)| *op as u32 == opcode)
+        .map_or("FUSE_UNKNOWN", |(_, name)| name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn completed(tracer: &FsTracer, unique: u64) -> RequestTrace {
+        let mut trace = tracer.start(1).unwrap();
+        trace.unique = unique;
+        trace.opcode = Opcode::Lookup as u32;
+        trace.dispatched = Some(Instant::now());
+        trace.returned = Some(Instant::now());
+        trace
+    }
+
+    #[test]
+    fn disabled() {
+        let tracer = FsTracer::default();
+        assert!(tracer.start(1).is_none());
+        assert_eq!(tracer.chrome_trace(), "{\"traceEvents\":[]}");
+    }
+
+    #[test]
+    fn chrome_trace() {
+        let tracer = FsTracer::new(2);
+        tracer.enable();
+
+        // Requests rejected before being dispatched are left out
+        let rejected = tracer.start(1).unwrap();
+        tracer.finish([rejected]);
+        tracer.finish([completed(&tracer, 7)]);
+
+        let json = tracer.chrome_trace();
+        assert_eq!(json.matches("\"ph\":\"X\"").count(), 3);
+        for stage in ["vring", "host", "publish"] {
+            assert!(json.contains(&format!("\"cat\":\"{stage}\"")), "{json}");
+        }
+        assert!(json.contains("\"name\":\"FUSE_LOOKUP\""));
+        assert!(json.contains("\"unique\":7,\"opcode\":1"));
+
+        // The oldest requests are dropped once the capacity is reached
+        tracer.finish([completed(&tracer, 8), completed(&tracer, 9)]);
+        let json = tracer.chrome_trace();
+        assert!(!json.contains("\"unique\":7,"));
+        assert!(json.contains("\"unique\":9,"));
+
+        tracer.clear();
+        assert_eq!(tracer.chrome_trace(), "{\"traceEvents\":[]}");
+    }
+}
@@ -0,0 +1,258 @@
+//! The Unicode normalization of the names of the entries of a passthrough share.
+//!
+//! A name may be spelled with distinct byte sequences that Unicode takes as the same text, e.g. an
+//! accented letter either precomposed (NFC, which Linux guests mostly use) or decomposed into the
+//! letter and a combining mark (NFD). Linux file systems store and match names byte for byte, while
+//! some host volumes, such as the HFS+ ones of macOS, store them decomposed, and others, such as the
+//! network shares of macOS, match them byte for byte but were filled with decomposed names. A file
+//! the guest just created may then be listed under other bytes than the ones the guest gave, or be
+//! missing under the bytes the guest looks it up with.
+//!
+//! With `normalize_names` set in the config of a passthrough share, a lookup of a name missing on
+//! the host falls back to the entry of the directory whose name is canonically equivalent, the
+//! exact one being preferred if it exists. The share also probes whether its host volume stores
+//! the names in another form than the one they are given in: if it does, each entry the guest
+//! creates under a name the volume would change keeps the bytes of the guest in the
+//! [`GUEST_NAME_XATTR`] extended attribute, and is listed under them for as long as they are
+//! equivalent to the name of the entry on the host.
+
+use std::ffi::{CStr, CString, OsStr};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use unicode_normalization::UnicodeNormalization;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The extended attribute keeping the name the guest gave an entry, when the host volume changed
+/// it.
+pub(crate) const GUEST_NAME_XATTR: &CStr = c"user.vm.guest_name";
+
+/// The name of the file created to probe the host volume, precomposed.
+const PROBE_NAME: &str = "\u{e9}";
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Returns the canonical decomposition (NFD) of `name`, or `None` if the name is ASCII, which has
+/// no other form, or isn't UTF-8, which has none at all.
+pub(crate) fn decompose(name: &[u8]) -> Option<String> {
+    if name.is_ascii() {
+        return None;
+    }
+    let name = std::str::from_utf8(name).ok()?;
+    Some(name.nfd().collect())
+}
+
+/// Returns whether the names `a` and `b` are the same text, whatever their normalization forms.
+pub(crate) fn equivalent(a: &[u8], b: &[u8]) -> bool {
+    if a == b {
+        return true;
+    }
+    match (decompose(a), decompose(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Returns the name among `names` to look `name` up as: `name` itself if it is listed, or else the
+/// first one equivalent to it.
+pub(crate) fn find_equivalent<I>(names: I, name: &[u8]) -> Option<CString>
+where
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+{
+    let decomposed = decompose(name)?;
+    let mut found = None;
+    for other in names {
+        let other = other.as_ref();
+        if other == name {
+            return CString::new(other).ok();
+        }
+        if found.is_none() && decompose(other).as_deref() == Some(&decomposed) {
+            found = CString::new(other).ok();
+        }
+    }
+    found
+}
+
+/// Returns the name to list the entry named `host_name` on the host as, given the name `sidecar`
+/// the guest created it with: the one of the guest, unless the entry was renamed on the host
+/// since.
+pub(crate) fn guest_name<'a>(host_name: &'a [u8], sidecar: Option<&'a [u8]>) -> &'a [u8] {
+    match sidecar {
+        Some(sidecar) if equivalent(host_name, sidecar) => sidecar,
+        _ => host_name,
+    }
+}
+
+/// Returns whether an entry the guest creates as `name` must keep it in [`GUEST_NAME_XATTR`] on a
+/// host volume storing the names decomposed.
+pub(crate) fn needs_sidecar(name: &[u8]) -> bool {
+    decompose(name).is_some_and(|decomposed| decomposed.as_bytes() != name)
+}
+
+/// Returns whether the host volume of the directory `dir` stores the names in another form than
+/// the one they are given in, by creating an entry with a precomposed name in a new directory and
+/// listing it. A volume the probe can't write to is taken as storing them as they are.
+pub(crate) fn host_changes_names(dir: &Path) -> bool {
+    let probe_dir = dir.join(format!(".krun-name-probe-{}", std::process::id()));
+    if fs::create_dir(&probe_dir).is_err() {
+        return false;
+    }
+    let res = (|| -> io::Result<bool> {
+        fs::File::create(probe_dir.join(PROBE_NAME))?;
+        for entry in fs::read_dir(&probe_dir)? {
+            if entry?.file_name() != OsStr::new(PROBE_NAME) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    })();
+    if let Err(e) = fs::remove_dir_all(&probe_dir) {
+        warn!(
+            "virtio-fs: failed to remove the name probe {}: {e}",
+            probe_dir.display()
+        );
+    }
+    match res {
+        Ok(changed) => changed,
+        Err(e) => {
+            debug!("virtio-fs: failed to probe the names of the host volume: {e}");
+            false
+        }
+    }
+}
+
+/// Returns the name the guest gave the entry at `path`, kept in [`GUEST_NAME_XATTR`], if any.
+pub(crate) fn read_sidecar(path: &CStr) -> Option<CString> {
+    let mut buf = vec![0u8; libc::PATH_MAX as usize];
+    // Safe because this only writes to `buf`, within its length, and we check the return value.
+    #[cfg(target_os = "linux")]
+    let res = unsafe {
+        libc::lgetxattr(
+            path.as_ptr(),
+            GUEST_NAME_XATTR.as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+        )
+    };
+    #[cfg(target_os = "macos")]
+    let res = unsafe {
+        libc::getxattr(
+            path.as_ptr(),
+            GUEST_NAME_XATTR.as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            0,
+            libc::XATTR_NOFOLLOW,
+        )
+    };
+    if res < 0 {
+        return None;
+    }
+    buf.truncate(res as usize);
+    CString::new(buf).ok()
+}
+
+/// Keeps the name `name` the guest gave the entry at `path` in [`GUEST_NAME_XATTR`]. The volumes
+/// without user extended attributes on some entries, such as the symlinks on Linux, list them
+/// under their host names.
+pub(crate) fn write_sidecar(path: &CStr, name: &[u8]) {
+    // Safe because this doesn't modify any memory and we check the return value.
+    #[cfg(target_os = "linux")]
+    let res = unsafe {
+        libc::lsetxattr(
+            path.as_ptr(),
+            GUEST_NAME_XATTR.as_ptr(),
+            name.as_ptr() as *const libc::c_void,
+            name.len(),
+            0,
+        )
+    };
+    #[cfg(target_os = "macos")]
+    let res = unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            GUEST_NAME_XATTR.as_ptr(),
+            name.as_ptr() as *const libc::c_void,
+            name.len(),
+            0,
+            libc::XATTR_NOFOLLOW,
+        )
+    };
+    if res < 0 {
+        debug!(
+            "virtio-fs: failed to keep the guest name of {}: {}",
+            OsStr::from_bytes(path.to_bytes()).to_string_lossy(),
+            io::Error::last_os_error()
+        );
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Tests
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn normalize() {
+        // Precomposed letters, Hangul syllables, and the marks out of canonical order
+        assert_eq!(decompose("caf\u{e9}".as_bytes()).unwrap(), "cafe\u{301}");
+        assert_eq!(
+            decompose("\u{d55c}".as_bytes()).unwrap(),
+            "\u{1112}\u{1161}\u{11ab}"
+        );
+        assert_eq!(
+            decompose("\u{ac00}".as_bytes()).unwrap(),
+            "\u{1100}\u{1161}"
+        );
+        assert_eq!(
+            decompose("a\u{301}\u{323}".as_bytes()).unwrap(),
+            "a\u{323}\u{301}"
+        );
+        assert_eq!(decompose("\u{1e69}".as_bytes()).unwrap(), "s\u{323}\u{307}");
+        assert!(decompose(b"ascii").is_none());
+        assert!(decompose(b"\xff\xfe").is_none());
+
+        assert!(equivalent("caf\u{e9}".as_bytes(), "cafe\u{301}".as_bytes()));
+        assert!(!equivalent("caf\u{e9}".as_bytes(), b"cafe"));
+        assert!(needs_sidecar("caf\u{e9}".as_bytes()));
+        assert!(!needs_sidecar("cafe\u{301}".as_bytes()));
+        assert!(!needs_sidecar(b"cafe"));
+    }
+
+    #[test]
+    fn find() {
+        let nfc = "caf\u{e9}";
+        let nfd = "cafe\u{301}";
+
+        // The exact name is preferred over an equivalent one listed before it
+        assert_eq!(
+            find_equivalent([nfd, nfc], nfc.as_bytes())
+                .unwrap()
+                .as_bytes(),
+            nfc.as_bytes()
+        );
+        assert_eq!(
+            find_equivalent(["cafe", nfd], nfc.as_bytes())
+                .unwrap()
+                .as_bytes(),
+            nfd.as_bytes()
+        );
+        assert!(find_equivalent(["cafe"], nfc.as_bytes()).is_none());
+
+        // The guest name is only kept while it is equivalent to the host one
+        let sidecar = Some(nfc.as_bytes());
+        assert_eq!(guest_name(nfd.as_bytes(), sidecar), nfc.as_bytes());
+        assert_eq!(guest_name(b"other", sidecar), b"other");
+    }
+}
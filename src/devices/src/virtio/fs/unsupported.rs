@@ -0,0 +1,53 @@
+//! The requests of the guest a share doesn't support.
+//!
+//! A guest kernel newer than the device sends the opcodes of the features it knows of, and a
+//! misbehaving guest may send anything at all. The server answers every opcode it doesn't
+//! implement with `ENOSYS`, which the guest kernel takes as the feature being missing, rather than
+//! failing the request in any other way. Each of them is logged the first time the guest sends it,
+//! and counted in a [`FsUnsupportedStats`], so that embedders can tell which features their guests
+//! look for.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use super::fuse::Opcode;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// The count of the requests of a share answered with `ENOSYS`, by opcode, kept across the servers
+/// of the share a pause may drop.
+///
+/// Cloning it gives another handle to the same counts.
+#[derive(Clone, Debug, Default)]
+pub struct FsUnsupportedStats(Arc<Mutex<BTreeMap<u32, u64>>>);
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl FsUnsupportedStats {
+    /// Returns the number of requests answered with `ENOSYS` for their opcode.
+    pub fn total(&self) -> u64 {
+        self.0.lock().unwrap().values().sum()
+    }
+
+    /// Returns the number of requests answered with `ENOSYS` of each opcode the guest sent.
+    pub fn counts(&self) -> BTreeMap<u32, u64> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Counts a request of `opcode`, which isn't supported.
+    pub(crate) fn record(&self, opcode: u32) {
+        let mut counts = self.0.lock().unwrap();
+        let count = counts.entry(opcode).or_default();
+        *count += 1;
+        if *count == 1 {
+            match Opcode::try_from(opcode) {
+                Ok(known) => warn!("virtio-fs: the guest uses the unsupported {known:?} requests"),
+                Err(()) => warn!("virtio-fs: the guest sent a request of unknown opcode {opcode}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,232 @@
+//! Files whose content is provided by the embedder rather than read from the host.
+//!
+//! The embedder registers a virtual file at a path of the share, with a callback reporting its
+//! attributes and another reading its content. The callbacks are called every time the guest
+//! stats or reads the file, whose content may then change at any time, like a rotating token. The
+//! server resolves the virtual files before the file system: a lookup of a registered path is
+//! answered with the virtual file, whatever the host or the layers of an overlay hold there.
+//!
+//! Virtual files are read-only, and aren't listed in their directory, whose entries come from the
+//! file system. The directory itself must exist in the share for the guest to reach the file.
+
+use std::ffi::OsStr;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use super::bindings;
+use super::filesystem::Entry;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The inode of the first virtual file. The file systems number their inodes sequentially from 1,
+/// so they never reach the range of the virtual files.
+const FIRST_INODE: u64 = 1 << 63;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// The attributes of a virtual file reported by the embedder.
+#[derive(Clone, Copy, Debug)]
+pub struct FsVirtualAttr {
+    pub size: u64,
+    pub mtime: SystemTime,
+}
+
+/// Called for the attributes of a virtual file, from the worker thread of the share.
+pub type FsVirtualGetattrFn = Arc<dyn Fn() -> io::Result<FsVirtualAttr> + Send + Sync>;
+
+/// Called to read the content of a virtual file at an offset into a buffer, from the worker
+/// thread of the share. Returns the number of bytes read, zero at the end of the file.
+pub type FsVirtualReadFn = Arc<dyn Fn(u64, &mut [u8]) -> io::Result<usize> + Send + Sync>;
+
+/// A read-only file at a path of a share, whose attributes and content come from the embedder.
+#[derive(Clone)]
+pub struct FsVirtualFile {
+    /// The path of the file, relative to the root of the share.
+    pub path: PathBuf,
+    /// The permission bits of the file.
+    pub mode: u32,
+    pub getattr: FsVirtualGetattrFn,
+    pub read: FsVirtualReadFn,
+}
+
+/// The virtual files of a share, numbered in the order they were registered.
+#[derive(Default)]
+pub(crate) struct VirtualFiles(Vec<FsVirtualFile>);
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl VirtualFiles {
+    pub(crate) fn new(files: Vec<FsVirtualFile>) -> Self {
+        let files = files
+            .into_iter()
+            .map(|mut file| {
+                file.path = relative_path(&file.path);
+                file
+            })
+            .collect();
+        VirtualFiles(files)
+    }
+
+    /// Returns the inode of the virtual file named `name` in the directory `parent_path` returns
+    /// the path of, which is only called if a virtual file has that name.
+    pub(crate) fn lookup(
+        &self,
+        parent_path: impl FnOnce() -> Option<PathBuf>,
+        name: &[u8],
+    ) -> Option<u64> {
+        let name = OsStr::from_bytes(name);
+        if !self
+            .0
+            .iter()
+            .any(|file| file.path.file_name() == Some(name))
+        {
+            return None;
+        }
+
+        let path = parent_path()?.join(name);
+        self.0
+            .iter()
+            .position(|file| file.path == path)
+            .map(|index| FIRST_INODE + index as u64)
+    }
+
+    /// Returns the virtual file with `inode`.
+    pub(crate) fn get(&self, inode: u64) -> Option<&FsVirtualFile> {
+        let index = inode.checked_sub(FIRST_INODE)?;
+        self.0.get(usize::try_from(index).ok()?)
+    }
+
+    /// Returns the entry of the virtual file with `inode`. Neither the entry nor the attributes
+    /// are cached by the guest, so that it always sees the current ones.
+    pub(crate) fn entry(&self, inode: u64) -> io::Result<Entry> {
+        Ok(Entry {
+            inode,
+            generation: 0,
+            attr: self.stat(inode)?,
+            attr_flags: 0,
+            attr_timeout: Duration::ZERO,
+            entry_timeout: Duration::ZERO,
+        })
+    }
+
+    /// Returns the attributes of the virtual file with `inode`, as reported by the embedder.
+    pub(crate) fn stat(&self, inode: u64) -> io::Result<bindings::stat64> {
+        let file = self
+            .get(inode)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        let attr = (file.getattr)()?;
+        let mtime = attr
+            .mtime
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+
+        // Safe because the structure is plain data, for which all zeroes is a valid value.
+        let mut st: bindings::stat64 = unsafe { mem::zeroed() };
+        st.st_ino = inode;
+        st.st_mode = libc::S_IFREG | (file.mode & 0o7777) as libc::mode_t;
+        st.st_nlink = 1;
+        st.st_size = attr.size as i64;
+        st.st_blksize = 4096;
+        st.st_blocks = attr.size.div_ceil(512) as i64;
+        st.st_mtime = mtime.as_secs() as i64;
+        st.st_mtime_nsec = mtime.subsec_nanos() as i64;
+        st.st_ctime = st.st_mtime;
+        st.st_ctime_nsec = st.st_mtime_nsec;
+        st.st_atime = st.st_mtime;
+        st.st_atime_nsec = st.st_mtime_nsec;
+        Ok(st)
+    }
+}
+
+impl fmt::Debug for FsVirtualFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FsVirtualFile")
+            .field("path", &self.path)
+            .field("mode", &format_args!("{:o}", self.mode))
+            .finish_non_exhaustive()
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Whether `inode` is in the range of the virtual files.
+pub(crate) fn is_virtual_inode(inode: u64) -> bool {
+    inode >= FIRST_INODE
+}
+
+/// Returns `path` relative to the root of the share, without its `.` components.
+fn relative_path(path: &Path) -> PathBuf {
+    let path = path.components().collect::<PathBuf>();
+    match path.strip_prefix("/") {
+        Ok(relative) => relative.to_path_buf(),
+        Err(_) => path,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn virtual_file(path: &str, content: &'static [u8]) -> FsVirtualFile {
+        FsVirtualFile {
+            path: PathBuf::from(path),
+            mode: 0o444,
+            getattr: Arc::new(move || {
+                Ok(FsVirtualAttr {
+                    size: content.len() as u64,
+                    mtime: SystemTime::UNIX_EPOCH + Duration::from_secs(10),
+                })
+            }),
+            read: Arc::new(move |offset, buf| {
+                let content = content.get(offset as usize..).unwrap_or_default();
+                let len = content.len().min(buf.len());
+                buf[..len].copy_from_slice(&content[..len]);
+                Ok(len)
+            }),
+        }
+    }
+
+    #[test]
+    fn lookup() {
+        let files = VirtualFiles::new(vec![
+            virtual_file("/etc/./metadata.json", b"{}"),
+            virtual_file("token", b"secret"),
+        ]);
+
+        let inode = files
+            .lookup(|| Some(PathBuf::from("etc")), b"metadata.json")
+            .unwrap();
+        assert!(is_virtual_inode(inode));
+        assert_eq!(
+            files.get(inode).unwrap().path,
+            Path::new("etc/metadata.json")
+        );
+        let token = files.lookup(|| Some(PathBuf::new()), b"token").unwrap();
+        assert_ne!(token, inode);
+
+        // The path of the directory is only needed for the names of virtual files
+        assert_eq!(files.lookup(|| unreachable!(), b"other"), None);
+        assert_eq!(files.lookup(|| Some(PathBuf::from("var")), b"token"), None);
+        assert!(files.get(1).is_none());
+        assert!(files.get(token + 1).is_none());
+
+        let st = files.stat(token).unwrap();
+        assert_eq!(st.st_mode & libc::S_IFMT, libc::S_IFREG);
+        assert_eq!(st.st_mode & 0o7777, 0o444);
+        assert_eq!((st.st_size, st.st_mtime), (6, 10));
+        assert_eq!(files.entry(token).unwrap().attr_timeout, Duration::ZERO);
+    }
+}
@@ -0,0 +1,309 @@
+//! Notifications of the changes the guest makes to a share.
+//!
+//! Embedders subscribe to the changes under a path of the share. The server reports every request
+//! that successfully changed a directory entry, the content of a file or its attributes, and a
+//! delivery thread hands the events to the subscribers once the share has been quiet for the
+//! debounce interval. Repeated changes of the same kind to the same path, such as the many writes
+//! of a file being copied, are coalesced into a single event.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// Time without changes after which the pending events are delivered.
+const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// Events are delivered at the latest this many debounce intervals after the first of them, even
+/// if the guest keeps changing the share.
+const MAX_DEBOUNCE_INTERVALS: u32 = 10;
+
+/// How long the delivery thread sleeps when there is no pending event, before checking whether
+/// the watcher is gone.
+const IDLE_WAIT: Duration = Duration::from_secs(1);
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// The kind of change reported by a [`FsWatchEvent`].
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FsWatchOp {
+    /// An entry was created, including by a hard link.
+    Create = 1,
+    /// The content of a file was written, truncated or allocated.
+    Write = 2,
+    /// An entry was removed.
+    Remove = 3,
+    /// An entry was renamed away from this path.
+    RenameFrom = 4,
+    /// An entry was renamed to this path.
+    RenameTo = 5,
+    /// The attributes or extended attributes of an entry changed.
+    Attrib = 6,
+}
+
+/// A change made by the guest.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FsWatchEvent {
+    /// The path of the changed entry, relative to the root of the share.
+    pub path: PathBuf,
+    pub op: FsWatchOp,
+}
+
+/// Called with the events under the watched path, from the delivery thread of the share.
+pub type FsWatchCallback = Arc<dyn Fn(&FsWatchEvent) + Send + Sync>;
+
+/// A subscription to the changes under a path of a share.
+#[derive(Clone)]
+pub struct FsWatch {
+    /// The watched path, relative to the root of the share. The empty path watches the whole share.
+    pub path: PathBuf,
+    pub callback: FsWatchCallback,
+}
+
+/// Delivers the changes made by the guest to the subscribers of a share. Clones share the same
+/// subscriptions, so a handle obtained from the device before it is activated can be used to
+/// subscribe while the guest is running.
+#[derive(Clone)]
+pub struct FsWatcher(Arc<WatcherState>);
+
+struct WatcherState {
+    debounce: Duration,
+    active: AtomicBool,
+    watches: RwLock<Vec<FsWatch>>,
+    pending: Mutex<PendingEvents>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct PendingEvents {
+    events: Vec<FsWatchEvent>,
+    seen: HashSet<FsWatchEvent>,
+    first_change: Option<Instant>,
+    last_change: Option<Instant>,
+    delivering: bool,
+    /// Whether the delivery thread is handing events to the subscribers.
+    dispatching: bool,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl FsWatch {
+    fn matches(&self, path: &Path) -> bool {
+        path.starts_with(&self.path)
+    }
+}
+
+impl FsWatcher {
+    /// Creates a watcher delivering the events once no change came in for `debounce`.
+    pub fn new(debounce: Duration) -> Self {
+        FsWatcher(Arc::new(WatcherState {
+            debounce,
+            active: AtomicBool::new(false),
+            watches: RwLock::new(Vec::new()),
+            pending: Mutex::new(PendingEvents::default()),
+            changed: Condvar::new(),
+        }))
+    }
+
+    /// Subscribes to the changes under `watch.path`, starting the delivery thread on the first
+    /// subscription.
+    pub fn watch(&self, mut watch: FsWatch) {
+        watch.path = watch.path.components().collect::<PathBuf>();
+        if let Ok(relative) = watch.path.strip_prefix("/") {
+            watch.path = relative.to_path_buf();
+        }
+        self.0.watches.write().unwrap().push(watch);
+        self.0.active.store(true, Ordering::Release);
+
+        let mut pending = self.0.pending.lock().unwrap();
+        if !pending.delivering {
+            pending.delivering = true;
+            let state = Arc::downgrade(&self.0);
+            thread::Builder::new()
+                .name("fs watch".into())
+                .spawn(move || deliver(state))
+                .unwrap();
+        }
+    }
+
+    /// Whether anyone subscribed, so that the server only looks up the paths of the changed
+    /// entries when they are needed.
+    pub(crate) fn is_active(&self) -> bool {
+        self.0.active.load(Ordering::Acquire)
+    }
+
+    /// Queues an event for delivery, unless nobody watches its path or the same event is already
+    /// pending.
+    pub(crate) fn record(&self, path: PathBuf, op: FsWatchOp) {
+        let watches = self.0.watches.read().unwrap();
+        if !watches.iter().any(|w| w.matches(&path)) {
+            return;
+        }
+        drop(watches);
+
+        let event = FsWatchEvent { path, op };
+        let now = Instant::now();
+        let mut pending = self.0.pending.lock().unwrap();
+        pending.first_change.get_or_insert(now);
+        pending.last_change = Some(now);
+        if pending.seen.insert(event.clone()) {
+            pending.events.push(event);
+        }
+        self.0.changed.notify_one();
+    }
+
+    /// Whether every change recorded so far was handed to the subscribers.
+    pub(crate) fn is_idle(&self) -> bool {
+        let pending = self.0.pending.lock().unwrap();
+        pending.events.is_empty() && !pending.dispatching
+    }
+}
+
+impl WatcherState {
+    /// Returns the pending events if they are due, or how long to wait before checking again.
+    fn take_due(&self, pending: &mut PendingEvents) -> Result<Vec<FsWatchEvent>, Duration> {
+        let (Some(first), Some(last)) = (pending.first_change, pending.last_change) else {
+            return Err(IDLE_WAIT);
+        };
+
+        let quiet = self.debounce.saturating_sub(last.elapsed());
+        let deadline = (self.debounce * MAX_DEBOUNCE_INTERVALS).saturating_sub(first.elapsed());
+        if !quiet.is_zero() && !deadline.is_zero() {
+            return Err(quiet.min(deadline));
+        }
+
+        pending.first_change = None;
+        pending.last_change = None;
+        pending.seen.clear();
+        pending.dispatching = true;
+        Ok(std::mem::take(&mut pending.events))
+    }
+
+    fn dispatch(&self, events: Vec<FsWatchEvent>) {
+        let watches = self.watches.read().unwrap().clone();
+        for event in &events {
+            for watch in watches.iter().filter(|w| w.matches(&event.path)) {
+                (watch.callback)(event);
+            }
+        }
+    }
+}
+
+impl Default for FsWatcher {
+    fn default() -> Self {
+        Self::new(DEFAULT_DEBOUNCE)
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Trait Implementations
+//--------------------------------------------------------------------------------------------------
+
+impl fmt::Debug for FsWatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FsWatch")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Debug for FsWatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FsWatcher")
+            .field("debounce", &self.0.debounce)
+            .field("watches", &self.0.watches.read().unwrap())
+            .finish()
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// The body of the delivery thread, which exits once the watcher is dropped.
+fn deliver(state: Weak<WatcherState>) {
+    while let Some(state) = state.upgrade() {
+        let mut pending = state.pending.lock().unwrap();
+        match state.take_due(&mut pending) {
+            Ok(events) => {
+                drop(pending);
+                state.dispatch(events);
+                state.pending.lock().unwrap().dispatching = false;
+            }
+            Err(wait) => {
+                let _ = state.changed.wait_timeout(pending, wait).unwrap();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn collecting_watch(path: &str) -> (FsWatch, Arc<Mutex<Vec<FsWatchEvent>>>) {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let watch = FsWatch {
+            path: PathBuf::from(path),
+            callback: {
+                let events = events.clone();
+                Arc::new(move |event| events.lock().unwrap().push(event.clone()))
+            },
+        };
+        (watch, events)
+    }
+
+    #[test]
+    fn watch() {
+        let watcher = FsWatcher::new(Duration::from_millis(20));
+        assert!(!watcher.is_active());
+
+        let (watch, events) = collecting_watch("/output/");
+        watcher.watch(watch);
+        assert!(watcher.is_active());
+
+        // Only the changes under the watched path are delivered, and repeated ones only once
+        watcher.record(PathBuf::from("output/a"), FsWatchOp::Create);
+        for _ in 0..10 {
+            watcher.record(PathBuf::from("output/a"), FsWatchOp::Write);
+        }
+        watcher.record(PathBuf::from("outputs/b"), FsWatchOp::Create);
+        watcher.record(PathBuf::from("output"), FsWatchOp::Attrib);
+        thread::sleep(Duration::from_millis(200));
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                FsWatchEvent {
+                    path: PathBuf::from("output/a"),
+                    op: FsWatchOp::Create,
+                },
+                FsWatchEvent {
+                    path: PathBuf::from("output/a"),
+                    op: FsWatchOp::Write,
+                },
+                FsWatchEvent {
+                    path: PathBuf::from("output"),
+                    op: FsWatchOp::Attrib,
+                },
+            ]
+        );
+
+        // The same change made again after the delivery is reported again
+        watcher.record(PathBuf::from("output/a"), FsWatchOp::Write);
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(events.lock().unwrap().len(), 4);
+    }
+}
@@ -0,0 +1,159 @@
+//! Memoized whiteout and opaque marker probes of the overlay lookups.
+//!
+//! A lookup walks the path of the entry from the root of every layer it searches, probing each
+//! directory on the way for a whiteout of the next segment and for an opaque marker. The probes of
+//! a request are memoized per directory of a layer, so that a request looking up many entries of
+//! the same directory, e.g. copying up a directory tree, probes each ancestor once.
+//!
+//! The results may also be kept for a short time across requests, so that the ancestors of the
+//! entries looked up one after the other aren't probed again at every layer. The overlay forgets
+//! them whenever it creates or removes a whiteout or an opaque marker, but the ones added or removed
+//! on the host are only seen once they expire, or once the layers are refreshed.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::inode_path::Name;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// The number of results of a cache above which the expired ones are dropped.
+const MAX_CACHED: usize = 4096;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// A probe of a directory, given by its inode in the overlay: for the whiteout of a name, or for
+/// the opaque marker when there is no name.
+type ProbeKey = (u64, Option<Name>);
+
+/// The probes made while serving a request.
+#[derive(Default)]
+pub(crate) struct WhiteoutProbes {
+    results: HashMap<ProbeKey, bool>,
+}
+
+/// The probes kept across requests for a short time.
+pub(crate) struct WhiteoutCache {
+    ttl: Option<Duration>,
+    results: Mutex<HashMap<ProbeKey, (bool, Instant)>>,
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl WhiteoutProbes {
+    /// Returns whether the directory `dir` holds the whiteout of `name`, or the opaque marker if
+    /// `name` is `None`, running `probe` if neither this request nor `cache` already know.
+    pub(crate) fn probe(
+        &mut self,
+        cache: &WhiteoutCache,
+        dir: u64,
+        name: Option<&Name>,
+        probe: impl FnOnce() -> io::Result<bool>,
+    ) -> io::Result<bool> {
+        let key = (dir, name.cloned());
+        if let Some(found) = self.results.get(&key) {
+            return Ok(*found);
+        }
+
+        let found = match cache.get(&key) {
+            Some(found) => found,
+            None => {
+                let found = probe()?;
+                cache.insert(key.clone(), found);
+                found
+            }
+        };
+        self.results.insert(key, found);
+        Ok(found)
+    }
+}
+
+impl WhiteoutCache {
+    /// Creates a cache keeping the results for `ttl`, or none at all if it's `None`.
+    pub(crate) fn new(ttl: Option<Duration>) -> Self {
+        WhiteoutCache {
+            ttl: ttl.filter(|ttl| !ttl.is_zero()),
+            results: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, key: &ProbeKey) -> Option<bool> {
+        self.ttl?;
+        let results = self.results.lock().unwrap();
+        let (found, expires) = results.get(key)?;
+        (Instant::now() < *expires).then_some(*found)
+    }
+
+    fn insert(&self, key: ProbeKey, found: bool) {
+        let Some(ttl) = self.ttl else {
+            return;
+        };
+
+        let now = Instant::now();
+        let mut results = self.results.lock().unwrap();
+        if results.len() >= MAX_CACHED {
+            results.retain(|_, (_, expires)| now < *expires);
+        }
+        results.insert(key, (found, now + ttl));
+    }
+
+    /// Forgets every result, after the overlay created or removed a whiteout or an opaque marker.
+    pub(crate) fn clear(&self) {
+        if self.ttl.is_some() {
+            self.results.lock().unwrap().clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::cell::Cell;
+
+    #[test]
+    fn probe() {
+        let name = Name::from(c"a");
+        let probes = Cell::new(0);
+        let probe = || {
+            probes.set(probes.get() + 1);
+            Ok(true)
+        };
+
+        // Without a cache, each request probes once
+        let cache = WhiteoutCache::new(None);
+        let mut request = WhiteoutProbes::default();
+        assert!(request.probe(&cache, 1, Some(&name), probe).unwrap());
+        assert!(request.probe(&cache, 1, Some(&name), probe).unwrap());
+        assert_eq!(probes.get(), 1);
+        assert!(request.probe(&cache, 1, None, probe).unwrap());
+        assert!(request.probe(&cache, 2, Some(&name), probe).unwrap());
+        assert_eq!(probes.get(), 3);
+        let mut request = WhiteoutProbes::default();
+        request.probe(&cache, 1, Some(&name), probe).unwrap();
+        assert_eq!(probes.get(), 4);
+
+        // With a cache, the next requests reuse the result until it's cleared
+        let cache = WhiteoutCache::new(Some(Duration::from_secs(60)));
+        WhiteoutProbes::default()
+            .probe(&cache, 1, Some(&name), probe)
+            .unwrap();
+        WhiteoutProbes::default()
+            .probe(&cache, 1, Some(&name), probe)
+            .unwrap();
+        assert_eq!(probes.get(), 5);
+        cache.clear();
+        WhiteoutProbes::default()
+            .probe(&cache, 1, Some(&name), probe)
+            .unwrap();
+        assert_eq!(probes.get(), 6);
+    }
+}
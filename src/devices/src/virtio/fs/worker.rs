@@ -3,25 +3,53 @@ use crossbeam_channel::Sender;
 #[cfg(target_os = "macos")]
 use utils::worker_message::WorkerMessage;
 
-use std::os::fd::AsRawFd;
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{self, Write};
+use std::mem::size_of;
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
 use utils::eventfd::EventFd;
-use vm_memory::GuestMemoryMmap;
+use vm_memory::{ByteValued, GuestMemoryMmap};
 
-use super::super::{FsError, Queue, VIRTIO_MMIO_INT_VRING};
-use super::defs::{HPQ_INDEX, REQ_INDEX};
+use super::super::{DescriptorChain, FsError, Queue, VIRTIO_MMIO_INT_VRING};
+use super::defs::{
+    HPQ_INDEX, LOCK_RETRY_INTERVAL, MAX_USED_BATCH_LATENCY, NOTIFY_INDEX, REQ_INDEX,
+};
+use super::degrade::FsDegradation;
 use super::descriptor_utils::{Reader, Writer};
-use super::server::FsImplServer;
+use super::fuse::{NotifyInvalInodeOut, NotifyOpcode, OutHeader};
+use super::inode_numbers::InodeNumbers;
+use super::mode_policy::GuestModes;
 use super::overlayfs::OverlayFs;
+use super::p9::{self, P9Server};
 use super::passthrough::PassthroughFs;
-use super::{FsImpl, FsImplConfig};
+use super::pause::{FsPause, FsPauseOptions, FsPauseTimeout, PauseRequest};
+use super::server::{classify_request, reply_unavailable, FsImplServer, RequestClass};
+use super::trace::{FsTracer, RequestTrace};
+use super::unsupported::FsUnsupportedStats;
+use super::watch::FsWatcher;
+use super::{
+    FsAccessRules, FsBackgroundLimits, FsCacheTimeouts, FsCredentials, FsDirTemplate, FsImpl,
+    FsImplConfig, FsInodeNumbers, FsLeases, FsModePolicy, FsProtocol, FsVirtualFile,
+    FsWriteCoalescing,
+};
 use crate::legacy::IrqChip;
 use crate::virtio::VirtioShmRegion;
 
+#[path = "tests/worker/mod.rs"]
+#[cfg(test)]
+mod tests;
+
+/// The server of a running worker, which the device saves the state of. It is empty while the
+/// worker closed the host files of the share.
+pub type SharedServer = Arc<Mutex<Option<Arc<FsImplServer>>>>;
+
 pub struct FsWorker {
     queues: Vec<Queue>,
     queue_evts: Vec<EventFd>,
@@ -32,13 +60,53 @@ pub struct FsWorker {
 
     mem: GuestMemoryMmap,
     shm_region: Option<VirtioShmRegion>,
-    server: FsImplServer,
+    // The server of the share, dropped while paused with the host files closed.
+    server: Option<Arc<FsImplServer>>,
+    shared_server: SharedServer,
+    // Creates the server anew, opening the host files again.
+    make_server: Box<dyn Fn() -> io::Result<FsImplServer> + Send>,
+    pause: FsPause,
+    paused: Option<Paused>,
+    // The 9P session of the guest, if the share is used over 9p.
+    p9: Option<P9Server>,
     stop_fd: EventFd,
     exit_code: Arc<AtomicI32>,
+    tracer: FsTracer,
+    // Traces of the requests whose completions are waiting to be published.
+    traced: Vec<RequestTrace>,
+    // Requests waiting for a lock held by another owner, handled again until they get it.
+    blocked: Vec<BlockedRequest>,
+    // Number of background requests that may wait behind the other requests of a queue.
+    max_deferred: usize,
+    // The queue of the requests, which follows the notification queue if the guest accepted it.
+    req_index: usize,
+    notify_index: Option<usize>,
+    // Inodes to invalidate in the guest once it provides buffers in the notification queue.
+    pending_invalidations: VecDeque<u64>,
+    cache_timeouts: FsCacheTimeouts,
     #[cfg(target_os = "macos")]
     map_sender: Option<Sender<WorkerMessage>>,
 }
 
+/// A request taken from a queue that waits for a lock, which stays in the guest's hands until it
+/// gets it and is completed.
+struct BlockedRequest {
+    queue_index: usize,
+    // The head of the descriptor chain of the request.
+    head: u16,
+    trace: Option<RequestTrace>,
+}
+
+/// A pause of the worker, which leaves the requests in the queues.
+struct Paused {
+    options: FsPauseOptions,
+    since: Instant,
+    // Whether the pause timed out, and the requests are failed rather than left in the queues.
+    failing: bool,
+    // The state of the server, if it was dropped to close the host files.
+    saved_state: Option<Vec<u8>>,
+}
+
 impl FsWorker {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
@@ -51,18 +119,63 @@ impl FsWorker {
         mem: GuestMemoryMmap,
         shm_region: Option<VirtioShmRegion>,
         fs_config: FsImplConfig,
+        access_rules: Option<FsAccessRules>,
+        credentials: Option<FsCredentials>,
+        cache_timeouts: FsCacheTimeouts,
+        background_limits: FsBackgroundLimits,
         stop_fd: EventFd,
         exit_code: Arc<AtomicI32>,
+        tracer: FsTracer,
+        watcher: FsWatcher,
+        revalidate_interval: Option<Duration>,
+        write_coalescing: Option<FsWriteCoalescing>,
+        virtual_files: Vec<FsVirtualFile>,
+        protect_init_config: bool,
+        leases: Option<FsLeases>,
+        inspect_socket: Option<PathBuf>,
+        dir_templates: Vec<FsDirTemplate>,
+        pause: FsPause,
+        protocol: FsProtocol,
+        inode_numbers: FsInodeNumbers,
+        mode_policy: Option<FsModePolicy>,
+        degradation: FsDegradation,
+        unsupported: FsUnsupportedStats,
         #[cfg(target_os = "macos")] map_sender: Option<Sender<WorkerMessage>>,
     ) -> Self {
-        let server = match fs_config {
-            FsImplConfig::Passthrough(passthrough_cfg) => FsImplServer::new(FsImpl::Passthrough(
-                PassthroughFs::new(passthrough_cfg).unwrap(),
-            )),
-            FsImplConfig::Overlayfs(overlayfs_cfg) => {
-                FsImplServer::new(FsImpl::Overlayfs(OverlayFs::new(overlayfs_cfg).unwrap()))
-            }
+        // The numbers and the modes the guest was given outlive the servers a pause drops
+        let inode_numbers = InodeNumbers::new(inode_numbers);
+        let guest_modes = GuestModes::new(mode_policy);
+        let timeouts = cache_timeouts.clone();
+        let make_server = move || {
+            let fs = match fs_config.clone() {
+                FsImplConfig::Passthrough(passthrough_cfg) => {
+                    FsImpl::Passthrough(Box::new(PassthroughFs::new(passthrough_cfg)?))
+                }
+                FsImplConfig::Overlayfs(overlayfs_cfg) => {
+                    FsImpl::Overlayfs(Box::new(OverlayFs::new(overlayfs_cfg)?))
+                }
+            };
+            Ok(FsImplServer::new(
+                fs,
+                access_rules.clone(),
+                credentials.clone(),
+                cache_timeouts.clone(),
+                background_limits,
+                watcher.clone(),
+                revalidate_interval,
+                write_coalescing,
+                virtual_files.clone(),
+                protect_init_config,
+                leases,
+                inspect_socket.clone(),
+                dir_templates.clone(),
+                inode_numbers.clone(),
+                guest_modes.clone(),
+                degradation.clone(),
+                unsupported.clone(),
+            ))
         };
+        let server = Arc::new(make_server().unwrap());
 
         Self {
             queues,
@@ -73,24 +186,68 @@ impl FsWorker {
             irq_line,
             mem,
             shm_region,
-            server,
+            server: Some(server.clone()),
+            shared_server: Arc::new(Mutex::new(Some(server))),
+            make_server: Box::new(make_server),
+            pause,
+            paused: None,
+            p9: (protocol == FsProtocol::P9).then(P9Server::default),
             stop_fd,
             exit_code,
+            tracer,
+            traced: Vec::new(),
+            blocked: Vec::new(),
+            max_deferred: background_limits.congestion_threshold.into(),
+            // Leases are only granted to a guest that accepted the notification queue, and a 9p
+            // device only has the request queue
+            req_index: match protocol {
+                FsProtocol::P9 => HPQ_INDEX,
+                FsProtocol::Fuse if leases.is_some() => REQ_INDEX + 1,
+                FsProtocol::Fuse => REQ_INDEX,
+            },
+            notify_index: leases.map(|_| NOTIFY_INDEX),
+            pending_invalidations: VecDeque::new(),
+            cache_timeouts: timeouts,
             #[cfg(target_os = "macos")]
             map_sender,
         }
     }
 
+    /// Returns the server of the share, to save and restore its state. The host files of the
+    /// share mustn't be closed.
+    pub fn server(&self) -> Arc<FsImplServer> {
+        self.server
+            .clone()
+            .expect("the host files of the share are closed")
+    }
+
+    /// Returns the server of the share as it changes while the worker runs.
+    pub fn shared_server(&self) -> SharedServer {
+        self.shared_server.clone()
+    }
+
     pub fn run(self) -> thread::JoinHandle<()> {
+        self.pause.attach(true);
         thread::Builder::new()
             .name("fs worker".into())
-            .spawn(|| self.work())
+            .spawn(|| {
+                let pause = self.pause.clone();
+                self.work();
+                pause.attach(false);
+            })
             .unwrap()
     }
 
     fn work(mut self) {
         let virtq_hpq_ev_fd = self.queue_evts[HPQ_INDEX].as_raw_fd();
-        let virtq_req_ev_fd = self.queue_evts[REQ_INDEX].as_raw_fd();
+        let virtq_req_ev_fd = self.queue_evts[self.req_index].as_raw_fd();
+        let virtq_notify_ev_fd = self.notify_index.map(|i| self.queue_evts[i].as_raw_fd());
+        // Shortened timeouts can only be pushed to the guest through the notification queue
+        let timeouts_ev_fd = self
+            .notify_index
+            .map(|_| self.cache_timeouts.event().as_raw_fd());
+        let mut lease_ev_fd = self.lease_event_fd();
+        let pause_ev_fd = self.pause.event().as_raw_fd();
         let stop_ev_fd = self.stop_fd.as_raw_fd();
 
         let epoll = Epoll::new().unwrap();
@@ -105,6 +262,18 @@ impl FsWorker {
             virtq_req_ev_fd,
             &EpollEvent::new(EventSet::IN, virtq_req_ev_fd as u64),
         );
+        for fd in virtq_notify_ev_fd
+            .into_iter()
+            .chain(timeouts_ev_fd)
+            .chain(lease_ev_fd)
+            .chain([pause_ev_fd])
+        {
+            let _ = epoll.ctl(
+                ControlOperation::Add,
+                fd,
+                &EpollEvent::new(EventSet::IN, fd as u64),
+            );
+        }
         let _ = epoll.ctl(
             ControlOperation::Add,
             stop_ev_fd,
@@ -112,8 +281,18 @@ impl FsWorker {
         );
 
         loop {
+            self.check_pause_timeout();
             let mut epoll_events = vec![EpollEvent::new(EventSet::empty(), 0); 32];
-            match epoll.wait(epoll_events.len(), -1, epoll_events.as_mut_slice()) {
+            let timeout = self
+                .pause_time_left()
+                .into_iter()
+                .chain((!self.blocked.is_empty()).then_some(LOCK_RETRY_INTERVAL))
+                .min()
+                .map_or(-1, |left| {
+                    i32::try_from(left.as_millis() + 1).unwrap_or(i32::MAX)
+                });
+            match epoll.wait(epoll_events.len(), timeout, epoll_events.as_mut_slice()) {
+                Ok(0) => self.retry_blocked(),
                 Ok(ev_cnt) => {
                     for event in &epoll_events[0..ev_cnt] {
                         let source = event.fd();
@@ -123,7 +302,41 @@ impl FsWorker {
                                 self.handle_event(HPQ_INDEX);
                             }
                             EventSet::IN if source == virtq_req_ev_fd => {
-                                self.handle_event(REQ_INDEX);
+                                self.handle_event(self.req_index);
+                            }
+                            EventSet::IN if Some(source) == virtq_notify_ev_fd => {
+                                let _ = self.queue_evts[NOTIFY_INDEX].read();
+                                if self.paused.is_none() {
+                                    self.send_invalidations();
+                                }
+                            }
+                            EventSet::IN if Some(source) == timeouts_ev_fd => {
+                                self.handle_timeouts_event();
+                            }
+                            EventSet::IN if Some(source) == lease_ev_fd => {
+                                if self.paused.is_none() {
+                                    self.send_invalidations();
+                                }
+                            }
+                            EventSet::IN if source == pause_ev_fd => {
+                                // The event of the leases goes with the server, which a pause
+                                // may drop and a resume create anew
+                                if let Some(fd) = lease_ev_fd {
+                                    let _ = epoll.ctl(
+                                        ControlOperation::Delete,
+                                        fd,
+                                        &EpollEvent::new(EventSet::IN, fd as u64),
+                                    );
+                                }
+                                self.handle_pause_event();
+                                lease_ev_fd = self.lease_event_fd();
+                                if let Some(fd) = lease_ev_fd {
+                                    let _ = epoll.ctl(
+                                        ControlOperation::Add,
+                                        fd,
+                                        &EpollEvent::new(EventSet::IN, fd as u64),
+                                    );
+                                }
                             }
                             EventSet::IN if source == stop_ev_fd => {
                                 debug!("stopping worker thread");
@@ -153,6 +366,14 @@ impl FsWorker {
             error!("Failed to get queue event: {:?}", e);
         }
 
+        // A paused worker leaves the requests in the queue until it resumes
+        if self.paused.as_ref().is_some_and(|paused| !paused.failing) {
+            return;
+        }
+        self.drain_queue(queue_index);
+    }
+
+    fn drain_queue(&mut self, queue_index: usize) {
         loop {
             self.queues[queue_index]
                 .disable_notification(&self.mem)
@@ -167,44 +388,362 @@ impl FsWorker {
                 break;
             }
         }
+
+        // The requests handled may have released the locks the blocked ones wait for
+        self.retry_blocked();
     }
 
     fn process_queue(&mut self, queue_index: usize) {
-        let queue = &mut self.queues[queue_index];
-        while let Some(head) = queue.pop(&self.mem) {
-            let reader = Reader::new(&self.mem, head.clone())
-                .map_err(FsError::QueueReader)
-                .unwrap();
-            let writer = Writer::new(&self.mem, head.clone())
-                .map_err(FsError::QueueWriter)
-                .unwrap();
+        let mem = self.mem.clone();
+
+        // Completions are written to the used ring as they happen, but only published (and
+        // signaled to the guest) once per batch, or before the next request is handled if it may
+        // block or once the oldest one has waited too long, so that they don't wait for the
+        // requests after them.
+        let mut batch_start: Option<Instant> = None;
+
+        // Background requests are handled once the queue has no other request, or when too many
+        // of them are waiting, oldest first.
+        let mut deferred = VecDeque::new();
 
-            if let Err(e) = self.server.handle_message(
+        loop {
+            let (head, mut trace, class) = match self.queues[queue_index].pop(&mem) {
+                Some(head) => {
+                    let trace = self.tracer.start(queue_index);
+                    // The 9p messages aren't classified, any of them may block
+                    let class = match self.p9 {
+                        Some(_) => RequestClass::MayBlock,
+                        None => Reader::new(&mem, head.clone())
+                            .map_or(RequestClass::Normal, classify_request),
+                    };
+                    if class != RequestClass::Background {
+                        (head, trace, class)
+                    } else {
+                        deferred.push_back((head, trace));
+                        if deferred.len() <= self.max_deferred {
+                            continue;
+                        }
+                        let (head, trace) = deferred.pop_front().unwrap();
+                        (head, trace, class)
+                    }
+                }
+                None => match deferred.pop_front() {
+                    Some((head, trace)) => (head, trace, RequestClass::Background),
+                    None => break,
+                },
+            };
+
+            if batch_start.is_some_and(|started| {
+                started.elapsed() >= MAX_USED_BATCH_LATENCY || class.may_block()
+            }) {
+                self.publish_used(queue_index);
+                batch_start = None;
+            }
+
+            let Some(used_len) = self.handle_request(&head, &mut trace) else {
+                self.blocked.push(BlockedRequest {
+                    queue_index,
+                    head: head.index,
+                    trace,
+                });
+                continue;
+            };
+
+            if let Err(e) = self.queues[queue_index].add_used_deferred(&mem, head.index, used_len) {
+                error!("failed to add used elements to the queue: {:?}", e);
+                continue;
+            }
+            self.traced.extend(trace);
+            batch_start.get_or_insert_with(Instant::now);
+        }
+
+        if batch_start.is_some() {
+            self.publish_used(queue_index);
+        }
+    }
+
+    /// Handles the request of `head`, returning the length of its reply for the used ring, or
+    /// `None` if it waits for a lock and must be handled again later.
+    fn handle_request(
+        &mut self,
+        head: &DescriptorChain,
+        trace: &mut Option<RequestTrace>,
+    ) -> Option<u32> {
+        let reader = Reader::new(head.mem, head.clone())
+            .map_err(FsError::QueueReader)
+            .unwrap();
+        let writer = Writer::new(head.mem, head.clone())
+            .map_err(FsError::QueueWriter)
+            .unwrap();
+
+        let result = match (&self.server, &self.paused, &mut self.p9) {
+            (Some(server), None, Some(p9)) => p9.handle_message(server.fs(), reader, writer),
+            (Some(server), None, None) => server.handle_message(
                 reader,
                 writer,
                 &self.shm_region,
                 &self.exit_code,
+                trace.as_mut(),
                 #[cfg(target_os = "macos")]
                 &self.map_sender,
-            ) {
-                error!("error handling message: {:?}", e);
+            ),
+            (_, _, Some(_)) => p9::reply_unavailable(reader, writer),
+            _ => reply_unavailable(reader, writer),
+        };
+        // The 9p driver takes the size of the reply from the used ring
+        let used_len = match &result {
+            Ok(len) if self.p9.is_some() => *len as u32,
+            Err(FsError::WouldBlock) => return None,
+            _ => 0,
+        };
+        if let Err(e) = &result {
+            error!("error handling message: {:?}", e);
+        }
+        Some(used_len)
+    }
+
+    /// Handles the requests waiting for a lock again, completing the ones that got it. A paused
+    /// worker leaves them waiting until it resumes.
+    fn retry_blocked(&mut self) {
+        if self.blocked.is_empty() || self.paused.as_ref().is_some_and(|paused| !paused.failing) {
+            return;
+        }
+
+        let mem = self.mem.clone();
+        let mut completed = BTreeSet::new();
+        for mut request in std::mem::take(&mut self.blocked) {
+            let queue = &self.queues[request.queue_index];
+            let Some(head) = DescriptorChain::checked_new(
+                &mem,
+                queue.desc_table,
+                queue.actual_size(),
+                request.head,
+            ) else {
+                error!("failed to find the descriptors of a blocked request");
+                continue;
+            };
+            let Some(used_len) = self.handle_request(&head, &mut request.trace) else {
+                self.blocked.push(request);
+                continue;
+            };
+
+            let queue = &mut self.queues[request.queue_index];
+            if let Err(e) = queue.add_used_deferred(&mem, request.head, used_len) {
+                error!("failed to add used elements to the queue: {:?}", e);
+                continue;
             }
+            self.traced.extend(request.trace);
+            completed.insert(request.queue_index);
+        }
 
-            if let Err(e) = queue.add_used(&self.mem, head.index, 0) {
+        for queue_index in completed {
+            self.publish_used(queue_index);
+        }
+    }
+
+    /// Has the guest drop the attributes it cached of the inodes it holds if the timeouts of the
+    /// share may have been shortened, as it could otherwise keep them for the longer timeouts.
+    fn handle_timeouts_event(&mut self) {
+        if !self.cache_timeouts.take_shortened() {
+            return;
+        }
+        if let Some(server) = &self.server {
+            self.pending_invalidations.extend(server.fs().held_inodes());
+        }
+        if self.paused.is_none() {
+            self.send_invalidations();
+        }
+    }
+
+    /// Tells the guest to drop what it cached of the inodes whose leases were broken, for as many
+    /// of them as it provided buffers for in the notification queue. The others wait for more.
+    fn send_invalidations(&mut self) {
+        let Some(notify_index) = self.notify_index else {
+            return;
+        };
+        if let Some(server) = &self.server {
+            self.pending_invalidations
+                .extend(server.leases().take_broken());
+        }
+
+        let mem = self.mem.clone();
+        let mut sent = false;
+        while let Some(&inode) = self.pending_invalidations.front() {
+            let Some(head) = self.queues[notify_index].pop(&mem) else {
+                break;
+            };
+            self.pending_invalidations.pop_front();
+
+            let header = OutHeader {
+                len: (size_of::<OutHeader>() + size_of::<NotifyInvalInodeOut>()) as u32,
+                error: NotifyOpcode::InvalInode as i32,
+                unique: 0,
+            };
+            // Invalidates the attributes and all the data of the inode
+            let out = NotifyInvalInodeOut {
+                ino: inode,
+                off: 0,
+                len: 0,
+            };
+            let len = Writer::new(&mem, head.clone())
+                .map_err(FsError::QueueWriter)
+                .and_then(|mut w| {
+                    w.write_all(header.as_slice())
+                        .and_then(|_| w.write_all(out.as_slice()))
+                        .map_err(FsError::EncodeMessage)?;
+                    Ok(w.bytes_written())
+                })
+                .unwrap_or_else(|e| {
+                    error!("failed to write an invalidation of inode {inode}: {e:?}");
+                    0
+                });
+
+            if let Err(e) =
+                self.queues[notify_index].add_used_deferred(&mem, head.index, len as u32)
+            {
                 error!("failed to add used elements to the queue: {:?}", e);
+                continue;
             }
+            sent = true;
+        }
+
+        if sent {
+            self.publish_used(notify_index);
+        }
+    }
+
+    /// Handles the pause or the resume requested, if any, and reports how it went.
+    fn handle_pause_event(&mut self) {
+        let result = match self.pause.take_request() {
+            Some(PauseRequest::Pause(options)) => self.pause_share(options),
+            Some(PauseRequest::Resume) => self.resume_share(),
+            None => return,
+        };
+        self.pause.report(result);
+    }
+
+    /// Stops handling requests and using the host files of the share, closing them if asked to.
+    fn pause_share(&mut self, options: FsPauseOptions) -> io::Result<()> {
+        let server = self.server();
+        server.pause();
+
+        let mut saved_state = None;
+        if options.close_files {
+            let state = server.save_state().inspect_err(|_| server.resume())?;
+            // The worker and the device hold the only references to the server
+            *self.shared_server.lock().unwrap() = None;
+            self.server = None;
+            saved_state = Some(state);
+        }
+
+        self.paused = Some(Paused {
+            options,
+            since: Instant::now(),
+            failing: false,
+            saved_state,
+        });
+        Ok(())
+    }
+
+    /// Uses the share again, opening its host files again if they were closed, and handles the
+    /// requests left in the queues. If the files can't be opened, the worker stays paused.
+    fn resume_share(&mut self) -> io::Result<()> {
+        let Some(paused) = self.paused.take() else {
+            return Ok(());
+        };
 
-            if queue.needs_notification(&self.mem).unwrap() {
-                self.interrupt_status
-                    .fetch_or(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
-                if let Some(intc) = &self.intc {
-                    if let Err(e) = intc
-                        .lock()
-                        .unwrap()
-                        .set_irq(self.irq_line, Some(&self.interrupt_evt))
-                    {
-                        error!("Failed to signal used queue: {:?}", e);
+        match &paused.saved_state {
+            Some(state) => {
+                let server = (self.make_server)().and_then(|server| {
+                    server.restore_state(state)?;
+                    Ok(Arc::new(server))
+                });
+                match server {
+                    Ok(server) => {
+                        *self.shared_server.lock().unwrap() = Some(server.clone());
+                        self.server = Some(server);
                     }
+                    Err(e) => {
+                        self.paused = Some(paused);
+                        return Err(e);
+                    }
+                }
+            }
+            None => self.server().resume(),
+        }
+
+        self.drain_queue(HPQ_INDEX);
+        self.drain_queue(self.req_index);
+        self.send_invalidations();
+        Ok(())
+    }
+
+    /// Returns how long the pause may still last, unless it has no timeout or timed out already.
+    fn pause_time_left(&self) -> Option<Duration> {
+        let paused = self.paused.as_ref().filter(|paused| !paused.failing)?;
+        let timeout = paused.options.timeout?;
+        Some(timeout.saturating_sub(paused.since.elapsed()))
+    }
+
+    /// Applies the timeout policy of the pause once it timed out: resumes, trying again after as
+    /// long again if the host files can't be opened, or fails the requests left in the queues and
+    /// the next ones.
+    fn check_pause_timeout(&mut self) {
+        if self.pause_time_left() != Some(Duration::ZERO) {
+            return;
+        }
+        let Some(paused) = &mut self.paused else {
+            return;
+        };
+
+        match paused.options.on_timeout {
+            FsPauseTimeout::Resume => {
+                warn!("virtio-fs: the pause of the share timed out, resuming");
+                match self.resume_share() {
+                    Ok(()) => self.pause.timed_out(),
+                    Err(e) => {
+                        error!("virtio-fs: failed to resume the share: {e}");
+                        if let Some(paused) = &mut self.paused {
+                            paused.since = Instant::now();
+                        }
+                    }
+                }
+            }
+            FsPauseTimeout::Fail => {
+                warn!("virtio-fs: the pause of the share timed out, failing the requests");
+                paused.failing = true;
+                self.drain_queue(HPQ_INDEX);
+                self.drain_queue(self.req_index);
+            }
+        }
+    }
+
+    fn lease_event_fd(&self) -> Option<RawFd> {
+        let server = self.server.as_ref()?;
+        server.leases().event().map(|e| e.as_raw_fd())
+    }
+
+    fn publish_used(&mut self, queue_index: usize) {
+        let queue = &mut self.queues[queue_index];
+        if let Err(e) = queue.publish_used(&self.mem) {
+            error!("failed to publish used elements to the queue: {:?}", e);
+            return;
+        }
+
+        if !self.traced.is_empty() {
+            self.tracer.finish(self.traced.drain(..));
+        }
+
+        if queue.needs_notification(&self.mem).unwrap() {
+            self.interrupt_status
+                .fetch_or(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
+            if let Some(intc) = &self.intc {
+                if let Err(e) = intc
+                    .lock()
+                    .unwrap()
+                    .set_irq(self.irq_line, Some(&self.interrupt_evt))
+                {
+                    error!("Failed to signal used queue: {:?}", e);
                 }
             }
         }
@@ -26,6 +26,7 @@ use rutabaga_gfx::{
     RUTABAGA_MAP_ACCESS_READ, RUTABAGA_MAP_ACCESS_RW, RUTABAGA_MAP_ACCESS_WRITE,
 };
 use utils::eventfd::EventFd;
+use utils::fd_budget::{FdBudget, FdClient, FdGrant, FdPriority};
 #[cfg(target_os = "macos")]
 use utils::worker_message::WorkerMessage;
 use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap, VolatileSlice};
@@ -88,6 +89,8 @@ struct VirtioGpuResource {
     size: u64,
     shmem_offset: Option<u64>,
     rutabaga_external_mapping: bool,
+    /// The descriptor holding the memory of a blob resource, in the budget of the process.
+    _fd_grant: Option<FdGrant>,
 }
 
 impl VirtioGpuResource {
@@ -98,6 +101,7 @@ impl VirtioGpuResource {
             size,
             shmem_offset: None,
             rutabaga_external_mapping: false,
+            _fd_grant: None,
         }
     }
 }
@@ -106,6 +110,7 @@ pub struct VirtioGpu {
     rutabaga: Rutabaga,
     resources: BTreeMap<u32, VirtioGpuResource>,
     fence_state: Arc<Mutex<FenceState>>,
+    fd_client: FdClient,
     #[cfg(target_os = "macos")]
     map_sender: Sender<WorkerMessage>,
 }
@@ -257,6 +262,7 @@ impl VirtioGpu {
             rutabaga,
             resources: Default::default(),
             fence_state,
+            fd_client: FdBudget::global().register("virtio-gpu", FdPriority::Normal),
             #[cfg(target_os = "macos")]
             map_sender,
         }
@@ -508,6 +514,8 @@ impl VirtioGpu {
                 Some(sglist_to_rutabaga_iovecs(&vecs[..], mem).map_err(|_| ErrUnspec)?);
         }
 
+        let fd_grant = self.fd_client.try_acquire(1).map_err(|_| ErrOutOfMemory)?;
+
         self.rutabaga.resource_create_blob(
             ctx_id,
             resource_id,
@@ -516,7 +524,8 @@ impl VirtioGpu {
             None,
         )?;
 
-        let resource = VirtioGpuResource::new(resource_id, 0, 0, resource_create_blob.size);
+        let mut resource = VirtioGpuResource::new(resource_id, 0, 0, resource_create_blob.size);
+        resource._fd_grant = Some(fd_grant);
 
         // Rely on rutabaga to check for duplicate resource ids.
         self.resources.insert(resource_id, resource);
@@ -0,0 +1,536 @@
+use std::cmp;
+use std::io::Write;
+use std::mem::size_of;
+use std::ops::Range;
+use std::result;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use utils::eventfd::EventFd;
+use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
+
+use super::super::{
+    ActivateError, ActivateResult, DeviceState, MemError, Queue as VirtQueue, VirtioDevice,
+    VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_INT_VRING,
+};
+use super::{defs, defs::uapi};
+use crate::legacy::IrqChip;
+use crate::Error as DeviceError;
+
+// Guest request queue.
+pub(crate) const REQ_INDEX: usize = 0;
+
+// Supported features.
+pub(crate) const AVAIL_FEATURES: u64 = 1 << uapi::VIRTIO_F_VERSION_1 as u64;
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+pub struct VirtioMemConfig {
+    /* Size of the blocks the guest plugs and unplugs. */
+    block_size: u64,
+    /* NUMA node of the region, unused without VIRTIO_MEM_F_ACPI_PXM. */
+    node_id: u16,
+    padding: [u8; 6],
+    /* Start and size of the hotpluggable region in guest memory. */
+    addr: u64,
+    region_size: u64,
+    /* Part of the region the guest may plug memory in. */
+    usable_region_size: u64,
+    /* Memory plugged in by the guest. */
+    plugged_size: u64,
+    /* Memory the host wants the guest to have plugged in. */
+    requested_size: u64,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioMemConfig {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioMemReq {
+    req_type: u16,
+    padding: [u16; 3],
+    addr: u64,
+    nb_blocks: u16,
+    padding_1: [u16; 3],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioMemReq {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioMemResp {
+    resp_type: u16,
+    padding: [u16; 3],
+    state: u16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioMemResp {}
+
+/// Which blocks of the region the guest has plugged in.
+struct Blocks {
+    addr: u64,
+    plugged: Vec<bool>,
+    plugged_count: usize,
+}
+
+/// Changes the memory the guest is requested to have plugged in, from any thread.
+#[derive(Clone)]
+pub struct MemResizer {
+    region_size: u64,
+    requested_size: Arc<AtomicU64>,
+    activated: Arc<AtomicBool>,
+    resize_evt: Arc<EventFd>,
+}
+
+pub struct Mem {
+    pub(crate) queues: Vec<VirtQueue>,
+    pub(crate) queue_events: Vec<EventFd>,
+    pub(crate) avail_features: u64,
+    pub(crate) acked_features: u64,
+    pub(crate) interrupt_status: Arc<AtomicUsize>,
+    pub(crate) interrupt_evt: EventFd,
+    pub(crate) activate_evt: EventFd,
+    pub(crate) device_state: DeviceState,
+    config: VirtioMemConfig,
+    blocks: Blocks,
+    resizer: MemResizer,
+    intc: Option<IrqChip>,
+    irq_line: Option<u32>,
+}
+
+impl Blocks {
+    fn new(addr: u64, size: u64) -> Blocks {
+        Blocks {
+            addr,
+            plugged: vec![false; (size / defs::BLOCK_SIZE) as usize],
+            plugged_count: 0,
+        }
+    }
+
+    /// Returns the blocks of a request, unless they aren't all in the region.
+    fn range(&self, addr: u64, nb_blocks: u16) -> Option<Range<usize>> {
+        let offset = addr.checked_sub(self.addr)?;
+        if !offset.is_multiple_of(defs::BLOCK_SIZE) || nb_blocks == 0 {
+            return None;
+        }
+        let start = (offset / defs::BLOCK_SIZE) as usize;
+        let end = start.checked_add(nb_blocks as usize)?;
+        (end <= self.plugged.len()).then_some(start..end)
+    }
+
+    fn state(&self, range: Range<usize>) -> u16 {
+        let plugged = self.plugged[range.clone()].iter().filter(|p| **p).count();
+        if plugged == range.len() {
+            uapi::VIRTIO_MEM_STATE_PLUGGED
+        } else if plugged == 0 {
+            uapi::VIRTIO_MEM_STATE_UNPLUGGED
+        } else {
+            uapi::VIRTIO_MEM_STATE_MIXED
+        }
+    }
+
+    fn set(&mut self, range: Range<usize>, plugged: bool) {
+        for block in &mut self.plugged[range] {
+            if *block != plugged {
+                *block = plugged;
+                if plugged {
+                    self.plugged_count += 1;
+                } else {
+                    self.plugged_count -= 1;
+                }
+            }
+        }
+    }
+
+    fn plugged_size(&self) -> u64 {
+        self.plugged_count as u64 * defs::BLOCK_SIZE
+    }
+
+    fn guest_range(&self, range: &Range<usize>) -> (GuestAddress, usize) {
+        let addr = self.addr + range.start as u64 * defs::BLOCK_SIZE;
+        (GuestAddress(addr), range.len() * defs::BLOCK_SIZE as usize)
+    }
+}
+
+impl MemResizer {
+    /// Requests the guest to have `size` bytes plugged in, which must be a multiple of the block
+    /// size no larger than the region. The guest plugs and unplugs its memory at its own pace.
+    pub fn resize(&self, size: u64) -> super::Result<()> {
+        if !size.is_multiple_of(defs::BLOCK_SIZE) || size > self.region_size {
+            return Err(MemError::InvalidSize(size));
+        }
+        if !self.activated.load(Ordering::Acquire) {
+            return Err(MemError::NotActivated);
+        }
+
+        self.requested_size.store(size, Ordering::Release);
+        self.resize_evt.write(1).map_err(MemError::EventFd)
+    }
+
+    /// The most memory the guest can be requested to have plugged in.
+    pub fn region_size(&self) -> u64 {
+        self.region_size
+    }
+}
+
+impl Mem {
+    pub(crate) fn with_queues(
+        queues: Vec<VirtQueue>,
+        addr: GuestAddress,
+        region_size: u64,
+    ) -> super::Result<Mem> {
+        let mut queue_events = Vec::new();
+        for _ in 0..queues.len() {
+            queue_events
+                .push(EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(MemError::EventFd)?);
+        }
+
+        let config = VirtioMemConfig {
+            block_size: defs::BLOCK_SIZE,
+            addr: addr.0,
+            region_size,
+            usable_region_size: region_size,
+            ..Default::default()
+        };
+        let resizer = MemResizer {
+            region_size,
+            requested_size: Arc::new(AtomicU64::new(0)),
+            activated: Arc::new(AtomicBool::new(false)),
+            resize_evt: Arc::new(
+                EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(MemError::EventFd)?,
+            ),
+        };
+
+        Ok(Mem {
+            queues,
+            queue_events,
+            avail_features: AVAIL_FEATURES,
+            acked_features: 0,
+            interrupt_status: Arc::new(AtomicUsize::new(0)),
+            interrupt_evt: EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(MemError::EventFd)?,
+            activate_evt: EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(MemError::EventFd)?,
+            device_state: DeviceState::Inactive,
+            config,
+            blocks: Blocks::new(addr.0, region_size),
+            resizer,
+            intc: None,
+            irq_line: None,
+        })
+    }
+
+    /// Creates the device of the hotpluggable region of `region_size` bytes at `addr` in guest
+    /// memory, both aligned to `MEM_REGION_ALIGN`.
+    pub fn new(addr: GuestAddress, region_size: u64) -> super::Result<Mem> {
+        let queues: Vec<VirtQueue> = defs::QUEUE_SIZES
+            .iter()
+            .map(|&max_size| VirtQueue::new(max_size))
+            .collect();
+        Self::with_queues(queues, addr, region_size)
+    }
+
+    pub fn id(&self) -> &str {
+        defs::MEM_DEV_ID
+    }
+
+    pub fn set_intc(&mut self, intc: IrqChip) {
+        self.intc = Some(intc);
+    }
+
+    /// Returns the handle resizing the memory of the guest.
+    pub fn resizer(&self) -> MemResizer {
+        self.resizer.clone()
+    }
+
+    pub(crate) fn resize_evt(&self) -> &EventFd {
+        &self.resizer.resize_evt
+    }
+
+    pub fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+        debug!("mem: raising IRQ");
+        self.signal(VIRTIO_MMIO_INT_VRING)
+    }
+
+    fn signal(&self, status: u32) -> result::Result<(), DeviceError> {
+        self.interrupt_status
+            .fetch_or(status as usize, Ordering::SeqCst);
+        if let Some(intc) = &self.intc {
+            intc.lock()
+                .unwrap()
+                .set_irq(self.irq_line, Some(&self.interrupt_evt))?;
+        }
+        Ok(())
+    }
+
+    /// Tells the guest about the memory it is requested to have plugged in.
+    pub(crate) fn update_requested_size(&mut self) {
+        let requested_size = self.resizer.requested_size.load(Ordering::Acquire);
+        debug!("mem: requesting {requested_size} bytes plugged in");
+        self.config.requested_size = requested_size;
+        if let Err(e) = self.signal(VIRTIO_MMIO_INT_CONFIG) {
+            warn!("Failed to signal config change: {e:?}");
+        }
+    }
+
+    pub fn process_req(&mut self) -> bool {
+        debug!("mem: process_req()");
+        let mem = match self.device_state {
+            DeviceState::Activated(ref mem) => mem.clone(),
+            // This should never happen, it's been already validated in the event handler.
+            DeviceState::Inactive => unreachable!(),
+        };
+
+        let mut have_used = false;
+
+        while let Some(head) = self.queues[REQ_INDEX].pop(&mem) {
+            let index = head.index;
+            let resp = if head.is_write_only() || (head.len as usize) < size_of::<VirtioMemReq>() {
+                error!("mem: invalid request descriptor");
+                VirtioMemResp {
+                    resp_type: uapi::VIRTIO_MEM_RESP_ERROR,
+                    ..Default::default()
+                }
+            } else {
+                match mem.read_obj::<VirtioMemReq>(head.addr) {
+                    Ok(req) => self.handle_req(&mem, &req),
+                    Err(e) => {
+                        error!("Failed to read request: {:?}", e);
+                        VirtioMemResp {
+                            resp_type: uapi::VIRTIO_MEM_RESP_ERROR,
+                            ..Default::default()
+                        }
+                    }
+                }
+            };
+
+            let mut written = 0;
+            match head.next_descriptor() {
+                Some(desc)
+                    if desc.is_write_only() && desc.len as usize >= size_of::<VirtioMemResp>() =>
+                {
+                    match mem.write_obj(resp, desc.addr) {
+                        Ok(()) => written = size_of::<VirtioMemResp>() as u32,
+                        Err(e) => error!("Failed to write response: {:?}", e),
+                    }
+                }
+                _ => error!("mem: invalid response descriptor"),
+            }
+
+            have_used = true;
+            if let Err(e) = self.queues[REQ_INDEX].add_used(&mem, index, written) {
+                error!("failed to add used elements to the queue: {:?}", e);
+            }
+        }
+
+        self.config.plugged_size = self.blocks.plugged_size();
+        have_used
+    }
+
+    fn handle_req(&mut self, mem: &GuestMemoryMmap, req: &VirtioMemReq) -> VirtioMemResp {
+        let mut resp = VirtioMemResp {
+            resp_type: uapi::VIRTIO_MEM_RESP_ACK,
+            ..Default::default()
+        };
+        let range = self.blocks.range(req.addr, req.nb_blocks);
+
+        match (req.req_type, range) {
+            (uapi::VIRTIO_MEM_REQ_PLUG, Some(range))
+                if self.blocks.state(range.clone()) == uapi::VIRTIO_MEM_STATE_UNPLUGGED =>
+            {
+                let size = range.len() as u64 * defs::BLOCK_SIZE;
+                if self.blocks.plugged_size() + size > self.config.requested_size {
+                    resp.resp_type = uapi::VIRTIO_MEM_RESP_NACK;
+                } else {
+                    self.blocks.set(range, true);
+                }
+            }
+            (uapi::VIRTIO_MEM_REQ_UNPLUG, Some(range))
+                if self.blocks.state(range.clone()) == uapi::VIRTIO_MEM_STATE_PLUGGED =>
+            {
+                discard(mem, self.blocks.guest_range(&range));
+                self.blocks.set(range, false);
+            }
+            (uapi::VIRTIO_MEM_REQ_UNPLUG_ALL, _) => {
+                let range = 0..self.blocks.plugged.len();
+                discard(mem, self.blocks.guest_range(&range));
+                self.blocks.set(range, false);
+            }
+            (uapi::VIRTIO_MEM_REQ_STATE, Some(range)) => {
+                resp.state = self.blocks.state(range);
+            }
+            (req_type, _) => {
+                warn!(
+                    "mem: invalid request {req_type} for {} blocks at {:#x}",
+                    req.nb_blocks, req.addr
+                );
+                resp.resp_type = uapi::VIRTIO_MEM_RESP_ERROR;
+            }
+        }
+
+        resp
+    }
+}
+
+/// Releases the host memory backing the guest memory range, which reads as zeros afterwards.
+fn discard(mem: &GuestMemoryMmap, (addr, len): (GuestAddress, usize)) {
+    let host_addr = match mem.get_host_address(addr) {
+        Ok(host_addr) => host_addr,
+        Err(e) => {
+            error!("mem: failed to discard {len} bytes at {addr:?}: {e:?}");
+            return;
+        }
+    };
+    debug!("mem: discarding guest_addr={addr:?} host_addr={host_addr:p} len={len}");
+    unsafe { libc::madvise(host_addr as *mut libc::c_void, len, libc::MADV_DONTNEED) };
+}
+
+impl VirtioDevice for Mem {
+    fn avail_features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn acked_features(&self) -> u64 {
+        self.acked_features
+    }
+
+    fn set_acked_features(&mut self, acked_features: u64) {
+        self.acked_features = acked_features
+    }
+
+    fn device_type(&self) -> u32 {
+        uapi::VIRTIO_ID_MEM
+    }
+
+    fn queues(&self) -> &[VirtQueue] {
+        &self.queues
+    }
+
+    fn queues_mut(&mut self) -> &mut [VirtQueue] {
+        &mut self.queues
+    }
+
+    fn queue_events(&self) -> &[EventFd] {
+        &self.queue_events
+    }
+
+    fn interrupt_evt(&self) -> &EventFd {
+        &self.interrupt_evt
+    }
+
+    fn interrupt_status(&self) -> Arc<AtomicUsize> {
+        self.interrupt_status.clone()
+    }
+
+    fn set_irq_line(&mut self, irq: u32) {
+        debug!("SET_IRQ_LINE (MEM)={}", irq);
+        self.irq_line = Some(irq);
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_slice = self.config.as_slice();
+        let config_len = config_slice.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            return;
+        }
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(&config_slice[offset as usize..cmp::min(end, config_len) as usize])
+                .unwrap();
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        warn!(
+            "mem: guest driver attempted to write device config (offset={:x}, len={:x})",
+            offset,
+            data.len()
+        );
+    }
+
+    fn activate(&mut self, mem: GuestMemoryMmap) -> ActivateResult {
+        if self.queues.len() != defs::NUM_QUEUES {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                defs::NUM_QUEUES,
+                self.queues.len()
+            );
+            return Err(ActivateError::BadActivate);
+        }
+
+        if self.activate_evt.write(1).is_err() {
+            error!("Cannot write to activate_evt",);
+            return Err(ActivateError::BadActivate);
+        }
+
+        self.device_state = DeviceState::Activated(mem);
+        self.resizer.activated.store(true, Ordering::Release);
+
+        Ok(())
+    }
+
+    fn is_activated(&self) -> bool {
+        match self.device_state {
+            DeviceState::Inactive => false,
+            DeviceState::Activated(_) => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocks() {
+        let base = 1 << 32;
+        let mut blocks = Blocks::new(base, 8 * defs::BLOCK_SIZE);
+
+        // Requests must cover whole blocks of the region
+        assert_eq!(blocks.range(base + defs::BLOCK_SIZE, 2), Some(1..3));
+        assert_eq!(blocks.range(base + 1, 1), None);
+        assert_eq!(blocks.range(base - defs::BLOCK_SIZE, 1), None);
+        assert_eq!(blocks.range(base, 9), None);
+        assert_eq!(blocks.range(base, 0), None);
+
+        blocks.set(1..3, true);
+        blocks.set(2..4, true);
+        assert_eq!(blocks.plugged_size(), 3 * defs::BLOCK_SIZE);
+        assert_eq!(blocks.state(1..4), uapi::VIRTIO_MEM_STATE_PLUGGED);
+        assert_eq!(blocks.state(0..2), uapi::VIRTIO_MEM_STATE_MIXED);
+        assert_eq!(blocks.state(4..8), uapi::VIRTIO_MEM_STATE_UNPLUGGED);
+
+        blocks.set(0..8, false);
+        assert_eq!(blocks.plugged_size(), 0);
+        assert_eq!(
+            blocks.guest_range(&(2..4)),
+            (
+                GuestAddress(base + 2 * defs::BLOCK_SIZE),
+                2 * defs::BLOCK_SIZE as usize
+            )
+        );
+    }
+
+    #[test]
+    fn test_resize() {
+        let mem = Mem::new(GuestAddress(1 << 32), 1 << 30).unwrap();
+        let resizer = mem.resizer();
+
+        // Nothing is requested of a guest without a driver
+        assert!(matches!(resizer.resize(0), Err(MemError::NotActivated)));
+
+        resizer.activated.store(true, Ordering::Release);
+        assert!(matches!(
+            resizer.resize(defs::BLOCK_SIZE + 1),
+            Err(MemError::InvalidSize(_))
+        ));
+        assert!(matches!(
+            resizer.resize(2 << 30),
+            Err(MemError::InvalidSize(_))
+        ));
+        resizer.resize(512 << 20).unwrap();
+        assert_eq!(mem.resize_evt().read().unwrap(), 1);
+    }
+}
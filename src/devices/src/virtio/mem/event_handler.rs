@@ -0,0 +1,117 @@
+use std::os::unix::io::AsRawFd;
+
+use polly::event_manager::{EventManager, Subscriber};
+use utils::epoll::{EpollEvent, EventSet};
+
+use super::device::{Mem, REQ_INDEX};
+use crate::virtio::device::VirtioDevice;
+
+impl Mem {
+    pub(crate) fn handle_req_event(&mut self, event: &EpollEvent) {
+        debug!("mem: request queue event");
+
+        let event_set = event.event_set();
+        if event_set != EventSet::IN {
+            warn!("mem: request queue unexpected event {:?}", event_set);
+            return;
+        }
+
+        if let Err(e) = self.queue_events[REQ_INDEX].read() {
+            error!("Failed to read request queue event: {:?}", e);
+        } else if self.process_req() {
+            if let Err(e) = self.signal_used_queue() {
+                warn!("Failed to signal queue: {e:?}");
+            }
+        }
+    }
+
+    pub(crate) fn handle_resize_event(&mut self, event: &EpollEvent) {
+        debug!("mem: resize event");
+
+        let event_set = event.event_set();
+        if event_set != EventSet::IN {
+            warn!("mem: resize unexpected event {:?}", event_set);
+            return;
+        }
+
+        if let Err(e) = self.resize_evt().read() {
+            error!("Failed to read resize event: {:?}", e);
+        } else {
+            self.update_requested_size();
+        }
+    }
+
+    fn handle_activate_event(&self, event_manager: &mut EventManager) {
+        debug!("mem: activate event");
+        if let Err(e) = self.activate_evt.read() {
+            error!("Failed to consume mem activate event: {:?}", e);
+        }
+
+        // The subscriber must exist as we previously registered activate_evt via
+        // `interest_list()`.
+        let self_subscriber = event_manager
+            .subscriber(self.activate_evt.as_raw_fd())
+            .unwrap();
+
+        event_manager
+            .register(
+                self.queue_events[REQ_INDEX].as_raw_fd(),
+                EpollEvent::new(
+                    EventSet::IN,
+                    self.queue_events[REQ_INDEX].as_raw_fd() as u64,
+                ),
+                self_subscriber.clone(),
+            )
+            .unwrap_or_else(|e| {
+                error!("Failed to register mem req with event manager: {:?}", e);
+            });
+
+        event_manager
+            .register(
+                self.resize_evt().as_raw_fd(),
+                EpollEvent::new(EventSet::IN, self.resize_evt().as_raw_fd() as u64),
+                self_subscriber.clone(),
+            )
+            .unwrap_or_else(|e| {
+                error!("Failed to register mem resize with event manager: {:?}", e);
+            });
+
+        event_manager
+            .unregister(self.activate_evt.as_raw_fd())
+            .unwrap_or_else(|e| {
+                error!("Failed to unregister mem activate evt: {:?}", e);
+            })
+    }
+}
+
+impl Subscriber for Mem {
+    fn process(&mut self, event: &EpollEvent, event_manager: &mut EventManager) {
+        let source = event.fd();
+        let req = self.queue_events[REQ_INDEX].as_raw_fd();
+        let resize_evt = self.resize_evt().as_raw_fd();
+        let activate_evt = self.activate_evt.as_raw_fd();
+
+        if self.is_activated() {
+            match source {
+                _ if source == req => self.handle_req_event(event),
+                _ if source == resize_evt => self.handle_resize_event(event),
+                _ if source == activate_evt => {
+                    self.handle_activate_event(event_manager);
+                }
+                _ => warn!("Unexpected mem event received: {:?}", source),
+            }
+        } else {
+            warn!(
+                "mem: The device is not yet activated. Spurious event received: {:?}",
+                source
+            );
+        }
+    }
+
+    fn interest_list(&self) -> Vec<EpollEvent> {
+        vec![EpollEvent::new(
+            EventSet::IN,
+            self.activate_evt.as_raw_fd() as u64,
+        )]
+    }
+}
@@ -0,0 +1,48 @@
+mod device;
+mod event_handler;
+
+pub use self::defs::uapi::VIRTIO_ID_MEM as TYPE_MEM;
+pub use self::defs::REGION_ALIGN as MEM_REGION_ALIGN;
+pub use self::device::{Mem, MemResizer};
+
+mod defs {
+    pub const MEM_DEV_ID: &str = "virtio_mem";
+    pub const NUM_QUEUES: usize = 1;
+    pub const QUEUE_SIZES: &[u16] = &[128; NUM_QUEUES];
+
+    /// The granularity at which memory is plugged into and unplugged from the guest.
+    pub const BLOCK_SIZE: u64 = 2 << 20;
+    /// The alignment of the hotpluggable region in guest memory and of its size, which covers
+    /// the memory block sizes of the guests.
+    pub const REGION_ALIGN: u64 = 1 << 30;
+
+    pub mod uapi {
+        pub const VIRTIO_F_VERSION_1: u32 = 32;
+        pub const VIRTIO_ID_MEM: u32 = 24;
+
+        pub const VIRTIO_MEM_REQ_PLUG: u16 = 0;
+        pub const VIRTIO_MEM_REQ_UNPLUG: u16 = 1;
+        pub const VIRTIO_MEM_REQ_UNPLUG_ALL: u16 = 2;
+        pub const VIRTIO_MEM_REQ_STATE: u16 = 3;
+
+        pub const VIRTIO_MEM_RESP_ACK: u16 = 0;
+        pub const VIRTIO_MEM_RESP_NACK: u16 = 1;
+        pub const VIRTIO_MEM_RESP_ERROR: u16 = 3;
+
+        pub const VIRTIO_MEM_STATE_PLUGGED: u16 = 0;
+        pub const VIRTIO_MEM_STATE_UNPLUGGED: u16 = 1;
+        pub const VIRTIO_MEM_STATE_MIXED: u16 = 2;
+    }
+}
+
+#[derive(Debug)]
+pub enum MemError {
+    /// Failed to create event fd.
+    EventFd(std::io::Error),
+    /// The requested size isn't a multiple of the block size or exceeds the region.
+    InvalidSize(u64),
+    /// The guest has no driver for the device.
+    NotActivated,
+}
+
+type Result<T> = std::result::Result<T, MemError>;
@@ -26,6 +26,34 @@ const MMIO_MAGIC_VALUE: u32 = 0x7472_6976;
 //current version specified by the mmio standard (legacy devices used 1 here)
 const MMIO_VERSION: u32 = 2;
 
+/// The registers of a transport as the guest driver left them, and the state of its device, saved
+/// in a snapshot of the guest.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MmioState {
+    pub device_type: u32,
+    pub features_select: u32,
+    pub acked_features_select: u32,
+    pub queue_select: u32,
+    pub device_status: u32,
+    pub config_generation: u32,
+    pub interrupt_status: u32,
+    pub acked_features: u64,
+    pub queues: Vec<QueueState>,
+    /// The state returned by `VirtioDevice::save_state`.
+    pub device: Vec<u8>,
+}
+
+/// The configuration of a queue by the guest driver. Where the device stands in the queue is
+/// found in the used ring once the guest memory is restored.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueueState {
+    pub size: u16,
+    pub ready: bool,
+    pub desc_table: u64,
+    pub avail_ring: u64,
+    pub used_ring: u64,
+}
+
 /// Implements the
 /// [MMIO](http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-1090002)
 /// transport for virtio devices.
@@ -90,6 +118,105 @@ impl MmioTransport {
         self.queue_evts.insert(id, queue_evt);
     }
 
+    /// Returns whether the device can be snapshotted.
+    pub fn snapshot_supported(&self) -> bool {
+        self.locked_device().snapshot_supported()
+    }
+
+    /// Returns the state of the transport and of its device, which must be quiesced.
+    pub fn save_state(&self) -> std::io::Result<MmioState> {
+        let device = self.locked_device();
+        if !device.snapshot_supported() {
+            return Err(std::io::Error::from_raw_os_error(libc::ENOTSUP));
+        }
+        let queues = device
+            .queues()
+            .iter()
+            .map(|queue| QueueState {
+                size: queue.size,
+                ready: queue.ready,
+                desc_table: queue.desc_table.0,
+                avail_ring: queue.avail_ring.0,
+                used_ring: queue.used_ring.0,
+            })
+            .collect();
+
+        Ok(MmioState {
+            device_type: device.device_type(),
+            features_select: self.features_select,
+            acked_features_select: self.acked_features_select,
+            queue_select: self.queue_select,
+            device_status: self.device_status,
+            config_generation: self.config_generation,
+            interrupt_status: self.interrupt_status.load(Ordering::SeqCst) as u32,
+            acked_features: device.acked_features(),
+            queues,
+            device: device.save_state()?,
+        })
+    }
+
+    /// Puts the transport and its device back in `state`, once the guest memory was restored
+    /// from the same snapshot. A device the guest driver had set up is activated.
+    pub fn restore_state(&mut self, state: &MmioState) -> std::io::Result<()> {
+        let mut device = self.device.lock().expect("Poisoned device lock");
+        if device.device_type() != state.device_type || device.queues().len() != state.queues.len()
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "the snapshot has a device of type {} with {} queues in place of one of type \
+                     {} with {}",
+                    state.device_type,
+                    state.queues.len(),
+                    device.device_type(),
+                    device.queues().len()
+                ),
+            ));
+        }
+
+        self.features_select = state.features_select;
+        self.acked_features_select = state.acked_features_select;
+        self.queue_select = state.queue_select;
+        self.device_status = state.device_status;
+        self.config_generation = state.config_generation;
+        self.interrupt_status
+            .store(state.interrupt_status as usize, Ordering::SeqCst);
+        device.set_acked_features(state.acked_features);
+
+        let running = self.device_status & device_status::DRIVER_OK != 0
+            && self.device_status & device_status::FAILED == 0;
+        for (queue, saved) in device.queues_mut().iter_mut().zip(&state.queues) {
+            queue.size = saved.size;
+            queue.ready = saved.ready;
+            queue.desc_table = GuestAddress(saved.desc_table);
+            queue.avail_ring = GuestAddress(saved.avail_ring);
+            queue.used_ring = GuestAddress(saved.used_ring);
+            if running && queue.ready {
+                queue
+                    .restore_position(&self.mem)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            }
+        }
+
+        device.restore_state(&state.device)?;
+        if running {
+            device
+                .activate(self.mem.clone())
+                .map_err(|e| std::io::Error::other(format!("{e:?}")))?;
+        }
+        Ok(())
+    }
+
+    /// Notifies the device of every queue, for it to look for the requests it missed while the
+    /// queues were quiesced.
+    pub fn kick_queues(&self) {
+        for (id, eventfd) in &self.queue_evts {
+            if let Err(e) = eventfd.write(u64::from(*id)) {
+                error!("failed to notify virtio queue {id}: {e}");
+            }
+        }
+    }
+
     fn check_device_status(&self, set: u32, clr: u32) -> bool {
         self.device_status & (set | clr) == set
     }
@@ -849,6 +976,18 @@ pub(crate) mod tests {
         assert!(d.locked_device().is_activated());
     }
 
+    #[test]
+    fn test_save_state_unsupported() {
+        let m = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut d = MmioTransport::new(m, Arc::new(Mutex::new(DummyDevice::new())));
+        activate_device(&mut d);
+
+        // The dummy device doesn't say its state can be saved
+        assert!(!d.snapshot_supported());
+        let err = d.save_state().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTSUP));
+    }
+
     #[test]
     fn test_get_avail_features() {
         let dummy_dev = DummyDevice::new();
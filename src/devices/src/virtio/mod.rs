@@ -26,10 +26,13 @@ pub mod fs;
 #[cfg(feature = "gpu")]
 pub mod gpu;
 pub mod linux_errno;
+#[cfg(not(feature = "tee"))]
+pub mod mem;
 mod mmio;
 #[cfg(feature = "net")]
 pub mod net;
 mod queue;
+pub mod quiesce;
 #[cfg(not(feature = "tee"))]
 pub mod rng;
 #[cfg(feature = "snd")]
@@ -46,6 +49,8 @@ pub use self::device::*;
 pub use self::fs::*;
 #[cfg(feature = "gpu")]
 pub use self::gpu::*;
+#[cfg(not(feature = "tee"))]
+pub use self::mem::*;
 pub use self::mmio::*;
 #[cfg(feature = "net")]
 pub use self::net::Net;
@@ -21,6 +21,7 @@ use std::path::PathBuf;
 use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 use utils::eventfd::{EventFd, EFD_NONBLOCK};
+use utils::fd_budget::{FdBudget, FdClient, FdPriority};
 use virtio_bindings::virtio_net::{
     VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO,
     VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC,
@@ -88,6 +89,8 @@ pub struct Net {
     irq_line: Option<u32>,
 
     config: VirtioNetConfig,
+
+    fd_client: FdClient,
 }
 
 impl Net {
@@ -116,6 +119,8 @@ impl Net {
             max_virtqueue_pairs: 0,
         };
 
+        let fd_client = FdBudget::global().register(format!("virtio-net {id}"), FdPriority::High);
+
         Ok(Net {
             id,
             cfg_backend,
@@ -135,6 +140,8 @@ impl Net {
             irq_line: None,
 
             config,
+
+            fd_client,
         })
     }
 
@@ -231,6 +238,7 @@ impl VirtioDevice for Net {
             self.irq_line,
             mem.clone(),
             self.cfg_backend.clone(),
+            &self.fd_client,
         );
         worker.run();
 
@@ -244,4 +252,9 @@ impl VirtioDevice for Net {
             DeviceState::Activated(_) => true,
         }
     }
+
+    fn snapshot_supported(&self) -> bool {
+        // The connections are lost, as if the host had closed them
+        true
+    }
 }
@@ -16,6 +16,7 @@ use std::thread;
 use std::{cmp, mem, result};
 use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
 use utils::eventfd::EventFd;
+use utils::fd_budget::{FdClient, FdGrant};
 use virtio_bindings::virtio_net::virtio_net_hdr_v1;
 use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
 
@@ -49,6 +50,8 @@ pub struct NetWorker {
     tx_iovec: Vec<(GuestAddress, usize)>,
     tx_frame_buf: [u8; MAX_BUFFER_SIZE],
     tx_frame_len: usize,
+
+    _fd_grant: Option<FdGrant>,
 }
 
 impl NetWorker {
@@ -62,7 +65,13 @@ impl NetWorker {
         irq_line: Option<u32>,
         mem: GuestMemoryMmap,
         cfg_backend: VirtioNetBackend,
+        fd_client: &FdClient,
     ) -> Self {
+        // The device can't work without its backend, so it isn't held back by the budget
+        let fd_grant = fd_client
+            .try_acquire(1)
+            .map_err(|e| log::warn!("virtio-net: backend socket over the fd budget: {e}"))
+            .ok();
         let backend = match cfg_backend {
             VirtioNetBackend::Passt(fd) => Box::new(Passt::new(fd)) as Box<dyn NetBackend + Send>,
             VirtioNetBackend::Gvproxy(path) => {
@@ -88,6 +97,8 @@ impl NetWorker {
             tx_frame_buf: [0u8; MAX_BUFFER_SIZE],
             tx_frame_len: 0,
             tx_iovec: Vec::with_capacity(QUEUE_SIZE as usize),
+
+            _fd_grant: fd_grant,
         }
     }
 
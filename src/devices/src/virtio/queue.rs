@@ -15,6 +15,8 @@ use vm_memory::{
     VolatileMemoryError,
 };
 
+use super::quiesce;
+
 /// Size of used ring header: flags (u16) + idx (u16)
 pub(crate) const VIRTQ_USED_RING_HEADER_SIZE: u64 = 4;
 
@@ -499,11 +501,42 @@ impl Queue {
         self.next_avail -= Wrapping(1);
     }
 
+    /// Picks up the processing of the queue where the used ring says it stopped, once the guest
+    /// memory was restored from a snapshot taken while the queues were quiesced. The requests the
+    /// device had popped without completing them are popped again.
+    pub fn restore_position(&mut self, mem: &GuestMemoryMmap) -> Result<(), Error> {
+        let addr = self
+            .used_ring
+            .checked_add(2)
+            .ok_or(Error::AddressOverflow)?;
+        let used_idx: u16 = mem
+            .load(addr, Ordering::Acquire)
+            .map_err(Error::GuestMemory)?;
+
+        self.next_avail = Wrapping(used_idx);
+        self.next_used = Wrapping(used_idx);
+        self.num_added = Wrapping(0);
+        Ok(())
+    }
+
     pub fn add_used(
         &mut self,
         mem: &GuestMemoryMmap,
         head_index: u16,
         len: u32,
+    ) -> Result<(), Error> {
+        self.add_used_deferred(mem, head_index, len)?;
+        self.publish_used(mem)
+    }
+
+    /// Writes a used element to the used ring without making it visible to the driver. The
+    /// element becomes visible, together with any other deferred elements, on the next call to
+    /// `publish_used()` (or `add_used()`).
+    pub fn add_used_deferred(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        head_index: u16,
+        len: u32,
     ) -> Result<(), Error> {
         if head_index >= self.size {
             error!(
@@ -513,6 +546,9 @@ impl Queue {
             return Err(Error::InvalidDescriptorIndex);
         }
 
+        quiesce::wait_completion();
+        quiesce::record_completion(mem, self.desc_table, self.actual_size(), head_index);
+
         let next_used_index = u64::from(self.next_used.0 % self.size);
         // This can not overflow an u64 since it is working with relatively small numbers compared
         // to u64::MAX.
@@ -527,6 +563,12 @@ impl Queue {
         self.next_used += Wrapping(1);
         self.num_added += Wrapping(1);
 
+        Ok(())
+    }
+
+    /// Publishes all the used elements added so far by updating the index of the used ring.
+    pub fn publish_used(&mut self, mem: &GuestMemoryMmap) -> Result<(), Error> {
+        quiesce::wait_completion();
         mem.store(
             self.next_used.0,
             self.used_ring
@@ -702,7 +744,13 @@ impl Queue {
     /// Fetch the available ring index (`virtq_avail->idx`) from guest memory.
     /// This is written by the driver, to indicate the next slot that will be filled in the avail
     /// ring.
+    ///
+    /// While the queues are frozen, the ring looks as if the driver made nothing more available.
     fn avail_idx(&self, mem: &GuestMemoryMmap, order: Ordering) -> Result<Wrapping<u16>, Error> {
+        if quiesce::frozen() {
+            return Ok(self.next_avail);
+        }
+
         let addr = self
             .avail_ring
             .checked_add(2)
@@ -1142,4 +1190,60 @@ pub(crate) mod tests {
         assert_eq!(x.id, 1);
         assert_eq!(x.len, 0x1000);
     }
+
+    #[test]
+    fn test_add_used_deferred() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        let mut q = vq.create_queue();
+
+        // deferred elements are written but not visible to the driver
+        q.add_used_deferred(m, 1, 0x1000).unwrap();
+        q.add_used_deferred(m, 2, 0x2000).unwrap();
+        assert_eq!(vq.used.idx.get(), 0);
+        assert_eq!(vq.used.ring[1].get().id, 2);
+
+        // a single publish makes all of them visible at once
+        q.publish_used(m).unwrap();
+        assert_eq!(vq.used.idx.get(), 2);
+        assert!(q.needs_notification(m).unwrap());
+
+        //index too large
+        assert!(q.add_used_deferred(m, 16, 0x1000).is_err());
+        q.publish_used(m).unwrap();
+        assert_eq!(vq.used.idx.get(), 2);
+    }
+
+    #[test]
+    fn test_restore_position() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        let mut q = vq.create_queue();
+
+        for i in 0..3 {
+            vq.dtable[i].set(0x1000 * (i as u64 + 1), 0x100, VIRTQ_DESC_F_WRITE, 0);
+            vq.avail.ring[i].set(i as u16);
+        }
+        vq.avail.idx.set(3);
+
+        // The buffers written by the requests completed while tracking are recorded
+        quiesce::start_tracking();
+        for _ in 0..2 {
+            let head = q.pop(m).unwrap();
+            q.add_used(m, head.index, 0x100).unwrap();
+        }
+        let written = quiesce::stop_tracking();
+        assert!(written.contains(&(GuestAddress(0x1000), 0x100)));
+        assert!(written.contains(&(GuestAddress(0x2000), 0x100)));
+
+        // A queue restored from the guest memory picks up after the last completed request
+        let mut restored = vq.create_queue();
+        restored.restore_position(m).unwrap();
+        assert_eq!(restored.pop(m).unwrap().index, 2);
+        assert!(restored.pop(m).is_none());
+        restored.add_used(m, 2, 0x100).unwrap();
+        assert_eq!(vq.used.idx.get(), 3);
+        assert_eq!(vq.used.ring[2].get().id, 2);
+    }
 }
@@ -0,0 +1,123 @@
+//! Quiescing the queues of every virtio device, to snapshot the guest.
+//!
+//! The devices process their queues on threads of their own, so instead of asking each of them to
+//! stop, the queues are frozen where the devices pop requests and complete them. Once frozen, the
+//! queues look empty to the devices, which finish the requests they started and go idle. The
+//! completions can then be held, leaving the used rings alone while the guest memory is copied,
+//! and the devices that keep buffers of the guest around, like the receive queues, block on their
+//! next completion until the queues are thawed.
+//!
+//! The writes of the devices to the guest memory don't show in the dirty log of the hypervisor, so
+//! the buffers written by the completed requests are recorded while tracking is on.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+
+use vm_memory::{GuestAddress, GuestMemoryMmap};
+
+use super::DescriptorChain;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+/// Whether the queues look empty to the devices.
+static FROZEN: AtomicBool = AtomicBool::new(false);
+
+/// Whether the completions wait for `HELD_CHANGED`.
+static HELD: AtomicBool = AtomicBool::new(false);
+static HELD_LOCK: Mutex<()> = Mutex::new(());
+static HELD_CHANGED: Condvar = Condvar::new();
+
+/// Whether the buffers written by the completed requests are recorded in `WRITTEN`.
+static TRACKING: AtomicBool = AtomicBool::new(false);
+static WRITTEN: Mutex<Vec<(GuestAddress, u32)>> = Mutex::new(Vec::new());
+
+/// The number of requests completed since the process started.
+static COMPLETIONS: AtomicU64 = AtomicU64::new(0);
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Makes the queues look empty to the devices, which stop popping requests. The devices must be
+/// kicked once the queues are thawed, as the notifications of the guest in the meantime are lost.
+pub fn freeze_queues() {
+    FROZEN.store(true, Ordering::SeqCst);
+}
+
+/// Lets the devices pop requests again.
+pub fn thaw_queues() {
+    FROZEN.store(false, Ordering::SeqCst);
+}
+
+/// Makes the devices wait before completing requests, until `release_completions` is called.
+pub fn hold_completions() {
+    let _lock = HELD_LOCK.lock().unwrap();
+    HELD.store(true, Ordering::SeqCst);
+}
+
+/// Lets the devices complete the requests they were holding.
+pub fn release_completions() {
+    let _lock = HELD_LOCK.lock().unwrap();
+    HELD.store(false, Ordering::SeqCst);
+    HELD_CHANGED.notify_all();
+}
+
+/// Starts recording the guest memory written by the requests completed from now on, forgetting
+/// what was recorded before.
+pub fn start_tracking() {
+    WRITTEN.lock().unwrap().clear();
+    TRACKING.store(true, Ordering::SeqCst);
+}
+
+/// Stops recording the guest memory written by the devices, returning the ranges written since
+/// `start_tracking` was called.
+pub fn stop_tracking() -> Vec<(GuestAddress, u32)> {
+    TRACKING.store(false, Ordering::SeqCst);
+    std::mem::take(&mut *WRITTEN.lock().unwrap())
+}
+
+/// Returns the number of requests the devices completed so far, to tell when they went idle.
+pub fn completions() -> u64 {
+    COMPLETIONS.load(Ordering::SeqCst)
+}
+
+pub(crate) fn frozen() -> bool {
+    FROZEN.load(Ordering::Relaxed)
+}
+
+/// Waits until the completions aren't held, before one is written to the used ring.
+pub(crate) fn wait_completion() {
+    if !HELD.load(Ordering::SeqCst) {
+        return;
+    }
+
+    let mut lock = HELD_LOCK.lock().unwrap();
+    while HELD.load(Ordering::SeqCst) {
+        lock = HELD_CHANGED.wait(lock).unwrap();
+    }
+}
+
+/// Records the completion of the descriptor chain at `index` of the descriptor table at
+/// `desc_table`, along with the buffers it let the device write if tracking is on.
+pub(crate) fn record_completion(
+    mem: &GuestMemoryMmap,
+    desc_table: GuestAddress,
+    queue_size: u16,
+    index: u16,
+) {
+    COMPLETIONS.fetch_add(1, Ordering::Relaxed);
+    if !TRACKING.load(Ordering::SeqCst) {
+        return;
+    }
+
+    let Some(head) = DescriptorChain::checked_new(mem, desc_table, queue_size, index) else {
+        return;
+    };
+    let written = head
+        .into_iter()
+        .writable()
+        .map(|desc| (desc.addr, desc.len));
+    WRITTEN.lock().unwrap().extend(written);
+}
@@ -1,6 +1,8 @@
 use std::result;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use rand::{rngs::OsRng, RngCore};
 use utils::eventfd::EventFd;
@@ -10,6 +12,7 @@ use super::super::{
     ActivateError, ActivateResult, DeviceState, Queue as VirtQueue, RngError, VirtioDevice,
     VIRTIO_MMIO_INT_VRING,
 };
+use super::rate_limiter::RateLimiter;
 use super::{defs, defs::uapi};
 use crate::legacy::IrqChip;
 use crate::Error as DeviceError;
@@ -24,6 +27,14 @@ pub(crate) const AVAIL_FEATURES: u64 = 1 << uapi::VIRTIO_F_VERSION_1 as u64;
 #[repr(C, packed)]
 pub struct VirtioRng {}
 
+/// The statistics of the entropy given to the guest.
+#[derive(Debug, Default)]
+pub struct RngStats {
+    requests: AtomicU64,
+    bytes: AtomicU64,
+    throttled: AtomicU64,
+}
+
 pub struct Rng {
     pub(crate) queues: Vec<VirtQueue>,
     pub(crate) queue_events: Vec<EventFd>,
@@ -35,6 +46,27 @@ pub struct Rng {
     pub(crate) device_state: DeviceState,
     intc: Option<IrqChip>,
     irq_line: Option<u32>,
+    rate_limiter: Option<RateLimiter>,
+    // Whether a thread will notify the request queue once the rate limiter allows more bytes.
+    retry_scheduled: Arc<AtomicBool>,
+    stats: Arc<RngStats>,
+}
+
+impl RngStats {
+    /// Returns the number of requests of the guest served so far.
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of bytes of entropy given to the guest so far.
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of times the guest had to wait for the rate limiter.
+    pub fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
 }
 
 impl Rng {
@@ -56,6 +88,9 @@ impl Rng {
             device_state: DeviceState::Inactive,
             intc: None,
             irq_line: None,
+            rate_limiter: None,
+            retry_scheduled: Arc::new(AtomicBool::new(false)),
+            stats: Arc::new(RngStats::default()),
         })
     }
 
@@ -75,6 +110,17 @@ impl Rng {
         self.intc = Some(intc);
     }
 
+    /// Limits the entropy given to the guest to `bytes_per_sec`, with bursts of up to a second
+    /// worth of bytes.
+    pub fn set_rate_limit(&mut self, bytes_per_sec: u64) {
+        self.rate_limiter =
+            (bytes_per_sec != 0).then(|| RateLimiter::new(bytes_per_sec, Instant::now()));
+    }
+
+    pub fn stats(&self) -> Arc<RngStats> {
+        self.stats.clone()
+    }
+
     pub fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
         debug!("rng: raising IRQ");
         self.interrupt_status
@@ -100,17 +146,51 @@ impl Rng {
         while let Some(head) = self.queues[REQ_INDEX].pop(mem) {
             let index = head.index;
             let mut written = 0;
+            let mut throttled = None;
             for desc in head.into_iter() {
-                let mut rand_bytes = vec![0u8; desc.len as usize];
+                // The guest may be given fewer bytes than it asked for
+                let len = match self.rate_limiter.as_mut() {
+                    Some(limiter) => {
+                        let now = Instant::now();
+                        let len = limiter.take(u64::from(desc.len), now) as u32;
+                        if len < desc.len {
+                            throttled = Some(limiter.delay(u64::from(desc.len), now));
+                        }
+                        len
+                    }
+                    None => desc.len,
+                };
+                if len == 0 {
+                    break;
+                }
+
+                let mut rand_bytes = vec![0u8; len as usize];
                 OsRng.fill_bytes(&mut rand_bytes);
                 if let Err(e) = mem.write_slice(&rand_bytes[..], desc.addr) {
                     error!("Failed to write slice: {:?}", e);
                     self.queues[REQ_INDEX].go_to_previous_position();
                     break;
                 }
-                written += desc.len;
+                written += len;
+                if throttled.is_some() {
+                    break;
+                }
+            }
+
+            if let Some(delay) = throttled {
+                self.stats.throttled.fetch_add(1, Ordering::Relaxed);
+                if written == 0 {
+                    // Nothing can be given yet, the request waits for the rate limiter
+                    self.queues[REQ_INDEX].undo_pop();
+                    self.schedule_retry(delay);
+                    break;
+                }
             }
 
+            self.stats.requests.fetch_add(1, Ordering::Relaxed);
+            self.stats
+                .bytes
+                .fetch_add(u64::from(written), Ordering::Relaxed);
             have_used = true;
             if let Err(e) = self.queues[REQ_INDEX].add_used(mem, index, written) {
                 error!("failed to add used elements to the queue: {:?}", e);
@@ -119,6 +199,36 @@ impl Rng {
 
         have_used
     }
+
+    /// Notifies the request queue after `delay`, once the rate limiter allows more bytes.
+    fn schedule_retry(&self, delay: Duration) {
+        if self.retry_scheduled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let retry_scheduled = self.retry_scheduled.clone();
+        let queue_evt = match self.queue_events[REQ_INDEX].try_clone() {
+            Ok(queue_evt) => queue_evt,
+            Err(e) => {
+                error!("rng: failed to clone the request queue event: {e}");
+                retry_scheduled.store(false, Ordering::SeqCst);
+                return;
+            }
+        };
+        let spawned = thread::Builder::new()
+            .name("rng throttle".into())
+            .spawn(move || {
+                thread::sleep(delay);
+                retry_scheduled.store(false, Ordering::SeqCst);
+                if let Err(e) = queue_evt.write(1) {
+                    error!("rng: failed to notify the request queue: {e}");
+                }
+            });
+        if let Err(e) = spawned {
+            error!("rng: failed to spawn the throttle thread: {e}");
+            self.retry_scheduled.store(false, Ordering::SeqCst);
+        }
+    }
 }
 
 impl VirtioDevice for Rng {
@@ -202,6 +312,10 @@ impl VirtioDevice for Rng {
         }
     }
 
+    fn snapshot_supported(&self) -> bool {
+        true
+    }
+
     fn reset(&mut self) -> bool {
         // Strictly speaking, we should unsubscribe the queue events resubscribe
         // the activate eventfd and deactivate the device, but we don't support
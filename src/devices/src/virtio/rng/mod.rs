@@ -1,8 +1,9 @@
 mod device;
 mod event_handler;
+mod rate_limiter;
 
 pub use self::defs::uapi::VIRTIO_ID_RNG as TYPE_RNG;
-pub use self::device::Rng;
+pub use self::device::{Rng, RngStats};
 
 mod defs {
     pub const RNG_DEV_ID: &str = "virtio_rng";
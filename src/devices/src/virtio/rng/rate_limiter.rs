@@ -0,0 +1,79 @@
+use std::time::{Duration, Instant};
+
+/// A token bucket limiting the bytes of entropy given to the guest, allowing bursts of up to a
+/// second worth of bytes.
+pub(crate) struct RateLimiter {
+    /// The bytes per second the bucket refills by, which is also its size.
+    rate: u64,
+    budget: u64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    /// Creates a full bucket at `now` refilling by `rate` bytes per second.
+    pub(crate) fn new(rate: u64, now: Instant) -> Self {
+        RateLimiter {
+            rate,
+            budget: rate,
+            refilled: now,
+        }
+    }
+
+    /// Takes up to `wanted` bytes from the budget at `now`, returning how many were taken.
+    pub(crate) fn take(&mut self, wanted: u64, now: Instant) -> u64 {
+        self.refill(now);
+        let taken = wanted.min(self.budget);
+        self.budget -= taken;
+        taken
+    }
+
+    /// Returns how long after `now` the budget will have refilled by `wanted` bytes, or by all it
+    /// can hold if that's less.
+    pub(crate) fn delay(&mut self, wanted: u64, now: Instant) -> Duration {
+        self.refill(now);
+        let missing = wanted.min(self.rate).saturating_sub(self.budget);
+        Duration::from_nanos((missing as u128 * 1_000_000_000).div_ceil(self.rate as u128) as u64)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_nanos();
+        let earned = elapsed * self.rate as u128 / 1_000_000_000;
+        if earned == 0 {
+            return;
+        }
+
+        // The time of the fraction of a byte not earned yet is kept for the next refill
+        self.budget = (self.budget as u128 + earned).min(self.rate as u128) as u64;
+        if self.budget == self.rate {
+            self.refilled = now;
+        } else {
+            let spent = (earned * 1_000_000_000).div_ceil(self.rate as u128) as u64;
+            self.refilled += Duration::from_nanos(spent);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rate_limiter() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(1000, start);
+
+        // A second worth of bytes is available right away
+        assert_eq!(limiter.take(600, start), 600);
+        assert_eq!(limiter.take(600, start), 400);
+        assert_eq!(limiter.take(1, start), 0);
+        assert_eq!(limiter.delay(100, start), Duration::from_millis(100));
+
+        // Then it refills at the rate, up to a second worth of bytes
+        let later = start + Duration::from_millis(250);
+        assert_eq!(limiter.take(1000, later), 250);
+        assert_eq!(limiter.delay(5000, later), Duration::from_secs(1));
+        let much_later = later + Duration::from_secs(10);
+        assert_eq!(limiter.delay(100, much_later), Duration::ZERO);
+        assert_eq!(limiter.take(5000, much_later), 1000);
+    }
+}
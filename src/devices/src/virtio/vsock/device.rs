@@ -7,6 +7,7 @@
 
 use std::collections::HashMap;
 use std::net::Ipv4Addr;
+use std::os::unix::io::OwnedFd;
 use std::path::PathBuf;
 use std::result;
 use std::sync::atomic::{AtomicUsize, Ordering};
@@ -25,6 +26,7 @@ use super::super::{
 use super::ip_filter::IpFilterConfig;
 use super::muxer::VsockMuxer;
 use super::packet::VsockPacket;
+use super::port_map::PortMap;
 use super::{defs, defs::uapi};
 use crate::legacy::IrqChip;
 
@@ -60,7 +62,7 @@ pub struct Vsock {
 impl Vsock {
     pub(crate) fn with_queues(
         cid: u64,
-        host_port_map: Option<HashMap<u16, u16>>,
+        host_port_map: Option<PortMap>,
         queues: Vec<VirtQueue>,
         unix_ipc_port_map: Option<HashMap<u32, (PathBuf, bool)>>,
         ip: Option<Ipv4Addr>,
@@ -113,7 +115,7 @@ impl Vsock {
     /// Create a new virtio-vsock device with the given VM CID.
     pub fn new(
         cid: u64,
-        host_port_map: Option<HashMap<u16, u16>>,
+        host_port_map: Option<PortMap>,
         unix_ipc_port_map: Option<HashMap<u32, (PathBuf, bool)>>,
         ip: Option<Ipv4Addr>,
         subnet: Option<Ipv4Network>,
@@ -138,6 +140,12 @@ impl Vsock {
         self.cid
     }
 
+    /// Forwards the connections accepted from `fd`, a socket the embedder already bound and
+    /// listens on, to the guest port `port`. The device takes ownership of the socket.
+    pub fn add_listener_fd(&mut self, port: u32, fd: OwnedFd) {
+        self.muxer.add_listener_fd(port, fd);
+    }
+
     /// Signal the guest driver that we've used some virtio buffers that it had previously made
     /// available.
     pub fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
@@ -348,4 +356,9 @@ impl VirtioDevice for Vsock {
             DeviceState::Activated(_) => true,
         }
     }
+
+    fn snapshot_supported(&self) -> bool {
+        // The connections are lost, as if the host had closed them
+        true
+    }
 }
@@ -20,9 +20,11 @@ mod timesync;
 mod udp;
 mod unix;
 mod ip_filter;
+mod port_map;
 
 pub use self::defs::uapi::VIRTIO_ID_VSOCK as TYPE_VSOCK;
 pub use self::device::Vsock;
+pub use self::port_map::{PortMap, PortMapError, PortMapping};
 
 use vm_memory::GuestMemoryError;
 
@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{OwnedFd, RawFd};
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
@@ -12,6 +12,7 @@ use super::defs::uapi;
 use super::muxer_rxq::{rx_to_pkt, MuxerRxQ};
 use super::muxer_thread::MuxerThread;
 use super::packet::{TsiConnectReq, TsiGetnameRsp, VsockPacket};
+use super::port_map::PortMap;
 use super::proxy::{Proxy, ProxyRemoval, ProxyUpdate};
 use super::reaper::ReaperThread;
 use super::tcp::TcpProxy;
@@ -23,6 +24,7 @@ use super::VsockError;
 use crossbeam_channel::{unbounded, Sender};
 use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
 use utils::eventfd::EventFd;
+use utils::fd_budget::{FdBudget, FdClient, FdPriority};
 use vm_memory::GuestMemoryMmap;
 
 use std::net::Ipv4Addr;
@@ -102,7 +104,7 @@ pub fn push_packet(
 
 pub struct VsockMuxer {
     cid: u64,
-    host_port_map: Option<HashMap<u16, u16>>,
+    host_port_map: Option<PortMap>,
     queue: Option<Arc<Mutex<VirtQueue>>>,
     mem: Option<GuestMemoryMmap>,
     rxq: Arc<Mutex<MuxerRxQ>>,
@@ -114,13 +116,15 @@ pub struct VsockMuxer {
     proxy_map: ProxyMap,
     reaper_sender: Option<Sender<u64>>,
     unix_ipc_port_map: Option<HashMap<u32, (PathBuf, bool)>>,
+    listener_fds: HashMap<u32, OwnedFd>,
     ip_filter: IpFilterConfig,
+    fd_client: FdClient,
 }
 
 impl VsockMuxer {
     pub(crate) fn new(
         cid: u64,
-        host_port_map: Option<HashMap<u16, u16>>,
+        host_port_map: Option<PortMap>,
         interrupt_evt: EventFd,
         interrupt_status: Arc<AtomicUsize>,
         unix_ipc_port_map: Option<HashMap<u32, (PathBuf, bool)>>,
@@ -144,10 +148,17 @@ impl VsockMuxer {
             proxy_map: Arc::new(RwLock::new(HashMap::new())),
             reaper_sender: None,
             unix_ipc_port_map,
+            listener_fds: HashMap::new(),
             ip_filter,
+            fd_client: FdBudget::global().register("virtio-vsock", FdPriority::Normal),
         }
     }
 
+    /// Forwards the connections accepted from the listening socket `fd` to the guest port `port`.
+    pub(crate) fn add_listener_fd(&mut self, port: u32, fd: OwnedFd) {
+        self.listener_fds.insert(port, fd);
+    }
+
     pub(crate) fn activate(
         &mut self,
         mem: GuestMemoryMmap,
@@ -189,6 +200,8 @@ impl VsockMuxer {
             irq_line,
             sender.clone(),
             self.unix_ipc_port_map.clone().unwrap_or_default(),
+            std::mem::take(&mut self.listener_fds),
+            self.fd_client.clone(),
         );
         thread.run();
 
@@ -296,6 +309,7 @@ impl VsockMuxer {
                         mem.clone(),
                         queue.clone(),
                         self.rxq.clone(),
+                        &self.fd_client,
                     ) {
                         Ok(proxy) => {
                             self.proxy_map
@@ -316,6 +330,7 @@ impl VsockMuxer {
                         mem.clone(),
                         queue.clone(),
                         self.rxq.clone(),
+                        &self.fd_client,
                     ) {
                         Ok(proxy) => {
                             self.proxy_map
@@ -561,7 +576,7 @@ impl VsockMuxer {
                 }
                 let rxq = self.rxq.clone();
 
-                let mut unix = UnixProxy::new(
+                let mut unix = match UnixProxy::new(
                     id,
                     self.cid,
                     pkt.dst_port(),
@@ -570,8 +585,19 @@ impl VsockMuxer {
                     queue.clone(),
                     rxq,
                     path.to_path_buf(),
-                )
-                .unwrap();
+                    &self.fd_client,
+                ) {
+                    Ok(unix) => unix,
+                    Err(e) => {
+                        warn!("vsock: error creating unix proxy, sending rst: {:?}", e);
+                        let rx = MuxerRx::Reset {
+                            local_port: pkt.dst_port(),
+                            peer_port: pkt.src_port(),
+                        };
+                        push_packet(self.cid, rx, &self.rxq, queue, mem);
+                        return;
+                    }
+                };
                 let tsi = TsiConnectReq {
                     peer_port: 0,
                     addr: Ipv4Addr::new(0, 0, 0, 0),
@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{IntoRawFd, OwnedFd, RawFd};
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
@@ -19,6 +19,7 @@ use crossbeam_channel::Sender;
 use rand::{rngs::ThreadRng, thread_rng, Rng};
 use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
 use utils::eventfd::EventFd;
+use utils::fd_budget::{FdClient, FdGrant};
 use vm_memory::GuestMemoryMmap;
 
 pub struct MuxerThread {
@@ -34,6 +35,8 @@ pub struct MuxerThread {
     irq_line: Option<u32>,
     reaper_sender: Sender<u64>,
     unix_ipc_port_map: HashMap<u32, (PathBuf, bool)>,
+    listener_fds: HashMap<u32, OwnedFd>,
+    fd_client: FdClient,
 }
 
 impl MuxerThread {
@@ -51,6 +54,8 @@ impl MuxerThread {
         irq_line: Option<u32>,
         reaper_sender: Sender<u64>,
         unix_ipc_port_map: HashMap<u32, (PathBuf, bool)>,
+        listener_fds: HashMap<u32, OwnedFd>,
+        fd_client: FdClient,
     ) -> Self {
         MuxerThread {
             cid,
@@ -65,6 +70,8 @@ impl MuxerThread {
             irq_line,
             reaper_sender,
             unix_ipc_port_map,
+            listener_fds,
+            fd_client,
         }
     }
 
@@ -119,39 +126,19 @@ impl MuxerThread {
         let mut should_signal = update.signal_queue;
 
         if let Some((peer_port, accept_fd, proxy_type)) = update.new_proxy {
-            let local_port: u32 = thread_rng.gen_range(1024..u32::MAX);
-            let new_id: u64 = ((peer_port as u64) << 32) | (local_port as u64);
-            let new_proxy: Box<dyn Proxy> = match proxy_type {
-                NewProxyType::Tcp => Box::new(TcpProxy::new_reverse(
-                    new_id,
-                    self.cid,
-                    id,
-                    local_port,
-                    peer_port,
-                    accept_fd,
-                    self.mem.clone(),
-                    self.queue.clone(),
-                    self.rxq.clone(),
-                )),
-                NewProxyType::Unix => Box::new(UnixProxy::new_reverse(
-                    new_id,
-                    self.cid,
-                    local_port,
-                    peer_port,
-                    accept_fd,
-                    self.mem.clone(),
-                    self.queue.clone(),
-                    self.rxq.clone(),
-                )),
-            };
-            self.proxy_map
-                .write()
-                .unwrap()
-                .insert(new_id, Mutex::new(new_proxy));
-            if let Some(proxy) = self.proxy_map.read().unwrap().get(&new_id) {
-                proxy.lock().unwrap().push_op_request();
-            };
-            should_signal = true;
+            // The connection is already accepted, so it's refused rather than held back
+            match self.fd_client.try_acquire(1) {
+                Ok(fd_grant) => {
+                    self.add_reverse_proxy(
+                        id, peer_port, accept_fd, proxy_type, fd_grant, thread_rng,
+                    );
+                    should_signal = true;
+                }
+                Err(e) => {
+                    warn!("vsock: refusing connection to port {}: {}", peer_port, e);
+                    let _ = nix::unistd::close(accept_fd);
+                }
+            }
         }
 
         if should_signal {
@@ -170,6 +157,53 @@ impl MuxerThread {
         }
     }
 
+    /// Adds a proxy for the connection `accept_fd` accepted by the proxy `id`, to be forwarded to
+    /// the guest port `peer_port`.
+    fn add_reverse_proxy(
+        &self,
+        id: u64,
+        peer_port: u32,
+        accept_fd: RawFd,
+        proxy_type: NewProxyType,
+        fd_grant: FdGrant,
+        thread_rng: &mut ThreadRng,
+    ) {
+        let local_port: u32 = thread_rng.gen_range(1024..u32::MAX);
+        let new_id: u64 = ((peer_port as u64) << 32) | (local_port as u64);
+        let new_proxy: Box<dyn Proxy> = match proxy_type {
+            NewProxyType::Tcp => Box::new(TcpProxy::new_reverse(
+                new_id,
+                self.cid,
+                id,
+                local_port,
+                peer_port,
+                accept_fd,
+                self.mem.clone(),
+                self.queue.clone(),
+                self.rxq.clone(),
+                fd_grant,
+            )),
+            NewProxyType::Unix => Box::new(UnixProxy::new_reverse(
+                new_id,
+                self.cid,
+                local_port,
+                peer_port,
+                accept_fd,
+                self.mem.clone(),
+                self.queue.clone(),
+                self.rxq.clone(),
+                fd_grant,
+            )),
+        };
+        self.proxy_map
+            .write()
+            .unwrap()
+            .insert(new_id, Mutex::new(new_proxy));
+        if let Some(proxy) = self.proxy_map.read().unwrap().get(&new_id) {
+            proxy.lock().unwrap().push_op_request();
+        };
+    }
+
     fn create_lisening_ipc_sockets(&self) {
         for (port, (path, do_listen)) in &self.unix_ipc_port_map {
             if !do_listen {
@@ -183,19 +217,36 @@ impl MuxerThread {
                     continue;
                 }
             };
-            self.proxy_map
-                .write()
-                .unwrap()
-                .insert(id, Mutex::new(Box::new(proxy)));
-            if let Some(proxy) = self.proxy_map.read().unwrap().get(&id) {
-                self.update_polling(id, proxy.lock().unwrap().as_raw_fd(), EventSet::IN);
-            };
+            self.add_acceptor_proxy(id, proxy);
         }
     }
 
-    fn work(self) {
+    /// Starts accepting from the listening sockets passed by the embedder. Each of them gets its
+    /// own acceptor, which closes the socket once the socket is hung up or the VM shuts down.
+    fn add_listener_fds(&mut self) {
+        for (port, fd) in std::mem::take(&mut self.listener_fds) {
+            let id = ((port as u64) << 32) | (defs::TSI_PROXY_PORT as u64);
+            match UnixAcceptorProxy::from_listener(id, fd.into_raw_fd(), port) {
+                Ok(proxy) => self.add_acceptor_proxy(id, proxy),
+                Err(e) => warn!("Failed to accept from the listener of port {port}: {e:?}"),
+            }
+        }
+    }
+
+    fn add_acceptor_proxy(&self, id: u64, proxy: UnixAcceptorProxy) {
+        self.proxy_map
+            .write()
+            .unwrap()
+            .insert(id, Mutex::new(Box::new(proxy)));
+        if let Some(proxy) = self.proxy_map.read().unwrap().get(&id) {
+            self.update_polling(id, proxy.lock().unwrap().as_raw_fd(), EventSet::IN);
+        };
+    }
+
+    fn work(mut self) {
         let mut thread_rng = thread_rng();
         self.create_lisening_ipc_sockets();
+        self.add_listener_fds();
         loop {
             let mut epoll_events = vec![EpollEvent::new(EventSet::empty(), 0); 32];
             match self
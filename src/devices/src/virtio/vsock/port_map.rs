@@ -0,0 +1,272 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// A range of guest TCP ports exposed on a range of host ports of the same length, where guest
+/// ports map to host ports by their offset in the range.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PortMapping {
+    /// The host address the listeners are bound to. `None` binds them to the address the guest
+    /// listens on. The unspecified IPv6 address `::` gives dual-stack listeners, accepting both
+    /// IPv4 and IPv6 connections.
+    pub host_addr: Option<IpAddr>,
+
+    pub host_ports: RangeInclusive<u16>,
+
+    pub guest_ports: RangeInclusive<u16>,
+}
+
+/// The guest TCP ports exposed on the host, none of which are exposed twice.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PortMap {
+    mappings: Vec<PortMapping>,
+}
+
+/// Errors building a `PortMap`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PortMapError {
+    /// The mapping isn't of the form `[host_addr:]host_ports:guest_ports`.
+    Invalid(String),
+    /// The host and guest port ranges have different lengths.
+    RangeLengthMismatch(String),
+    /// Some guest ports are already exposed by another mapping.
+    GuestPortConflict(String),
+    /// Some host ports on the same address are already used by another mapping.
+    HostPortConflict(String),
+}
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl PortMapping {
+    /// Returns the host address and port `guest_port` is exposed on, if it is in the mapping.
+    pub fn host_port(&self, guest_port: u16) -> Option<(Option<IpAddr>, u16)> {
+        if !self.guest_ports.contains(&guest_port) {
+            return None;
+        }
+
+        let offset = guest_port - self.guest_ports.start();
+        Some((self.host_addr, self.host_ports.start() + offset))
+    }
+
+    /// Whether the host addresses of the two mappings may be the same socket address. A mapping
+    /// without an address may be bound to any, and the unspecified addresses cover all the others.
+    fn host_addr_overlaps(&self, other: &PortMapping) -> bool {
+        match (self.host_addr, other.host_addr) {
+            (Some(a), Some(b)) => a == b || a.is_unspecified() || b.is_unspecified(),
+            _ => true,
+        }
+    }
+}
+
+impl PortMap {
+    /// Adds `mapping`, unless it exposes guest ports or uses host ports that another one already
+    /// does.
+    pub fn insert(&mut self, mapping: PortMapping) -> Result<(), PortMapError> {
+        for other in &self.mappings {
+            if ranges_overlap(&mapping.guest_ports, &other.guest_ports) {
+                return Err(PortMapError::GuestPortConflict(format!(
+                    "{mapping} and {other}"
+                )));
+            }
+            if ranges_overlap(&mapping.host_ports, &other.host_ports)
+                && mapping.host_addr_overlaps(other)
+            {
+                return Err(PortMapError::HostPortConflict(format!(
+                    "{mapping} and {other}"
+                )));
+            }
+        }
+
+        self.mappings.push(mapping);
+        Ok(())
+    }
+
+    /// Returns the host address and port `guest_port` is exposed on, if it is exposed.
+    pub fn host_port(&self, guest_port: u16) -> Option<(Option<IpAddr>, u16)> {
+        self.mappings
+            .iter()
+            .find_map(|mapping| mapping.host_port(guest_port))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mappings.is_empty()
+    }
+}
+
+impl FromStr for PortMapping {
+    type Err = PortMapError;
+
+    /// Parses a mapping of the form `[host_addr:]host_ports:guest_ports`, where IPv6 addresses
+    /// are enclosed in brackets and port ranges are given as `first-last`, e.g.
+    /// `[::1]:8000-8100:9000-9100`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || PortMapError::Invalid(s.to_string());
+
+        let (host_addr, ports) = if let Some(rest) = s.strip_prefix('[') {
+            let (addr, ports) = rest.split_once("]:").ok_or_else(invalid)?;
+            let addr: Ipv6Addr = addr.parse().map_err(|_| invalid())?;
+            (Some(IpAddr::V6(addr)), ports)
+        } else if s.matches(':').count() == 2 {
+            let (addr, ports) = s.split_once(':').unwrap();
+            let addr: Ipv4Addr = addr.parse().map_err(|_| invalid())?;
+            (Some(IpAddr::V4(addr)), ports)
+        } else {
+            (None, s)
+        };
+
+        let (host_ports, guest_ports) = ports.split_once(':').ok_or_else(invalid)?;
+        let host_ports = parse_port_range(host_ports).ok_or_else(invalid)?;
+        let guest_ports = parse_port_range(guest_ports).ok_or_else(invalid)?;
+        if host_ports.end() - host_ports.start() != guest_ports.end() - guest_ports.start() {
+            return Err(PortMapError::RangeLengthMismatch(s.to_string()));
+        }
+
+        Ok(PortMapping {
+            host_addr,
+            host_ports,
+            guest_ports,
+        })
+    }
+}
+
+impl fmt::Display for PortMapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.host_addr {
+            Some(IpAddr::V4(addr)) => write!(f, "{addr}:")?,
+            Some(IpAddr::V6(addr)) => write!(f, "[{addr}]:")?,
+            None => {}
+        }
+        write!(
+            f,
+            "{}:{}",
+            PortRange(&self.host_ports),
+            PortRange(&self.guest_ports)
+        )
+    }
+}
+
+impl fmt::Display for PortMapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PortMapError::Invalid(s) => write!(f, "invalid port mapping \"{s}\""),
+            PortMapError::RangeLengthMismatch(s) => {
+                write!(f, "port ranges of different lengths in \"{s}\"")
+            }
+            PortMapError::GuestPortConflict(s) => write!(f, "guest ports mapped twice by {s}"),
+            PortMapError::HostPortConflict(s) => write!(f, "host ports mapped twice by {s}"),
+        }
+    }
+}
+
+/// Displays a port range as `first-last`, or as a single port.
+struct PortRange<'a>(&'a RangeInclusive<u16>);
+
+impl fmt::Display for PortRange<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.start() == self.0.end() {
+            write!(f, "{}", self.0.start())
+        } else {
+            write!(f, "{}-{}", self.0.start(), self.0.end())
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+fn parse_port_range(s: &str) -> Option<RangeInclusive<u16>> {
+    let (first, last) = s.split_once('-').unwrap_or((s, s));
+    let first: u16 = first.parse().ok()?;
+    let last: u16 = last.parse().ok()?;
+    (first <= last).then_some(first..=last)
+}
+
+fn ranges_overlap(a: &RangeInclusive<u16>, b: &RangeInclusive<u16>) -> bool {
+    a.start() <= b.end() && b.start() <= a.end()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn mapping(s: &str) -> PortMapping {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            mapping("8080:80"),
+            PortMapping {
+                host_addr: None,
+                host_ports: 8080..=8080,
+                guest_ports: 80..=80,
+            }
+        );
+        assert_eq!(
+            mapping("127.0.0.1:8000-8100:9000-9100").host_addr,
+            Some(IpAddr::V4(Ipv4Addr::LOCALHOST))
+        );
+        assert_eq!(
+            mapping("[::]:8000-8100:9000-9100").host_port(9050),
+            Some((Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED)), 8050))
+        );
+        assert_eq!(
+            mapping("[::1]:8000-8001:9000-9001").to_string(),
+            "[::1]:8000-8001:9000-9001"
+        );
+
+        for invalid in [
+            "80",
+            "::1:80:80",
+            "[::1]80:80",
+            "a:80:80",
+            "90-80:90-80",
+            "1:2:3:4",
+        ] {
+            assert!(matches!(
+                invalid.parse::<PortMapping>(),
+                Err(PortMapError::Invalid(_))
+            ));
+        }
+        assert!(matches!(
+            "8000-8100:80".parse::<PortMapping>(),
+            Err(PortMapError::RangeLengthMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn conflicts() {
+        let mut map = PortMap::default();
+        map.insert(mapping("127.0.0.1:8000-8100:8000-8100"))
+            .unwrap();
+        map.insert(mapping("[::1]:8000-8100:9000-9100")).unwrap();
+
+        // Guest ports are only exposed once
+        assert!(matches!(
+            map.insert(mapping("7000:8100")),
+            Err(PortMapError::GuestPortConflict(_))
+        ));
+        // And host ports only used once per address
+        assert!(matches!(
+            map.insert(mapping("[::]:8050:10000")),
+            Err(PortMapError::HostPortConflict(_))
+        ));
+        assert!(matches!(
+            map.insert(mapping("8100-8101:10000-10001")),
+            Err(PortMapError::HostPortConflict(_))
+        ));
+        map.insert(mapping("10.0.0.1:8050:10000")).unwrap();
+
+        assert_eq!(map.host_port(9001).unwrap().1, 8001);
+        assert_eq!(map.host_port(10001), None);
+    }
+}
@@ -1,10 +1,11 @@
-use std::collections::HashMap;
 use std::fmt;
 use std::os::unix::io::{AsRawFd, RawFd};
 
 use super::muxer::MuxerRx;
 use super::packet::{TsiAcceptReq, TsiConnectReq, TsiListenReq, TsiSendtoAddr, VsockPacket};
+use super::port_map::PortMap;
 use utils::epoll::EventSet;
+use utils::fd_budget::{FdClient, FdGrant};
 
 #[derive(Debug)]
 pub enum RecvPkt {
@@ -21,6 +22,14 @@ pub enum ProxyError {
     SettingReusePort(nix::errno::Errno),
 }
 
+/// Accounts for the socket of a new proxy in the budget of the device, failing as if the socket
+/// couldn't be created when the budget is exhausted.
+pub fn acquire_fd(fd_client: &FdClient) -> Result<FdGrant, ProxyError> {
+    fd_client
+        .try_acquire(1)
+        .map_err(|_| ProxyError::CreatingSocket(nix::errno::Errno::EMFILE))
+}
+
 #[derive(Eq, PartialEq, Clone, Copy, Debug)]
 pub enum ProxyStatus {
     Idle,
@@ -80,7 +89,7 @@ pub trait Proxy: Send + AsRawFd {
         &mut self,
         pkt: &VsockPacket,
         req: TsiListenReq,
-        host_port_map: &Option<HashMap<u16, u16>>,
+        host_port_map: &Option<PortMap>,
     ) -> ProxyUpdate;
     fn accept(&mut self, req: TsiAcceptReq) -> ProxyUpdate;
     fn update_peer_credit(&mut self, pkt: &VsockPacket) -> ProxyUpdate;
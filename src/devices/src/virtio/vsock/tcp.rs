@@ -1,5 +1,4 @@
-use std::collections::HashMap;
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4, SocketAddrV6};
 use std::num::Wrapping;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::{Arc, Mutex};
@@ -7,9 +6,10 @@ use std::sync::{Arc, Mutex};
 use nix::fcntl::{fcntl, FcntlArg, OFlag};
 use nix::sys::socket::{
     accept, bind, connect, getpeername, listen, recv, send, setsockopt, shutdown, socket, sockopt,
-    AddressFamily, MsgFlags, Shutdown, SockFlag, SockType, SockaddrIn,
+    AddressFamily, MsgFlags, Shutdown, SockFlag, SockType, SockaddrIn, SockaddrIn6,
+    SockaddrStorage,
 };
-use nix::unistd::close;
+use nix::unistd::{close, dup2};
 
 #[cfg(target_os = "macos")]
 use super::super::linux_errno::linux_errno_raw;
@@ -21,10 +21,12 @@ use super::muxer_rxq::MuxerRxQ;
 use super::packet::{
     TsiAcceptReq, TsiConnectReq, TsiGetnameRsp, TsiListenReq, TsiSendtoAddr, VsockPacket,
 };
+use super::port_map::PortMap;
 use super::proxy::{
-    NewProxyType, Proxy, ProxyError, ProxyRemoval, ProxyStatus, ProxyUpdate, RecvPkt,
+    acquire_fd, NewProxyType, Proxy, ProxyError, ProxyRemoval, ProxyStatus, ProxyUpdate, RecvPkt,
 };
 use utils::epoll::EventSet;
+use utils::fd_budget::{FdClient, FdGrant};
 
 use vm_memory::GuestMemoryMmap;
 
@@ -47,6 +49,7 @@ pub struct TcpProxy {
     peer_fwd_cnt: Wrapping<u32>,
     push_cnt: Wrapping<u32>,
     pending_accepts: u64,
+    _fd_grant: FdGrant,
 }
 
 impl TcpProxy {
@@ -60,14 +63,37 @@ impl TcpProxy {
         mem: GuestMemoryMmap,
         queue: Arc<Mutex<VirtQueue>>,
         rxq: Arc<Mutex<MuxerRxQ>>,
+        fd_client: &FdClient,
     ) -> Result<Self, ProxyError> {
-        let fd = socket(
-            AddressFamily::Inet,
-            SockType::Stream,
-            SockFlag::empty(),
-            None,
-        )
-        .map_err(ProxyError::CreatingSocket)?;
+        let fd_grant = acquire_fd(fd_client)?;
+        let fd = Self::create_socket(id, AddressFamily::Inet)?;
+
+        Ok(TcpProxy {
+            id,
+            cid,
+            parent_id: 0,
+            local_port,
+            peer_port,
+            control_port,
+            fd,
+            status: ProxyStatus::Idle,
+            mem,
+            queue,
+            rxq,
+            rx_cnt: Wrapping(0),
+            tx_cnt: Wrapping(0),
+            last_tx_cnt_sent: Wrapping(0),
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: Wrapping(0),
+            push_cnt: Wrapping(0),
+            pending_accepts: 0,
+            _fd_grant: fd_grant,
+        })
+    }
+
+    fn create_socket(id: u64, family: AddressFamily) -> Result<RawFd, ProxyError> {
+        let fd = socket(family, SockType::Stream, SockFlag::empty(), None)
+            .map_err(ProxyError::CreatingSocket)?;
 
         // macOS forces us to do this here instead of just using SockFlag::SOCK_NONBLOCK above.
         match fcntl(fd, FcntlArg::F_GETFL) {
@@ -98,26 +124,20 @@ impl TcpProxy {
             };
         }
 
-        Ok(TcpProxy {
-            id,
-            cid,
-            parent_id: 0,
-            local_port,
-            peer_port,
-            control_port,
-            fd,
-            status: ProxyStatus::Idle,
-            mem,
-            queue,
-            rxq,
-            rx_cnt: Wrapping(0),
-            tx_cnt: Wrapping(0),
-            last_tx_cnt_sent: Wrapping(0),
-            peer_buf_alloc: 0,
-            peer_fwd_cnt: Wrapping(0),
-            push_cnt: Wrapping(0),
-            pending_accepts: 0,
-        })
+        Ok(fd)
+    }
+
+    /// Replaces the IPv4 socket the proxy was created with by an IPv6 one, keeping the same fd.
+    /// Listeners on the unspecified address are dual-stack, the others only accept IPv6.
+    fn switch_to_ipv6(&mut self, dual_stack: bool) -> nix::Result<()> {
+        let fd = Self::create_socket(self.id, AddressFamily::Inet6).map_err(|e| match e {
+            ProxyError::CreatingSocket(e) | ProxyError::SettingReusePort(e) => e,
+        })?;
+
+        let result = setsockopt(fd, sockopt::Ipv6V6Only, &!dual_stack)
+            .and_then(|_| dup2(fd, self.fd).map(|_| ()));
+        let _ = close(fd);
+        result
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -131,6 +151,7 @@ impl TcpProxy {
         mem: GuestMemoryMmap,
         queue: Arc<Mutex<VirtQueue>>,
         rxq: Arc<Mutex<MuxerRxQ>>,
+        fd_grant: FdGrant,
     ) -> Self {
         debug!(
             "new_reverse: id={} local_port={} peer_port={}",
@@ -155,6 +176,7 @@ impl TcpProxy {
             peer_fwd_cnt: Wrapping(0),
             push_cnt: Wrapping(0),
             pending_accepts: 0,
+            _fd_grant: fd_grant,
         }
     }
 
@@ -173,25 +195,32 @@ impl TcpProxy {
             .set_fwd_cnt(self.tx_cnt.0);
     }
 
-    fn try_listen(&mut self, req: &TsiListenReq, host_port_map: &Option<HashMap<u16, u16>>) -> i32 {
+    fn try_listen(&mut self, req: &TsiListenReq, host_port_map: &Option<PortMap>) -> i32 {
         if self.status == ProxyStatus::Listening || self.status == ProxyStatus::WaitingOnAccept {
             return 0;
         }
 
-        let port = if let Some(port_map) = host_port_map {
-            if let Some(port) = port_map.get(&req.port) {
-                *port
+        let (host_addr, port) = if let Some(port_map) = host_port_map {
+            if let Some(host_port) = port_map.host_port(req.port) {
+                host_port
             } else {
                 return -libc::EPERM;
             }
         } else {
-            req.port
+            (None, req.port)
         };
 
-        match bind(
-            self.fd,
-            &SockaddrIn::from(SocketAddrV4::new(req.addr, port)),
-        ) {
+        let result = match host_addr.unwrap_or(IpAddr::V4(req.addr)) {
+            IpAddr::V4(addr) => bind(self.fd, &SockaddrIn::from(SocketAddrV4::new(addr, port))),
+            IpAddr::V6(addr) => self.switch_to_ipv6(addr.is_unspecified()).and_then(|_| {
+                bind(
+                    self.fd,
+                    &SockaddrIn6::from(SocketAddrV6::new(addr, port, 0, 0)),
+                )
+            }),
+        };
+
+        match result {
             Ok(_) => {
                 debug!("tcp bind: id={}", self.id);
                 match listen(self.fd, req.backlog as usize) {
@@ -210,7 +239,7 @@ impl TcpProxy {
                 }
             }
             Err(e) => {
-                warn!("tcp bind: id={} err={}", self.id, e);
+                warn!("tcp bind: id={} port={} err={}", self.id, port, e);
                 #[cfg(target_os = "macos")]
                 let errno = -linux_errno_raw(e as i32);
                 #[cfg(target_os = "linux")]
@@ -437,10 +466,18 @@ impl Proxy for TcpProxy {
     fn getpeername(&mut self, pkt: &VsockPacket) {
         debug!("getpeername: id={}", self.id);
 
-        let (result, addr, port) = match getpeername::<SockaddrIn>(self.fd) {
+        let (result, addr, port) = match getpeername::<SockaddrStorage>(self.fd) {
             Ok(name) => {
-                let addr = Ipv4Addr::from(name.ip());
-                (0, addr, name.port())
+                if let Some(name) = name.as_sockaddr_in() {
+                    (0, Ipv4Addr::from(name.ip()), name.port())
+                } else if let Some(name) = name.as_sockaddr_in6() {
+                    // The guest only knows about IPv4, so IPv6 peers are reported as unspecified
+                    // unless they are IPv4-mapped, which happens on dual-stack listeners.
+                    let addr = name.ip().to_ipv4_mapped().unwrap_or(Ipv4Addr::UNSPECIFIED);
+                    (0, addr, name.port())
+                } else {
+                    (-libc::EINVAL, Ipv4Addr::UNSPECIFIED, 0)
+                }
             }
             Err(e) => {
                 #[cfg(target_os = "macos")]
@@ -525,7 +562,7 @@ impl Proxy for TcpProxy {
         &mut self,
         pkt: &VsockPacket,
         req: TsiListenReq,
-        host_port_map: &Option<HashMap<u16, u16>>,
+        host_port_map: &Option<PortMap>,
     ) -> ProxyUpdate {
         debug!(
             "listen: id={} addr={}, port={}, vm_port={} backlog={}",
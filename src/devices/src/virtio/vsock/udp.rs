@@ -1,4 +1,3 @@
-use std::collections::HashMap;
 use std::net::{Ipv4Addr, SocketAddrV4};
 use std::num::Wrapping;
 use std::os::unix::io::{AsRawFd, RawFd};
@@ -21,8 +20,12 @@ use super::muxer_rxq::MuxerRxQ;
 use super::packet::{
     TsiAcceptReq, TsiConnectReq, TsiGetnameRsp, TsiListenReq, TsiSendtoAddr, VsockPacket,
 };
-use super::proxy::{Proxy, ProxyError, ProxyRemoval, ProxyStatus, ProxyUpdate, RecvPkt};
+use super::port_map::PortMap;
+use super::proxy::{
+    acquire_fd, Proxy, ProxyError, ProxyRemoval, ProxyStatus, ProxyUpdate, RecvPkt,
+};
 use utils::epoll::EventSet;
+use utils::fd_budget::{FdClient, FdGrant};
 
 use vm_memory::GuestMemoryMmap;
 
@@ -42,6 +45,7 @@ pub struct UdpProxy {
     tx_cnt: Wrapping<u32>,
     peer_buf_alloc: u32,
     peer_fwd_cnt: Wrapping<u32>,
+    _fd_grant: FdGrant,
 }
 
 impl UdpProxy {
@@ -52,7 +56,9 @@ impl UdpProxy {
         mem: GuestMemoryMmap,
         queue: Arc<Mutex<VirtQueue>>,
         rxq: Arc<Mutex<MuxerRxQ>>,
+        fd_client: &FdClient,
     ) -> Result<Self, ProxyError> {
+        let fd_grant = acquire_fd(fd_client)?;
         let fd = socket(
             AddressFamily::Inet,
             SockType::Datagram,
@@ -105,6 +111,7 @@ impl UdpProxy {
             tx_cnt: Wrapping(0),
             peer_buf_alloc: 0,
             peer_fwd_cnt: Wrapping(0),
+            _fd_grant: fd_grant,
         })
     }
 
@@ -372,7 +379,7 @@ impl Proxy for UdpProxy {
         &mut self,
         _pkt: &VsockPacket,
         _req: TsiListenReq,
-        _host_port_map: &Option<HashMap<u16, u16>>,
+        _host_port_map: &Option<PortMap>,
     ) -> ProxyUpdate {
         ProxyUpdate::default()
     }
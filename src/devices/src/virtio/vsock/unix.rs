@@ -9,7 +9,6 @@ use nix::sys::socket::{
     SockFlag, SockType, UnixAddr,
 };
 use nix::unistd::close;
-use std::collections::HashMap;
 use std::num::Wrapping;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::PathBuf;
@@ -21,8 +20,10 @@ use super::super::Queue as VirtQueue;
 use super::muxer::{push_packet, MuxerRx};
 use super::muxer_rxq::MuxerRxQ;
 use super::packet::{TsiAcceptReq, TsiConnectReq, TsiListenReq, TsiSendtoAddr, VsockPacket};
-use super::proxy::{NewProxyType, Proxy, ProxyError, ProxyStatus, ProxyUpdate};
+use super::port_map::PortMap;
+use super::proxy::{acquire_fd, NewProxyType, Proxy, ProxyError, ProxyStatus, ProxyUpdate};
 use utils::epoll::EventSet;
+use utils::fd_budget::{FdClient, FdGrant};
 
 use vm_memory::GuestMemoryMmap;
 
@@ -44,6 +45,7 @@ pub struct UnixProxy {
     last_tx_cnt_sent: Wrapping<u32>,
     push_cnt: Wrapping<u32>,
     rx_cnt: Wrapping<u32>,
+    _fd_grant: FdGrant,
 }
 
 fn proxy_fd_create(id: u64) -> Result<RawFd, ProxyError> {
@@ -97,7 +99,9 @@ impl UnixProxy {
         queue: Arc<Mutex<VirtQueue>>,
         rxq: Arc<Mutex<MuxerRxQ>>,
         path: PathBuf,
+        fd_client: &FdClient,
     ) -> Result<Self, ProxyError> {
+        let fd_grant = acquire_fd(fd_client)?;
         let fd = proxy_fd_create(id)?;
 
         Ok(UnixProxy {
@@ -118,6 +122,7 @@ impl UnixProxy {
             last_tx_cnt_sent: Wrapping(0),
             push_cnt: Wrapping(0),
             rx_cnt: Wrapping(0),
+            _fd_grant: fd_grant,
         })
     }
 
@@ -131,6 +136,7 @@ impl UnixProxy {
         mem: GuestMemoryMmap,
         queue: Arc<Mutex<VirtQueue>>,
         rxq: Arc<Mutex<MuxerRxQ>>,
+        fd_grant: FdGrant,
     ) -> Self {
         debug!(
             "new_reverse: id={} local_port={} peer_port={}",
@@ -154,6 +160,7 @@ impl UnixProxy {
             peer_fwd_cnt: Wrapping(0),
             push_cnt: Wrapping(0),
             path: Default::default(),
+            _fd_grant: fd_grant,
         }
     }
 
@@ -448,7 +455,7 @@ impl Proxy for UnixProxy {
         &mut self,
         _pkt: &VsockPacket,
         _req: TsiListenReq,
-        _host_port_map: &Option<HashMap<u16, u16>>,
+        _host_port_map: &Option<PortMap>,
     ) -> ProxyUpdate {
         todo!();
     }
@@ -653,6 +660,20 @@ impl UnixAcceptorProxy {
         listen(fd, 5).map_err(ProxyError::CreatingSocket)?;
         Ok(UnixAcceptorProxy { id, fd, peer_port })
     }
+
+    /// Takes ownership of a socket that is already listening, such as one passed by a service
+    /// manager. The socket is made non-blocking, as other processes holding it may accept its
+    /// connections too.
+    pub fn from_listener(id: u64, fd: RawFd, peer_port: u32) -> Result<Self, ProxyError> {
+        let proxy = UnixAcceptorProxy { id, fd, peer_port };
+        let flags = fcntl(fd, FcntlArg::F_GETFL).map_err(ProxyError::CreatingSocket)?;
+        fcntl(
+            fd,
+            FcntlArg::F_SETFL(OFlag::from_bits_truncate(flags) | OFlag::O_NONBLOCK),
+        )
+        .map_err(ProxyError::CreatingSocket)?;
+        Ok(proxy)
+    }
 }
 
 impl Proxy for UnixAcceptorProxy {
@@ -674,12 +695,7 @@ impl Proxy for UnixAcceptorProxy {
     fn sendto_addr(&mut self, _: TsiSendtoAddr) -> ProxyUpdate {
         unreachable!()
     }
-    fn listen(
-        &mut self,
-        _: &VsockPacket,
-        _: TsiListenReq,
-        _: &Option<HashMap<u16, u16>>,
-    ) -> ProxyUpdate {
+    fn listen(&mut self, _: &VsockPacket, _: TsiListenReq, _: &Option<PortMap>) -> ProxyUpdate {
         unreachable!()
     }
     fn accept(&mut self, _: TsiAcceptReq) -> ProxyUpdate {
@@ -709,6 +725,9 @@ impl Proxy for UnixAcceptorProxy {
                 Ok(accept_fd) => {
                     update.new_proxy = Some((self.peer_port, accept_fd, NewProxyType::Unix));
                 }
+                Err(nix::errno::Errno::EAGAIN) => {
+                    debug!("connection accepted elsewhere: id={}", self.id);
+                }
                 Err(e) => warn!("error accepting connection: id={}, err={}", self.id, e),
             };
             update.signal_queue = true;
@@ -0,0 +1,72 @@
+//! Capabilities of this build of the library, for embedders to check before configuring a
+//! microVM instead of finding out from the calls failing.
+//!
+//! The devices and file system backends available depend on the features the library was built
+//! with and on the host platform. Each capability is a bit of the mask returned by
+//! `krun_get_capabilities`, and the bits are never reused, so new ones can be added without
+//! breaking the embedders built against an older header.
+
+/// virtio-fs shares of host directories.
+pub const KRUN_CAP_VIRTIOFS: u64 = 1 << 0;
+/// Overlay roots made of read-only layers and a writable top layer.
+pub const KRUN_CAP_OVERLAYFS: u64 = 1 << 1;
+/// Overlay roots whose writable top layer lives in memory.
+pub const KRUN_CAP_OVERLAYFS_EPHEMERAL: u64 = 1 << 2;
+/// DAX windows mapping the files of virtio-fs shares into the guest.
+pub const KRUN_CAP_DAX: u64 = 1 << 3;
+/// virtio-blk disks.
+pub const KRUN_CAP_BLK: u64 = 1 << 4;
+/// virtio-net devices backed by passt or gvproxy.
+pub const KRUN_CAP_NET: u64 = 1 << 5;
+/// virtio-gpu devices.
+pub const KRUN_CAP_GPU: u64 = 1 << 6;
+/// virtio-snd devices.
+pub const KRUN_CAP_SND: u64 = 1 << 7;
+/// Memory hotplug and resizing of running microVMs.
+pub const KRUN_CAP_MEMORY_HOTPLUG: u64 = 1 << 8;
+/// Snapshots of running microVMs.
+pub const KRUN_CAP_SNAPSHOT: u64 = 1 << 9;
+/// Booting EFI firmware instead of a kernel.
+pub const KRUN_CAP_EFI: u64 = 1 << 10;
+/// Confidential microVMs running in a trusted execution environment.
+pub const KRUN_CAP_TEE: u64 = 1 << 11;
+/// Confidential microVMs running on AMD SEV.
+pub const KRUN_CAP_AMD_SEV: u64 = 1 << 12;
+/// Roots built from OCI images.
+pub const KRUN_CAP_OCI: u64 = 1 << 13;
+
+/// Returns the mask of the capabilities of this build.
+pub fn capabilities() -> u64 {
+    [
+        (KRUN_CAP_VIRTIOFS, cfg!(not(feature = "tee"))),
+        (KRUN_CAP_OVERLAYFS, cfg!(not(feature = "tee"))),
+        (
+            KRUN_CAP_OVERLAYFS_EPHEMERAL,
+            cfg!(all(target_os = "linux", not(feature = "tee"))),
+        ),
+        (KRUN_CAP_DAX, cfg!(not(feature = "tee"))),
+        (KRUN_CAP_BLK, cfg!(feature = "blk")),
+        (KRUN_CAP_NET, cfg!(feature = "net")),
+        (KRUN_CAP_GPU, cfg!(feature = "gpu")),
+        (KRUN_CAP_SND, cfg!(feature = "snd")),
+        (KRUN_CAP_MEMORY_HOTPLUG, cfg!(not(feature = "tee"))),
+        (
+            KRUN_CAP_SNAPSHOT,
+            cfg!(all(
+                target_os = "linux",
+                target_arch = "x86_64",
+                not(feature = "tee")
+            )),
+        ),
+        (KRUN_CAP_EFI, cfg!(feature = "efi")),
+        (KRUN_CAP_TEE, cfg!(feature = "tee")),
+        (KRUN_CAP_AMD_SEV, cfg!(feature = "amd-sev")),
+        (
+            KRUN_CAP_OCI,
+            cfg!(all(feature = "oci", not(feature = "tee"))),
+        ),
+    ]
+    .into_iter()
+    .filter(|(_, available)| *available)
+    .fold(0, |caps, (cap, _)| caps | cap)
+}
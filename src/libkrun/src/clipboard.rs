@@ -0,0 +1,255 @@
+//! Clipboard channel between the host and an agent running in the guest.
+//!
+//! The guest agent connects to vsock port `CLIPBOARD_VSOCK_PORT`, which libkrun bridges to a
+//! private UNIX socket served by a host thread. Every message on the connection is made of an
+//! 8-byte header, holding the message type and the payload length as little endian `u32`s,
+//! followed by the payload:
+//!
+//! - `HELLO` (1): `version: u32`, `max_size: u32` and the supported mime types as a list of
+//!   NUL-terminated strings, in order of preference. Both sides send it right after connecting,
+//!   the host first. The channel then uses the smaller of both sizes as the limit for clipboard
+//!   contents, and only the mime types supported by both sides, in the host's order.
+//! - `OFFER` (2), guest to host: the clipboard of the guest changed. The payload lists the mime
+//!   types it is available in, as NUL-terminated strings. The host answers with a `REQUEST` for
+//!   the type it prefers, if any.
+//! - `REQUEST` (3): asks for the clipboard contents in the NUL-terminated mime type carried as
+//!   payload. Answered with `DATA` or `ERROR`.
+//! - `DATA` (4): a NUL-terminated mime type followed by the clipboard contents in that type.
+//! - `ERROR` (5): `errno: u32`, using Linux values. `E2BIG` is returned for messages over the
+//!   negotiated size, `EINVAL` for malformed messages or mime types that weren't negotiated and
+//!   `ENOENT` when there is no content to return.
+
+use std::ffi::{c_void, CStr, CString};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::thread;
+
+use libc::{c_char, size_t, ssize_t};
+
+/// vsock port the guest agent connects to.
+pub const CLIPBOARD_VSOCK_PORT: u32 = 0x4b43_0000;
+/// Version of the protocol implemented by the host.
+const CLIPBOARD_PROTOCOL_VERSION: u32 = 1;
+/// Size limit used when the caller doesn't set one.
+pub const CLIPBOARD_DEFAULT_MAX_SIZE: u32 = 16 << 20;
+/// Largest size limit a caller may set.
+pub const CLIPBOARD_MAX_SIZE: u32 = 64 << 20;
+/// Maximum length of a mime type, including its terminating NUL.
+pub const MAX_MIME_TYPE_LEN: u32 = 256;
+/// Maximum size of a `HELLO` payload.
+const MAX_HELLO_SIZE: u32 = 64 << 10;
+
+const MSG_HELLO: u32 = 1;
+const MSG_OFFER: u32 = 2;
+const MSG_REQUEST: u32 = 3;
+const MSG_DATA: u32 = 4;
+const MSG_ERROR: u32 = 5;
+
+/// Called when the guest pastes. Fills `buf` with the host clipboard contents in `mime_type` and
+/// returns their length, or a negative errno.
+pub type ClipboardGetFn = unsafe extern "C" fn(
+    opaque: *mut c_void,
+    mime_type: *const c_char,
+    buf: *mut u8,
+    buf_len: size_t,
+) -> ssize_t;
+
+/// Called when the guest copies, with the new clipboard contents in `mime_type`.
+pub type ClipboardSetFn = unsafe extern "C" fn(
+    opaque: *mut c_void,
+    mime_type: *const c_char,
+    data: *const u8,
+    len: size_t,
+);
+
+pub struct ClipboardConfig {
+    pub mime_types: Vec<CString>,
+    pub max_size: u32,
+    pub get: Option<ClipboardGetFn>,
+    pub set: Option<ClipboardSetFn>,
+    pub opaque: *mut c_void,
+}
+
+// Safe because the opaque pointer is only ever handed back to the caller's callbacks, which are
+// documented to be invoked from a libkrun thread.
+unsafe impl Send for ClipboardConfig {}
+
+enum Incoming {
+    Message(u32, Vec<u8>),
+    TooLarge,
+    Closed,
+}
+
+/// Listens on `socket_path` and serves the guest agent connections from a dedicated thread, one
+/// at a time.
+pub fn start(config: ClipboardConfig, socket_path: &Path) -> io::Result<()> {
+    let _ = fs::remove_file(socket_path);
+    let listener = UnixListener::bind(socket_path)?;
+
+    thread::Builder::new()
+        .name("clipboard".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = serve(&config, stream) {
+                            debug!("clipboard connection closed: {e}");
+                        }
+                    }
+                    Err(e) => error!("failed to accept clipboard connection: {e}"),
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+fn serve(config: &ClipboardConfig, mut stream: UnixStream) -> io::Result<()> {
+    let mut hello = Vec::new();
+    hello.extend_from_slice(&CLIPBOARD_PROTOCOL_VERSION.to_le_bytes());
+    hello.extend_from_slice(&config.max_size.to_le_bytes());
+    for mime_type in &config.mime_types {
+        hello.extend_from_slice(mime_type.as_bytes_with_nul());
+    }
+    write_message(&mut stream, MSG_HELLO, &hello)?;
+
+    let payload = match read_message(&mut stream, MAX_HELLO_SIZE)? {
+        Incoming::Message(MSG_HELLO, payload) if payload.len() >= 8 => payload,
+        _ => return Err(io::Error::from(io::ErrorKind::InvalidData)),
+    };
+    let version = u32::from_le_bytes(payload[0..4].try_into().unwrap());
+    if version != CLIPBOARD_PROTOCOL_VERSION {
+        return Err(io::Error::from(io::ErrorKind::Unsupported));
+    }
+    let max_size = config
+        .max_size
+        .min(u32::from_le_bytes(payload[4..8].try_into().unwrap()));
+    let guest_types: Vec<&[u8]> = mime_types(&payload[8..]).collect();
+    let negotiated: Vec<&CString> = config
+        .mime_types
+        .iter()
+        .filter(|mime_type| guest_types.contains(&mime_type.as_bytes()))
+        .collect();
+
+    loop {
+        let (kind, payload) = match read_message(&mut stream, max_size + MAX_MIME_TYPE_LEN)? {
+            Incoming::Message(kind, payload) => (kind, payload),
+            Incoming::TooLarge => {
+                write_error(&mut stream, libc::E2BIG)?;
+                continue;
+            }
+            Incoming::Closed => return Ok(()),
+        };
+
+        match kind {
+            MSG_OFFER => {
+                let offered: Vec<&[u8]> = mime_types(&payload).collect();
+                let preferred = negotiated
+                    .iter()
+                    .find(|mime_type| offered.contains(&mime_type.as_bytes()));
+                if let (Some(mime_type), Some(_)) = (preferred, config.set) {
+                    write_message(&mut stream, MSG_REQUEST, mime_type.as_bytes_with_nul())?;
+                }
+            }
+            MSG_REQUEST => {
+                let mime_type = match parse_mime_type(&payload, &negotiated) {
+                    Some((mime_type, _)) => mime_type,
+                    None => {
+                        write_error(&mut stream, libc::EINVAL)?;
+                        continue;
+                    }
+                };
+                let Some(get) = config.get else {
+                    write_error(&mut stream, libc::ENOENT)?;
+                    continue;
+                };
+
+                let mut data = mime_type.to_bytes_with_nul().to_vec();
+                let header_len = data.len();
+                data.resize(header_len + max_size as usize, 0);
+                let buf = &mut data[header_len..];
+                let ret = unsafe {
+                    get(
+                        config.opaque,
+                        mime_type.as_ptr(),
+                        buf.as_mut_ptr(),
+                        buf.len(),
+                    )
+                };
+
+                if ret < 0 {
+                    write_error(&mut stream, -ret as i32)?;
+                } else if ret as usize > buf.len() {
+                    write_error(&mut stream, libc::E2BIG)?;
+                } else {
+                    data.truncate(header_len + ret as usize);
+                    write_message(&mut stream, MSG_DATA, &data)?;
+                }
+            }
+            MSG_DATA => match (parse_mime_type(&payload, &negotiated), config.set) {
+                (Some((mime_type, data)), Some(set)) => unsafe {
+                    set(config.opaque, mime_type.as_ptr(), data.as_ptr(), data.len());
+                },
+                (None, _) => write_error(&mut stream, libc::EINVAL)?,
+                (Some(_), None) => {}
+            },
+            MSG_ERROR => debug!("clipboard guest agent returned an error: {payload:?}"),
+            _ => write_error(&mut stream, libc::EINVAL)?,
+        }
+    }
+}
+
+/// Iterates over a list of NUL-terminated mime types.
+fn mime_types(payload: &[u8]) -> impl Iterator<Item = &[u8]> {
+    payload
+        .split(|b| *b == 0)
+        .filter(|mime_type| !mime_type.is_empty())
+}
+
+/// Splits a payload starting with a NUL-terminated mime type, which must be one of `negotiated`.
+fn parse_mime_type<'a, 'b>(
+    payload: &'b [u8],
+    negotiated: &[&'a CString],
+) -> Option<(&'a CStr, &'b [u8])> {
+    let mime_type = CStr::from_bytes_until_nul(payload).ok()?;
+    let rest = &payload[mime_type.to_bytes_with_nul().len()..];
+    negotiated
+        .iter()
+        .find(|negotiated| negotiated.as_c_str() == mime_type)
+        .map(|negotiated| (negotiated.as_c_str(), rest))
+}
+
+fn read_message(stream: &mut UnixStream, max_len: u32) -> io::Result<Incoming> {
+    let mut header = [0u8; 8];
+    match stream.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(Incoming::Closed),
+        Err(e) => return Err(e),
+    }
+
+    let kind = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    let len = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    if len > max_len {
+        // Skip the payload so the stream stays in sync.
+        io::copy(&mut stream.take(len as u64), &mut io::sink())?;
+        return Ok(Incoming::TooLarge);
+    }
+
+    let mut payload = vec![0u8; len as usize];
+    stream.read_exact(&mut payload)?;
+    Ok(Incoming::Message(kind, payload))
+}
+
+fn write_message(stream: &mut UnixStream, kind: u32, payload: &[u8]) -> io::Result<()> {
+    let mut header = [0u8; 8];
+    header[0..4].copy_from_slice(&kind.to_le_bytes());
+    header[4..8].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+    stream.write_all(&header)?;
+    stream.write_all(payload)
+}
+
+fn write_error(stream: &mut UnixStream, errno: i32) -> io::Result<()> {
+    write_message(stream, MSG_ERROR, &(errno as u32).to_le_bytes())
+}
@@ -1,33 +1,65 @@
 #[macro_use]
 extern crate log;
 
+mod capabilities;
+mod clipboard;
+
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::env;
-use std::ffi::CStr;
-#[cfg(target_os = "linux")]
+#[cfg(any(target_os = "linux", not(feature = "tee")))]
 use std::ffi::CString;
+use std::ffi::{c_void, CStr};
 use std::fs::File;
+#[cfg(not(feature = "tee"))]
+use std::io;
 use std::net::Ipv4Addr;
 #[cfg(target_os = "linux")]
 use std::os::fd::AsRawFd;
-use std::os::fd::{FromRawFd, RawFd};
+use std::os::fd::{FromRawFd, IntoRawFd, OwnedFd, RawFd};
+#[cfg(not(feature = "tee"))]
+use std::os::unix::ffi::OsStrExt;
 use std::path::PathBuf;
 use std::slice;
 use std::sync::atomic::{AtomicI32, Ordering};
+#[cfg(not(feature = "tee"))]
+use std::sync::Arc;
 #[cfg(not(feature = "efi"))]
 use std::sync::LazyLock;
 use std::sync::Mutex;
+#[cfg(not(feature = "tee"))]
+use std::time::{Duration, UNIX_EPOCH};
 
+use clipboard::{
+    ClipboardConfig, ClipboardGetFn, ClipboardSetFn, CLIPBOARD_DEFAULT_MAX_SIZE,
+    CLIPBOARD_MAX_SIZE, CLIPBOARD_VSOCK_PORT, MAX_MIME_TYPE_LEN,
+};
 use crossbeam_channel::unbounded;
 #[cfg(feature = "blk")]
-use devices::virtio::block::ImageType;
-use devices::virtio::fs::FsImplShare;
+use devices::virtio::block::{BlockFormat, DiskSnapshot};
+#[cfg(not(feature = "tee"))]
+use devices::virtio::fs::overlayfs::UpperLayer;
+#[cfg(all(feature = "oci", not(feature = "tee")))]
+use devices::virtio::fs::OciImage;
+use devices::virtio::fs::{
+    FsAccessRules, FsAtime, FsBackgroundLimits, FsCredentials, FsDirTemplate, FsGuestMount, FsHook,
+    FsHookPoint, FsIdMap, FsIdRange, FsImplShare, FsInodeNumbers, FsLeases, FsModePolicy,
+    FsOwnership, FsPageCache, FsProtocol, FsSquashAll, FsVirtualAttr, FsVirtualFile, FsWatch,
+    FsWriteCoalescing,
+};
+#[cfg(not(feature = "tee"))]
+use devices::virtio::fs::{
+    FsCacheTimeouts, FsDegradation, FsDegradeCallback, FsDegradeOptions, FsHandleQuota, FsMirror,
+    FsPause, FsPauseOptions, FsPauseTimeout, FsRetryStats, FsTracer,
+};
 #[cfg(feature = "net")]
 use devices::virtio::net::device::VirtioNetBackend;
 #[cfg(feature = "blk")]
 use devices::virtio::CacheType;
+#[cfg(not(feature = "tee"))]
+use devices::virtio::{MemError, MemResizer, RngStats};
+use devices::virtio::{PortMap, PortMapError, PortMapping};
 use env_logger::{Env, Target};
 use ipnetwork::Ipv4Network;
 #[cfg(not(feature = "efi"))]
@@ -36,6 +68,7 @@ use libc::{c_char, c_int};
 use once_cell::sync::Lazy;
 use polly::event_manager::EventManager;
 use utils::eventfd::EventFd;
+use utils::fd_budget::FdBudget;
 use vmm::resources::VmResources;
 #[cfg(feature = "blk")]
 use vmm::vmm_config::block::BlockDeviceConfig;
@@ -69,6 +102,13 @@ const KRUNFW_NAME: &str = "libkrunfw.4.dylib";
 // Path to the init binary to be executed inside the VM.
 const INIT_PATH: &str = "/init.krun";
 
+// First vsock port used for the bridges created by krun_add_unix_socket_map.
+const UNIX_SOCKET_MAP_PORT_BASE: u32 = 0x4b52_0000;
+
+// Flags of krun_pause_virtiofs.
+const KRUN_FS_PAUSE_CLOSE_FILES: u32 = 1 << 0;
+const KRUN_FS_PAUSE_FAIL_ON_TIMEOUT: u32 = 1 << 1;
+
 #[cfg(not(feature = "efi"))]
 static KRUNFW: LazyLock<Option<libloading::Library>> =
     LazyLock::new(|| unsafe { libloading::Library::new(KRUNFW_NAME).ok() });
@@ -110,7 +150,7 @@ impl KrunfwBindings {
 
 #[derive(Default)]
 struct TsiConfig {
-    port_map: Option<HashMap<u16, u16>>,
+    port_map: Option<PortMap>,
     ip: Option<Ipv4Addr>,
     subnet: Option<Ipv4Network>,
     scope: u8,
@@ -150,6 +190,9 @@ struct ContextConfig {
     #[cfg(feature = "tee")]
     tee_config_file: Option<PathBuf>,
     unix_ipc_port_map: Option<HashMap<u32, (PathBuf, bool)>>,
+    vsock_listener_fds: HashMap<u32, OwnedFd>,
+    unix_socket_maps: Vec<String>,
+    clipboard: Option<ClipboardConfig>,
     shutdown_efd: Option<EventFd>,
     gpu_virgl_flags: Option<u32>,
     gpu_shm_size: Option<usize>,
@@ -220,6 +263,13 @@ impl ContextConfig {
         self.block_cfgs.push(block_cfg);
     }
 
+    #[cfg(feature = "blk")]
+    fn find_block_cfg(&mut self, block_id: &str) -> Option<&mut BlockDeviceConfig> {
+        self.block_cfgs
+            .iter_mut()
+            .find(|block_cfg| block_cfg.block_id == block_id)
+    }
+
     #[cfg(feature = "blk")]
     fn set_root_block_cfg(&mut self, block_cfg: BlockDeviceConfig) {
         self.root_block_cfg = Some(block_cfg);
@@ -255,7 +305,7 @@ impl ContextConfig {
         self.mac = Some(mac);
     }
 
-    fn set_port_map(&mut self, new_port_map: HashMap<u16, u16>) -> Result<(), ()> {
+    fn set_port_map(&mut self, new_port_map: PortMap) -> Result<(), ()> {
         match &mut self.net_cfg {
             NetworkConfig::Tsi(tsi_config) => {
                 tsi_config.port_map.replace(new_port_map);
@@ -286,6 +336,79 @@ impl ContextConfig {
         }
     }
 
+    fn add_vsock_listener_fd(&mut self, port: u32, fd: OwnedFd) {
+        self.vsock_listener_fds.insert(port, fd);
+    }
+
+    fn vsock_port_in_use(&self, port: u32) -> bool {
+        self.vsock_listener_fds.contains_key(&port)
+            || self
+                .unix_ipc_port_map
+                .as_ref()
+                .is_some_and(|map| map.contains_key(&port))
+    }
+
+    fn add_unix_socket_map(
+        &mut self,
+        host_path: PathBuf,
+        guest_path: &str,
+        listen: bool,
+    ) -> Result<(), i32> {
+        let port = (UNIX_SOCKET_MAP_PORT_BASE..)
+            .find(|port| !self.vsock_port_in_use(*port))
+            .ok_or(-libc::ENOSPC)?;
+
+        // When we listen on host_path, init connects the guest end to guest_path, which a guest
+        // service creates. Otherwise init listens on guest_path and we connect to host_path.
+        let mode = if listen { 'c' } else { 'l' };
+
+        self.add_vsock_port(port, host_path, listen);
+        self.unix_socket_maps
+            .push(format!("{mode}{port}:{guest_path}"));
+        Ok(())
+    }
+
+    fn get_unix_socket_maps(&self) -> String {
+        if self.unix_socket_maps.is_empty() {
+            "".to_string()
+        } else {
+            format!(
+                "KRUN_UNIX_SOCKET_MAP=\"{}\"",
+                self.unix_socket_maps.join(",")
+            )
+        }
+    }
+
+    #[cfg(not(feature = "tee"))]
+    fn get_virtiofs_mounts(&self) -> String {
+        let mounts: Vec<String> = self
+            .vmr
+            .fs
+            .iter()
+            .filter_map(|device| {
+                let mount = device.guest_mount.as_ref()?;
+                let dax = device.shm_size.is_some() && device.protocol == FsProtocol::Fuse;
+                Some(format!(
+                    "{}:{}:{}:{}",
+                    device.fs_id,
+                    device.protocol.guest_fs_type(),
+                    mount.path,
+                    mount.mount_options(device.protocol, dax)
+                ))
+            })
+            .collect();
+        if mounts.is_empty() {
+            "".to_string()
+        } else {
+            format!("KRUN_VIRTIOFS_MOUNTS=\"{}\"", mounts.join(";"))
+        }
+    }
+
+    #[cfg(feature = "tee")]
+    fn get_virtiofs_mounts(&self) -> String {
+        "".to_string()
+    }
+
     fn set_gpu_virgl_flags(&mut self, virgl_flags: u32) {
         self.gpu_virgl_flags = Some(virgl_flags);
     }
@@ -303,8 +426,32 @@ impl ContextConfig {
     }
 }
 
+/// The memory a running microVM booted with, and what resizes it.
+struct RunningVm {
+    ram_mib: usize,
+    #[cfg(not(feature = "tee"))]
+    mem_resizer: Option<MemResizer>,
+    #[cfg(not(feature = "tee"))]
+    rng_stats: Option<Arc<RngStats>>,
+    #[cfg(not(feature = "tee"))]
+    fs_handles: Vec<(String, FsHandleQuota)>,
+    #[cfg(not(feature = "tee"))]
+    fs_mirrors: Vec<(String, FsMirror)>,
+    #[cfg(not(feature = "tee"))]
+    fs_pauses: Vec<(String, FsPause)>,
+    #[cfg(not(feature = "tee"))]
+    fs_degradations: Vec<(String, FsDegradation)>,
+    #[cfg(not(feature = "tee"))]
+    fs_cache_timeouts: Vec<(String, FsCacheTimeouts)>,
+    #[cfg(not(feature = "tee"))]
+    fs_tracers: Vec<(String, FsTracer)>,
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    vmm: Arc<Mutex<vmm::Vmm>>,
+}
+
 static CTX_MAP: Lazy<Mutex<HashMap<u32, ContextConfig>>> = Lazy::new(|| Mutex::new(HashMap::new()));
 static CTX_IDS: AtomicI32 = AtomicI32::new(0);
+static RUNNING_VMS: Lazy<Mutex<HashMap<u32, RunningVm>>> = Lazy::new(|| Mutex::new(HashMap::new()));
 
 fn log_level_to_filter_str(level: u32) -> &'static str {
     match level {
@@ -374,6 +521,36 @@ pub unsafe extern "C" fn krun_init_log(target: RawFd, level: u32, style: u32, op
     KRUN_SUCCESS
 }
 
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_get_fd_usage(in_use: *mut size_t, limit: *mut size_t) -> i32 {
+    if in_use.is_null() || limit.is_null() {
+        return -libc::EINVAL;
+    }
+
+    let usage = FdBudget::global().usage();
+    *in_use = usage.in_use;
+    *limit = usage.limit;
+    KRUN_SUCCESS
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_fd_budget(limit: size_t) -> i32 {
+    FdBudget::global().set_limit(limit);
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_get_capabilities(caps: *mut u64) -> i32 {
+    if caps.is_null() {
+        return -libc::EINVAL;
+    }
+
+    *caps = capabilities::capabilities();
+    KRUN_SUCCESS
+}
+
 #[no_mangle]
 pub extern "C" fn krun_create_ctx() -> i32 {
     let ctx_cfg = {
@@ -438,6 +615,143 @@ pub extern "C" fn krun_set_vm_config(ctx_id: u32, num_vcpus: u8, ram_mib: u32) -
     KRUN_SUCCESS
 }
 
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub extern "C" fn krun_set_hotplug_memory(ctx_id: u32, max_mib: u32) -> i32 {
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().vmr.hotplug_mem_mib = (max_mib != 0).then_some(max_mib as usize);
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[no_mangle]
+pub extern "C" fn krun_resize_vm(ctx_id: u32, ram_mib: u32) -> i32 {
+    // A microVM that wasn't started yet simply boots with the new amount of memory
+    if let Some(ctx_cfg) = CTX_MAP.lock().unwrap().get_mut(&ctx_id) {
+        let vm_config = VmConfig {
+            vcpu_count: None,
+            mem_size_mib: Some(ram_mib as usize),
+            ht_enabled: None,
+            cpu_template: None,
+        };
+        if ctx_cfg.vmr.set_vm_config(&vm_config).is_err() {
+            return -libc::EINVAL;
+        }
+        return KRUN_SUCCESS;
+    }
+
+    let running_vms = RUNNING_VMS.lock().unwrap();
+    let Some(vm) = running_vms.get(&ctx_id) else {
+        return -libc::ENOENT;
+    };
+    let Some(hotplug_mib) = (ram_mib as usize).checked_sub(vm.ram_mib) else {
+        warn!(
+            "Cannot resize the microVM below the {} MiB it booted with",
+            vm.ram_mib
+        );
+        return -libc::EINVAL;
+    };
+
+    #[cfg(not(feature = "tee"))]
+    if let Some(mem_resizer) = &vm.mem_resizer {
+        return match mem_resizer.resize((hotplug_mib as u64) << 20) {
+            Ok(()) => KRUN_SUCCESS,
+            Err(MemError::InvalidSize(_)) => {
+                warn!(
+                    "Cannot resize the microVM to {ram_mib} MiB, at most {} MiB can be \
+                     hotplugged, in multiples of 2 MiB",
+                    mem_resizer.region_size() >> 20
+                );
+                -libc::EINVAL
+            }
+            Err(MemError::NotActivated) => {
+                warn!("The guest has no virtio-mem driver to hotplug memory");
+                -libc::ENOTSUP
+            }
+            Err(e) => {
+                error!("Failed to resize the microVM: {e:?}");
+                -libc::EIO
+            }
+        };
+    }
+
+    if hotplug_mib != 0 {
+        warn!("No memory can be hotplugged into the microVM");
+        return -libc::ENOTSUP;
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_snapshot_save(ctx_id: u32, c_path: *const c_char) -> i32 {
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return -libc::EINVAL,
+    };
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    {
+        let Some(vmm) = RUNNING_VMS
+            .lock()
+            .unwrap()
+            .get(&ctx_id)
+            .map(|vm| vm.vmm.clone())
+        else {
+            return -libc::ENOENT;
+        };
+
+        let result = vmm.lock().unwrap().snapshot_save(&path);
+        match result {
+            Ok(()) => KRUN_SUCCESS,
+            Err(e) => {
+                error!("Failed to snapshot the microVM: {e}");
+                match e {
+                    vmm::snapshot::Error::File(e) => -e.raw_os_error().unwrap_or(libc::EIO),
+                    vmm::snapshot::Error::Unsupported(_) => -libc::ENOTSUP,
+                    vmm::snapshot::Error::Device(e) => -e.raw_os_error().unwrap_or(libc::EBUSY),
+                    _ => -libc::EIO,
+                }
+            }
+        }
+    }
+
+    #[cfg(not(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee"))))]
+    {
+        let _ = (ctx_id, path);
+        -libc::ENOTSUP
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_snapshot_restore(ctx_id: u32, c_path: *const c_char) -> i32 {
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return -libc::EINVAL,
+    };
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            ctx_cfg.get_mut().vmr.snapshot_restore = Some(path);
+            KRUN_SUCCESS
+        }
+        Entry::Vacant(_) => -libc::ENOENT,
+    }
+
+    #[cfg(not(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee"))))]
+    {
+        let _ = (ctx_id, path);
+        -libc::ENOTSUP
+    }
+}
+
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
 #[cfg(not(feature = "tee"))]
@@ -466,6 +780,123 @@ pub unsafe extern "C" fn krun_set_root(ctx_id: u32, c_root_path: *const c_char)
                 fs_share,
                 // Default to a conservative 512 MB window.
                 shm_size: Some(1 << 29),
+                access_rules: None,
+                credentials: None,
+                durable: false,
+                allow_file_flags: false,
+                remove_tree: false,
+                batch_create: false,
+                dentry_warming: None,
+                max_handles: None,
+                atime: FsAtime::Host,
+                background_limits: None,
+                watches: Vec::new(),
+                mirror: None,
+                revalidate_interval: None,
+                write_coalescing: None,
+                virtual_files: Vec::new(),
+                protect_init_config: true,
+                leases: None,
+                inspect_socket: None,
+                dir_templates: Vec::new(),
+                protocol: FsProtocol::Fuse,
+                inode_numbers: FsInodeNumbers::Native,
+                mode_policy: None,
+                hooks: Vec::new(),
+                shared_lower_layers: false,
+                normalize_names: false,
+                page_cache: FsPageCache::Host,
+                guest_mount: None,
+                degrade: None,
+                ownership: FsOwnership::Auto,
+            });
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_overlayfs_root(
+    ctx_id: u32,
+    c_root_layers: *const *const c_char,
+) -> i32 {
+    let mut layers = Vec::new();
+    let layers_array: &[*const c_char] = slice::from_raw_parts(c_root_layers, MAX_ARGS);
+
+    for item in layers_array.iter().take(MAX_ARGS) {
+        if item.is_null() {
+            break;
+        } else {
+            let layer_path = match CStr::from_ptr(*item).to_str() {
+                Ok(path) => path,
+                Err(_) => return -libc::EINVAL,
+            };
+            layers.push(PathBuf::from(layer_path));
+        }
+    }
+
+    // Need at least one layer
+    if layers.is_empty() {
+        return -libc::EINVAL;
+    }
+
+    set_root_share(ctx_id, FsImplShare::Overlayfs(layers, UpperLayer::Disk))
+}
+
+/// Sets the overlay `fs_share` as the root of the microVM.
+#[cfg(not(feature = "tee"))]
+fn set_root_share(ctx_id: u32, fs_share: FsImplShare) -> i32 {
+    let fs_id = "/dev/root".to_string();
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+
+            // Check if root device is already set
+            for device in &cfg.vmr.fs {
+                if device.fs_id == fs_id {
+                    return -libc::EEXIST;
+                }
+            }
+
+            cfg.vmr.add_fs_device(FsDeviceConfig {
+                fs_id,
+                fs_share,
+                // Default to a conservative 512 MB window.
+                shm_size: Some(1 << 29),
+                access_rules: None,
+                credentials: None,
+                durable: false,
+                allow_file_flags: false,
+                remove_tree: false,
+                batch_create: false,
+                dentry_warming: None,
+                max_handles: None,
+                atime: FsAtime::Host,
+                background_limits: None,
+                watches: Vec::new(),
+                mirror: None,
+                revalidate_interval: None,
+                write_coalescing: None,
+                virtual_files: Vec::new(),
+                protect_init_config: true,
+                leases: None,
+                inspect_socket: None,
+                dir_templates: Vec::new(),
+                protocol: FsProtocol::Fuse,
+                inode_numbers: FsInodeNumbers::Native,
+                mode_policy: None,
+                hooks: Vec::new(),
+                shared_lower_layers: false,
+                normalize_names: false,
+                page_cache: FsPageCache::Host,
+                guest_mount: None,
+                degrade: None,
+                ownership: FsOwnership::Auto,
             });
         }
         Entry::Vacant(_) => return -libc::ENOENT,
@@ -474,53 +905,1570 @@ pub unsafe extern "C" fn krun_set_root(ctx_id: u32, c_root_path: *const c_char)
     KRUN_SUCCESS
 }
 
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(all(feature = "oci", not(feature = "tee")))]
+pub unsafe extern "C" fn krun_set_root_oci(ctx_id: u32, c_image_path: *const c_char) -> i32 {
+    set_root_oci(ctx_id, c_image_path, false)
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(all(feature = "oci", not(feature = "tee")))]
+pub unsafe extern "C" fn krun_set_root_oci_lazy(ctx_id: u32, c_image_path: *const c_char) -> i32 {
+    set_root_oci(ctx_id, c_image_path, true)
+}
+
+/// Sets the root to an overlay of the layers of the OCI image at `c_image_path`, unpacked now or,
+/// if `lazy`, read from their blobs as the guest uses them.
+#[cfg(all(feature = "oci", not(feature = "tee")))]
+unsafe fn set_root_oci(ctx_id: u32, c_image_path: *const c_char, lazy: bool) -> i32 {
+    let image_path = match CStr::from_ptr(c_image_path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return -libc::EINVAL,
+    };
+
+    // Fail early rather than after unpacking the image
+    match CTX_MAP.lock().unwrap().get(&ctx_id) {
+        Some(cfg) if cfg.vmr.fs.iter().any(|device| device.fs_id == "/dev/root") => {
+            return -libc::EEXIST
+        }
+        Some(_) => (),
+        None => return -libc::ENOENT,
+    }
+
+    let share = oci_cache_dir().and_then(|cache| {
+        let image = OciImage::open(&image_path)?;
+        let top_layer = oci_top_layer(&cache)?;
+        if lazy {
+            image.lazy_share(&cache, top_layer)
+        } else {
+            image.share(&cache, top_layer)
+        }
+    });
+    match share {
+        Ok(fs_share) => set_root_share(ctx_id, fs_share),
+        Err(e) => {
+            error!(
+                "Failed to set up the root from {}: {e}",
+                image_path.display()
+            );
+            match e.kind() {
+                io::ErrorKind::Unsupported => -libc::ENOTSUP,
+                _ => -e.raw_os_error().unwrap_or(libc::EINVAL),
+            }
+        }
+    }
+}
+
+/// Returns the directory the layers of the OCI images are unpacked into.
+#[cfg(all(feature = "oci", not(feature = "tee")))]
+fn oci_cache_dir() -> io::Result<PathBuf> {
+    let cache = match (env::var_os("XDG_CACHE_HOME"), env::var_os("HOME")) {
+        (Some(dir), _) if !dir.is_empty() => PathBuf::from(dir),
+        (_, Some(home)) => PathBuf::from(home).join(".cache"),
+        _ => return Err(io::Error::from_raw_os_error(libc::ENOENT)),
+    };
+    Ok(cache.join("libkrun/oci"))
+}
+
+/// Creates a new writable top layer for an overlay of the layers of an OCI image.
+#[cfg(all(feature = "oci", not(feature = "tee")))]
+fn oci_top_layer(cache: &std::path::Path) -> io::Result<PathBuf> {
+    let containers = cache.join("containers");
+    std::fs::create_dir_all(&containers)?;
+    let template = CString::new(containers.join("XXXXXX").as_os_str().as_bytes())?;
+    let template = template.into_raw();
+    // Safe because the template is a valid nul-terminated string we own, and we check the return
+    // value.
+    let res = unsafe { libc::mkdtemp(template) };
+    // Safe because the template comes from CString::into_raw.
+    let template = unsafe { CString::from_raw(template) };
+    if res.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PathBuf::from(std::ffi::OsStr::from_bytes(
+        template.as_bytes(),
+    )))
+}
+
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub extern "C" fn krun_set_overlayfs_ephemeral(ctx_id: u32, size_limit: u64) -> i32 {
+    if size_limit == 0 {
+        return -libc::EINVAL;
+    }
+
+    // The overlay of macOS only writes to a top layer on disk
+    if cfg!(target_os = "macos") {
+        return -libc::ENOTSUP;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            let upper_layer = cfg
+                .vmr
+                .fs
+                .iter_mut()
+                .filter(|device| device.fs_id == "/dev/root")
+                .find_map(|device| match &mut device.fs_share {
+                    FsImplShare::Overlayfs(_, upper_layer) => Some(upper_layer),
+                    #[cfg(feature = "oci")]
+                    FsImplShare::LazyOverlayfs(_, _, upper_layer) => Some(upper_layer),
+                    FsImplShare::Passthrough(_) => None,
+                });
+
+            match upper_layer {
+                Some(upper_layer) => *upper_layer = UpperLayer::Ram { size_limit },
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_add_virtiofs(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    c_path: *const c_char,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => path,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+
+            // Check if a device with the same tag already exists
+            let fs_id = tag.to_string();
+            for device in &cfg.vmr.fs {
+                if device.fs_id == fs_id {
+                    return -libc::EEXIST;
+                }
+            }
+
+            cfg.vmr.add_fs_device(FsDeviceConfig {
+                fs_id,
+                fs_share: FsImplShare::Passthrough(path.to_string()),
+                shm_size: None,
+                access_rules: None,
+                credentials: None,
+                durable: false,
+                allow_file_flags: false,
+                remove_tree: false,
+                batch_create: false,
+                dentry_warming: None,
+                max_handles: None,
+                atime: FsAtime::Host,
+                background_limits: None,
+                watches: Vec::new(),
+                mirror: None,
+                revalidate_interval: None,
+                write_coalescing: None,
+                virtual_files: Vec::new(),
+                protect_init_config: false,
+                leases: None,
+                inspect_socket: None,
+                dir_templates: Vec::new(),
+                protocol: FsProtocol::Fuse,
+                inode_numbers: FsInodeNumbers::Native,
+                mode_policy: None,
+                hooks: Vec::new(),
+                shared_lower_layers: false,
+                normalize_names: false,
+                page_cache: FsPageCache::Host,
+                guest_mount: None,
+                degrade: None,
+                ownership: FsOwnership::Auto,
+            });
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_add_virtiofs2(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    c_path: *const c_char,
+    shm_size: u64,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => path,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+
+            // Check if a device with the same tag already exists
+            let fs_id = tag.to_string();
+            for device in &cfg.vmr.fs {
+                if device.fs_id == fs_id {
+                    return -libc::EEXIST;
+                }
+            }
+
+            cfg.vmr.add_fs_device(FsDeviceConfig {
+                fs_id,
+                fs_share: FsImplShare::Passthrough(path.to_string()),
+                shm_size: Some(shm_size.try_into().unwrap()),
+                access_rules: None,
+                credentials: None,
+                durable: false,
+                allow_file_flags: false,
+                remove_tree: false,
+                batch_create: false,
+                dentry_warming: None,
+                max_handles: None,
+                atime: FsAtime::Host,
+                background_limits: None,
+                watches: Vec::new(),
+                mirror: None,
+                revalidate_interval: None,
+                write_coalescing: None,
+                virtual_files: Vec::new(),
+                protect_init_config: false,
+                leases: None,
+                inspect_socket: None,
+                dir_templates: Vec::new(),
+                protocol: FsProtocol::Fuse,
+                inode_numbers: FsInodeNumbers::Native,
+                mode_policy: None,
+                hooks: Vec::new(),
+                shared_lower_layers: false,
+                normalize_names: false,
+                page_cache: FsPageCache::Host,
+                guest_mount: None,
+                degrade: None,
+                ownership: FsOwnership::Auto,
+            });
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_access(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    uids: *const u32,
+    num_uids: usize,
+    gids: *const u32,
+    num_gids: usize,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+    if (uids.is_null() && num_uids != 0) || (gids.is_null() && num_gids != 0) {
+        return -libc::EINVAL;
+    }
+
+    let access_rules = FsAccessRules {
+        uids: match num_uids {
+            0 => Vec::new(),
+            _ => slice::from_raw_parts(uids, num_uids).to_vec(),
+        },
+        gids: match num_gids {
+            0 => Vec::new(),
+            _ => slice::from_raw_parts(gids, num_gids).to_vec(),
+        },
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => device.access_rules = Some(access_rules),
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[cfg(not(feature = "tee"))]
+unsafe fn set_virtiofs_credentials(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    credentials: FsCredentials,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => device.credentials = Some(credentials),
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_squash(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    uid: u32,
+    gid: u32,
+) -> i32 {
+    set_virtiofs_credentials(ctx_id, c_tag, Arc::new(FsSquashAll { uid, gid }))
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_idmap(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    uid_map: *const u32,
+    num_uid_ranges: usize,
+    gid_map: *const u32,
+    num_gid_ranges: usize,
+) -> i32 {
+    if (uid_map.is_null() && num_uid_ranges != 0) || (gid_map.is_null() && num_gid_ranges != 0) {
+        return -libc::EINVAL;
+    }
+
+    let ranges = |map: *const u32, num_ranges: usize| match num_ranges {
+        0 => Vec::new(),
+        _ => slice::from_raw_parts(map, num_ranges * 3)
+            .chunks_exact(3)
+            .map(|range| FsIdRange {
+                guest_start: range[0],
+                host_start: range[1],
+                count: range[2],
+            })
+            .collect(),
+    };
+    let id_map = FsIdMap {
+        uids: ranges(uid_map, num_uid_ranges),
+        gids: ranges(gid_map, num_gid_ranges),
+    };
+
+    set_virtiofs_credentials(ctx_id, c_tag, Arc::new(id_map))
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_durable(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    durable: bool,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => device.durable = durable,
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_file_flags(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    allow: bool,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => device.allow_file_flags = allow,
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_remove_tree(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    allow: bool,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => device.remove_tree = allow,
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_batch_create(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    allow: bool,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => device.batch_create = allow,
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_dentry_warming(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    max_entries: u32,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => {
+                    device.dentry_warming = (max_entries != 0).then_some(max_entries as usize)
+                }
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_max_handles(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    max_handles: u32,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => {
+                    device.max_handles = (max_handles != 0).then_some(max_handles as usize)
+                }
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_atime(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    atime: u32,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let atime = match atime {
+        0 => FsAtime::Host,
+        1 => FsAtime::Relatime,
+        2 => FsAtime::Noatime,
+        _ => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => device.atime = atime,
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_normalize_names(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    enable: bool,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => device.normalize_names = enable,
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_page_cache(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    policy: u32,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let page_cache = match policy {
+        0 => FsPageCache::Host,
+        1 => FsPageCache::Streaming,
+        2 => FsPageCache::NoCache,
+        _ => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => device.page_cache = page_cache,
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_ownership(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    ownership: u32,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let ownership = match ownership {
+        0 => FsOwnership::Auto,
+        1 => FsOwnership::Host,
+        2 => FsOwnership::Virtual,
+        _ => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => device.ownership = ownership,
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_protocol(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    protocol: u32,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let protocol = match protocol {
+        0 => FsProtocol::Fuse,
+        1 => FsProtocol::P9,
+        _ => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => {
+                    device.protocol = protocol;
+                    // 9p has no DAX window
+                    if protocol == FsProtocol::P9 {
+                        device.shm_size = None;
+                    }
+                }
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_inode_width(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    width: u32,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let inode_numbers = match width {
+        64 => FsInodeNumbers::Native,
+        32 => FsInodeNumbers::Folded32,
+        _ => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => device.inode_numbers = inode_numbers,
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_mode_policy(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    umask: u32,
+    file_mode: i32,
+    dir_mode: i32,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let mode = |mode: i32| match mode {
+        0..=0o7777 => Ok(Some(mode as u32)),
+        -1 => Ok(None),
+        _ => Err(-libc::EINVAL),
+    };
+    let policy = match (mode(file_mode), mode(dir_mode)) {
+        (Ok(file_mode), Ok(dir_mode)) if umask <= 0o7777 => FsModePolicy {
+            umask,
+            file_mode,
+            dir_mode,
+        },
+        _ => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => device.mode_policy = Some(policy),
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_guest_mount(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    c_guest_path: *const c_char,
+    c_options: *const c_char,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+    let path = match CStr::from_ptr(c_guest_path).to_str() {
+        Ok(path) => path,
+        Err(_) => return -libc::EINVAL,
+    };
+    let options = if c_options.is_null() {
+        ""
+    } else {
+        match CStr::from_ptr(c_options).to_str() {
+            Ok(options) => options,
+            Err(_) => return -libc::EINVAL,
+        }
+    };
+
+    // The mounts reach the init on the kernel command line, as ':'- and ';'-separated lists
+    let valid = |s: &str| {
+        s.len() <= FsGuestMount::MAX_LEN
+            && !s
+                .chars()
+                .any(|c| c.is_whitespace() || matches!(c, ':' | ';' | '"'))
+    };
+    if tag == "/dev/root"
+        || !valid(tag)
+        || !path.starts_with('/')
+        || !valid(path)
+        || !valid(options)
+    {
+        return -libc::EINVAL;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => {
+                    device.guest_mount = Some(FsGuestMount {
+                        path: path.to_string(),
+                        options: options.to_string(),
+                    })
+                }
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_get_virtiofs_handles(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    open: *mut u64,
+    denied: *mut u64,
+) -> i32 {
+    if open.is_null() || denied.is_null() {
+        return -libc::EINVAL;
+    }
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let running_vms = RUNNING_VMS.lock().unwrap();
+    let Some(vm) = running_vms.get(&ctx_id) else {
+        return -libc::ENOENT;
+    };
+    let Some((_, quota)) = vm.fs_handles.iter().find(|(fs_id, _)| fs_id == tag) else {
+        return -libc::ENODEV;
+    };
+
+    *open = quota.open() as u64;
+    *denied = quota.denied();
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_get_virtiofs_retries(
+    interrupted: *mut u64,
+    retries: *mut u64,
+    recovered: *mut u64,
+    exhausted: *mut u64,
+) -> i32 {
+    if interrupted.is_null() || retries.is_null() || recovered.is_null() || exhausted.is_null() {
+        return -libc::EINVAL;
+    }
+
+    let stats = FsRetryStats::global();
+    *interrupted = stats.interrupted();
+    *retries = stats.retries();
+    *recovered = stats.recovered();
+    *exhausted = stats.exhausted();
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_drain_virtiofs_mirror(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    timeout_ms: u32,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    // The lock mustn't be held while waiting for the mirror
+    let mirror = {
+        let running_vms = RUNNING_VMS.lock().unwrap();
+        let Some(vm) = running_vms.get(&ctx_id) else {
+            return -libc::ENOENT;
+        };
+        let Some((_, mirror)) = vm.fs_mirrors.iter().find(|(fs_id, _)| fs_id == tag) else {
+            return -libc::ENODEV;
+        };
+        mirror.clone()
+    };
+
+    if !mirror.drain(Duration::from_millis(timeout_ms as u64)) {
+        return -libc::ETIMEDOUT;
+    }
+    KRUN_SUCCESS
+}
+
+/// Returns the handle pausing the virtio-fs device with `tag` of the running microVM `ctx_id`.
+#[cfg(not(feature = "tee"))]
+fn running_fs_pause(ctx_id: u32, tag: &str) -> Result<FsPause, i32> {
+    let running_vms = RUNNING_VMS.lock().unwrap();
+    let Some(vm) = running_vms.get(&ctx_id) else {
+        return Err(-libc::ENOENT);
+    };
+    match vm.fs_pauses.iter().find(|(fs_id, _)| fs_id == tag) {
+        Some((_, pause)) => Ok(pause.clone()),
+        None => Err(-libc::ENODEV),
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_pause_virtiofs(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    flags: u32,
+    timeout_ms: u32,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+    if flags & !(KRUN_FS_PAUSE_CLOSE_FILES | KRUN_FS_PAUSE_FAIL_ON_TIMEOUT) != 0 {
+        return -libc::EINVAL;
+    }
+
+    // The lock mustn't be held while waiting for the device
+    let pause = match running_fs_pause(ctx_id, tag) {
+        Ok(pause) => pause,
+        Err(e) => return e,
+    };
+    let options = FsPauseOptions {
+        close_files: flags & KRUN_FS_PAUSE_CLOSE_FILES != 0,
+        timeout: (timeout_ms != 0).then(|| Duration::from_millis(timeout_ms as u64)),
+        on_timeout: if flags & KRUN_FS_PAUSE_FAIL_ON_TIMEOUT != 0 {
+            FsPauseTimeout::Fail
+        } else {
+            FsPauseTimeout::Resume
+        },
+    };
+
+    match pause.pause(options) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => -e.raw_os_error().unwrap_or(libc::EBUSY),
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_resume_virtiofs(ctx_id: u32, c_tag: *const c_char) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let pause = match running_fs_pause(ctx_id, tag) {
+        Ok(pause) => pause,
+        Err(e) => return e,
+    };
+    match pause.resume() {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => -e.raw_os_error().unwrap_or(libc::EIO),
+    }
+}
+
+/// Called with the number of failed writes that degraded a virtio-fs share to read-only.
+#[cfg(not(feature = "tee"))]
+pub type FsDegradeFn = unsafe extern "C" fn(opaque: *mut c_void, errors: u32);
+
+#[cfg(not(feature = "tee"))]
+struct FsDegradeOpaque(*mut c_void);
+
+// Safe because the opaque pointer is only ever handed back to the caller's callback, which is
+// documented to be invoked from a libkrun thread.
+#[cfg(not(feature = "tee"))]
+unsafe impl Send for FsDegradeOpaque {}
+#[cfg(not(feature = "tee"))]
+unsafe impl Sync for FsDegradeOpaque {}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_degrade(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    max_errors: u32,
+    window_ms: u32,
+    callback: Option<FsDegradeFn>,
+    opaque: *mut c_void,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+    if max_errors == 0 || window_ms == 0 {
+        return -libc::EINVAL;
+    }
+
+    let opaque = FsDegradeOpaque(opaque);
+    let options = FsDegradeOptions {
+        max_errors,
+        window: Duration::from_millis(window_ms as u64),
+        callback: callback.map(|callback| -> FsDegradeCallback {
+            Arc::new(move |errors| {
+                // Capture the whole wrapper rather than only its pointer field
+                let FsDegradeOpaque(opaque) = &opaque;
+                callback(*opaque, errors);
+            })
+        }),
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => device.degrade = Some(options),
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_reenable_virtiofs(ctx_id: u32, c_tag: *const c_char) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let running_vms = RUNNING_VMS.lock().unwrap();
+    let Some(vm) = running_vms.get(&ctx_id) else {
+        return -libc::ENOENT;
+    };
+    match vm.fs_degradations.iter().find(|(fs_id, _)| fs_id == tag) {
+        Some((_, degradation)) => degradation.reenable() as i32,
+        None => -libc::ENODEV,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_timeouts(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    entry_timeout_ms: u32,
+    attr_timeout_ms: u32,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let running_vms = RUNNING_VMS.lock().unwrap();
+    let Some(vm) = running_vms.get(&ctx_id) else {
+        return -libc::ENOENT;
+    };
+    match vm.fs_cache_timeouts.iter().find(|(fs_id, _)| fs_id == tag) {
+        Some((_, timeouts)) => {
+            timeouts.set(
+                Duration::from_millis(entry_timeout_ms as u64),
+                Duration::from_millis(attr_timeout_ms as u64),
+            );
+            KRUN_SUCCESS
+        }
+        None => -libc::ENODEV,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_tracing(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    enable: bool,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let running_vms = RUNNING_VMS.lock().unwrap();
+    let Some(vm) = running_vms.get(&ctx_id) else {
+        return -libc::ENOENT;
+    };
+    match vm.fs_tracers.iter().find(|(fs_id, _)| fs_id == tag) {
+        Some((_, tracer)) if enable => {
+            tracer.enable();
+            KRUN_SUCCESS
+        }
+        Some((_, tracer)) => {
+            tracer.disable();
+            KRUN_SUCCESS
+        }
+        None => -libc::ENODEV,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_export_virtiofs_trace(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    c_path: *const c_char,
+    clear: bool,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => path,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    // The trace is taken with the lock held, but written without it
+    let json = {
+        let running_vms = RUNNING_VMS.lock().unwrap();
+        let Some(vm) = running_vms.get(&ctx_id) else {
+            return -libc::ENOENT;
+        };
+        let Some((_, tracer)) = vm.fs_tracers.iter().find(|(fs_id, _)| fs_id == tag) else {
+            return -libc::ENODEV;
+        };
+        let json = tracer.chrome_trace();
+        if clear {
+            tracer.clear();
+        }
+        json
+    };
+
+    match std::fs::write(path, json) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => -e.raw_os_error().unwrap_or(libc::EIO),
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_background(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    max_background: u16,
+    congestion_threshold: u16,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+    if max_background == 0 || congestion_threshold > max_background {
+        return -libc::EINVAL;
+    }
+
+    let background_limits = FsBackgroundLimits {
+        max_background,
+        congestion_threshold,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => device.background_limits = Some(background_limits),
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_revalidate(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    interval_ms: u32,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+    let revalidate_interval = match interval_ms {
+        0 => None,
+        ms => Some(Duration::from_millis(ms.into())),
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => device.revalidate_interval = revalidate_interval,
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_write_coalescing(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    buffer_size: u32,
+    delay_ms: u32,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+    let write_coalescing = match (buffer_size, delay_ms) {
+        (0, _) => None,
+        (buffer_size, _) if buffer_size > 1 << 20 => return -libc::EINVAL,
+        (_, 0) => return -libc::EINVAL,
+        (buffer_size, delay_ms) => Some(FsWriteCoalescing {
+            buffer_size,
+            delay: Duration::from_millis(delay_ms.into()),
+        }),
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => device.write_coalescing = write_coalescing,
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_leases(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    timeout_ms: u32,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+    let leases = match timeout_ms {
+        0 => None,
+        ms => Some(FsLeases {
+            timeout: Duration::from_millis(ms.into()),
+        }),
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => device.leases = leases,
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_inspect_socket(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    c_socket_path: *const c_char,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+    let socket_path = match CStr::from_ptr(c_socket_path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => device.inspect_socket = Some(socket_path),
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_add_virtiofs_dir_template(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    c_path: *const c_char,
+    c_template: *const c_char,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return -libc::EINVAL,
+    };
+    let template = match CStr::from_ptr(c_template).to_str() {
+        Ok(template) => PathBuf::from(template),
+        Err(_) => return -libc::EINVAL,
+    };
+    if path.file_name().is_none() || !template.is_dir() {
+        return -libc::EINVAL;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => device.dir_templates.push(FsDirTemplate { path, template }),
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+/// Called with each change the guest makes under a watched path of a virtio-fs share.
+#[cfg(not(feature = "tee"))]
+pub type FsWatchFn = unsafe extern "C" fn(opaque: *mut c_void, path: *const c_char, op: u32);
+
+#[cfg(not(feature = "tee"))]
+struct FsWatchOpaque(*mut c_void);
+
+// Safe because the opaque pointer is only ever handed back to the caller's callback, which is
+// documented to be invoked from a libkrun thread.
+#[cfg(not(feature = "tee"))]
+unsafe impl Send for FsWatchOpaque {}
+#[cfg(not(feature = "tee"))]
+unsafe impl Sync for FsWatchOpaque {}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_watch_guest_path(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    c_path: *const c_char,
+    callback: Option<FsWatchFn>,
+    opaque: *mut c_void,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return -libc::EINVAL,
+    };
+    let Some(callback) = callback else {
+        return -libc::EINVAL;
+    };
+
+    let opaque = FsWatchOpaque(opaque);
+    let watch = FsWatch {
+        path,
+        callback: Arc::new(move |event| {
+            // Capture the whole wrapper rather than only its pointer field
+            let FsWatchOpaque(opaque) = &opaque;
+            if let Ok(path) = CString::new(event.path.as_os_str().as_bytes()) {
+                callback(*opaque, path.as_ptr(), event.op as u32);
+            }
+        }),
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => device.watches.push(watch),
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_set_virtiofs_mirror(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    c_mirror_path: *const c_char,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+    let mirror_path = match CStr::from_ptr(c_mirror_path).to_str() {
+        Ok(path) if !path.is_empty() => PathBuf::from(path),
+        _ => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => device.mirror = Some(mirror_path),
+                None => return -libc::ENOENT,
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+/// Called for the size and modification time of a virtual file of a virtio-fs share.
+#[cfg(not(feature = "tee"))]
+pub type FsVirtualFileGetattrFn =
+    unsafe extern "C" fn(opaque: *mut c_void, size: *mut u64, mtime: *mut i64) -> i32;
+
+/// Called to read the content of a virtual file of a virtio-fs share.
+#[cfg(not(feature = "tee"))]
+pub type FsVirtualFileReadFn =
+    unsafe extern "C" fn(opaque: *mut c_void, offset: u64, buf: *mut c_void, len: usize) -> i64;
+
+#[cfg(not(feature = "tee"))]
+struct FsVirtualFileOpaque(*mut c_void);
+
+// Safe because the opaque pointer is only ever handed back to the caller's callbacks, which are
+// documented to be invoked from a libkrun thread.
+#[cfg(not(feature = "tee"))]
+unsafe impl Send for FsVirtualFileOpaque {}
+#[cfg(not(feature = "tee"))]
+unsafe impl Sync for FsVirtualFileOpaque {}
+
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
 #[cfg(not(feature = "tee"))]
-pub unsafe extern "C" fn krun_set_overlayfs_root(
+pub unsafe extern "C" fn krun_add_virtiofs_virtual_file(
     ctx_id: u32,
-    c_root_layers: *const *const c_char,
+    c_tag: *const c_char,
+    c_path: *const c_char,
+    mode: u32,
+    getattr: Option<FsVirtualFileGetattrFn>,
+    read: Option<FsVirtualFileReadFn>,
+    opaque: *mut c_void,
 ) -> i32 {
-    let mut layers = Vec::new();
-    let layers_array: &[*const c_char] = slice::from_raw_parts(c_root_layers, MAX_ARGS);
-
-    for item in layers_array.iter().take(MAX_ARGS) {
-        if item.is_null() {
-            break;
-        } else {
-            let layer_path = match CStr::from_ptr(*item).to_str() {
-                Ok(path) => path,
-                Err(_) => return -libc::EINVAL,
-            };
-            layers.push(PathBuf::from(layer_path));
-        }
-    }
-
-    // Need at least one layer
-    if layers.is_empty() {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return -libc::EINVAL,
+    };
+    let (Some(getattr), Some(read)) = (getattr, read) else {
+        return -libc::EINVAL;
+    };
+    if path.file_name().is_none() {
         return -libc::EINVAL;
     }
 
-    let fs_id = "/dev/root".to_string();
-    let fs_share = FsImplShare::Overlayfs(layers);
+    let opaque = Arc::new(FsVirtualFileOpaque(opaque));
+    let getattr_opaque = opaque.clone();
+    let file = FsVirtualFile {
+        path,
+        mode,
+        getattr: Arc::new(move || {
+            let FsVirtualFileOpaque(opaque) = *getattr_opaque;
+            let (mut size, mut mtime) = (0, 0);
+            let ret = getattr(opaque, &mut size, &mut mtime);
+            if ret < 0 {
+                return Err(io::Error::from_raw_os_error(-ret));
+            }
+            Ok(FsVirtualAttr {
+                size,
+                mtime: UNIX_EPOCH + Duration::from_secs(mtime.max(0) as u64),
+            })
+        }),
+        read: Arc::new(move |offset, buf| {
+            let FsVirtualFileOpaque(opaque) = *opaque;
+            let ret = read(opaque, offset, buf.as_mut_ptr() as *mut c_void, buf.len());
+            if ret < 0 {
+                return Err(io::Error::from_raw_os_error(-ret as i32));
+            }
+            Ok(ret as usize)
+        }),
+    };
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-
-            // Check if root device is already set
-            for device in &cfg.vmr.fs {
-                if device.fs_id == fs_id {
-                    return -libc::EEXIST;
-                }
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => device.virtual_files.push(file),
+                None => return -libc::ENOENT,
             }
-
-            cfg.vmr.add_fs_device(FsDeviceConfig {
-                fs_id,
-                fs_share,
-                // Default to a conservative 512 MB window.
-                shm_size: Some(1 << 29),
-            });
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -528,40 +2476,58 @@ pub unsafe extern "C" fn krun_set_overlayfs_root(
     KRUN_SUCCESS
 }
 
+/// Called at the point of the life of a virtio-fs share a hook is registered for.
+#[cfg(not(feature = "tee"))]
+pub type FsHookCallbackFn =
+    unsafe extern "C" fn(opaque: *mut c_void, point: u32, path: *const c_char) -> i32;
+
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
 #[cfg(not(feature = "tee"))]
-pub unsafe extern "C" fn krun_add_virtiofs(
+pub unsafe extern "C" fn krun_add_virtiofs_hook(
     ctx_id: u32,
     c_tag: *const c_char,
-    c_path: *const c_char,
+    point: u32,
+    callback: Option<FsHookCallbackFn>,
+    opaque: *mut c_void,
 ) -> i32 {
     let tag = match CStr::from_ptr(c_tag).to_str() {
         Ok(tag) => tag,
         Err(_) => return -libc::EINVAL,
     };
-    let path = match CStr::from_ptr(c_path).to_str() {
-        Ok(path) => path,
-        Err(_) => return -libc::EINVAL,
+    let Some(callback) = callback else {
+        return -libc::EINVAL;
+    };
+    let hook_point = match point {
+        1 => FsHookPoint::PreMount,
+        2 => FsHookPoint::PostUnmount,
+        3 => FsHookPoint::PreCopyUp,
+        4 => FsHookPoint::PreUnlink,
+        _ => return -libc::EINVAL,
+    };
+
+    let opaque = Arc::new(FsVirtualFileOpaque(opaque));
+    let hook = FsHook {
+        point: hook_point,
+        callback: Arc::new(move |event| {
+            let FsVirtualFileOpaque(opaque) = *opaque;
+            let path = CString::new(event.path.as_os_str().as_bytes())
+                .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+            let ret = callback(opaque, point, path.as_ptr());
+            if ret < 0 {
+                return Err(io::Error::from_raw_os_error(-ret));
+            }
+            Ok(())
+        }),
     };
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-
-            // Check if a device with the same tag already exists
-            let fs_id = tag.to_string();
-            for device in &cfg.vmr.fs {
-                if device.fs_id == fs_id {
-                    return -libc::EEXIST;
-                }
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => device.hooks.push(hook),
+                None => return -libc::ENOENT,
             }
-
-            cfg.vmr.add_fs_device(FsDeviceConfig {
-                fs_id,
-                fs_share: FsImplShare::Passthrough(path.to_string()),
-                shm_size: None,
-            });
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -572,38 +2538,23 @@ pub unsafe extern "C" fn krun_add_virtiofs(
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
 #[cfg(not(feature = "tee"))]
-pub unsafe extern "C" fn krun_add_virtiofs2(
+pub unsafe extern "C" fn krun_set_virtiofs_shared_lowers(
     ctx_id: u32,
     c_tag: *const c_char,
-    c_path: *const c_char,
-    shm_size: u64,
+    enable: bool,
 ) -> i32 {
     let tag = match CStr::from_ptr(c_tag).to_str() {
         Ok(tag) => tag,
         Err(_) => return -libc::EINVAL,
     };
-    let path = match CStr::from_ptr(c_path).to_str() {
-        Ok(path) => path,
-        Err(_) => return -libc::EINVAL,
-    };
 
     match CTX_MAP.lock().unwrap().entry(ctx_id) {
         Entry::Occupied(mut ctx_cfg) => {
             let cfg = ctx_cfg.get_mut();
-
-            // Check if a device with the same tag already exists
-            let fs_id = tag.to_string();
-            for device in &cfg.vmr.fs {
-                if device.fs_id == fs_id {
-                    return -libc::EEXIST;
-                }
+            match cfg.vmr.fs.iter_mut().find(|device| device.fs_id == tag) {
+                Some(device) => device.shared_lower_layers = enable,
+                None => return -libc::ENOENT,
             }
-
-            cfg.vmr.add_fs_device(FsDeviceConfig {
-                fs_id,
-                fs_share: FsImplShare::Passthrough(path.to_string()),
-                shm_size: Some(shm_size.try_into().unwrap()),
-            });
         }
         Entry::Vacant(_) => return -libc::ENOENT,
     }
@@ -647,7 +2598,8 @@ pub unsafe extern "C" fn krun_add_disk(
                 block_id: block_id.to_string(),
                 cache_type: CacheType::auto(disk_path),
                 disk_image_path: disk_path.to_string(),
-                disk_image_format: ImageType::Raw,
+                disk_image_format: BlockFormat::Raw,
+                disk_snapshot: None,
                 is_disk_read_only: read_only,
             };
             cfg.add_block_cfg(block_device_config);
@@ -679,8 +2631,8 @@ pub unsafe extern "C" fn krun_add_disk2(
     };
 
     let format = match disk_format {
-        0 => ImageType::Raw,
-        1 => ImageType::Qcow2,
+        0 => BlockFormat::Raw,
+        1 => BlockFormat::Qcow2,
         _ => {
             // Do not continue if the user cannot specify a valid disk format
             return -libc::EINVAL;
@@ -695,6 +2647,7 @@ pub unsafe extern "C" fn krun_add_disk2(
                 cache_type: CacheType::auto(disk_path),
                 disk_image_path: disk_path.to_string(),
                 disk_image_format: format,
+                disk_snapshot: None,
                 is_disk_read_only: read_only,
             };
             cfg.add_block_cfg(block_device_config);
@@ -705,6 +2658,47 @@ pub unsafe extern "C" fn krun_add_disk2(
     KRUN_SUCCESS
 }
 
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(feature = "blk")]
+pub unsafe extern "C" fn krun_set_disk_snapshot(
+    ctx_id: u32,
+    c_block_id: *const c_char,
+    c_snapshot_name: *const c_char,
+    action: u32,
+) -> i32 {
+    let block_id = match CStr::from_ptr(c_block_id).to_str() {
+        Ok(block_id) => block_id,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let snapshot_name = match CStr::from_ptr(c_snapshot_name).to_str() {
+        Ok(name) if !name.is_empty() => name.to_string(),
+        _ => return -libc::EINVAL,
+    };
+
+    let snapshot = match action {
+        0 => DiskSnapshot::Apply(snapshot_name),
+        1 => DiskSnapshot::Create(snapshot_name),
+        _ => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let Some(block_cfg) = ctx_cfg.get_mut().find_block_cfg(block_id) else {
+                return -libc::ENOENT;
+            };
+            if block_cfg.disk_image_format != BlockFormat::Qcow2 || block_cfg.is_disk_read_only {
+                return -libc::EINVAL;
+            }
+            block_cfg.disk_snapshot = Some(snapshot);
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
 #[cfg(feature = "blk")]
@@ -721,7 +2715,8 @@ pub unsafe extern "C" fn krun_set_root_disk(ctx_id: u32, c_disk_path: *const c_c
                 block_id: "root".to_string(),
                 cache_type: CacheType::auto(disk_path),
                 disk_image_path: disk_path.to_string(),
-                disk_image_format: ImageType::Raw,
+                disk_image_format: BlockFormat::Raw,
+                disk_snapshot: None,
                 is_disk_read_only: false,
             };
             cfg.set_root_block_cfg(block_device_config);
@@ -748,7 +2743,8 @@ pub unsafe extern "C" fn krun_set_data_disk(ctx_id: u32, c_disk_path: *const c_c
                 block_id: "data".to_string(),
                 cache_type: CacheType::auto(disk_path),
                 disk_image_path: disk_path.to_string(),
-                disk_image_format: ImageType::Raw,
+                disk_image_format: BlockFormat::Raw,
+                disk_snapshot: None,
                 is_disk_read_only: false,
             };
             cfg.set_data_block_cfg(block_device_config);
@@ -828,7 +2824,7 @@ pub unsafe extern "C" fn krun_set_net_mac(ctx_id: u32, c_mac: *const u8) -> i32
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
 pub unsafe extern "C" fn krun_set_port_map(ctx_id: u32, c_port_map: *const *const c_char) -> i32 {
-    let mut port_map = HashMap::new();
+    let mut port_map = PortMap::default();
     let port_map_array: &[*const c_char] = slice::from_raw_parts(c_port_map, MAX_ARGS);
     for item in port_map_array.iter().take(MAX_ARGS) {
         if item.is_null() {
@@ -838,28 +2834,26 @@ pub unsafe extern "C" fn krun_set_port_map(ctx_id: u32, c_port_map: *const *cons
                 Ok(s) => s,
                 Err(_) => return -libc::EINVAL,
             };
-            let port_tuple: Vec<&str> = s.split(':').collect();
-            if port_tuple.len() != 2 {
-                return -libc::EINVAL;
-            }
-            let host_port: u16 = match port_tuple[0].parse() {
-                Ok(p) => p,
-                Err(_) => return -libc::EINVAL,
-            };
-            let guest_port: u16 = match port_tuple[1].parse() {
-                Ok(p) => p,
-                Err(_) => return -libc::EINVAL,
+            let mapping = match s.parse::<PortMapping>() {
+                Ok(mapping) => mapping,
+                Err(e) => {
+                    error!("{e}");
+                    return -libc::EINVAL;
+                }
             };
-
-            if port_map.contains_key(&guest_port) {
-                return -libc::EINVAL;
-            }
-            for hp in port_map.values() {
-                if *hp == host_port {
+            match port_map.insert(mapping) {
+                Ok(()) => {}
+                Err(
+                    e @ (PortMapError::GuestPortConflict(_) | PortMapError::HostPortConflict(_)),
+                ) => {
+                    error!("{e}");
+                    return -libc::EEXIST;
+                }
+                Err(e) => {
+                    error!("{e}");
                     return -libc::EINVAL;
                 }
             }
-            port_map.insert(guest_port, host_port);
         }
     }
 
@@ -1164,6 +3158,134 @@ pub unsafe extern "C" fn krun_add_vsock_port2(
     KRUN_SUCCESS
 }
 
+#[no_mangle]
+pub extern "C" fn krun_add_vsock_listener_fd(ctx_id: u32, port: u32, fd: c_int) -> i32 {
+    // Only sockets already listening can be accepted from
+    let mut accepting: c_int = 0;
+    let mut len = std::mem::size_of::<c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            &mut accepting as *mut c_int as *mut c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 || accepting == 0 {
+        return -libc::EINVAL;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            if cfg.vsock_port_in_use(port) {
+                return -libc::EEXIST;
+            }
+            // Safe because the caller hands the socket over to us on success.
+            cfg.add_vsock_listener_fd(port, unsafe { OwnedFd::from_raw_fd(fd) });
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_add_unix_socket_map(
+    ctx_id: u32,
+    c_host_path: *const c_char,
+    c_guest_path: *const c_char,
+    listen: bool,
+) -> i32 {
+    let host_path = match CStr::from_ptr(c_host_path).to_str() {
+        Ok(f) => PathBuf::from(f.to_string()),
+        Err(_) => return -libc::EINVAL,
+    };
+
+    // The guest path is passed to init through the kernel command line, so it can't contain
+    // any of the characters used to delimit it there.
+    let guest_path = match CStr::from_ptr(c_guest_path).to_str() {
+        Ok(p)
+            if p.starts_with('/')
+                && !p.contains(['"', ','])
+                && !p.contains(char::is_whitespace) =>
+        {
+            p
+        }
+        _ => return -libc::EINVAL,
+    };
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            if let Err(e) = ctx_cfg
+                .get_mut()
+                .add_unix_socket_map(host_path, guest_path, listen)
+            {
+                return e;
+            }
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_clipboard_callbacks(
+    ctx_id: u32,
+    c_mime_types: *const *const c_char,
+    max_size: u32,
+    get_cb: Option<ClipboardGetFn>,
+    set_cb: Option<ClipboardSetFn>,
+    opaque: *mut c_void,
+) -> i32 {
+    let max_size = match max_size {
+        0 => CLIPBOARD_DEFAULT_MAX_SIZE,
+        size if size <= CLIPBOARD_MAX_SIZE => size,
+        _ => return -libc::EINVAL,
+    };
+
+    if c_mime_types.is_null() {
+        return -libc::EINVAL;
+    }
+
+    let mut mime_types = Vec::new();
+    let array: &[*const c_char] = slice::from_raw_parts(c_mime_types, MAX_ARGS);
+    for item in array.iter().take(MAX_ARGS) {
+        if item.is_null() {
+            break;
+        }
+        let mime_type = CStr::from_ptr(*item);
+        if mime_type.is_empty() || mime_type.to_bytes().len() >= MAX_MIME_TYPE_LEN as usize {
+            return -libc::EINVAL;
+        }
+        mime_types.push(mime_type.to_owned());
+    }
+
+    if mime_types.is_empty() {
+        return -libc::EINVAL;
+    }
+
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.clipboard = Some(ClipboardConfig {
+                mime_types,
+                max_size,
+                get: get_cb,
+                set: set_cb,
+                opaque,
+            });
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
 pub unsafe extern "C" fn krun_set_gpu_options(ctx_id: u32, virgl_flags: u32) -> i32 {
@@ -1211,6 +3333,48 @@ pub unsafe extern "C" fn krun_set_snd_device(ctx_id: u32, enable: bool) -> i32 {
     KRUN_SUCCESS
 }
 
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub extern "C" fn krun_set_rng_device(ctx_id: u32, enable: bool, bytes_per_sec: u64) -> i32 {
+    match CTX_MAP.lock().unwrap().entry(ctx_id) {
+        Entry::Occupied(mut ctx_cfg) => {
+            let cfg = ctx_cfg.get_mut();
+            cfg.vmr.disable_rng = !enable;
+            cfg.vmr.rng_rate_limit = (bytes_per_sec != 0).then_some(bytes_per_sec);
+        }
+        Entry::Vacant(_) => return -libc::ENOENT,
+    }
+
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+#[cfg(not(feature = "tee"))]
+pub unsafe extern "C" fn krun_get_rng_stats(
+    ctx_id: u32,
+    requests: *mut u64,
+    bytes: *mut u64,
+    throttled: *mut u64,
+) -> i32 {
+    if requests.is_null() || bytes.is_null() || throttled.is_null() {
+        return -libc::EINVAL;
+    }
+
+    let running_vms = RUNNING_VMS.lock().unwrap();
+    let Some(vm) = running_vms.get(&ctx_id) else {
+        return -libc::ENOENT;
+    };
+    let Some(stats) = &vm.rng_stats else {
+        return -libc::ENODEV;
+    };
+
+    *requests = stats.requests();
+    *bytes = stats.bytes();
+    *throttled = stats.throttled();
+    KRUN_SUCCESS
+}
+
 #[allow(unused_assignments)]
 #[no_mangle]
 pub extern "C" fn krun_get_shutdown_eventfd(ctx_id: u32) -> i32 {
@@ -1620,12 +3784,14 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
 
     let boot_source = BootSourceConfig {
         kernel_cmdline_prolog: Some(format!(
-            "{} init={} {} {} {} {}",
+            "{} init={} {} {} {} {} {} {}",
             DEFAULT_KERNEL_CMDLINE,
             INIT_PATH,
             ctx_cfg.get_exec_path(),
             ctx_cfg.get_workdir(),
             ctx_cfg.get_rlimits(),
+            ctx_cfg.get_unix_socket_maps(),
+            ctx_cfg.get_virtiofs_mounts(),
             ctx_cfg.get_env(),
         )),
         kernel_cmdline_epilog: Some(format!(" -- {}", ctx_cfg.get_args())),
@@ -1641,16 +3807,38 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
         guest_cid: 3,
         host_port_map: None,
         unix_ipc_port_map: None,
+        listener_fds: HashMap::new(),
         ip: None,
         subnet: None,
         scope: 0,
     };
 
+    if let Some(clipboard_cfg) = ctx_cfg.clipboard.take() {
+        let socket_path = env::temp_dir().join(format!(
+            "krun-clipboard-{}-{ctx_id}.sock",
+            std::process::id()
+        ));
+        if let Err(e) = clipboard::start(clipboard_cfg, &socket_path) {
+            error!("Error starting the clipboard channel: {:?}", e);
+            return -libc::EINVAL;
+        }
+        ctx_cfg.add_vsock_port(CLIPBOARD_VSOCK_PORT, socket_path, false);
+    }
+
     if let Some(ref map) = ctx_cfg.unix_ipc_port_map {
         vsock_config.unix_ipc_port_map = Some(map.clone());
         vsock_set = true;
     }
 
+    if !ctx_cfg.vsock_listener_fds.is_empty() {
+        vsock_config.listener_fds = ctx_cfg
+            .vsock_listener_fds
+            .drain()
+            .map(|(port, fd)| (port, fd.into_raw_fd()))
+            .collect();
+        vsock_set = true;
+    }
+
     match ctx_cfg.net_cfg {
         NetworkConfig::Tsi(tsi_cfg) => {
             vsock_config.host_port_map = tsi_cfg.port_map;
@@ -1722,6 +3910,32 @@ pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
         }
     };
 
+    let vm_config = ctx_cfg.vmr.vm_config();
+    RUNNING_VMS.lock().unwrap().insert(
+        ctx_id,
+        RunningVm {
+            ram_mib: vm_config.mem_size_mib.unwrap(),
+            #[cfg(not(feature = "tee"))]
+            mem_resizer: _vmm.lock().unwrap().mem_resizer(),
+            #[cfg(not(feature = "tee"))]
+            rng_stats: _vmm.lock().unwrap().rng_stats(),
+            #[cfg(not(feature = "tee"))]
+            fs_handles: _vmm.lock().unwrap().fs_handles(),
+            #[cfg(not(feature = "tee"))]
+            fs_mirrors: _vmm.lock().unwrap().fs_mirrors(),
+            #[cfg(not(feature = "tee"))]
+            fs_pauses: _vmm.lock().unwrap().fs_pauses(),
+            #[cfg(not(feature = "tee"))]
+            fs_degradations: _vmm.lock().unwrap().fs_degradations(),
+            #[cfg(not(feature = "tee"))]
+            fs_cache_timeouts: _vmm.lock().unwrap().fs_cache_timeouts(),
+            #[cfg(not(feature = "tee"))]
+            fs_tracers: _vmm.lock().unwrap().fs_tracers(),
+            #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+            vmm: _vmm.clone(),
+        },
+    );
+
     #[cfg(target_os = "macos")]
     if ctx_cfg.gpu_virgl_flags.is_some() {
         vmm::worker::start_worker_thread(_vmm.clone(), _receiver).unwrap();
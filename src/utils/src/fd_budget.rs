@@ -0,0 +1,448 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! Accounting of the host file descriptors held by the devices.
+//!
+//! All the devices of the process share its file descriptor limit, so that one of them opening
+//! many files, e.g. a virtio-fs share walked by the guest, makes the others fail randomly with
+//! `EMFILE`. Each device registers with a [`FdBudget`] and takes a [`FdGrant`] for the
+//! descriptors it keeps open, which is given back when dropped.
+//!
+//! The registration has a [`FdPriority`], which sets how much of the budget it can use: the
+//! low-priority devices can only use three quarters of it and are held back, rather than failed
+//! right away, when they reach that share, until the other devices release some descriptors or
+//! the back-pressure timeout expires. The other priorities fail as soon as they reach their
+//! share, so that the high-priority devices always have some descriptors left.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Descriptors of the process left out of the budget of the default one, for the VMM itself and
+/// the libraries it uses.
+const RESERVED_FDS: usize = 128;
+
+/// Used when the limit of the process can't be read.
+const DEFAULT_LIMIT: usize = 1024;
+
+/// How long a low-priority acquisition waits for descriptors by default.
+const DEFAULT_BACKPRESSURE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How much a device registering with it can use of a budget of file descriptors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FdPriority {
+    /// Descriptors opened on behalf of the guest in large numbers, e.g. the files of a share.
+    /// Limited to three quarters of the budget, and delayed when reaching it.
+    Low,
+    /// Limited to seven eighths of the budget.
+    Normal,
+    /// Descriptors the device can't work without, e.g. the socket of a network backend. Can use
+    /// the whole budget.
+    High,
+}
+
+impl FdPriority {
+    /// Returns how many descriptors can be in use, by all the devices, for an acquisition of this
+    /// priority to succeed.
+    fn ceiling(self, limit: usize) -> usize {
+        match self {
+            FdPriority::Low => limit / 4 * 3,
+            FdPriority::Normal => limit / 8 * 7,
+            FdPriority::High => limit,
+        }
+    }
+}
+
+impl fmt::Display for FdPriority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FdPriority::Low => write!(f, "low"),
+            FdPriority::Normal => write!(f, "normal"),
+            FdPriority::High => write!(f, "high"),
+        }
+    }
+}
+
+/// The usage of a budget of file descriptors, as returned by [`FdBudget::usage`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FdUsage {
+    /// How many descriptors the devices can hold in total.
+    pub limit: usize,
+    /// How many descriptors the devices hold.
+    pub in_use: usize,
+    /// The registered devices, in the order they registered.
+    pub clients: Vec<FdClientUsage>,
+}
+
+/// The usage of a device registered with a budget of file descriptors.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FdClientUsage {
+    pub name: String,
+    pub priority: FdPriority,
+    /// How many descriptors the device holds.
+    pub in_use: usize,
+    /// How many acquisitions of the device are waiting for descriptors.
+    pub waiting: usize,
+    /// How many acquisitions of the device had to wait for descriptors.
+    pub delayed: u64,
+    /// How many acquisitions of the device failed for lack of descriptors.
+    pub denied: u64,
+}
+
+struct ClientState {
+    name: String,
+    priority: FdPriority,
+    in_use: usize,
+    waiting: usize,
+    delayed: u64,
+    denied: u64,
+}
+
+struct BudgetState {
+    limit: usize,
+    in_use: usize,
+    backpressure_timeout: Duration,
+    clients: BTreeMap<u64, ClientState>,
+    next_client: u64,
+}
+
+struct BudgetShared {
+    state: Mutex<BudgetState>,
+    /// Signaled when descriptors are released or the limit raised.
+    released: Condvar,
+}
+
+/// A budget of file descriptors shared by the devices registering with it.
+///
+/// Cloning it gives another handle to the same budget.
+#[derive(Clone)]
+pub struct FdBudget {
+    shared: Arc<BudgetShared>,
+}
+
+impl FdBudget {
+    /// Creates a budget of `limit` descriptors.
+    pub fn new(limit: usize) -> Self {
+        FdBudget {
+            shared: Arc::new(BudgetShared {
+                state: Mutex::new(BudgetState {
+                    limit,
+                    in_use: 0,
+                    backpressure_timeout: DEFAULT_BACKPRESSURE_TIMEOUT,
+                    clients: BTreeMap::new(),
+                    next_client: 0,
+                }),
+                released: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Returns the budget shared by all the devices of the process, whose limit is the soft
+    /// `RLIMIT_NOFILE` of the process when first called, minus a reserve for the VMM itself.
+    pub fn global() -> &'static FdBudget {
+        static GLOBAL: OnceLock<FdBudget> = OnceLock::new();
+        GLOBAL.get_or_init(|| FdBudget::new(process_limit().saturating_sub(RESERVED_FDS)))
+    }
+
+    /// Registers a device named `name`, whose acquisitions have `priority`.
+    pub fn register(&self, name: impl Into<String>, priority: FdPriority) -> FdClient {
+        let mut state = self.shared.state.lock().unwrap();
+        let id = state.next_client;
+        state.next_client += 1;
+        state.clients.insert(
+            id,
+            ClientState {
+                name: name.into(),
+                priority,
+                in_use: 0,
+                waiting: 0,
+                delayed: 0,
+                denied: 0,
+            },
+        );
+
+        FdClient {
+            registration: Arc::new(Registration {
+                budget: self.clone(),
+                id,
+                priority,
+            }),
+        }
+    }
+
+    /// Changes how many descriptors the devices can hold in total. Lowering it below the ones in
+    /// use doesn't take any back, but fails or delays the acquisitions until enough are released.
+    pub fn set_limit(&self, limit: usize) {
+        self.shared.state.lock().unwrap().limit = limit;
+        self.shared.released.notify_all();
+    }
+
+    /// Changes how long the low-priority acquisitions wait for descriptors before failing.
+    pub fn set_backpressure_timeout(&self, timeout: Duration) {
+        self.shared.state.lock().unwrap().backpressure_timeout = timeout;
+    }
+
+    /// Returns how many descriptors the budget has and how many each device holds.
+    pub fn usage(&self) -> FdUsage {
+        let state = self.shared.state.lock().unwrap();
+        FdUsage {
+            limit: state.limit,
+            in_use: state.in_use,
+            clients: state
+                .clients
+                .values()
+                .map(|client| FdClientUsage {
+                    name: client.name.clone(),
+                    priority: client.priority,
+                    in_use: client.in_use,
+                    waiting: client.waiting,
+                    delayed: client.delayed,
+                    denied: client.denied,
+                })
+                .collect(),
+        }
+    }
+
+    fn acquire(
+        &self,
+        registration: &Arc<Registration>,
+        count: usize,
+        wait: bool,
+    ) -> io::Result<FdGrant> {
+        let mut state = self.shared.state.lock().unwrap();
+        let fits = |state: &BudgetState| {
+            state.in_use + count <= registration.priority.ceiling(state.limit)
+        };
+
+        if !fits(&state) {
+            let wait = wait && registration.priority == FdPriority::Low;
+            let deadline = Instant::now() + state.backpressure_timeout;
+            if wait {
+                let client = state.clients.get_mut(&registration.id).unwrap();
+                client.waiting += 1;
+                client.delayed += 1;
+                while !fits(&state) {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    state = self
+                        .shared
+                        .released
+                        .wait_timeout(state, deadline - now)
+                        .unwrap()
+                        .0;
+                }
+                state.clients.get_mut(&registration.id).unwrap().waiting -= 1;
+            }
+
+            if !fits(&state) {
+                let (in_use, limit) = (state.in_use, state.limit);
+                let client = state.clients.get_mut(&registration.id).unwrap();
+                client.denied += 1;
+                log::debug!(
+                    "fd budget: denied {} descriptors to {} ({} priority), {} of {} in use",
+                    count,
+                    client.name,
+                    client.priority,
+                    in_use,
+                    limit
+                );
+                return Err(io::Error::from_raw_os_error(libc::EMFILE));
+            }
+        }
+
+        state.in_use += count;
+        state.clients.get_mut(&registration.id).unwrap().in_use += count;
+        Ok(FdGrant {
+            registration: registration.clone(),
+            count,
+        })
+    }
+
+    fn release(&self, id: u64, count: usize) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.in_use -= count;
+        if let Some(client) = state.clients.get_mut(&id) {
+            client.in_use -= count;
+        }
+        drop(state);
+        self.shared.released.notify_all();
+    }
+}
+
+/// Returns the soft limit on the number of descriptors of the process.
+fn process_limit() -> usize {
+    let mut rlimit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `rlimit` is a valid `struct rlimit` for the call to fill in.
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlimit) } != 0
+        || rlimit.rlim_cur == libc::RLIM_INFINITY
+    {
+        return DEFAULT_LIMIT;
+    }
+    usize::try_from(rlimit.rlim_cur).unwrap_or(DEFAULT_LIMIT)
+}
+
+/// Unregisters the device once its last [`FdClient`] and [`FdGrant`] are dropped.
+struct Registration {
+    budget: FdBudget,
+    id: u64,
+    priority: FdPriority,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.budget
+            .shared
+            .state
+            .lock()
+            .unwrap()
+            .clients
+            .remove(&self.id);
+    }
+}
+
+/// The registration of a device with a [`FdBudget`], through which it acquires descriptors.
+///
+/// Cloning it gives another handle to the same registration.
+#[derive(Clone)]
+pub struct FdClient {
+    registration: Arc<Registration>,
+}
+
+impl FdClient {
+    /// Acquires `count` descriptors, waiting for them if the device has a low priority and the
+    /// budget is short, up to the back-pressure timeout. Fails with `EMFILE` if they can't be
+    /// acquired.
+    pub fn acquire(&self, count: usize) -> io::Result<FdGrant> {
+        self.registration
+            .budget
+            .acquire(&self.registration, count, true)
+    }
+
+    /// Acquires `count` descriptors, failing with `EMFILE` rather than waiting for them.
+    pub fn try_acquire(&self, count: usize) -> io::Result<FdGrant> {
+        self.registration
+            .budget
+            .acquire(&self.registration, count, false)
+    }
+
+    pub fn priority(&self) -> FdPriority {
+        self.registration.priority
+    }
+}
+
+impl fmt::Debug for FdClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FdClient")
+            .field("id", &self.registration.id)
+            .field("priority", &self.registration.priority)
+            .finish()
+    }
+}
+
+/// Descriptors acquired from a [`FdBudget`], released when dropped.
+pub struct FdGrant {
+    registration: Arc<Registration>,
+    count: usize,
+}
+
+impl FdGrant {
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+impl Drop for FdGrant {
+    fn drop(&mut self) {
+        self.registration
+            .budget
+            .release(self.registration.id, self.count);
+    }
+}
+
+impl fmt::Debug for FdGrant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FdGrant")
+            .field("client", &self.registration.id)
+            .field("count", &self.count)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_priorities() {
+        let budget = FdBudget::new(16);
+        budget.set_backpressure_timeout(Duration::ZERO);
+        let low = budget.register("low", FdPriority::Low);
+        let normal = budget.register("normal", FdPriority::Normal);
+        let high = budget.register("high", FdPriority::High);
+
+        // Each priority stops at its share of the budget
+        let low_grant = low.acquire(12).unwrap();
+        assert_eq!(
+            low.acquire(1).unwrap_err().raw_os_error(),
+            Some(libc::EMFILE)
+        );
+        let normal_grant = normal.try_acquire(2).unwrap();
+        assert!(normal.try_acquire(1).is_err());
+        let high_grant = high.try_acquire(2).unwrap();
+        assert!(high.try_acquire(1).is_err());
+
+        let usage = budget.usage();
+        assert_eq!((usage.limit, usage.in_use), (16, 16));
+        let in_use: Vec<_> = usage.clients.iter().map(|c| (c.in_use, c.denied)).collect();
+        assert_eq!(in_use, vec![(12, 1), (2, 1), (2, 1)]);
+
+        drop((low_grant, normal_grant, high_grant));
+        assert_eq!(budget.usage().in_use, 0);
+
+        // Devices are unregistered once their last handle is gone
+        let grant = low.acquire(1).unwrap();
+        drop(low);
+        assert_eq!(budget.usage().clients.len(), 3);
+        drop(grant);
+        let names: Vec<_> = budget.usage().clients.into_iter().map(|c| c.name).collect();
+        assert_eq!(names, vec!["normal", "high"]);
+    }
+
+    #[test]
+    fn test_backpressure() {
+        let budget = FdBudget::new(8);
+        budget.set_backpressure_timeout(Duration::from_secs(10));
+        let low = budget.register("low", FdPriority::Low);
+        let high = budget.register("high", FdPriority::High);
+
+        let high_grant = high.acquire(4).unwrap();
+        let low_grant = low.acquire(2).unwrap();
+
+        // The low-priority acquisition waits for the high-priority descriptors to be released
+        let waiter = {
+            let low = low.clone();
+            thread::spawn(move || low.acquire(2).map(|grant| grant.count()))
+        };
+        while budget.usage().clients[0].waiting == 0 {
+            thread::yield_now();
+        }
+        assert_eq!(budget.usage().clients[0].delayed, 1);
+        drop(high_grant);
+        assert_eq!(waiter.join().unwrap().unwrap(), 2);
+
+        // Without any release, it fails once the timeout expires
+        budget.set_backpressure_timeout(Duration::from_millis(10));
+        let _high_grant = high.acquire(4).unwrap();
+        assert!(low.acquire(2).is_err());
+        assert!(low.try_acquire(1).is_err());
+        drop(low_grant);
+        assert!(low.try_acquire(2).is_ok());
+    }
+}
@@ -65,6 +65,8 @@ use crate::vstate::{Error as VstateError, Vcpu, VcpuConfig, Vm};
 use arch::{ArchMemoryInfo, InitrdConfig};
 use device_manager::shm::ShmManager;
 #[cfg(not(feature = "tee"))]
+use device_manager::shm::ShmRegion;
+#[cfg(not(feature = "tee"))]
 use devices::virtio::{fs::ExportTable, VirtioShmRegion};
 use flate2::read::GzDecoder;
 #[cfg(feature = "tee")]
@@ -178,6 +180,8 @@ pub enum StartMicrovmError {
     RegisterFsSigwinch(kvm_ioctls::Error),
     /// Cannot initialize a MMIO Gpu device or add a device to the MMIO Bus.
     RegisterGpuDevice(device_manager::mmio::Error),
+    /// Cannot initialize a MMIO Mem device or add a device to the MMIO Bus.
+    RegisterMemDevice(device_manager::mmio::Error),
     /// Cannot initialize a MMIO Network Device or add a device to the MMIO Bus.
     RegisterNetDevice(device_manager::mmio::Error),
     /// Cannot initialize a MMIO Rng device or add a device to the MMIO Bus.
@@ -186,6 +190,9 @@ pub enum StartMicrovmError {
     RegisterSndDevice(device_manager::mmio::Error),
     /// Cannot initialize a MMIO Vsock Device or add a device to the MMIO Bus.
     RegisterVsockDevice(device_manager::mmio::Error),
+    /// Cannot restore the microVM from a snapshot.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    RestoreSnapshot(crate::snapshot::Error),
     /// Cannot attest the VM in the Secure Virtualization context.
     SecureVirtAttest(VstateError),
     /// Cannot initialize the Secure Virtualization backend.
@@ -381,6 +388,14 @@ impl Display for StartMicrovmError {
                     "Cannot initialize a MMIO Gpu Device or add a device to the MMIO Bus. {err_msg}"
                 )
             }
+            RegisterMemDevice(ref err) => {
+                let mut err_msg = format!("{err}");
+                err_msg = err_msg.replace('\"', "");
+                write!(
+                    f,
+                    "Cannot initialize a MMIO Mem Device or add a device to the MMIO Bus. {err_msg}"
+                )
+            }
             RegisterNetDevice(ref err) => {
                 let mut err_msg = format!("{err}");
                 err_msg = err_msg.replace('\"', "");
@@ -415,6 +430,10 @@ impl Display for StartMicrovmError {
                     "Cannot initialize a MMIO Vsock Device or add a device to the MMIO Bus. {err_msg}"
                 )
             }
+            #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+            RestoreSnapshot(ref err) => {
+                write!(f, "Cannot restore the microVM from a snapshot. {err}")
+            }
             SecureVirtAttest(ref err) => {
                 let mut err_msg = format!("{err}");
                 err_msg = err_msg.replace('\"', "");
@@ -771,12 +790,41 @@ pub fn build_microvm(
         mmio_device_manager,
         #[cfg(target_arch = "x86_64")]
         pio_device_manager,
+        #[cfg(target_arch = "x86_64")]
+        split_irqchip: vm_resources.split_irqchip,
+        #[cfg(not(feature = "tee"))]
+        mem_resizer: None,
+        #[cfg(not(feature = "tee"))]
+        rng_stats: None,
+        #[cfg(not(feature = "tee"))]
+        fs_handles: Vec::new(),
+        #[cfg(not(feature = "tee"))]
+        fs_mirrors: Vec::new(),
+        #[cfg(not(feature = "tee"))]
+        fs_pauses: Vec::new(),
+        #[cfg(not(feature = "tee"))]
+        fs_degradations: Vec::new(),
+        #[cfg(not(feature = "tee"))]
+        fs_cache_timeouts: Vec::new(),
+        #[cfg(not(feature = "tee"))]
+        fs_tracers: Vec::new(),
     };
 
     #[cfg(not(feature = "tee"))]
     attach_balloon_device(&mut vmm, event_manager, intc.clone())?;
     #[cfg(not(feature = "tee"))]
-    attach_rng_device(&mut vmm, event_manager, intc.clone())?;
+    if let Some(mem_region) = _shm_manager.mem_region().cloned() {
+        attach_mem_device(&mut vmm, event_manager, intc.clone(), mem_region)?;
+    }
+    #[cfg(not(feature = "tee"))]
+    if !vm_resources.disable_rng {
+        attach_rng_device(
+            &mut vmm,
+            event_manager,
+            intc.clone(),
+            vm_resources.rng_rate_limit,
+        )?;
+    }
     attach_console_devices(
         &mut vmm,
         event_manager,
@@ -846,18 +894,30 @@ pub fn build_microvm(
         vmm.kernel_cmdline.insert_str(s).unwrap();
     };
 
-    // Write the kernel command line to guest memory. This is x86_64 specific, since on
-    // aarch64 the command line will be specified through the FDT.
-    #[cfg(all(target_arch = "x86_64", not(feature = "tee")))]
-    load_cmdline(&vmm)?;
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    let restore = vm_resources.snapshot_restore.as_deref();
+    #[cfg(not(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee"))))]
+    let restore: Option<&std::path::Path> = None;
 
-    vmm.configure_system(
-        vcpus.as_slice(),
-        &intc,
-        &payload_config.initrd_config,
-        &vm_resources.smbios_oem_strings,
-    )
-    .map_err(StartMicrovmError::Internal)?;
+    if let Some(_path) = restore {
+        // The guest memory, the vCPUs and the devices are restored where the guest left them
+        #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+        vmm.snapshot_restore(&vcpus, _path)
+            .map_err(StartMicrovmError::RestoreSnapshot)?;
+    } else {
+        // Write the kernel command line to guest memory. This is x86_64 specific, since on
+        // aarch64 the command line will be specified through the FDT.
+        #[cfg(all(target_arch = "x86_64", not(feature = "tee")))]
+        load_cmdline(&vmm)?;
+
+        vmm.configure_system(
+            vcpus.as_slice(),
+            &intc,
+            &payload_config.initrd_config,
+            &vm_resources.smbios_oem_strings,
+        )
+        .map_err(StartMicrovmError::Internal)?;
+    }
 
     #[cfg(feature = "tee")]
     {
@@ -1238,6 +1298,16 @@ fn create_guest_memory(
 
     let mut shm_manager = ShmManager::new(&arch_mem_info);
 
+    #[cfg(not(feature = "tee"))]
+    if let Some(hotplug_mem_mib) = vm_resources.hotplug_mem_mib {
+        let size = arch::round_up(
+            hotplug_mem_mib << 20,
+            devices::virtio::MEM_REGION_ALIGN as usize,
+        );
+        shm_manager
+            .create_mem_region(size)
+            .map_err(StartMicrovmError::ShmCreate)?;
+    }
     #[cfg(not(feature = "tee"))]
     for (index, fs) in vm_resources.fs.iter().enumerate() {
         if let Some(shm_size) = fs.shm_size {
@@ -1604,6 +1674,15 @@ fn attach_fs_devices(
         let id = format!("{}{}", String::from(fs.lock().unwrap().id()), i);
 
         fs.lock().unwrap().set_intc(intc.clone());
+        fs.lock().unwrap().set_protocol(config.protocol);
+        fs.lock().unwrap().set_inode_numbers(config.inode_numbers);
+        fs.lock().unwrap().set_mode_policy(config.mode_policy);
+        if let Some(guest_mount) = config.guest_mount.clone() {
+            fs.lock().unwrap().set_guest_mount(guest_mount);
+        }
+        if let Some(degrade) = config.degrade.clone() {
+            fs.lock().unwrap().set_degrade_options(degrade);
+        }
 
         if let Some(shm_region) = shm_manager.fs_region(i) {
             fs.lock().unwrap().set_shm_region(VirtioShmRegion {
@@ -1621,6 +1700,106 @@ fn attach_fs_devices(
             fs.lock().unwrap().set_export_table(export_table.clone());
         }
 
+        if let Some(access_rules) = config.access_rules.as_ref() {
+            fs.lock().unwrap().set_access_rules(access_rules.clone());
+        }
+
+        if let Some(credentials) = config.credentials.as_ref() {
+            fs.lock().unwrap().set_credentials(credentials.clone());
+        }
+
+        if config.durable {
+            fs.lock().unwrap().set_durable(true);
+        }
+
+        if config.allow_file_flags {
+            fs.lock().unwrap().set_allow_file_flags(true);
+        }
+
+        if config.remove_tree {
+            fs.lock().unwrap().set_remove_tree(true);
+        }
+
+        if config.batch_create {
+            fs.lock().unwrap().set_batch_create(true);
+        }
+
+        if let Some(max_entries) = config.dentry_warming {
+            fs.lock().unwrap().set_dentry_warming(max_entries);
+        }
+
+        if let Some(max_handles) = config.max_handles {
+            fs.lock().unwrap().set_max_handles(max_handles);
+        }
+        vmm.fs_handles
+            .push((config.fs_id.clone(), fs.lock().unwrap().handle_quota()));
+        vmm.fs_pauses
+            .push((config.fs_id.clone(), fs.lock().unwrap().pause()));
+        vmm.fs_degradations
+            .push((config.fs_id.clone(), fs.lock().unwrap().degradation()));
+        vmm.fs_cache_timeouts
+            .push((config.fs_id.clone(), fs.lock().unwrap().cache_timeouts()));
+        vmm.fs_tracers
+            .push((config.fs_id.clone(), fs.lock().unwrap().tracer()));
+
+        fs.lock().unwrap().set_atime(config.atime);
+        fs.lock().unwrap().set_normalize_names(config.normalize_names);
+        fs.lock().unwrap().set_page_cache(config.page_cache);
+        fs.lock().unwrap().set_ownership(config.ownership);
+
+        if let Some(background_limits) = config.background_limits {
+            fs.lock().unwrap().set_background_limits(background_limits);
+        }
+
+        for watch in config.watches.iter() {
+            fs.lock().unwrap().watcher().watch(watch.clone());
+        }
+
+        if let Some(target) = config.mirror.as_ref() {
+            fs.lock().unwrap().set_mirror(target.clone());
+            if let Some(mirror) = fs.lock().unwrap().mirror() {
+                vmm.exit_observers
+                    .push(Arc::new(Mutex::new(mirror.clone())));
+                vmm.fs_mirrors.push((config.fs_id.clone(), mirror));
+            }
+        }
+
+        if let Some(interval) = config.revalidate_interval {
+            fs.lock().unwrap().set_revalidate_interval(interval);
+        }
+
+        if let Some(write_coalescing) = config.write_coalescing {
+            fs.lock().unwrap().set_write_coalescing(write_coalescing);
+        }
+
+        for file in config.virtual_files.iter() {
+            fs.lock().unwrap().add_virtual_file(file.clone());
+        }
+
+        for hook in config.hooks.iter() {
+            fs.lock().unwrap().add_hook(hook.clone());
+        }
+
+        if config.shared_lower_layers {
+            fs.lock().unwrap().set_shared_lower_layers();
+        }
+
+        if config.protect_init_config {
+            fs.lock().unwrap().set_protect_init_config(true);
+        }
+
+        if let Some(leases) = config.leases {
+            fs.lock().unwrap().set_leases(leases);
+        }
+
+        if let Some(socket_path) = config.inspect_socket.as_ref() {
+            fs.lock().unwrap().set_inspect_socket(socket_path.clone());
+        }
+
+        for template in config.dir_templates.iter() {
+            fs.lock().unwrap().add_dir_template(template.clone());
+        }
+
         #[cfg(target_os = "macos")]
         fs.lock().unwrap().set_map_sender(map_sender.clone());
 
@@ -1810,6 +1989,38 @@ fn attach_balloon_device(
     Ok(())
 }
 
+#[cfg(not(feature = "tee"))]
+fn attach_mem_device(
+    vmm: &mut Vmm,
+    event_manager: &mut EventManager,
+    intc: IrqChip,
+    region: ShmRegion,
+) -> std::result::Result<(), StartMicrovmError> {
+    use self::StartMicrovmError::*;
+
+    let mem = Arc::new(Mutex::new(
+        devices::virtio::Mem::new(region.guest_addr, region.size as u64).unwrap(),
+    ));
+
+    event_manager
+        .add_subscriber(mem.clone())
+        .map_err(RegisterEvent)?;
+
+    let id = String::from(mem.lock().unwrap().id());
+
+    mem.lock().unwrap().set_intc(intc);
+    vmm.mem_resizer = Some(mem.lock().unwrap().resizer());
+    // Nothing in the guest onlines the memory it plugs in otherwise
+    vmm.kernel_cmdline
+        .insert_str("memhp_default_state=online")?;
+
+    // The device mutex mustn't be locked here otherwise it will deadlock.
+    attach_mmio_device(vmm, id, MmioTransport::new(vmm.guest_memory().clone(), mem))
+        .map_err(RegisterMemDevice)?;
+
+    Ok(())
+}
+
 #[cfg(feature = "blk")]
 fn attach_block_devices(
     vmm: &mut Vmm,
@@ -1840,10 +2051,15 @@ fn attach_rng_device(
     vmm: &mut Vmm,
     event_manager: &mut EventManager,
     intc: IrqChip,
+    rate_limit: Option<u64>,
 ) -> std::result::Result<(), StartMicrovmError> {
     use self::StartMicrovmError::*;
 
     let rng = Arc::new(Mutex::new(devices::virtio::Rng::new().unwrap()));
+    if let Some(bytes_per_sec) = rate_limit {
+        rng.lock().unwrap().set_rate_limit(bytes_per_sec);
+    }
+    vmm.rng_stats = Some(rng.lock().unwrap().stats());
 
     event_manager
         .add_subscriber(rng.clone())
@@ -71,6 +71,7 @@ pub struct MMIODeviceManager {
     irq: u32,
     last_irq: u32,
     id_to_dev_info: HashMap<(DeviceType, String), MMIODeviceInfo>,
+    transports: Vec<Arc<Mutex<devices::virtio::MmioTransport>>>,
 }
 
 impl MMIODeviceManager {
@@ -85,6 +86,7 @@ impl MMIODeviceManager {
             last_irq: irq_interval.1,
             bus: devices::Bus::new(),
             id_to_dev_info: HashMap::new(),
+            transports: Vec::new(),
         }
     }
 
@@ -136,9 +138,11 @@ impl MMIODeviceManager {
 
         mmio_device.locked_device().set_irq_line(self.irq);
 
+        let mmio_device = Arc::new(Mutex::new(mmio_device));
         self.bus
-            .insert(Arc::new(Mutex::new(mmio_device)), self.mmio_base, MMIO_LEN)
+            .insert(mmio_device.clone(), self.mmio_base, MMIO_LEN)
             .map_err(Error::BusError)?;
+        self.transports.push(mmio_device);
         let ret = (self.mmio_base, self.irq);
         self.id_to_dev_info.insert(
             (DeviceType::Virtio(type_id), device_id),
@@ -256,6 +260,11 @@ impl MMIODeviceManager {
         &self.id_to_dev_info
     }
 
+    /// Gets the transports of the virtio devices, in the order they were registered.
+    pub fn transports(&self) -> &[Arc<Mutex<devices::virtio::MmioTransport>>] {
+        &self.transports
+    }
+
     /// Gets the the specified device.
     pub fn get_device(
         &self,
@@ -6,6 +6,7 @@ use vm_memory::GuestAddress;
 #[derive(Debug)]
 pub enum Error {
     DuplicatedGpuRegion,
+    DuplicatedMemRegion,
     OutOfSpace,
 }
 
@@ -20,6 +21,7 @@ pub struct ShmManager {
     page_size: usize,
     fs_regions: BTreeMap<usize, ShmRegion>,
     gpu_region: Option<ShmRegion>,
+    mem_region: Option<ShmRegion>,
 }
 
 impl ShmManager {
@@ -29,12 +31,17 @@ impl ShmManager {
             page_size: info.page_size,
             fs_regions: BTreeMap::new(),
             gpu_region: None,
+            mem_region: None,
         }
     }
 
     pub fn regions(&self) -> Vec<(GuestAddress, usize)> {
         let mut regions: Vec<(GuestAddress, usize)> = Vec::new();
 
+        if let Some(region) = &self.mem_region {
+            regions.push((region.guest_addr, region.size));
+        }
+
         for region in self.fs_regions.iter() {
             regions.push((region.1.guest_addr, region.1.size));
         }
@@ -56,6 +63,11 @@ impl ShmManager {
         self.gpu_region.as_ref()
     }
 
+    #[cfg(not(feature = "tee"))]
+    pub fn mem_region(&self) -> Option<&ShmRegion> {
+        self.mem_region.as_ref()
+    }
+
     fn create_region(&mut self, size: usize) -> Result<ShmRegion, Error> {
         let size = round_up(size, self.page_size);
 
@@ -81,6 +93,18 @@ impl ShmManager {
         }
     }
 
+    /// Creates the region memory is hotplugged into, which must come first to be aligned like the
+    /// start of the regions.
+    #[cfg(not(feature = "tee"))]
+    pub fn create_mem_region(&mut self, size: usize) -> Result<(), Error> {
+        if self.mem_region.is_some() {
+            Err(Error::DuplicatedMemRegion)
+        } else {
+            self.mem_region = Some(self.create_region(size)?);
+            Ok(())
+        }
+    }
+
     #[cfg(not(feature = "tee"))]
     pub fn create_fs_region(&mut self, index: usize, size: usize) -> Result<(), Error> {
         let region = self.create_region(size)?;
@@ -21,6 +21,9 @@ pub mod resources;
 /// Signal handling utilities.
 #[cfg(target_os = "linux")]
 pub mod signal_handler;
+/// Snapshots of running microVMs.
+#[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+pub mod snapshot;
 /// Wrappers over structures used to configure the VMM.
 pub mod vmm_config;
 
@@ -59,6 +62,12 @@ use crossbeam_channel::Sender;
 use devices::fdt;
 use devices::legacy::IrqChip;
 use devices::virtio::VmmExitObserver;
+#[cfg(not(feature = "tee"))]
+use devices::virtio::fs::{
+    FsCacheTimeouts, FsDegradation, FsHandleQuota, FsMirror, FsPause, FsTracer,
+};
+#[cfg(not(feature = "tee"))]
+use devices::virtio::{MemResizer, RngStats};
 use devices::{BusDevice, DeviceType};
 use kernel::cmdline::Cmdline as KernelCmdline;
 use polly::event_manager::{self, EventManager, Subscriber};
@@ -208,6 +217,25 @@ pub struct Vmm {
     mmio_device_manager: MMIODeviceManager,
     #[cfg(target_arch = "x86_64")]
     pio_device_manager: PortIODeviceManager,
+    // Whether the IOAPIC is emulated by the VMM, which snapshots don't save the state of.
+    #[cfg(target_arch = "x86_64")]
+    split_irqchip: bool,
+    #[cfg(not(feature = "tee"))]
+    mem_resizer: Option<MemResizer>,
+    #[cfg(not(feature = "tee"))]
+    rng_stats: Option<Arc<RngStats>>,
+    #[cfg(not(feature = "tee"))]
+    fs_handles: Vec<(String, FsHandleQuota)>,
+    #[cfg(not(feature = "tee"))]
+    fs_mirrors: Vec<(String, FsMirror)>,
+    #[cfg(not(feature = "tee"))]
+    fs_pauses: Vec<(String, FsPause)>,
+    #[cfg(not(feature = "tee"))]
+    fs_degradations: Vec<(String, FsDegradation)>,
+    #[cfg(not(feature = "tee"))]
+    fs_cache_timeouts: Vec<(String, FsCacheTimeouts)>,
+    #[cfg(not(feature = "tee"))]
+    fs_tracers: Vec<(String, FsTracer)>,
 }
 
 impl Vmm {
@@ -220,6 +248,55 @@ impl Vmm {
         self.mmio_device_manager.get_device(device_type, device_id)
     }
 
+    /// Returns the handle resizing the memory hotplugged into the guest, if it has any.
+    #[cfg(not(feature = "tee"))]
+    pub fn mem_resizer(&self) -> Option<MemResizer> {
+        self.mem_resizer.clone()
+    }
+
+    /// Returns the statistics of the entropy device, if the guest has one.
+    #[cfg(not(feature = "tee"))]
+    pub fn rng_stats(&self) -> Option<Arc<RngStats>> {
+        self.rng_stats.clone()
+    }
+
+    /// Returns the counts of the handles the guest keeps open on each virtio-fs device, by tag.
+    #[cfg(not(feature = "tee"))]
+    pub fn fs_handles(&self) -> Vec<(String, FsHandleQuota)> {
+        self.fs_handles.clone()
+    }
+
+    /// Returns the mirrors of the virtio-fs devices that have one, by tag.
+    #[cfg(not(feature = "tee"))]
+    pub fn fs_mirrors(&self) -> Vec<(String, FsMirror)> {
+        self.fs_mirrors.clone()
+    }
+
+    /// Returns the handles pausing each virtio-fs device, by tag.
+    #[cfg(not(feature = "tee"))]
+    pub fn fs_pauses(&self) -> Vec<(String, FsPause)> {
+        self.fs_pauses.clone()
+    }
+
+    /// Returns the handles re-enabling each virtio-fs device degraded to read-only, by tag.
+    #[cfg(not(feature = "tee"))]
+    pub fn fs_degradations(&self) -> Vec<(String, FsDegradation)> {
+        self.fs_degradations.clone()
+    }
+
+    /// Returns the handles changing the entry and attribute timeouts of each virtio-fs device, by
+    /// tag.
+    #[cfg(not(feature = "tee"))]
+    pub fn fs_cache_timeouts(&self) -> Vec<(String, FsCacheTimeouts)> {
+        self.fs_cache_timeouts.clone()
+    }
+
+    /// Returns the handles tracing the requests of each virtio-fs device, by tag.
+    #[cfg(not(feature = "tee"))]
+    pub fn fs_tracers(&self) -> Vec<(String, FsTracer)> {
+        self.fs_tracers.clone()
+    }
+
     /// Starts the microVM vcpus.
     pub fn start_vcpus(&mut self, mut vcpus: Vec<Vcpu>) -> Result<()> {
         let vcpu_count = vcpus.len();
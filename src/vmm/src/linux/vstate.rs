@@ -35,15 +35,16 @@ use crate::vmm_config::machine_config::CpuFeaturesTemplate;
 use cpuid::{c3, filter_cpuid, t2, VmSpec};
 #[cfg(target_arch = "x86_64")]
 use kvm_bindings::{
-    kvm_clock_data, kvm_debugregs, kvm_irqchip, kvm_lapic_state, kvm_mp_state, kvm_pit_state2,
-    kvm_regs, kvm_sregs, kvm_vcpu_events, kvm_xcrs, kvm_xsave, CpuId, MsrList, Msrs,
-    KVM_CLOCK_TSC_STABLE, KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE,
-    KVM_MAX_CPUID_ENTRIES,
+    kvm_clock_data, kvm_cpuid_entry2, kvm_debugregs, kvm_irqchip, kvm_lapic_state, kvm_mp_state,
+    kvm_msr_entry, kvm_pit_state2, kvm_regs, kvm_sregs, kvm_vcpu_events, kvm_xcrs, kvm_xsave,
+    CpuId, MsrList, Msrs, KVM_CLOCK_TSC_STABLE, KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER,
+    KVM_IRQCHIP_PIC_SLAVE, KVM_MAX_CPUID_ENTRIES,
 };
 use kvm_bindings::{
     kvm_create_guest_memfd, kvm_memory_attributes, kvm_userspace_memory_region,
     kvm_userspace_memory_region2, KVM_API_VERSION, KVM_MEMORY_ATTRIBUTE_PRIVATE,
-    KVM_MEM_GUEST_MEMFD, KVM_SYSTEM_EVENT_RESET, KVM_SYSTEM_EVENT_SHUTDOWN,
+    KVM_MEM_GUEST_MEMFD, KVM_MEM_LOG_DIRTY_PAGES, KVM_SYSTEM_EVENT_RESET,
+    KVM_SYSTEM_EVENT_SHUTDOWN,
 };
 #[cfg(feature = "tee")]
 use kvm_bindings::{kvm_enable_cap, KVM_CAP_EXIT_HYPERCALL, KVM_MEMORY_EXIT_FLAG_PRIVATE};
@@ -144,6 +145,10 @@ pub enum Error {
     VcpuCountNotInitialized,
     /// Cannot open the VCPU file descriptor.
     VcpuFd(kvm_ioctls::Error),
+    /// Cannot get the log of the pages written by the vCPUs.
+    GetDirtyLog(kvm_ioctls::Error),
+    /// The saved state of the VM or of a vCPU is invalid.
+    InvalidState,
     #[cfg(target_arch = "x86_64")]
     /// Failed to get KVM vcpu debug regs.
     VcpuGetDebugRegs(kvm_ioctls::Error),
@@ -261,6 +266,8 @@ impl Display for Error {
             VcpuCountNotInitialized => write!(f, "vCPU count is not initialized"),
             VmFd(e) => write!(f, "Cannot open the VM file descriptor: {e}"),
             VcpuFd(e) => write!(f, "Cannot open the VCPU file descriptor: {e}"),
+            GetDirtyLog(e) => write!(f, "Cannot get the log of the dirty pages: {e}"),
+            InvalidState => write!(f, "The saved state of the VM is invalid"),
             VmSetup(e) => write!(f, "Cannot configure the microvm: {e}"),
             VcpuRun(e) => write!(f, "Cannot run the VCPUs: {e}"),
             NotEnoughMemorySlots => write!(
@@ -443,6 +450,8 @@ impl KvmContext {
 pub struct Vm {
     fd: VmFd,
     next_mem_slot: u32,
+    // The memory regions set without a guest_memfd, by slot, to log the pages written to them.
+    mem_slots: Vec<kvm_userspace_memory_region>,
 
     // X86 specific fields.
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
@@ -477,6 +486,7 @@ impl Vm {
         Ok(Vm {
             fd: vm_fd,
             next_mem_slot: 0,
+            mem_slots: Vec::new(),
             #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
             supported_cpuid,
             #[cfg(target_arch = "x86_64")]
@@ -516,6 +526,7 @@ impl Vm {
         Ok(Vm {
             fd: vm_fd,
             next_mem_slot: 0,
+            mem_slots: Vec::new(),
             supported_cpuid,
             supported_msrs,
             tee,
@@ -593,6 +604,7 @@ impl Vm {
                     .set_user_memory_region(memory_region)
                     .map_err(Error::SetUserMemoryRegion)?;
             };
+            self.mem_slots.push(memory_region);
         } else {
             // Create a guest_memfd and set the region.
             let guest_memfd = self
@@ -677,6 +689,49 @@ impl Vm {
         &self.fd
     }
 
+    /// Starts or stops logging the pages of the guest memory the vCPUs write to, which
+    /// `dirty_pages` returns. The memory backed by guest_memfds can't be logged.
+    pub fn set_dirty_logging(&mut self, enabled: bool) -> Result<()> {
+        if !self.guest_memfds.is_empty() {
+            return Err(Error::GetDirtyLog(kvm_ioctls::Error::new(libc::ENOTSUP)));
+        }
+
+        for region in self.mem_slots.iter_mut() {
+            region.flags = if enabled { KVM_MEM_LOG_DIRTY_PAGES } else { 0 };
+            // Safe because the region is the one already set for the slot, with other flags.
+            unsafe {
+                self.fd
+                    .set_user_memory_region(*region)
+                    .map_err(Error::SetUserMemoryRegion)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the guest addresses of the pages written since the dirty logging started, or since
+    /// the last call.
+    pub fn dirty_pages(&self) -> Result<Vec<GuestAddress>> {
+        // Safe because it only reads a configuration value.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        let mut pages = Vec::new();
+        for region in &self.mem_slots {
+            let bitmap = self
+                .fd
+                .get_dirty_log(region.slot, region.memory_size as usize)
+                .map_err(Error::GetDirtyLog)?;
+            for (i, word) in bitmap.iter().enumerate() {
+                let mut word = *word;
+                while word != 0 {
+                    let bit = word.trailing_zeros() as u64;
+                    word &= word - 1;
+                    let page = i as u64 * 64 + bit;
+                    pages.push(GuestAddress(region.guest_phys_addr + page * page_size));
+                }
+            }
+        }
+        Ok(pages)
+    }
+
     #[allow(unused)]
     #[cfg(target_arch = "x86_64")]
     /// Saves and returns the Kvm Vm state.
@@ -752,6 +807,35 @@ pub struct VmState {
     ioapic: kvm_irqchip,
 }
 
+#[cfg(target_arch = "x86_64")]
+impl VmState {
+    /// Returns the state as bytes, which only `from_bytes` on the same host understands.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        put_state(&mut out, &self.pitstate);
+        put_state(&mut out, &self.clock);
+        put_state(&mut out, &self.pic_master);
+        put_state(&mut out, &self.pic_slave);
+        put_state(&mut out, &self.ioapic);
+        out
+    }
+
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        let bytes = &mut bytes;
+        let state = VmState {
+            pitstate: take_state(bytes)?,
+            clock: take_state(bytes)?,
+            pic_master: take_state(bytes)?,
+            pic_slave: take_state(bytes)?,
+            ioapic: take_state(bytes)?,
+        };
+        if !bytes.is_empty() {
+            return Err(Error::InvalidState);
+        }
+        Ok(state)
+    }
+}
+
 /// Encapsulates configuration parameters for the guest vCPUS.
 #[derive(Debug, Eq, PartialEq)]
 pub struct VcpuConfig {
@@ -1081,7 +1165,6 @@ impl Vcpu {
         ))
     }
 
-    #[allow(unused)]
     #[cfg(target_arch = "x86_64")]
     fn save_state(&self) -> Result<VcpuState> {
         /*
@@ -1147,7 +1230,8 @@ impl Vcpu {
 
     #[allow(unused)]
     #[cfg(target_arch = "x86_64")]
-    fn restore_state(&self, state: VcpuState) -> Result<()> {
+    /// Restores the state saved by `save_state`, before the vcpu is started.
+    pub fn restore_state(&self, state: VcpuState) -> Result<()> {
         /*
          * Ordering requirements:
          *
@@ -1319,6 +1403,12 @@ impl Vcpu {
                         // Notify that this KVM_RUN was interrupted.
                         Ok(VcpuEmulation::Interrupted)
                     }
+                    // The guest accessed a page of a DAX mapping whose file was truncated on the
+                    // host. Retry once the truncated pages are zero-filled.
+                    #[cfg(not(feature = "tee"))]
+                    libc::EFAULT if devices::virtio::zero_fill_truncated_mappings() => {
+                        Ok(VcpuEmulation::Handled)
+                    }
                     _ => {
                         error!("Failure during vcpu run: {}", e);
                         Err(Error::VcpuUnhandledKvmExit)
@@ -1384,6 +1474,14 @@ impl Vcpu {
                     .send(VcpuResponse::Resumed)
                     .expect("failed to send resume status");
             }
+            // The registers of a running Vcpu keep changing
+            Ok(VcpuEvent::SaveState) => {
+                self.response_sender
+                    .send(VcpuResponse::SavedState(Err(
+                        "the vCPU is running".to_string()
+                    )))
+                    .expect("failed to send the saved state");
+            }
             // Unhandled exit of the other end.
             Err(TryRecvError::Disconnected) => {
                 // Move to 'exited' state.
@@ -1408,6 +1506,19 @@ impl Vcpu {
                 // Move to 'running' state.
                 StateMachine::next(Self::running)
             }
+            Ok(VcpuEvent::SaveState) => {
+                #[cfg(target_arch = "x86_64")]
+                let state = self
+                    .save_state()
+                    .map(|state| state.to_bytes())
+                    .map_err(|e| e.to_string());
+                #[cfg(not(target_arch = "x86_64"))]
+                let state = Err("saving the state of the vCPUs is not supported".to_string());
+                self.response_sender
+                    .send(VcpuResponse::SavedState(state))
+                    .expect("failed to send the saved state");
+                StateMachine::next(Self::paused)
+            }
             // All other events have no effect on current 'paused' state.
             Ok(_) => StateMachine::next(Self::paused),
             // Unhandled exit of the other end.
@@ -1475,6 +1586,124 @@ pub struct VcpuState {
     xsave: kvm_xsave,
 }
 
+#[cfg(target_arch = "x86_64")]
+impl VcpuState {
+    /// Returns the state as bytes, which only `from_bytes` on the same host understands.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        put_states(&mut out, self.cpuid.as_slice());
+        put_states(&mut out, self.msrs.as_slice());
+        put_state(&mut out, &self.debug_regs);
+        put_state(&mut out, &self.lapic);
+        put_state(&mut out, &self.mp_state);
+        put_state(&mut out, &self.regs);
+        put_state(&mut out, &self.sregs);
+        put_state(&mut out, &self.vcpu_events);
+        put_state(&mut out, &self.xcrs);
+        put_state(&mut out, &self.xsave);
+        out
+    }
+
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        let bytes = &mut bytes;
+        let cpuid = CpuId::from_entries(&take_states(bytes)?).map_err(|_| Error::InvalidState)?;
+        let msrs = Msrs::from_entries(&take_states(bytes)?).map_err(|_| Error::InvalidState)?;
+        let state = VcpuState {
+            cpuid,
+            msrs,
+            debug_regs: take_state(bytes)?,
+            lapic: take_state(bytes)?,
+            mp_state: take_state(bytes)?,
+            regs: take_state(bytes)?,
+            sregs: take_state(bytes)?,
+            vcpu_events: take_state(bytes)?,
+            xcrs: take_state(bytes)?,
+            xsave: take_state(bytes)?,
+        };
+        if !bytes.is_empty() {
+            return Err(Error::InvalidState);
+        }
+        Ok(state)
+    }
+}
+
+/// The KVM structures of the saved states.
+///
+/// # Safety
+///
+/// The structures must be plain C structures without implicit padding, of which any bytes are a
+/// valid value.
+#[cfg(target_arch = "x86_64")]
+unsafe trait PlainState: Sized {}
+
+#[cfg(target_arch = "x86_64")]
+unsafe impl PlainState for u64 {}
+#[cfg(target_arch = "x86_64")]
+unsafe impl PlainState for kvm_pit_state2 {}
+#[cfg(target_arch = "x86_64")]
+unsafe impl PlainState for kvm_clock_data {}
+#[cfg(target_arch = "x86_64")]
+unsafe impl PlainState for kvm_irqchip {}
+#[cfg(target_arch = "x86_64")]
+unsafe impl PlainState for kvm_cpuid_entry2 {}
+#[cfg(target_arch = "x86_64")]
+unsafe impl PlainState for kvm_msr_entry {}
+#[cfg(target_arch = "x86_64")]
+unsafe impl PlainState for kvm_debugregs {}
+#[cfg(target_arch = "x86_64")]
+unsafe impl PlainState for kvm_lapic_state {}
+#[cfg(target_arch = "x86_64")]
+unsafe impl PlainState for kvm_mp_state {}
+#[cfg(target_arch = "x86_64")]
+unsafe impl PlainState for kvm_regs {}
+#[cfg(target_arch = "x86_64")]
+unsafe impl PlainState for kvm_sregs {}
+#[cfg(target_arch = "x86_64")]
+unsafe impl PlainState for kvm_vcpu_events {}
+#[cfg(target_arch = "x86_64")]
+unsafe impl PlainState for kvm_xcrs {}
+#[cfg(target_arch = "x86_64")]
+unsafe impl PlainState for kvm_xsave {}
+
+#[cfg(target_arch = "x86_64")]
+fn put_state<T: PlainState>(out: &mut Vec<u8>, value: &T) {
+    // Safe because the structure is plain data, whose bytes are all initialized.
+    let bytes = unsafe {
+        std::slice::from_raw_parts(value as *const T as *const u8, std::mem::size_of::<T>())
+    };
+    out.extend_from_slice(bytes);
+}
+
+#[cfg(target_arch = "x86_64")]
+fn put_states<T: PlainState>(out: &mut Vec<u8>, values: &[T]) {
+    put_state(out, &(values.len() as u64));
+    for value in values {
+        put_state(out, value);
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn take_state<T: PlainState>(bytes: &mut &[u8]) -> Result<T> {
+    let size = std::mem::size_of::<T>();
+    if bytes.len() < size {
+        return Err(Error::InvalidState);
+    }
+    // Safe because there are enough bytes, which are a valid value of the structure whatever they
+    // are.
+    let value = unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const T) };
+    *bytes = &bytes[size..];
+    Ok(value)
+}
+
+#[cfg(target_arch = "x86_64")]
+fn take_states<T: PlainState>(bytes: &mut &[u8]) -> Result<Vec<T>> {
+    let count = take_state::<u64>(bytes)?;
+    if count > (bytes.len() / std::mem::size_of::<T>()) as u64 {
+        return Err(Error::InvalidState);
+    }
+    (0..count).map(|_| take_state(bytes)).collect()
+}
+
 // Allow currently unused Pause and Exit events. These will be used by the vmm later on.
 #[allow(unused)]
 #[derive(Debug)]
@@ -1484,7 +1713,8 @@ pub enum VcpuEvent {
     Pause,
     /// Event that should resume the Vcpu.
     Resume,
-    // Serialize and Deserialize to follow after we get the support from kvm-ioctls.
+    /// Save the state of the paused Vcpu, for a snapshot of the guest.
+    SaveState,
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -1496,6 +1726,8 @@ pub enum VcpuResponse {
     Resumed,
     /// Vcpu is stopped.
     Exited(u8),
+    /// The state of the paused Vcpu, as returned by `VcpuState::to_bytes`, or the error saving it.
+    SavedState(result::Result<Vec<u8>, String>),
 }
 
 /// Wrapper over Vcpu that hides the underlying interactions with the Vcpu thread.
@@ -1670,6 +1902,43 @@ mod tests {
             .is_ok());
     }
 
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_save_restore_state() {
+        let (vm, mut vcpu, vm_mem) = setup_vcpu(0x10000);
+        let vcpu_config = VcpuConfig {
+            vcpu_count: 1,
+            ht_enabled: false,
+            cpu_template: None,
+        };
+        vcpu.configure_x86_64(&vm_mem, GuestAddress(0x1234), &vcpu_config)
+            .unwrap();
+
+        let bytes = vcpu.save_state().unwrap().to_bytes();
+        let state = VcpuState::from_bytes(&bytes).unwrap();
+        assert_eq!(state.to_bytes(), bytes);
+        assert_eq!(state.regs.rip, 0x1234);
+        vcpu.restore_state(state).unwrap();
+        assert!(VcpuState::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        let bytes = vm.save_state().unwrap().to_bytes();
+        vm.restore_state(&VmState::from_bytes(&bytes).unwrap())
+            .unwrap();
+        assert!(VmState::from_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+    }
+
+    #[test]
+    fn test_dirty_logging() {
+        let (mut vm, _, _) = setup_vcpu(0x10000);
+
+        // The log is only kept while logging
+        assert!(vm.dirty_pages().is_err());
+        vm.set_dirty_logging(true).unwrap();
+        assert_eq!(vm.dirty_pages().unwrap(), []);
+        vm.set_dirty_logging(false).unwrap();
+        assert!(vm.dirty_pages().is_err());
+    }
+
     #[cfg(target_arch = "aarch64")]
     #[test]
     fn test_configure_vcpu() {
@@ -122,6 +122,18 @@ pub struct VmResources {
     pub nested_enabled: bool,
     /// Whether to enable split irqchip
     pub split_irqchip: bool,
+    /// The memory that can be plugged into the guest at runtime, in MiB.
+    #[cfg(not(feature = "tee"))]
+    pub hotplug_mem_mib: Option<usize>,
+    /// Whether the guest has no entropy device.
+    #[cfg(not(feature = "tee"))]
+    pub disable_rng: bool,
+    /// The bytes of entropy per second the guest may get from its entropy device.
+    #[cfg(not(feature = "tee"))]
+    pub rng_rate_limit: Option<u64>,
+    /// The snapshot to restore the microVM from, in place of booting it.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+    pub snapshot_restore: Option<PathBuf>,
 }
 
 impl VmResources {
@@ -347,6 +359,14 @@ mod tests {
             smbios_oem_strings: None,
             nested_enabled: false,
             split_irqchip: false,
+            #[cfg(not(feature = "tee"))]
+            hotplug_mem_mib: None,
+            #[cfg(not(feature = "tee"))]
+            disable_rng: false,
+            #[cfg(not(feature = "tee"))]
+            rng_rate_limit: None,
+            #[cfg(all(target_os = "linux", target_arch = "x86_64", not(feature = "tee")))]
+            snapshot_restore: None,
         }
     }
 
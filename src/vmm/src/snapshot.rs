@@ -0,0 +1,667 @@
+//! Snapshots of a running microVM, to start it again later from where it was.
+//!
+//! A snapshot holds the RAM of the guest, the state of its vCPUs and of the VM, and the state of
+//! its virtio devices. The RAM is first copied while the guest keeps running, with KVM logging the
+//! pages the vCPUs write and the queues recording the buffers the devices write. The vCPUs are then
+//! paused and the queues quiesced, and only the pages written in the meantime are copied again, so
+//! that the guest stops for as short as possible.
+//!
+//! A snapshot is restored into a microVM configured like the one it was taken of, in place of
+//! booting it. Only the devices that can save their state are supported: the consoles, virtio-fs
+//! over FUSE, the balloon, the RNG, and vsock and the network, which start over without their
+//! connections. The disks, whose images keep changing after the snapshot, the GPU and sound
+//! devices aren't, nor is a split irqchip, whose IOAPIC KVM doesn't save, nor the memory
+//! hotplugged into the guest.
+//!
+//! The snapshot file starts with a header page describing where the rest is, followed by the RAM
+//! regions at page-aligned offsets and the encoded states of the vCPUs, of the VM and of the
+//! devices.
+
+use std::collections::BTreeSet;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use devices::virtio::{quiesce, MmioState, QueueState};
+use vm_memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+use crate::vstate::{self, Vcpu, VcpuEvent, VcpuResponse, VcpuState, VmState};
+use crate::Vmm;
+
+//--------------------------------------------------------------------------------------------------
+// Constants
+//--------------------------------------------------------------------------------------------------
+
+const MAGIC: &[u8; 8] = b"KRUNSNAP";
+
+/// The version of the file format, bumped whenever it changes.
+const VERSION: u64 = 1;
+
+/// The size of the header, which the RAM regions follow.
+const HEADER_SIZE: u64 = 4096;
+
+/// The size of the chunks the RAM is copied by.
+const CHUNK_SIZE: u64 = 1 << 20;
+
+/// How long the devices must go without completing requests to be considered idle.
+const SETTLE_TIME: Duration = Duration::from_millis(20);
+
+/// How long the devices may take to finish the requests they started once their queues froze.
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a vCPU may take to answer an event.
+const VCPU_TIMEOUT: Duration = Duration::from_millis(1000);
+
+//--------------------------------------------------------------------------------------------------
+// Types
+//--------------------------------------------------------------------------------------------------
+
+/// Errors taking or restoring a snapshot.
+#[derive(Debug)]
+pub enum Error {
+    /// A device failed to save or restore its state.
+    Device(io::Error),
+    /// Reading or writing the snapshot file failed.
+    File(io::Error),
+    /// The snapshot file is truncated or corrupted.
+    InvalidSnapshot,
+    /// The snapshot wasn't taken of a microVM configured like this one.
+    Mismatch(&'static str),
+    /// The devices kept completing requests once their queues were frozen.
+    Quiesce,
+    /// The microVM can't be snapshotted.
+    Unsupported(&'static str),
+    /// A vCPU didn't pause, resume, or save its state.
+    Vcpu(String),
+    /// Getting or setting the state of the VM or of a vCPU failed.
+    Vstate(vstate::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Where the RAM regions of the guest are in the snapshot file.
+#[derive(Debug, PartialEq, Eq)]
+struct Layout {
+    /// The guest address, size and file offset of each region.
+    regions: Vec<(u64, u64, u64)>,
+    /// The file offset of the encoded states.
+    state_offset: u64,
+}
+
+/// The header of the snapshot file.
+struct Header {
+    vcpus: u64,
+    layout: Layout,
+    state_len: u64,
+}
+
+/// The states of the microVM, besides its RAM.
+struct State {
+    vcpus: Vec<Vec<u8>>,
+    vm: Vec<u8>,
+    devices: Vec<MmioState>,
+}
+
+/// Reads the fields of an encoded header or state.
+struct Decoder<'a>(&'a [u8]);
+
+//--------------------------------------------------------------------------------------------------
+// Methods
+//--------------------------------------------------------------------------------------------------
+
+impl Vmm {
+    /// Saves a snapshot of the running microVM to `path`, which it keeps running afterwards.
+    pub fn snapshot_save(&mut self, path: &Path) -> Result<()> {
+        if self.mem_resizer.is_some() {
+            return Err(Error::Unsupported(
+                "the memory hotplugged into the guest can't be saved",
+            ));
+        }
+        self.check_supported()?;
+
+        let layout = Layout::new(&self.guest_memory, self.arch_memory_info.shm_start_addr);
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+        let file = File::create(&partial).map_err(Error::File)?;
+
+        // The RAM is copied while the guest keeps running if the pages it writes can be told
+        let precopy = self.vm.set_dirty_logging(true).is_ok();
+        quiesce::start_tracking();
+        let mut result = if precopy {
+            save_ram(&file, &self.guest_memory, &layout)
+        } else {
+            Ok(())
+        };
+        if result.is_ok() {
+            result = self
+                .pause_vcpus()
+                .and_then(|()| self.save_paused(&file, &layout, precopy));
+        }
+
+        // Whatever happened, the guest must run again as it was
+        quiesce::release_completions();
+        quiesce::stop_tracking();
+        quiesce::thaw_queues();
+        for transport in self.mmio_device_manager.transports() {
+            transport.lock().unwrap().kick_queues();
+        }
+        if precopy {
+            if let Err(e) = self.vm.set_dirty_logging(false) {
+                error!("Failed to stop logging the dirty pages of the guest: {e}");
+            }
+        }
+        let resumed = self
+            .resume_vcpus()
+            .map_err(|e| Error::Vcpu(format!("failed to resume the vCPUs: {e}")));
+
+        let result = result
+            .and_then(|()| file.sync_all().map_err(Error::File))
+            .and_then(|()| fs::rename(&partial, path).map_err(Error::File));
+        if result.is_err() {
+            let _ = fs::remove_file(&partial);
+        }
+        result.and(resumed)
+    }
+
+    /// Restores the snapshot at `path` into the microVM, just built with `vcpus`, in place of
+    /// booting it.
+    pub fn snapshot_restore(&mut self, vcpus: &[Vcpu], path: &Path) -> Result<()> {
+        self.check_supported()?;
+        let file = File::open(path).map_err(Error::File)?;
+        let mut header = vec![0; HEADER_SIZE as usize];
+        read_at(&file, &mut header, 0)?;
+        let header = Header::decode(&header)?;
+
+        let layout = Layout::new(&self.guest_memory, self.arch_memory_info.shm_start_addr);
+        if header.layout != layout {
+            return Err(Error::Mismatch("the RAM of the guest has another size"));
+        }
+        if header.vcpus != vcpus.len() as u64 {
+            return Err(Error::Mismatch("the guest has another number of vCPUs"));
+        }
+
+        let state_len = usize::try_from(header.state_len).map_err(|_| Error::InvalidSnapshot)?;
+        let mut state = vec![0; state_len];
+        read_at(&file, &mut state, layout.state_offset)?;
+        let state = State::decode(&state)?;
+        let transports = self.mmio_device_manager.transports();
+        if state.vcpus.len() != vcpus.len() || state.devices.len() != transports.len() {
+            return Err(Error::Mismatch("the guest has other devices"));
+        }
+
+        for &(addr, size, offset) in &layout.regions {
+            restore_range(&file, &self.guest_memory, GuestAddress(addr), size, offset)?;
+        }
+
+        let vm = VmState::from_bytes(&state.vm).map_err(Error::Vstate)?;
+        self.vm.restore_state(&vm).map_err(Error::Vstate)?;
+        for (vcpu, saved) in vcpus.iter().zip(&state.vcpus) {
+            let saved = VcpuState::from_bytes(saved).map_err(Error::Vstate)?;
+            vcpu.restore_state(saved).map_err(Error::Vstate)?;
+        }
+        for (transport, saved) in transports.iter().zip(&state.devices) {
+            let mut transport = transport.lock().unwrap();
+            transport.restore_state(saved).map_err(Error::Device)?;
+            // The guest may have queued requests the device never saw
+            transport.kick_queues();
+        }
+
+        Ok(())
+    }
+
+    /// Fails if the microVM has an interrupt controller or devices whose state can't be saved.
+    fn check_supported(&self) -> Result<()> {
+        if self.split_irqchip {
+            return Err(Error::Unsupported(
+                "the state of the IOAPIC of a split irqchip can't be saved",
+            ));
+        }
+        let transports = self.mmio_device_manager.transports();
+        if !transports
+            .iter()
+            .all(|transport| transport.lock().unwrap().snapshot_supported())
+        {
+            return Err(Error::Unsupported(
+                "the state of some of the devices of the guest can't be saved",
+            ));
+        }
+        Ok(())
+    }
+
+    fn pause_vcpus(&self) -> Result<()> {
+        for handle in &self.vcpus_handles {
+            handle.send_event(VcpuEvent::Pause).map_err(Error::Vstate)?;
+        }
+        for handle in &self.vcpus_handles {
+            match handle.response_receiver().recv_timeout(VCPU_TIMEOUT) {
+                Ok(VcpuResponse::Paused) => (),
+                _ => return Err(Error::Vcpu("a vCPU didn't pause".to_string())),
+            }
+        }
+        Ok(())
+    }
+
+    /// Saves everything but the RAM already copied, once the vCPUs are paused.
+    fn save_paused(&mut self, file: &File, layout: &Layout, precopy: bool) -> Result<()> {
+        quiesce::freeze_queues();
+        settle()?;
+        quiesce::hold_completions();
+
+        let devices = self
+            .mmio_device_manager
+            .transports()
+            .iter()
+            .map(|transport| transport.lock().unwrap().save_state())
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(Error::Device)?;
+
+        // The interrupts of the completions are in the state of the vCPUs and of the VM by now
+        let vcpus = self
+            .vcpus_handles
+            .iter()
+            .map(|handle| {
+                handle
+                    .send_event(VcpuEvent::SaveState)
+                    .map_err(Error::Vstate)?;
+                match handle.response_receiver().recv_timeout(VCPU_TIMEOUT) {
+                    Ok(VcpuResponse::SavedState(state)) => state.map_err(Error::Vcpu),
+                    _ => Err(Error::Vcpu("a vCPU didn't save its state".to_string())),
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let vm = self.vm.save_state().map_err(Error::Vstate)?.to_bytes();
+
+        let written = quiesce::stop_tracking();
+        if precopy {
+            let page_size = self.arch_memory_info.page_size as u64;
+            let mut pages: BTreeSet<u64> = self
+                .vm
+                .dirty_pages()
+                .map_err(Error::Vstate)?
+                .into_iter()
+                .map(|page| page.0)
+                .collect();
+            for (addr, len) in written {
+                let first = addr.0 - addr.0 % page_size;
+                let end = addr.0 + u64::from(len);
+                pages.extend((first..end).step_by(page_size as usize));
+            }
+            for page in pages {
+                let Some(offset) = layout.file_offset(page, page_size) else {
+                    continue;
+                };
+                save_range(
+                    file,
+                    &self.guest_memory,
+                    GuestAddress(page),
+                    page_size,
+                    offset,
+                )?;
+            }
+        } else {
+            save_ram(file, &self.guest_memory, layout)?;
+        }
+
+        let state = State { vcpus, vm, devices }.encode();
+        file.write_all_at(&state, layout.state_offset)
+            .map_err(Error::File)?;
+
+        // The header is written last, so that a partial snapshot is never taken for a valid one
+        let header = Header {
+            vcpus: self.vcpus_handles.len() as u64,
+            layout: Layout {
+                regions: layout.regions.clone(),
+                state_offset: layout.state_offset,
+            },
+            state_len: state.len() as u64,
+        };
+        file.write_all_at(&header.encode(), 0).map_err(Error::File)
+    }
+}
+
+impl Layout {
+    /// Lays out the RAM regions of `mem`, the ones below `shm_start`.
+    fn new(mem: &GuestMemoryMmap, shm_start: u64) -> Self {
+        let mut regions = Vec::new();
+        let mut offset = HEADER_SIZE;
+        for region in mem.iter() {
+            let addr = region.start_addr().0;
+            if addr >= shm_start {
+                continue;
+            }
+            let size = region.len();
+            regions.push((addr, size, offset));
+            offset += size.next_multiple_of(HEADER_SIZE);
+        }
+        Layout {
+            regions,
+            state_offset: offset,
+        }
+    }
+
+    /// Returns the file offset of the `len` bytes at the guest address `addr`, if they're in RAM.
+    fn file_offset(&self, addr: u64, len: u64) -> Option<u64> {
+        self.regions
+            .iter()
+            .find(|(start, size, _)| *start <= addr && addr + len <= start + size)
+            .map(|(start, _, offset)| offset + addr - start)
+    }
+}
+
+impl Header {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        put_u64(&mut out, VERSION);
+        put_u64(&mut out, self.vcpus);
+        put_u64(&mut out, self.layout.regions.len() as u64);
+        for &(addr, size, offset) in &self.layout.regions {
+            put_u64(&mut out, addr);
+            put_u64(&mut out, size);
+            put_u64(&mut out, offset);
+        }
+        put_u64(&mut out, self.layout.state_offset);
+        put_u64(&mut out, self.state_len);
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        let mut decoder = Decoder(bytes);
+        if decoder.bytes(MAGIC.len() as u64)? != MAGIC || decoder.u64()? != VERSION {
+            return Err(Error::InvalidSnapshot);
+        }
+
+        let vcpus = decoder.u64()?;
+        let count = decoder.u64()?;
+        let mut regions = Vec::new();
+        for _ in 0..count {
+            regions.push((decoder.u64()?, decoder.u64()?, decoder.u64()?));
+        }
+        Ok(Header {
+            vcpus,
+            layout: Layout {
+                regions,
+                state_offset: decoder.u64()?,
+            },
+            state_len: decoder.u64()?,
+        })
+    }
+}
+
+impl State {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        put_u64(&mut out, self.vcpus.len() as u64);
+        for vcpu in &self.vcpus {
+            put_bytes(&mut out, vcpu);
+        }
+        put_bytes(&mut out, &self.vm);
+
+        put_u64(&mut out, self.devices.len() as u64);
+        for device in &self.devices {
+            put_u64(&mut out, device.device_type.into());
+            put_u64(&mut out, device.features_select.into());
+            put_u64(&mut out, device.acked_features_select.into());
+            put_u64(&mut out, device.queue_select.into());
+            put_u64(&mut out, device.device_status.into());
+            put_u64(&mut out, device.config_generation.into());
+            put_u64(&mut out, device.interrupt_status.into());
+            put_u64(&mut out, device.acked_features);
+            put_u64(&mut out, device.queues.len() as u64);
+            for queue in &device.queues {
+                put_u64(&mut out, queue.size.into());
+                put_u64(&mut out, queue.ready.into());
+                put_u64(&mut out, queue.desc_table);
+                put_u64(&mut out, queue.avail_ring);
+                put_u64(&mut out, queue.used_ring);
+            }
+            put_bytes(&mut out, &device.device);
+        }
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        let mut decoder = Decoder(bytes);
+        let count = decoder.u64()?;
+        let mut vcpus = Vec::new();
+        for _ in 0..count {
+            vcpus.push(decoder.vec()?);
+        }
+        let vm = decoder.vec()?;
+
+        let count = decoder.u64()?;
+        let mut devices = Vec::new();
+        for _ in 0..count {
+            let mut device = MmioState {
+                device_type: decoder.u32()?,
+                features_select: decoder.u32()?,
+                acked_features_select: decoder.u32()?,
+                queue_select: decoder.u32()?,
+                device_status: decoder.u32()?,
+                config_generation: decoder.u32()?,
+                interrupt_status: decoder.u32()?,
+                acked_features: decoder.u64()?,
+                ..Default::default()
+            };
+            let queues = decoder.u64()?;
+            for _ in 0..queues {
+                device.queues.push(QueueState {
+                    size: u16::try_from(decoder.u64()?).map_err(|_| Error::InvalidSnapshot)?,
+                    ready: decoder.u64()? != 0,
+                    desc_table: decoder.u64()?,
+                    avail_ring: decoder.u64()?,
+                    used_ring: decoder.u64()?,
+                });
+            }
+            device.device = decoder.vec()?;
+            devices.push(device);
+        }
+
+        if !decoder.0.is_empty() {
+            return Err(Error::InvalidSnapshot);
+        }
+        Ok(State { vcpus, vm, devices })
+    }
+}
+
+impl<'a> Decoder<'a> {
+    fn bytes(&mut self, len: u64) -> Result<&'a [u8]> {
+        let len = usize::try_from(len).map_err(|_| Error::InvalidSnapshot)?;
+        if self.0.len() < len {
+            return Err(Error::InvalidSnapshot);
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn vec(&mut self) -> Result<Vec<u8>> {
+        let len = self.u64()?;
+        Ok(self.bytes(len)?.to_vec())
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        u32::try_from(self.u64()?).map_err(|_| Error::InvalidSnapshot)
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Device(e) => write!(f, "Cannot save or restore the state of a device: {e}"),
+            File(e) => write!(f, "Cannot read or write the snapshot: {e}"),
+            InvalidSnapshot => write!(f, "The snapshot is truncated or corrupted"),
+            Mismatch(e) => write!(f, "The snapshot is of another microVM: {e}"),
+            Quiesce => write!(f, "The devices didn't go idle"),
+            Unsupported(e) => write!(f, "The microVM can't be snapshotted: {e}"),
+            Vcpu(e) => write!(f, "Cannot save the state of the vCPUs: {e}"),
+            Vstate(e) => write!(f, "Cannot save or restore the state of the VM: {e}"),
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+// Functions
+//--------------------------------------------------------------------------------------------------
+
+/// Waits until the devices stop completing requests, once their queues are frozen.
+fn settle() -> Result<()> {
+    let deadline = Instant::now() + SETTLE_TIMEOUT;
+    let mut completions = quiesce::completions();
+    loop {
+        thread::sleep(SETTLE_TIME);
+        let now = quiesce::completions();
+        if now == completions {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(Error::Quiesce);
+        }
+        completions = now;
+    }
+}
+
+/// Copies the whole RAM of the guest to the snapshot.
+fn save_ram(file: &File, mem: &GuestMemoryMmap, layout: &Layout) -> Result<()> {
+    for &(addr, size, offset) in &layout.regions {
+        save_range(file, mem, GuestAddress(addr), size, offset)?;
+    }
+    Ok(())
+}
+
+/// Copies the `len` bytes of the guest memory at `addr` to `offset` in the snapshot.
+fn save_range(
+    file: &File,
+    mem: &GuestMemoryMmap,
+    addr: GuestAddress,
+    len: u64,
+    offset: u64,
+) -> Result<()> {
+    let mut buf = vec![0; len.min(CHUNK_SIZE) as usize];
+    let mut done = 0;
+    while done < len {
+        let chunk = &mut buf[..(len - done).min(CHUNK_SIZE) as usize];
+        mem.read_slice(chunk, GuestAddress(addr.0 + done))
+            .map_err(|e| Error::File(io::Error::other(e)))?;
+        file.write_all_at(chunk, offset + done)
+            .map_err(Error::File)?;
+        done += chunk.len() as u64;
+    }
+    Ok(())
+}
+
+/// Copies the `len` bytes at `offset` in the snapshot to the guest memory at `addr`.
+fn restore_range(
+    file: &File,
+    mem: &GuestMemoryMmap,
+    addr: GuestAddress,
+    len: u64,
+    offset: u64,
+) -> Result<()> {
+    let mut buf = vec![0; len.min(CHUNK_SIZE) as usize];
+    let mut done = 0;
+    while done < len {
+        let chunk = &mut buf[..(len - done).min(CHUNK_SIZE) as usize];
+        read_at(file, chunk, offset + done)?;
+        mem.write_slice(chunk, GuestAddress(addr.0 + done))
+            .map_err(|e| Error::File(io::Error::other(e)))?;
+        done += chunk.len() as u64;
+    }
+    Ok(())
+}
+
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> Result<()> {
+    file.read_exact_at(buf, offset).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => Error::InvalidSnapshot,
+        _ => Error::File(e),
+    })
+}
+
+fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_u64(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let state = State {
+            vcpus: vec![vec![1, 2, 3], vec![]],
+            vm: vec![4; 10],
+            devices: vec![MmioState {
+                device_type: 26,
+                device_status: 0xf,
+                acked_features: 1 << 32,
+                queues: vec![QueueState {
+                    size: 256,
+                    ready: true,
+                    desc_table: 0x1000,
+                    avail_ring: 0x2000,
+                    used_ring: 0x3000,
+                }],
+                device: vec![5; 3],
+                ..Default::default()
+            }],
+        };
+        let bytes = state.encode();
+        let decoded = State::decode(&bytes).unwrap();
+        assert_eq!(decoded.vcpus, state.vcpus);
+        assert_eq!(decoded.vm, state.vm);
+        assert_eq!(decoded.devices, state.devices);
+        assert!(State::decode(&bytes[..bytes.len() - 1]).is_err());
+
+        let header = Header {
+            vcpus: 2,
+            layout: Layout {
+                regions: vec![(0, 0x10000, HEADER_SIZE), (0x100000, 0x2000, 0x11000)],
+                state_offset: 0x13000,
+            },
+            state_len: bytes.len() as u64,
+        };
+        let mut encoded = header.encode();
+        assert!(encoded.len() as u64 <= HEADER_SIZE);
+        let decoded = Header::decode(&encoded).unwrap();
+        assert_eq!(decoded.vcpus, 2);
+        assert_eq!(decoded.layout, header.layout);
+        assert_eq!(decoded.state_len, header.state_len);
+        encoded[0] = 0;
+        assert!(Header::decode(&encoded).is_err());
+    }
+
+    #[test]
+    fn test_layout() {
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[
+            (GuestAddress(0), 0x3000),
+            (GuestAddress(0x100000), 0x1800),
+            (GuestAddress(0x200000), 0x1000),
+        ])
+        .unwrap();
+        let layout = Layout::new(&mem, 0x200000);
+        assert_eq!(
+            layout.regions,
+            [(0, 0x3000, HEADER_SIZE), (0x100000, 0x1800, 0x4000)]
+        );
+        assert_eq!(layout.state_offset, 0x6000);
+        assert_eq!(layout.file_offset(0x1000, 0x1000), Some(0x2000));
+        assert_eq!(layout.file_offset(0x100800, 0x1000), Some(0x4800));
+        assert_eq!(layout.file_offset(0x2800, 0x1000), None);
+        assert_eq!(layout.file_offset(0x200000, 0x1000), None);
+    }
+}
@@ -2,7 +2,10 @@ use std::collections::VecDeque;
 use std::fmt;
 use std::sync::{Arc, Mutex};
 
-use devices::virtio::{block::ImageType, Block, CacheType};
+use devices::virtio::{
+    block::{BlockFormat, DiskSnapshot},
+    Block, CacheType,
+};
 
 #[derive(Debug)]
 pub enum BlockConfigError {
@@ -26,7 +29,8 @@ pub struct BlockDeviceConfig {
     pub block_id: String,
     pub cache_type: CacheType,
     pub disk_image_path: String,
-    pub disk_image_format: ImageType,
+    pub disk_image_format: BlockFormat,
+    pub disk_snapshot: Option<DiskSnapshot>,
     pub is_disk_read_only: bool,
 }
 
@@ -55,6 +59,7 @@ impl BlockBuilder {
             config.cache_type,
             config.disk_image_path,
             config.disk_image_format,
+            config.disk_snapshot,
             config.is_disk_read_only,
         )
         .map_err(BlockConfigError::CreateBlockDevice)
@@ -1,8 +1,44 @@
-use devices::virtio::fs::FsImplShare;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use devices::virtio::fs::{
+    FsAccessRules, FsAtime, FsBackgroundLimits, FsCredentials, FsDegradeOptions, FsDirTemplate,
+    FsGuestMount, FsHook, FsImplShare, FsInodeNumbers, FsLeases, FsModePolicy, FsOwnership,
+    FsPageCache, FsProtocol, FsVirtualFile, FsWatch, FsWriteCoalescing,
+};
 
 #[derive(Clone, Debug)]
 pub struct FsDeviceConfig {
     pub fs_id: String,
     pub fs_share: FsImplShare,
     pub shm_size: Option<usize>,
+    pub access_rules: Option<FsAccessRules>,
+    pub credentials: Option<FsCredentials>,
+    pub durable: bool,
+    pub allow_file_flags: bool,
+    pub remove_tree: bool,
+    pub batch_create: bool,
+    pub dentry_warming: Option<usize>,
+    pub max_handles: Option<usize>,
+    pub atime: FsAtime,
+    pub background_limits: Option<FsBackgroundLimits>,
+    pub watches: Vec<FsWatch>,
+    pub mirror: Option<PathBuf>,
+    pub revalidate_interval: Option<Duration>,
+    pub write_coalescing: Option<FsWriteCoalescing>,
+    pub virtual_files: Vec<FsVirtualFile>,
+    pub protect_init_config: bool,
+    pub leases: Option<FsLeases>,
+    pub inspect_socket: Option<PathBuf>,
+    pub dir_templates: Vec<FsDirTemplate>,
+    pub protocol: FsProtocol,
+    pub inode_numbers: FsInodeNumbers,
+    pub mode_policy: Option<FsModePolicy>,
+    pub hooks: Vec<FsHook>,
+    pub shared_lower_layers: bool,
+    pub normalize_names: bool,
+    pub page_cache: FsPageCache,
+    pub guest_mount: Option<FsGuestMount>,
+    pub degrade: Option<FsDegradeOptions>,
+    pub ownership: FsOwnership,
 }
@@ -4,12 +4,13 @@
 use std::collections::HashMap;
 use std::fmt;
 use std::net::Ipv4Addr;
+use std::os::unix::io::{FromRawFd, OwnedFd, RawFd};
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
 use ipnetwork::Ipv4Network;
 
-use devices::virtio::{Vsock, VsockError};
+use devices::virtio::{PortMap, Vsock, VsockError};
 
 type MutexVsock = Arc<Mutex<Vsock>>;
 
@@ -39,10 +40,13 @@ pub struct VsockDeviceConfig {
     pub vsock_id: String,
     /// A 32-bit Context Identifier (CID) used to identify the guest.
     pub guest_cid: u32,
-    /// An optional map of host to guest port mappings.
-    pub host_port_map: Option<HashMap<u16, u16>>,
+    /// An optional map of guest ports to the host addresses and ports they are exposed on.
+    pub host_port_map: Option<PortMap>,
     /// An optional map of guest port to host UNIX domain sockets for IPC.
     pub unix_ipc_port_map: Option<HashMap<u32, (PathBuf, bool)>>,
+    /// A map of guest port to host sockets already listening, whose connections are forwarded to
+    /// the guest. The device takes ownership of the sockets.
+    pub listener_fds: HashMap<u32, RawFd>,
     /// Optional static IP address for TSI.
     pub ip: Option<Ipv4Addr>,
     /// Optional subnet for TSI.
@@ -83,7 +87,7 @@ impl VsockBuilder {
 
     /// Creates a Vsock device from a VsockDeviceConfig.
     pub fn create_vsock(cfg: VsockDeviceConfig) -> Result<Vsock> {
-        Vsock::new(
+        let mut vsock = Vsock::new(
             u64::from(cfg.guest_cid),
             cfg.host_port_map,
             cfg.unix_ipc_port_map,
@@ -91,7 +95,14 @@ impl VsockBuilder {
             cfg.subnet,
             cfg.scope,
         )
-        .map_err(VsockConfigError::CreateVsockDevice)
+        .map_err(VsockConfigError::CreateVsockDevice)?;
+
+        for (port, fd) in cfg.listener_fds {
+            // Safe because the embedder handed the socket over to us.
+            vsock.add_listener_fd(port, unsafe { OwnedFd::from_raw_fd(fd) });
+        }
+
+        Ok(vsock)
     }
 }
 
@@ -127,6 +138,7 @@ pub(crate) mod tests {
             guest_cid: 3,
             host_port_map: None,
             unix_ipc_port_map: None,
+            listener_fds: HashMap::new(),
             ip: None,
             subnet: None,
             scope: 0,